@@ -0,0 +1,111 @@
+//! This application translates virtual addresses into the crate, symbol, and offset that contain them,
+//! which is useful for interpreting raw instruction pointers or fault addresses from a log.
+//! 
+//! It performs the inverse translation of the `sym2addr` application.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate memory;
+extern crate mod_mgmt;
+extern crate task;
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use memory::VirtualAddress;
+use mod_mgmt::CrateNamespace;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("t", "text-only", "only search executable (.text) sections, e.g., for instruction pointers");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let namespace = get_my_current_namespace();
+    let search_all_section_types = !matches.opt_present("t");
+
+    let mut not_found = 0;
+    for address in matches.free.iter() {
+        let vaddr = parse_address(address)?;
+        match namespace.get_section_containing_address(vaddr, search_all_section_types) {
+            Some((sec, offset)) => {
+                let crate_name = sec.parent_crate.upgrade()
+                    .map(|parent| parent.lock_as_ref().crate_name.clone())
+                    .unwrap_or_else(|| String::from("<unknown crate>"));
+                println!("{:#X}: {} + {:#X} [{:?}, {} bytes] in crate {}",
+                    vaddr, sec.name, offset, sec.typ, sec.size(), crate_name,
+                );
+            }
+            None => {
+                println!("{:#X}: not found in any loaded crate in namespace {:?}", vaddr, namespace.name());
+                not_found += 1;
+            }
+        }
+    }
+
+    if not_found > 0 {
+        Err(format!("couldn't translate {} of {} addresses", not_found, matches.free.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parses a virtual address in hexadecimal, with or without a leading `0x`.
+fn parse_address(address: &str) -> Result<VirtualAddress, String> {
+    let digits = if address.starts_with("0x") || address.starts_with("0X") {
+        &address[2..]
+    } else {
+        address
+    };
+    let value = usize::from_str_radix(digits, 16).map_err(|_e| format!("invalid virtual address {:?}", address))?;
+    VirtualAddress::new(value).map_err(|e| e.to_string())
+}
+
+
+fn get_my_current_namespace() -> Arc<CrateNamespace> {
+    task::get_my_current_task().map(|t| t.get_namespace()).unwrap_or_else(|| 
+        mod_mgmt::get_initial_kernel_namespace().expect("BUG: initial kernel namespace wasn't initialized").clone()
+    )
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: addr2sym [-t] ADDRESS...
+Translates each virtual ADDRESS (in hexadecimal) into the crate, section, and offset that contains it.
+Searches all sections of the crates loaded in the current namespace, or only .text sections with -t.";
@@ -167,7 +167,8 @@ pub fn remove_child_streams(task_id: &usize) -> Option<IoStreams> {
 /// Applications call this function to acquire a reader to its stdin queue.
 /// 
 /// Errors can occur in two cases. One is when it fails to get the task_id of the calling
-/// task, and the second is that there's no stdin reader stored in the map. Shells should
+/// task, and the second is that there's no stdin reader stored in the map
+/// or in the task's `Environment`. Shells should
 /// make sure to store IoStreams for the newly spawned app first, and then unblocks the app
 /// to let it run.
 pub fn stdin() -> Result<StdioReader, &'static str> {
@@ -175,14 +176,17 @@ pub fn stdin() -> Result<StdioReader, &'static str> {
     let locked_streams = shared_maps::lock_stream_map();
     match locked_streams.get(&task_id) {
         Some(queues) => Ok(queues.stdin.clone()),
-        None => Err("no stdin for this task")
+        None => task::get_my_current_task()
+            .and_then(|t| t.get_env().lock().stdin.clone())
+            .ok_or("no stdin for this task")
     }
 }
 
 /// Applications call this function to acquire a writer to its stdout queue.
 /// 
 /// Errors can occur in two cases. One is when it fails to get the task_id of the calling
-/// task, and the second is that there's no stdout writer stored in the map. Shells should
+/// task, and the second is that there's no stdout writer stored in the map
+/// or in the task's `Environment`. Shells should
 /// make sure to store IoStreams for the newly spawned app first, and then unblocks the app
 /// to let it run.
 pub fn stdout() -> Result<StdioWriter, &'static str> {
@@ -190,14 +194,17 @@ pub fn stdout() -> Result<StdioWriter, &'static str> {
     let locked_streams = shared_maps::lock_stream_map();
     match locked_streams.get(&task_id) {
         Some(queues) => Ok(queues.stdout.clone()),
-        None => Err("no stdout for this task")
+        None => task::get_my_current_task()
+            .and_then(|t| t.get_env().lock().stdout.clone())
+            .ok_or("no stdout for this task")
     }
 }
 
 /// Applications call this function to acquire a writer to its stderr queue.
 /// 
 /// Errors can occur in two cases. One is when it fails to get the task_id of the calling
-/// task, and the second is that there's no stderr writer stored in the map. Shells should
+/// task, and the second is that there's no stderr writer stored in the map
+/// or in the task's `Environment`. Shells should
 /// make sure to store IoStreams for the newly spawned app first, and then unblocks the app
 /// to let it run.
 pub fn stderr() -> Result<StdioWriter, &'static str> {
@@ -205,7 +212,9 @@ pub fn stderr() -> Result<StdioWriter, &'static str> {
     let locked_streams = shared_maps::lock_stream_map();
     match locked_streams.get(&task_id) {
         Some(queues) => Ok(queues.stderr.clone()),
-        None => Err("no stderr for this task")
+        None => task::get_my_current_task()
+            .and_then(|t| t.get_env().lock().stderr.clone())
+            .ok_or("no stderr for this task")
     }
 }
 
@@ -333,10 +342,14 @@ pub fn print_to_stdout_args(fmt_args: fmt::Arguments) {
     };
 
     // Obtains the correct stdout stream and push the output bytes.
-    let locked_streams = shared_maps::lock_stream_map();
-    match locked_streams.get(&task_id) {
-        Some(queues) => {
-            if let Err(_) = queues.stdout.lock().write_all(format!("{}", fmt_args).as_bytes()) {
+    // Like `stdout()`, this falls back to the stdout of the task's `Environment`, e.g., a remote shell's pseudo-terminal.
+    let stdout = match shared_maps::lock_stream_map().get(&task_id) {
+        Some(queues) => Some(queues.stdout.clone()),
+        None => task::get_my_current_task().and_then(|t| t.get_env().lock().stdout.clone()),
+    };
+    match stdout {
+        Some(stdout) => {
+            if let Err(_) = stdout.lock().write_all(format!("{}", fmt_args).as_bytes()) {
                 let _ = serial_port::write_str("\x1b[31m [E] failed to write to stdout \x1b[0m\n");
             }
         },
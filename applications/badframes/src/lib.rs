@@ -0,0 +1,94 @@
+//! This application lists or adds to the quarantined physical frames that will never be used again,
+//! e.g., because they reported memory errors.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate memory;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use memory::{Frame, PhysicalAddress};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optmulti("q", "quarantine", "quarantine the frame containing the given physical ADDRESS", "ADDRESS");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    for address in matches.opt_strs("q") {
+        let paddr = parse_address(&address)?;
+        let frame = Frame::containing_address(paddr);
+        if memory::quarantine_frame(frame)? {
+            println!("Quarantined {:?}, which will be discarded once it is no longer in use.", frame);
+        } else {
+            println!("Quarantined {:?}.", frame);
+        }
+    }
+
+    let frames = memory::quarantined_frames();
+    if frames.is_empty() {
+        println!("There are no quarantined frames.");
+        return Ok(());
+    }
+    println!("{} quarantined frames:", frames.len());
+    for frame in frames.iter() {
+        println!("    {:?}", frame);
+    }
+    if let Some(boot_arg) = memory::quarantine_boot_arg() {
+        println!("To avoid these frames upon the next boot, add this to the kernel's boot command line:\n    {}", boot_arg);
+    }
+    Ok(())
+}
+
+/// Parses a physical address in either hexadecimal (starting with `0x`) or decimal.
+fn parse_address(address: &str) -> Result<PhysicalAddress, String> {
+    let parsed = if address.starts_with("0x") || address.starts_with("0X") {
+        usize::from_str_radix(&address[2..], 16)
+    } else {
+        address.parse::<usize>()
+    };
+    let value = parsed.map_err(|_e| format!("invalid physical address {:?}", address))?;
+    PhysicalAddress::new(value).map_err(|e| e.to_string())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: badframes [-q ADDRESS]...
+Lists the quarantined physical frames that will never be used again, e.g., because they reported memory errors.
+With -q, first quarantines the frame containing the given physical ADDRESS.";
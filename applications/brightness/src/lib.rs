@@ -0,0 +1,100 @@
+//! This application shows or changes the brightness of the display backlight.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate backlight;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflagopt("i", "increase", "increase the brightness by STEP percent (default 10)", "STEP");
+    opts.optflagopt("d", "decrease", "decrease the brightness by STEP percent (default 10)", "STEP");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let name = backlight::backlight_name().ok_or("no display backlight with brightness control was found")?;
+
+    let delta = if matches.opt_present("i") {
+        Some(parse_step(matches.opt_str("i"))? as i8)
+    } else if matches.opt_present("d") {
+        Some(-(parse_step(matches.opt_str("d"))? as i8))
+    } else {
+        None
+    };
+
+    if let Some(delta) = delta {
+        let percent = backlight::adjust_brightness(delta)?;
+        println!("Brightness: {}%", percent);
+        return Ok(());
+    }
+
+    match matches.free.first() {
+        Some(arg) => {
+            let percent = arg.trim_end_matches('%').parse::<u8>()
+                .ok()
+                .filter(|p| *p <= 100)
+                .ok_or_else(|| format!("invalid brightness {:?}, expected a percentage from 0 to 100", arg))?;
+            backlight::set_brightness_percent(percent)?;
+            println!("Brightness: {}%", backlight::brightness_percent()?);
+        }
+        None => {
+            println!("{} backlight, brightness: {}%", name, backlight::brightness_percent()?);
+        }
+    }
+    Ok(())
+}
+
+/// Parses the optional STEP argument of `--increase` or `--decrease`.
+fn parse_step(step: Option<String>) -> Result<u8, String> {
+    match step {
+        Some(step) => step.trim_end_matches('%').parse::<u8>()
+            .ok()
+            .filter(|s| *s <= 100)
+            .ok_or_else(|| format!("invalid step {:?}, expected a percentage from 0 to 100", step)),
+        None => Ok(backlight::BRIGHTNESS_STEP_PERCENT),
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: brightness [PERCENT | -i [STEP] | -d [STEP]]
+Shows the brightness of the display backlight, or sets it to the given PERCENT.
+The brightness can also be changed with the Super+F11 and Super+F12 keyboard shortcuts.";
@@ -0,0 +1,84 @@
+//! This application lists the checkpoints of application tasks that were saved to disk,
+//! and restores or deletes them. See the `checkpoint` crate.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate checkpoint;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("r", "restore", "respawn the task that was saved in the checkpoint named NAME", "NAME");
+    opts.optopt("d", "delete", "delete the checkpoint named NAME", "NAME");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if let Some(name) = matches.opt_str("r") {
+        let task = checkpoint::restore(&name)?;
+        println!("Restored checkpoint {:?} as task {}.", name, task.lock().id);
+        return Ok(());
+    }
+    if let Some(name) = matches.opt_str("d") {
+        checkpoint::delete(&name)?;
+        println!("Deleted checkpoint {:?}.", name);
+        return Ok(());
+    }
+
+    let names = checkpoint::list()?;
+    if names.is_empty() {
+        println!("No checkpoints have been saved.");
+        return Ok(());
+    }
+    println!("{0:<24}  {1:<24}  {2:>12}", "NAME", "APPLICATION", "REGION BYTES");
+    for name in names {
+        match checkpoint::describe(&name) {
+            Ok((app_name, region_bytes)) => println!("{0:<24}  {1:<24}  {2:>12}", name, app_name, region_bytes),
+            Err(e) => println!("{0:<24}  <{1}>", name, e),
+        }
+    }
+    Ok(())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: ckpt [-r NAME | -d NAME]
+Lists the checkpoints that application tasks saved of themselves, which are stored in the key-value store.
+A restored task is spawned from the latest version of its application crate, with the checkpoint's arguments and environment.";
@@ -0,0 +1,101 @@
+//! This application lists, reads, and changes the configuration parameters ("tunables")
+//! that can be tuned at runtime. See the `config_registry` crate.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate config_registry;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use config_registry::Tunable;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("r", "reset", "set the tunable back to its default value");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let name = match matches.free.get(0) {
+        Some(name) => name,
+        None => {
+            for tunable in config_registry::tunables() {
+                print_tunable(tunable);
+            }
+            return Ok(());
+        }
+    };
+    let tunable = config_registry::get(name).ok_or_else(|| format!("no tunable named {:?}", name))?;
+
+    if matches.opt_present("r") {
+        config_registry::reset(name)?;
+    } else if let Some(value) = matches.free.get(1) {
+        let value = parse_value(value).ok_or_else(|| format!("invalid value {:?}", value))?;
+        config_registry::set(name, value).map_err(|e| {
+            let (min, max) = tunable.bounds();
+            format!("{} (bounds: {} ..= {})", e, min, max)
+        })?;
+    }
+    print_tunable(tunable);
+    Ok(())
+}
+
+
+/// Parses a decimal value, or a hexadecimal value that starts with "0x".
+fn parse_value(s: &str) -> Option<u64> {
+    if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn print_tunable(tunable: &Tunable) {
+    let (min, max) = tunable.bounds();
+    println!("{} = {}  (default: {}, bounds: {} ..= {})\n    {}",
+        tunable.name(), tunable.get(), tunable.default(), min, max, tunable.description()
+    );
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: config
+       config NAME [VALUE]
+       config -r NAME
+Lists all configuration parameters that can be tuned at runtime, or prints the parameter with the given name.
+If a value is given, the parameter is first set to that value, which must lie within the parameter's bounds.";
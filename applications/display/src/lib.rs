@@ -0,0 +1,137 @@
+//! This application lists the displays managed by the window manager,
+//! and lists or changes the display modes of the Bochs/QEMU display adapter.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate bochs_display;
+extern crate framebuffer;
+extern crate window_manager;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use bochs_display::{BochsDisplay, DisplayMode};
+use framebuffer::Framebuffer;
+use getopts::{Options, Matches};
+use window_manager::WINDOW_MANAGER;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("l", "list", "list the display modes supported by the display adapter");
+    opts.optopt("s", "set", "change the primary display to the given MODE, e.g., 1024x768", "MODE");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if let Some(mode) = matches.opt_str("s") {
+        let mode = parse_mode(&mode)?;
+        let adapter = get_adapter()?;
+        return set_primary_mode(adapter, &mode);
+    }
+
+    if matches.opt_present("l") {
+        let adapter = get_adapter()?;
+        let current = adapter.current_mode();
+        let (max_width, max_height) = adapter.max_resolution();
+        println!("Bochs display adapter (DISPI version {:#X}), {} KiB of video memory, up to {}x{}:",
+            adapter.version(), adapter.video_memory_size() / 1024, max_width, max_height,
+        );
+        for mode in adapter.modes() {
+            println!("    {}{}", mode, if Some(mode) == current { "  (current)" } else { "" });
+        }
+        return Ok(());
+    }
+
+    let wm = WINDOW_MANAGER.try().ok_or("the window manager was not yet initialized")?;
+    for (number, area) in wm.lock().displays().iter().enumerate() {
+        println!("display {}: {}x{} at ({}, {}){}",
+            number, area.width(), area.height(), area.top_left.x, area.top_left.y,
+            if number == 0 { "  (primary)" } else { "" },
+        );
+    }
+    Ok(())
+}
+
+
+/// Returns the Bochs display adapter, initializing it if needed.
+fn get_adapter() -> Result<&'static BochsDisplay, String> {
+    bochs_display::init()
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| String::from("no supported display adapter was found"))
+}
+
+/// Parses a display mode of the form `WIDTHxHEIGHT`, which always uses 32 bits per pixel.
+fn parse_mode(mode: &str) -> Result<DisplayMode, String> {
+    let invalid = || format!("invalid display mode {:?}, expected WIDTHxHEIGHT", mode);
+    let mut parts = mode.split('x');
+    let width  = parts.next().and_then(|w| w.parse::<usize>().ok()).ok_or_else(invalid)?;
+    let height = parts.next().and_then(|h| h.parse::<usize>().ok()).ok_or_else(invalid)?;
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(DisplayMode { width, height, bits_per_pixel: 32 })
+}
+
+/// Switches the adapter to the given `mode` and gives the window manager a new final framebuffer for it.
+fn set_primary_mode(adapter: &BochsDisplay, mode: &DisplayMode) -> Result<(), String> {
+    let wm = WINDOW_MANAGER.try().ok_or("the window manager was not yet initialized")?;
+    // Hold the window manager lock such that nothing is rendered while the mode changes.
+    let mut wm = wm.lock();
+    let old_mode = adapter.current_mode();
+    adapter.set_mode(mode)?;
+    let result = Framebuffer::new(mode.width, mode.height, Some(adapter.framebuffer_address()))
+        .and_then(|framebuffer| wm.set_primary_framebuffer(framebuffer));
+    match result {
+        Ok(_old_framebuffer) => {
+            println!("Changed the primary display mode to {}.", mode);
+            Ok(())
+        }
+        Err(e) => {
+            // Restore the previous mode, which the window manager's framebuffer still expects.
+            if let Some(old) = old_mode {
+                let _ = adapter.set_mode(&old);
+            }
+            Err(e.to_string())
+        }
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: display [-l | -s MODE]
+Lists the displays managed by the window manager.
+With -l, lists the display modes supported by the display adapter.
+With -s, changes the display mode of the primary display, e.g., `display -s 1024x768`.";
@@ -0,0 +1,134 @@
+//! This application lists, reads, writes, and deletes UEFI firmware variables,
+//! which are stored in NVRAM and persist across reboots. See the `uefi_runtime` crate.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate uefi_runtime;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use uefi_runtime::{
+    Guid, EFI_GLOBAL_VARIABLE, THESEUS_VARIABLE, VARIABLE_DEFAULT_ATTRIBUTES,
+    VARIABLE_NON_VOLATILE, VARIABLE_BOOTSERVICE_ACCESS, VARIABLE_RUNTIME_ACCESS,
+};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("l", "list", "list the names and vendors of all variables");
+    opts.optopt("g", "guid", "the vendor GUID of the variable, or \"global\" or \"theseus\" (default: theseus)", "GUID");
+    opts.optopt("s", "set", "set the variable to this value", "VALUE");
+    opts.optflag("x", "hex", "the value given to --set is a hexadecimal byte string, e.g., 0a0b0c");
+    opts.optflag("d", "delete", "delete the variable");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if !uefi_runtime::is_available() {
+        return Err("UEFI runtime services are unavailable, e.g., because Theseus wasn't booted from UEFI".into());
+    }
+
+    if matches.opt_present("l") || matches.free.is_empty() {
+        for (name, vendor) in uefi_runtime::variable_names()? {
+            println!("{}  {}", vendor, name);
+        }
+        return Ok(());
+    }
+
+    let vendor = match matches.opt_str("g").as_ref().map(|g| g.as_str()) {
+        None | Some("theseus") => THESEUS_VARIABLE,
+        Some("global") => EFI_GLOBAL_VARIABLE,
+        Some(g) => g.parse::<Guid>()?,
+    };
+    let name = &matches.free[0];
+
+    if matches.opt_present("d") {
+        uefi_runtime::delete_variable(name, &vendor)?;
+    } else if let Some(value) = matches.opt_str("s") {
+        let data = if matches.opt_present("x") {
+            parse_hex(&value).ok_or_else(|| format!("invalid hexadecimal byte string {:?}", value))?
+        } else {
+            value.into_bytes()
+        };
+        uefi_runtime::set_variable(name, &vendor, VARIABLE_DEFAULT_ATTRIBUTES, &data)?;
+    } else {
+        let (data, attributes) = uefi_runtime::get_variable(name, &vendor)?;
+        println!("{}-{}: {} bytes, attributes {:#X}{}{}{}", name, vendor, data.len(), attributes,
+            if attributes & VARIABLE_NON_VOLATILE != 0 { " NV" } else { "" },
+            if attributes & VARIABLE_BOOTSERVICE_ACCESS != 0 { " BS" } else { "" },
+            if attributes & VARIABLE_RUNTIME_ACCESS != 0 { " RT" } else { "" },
+        );
+        print_hex_dump(&data);
+    }
+    Ok(())
+}
+
+
+/// Parses a string of hexadecimal byte values, e.g., "0a0b0c".
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0 .. s.len()).step_by(2)
+        .map(|i| s.get(i .. i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Prints the given bytes in rows of 16, in hexadecimal and as ASCII characters.
+fn print_hex_dump(data: &[u8]) {
+    for (row, chunk) in data.chunks(16).enumerate() {
+        let mut line = format!("{:08x}  ", row * 16);
+        for byte in chunk {
+            line.push_str(&format!("{:02x} ", byte));
+        }
+        for _ in chunk.len() .. 16 {
+            line.push_str("   ");
+        }
+        line.push(' ');
+        line.extend(chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+        println!("{}", line);
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: efivar [-l]
+       efivar [-g GUID] NAME
+       efivar [-g GUID] -s VALUE [-x] NAME
+       efivar [-g GUID] -d NAME
+Lists all UEFI firmware variables, or prints, sets, or deletes the variable with the given name and vendor GUID.
+Variables that are set persist across reboots.";
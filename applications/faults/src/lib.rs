@@ -0,0 +1,76 @@
+//! This application prints how many page faults of each kind have occurred, both system-wide and for each task,
+//! e.g., to find out which workloads are fault-heavy. See the `fault_stats` module of the `memory` crate.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate memory;
+extern crate task;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::Options;
+use task::TASKLIST;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("a", "all", "also list tasks that haven't caused any page faults");
+    opts.optflag("r", "reset", "reset the system-wide counts to zero after printing them");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    println!("System-wide: {}", memory::fault_stats());
+    if matches.opt_present("r") {
+        memory::reset_fault_stats();
+    }
+
+    let mut tasks: Vec<_> = TASKLIST.lock().iter()
+        .map(|(id, taskref)| {
+            let task = taskref.lock();
+            (*id, task.name.clone(), task.page_faults)
+        })
+        .filter(|(_, _, faults)| matches.opt_present("a") || faults.total() > 0)
+        .collect();
+    // Show the most fault-heavy tasks first.
+    tasks.sort_by(|a, b| b.2.total().cmp(&a.2.total()));
+
+    println!("{0:<5}  {1:>10}  {2:>10}  {3:>10}  {4:>10}  {5:>10}  {6:>10}  {7}",
+        "ID", "TOTAL", "DEMAND", "COW", "SWAP-IN", "GUARD", "INVALID", "NAME"
+    );
+    for (id, name, faults) in tasks {
+        println!("{0:<5}  {1:>10}  {2:>10}  {3:>10}  {4:>10}  {5:>10}  {6:>10}  {7}",
+            id, faults.total(), faults.demand_zero, faults.copy_on_write, faults.swap_in, faults.guard_page, faults.invalid, name
+        );
+    }
+    0
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: faults [-a] [-r]
+Prints the number of page faults of each kind that have occurred system-wide and in each task:
+demand-zero (first access to a lazily-mapped page), copy-on-write, swap-in,
+guard page (stack overflow), and invalid (any other fault, which kills the task).";
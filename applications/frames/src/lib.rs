@@ -0,0 +1,112 @@
+//! This application enables or disables frame accounting,
+//! and shows how many frames of physical memory are attributed to each crate or task.
+//! It can also print the internal state of the system-wide frame allocator,
+//! for diagnosing frame leaks and fragmentation.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate memory;
+extern crate task;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use memory::FrameOwner;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("e", "enable", "enable frame accounting");
+    opts.optflag("d", "disable", "disable frame accounting and discard all frame counts");
+    opts.optopt("n", "top", "only show the NUM owners with the most frames", "NUM");
+    opts.optflag("s", "state", "print the frame allocator's internal state, e.g., its memory areas and free frames");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if matches.opt_present("s") {
+        let frame_allocator = memory::get_frame_allocator_ref().ok_or("the frame allocator was not yet initialized")?;
+        // render the state before printing it, such that the frame allocator isn't locked while printing
+        let state = frame_allocator.lock().dump_state();
+        print!("{}", state);
+        return Ok(());
+    }
+    if matches.opt_present("e") {
+        memory::enable_frame_accounting()?;
+        println!("Frame accounting is enabled. Frames allocated from now on will be attributed to their owner.");
+        return Ok(());
+    }
+    if matches.opt_present("d") {
+        memory::disable_frame_accounting();
+        println!("Frame accounting is disabled.");
+        return Ok(());
+    }
+
+    if !memory::frame_accounting_enabled() {
+        return Err("frame accounting is disabled, enable it with `frames -e`".to_string());
+    }
+    let limit = match matches.opt_str("n") {
+        Some(n) => n.parse::<usize>().map_err(|_e| format!("invalid number {:?}", n))?,
+        None => usize::max_value(),
+    };
+
+    let usage = memory::usage_by_owner();
+    let total: usize = usage.iter().map(|(_, count)| count).sum();
+    println!("{0:<8}  {1:<10}  {2}", "FRAMES", "SIZE (KiB)", "OWNER");
+    for (owner, count) in usage.iter().take(limit) {
+        println!("{0:<8}  {1:<10}  {2}", count, count * 4, owner_name(owner));
+    }
+    println!("{} frames ({} KiB) in total, {} free frames remaining.", total, total * 4, memory::free_frame_count());
+    Ok(())
+}
+
+/// Returns a description of the given frame `owner`, including the task's name if the owner is a task.
+fn owner_name(owner: &FrameOwner) -> String {
+    match owner {
+        FrameOwner::Task(id) => match task::get_task(*id) {
+            Some(taskref) => format!("task {} ({})", id, taskref.lock().name),
+            None => format!("task {} (exited)", id),
+        },
+        other => other.to_string(),
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: frames [-e | -d | -n NUM | -s]
+Shows how many frames are attributed to each crate or task, from the most to the fewest frames.
+Frame accounting must first be enabled with -e, after which newly-allocated frames are attributed to their owner.
+With -s, prints the frame allocator's internal state instead, including its occupancy and free frames.";
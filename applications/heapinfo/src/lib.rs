@@ -0,0 +1,67 @@
+//! This application prints statistics about the heap's usage, e.g., to find memory leaks,
+//! and starts or stops the heap's allocation profiler. See the `heap` crate.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate heap;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::Options;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("s", "start", "start the allocation profiler, discarding its previous results");
+    opts.optflag("t", "stop", "stop the allocation profiler, keeping its results");
+    opts.optflag("a", "arenas", "also print the free chunks of each heap arena");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    if matches.opt_present("s") {
+        heap::profiler::start();
+        println!("Started the allocation profiler.");
+        return 0;
+    }
+    if matches.opt_present("t") {
+        heap::profiler::stop();
+    }
+
+    println!("{}", heap::stats());
+    if matches.opt_present("a") {
+        for arena in heap::arena_stats() {
+            println!("{}", arena);
+        }
+    }
+    0
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: heapinfo [-s | -t] [-a]
+Prints the bytes and allocations in use on the heap, and how many allocations of each size are in use.
+While the allocation profiler is running, or after it has been stopped, also prints the bytes and allocations
+that each crate allocated since the profiler was started and hasn't deallocated yet, which points to the crates that leak.";
@@ -0,0 +1,122 @@
+//! This application starts the persistent key-value store on a storage device, and reads and changes its contents.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate storage_manager;
+extern crate kv_store;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use storage_manager::{StorageDeviceRef, STORAGE_CONTROLLERS};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("f", "format", "with `start`, erase the device and create an empty store on it");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let command = matches.free[0].as_str();
+    let args = &matches.free[1..];
+    let arg = |i: usize, name: &str| args.get(i).ok_or_else(|| format!("missing {} argument", name));
+
+    match command {
+        "start" => {
+            let device_name = arg(0, "DEVICE")?;
+            let device = find_device(device_name)?;
+            kv_store::start(device, matches.opt_present("f")).map_err(|e| e.to_string())?;
+            println!("Started key-value store on {}", device_name);
+        }
+        "get" => {
+            let key = arg(0, "KEY")?;
+            match kv_store::get(key)? {
+                Some(value) => println!("{}", String::from_utf8_lossy(&value)),
+                None => return Err(format!("key {:?} doesn't exist", key)),
+            }
+        }
+        "set" => {
+            let value = args.get(1..).filter(|v| !v.is_empty()).ok_or_else(|| format!("missing VALUE argument"))?;
+            kv_store::put(arg(0, "KEY")?, value.join(" ").as_bytes())?;
+        }
+        "rm" => {
+            let mut transaction = kv_store::Transaction::new();
+            for key in args {
+                transaction.delete(key);
+            }
+            if transaction.is_empty() {
+                return Err(format!("missing KEY argument"));
+            }
+            transaction.commit()?;
+        }
+        "ls" => {
+            let prefix = args.get(0).map(|p| p.as_str()).unwrap_or("");
+            for key in kv_store::keys(prefix)? {
+                println!("{}", key);
+            }
+        }
+        _ => return Err(format!("unknown command {:?}", command)),
+    }
+    Ok(())
+}
+
+
+/// Finds the storage device named `cXdY`, i.e., device `Y` of storage controller `X`.
+fn find_device(name: &str) -> Result<StorageDeviceRef, String> {
+    let invalid = || format!("invalid device {:?}, expected a name like \"c0d1\"", name);
+    if !name.starts_with('c') {
+        return Err(invalid());
+    }
+    let mut parts = name[1..].splitn(2, 'd');
+    let controller_index: usize = parts.next().and_then(|c| c.parse().ok()).ok_or_else(invalid)?;
+    let device_index: usize = parts.next().and_then(|d| d.parse().ok()).ok_or_else(invalid)?;
+
+    let controllers = STORAGE_CONTROLLERS.lock();
+    let controller = controllers.get(controller_index).ok_or_else(|| format!("storage controller {} doesn't exist", controller_index))?;
+    let device = controller.lock().devices().nth(device_index);
+    device.ok_or_else(|| format!("storage device {} doesn't exist", name))
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: kv [-f] start DEVICE
+       kv get KEY
+       kv set KEY VALUE
+       kv rm KEY...
+       kv ls [PREFIX]
+Starts the persistent key-value store on a storage device, e.g., `c0d1`, or reads and changes its contents.
+The `start` command uses the entire device, and with `-f`, erases it and creates an empty store on it.";
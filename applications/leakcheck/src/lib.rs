@@ -0,0 +1,115 @@
+//! This application runs another application repeatedly, checking that each run returns all of the
+//! frames, heap memory, and mappings that it took, e.g., to find leaks in the code that an application
+//! exercises or in the loading and unloading of its crate. See the `memory_diff` crate.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate task;
+extern crate spawn;
+extern crate path;
+extern crate mod_mgmt;
+extern crate memory_diff;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use getopts::{Matches, Options, ParsingStyle};
+use path::Path;
+use mod_mgmt::crate_name_from_path;
+use task::ExitValue;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.parsing_style(ParsingStyle::StopAtFirstFree);
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("w", "warmup", "the number of runs before memory usage is checked (default: 1)", "RUNS");
+    opts.optopt("n", "iterations", "the number of runs whose memory usage is checked (default: 5)", "RUNS");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(true) => 0,
+        Ok(false) => -1,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+/// Returns whether the application ran without leaking memory.
+fn rmain(matches: Matches) -> Result<bool, String> {
+    let warmup = match matches.opt_str("w") {
+        Some(w) => w.parse().map_err(|_| format!("invalid number of warm-up runs {:?}", w))?,
+        None => 1,
+    };
+    let iterations = match matches.opt_str("n") {
+        Some(n) => n.parse().map_err(|_| format!("invalid number of iterations {:?}", n))?,
+        None => 5,
+    };
+    let app_name = &matches.free[0];
+    let app_args: Vec<String> = matches.free[1..].to_vec();
+
+    let current_task = task::get_my_current_task().ok_or("couldn't get the current task")?;
+    let namespace = current_task.get_namespace();
+    let env = current_task.get_env();
+    let mut matching_apps = namespace.dir().get_files_starting_with(&format!("{}-", app_name)).into_iter();
+    let app_file = matching_apps.next();
+    let second_match = matching_apps.next();
+    let app_path = app_file.xor(second_match)
+        .map(|f| Path::new(f.lock().get_absolute_path()))
+        .ok_or_else(|| format!("couldn't find a single application named {:?}", app_name))?;
+    let crate_name = crate_name_from_path(&app_path).to_string();
+
+    let run = || -> Result<(), &'static str> {
+        // The application's crate must be fully unloaded from the namespace before it can be loaded again.
+        while namespace.get_crate(&crate_name).is_some() { }
+
+        let child = spawn::new_application_task_builder(app_path.clone(), None)?
+            .argument(app_args.clone())
+            .env(env.clone())
+            .spawn()?;
+        child.join()?;
+        match child.take_exit_value() {
+            Some(ExitValue::Completed(exit_value)) => match exit_value.downcast_ref::<isize>() {
+                Some(0) => Ok(()),
+                _ => Err("the application returned an error"),
+            },
+            Some(ExitValue::Killed(_)) => Err("the application was killed"),
+            None => Err("couldn't get the application's exit value"),
+        }
+    };
+
+    let report = memory_diff::check(warmup, iterations, run)?;
+    println!("{}", report);
+    Ok(!report.leaks())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: leakcheck [-w RUNS] [-n RUNS] APPLICATION [ARGUMENTS]...
+Runs the given application with the given arguments several times, and reports whether each run left
+any frames, heap memory, or mappings behind. The application must return 0 from every run.";
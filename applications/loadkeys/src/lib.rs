@@ -0,0 +1,80 @@
+//! This application changes the keyboard layout at runtime, or lists the available keyboard layouts.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate keycodes_ascii;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use keycodes_ascii::{KeyboardLayout, KEYBOARD_LAYOUTS};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("l", "list", "list the available keyboard layouts");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let current = keycodes_ascii::keyboard_layout();
+    if matches.opt_present("l") {
+        for layout in KEYBOARD_LAYOUTS.iter() {
+            let marker = if *layout == current { "*" } else { " " };
+            println!("{} {:<8} {}", marker, layout.name(), layout.description());
+        }
+        return Ok(());
+    }
+
+    match matches.free.first() {
+        Some(name) => {
+            let layout = KeyboardLayout::from_name(name)
+                .ok_or_else(|| format!("unknown keyboard layout {:?}, use `loadkeys -l` to list the available layouts", name))?;
+            keycodes_ascii::set_keyboard_layout(layout);
+            println!("Changed the keyboard layout to {} ({}).", layout.name(), layout.description());
+        }
+        None => {
+            println!("The current keyboard layout is {} ({}).", current.name(), current.description());
+        }
+    }
+    Ok(())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: loadkeys [-l | LAYOUT]
+Changes the keyboard layout to LAYOUT, e.g., `us`, `de`, or `dvorak`.
+Without any arguments, shows the current keyboard layout.";
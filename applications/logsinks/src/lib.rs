@@ -0,0 +1,115 @@
+//! This application lists the log sinks that are attached to the system logger,
+//! and attaches, detaches, or sets the level filter of a sink at runtime. See the `logger` crate.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate log;
+extern crate logger;
+extern crate log_file;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use log::LevelFilter;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("s", "set", "set the level filter of the sink named NAME to LEVEL", "NAME=LEVEL");
+    opts.optopt("d", "detach", "detach the sink named NAME", "NAME");
+    opts.optopt("f", "file", "attach the file sink, which writes the log to the file at PATH", "PATH");
+    opts.optflag("", "serial", "re-attach the serial port sink");
+    opts.optopt("l", "level", "the level filter of a newly-attached sink (default: info)", "LEVEL");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let level = match matches.opt_str("l") {
+        Some(l) => parse_level(&l)?,
+        None => LevelFilter::Info,
+    };
+
+    if let Some(setting) = matches.opt_str("s") {
+        let mut parts = setting.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let level = parse_level(parts.next().ok_or_else(|| format!("expected NAME=LEVEL, got {:?}", setting))?)?;
+        logger::set_sink_level(name, level)?;
+    }
+    else if let Some(name) = matches.opt_str("d") {
+        // The file sink has buffered lines that must be written out before it is detached.
+        if name == "file" {
+            log_file::detach()?;
+        } else if !logger::detach_sink(&name) {
+            return Err(format!("no sink named {:?} is attached", name));
+        }
+    }
+    else if let Some(path) = matches.opt_str("f") {
+        log_file::attach(&path, level)?;
+    }
+    else if matches.opt_present("serial") {
+        logger::attach_sink("serial", &logger::SERIAL_SINK, level)?;
+    }
+
+    print_sinks();
+    Ok(())
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level.parse::<LevelFilter>().map_err(|_e| format!("invalid level {:?}, expected off, error, warn, info, debug, or trace", level))
+}
+
+fn print_sinks() {
+    let sinks = logger::sinks();
+    if sinks.is_empty() {
+        println!("No log sinks are attached.");
+        return;
+    }
+    println!("{0:<16}  {1}", "SINK", "LEVEL");
+    for (name, level) in sinks {
+        if name == "file" {
+            println!("{0:<16}  {1:<8} {2}", name, level, log_file::path().unwrap_or_default());
+        } else {
+            println!("{0:<16}  {1}", name, level);
+        }
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: logsinks [-s NAME=LEVEL | -d NAME | -f PATH [-l LEVEL] | --serial [-l LEVEL]]
+Lists every log sink and its level filter, after applying the given change, if any.
+The built-in sinks are \"serial\", \"vga\", \"log_stream\", and \"file\".
+For example, `logsinks -f /var/log/kernel.log -l debug` starts writing debug logs and above to that file.";
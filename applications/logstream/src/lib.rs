@@ -0,0 +1,75 @@
+//! This application starts the log streaming server, which streams log records over TCP
+//! to remote clients that subscribe to them, see the `log_stream` crate.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate log_stream;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("p", "port", "listen on the given TCP PORT instead of the default port", "PORT");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if let Some(port) = log_stream::server_port() {
+        println!("The log streaming server is already running on TCP port {}.", port);
+        return Ok(());
+    }
+
+    let port = match matches.opt_str("p") {
+        Some(p) => p.parse::<u16>().map_err(|_e| format!("invalid port {:?}", p))?,
+        None => log_stream::DEFAULT_PORT,
+    };
+    log_stream::start(port)?;
+    println!("Streaming log records on TCP port {}.", port);
+    println!("Clients subscribe by sending a line such as \"level=info crates=memory,task\", or an empty line for all records.");
+    Ok(())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: logstream [-p PORT]
+Starts the server that streams log records over TCP to remote clients, e.g., `nc <THESEUS_IP> 5555`.
+Each client sends a subscription line of space-separated options:
+    level=LEVEL                only stream records of the given LEVEL or more severe (default: trace)
+    crates=CRATE[,CRATE...]    only stream records logged by the given crates
+    backlog                    also stream the buffered records that were logged before subscribing";
@@ -0,0 +1,97 @@
+//! This application creates and lists loop devices, which present regular files as storage devices.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate task;
+extern crate path;
+extern crate fs_node;
+extern crate storage_device;
+extern crate loop_device;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use path::Path;
+use fs_node::FileOrDir;
+use storage_device::StorageDevice;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("l", "list", "list all existing loop devices");
+    opts.optflag("r", "read-only", "create a read-only loop device");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if matches.opt_present("l") {
+        let controller = loop_device::loop_device_controller().lock();
+        let mut count = 0;
+        for (i, dev) in controller.iter().enumerate() {
+            let dev = dev.lock();
+            println!("loop{}: {} ({} sectors{})", 
+                i, 
+                dev.backing_file().lock().get_absolute_path(), 
+                dev.size_in_sectors(), 
+                if dev.is_read_only() { ", read-only" } else { "" },
+            );
+            count += 1;
+        }
+        if count == 0 {
+            println!("No loop devices exist.");
+        }
+        return Ok(());
+    }
+
+    let path_arg = matches.free.get(0).ok_or_else(|| format!("missing FILE argument"))?;
+    let env = task::get_my_current_task()
+        .ok_or_else(|| format!("failed to get current task"))?
+        .get_env();
+    let file = match env.lock().resolve_path(&Path::new(path_arg.to_string())) {
+        Some(FileOrDir::File(f)) => f,
+        Some(FileOrDir::Dir(_)) => return Err(format!("{:?} is a directory, not a file", path_arg)),
+        None => return Err(format!("couldn't find file {:?}", path_arg)),
+    };
+    let loop_dev = loop_device::create_loop_device(file, matches.opt_present("r")).map_err(|e| e.to_string())?;
+    println!("Created loop device with {} sectors", loop_dev.lock().size_in_sectors());
+    Ok(())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: losetup [-l] [-r] [FILE]
+Creates a new loop device that presents the given FILE as a storage device,
+e.g., to use a filesystem image file as if it were a disk.";
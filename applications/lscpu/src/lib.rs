@@ -0,0 +1,122 @@
+//! This application prints the CPU topology, similar to `lscpu` on Linux:
+//! how many packages, cores, and SMT threads there are, which caches they have, and which CPUs share each cache.
+//! See the `cpu_topology` crate.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate cpu_topology;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::Options;
+use cpu_topology::{CacheInfo, CacheKind, CoreType, CpuInfo};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("e", "extended", "print one line per CPU, including which caches it shares with other CPUs");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    let cpus = cpu_topology::cpus();
+    if cpus.is_empty() {
+        println!("No CPUs have registered their topology yet.");
+        return -1;
+    }
+
+    if matches.opt_present("e") {
+        print_extended(&cpus);
+    } else {
+        print_summary(&cpus);
+    }
+    0
+}
+
+fn print_summary(cpus: &[&CpuInfo]) {
+    let mut cores: Vec<(u32, u32)> = cpus.iter().map(|c| (c.package_id, c.core_id)).collect();
+    cores.dedup();
+    let packages = cpu_topology::package_count();
+
+    println!("{:<24}{}", "CPU(s):", cpus.len());
+    println!("{:<24}{}", "Thread(s) per core:", cpus.len() / cores.len());
+    println!("{:<24}{}", "Core(s) per socket:", cores.len() / packages);
+    println!("{:<24}{}", "Socket(s):", packages);
+    if cpu_topology::is_hybrid() {
+        println!("{:<24}{}", "Performance cores:", count_cores(cpus, CoreType::Performance));
+        println!("{:<24}{}", "Efficiency cores:", count_cores(cpus, CoreType::Efficiency));
+    }
+
+    // Each cache is described once, as seen by the first CPU, along with how many instances of it there are.
+    for cache in &cpus[0].caches {
+        let mut instances: Vec<u32> = cpus.iter()
+            .flat_map(|c| c.caches.iter().filter(|o| o.level == cache.level && o.kind == cache.kind).map(|o| o.sharing_id))
+            .collect();
+        instances.sort_unstable();
+        instances.dedup();
+        println!("{:<24}{} KiB, {}-way, {}-byte lines, shared by up to {} CPUs ({} instances)",
+            format!("{} cache:", cache_name(cache)),
+            cache.size_in_bytes / 1024, cache.ways, cache.line_size, cache.shared_by, instances.len()
+        );
+    }
+}
+
+fn print_extended(cpus: &[&CpuInfo]) {
+    println!("{0:<6}  {1:>6}  {2:>6}  {3:>6}  {4:<12}  {5}", "CPU", "SOCKET", "CORE", "THREAD", "TYPE", "CACHES");
+    for cpu in cpus {
+        let caches: Vec<String> = cpu.caches.iter()
+            .map(|c| format!("{}:{}", cache_name(c), c.sharing_id))
+            .collect();
+        println!("{0:<6}  {1:>6}  {2:>6}  {3:>6}  {4:<12}  {5}",
+            cpu.apic_id, cpu.package_id, cpu.core_id, cpu.smt_id, format!("{:?}", cpu.core_type), caches.join(" ")
+        );
+    }
+}
+
+/// Returns the number of physical cores of the given type.
+fn count_cores(cpus: &[&CpuInfo], core_type: CoreType) -> usize {
+    let mut cores: Vec<(u32, u32)> = cpus.iter()
+        .filter(|c| c.core_type == core_type)
+        .map(|c| (c.package_id, c.core_id))
+        .collect();
+    cores.dedup();
+    cores.len()
+}
+
+/// Returns the conventional name of the given cache, e.g., "L1d".
+fn cache_name(cache: &CacheInfo) -> String {
+    let suffix = match cache.kind {
+        CacheKind::Data => "d",
+        CacheKind::Instruction => "i",
+        CacheKind::Unified => "",
+    };
+    format!("L{}{}", cache.level, suffix)
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: lscpu [-e]
+Prints how many sockets, cores, and SMT threads there are, and the caches of each CPU.
+With -e, prints one line per CPU; CPUs that list the same ID for a cache share that cache.";
@@ -0,0 +1,92 @@
+//! This application creates and lists RAM-backed storage devices (ramdisks).
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate storage_device;
+extern crate ramdisk;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use storage_device::StorageDevice;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("l", "list", "list all existing ramdisks");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if matches.opt_present("l") {
+        let controller = ramdisk::ramdisk_controller().lock();
+        let mut count = 0;
+        for (i, disk) in controller.iter().enumerate() {
+            let disk = disk.lock();
+            println!("ramdisk {}: {} bytes ({} sectors)", i, disk.size_in_bytes(), disk.size_in_sectors());
+            count += 1;
+        }
+        if count == 0 {
+            println!("No ramdisks exist.");
+        }
+        return Ok(());
+    }
+
+    let size_arg = matches.free.get(0).ok_or_else(|| format!("missing SIZE argument"))?;
+    let size_in_bytes = parse_size(size_arg).ok_or_else(|| format!("invalid SIZE {:?}", size_arg))?;
+    let disk = ramdisk::create_ramdisk(size_in_bytes).map_err(|e| String::from(e))?;
+    println!("Created ramdisk with {} bytes ({} sectors)", disk.lock().size_in_bytes(), disk.lock().size_in_sectors());
+    Ok(())
+}
+
+
+/// Parses a size in bytes with an optional `K`, `M`, or `G` suffix, e.g., `64M`.
+fn parse_size(arg: &str) -> Option<usize> {
+    let (digits, multiplier) = match arg.chars().last()? {
+        'K' | 'k' => (&arg[.. arg.len() - 1], 1024),
+        'M' | 'm' => (&arg[.. arg.len() - 1], 1024 * 1024),
+        'G' | 'g' => (&arg[.. arg.len() - 1], 1024 * 1024 * 1024),
+        _ => (arg, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: mkramdisk [-l] [SIZE]
+Creates a new RAM-backed storage device of the given SIZE in bytes, 
+which may have a K, M, or G suffix, e.g., \"mkramdisk 16M\".
+The new ramdisk is registered as a storage device and can be used by filesystems.";
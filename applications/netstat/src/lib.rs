@@ -0,0 +1,111 @@
+//! This application lists the open sockets and listeners of all tasks,
+//! and shows the packet counters of each network protocol.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate network_manager;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use network_manager::stats::{self, Protocol, SocketInfo};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("a", "all", "list both listening and non-listening sockets");
+    opts.optflag("l", "listening", "list only listening sockets");
+    opts.optflag("s", "statistics", "show the counters of each protocol instead of sockets");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    rmain(matches);
+    0
+}
+
+
+fn rmain(matches: Matches) {
+    if matches.opt_present("s") {
+        print_statistics();
+        return;
+    }
+
+    let all = matches.opt_present("a");
+    let listening_only = matches.opt_present("l");
+    let mut sockets: Vec<SocketInfo> = stats::sockets().into_iter()
+        .filter(|s| s.is_open)
+        .filter(|s| if listening_only { s.is_listener() } else { all || !s.is_listener() })
+        .collect();
+    sockets.sort_by_key(|s| (protocol_name(s.protocol), s.local_endpoint.port));
+
+    println!("{:<6} {:<24} {:<24} {:<12} {:>6}", "PROTO", "LOCAL ADDRESS", "REMOTE ADDRESS", "STATE", "TASK");
+    for socket in sockets {
+        let remote = socket.remote_endpoint.map_or(String::from("*"), |e| format!("{}", e));
+        let state = match socket.tcp_state {
+            Some(state) => format!("{}", state),
+            None => String::new(),
+        };
+        let task = socket.task_id.map_or(String::from("-"), |id| format!("{}", id));
+        println!("{:<6} {:<24} {:<24} {:<12} {:>6}",
+            protocol_name(socket.protocol),
+            format!("{}", socket.local_endpoint),
+            remote,
+            state,
+            task,
+        );
+    }
+}
+
+fn print_statistics() {
+    println!("{:<6} {:>12} {:>12} {:>12} {:>10} {:>16}", "PROTO", "IN", "OUT", "RETRANSMITS", "DROPS", "CHECKSUM ERRORS");
+    for &protocol in Protocol::ALL.iter() {
+        let s = stats::protocol_stats(protocol);
+        println!("{:<6} {:>12} {:>12} {:>12} {:>10} {:>16}",
+            protocol_name(protocol),
+            s.packets_in,
+            s.packets_out,
+            s.retransmits,
+            s.drops,
+            s.checksum_errors,
+        );
+    }
+}
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+        Protocol::Icmp => "icmp",
+        Protocol::Other => "other",
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: netstat [-a | -l]
+       netstat -s
+Lists the open sockets of all tasks, or shows the counters of each network protocol since boot.
+Sockets appear once their task has polled them, and disappear if it hasn't done so for a few seconds.";
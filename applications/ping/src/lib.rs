@@ -30,7 +30,7 @@ use smoltcp::{
     wire::{IpAddress, Icmpv4Repr, Icmpv4Packet},
     phy::{ChecksumCapabilities},
 };
-use network_manager::{NetworkInterfaceRef, NETWORK_INTERFACES};
+use network_manager::{routing, NetworkInterfaceRef, NETWORK_INTERFACES};
 use byteorder::{ByteOrder, NetworkEndian};
 use smoltcp_helper::{millis_since, poll_iface};
 
@@ -140,13 +140,12 @@ pub fn rmain(matches: &Matches, _opts: Options, address: IpAddress) -> Result<()
     }
 }
 
-/// Used to gain access to the ethernet interface
-fn get_default_iface() -> Result<NetworkInterfaceRef, String> {
-    NETWORK_INTERFACES.lock()
-        .iter()
-        .next()
-        .cloned()
-        .ok_or_else(|| format!("no network interfaces available"))
+/// Used to gain access to the ethernet interface that the routing table chooses for the given `address`
+fn get_iface_for(address: IpAddress) -> Result<NetworkInterfaceRef, String> {
+    if NETWORK_INTERFACES.lock().is_empty() {
+        return Err(format!("no network interfaces available"));
+    }
+    routing::interface_for(address).ok_or_else(|| format!("no route to {}", address))
 }
 
 // Retrieves the echo reply contained in the receive buffer and prints data pertaining to the packet
@@ -184,8 +183,8 @@ fn ping(address: IpAddress, count: usize, interval: u64, timeout: u64, verbose:
     let icmp_tx_buffer = IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY], vec![0; 256]);
     let icmp_socket = IcmpSocket::new(icmp_rx_buffer, icmp_tx_buffer);
     
-    // Get the default ethernet interface to ping with
-    let iface_result = get_default_iface();
+    // Get the ethernet interface to ping with
+    let iface_result = get_iface_for(address);
     let iface = match iface_result {
         Ok(network) => network,
         Err(err) => return println!("couldn't initialize the network: {}", err),
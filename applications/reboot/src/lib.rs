@@ -0,0 +1,55 @@
+//! This application quiesces all registered subsystems and then reboots the machine.
+//! It is equivalent to `shutdown -r`; see the `shutdown_manager` crate.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate shutdown_manager;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::Options;
+use shutdown_manager::ShutdownKind;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    println!("Rebooting ...");
+    match shutdown_manager::shutdown(ShutdownKind::Reboot) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: reboot
+Quiesces all subsystems in reverse dependency order, and then reboots the machine.";
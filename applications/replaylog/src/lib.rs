@@ -0,0 +1,69 @@
+//! This application prints the digest of the deterministic replay log, and optionally its most recent events,
+//! which must be identical at the same point of a recorded and a replayed execution. See the `replay_log` crate.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate replay_log;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::Options;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("n", "events", "also print the most recent COUNT events", "COUNT");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    if !replay_log::is_enabled() {
+        println!("The replay log is disabled. Build Theseus with `make record` to enable it.");
+        return -1;
+    }
+
+    let count = match matches.opt_str("n").map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            println!("Error: invalid number of events");
+            return -1;
+        }
+        None => 0,
+    };
+    let (logged, digest) = replay_log::digest();
+    let events = replay_log::events();
+    for event in events.iter().skip(events.len().saturating_sub(count)) {
+        println!("{}", event);
+    }
+    println!("{} events logged, digest {:#018X}", logged, digest);
+    0
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: replaylog [-n COUNT]
+Prints the number of events in the deterministic replay log and their digest.
+Run this at the same point of a recorded and a replayed execution: if the digests differ, the replay diverged,
+and the first differing event shows where.";
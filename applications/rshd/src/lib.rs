@@ -0,0 +1,84 @@
+//! This application starts the remote shell server, which lets remote clients
+//! run commands over TCP through a WireGuard tunnel, see the `remote_shell` crate.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate remote_shell;
+extern crate wireguard;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("p", "port", "listen on the given TCP PORT instead of the default port", "PORT");
+    opts.optopt("k", "key", "require clients to send the given KEY before running commands", "KEY");
+    opts.optopt("t", "tunnel", "serve on the tunnel with the given INDEX, as listed by `wg show` (default: 0)", "INDEX");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if let Some(port) = remote_shell::server_port() {
+        println!("The remote shell server is already running on TCP port {}.", port);
+        return Ok(());
+    }
+
+    let key = matches.opt_str("k").ok_or("a key must be given with -k KEY")?;
+    let port = match matches.opt_str("p") {
+        Some(p) => p.parse::<u16>().map_err(|_e| format!("invalid port {:?}", p))?,
+        None => remote_shell::DEFAULT_PORT,
+    };
+    let tunnel_index = match matches.opt_str("t") {
+        Some(t) => t.parse::<usize>().map_err(|_e| format!("invalid tunnel index {:?}", t))?,
+        None => 0,
+    };
+    let tunnel_iface = wireguard::tunnel_interfaces().into_iter().nth(tunnel_index)
+        .ok_or_else(|| format!("there is no tunnel {}; create one with `wg up` first", tunnel_index))?;
+    remote_shell::start(tunnel_iface, port, key)?;
+    println!("Remote shell server listening on TCP port {} of tunnel {}.", port, tunnel_index);
+    Ok(())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: rshd -k KEY [-p PORT] [-t INDEX]
+Starts the remote shell server, which lets the peer of a WireGuard tunnel run commands over TCP through that tunnel,
+e.g., `nc <THESEUS_TUNNEL_IP> 2222`. Connections that don't arrive through the tunnel are never accepted.
+Clients must first send the KEY, after which each line they send is run as a command line.
+While a command runs, Ctrl+C kills it. The `exit` command ends the session,
+and `stty echo` makes the server echo typed characters for clients that don't do so themselves.";
@@ -0,0 +1,509 @@
+//! A benchmark suite for the scheduler, which measures:
+//! * wakeup latency: the time from when a blocked task is unblocked until it actually runs,
+//! * context switch cost: the time to switch between two tasks on the same core that yield to each other,
+//! * IPI round-trip time: the time to send an IPI to all other cores until they have all handled it.
+//!
+//! Each test can be run under load, i.e., while a number of CPU-bound tasks run in the background.
+//! The results are printed as CSV, one line per test, such that they can be saved into a file
+//! and later used as a baseline to find regressions caused by changes to the scheduler.
+//!
+//! # Examples
+//! * `schedbench -o base.csv`: runs all tests and saves the results into `base.csv`.
+//! * `schedbench -l 4 -b base.csv`: runs all tests with 4 load tasks and compares the results against `base.csv`.
+//!
+//! Only results with the same test name and load are compared.
+//! If any median is more than the threshold percentage above its baseline, the exit value is `1`.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+extern crate getopts;
+extern crate task;
+extern crate spawn;
+extern crate scheduler;
+extern crate apic;
+extern crate hpet;
+extern crate libtest;
+extern crate memory;
+extern crate tlb_shootdown;
+extern crate path;
+extern crate fs_node;
+
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use getopts::{Matches, Options};
+use hpet::get_hpet;
+use libtest::{calculate_stats, hpet_2_ns};
+use memory::PageRange;
+use task::{ExitValue, TaskRef};
+use path::Path;
+use fs_node::FileOrDir;
+
+
+/// The number of context switch round trips that are timed together as one sample,
+/// since a single one is too short to be timed accurately with the HPET.
+const SWITCHES_PER_SAMPLE: u64 = 100;
+
+/// The default number of samples taken by each test.
+const DEFAULT_ITERATIONS: usize = 1000;
+
+/// The default percentage by which the median of a test may exceed its baseline before it is reported as a regression.
+const DEFAULT_THRESHOLD_PERCENT: u64 = 10;
+
+/// The first line of the CSV results.
+const CSV_HEADER: &'static str = "test,load,samples,min_ns,p25_ns,median_ns,p75_ns,max_ns,mean_ns,std_dev_ns";
+
+/// Tells the background load tasks to exit.
+static STOP_LOAD: AtomicBool = AtomicBool::new(false);
+/// Tells the partner task of the context switch test to exit.
+static STOP_SWITCH_PARTNER: AtomicBool = AtomicBool::new(false);
+/// The HPET counter value at which the sleeper task of the wakeup test was unblocked.
+static WAKEUP_START: AtomicU64 = AtomicU64::new(0);
+/// The HPET counter value at which the sleeper task of the wakeup test ran after being unblocked,
+/// or `0` if it hasn't run yet.
+static WAKEUP_END: AtomicU64 = AtomicU64::new(0);
+
+
+/// The tests that `schedbench` can run.
+#[derive(Clone, Copy, PartialEq)]
+enum Test {
+    Wakeup,
+    Switch,
+    Ipi,
+}
+
+const ALL_TESTS: [Test; 3] = [Test::Wakeup, Test::Switch, Test::Ipi];
+
+impl Test {
+    fn name(&self) -> &'static str {
+        match self {
+            Test::Wakeup => "wakeup",
+            Test::Switch => "switch",
+            Test::Ipi    => "ipi",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Test> {
+        ALL_TESTS.iter().find(|t| t.name() == name).cloned()
+    }
+}
+
+
+/// The statistics of the samples (in nanoseconds) taken by one test.
+struct TestResult {
+    test: String,
+    load: usize,
+    samples: usize,
+    min: u64,
+    p_25: u64,
+    median: u64,
+    p_75: u64,
+    max: u64,
+    mean: f64,
+    std_dev: f64,
+}
+
+impl TestResult {
+    fn from_samples(test: Test, load: usize, samples: &Vec<u64>) -> Option<TestResult> {
+        let stats = calculate_stats(samples)?;
+        Some(TestResult {
+            test: test.name().to_string(),
+            load,
+            samples: samples.len(),
+            min: stats.min,
+            p_25: stats.p_25,
+            median: stats.median,
+            p_75: stats.p_75,
+            max: stats.max,
+            mean: stats.mean,
+            std_dev: stats.std_dev,
+        })
+    }
+
+    fn to_csv(&self) -> String {
+        format!("{},{},{},{},{},{},{},{},{:.1},{:.1}",
+            self.test, self.load, self.samples, self.min, self.p_25, self.median, self.p_75, self.max, self.mean, self.std_dev
+        )
+    }
+
+    fn from_csv(line: &str) -> Option<TestResult> {
+        let mut fields = line.trim().split(',');
+        let result = TestResult {
+            test:    fields.next()?.to_string(),
+            load:    fields.next()?.parse().ok()?,
+            samples: fields.next()?.parse().ok()?,
+            min:     fields.next()?.parse().ok()?,
+            p_25:    fields.next()?.parse().ok()?,
+            median:  fields.next()?.parse().ok()?,
+            p_75:    fields.next()?.parse().ok()?,
+            max:     fields.next()?.parse().ok()?,
+            mean:    fields.next()?.parse().ok()?,
+            std_dev: fields.next()?.parse().ok()?,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(result)
+    }
+}
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("n", "iterations", "the number of samples taken by each test (default 1000)", "N");
+    opts.optopt("l", "load", "the number of CPU-bound tasks that run in the background (default 0)", "N");
+    opts.optopt("c", "core", "the core on which the woken-up and context-switching tasks run (default: a free core)", "CORE");
+    opts.optmulti("t", "test", "a test to run: wakeup, switch, or ipi (default: all tests)", "TEST");
+    opts.optopt("o", "output", "save the results as CSV into FILE in the current directory", "FILE");
+    opts.optopt("b", "baseline", "compare the results against the CSV results in FILE", "FILE");
+    opts.optopt("", "threshold", "the percentage by which a median may exceed its baseline before it is a regression (default 10)", "PCT");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(&matches) {
+        Ok(0) => 0,
+        Ok(regressions) => {
+            println!("Found {} regression(s) compared to the baseline.", regressions);
+            1
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+/// Runs the requested tests and returns the number of regressions compared to the baseline, if any.
+fn rmain(matches: &Matches) -> Result<usize, String> {
+    let iterations = parse_opt(matches, "n", DEFAULT_ITERATIONS)?;
+    let load = parse_opt(matches, "l", 0)?;
+    let threshold = parse_opt(matches, "threshold", DEFAULT_THRESHOLD_PERCENT)?;
+    if iterations == 0 {
+        return Err(format!("the number of iterations must be nonzero"));
+    }
+    let tests = if matches.opt_present("t") {
+        matches.opt_strs("t").iter()
+            .map(|name| Test::from_name(name).ok_or_else(|| format!("unknown test {:?}", name)))
+            .collect::<Result<Vec<Test>, String>>()?
+    } else {
+        ALL_TESTS.to_vec()
+    };
+    let core = match matches.opt_str("c") {
+        Some(c) => c.parse::<u8>().map_err(|_e| format!("invalid core {:?}", c))?,
+        None => libtest::pick_free_core().unwrap_or_else(|_e| apic::get_my_apic_id()),
+    };
+    if core == apic::get_my_apic_id() {
+        println!("Warning: running on the same core as schedbench itself, which adds to the measured latencies.");
+    }
+    get_hpet().ok_or("couldn't get HPET timer")?;
+
+    // Read the baseline first, such that a missing or invalid baseline is reported before running any tests.
+    let baseline = match matches.opt_str("b") {
+        Some(file_name) => Some(read_results(&file_name)?),
+        None => None,
+    };
+
+    STOP_LOAD.store(false, Ordering::SeqCst);
+    let mut load_tasks = Vec::with_capacity(load);
+    for i in 0..load {
+        let taskref = spawn::new_task_builder(load_task, ())
+            .name(format!("schedbench_load_{}", i))
+            .spawn();
+        match taskref {
+            Ok(t) => load_tasks.push(t),
+            Err(e) => {
+                stop_load(&load_tasks);
+                return Err(e.to_string());
+            }
+        }
+    }
+
+    let mut results = Vec::with_capacity(tests.len());
+    for test in tests {
+        let samples = match test {
+            Test::Wakeup => wakeup_latency(core, iterations),
+            Test::Switch => context_switch_cost(core, iterations),
+            Test::Ipi if apic::core_count() <= 1 => {
+                println!("Skipping the ipi test, which requires more than one core.");
+                continue;
+            }
+            Test::Ipi => ipi_round_trip(iterations),
+        };
+        match samples {
+            Ok(samples) => results.extend(TestResult::from_samples(test, load, &samples)),
+            Err(e) => {
+                stop_load(&load_tasks);
+                return Err(format!("{} test failed: {}", test.name(), e));
+            }
+        }
+    }
+    stop_load(&load_tasks);
+
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+    for result in results.iter() {
+        csv.push_str(&result.to_csv());
+        csv.push('\n');
+    }
+    print!("{}", csv);
+
+    if let Some(file_name) = matches.opt_str("o") {
+        let path = write_results(file_name, &csv)?;
+        println!("Saved results to {}", path);
+    }
+
+    let mut regressions = 0;
+    if let Some(baseline) = baseline {
+        for result in results.iter() {
+            match baseline.iter().find(|b| b.test == result.test && b.load == result.load) {
+                Some(base) => {
+                    let limit = base.median.saturating_add(base.median.saturating_mul(threshold) / 100);
+                    let status = if result.median > limit {
+                        regressions += 1;
+                        "REGRESSION"
+                    } else {
+                        "ok"
+                    };
+                    println!("{} (load {}): median {} ns, baseline {} ns: {}", result.test, result.load, result.median, base.median, status);
+                }
+                None => println!("{} (load {}): no baseline", result.test, result.load),
+            }
+        }
+    }
+    Ok(regressions)
+}
+
+
+/// Parses the value of the option `name`, or returns the `default` value if it isn't present.
+fn parse_opt<T: str::FromStr>(matches: &Matches, name: &str, default: T) -> Result<T, String> {
+    match matches.opt_str(name) {
+        Some(s) => s.parse::<T>().map_err(|_e| format!("invalid value for option {:?}: {:?}", name, s)),
+        None => Ok(default),
+    }
+}
+
+
+/// Returns the current value of the HPET counter.
+///
+/// The HPET is used instead of the TSC, because its counter is the same on all cores.
+fn hpet_counter() -> u64 {
+    get_hpet().map(|hpet| hpet.get_counter()).unwrap_or(0)
+}
+
+/// Waits for the given task to exit, yielding the CPU in the meantime
+/// in case the task runs on the same core, and then reaps it.
+fn wait_for_exit(taskref: &TaskRef) -> Result<Option<ExitValue>, &'static str> {
+    while !taskref.lock().has_exited() {
+        scheduler::schedule();
+    }
+    taskref.join()?;
+    Ok(taskref.take_exit_value())
+}
+
+
+/// Measures the wakeup latency of a task on the given `core` in nanoseconds,
+/// i.e., the time from when it is unblocked until it actually runs.
+fn wakeup_latency(core: u8, iterations: usize) -> Result<Vec<u64>, &'static str> {
+    let sleeper = spawn::new_task_builder(sleeper_task, iterations)
+        .name(String::from("schedbench_sleeper"))
+        .pin_on_core(core)
+        .spawn()?;
+
+    let mut samples = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        // Wait for the sleeper to block itself and to be switched out.
+        loop {
+            let (blocked, exited) = {
+                let t = sleeper.lock();
+                (!t.is_runnable() && !t.is_running(), t.has_exited())
+            };
+            if exited {
+                return Err("the sleeper task exited early");
+            }
+            if blocked {
+                break;
+            }
+            scheduler::schedule();
+        }
+
+        WAKEUP_END.store(0, Ordering::SeqCst);
+        WAKEUP_START.store(hpet_counter(), Ordering::SeqCst);
+        sleeper.unblock();
+        let end = loop {
+            let end = WAKEUP_END.load(Ordering::SeqCst);
+            if end != 0 {
+                break end;
+            }
+            scheduler::schedule();
+        };
+        samples.push(hpet_2_ns(end.saturating_sub(WAKEUP_START.load(Ordering::SeqCst))));
+    }
+
+    wait_for_exit(&sleeper)?;
+    Ok(samples)
+}
+
+/// The task that is repeatedly woken up by the wakeup test.
+fn sleeper_task(iterations: usize) {
+    let me = match task::get_my_current_task() {
+        Some(t) => t,
+        None => return,
+    };
+    for _ in 0..iterations {
+        me.block();
+        scheduler::schedule();
+        WAKEUP_END.store(hpet_counter(), Ordering::SeqCst);
+    }
+}
+
+
+/// Measures the cost of a context switch on the given `core` in nanoseconds,
+/// by timing two tasks that repeatedly yield to each other.
+fn context_switch_cost(core: u8, iterations: usize) -> Result<Vec<u64>, &'static str> {
+    STOP_SWITCH_PARTNER.store(false, Ordering::SeqCst);
+    let partner = spawn::new_task_builder(switch_partner_task, ())
+        .name(String::from("schedbench_switch_partner"))
+        .pin_on_core(core)
+        .spawn()?;
+    let measurer = spawn::new_task_builder(switch_measurer_task, iterations)
+        .name(String::from("schedbench_switch_measurer"))
+        .pin_on_core(core)
+        .spawn();
+
+    let exit_value = match measurer {
+        Ok(ref m) => wait_for_exit(m),
+        Err(e) => Err(e),
+    };
+    STOP_SWITCH_PARTNER.store(true, Ordering::SeqCst);
+    wait_for_exit(&partner)?;
+
+    match exit_value? {
+        Some(ExitValue::Completed(samples)) => samples.downcast::<Vec<u64>>()
+            .map(|samples| *samples)
+            .map_err(|_e| "the context switch task returned an unexpected value"),
+        _ => Err("the context switch task was killed"),
+    }
+}
+
+/// The task that times context switches, returning the samples.
+fn switch_measurer_task(iterations: usize) -> Vec<u64> {
+    let mut samples = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = hpet_counter();
+        for _ in 0..SWITCHES_PER_SAMPLE {
+            scheduler::schedule();
+        }
+        let end = hpet_counter();
+        // Each yield switches to the partner task and back again.
+        samples.push(hpet_2_ns(end.saturating_sub(start)) / (2 * SWITCHES_PER_SAMPLE));
+    }
+    samples
+}
+
+/// The task that the measurer task of the context switch test yields to.
+fn switch_partner_task(_: ()) {
+    while !STOP_SWITCH_PARTNER.load(Ordering::SeqCst) {
+        scheduler::schedule();
+    }
+}
+
+
+/// Measures the round-trip time of an IPI in nanoseconds, i.e., the time it takes to send
+/// a TLB shootdown IPI (for no pages) to all other cores until they have all handled it.
+fn ipi_round_trip(iterations: usize) -> Result<Vec<u64>, &'static str> {
+    let mut samples = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = hpet_counter();
+        tlb_shootdown::send_tlb_shootdown_ipi(PageRange::empty());
+        let end = hpet_counter();
+        samples.push(hpet_2_ns(end.saturating_sub(start)));
+    }
+    Ok(samples)
+}
+
+
+/// A CPU-bound task that runs in the background until `STOP_LOAD` is set.
+fn load_task(_: ()) -> u64 {
+    let mut x: u64 = 1;
+    while !STOP_LOAD.load(Ordering::Relaxed) {
+        x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    }
+    x
+}
+
+/// Tells the given load tasks to exit and waits for them to do so.
+fn stop_load(load_tasks: &[TaskRef]) {
+    STOP_LOAD.store(true, Ordering::SeqCst);
+    for t in load_tasks {
+        if let Err(e) = wait_for_exit(t) {
+            println!("Warning: failed to wait for load task to exit: {}", e);
+        }
+    }
+}
+
+
+/// Reads the CSV results from the given file, relative to the current directory.
+fn read_results(file_name: &str) -> Result<Vec<TestResult>, String> {
+    let env = task::get_my_current_task()
+        .ok_or_else(|| format!("failed to get current task"))?
+        .get_env();
+    let file = match env.lock().resolve_path(&Path::new(file_name.to_string())) {
+        Some(FileOrDir::File(f)) => f,
+        Some(FileOrDir::Dir(_)) => return Err(format!("{:?} is a directory, not a file", file_name)),
+        None => return Err(format!("couldn't find file {:?}", file_name)),
+    };
+    let file_locked = file.lock();
+    let mut contents = vec![0; file_locked.size()];
+    file_locked.read(&mut contents, 0).map_err(|e| e.to_string())?;
+    let contents = str::from_utf8(&contents).map_err(|_e| format!("{:?} is not a text file", file_name))?;
+
+    contents.lines()
+        .filter(|line| !line.trim().is_empty() && line.trim() != CSV_HEADER)
+        .map(|line| TestResult::from_csv(line).ok_or_else(|| format!("invalid line in {:?}: {:?}", file_name, line)))
+        .collect()
+}
+
+/// Writes the given CSV results into a new file in the current directory, and returns its absolute path.
+fn write_results(file_name: String, csv: &str) -> Result<String, String> {
+    let env = task::get_my_current_task()
+        .ok_or_else(|| format!("failed to get current task"))?
+        .get_env();
+    let env = env.lock();
+    if env.working_dir.lock().get(&file_name).is_some() {
+        return Err(format!("a file or directory named {:?} already exists", file_name));
+    }
+    let file = env.create_file(file_name)?;
+    file.lock().write(csv.as_bytes(), 0)?;
+    let path = file.lock().get_absolute_path();
+    Ok(path)
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: schedbench [OPTIONS]
+Measures scheduler wakeup latency, context switch cost, and IPI round-trip time,
+printing the results (in nanoseconds) as CSV.
+Use -o to save the results, and -b to compare them against previously-saved results.";
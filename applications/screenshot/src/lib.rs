@@ -0,0 +1,110 @@
+//! This application captures the screen or the active window to an image file,
+//! or records the screen as a sequence of image files.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate task;
+extern crate path;
+extern crate fs_node;
+extern crate framebuffer_capture;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use path::Path;
+use fs_node::FileOrDir;
+use framebuffer_capture::ImageFormat;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("w", "window", "capture only the active window instead of the whole screen");
+    opts.optopt("f", "format", "the image format, either \"png\" (the default) or \"ppm\"", "FORMAT");
+    opts.optopt("n", "frames", "record COUNT frames of the screen into the directory DIR", "COUNT");
+    opts.optopt("i", "interval", "when recording, the interval between frames in milliseconds (default 100)", "MS");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let format = match matches.opt_str("f").as_ref().map(|f| f.as_str()) {
+        None | Some("png") => ImageFormat::Png,
+        Some("ppm") => ImageFormat::Ppm,
+        Some(other) => return Err(format!("unknown image format {:?}", other)),
+    };
+    let name = matches.free.get(0).ok_or_else(|| format!("missing NAME argument"))?;
+    let env = task::get_my_current_task()
+        .ok_or_else(|| format!("failed to get current task"))?
+        .get_env();
+
+    if let Some(count) = matches.opt_str("n") {
+        let count = count.parse::<usize>().map_err(|_e| format!("invalid frame count {:?}", count))?;
+        let interval_ms = match matches.opt_str("i") {
+            Some(i) => i.parse::<u64>().map_err(|_e| format!("invalid interval {:?}", i))?,
+            None => 100,
+        };
+        let dir = match env.lock().resolve_path(&Path::new(name.to_string())) {
+            Some(FileOrDir::Dir(d)) => d,
+            Some(FileOrDir::File(_)) => return Err(format!("{:?} is a file, not a directory", name)),
+            None => return Err(format!("couldn't find directory {:?}", name)),
+        };
+        let files = framebuffer_capture::record_screen(count, interval_ms, format, &dir).map_err(|e| e.to_string())?;
+        println!("Recorded {} frames into {}", files.len(), dir.lock().get_absolute_path());
+        return Ok(());
+    }
+
+    let image = if matches.opt_present("w") {
+        framebuffer_capture::capture_active_window()
+    } else {
+        framebuffer_capture::capture_screen()
+    }.map_err(|e| e.to_string())?;
+
+    let mut file_name = name.clone();
+    if !file_name.ends_with(&format!(".{}", format.extension())) {
+        file_name = format!("{}.{}", file_name, format.extension());
+    }
+    let working_dir = env.lock().working_dir.clone();
+    let file = image.save(format, file_name, &working_dir).map_err(|e| e.to_string())?;
+    let (width, height) = image.get_size();
+    println!("Saved {}x{} image to {}", width, height, file.lock().get_absolute_path());
+    Ok(())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: screenshot [-w] [-f FORMAT] NAME
+       screenshot -n COUNT [-i MS] [-f FORMAT] DIR
+Captures the screen (or the active window) into the file NAME in the current directory,
+or records COUNT frames of the screen into the existing directory DIR.";
@@ -0,0 +1,127 @@
+//! This application configures the rate limits of network traffic classes, e.g., the `bulk` class
+//! used for crate downloads, and shows the limits and statistics of all classes.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate network_manager;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use network_manager::shaping::{self, RateLimit};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("c", "class", "the traffic class to configure, which is created if it doesn't exist", "CLASS");
+    opts.optopt("e", "egress", "the limit for outgoing traffic in KiB/s, or \"none\"", "RATE");
+    opts.optopt("i", "ingress", "the limit for incoming traffic in KiB/s, or \"none\"", "RATE");
+    opts.optopt("b", "burst", "the burst size in KiB", "SIZE");
+    opts.optflag("r", "remove", "remove the traffic class");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if let Some(name) = matches.opt_str("c") {
+        if matches.opt_present("r") {
+            shaping::remove_class(&name).ok_or_else(|| format!("traffic class {:?} doesn't exist", name))?;
+            println!("Removed traffic class {:?}", name);
+            return Ok(());
+        }
+
+        let mut limit = shaping::get_class(&name).map_or(
+            RateLimit { egress_rate: None, ingress_rate: None, burst: shaping::DEFAULT_BURST },
+            |class| class.limit(),
+        );
+        if let Some(rate) = matches.opt_str("e") {
+            limit.egress_rate = parse_rate(&rate)?;
+        }
+        if let Some(rate) = matches.opt_str("i") {
+            limit.ingress_rate = parse_rate(&rate)?;
+        }
+        if let Some(burst) = matches.opt_str("b") {
+            let kib: u64 = burst.parse().map_err(|_| format!("invalid burst size {:?}", burst))?;
+            limit.burst = kib * 1024;
+        }
+        shaping::set_class(name, limit);
+    } else if matches.opt_present("r") {
+        return Err("the class to remove must be given with -c".into());
+    }
+
+    println!("{:<16} {:>13} {:>13} {:>10} {:>14} {:>14} {:>10} {:>10}",
+        "CLASS", "EGRESS KiB/s", "INGRESS KiB/s", "BURST KiB", "SENT", "RECEIVED", "DELAYED", "DROPPED",
+    );
+    for class in shaping::classes() {
+        let limit = class.limit();
+        let stats = class.stats();
+        println!("{:<16} {:>13} {:>13} {:>10} {:>14} {:>14} {:>10} {:>10}",
+            class.name(),
+            format_rate(limit.egress_rate),
+            format_rate(limit.ingress_rate),
+            limit.burst / 1024,
+            stats.bytes_sent,
+            stats.bytes_received,
+            stats.frames_delayed,
+            stats.frames_dropped,
+        );
+    }
+    Ok(())
+}
+
+
+/// Parses a rate in KiB/s into bytes per second, where "none" means no limit.
+fn parse_rate(rate: &str) -> Result<Option<u64>, String> {
+    if rate == "none" {
+        return Ok(None);
+    }
+    let kib: u64 = rate.parse().map_err(|_| format!("invalid rate {:?}", rate))?;
+    Ok(Some(kib * 1024))
+}
+
+fn format_rate(rate: Option<u64>) -> String {
+    match rate {
+        Some(rate) => format!("{}", rate / 1024),
+        None => "none".into(),
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: shape [-c CLASS [-e RATE] [-i RATE] [-b SIZE]]
+       shape -c CLASS -r
+Configures the rate limits of a network traffic class, and then shows all traffic classes.
+Sockets are assigned to traffic classes by their tasks; crate downloads use the `bulk` class.
+Traffic of sockets that aren't assigned to any class, e.g., remote shell sessions, is never limited.";
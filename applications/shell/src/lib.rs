@@ -33,8 +33,8 @@ use keycodes_ascii::{Keycode, KeyAction, KeyEvent};
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use path::Path;
-use task::{TaskRef, ExitValue, KillReason};
-use libterm::Terminal;
+use task::{TaskRef, ExitValue, KillReason, CpuTimeLimit};
+use libterm::{Terminal, text};
 use dfqueue::{DFQueue, DFQueueConsumer, DFQueueProducer};
 use alloc::sync::Arc;
 use spin::{Mutex, MutexGuard};
@@ -48,6 +48,10 @@ use core::ops::Deref;
 use app_io::{IoStreams, IoControlFlags};
 use fs_node::FileOrDir;
 
+/// How much CPU time (in microseconds) a task may use beyond the limit set by `ulimit -t`
+/// after it has been requested to cancel itself, before it is killed.
+const CPU_TIME_LIMIT_GRACE_PERIOD_US: u64 = 1_000_000;
+
 /// The status of a job.
 #[derive(PartialEq)]
 enum JobStatus {
@@ -87,6 +91,11 @@ struct Job {
     stdin_writer: StdioWriter,
     /// The output reader of the job. It is the reader of `pipe_queues[N]`.
     stdout_reader: StdioReader,
+    /// Bytes read from `stdout_reader` that form the beginning of an incomplete UTF-8 character,
+    /// which is printed once the rest of it has been read.
+    stdout_pending: Vec<u8>,
+    /// Same as `stdout_pending`, but for each of the `stderr_queues`.
+    stderr_pending: Vec<Vec<u8>>,
     /// Command line that was used to create the job.
     cmd: String
 }
@@ -165,6 +174,8 @@ struct Shell {
     print_producer: DFQueueProducer<Event>,
     /// The terminal's current environment
     env: Arc<Mutex<Environment>>,
+    /// The CPU time limit of each task spawned from the shell, set by `ulimit -t`.
+    cpu_time_limit: Option<CpuTimeLimit>,
     /// the terminal that is bind with the shell instance
     terminal: Arc<Mutex<Terminal>>
 }
@@ -189,9 +200,7 @@ impl Shell {
         // this function call will do nothing. 
         print::set_default_print_output(print_producer.obtain_producer());
 
-        let env = Environment {
-            working_dir: Arc::clone(root::get_root()), 
-        };
+        let env = Environment::default();
 
         let terminal = Arc::new(Mutex::new(Terminal::new()?));
 
@@ -209,6 +218,7 @@ impl Shell {
             print_consumer,
             print_producer,
             env: Arc::new(Mutex::new(env)),
+            cpu_time_limit: None,
             terminal
         })
     }
@@ -219,7 +229,8 @@ impl Shell {
     fn insert_char_to_cmdline(&mut self, c: char, sync_terminal: bool) -> Result<(), &'static str> {
         let mut terminal = self.terminal.lock();
         let offset_from_end = terminal.get_cursor_offset_from_end();
-        let insert_idx = self.cmdline.len() - offset_from_end;
+        let insert_idx = text::byte_index_from_end(&self.cmdline, offset_from_end)
+            .ok_or("BUG: the cursor is beyond the start of the command line")?;
         self.cmdline.insert(insert_idx, c);
         if sync_terminal {
             // disable cursor before updating in case the cursor is not at the end and the old text is the prefix of the new one
@@ -234,18 +245,34 @@ impl Shell {
     /// Remove a character from the command line buffer in the shell. If there is nothing to
     /// be removed, it does nothing and returns.
     /// The position to remove is determined by the position of the cursor in the terminal.
+    /// A character is removed together with any combining characters that follow it.
     /// `sync_terminal` indicates whether the terminal screen will be synchronically updated.
     fn remove_char_from_cmdline(&mut self, erase_left: bool, sync_terminal: bool) -> Result<(), &'static str> {
-        let mut cursor_offset_from_end = self.terminal.lock().get_cursor_offset_from_end();
-        if erase_left { cursor_offset_from_end += 1; }
-        if cursor_offset_from_end > self.cmdline.len() || cursor_offset_from_end == 0 { return Ok(()); }
-        let erase_idx = self.cmdline.len() - cursor_offset_from_end;
-        self.cmdline.remove(erase_idx);
+        let cursor_offset_from_end = self.terminal.lock().get_cursor_offset_from_end();
+        let cursor_idx = match text::byte_index_from_end(&self.cmdline, cursor_offset_from_end) {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+        // the number of characters to remove, and the byte index and offset from the end of the first one
+        let (num_chars, erase_idx, erase_offset_from_end) = if erase_left {
+            let n = text::cluster_len_before(&self.cmdline, cursor_idx);
+            let idx = text::byte_index_from_end(&self.cmdline, cursor_offset_from_end + n).unwrap_or(cursor_idx);
+            (n, idx, cursor_offset_from_end + n)
+        } else {
+            (text::cluster_len_after(&self.cmdline, cursor_idx), cursor_idx, cursor_offset_from_end)
+        };
+        if num_chars == 0 { return Ok(()); }
+
+        let erase_len: usize = self.cmdline[erase_idx..].chars().take(num_chars).map(char::len_utf8).sum();
+        self.cmdline.replace_range(erase_idx .. erase_idx + erase_len, "");
         if sync_terminal {
-            self.terminal.lock().remove_char(cursor_offset_from_end)?;
+            let mut terminal = self.terminal.lock();
+            for _ in 0..num_chars {
+                terminal.remove_char(erase_offset_from_end - num_chars + 1)?;
+            }
         }
         if !erase_left {            
-            self.update_cursor_pos(cursor_offset_from_end - 1)?;
+            self.update_cursor_pos(cursor_offset_from_end - num_chars)?;
         }
         Ok(())
     }
@@ -254,7 +281,7 @@ impl Shell {
     /// `sync_terminal` indicates whether the terminal screen will be synchronically updated.
     fn clear_cmdline(&mut self, sync_terminal: bool) -> Result<(), &'static str> {
         if sync_terminal {
-            for _i in 0..self.cmdline.len() {
+            for _i in 0..self.cmdline.chars().count() {
                 self.terminal.lock().remove_char(1)?;
             }
         }
@@ -287,19 +314,45 @@ impl Shell {
         Ok(())
     }
 
+    /// Paste the text in the clipboard into the input buffer of the foreground job, if there is one,
+    /// or otherwise into the command line at the cursor position.
+    /// When pasting into the command line, only the first line of the text is pasted.
+    fn paste_from_clipboard(&mut self) -> Result<(), &'static str> {
+        let text = match window_manager::clipboard::get_clipboard_text() {
+            Some(text) => text,
+            None => return Ok(()),
+        };
+        if self.fg_job_num.is_some() {
+            for c in text.chars() {
+                self.insert_char_to_input_buff(c, true)?;
+            }
+        } else {
+            for c in text.chars().take_while(|&c| c != '\n' && c != '\r') {
+                if c.is_control() { continue; }
+                self.insert_char_to_cmdline(c, true)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Remove a character from the input buffer to the application.
     /// `sync_terminal` indicates whether the terminal screen will be synchronically updated.
+    /// The last character is removed together with any combining characters that follow it.
     fn remove_char_from_input_buff(&mut self, sync_terminal: bool) -> Result<(), &'static str> {
-        let popped = self.input_buffer.pop();
-        if popped.is_some() && sync_terminal {
-            self.terminal.lock().remove_char(1)?;
+        let num_chars = text::cluster_len_before(&self.input_buffer, self.input_buffer.len());
+        for _ in 0..num_chars {
+            self.input_buffer.pop();
+            if sync_terminal {
+                self.terminal.lock().remove_char(1)?;
+            }
         }
         Ok(())
     }
 
     /// Move the cursor to the very beginning of the input command line.
     fn move_cursor_leftmost(&mut self) -> Result<(), &'static str> {
-        self.update_cursor_pos(self.cmdline.len())?;
+        let num_chars = self.cmdline.chars().count();
+        self.update_cursor_pos(num_chars)?;
         Ok(())
     }
 
@@ -309,12 +362,15 @@ impl Shell {
         Ok(())
     }
 
-    /// Move the cursor a character left. If the cursor is already at the beginning of the command line,
-    /// it simply returns.
+    /// Move the cursor a character left, skipping over combining characters.
+    /// If the cursor is already at the beginning of the command line, it simply returns.
     fn move_cursor_left(&mut self) -> Result<(), &'static str> {
         let offset_from_end = self.terminal.lock().get_cursor_offset_from_end();
-        if offset_from_end < self.cmdline.len() {
-            self.update_cursor_pos(offset_from_end + 1)?;
+        if let Some(cursor_idx) = text::byte_index_from_end(&self.cmdline, offset_from_end) {
+            let num_chars = text::cluster_len_before(&self.cmdline, cursor_idx);
+            if num_chars > 0 {
+                self.update_cursor_pos(offset_from_end + num_chars)?;
+            }
         }
         Ok(())
     }
@@ -323,8 +379,11 @@ impl Shell {
     /// it simply returns.
     fn move_cursor_right(&mut self) -> Result<(), &'static str> {
         let offset_from_end = self.terminal.lock().get_cursor_offset_from_end();
-        if offset_from_end > 0 {
-            self.update_cursor_pos(offset_from_end - 1)?;
+        if let Some(cursor_idx) = text::byte_index_from_end(&self.cmdline, offset_from_end) {
+            let num_chars = text::cluster_len_after(&self.cmdline, cursor_idx);
+            if num_chars > 0 {
+                self.update_cursor_pos(offset_from_end - num_chars)?;
+            }
         }
         self.terminal.lock().cursor.enable();
         
@@ -337,9 +396,10 @@ impl Shell {
         terminal.cursor.disable();
         terminal.display_cursor()?;
         if offset_from_end == 0 {
-            terminal.update_cursor_pos(0, 0)
-        } else if offset_from_end <= self.cmdline.len() {
-            terminal.update_cursor_pos(offset_from_end, self.cmdline.as_bytes()[self.cmdline.len() - offset_from_end]);
+            terminal.update_cursor_pos(0, ' ')
+        } else if let Some(idx) = text::byte_index_from_end(&self.cmdline, offset_from_end) {
+            let underlying_char = self.cmdline[idx..].chars().next().unwrap_or(' ');
+            terminal.update_cursor_pos(offset_from_end, underlying_char);
         }
         terminal.cursor.enable();
         
@@ -396,6 +456,19 @@ impl Shell {
             return Ok(()); 
         }
 
+        // Ctrl+Shift+C copies the current command line (or the pending input of the foreground job) to the clipboard.
+        // This must be checked before Ctrl+C, which would otherwise kill the foreground job.
+        if keyevent.modifiers.is_control() && keyevent.modifiers.is_shift() && keyevent.keycode == Keycode::C {
+            let text = if self.fg_job_num.is_some() { self.input_buffer.clone() } else { self.cmdline.clone() };
+            window_manager::clipboard::set_clipboard_text(text);
+            return Ok(());
+        }
+
+        // Ctrl+Shift+V pastes text from the clipboard.
+        if keyevent.modifiers.is_control() && keyevent.modifiers.is_shift() && keyevent.keycode == Keycode::V {
+            return self.paste_from_clipboard();
+        }
+
         // Ctrl+C signals the shell to exit the job
         if keyevent.modifiers.is_control() && keyevent.keycode == Keycode::C {
             if let Some(ref fg_job_num) = self.fg_job_num {
@@ -523,7 +596,7 @@ impl Shell {
         }
 
         // Attempts to run the command whenever the user presses enter and updates the cursor tracking variables 
-        if keyevent.keycode == Keycode::Enter && keyevent.keycode.to_ascii(keyevent.modifiers).is_some() {
+        if keyevent.keycode == Keycode::Enter && keyevent.character.is_some() {
             let cmdline = self.cmdline.clone();
             if cmdline.len() == 0 && self.fg_job_num.is_none() {
                 // reprints the prompt on the next line if the user presses enter and hasn't typed anything into the prompt
@@ -629,29 +702,23 @@ impl Shell {
         }
 
         // Tracks what the user has typed so far, excluding any keypresses by the backspace and Enter key, which are special and are handled directly below
-        if keyevent.keycode.to_ascii(keyevent.modifiers).is_some() {
-            match keyevent.keycode.to_ascii(keyevent.modifiers) {
-                Some(c) => {
-                    // If currently we have a task running, insert it to the input buffer, otherwise
-                    // to the cmdline.
-                    if let Some(fg_job_num) = self.fg_job_num {
-                        self.insert_char_to_input_buff(c, true)?;
-                        if let Some(job) = self.jobs.get(&fg_job_num) {
-                            if app_io::is_requesting_instant_flush(&job.task_ids[0])? {
-                                job.stdin_writer.lock().write_all(self.input_buffer.as_bytes())
-                                    .or(Err("shell failed to write to stdin"))?;
-                                self.input_buffer.clear();
-                            }
-                        }
-                        return Ok(());
-                    }
-                    else {
-                        self.insert_char_to_cmdline(c, true)?;
+        // The character is determined by the current keyboard layout, including any dead keys.
+        if let Some(c) = keyevent.character {
+            // If currently we have a task running, insert it to the input buffer, otherwise
+            // to the cmdline.
+            if let Some(fg_job_num) = self.fg_job_num {
+                self.insert_char_to_input_buff(c, true)?;
+                if let Some(job) = self.jobs.get(&fg_job_num) {
+                    if app_io::is_requesting_instant_flush(&job.task_ids[0])? {
+                        job.stdin_writer.lock().write_all(self.input_buffer.as_bytes())
+                            .or(Err("shell failed to write to stdin"))?;
+                        self.input_buffer.clear();
                     }
-                },
-                None => {
-                    return Err("Couldn't get key event");
                 }
+                return Ok(());
+            }
+            else {
+                self.insert_char_to_cmdline(c, true)?;
             }
         }
         Ok(())
@@ -673,14 +740,16 @@ impl Shell {
             .map(|f| Path::new(f.lock().get_absolute_path()))
             .ok_or(AppErr::NotFound(cmd))?;
 
-        let taskref = spawn::new_application_task_builder(app_path, None)
+        let mut task_builder = spawn::new_application_task_builder(app_path, None)
             .map_err(|e| AppErr::SpawnErr(e.to_string()))?
             .argument(args)
-            .block()
-            .spawn()
+            .env(Arc::clone(&self.env)) // the application shares the terminal task's environment
+            .block();
+        if let Some(limit) = self.cpu_time_limit {
+            task_builder = task_builder.cpu_time_limit(limit);
+        }
+        let taskref = task_builder.spawn()
             .map_err(|e| AppErr::SpawnErr(e.to_string()))?;
-        
-        taskref.set_env(self.env.clone()); // Set environment variable of application to the same as terminal task
 
         // Gets the task id so we can reference this task if we need to kill it with Ctrl+C
         return Ok(taskref);
@@ -695,7 +764,10 @@ impl Shell {
         let mut task_refs = Vec::new();
 
         for single_task_cmd in cmdline.split("|") {
-            let mut args: Vec<String> = single_task_cmd.split_whitespace().map(|s| s.to_string()).collect();
+            let mut args: Vec<String> = {
+                let env = self.env.lock();
+                single_task_cmd.split_whitespace().map(|s| expand_env_var(s, &env)).collect()
+            };
             let command = args.remove(0);
 
             // If the last arg is `&`, remove it.
@@ -764,6 +836,7 @@ impl Shell {
                 }
 
                 let job_stdout_reader = previous_queue_reader;
+                let stderr_pending = vec![Vec::new(); stderr_queues.len()];
 
                 let new_job = Job {
                     tasks: task_refs,
@@ -773,6 +846,8 @@ impl Shell {
                     stderr_queues,
                     stdin_writer: job_stdin_writer,
                     stdout_reader: job_stdout_reader,
+                    stdout_pending: Vec::new(),
+                    stderr_pending,
                     cmd: self.cmdline.clone()
                 };
 
@@ -818,7 +893,7 @@ impl Shell {
     /// Try to match the incomplete command against all internal commands. Returns a
     /// vector that contains all matching results.
     fn find_internal_cmd_match(&mut self, incomplete_cmd: &String) -> Result<Vec<String>, &'static str> {
-        let internal_cmds = vec!["fg", "bg", "jobs", "clear"];
+        let internal_cmds = vec!["fg", "bg", "jobs", "clear", "export", "unset", "ulimit", "umask"];
         let mut match_cmds = Vec::new();
         for cmd in internal_cmds.iter() {
             if cmd.starts_with(incomplete_cmd) {
@@ -1008,7 +1083,9 @@ impl Shell {
     fn complete_cmdline(&mut self) -> Result<(), &'static str> {
 
         // Get the last string slice in the pipe chain.
-        let cmdline = self.cmdline[0..self.cmdline.len()-self.terminal.lock().get_cursor_offset_from_end()].to_string();
+        let cursor_offset_from_end = self.terminal.lock().get_cursor_offset_from_end();
+        let cursor_idx = text::byte_index_from_end(&self.cmdline, cursor_offset_from_end).unwrap_or(0);
+        let cmdline = self.cmdline[0..cursor_idx].to_string();
         let last_cmd_in_pipe = match cmdline.split("|").last() {
             Some(cmd) => cmd,
             None => return Ok(())
@@ -1230,14 +1307,14 @@ impl Shell {
         let mut buf: [u8; 256] = [0; 256];
 
         // iterate through all jobs to see if they have something to print
-        for (_job_num, job) in self.jobs.iter() {
+        for (_job_num, job) in self.jobs.iter_mut() {
 
             // Deal with all stdout output.
             let mut stdout = job.stdout_reader.lock();
             match stdout.try_read(&mut buf) {
                 Ok(cnt) => {
                     mem::drop(stdout);
-                    let s = String::from_utf8_lossy(&buf[0..cnt]);
+                    let s = decode_utf8_stream(&mut job.stdout_pending, &buf[0..cnt]);
                     let mut locked_terminal = self.terminal.lock();
                     locked_terminal.print_to_terminal(s.to_string());
                     if cnt != 0 { need_refresh = true; }
@@ -1249,13 +1326,13 @@ impl Shell {
             };
 
             // Deal with all stderr output.
-            for stderr in &job.stderr_queues {
+            for (stderr, pending) in job.stderr_queues.iter().zip(job.stderr_pending.iter_mut()) {
                 let stderr = stderr.get_reader();
                 let mut stderr = stderr.lock();
                 match stderr.try_read(&mut buf) {
                     Ok(cnt) => {
                         mem::drop(stderr);
-                        let s = String::from_utf8_lossy(&buf[0..cnt]);
+                        let s = decode_utf8_stream(pending, &buf[0..cnt]);
                         let mut locked_terminal = self.terminal.lock();
                         locked_terminal.print_to_terminal(s.to_string());
                         if cnt != 0 { need_refresh = true; }
@@ -1389,6 +1466,10 @@ impl Shell {
                 "fg" => return true,
                 "bg" => return true,
                 "clear" => return true,
+                "export" => return true,
+                "unset" => return true,
+                "ulimit" => return true,
+                "umask" => return true,
                 _ => return false
             }
         }
@@ -1406,6 +1487,10 @@ impl Shell {
                 "fg" => self.execute_internal_fg(),
                 "bg" => self.execute_internal_bg(),
                 "clear" => self.execute_internal_clear(),
+                "export" => self.execute_internal_export(),
+                "unset" => self.execute_internal_unset(),
+                "ulimit" => self.execute_internal_ulimit(),
+                "umask" => self.execute_internal_umask(),
                 _ => Ok(())
             }
         } else {
@@ -1499,9 +1584,150 @@ impl Shell {
         self.redisplay_prompt();
         Ok(())
     }
+
+    /// Execute `export` command. With no arguments, it prints all environment variables.
+    /// Otherwise, it sets each `NAME=VALUE` argument as an environment variable.
+    fn execute_internal_export(&mut self) -> Result<(), &'static str> {
+        let cmdline_copy = self.cmdline.clone();
+        let mut iter = cmdline_copy.split_whitespace();
+        iter.next();
+        let args: Vec<&str> = iter.collect();
+        let mut output = String::new();
+        {
+            let mut env = self.env.lock();
+            if args.is_empty() {
+                for (name, value) in env.variables.iter() {
+                    output.push_str(&format!("{}={}\n", name, value));
+                }
+            }
+            for arg in args {
+                let mut split = arg.splitn(2, '=');
+                match (split.next(), split.next()) {
+                    (Some(name), Some(value)) if !name.is_empty() => {
+                        let value = expand_env_var(value, &env);
+                        env.set_var(name.to_string(), value);
+                    }
+                    _ => output.push_str("Usage: export NAME=VALUE\n"),
+                }
+            }
+        }
+        if !output.is_empty() {
+            self.terminal.lock().print_to_terminal(output);
+        }
+        self.clear_cmdline(false)?;
+        self.redisplay_prompt();
+        Ok(())
+    }
+
+    /// Execute `unset` command. It removes each of the given environment variables.
+    fn execute_internal_unset(&mut self) -> Result<(), &'static str> {
+        let cmdline_copy = self.cmdline.clone();
+        let mut iter = cmdline_copy.split_whitespace();
+        iter.next();
+        {
+            let mut env = self.env.lock();
+            for name in iter {
+                env.unset_var(name);
+            }
+        }
+        self.clear_cmdline(false)?;
+        self.redisplay_prompt();
+        Ok(())
+    }
+
+    /// Execute `ulimit` command. `ulimit -t` prints the CPU time limit (in seconds) of the tasks spawned from this shell,
+    /// and `ulimit -t SECONDS` sets it for the tasks spawned afterwards, or removes it if `SECONDS` is `unlimited`.
+    /// A task that exceeds the limit is requested to cancel itself, and is killed if it keeps running
+    /// for the grace period of `CPU_TIME_LIMIT_GRACE_PERIOD_US` afterwards.
+    fn execute_internal_ulimit(&mut self) -> Result<(), &'static str> {
+        let cmdline_copy = self.cmdline.clone();
+        let mut iter = cmdline_copy.split_whitespace();
+        iter.next();
+        let output = match (iter.next(), iter.next(), iter.next()) {
+            (Some("-t"), None, None) => match self.cpu_time_limit {
+                Some(limit) => format!("{}\n", limit.soft_limit_us / 1_000_000),
+                None => "unlimited\n".to_string(),
+            },
+            (Some("-t"), Some("unlimited"), None) => {
+                self.cpu_time_limit = None;
+                String::new()
+            }
+            (Some("-t"), Some(seconds), None) => match seconds.parse::<u64>() {
+                Ok(seconds) => {
+                    let soft_limit_us = seconds.saturating_mul(1_000_000);
+                    self.cpu_time_limit = Some(CpuTimeLimit {
+                        soft_limit_us,
+                        hard_limit_us: soft_limit_us.saturating_add(CPU_TIME_LIMIT_GRACE_PERIOD_US),
+                    });
+                    String::new()
+                }
+                Err(_) => format!("ulimit: invalid CPU time limit: {}\n", seconds),
+            },
+            _ => "Usage: ulimit -t [SECONDS | unlimited]\n".to_string(),
+        };
+        if !output.is_empty() {
+            self.terminal.lock().print_to_terminal(output);
+        }
+        self.clear_cmdline(false)?;
+        self.redisplay_prompt();
+        Ok(())
+    }
+
+    /// Execute `umask` command. With no arguments, it prints the file mode mask (in octal) of the shell's environment.
+    /// Otherwise, it sets the mask to the given octal value, which applies to the files created afterwards.
+    fn execute_internal_umask(&mut self) -> Result<(), &'static str> {
+        let cmdline_copy = self.cmdline.clone();
+        let mut iter = cmdline_copy.split_whitespace();
+        iter.next();
+        let output = match (iter.next(), iter.next()) {
+            (None, _) => format!("{:04o}\n", self.env.lock().file_mode_mask),
+            (Some(mask), None) => match u16::from_str_radix(mask, 8) {
+                Ok(mask) if mask <= 0o777 => {
+                    self.env.lock().file_mode_mask = mask;
+                    String::new()
+                }
+                _ => format!("umask: invalid octal mask: {}\n", mask),
+            },
+            _ => "Usage: umask [MASK]\n".to_string(),
+        };
+        if !output.is_empty() {
+            self.terminal.lock().print_to_terminal(output);
+        }
+        self.clear_cmdline(false)?;
+        self.redisplay_prompt();
+        Ok(())
+    }
 }
 
 
+/// Expands a command line word of the form `$NAME` into the value of the environment variable `NAME`, 
+/// or into an empty string if no such variable exists. Any other word is returned unchanged.
+fn expand_env_var(word: &str, env: &Environment) -> String {
+    if word.starts_with('$') && word.len() > 1 {
+        env.get_var(&word[1..]).cloned().unwrap_or_default()
+    } else {
+        word.to_string()
+    }
+}
+
+
+/// Appends `bytes` to the `pending` bytes of a UTF-8 byte stream and returns the text they contain.
+/// If the bytes end with an incomplete UTF-8 character, e.g., because it was split across two reads,
+/// that character is kept in `pending` rather than being decoded. Invalid bytes are replaced with U+FFFD.
+fn decode_utf8_stream(pending: &mut Vec<u8>, bytes: &[u8]) -> String {
+    pending.extend_from_slice(bytes);
+    let complete_len = match core::str::from_utf8(&pending[..]) {
+        Ok(_) => pending.len(),
+        // `error_len()` is `None` if the bytes end in the middle of a character.
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => pending.len(),
+    };
+    let rest = pending.split_off(complete_len);
+    let text = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+    text
+}
+
 /// Start a new shell. Shell::start() is an infinite loop, so normally we do not return from this function.
 fn shell_loop(mut _dummy: ()) -> Result<(), &'static str> {
     Shell::new()?.start()?;
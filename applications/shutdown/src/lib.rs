@@ -0,0 +1,71 @@
+//! This application quiesces all registered subsystems and then powers off or reboots the machine.
+//! See the `shutdown_manager` crate.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate shutdown_manager;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use shutdown_manager::ShutdownKind;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("r", "reboot", "reboot the machine instead of powering it off");
+    opts.optflag("l", "list", "list the subsystems in the order they would be quiesced, without shutting down");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if matches.opt_present("l") {
+        for name in shutdown_manager::quiesce_order() {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+
+    let kind = if matches.opt_present("r") { ShutdownKind::Reboot } else { ShutdownKind::PowerOff };
+    println!("{} ...", if kind == ShutdownKind::Reboot { "Rebooting" } else { "Powering off" });
+    shutdown_manager::shutdown(kind)?;
+    Ok(())
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: shutdown [-r | -l]
+Quiesces all subsystems in reverse dependency order, and then powers off or reboots the machine.";
@@ -0,0 +1,63 @@
+//! This application prints statistics about every object cache, similar to `slabinfo` on Linux.
+//! See the `object_cache` crate.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate object_cache;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::Options;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    let caches = object_cache::stats();
+    if caches.is_empty() {
+        println!("No object caches have been created.");
+        return 0;
+    }
+
+    println!("{0:<24}  {1:>8}  {2:>8}  {3:>6}  {4:>6}  {5:>6}  {6:>6}  {7:>6}",
+        "NAME", "IN USE", "TOTAL", "SIZE", "OBJ/SL", "PG/SL", "SLABS", "EMPTY"
+    );
+    for cache in caches {
+        println!("{0:<24}  {1:>8}  {2:>8}  {3:>6}  {4:>6}  {5:>6}  {6:>6}  {7:>6}",
+            cache.name, cache.objects_in_use, cache.objects_total, cache.object_size,
+            cache.objects_per_slab, cache.pages_per_slab, cache.slabs, cache.empty_slabs
+        );
+    }
+    0
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: slabinfo
+Prints how many objects are in use in each object cache, and how many slabs each cache holds.
+Empty slabs are released automatically when memory is under pressure.";
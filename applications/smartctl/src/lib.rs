@@ -0,0 +1,107 @@
+//! This application shows the S.M.A.R.T. health status of storage devices,
+//! similar to the `smartctl` utility on Linux.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate storage_health;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use storage_health::HealthReport;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("a", "all", "show all S.M.A.R.T. attributes of each device");
+    opts.optopt("m", "monitor", "start a background task that checks all devices every SECS seconds", "SECS");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if let Some(secs) = matches.opt_str("m") {
+        let secs = secs.parse::<u64>().map_err(|_e| format!("invalid interval {:?}", secs))?;
+        storage_health::spawn_health_monitor(secs).map_err(|e| e.to_string())?;
+        println!("Started checking storage device health every {} seconds.", secs);
+        return Ok(());
+    }
+
+    let device_filter = matches.free.get(0);
+    let reports: Vec<HealthReport> = storage_health::check_all_devices()
+        .into_iter()
+        .filter(|r| device_filter.map_or(true, |d| *d == r.device.to_string()))
+        .collect();
+    if reports.is_empty() {
+        match device_filter {
+            Some(d) => return Err(format!("device {:?} does not exist or does not support S.M.A.R.T.", d)),
+            None => println!("No storage devices support S.M.A.R.T."),
+        }
+        return Ok(());
+    }
+
+    for report in reports {
+        print_report(&report, matches.opt_present("a"));
+    }
+    Ok(())
+}
+
+
+fn print_report(report: &HealthReport, show_attributes: bool) {
+    let unknown = || String::from("unknown");
+    println!("{}: {}", report.device, report.model);
+    println!("    overall health:      {}", if report.passed { "PASSED" } else { "FAILED" });
+    println!("    temperature:         {}", report.temperature_celsius.map(|t| format!("{} C", t)).unwrap_or_else(unknown));
+    println!("    reallocated sectors: {}", report.reallocated_sectors.map(|s| s.to_string()).unwrap_or_else(unknown));
+    println!("    wear:                {}", report.wear_percent_used.map(|w| format!("{}% used", w)).unwrap_or_else(unknown));
+    println!("    power-on hours:      {}", report.power_on_hours.map(|h| h.to_string()).unwrap_or_else(unknown));
+
+    if show_attributes {
+        println!("    {:>3}  {:>7}  {:>5}  {:>5}  {:>6}  {:>14}", "ID", "FLAGS", "VALUE", "WORST", "THRESH", "RAW");
+        for attr in &report.attributes.0 {
+            println!("    {:>3}  {:#07X}  {:>5}  {:>5}  {:>6}  {:>14}{}", 
+                attr.id, attr.flags, attr.current, attr.worst, attr.threshold, attr.raw,
+                if attr.is_failing() { "  FAILING" } else { "" },
+            );
+        }
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: smartctl [-a] [DEVICE]
+       smartctl -m SECS
+Shows the S.M.A.R.T. health status of the given storage DEVICE (e.g., c0d1),
+or of all storage devices that support S.M.A.R.T. if no DEVICE is given.";
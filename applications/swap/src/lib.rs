@@ -40,6 +40,7 @@ pub fn main(args: Vec<String>) -> isize {
     opts.optflag("h", "help", "print this help menu");
     opts.optflag("v", "verbose", "enable verbose logging of crate swapping actions");
     opts.optflag("c", "cache", "enable caching of the old crate(s) removed by the swapping action");
+    opts.optflag("a", "async-relink", "defer rewriting the relocations of existing dependents to the background relink service");
     opts.optopt("d", "directory-crates", "the absolute path of the base directory where new crates will be loaded from", "PATH");
     opts.optmulti("t", "state-transfer", "the fully-qualified symbol names of state transfer functions, to be run in the order given", "SYMBOL");
 
@@ -91,6 +92,7 @@ fn rmain(matches: Matches) -> Result<(), String> {
 
     let verbose = matches.opt_present("v");
     let cache_old_crates = matches.opt_present("c");
+    let async_relink = matches.opt_present("a");
     let state_transfer_functions = matches.opt_strs("t");
 
     let free_args = matches.free.join(" ");
@@ -106,7 +108,8 @@ fn rmain(matches: Matches) -> Result<(), String> {
         override_namespace_crate_dir,
         state_transfer_functions,
         verbose,
-        cache_old_crates
+        cache_old_crates,
+        async_relink,
     )
 }
 
@@ -162,7 +165,8 @@ fn do_swap(
     override_namespace_crate_dir: Option<NamespaceDir>, 
     state_transfer_functions: Vec<String>,
     verbose_log: bool,
-    cache_old_crates: bool
+    cache_old_crates: bool,
+    async_relink: bool,
 ) -> Result<(), String> {
     let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or_else(|| "couldn't get kernel_mmi_ref".to_string())?;
     let namespace = task::get_my_current_task().ok_or("Couldn't get current task")?.get_namespace();
@@ -198,7 +202,8 @@ fn do_swap(
     
     let start = get_hpet().as_ref().ok_or("couldn't get HPET timer")?.get_counter();
 
-    let swap_result = crate_swap::swap_crates(
+    let swap_fn = if async_relink { crate_swap::swap_crates_deferred } else { crate_swap::swap_crates };
+    let swap_result = swap_fn(
         &namespace,
         swap_requests, 
         override_namespace_crate_dir,
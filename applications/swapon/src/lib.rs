@@ -0,0 +1,116 @@
+//! This application enables or disables swapping to a storage device, and shows swapping statistics.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate memory;
+extern crate storage_manager;
+extern crate swap_space;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use storage_manager::{StorageDeviceRef, STORAGE_CONTROLLERS};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("d", "disable", "disable swapping, which requires that no pages are swapped out");
+    opts.optopt("s", "start", "the first sector of the swap space on the device (default: 0)", "SECTOR");
+    opts.optopt("n", "sectors", "the number of sectors in the swap space (default: until the end of the device)", "COUNT");
+    opts.optopt("e", "evict", "immediately evict up to this many pages to swap", "PAGES");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    if matches.opt_present("d") {
+        swap_space::disable_swap()?;
+        println!("Disabled swapping");
+        return Ok(());
+    }
+
+    if let Some(device_name) = matches.free.get(0) {
+        let device = find_device(device_name)?;
+        let start_sector = match matches.opt_str("s") {
+            Some(s) => s.parse().map_err(|_| format!("invalid start sector {:?}", s))?,
+            None => 0,
+        };
+        let num_sectors = match matches.opt_str("n") {
+            Some(n) => Some(n.parse().map_err(|_| format!("invalid number of sectors {:?}", n))?),
+            None => None,
+        };
+        let num_pages = swap_space::enable_swap(device, start_sector, num_sectors)?;
+        println!("Enabled swapping to {}, which can hold {} pages", device_name, num_pages);
+    }
+
+    if let Some(pages) = matches.opt_str("e") {
+        let pages = pages.parse().map_err(|_| format!("invalid number of pages {:?}", pages))?;
+        let freed = memory::swap_out(pages)?;
+        println!("Evicted {} pages to swap", freed);
+    }
+
+    let stats = memory::swap_stats();
+    println!("Swapping enabled:   {}", stats.enabled);
+    println!("Swappable mappings: {}", stats.swappable_mappings);
+    println!("Swapped-out pages:  {}", stats.swapped_pages);
+    println!("Pages swapped out:  {} (since boot)", stats.pages_swapped_out);
+    println!("Pages swapped in:   {} (since boot)", stats.pages_swapped_in);
+    Ok(())
+}
+
+
+/// Finds the storage device named `cXdY`, i.e., device `Y` of storage controller `X`.
+fn find_device(name: &str) -> Result<StorageDeviceRef, String> {
+    let invalid = || format!("invalid device {:?}, expected a name like \"c0d1\"", name);
+    if !name.starts_with('c') {
+        return Err(invalid());
+    }
+    let mut parts = name[1..].splitn(2, 'd');
+    let controller_index: usize = parts.next().and_then(|c| c.parse().ok()).ok_or_else(invalid)?;
+    let device_index: usize = parts.next().and_then(|d| d.parse().ok()).ok_or_else(invalid)?;
+
+    let controllers = STORAGE_CONTROLLERS.lock();
+    let controller = controllers.get(controller_index).ok_or_else(|| format!("storage controller {} doesn't exist", controller_index))?;
+    let device = controller.lock().devices().nth(device_index);
+    device.ok_or_else(|| format!("storage device {} doesn't exist", name))
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: swapon [-s SECTOR] [-n COUNT] [DEVICE]
+       swapon -e PAGES
+       swapon -d
+Enables swapping to a storage device, e.g., `c0d1`, or disables it, and then shows swapping statistics.
+Any existing contents of the swap space on the device are overwritten.";
@@ -0,0 +1,123 @@
+//! This application translates symbol names into the virtual addresses where their sections are loaded.
+//! 
+//! It performs the inverse translation of the `addr2sym` application.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate mod_mgmt;
+extern crate crate_name_utils;
+extern crate task;
+
+use alloc::{
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use mod_mgmt::{CrateNamespace, StrongSectionRef};
+use crate_name_utils::get_containing_crate_name;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1;
+        }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let namespace = get_my_current_namespace();
+
+    let mut not_found = 0;
+    for symbol in matches.free.iter() {
+        let sections = find_sections(&namespace, symbol);
+        if sections.is_empty() {
+            println!("{}: not found in namespace {:?}", symbol, namespace.name());
+            not_found += 1;
+        }
+        for (sec, ns_name) in sections {
+            println!("{:#X} - {:#X}: {} [{:?}, {} bytes, {}] in namespace {:?}",
+                sec.start_address(), sec.address_range.end, sec.name, sec.typ, sec.size(),
+                if sec.global { "global" } else { "private" }, ns_name,
+            );
+        }
+    }
+
+    if not_found > 0 {
+        Err(format!("couldn't translate {} of {} symbols", not_found, matches.free.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns all loaded sections whose names start with the given `symbol`, 
+/// along with the name of the namespace they were found in.
+/// 
+/// The symbol maps of the given `namespace` and its recursive namespace are searched first.
+/// Since those only contain global symbols, if nothing matches there, 
+/// the private sections of the symbol's containing crate(s) are searched instead.
+fn find_sections(namespace: &Arc<CrateNamespace>, symbol: &str) -> Vec<(StrongSectionRef, String)> {
+    let global_sections: Vec<(StrongSectionRef, String)> = namespace.find_symbols_starting_with_and_namespace(symbol)
+        .into_iter()
+        .filter_map(|(_name, weak_sec, ns)| weak_sec.upgrade().map(|sec| (sec, String::from(ns.name()))))
+        .collect();
+    if !global_sections.is_empty() {
+        return global_sections;
+    }
+
+    let mut private_sections = Vec::new();
+    for crate_name in get_containing_crate_name(symbol) {
+        let crate_prefix = format!("{}-", crate_name);
+        for (_cname, crate_ref, ns) in CrateNamespace::get_crates_starting_with(namespace, &crate_prefix) {
+            for sec in crate_ref.lock_as_ref().sections.values() {
+                if sec.name.starts_with(symbol) {
+                    private_sections.push((sec.clone(), String::from(ns.name())));
+                }
+            }
+        }
+    }
+    private_sections
+}
+
+
+fn get_my_current_namespace() -> Arc<CrateNamespace> {
+    task::get_my_current_task().map(|t| t.get_namespace()).unwrap_or_else(|| 
+        mod_mgmt::get_initial_kernel_namespace().expect("BUG: initial kernel namespace wasn't initialized").clone()
+    )
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: sym2addr SYMBOL...
+Translates each SYMBOL into the virtual address range of every loaded section whose name starts with it.
+Global symbols are found in the symbol map of the current namespace; 
+private symbols are found by searching their containing crate, e.g., \"my_crate::foo\".";
@@ -97,6 +97,11 @@ fn test_filerw() -> Result<(), &'static str> {
     println!("seventh test read output (part 1) should be 'from hello', actually is {} ", str::from_utf8(&mut oversize_buffer2).unwrap());    
     println!("seventh test successful: read with oversized buffers works");
 
+    // tests that a file created with a specific mode reports that mode, and one created without a mode reports the base mode
+    let testfile3 = MemFile::new_with_mode("testfile3".to_string(), &parent, 0o644)?;
+    println!("eighth test file modes should be 644 and 666, actually are {:o} and {:o}", testfile3.lock().mode(), testfile2.lock().mode());
+    println!("eighth test successful: files keep the mode they were created with");
+
 
     Ok(())
 }
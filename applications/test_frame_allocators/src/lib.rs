@@ -0,0 +1,332 @@
+//! A conformance test suite that every frame allocator backend must pass.
+//!
+//! Each test creates a new instance of a backend that manages a few synthetic ranges of frames,
+//! and then checks its behavior using only the `FrameAllocator` and `FrameAllocatorBackend` traits.
+//! Frame allocators never access the memory of the frames they manage, so these frames need not exist.
+//! The last test drives each backend with randomized sequences of operations using the `frame_allocator_stress` harness.
+//!
+//! Usage: `test_frame_allocators [area|bitmap|buddy]...`, which tests all backends by default.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+#[macro_use] extern crate terminal_print;
+extern crate memory;
+extern crate kernel_config;
+extern crate frame_allocator_stress;
+
+use core::ops::Range;
+use alloc::{
+    boxed::Box,
+    collections::BTreeSet,
+    string::String,
+    vec::Vec,
+};
+use kernel_config::memory::PAGE_SIZE;
+use memory::{
+    AreaFrameAllocator, BitmapFrameAllocator, BuddyFrameAllocator, Frame, FrameAllocator, FrameAllocError,
+    FrameAllocatorBackend, FrameAllocatorKind, FrameRange, PhysicalAddress, PhysicalMemoryArea,
+};
+use frame_allocator_stress::{StressConfig, StressTarget};
+
+
+/// The ranges of free frames that each backend initially manages, as (first frame number, number of frames).
+const INITIAL_RANGES: [(usize, usize); 2] = [(0x10_0000, 256), (0x10_0200, 64)];
+/// The total number of frames in `INITIAL_RANGES`.
+const INITIAL_FRAMES: usize = 256 + 64;
+/// A range of frames that is added to each backend after it was created, like memory that was hotplugged.
+const HOTPLUG_RANGE: (usize, usize) = (0x10_0400, 32);
+/// The highest frame that the bitmap frame allocator can track.
+const BITMAP_END: usize = 0x10_07FF;
+/// The seeds of the randomized stress test runs, one run per seed.
+const STRESS_SEEDS: [u64; 4] = [0x1, 0x5EED, 0xDEAD_BEEF, 0x1234_5678_9ABC_DEF0];
+/// The number of random operations in each stress test run.
+const STRESS_STEPS: usize = 5000;
+
+const ALL_KINDS: [FrameAllocatorKind; 3] = [FrameAllocatorKind::Area, FrameAllocatorKind::Bitmap, FrameAllocatorKind::Buddy];
+
+/// Every test and its name.
+const TESTS: [(&'static str, fn(&mut dyn FrameAllocatorBackend) -> Result<(), &'static str>); 7] = [
+    ("initial state", test_initial_state),
+    ("single frames", test_single_frames),
+    ("contiguous frames", test_contiguous_frames),
+    ("invalid request", test_invalid_request),
+    ("fragmented", test_fragmented),
+    ("remove and add frames", test_remove_and_add_frames),
+    ("randomized stress", test_randomized_stress),
+];
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut kinds = Vec::new();
+    for arg in args.iter() {
+        match FrameAllocatorKind::from_name(arg) {
+            Some(kind) => kinds.push(kind),
+            None => {
+                println!("Unknown frame allocator backend {:?}, expected \"area\", \"bitmap\", or \"buddy\"", arg);
+                return -1;
+            }
+        }
+    }
+    if kinds.is_empty() {
+        kinds.extend_from_slice(&ALL_KINDS);
+    }
+
+    let mut failures = 0;
+    for kind in kinds {
+        for (name, test) in TESTS.iter() {
+            let result = new_backend(kind).and_then(|mut backend| test(&mut *backend));
+            match result {
+                Ok(_) => println!("[{}] {}: passed", kind.name(), name),
+                Err(e) => {
+                    println!("[{}] {}: FAILED: {}", kind.name(), name, e);
+                    error!("test_frame_allocators: [{}] {} failed: {}", kind.name(), name, e);
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    if failures == 0 {
+        println!("All frame allocator conformance tests passed.");
+        0
+    } else {
+        println!("{} frame allocator conformance tests failed.", failures);
+        -1
+    }
+}
+
+
+fn frame_range((start, len): (usize, usize)) -> FrameRange {
+    FrameRange::new(Frame { number: start }, Frame { number: start + len - 1 })
+}
+
+fn area((start, len): (usize, usize)) -> Result<PhysicalMemoryArea, &'static str> {
+    Ok(PhysicalMemoryArea::new(PhysicalAddress::new(start * PAGE_SIZE)?, len * PAGE_SIZE, 1, 0))
+}
+
+/// Creates a new backend of the given `kind` in which only the frames in `INITIAL_RANGES` are free.
+fn new_backend(kind: FrameAllocatorKind) -> Result<Box<dyn FrameAllocatorBackend>, &'static str> {
+    let ranges: Vec<FrameRange> = INITIAL_RANGES.iter().map(|r| frame_range(*r)).collect();
+    let backend: Box<dyn FrameAllocatorBackend> = match kind {
+        FrameAllocatorKind::Area => {
+            let mut available = [PhysicalMemoryArea::default(); 4];
+            for (i, r) in INITIAL_RANGES.iter().enumerate() {
+                available[i] = area(*r)?;
+            }
+            let occupied = [PhysicalMemoryArea::default(); 4];
+            let mut fa = AreaFrameAllocator::<4>::new(available, INITIAL_RANGES.len(), occupied, 0)?;
+            fa.alloc_ready();
+            Box::new(fa)
+        }
+        FrameAllocatorKind::Bitmap => {
+            let bounds = FrameRange::new(Frame { number: INITIAL_RANGES[0].0 }, Frame { number: BITMAP_END });
+            Box::new(BitmapFrameAllocator::new(bounds, &ranges)?)
+        }
+        FrameAllocatorKind::Buddy => Box::new(BuddyFrameAllocator::new(&ranges)),
+    };
+    Ok(backend)
+}
+
+/// Returns whether the given `frame` is one that a backend may hand out.
+fn is_managed(frame: Frame) -> bool {
+    INITIAL_RANGES.iter().chain(core::iter::once(&HOTPLUG_RANGE))
+        .any(|&(start, len)| frame.number >= start && frame.number < start + len)
+}
+
+/// Allocates single frames until the given `backend` runs out of memory, checking that every frame is unique
+/// and that the free frame count decreases accordingly. Returns the allocated frames.
+fn allocate_all(backend: &mut dyn FrameAllocatorBackend) -> Result<Vec<Frame>, &'static str> {
+    let mut frames = BTreeSet::new();
+    loop {
+        let free_before = backend.free_frame_count();
+        match backend.allocate_frame() {
+            Ok(frame) => {
+                if !is_managed(frame) {
+                    return Err("allocated a frame outside of the managed ranges");
+                }
+                if !frames.insert(frame) {
+                    return Err("allocated the same frame twice");
+                }
+                if backend.free_frame_count() + 1 != free_before {
+                    return Err("free frame count did not decrease by one after allocating a frame");
+                }
+                if frames.len() > INITIAL_FRAMES + HOTPLUG_RANGE.1 {
+                    return Err("allocated more frames than the backend manages");
+                }
+            }
+            Err(FrameAllocError::OutOfMemory) => break,
+            Err(_) => return Err("allocate_frame() failed with an error other than OutOfMemory"),
+        }
+    }
+    if backend.free_frame_count() != 0 {
+        return Err("ran out of memory, but the free frame count is not zero");
+    }
+    Ok(frames.into_iter().collect())
+}
+
+fn deallocate_all<I: IntoIterator<Item = Frame>>(backend: &mut dyn FrameAllocatorBackend, frames: I) {
+    for frame in frames {
+        backend.deallocate_frame(frame);
+    }
+}
+
+
+fn test_initial_state(backend: &mut dyn FrameAllocatorBackend) -> Result<(), &'static str> {
+    if backend.free_frame_count() != INITIAL_FRAMES {
+        return Err("initial free frame count is wrong");
+    }
+    let runs = backend.free_runs();
+    let expected: Vec<FrameRange> = INITIAL_RANGES.iter().map(|r| frame_range(*r)).collect();
+    if runs.len() != expected.len() || runs.iter().zip(expected.iter()).any(|(a, b)| a.start() != b.start() || a.end() != b.end()) {
+        return Err("initial free runs don't match the initial ranges");
+    }
+    match backend.highest_frame() {
+        Some(highest) if highest >= *expected[1].end() => Ok(()),
+        _ => Err("highest frame is below the highest free frame"),
+    }
+}
+
+fn test_single_frames(backend: &mut dyn FrameAllocatorBackend) -> Result<(), &'static str> {
+    let frames = allocate_all(backend)?;
+    if frames.len() != INITIAL_FRAMES {
+        return Err("couldn't allocate every free frame");
+    }
+    if backend.allocate_frames(2).err() != Some(FrameAllocError::OutOfMemory) {
+        return Err("allocate_frames() did not report OutOfMemory with no free frames");
+    }
+    deallocate_all(backend, frames);
+    if backend.free_frame_count() != INITIAL_FRAMES {
+        return Err("free frame count was not restored after deallocating every frame");
+    }
+    // Every deallocated frame must be reusable.
+    if allocate_all(backend)?.len() != INITIAL_FRAMES {
+        return Err("couldn't reallocate every deallocated frame");
+    }
+    Ok(())
+}
+
+fn test_contiguous_frames(backend: &mut dyn FrameAllocatorBackend) -> Result<(), &'static str> {
+    let mut allocated: Vec<FrameRange> = Vec::new();
+    for &num_frames in [16, 64, 1, 100].iter() {
+        let free_before = backend.free_frame_count();
+        let frames = backend.allocate_frames(num_frames).map_err(|_e| "couldn't allocate contiguous frames")?;
+        if frames.size_in_frames() != num_frames {
+            return Err("allocated the wrong number of contiguous frames");
+        }
+        if !is_managed(*frames.start()) || !is_managed(*frames.end()) || frames.clone().into_iter().any(|f| !is_managed(f)) {
+            return Err("allocated contiguous frames outside of the managed ranges");
+        }
+        if allocated.iter().any(|r| r.start() <= frames.end() && frames.start() <= r.end()) {
+            return Err("allocated contiguous frames that overlap previously-allocated ones");
+        }
+        if backend.free_frame_count() + num_frames != free_before {
+            return Err("free frame count did not decrease by the number of contiguous frames allocated");
+        }
+        allocated.push(frames);
+    }
+    deallocate_all(backend, allocated.into_iter().flat_map(|r| r.into_iter()));
+    if backend.free_frame_count() != INITIAL_FRAMES {
+        return Err("free frame count was not restored after deallocating the contiguous frames");
+    }
+    if allocate_all(backend)?.len() != INITIAL_FRAMES {
+        return Err("couldn't reallocate every deallocated frame");
+    }
+    Ok(())
+}
+
+fn test_invalid_request(backend: &mut dyn FrameAllocatorBackend) -> Result<(), &'static str> {
+    if backend.allocate_frames(0).err() != Some(FrameAllocError::InvalidRequest) {
+        return Err("allocating zero frames did not report InvalidRequest");
+    }
+    if backend.free_frame_count() != INITIAL_FRAMES {
+        return Err("free frame count changed after an invalid request");
+    }
+    Ok(())
+}
+
+fn test_fragmented(backend: &mut dyn FrameAllocatorBackend) -> Result<(), &'static str> {
+    // There are enough free frames in total, but no free run is this large.
+    let num_frames = INITIAL_FRAMES - 20;
+    match backend.allocate_frames(num_frames) {
+        Err(FrameAllocError::Fragmented { largest_run }) if largest_run > 0 && largest_run < num_frames => Ok(()),
+        Err(FrameAllocError::Fragmented { .. }) => Err("Fragmented error reported an invalid largest run"),
+        Ok(_) => Err("allocated more contiguous frames than the largest free run"),
+        Err(_) => Err("allocating more contiguous frames than the largest free run did not report Fragmented"),
+    }
+}
+
+fn test_remove_and_add_frames(backend: &mut dyn FrameAllocatorBackend) -> Result<(), &'static str> {
+    let removed = frame_range((INITIAL_RANGES[0].0, 32));
+    if backend.remove_free_frames(&removed) != 32 {
+        return Err("remove_free_frames() didn't remove the right number of free frames");
+    }
+    if backend.free_frame_count() != INITIAL_FRAMES - 32 {
+        return Err("free frame count did not decrease after removing free frames");
+    }
+    if backend.remove_free_frames(&removed) != 0 {
+        return Err("remove_free_frames() removed frames that were no longer free");
+    }
+
+    let frames = allocate_all(backend)?;
+    if frames.iter().any(|f| f >= removed.start() && f <= removed.end()) {
+        return Err("allocated a frame that was removed");
+    }
+    if frames.len() != INITIAL_FRAMES - 32 {
+        return Err("couldn't allocate every remaining free frame");
+    }
+    deallocate_all(backend, frames);
+
+    let hotplugged = frame_range(HOTPLUG_RANGE);
+    backend.add_free_frames(&hotplugged)?;
+    if backend.free_frame_count() != INITIAL_FRAMES - 32 + HOTPLUG_RANGE.1 {
+        return Err("free frame count did not increase after adding free frames");
+    }
+    if !backend.free_runs().iter().any(|r| r.start() <= hotplugged.start() && r.end() >= hotplugged.end()) {
+        return Err("added frames are not among the free runs");
+    }
+    let frames = allocate_all(backend)?;
+    if frames.iter().filter(|f| *f >= hotplugged.start() && *f <= hotplugged.end()).count() != HOTPLUG_RANGE.1 {
+        return Err("couldn't allocate every added frame");
+    }
+    Ok(())
+}
+
+/// Adapts a frame allocator backend to the stress test harness, which deals in frame numbers.
+struct BackendStressTarget<'b>(&'b mut dyn FrameAllocatorBackend);
+
+impl<'b> StressTarget for BackendStressTarget<'b> {
+    fn allocate_frame(&mut self) -> Option<usize> {
+        self.0.allocate_frame().ok().map(|f| f.number)
+    }
+
+    fn allocate_frames(&mut self, num_frames: usize) -> Option<Range<usize>> {
+        self.0.allocate_frames(num_frames).ok().map(|r| r.start().number .. r.end().number + 1)
+    }
+
+    fn deallocate_frame(&mut self, frame: usize) {
+        self.0.deallocate_frame(Frame { number: frame })
+    }
+
+    fn free_frame_count(&self) -> usize {
+        self.0.free_frame_count()
+    }
+}
+
+fn test_randomized_stress(backend: &mut dyn FrameAllocatorBackend) -> Result<(), &'static str> {
+    let managed: Vec<Range<usize>> = INITIAL_RANGES.iter().map(|&(start, len)| start .. start + len).collect();
+    let mut target = BackendStressTarget(backend);
+    // The harness leaves every frame deallocated, so each run starts from the initial state again.
+    for &seed in STRESS_SEEDS.iter() {
+        let config = StressConfig { seed, steps: STRESS_STEPS, max_contiguous: 48, managed: &managed };
+        match frame_allocator_stress::run(&mut target, &config) {
+            Ok(stats) => debug!("test_frame_allocators: stress run with seed {:#X}: {:?}", seed, stats),
+            Err(failure) => {
+                error!("test_frame_allocators: stress run failed: {}", failure);
+                return Err(failure.reason);
+            }
+        }
+    }
+    Ok(())
+}
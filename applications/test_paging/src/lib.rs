@@ -0,0 +1,188 @@
+//! Checks the `Mapper` against the software page table model of the `page_table_model` crate.
+//!
+//! This maps a scratch region that spans two 2MiB huge pages with the `Mapper`, and then remaps, splits, and unmaps
+//! parts of it, performing the same operations on a `PageTableModel` and recording their intended effects
+//! in `ExpectedMappings`. After every step, the live page table's mappings of the scratch region are walked and
+//! checked against the expected mappings, i.e., every page's frame and effective flags,
+//! and against the model, i.e., which pages are mapped by huge pages.
+//!
+//! Usage: `test_paging`
+
+#![no_std]
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate terminal_print;
+extern crate alloc;
+extern crate memory;
+extern crate kernel_config;
+extern crate page_table_model;
+
+use core::ops::{DerefMut, Range};
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use kernel_config::memory::{MAP_HUGE_PAGES, PAGE_SHIFT, PAGE_SIZE};
+use memory::{AllocatedPages, EntryFlags, Frame, FrameRange, Mapper, PageRange, VirtualAddress, get_frame_allocator_ref, get_kernel_mmi_ref};
+use page_table_model::{ExpectedMappings, PageSize, PageTableAccess, PageTableModel};
+
+
+/// The number of pages in the scratch region.
+const NUM_PAGES: usize = 1024;
+/// The number of regular pages in one 2MiB huge page.
+const HUGE_PAGE_PAGES: usize = 512;
+
+
+pub fn main(_args: Vec<String>) -> isize {
+    match rmain() {
+        Ok(_) => {
+            println!("All paging tests passed.");
+            0
+        }
+        Err(e) => {
+            println!("Paging test failed: {}", e);
+            error!("test_paging: {}", e);
+            -1
+        }
+    }
+}
+
+fn rmain() -> Result<(), &'static str> {
+    let allocator = get_frame_allocator_ref().ok_or("couldn't get the frame allocator")?;
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("couldn't get kernel_mmi_ref")?;
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+    let mapper: &mut Mapper = &mut kernel_mmi.page_table;
+
+    let pages = allocate_aligned_pages()?;
+    let frames = allocate_aligned_frames()?;
+    let first_page = *pages.start();
+    let vaddr = first_page.start_address().value() as u64;
+    let paddr = frames.start().start_address().value() as u64;
+    let page_vaddr = |i: usize| vaddr + (i * PAGE_SIZE) as u64;
+    let range = vaddr .. page_vaddr(NUM_PAGES);
+
+    let mut model = PageTableModel::new(if MAP_HUGE_PAGES { PageSize::Size2MiB } else { PageSize::Size4KiB });
+    let mut expected = ExpectedMappings::new();
+    // Earlier mappings of the scratch region may have left page tables behind, which prevent the use of huge pages.
+    for i in 0 .. NUM_PAGES / HUGE_PAGE_PAGES {
+        let huge_page_vaddr = page_vaddr(i * HUGE_PAGE_PAGES);
+        let p2_entry = page_table_model::entry_at(&LivePageTable(mapper), huge_page_vaddr, 2).unwrap_or(0);
+        if p2_entry & page_table_model::PRESENT != 0 && p2_entry & page_table_model::HUGE_PAGE == 0 {
+            model.create_page_tables(huge_page_vaddr, 1, page_table_model::WRITABLE)?;
+        }
+    }
+
+    let writable = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    let executable = EntryFlags::PRESENT;
+
+    // Note that the frame allocator must be unlocked before any `MappedPages` is dropped.
+    let result = {
+        let mut fa = allocator.lock();
+        mapper.map_allocated_pages_to(pages, frames, writable, fa.deref_mut())
+    };
+    let mapped = result?;
+    model.map(vaddr, paddr, NUM_PAGES as u64, writable.bits())?;
+    expected.map(vaddr, paddr, NUM_PAGES as u64, writable.bits());
+    check("map", mapper, &model, &expected, range.clone())?;
+
+    let result = {
+        let mut fa = allocator.lock();
+        mapped.remap_range(PageRange::new(first_page + 100, first_page + 199), executable, mapper, fa.deref_mut())
+    };
+    let (before, middle, after) = result.map_err(|(e, _mp)| e)?;
+    model.remap(page_vaddr(100), 100, executable.bits())?;
+    expected.remap(page_vaddr(100), 100, executable.bits());
+    check("remap_range", mapper, &model, &expected, range.clone())?;
+
+    let after = after.ok_or("remap_range() didn't return the pages after the remapped pages")?;
+    let result = {
+        let mut fa = allocator.lock();
+        after.split(first_page + 700, mapper, fa.deref_mut())
+    };
+    let (low, high) = result.map_err(|(e, _mp)| e)?;
+    model.split_huge_page(page_vaddr(700), true)?;
+    check("split", mapper, &model, &expected, range.clone())?;
+
+    drop(high);
+    model.unmap(page_vaddr(700), (NUM_PAGES - 700) as u64)?;
+    expected.unmap(page_vaddr(700), (NUM_PAGES - 700) as u64);
+    check("unmap", mapper, &model, &expected, range.clone())?;
+
+    let mut middle = middle;
+    middle.remap(mapper, writable)?;
+    model.remap(page_vaddr(100), 100, writable.bits())?;
+    expected.remap(page_vaddr(100), 100, writable.bits());
+    check("remap", mapper, &model, &expected, range.clone())?;
+
+    drop(before);
+    drop(middle);
+    drop(low);
+    model.unmap(vaddr, 700)?;
+    expected.unmap(vaddr, 700);
+    check("unmap all", mapper, &model, &expected, range)
+}
+
+
+/// Checks the live page table's mappings of the given `range` against the expected mappings and the model.
+fn check(step: &str, mapper: &Mapper, model: &PageTableModel, expected: &ExpectedMappings, range: Range<u64>) -> Result<(), &'static str> {
+    let live = LivePageTable(mapper);
+    let result = expected.check(&live, range.clone())
+        .and_then(|_| page_table_model::compare(&live, model, range));
+    match result {
+        Ok(_) => {
+            println!("{}: passed", step);
+            Ok(())
+        }
+        Err(e) => {
+            println!("{}: FAILED: {}", step, e);
+            Err(e.reason)
+        }
+    }
+}
+
+/// Allocates `NUM_PAGES` pages that start at a 2MiB boundary, such that they can be mapped by huge pages.
+fn allocate_aligned_pages() -> Result<AllocatedPages, &'static str> {
+    let pages = memory::allocate_pages(NUM_PAGES + HUGE_PAGE_PAGES - 1).ok_or("couldn't allocate pages")?;
+    let misalignment = pages.start().p1_index();
+    let pages = if misalignment == 0 {
+        pages
+    } else {
+        let aligned_page = *pages.start() + (HUGE_PAGE_PAGES - misalignment);
+        pages.split(aligned_page).ok_or("BUG: couldn't split the allocated pages")?.1
+    };
+    let end_page = *pages.start() + NUM_PAGES;
+    Ok(pages.split(end_page).ok_or("BUG: couldn't split the allocated pages")?.0)
+}
+
+/// Allocates `NUM_PAGES` frames that start at a 2MiB boundary, such that they can be mapped by huge pages.
+fn allocate_aligned_frames() -> Result<FrameRange, &'static str> {
+    let frames = memory::allocate_frames(NUM_PAGES + HUGE_PAGE_PAGES - 1).map_err(|_e| "couldn't allocate frames")?;
+    let start = frames.start().number;
+    let aligned_start = start + (HUGE_PAGE_PAGES - start % HUGE_PAGE_PAGES) % HUGE_PAGE_PAGES;
+    let aligned_end = aligned_start + NUM_PAGES;
+    for number in (start .. aligned_start).chain(aligned_end ..= frames.end().number) {
+        memory::deallocate_frame(Frame { number });
+    }
+    Ok(FrameRange::new(Frame { number: aligned_start }, Frame { number: aligned_end - 1 }))
+}
+
+
+/// Reads the active page table through its recursive P4 entry,
+/// which maps each page table at a virtual address that is derived from its parent table's virtual address.
+struct LivePageTable<'m>(&'m Mapper);
+
+impl<'m> PageTableAccess for LivePageTable<'m> {
+    type Table = usize;
+
+    fn root(&self) -> usize {
+        self.0.p4() as *const _ as usize
+    }
+
+    fn entry(&self, table: usize, index: usize) -> u64 {
+        unsafe { core::ptr::read_volatile((table as *const u64).add(index)) }
+    }
+
+    fn next_table(&self, table: usize, index: usize) -> usize {
+        VirtualAddress::new_canonical((table << 9) | (index << PAGE_SHIFT)).value()
+    }
+}
@@ -0,0 +1,106 @@
+//! Tests the RCU primitive by running several reader tasks concurrently with a writer task.
+//! 
+//! Every version of the shared data is a list whose elements are all equal,
+//! so a reader that ever observes a list with differing elements has seen a torn or reclaimed version.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+#[macro_use] extern crate terminal_print;
+extern crate apic;
+extern crate spawn;
+extern crate rcu;
+extern crate task;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::{
+    vec::Vec,
+    string::String,
+    sync::Arc,
+};
+use rcu::Rcu;
+
+
+const NUM_READERS: usize = 4;
+const NUM_UPDATES: usize = 1000;
+const LIST_LENGTH: usize = 64;
+
+
+pub fn main(_args: Vec<String>) -> isize {    
+    match rmain() {
+        Ok(_) => {
+            println!("RCU test passed.");
+            0
+        }
+        Err(e) => {
+            println!("RCU test failed: {}", e); 
+            error!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain() -> Result<(), &'static str> {
+    let cores: Vec<u8> = apic::get_lapics().iter().map(|(apic_id, _lapic)| *apic_id).collect();
+    let data = Arc::new(Rcu::new(vec![0usize; LIST_LENGTH]));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let mut readers = Vec::with_capacity(NUM_READERS);
+    for i in 0..NUM_READERS {
+        let reader = spawn::new_task_builder(reader_task, (data.clone(), done.clone()))
+            .name(format!("rcu_reader_{}", i))
+            .pin_on_core(cores[i % cores.len()])
+            .spawn()?;
+        readers.push(reader);
+    }
+
+    for version in 1..=NUM_UPDATES {
+        data.update(|_old| vec![version; LIST_LENGTH]);
+    }
+    done.store(true, Ordering::SeqCst);
+
+    let mut result = Ok(());
+    for reader in readers {
+        reader.join()?;
+        match reader.take_exit_value() {
+            Some(task::ExitValue::Completed(exit_value)) => {
+                if let Some(Err(e)) = exit_value.downcast_ref::<Result<usize, &'static str>>() {
+                    result = Err(*e);
+                }
+            }
+            _ => result = Err("a reader task did not complete"),
+        }
+    }
+    result?;
+
+    let latest = data.read()[0];
+    if latest != NUM_UPDATES {
+        return Err("the final version did not contain the last update");
+    }
+    rcu::synchronize();
+    println!("Reclaimed {} more retired versions after synchronizing.", rcu::try_reclaim());
+    Ok(())
+}
+
+
+/// Repeatedly reads the shared data until the writer is done,
+/// returning the number of reads if every version it observed was consistent.
+fn reader_task((data, done): (Arc<Rcu<Vec<usize>>>, Arc<AtomicBool>)) -> Result<usize, &'static str> {
+    let mut reads = 0;
+    let mut last_version = 0;
+    while !done.load(Ordering::SeqCst) {
+        let list = data.read();
+        let version = list[0];
+        if list.iter().any(|&v| v != version) {
+            return Err("observed a version whose elements were not all equal");
+        }
+        if version < last_version {
+            return Err("observed an older version after a newer one");
+        }
+        last_version = version;
+        reads += 1;
+    }
+    Ok(reads)
+}
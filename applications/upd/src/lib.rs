@@ -34,7 +34,7 @@ use alloc::{
 };
 use spin::Once;
 use getopts::{Matches, Options};
-use network_manager::{NetworkInterfaceRef, NETWORK_INTERFACES};
+use network_manager::{routing, NetworkInterfaceRef, NETWORK_INTERFACES};
 use smoltcp::wire::IpEndpoint;
 use mod_mgmt::{
     CrateNamespace,
@@ -137,7 +137,7 @@ fn rmain(matches: Matches) -> Result<(), String> {
 /// Lists the set of crates in the given update_build,
 /// or if no update build is specified, lists all available update builds by default.
 fn list(remote_endpoint: IpEndpoint, update_build: Option<&String>) -> Result<(), String> {
-    let iface = get_default_iface()?;
+    let iface = get_iface_for(remote_endpoint)?;
 
     if let Some(ub) = update_build {
         let listing = ota_update_client::download_listing(&iface, remote_endpoint, &*ub)
@@ -155,7 +155,7 @@ fn list(remote_endpoint: IpEndpoint, update_build: Option<&String>) -> Result<()
 
 /// Lists the contents of the diff file for the given update build.
 fn diff(remote_endpoint: IpEndpoint, update_build: &str) -> Result<(), String> {
-    let iface = get_default_iface()?;
+    let iface = get_iface_for(remote_endpoint)?;
 
     let file_str = ota_update_client::download_diff(&iface, remote_endpoint, update_build)
         .map_err(|e| e.to_string())?;
@@ -167,7 +167,7 @@ fn diff(remote_endpoint: IpEndpoint, update_build: &str) -> Result<(), String> {
 
 /// Downloads all of the new or changed crates from the `diff` file of the 
 fn download(remote_endpoint: IpEndpoint, update_build: &str, crate_list: Option<&[String]>) -> Result<(), String> {
-    let iface = get_default_iface()?;
+    let iface = get_iface_for(remote_endpoint)?;
     println!("Downloading crates...");
     let crate_list = if crate_list == Some(&[]) { None } else { crate_list };
 
@@ -297,13 +297,12 @@ fn get_my_current_namespace() -> Arc<CrateNamespace> {
 }
 
 
-/// Returns the first network interface available in the system.
-fn get_default_iface() -> Result<NetworkInterfaceRef, String> {
-    NETWORK_INTERFACES.lock()
-        .iter()
-        .next()
-        .cloned()
-        .ok_or_else(|| format!("no network interfaces available"))
+/// Returns the network interface that the routing table chooses for reaching the given update server.
+fn get_iface_for(remote_endpoint: IpEndpoint) -> Result<NetworkInterfaceRef, String> {
+    if NETWORK_INTERFACES.lock().is_empty() {
+        return Err(format!("no network interfaces available"));
+    }
+    routing::interface_for(remote_endpoint.addr).ok_or_else(|| format!("no route to {}", remote_endpoint.addr))
 }
 
 
@@ -0,0 +1,132 @@
+//! This application lists all mappings of the current page table:
+//! each virtual address range with the physical range it's mapped to, its flags, and its page size.
+//! It can also list only the mappings that are both writable and executable, i.e., that violate W^X.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate memory;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use memory::{EntryFlags, HugeSize, MappingRegion};
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("w", "wx", "list only mappings that are both writable and executable");
+    opts.optflag("u", "user", "list only user-accessible mappings");
+    opts.optopt("a", "address", "list only the mapping that contains this virtual address (in hexadecimal)", "VADDR");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let address = match matches.opt_str("a") {
+        Some(a) => Some(usize::from_str_radix(a.trim_start_matches("0x"), 16).map_err(|_| format!("invalid address {:?}", a))?),
+        None => None,
+    };
+
+    // Walk the page table while holding the lock, but print afterwards.
+    let regions = {
+        let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("couldn't get kernel_mmi_ref")?;
+        let kernel_mmi = kernel_mmi_ref.lock();
+        kernel_mmi.page_table.mappings()
+    };
+
+    let total_bytes: usize = regions.iter().map(|r| r.size_in_bytes()).sum();
+    let wx_count = regions.iter().filter(|r| r.is_writable_and_executable()).count();
+    let selected: Vec<&MappingRegion> = regions.iter()
+        .filter(|r| !matches.opt_present("w") || r.is_writable_and_executable())
+        .filter(|r| !matches.opt_present("u") || r.flags.contains(EntryFlags::USER_ACCESSIBLE))
+        .filter(|r| address.map_or(true, |a| a.wrapping_sub(r.start_vaddr.value()) < r.size_in_bytes()))
+        .collect();
+
+    println!("{:<37} {:<33} {:<6} {:>5} {:>10}", "VIRTUAL RANGE", "PHYSICAL RANGE", "FLAGS", "PAGE", "SIZE");
+    for region in selected.iter() {
+        let size = region.size_in_bytes();
+        println!("{:#018X}-{:#018X} {:#014X}-{:#014X}    {:<6} {:>5} {:>10}",
+            region.start_vaddr.value(),
+            region.start_vaddr.value().wrapping_add(size - 1),
+            region.start_paddr.value(),
+            region.start_paddr.value() + size - 1,
+            format_flags(region.flags),
+            match region.huge {
+                Some(HugeSize::Size1GiB) => "1G",
+                Some(HugeSize::Size2MiB) => "2M",
+                None => "4K",
+            },
+            format_size(size),
+        );
+    }
+    println!("{} of {} mappings shown, {} mapped in total.", selected.len(), regions.len(), format_size(total_bytes));
+    if wx_count == 0 {
+        println!("No mappings are both writable and executable.");
+    } else {
+        println!("WARNING: {} mappings are both writable and executable!", wx_count);
+    }
+    Ok(())
+}
+
+/// Formats flags like `rwx-ug`: readable (always), writable, executable, no-cache, user-accessible, global.
+fn format_flags(flags: EntryFlags) -> String {
+    let bit = |set: bool, c: char| if set { c } else { '-' };
+    let mut s = String::with_capacity(6);
+    s.push('r');
+    s.push(bit(flags.is_writable(), 'w'));
+    s.push(bit(flags.is_executable(), 'x'));
+    s.push(bit(flags.contains(EntryFlags::NO_CACHE), 'c'));
+    s.push(bit(flags.contains(EntryFlags::USER_ACCESSIBLE), 'u'));
+    s.push(bit(!EntryFlags::GLOBAL.is_empty() && flags.contains(EntryFlags::GLOBAL), 'g'));
+    s
+}
+
+fn format_size(bytes: usize) -> String {
+    const KIB: usize = 1024;
+    if bytes >= KIB * KIB * KIB && bytes % (KIB * KIB * KIB) == 0 {
+        format!("{} GiB", bytes / (KIB * KIB * KIB))
+    } else if bytes >= KIB * KIB && bytes % (KIB * KIB) == 0 {
+        format!("{} MiB", bytes / (KIB * KIB))
+    } else {
+        format!("{} KiB", bytes / KIB)
+    }
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: vmmap [-w] [-u] [-a VADDR]
+Lists all mappings of the current page table with their effective flags,
+in which `c` means no-cache, `u` means user-accessible, and `g` means global.
+The recursive page-table mapping is omitted.";
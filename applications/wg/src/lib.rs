@@ -0,0 +1,161 @@
+//! This application creates WireGuard tunnels, shows their state, and generates keys for them.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate terminal_print;
+
+extern crate getopts;
+extern crate smoltcp;
+extern crate wireguard;
+
+use core::str::FromStr;
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use getopts::{Options, Matches};
+use smoltcp::wire::{IpCidr, IpEndpoint, Ipv4Address};
+use wireguard::TunnelConfig;
+
+
+pub fn main(args: Vec<String>) -> isize {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optopt("k", "key", "with `up`, the local private key", "KEY");
+    opts.optopt("p", "peer", "with `up`, the peer's public key", "KEY");
+    opts.optopt("a", "address", "with `up`, the local address and subnet inside the tunnel, e.g., 10.0.0.2/24", "CIDR");
+    opts.optopt("e", "endpoint", "with `up`, the peer's UDP endpoint, e.g., 192.168.1.10:51820", "IP:PORT");
+    opts.optopt("l", "listen-port", "with `up`, the local UDP port (default: a free port)", "PORT");
+    opts.optopt("g", "gateway", "with `up`, the peer's address inside the tunnel, for allowed IPs outside of the subnet", "IP");
+    opts.optmulti("", "allowed", "with `up`, a subnet that is reachable through the peer (default: the tunnel's subnet)", "CIDR");
+    opts.optopt("", "psk", "with `up`, a preshared key", "KEY");
+    opts.optopt("", "keepalive", "with `up`, send a keepalive after this many idle seconds", "SECS");
+
+    let matches = match opts.parse(&args) {
+        Ok(m) => m,
+        Err(_f) => {
+            println!("{}", _f);
+            print_usage(opts);
+            return -1; 
+        }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        print_usage(opts);
+        return 0;
+    }
+
+    match rmain(matches) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e); 
+            -1
+        }
+    }
+}
+
+
+fn rmain(matches: Matches) -> Result<(), String> {
+    let command = matches.free[0].as_str();
+    let args = &matches.free[1..];
+
+    match command {
+        "genkey" => println!("{}", wireguard::format_key(&wireguard::generate_private_key())),
+        "pubkey" => {
+            let private_key = wireguard::parse_key(args.get(0).ok_or_else(|| format!("missing KEY argument"))?)?;
+            println!("{}", wireguard::format_key(&wireguard::public_key(&private_key)));
+        }
+        "up" => {
+            let config = parse_config(&matches)?;
+            let public_key = wireguard::public_key(&config.private_key);
+            wireguard::create_tunnel(config)?;
+            println!("Created tunnel with public key {}", wireguard::format_key(&public_key));
+        }
+        "show" => {
+            let tunnels = wireguard::tunnels();
+            if tunnels.is_empty() {
+                println!("No tunnels");
+            }
+            for tunnel in tunnels {
+                println!("tunnel {}:", tunnel.address);
+                println!("  public key: {}", wireguard::format_key(&tunnel.public_key));
+                println!("  listening port: {}", tunnel.listen_port);
+                match tunnel.peer_endpoint {
+                    Some(endpoint) => println!("  peer endpoint: {}", endpoint),
+                    None => println!("  peer endpoint: (unknown)"),
+                }
+                match tunnel.seconds_since_handshake {
+                    Some(secs) => println!("  latest handshake: {} seconds ago", secs),
+                    None => println!("  latest handshake: (none)"),
+                }
+                println!("  transfer: {} bytes received, {} bytes sent", tunnel.rx_bytes, tunnel.tx_bytes);
+            }
+        }
+        _ => return Err(format!("unknown command {:?}", command)),
+    }
+    Ok(())
+}
+
+
+/// Builds the configuration of a new tunnel from the options given to the `up` command.
+fn parse_config(matches: &Matches) -> Result<TunnelConfig, String> {
+    let required = |name: &str| matches.opt_str(name).ok_or_else(|| format!("missing option --{}", name));
+
+    let private_key = wireguard::parse_key(&required("key")?)?;
+    let peer_public_key = wireguard::parse_key(&required("peer")?)?;
+    let preshared_key = match matches.opt_str("psk") {
+        Some(psk) => Some(wireguard::parse_key(&psk)?),
+        None => None,
+    };
+    let address_str = required("address")?;
+    let address = IpCidr::from_str(&address_str).map_err(|_| format!("invalid address {:?}", address_str))?;
+    let peer_endpoint = match matches.opt_str("endpoint") {
+        Some(e) => Some(IpEndpoint::from_str(&e).map_err(|_| format!("invalid endpoint {:?}", e))?),
+        None => None,
+    };
+    let peer_address = match matches.opt_str("gateway") {
+        Some(g) => Some(Ipv4Address::from_str(&g).map_err(|_| format!("invalid gateway {:?}", g))?),
+        None => None,
+    };
+    let listen_port = match matches.opt_str("listen-port") {
+        Some(p) => p.parse().map_err(|_| format!("invalid port {:?}", p))?,
+        None => 0,
+    };
+    let persistent_keepalive_secs = match matches.opt_str("keepalive") {
+        Some(s) => Some(s.parse().map_err(|_| format!("invalid keepalive interval {:?}", s))?),
+        None => None,
+    };
+    let mut allowed_ips = Vec::new();
+    for cidr in matches.opt_strs("allowed") {
+        allowed_ips.push(IpCidr::from_str(&cidr).map_err(|_| format!("invalid allowed IP subnet {:?}", cidr))?);
+    }
+    if allowed_ips.is_empty() {
+        allowed_ips.push(IpCidr::new(address.address(), address.prefix_len()));
+    }
+
+    Ok(TunnelConfig {
+        private_key,
+        listen_port,
+        address,
+        peer_public_key,
+        preshared_key,
+        peer_endpoint,
+        peer_address,
+        allowed_ips,
+        persistent_keepalive_secs,
+    })
+}
+
+
+fn print_usage(opts: Options) {
+    println!("{}", opts.usage(USAGE));
+}
+
+
+const USAGE: &'static str = "Usage: wg genkey
+       wg pubkey KEY
+       wg up -k KEY -p PEER_KEY -a CIDR [-e IP:PORT] [OPTIONS]
+       wg show
+Creates an encrypted tunnel to a WireGuard peer, e.g., the development host, or shows the existing tunnels.
+Keys are given in base64, as produced by `wg genkey` here or on the peer.";
@@ -27,6 +27,8 @@ extern crate rsdp;
 extern crate rsdt;
 extern crate fadt;
 extern crate madt;
+extern crate srat;
+extern crate acpi_aml;
 
 
 use alloc::vec::Vec;
@@ -81,10 +83,14 @@ pub fn init(page_table: &mut PageTable) -> Result<(), &'static str> {
 
     // FADT is mandatory, and contains the address of the DSDT
     {
-        let acpi_tables = ACPI_TABLES.lock();
-        let _fadt = fadt::Fadt::get(&acpi_tables).ok_or("The required FADT APIC table wasn't found (signature 'FACP')")?;
-        // here: do something with the DSDT here, when needed.
-        // debug!("DSDT physical address: {:#X}", fadt.dsdt);
+        let mut acpi_tables = ACPI_TABLES.lock();
+        let dsdt_phys_addr = {
+            let fadt = fadt::Fadt::get(&acpi_tables).ok_or("The required FADT APIC table wasn't found (signature 'FACP')")?;
+            PhysicalAddress::new(fadt.dsdt as usize)?
+        };
+        debug!("DSDT physical address: {:#X}", dsdt_phys_addr);
+        let (sdt_signature, sdt_total_length) = acpi_tables.map_new_table(dsdt_phys_addr, page_table)?;
+        acpi_table_handler(&mut acpi_tables, sdt_signature, sdt_total_length, dsdt_phys_addr)?;
     }
     
     // HPET is optional, but usually present.
@@ -104,5 +110,24 @@ pub fn init(page_table: &mut PageTable) -> Result<(), &'static str> {
         madt.bsp_init(page_table)?;
     }
 
+    // SRAT is optional, and only exists on machines with multiple NUMA nodes.
+    {
+        let acpi_tables = ACPI_TABLES.lock();
+        if let Some(srat) = srat::Srat::get(&acpi_tables) {
+            memory::init_numa_nodes(srat.numa_nodes())?;
+        } else {
+            info!("This machine has no SRAT, so NUMA-aware frame allocation is disabled.");
+        }
+    }
+
     Ok(())
 }
+
+/// Loads the AML in the DSDT and SSDTs into the ACPI namespace, see the `acpi_aml` crate.
+///
+/// This must be invoked after [`init()`](fn.init.html), once the kernel's page table is no longer locked,
+/// because evaluating AML may map physical memory.
+pub fn init_aml() -> Result<(), &'static str> {
+    let acpi_tables = ACPI_TABLES.lock();
+    acpi_aml::init(&acpi_tables)
+}
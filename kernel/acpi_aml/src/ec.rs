@@ -0,0 +1,186 @@
+//! Support for the ACPI embedded controller (EC), which laptops use for things like hotkeys, battery status, and fans.
+//!
+//! The EC is a device in the ACPI namespace with the hardware ID `PNP0C09`,
+//! whose `_CRS` lists its data port followed by its command/status port.
+//! Its registers are accessed with the EC protocol over these two ports, see [`read()`] and [`write()`].
+//!
+//! When the EC has an event to report, e.g., a hotkey was pressed, it sets the `SCI_EVT` status bit
+//! and raises its GPE. Then, [`handle_event()`] queries the EC for the event's number `xx`,
+//! and evaluates the corresponding `_Qxx` method of the EC device, which is how the firmware handles the event.
+//!
+//! [`read()`]: fn.read.html
+//! [`write()`]: fn.write.html
+//! [`handle_event()`]: fn.handle_event.html
+
+use core::sync::atomic::spin_loop_hint;
+use aml::resource::Resource;
+use port_io::Port;
+use port_registry::{claim_ports, PortClaim};
+use spin::{Mutex, Once};
+use super::{aml_error, current_resources, evaluate, find_devices, path, AmlName};
+use aml::value::Args;
+
+
+/// The hardware ID of an embedded controller.
+const EC_HARDWARE_ID: &'static str = "PNP0C09";
+
+// The bits of the EC status register.
+/// The output buffer is full, i.e., the data port holds a byte for the host to read.
+const EC_STATUS_OBF: u8 = 1 << 0;
+/// The input buffer is full, i.e., the EC hasn't yet consumed the last byte the host wrote.
+const EC_STATUS_IBF: u8 = 1 << 1;
+/// The EC has an event pending, which must be queried with `EC_QUERY`.
+const EC_STATUS_SCI_EVT: u8 = 1 << 5;
+
+// The commands that can be written to the EC command port.
+const EC_READ: u8 = 0x80;
+const EC_WRITE: u8 = 0x81;
+const EC_QUERY: u8 = 0x84;
+
+/// How many times to poll the EC status register before giving up on the EC.
+const EC_TIMEOUT_ITERATIONS: usize = 1_000_000;
+
+static EMBEDDED_CONTROLLER: Once<Mutex<EmbeddedController>> = Once::new();
+
+
+/// The embedded controller device and its I/O ports.
+struct EmbeddedController {
+    /// The path of the EC device in the ACPI namespace, e.g., `\_SB.PCI0.LPCB.EC0`.
+    device: AmlName,
+    data: Port<u8>,
+    /// The command port when written, and the status port when read.
+    command: Port<u8>,
+    _data_claim: PortClaim,
+    _command_claim: PortClaim,
+}
+
+impl EmbeddedController {
+    fn status(&self) -> u8 {
+        self.command.read()
+    }
+
+    fn wait_for(&self, condition: fn(u8) -> bool) -> Result<(), &'static str> {
+        for _ in 0 .. EC_TIMEOUT_ITERATIONS {
+            if condition(self.status()) {
+                return Ok(());
+            }
+            spin_loop_hint();
+        }
+        Err("acpi_aml: timed out waiting for the embedded controller")
+    }
+
+    fn send_command(&self, command: u8) -> Result<(), &'static str> {
+        self.wait_for(|status| status & EC_STATUS_IBF == 0)?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    fn write_data(&self, value: u8) -> Result<(), &'static str> {
+        self.wait_for(|status| status & EC_STATUS_IBF == 0)?;
+        unsafe { self.data.write(value) };
+        Ok(())
+    }
+
+    fn read_data(&self) -> Result<u8, &'static str> {
+        self.wait_for(|status| status & EC_STATUS_OBF != 0)?;
+        Ok(self.data.read())
+    }
+
+    fn read(&self, address: u8) -> Result<u8, &'static str> {
+        self.send_command(EC_READ)?;
+        self.write_data(address)?;
+        self.read_data()
+    }
+
+    fn write(&self, address: u8, value: u8) -> Result<(), &'static str> {
+        self.send_command(EC_WRITE)?;
+        self.write_data(address)?;
+        self.write_data(value)?;
+        self.wait_for(|status| status & EC_STATUS_IBF == 0)
+    }
+
+    /// Returns the number of the EC's pending event, or `0` if there is none.
+    fn query(&self) -> Result<u8, &'static str> {
+        if self.status() & EC_STATUS_SCI_EVT == 0 {
+            return Ok(0);
+        }
+        self.send_command(EC_QUERY)?;
+        self.read_data()
+    }
+}
+
+
+/// Finds the embedded controller in the ACPI namespace and claims its I/O ports.
+///
+/// Returns an error if there is no EC, which is normal for desktops and virtual machines.
+pub(crate) fn init() -> Result<(), &'static str> {
+    let device = find_devices(EC_HARDWARE_ID)?.into_iter().next().ok_or("acpi_aml: no embedded controller was found")?;
+    if !super::is_present(&device)? {
+        return Err("acpi_aml: the embedded controller is not present");
+    }
+    let mut ports = current_resources(&device)?.into_iter().filter_map(|resource| match resource {
+        Resource::IOPort(descriptor) => Some(descriptor.memory_range.0),
+        _ => None,
+    });
+    let (data_port, command_port) = match (ports.next(), ports.next()) {
+        (Some(data), Some(command)) => (data, command),
+        _ => return Err("acpi_aml: the embedded controller's _CRS doesn't have two I/O ports"),
+    };
+
+    let data_claim = claim_ports(data_port, 1, "acpi_ec")?;
+    let command_claim = claim_ports(command_port, 1, "acpi_ec")?;
+    let ec = EmbeddedController {
+        device,
+        data: data_claim.port(0)?,
+        command: command_claim.port(0)?,
+        _data_claim: data_claim,
+        _command_claim: command_claim,
+    };
+    info!("Found embedded controller {:?} with data port {:#X} and command port {:#X}", ec.device, data_port, command_port);
+    EMBEDDED_CONTROLLER.call_once(|| Mutex::new(ec));
+    Ok(())
+}
+
+fn embedded_controller() -> Result<&'static Mutex<EmbeddedController>, &'static str> {
+    EMBEDDED_CONTROLLER.try().ok_or("acpi_aml: no embedded controller was initialized")
+}
+
+/// Returns whether an embedded controller was found.
+pub fn is_present() -> bool {
+    EMBEDDED_CONTROLLER.try().is_some()
+}
+
+/// Reads the EC register at the given `address`.
+pub fn read(address: u8) -> Result<u8, &'static str> {
+    embedded_controller()?.lock().read(address)
+}
+
+/// Writes the given `value` to the EC register at the given `address`.
+pub fn write(address: u8, value: u8) -> Result<(), &'static str> {
+    embedded_controller()?.lock().write(address, value)
+}
+
+/// Handles all of the EC's pending events by evaluating their `_Qxx` methods,
+/// and returns the number of events that were handled.
+///
+/// This should be invoked when the EC's GPE fires, and can also be polled.
+/// An event without a `_Qxx` method is logged and dropped.
+pub fn handle_event() -> Result<usize, &'static str> {
+    let mut handled = 0;
+    loop {
+        // Don't hold the EC lock while evaluating the event method, since it may access the EC itself.
+        let (event, device) = {
+            let ec = embedded_controller()?.lock();
+            (ec.query()?, ec.device.clone())
+        };
+        if event == 0 {
+            return Ok(handled);
+        }
+        let method = path(&format!("_Q{:02X}", event))?.resolve(&device).map_err(|e| aml_error("acpi_aml: invalid EC event method path", e))?;
+        match evaluate(&method, Args::default()) {
+            Ok(_) => trace!("acpi_aml: handled EC event {:#X} with {:?}", event, method),
+            Err(e) => warn!("acpi_aml: couldn't handle EC event {:#X}: {}", event, e),
+        }
+        handled += 1;
+    }
+}
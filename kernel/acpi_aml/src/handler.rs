@@ -0,0 +1,134 @@
+//! The hardware accesses that the AML interpreter performs on behalf of AML code,
+//! e.g., when a method reads or writes a field in a `SystemMemory`, `SystemIO`, or `PCI_Config` operation region.
+//!
+//! The `aml::Handler` methods can't fail, so failed accesses are logged, reads of them return `0`,
+//! and writes to them are dropped.
+
+use core::{mem::size_of, ops::DerefMut, ptr};
+use aml::Handler;
+use memory::{allocate_pages, get_frame_allocator_ref, get_kernel_mmi_ref, EntryFlags, FrameRange, MappedPages, PhysicalAddress};
+use pci::PciLocation;
+use port_io::{Port, PortIn, PortOut};
+use zerocopy::FromBytes;
+
+
+/// Performs the interpreter's hardware accesses directly, without any caching.
+pub struct AmlHandler;
+
+impl AmlHandler {
+    /// Temporarily maps the physical memory that holds a `T` at the given `address`.
+    ///
+    /// This creates a new uncached mapping for every access, which is slow,
+    /// but AML code rarely accesses memory outside of hot paths.
+    fn map_physical<T>(address: usize) -> Result<(MappedPages, usize), &'static str> {
+        let paddr = PhysicalAddress::new(address)?;
+        let frames = FrameRange::from_phys_addr(paddr, size_of::<T>());
+        let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("acpi_aml: KERNEL_MMI was not yet initialized")?;
+        let fa = get_frame_allocator_ref().ok_or("acpi_aml: couldn't get the frame allocator")?;
+        let pages = allocate_pages(frames.size_in_frames()).ok_or("acpi_aml: couldn't allocate pages")?;
+        let mp = kernel_mmi_ref.lock().page_table.map_allocated_pages_to(
+            pages,
+            frames,
+            EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE,
+            fa.lock().deref_mut(),
+        )?;
+        Ok((mp, paddr.frame_offset()))
+    }
+
+    fn read_memory<T: FromBytes + Copy + Default>(address: usize) -> T {
+        let result = Self::map_physical::<T>(address).and_then(|(mp, offset)| {
+            let value: &T = mp.as_type(offset)?;
+            Ok(unsafe { ptr::read_volatile(value) })
+        });
+        result.unwrap_or_else(|e| {
+            error!("acpi_aml: couldn't read {} bytes of physical memory at {:#X}: {}", size_of::<T>(), address, e);
+            T::default()
+        })
+    }
+
+    fn write_memory<T: FromBytes + Copy>(address: usize, value: T) {
+        let result = Self::map_physical::<T>(address).and_then(|(mut mp, offset)| {
+            let dest: &mut T = mp.as_type_mut(offset)?;
+            unsafe { ptr::write_volatile(dest, value) };
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!("acpi_aml: couldn't write {} bytes of physical memory at {:#X}: {}", size_of::<T>(), address, e);
+        }
+    }
+
+    fn read_port<T: PortIn + PortOut>(port: u16) -> T {
+        Port::<T>::new(port).read()
+    }
+
+    fn write_port<T: PortIn + PortOut>(port: u16, value: T) {
+        // SAFE: AML code is trusted firmware, which knows which ports belong to which devices.
+        unsafe { Port::<T>::new(port).write(value) }
+    }
+
+    /// Returns the location of the given PCI function, which must be in segment 0,
+    /// since only the legacy configuration space ports are supported.
+    fn pci_location(segment: u16, bus: u8, device: u8, function: u8) -> Option<PciLocation> {
+        if segment != 0 {
+            error!("acpi_aml: PCI segment {} is unsupported, only segment 0 can be accessed", segment);
+            return None;
+        }
+        Some(PciLocation::new(bus as u16, device as u16, function as u16))
+    }
+
+    /// Writes the `size`-byte `value` at the given `offset` of a PCI function's configuration space,
+    /// by replacing those bytes within the 32-bit word that contains them.
+    fn write_pci(location: PciLocation, offset: u16, size: u16, value: u32) {
+        let aligned_offset = offset & !0x3;
+        let shift = (offset & 0x3) * 8;
+        let mask: u32 = if size == 4 { !0 } else { ((1 << (size * 8)) - 1) << shift };
+        let old = location.pci_read_32(aligned_offset);
+        location.pci_write(aligned_offset, (old & !mask) | ((value << shift) & mask));
+    }
+}
+
+impl Handler for AmlHandler {
+    fn read_u8(&self, address: usize) -> u8 { Self::read_memory(address) }
+    fn read_u16(&self, address: usize) -> u16 { Self::read_memory(address) }
+    fn read_u32(&self, address: usize) -> u32 { Self::read_memory(address) }
+    fn read_u64(&self, address: usize) -> u64 { Self::read_memory(address) }
+
+    fn write_u8(&mut self, address: usize, value: u8) { Self::write_memory(address, value) }
+    fn write_u16(&mut self, address: usize, value: u16) { Self::write_memory(address, value) }
+    fn write_u32(&mut self, address: usize, value: u32) { Self::write_memory(address, value) }
+    fn write_u64(&mut self, address: usize, value: u64) { Self::write_memory(address, value) }
+
+    fn read_io_u8(&self, port: u16) -> u8 { Self::read_port(port) }
+    fn read_io_u16(&self, port: u16) -> u16 { Self::read_port(port) }
+    fn read_io_u32(&self, port: u16) -> u32 { Self::read_port(port) }
+
+    fn write_io_u8(&self, port: u16, value: u8) { Self::write_port(port, value) }
+    fn write_io_u16(&self, port: u16, value: u16) { Self::write_port(port, value) }
+    fn write_io_u32(&self, port: u16, value: u32) { Self::write_port(port, value) }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        Self::pci_location(segment, bus, device, function).map_or(0, |loc| loc.pci_read_8(offset))
+    }
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        Self::pci_location(segment, bus, device, function).map_or(0, |loc| loc.pci_read_16(offset))
+    }
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        Self::pci_location(segment, bus, device, function).map_or(0, |loc| loc.pci_read_32(offset))
+    }
+
+    fn write_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u8) {
+        if let Some(loc) = Self::pci_location(segment, bus, device, function) {
+            Self::write_pci(loc, offset, 1, value as u32);
+        }
+    }
+    fn write_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u16) {
+        if let Some(loc) = Self::pci_location(segment, bus, device, function) {
+            Self::write_pci(loc, offset, 2, value as u32);
+        }
+    }
+    fn write_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+        if let Some(loc) = Self::pci_location(segment, bus, device, function) {
+            Self::write_pci(loc, offset, 4, value);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Evaluates the AML code in the ACPI DSDT and SSDTs, using the [`aml`] interpreter crate.
+//!
+//! The static ACPI tables (e.g., the MADT) only describe a fixed set of hardware;
+//! everything else, such as how PCI interrupts are routed, which resources a device uses,
+//! and how to talk to the embedded controller, is only available by evaluating AML objects and methods
+//! like `_PRT`, `_CRS`, and `_Qxx` in the ACPI namespace.
+//!
+//! [`init()`] loads all AML definition blocks into a single namespace, initializes its devices,
+//! and tells the firmware that interrupts are routed through the IOAPIC.
+//! Afterwards, any object can be evaluated with [`evaluate()`], and:
+//! * the [`pci_routing`] module determines which interrupt a PCI device's interrupt pin is wired to, and
+//! * the [`ec`] module accesses the embedded controller and dispatches its events (e.g., laptop hotkeys)
+//!   to their `_Qxx` event methods.
+//!
+//! [`aml`]: https://docs.rs/aml
+//! [`init()`]: fn.init.html
+//! [`evaluate()`]: fn.evaluate.html
+//! [`pci_routing`]: pci_routing/index.html
+//! [`ec`]: ec/index.html
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate zerocopy;
+extern crate memory;
+extern crate port_io;
+extern crate port_registry;
+extern crate pci;
+extern crate acpi_table;
+extern crate dsdt;
+pub extern crate aml;
+
+mod handler;
+pub mod pci_routing;
+pub mod ec;
+
+use alloc::{
+    boxed::Box,
+    vec::Vec,
+};
+use core::str::FromStr;
+use aml::{AmlContext, AmlError, AmlName, AmlValue, DebugVerbosity};
+use aml::namespace::LevelType;
+use aml::value::Args;
+use aml::resource::{resource_descriptor_list, Resource};
+use spin::{Mutex, Once};
+use acpi_table::AcpiTables;
+use handler::AmlHandler;
+
+
+/// The single AML namespace, containing the objects defined by the DSDT and all SSDTs.
+static AML_CONTEXT: Once<Mutex<AmlContext>> = Once::new();
+
+/// The value passed to the `\_PIC` method to select the interrupt model: `1` means APIC mode.
+const PIC_MODE_APIC: u64 = 1;
+
+
+/// Loads the AML in the DSDT and all SSDTs within the given `acpi_tables` into the ACPI namespace,
+/// and initializes the namespace's devices by running their `_STA` and `_INI` methods.
+///
+/// This must be invoked after the DSDT has been mapped, i.e., after it has been discovered via the FADT.
+/// An SSDT that can't be parsed is skipped, but a DSDT that can't be parsed is an error.
+pub fn init(acpi_tables: &AcpiTables) -> Result<(), &'static str> {
+    if AML_CONTEXT.try().is_some() {
+        return Err("acpi_aml::init(): the AML namespace was already initialized");
+    }
+    let dsdt = dsdt::dsdt_aml(acpi_tables).ok_or("acpi_aml::init(): the DSDT wasn't found")?;
+
+    let mut context = AmlContext::new(Box::new(AmlHandler), false, DebugVerbosity::None);
+    context.parse_table(dsdt).map_err(|e| aml_error("acpi_aml::init(): couldn't parse the DSDT", e))?;
+    for (i, ssdt) in dsdt::ssdt_amls(acpi_tables).into_iter().enumerate() {
+        if let Err(e) = context.parse_table(ssdt) {
+            warn!("acpi_aml::init(): skipping SSDT {} that couldn't be parsed: {:?}", i, e);
+        }
+    }
+    context.initialize_objects().map_err(|e| aml_error("acpi_aml::init(): couldn't initialize the ACPI namespace", e))?;
+
+    // Tell the firmware that we use the IOAPIC, such that `_PRT` methods return IOAPIC routings.
+    // `\_PIC` is optional, so failing to find it isn't an error.
+    let pic_path = AmlName::from_str("\\_PIC").map_err(|e| aml_error("acpi_aml::init(): invalid path", e))?;
+    match context.invoke_method(&pic_path, args(&[AmlValue::Integer(PIC_MODE_APIC)])) {
+        Ok(_) => {}
+        Err(AmlError::ValueDoesNotExist(_)) => debug!("acpi_aml::init(): firmware has no \\_PIC method"),
+        Err(e) => warn!("acpi_aml::init(): couldn't switch the firmware to APIC mode: {:?}", e),
+    }
+
+    AML_CONTEXT.call_once(|| Mutex::new(context));
+    info!("Loaded the ACPI namespace from the DSDT and SSDTs");
+
+    if let Err(e) = ec::init() {
+        warn!("acpi_aml::init(): couldn't initialize the embedded controller: {}", e);
+    }
+    Ok(())
+}
+
+/// Returns the ACPI namespace, if [`init()`](fn.init.html) has completed.
+fn context() -> Result<&'static Mutex<AmlContext>, &'static str> {
+    AML_CONTEXT.try().ok_or("acpi_aml: the AML namespace wasn't initialized")
+}
+
+/// Logs the given AML error along with `msg`, and returns `msg`.
+fn aml_error(msg: &'static str, error: AmlError) -> &'static str {
+    error!("{}: {:?}", msg, error);
+    msg
+}
+
+/// Parses the given absolute or relative ACPI namespace `path`, e.g., `"\\_SB.PCI0._PRT"`.
+pub fn path(path: &str) -> Result<AmlName, &'static str> {
+    AmlName::from_str(path).map_err(|e| aml_error("acpi_aml: invalid ACPI namespace path", e))
+}
+
+/// Returns the method arguments that consist of the given `values`, in order.
+/// At most 7 arguments are supported; any beyond that are ignored.
+pub fn args(values: &[AmlValue]) -> Args {
+    let mut iter = values.iter().cloned();
+    Args {
+        arg_0: iter.next(),
+        arg_1: iter.next(),
+        arg_2: iter.next(),
+        arg_3: iter.next(),
+        arg_4: iter.next(),
+        arg_5: iter.next(),
+        arg_6: iter.next(),
+    }
+}
+
+
+/// Evaluates the object at the given absolute `path` with the given method `args`.
+///
+/// If the object is a method, it is invoked and its return value is returned.
+/// Otherwise, the object's value itself is returned and the `args` are ignored.
+pub fn evaluate(path: &AmlName, args: Args) -> Result<AmlValue, &'static str> {
+    let mut context = context()?.lock();
+    evaluate_in(&mut context, path, args)
+}
+
+fn evaluate_in(context: &mut AmlContext, path: &AmlName, args: Args) -> Result<AmlValue, &'static str> {
+    let is_method = match context.namespace.get_by_path(path) {
+        Ok(AmlValue::Method { .. }) => true,
+        Ok(_) => false,
+        Err(e) => {
+            debug!("acpi_aml: object {:?} doesn't exist: {:?}", path, e);
+            return Err("acpi_aml: the ACPI object doesn't exist");
+        }
+    };
+    if is_method {
+        context.invoke_method(path, args).map_err(|e| aml_error("acpi_aml: ACPI method failed", e))
+    } else {
+        context.namespace.get_by_path(path).map(|value| value.clone()).map_err(|e| aml_error("acpi_aml: couldn't get ACPI object", e))
+    }
+}
+
+/// Evaluates the given `object` within the given `device`, e.g., `_STA`, returning `Ok(None)` if the device has no such object.
+///
+/// If the object is a method, it is invoked without arguments.
+pub fn evaluate_child(device: &AmlName, object: &str) -> Result<Option<AmlValue>, &'static str> {
+    let mut context = context()?.lock();
+    evaluate_child_in(&mut context, device, object)
+}
+
+fn evaluate_child_in(context: &mut AmlContext, device: &AmlName, object: &str) -> Result<Option<AmlValue>, &'static str> {
+    let child = AmlName::from_str(object)
+        .and_then(|name| name.resolve(device))
+        .map_err(|e| aml_error("acpi_aml: invalid ACPI object name", e))?;
+    if context.namespace.get_by_path(&child).is_err() {
+        return Ok(None);
+    }
+    evaluate_in(context, &child, Args::default()).map(Some)
+}
+
+
+/// Returns the paths of all devices whose hardware ID (`_HID`) or compatible ID (`_CID`) is the given `id`,
+/// which is either a PNP ID string like `"PNP0A03"`, or another string ID like `"ACPI0003"`.
+pub fn find_devices(id: &str) -> Result<Vec<AmlName>, &'static str> {
+    let mut context = context()?.lock();
+    find_devices_in(&mut context, id)
+}
+
+fn find_devices_in(context: &mut AmlContext, id: &str) -> Result<Vec<AmlName>, &'static str> {
+    let devices = find_levels_in(context, |typ| matches!(typ, LevelType::Device))?;
+    let eisa_id = eisa_id(id);
+    let mut matching = Vec::new();
+    for device in devices {
+        for object in &["_HID", "_CID"] {
+            let matches = match evaluate_child_in(context, &device, object) {
+                Ok(Some(AmlValue::Integer(value))) => Some(value) == eisa_id,
+                Ok(Some(AmlValue::String(ref s))) => s.as_str() == id,
+                _ => false,
+            };
+            if matches {
+                matching.push(device.clone());
+                break;
+            }
+        }
+    }
+    Ok(matching)
+}
+
+/// Returns the paths of all thermal zones, e.g., `\_TZ.THRM`, which report temperatures via their `_TMP` objects.
+pub fn find_thermal_zones() -> Result<Vec<AmlName>, &'static str> {
+    let mut context = context()?.lock();
+    find_levels_in(&mut context, |typ| matches!(typ, LevelType::ThermalZone))
+}
+
+/// Returns the paths of all namespace levels, e.g., devices, whose type satisfies the given `filter`.
+fn find_levels_in(context: &mut AmlContext, filter: fn(&LevelType) -> bool) -> Result<Vec<AmlName>, &'static str> {
+    let mut levels = Vec::new();
+    context.namespace.traverse(|name, level| {
+        if filter(&level.typ) {
+            levels.push(name.clone());
+        }
+        Ok(true)
+    }).map_err(|e| aml_error("acpi_aml: couldn't traverse the ACPI namespace", e))?;
+    Ok(levels)
+}
+
+/// Returns the resources that the given `device` currently uses, from its `_CRS` object.
+pub fn current_resources(device: &AmlName) -> Result<Vec<Resource>, &'static str> {
+    let mut context = context()?.lock();
+    let crs = evaluate_child_in(&mut context, device, "_CRS")?.ok_or("acpi_aml: device has no _CRS object")?;
+    resource_descriptor_list(&crs).map_err(|e| aml_error("acpi_aml: couldn't parse the device's _CRS", e))
+}
+
+/// Returns whether the given `device` is present, according to its `_STA` object.
+/// A device without `_STA` is always present.
+pub fn is_present(device: &AmlName) -> Result<bool, &'static str> {
+    /// The bit in a `_STA` value that indicates the device is present.
+    const STA_PRESENT: u64 = 1 << 0;
+    let mut context = context()?.lock();
+    match evaluate_child_in(&mut context, device, "_STA")? {
+        None => Ok(true),
+        Some(AmlValue::Integer(status)) => Ok(status & STA_PRESENT != 0),
+        Some(_) => Err("acpi_aml: device's _STA is not an integer"),
+    }
+}
+
+
+/// Encodes the given PNP ID string, e.g., `"PNP0A03"`, as a compressed EISA ID,
+/// which is how most `_HID` and `_CID` objects are represented as integers.
+///
+/// Returns `None` if the `id` isn't a PNP ID, i.e., three uppercase letters followed by four hex digits.
+fn eisa_id(id: &str) -> Option<u64> {
+    let bytes = id.as_bytes();
+    if bytes.len() != 7 || !bytes[..3].iter().all(|b| b.is_ascii_uppercase()) {
+        return None;
+    }
+    let product = u16::from_str_radix(&id[3..], 16).ok()?;
+    let letter = |i: usize| ((bytes[i] - 0x40) & 0x1F) as u32;
+    let id = (letter(0) << 26) | (letter(1) << 21) | (letter(2) << 16) | product as u32;
+    // The compressed ID is stored in big-endian order, but is read as a little-endian integer.
+    Some(id.swap_bytes() as u64)
+}
@@ -0,0 +1,68 @@
+//! Determines which interrupt each PCI interrupt pin is routed to, from the root PCI bridge's `_PRT` object.
+//!
+//! The interrupt line in a PCI function's configuration space is only meaningful for the legacy PIC,
+//! so with the IOAPIC, the global system interrupt that a pin is wired to must be looked up in the `_PRT`.
+//! A `_PRT` entry either names the interrupt directly, or names a PCI interrupt link device,
+//! whose current resources (`_CRS`) describe which interrupt it is configured to use.
+//!
+//! Only functions on the root bus are supported, since functions behind PCI-to-PCI bridges
+//! are described by those bridges' own `_PRT` objects.
+
+use aml::pci_routing::PciRoutingTable;
+use spin::Once;
+use super::{aml_error, context, find_devices_in, path, AmlName};
+
+pub use aml::pci_routing::Pin;
+pub use aml::resource::{InterruptPolarity, InterruptTrigger, IrqDescriptor};
+
+
+/// The hardware IDs of a PCI Express root bridge and a conventional PCI root bridge.
+const ROOT_BRIDGE_IDS: [&'static str; 2] = ["PNP0A08", "PNP0A03"];
+
+/// The routing table of the root PCI bus, which is parsed upon first use.
+static ROOT_ROUTING_TABLE: Once<PciRoutingTable> = Once::new();
+
+
+/// Returns the interrupt that the given interrupt `pin` of the PCI function at `slot` and `func` on the root bus is routed to.
+///
+/// The returned descriptor's `irq` is a global system interrupt number, which the IOAPICs map to their inputs.
+pub fn pci_interrupt(bus: u16, slot: u16, func: u16, pin: Pin) -> Result<IrqDescriptor, &'static str> {
+    if bus != 0 {
+        error!("acpi_aml: can't route interrupts for PCI bus {}, only the root bus is supported", bus);
+        return Err("acpi_aml: interrupt routing is only supported for the root PCI bus");
+    }
+    let mut context = context()?.lock();
+    let table = match ROOT_ROUTING_TABLE.try() {
+        Some(table) => table,
+        None => {
+            let prt_path = root_bridge_prt(&mut context)?;
+            let table = PciRoutingTable::from_prt_path(&prt_path, &mut context)
+                .map_err(|e| aml_error("acpi_aml: couldn't parse the root bridge's _PRT", e))?;
+            ROOT_ROUTING_TABLE.call_once(|| table)
+        }
+    };
+    table.route(slot, func, pin, &mut context).map_err(|e| aml_error("acpi_aml: couldn't route PCI interrupt", e))
+}
+
+/// Converts the value of a PCI function's interrupt pin register (`1` through `4` for INTA# through INTD#) into a `Pin`.
+/// Returns `None` if the function doesn't use an interrupt pin.
+pub fn pin_from_register(interrupt_pin: u8) -> Option<Pin> {
+    match interrupt_pin {
+        1 => Some(Pin::IntA),
+        2 => Some(Pin::IntB),
+        3 => Some(Pin::IntC),
+        4 => Some(Pin::IntD),
+        _ => None,
+    }
+}
+
+/// Returns the path of the `_PRT` object of the first root PCI bridge in the namespace.
+fn root_bridge_prt(context: &mut aml::AmlContext) -> Result<AmlName, &'static str> {
+    for id in ROOT_BRIDGE_IDS.iter() {
+        if let Some(bridge) = find_devices_in(context, id)?.into_iter().next() {
+            debug!("acpi_aml: found root PCI bridge {:?}", bridge);
+            return path("_PRT")?.resolve(&bridge).map_err(|e| aml_error("acpi_aml: invalid _PRT path", e));
+        }
+    }
+    Err("acpi_aml: couldn't find a root PCI bridge in the ACPI namespace")
+}
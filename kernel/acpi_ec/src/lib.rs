@@ -0,0 +1,215 @@
+//! A driver for the laptop events that the ACPI embedded controller (EC) reports,
+//! such as the lid being closed, the AC adapter being unplugged, the battery's charge changing, or a thermal zone heating up.
+//!
+//! The EC signals each event by raising an event query, which is dispatched to the EC device's `_Qxx` method
+//! by [`acpi_aml::ec::handle_event()`]. The firmware's `_Qxx` methods update the state of the affected ACPI devices,
+//! so after handling EC events, this driver re-reads the state of the lid (`_LID`), AC adapter (`_PSR`),
+//! batteries (`_STA`, `_BST`, and `_BIX`/`_BIF`), and thermal zones (`_TMP` and `_CRT`),
+//! and publishes a [`PowerEvent`] for each change on the `power_events` bus.
+//!
+//! Theseus doesn't yet handle ACPI general-purpose events (GPEs), so the EC is polled by a background task
+//! that is started by [`init()`].
+//!
+//! [`acpi_aml::ec::handle_event()`]: ../acpi_aml/ec/fn.handle_event.html
+//! [`PowerEvent`]: ../power_events/enum.PowerEvent.html
+//! [`init()`]: fn.init.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate acpi_aml;
+extern crate power_events;
+extern crate tsc;
+extern crate spawn;
+extern crate sleep;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use acpi_aml::{ec, evaluate_child, find_devices, find_thermal_zones, is_present};
+use acpi_aml::aml::{AmlName, AmlValue};
+use power_events::{BatteryStatus, PowerEvent, Temperature};
+
+
+/// The hardware ID of a lid switch device.
+const LID_DEVICE_ID: &'static str = "PNP0C0D";
+/// The hardware ID of a control method battery.
+const BATTERY_DEVICE_ID: &'static str = "PNP0C0A";
+/// The hardware ID of an AC adapter.
+const AC_ADAPTER_DEVICE_ID: &'static str = "ACPI0003";
+
+/// How often the EC is polled for events, in milliseconds.
+const POLL_INTERVAL_MS: u64 = 250;
+/// Battery charge levels change gradually without an EC event on some firmware,
+/// so all devices are also re-read after this many polls, even if there were no events.
+const REFRESH_EVERY_POLLS: u64 = 120;
+
+// The bits of the battery state in the first element of the `_BST` package.
+const BST_DISCHARGING: u64 = 1 << 0;
+const BST_CHARGING: u64 = 1 << 1;
+const BST_CRITICAL: u64 = 1 << 2;
+/// The value of a battery capacity that is unknown.
+const BATTERY_UNKNOWN: u64 = 0xFFFF_FFFF;
+
+/// How much a thermal zone's temperature (in tenths of a kelvin) must change before another event is published.
+const THERMAL_HYSTERESIS: u64 = 10;
+
+
+/// The ACPI devices whose state is reported, and the state they had when they were last read.
+struct Monitor {
+    lid: Option<(AmlName, Option<bool>)>,
+    ac_adapter: Option<(AmlName, Option<bool>)>,
+    batteries: Vec<(AmlName, Option<BatteryStatus>)>,
+    thermal_zones: Vec<(AmlName, Option<(Temperature, bool)>)>,
+}
+
+impl Monitor {
+    /// Re-reads the state of every device, and publishes an event for each one whose state changed.
+    fn refresh(&mut self) {
+        if let Some((ref lid, ref mut last)) = self.lid {
+            if let Some(open) = changed(last, read_integer(lid, "_LID").map(|v| v != 0)) {
+                power_events::publish(PowerEvent::LidSwitch { open });
+            }
+        }
+        if let Some((ref adapter, ref mut last)) = self.ac_adapter {
+            if let Some(online) = changed(last, read_integer(adapter, "_PSR").map(|v| v == 1)) {
+                power_events::publish(PowerEvent::AcAdapter { online });
+            }
+        }
+        for (index, &mut (ref battery, ref mut last)) in self.batteries.iter_mut().enumerate() {
+            if let Some(status) = changed(last, read_battery(battery)) {
+                power_events::publish(PowerEvent::BatteryStatus { index, status });
+            }
+        }
+        for (index, &mut (ref zone, ref mut last)) in self.thermal_zones.iter_mut().enumerate() {
+            let current = match read_thermal_zone(zone) {
+                Some(c) => c,
+                None => continue,
+            };
+            let significant = match *last {
+                None => true,
+                Some((temperature, critical)) => critical != current.1 || abs_diff(temperature.0, (current.0).0) >= THERMAL_HYSTERESIS,
+            };
+            if significant {
+                *last = Some(current);
+                power_events::publish(PowerEvent::Thermal { index, temperature: current.0, critical: current.1 });
+            }
+        }
+    }
+}
+
+/// Stores the `current` value in `last` and returns it, if it differs from the `last` value.
+/// Values that couldn't be read are ignored.
+fn changed<T: Copy + PartialEq>(last: &mut Option<T>, current: Option<T>) -> Option<T> {
+    match current {
+        Some(c) if *last != current => {
+            *last = Some(c);
+            Some(c)
+        }
+        _ => None,
+    }
+}
+
+fn abs_diff(a: u64, b: u64) -> u64 {
+    if a > b { a - b } else { b - a }
+}
+
+
+/// Finds the lid, AC adapter, battery, and thermal zone devices, and starts polling the embedded controller for events.
+///
+/// Returns `Ok(false)` if there is no embedded controller, which is normal for desktops and virtual machines.
+pub fn init() -> Result<bool, &'static str> {
+    if !ec::is_present() {
+        return Ok(false);
+    }
+    let mut monitor = Monitor {
+        lid: find_devices(LID_DEVICE_ID)?.into_iter().next().map(|d| (d, None)),
+        ac_adapter: find_devices(AC_ADAPTER_DEVICE_ID)?.into_iter().next().map(|d| (d, None)),
+        batteries: find_devices(BATTERY_DEVICE_ID)?.into_iter().map(|d| (d, None)).collect(),
+        thermal_zones: find_thermal_zones()?.into_iter().map(|z| (z, None)).collect(),
+    };
+    info!("acpi_ec: found lid {:?}, AC adapter {:?}, {} batteries, and {} thermal zones",
+        monitor.lid.as_ref().map(|l| &l.0), monitor.ac_adapter.as_ref().map(|a| &a.0),
+        monitor.batteries.len(), monitor.thermal_zones.len()
+    );
+    // Publish the initial state, such that subscribers don't have to query it separately.
+    monitor.refresh();
+
+    let interval_ticks = tsc::get_tsc_frequency()?.saturating_mul(POLL_INTERVAL_MS) / 1000;
+    spawn::new_task_builder(ec_event_loop, (monitor, interval_ticks))
+        .name(String::from("acpi_ec_events"))
+        .spawn()?;
+    Ok(true)
+}
+
+fn ec_event_loop((mut monitor, interval_ticks): (Monitor, u64)) -> Result<(), &'static str> {
+    let mut polls: u64 = 0;
+    loop {
+        let start: u64 = tsc::tsc_ticks().into();
+        let handled = ec::handle_event().unwrap_or_else(|e| {
+            warn!("acpi_ec: couldn't handle embedded controller events: {}", e);
+            0
+        });
+        polls = polls.wrapping_add(1);
+        if handled > 0 || polls % REFRESH_EVERY_POLLS == 0 {
+            monitor.refresh();
+        }
+        sleep::sleep_until(start.saturating_add(interval_ticks))?;
+    }
+}
+
+
+/// Evaluates the given `object` of the given `device`, which must be an integer.
+fn read_integer(device: &AmlName, object: &str) -> Option<u64> {
+    match evaluate_child(device, object) {
+        Ok(Some(AmlValue::Integer(value))) => Some(value),
+        Ok(Some(other)) => {
+            warn!("acpi_ec: {:?}.{} is not an integer: {:?}", device, object, other);
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Evaluates the given `object` of the given `device`, which must be a package, and returns the integer at `index` in it.
+fn read_package_integer(device: &AmlName, object: &str, index: usize) -> Option<u64> {
+    match evaluate_child(device, object) {
+        Ok(Some(AmlValue::Package(elements))) => match elements.get(index) {
+            Some(&AmlValue::Integer(value)) => Some(value),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn read_battery(battery: &AmlName) -> Option<BatteryStatus> {
+    if !is_present(battery).ok()? {
+        return Some(BatteryStatus::default());
+    }
+    let state = read_package_integer(battery, "_BST", 0)?;
+    let remaining = read_package_integer(battery, "_BST", 2).filter(|&r| r != BATTERY_UNKNOWN);
+    // The last full charge capacity is the fourth element of `_BIX`, or the third of the older `_BIF`.
+    let full = read_package_integer(battery, "_BIX", 3)
+        .or_else(|| read_package_integer(battery, "_BIF", 2))
+        .filter(|&f| f != BATTERY_UNKNOWN && f != 0);
+    let percent = match (remaining, full) {
+        (Some(r), Some(f)) => Some(core::cmp::min(r.saturating_mul(100) / f, 100) as u8),
+        _ => None,
+    };
+    Some(BatteryStatus {
+        present: true,
+        charging: state & BST_CHARGING != 0,
+        discharging: state & BST_DISCHARGING != 0,
+        critical: state & BST_CRITICAL != 0,
+        percent,
+    })
+}
+
+/// Returns the thermal zone's current temperature, and whether it has reached the zone's critical trip point.
+fn read_thermal_zone(zone: &AmlName) -> Option<(Temperature, bool)> {
+    let temperature = read_integer(zone, "_TMP")?;
+    let critical = read_integer(zone, "_CRT").map_or(false, |crt| temperature >= crt);
+    Some((Temperature(temperature), critical))
+}
@@ -0,0 +1,232 @@
+//! Handles ACPI fixed events, i.e., presses of the power and sleep buttons,
+//! and general-purpose events (GPEs), which the firmware uses to signal changes like the lid being closed.
+//!
+//! A press of a fixed-feature button is published as a [`PowerEvent::PowerButton`] or [`PowerEvent::SleepButton`]
+//! on the `power_events` bus; this crate doesn't act on them itself, see the `power_policy` crate for that.
+//! Each GPE is dispatched to its `\_GPE._Lxx` (level-triggered) or `\_GPE._Exx` (edge-triggered) handler method,
+//! which updates the state of the affected ACPI devices.
+//! Afterwards, the state of the lid (`_LID`) is re-read and a [`PowerEvent::LidSwitch`] is published if it changed,
+//! unless there is an embedded controller, in which case the `acpi_ec` driver already reports the lid.
+//!
+//! Theseus doesn't yet handle the ACPI system control interrupt (SCI), so the status registers are polled
+//! by a background task that is started by [`init()`]. Buttons that are control method devices rather than
+//! fixed features only report presses through `Notify` operations in their GPE handler, which aren't observed yet.
+//!
+//! [`PowerEvent::PowerButton`]: ../power_events/enum.PowerEvent.html
+//! [`PowerEvent::SleepButton`]: ../power_events/enum.PowerEvent.html
+//! [`PowerEvent::LidSwitch`]: ../power_events/enum.PowerEvent.html
+//! [`init()`]: fn.init.html
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate port_io;
+extern crate acpi;
+extern crate fadt;
+extern crate acpi_aml;
+extern crate power_events;
+extern crate tsc;
+extern crate spawn;
+extern crate sleep;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use port_io::Port;
+use fadt::Fadt;
+use acpi_aml::{evaluate_child, find_devices};
+use acpi_aml::aml::{AmlName, AmlValue};
+use power_events::PowerEvent;
+
+
+/// How often the status registers are polled, in milliseconds.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// The power button status bit in the PM1 status register, which is cleared by writing a 1 to it.
+const PM1_STS_PWRBTN: u16 = 1 << 8;
+/// The sleep button status bit in the PM1 status register, which is cleared by writing a 1 to it.
+const PM1_STS_SLPBTN: u16 = 1 << 9;
+/// The FADT flag that indicates that the power button is a control method device rather than a fixed feature.
+const FADT_FLAG_PWR_BUTTON: u32 = 1 << 4;
+/// The FADT flag that indicates that the sleep button is a control method device rather than a fixed feature.
+const FADT_FLAG_SLP_BUTTON: u32 = 1 << 5;
+
+/// The hardware ID of a lid switch device.
+const LID_DEVICE_ID: &'static str = "PNP0C0D";
+
+
+/// A GPE register block, whose first half holds the status registers and whose second half holds the enable registers.
+struct GpeBlock {
+    /// The port of the first status register.
+    status_port: u16,
+    /// The number of status registers, each of which is one byte that holds 8 GPEs.
+    len: u16,
+    /// The number of the first GPE in this block.
+    base: u16,
+}
+
+/// The event registers that are polled, and the state of the lid when it was last read.
+struct Monitor {
+    /// The ports of the PM1a and PM1b status registers, if they exist.
+    pm1_status_ports: Vec<u16>,
+    /// The bits in the PM1 status registers of the fixed-feature buttons that exist.
+    fixed_events: u16,
+    gpe_blocks: Vec<GpeBlock>,
+    /// The `\_GPE` scope, if the ACPI namespace has been loaded.
+    gpe_scope: Option<AmlName>,
+    lid: Option<(AmlName, Option<bool>)>,
+}
+
+impl Monitor {
+    /// Handles every pending fixed event and GPE.
+    fn poll(&mut self) {
+        for &port in &self.pm1_status_ports {
+            let status_register = Port::<u16>::new(port);
+            let status = status_register.read() & self.fixed_events;
+            if status == 0 {
+                continue;
+            }
+            unsafe { status_register.write(status) };
+            if status & PM1_STS_PWRBTN != 0 {
+                info!("acpi_events: the power button was pressed");
+                power_events::publish(PowerEvent::PowerButton);
+            }
+            if status & PM1_STS_SLPBTN != 0 {
+                info!("acpi_events: the sleep button was pressed");
+                power_events::publish(PowerEvent::SleepButton);
+            }
+        }
+
+        let mut handled_gpes = 0;
+        for block in &self.gpe_blocks {
+            for i in 0 .. block.len {
+                let status_register = Port::<u8>::new(block.status_port + i);
+                let status = status_register.read();
+                for bit in (0 .. 8).filter(|bit| status & (1 << bit) != 0) {
+                    handle_gpe(self.gpe_scope.as_ref(), block.base + i * 8 + bit, &status_register, 1 << bit);
+                    handled_gpes += 1;
+                }
+            }
+        }
+        if handled_gpes > 0 {
+            self.refresh_lid();
+        }
+    }
+
+    /// Re-reads the state of the lid, and publishes an event if it changed.
+    fn refresh_lid(&mut self) {
+        if let Some((ref lid, ref mut last)) = self.lid {
+            let current = match evaluate_child(lid, "_LID") {
+                Ok(Some(AmlValue::Integer(value))) => value != 0,
+                _ => return,
+            };
+            if *last != Some(current) {
+                *last = Some(current);
+                power_events::publish(PowerEvent::LidSwitch { open: current });
+            }
+        }
+    }
+}
+
+/// Runs the handler method of the given GPE, and clears its status bit (`mask`) in the given `status_register`.
+fn handle_gpe(gpe_scope: Option<&AmlName>, gpe: u16, status_register: &Port<u8>, mask: u8) {
+    // An edge-triggered GPE is cleared before its handler runs, such that the next edge isn't lost.
+    unsafe { status_register.write(mask) };
+    let scope = match gpe_scope {
+        Some(scope) => scope,
+        None => return,
+    };
+    // Only one of the two handler methods exists, which is only known once we try to evaluate it.
+    let result = match evaluate_child(scope, &format!("_E{:02X}", gpe)) {
+        Ok(None) => {
+            let result = evaluate_child(scope, &format!("_L{:02X}", gpe));
+            // A level-triggered GPE stays set until its handler has dealt with its source, so it's cleared again afterwards.
+            unsafe { status_register.write(mask) };
+            result
+        }
+        other => other,
+    };
+    match result {
+        Ok(Some(_)) => { }
+        Ok(None) => debug!("acpi_events: GPE {:#X} has no handler", gpe),
+        Err(e) => warn!("acpi_events: the handler of GPE {:#X} failed: {}", gpe, e),
+    }
+}
+
+
+/// Finds the fixed-feature buttons, GPE blocks, and lid, and starts polling for their events.
+///
+/// The ACPI namespace should already be loaded, otherwise GPEs are cleared without being handled.
+/// Returns `Ok(false)` if there are no fixed-feature buttons and no GPE blocks.
+pub fn init() -> Result<bool, &'static str> {
+    let fadt = {
+        let tables = acpi::get_acpi_tables().lock();
+        *Fadt::get(&tables).ok_or("acpi_events: the FADT ACPI table wasn't found")?
+    };
+
+    let mut fixed_events = 0;
+    if fadt.flags & FADT_FLAG_PWR_BUTTON == 0 {
+        fixed_events |= PM1_STS_PWRBTN;
+    }
+    if fadt.flags & FADT_FLAG_SLP_BUTTON == 0 {
+        fixed_events |= PM1_STS_SLPBTN;
+    }
+    // The status register is at the start of each PM1 event block.
+    let pm1_status_ports: Vec<u16> = [fadt.pm1a_event_block, fadt.pm1b_event_block].iter()
+        .filter(|&&block| block != 0)
+        .map(|&block| block as u16)
+        .collect();
+    if pm1_status_ports.is_empty() {
+        fixed_events = 0;
+    }
+
+    let mut gpe_blocks = Vec::new();
+    if fadt.gpe0_block != 0 && fadt.gpe0_ength != 0 {
+        gpe_blocks.push(GpeBlock { status_port: fadt.gpe0_block as u16, len: fadt.gpe0_ength as u16 / 2, base: 0 });
+    }
+    if fadt.gpe1_block != 0 && fadt.gpe1_length != 0 {
+        gpe_blocks.push(GpeBlock { status_port: fadt.gpe1_block as u16, len: fadt.gpe1_length as u16 / 2, base: fadt.gpe1_base as u16 });
+    }
+    if fixed_events == 0 && gpe_blocks.is_empty() {
+        return Ok(false);
+    }
+
+    // Finding devices fails if the ACPI namespace hasn't been loaded.
+    let lids = find_devices(LID_DEVICE_ID).ok();
+    let gpe_scope = match lids {
+        Some(_) => Some(acpi_aml::path("\\_GPE")?),
+        None => None,
+    };
+    let lid = if acpi_aml::ec::is_present() {
+        None
+    } else {
+        lids.and_then(|lids| lids.into_iter().next()).map(|lid| (lid, None))
+    };
+    info!("acpi_events: fixed events {:#X}, {} GPE blocks, lid {:?}",
+        fixed_events, gpe_blocks.len(), lid.as_ref().map(|l| &l.0)
+    );
+
+    // Discard any press that happened before we started, e.g., the one that turned the machine on.
+    for &port in &pm1_status_ports {
+        unsafe { Port::<u16>::new(port).write(fixed_events) };
+    }
+    let mut monitor = Monitor { pm1_status_ports, fixed_events, gpe_blocks, gpe_scope, lid };
+    // Publish the initial state of the lid, such that subscribers don't have to query it separately.
+    monitor.refresh_lid();
+
+    let interval_ticks = tsc::get_tsc_frequency()?.saturating_mul(POLL_INTERVAL_MS) / 1000;
+    spawn::new_task_builder(acpi_event_loop, (monitor, interval_ticks))
+        .name(String::from("acpi_events"))
+        .spawn()?;
+    Ok(true)
+}
+
+fn acpi_event_loop((mut monitor, interval_ticks): (Monitor, u64)) -> Result<(), &'static str> {
+    loop {
+        let start: u64 = tsc::tsc_ticks().into();
+        monitor.poll();
+        sleep::sleep_until(start.saturating_add(interval_ticks))?;
+    }
+}
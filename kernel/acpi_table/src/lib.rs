@@ -15,7 +15,7 @@ extern crate zerocopy;
 
 use core::ops::DerefMut;
 use alloc::collections::BTreeMap;
-use memory::{MappedPages, allocate_pages, PageTable, EntryFlags, PhysicalAddress, Frame, FrameRange, get_frame_allocator_ref, PhysicalMemoryArea};
+use memory::{MappedPages, allocate_pages, PageTable, EntryFlags, PhysicalAddress, Frame, FrameRange, get_frame_allocator_ref};
 use sdt::Sdt;
 use core::ops::Add;
 use zerocopy::FromBytes;
@@ -128,8 +128,7 @@ impl AcpiTables {
 
         // Inform the frame allocator that the physical frame(s) where the RSDT/XSDT exists are now in use.
         if mapping_changed {
-            let sdt_area = PhysicalMemoryArea::new(sdt_phys_addr, sdt_length, 1, 3);
-            allocator.lock().add_area(sdt_area, false)?;
+            memory::reserve_physical_region(sdt_phys_addr, sdt_length, "ACPI table")?;
         }
 
         // Here, the entire table is mapped into memory, and ready to be used elsewhere.
@@ -226,6 +225,14 @@ impl AcpiTables {
         self.mapped_pages.as_slice_mut(offset, len)
     }
 
+    /// Returns the `length` bytes at the given `phys_addr`, which must lie within a table that has already been mapped.
+    ///
+    /// This is useful for tables that may occur more than once, e.g., SSDTs, which can't be looked up by their signature.
+    pub fn bytes(&self, phys_addr: PhysicalAddress, length: usize) -> Result<&[u8], &'static str> {
+        let offset = self.frames.offset_from_start(phys_addr).ok_or("physical address is beyond the ACPI table bounds.")?;
+        self.mapped_pages.as_slice(offset, length)
+    }
+
     /// Returns an immutable reference to the underlying `MappedPages` that covers the ACPI tables.
     /// To access the ACPI tables, use the table's `get()` function, e.g., `Fadt::get(...)` instead of this function.
     pub fn mapping(&self) -> &MappedPages {
@@ -11,12 +11,15 @@ extern crate spin;
 extern crate irq_safety;
 extern crate memory;
 extern crate stack;
+extern crate tss;
 extern crate interrupts;
 extern crate spawn;
 extern crate scheduler;
 extern crate kernel_config;
 extern crate apic;
 extern crate tlb_shootdown;
+extern crate cpu_topology;
+extern crate protection_keys;
 
 use alloc::collections::BTreeMap;
 use core::sync::atomic::{AtomicBool, Ordering};
@@ -63,19 +66,26 @@ pub fn kstart_ap(processor_id: u8, apic_id: u8,
 
     // initialize interrupts (including TSS/GDT) for this AP
     let kernel_mmi_ref = get_kernel_mmi_ref().expect("kstart_ap(): kernel_mmi ref was None");
-    let (double_fault_stack, privilege_stack) = {
+    let (interrupt_stacks, privilege_stack) = {
         let frame_allocator_ref = memory::get_frame_allocator_ref().expect("kstart_ap(): frame allocator not initialized");
         let mut kernel_mmi = kernel_mmi_ref.lock();
         (
-            stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, &mut kernel_mmi.page_table, frame_allocator_ref)
-                .expect("kstart_ap(): could not allocate double fault stack"),
+            tss::InterruptStacks::new(&mut kernel_mmi.page_table, frame_allocator_ref)
+                .expect("kstart_ap(): could not allocate interrupt stacks"),
             stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, &mut kernel_mmi.page_table, frame_allocator_ref)
                 .expect("kstart_ap(): could not allocate privilege stack"),
         )
     };
-    let _idt = interrupts::init_ap(apic_id, double_fault_stack.top_unusable(), privilege_stack.top_unusable())
+    let _idt = interrupts::init_ap(apic_id, interrupt_stacks, privilege_stack.top_unusable())
         .expect("kstart_ap(): failed to initialize interrupts!");
 
+    let cpu = cpu_topology::init_current_core(apic_id)
+        .expect("kstart_ap(): failed to register this core's topology!");
+    info!("AP core {} is a {:?} core (package {}, core {}, SMT thread {})",
+        apic_id, cpu.core_type, cpu.package_id, cpu.core_id, cpu.smt_id
+    );
+    memory::enable_smep_smap();
+    protection_keys::init_current_core();
     let bootstrap_task = spawn::init(kernel_mmi_ref.clone(), apic_id, this_ap_stack).unwrap();
 
     // as a final step, init this apic as a new LocalApic, and add it to the list of all lapics.
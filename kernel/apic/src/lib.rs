@@ -21,7 +21,7 @@ extern crate atomic;
 extern crate bit_field;
 
 use core::ops::DerefMut;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicU32, Ordering};
 use volatile::{Volatile, ReadOnly, WriteOnly};
 use zerocopy::FromBytes;
 use alloc::boxed::Box;
@@ -60,6 +60,39 @@ static APIC_REGS: Once<BoxRef<MappedPages, ApicRegisters>> = Once::new();
 /// The processor id (from the ACPI MADT table) of the bootstrap processor
 static BSP_PROCESSOR_ID: Once<u8> = Once::new(); 
 
+/// The period of every core's timer in microseconds, see `set_timer_period_microseconds()`.
+static TIMER_PERIOD_MICROSECONDS: AtomicU32 = AtomicU32::new(CONFIG_TIMESLICE_PERIOD_MICROSECONDS);
+
+
+/// Returns the period of every core's timer in microseconds, i.e., the interval between timer interrupts.
+pub fn timer_period_microseconds() -> u32 {
+    TIMER_PERIOD_MICROSECONDS.load(Ordering::Relaxed)
+}
+
+/// Sets the period of every core's timer, i.e., the interval between timer interrupts, to the given microseconds.
+///
+/// The timers were calibrated for `CONFIG_TIMESLICE_PERIOD_MICROSECONDS` when each core booted,
+/// so the new period is scaled from that instead of calibrating them again.
+/// Each core reprograms its own timer the next time it invokes `sync_my_timer_period()`,
+/// which the timer interrupt handler does upon every interrupt.
+pub fn set_timer_period_microseconds(microseconds: u32) -> Result<(), &'static str> {
+    if microseconds == 0 {
+        return Err("the timer period must be at least one microsecond");
+    }
+    TIMER_PERIOD_MICROSECONDS.store(microseconds, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Reprograms the current core's timer if its period differs from the one given to `set_timer_period_microseconds()`.
+pub fn sync_my_timer_period() -> Result<(), &'static str> {
+    let microseconds = timer_period_microseconds();
+    let lapic = get_my_apic().ok_or("couldn't get this core's LocalApic")?;
+    if lapic.read().timer_period_microseconds == microseconds {
+        return Ok(());
+    }
+    lapic.write().program_timer_period(microseconds)
+}
+
 pub fn get_bsp_id() -> Option<u8> {
     BSP_PROCESSOR_ID.try().cloned()
 }
@@ -282,6 +315,13 @@ pub struct LocalApic {
     pub apic_id: u8,
     /// Whether this `LocalApic` is the bootstrap processor (the first processor to boot up).
     pub is_bsp: bool,
+    /// The calibrated initial count of the periodic timer for a period of `CONFIG_TIMESLICE_PERIOD_MICROSECONDS`,
+    /// which is kept such that the timer can be reprogrammed, e.g., after a system suspend, without calibrating it again.
+    timer_period: u64,
+    /// The period in microseconds that the timer is currently programmed with.
+    timer_period_microseconds: u32,
+    /// The `lint` and `flags` that the NMI was redirected with in `new()`.
+    nmi_config: (u8, u16),
 }
 use core::fmt;
 impl fmt::Debug for LocalApic {
@@ -306,6 +346,9 @@ impl LocalApic {
             processor: processor,
             apic_id: apic_id,
             is_bsp: is_bsp,
+            timer_period: 0,
+            timer_period_microseconds: CONFIG_TIMESLICE_PERIOD_MICROSECONDS,
+            nmi_config: (nmi_lint, nmi_flags),
 		};
 
         if is_bsp {
@@ -449,7 +492,12 @@ impl LocalApic {
             self.calibrate_apic_timer(CONFIG_TIMESLICE_PERIOD_MICROSECONDS)?
         };
         trace!("APIC {}, timer period count: {}({:#X})", self.apic_id, apic_period, apic_period);
+        self.timer_period = apic_period as u64;
+        self.program_timer_period(timer_period_microseconds())
+    }
 
+    /// Starts the periodic timer with the given initial count.
+    fn program_timer(&mut self, apic_period: u32) -> Result<(), &'static str> {
         if let Some(ref mut regs) = self.regs {
             regs.timer_divide.write(3); // set divide value to 16 ( ... how does 3 => 16 )
             // map APIC timer to an interrupt handler in the IDT
@@ -480,7 +528,27 @@ impl LocalApic {
             self.calibrate_x2apic_timer(CONFIG_TIMESLICE_PERIOD_MICROSECONDS)
         };
         trace!("X2APIC {}, timer period count: {}({:#X})", self.apic_id, x2apic_period, x2apic_period);
+        self.timer_period = x2apic_period;
+        // programming an x2apic timer cannot fail
+        let _ = self.program_timer_period(timer_period_microseconds());
+    }
 
+    /// Starts the periodic timer with the given period in microseconds, scaled from the calibrated `timer_period`.
+    fn program_timer_period(&mut self, microseconds: u32) -> Result<(), &'static str> {
+        let count = self.timer_period * microseconds as u64 / CONFIG_TIMESLICE_PERIOD_MICROSECONDS as u64;
+        // the initial count register is only 32 bits wide
+        let count = core::cmp::max(1, core::cmp::min(count, u32::max_value() as u64));
+        if has_x2apic() {
+            self.program_timer_x2apic(count);
+        } else {
+            self.program_timer(count as u32)?;
+        }
+        self.timer_period_microseconds = microseconds;
+        Ok(())
+    }
+
+    /// Starts the periodic timer with the given initial count.
+    fn program_timer_x2apic(&mut self, x2apic_period: u64) {
         unsafe {
             wrmsr(IA32_X2APIC_DIV_CONF, 3); // set divide value to 16 ( ... how does 3 => 16 )
             
@@ -497,6 +565,26 @@ impl LocalApic {
     }
 
     
+    /// Re-enables this core's APIC after a system suspend, which resets the APIC's state.
+    /// The timer is reprogrammed with its current period, scaled from the calibration done when this core booted,
+    /// and the NMI is redirected again.
+    ///
+    /// This must be invoked from the core that this APIC belongs to.
+    pub fn restore_after_resume(&mut self) -> Result<(), &'static str> {
+        // The suspend also cleared the MSR that holds this core's ID, see `new()`.
+        unsafe { wrmsr(IA32_TSC_AUX, self.apic_id as u64); }
+        if has_x2apic() {
+            self.enable_x2apic();
+        } else {
+            self.enable_apic()?;
+        }
+        let microseconds = self.timer_period_microseconds;
+        self.program_timer_period(microseconds)?;
+        let (lint, flags) = self.nmi_config;
+        self.set_nmi(lint, flags)
+    }
+
+
     pub fn id(&self) -> u8 {
         let id: u8 = if has_x2apic() {
             rdmsr(IA32_X2APIC_APICID) as u32 as u8
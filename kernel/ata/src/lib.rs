@@ -18,6 +18,7 @@ use alloc::{
 	string::String,
 	boxed::Box,
 	sync::Arc,
+	vec::Vec,
 };
 use port_io::{Port, PortReadOnly, PortWriteOnly};
 use pci::PciDevice;
@@ -115,8 +116,34 @@ enum AtaCommand {
 	IdentifyDevice  = 0xEC,
 	/// Get identifying details of an ATAPI drive.
 	IdentifyPacket  = 0xA1,
+	/// Self-Monitoring, Analysis, and Reporting Technology (S.M.A.R.T.) commands,
+	/// in which the `features` port specifies the actual `SmartFeature` to be executed.
+	Smart           = 0xB0,
 }
 
+/// The sub-commands of `AtaCommand::Smart`, which are written to the `features` port.
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+enum SmartFeature {
+	/// Reads one sector of S.M.A.R.T. attribute values.
+	ReadData       = 0xD0,
+	/// Reads one sector of S.M.A.R.T. attribute thresholds.
+	ReadThresholds = 0xD1,
+	/// Enables S.M.A.R.T. operations on the drive.
+	Enable         = 0xD8,
+	/// Returns the drive's overall health status in the `lba_mid` and `lba_high` ports.
+	ReturnStatus   = 0xDA,
+}
+
+/// The values that must be written to the `lba_mid` and `lba_high` ports for every S.M.A.R.T. command.
+const SMART_LBA_MID:  u8 = 0x4F;
+const SMART_LBA_HIGH: u8 = 0xC2;
+/// The values of the `lba_mid` and `lba_high` ports after a `SmartFeature::ReturnStatus` command
+/// if the drive has detected that one of its attributes has exceeded its threshold.
+const SMART_THRESHOLD_EXCEEDED_LBA_MID:  u8 = 0xF4;
+const SMART_THRESHOLD_EXCEEDED_LBA_HIGH: u8 = 0x2C;
+
 
 /// The possible types of drive devices that can be attached to an IDE controller via ATA.
 pub enum AtaDeviceType {
@@ -430,6 +457,41 @@ impl AtaBus {
 		self.wait_for_data_done().map_err(|_| "error after identify data read")?;
 		Ok(AtaIdentifyData::new(buffer))
     }
+
+	/// Issues a S.M.A.R.T. command with the given `feature` to the given drive.
+	/// 
+	/// If a `buffer` is provided, one sector of data is read into it after the command completes,
+	/// which is required for the `ReadData` and `ReadThresholds` features.
+	/// 
+	/// Returns the values of the `lba_mid` and `lba_high` ports after the command completes.
+	fn smart_command(&mut self,
+		which: BusDriveSelect,
+		feature: SmartFeature,
+		buffer: Option<&mut [u8; SECTOR_SIZE_IN_BYTES]>,
+	) -> Result<(u8, u8), &'static str> {
+		self.wait_for_data_done().map_err(|_| "error before issuing S.M.A.R.T. command")?;
+
+		unsafe {
+			self.drive_select.write(0xA0 | which as u8);
+			self.features.write(feature as u8);
+			self.sector_count.write(if buffer.is_some() { 1 } else { 0 });
+			self.lba_high.write(SMART_LBA_HIGH);
+			self.lba_mid.write(SMART_LBA_MID);
+			self.lba_low.write(0);
+			self.command.write(AtaCommand::Smart as u8);
+		}
+
+		if let Some(buffer) = buffer {
+			self.wait_for_data_ready().map_err(|_| "error before S.M.A.R.T. data read")?;
+			for chunk in buffer.chunks_exact_mut(2) {
+				let word: u16 = self.data.read();
+				chunk[0] = word as u8;
+				chunk[1] = (word >> 8) as u8;
+			}
+		}
+		self.wait_for_data_done().map_err(|_| "S.M.A.R.T. command failed (is S.M.A.R.T. enabled?)")?;
+		Ok((self.lba_mid.read(), self.lba_high.read()))
+	}
 	
 	/// Waits until the bus is ready to transfer data (either read or write).
 	/// This is intended to be used **after** commands have been issued.
@@ -652,6 +714,54 @@ impl AtaDrive {
 			BusDriveSelect::Slave => false,
 		}
 	}
+
+	/// Returns the identify data that was obtained when this drive was initialized.
+	pub fn identify_data(&self) -> &AtaIdentifyData {
+		&self.identify_data
+	}
+
+	/// Returns `true` if this drive supports the S.M.A.R.T. feature set.
+	pub fn supports_smart(&self) -> bool {
+		self.identify_data.command_set_support[0] & 0x1 != 0
+	}
+
+	/// Enables S.M.A.R.T. operations on this drive, which is required before reading its attributes.
+	/// This has no effect if they were already enabled.
+	pub fn smart_enable(&mut self) -> Result<(), &'static str> {
+		if !self.supports_smart() {
+			return Err("drive does not support S.M.A.R.T.");
+		}
+		self.bus.lock().smart_command(self.master_slave, SmartFeature::Enable, None).map(|_| ())
+	}
+
+	/// Returns the drive's own assessment of its overall health: 
+	/// `true` if all S.M.A.R.T. attributes are within their thresholds, 
+	/// `false` if any attribute has exceeded its threshold and the drive is likely to fail soon.
+	pub fn smart_status(&mut self) -> Result<bool, &'static str> {
+		if !self.supports_smart() {
+			return Err("drive does not support S.M.A.R.T.");
+		}
+		match self.bus.lock().smart_command(self.master_slave, SmartFeature::ReturnStatus, None)? {
+			(SMART_LBA_MID, SMART_LBA_HIGH) => Ok(true),
+			(SMART_THRESHOLD_EXCEEDED_LBA_MID, SMART_THRESHOLD_EXCEEDED_LBA_HIGH) => Ok(false),
+			_ => Err("drive returned an invalid S.M.A.R.T. status"),
+		}
+	}
+
+	/// Reads all of this drive's S.M.A.R.T. attributes, along with their thresholds.
+	pub fn smart_attributes(&mut self) -> Result<SmartAttributes, &'static str> {
+		if !self.supports_smart() {
+			return Err("drive does not support S.M.A.R.T.");
+		}
+		let mut data = [0u8; SECTOR_SIZE_IN_BYTES];
+		let mut thresholds = [0u8; SECTOR_SIZE_IN_BYTES];
+		{
+			let mut bus = self.bus.lock();
+			bus.smart_command(self.master_slave, SmartFeature::ReadData, Some(&mut data))?;
+			bus.smart_command(self.master_slave, SmartFeature::ReadThresholds, Some(&mut thresholds))?;
+		}
+		Ok(SmartAttributes::new(&data, &thresholds))
+	}
 }
 
 impl StorageDevice for AtaDrive {
@@ -842,6 +952,85 @@ impl<'c> Iterator for IdeControllerIter<'c> {
 }
 
 
+/// The maximum number of attributes in a S.M.A.R.T. data sector.
+const MAX_SMART_ATTRIBUTES: usize = 30;
+/// The size in bytes of each attribute entry in a S.M.A.R.T. data or thresholds sector.
+const SMART_ATTRIBUTE_ENTRY_SIZE: usize = 12;
+/// The offset of the first attribute entry in a S.M.A.R.T. data or thresholds sector.
+const SMART_ATTRIBUTES_OFFSET: usize = 2;
+
+/// A single S.M.A.R.T. attribute of an ATA drive.
+/// 
+/// The meaning of each attribute `id` and its `raw` value is vendor-specific,
+/// though most common attributes are used consistently across vendors, 
+/// e.g., `5` is the reallocated sector count and `194` is the temperature in Celsius.
+#[derive(Copy, Clone, Debug)]
+pub struct SmartAttribute {
+	/// The attribute ID.
+	pub id: u8,
+	/// The attribute's status flags. Bit 0 indicates that this attribute is a pre-failure indicator.
+	pub flags: u16,
+	/// The current normalized value, typically from 1 to 253, in which higher is better.
+	pub current: u8,
+	/// The worst normalized value that has ever been recorded.
+	pub worst: u8,
+	/// The normalized value at or below which this attribute is considered to be failing.
+	pub threshold: u8,
+	/// The vendor-specific raw value (only the lower 48 bits are used).
+	pub raw: u64,
+}
+impl SmartAttribute {
+	/// Returns `true` if this attribute's current value is at or below its failure threshold.
+	pub fn is_failing(&self) -> bool {
+		self.threshold != 0 && self.current <= self.threshold
+	}
+}
+
+/// The set of all S.M.A.R.T. attributes read from an ATA drive.
+#[derive(Clone, Debug)]
+pub struct SmartAttributes(pub Vec<SmartAttribute>);
+impl SmartAttributes {
+	/// Parses the given S.M.A.R.T. data and thresholds sectors.
+	fn new(data: &[u8; SECTOR_SIZE_IN_BYTES], thresholds: &[u8; SECTOR_SIZE_IN_BYTES]) -> SmartAttributes {
+		let mut attributes = Vec::new();
+		for i in 0 .. MAX_SMART_ATTRIBUTES {
+			let offset = SMART_ATTRIBUTES_OFFSET + (i * SMART_ATTRIBUTE_ENTRY_SIZE);
+			let entry = &data[offset .. offset + SMART_ATTRIBUTE_ENTRY_SIZE];
+			let id = entry[0];
+			// an ID of zero denotes an unused entry
+			if id == 0 {
+				continue;
+			}
+			// The thresholds sector usually has the same layout as the data sector, but we search by ID just in case.
+			let threshold = thresholds[SMART_ATTRIBUTES_OFFSET ..]
+				.chunks_exact(SMART_ATTRIBUTE_ENTRY_SIZE)
+				.take(MAX_SMART_ATTRIBUTES)
+				.find(|t| t[0] == id)
+				.map(|t| t[1])
+				.unwrap_or(0);
+			let mut raw: u64 = 0;
+			for (shift, byte) in entry[5..11].iter().enumerate() {
+				raw |= (*byte as u64) << (shift * 8);
+			}
+			attributes.push(SmartAttribute {
+				id,
+				flags: (entry[1] as u16) | ((entry[2] as u16) << 8),
+				current: entry[3],
+				worst: entry[4],
+				threshold,
+				raw,
+			});
+		}
+		SmartAttributes(attributes)
+	}
+
+	/// Returns the attribute with the given `id`, if it exists.
+	pub fn get(&self, id: u8) -> Option<&SmartAttribute> {
+		self.0.iter().find(|a| a.id == id)
+	}
+}
+
+
 /// Information that describes an ATA drive, 
 /// obtained from the response to an identify command.
 /// 
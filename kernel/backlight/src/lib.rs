@@ -0,0 +1,176 @@
+//! Support for controlling the brightness of a laptop's built-in display backlight.
+//!
+//! On ACPI systems, the brightness is normally changed by evaluating the display output device's
+//! `_BCM` control method, using one of the levels listed by its `_BCL` method.
+//! Theseus cannot yet evaluate ACPI control methods (AML), so instead we program the register
+//! that the firmware's `_BCM` implementation uses on Intel integrated graphics:
+//! the Legacy Backlight Brightness (LBB) register in the graphics device's PCI configuration space.
+//!
+//! Other backlight drivers can be used by implementing the [`Backlight`] trait
+//! and registering them via [`set_backlight()`].
+//!
+//! [`Backlight`]: trait.Backlight.html
+//! [`set_backlight()`]: fn.set_backlight.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate pci;
+
+use alloc::boxed::Box;
+use core::cmp::{max, min};
+use pci::{PciDevice, PciLocation};
+use spin::Mutex;
+
+
+/// The amount (in percent) that the brightness hotkeys change the brightness by.
+pub const BRIGHTNESS_STEP_PERCENT: u8 = 10;
+
+/// The lowest brightness (in percent) that the brightness can be decreased to by [`adjust_brightness()`],
+/// which ensures that the display never goes completely dark from repeatedly decreasing the brightness.
+///
+/// [`adjust_brightness()`]: fn.adjust_brightness.html
+pub const MIN_ADJUSTED_PERCENT: u8 = 5;
+
+const INTEL_VENDOR_ID: u16 = 0x8086;
+/// The PCI class code of display controllers.
+const PCI_CLASS_DISPLAY: u8 = 0x03;
+/// The offset of the 32-bit register in PCI configuration space that contains the 8-bit LBB register in its lowest byte.
+const INTEL_LBB_OFFSET: u16 = 0xF4;
+/// The offset of the ASL Storage (ASLS) register in PCI configuration space, which holds the
+/// physical address of the IGD OpRegion that ACPI firmware uses to communicate with the graphics device.
+/// Firmware only provides this if its ACPI methods support the graphics device's backlight.
+const INTEL_ASLS_OFFSET: u16 = 0xFC;
+
+
+/// A device that controls the brightness of a display backlight.
+pub trait Backlight: Send {
+    /// Returns a short name that describes this backlight device.
+    fn name(&self) -> &'static str;
+    /// Returns the highest brightness level supported by this device.
+    fn max_brightness(&self) -> u32;
+    /// Returns the current brightness level, from `0` to `max_brightness()`.
+    fn brightness(&self) -> u32;
+    /// Sets the current brightness level, which must not be greater than `max_brightness()`.
+    fn set_brightness(&mut self, level: u32) -> Result<(), &'static str>;
+}
+
+/// The backlight of the system's built-in display, if one has been found.
+static BACKLIGHT: Mutex<Option<Box<dyn Backlight>>> = Mutex::new(None);
+
+
+/// Sets the given `backlight` device as the system's backlight, replacing the previous one.
+pub fn set_backlight(backlight: Box<dyn Backlight>) {
+    info!("backlight: using {} backlight with {} levels", backlight.name(), backlight.max_brightness() + 1);
+    *BACKLIGHT.lock() = Some(backlight);
+}
+
+/// Returns `true` if the system has a backlight whose brightness can be controlled.
+pub fn has_backlight() -> bool {
+    BACKLIGHT.lock().is_some()
+}
+
+/// Returns the name of the system's backlight device, if there is one.
+pub fn backlight_name() -> Option<&'static str> {
+    BACKLIGHT.lock().as_ref().map(|b| b.name())
+}
+
+/// Checks whether the given PCI device has a backlight that we support, and if so,
+/// uses it as the system's backlight.
+///
+/// Returns `Ok(true)` if the device was used as the backlight, `Ok(false)` otherwise.
+pub fn init_device(dev: &PciDevice) -> Result<bool, &'static str> {
+    if dev.class != PCI_CLASS_DISPLAY || dev.vendor_id != INTEL_VENDOR_ID {
+        return Ok(false);
+    }
+    // Desktop machines without a built-in display have no ACPI backlight support.
+    if dev.location.pci_read_32(INTEL_ASLS_OFFSET) == 0 {
+        info!("backlight: Intel graphics device at {:?} has no IGD OpRegion, so it has no backlight control", dev.location);
+        return Ok(false);
+    }
+    set_backlight(Box::new(IntelLegacyBacklight { location: dev.location }));
+    Ok(true)
+}
+
+
+/// Returns the current brightness as a percentage of the maximum brightness.
+pub fn brightness_percent() -> Result<u8, &'static str> {
+    let backlight = BACKLIGHT.lock();
+    let backlight = backlight.as_ref().ok_or("no backlight device was found")?;
+    Ok(level_to_percent(backlight.brightness(), backlight.max_brightness()))
+}
+
+/// Sets the brightness to the given `percent` of the maximum brightness, which is capped at 100.
+pub fn set_brightness_percent(percent: u8) -> Result<(), &'static str> {
+    let mut backlight = BACKLIGHT.lock();
+    let backlight = backlight.as_mut().ok_or("no backlight device was found")?;
+    let max_level = backlight.max_brightness();
+    backlight.set_brightness(percent_to_level(min(percent, 100), max_level))
+}
+
+/// Increases (or decreases, if negative) the brightness by `delta_percent`,
+/// but never decreases it below `MIN_ADJUSTED_PERCENT` of the maximum brightness.
+///
+/// Returns the new brightness as a percentage of the maximum brightness.
+pub fn adjust_brightness(delta_percent: i8) -> Result<u8, &'static str> {
+    let mut backlight = BACKLIGHT.lock();
+    let backlight = backlight.as_mut().ok_or("no backlight device was found")?;
+    let max_level = backlight.max_brightness();
+    let current = level_to_percent(backlight.brightness(), max_level) as i16;
+    let new_percent = min(100, max(MIN_ADJUSTED_PERCENT as i16, current + delta_percent as i16)) as u8;
+    let mut new_level = percent_to_level(new_percent, max_level);
+    // With coarse levels, a small step may round back to the current level, so move by at least one level.
+    let current_level = backlight.brightness();
+    if new_level == current_level {
+        if delta_percent > 0 && new_level < max_level {
+            new_level += 1;
+        } else if delta_percent < 0 && new_level > percent_to_level(MIN_ADJUSTED_PERCENT, max_level) {
+            new_level -= 1;
+        }
+    }
+    backlight.set_brightness(new_level)?;
+    Ok(level_to_percent(new_level, max_level))
+}
+
+
+fn level_to_percent(level: u32, max_level: u32) -> u8 {
+    if max_level == 0 {
+        return 100;
+    }
+    ((level as u64 * 100 + max_level as u64 / 2) / max_level as u64) as u8
+}
+
+fn percent_to_level(percent: u8, max_level: u32) -> u32 {
+    ((percent as u64 * max_level as u64 + 50) / 100) as u32
+}
+
+
+/// The backlight of Intel integrated graphics, controlled via the Legacy Backlight Brightness (LBB) register.
+struct IntelLegacyBacklight {
+    location: PciLocation,
+}
+
+impl Backlight for IntelLegacyBacklight {
+    fn name(&self) -> &'static str {
+        "Intel integrated graphics (LBB)"
+    }
+
+    fn max_brightness(&self) -> u32 {
+        0xFF
+    }
+
+    fn brightness(&self) -> u32 {
+        self.location.pci_read_32(INTEL_LBB_OFFSET) & 0xFF
+    }
+
+    fn set_brightness(&mut self, level: u32) -> Result<(), &'static str> {
+        if level > self.max_brightness() {
+            return Err("brightness level is greater than the maximum brightness");
+        }
+        let value = self.location.pci_read_32(INTEL_LBB_OFFSET);
+        self.location.pci_write(INTEL_LBB_OFFSET, (value & !0xFF) | level);
+        Ok(())
+    }
+}
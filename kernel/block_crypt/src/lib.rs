@@ -0,0 +1,174 @@
+//! A stacking storage device that transparently encrypts all data written to
+//! an underlying storage device, and decrypts all data read from it, similar to Linux's dm-crypt. 
+//! 
+//! Each sector is encrypted with AES-256 in XTS mode, using its absolute sector number as the tweak,
+//! as implemented by the `crypto` crate.
+//! Thus, a [`CryptDevice`] is the same size as its backing device, 
+//! and can be used anywhere a regular [`StorageDevice`] is expected, e.g., by `block_io` and filesystems. 
+//! 
+//! The 512-bit encryption key can be derived from a passphrase via [`CryptKey::from_passphrase()`],
+//! unsealed from the TPM via [`CryptKey::from_tpm()`], or provided directly via [`CryptKey::from_bytes()`].
+//! 
+//! # Example
+//! ```rust
+//! let key = CryptKey::from_passphrase(b"correct horse battery staple", b"lab-machine-1", DEFAULT_PBKDF2_ITERATIONS);
+//! // or, to use a key that was sealed to the TPM as the persistent object 0x81010001:
+//! let key = CryptKey::from_tpm(0x81010001, b"")?;
+//! let crypt_dev = CryptDevice::new(backing_device, &key)?;
+//! let crypt_dev_ref: StorageDeviceRef = Arc::new(Mutex::new(crypt_dev));
+//! let mut block_io = BlockIo::new_scheduled(crypt_dev_ref, IoSchedulerConfig::default())?;
+//! ```
+//! 
+//! [`CryptDevice`]: struct.CryptDevice.html
+//! [`StorageDevice`]: ../storage_device/trait.StorageDevice.html
+//! [`CryptKey::from_passphrase()`]: struct.CryptKey.html#method.from_passphrase
+//! [`CryptKey::from_tpm()`]: struct.CryptKey.html#method.from_tpm
+//! [`CryptKey::from_bytes()`]: struct.CryptKey.html#method.from_bytes
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate crypto;
+extern crate storage_device;
+extern crate tpm;
+
+pub use crypto::xts::XTS_KEY_SIZE;
+
+use alloc::vec::Vec;
+use crypto::aes::AES_BLOCK_SIZE;
+use crypto::pbkdf2::pbkdf2_hmac_sha3_256;
+use crypto::xts::XtsCipher;
+use storage_device::{StorageDevice, StorageDeviceRef};
+
+
+/// The default number of PBKDF2 iterations used to derive a key from a passphrase.
+pub const DEFAULT_PBKDF2_ITERATIONS: u32 = 100_000;
+
+
+/// A 512-bit key used to encrypt a [`CryptDevice`](struct.CryptDevice.html).
+/// 
+/// The key bytes are zeroed out when this is dropped.
+pub struct CryptKey {
+    bytes: [u8; XTS_KEY_SIZE],
+}
+
+impl CryptKey {
+    /// Creates a key from the given raw key bytes.
+    pub fn from_bytes(bytes: [u8; XTS_KEY_SIZE]) -> CryptKey {
+        CryptKey { bytes }
+    }
+
+    /// Unseals the key from the TPM, in which it is stored as the sealed data object
+    /// with the given persistent `object_handle`, using the given `auth_value` (password) to authorize it.
+    /// 
+    /// The sealed data must be exactly [`XTS_KEY_SIZE`](constant.XTS_KEY_SIZE.html) bytes long,
+    /// see the `tpm` crate for how to seal a key.
+    pub fn from_tpm(object_handle: u32, auth_value: &[u8]) -> Result<CryptKey, &'static str> {
+        let mut secret = tpm::unseal(object_handle, auth_value)?;
+        let key = if secret.len() == XTS_KEY_SIZE {
+            let mut bytes = [0u8; XTS_KEY_SIZE];
+            bytes.copy_from_slice(&secret);
+            Ok(CryptKey { bytes })
+        } else {
+            error!("CryptKey::from_tpm(): the unsealed key had {} bytes instead of {}", secret.len(), XTS_KEY_SIZE);
+            Err("CryptKey::from_tpm(): the unsealed key had the wrong size")
+        };
+        zero(&mut secret);
+        key
+    }
+
+    /// Derives a key from the given `passphrase` and `salt` using PBKDF2 with HMAC-SHA3-256.
+    /// 
+    /// The `salt` should be unique per device, and the same `salt` and `iterations` 
+    /// must be used every time the device is unlocked.
+    /// A larger number of `iterations` makes brute-forcing the passphrase slower,
+    /// see [`DEFAULT_PBKDF2_ITERATIONS`](constant.DEFAULT_PBKDF2_ITERATIONS.html).
+    pub fn from_passphrase(passphrase: &[u8], salt: &[u8], iterations: u32) -> CryptKey {
+        let mut bytes = [0u8; XTS_KEY_SIZE];
+        pbkdf2_hmac_sha3_256(passphrase, salt, iterations, &mut bytes);
+        CryptKey { bytes }
+    }
+}
+
+impl Drop for CryptKey {
+    fn drop(&mut self) {
+        zero(&mut self.bytes);
+    }
+}
+
+/// Zeroes the given key material with volatile writes, such that the compiler can't optimize away the zeroing.
+fn zero(bytes: &mut [u8]) {
+    for b in bytes.iter_mut() {
+        unsafe { core::ptr::write_volatile(b, 0); }
+    }
+}
+
+/// A storage device that encrypts and decrypts the sectors of a backing storage device.
+/// 
+/// All sector reads and writes are passed through to the backing device, 
+/// so this device has the same sector size and number of sectors as the backing device.
+pub struct CryptDevice {
+    backing_device: StorageDeviceRef,
+    cipher: XtsCipher,
+    sector_size: usize,
+}
+
+impl CryptDevice {
+    /// Creates a new encrypted device on top of the given `backing_device`, using the given `key`.
+    /// 
+    /// Returns an error if the backing device's sector size isn't a multiple of the AES block size,
+    /// or if the key is invalid for XTS mode.
+    pub fn new(backing_device: StorageDeviceRef, key: &CryptKey) -> Result<CryptDevice, &'static str> {
+        let sector_size = backing_device.lock().sector_size_in_bytes();
+        if sector_size == 0 || sector_size % AES_BLOCK_SIZE != 0 {
+            return Err("CryptDevice::new(): backing device's sector size must be a multiple of 16 bytes");
+        }
+        let cipher = XtsCipher::new(&key.bytes)?;
+        debug!("Created CryptDevice with sector size {}", sector_size);
+        Ok(CryptDevice {
+            backing_device,
+            cipher,
+            sector_size,
+        })
+    }
+
+    /// Returns a reference to the backing storage device, which contains the encrypted data.
+    pub fn backing_device(&self) -> &StorageDeviceRef {
+        &self.backing_device
+    }
+}
+
+impl StorageDevice for CryptDevice {
+    fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        if buffer.len() % self.sector_size != 0 {
+            return Err("CryptDevice::read_sectors(): buffer length must be a multiple of the sector size");
+        }
+        let sectors_read = self.backing_device.lock().read_sectors(buffer, offset_in_sectors)?;
+        for (i, sector) in buffer.chunks_exact_mut(self.sector_size).take(sectors_read).enumerate() {
+            self.cipher.decrypt_sector(sector, (offset_in_sectors + i) as u64)?;
+        }
+        Ok(sectors_read)
+    }
+
+    fn write_sectors(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        if buffer.len() % self.sector_size != 0 {
+            return Err("CryptDevice::write_sectors(): buffer length must be a multiple of the sector size");
+        }
+        // Encrypt a copy of the buffer, since the caller's plaintext must remain unmodified.
+        let mut ciphertext: Vec<u8> = buffer.to_vec();
+        for (i, sector) in ciphertext.chunks_exact_mut(self.sector_size).enumerate() {
+            self.cipher.encrypt_sector(sector, (offset_in_sectors + i) as u64)?;
+        }
+        self.backing_device.lock().write_sectors(&ciphertext, offset_in_sectors)
+    }
+
+    fn sector_size_in_bytes(&self) -> usize {
+        self.sector_size
+    }
+
+    fn size_in_sectors(&self) -> usize {
+        self.backing_device.lock().size_in_sectors()
+    }
+}
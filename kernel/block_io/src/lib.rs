@@ -14,20 +14,35 @@
 //! Cached blocks are stored as vectors of bytes on the heap, 
 //! we should do something else such as separate mapped regions. 
 //! Cached blocks cannot yet be dropped to relieve memory pressure. 
+//! 
+//! # I/O scheduling
+//! A `BlockIo` created by [`BlockIo::new_scheduled()`] sits on top of an `io_scheduler::IoScheduler`
+//! rather than directly on top of the storage device driver, such that the blocks written through the cache
+//! are written back in the background, merged and shared fairly between tasks,
+//! while reads of uncached blocks are still issued to the device right away.
+//! 
+//! [`BlockIo::new_scheduled()`]: struct.BlockIo.html#method.new_scheduled
 
 #![no_std]
 
 #[macro_use] extern crate alloc;
 #[macro_use] extern crate log;
 extern crate hashbrown;
+extern crate spin;
 extern crate storage_device;
+extern crate io_scheduler;
 
-use alloc::vec::Vec;
+use alloc::{
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
 use hashbrown::{
     HashMap,
     hash_map::Entry,
 };
 use storage_device::{StorageDevice, StorageDeviceRef, BlockBounds};
+use io_scheduler::{IoScheduler, IoSchedulerConfig};
 
 /// A wrapper around a `StorageDevice` that supports reads and writes of arbitrary byte lengths
 /// (down to a single byte) by issuing commands to the underlying storage device.
@@ -42,6 +57,8 @@ pub struct BlockIo {
     cache: BlockCache, 
     /// The underlying storage device from where the blocks are read/written.
     device: StorageDeviceRef,
+    /// The I/O scheduler that `device` refers to, if this was created by `new_scheduled()`.
+    scheduler: Option<Arc<Mutex<IoScheduler>>>,
 }
 impl BlockIo {
     /// Creates a new `BlockIo` device 
@@ -49,9 +66,21 @@ impl BlockIo {
         BlockIo {
             cache: HashMap::new(),
             device: storage_device, 
+            scheduler: None,
         }
     }
 
+    /// Creates a new `BlockIo` device whose cache issues its reads and writes to the given `storage_device`
+    /// through a new `IoScheduler` with the given `config`.
+    pub fn new_scheduled(storage_device: StorageDeviceRef, config: IoSchedulerConfig) -> Result<BlockIo, &'static str> {
+        let scheduler = IoScheduler::new_shared(storage_device, config)?;
+        Ok(BlockIo {
+            cache: HashMap::new(),
+            device: scheduler.clone(),
+            scheduler: Some(scheduler),
+        })
+    }
+
     /// Reads data from this block storage device and places it into the provided `buffer`.
     /// The length of the given `buffer` determines the maximum number of bytes to be read.
 	/// 
@@ -139,8 +168,17 @@ impl BlockIo {
 
     /// Flushes the given block to the backing storage device. 
     /// If the `block_to_flush` is None, all blocks in the entire cache
-    /// will be written back to the storage device.
+    /// will be written back to the storage device, 
+    /// including those still pending in the I/O scheduler, if any.
     pub fn flush(&mut self, block_num: Option<usize>) -> Result<(), &'static str> {
+        self.flush_cache(block_num)?;
+        if let (None, Some(scheduler)) = (block_num, self.scheduler.as_ref()) {
+            scheduler.lock().flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush_cache(&mut self, block_num: Option<usize>) -> Result<(), &'static str> {
         let mut locked_device = self.device.lock();
         if let Some(bn) = block_num {
             // Flush just one block
@@ -0,0 +1,254 @@
+//! Support for enumerating and setting the display modes of the Bochs/QEMU standard VGA adapter
+//! (PCI device `1234:1111`), which is used by QEMU's `-vga std` and by Bochs.
+//!
+//! Unlike the VESA mode that is chosen once at boot by the real-mode AP trampoline,
+//! this adapter's "DISPI" interface allows the resolution to be changed at any time.
+//! Its linear framebuffer is always located at the physical address in PCI BAR0,
+//! so after a successful [`BochsDisplay::set_mode()`], a new final framebuffer can be mapped
+//! at [`BochsDisplay::framebuffer_address()`] with the new dimensions.
+//!
+//! Other display interfaces, e.g., UEFI GOP or virtio-gpu, are not yet supported.
+//!
+//! [`BochsDisplay::set_mode()`]: struct.BochsDisplay.html#method.set_mode
+//! [`BochsDisplay::framebuffer_address()`]: struct.BochsDisplay.html#method.framebuffer_address
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate port_io;
+extern crate pci;
+extern crate memory;
+
+use alloc::vec::Vec;
+use core::fmt;
+use memory::PhysicalAddress;
+use port_io::Port;
+use spin::{Mutex, Once};
+
+
+const BOCHS_VENDOR_ID: u16 = 0x1234;
+const BOCHS_DEVICE_ID: u16 = 0x1111;
+
+/// The I/O port used to select a DISPI register.
+const DISPI_INDEX_PORT: u16 = 0x01CE;
+/// The I/O port used to read or write the selected DISPI register.
+const DISPI_DATA_PORT: u16 = 0x01CF;
+
+const DISPI_INDEX_ID: u16 = 0x0;
+const DISPI_INDEX_XRES: u16 = 0x1;
+const DISPI_INDEX_YRES: u16 = 0x2;
+const DISPI_INDEX_BPP: u16 = 0x3;
+const DISPI_INDEX_ENABLE: u16 = 0x4;
+const DISPI_INDEX_VIRT_WIDTH: u16 = 0x6;
+const DISPI_INDEX_VIRT_HEIGHT: u16 = 0x7;
+const DISPI_INDEX_X_OFFSET: u16 = 0x8;
+const DISPI_INDEX_Y_OFFSET: u16 = 0x9;
+const DISPI_INDEX_VIDEO_MEMORY_64K: u16 = 0xA;
+
+/// The lowest DISPI interface version that supports reading the mode capabilities.
+const DISPI_ID_GETCAPS: u16 = 0xB0C2;
+/// The highest DISPI interface version that we know about.
+const DISPI_ID_LATEST: u16 = 0xB0C5;
+/// The lowest DISPI interface version that reports the amount of video memory.
+const DISPI_ID_VIDEO_MEMORY: u16 = 0xB0C5;
+
+const DISPI_ENABLED: u16 = 0x01;
+const DISPI_GETCAPS: u16 = 0x02;
+const DISPI_LFB_ENABLED: u16 = 0x40;
+
+/// The only pixel depth supported, which matches the `framebuffer` crate's pixel formats.
+const BITS_PER_PIXEL: u16 = 32;
+
+/// Standard resolutions that are offered by [`BochsDisplay::modes()`](struct.BochsDisplay.html#method.modes),
+/// if the adapter supports them.
+const STANDARD_RESOLUTIONS: [(usize, usize); 10] = [
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 800),
+    (1280, 1024),
+    (1440, 900),
+    (1600, 1200),
+    (1920, 1080),
+    (1920, 1200),
+];
+
+/// The DISPI index and data ports, which must be accessed together.
+static DISPI_PORTS: Mutex<(Port<u16>, Port<u16>)> = Mutex::new((Port::new(DISPI_INDEX_PORT), Port::new(DISPI_DATA_PORT)));
+
+/// The Bochs display adapter, if one was found by `init()`.
+static BOCHS_DISPLAY: Once<BochsDisplay> = Once::new();
+
+
+/// A display mode, i.e., a resolution and pixel depth.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DisplayMode {
+    pub width: usize,
+    pub height: usize,
+    pub bits_per_pixel: u16,
+}
+impl DisplayMode {
+    /// Returns the number of bytes of video memory needed to display this mode.
+    pub fn size_in_bytes(&self) -> usize {
+        self.width * self.height * (self.bits_per_pixel as usize / 8)
+    }
+}
+impl fmt::Display for DisplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}x{}", self.width, self.height, self.bits_per_pixel)
+    }
+}
+
+
+/// A Bochs/QEMU standard VGA display adapter.
+#[derive(Debug)]
+pub struct BochsDisplay {
+    /// The DISPI interface version.
+    version: u16,
+    /// The physical address of the linear framebuffer.
+    framebuffer_address: PhysicalAddress,
+    /// The amount of video memory in bytes.
+    video_memory_size: usize,
+    /// The largest supported resolution.
+    max_width: usize,
+    max_height: usize,
+}
+
+/// Searches the PCI bus for a Bochs display adapter and initializes it.
+///
+/// Returns `Ok(None)` if there is no Bochs display adapter.
+pub fn init() -> Result<Option<&'static BochsDisplay>, &'static str> {
+    if let Some(display) = BOCHS_DISPLAY.try() {
+        return Ok(Some(display));
+    }
+    let pci_dev = match pci::pci_device_iter().find(|dev| dev.vendor_id == BOCHS_VENDOR_ID && dev.device_id == BOCHS_DEVICE_ID) {
+        Some(dev) => dev,
+        None => return Ok(None),
+    };
+
+    let version = read_register(DISPI_INDEX_ID);
+    if version < DISPI_ID_GETCAPS || version > DISPI_ID_LATEST {
+        error!("bochs_display: unsupported DISPI interface version {:#X}", version);
+        return Err("unsupported Bochs DISPI interface version");
+    }
+    let framebuffer_address = pci_dev.determine_mem_base()?;
+
+    // Reading the resolution registers while the GETCAPS flag is set returns the maximum supported values.
+    let enable = read_register(DISPI_INDEX_ENABLE);
+    write_register(DISPI_INDEX_ENABLE, enable | DISPI_GETCAPS);
+    let max_width = read_register(DISPI_INDEX_XRES) as usize;
+    let max_height = read_register(DISPI_INDEX_YRES) as usize;
+    write_register(DISPI_INDEX_ENABLE, enable);
+
+    let video_memory_size = if version >= DISPI_ID_VIDEO_MEMORY {
+        read_register(DISPI_INDEX_VIDEO_MEMORY_64K) as usize * 64 * 1024
+    } else {
+        // Older versions don't report the video memory size, so assume that the largest mode fits.
+        max_width * max_height * (BITS_PER_PIXEL as usize / 8)
+    };
+
+    let display = BochsDisplay { version, framebuffer_address, video_memory_size, max_width, max_height };
+    info!("bochs_display: found adapter at {:?}: {:?}", pci_dev.location, display);
+    Ok(Some(BOCHS_DISPLAY.call_once(|| display)))
+}
+
+/// Returns the Bochs display adapter, if it was found by `init()`.
+pub fn get_bochs_display() -> Option<&'static BochsDisplay> {
+    BOCHS_DISPLAY.try()
+}
+
+impl BochsDisplay {
+    /// Returns the version of this adapter's DISPI interface.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Returns the physical address of this adapter's linear framebuffer.
+    pub fn framebuffer_address(&self) -> PhysicalAddress {
+        self.framebuffer_address
+    }
+
+    /// Returns the amount of video memory in bytes.
+    pub fn video_memory_size(&self) -> usize {
+        self.video_memory_size
+    }
+
+    /// Returns `true` if this adapter can display the given `mode`.
+    pub fn supports_mode(&self, mode: &DisplayMode) -> bool {
+        mode.bits_per_pixel == BITS_PER_PIXEL
+            && mode.width > 0 && mode.width <= self.max_width
+            && mode.height > 0 && mode.height <= self.max_height
+            && mode.size_in_bytes() <= self.video_memory_size
+    }
+
+    /// Returns the standard display modes that this adapter supports, from smallest to largest.
+    ///
+    /// Other resolutions are supported too, up to a maximum of `max_width` by `max_height`.
+    pub fn modes(&self) -> Vec<DisplayMode> {
+        STANDARD_RESOLUTIONS.iter()
+            .map(|&(width, height)| DisplayMode { width, height, bits_per_pixel: BITS_PER_PIXEL })
+            .filter(|mode| self.supports_mode(mode))
+            .collect()
+    }
+
+    /// Returns the largest supported `(width, height)` in pixels.
+    pub fn max_resolution(&self) -> (usize, usize) {
+        (self.max_width, self.max_height)
+    }
+
+    /// Returns the current display mode, or `None` if the DISPI interface is disabled,
+    /// e.g., because the display is in a legacy VGA mode.
+    pub fn current_mode(&self) -> Option<DisplayMode> {
+        if read_register(DISPI_INDEX_ENABLE) & DISPI_ENABLED == 0 {
+            return None;
+        }
+        Some(DisplayMode {
+            width: read_register(DISPI_INDEX_XRES) as usize,
+            height: read_register(DISPI_INDEX_YRES) as usize,
+            bits_per_pixel: read_register(DISPI_INDEX_BPP),
+        })
+    }
+
+    /// Switches the display to the given `mode` with the linear framebuffer enabled.
+    ///
+    /// The contents of the framebuffer are undefined afterwards,
+    /// and any existing mapping of the framebuffer must be recreated with the new dimensions.
+    pub fn set_mode(&self, mode: &DisplayMode) -> Result<(), &'static str> {
+        if !self.supports_mode(mode) {
+            return Err("the Bochs display adapter does not support that display mode");
+        }
+        write_register(DISPI_INDEX_ENABLE, 0);
+        write_register(DISPI_INDEX_XRES, mode.width as u16);
+        write_register(DISPI_INDEX_YRES, mode.height as u16);
+        write_register(DISPI_INDEX_BPP, mode.bits_per_pixel);
+        write_register(DISPI_INDEX_VIRT_WIDTH, mode.width as u16);
+        write_register(DISPI_INDEX_VIRT_HEIGHT, mode.height as u16);
+        write_register(DISPI_INDEX_X_OFFSET, 0);
+        write_register(DISPI_INDEX_Y_OFFSET, 0);
+        write_register(DISPI_INDEX_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED);
+
+        if self.current_mode().as_ref() != Some(mode) {
+            return Err("the Bochs display adapter did not accept the display mode");
+        }
+        info!("bochs_display: set display mode to {}", mode);
+        Ok(())
+    }
+}
+
+
+fn read_register(index: u16) -> u16 {
+    let ports = DISPI_PORTS.lock();
+    unsafe { ports.0.write(index); }
+    ports.1.read()
+}
+
+fn write_register(index: u16, value: u16) {
+    let ports = DISPI_PORTS.lock();
+    unsafe {
+        ports.0.write(index);
+        ports.1.write(value);
+    }
+}
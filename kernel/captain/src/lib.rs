@@ -26,13 +26,17 @@ extern crate irq_safety; // for irq-safe locking and interrupt utilities
 extern crate dfqueue; // decoupled, fault-tolerant queue
 
 extern crate logger;
+extern crate log_file;
 extern crate memory; // the virtual memory subsystem 
 extern crate stack;
+extern crate tss;
 extern crate apic; 
 extern crate mod_mgmt;
 extern crate spawn;
 extern crate tsc;
 extern crate task; 
+extern crate cpu_topology;
+extern crate protection_keys;
 extern crate interrupts;
 extern crate acpi;
 extern crate device_manager;
@@ -41,10 +45,17 @@ extern crate scheduler;
 #[cfg(mirror_log_to_vga)] #[macro_use] extern crate print;
 extern crate first_application;
 extern crate exceptions_full;
+extern crate tlb_shootdown;
 extern crate network_manager;
 extern crate window_manager;
 extern crate multiple_heaps;
+extern crate heap;
+extern crate relink_service;
+extern crate frame_zeroer;
+extern crate smp_call;
+extern crate heap_shrinker;
 #[cfg(simd_personality)] extern crate simd_personality;
+#[cfg(parallel_crate_loading)] extern crate parallel_crate_loader;
 
 
 
@@ -84,37 +95,52 @@ pub fn init(
 
     // calculate TSC period and initialize it
     // not strictly necessary, but more accurate if we do it early on before interrupts, multicore, and multitasking
-    let _tsc_freq = tsc::get_tsc_frequency()?;
-    // info!("TSC frequency calculated: {}", _tsc_freq);
+    let tsc_freq = tsc::get_tsc_frequency()?;
+    // info!("TSC frequency calculated: {}", tsc_freq);
+    // the logger needs the TSC frequency in order to rate limit noisy call sites
+    logger::set_timestamp_frequency(tsc_freq);
 
     // now we initialize early driver stuff, like APIC/ACPI
     device_manager::early_init(kernel_mmi_ref.lock().deref_mut())?;
 
     // initialize the rest of the BSP's interrupt stuff, including TSS & GDT
-    let (double_fault_stack, privilege_stack) = {
+    let (interrupt_stacks, privilege_stack) = {
         let frame_allocator_ref = memory::get_frame_allocator_ref().ok_or("frame allocator not initialized")?;
         let mut kernel_mmi = kernel_mmi_ref.lock();
         (
-            stack::alloc_stack(1, &mut kernel_mmi.page_table, frame_allocator_ref)
-                .ok_or("could not allocate double fault stack")?,
+            tss::InterruptStacks::new(&mut kernel_mmi.page_table, frame_allocator_ref)?,
             stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, &mut kernel_mmi.page_table, frame_allocator_ref)
                 .ok_or("could not allocate privilege stack")?,
         )
     };
-    let idt = interrupts::init(double_fault_stack.top_unusable(), privilege_stack.top_unusable())?;
+    let idt = interrupts::init(interrupt_stacks, privilege_stack.top_unusable())?;
     
     // init other featureful (non-exception) interrupt handlers
     // interrupts::init_handlers_pic();
     interrupts::init_handlers_apic();
+    // allow functions to be run on other cores via IPIs
+    smp_call::init()?;
     
     // get BSP's apic id
     let bsp_apic_id = apic::get_bsp_id().ok_or("captain::init(): Coudln't get BSP's apic_id!")?;
+    let bsp_cpu = cpu_topology::init_current_core(bsp_apic_id)?;
+    info!("BSP core {} is a {:?} core (package {}, core {}, SMT thread {})",
+        bsp_apic_id, bsp_cpu.core_type, bsp_cpu.package_id, bsp_cpu.core_id, bsp_cpu.smt_id
+    );
+    let (smep, smap) = memory::enable_smep_smap();
+    info!("SMEP enabled: {}, SMAP enabled: {}", smep, smap);
+    info!("Protection keys enforced: {}", protection_keys::init_current_core());
 
     // create the initial `Task`, which is bootstrapped from this execution context.
     let bootstrap_task = spawn::init(kernel_mmi_ref.clone(), bsp_apic_id, bsp_initial_stack)?;
+    // the heap profiler attributes each allocation to the application crate of the task that requested it
+    heap::profiler::set_requester_function(task::get_my_current_app_crate_name);
 
     // after we've initialized the task subsystem, we can use better exception handlers
     exceptions_full::init(idt);
+
+    // the BSP must receive TLB shootdowns from the APs, just like each AP does from every other core
+    tlb_shootdown::init();
     
     // boot up the other cores (APs)
     let ap_count = multicore_bringup::handle_ap_cores(kernel_mmi_ref.clone(), ap_start_realmode_begin, ap_start_realmode_end)?;
@@ -124,12 +150,37 @@ pub fn init(
     multiple_heaps::switch_to_multiple_heaps()?;
     info!("Initialized per-core heaps");
 
+    // initialize the per core frame caches, now that we know how many cores there are
+    memory::init_frame_caches(apic::get_lapics().iter().map(|(apic_id, _lapic)| *apic_id))?;
+    info!("Initialized per-core frame caches");
+
+    // let all cores keep each page table's TLB entries across page table switches, if the CPU supports it
+    memory::init_pcids(apic::get_lapics().iter().map(|(apic_id, _lapic)| *apic_id))?;
+
+    // allow the tick length and timeslices, and the maximum heap size, to be tuned at runtime
+    scheduler::register_tunables()?;
+    multiple_heaps::register_tunables()?;
+
+    // Now that all cores are up and running, we can use them to load the rest of the kernel crates in parallel.
+    #[cfg(parallel_crate_loading)]
+    {
+        let default_namespace = mod_mgmt::get_initial_kernel_namespace().ok_or("initial kernel CrateNamespace not yet initialized")?;
+        parallel_crate_loader::preload_namespace_crates(default_namespace, &kernel_mmi_ref)?;
+    }
+
     // initialize window manager.
     let (key_producer, mouse_producer) = window_manager::init()?;
 
     // initialize the rest of our drivers
     device_manager::init(key_producer, mouse_producer)?;
     task_fs::init()?;
+    // now that the filesystem is available, the log can also be written to a file
+    if let Err(e) = log_file::attach(log_file::DEFAULT_LOG_FILE_PATH, log::LevelFilter::Info) {
+        error!("captain::init(): couldn't write the log to {:?}: {}", log_file::DEFAULT_LOG_FILE_PATH, e);
+    }
+    relink_service::init()?;
+    frame_zeroer::init()?;
+    heap_shrinker::init()?;
 
 
     // We can drop and unmap the identity mappings (e.g., for the multiboot2 boot_info) 
@@ -0,0 +1,166 @@
+//! The format in which a checkpoint is stored in the key-value store.
+//!
+//! A checkpoint named `NAME` is stored under the following keys:
+//! * `checkpoint/NAME/info`: the [`Header`], which describes the task and its regions and files.
+//! * `checkpoint/NAME/region/R/C`: the `C`th chunk of the contents of the `R`th region,
+//!   each of which is `kv_store::MAX_VALUE_LEN` bytes long, except for the last chunk of a region.
+//!
+//! All integers in the header are little-endian, and every string or list is preceded by its `u32` length.
+//!
+//! [`Header`]: struct.Header.html
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::convert::TryInto;
+
+/// The first 4 bytes of every header, "CKPT".
+const HEADER_MAGIC: u32 = 0x5450_4B43;
+/// The version of the header format, which is increased on every incompatible change.
+const HEADER_VERSION: u32 = 1;
+
+/// The prefix of every key that belongs to a checkpoint.
+pub const KEY_PREFIX: &'static str = "checkpoint/";
+
+pub fn info_key(name: &str) -> String {
+    format!("{}{}/info", KEY_PREFIX, name)
+}
+
+pub fn chunk_key(name: &str, region: usize, chunk: usize) -> String {
+    format!("{}{}/region/{}/{}", KEY_PREFIX, name, region, chunk)
+}
+
+/// Returns the number of chunks that a region of the given size is stored in.
+pub fn num_chunks(size_in_bytes: usize) -> usize {
+    (size_in_bytes + kv_store::MAX_VALUE_LEN - 1) / kv_store::MAX_VALUE_LEN
+}
+
+
+/// Everything about a checkpointed task except for the contents of its regions.
+#[derive(Debug, Default)]
+pub struct Header {
+    /// The name of the task's application crate, without its hash.
+    pub app_name: String,
+    pub args: Vec<String>,
+    /// The absolute path of the task's working directory.
+    pub working_dir: String,
+    pub variables: Vec<(String, String)>,
+    pub resume_data: Vec<u8>,
+    /// The name and size in bytes of each region.
+    pub regions: Vec<(String, usize)>,
+    /// The absolute path and offset of each file.
+    pub files: Vec<(String, usize)>,
+}
+
+impl Header {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_u32(&mut out, HEADER_MAGIC);
+        put_u32(&mut out, HEADER_VERSION);
+        put_str(&mut out, &self.app_name);
+        put_u32(&mut out, self.args.len() as u32);
+        for arg in self.args.iter() {
+            put_str(&mut out, arg);
+        }
+        put_str(&mut out, &self.working_dir);
+        put_u32(&mut out, self.variables.len() as u32);
+        for (key, value) in self.variables.iter() {
+            put_str(&mut out, key);
+            put_str(&mut out, value);
+        }
+        put_bytes(&mut out, &self.resume_data);
+        put_u32(&mut out, self.regions.len() as u32);
+        for (name, size) in self.regions.iter() {
+            put_str(&mut out, name);
+            put_u64(&mut out, *size as u64);
+        }
+        put_u32(&mut out, self.files.len() as u32);
+        for (path, offset) in self.files.iter() {
+            put_str(&mut out, path);
+            put_u64(&mut out, *offset as u64);
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Header, &'static str> {
+        let mut r = Reader { bytes };
+        if r.u32()? != HEADER_MAGIC {
+            return Err("checkpoint: the checkpoint's header is invalid");
+        }
+        if r.u32()? != HEADER_VERSION {
+            return Err("checkpoint: the checkpoint was written in an unsupported format version");
+        }
+        let mut header = Header::default();
+        header.app_name = r.string()?;
+        for _ in 0 .. r.u32()? {
+            header.args.push(r.string()?);
+        }
+        header.working_dir = r.string()?;
+        for _ in 0 .. r.u32()? {
+            let key = r.string()?;
+            header.variables.push((key, r.string()?));
+        }
+        header.resume_data = r.bytes()?.to_vec();
+        for _ in 0 .. r.u32()? {
+            let name = r.string()?;
+            header.regions.push((name, r.u64()? as usize));
+        }
+        for _ in 0 .. r.u32()? {
+            let path = r.string()?;
+            header.files.push((path, r.u64()? as usize));
+        }
+        Ok(header)
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_bytes(out, s.as_bytes());
+}
+
+/// Reads the fields of a header in order.
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, len: usize) -> Result<&'b [u8], &'static str> {
+        if self.bytes.len() < len {
+            return Err("checkpoint: the checkpoint's header is truncated");
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        self.take(4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        self.take(8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'b [u8], &'static str> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        let bytes = self.bytes()?;
+        core::str::from_utf8(bytes).map(String::from).map_err(|_| "checkpoint: the checkpoint's header contains an invalid string")
+    }
+}
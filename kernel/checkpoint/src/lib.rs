@@ -0,0 +1,327 @@
+//! Checkpoints of an application task's state, which are written to disk and can be restored after a reboot,
+//! e.g., to resume a long-running experiment after the machine was rebooted to apply an update.
+//!
+//! Crates are loaded at different, randomized addresses on every boot, and may even be updated in between,
+//! so a task's saved registers and any pointers into its stack are meaningless after a reboot.
+//! Thus, a [`Checkpoint`] instead captures a task's state at the level at which it can be recreated:
+//! * the application crate and arguments that the task was spawned with, in place of its registers,
+//!   along with its working directory and environment variables;
+//! * a small blob of "resume data", e.g., the experiment's current iteration, which tells the task where to resume;
+//! * the contents of the memory regions that hold the task's state, e.g., its buffers of intermediate results;
+//! * the files that the task had open, by path and offset.
+//!
+//! A task takes a checkpoint of itself at a point where its state is consistent, by adding its regions and files
+//! to a `Checkpoint` and then [`save()`]ing it. Each region is duplicated via a copy-on-write clone when it's added,
+//! so the task may keep modifying it right away, while the clone is written out by a background task.
+//! A checkpoint is written to the `kv_store` in a single transaction, so a crash or reboot midway through leaves
+//! the previous checkpoint of the same name intact. The `kv_store` must have been started on a storage device.
+//!
+//! [`restore()`] respawns the checkpointed task from the latest version of its application crate,
+//! and the new task retrieves its regions, files, and resume data via [`take_restored_state()`].
+//!
+//! [`Checkpoint`]: struct.Checkpoint.html
+//! [`save()`]: struct.Checkpoint.html#method.save
+//! [`restore()`]: fn.restore.html
+//! [`take_restored_state()`]: fn.take_restored_state.html
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
+extern crate spin;
+extern crate memory;
+extern crate task;
+extern crate spawn;
+extern crate environment;
+extern crate fs_node;
+extern crate path;
+extern crate root;
+extern crate kv_store;
+
+mod format;
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use memory::{EntryFlags, MappedPages, CachedFrameAllocator};
+use task::{TaskRef, ExitValue};
+use environment::Environment;
+use fs_node::{FileOrDir, FileRef};
+use path::Path;
+use format::{Header, KEY_PREFIX, info_key, chunk_key, num_chunks};
+
+
+/// The maximum length of a checkpoint's name.
+pub const MAX_NAME_LEN: usize = 64;
+
+lazy_static! {
+    /// The state of each restored task that it hasn't taken yet, keyed by task ID.
+    static ref RESTORED_STATES: Mutex<BTreeMap<usize, RestoredState>> = Mutex::new(BTreeMap::new());
+}
+
+
+/// The state of an application task that is about to be saved, see the [crate-level docs](index.html).
+pub struct Checkpoint {
+    header: Header,
+    /// The copy-on-write clone of each region, in the same order as `header.regions`.
+    regions: Vec<MappedPages>,
+}
+
+impl Checkpoint {
+    /// Starts a checkpoint of the current task, which must be an application task,
+    /// capturing its application crate and environment, along with the given `args` that it was spawned with.
+    pub fn of_current_task(args: &[String]) -> Result<Checkpoint, &'static str> {
+        let curr = task::get_my_current_task().ok_or("checkpoint: couldn't get the current task")?;
+        let crate_name = curr.lock().app_crate.as_ref()
+            .map(|app_crate| app_crate.lock_as_ref().crate_name.clone())
+            .ok_or("checkpoint: only application tasks can be checkpointed")?;
+        let env = curr.get_env();
+        let env = env.lock();
+        let header = Header {
+            // The hash is omitted, such that the task can be restored from an updated version of its crate.
+            app_name: String::from(crate_name.split('-').next().unwrap_or(&crate_name)),
+            args: args.to_vec(),
+            working_dir: env.get_wd_path(),
+            variables: env.variables.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            ..Header::default()
+        };
+        Ok(Checkpoint { header, regions: Vec::new() })
+    }
+
+    /// Sets the data that tells the restored task where to resume, which is empty by default.
+    pub fn set_resume_data(&mut self, data: &[u8]) -> &mut Checkpoint {
+        self.header.resume_data = data.to_vec();
+        self
+    }
+
+    /// Adds the current contents of the given memory region to this checkpoint under the given `name`,
+    /// by which the restored task can retrieve it.
+    ///
+    /// The region is duplicated via a copy-on-write clone, so later modifications of it aren't included in this checkpoint.
+    /// The region must not be a lazily-mapped one.
+    pub fn add_region(&mut self, name: &str, region: &mut MappedPages) -> Result<&mut Checkpoint, &'static str> {
+        if self.header.regions.iter().any(|(n, _)| n == name) {
+            return Err("checkpoint: a region with that name was already added");
+        }
+        let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("checkpoint: KERNEL_MMI was not yet initialized!")?;
+        let clone = region.clone_cow(&mut kernel_mmi_ref.lock().page_table, &mut CachedFrameAllocator)?;
+        self.header.regions.push((String::from(name), clone.size_in_bytes()));
+        self.regions.push(clone);
+        Ok(self)
+    }
+
+    /// Adds the given file, from which the task will resume reading or writing at the given `offset`.
+    ///
+    /// Only the file's path is saved, not its contents, so it must be on a filesystem that persists across reboots
+    /// in order to be reopened by the restored task.
+    pub fn add_file(&mut self, file: &FileRef, offset: usize) -> &mut Checkpoint {
+        self.header.files.push((file.lock().get_absolute_path(), offset));
+        self
+    }
+
+    /// Writes this checkpoint to disk under the given `name`, replacing any previous checkpoint with that name.
+    ///
+    /// The checkpoint is written by a background task, so this returns right away;
+    /// call [`PendingSave::wait()`](struct.PendingSave.html#method.wait) to find out whether it was written successfully.
+    pub fn save(self, name: &str) -> Result<PendingSave, &'static str> {
+        validate_name(name)?;
+        if !kv_store::is_started() {
+            return Err("checkpoint: the key-value store hasn't been started on a storage device");
+        }
+        let writer = spawn::new_task_builder(write_checkpoint, (String::from(name), self))
+            .name(format!("checkpoint_writer_{}", name))
+            .spawn()?;
+        Ok(PendingSave { writer })
+    }
+}
+
+/// A checkpoint that is being written to disk, see [`Checkpoint::save()`](struct.Checkpoint.html#method.save).
+pub struct PendingSave {
+    writer: TaskRef,
+}
+
+impl PendingSave {
+    /// Waits until the checkpoint has been written, and returns whether it was written successfully.
+    pub fn wait(self) -> Result<(), &'static str> {
+        self.writer.join()?;
+        match self.writer.take_exit_value() {
+            Some(ExitValue::Completed(exit_value)) => exit_value.downcast_ref::<Result<(), &'static str>>()
+                .cloned()
+                .unwrap_or(Err("checkpoint: the checkpoint writer returned an invalid value")),
+            _ => Err("checkpoint: the checkpoint writer was killed"),
+        }
+    }
+}
+
+fn write_checkpoint((name, checkpoint): (String, Checkpoint)) -> Result<(), &'static str> {
+    // Any keys of an older checkpoint with the same name are removed in the same transaction,
+    // since it may have had more regions or chunks than this one.
+    let old_keys = kv_store::keys(&format!("{}{}/", KEY_PREFIX, name))?;
+    let mut transaction = kv_store::Transaction::new();
+    for key in old_keys.iter() {
+        transaction.delete(key);
+    }
+    for (region_index, region) in checkpoint.regions.iter().enumerate() {
+        let size = checkpoint.header.regions[region_index].1;
+        let bytes: &[u8] = region.as_slice(0, size)?;
+        for (chunk_index, chunk) in bytes.chunks(kv_store::MAX_VALUE_LEN).enumerate() {
+            transaction.put(&chunk_key(&name, region_index, chunk_index), chunk);
+        }
+    }
+    transaction.put(&info_key(&name), &checkpoint.header.encode());
+    let result = transaction.commit();
+    match result {
+        Ok(()) => info!("checkpoint: saved checkpoint {:?} of application {:?}", name, checkpoint.header.app_name),
+        Err(e) => error!("checkpoint: couldn't save checkpoint {:?}: {}", name, e),
+    }
+    result
+}
+
+
+/// A file that was added to a checkpoint.
+#[derive(Debug)]
+pub struct RestoredFile {
+    /// The absolute path of the file.
+    pub path: String,
+    /// The file at that path, if it still exists.
+    pub file: Option<FileRef>,
+    /// The offset at which to resume reading or writing the file.
+    pub offset: usize,
+}
+
+/// The state of a task that was restored from a checkpoint, see [`take_restored_state()`](fn.take_restored_state.html).
+pub struct RestoredState {
+    /// The name of the checkpoint that this task was restored from.
+    pub name: String,
+    /// The data that was set via `Checkpoint::set_resume_data()`.
+    pub resume_data: Vec<u8>,
+    /// Each region of the checkpoint by name, which is a new writable mapping with the region's saved contents.
+    pub regions: BTreeMap<String, MappedPages>,
+    /// The files of the checkpoint, in the order they were added.
+    pub files: Vec<RestoredFile>,
+}
+
+/// Respawns the task that was saved in the checkpoint with the given `name`,
+/// using the latest version of its application crate in the current task's namespace.
+///
+/// The new task is spawned with the checkpoint's arguments and environment, and must retrieve the rest of its state
+/// via [`take_restored_state()`](fn.take_restored_state.html).
+pub fn restore(name: &str) -> Result<TaskRef, &'static str> {
+    validate_name(name)?;
+    let header = kv_store::get(&info_key(name))?.ok_or("checkpoint: no checkpoint with that name exists")?;
+    let header = Header::decode(&header)?;
+
+    let mut regions = BTreeMap::new();
+    for (region_index, (region_name, size)) in header.regions.iter().enumerate() {
+        let mut region = memory::create_mapping(*size, EntryFlags::WRITABLE)?;
+        {
+            let bytes: &mut [u8] = region.as_slice_mut(0, *size)?;
+            for chunk_index in 0 .. num_chunks(*size) {
+                let chunk = kv_store::get(&chunk_key(name, region_index, chunk_index))?
+                    .ok_or("checkpoint: a chunk of the checkpoint's regions is missing")?;
+                let start = chunk_index * kv_store::MAX_VALUE_LEN;
+                if chunk.len() != core::cmp::min(kv_store::MAX_VALUE_LEN, *size - start) {
+                    return Err("checkpoint: a chunk of the checkpoint's regions has the wrong size");
+                }
+                bytes[start .. start + chunk.len()].copy_from_slice(&chunk);
+            }
+        }
+        regions.insert(region_name.clone(), region);
+    }
+
+    let root = root::get_root();
+    let files = header.files.iter().map(|(path, offset)| {
+        let file = Path::new(path.clone()).get_file(root);
+        if file.is_none() {
+            warn!("checkpoint: file {:?} of checkpoint {:?} no longer exists", path, name);
+        }
+        RestoredFile { path: path.clone(), file, offset: *offset }
+    }).collect();
+
+    let working_dir = match Path::new(header.working_dir.clone()).get(root) {
+        Some(FileOrDir::Dir(dir)) => dir,
+        _ => {
+            warn!("checkpoint: working directory {:?} of checkpoint {:?} no longer exists", header.working_dir, name);
+            root.clone()
+        }
+    };
+    let env = Environment {
+        working_dir,
+        variables: header.variables.iter().cloned().collect(),
+        ..Environment::default()
+    };
+
+    let namespace = task::get_my_current_task().ok_or("checkpoint: couldn't get the current task")?.get_namespace();
+    let app_file = namespace.dir().get_file_starting_with(&format!("{}-", header.app_name))
+        .ok_or("checkpoint: couldn't find a single application crate that the checkpointed task was spawned from")?;
+    let app_path = Path::new(app_file.lock().get_absolute_path());
+
+    // The new task is only unblocked once its restored state is available to it.
+    let new_task = spawn::new_application_task_builder(app_path, None)?
+        .argument(header.args.clone())
+        .env(Arc::new(Mutex::new(env)))
+        .block()
+        .spawn()?;
+    let new_task_id = new_task.lock().id;
+    RESTORED_STATES.lock().insert(new_task_id, RestoredState {
+        name: String::from(name),
+        resume_data: header.resume_data,
+        regions,
+        files,
+    });
+    new_task.unblock();
+    info!("checkpoint: restored checkpoint {:?} as task {}", name, new_task_id);
+    Ok(new_task)
+}
+
+/// Returns the restored state of the current task, if it was restored from a checkpoint via `restore()`.
+///
+/// This can only be taken once, so a task should do so when it starts.
+pub fn take_restored_state() -> Option<RestoredState> {
+    let id = task::get_my_current_task_id()?;
+    RESTORED_STATES.lock().remove(&id)
+}
+
+
+/// Returns the names of all saved checkpoints.
+pub fn list() -> Result<Vec<String>, &'static str> {
+    Ok(kv_store::keys(KEY_PREFIX)?.iter()
+        .filter(|key| key.ends_with("/info"))
+        .map(|key| String::from(&key[KEY_PREFIX.len() .. key.len() - "/info".len()]))
+        .collect())
+}
+
+/// Returns the name of the application crate that the checkpoint with the given `name` was taken of,
+/// and the total size in bytes of its regions.
+pub fn describe(name: &str) -> Result<(String, usize), &'static str> {
+    validate_name(name)?;
+    let header = kv_store::get(&info_key(name))?.ok_or("checkpoint: no checkpoint with that name exists")?;
+    let header = Header::decode(&header)?;
+    Ok((header.app_name, header.regions.iter().map(|(_, size)| size).sum()))
+}
+
+/// Deletes the checkpoint with the given `name` from disk.
+pub fn delete(name: &str) -> Result<(), &'static str> {
+    validate_name(name)?;
+    let keys = kv_store::keys(&format!("{}{}/", KEY_PREFIX, name))?;
+    if keys.is_empty() {
+        return Err("checkpoint: no checkpoint with that name exists");
+    }
+    let mut transaction = kv_store::Transaction::new();
+    for key in keys.iter() {
+        transaction.delete(key);
+    }
+    transaction.commit()
+}
+
+fn validate_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/') {
+        return Err("checkpoint: a checkpoint's name must be 1 to 64 characters long, and must not contain '/'");
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! A registry of named configuration parameters ("tunables") that can be changed at runtime,
+//! such that settings can be explored without recompiling `kernel_config`.
+//!
+//! A kernel component defines each of its tunables as a `static` [`Tunable`], which holds its current value
+//! and the bounds that any new value must lie within, and reads that value directly wherever it's needed.
+//! The component then registers its tunables via [`register()`], optionally with a function that applies
+//! each new value, after which they can be listed, read, and changed by name, e.g., by the `config` application.
+//!
+//! Names are dot-separated, starting with the name of the component that owns the tunable,
+//! e.g., `scheduler.tick_us`.
+//!
+//! Changed values are only kept in memory, unless a persistent store has been installed via [`set_persistence()`],
+//! e.g., by the `kv_store` once it has been started.
+//! Afterwards, every tunable is restored to its persisted value (if any) when it's registered,
+//! and every change is persisted.
+//!
+//! [`Tunable`]: struct.Tunable.html
+//! [`register()`]: fn.register.html
+//! [`set_persistence()`]: fn.set_persistence.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate log;
+extern crate spin;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use spin::{Mutex, Once};
+
+
+/// A function that applies a new value of a tunable, which is given as its argument.
+/// It's invoked after the new value has been stored, and the old value is restored if it returns an error.
+pub type ApplyFn = fn(u64) -> Result<(), &'static str>;
+
+/// The functions through which the values of tunables are persisted, see [`set_persistence()`](fn.set_persistence.html).
+#[derive(Clone, Copy)]
+pub struct Persistence {
+    /// Returns the persisted value of the tunable with the given name, if there is one.
+    pub load: fn(&str) -> Option<u64>,
+    /// Persists the given new value of the tunable with the given name.
+    pub save: fn(&str, u64) -> Result<(), &'static str>,
+}
+
+
+/// A configuration parameter whose value can be changed at runtime, within the bounds `min ..= max`.
+pub struct Tunable {
+    name: &'static str,
+    description: &'static str,
+    default: u64,
+    min: u64,
+    max: u64,
+    value: AtomicU64,
+}
+
+impl Tunable {
+    /// Creates a new tunable with the given `default` value, which must be within `min ..= max`.
+    pub const fn new(name: &'static str, description: &'static str, default: u64, min: u64, max: u64) -> Tunable {
+        Tunable { name, description, default, min, max, value: AtomicU64::new(default) }
+    }
+
+    /// Returns the current value of this tunable.
+    #[inline(always)]
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Returns the name of this tunable.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns a one-line description of this tunable, including its unit.
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+
+    /// Returns the value of this tunable before it was ever changed.
+    pub fn default(&self) -> u64 {
+        self.default
+    }
+
+    /// Returns the bounds `(min, max)`, inclusive, that the value of this tunable must lie within.
+    pub fn bounds(&self) -> (u64, u64) {
+        (self.min, self.max)
+    }
+}
+
+
+/// A registered tunable and the function that applies its new values, if any.
+struct Entry {
+    tunable: &'static Tunable,
+    apply: Option<ApplyFn>,
+}
+
+lazy_static! {
+    /// All registered tunables, keyed by name.
+    /// The lock is also held while a tunable is being changed, such that concurrent changes are applied in order.
+    static ref REGISTRY: Mutex<BTreeMap<&'static str, Entry>> = Mutex::new(BTreeMap::new());
+}
+
+/// The store that the values of tunables are persisted to, if one has been installed.
+static PERSISTENCE: Once<Persistence> = Once::new();
+
+
+/// Registers the given tunable, such that it can be found and changed by its name.
+///
+/// If `apply` is given, it's invoked with every new value of the tunable,
+/// e.g., to reprogram hardware whose setting cannot be read from the tunable on demand.
+///
+/// If a persistent store has been installed, the tunable is then set to its persisted value, if there is one.
+///
+/// Returns an error if a tunable with the same name was already registered.
+pub fn register(tunable: &'static Tunable, apply: Option<ApplyFn>) -> Result<(), &'static str> {
+    if tunable.default < tunable.min || tunable.default > tunable.max {
+        return Err("the default value of the tunable is out of its bounds");
+    }
+    {
+        let mut registry = REGISTRY.lock();
+        if registry.contains_key(tunable.name) {
+            return Err("a tunable with the same name was already registered");
+        }
+        registry.insert(tunable.name, Entry { tunable, apply });
+    }
+    if let Some(persistence) = PERSISTENCE.try() {
+        restore(persistence, tunable.name);
+    }
+    Ok(())
+}
+
+/// Installs the given store that the values of tunables are persisted to from now on,
+/// and sets every registered tunable to its persisted value, if there is one.
+///
+/// Returns the number of tunables whose persisted values were restored,
+/// or an error if a store was already installed.
+pub fn set_persistence(persistence: Persistence) -> Result<usize, &'static str> {
+    let mut newly_installed = false;
+    PERSISTENCE.call_once(|| { newly_installed = true; persistence });
+    if !newly_installed {
+        return Err("a persistent store for tunables was already installed");
+    }
+    let names: Vec<&'static str> = REGISTRY.lock().keys().cloned().collect();
+    Ok(names.into_iter().filter(|name| restore(&persistence, name)).count())
+}
+
+/// Sets the tunable with the given name to its persisted value, if there is one,
+/// and returns whether it was restored.
+fn restore(persistence: &Persistence, name: &str) -> bool {
+    let value = match (persistence.load)(name) {
+        Some(value) => value,
+        None => return false,
+    };
+    match set_value(name, value) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("config_registry: couldn't restore tunable {} to its persisted value {}: {}", name, value, e);
+            false
+        }
+    }
+}
+
+/// Returns the registered tunable with the given name.
+pub fn get(name: &str) -> Option<&'static Tunable> {
+    REGISTRY.lock().get(name).map(|entry| entry.tunable)
+}
+
+/// Returns all registered tunables, sorted by name.
+pub fn tunables() -> Vec<&'static Tunable> {
+    REGISTRY.lock().values().map(|entry| entry.tunable).collect()
+}
+
+/// Sets the tunable with the given name to the given value, and applies it.
+///
+/// Returns an error if there is no such tunable, if the value is out of its bounds,
+/// or if the new value couldn't be applied, in which case the tunable keeps its old value.
+///
+/// If a persistent store has been installed, the new value is then persisted;
+/// failing to do so is logged, but doesn't undo the change.
+pub fn set(name: &str, value: u64) -> Result<(), &'static str> {
+    set_value(name, value)?;
+    if let Some(persistence) = PERSISTENCE.try() {
+        if let Err(e) = (persistence.save)(name, value) {
+            warn!("config_registry: couldn't persist the new value {} of tunable {}: {}", value, name, e);
+        }
+    }
+    Ok(())
+}
+
+/// Sets the tunable with the given name to the given value, and applies it, without persisting it.
+fn set_value(name: &str, value: u64) -> Result<(), &'static str> {
+    let registry = REGISTRY.lock();
+    let entry = registry.get(name).ok_or("no tunable with that name was registered")?;
+    let tunable = entry.tunable;
+    if value < tunable.min || value > tunable.max {
+        return Err("the value is out of the tunable's bounds");
+    }
+    let old_value = tunable.value.swap(value, Ordering::SeqCst);
+    if let Some(apply) = entry.apply {
+        if let Err(e) = apply(value) {
+            tunable.value.store(old_value, Ordering::SeqCst);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Sets the tunable with the given name back to its default value, and applies it. See [`set()`](fn.set.html).
+pub fn reset(name: &str) -> Result<(), &'static str> {
+    let default = get(name).ok_or("no tunable with that name was registered")?.default;
+    set(name, default)
+}
@@ -0,0 +1,355 @@
+//! A registry of the CPU cores in the system and their properties:
+//! which package (socket) and physical core each logical CPU belongs to, which SMT thread it is on that core,
+//! which type of core it is, and which caches it has and shares with other CPUs.
+//!
+//! The logical CPUs themselves are enumerated via the ACPI MADT, which is used to boot each AP.
+//! Each CPU then detects its own properties via CPUID when it calls [`init_current_core()`] during its boot,
+//! so a CPU only appears in this registry once it has booted.
+//!
+//! # Heterogeneous cores
+//! On hybrid CPUs, e.g., Intel's Alder Lake, there are two types of cores:
+//! fast performance cores (P-cores) and slower but more power-efficient efficiency cores (E-cores).
+//! Each core's [`CoreType`] is detected via CPUID leaf `0x1A`,
+//! which the scheduler then uses to place each task on a core that matches its [`CoreTypeHint`].
+//! On CPUs that aren't hybrid, all cores are regarded as performance cores.
+//!
+//! # Packages, cores, and SMT threads
+//! The APIC ID of each logical CPU is composed of its package ID, core ID, and SMT ID, in that order from the highest bits.
+//! The width of each field is given by CPUID leaf `0xB`, or is derived from the number of logical CPUs and cores
+//! per package in CPUID leaves `0x1` and `0x4` on older CPUs.
+//!
+//! # Caches
+//! Each cache is described by CPUID leaf `0x4` on Intel CPUs, or leaf `0x8000_001D` on AMD CPUs,
+//! including how many logical CPUs share it. Two CPUs share a cache if their APIC IDs only differ in the bits
+//! that distinguish the CPUs sharing that cache, which is captured by each cache's [`sharing_id`].
+//!
+//! [`init_current_core()`]: fn.init_current_core.html
+//! [`CoreType`]: enum.CoreType.html
+//! [`CoreTypeHint`]: enum.CoreTypeHint.html
+//! [`sharing_id`]: struct.CacheInfo.html#structfield.sharing_id
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
+extern crate atomic_linked_list;
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use alloc::vec::Vec;
+use atomic_linked_list::atomic_map::AtomicMap;
+
+
+/// The CPUID leaf that enumerates the widths of the SMT and core fields of the APIC ID.
+const CPUID_LEAF_TOPOLOGY: u32 = 0xB;
+/// The CPUID leaf that describes Intel's caches.
+const CPUID_LEAF_INTEL_CACHES: u32 = 0x4;
+/// The CPUID leaf that describes AMD's caches, in the same format as `CPUID_LEAF_INTEL_CACHES`.
+const CPUID_LEAF_AMD_CACHES: u32 = 0x8000_001D;
+/// The bit in CPUID leaf `0x8000_0001`'s `ecx` that indicates that `CPUID_LEAF_AMD_CACHES` exists.
+const CPUID_8000_0001_ECX_TOPOLOGY_EXTENSIONS: u32 = 1 << 22;
+/// The bit in CPUID leaf 1's `edx` that indicates that the package may have more than one logical CPU.
+const CPUID_1_EDX_HTT: u32 = 1 << 28;
+/// The level type of the SMT level in CPUID leaf `0xB`.
+const TOPOLOGY_LEVEL_SMT: u32 = 1;
+/// The level type of the core level in CPUID leaf `0xB`.
+const TOPOLOGY_LEVEL_CORE: u32 = 2;
+
+/// The CPUID leaf that describes the type of the current core on a hybrid CPU.
+const CPUID_LEAF_HYBRID_INFO: u32 = 0x1A;
+/// The bit in CPUID leaf 7's `edx` that indicates a hybrid CPU.
+const CPUID_7_EDX_HYBRID: u32 = 1 << 15;
+/// The core type in bits 31:24 of CPUID leaf `0x1A`'s `eax` that denotes an Intel Atom core, i.e., an E-core.
+const CORE_TYPE_ATOM: u32 = 0x20;
+/// The core type in bits 31:24 of CPUID leaf `0x1A`'s `eax` that denotes an Intel Core core, i.e., a P-core.
+const CORE_TYPE_CORE: u32 = 0x40;
+
+
+/// The type of a CPU core.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoreType {
+    /// A fast core, i.e., a P-core. On CPUs that aren't hybrid, every core is a performance core.
+    Performance,
+    /// A slower but more power-efficient core, i.e., an E-core.
+    Efficiency,
+}
+
+/// A hint given to the scheduler about which type of core a task should run on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoreTypeHint {
+    /// The task can run on any core.
+    Any,
+    /// The task is latency-critical, so it should run on a performance core,
+    /// preferably one whose SMT siblings are idle.
+    LatencyCritical,
+    /// The task is background work, so it should run on an efficiency core.
+    Background,
+}
+
+impl Default for CoreTypeHint {
+    fn default() -> CoreTypeHint {
+        CoreTypeHint::Any
+    }
+}
+
+impl CoreTypeHint {
+    /// Returns the type of core that a task with this hint should preferably run on, if any.
+    pub fn preferred_core_type(&self) -> Option<CoreType> {
+        match *self {
+            CoreTypeHint::Any => None,
+            CoreTypeHint::LatencyCritical => Some(CoreType::Performance),
+            CoreTypeHint::Background => Some(CoreType::Efficiency),
+        }
+    }
+}
+
+
+/// The kind of contents that a cache holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// A cache of a logical CPU.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheInfo {
+    /// The level of this cache, e.g., 1 for the L1 cache.
+    pub level: u8,
+    pub kind: CacheKind,
+    pub size_in_bytes: usize,
+    pub line_size: usize,
+    /// The associativity of this cache, i.e., the number of ways.
+    pub ways: usize,
+    /// The maximum number of logical CPUs that share this cache.
+    pub shared_by: u32,
+    /// The APIC ID of this CPU without the bits that distinguish the CPUs sharing this cache.
+    /// All CPUs that share this cache have the same `sharing_id` for it.
+    pub sharing_id: u32,
+}
+
+/// The properties of a logical CPU.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuInfo {
+    pub apic_id: u8,
+    /// The ID of the package (socket) that this CPU is in.
+    pub package_id: u32,
+    /// The ID of the physical core that this CPU is on, within its package.
+    pub core_id: u32,
+    /// The ID of this CPU's SMT thread, within its physical core.
+    pub smt_id: u32,
+    pub core_type: CoreType,
+    /// The caches of this CPU, ordered by level.
+    pub caches: Vec<CacheInfo>,
+}
+
+impl CpuInfo {
+    /// Returns whether this CPU and the `other` CPU are SMT threads on the same physical core.
+    pub fn is_smt_sibling_of(&self, other: &CpuInfo) -> bool {
+        self.apic_id != other.apic_id && self.package_id == other.package_id && self.core_id == other.core_id
+    }
+
+    /// Returns whether this CPU and the `other` CPU share a cache at the given `level`.
+    pub fn shares_cache_with(&self, other: &CpuInfo, level: u8) -> bool {
+        self.caches.iter()
+            .filter(|c| c.level == level)
+            .any(|c| other.caches.iter().any(|o| o.level == level && o.kind == c.kind && o.sharing_id == c.sharing_id))
+    }
+}
+
+
+lazy_static! {
+    /// The properties of each CPU that has been initialized, keyed by its APIC ID.
+    static ref CPUS: AtomicMap<u8, CpuInfo> = AtomicMap::new();
+}
+
+
+/// Detects the properties of the current CPU and records them in the registry under the given `apic_id`,
+/// which must be the current CPU's APIC ID.
+///
+/// This should be invoked once by each CPU as it boots, before any tasks are spawned onto it.
+pub fn init_current_core(apic_id: u8) -> Result<&'static CpuInfo, &'static str> {
+    let (smt_shift, core_shift) = detect_topology_shifts();
+    let id = apic_id as u32;
+    let info = CpuInfo {
+        apic_id,
+        package_id: id >> core_shift,
+        core_id: (id & mask(core_shift)) >> smt_shift,
+        smt_id: id & mask(smt_shift),
+        core_type: detect_current_core_type(),
+        caches: detect_caches(id),
+    };
+    if CPUS.insert(apic_id, info).is_some() {
+        warn!("cpu_topology: core {} was already initialized", apic_id);
+    }
+    CPUS.get(&apic_id).ok_or("cpu_topology: couldn't register the current core")
+}
+
+/// Returns a mask of the lowest `bits` bits.
+fn mask(bits: u32) -> u32 {
+    if bits >= 32 { !0 } else { (1 << bits) - 1 }
+}
+
+/// Returns the number of bits needed to distinguish `count` different items.
+fn bits_needed(count: u32) -> u32 {
+    if count <= 1 { 0 } else { 32 - (count - 1).leading_zeros() }
+}
+
+/// Returns the number of low bits of an APIC ID that hold the SMT ID,
+/// and the number of low bits that hold both the SMT ID and the core ID.
+fn detect_topology_shifts() -> (u32, u32) {
+    // Safe because CPUID is supported on all x86_64 CPUs, and each leaf is only read if it exists.
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= CPUID_LEAF_TOPOLOGY && unsafe { __cpuid_count(CPUID_LEAF_TOPOLOGY, 0) }.ebx != 0 {
+        let (mut smt_shift, mut core_shift) = (0, 0);
+        for subleaf in 0 .. 8 {
+            let level = unsafe { __cpuid_count(CPUID_LEAF_TOPOLOGY, subleaf) };
+            let shift = level.eax & 0x1F;
+            match (level.ecx >> 8) & 0xFF {
+                0 => break,
+                TOPOLOGY_LEVEL_SMT => smt_shift = shift,
+                TOPOLOGY_LEVEL_CORE => core_shift = shift,
+                _ => { }
+            }
+        }
+        return (smt_shift, core_shift.max(smt_shift));
+    }
+
+    let leaf_1 = unsafe { __cpuid(1) };
+    let logical_per_package = if leaf_1.edx & CPUID_1_EDX_HTT != 0 { (leaf_1.ebx >> 16) & 0xFF } else { 1 };
+    let cores_per_package = if max_leaf >= CPUID_LEAF_INTEL_CACHES {
+        ((unsafe { __cpuid_count(CPUID_LEAF_INTEL_CACHES, 0) }.eax >> 26) & 0x3F) + 1
+    } else {
+        1
+    };
+    let smt_shift = bits_needed(logical_per_package / cores_per_package);
+    (smt_shift, bits_needed(logical_per_package).max(smt_shift))
+}
+
+/// Returns the caches of the current CPU, whose APIC ID is `apic_id`, ordered by level.
+fn detect_caches(apic_id: u32) -> Vec<CacheInfo> {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    let leaf = if max_extended_leaf >= CPUID_LEAF_AMD_CACHES
+        && unsafe { __cpuid(0x8000_0001) }.ecx & CPUID_8000_0001_ECX_TOPOLOGY_EXTENSIONS != 0
+    {
+        CPUID_LEAF_AMD_CACHES
+    } else if max_leaf >= CPUID_LEAF_INTEL_CACHES {
+        CPUID_LEAF_INTEL_CACHES
+    } else {
+        return Vec::new();
+    };
+
+    let mut caches = Vec::new();
+    for subleaf in 0 .. 16 {
+        let cache = unsafe { __cpuid_count(leaf, subleaf) };
+        let kind = match cache.eax & 0x1F {
+            0 => break,
+            1 => CacheKind::Data,
+            2 => CacheKind::Instruction,
+            3 => CacheKind::Unified,
+            _ => continue,
+        };
+        let line_size = (cache.ebx & 0xFFF) as usize + 1;
+        let partitions = ((cache.ebx >> 12) & 0x3FF) as usize + 1;
+        let ways = ((cache.ebx >> 22) & 0x3FF) as usize + 1;
+        let sets = cache.ecx as usize + 1;
+        let shared_by = ((cache.eax >> 14) & 0xFFF) + 1;
+        caches.push(CacheInfo {
+            level: ((cache.eax >> 5) & 0x7) as u8,
+            kind,
+            size_in_bytes: ways * partitions * line_size * sets,
+            line_size,
+            ways,
+            shared_by,
+            sharing_id: apic_id >> bits_needed(shared_by),
+        });
+    }
+    caches.sort_by_key(|c| c.level);
+    caches
+}
+
+/// Returns the type of the current core, as reported by CPUID.
+fn detect_current_core_type() -> CoreType {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < CPUID_LEAF_HYBRID_INFO || unsafe { __cpuid_count(7, 0) }.edx & CPUID_7_EDX_HYBRID == 0 {
+        return CoreType::Performance;
+    }
+    match unsafe { __cpuid_count(CPUID_LEAF_HYBRID_INFO, 0) }.eax >> 24 {
+        CORE_TYPE_ATOM => CoreType::Efficiency,
+        CORE_TYPE_CORE => CoreType::Performance,
+        other => {
+            warn!("cpu_topology: unknown hybrid core type {:#X}, regarding it as a performance core", other);
+            CoreType::Performance
+        }
+    }
+}
+
+
+/// Returns the properties of the CPU with the given `apic_id`, or `None` if that CPU hasn't been initialized.
+///
+/// This doesn't allocate, so it may be used by the heap.
+pub fn cpu(apic_id: u8) -> Option<&'static CpuInfo> {
+    CPUS.get(&apic_id)
+}
+
+/// Returns the properties of all initialized CPUs, ordered by package, core, and SMT thread.
+pub fn cpus() -> Vec<&'static CpuInfo> {
+    let mut cpus: Vec<&'static CpuInfo> = CPUS.iter().map(|(_, info)| info).collect();
+    cpus.sort_by_key(|c| (c.package_id, c.core_id, c.smt_id));
+    cpus
+}
+
+/// Returns the type of the core with the given `apic_id`, or `None` if that core hasn't been initialized.
+pub fn core_type(apic_id: u8) -> Option<CoreType> {
+    cpu(apic_id).map(|info| info.core_type)
+}
+
+/// Returns `true` if the initialized cores are of more than one type, i.e., if this is a hybrid CPU.
+pub fn is_hybrid() -> bool {
+    let mut types = CPUS.iter().map(|(_, info)| info.core_type);
+    match types.next() {
+        Some(first) => types.any(|t| t != first),
+        None => false,
+    }
+}
+
+/// Returns the APIC IDs of all initialized cores of the given type.
+pub fn cores_of_type(core_type: CoreType) -> Vec<u8> {
+    CPUS.iter()
+        .filter(|&(_, info)| info.core_type == core_type)
+        .map(|(apic_id, _)| *apic_id)
+        .collect()
+}
+
+/// Returns the APIC IDs of the other SMT threads on the same physical core as the CPU with the given `apic_id`.
+pub fn smt_siblings(apic_id: u8) -> Vec<u8> {
+    let this = match cpu(apic_id) {
+        Some(this) => this,
+        None => return Vec::new(),
+    };
+    CPUS.iter()
+        .filter(|&(_, info)| info.is_smt_sibling_of(this))
+        .map(|(apic_id, _)| *apic_id)
+        .collect()
+}
+
+/// Returns whether the CPUs with the given APIC IDs are in the same package.
+/// Returns `false` if either CPU hasn't been initialized.
+///
+/// This doesn't allocate, so it may be used by the heap.
+pub fn same_package(a: u8, b: u8) -> bool {
+    match (cpu(a), cpu(b)) {
+        (Some(a), Some(b)) => a.package_id == b.package_id,
+        _ => false,
+    }
+}
+
+/// Returns the number of packages (sockets) that the initialized CPUs are in.
+pub fn package_count() -> usize {
+    let mut packages: Vec<u32> = CPUS.iter().map(|(_, info)| info.package_id).collect();
+    packages.sort_unstable();
+    packages.dedup();
+    packages.len()
+}
@@ -0,0 +1,150 @@
+//! Pre-loads a crash kernel into the physical memory region reserved for it at boot,
+//! and jumps to it when the main kernel fails irrecoverably, in the style of Linux's kexec/kdump.
+//!
+//! The crash kernel region is reserved by the `memory` crate, see `memory::crash_kernel_region()`.
+//! A crash kernel image is a flat binary that is copied to the start of that region by [`load()`].
+//! When the main kernel can no longer handle a panic, [`jump_to_crash_kernel()`] disables interrupts
+//! and calls the image's entry point, passing it a pointer to a [`CrashInfo`] that describes the crash.
+//! The crash kernel runs on the current stack and in the current address space,
+//! and must not rely on anything else in the main kernel, whose memory may be corrupted.
+//!
+//! [`load()`]: fn.load.html
+//! [`jump_to_crash_kernel()`]: fn.jump_to_crash_kernel.html
+//! [`CrashInfo`]: struct.CrashInfo.html
+
+#![no_std]
+
+#[macro_use] extern crate log;
+extern crate irq_safety;
+extern crate kernel_config;
+extern crate memory;
+extern crate spin;
+
+use core::{fmt, ops::DerefMut};
+use kernel_config::memory::PAGE_SIZE;
+use memory::{allocate_pages, get_frame_allocator_ref, get_kernel_mmi_ref, EntryFlags, FrameRange, MappedPages};
+use spin::Once;
+
+
+/// The value of [`CrashInfo::magic`](struct.CrashInfo.html#structfield.magic), which a crash kernel can check.
+pub const CRASH_INFO_MAGIC: u64 = 0x5448_4553_4555_5321; // "THESEUS!"
+
+/// The maximum number of bytes of the crash message that are passed to the crash kernel.
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// The information about a crash that is passed to the crash kernel's entry point.
+#[repr(C)]
+pub struct CrashInfo {
+    /// Always `CRASH_INFO_MAGIC`.
+    pub magic: u64,
+    /// The physical address of the start of the crash kernel region.
+    pub region_start: usize,
+    /// The size in bytes of the crash kernel region.
+    pub region_size: usize,
+    /// The UTF-8 message that describes the crash, e.g., the panic message, which is not NUL-terminated.
+    pub message: *const u8,
+    /// The length of `message` in bytes.
+    pub message_len: usize,
+}
+
+/// The signature of a crash kernel's entry point.
+pub type CrashKernelEntry = extern "C" fn(info: *const CrashInfo) -> !;
+
+/// A crash kernel image that has been loaded into the crash kernel region.
+struct LoadedCrashKernel {
+    /// The mapping of the whole crash kernel region, which is never unmapped.
+    _mapped_pages: MappedPages,
+    region: FrameRange,
+    entry: CrashKernelEntry,
+}
+
+static CRASH_KERNEL: Once<LoadedCrashKernel> = Once::new();
+
+
+/// Copies the given crash kernel `image` to the start of the crash kernel region,
+/// such that its entry point is at `entry_offset` bytes into the `image`.
+///
+/// A crash kernel can only be loaded once. Returns an error if no crash kernel region was reserved at boot
+/// or if the `image` doesn't fit within it.
+pub fn load(image: &[u8], entry_offset: usize) -> Result<(), &'static str> {
+    if CRASH_KERNEL.try().is_some() {
+        return Err("crash_kernel::load(): a crash kernel has already been loaded");
+    }
+    if entry_offset >= image.len() {
+        return Err("crash_kernel::load(): entry point is outside of the image");
+    }
+    let region = memory::crash_kernel_region().ok_or("crash_kernel::load(): no crash kernel region was reserved at boot")?;
+    let region_size = region.size_in_frames() * PAGE_SIZE;
+    if image.len() > region_size {
+        error!("crash_kernel::load(): image of {} bytes is too large for the crash kernel region {:?}", image.len(), region);
+        return Err("crash_kernel::load(): image is too large for the crash kernel region");
+    }
+
+    let mut mapped_pages = {
+        let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("crash_kernel::load(): KERNEL_MMI was not yet initialized")?;
+        let fa = get_frame_allocator_ref().ok_or("crash_kernel::load(): couldn't get the frame allocator")?;
+        let pages = allocate_pages(region.size_in_frames()).ok_or("crash_kernel::load(): couldn't allocate pages")?;
+        let mut kernel_mmi = kernel_mmi_ref.lock();
+        // The crash kernel region must be executable, so it is not mapped with NO_EXECUTE.
+        kernel_mmi.page_table.map_allocated_pages_to(
+            pages,
+            region.clone(),
+            EntryFlags::PRESENT | EntryFlags::WRITABLE,
+            fa.lock().deref_mut(),
+        )?
+    };
+    mapped_pages.as_slice_mut::<u8>(0, image.len())?.copy_from_slice(image);
+
+    // SAFE: the caller guarantees that the image has a valid entry point at `entry_offset`, which we checked is within the image.
+    let entry: CrashKernelEntry = unsafe { core::mem::transmute(mapped_pages.start_address().value() + entry_offset) };
+    info!("Loaded crash kernel image of {} bytes into {:?}", image.len(), region);
+    CRASH_KERNEL.call_once(|| LoadedCrashKernel { _mapped_pages: mapped_pages, region, entry });
+    Ok(())
+}
+
+/// Returns whether a crash kernel has been loaded.
+pub fn is_loaded() -> bool {
+    CRASH_KERNEL.try().is_some()
+}
+
+/// Jumps to the loaded crash kernel, passing it the given `message` that describes the crash.
+///
+/// This never returns if a crash kernel has been loaded; otherwise, it returns immediately.
+/// This doesn't allocate or acquire any locks, since it is invoked when the main kernel is in an unknown state.
+pub fn jump_to_crash_kernel(message: fmt::Arguments) {
+    let crash_kernel = match CRASH_KERNEL.try() {
+        Some(ck) => ck,
+        None => return,
+    };
+    irq_safety::disable_interrupts();
+
+    let mut buffer = MessageBuffer { bytes: [0; MAX_MESSAGE_LEN], len: 0 };
+    let _ = fmt::write(&mut buffer, message);
+    let info = CrashInfo {
+        magic: CRASH_INFO_MAGIC,
+        region_start: crash_kernel.region.start_address().value(),
+        region_size: crash_kernel.region.size_in_frames() * PAGE_SIZE,
+        message: buffer.bytes.as_ptr(),
+        message_len: buffer.len,
+    };
+    (crash_kernel.entry)(&info)
+}
+
+/// A fixed-size buffer that holds the crash message, which is truncated if it's too long.
+struct MessageBuffer {
+    bytes: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut count = s.len().min(MAX_MESSAGE_LEN - self.len);
+        // Don't split a character, such that the message remains valid UTF-8.
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.bytes[self.len .. self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
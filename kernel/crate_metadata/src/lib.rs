@@ -381,7 +381,7 @@ impl LoadedCrate {
         allocator: &mut A
     ) -> Result<StrongCrateRef, &'static str> {
 
-        // This closure deep copies the given mapped_pages (mapping them as WRITABLE)
+        // This closure deep copies the given mapped_pages (mapping them as WRITABLE and non-executable, to comply with W^X)
         // and recalculates the the range of addresses covered by the new mapping.
         let mut deep_copy_mp = |old_mp_range: &(Arc<Mutex<MappedPages>>, Range<VirtualAddress>), flags: EntryFlags|
             -> Result<(Arc<Mutex<MappedPages>>, Range<VirtualAddress>), &'static str> 
@@ -390,7 +390,7 @@ impl LoadedCrate {
             let old_start_address = old_mp_range.1.start.value();
             let size = old_mp_range.1.end.value() - old_start_address;
             let offset = old_start_address - old_mp_locked.start_address().value();
-            let new_mp = old_mp_range.0.lock().deep_copy(Some(flags | EntryFlags::WRITABLE), page_table, allocator)?;
+            let new_mp = old_mp_range.0.lock().deep_copy(Some(flags | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE), page_table, allocator)?;
             let new_start_address = new_mp.start_address() + offset;
             Ok((Arc::new(Mutex::new(new_mp)), new_start_address .. (new_start_address + size)))
         };
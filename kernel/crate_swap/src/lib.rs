@@ -23,10 +23,10 @@ use core::{
     fmt,
     ops::Deref,
 };
-use spin::Mutex;
+use spin::{Mutex, Once};
 use alloc::{
     borrow::Cow,
-    collections::BTreeSet,
+    collections::{BTreeSet, VecDeque},
     string::{String, ToString},
     sync::Arc,
     vec::Vec,
@@ -42,8 +42,10 @@ use mod_mgmt::{
     write_relocation,
     crate_name_from_path,
     replace_containing_crate_name,
+    StrongCrateRef,
     StrongSectionRef,
     WeakDependent,
+    RelocationEntry,
 };
 use path::Path;
 use by_address::ByAddress;
@@ -60,8 +62,23 @@ lazy_static! {
     /// 
     /// This is soft state that can be removed at any time with no effect on correctness.
     static ref UNLOADED_CRATE_CACHE: Mutex<HashMap<SwapRequestList, CrateNamespace>> = Mutex::new(HashMap::new());
+
+    /// The queue of relocation fixups that were deferred by [`swap_crates_deferred()`](fn.swap_crates_deferred.html)
+    /// and have not yet been applied. Batches are applied in the order they were added.
+    static ref PENDING_RELINK_BATCHES: Mutex<VecDeque<RelinkBatch>> = Mutex::new(VecDeque::new());
 }
 
+/// Held while pending relink batches are being applied, which ensures that
+/// batches from different swap operations are never applied concurrently or out of order.
+/// This is separate from the `PENDING_RELINK_BATCHES` lock such that new batches
+/// can still be enqueued while previous batches are being applied.
+static RELINK_APPLY_LOCK: Mutex<()> = Mutex::new(());
+
+/// The function invoked after a new relink batch is enqueued, 
+/// which is used to wake up the background service that applies pending relink batches.
+/// If unset, pending relink batches are applied immediately by the swapping task itself.
+static RELINK_NOTIFIER: Once<fn()> = Once::new();
+
 /// Clears the cache of unloaded (swapped-out) crates saved from previous crate swapping operations. 
 pub fn clear_unloaded_crate_cache() {
     UNLOADED_CRATE_CACHE.lock().clear();
@@ -132,6 +149,79 @@ pub fn swap_crates(
     verbose_log: bool,
     cache_old_crates: bool
 ) -> Result<(), &'static str> {
+    swap_crates_internal(
+        this_namespace,
+        swap_requests,
+        override_namespace_dir,
+        state_transfer_functions,
+        kernel_mmi_ref,
+        verbose_log,
+        cache_old_crates,
+        false,
+    )
+}
+
+
+/// The same as [`swap_crates()`](fn.swap_crates.html), except that the relocation entries in existing sections
+/// that must be rewritten to point to the new crates are not rewritten immediately. 
+/// 
+/// Instead, those relocation fixups are collected into a single [`RelinkBatch`](struct.RelinkBatch.html)
+/// that is applied asynchronously by a background relink service (see the `relink_service` crate),
+/// or immediately by this function if no such service has registered itself via [`set_relink_notifier()`].
+/// This allows swapping a crate that many other crates depend on without requiring the swapping task
+/// to remap and rewrite every dependent section before returning.
+/// 
+/// All other steps of the swap, including symbol map updates and dependency bookkeeping, are still performed synchronously.
+/// Those symbol map updates don't stall other tasks that are looking up symbols, e.g., to load crates,
+/// because each namespace's symbol map is read via RCU and is only replaced once per swapped crate.
+/// Until the batch is applied, existing dependents will continue to use the old crates' sections,
+/// which are kept alive by the batch until then; this is similar to a grace period in RCU. 
+/// 
+/// Use [`apply_pending_relinks()`] to wait for all deferred relocation fixups to be applied.
+/// 
+/// [`set_relink_notifier()`]: fn.set_relink_notifier.html
+/// [`apply_pending_relinks()`]: fn.apply_pending_relinks.html
+pub fn swap_crates_deferred(
+    this_namespace: &Arc<CrateNamespace>,
+    swap_requests: SwapRequestList,
+    override_namespace_dir: Option<NamespaceDir>,
+    state_transfer_functions: Vec<String>,
+    kernel_mmi_ref: &MmiRef,
+    verbose_log: bool,
+    cache_old_crates: bool
+) -> Result<(), &'static str> {
+    swap_crates_internal(
+        this_namespace,
+        swap_requests,
+        override_namespace_dir,
+        state_transfer_functions,
+        kernel_mmi_ref,
+        verbose_log,
+        cache_old_crates,
+        true,
+    )
+}
+
+
+/// The routine that implements both [`swap_crates()`](fn.swap_crates.html) 
+/// and [`swap_crates_deferred()`](fn.swap_crates_deferred.html).
+/// 
+/// If `defer_relinking` is true, relocation fixups for existing dependents are enqueued as a `RelinkBatch`
+/// instead of being written immediately.
+fn swap_crates_internal(
+    this_namespace: &Arc<CrateNamespace>,
+    swap_requests: SwapRequestList,
+    override_namespace_dir: Option<NamespaceDir>,
+    state_transfer_functions: Vec<String>,
+    kernel_mmi_ref: &MmiRef,
+    verbose_log: bool,
+    cache_old_crates: bool,
+    defer_relinking: bool,
+) -> Result<(), &'static str> {
+
+    // Any relocation fixups deferred by a previous swap must be applied before we start this one,
+    // otherwise they could later overwrite the relocations that this swap rewrites.
+    apply_pending_relinks()?;
 
     #[cfg(not(loscd_eval))]
     debug!("swap_crates()[0]: \n\t-->override dir: {:?}, \n\t-->cache_old_crates: {:?}, \n\t-->state transfer: {:?},\n\t-->swap_requests: {:?}", 
@@ -218,6 +308,18 @@ pub fn swap_crates(
     #[cfg(loscd_eval)]
     let mut hpet_total_bss_transfer = 0;
 
+    // The relocation fixups that are deferred until after this function returns, if `defer_relinking` is enabled.
+    let mut relink_batch = if defer_relinking {
+        Some(RelinkBatch {
+            relocations: Vec::new(),
+            old_crates: Vec::new(),
+            kernel_mmi_ref: Arc::clone(kernel_mmi_ref),
+            verbose_log,
+        })
+    } else {
+        None
+    };
+
     // The name of the new crate in each swap request. There is one entry per swap request.
     let mut new_crate_names: Vec<String> = Vec::with_capacity(swap_requests.len());
     // Whether the old crate was actually loaded into the old namespace. There is one entry per swap request.
@@ -324,6 +426,10 @@ pub fn swap_crates(
             // Note that we only need to iterate through sections from the old crate that are public/global,
             // i.e., those that were previously added to this namespace's symbol map,
             // because other crates could not possibly depend on non-public sections in the old crate.
+            //
+            // The new sections that are reexported under the old sections' names are collected here
+            // and added to the symbol maps after this loop, such that each symbol map is only copied and republished once.
+            let mut pending_reexports: Vec<(&CrateNamespace, String, StrongSectionRef)> = Vec::new();
             for old_sec in old_crate.global_sections_iter() {
                 #[cfg(not(loscd_eval))]
                 debug!("swap_crates(): looking for old_sec_name: {:?}", old_sec.name);
@@ -343,7 +449,7 @@ pub fn swap_crates(
                 // This closure finds the section in the `new_crate` that corresponds to the given `old_sec` from the `old_crate`.
                 // And, if enabled, it will reexport that new section under the same name as the `old_sec`.
                 // We put this procedure in a closure because it's relatively expensive, allowing us to run it only when necessary.
-                let find_corresponding_new_section = |new_crate_reexported_symbols: &mut BTreeSet<String>| {
+                let find_corresponding_new_section = |new_crate_reexported_symbols: &mut BTreeSet<String>, pending_reexports: &mut Vec<_>| {
                     // Use the new namespace to find the new source_sec that old target_sec should point to.
                     // The new source_sec must have the same name as the old one (old_sec here),
                     // otherwise it wouldn't be a valid swap -- the target_sec's parent crate should have also been swapped.
@@ -375,10 +481,7 @@ pub fn swap_crates(
                         // reexport the new source section under the old sec's name, i.e., redirect the old mapping to the new source sec
                         let reexported_name = old_sec.name.clone();
                         new_crate_reexported_symbols.insert(reexported_name.clone());
-                        let _old_val = old_sec_ns.symbol_map().lock().insert(BString::from(reexported_name), Arc::downgrade(&new_crate_source_sec));
-                        if _old_val.is_none() { 
-                            warn!("swap_crates(): reexported new crate section that replaces old section {:?}, but that old section unexpectedly didn't exist in the symbol map", old_sec.name);
-                        }
+                        pending_reexports.push((old_sec_ns, reexported_name, Arc::clone(&new_crate_source_sec)));
                     }
                     Ok(new_crate_source_sec)
                 };
@@ -412,7 +515,7 @@ pub fn swap_crates(
                     } else {
                         #[cfg(not(loscd_eval))]
                         trace!("Finding new source section from scratch");
-                        let nsr = find_corresponding_new_section(&mut new_crate.reexported_symbols)?;
+                        let nsr = find_corresponding_new_section(&mut new_crate.reexported_symbols, &mut pending_reexports)?;
                         new_sec.get_or_insert(nsr)
                     };
 
@@ -424,9 +527,17 @@ pub fn swap_crates(
                     #[cfg(not(loscd_eval))]
                     debug!("    swap_crates(): target_sec: {:?}, old source sec: {:?}, new source sec: {:?}", &*target_sec, &*old_sec, &*new_source_sec);
 
+                    // If relinking is deferred, we just record the fixup such that it can be applied later as part of a batch.
+                    if let Some(ref mut batch) = relink_batch {
+                        batch.relocations.push(DeferredRelocation {
+                            target_sec: Arc::clone(&target_sec),
+                            relocation: relocation_entry,
+                            new_source_sec: Arc::clone(new_source_sec),
+                        });
+                    }
                     // If the target_sec's mapped pages aren't writable (which is common in the case of swapping),
                     // then we need to temporarily remap them as writable here so we can fix up the target_sec's new relocation entry.
-                    {
+                    else {
                         #[cfg(loscd_eval)]
                         let start_rewriting_relocations = hpet.get_counter();
 
@@ -502,6 +613,21 @@ pub fn swap_crates(
                 
             } // end of loop that rewrites dependencies for sections that depend on the old_crate
 
+            // Reexport the new source sections under the old sections' names, i.e., redirect the old mappings to the new source secs,
+            // with one update of each namespace's symbol map.
+            while let Some(&(ns, ..)) = pending_reexports.first() {
+                let (reexports_in_ns, others): (Vec<_>, Vec<_>) = pending_reexports.into_iter().partition(|&(n, ..)| core::ptr::eq(n, ns));
+                pending_reexports = others;
+                ns.symbol_map().modify(|symbol_map| {
+                    for (_ns, reexported_name, new_crate_source_sec) in reexports_in_ns {
+                        let _old_val = symbol_map.insert(BString::from(reexported_name.clone()), Arc::downgrade(&new_crate_source_sec));
+                        if _old_val.is_none() { 
+                            warn!("swap_crates(): reexported new crate section that replaces old section {:?}, but that old section unexpectedly didn't exist in the symbol map", reexported_name);
+                        }
+                    }
+                });
+            }
+
             
         } // end of scope, drops lock on `new_crate_ref`
     } // end of iterating over all swap requests to fix up old crate dependents
@@ -545,6 +671,11 @@ pub fn swap_crates(
 
                 core::mem::forget(old_crate_ref.clone());
 
+                // Existing dependents may still use the old crate's sections until the deferred relocation fixups are applied.
+                if let Some(ref mut batch) = relink_batch {
+                    batch.old_crates.push(old_crate_ref.clone());
+                }
+
 
                 #[cfg(not(loscd_eval))]
                 info!("  Removed old crate {:?} ({:?}) from namespace {}", old_crate_name, &*old_crate, old_namespace.name());
@@ -573,22 +704,28 @@ pub fn swap_crates(
                 // If reexport_new_symbols_as_old is true, we MUST NOT remove the old_crate's symbols from this symbol map,
                 // because we already replaced them above with mappings that redirect to the corresponding new crate sections.
                 if !reexport_new_symbols_as_old {
-                    let mut old_ns_symbol_map = old_namespace.symbol_map().lock();
-                    for old_sec in old_crate.global_sections_iter() {
-                        if old_ns_symbol_map.remove_str(&old_sec.name).is_none() {
-                            error!("swap_crates(): couldn't find old symbol {:?} in the old crate's namespace: {}.", old_sec.name, old_namespace.name());
-                            return Err("couldn't find old symbol {:?} in the old crate's namespace");
+                    old_namespace.symbol_map().modify(|old_ns_symbol_map| {
+                        for old_sec in old_crate.global_sections_iter() {
+                            if old_ns_symbol_map.remove_str(&old_sec.name).is_none() {
+                                error!("swap_crates(): couldn't find old symbol {:?} in the old crate's namespace: {}.", old_sec.name, old_namespace.name());
+                                return Err("couldn't find old symbol {:?} in the old crate's namespace");
+                            }
                         }
-                    }
+                        Ok(())
+                    })?;
                 }
 
                 // If the old crate had reexported its symbols, we should remove those reexports here,
                 // because they're no longer active since the old crate is being removed. 
-                for sym in &old_crate.reexported_symbols {
-                    let _old_reexported_symbol = old_namespace.symbol_map().lock().remove_str(sym);
-                    if _old_reexported_symbol.is_none() {
-                        warn!("swap_crates(): the old_crate {:?}'s reexported symbol was not in its old namespace, couldn't be removed.", sym);
-                    }
+                if !old_crate.reexported_symbols.is_empty() {
+                    old_namespace.symbol_map().modify(|old_ns_symbol_map| {
+                        for sym in &old_crate.reexported_symbols {
+                            let _old_reexported_symbol = old_ns_symbol_map.remove_str(sym);
+                            if _old_reexported_symbol.is_none() {
+                                warn!("swap_crates(): the old_crate {:?}'s reexported symbol was not in its old namespace, couldn't be removed.", sym);
+                            }
+                        }
+                    });
                 }
 
                 if cache_old_crates {
@@ -617,6 +754,10 @@ pub fn swap_crates(
     #[cfg(loscd_eval)]
     let start_symbol_cleanup = hpet.get_counter();
 
+    // The new crates whose symbols must be added to each namespace's symbol map,
+    // which is done once per namespace after all of the new crates have been moved, such that each symbol map is only copied once.
+    let mut pending_symbols: Vec<(&CrateNamespace, StrongCrateRef)> = Vec::new();
+
     // Here, we move all of the new crates into the actual new namespace where they belong. 
     for ((req, new_crate_name), is_old_crate_loaded) in swap_requests.iter().zip(new_crate_names.iter()).zip(old_crates_are_loaded.iter()) {
         // We only expect the new crate to have been loaded into the temp namespace if the old crate was actually loaded in the old namespace
@@ -627,8 +768,8 @@ pub fn swap_crates(
         #[cfg(not(loscd_eval))]
         debug!("swap_crates(): adding new crate {:?} to namespace {}", new_crate_ref, req.new_namespace.name());
 
-        req.new_namespace.add_symbols(new_crate_ref.lock_as_ref().sections.values(), verbose_log);
         req.new_namespace.crate_tree().lock().insert_str(new_crate_name, new_crate_ref.clone());
+        pending_symbols.push((&**req.new_namespace, new_crate_ref));
     }
     
     // Other crates may have been loaded from their object files into the `namespace_of_new_crates` as dependendencies (required by the new crates specified by swap requests).
@@ -672,8 +813,8 @@ pub fn swap_crates(
 
             // #[cfg(not(loscd_eval))]
             // warn!("swap_crates(): untested scenario of adding new non-requested (dependency) crate {:?} to namespace {}", new_crate_ref, target_ns.name());
-            target_ns.add_symbols(new_crate_ref.lock_as_ref().sections.values(), verbose_log);
             target_ns.crate_tree().lock().insert_str(new_crate_name, new_crate_ref.clone());
+            pending_symbols.push((&**target_ns, new_crate_ref.clone()));
         }
         else {
             #[cfg(not(loscd_eval))] {
@@ -688,6 +829,13 @@ pub fn swap_crates(
         true
     });
 
+    // Now add the new crates' symbols to their namespaces, with one update of each namespace's symbol map.
+    while let Some(&(ns, _)) = pending_symbols.first() {
+        let (crates_in_ns, others): (Vec<_>, Vec<_>) = pending_symbols.into_iter().partition(|&(n, _)| core::ptr::eq(n, ns));
+        pending_symbols = others;
+        ns.add_symbols_from_crates(crates_in_ns.iter().map(|(_ns, crate_ref)| crate_ref), verbose_log);
+    }
+
     #[cfg(loscd_eval)]
     let end_symbol_cleanup = hpet.get_counter();
 
@@ -778,11 +926,159 @@ pub fn swap_crates(
         );
     }
 
+    if let Some(batch) = relink_batch {
+        #[cfg(not(loscd_eval))]
+        debug!("swap_crates(): deferring {} relocation fixups to the relink service", batch.relocations.len());
+        enqueue_relink_batch(batch)?;
+    }
+
     Ok(())
     // here, "namespace_of_new_crates is dropped, but its crates have already been added to the current namespace 
 }
 
 
+/// A single relocation entry in an existing section that must be rewritten 
+/// to point to a newly swapped-in section.
+struct DeferredRelocation {
+    /// The existing section that contains the relocation entry.
+    target_sec: StrongSectionRef,
+    /// The relocation entry that must be rewritten.
+    relocation: RelocationEntry,
+    /// The new section that the relocation entry should point to.
+    new_source_sec: StrongSectionRef,
+}
+
+
+/// The set of relocation fixups deferred by a single invocation of 
+/// [`swap_crates_deferred()`](fn.swap_crates_deferred.html).
+pub struct RelinkBatch {
+    relocations: Vec<DeferredRelocation>,
+    /// The old crates that were swapped out, which must be kept alive until all of their 
+    /// existing dependents have been relinked to point to the new crates.
+    old_crates: Vec<StrongCrateRef>,
+    kernel_mmi_ref: MmiRef,
+    verbose_log: bool,
+}
+
+impl RelinkBatch {
+    /// Returns the number of relocation fixups in this batch.
+    pub fn len(&self) -> usize {
+        self.relocations.len()
+    }
+
+    /// Rewrites all of the relocation entries in this batch, and then releases the old crates.
+    /// 
+    /// The relocations are grouped by their target section, 
+    /// such that each target section only needs to be locked and remapped as writable once. 
+    /// 
+    /// If any relocation cannot be rewritten, this returns the error along with a batch 
+    /// containing the relocations that may not have been rewritten yet, which keeps the old crates alive. 
+    /// Rewriting a relocation is idempotent, so that batch can simply be applied again later.
+    fn apply(self) -> Result<usize, (&'static str, RelinkBatch)> {
+        let RelinkBatch { mut relocations, old_crates, kernel_mmi_ref, verbose_log } = self;
+        relocations.sort_unstable_by_key(|r| Arc::as_ptr(&r.target_sec) as usize);
+
+        let mut group_start = 0;
+        while group_start < relocations.len() {
+            let first = &relocations[group_start];
+            let group_len = relocations[group_start ..].iter()
+                .take_while(|r| Arc::ptr_eq(&r.target_sec, &first.target_sec))
+                .count();
+            let group = &relocations[group_start .. group_start + group_len];
+            if let Err(e) = apply_relocation_group(group, &kernel_mmi_ref, verbose_log) {
+                // The old crates must not be dropped while the remaining target sections still point to them.
+                relocations.drain(.. group_start);
+                return Err((e, RelinkBatch { relocations, old_crates, kernel_mmi_ref, verbose_log }));
+            }
+            group_start += group_len;
+        }
+
+        // Now that no existing sections point to the old crates via these relocations, they can be released.
+        drop(old_crates);
+        Ok(relocations.len())
+    }
+}
+
+/// Rewrites the given relocation entries, which must all have the same target section.
+/// 
+/// The target section is temporarily remapped as writable if needed, and its original flags are restored 
+/// even if one of the relocations could not be rewritten.
+fn apply_relocation_group(group: &[DeferredRelocation], kernel_mmi_ref: &MmiRef, verbose_log: bool) -> Result<(), &'static str> {
+    let target_sec = &group[0].target_sec;
+    let mut target_sec_mapped_pages = target_sec.mapped_pages.lock();
+    let target_sec_initial_flags = target_sec_mapped_pages.flags();
+    if !target_sec_initial_flags.is_writable() {
+        target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags | EntryFlags::WRITABLE)?;
+    }
+    let write_result = group.iter().try_for_each(|deferred| write_relocation(
+        deferred.relocation,
+        &mut target_sec_mapped_pages,
+        target_sec.mapped_pages_offset,
+        deferred.new_source_sec.start_address(),
+        verbose_log
+    ));
+    if !target_sec_initial_flags.is_writable() {
+        target_sec_mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, target_sec_initial_flags)?;
+    }
+    write_result
+}
+
+
+/// Sets the function that will be invoked whenever a new `RelinkBatch` is enqueued.
+/// 
+/// This is intended to be used by a background relink service to be notified of new pending batches,
+/// which it should then apply by calling [`apply_pending_relinks()`](fn.apply_pending_relinks.html).
+/// Once set, the notifier cannot be changed.
+pub fn set_relink_notifier(notifier: fn()) {
+    RELINK_NOTIFIER.call_once(|| notifier);
+}
+
+/// Returns `true` if there are deferred relocation fixups that have not yet been applied.
+pub fn has_pending_relinks() -> bool {
+    !PENDING_RELINK_BATCHES.lock().is_empty()
+}
+
+/// Applies all pending relink batches in the order they were enqueued,
+/// and returns the total number of relocation entries that were rewritten. 
+/// 
+/// This blocks until any other task that is currently applying pending batches has finished.
+pub fn apply_pending_relinks() -> Result<usize, &'static str> {
+    let _apply_guard = RELINK_APPLY_LOCK.lock();
+    let mut total_relocations = 0;
+    loop {
+        // Only hold the queue lock long enough to dequeue the next batch.
+        let next_batch = PENDING_RELINK_BATCHES.lock().pop_front();
+        match next_batch {
+            Some(batch) => match batch.apply() {
+                Ok(num_relocations) => total_relocations += num_relocations,
+                Err((e, remaining_batch)) => {
+                    // Put the un-applied remainder back at the front of the queue so it can be retried later,
+                    // which also keeps its old crates alive until then.
+                    error!("apply_pending_relinks(): failed to apply a relink batch, {} relocations remain pending. Error: {}",
+                        remaining_batch.len(), e
+                    );
+                    PENDING_RELINK_BATCHES.lock().push_front(remaining_batch);
+                    return Err(e);
+                }
+            },
+            None => break,
+        }
+    }
+    Ok(total_relocations)
+}
+
+/// Adds the given `batch` to the queue of pending relink batches and notifies the relink service.
+/// If there is no relink service, the batch is applied immediately.
+fn enqueue_relink_batch(batch: RelinkBatch) -> Result<(), &'static str> {
+    PENDING_RELINK_BATCHES.lock().push_back(batch);
+    match RELINK_NOTIFIER.try() {
+        Some(notifier) => notifier(),
+        None => { apply_pending_relinks()?; }
+    }
+    Ok(())
+}
+
+
 /// Convenience function that removes the given `file` from its parent directory 
 /// and inserts it into the given destination directory. 
 /// 
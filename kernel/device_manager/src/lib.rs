@@ -7,12 +7,18 @@ extern crate e1000;
 extern crate memory;
 extern crate apic;
 extern crate acpi;
+extern crate acpi_ec;
+extern crate acpi_events;
+extern crate power_policy;
 extern crate keyboard;
 extern crate pci;
 extern crate mouse;
 extern crate storage_manager;
+extern crate backlight;
+extern crate virtio_balloon;
 extern crate network_manager;
 extern crate ethernet_smoltcp_device;
+extern crate smoltcp;
 extern crate mpmc;
 extern crate ixgbe;
 extern crate alloc;
@@ -21,7 +27,8 @@ use mpmc::Queue;
 use event_types::Event;
 use memory::MemoryManagementInfo;
 use ethernet_smoltcp_device::EthernetNetworkInterface;
-use network_manager::add_to_network_interfaces;
+use network_manager::{add_to_network_interfaces, NetworkInterfaceRef};
+use smoltcp::wire::Ipv4Address;
 use alloc::vec::Vec;
 
 /// A randomly chosen IP address that must be outside of the DHCP range.. // TODO FIXME: use DHCP to acquire IP
@@ -49,6 +56,28 @@ pub fn early_init(kernel_mmi: &mut MemoryManagementInfo) -> Result<(), &'static
 /// Initializes all other devices, such as the keyboard and mouse
 /// as well as all devices discovered on the PCI bus.
 pub fn init(key_producer: Queue<Event>, mouse_producer: Queue<Event>) -> Result<(), &'static str>  {
+    // Power events should be acted upon from the start, including the initial state that the drivers below publish.
+    if let Err(e) = power_policy::init() {
+        error!("Couldn't start handling power events: {}", e);
+    }
+
+    // The ACPI namespace is only needed for devices beyond those in the static ACPI tables,
+    // so a failure to load it isn't fatal.
+    if let Err(e) = acpi::init_aml() {
+        error!("Couldn't load the ACPI namespace from the DSDT and SSDTs: {}", e);
+    } else {
+        match acpi_ec::init() {
+            Ok(true) => info!("Started handling embedded controller events"),
+            Ok(false) => debug!("No embedded controller, so no laptop events will be reported"),
+            Err(e) => error!("Couldn't start handling embedded controller events: {}", e),
+        }
+    }
+    match acpi_events::init() {
+        Ok(true) => info!("Started handling ACPI fixed events and GPEs"),
+        Ok(false) => debug!("No ACPI fixed-feature buttons or GPE blocks"),
+        Err(e) => error!("Couldn't start handling ACPI fixed events and GPEs: {}", e),
+    }
+
     keyboard::init(key_producer);
     mouse::init(mouse_producer);
 
@@ -81,6 +110,26 @@ pub fn init(key_producer: Queue<Event>, mouse_producer: Queue<Event>) -> Result<
             }
         }
 
+        // If this is a display controller with a backlight, use it to control the display brightness.
+        match backlight::init_device(dev) {
+            Ok(true)  => continue,
+            Ok(false) => { }
+            Err(e) => {
+                error!("Failed to initialize display backlight, it will be unavailable.\n{:?}\nError: {}", dev, e);
+                continue;
+            }
+        }
+
+        // If this is a virtio memory balloon, let the hypervisor reclaim unused memory through it.
+        match virtio_balloon::init_device(dev) {
+            Ok(true)  => continue,
+            Ok(false) => { }
+            Err(e) => {
+                error!("Failed to initialize virtio balloon device, it will be unavailable.\n{:?}\nError: {}", dev, e);
+                continue;
+            }
+        }
+
         // If this is a network device, initialize it as such.
         // Look for networking controllers, specifically ethernet cards
         if dev.class == 0x02 && dev.subclass == 0x00 {
@@ -88,7 +137,7 @@ pub fn init(key_producer: Queue<Event>, mouse_producer: Queue<Event>) -> Result<
                 info!("e1000 PCI device found at: {:?}", dev.location);
                 let e1000_nic_ref = e1000::E1000Nic::init(dev)?;
                 let e1000_interface = EthernetNetworkInterface::new_ipv4_interface(e1000_nic_ref, DEFAULT_LOCAL_IP, &DEFAULT_GATEWAY_IP)?;
-                add_to_network_interfaces(e1000_interface);
+                add_default_gateway(&add_to_network_interfaces(e1000_interface))?;
                 continue;
             }
             if dev.vendor_id == ixgbe::INTEL_VEND && dev.device_id == ixgbe::INTEL_82599 {
@@ -131,7 +180,7 @@ pub fn init(key_producer: Queue<Event>, mouse_producer: Queue<Event>) -> Result<
             DEFAULT_LOCAL_IP, 
             &DEFAULT_GATEWAY_IP
         )?;
-        add_to_network_interfaces(ixgbe_interface);
+        add_default_gateway(&add_to_network_interfaces(ixgbe_interface))?;
     }
 
     // Convenience notification for developers to inform them of no networking devices
@@ -141,3 +190,15 @@ pub fn init(key_producer: Queue<Event>, mouse_producer: Queue<Event>) -> Result<
 
     Ok(())
 }
+
+/// Adds a default route through the given network interface's default gateway to the routing table.
+///
+/// Interfaces that are added earlier are preferred, because equal routes are chosen in the order they were added.
+fn add_default_gateway(iface: &NetworkInterfaceRef) -> Result<(), &'static str> {
+    network_manager::routing::add_route(
+        network_manager::routing::default_destination(false),
+        Some(Ipv4Address::from_bytes(&DEFAULT_GATEWAY_IP).into()),
+        network_manager::routing::DEFAULT_ROUTE_METRIC,
+        iface,
+    )
+}
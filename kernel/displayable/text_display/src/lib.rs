@@ -41,7 +41,13 @@ impl Displayable for TextDisplay {
         coordinate: Coord,
         framebuffer: &mut Framebuffer<P>,
     ) -> Result<Rectangle, &'static str> {
-        let (string, col, line) = if self.cache.len() > 0 && self.text.starts_with(self.cache.as_str()) {
+        // Only the new text is displayed if the displayed text is a prefix of it,
+        // unless the new text starts with a combining character that modifies the last displayed character.
+        let starts_with_combining = |s: &str| s.chars().next().map_or(false, |c| font::char_width(c) == 0);
+        let (string, col, line) = if self.cache.len() > 0
+            && self.text.starts_with(self.cache.as_str())
+            && !starts_with_combining(&self.text[self.cache.len()..])
+        {
             (
                 &self.text.as_str()[self.cache.len()..self.text.len()],
                 self.next_col,
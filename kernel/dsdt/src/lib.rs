@@ -0,0 +1,76 @@
+//! Definitions for the ACPI DSDT and SSDT tables.
+//!
+//! DSDT is the Differentiated System Description Table, and
+//! SSDTs are Secondary System Description Tables.
+//! Neither has any fields beyond the `Sdt` header; instead, the rest of each table
+//! is a definition block of AML bytecode that describes the system's devices,
+//! which is given to an AML interpreter (see the `acpi_aml` crate).
+//!
+//! The DSDT is found via the FADT, whereas SSDTs are listed in the RSDT/XSDT.
+//! There may be any number of SSDTs, which all share the same signature,
+//! so they are tracked separately here instead of by signature in the `AcpiTables`.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate irq_safety;
+extern crate memory;
+extern crate sdt;
+extern crate acpi_table;
+
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use memory::PhysicalAddress;
+use sdt::SDT_SIZE_IN_BYTES;
+use acpi_table::{AcpiSignature, AcpiTables};
+
+
+pub const DSDT_SIGNATURE: &'static [u8; 4] = b"DSDT";
+pub const SSDT_SIGNATURE: &'static [u8; 4] = b"SSDT";
+
+/// The physical address and total length of every SSDT that has been discovered, in order of discovery.
+static SSDT_LOCATIONS: MutexIrqSafe<Vec<(PhysicalAddress, usize)>> = MutexIrqSafe::new(Vec::new());
+
+
+/// The handler for parsing the DSDT or an SSDT and adding it to the ACPI tables list.
+pub fn handle(
+    acpi_tables: &mut AcpiTables,
+    signature: AcpiSignature,
+    length: usize,
+    phys_addr: PhysicalAddress
+) -> Result<(), &'static str> {
+    if length < SDT_SIZE_IN_BYTES {
+        return Err("DSDT or SSDT is shorter than an SDT header");
+    }
+    match &signature {
+        DSDT_SIGNATURE => {
+            // The dynamic part of the DSDT is the AML definition block, one byte per element.
+            let slice_paddr = phys_addr + SDT_SIZE_IN_BYTES;
+            acpi_tables.add_table_location(signature, phys_addr, Some((slice_paddr, length - SDT_SIZE_IN_BYTES)))
+        }
+        SSDT_SIGNATURE => {
+            debug!("Found SSDT at {:#X}, length {}", phys_addr, length);
+            SSDT_LOCATIONS.lock().push((phys_addr, length));
+            Ok(())
+        }
+        _ => Err("unexpected ACPI table signature (not DSDT or SSDT)"),
+    }
+}
+
+
+/// Returns the AML definition block within the DSDT in the given `AcpiTables`, if the DSDT has been mapped.
+pub fn dsdt_aml<'t>(acpi_tables: &'t AcpiTables) -> Option<&'t [u8]> {
+    acpi_tables.table_slice(&DSDT_SIGNATURE).ok()
+}
+
+/// Returns the AML definition blocks within all SSDTs in the given `AcpiTables`, in order of discovery.
+pub fn ssdt_amls<'t>(acpi_tables: &'t AcpiTables) -> Vec<&'t [u8]> {
+    SSDT_LOCATIONS.lock().iter()
+        .filter_map(|&(phys_addr, length)| {
+            acpi_tables.bytes(phys_addr + SDT_SIZE_IN_BYTES, length - SDT_SIZE_IN_BYTES)
+                .map_err(|e| error!("Couldn't access SSDT at {:#X}: {}", phys_addr, e))
+                .ok()
+        })
+        .collect()
+}
@@ -0,0 +1,63 @@
+//! The kernel's source of entropy for seeding random number generators,
+//! e.g., the one behind KASLR and the one that chooses WireGuard's ephemeral keys.
+//!
+//! Random values come from the `RDRAND` instruction if the CPU supports it, mixed with the TSC.
+//! Without `RDRAND`, only the TSC is used, which is weak, but still varies with the time at which it's read.
+//!
+//! This crate has no dependencies and doesn't allocate, so it can be used at early boot,
+//! before the heap or any other subsystem has been initialized.
+
+#![no_std]
+
+use core::arch::x86_64::{__cpuid, _rdrand64_step, _rdtsc};
+
+/// The number of times that `RDRAND` is retried if it fails to produce a value, as recommended by Intel.
+const RDRAND_RETRIES: usize = 10;
+
+
+/// Returns whether the CPU supports the `RDRAND` instruction.
+pub fn has_rdrand() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 30) != 0
+}
+
+/// Returns 64 bits from the `RDRAND` instruction, or `None` if the CPU doesn't support it
+/// or if it failed to produce a value after a few retries.
+pub fn rdrand() -> Option<u64> {
+    if has_rdrand() {
+        unsafe { rdrand_unchecked() }
+    } else {
+        None
+    }
+}
+
+/// Returns a 64-bit seed that mixes the output of `RDRAND` (if available) with the TSC.
+pub fn seed() -> u64 {
+    let tsc = unsafe { _rdtsc() };
+    match rdrand() {
+        Some(value) => value.rotate_left(32) ^ tsc,
+        None => tsc,
+    }
+}
+
+/// Fills the given `buffer` with seeds, as returned by [`seed()`], in little-endian order.
+///
+/// [`seed()`]: fn.seed.html
+pub fn fill_bytes(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        let bytes = seed().to_le_bytes();
+        let len = chunk.len();
+        chunk.copy_from_slice(&bytes[.. len]);
+    }
+}
+
+/// Executes `RDRAND`, which must be supported by the CPU.
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand_unchecked() -> Option<u64> {
+    let mut value = 0;
+    for _ in 0 .. RDRAND_RETRIES {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
@@ -21,6 +21,8 @@ bitflags! {
         const HUGE_PAGE         = 1 << 7;
         // const GLOBAL            = 1 << 8;
         const GLOBAL            = 0; // disabling because VirtualBox doesn't like it
+        /// The 4-bit protection key of the page, see the `protection_keys` crate.
+        const PROTECTION_KEY    = 0b1111 << 59;
         const NO_EXECUTE        = 1 << 63;
     }
 
@@ -53,6 +55,16 @@ impl EntryFlags {
         !self.intersects(EntryFlags::NO_EXECUTE)
     }
 
+    /// Returns the protection key of the page, which is 0 unless it was tagged with another key.
+    pub fn protection_key(&self) -> u8 {
+        ((self.bits() & EntryFlags::PROTECTION_KEY.bits()) >> 59) as u8
+    }
+
+    /// Copies this new `EntryFlags` object and sets its protection key to the lowest 4 bits of `key`.
+    pub fn with_protection_key(self, key: u8) -> EntryFlags {
+        (self - EntryFlags::PROTECTION_KEY) | EntryFlags::from_bits_truncate(((key & 0xF) as u64) << 59)
+    }
+
     /// Gets flags according to the properties of a section from multiboot2.
     pub fn from_multiboot2_section_flags(section: &multiboot2::ElfSection) -> EntryFlags {
         use multiboot2::ElfSectionFlags;
@@ -2,23 +2,52 @@
 
 extern crate alloc;
 extern crate fs_node;
+extern crate memfs;
 extern crate root;
+extern crate path;
+extern crate stdio;
 
 use alloc::{
+    collections::BTreeMap,
     string::String,
     sync::Arc,
 };
-use fs_node::DirRef;
+use fs_node::{DirRef, FileOrDir, FileRef, BASE_FILE_MODE};
+use memfs::MemFile;
+use path::Path;
+use stdio::{StdioReader, StdioWriter};
+
+/// The default `file_mode_mask`, which removes write permissions for everyone but the owner.
+pub const DEFAULT_FILE_MODE_MASK: u16 = 0o022;
 
 /// A structure that contains environment state for a given `Task` or group of `Task`s.
 /// 
 /// A default environment can be created with the following state:
 /// * The working directory is the `root` directory.
+/// * There are no environment variables.
+/// * The file mode mask is [`DEFAULT_FILE_MODE_MASK`](constant.DEFAULT_FILE_MODE_MASK.html).
+/// * There are no stdio handles, meaning the task uses the ones provided by its shell (if any).
 ///
+/// A new task receives a snapshot (a copy) of its parent task's environment when it is spawned,
+/// so changes made by the new task do not affect its parent, and vice versa. 
+/// Tasks that want to share an environment, e.g., a shell and the applications it runs, 
+/// can do so explicitly via the spawn builder's `env()` function.
+#[derive(Clone)]
 pub struct Environment {
     /// The "current working directory", i.e., 
     /// where a task's relative path begins upon first execution.
     pub working_dir: DirRef, 
+    /// The environment variables, a map from variable name to value.
+    pub variables: BTreeMap<String, String>,
+    /// The mode bits that are removed from `fs_node::BASE_FILE_MODE`
+    /// when creating a new file, like a `umask` in Unix-like systems.
+    pub file_mode_mask: u16,
+    /// The default standard input handle for tasks using this environment.
+    pub stdin: Option<StdioReader>,
+    /// The default standard output handle for tasks using this environment.
+    pub stdout: Option<StdioWriter>,
+    /// The default standard error handle for tasks using this environment.
+    pub stderr: Option<StdioWriter>,
 }
 
 impl Environment {
@@ -27,12 +56,52 @@ impl Environment {
         let wd = self.working_dir.lock();
         wd.get_absolute_path()
     }
+
+    /// Finds the file or directory at the given `path`, 
+    /// which is resolved relative to the working directory if it's not an absolute path.
+    pub fn resolve_path(&self, path: &Path) -> Option<FileOrDir> {
+        path.get(&self.working_dir)
+    }
+
+    /// Returns the value of the environment variable with the given `name`, if it exists.
+    pub fn get_var(&self, name: &str) -> Option<&String> {
+        self.variables.get(name)
+    }
+
+    /// Sets the environment variable `name` to the given `value`,
+    /// returning the previous value if it existed.
+    pub fn set_var(&mut self, name: String, value: String) -> Option<String> {
+        self.variables.insert(name, value)
+    }
+
+    /// Removes the environment variable with the given `name`, 
+    /// returning its value if it existed.
+    pub fn unset_var(&mut self, name: &str) -> Option<String> {
+        self.variables.remove(name)
+    }
+
+    /// Returns the mode bits that a newly-created file should have in this environment,
+    /// i.e., `fs_node::BASE_FILE_MODE` with the `file_mode_mask` removed.
+    pub fn default_file_mode(&self) -> u16 {
+        BASE_FILE_MODE & !self.file_mode_mask
+    }
+
+    /// Creates a new empty in-memory file with the given `name` in the working directory,
+    /// which is given this environment's [`default_file_mode()`](#method.default_file_mode).
+    pub fn create_file(&self, name: String) -> Result<FileRef, &'static str> {
+        MemFile::new_with_mode(name, &self.working_dir, self.default_file_mode())
+    }
 }
 
 impl Default for Environment {
     fn default() -> Environment {
         Environment {
             working_dir: Arc::clone(root::get_root()),
+            variables: BTreeMap::new(),
+            file_mode_mask: DEFAULT_FILE_MODE_MASK,
+            stdin: None,
+            stdout: None,
+            stderr: None,
         }
     }
-}
\ No newline at end of file
+}
@@ -14,7 +14,9 @@ extern crate network_manager;
 
 use alloc::{
     boxed::Box,
-    collections::BTreeMap,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
 };
 use irq_safety::MutexIrqSafe;
 use smoltcp::{
@@ -27,17 +29,27 @@ use smoltcp::{
 use network_interface_card::NetworkInterfaceCard;
 use nic_buffers::{TransmitBuffer, ReceivedFrame};
 use owning_ref::BoxRefMut;
-use network_manager::NetworkInterface;
+use network_manager::{
+    NetworkInterface,
+    shaping::{self, TrafficClassRef},
+    stats,
+};
 use core::str::FromStr;
 
 /// standard MTU for ethernet cards
 const DEFAULT_MTU: usize = 1500;
+/// The maximum number of outgoing frames of each traffic class that can wait for their class's rate limit;
+/// further frames of that class are dropped.
+const MAX_SHAPED_FRAMES_PER_CLASS: usize = 256;
 
 
 /// A struct that implements the `NetworkInterface` trait for a NIC. 
 /// There should be one instance of this struct per interface, i.e., an Ethernet port on the NIC.
 pub struct EthernetNetworkInterface<N: NetworkInterfaceCard + 'static> {
     pub iface: EthernetInterface<'static, 'static, 'static, EthernetDevice<N>>,
+    /// The NIC and shaped frames of `iface`'s device, such that they can be flushed upon shutdown.
+    nic_ref: &'static MutexIrqSafe<N>,
+    shaped_frames: ShapedFramesRef,
 }
 
 impl<N: NetworkInterfaceCard + 'static> NetworkInterface for EthernetNetworkInterface<N> { 
@@ -68,6 +80,23 @@ impl<N: NetworkInterfaceCard + 'static> NetworkInterface for EthernetNetworkInte
     fn routes_mut(&mut self) -> &mut Routes<'static> {
         self.iface.routes_mut()
     }
+
+    /// Sends all shaped frames that are still waiting for their class's rate limit, regardless of that limit.
+    fn quiesce(&mut self) -> Result<(), &'static str> {
+        let waiting_frames = self.shaped_frames.lock().dequeue_all();
+        let num_frames = waiting_frames.len();
+        let mut num_failed = 0;
+        for frame in waiting_frames {
+            if send_frame(self.nic_ref, frame).is_err() {
+                num_failed += 1;
+            }
+        }
+        if num_failed > 0 {
+            warn!("EthernetNetworkInterface::quiesce(): failed to send {} of {} shaped frames", num_failed, num_frames);
+            return Err("EthernetNetworkInterface::quiesce(): failed to send some shaped frames");
+        }
+        Ok(())
+    }
 }
 
 impl<N: NetworkInterfaceCard + 'static > EthernetNetworkInterface<N> {
@@ -105,6 +134,7 @@ impl<N: NetworkInterfaceCard + 'static > EthernetNetworkInterface<N> {
         })?;
 
         let device = EthernetDevice::new(nic);
+        let shaped_frames = device.shaped_frames.clone();
         let hardware_mac_addr = EthernetAddress(nic.lock().mac_address());
         // When creating an EthernetInterface, only the `ethernet_addr` and `neighbor_cache` are required.
         let iface = EthernetInterfaceBuilder::new(device)
@@ -115,7 +145,7 @@ impl<N: NetworkInterfaceCard + 'static > EthernetNetworkInterface<N> {
             .finalize();
 
         Ok(
-            EthernetNetworkInterface { iface }
+            EthernetNetworkInterface { iface, nic_ref: nic, shaped_frames }
         )
     }
 
@@ -145,17 +175,98 @@ impl<N: NetworkInterfaceCard + 'static > EthernetNetworkInterface<N> {
 /// An instance of this `EthernetDevice` can be used in smoltcp's `EthernetInterface`.
 pub struct EthernetDevice<N: NetworkInterfaceCard + 'static> { 
     nic_ref: &'static MutexIrqSafe<N>,
+    shaped_frames: ShapedFramesRef,
 }
 impl<N: NetworkInterfaceCard + 'static> EthernetDevice<N> {
     /// Create a new instance of the `EthernetDevice`.
     pub fn new(nic_ref: &'static MutexIrqSafe<N>) -> EthernetDevice<N> {
         EthernetDevice {
             nic_ref: nic_ref,
+            shaped_frames: Arc::new(MutexIrqSafe::new(ShapedFrames::new())),
         }
     }
 }
 
 
+/// The egress queuing discipline of an `EthernetDevice`, which holds one queue per traffic class
+/// for the outgoing frames that exceeded their class's rate limit.
+/// See the [`network_manager::shaping`] module.
+///
+/// Frames that aren't shaped bypass these queues, so they are sent ahead of any waiting shaped frames.
+pub struct ShapedFrames {
+    queues: Vec<(TrafficClassRef, VecDeque<TransmitBuffer>)>,
+}
+type ShapedFramesRef = Arc<MutexIrqSafe<ShapedFrames>>;
+
+impl ShapedFrames {
+    fn new() -> ShapedFrames {
+        ShapedFrames { queues: Vec::new() }
+    }
+
+    /// Enqueues the given frame of the given class, or sends it right away if that class's queue is empty
+    /// and its rate limit allows it. Returns the frame if it should be sent now.
+    fn enqueue(&mut self, class: TrafficClassRef, frame: TransmitBuffer) -> Option<TransmitBuffer> {
+        let index = match self.queues.iter().position(|(c, _)| Arc::ptr_eq(c, &class)) {
+            Some(index) => index,
+            _ => {
+                self.queues.push((class, VecDeque::new()));
+                self.queues.len() - 1
+            }
+        };
+        let (class, queue) = &mut self.queues[index];
+        if queue.is_empty() && class.try_send(frame.length as usize) {
+            return Some(frame);
+        }
+        if queue.len() >= MAX_SHAPED_FRAMES_PER_CLASS {
+            // Dropping the frame is like a full NIC queue; TCP will retransmit it later.
+            class.note_dropped();
+            if let Ok(bytes) = frame.as_slice::<u8>(0, frame.length as usize) {
+                stats::record_drop(bytes);
+            }
+        } else {
+            class.note_delayed();
+            queue.push_back(frame);
+        }
+        None
+    }
+
+    /// Removes and returns all queued frames that their classes' rate limits now allow to be sent, in order.
+    fn dequeue_ready(&mut self) -> Vec<TransmitBuffer> {
+        let mut ready = Vec::new();
+        for (class, queue) in self.queues.iter_mut() {
+            while queue.front().map_or(false, |frame| class.try_send(frame.length as usize)) {
+                ready.extend(queue.pop_front());
+            }
+        }
+        // Forget classes that no longer have waiting frames and are no longer used by any port.
+        self.queues.retain(|(class, queue)| !queue.is_empty() || Arc::strong_count(class) > 1);
+        ready
+    }
+
+    /// Removes and returns all queued frames regardless of their classes' rate limits, 
+    /// ordered by class and then by the order in which they were queued.
+    fn dequeue_all(&mut self) -> Vec<TransmitBuffer> {
+        self.queues.iter_mut()
+            .flat_map(|(_, queue)| queue.drain(..))
+            .collect()
+    }
+}
+
+/// Sends the given frame on the given NIC, recording it in the network statistics.
+fn send_frame<N: NetworkInterfaceCard>(nic_ref: &MutexIrqSafe<N>, frame: TransmitBuffer) -> smoltcp::Result<()> {
+    let protocol = frame.as_slice::<u8>(0, frame.length as usize).ok().map(stats::record_outgoing);
+    nic_ref.lock()
+        .send_packet(frame)
+        .map_err(|e| {
+            error!("EthernetDevice::transmit(): error sending Ethernet packet: {:?}", e);
+            if let Some(protocol) = protocol {
+                stats::record_protocol_drop(protocol);
+            }
+            smoltcp::Error::Exhausted
+        })
+}
+
+
 /// To connect the ethernet driver to smoltcp, 
 /// we implement transmit and receive callbacks
 /// that allow smoltcp to interact with the NIC.
@@ -173,17 +284,46 @@ impl<'d, N: NetworkInterfaceCard + 'static> smoltcp::phy::Device<'d> for Etherne
     }
 
     fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        // smoltcp calls this at the start of every poll, so this is where shaped frames
+        // that have waited long enough for their rate limits are sent.
+        let ready_frames = self.shaped_frames.lock().dequeue_ready();
+        for frame in ready_frames {
+            let _ = send_frame(self.nic_ref, frame);
+        }
+
         // According to the smoltcp code, AFAICT, this function should poll the ethernet driver
         // to see if a new packet (Ethernet frame) has arrived, and if so, 
         // take ownership of it and return it inside of an RxToken.
         // Otherwise, if no new packets have arrived, return None.
-        let received_frame = {
-            let mut nic = self.nic_ref.lock();
-            nic.poll_receive().map_err(|_e| {
-                error!("EthernetDevice::receive(): error returned from poll_receive(): {}", _e);
-                _e
-            }).ok()?;
-            nic.get_received_frame()?
+        let received_frame = loop {
+            let received_frame = {
+                let mut nic = self.nic_ref.lock();
+                nic.poll_receive().map_err(|_e| {
+                    error!("EthernetDevice::receive(): error returned from poll_receive(): {}", _e);
+                    _e
+                }).ok()?;
+                nic.get_received_frame()?
+            };
+            // Drop incoming frames with invalid checksums, and police incoming frames of shaped traffic:
+            // drop those that exceed their class's rate limit.
+            let accepted = {
+                let first_buf = &received_frame.0[0];
+                match first_buf.as_slice::<u8>(0, first_buf.length as usize) {
+                    Ok(bytes) => {
+                        let conforms = shaping::classify_incoming(bytes).map_or(true, |class| class.try_receive(bytes.len()));
+                        if conforms {
+                            stats::record_incoming(bytes)
+                        } else {
+                            stats::record_drop(bytes);
+                            false
+                        }
+                    }
+                    _ => true,
+                }
+            };
+            if accepted {
+                break received_frame;
+            }
         };
 
         // debug!("EthernetDevice::receive(): got Ethernet frame, consists of {} ReceiveBuffers.", received_frame.0.len());
@@ -207,6 +347,7 @@ impl<'d, N: NetworkInterfaceCard + 'static> smoltcp::phy::Device<'d> for Etherne
             RxToken(rxbuf_byte_slice),
             TxToken {
                 nic_ref: self.nic_ref,
+                shaped_frames: self.shaped_frames.clone(),
             },
         ))
     }
@@ -218,15 +359,18 @@ impl<'d, N: NetworkInterfaceCard + 'static> smoltcp::phy::Device<'d> for Etherne
         // because we don't yet know its required length.
         Some(TxToken {
             nic_ref: self.nic_ref,
+            shaped_frames: self.shaped_frames.clone(),
         })
     }
 }
 
 
 /// The transmit token type used by smoltcp, which contains only a reference to the relevant NIC 
+/// (and its queues of shaped frames)
 /// because the actual transmit buffer is allocated lazily only when it needs to be consumed.
 pub struct TxToken<N: NetworkInterfaceCard + 'static> {
     nic_ref: &'static MutexIrqSafe<N>,
+    shaped_frames: ShapedFramesRef,
 }
 impl<N: NetworkInterfaceCard + 'static> smoltcp::phy::TxToken for TxToken<N> {
     fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
@@ -254,12 +398,16 @@ impl<N: NetworkInterfaceCard + 'static> smoltcp::phy::TxToken for TxToken<N> {
             })?;
             f(txbuf_byte_slice)?
         };
-        self.nic_ref.lock()
-            .send_packet(txbuf)
-            .map_err(|e| {
-                error!("EthernetDevice::transmit(): error sending Ethernet packet: {:?}", e);
-                smoltcp::Error::Exhausted
-            })?;
+        let class = txbuf.as_slice::<u8>(0, len).ok().and_then(shaping::classify_outgoing);
+
+        // Shaped frames may have to wait in their class's queue, from which they're sent during a later poll.
+        let frame_to_send = match class {
+            Some(class) => self.shaped_frames.lock().enqueue(class, txbuf),
+            _ => Some(txbuf),
+        };
+        if let Some(frame) = frame_to_send {
+            send_frame(self.nic_ref, frame)?;
+        }
         
         Ok(closure_retval)
     }
@@ -35,7 +35,7 @@ pub fn init(idt_ref: &'static LockedIdt) {
         // reserved: 0x0f vector 15
         // missing: 0x10 floating point exception
         // missing: 0x11 alignment check exception
-        // missing: 0x12 machine check exception
+        idt.machine_check.set_handler_fn(machine_check_handler);
         // missing: 0x13 SIMD floating point exception
         // missing: 0x14 virtualization vector 20
         // missing: 0x15 - 0x1d SIMD floating point exception
@@ -150,3 +150,13 @@ pub extern "x86-interrupt" fn early_page_fault_handler(stack_frame: &mut Excepti
     );
     loop {}
 }
+
+
+/// exception 0x12
+pub extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut ExceptionStackFrame) {
+    println_raw!("\nEXCEPTION (early): MACHINE CHECK at {:#x}\n{:#?}",
+             stack_frame.instruction_pointer,
+             stack_frame);
+
+    loop {}
+}
@@ -8,6 +8,7 @@ extern crate task;
 // extern crate apic;
 extern crate tlb_shootdown;
 extern crate pmu_x86;
+extern crate irq_safety;
 #[macro_use] extern crate log;
 #[macro_use] extern crate vga_buffer; // for println_raw!()
 #[macro_use] extern crate print; // for regular println!()
@@ -16,12 +17,14 @@ extern crate debug_info;
 extern crate gimli;
 
 extern crate memory;
+extern crate kernel_config;
 extern crate stack_trace;
 extern crate fault_log;
 
 use x86_64::structures::idt::{LockedIdt, ExceptionStackFrame, PageFaultErrorCode};
 use x86_64::registers::msr::*;
 use fault_log::log_exception;
+use memory::PageFaultKind;
 
 pub fn init(idt_ref: &'static LockedIdt) {
     { 
@@ -46,7 +49,7 @@ pub fn init(idt_ref: &'static LockedIdt) {
         // reserved: 0x0f vector 15
         // missing: 0x10 floating point exception
         // missing: 0x11 alignment check exception
-        // missing: 0x12 machine check exception
+        idt.machine_check.set_handler_fn(machine_check_handler);
         // missing: 0x13 SIMD floating point exception
         // missing: 0x14 virtualization vector 20
         // missing: 0x15 - 0x1d SIMD floating point exception
@@ -196,6 +199,12 @@ fn kill_and_halt(exception_number: u8, stack_frame: &ExceptionStackFrame) {
     // But in general, this task should have already been marked as killed and thus no longer schedulable,
     // so it should not reach this point. 
     // Only exceptions during the early OS initialization process will get here, meaning that the OS will basically stop.
+    //
+    // The double fault handler runs on its own per-core stack, which the killed task will never return to,
+    // so we can re-enable interrupts such that this core switches away from the killed task and keeps running other tasks.
+    if exception_number == 0x8 && task::get_my_current_task().map_or(false, |t| t.lock().has_exited()) {
+        irq_safety::enable_interrupts();
+    }
     loop { }
 }
 
@@ -231,13 +240,9 @@ extern "x86-interrupt" fn nmi_handler(stack_frame: &mut ExceptionStackFrame) {
     }
 
     // currently we're using NMIs to send TLB shootdown IPIs
-    {
-        let pages_to_invalidate = tlb_shootdown::TLB_SHOOTDOWN_IPI_PAGES.read().clone();
-        if let Some(pages) = pages_to_invalidate {
-            // trace!("nmi_handler (AP {})", apic::get_my_apic_id());
-            tlb_shootdown::handle_tlb_shootdown_ipi(pages);
-            expected_nmi = true;
-        }
+    if tlb_shootdown::handle_tlb_shootdown_ipi() {
+        // trace!("nmi_handler (AP {})", apic::get_my_apic_id());
+        expected_nmi = true;
     }
 
     if expected_nmi {
@@ -305,10 +310,70 @@ pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: &mut Exc
 
 /// exception 0x08
 pub extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+    use x86_64::registers::control_regs;
+
     println_both!("\nEXCEPTION: DOUBLE FAULT\n{:#?}\n", stack_frame);
-    
-    log_exception(0x8, stack_frame.instruction_pointer.0, Some(error_code), None);
-    kill_and_halt(0x8, stack_frame)
+
+    // The address accessed by the page fault that most likely led to this double fault, if there was one.
+    let fault_addr = control_regs::cr2().0;
+    let attributable = diagnose_double_fault(stack_frame, fault_addr);
+
+    log_exception(0x8, stack_frame.instruction_pointer.0, Some(error_code), Some(fault_addr));
+    if attributable {
+        kill_and_halt(0x8, stack_frame)
+    }
+    println_both!("The double fault cannot be attributed to a single task, halting this core.");
+    loop { }
+}
+
+/// Prints which task caused a double fault and where its stack pointer and the faulting address lie
+/// relative to that task's stack, detecting stack overflows specifically.
+/// 
+/// The double fault handler runs on its own stack, so we can inspect the current task's stack even if it has overflowed,
+/// which is the most common cause of a double fault: the CPU can't push the page fault's stack frame
+/// onto a stack whose pointer is already within its guard page.
+/// 
+/// Returns `true` if the double fault is attributable to the current task, such that it's safe to kill just that task.
+/// That is not the case for an idle task, or if the stack pointer lies outside of the current task's stack,
+/// which indicates that more than just that task may be corrupted.
+fn diagnose_double_fault(stack_frame: &ExceptionStackFrame, fault_addr: usize) -> bool {
+    let curr_task = match task::get_my_current_task() {
+        Some(t) => t,
+        None => {
+            println_both!("No current task, the double fault occurred during early initialization.");
+            return false;
+        }
+    };
+    let (task_id, core, guard_page_bottom, stack_bottom, stack_top, is_idle) = {
+        let t = curr_task.lock();
+        (t.id, t.running_on_cpu, t.kstack.guard_page().start_address().value(), t.kstack.bottom().value(), t.kstack.top_unusable().value(), t.is_an_idle_task)
+    };
+    let in_stack = |addr: usize| addr >= stack_bottom && addr < stack_top;
+    let in_guard_page = |addr: usize| addr >= guard_page_bottom && addr < stack_bottom;
+    let location = |addr: usize| {
+        if in_stack(addr) { "within the stack" }
+        else if in_guard_page(addr) { "within the stack's guard page" }
+        else { "outside of the stack" }
+    };
+    let stack_pointer = stack_frame.stack_pointer.0 as usize;
+
+    println_both!("Faulting task: {:?} (id {}) on core {:?}", curr_task, task_id, core);
+    println_both!("  stack:          {:#X} - {:#X}, guard page {:#X} - {:#X}", stack_bottom, stack_top, guard_page_bottom, stack_bottom);
+    println_both!("  stack pointer:  {:#X}, {}", stack_pointer, location(stack_pointer));
+    println_both!("  fault address:  {:#X}, {}", fault_addr, location(fault_addr));
+
+    if in_guard_page(stack_pointer) || in_guard_page(fault_addr) {
+        println_both!("Likely cause: STACK OVERFLOW of task {:?}", curr_task);
+    }
+    if is_idle {
+        println_both!("The faulting task is an idle task, which cannot be killed.");
+        return false;
+    }
+    if !in_stack(stack_pointer) && !in_guard_page(stack_pointer) {
+        println_both!("The stack pointer lies outside of the faulting task's stack, so the fault may not be caused by that task alone.");
+        return false;
+    }
+    true
 }
 
 /// exception 0x0a
@@ -348,9 +413,38 @@ pub extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: &mut
 pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut ExceptionStackFrame, error_code: PageFaultErrorCode) {
     use x86_64::registers::control_regs;
 
+    // A fault on a non-present page may be an access to a page that was evicted to swap or the first access to a lazily-mapped page,
+    // and a write fault on a present page may be the first write to a copy-on-write page, all of which can be fixed up.
+    let fault_vaddr = memory::VirtualAddress::new_canonical(control_regs::cr2().0);
+    let fixup = if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        match memory::handle_swap_page_fault(fault_vaddr) {
+            Ok(true) => Ok(Some(PageFaultKind::SwapIn)),
+            Ok(false) => memory::handle_demand_page_fault(fault_vaddr).map(|fixed| if fixed { Some(PageFaultKind::DemandZero) } else { None }),
+            Err(e) => Err(e),
+        }
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        memory::handle_cow_page_fault(fault_vaddr).map(|fixed| if fixed { Some(PageFaultKind::CopyOnWrite) } else { None })
+    } else {
+        Ok(None)
+    };
+    match fixup {
+        Ok(Some(kind)) => {
+            record_page_fault(kind);
+            return;
+        }
+        Ok(None) => { }
+        Err(e) => println_both!("\nCouldn't fix up page fault at {:#X}: {}", control_regs::cr2(), e),
+    }
+
+    // An access within the guard page beneath the current task's stack means that its stack has overflowed.
+    // The backtrace printed when killing the task shows which call chain overflowed it.
+    let stack_overflow = diagnose_stack_overflow(stack_frame, control_regs::cr2().0);
+    record_page_fault(if stack_overflow { PageFaultKind::GuardPage } else { PageFaultKind::Invalid });
+
     #[cfg(not(downtime_eval))]
-    println_both!("\nEXCEPTION: PAGE FAULT while accessing {:#X}\nerror code: \
+    println_both!("\nEXCEPTION: {} while accessing {:#X}\nerror code: \
                                   {:?}\n{:#?}\n",
+             if stack_overflow { "STACK OVERFLOW (PAGE FAULT)" } else { "PAGE FAULT" },
              control_regs::cr2(),
              error_code,
              stack_frame);
@@ -359,4 +453,46 @@ pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut ExceptionStac
     kill_and_halt(0xE, stack_frame)
 }
 
+/// Counts a page fault of the given `kind`, both system-wide and for the current task.
+fn record_page_fault(kind: PageFaultKind) {
+    memory::record_page_fault(kind);
+    if let Some(curr_task) = task::get_my_current_task() {
+        curr_task.record_page_fault(kind);
+    }
+}
+
+/// Returns whether the given faulting address lies within the guard page beneath the current task's stack,
+/// in which case it prints which task's stack overflowed.
+/// 
+/// Only overflows that skip past the stack pointer, e.g., by allocating a large stack frame, reach the page fault handler.
+/// Otherwise, the CPU can't push the page fault's stack frame onto the overflowed stack, which causes a double fault instead,
+/// see `diagnose_double_fault()`.
+fn diagnose_stack_overflow(stack_frame: &ExceptionStackFrame, fault_addr: usize) -> bool {
+    let curr_task = match task::get_my_current_task() {
+        Some(t) => t,
+        None => return false,
+    };
+    let (guard_page_bottom, stack_bottom) = {
+        let t = curr_task.lock();
+        (t.kstack.guard_page().start_address().value(), t.kstack.bottom().value())
+    };
+    if fault_addr < guard_page_bottom || fault_addr >= stack_bottom {
+        return false;
+    }
+    println_both!("\nSTACK OVERFLOW in task {:?}: accessed {:#X} within its stack's guard page {:#X} - {:#X}, stack pointer {:#X}",
+        curr_task, fault_addr, guard_page_bottom, stack_bottom, stack_frame.stack_pointer.0
+    );
+    true
+}
+
 // exception 0x0F is reserved on x86
+
+/// exception 0x12
+pub extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut ExceptionStackFrame) {
+    println_both!("\nEXCEPTION: MACHINE CHECK at {:#X}\n{:#?}\n",
+             stack_frame.instruction_pointer,
+             stack_frame);
+
+    log_exception(0x12, stack_frame.instruction_pointer.0, None, None);
+    kill_and_halt(0x12, stack_frame)
+}
@@ -1,6 +1,10 @@
 #![no_std]
 extern crate spin;
 
+mod unicode;
+
+pub use unicode::{char_width, compose, glyph_index, REPLACEMENT_GLYPH};
+
 /// The width of a character.
 pub const CHARACTER_WIDTH: usize = 9;
 /// The height of a character.
@@ -0,0 +1,95 @@
+//! Mapping of Unicode characters to the glyphs of the basic font, and their display widths.
+//!
+//! The basic font has the 256 glyphs of code page 437, so only the characters of that code page can be displayed.
+//! All other characters are displayed as a replacement glyph, which still occupies the same number of columns
+//! that the character would, such that the layout of text doesn't depend on which characters the font supports.
+
+/// The glyph used for characters that the basic font doesn't have.
+pub const REPLACEMENT_GLYPH: usize = b'?' as usize;
+
+/// The characters of the upper half (0x80 - 0xFF) of code page 437, in order.
+const CP437_UPPER_HALF: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Returns the index of the glyph in `FONT_BASIC` that displays the given character,
+/// or `REPLACEMENT_GLYPH` if the basic font doesn't have it.
+pub fn glyph_index(c: char) -> usize {
+    if c.is_ascii() {
+        return c as usize;
+    }
+    CP437_UPPER_HALF.iter()
+        .position(|&g| g == c)
+        .map(|i| 0x80 + i)
+        .unwrap_or(REPLACEMENT_GLYPH)
+}
+
+/// Returns the number of columns that the given character occupies when displayed:
+///  * `0` for combining characters and other zero-width characters, which modify the preceding character,
+///  * `2` for wide characters, e.g., East Asian ideographs and most emoji,
+///  * `1` for all other characters.
+pub fn char_width(c: char) -> usize {
+    match c as u32 {
+        // combining diacritical marks and other combining characters
+        0x0300 ..= 0x036F |
+        0x0483 ..= 0x0489 |
+        0x0591 ..= 0x05BD |
+        0x0610 ..= 0x061A |
+        0x064B ..= 0x065F |
+        0x1AB0 ..= 0x1AFF |
+        0x1DC0 ..= 0x1DFF |
+        0x20D0 ..= 0x20FF |
+        0xFE20 ..= 0xFE2F |
+        // zero-width spaces, joiners, and directional marks
+        0x200B ..= 0x200F |
+        0x2060 ..= 0x2064 |
+        0xFEFF |
+        // variation selectors
+        0xFE00 ..= 0xFE0F => 0,
+
+        // Hangul Jamo, CJK, Hiragana, Katakana, Hangul syllables, fullwidth forms, and emoji
+        0x1100 ..= 0x115F |
+        0x2E80 ..= 0x303E |
+        0x3041 ..= 0x33FF |
+        0x3400 ..= 0x4DBF |
+        0x4E00 ..= 0x9FFF |
+        0xA000 ..= 0xA4CF |
+        0xAC00 ..= 0xD7A3 |
+        0xF900 ..= 0xFAFF |
+        0xFE30 ..= 0xFE4F |
+        0xFF00 ..= 0xFF60 |
+        0xFFE0 ..= 0xFFE6 |
+        0x1F300 ..= 0x1F64F |
+        0x1F900 ..= 0x1F9FF |
+        0x20000 ..= 0x2FFFD |
+        0x30000 ..= 0x3FFFD => 2,
+
+        _ => 1,
+    }
+}
+
+/// Returns the precomposed character that is equivalent to the given `base` character
+/// followed by the combining character `mark`, e.g., `a` and U+0308 (combining diaeresis) into `ä`.
+///
+/// Only the common Latin accents are supported; returns `None` for any other combination.
+pub fn compose(base: char, mark: char) -> Option<char> {
+    let (bases, composed) = match mark {
+        '\u{0300}' => ("AEIOUaeiou", "ÀÈÌÒÙàèìòù"),       // grave
+        '\u{0301}' => ("AEIOUYaeiouy", "ÁÉÍÓÚÝáéíóúý"),   // acute
+        '\u{0302}' => ("AEIOUaeiou", "ÂÊÎÔÛâêîôû"),       // circumflex
+        '\u{0303}' => ("ANOano", "ÃÑÕãñõ"),               // tilde
+        '\u{0308}' => ("AEIOUaeiouy", "ÄËÏÖÜäëïöüÿ"),     // diaeresis
+        '\u{030A}' => ("Aa", "Åå"),                       // ring above
+        '\u{0327}' => ("Cc", "Çç"),                       // cedilla
+        _ => return None,
+    };
+    let index = bases.chars().position(|b| b == base)?;
+    composed.chars().nth(index)
+}
@@ -0,0 +1,92 @@
+//! A background service that zeroes freed frames ahead of time
+//! and places them into the memory crate's pool of pre-zeroed frames.
+//! 
+//! Allocations that require zeroed memory, e.g., [`memory::create_zeroed_mapping()`], 
+//! prefer pre-zeroed frames, which avoids the latency of zeroing frames synchronously.
+//! This service's task runs at the lowest priority, such that zeroing only happens when a core is otherwise idle.
+//! 
+//! [`memory::create_zeroed_mapping()`]: ../memory/fn.create_zeroed_mapping.html
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate kernel_config;
+extern crate memory;
+extern crate task;
+extern crate spawn;
+extern crate scheduler;
+extern crate wait_queue;
+
+use alloc::vec::Vec;
+use kernel_config::memory::ZEROED_FRAME_BATCH_SIZE;
+use spin::Once;
+use task::TaskRef;
+use wait_queue::WaitQueue;
+
+
+/// The lowest task priority, see `scheduler::set_priority()`.
+const IDLE_PRIORITY: u8 = 0;
+
+/// The queue that the frame zeroer task waits on until there are freed frames to zero.
+static FRAME_ZEROER_WAIT_QUEUE: Once<WaitQueue> = Once::new();
+
+
+/// Spawns the frame zeroer task and registers it with the `memory` crate
+/// such that it will be notified when frames are freed.
+/// 
+/// Returns the newly-spawned frame zeroer task. 
+/// This should only be invoked once; subsequent invocations return an error.
+pub fn init() -> Result<TaskRef, &'static str> {
+    if FRAME_ZEROER_WAIT_QUEUE.try().is_some() {
+        return Err("frame zeroer was already initialized");
+    }
+    FRAME_ZEROER_WAIT_QUEUE.call_once(|| WaitQueue::new());
+
+    let taskref = spawn::new_task_builder(frame_zeroer_loop, ())
+        .name(format!("frame_zeroer"))
+        .spawn()?;
+    if let Err(_e) = scheduler::set_priority(&taskref, IDLE_PRIORITY) {
+        debug!("frame zeroer: couldn't lower task priority: {}", _e);
+    }
+    memory::set_frames_freed_notifier(notify_frame_zeroer);
+    Ok(taskref)
+}
+
+
+/// Wakes up the frame zeroer task, which is invoked by `memory` after frames are freed.
+fn notify_frame_zeroer() {
+    if let Some(wq) = FRAME_ZEROER_WAIT_QUEUE.try() {
+        wq.notify_one();
+    }
+}
+
+
+/// Returns the number of freed frames that should be zeroed now, if any.
+fn frames_to_zero() -> Option<usize> {
+    let count = core::cmp::min(memory::zeroed_frame_pool_deficit(), memory::freed_frame_count());
+    if count > 0 { Some(core::cmp::min(count, ZEROED_FRAME_BATCH_SIZE)) } else { None }
+}
+
+
+/// The entry point of the frame zeroer task, which never returns unless an error occurs.
+fn frame_zeroer_loop(_: ()) -> Result<(), &'static str> {
+    let wait_queue = FRAME_ZEROER_WAIT_QUEUE.try().ok_or("BUG: frame zeroer wait queue wasn't initialized")?;
+    loop {
+        let count = wait_queue.wait_until(&frames_to_zero)
+            .map_err(|_e| "frame zeroer failed to wait on its wait queue")?;
+
+        let mut zeroed = Vec::with_capacity(count);
+        for frame in memory::take_freed_frames(count) {
+            match memory::zero_frame(frame) {
+                Ok(()) => zeroed.push(frame),
+                Err(e) => {
+                    error!("frame zeroer: failed to zero frame {:?}: {}", frame, e);
+                    memory::deallocate_frame(frame);
+                }
+            }
+        }
+        memory::add_zeroed_frames(zeroed);
+    }
+}
@@ -14,6 +14,9 @@ pub trait Pixel: Copy + Hash + FromBytes {
 
     /// Blend two pixels linearly with weights, as `blend` for `origin` and (1-`blend`) for `other`.
     fn weight_blend(origin: Self, other: Self, blend: f32) -> Self;
+
+    /// Returns the `(red, green, blue)` channels of this pixel, ignoring any extra channel.
+    fn rgb(&self) -> (u8, u8, u8);
 }
 
 
@@ -69,6 +72,11 @@ impl Pixel for RGBPixel {
             blue: new_blue
         }
     }
+
+    #[inline]
+    fn rgb(&self) -> (u8, u8, u8) {
+        (self.red, self.green, self.blue)
+    }
 }
 
 impl From<Color> for RGBPixel {
@@ -134,6 +142,11 @@ impl Pixel for AlphaPixel {
             blue: new_blue
         }
     }
+
+    #[inline]
+    fn rgb(&self) -> (u8, u8, u8) {
+        (self.red, self.green, self.blue)
+    }
 }
 
 impl From<Color> for AlphaPixel {
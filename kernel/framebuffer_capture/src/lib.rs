@@ -0,0 +1,163 @@
+//! Captures the contents of a framebuffer, e.g., the whole screen or a single window,
+//! and saves it as an image file in the filesystem.
+//!
+//! Captured images can be saved in either the PPM format, which is trivial to encode,
+//! or the PNG format, which is more widely supported by image viewers.
+//! Note that PNG images are not compressed, so they are slightly larger than PPM images.
+//!
+//! This crate also supports recording the screen as a sequence of numbered image files,
+//! which is useful for reporting rendering bugs that only occur when the screen changes.
+
+#![no_std]
+
+extern crate alloc;
+extern crate spin;
+extern crate framebuffer;
+extern crate window_manager;
+extern crate window_inner;
+extern crate fs_node;
+extern crate memfs;
+extern crate tsc;
+extern crate sleep;
+
+mod png;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::fmt::Write;
+use spin::Mutex;
+use framebuffer::{Framebuffer, Pixel};
+use fs_node::{DirRef, FileRef};
+use memfs::MemFile;
+use window_inner::WindowInner;
+use window_manager::WINDOW_MANAGER;
+
+
+/// The file format of a captured image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// The binary Portable Pixmap format (`P6`).
+    Ppm,
+    /// The Portable Network Graphics format, without compression.
+    Png,
+}
+impl ImageFormat {
+    /// Returns the file extension typically used for this format, without the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+
+/// An image captured from a framebuffer, with each pixel stored as three bytes: red, green, and blue.
+#[derive(Clone)]
+pub struct CapturedImage {
+    width: usize,
+    height: usize,
+    rgb: Vec<u8>,
+}
+
+impl CapturedImage {
+    /// Copies the current contents of the given framebuffer into a new image.
+    pub fn from_framebuffer<P: Pixel>(framebuffer: &Framebuffer<P>) -> CapturedImage {
+        let (width, height) = framebuffer.get_size();
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for pixel in framebuffer.buffer().iter() {
+            let (red, green, blue) = pixel.rgb();
+            rgb.push(red);
+            rgb.push(green);
+            rgb.push(blue);
+        }
+        CapturedImage { width, height, rgb }
+    }
+
+    /// Returns the `(width, height)` of this image in pixels.
+    pub fn get_size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Encodes this image into the given `format`, returning the contents of the image file.
+    pub fn encode(&self, format: ImageFormat) -> Vec<u8> {
+        match format {
+            ImageFormat::Ppm => {
+                let mut header = String::new();
+                let _ = write!(header, "P6\n{} {}\n255\n", self.width, self.height);
+                let mut file = Vec::with_capacity(header.len() + self.rgb.len());
+                file.extend_from_slice(header.as_bytes());
+                file.extend_from_slice(&self.rgb);
+                file
+            }
+            ImageFormat::Png => png::encode_rgb(self.width, self.height, &self.rgb),
+        }
+    }
+
+    /// Encodes this image into the given `format` and saves it as a new file
+    /// with the given `name` in the given `parent` directory.
+    pub fn save(&self, format: ImageFormat, name: String, parent: &DirRef) -> Result<FileRef, &'static str> {
+        if parent.lock().get(&name).is_some() {
+            return Err("a file or directory with that name already exists");
+        }
+        let contents = self.encode(format);
+        let file = MemFile::new(name, parent)?;
+        file.lock().write(&contents, 0)?;
+        Ok(file)
+    }
+}
+
+
+/// Captures the entire screen, i.e., the window manager's final framebuffer.
+pub fn capture_screen() -> Result<CapturedImage, &'static str> {
+    let window_manager = WINDOW_MANAGER.try().ok_or("the window manager was not yet initialized")?;
+    let image = CapturedImage::from_framebuffer(&window_manager.lock().final_fb);
+    Ok(image)
+}
+
+/// Captures the contents of the given window, including its border and title bar.
+pub fn capture_window(window: &Mutex<WindowInner>) -> CapturedImage {
+    CapturedImage::from_framebuffer(window.lock().framebuffer())
+}
+
+/// Captures the contents of the currently active window.
+pub fn capture_active_window() -> Result<CapturedImage, &'static str> {
+    let window_manager = WINDOW_MANAGER.try().ok_or("the window manager was not yet initialized")?;
+    let active = window_manager.lock().active_window().ok_or("there is no active window")?;
+    Ok(capture_window(&active))
+}
+
+
+/// Records the screen by capturing `frame_count` frames, one every `interval_ms` milliseconds,
+/// and saving each one into the given `parent` directory, named `frame_0000`, `frame_0001`, etc.
+///
+/// All frames are captured before any of them are encoded and saved,
+/// such that encoding does not affect the interval between frames.
+///
+/// Returns the saved image files in order.
+pub fn record_screen(
+    frame_count: usize,
+    interval_ms: u64,
+    format: ImageFormat,
+    parent: &DirRef,
+) -> Result<Vec<FileRef>, &'static str> {
+    let interval_ticks = interval_ms.saturating_mul(tsc::get_tsc_frequency()?) / 1000;
+    let mut frames = Vec::with_capacity(frame_count);
+    for i in 0 .. frame_count {
+        let start: u64 = tsc::tsc_ticks().into();
+        frames.push(capture_screen()?);
+        if i + 1 < frame_count {
+            sleep::sleep_until(start.saturating_add(interval_ticks))?;
+        }
+    }
+
+    let mut files = Vec::with_capacity(frames.len());
+    for (i, frame) in frames.iter().enumerate() {
+        let mut name = String::new();
+        let _ = write!(name, "frame_{:04}.{}", i, format.extension());
+        files.push(frame.save(format, name, parent)?);
+    }
+    Ok(files)
+}
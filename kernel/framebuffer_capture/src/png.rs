@@ -0,0 +1,111 @@
+//! A minimal PNG encoder for 8-bit RGB images.
+//!
+//! To avoid needing a full DEFLATE implementation, the image data is stored
+//! in uncompressed ("stored") DEFLATE blocks, which every PNG decoder supports.
+
+use alloc::vec::Vec;
+
+/// The 8-byte signature at the start of every PNG file.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+/// The maximum length of a single stored DEFLATE block.
+const MAX_STORED_BLOCK_LEN: usize = 0xFFFF;
+/// PNG color type 2 is truecolor RGB.
+const COLOR_TYPE_RGB: u8 = 2;
+/// The per-scanline filter type that means "no filter".
+const FILTER_NONE: u8 = 0;
+
+
+/// Encodes the given image, in which each pixel is three bytes of red, green, and blue, as a PNG file.
+pub fn encode_rgb(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    // Each scanline is prefixed with its filter type.
+    let row_len = width * 3;
+    let mut raw = Vec::with_capacity((row_len + 1) * height);
+    for y in 0 .. height {
+        raw.push(FILTER_NONE);
+        raw.extend_from_slice(&rgb[y * row_len .. (y + 1) * row_len]);
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, COLOR_TYPE_RGB, 0, 0, 0]); // bit depth, color type, compression, filter, interlace
+
+    let crc_table = crc32_table();
+    let mut png = Vec::with_capacity(raw.len() + 1024);
+    png.extend_from_slice(&PNG_SIGNATURE);
+    write_chunk(&mut png, b"IHDR", &ihdr, &crc_table);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw), &crc_table);
+    write_chunk(&mut png, b"IEND", &[], &crc_table);
+    png
+}
+
+/// Appends a PNG chunk with the given type and data to `png`.
+fn write_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8], crc_table: &[u32; 256]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let crc_start = png.len();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[crc_start..], crc_table);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps the given data in a zlib stream made of uncompressed DEFLATE blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let num_blocks = core::cmp::max(1, (data.len() + MAX_STORED_BLOCK_LEN - 1) / MAX_STORED_BLOCK_LEN);
+    let mut out = Vec::with_capacity(data.len() + (num_blocks * 5) + 6);
+    // CMF: deflate with a 32K window; FLG: no dictionary, fastest compression, with a valid check value.
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut chunks = data.chunks(MAX_STORED_BLOCK_LEN).peekable();
+    if chunks.peek().is_none() {
+        // An empty stream still needs one final block.
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let is_final = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(if is_final { 0x01 } else { 0x00 });
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Computes the Adler-32 checksum of the given data, as required at the end of a zlib stream.
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    // 5552 is the largest number of bytes that can be summed before `b` could overflow.
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+/// Builds the lookup table for the CRC-32 used by PNG chunks.
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+    table
+}
+
+fn crc32(data: &[u8], table: &[u32; 256]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc = table[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xFFFF_FFFF
+}
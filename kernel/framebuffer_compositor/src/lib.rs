@@ -95,6 +95,14 @@ pub struct FrameCompositor {
 }
 
 impl FrameCompositor {
+    /// Discards all cached blocks, such that the next composition redraws every given region.
+    /// 
+    /// This must be invoked when the destination framebuffer is replaced, e.g., after changing the display mode,
+    /// because the cache assumes that the destination still holds the previously-composited contents.
+    pub fn clear_cache(&mut self) {
+        self.caches.clear();
+    }
+
     /// Checks if some rows of a framebuffer are cached.
     /// # Arguments
     /// * `row_pixels`: the continuous pixels in the rows.
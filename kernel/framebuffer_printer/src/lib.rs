@@ -17,6 +17,7 @@ use shapes::{Coord, Rectangle};
 type ASCII = u8;
 
 /// Prints a string in a framebuffer.
+/// Wide characters (see `font::char_width()`) occupy two columns, and combining characters are merged into the preceding character.
 /// Returns (column, line, rectangle), i.e. the position of the next symbol and an rectangle which covers the updated area.
 /// A block item (index, width) represents the index of line number and the width of charaters in this line as pixels. It can be viewed as a framebuffer block which is described in the `framebuffer_compositor` crate.
 /// # Arguments
@@ -47,8 +48,11 @@ pub fn print_string<P: Pixel>(
 
     let top_left = Coord::new(0, (curr_line * CHARACTER_HEIGHT) as isize);
 
-    for byte in slice.bytes() {
-        if byte == b'\n' {
+    // The location and character of the most recently printed character, which a combining character modifies.
+    let mut previous: Option<(usize, usize, char)> = None;
+
+    for c in slice.chars() {
+        if c == '\n' {
             let mut blank = Rectangle {
                 top_left: Coord::new(
                     coordinate.x + (curr_column * CHARACTER_WIDTH) as isize,
@@ -67,11 +71,40 @@ pub fn print_string<P: Pixel>(
             );
             curr_column = 0;
             curr_line += 1;
+            previous = None;
             if curr_line == buffer_height {
                 break;
             }
         } else {
-            if curr_column == buffer_width {
+            let char_width = font::char_width(c);
+            if char_width == 0 {
+                // A combining character is displayed by replacing the preceding character with its precomposed form.
+                // Combinations that have no precomposed form are displayed without the combining character.
+                if let Some((column, line, base)) = previous {
+                    if let Some(composed) = font::compose(base, c) {
+                        print_character(framebuffer, composed, fg_pixel, bg_pixel, coordinate, column, line);
+                        previous = Some((column, line, composed));
+                    }
+                }
+                continue;
+            }
+            // wrap to the next line if the character doesn't fit, e.g., a wide character in the last column
+            if curr_column > 0 && curr_column + char_width > buffer_width {
+                let mut blank = Rectangle {
+                    top_left: Coord::new(
+                        coordinate.x + (curr_column * CHARACTER_WIDTH) as isize,
+                        coordinate.y + (curr_line * CHARACTER_HEIGHT) as isize,
+                    ),
+                    bottom_right: Coord::new(
+                        coordinate.x + width as isize,
+                        coordinate.y + ((curr_line + 1) * CHARACTER_HEIGHT) as isize,
+                    )
+                };
+                fill_blank(
+                    framebuffer,
+                    &mut blank,
+                    bg_pixel,
+                );
                 curr_column = 0;
                 curr_line += 1;
                 if curr_line == buffer_height {
@@ -79,16 +112,29 @@ pub fn print_string<P: Pixel>(
                 }
             }
             // print the next character
-            print_ascii_character(
+            print_character(
                 framebuffer,
-                byte,
+                c,
                 fg_pixel,
                 bg_pixel,
                 coordinate,
                 curr_column,
                 curr_line,
             );
-            curr_column += 1;
+            // the basic font has no wide glyphs, so the second column of a wide character is left blank
+            if char_width == 2 && curr_column + 1 < buffer_width {
+                print_ascii_character(
+                    framebuffer,
+                    b' ',
+                    fg_pixel,
+                    bg_pixel,
+                    coordinate,
+                    curr_column + 1,
+                    curr_line,
+                );
+            }
+            previous = Some((curr_column, curr_line, c));
+            curr_column = core::cmp::min(curr_column + char_width, buffer_width);
         }
     }  
 
@@ -140,10 +186,25 @@ pub fn print_string<P: Pixel>(
     (curr_column, curr_line, update_area)
 }
 
+/// Prints a Unicode character to the framebuffer at position (line, column) of all characters in the text area.
+/// Characters that the font doesn't have are displayed as `font::REPLACEMENT_GLYPH`.
+/// See [`print_ascii_character()`](fn.print_ascii_character.html) for the arguments.
+pub fn print_character<P: Pixel>(
+    framebuffer: &mut Framebuffer<P>,
+    character: char,
+    fg_pixel: P,
+    bg_pixel: P,
+    coordinate: Coord,
+    column: usize,
+    line: usize,
+) {
+    print_ascii_character(framebuffer, font::glyph_index(character) as ASCII, fg_pixel, bg_pixel, coordinate, column, line)
+}
+
 /// Prints a character to the framebuffer at position (line, column) of all characters in the text area.
 /// # Arguments
 /// * `framebuffer`: the framebuffer to display in.
-/// * `character`: the ASCII code of the character to display.
+/// * `character`: the code of the character to display, i.e., the index of its glyph in the font.
 /// * `fg_pixel`: the value of every pixel in the character.
 /// * `bg_color`: the value of every pixel in the background.
 /// * `coordinate`: the left top coordinate of the text block relative to the origin(top-left point) of the framebuffer.
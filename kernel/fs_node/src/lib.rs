@@ -22,6 +22,10 @@ use alloc::sync::{Arc, Weak};
 use memory::MappedPages;
 
 
+/// The file mode bits (in the traditional octal form) of a file that was created without a specific mode,
+/// i.e., readable and writable by everyone.
+pub const BASE_FILE_MODE: u16 = 0o666;
+
 /// A reference to any type that implements the Directory trait.
 pub type DirRef =  Arc<Mutex<dyn Directory + Send>>;
 /// A weak reference to any type that implements the Directory trait.
@@ -76,6 +80,14 @@ pub trait File : FsNode {
 
     /// Returns a view of this file as an immutable memory-mapped region.
     fn as_mapping(&self) -> Result<&MappedPages, &'static str>;
+
+    /// Returns the mode bits (in the traditional octal form) that this file was created with.
+    /// 
+    /// These are recorded for tools and future access checks, but are not yet enforced by the VFS.
+    /// The default implementation returns [`BASE_FILE_MODE`](constant.BASE_FILE_MODE.html).
+    fn mode(&self) -> u16 {
+        BASE_FILE_MODE
+    }
 }
 
 /// Trait for directories, implementors of Directory must also implement FsNode
@@ -21,6 +21,7 @@ use x86_64::{
 };
 use spin::Once;
 use memory::VirtualAddress;
+use tss::InterruptStacks;
 
 
 lazy_static! {
@@ -80,16 +81,16 @@ pub fn get_segment_selector(selector: AvailableSegmentSelector) -> SegmentSelect
 }
 
 
-/// Creates a new GDT, sets up the TSS with the given double fault stack
+/// Creates a new GDT, sets up the TSS with the given interrupt stacks
 /// and privilege stack, and then loads that new GDT & TSS.
 pub fn create_tss_gdt(apic_id: u8, 
-                  double_fault_stack_top_unusable: VirtualAddress, 
+                  interrupt_stacks: InterruptStacks, 
                   privilege_stack_top_unusable: VirtualAddress) {
     use x86_64::instructions::segmentation::{set_cs, load_ds, load_ss};
     use x86_64::instructions::tables::load_tss;
 
     
-    let tss_ref = tss::create_tss(apic_id, double_fault_stack_top_unusable, privilege_stack_top_unusable);
+    let tss_ref = tss::create_tss(apic_id, interrupt_stacks, privilege_stack_top_unusable);
 
     // set up this AP's GDT
     {
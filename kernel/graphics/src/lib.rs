@@ -0,0 +1,390 @@
+//! A 2D drawing library that rasterizes lines, rectangles, circles, images, and text in software.
+//!
+//! All drawing is done through a [`Canvas`], which wraps a framebuffer and an optional clipping region.
+//! Coordinates given to a `Canvas` are relative to the top-left corner of its clipping region,
+//! and anything drawn outside of that region is discarded.
+//! This allows an application to draw into the content area of its window
+//! without overwriting the window's title bar or border, see [`draw_in_window()`].
+//!
+//! Colors with a nonzero transparency are alpha-blended with the existing contents of the framebuffer.
+//! Text is rendered using the glyphs of the `font` crate.
+//!
+//! [`Canvas`]: struct.Canvas.html
+//! [`draw_in_window()`]: fn.draw_in_window.html
+
+#![no_std]
+
+extern crate framebuffer;
+extern crate font;
+extern crate shapes;
+extern crate color;
+extern crate window;
+
+use core::cmp::{max, min};
+use color::Color;
+use font::{CHARACTER_HEIGHT, CHARACTER_WIDTH, FONT_BASIC};
+use framebuffer::{AlphaPixel, Framebuffer, Pixel};
+use shapes::{Coord, Rectangle};
+use window::Window;
+
+
+/// Returns a copy of the given `color` with the given `opacity`,
+/// in which `0` is fully transparent and `0xFF` is fully opaque.
+///
+/// This is the inverse of `Color`'s transparency component, which is often more intuitive for blending.
+pub fn with_opacity(mut color: Color, opacity: u8) -> Color {
+    color.set_transparency(0xFF - opacity);
+    color
+}
+
+/// Returns the `(width, height)` in pixels of the given `text` when drawn via [`Canvas::draw_text()`].
+///
+/// [`Canvas::draw_text()`]: struct.Canvas.html#method.draw_text
+pub fn text_size(text: &str) -> (usize, usize) {
+    let mut lines = 0;
+    let mut longest_line = 0;
+    for line in text.split('\n') {
+        lines += 1;
+        longest_line = max(longest_line, line.len());
+    }
+    (longest_line * CHARACTER_WIDTH, lines * CHARACTER_HEIGHT)
+}
+
+
+/// A drawing surface that wraps a framebuffer and restricts drawing to a clipping region within it.
+pub struct Canvas<'f, P: Pixel + From<Color>> {
+    framebuffer: &'f mut Framebuffer<P>,
+    /// The region of the framebuffer that can be drawn in, relative to the framebuffer's origin.
+    /// This is always contained within the framebuffer's bounds.
+    clip: Rectangle,
+}
+
+impl<'f, P: Pixel + From<Color>> Canvas<'f, P> {
+    /// Creates a canvas for drawing onto the entire given `framebuffer`.
+    pub fn new(framebuffer: &'f mut Framebuffer<P>) -> Canvas<'f, P> {
+        let (width, height) = framebuffer.get_size();
+        let clip = Rectangle {
+            top_left: Coord::new(0, 0),
+            bottom_right: Coord::new(width as isize, height as isize),
+        };
+        Canvas { framebuffer, clip }
+    }
+
+    /// Creates a canvas for drawing only within the given `clip` region of the `framebuffer`,
+    /// which is relative to the framebuffer's origin.
+    ///
+    /// The canvas' origin is the top-left corner of the `clip` region.
+    /// Any part of the `clip` region that lies outside of the framebuffer is ignored.
+    pub fn with_clip(framebuffer: &'f mut Framebuffer<P>, clip: Rectangle) -> Canvas<'f, P> {
+        let (width, height) = framebuffer.get_size();
+        let bottom_right = Coord::new(
+            min(clip.bottom_right.x, width as isize),
+            min(clip.bottom_right.y, height as isize),
+        );
+        // Keep the canvas' origin where the caller asked for it, even if it lies outside the framebuffer,
+        // such that drawing coordinates remain relative to the given `clip` region.
+        let clip = Rectangle {
+            top_left: clip.top_left,
+            bottom_right: Coord::new(max(bottom_right.x, clip.top_left.x), max(bottom_right.y, clip.top_left.y)),
+        };
+        Canvas { framebuffer, clip }
+    }
+
+    /// Returns the `(width, height)` of this canvas' drawing region in pixels.
+    pub fn get_size(&self) -> (usize, usize) {
+        (self.clip.width(), self.clip.height())
+    }
+
+    /// Returns the rectangle of the given size starting at `coordinate`,
+    /// clipped to this canvas' drawing region and converted to framebuffer coordinates.
+    /// Returns `None` if nothing would be visible.
+    fn clip_rectangle(&self, coordinate: Coord, width: usize, height: usize) -> Option<Rectangle> {
+        let start = coordinate + self.clip.top_left;
+        let top_left = Coord::new(
+            max(max(start.x, self.clip.top_left.x), 0),
+            max(max(start.y, self.clip.top_left.y), 0),
+        );
+        let bottom_right = Coord::new(
+            min(start.x + width as isize, self.clip.bottom_right.x),
+            min(start.y + height as isize, self.clip.bottom_right.y),
+        );
+        if top_left.x >= bottom_right.x || top_left.y >= bottom_right.y {
+            None
+        } else {
+            Some(Rectangle { top_left, bottom_right })
+        }
+    }
+
+    /// Draws a single pixel at the given `coordinate` relative to this canvas, blending it with the existing pixel.
+    fn put_pixel(&mut self, coordinate: Coord, pixel: P) {
+        let absolute = coordinate + self.clip.top_left;
+        if absolute.x >= max(self.clip.top_left.x, 0)
+            && absolute.x < self.clip.bottom_right.x
+            && absolute.y >= max(self.clip.top_left.y, 0)
+            && absolute.y < self.clip.bottom_right.y
+        {
+            self.framebuffer.draw_pixel(absolute, pixel);
+        }
+    }
+
+    /// Returns the pixel at the given `coordinate` relative to this canvas,
+    /// or `None` if it lies outside of this canvas' drawing region.
+    pub fn get_pixel(&self, coordinate: Coord) -> Option<P> {
+        self.clip_rectangle(coordinate, 1, 1)
+            .and_then(|rect| self.framebuffer.get_pixel(rect.top_left))
+    }
+
+    /// Overwrites every pixel in this canvas' drawing region with the given `color`, without blending.
+    pub fn clear(&mut self, color: Color) {
+        let (width, height) = self.get_size();
+        if let Some(rect) = self.clip_rectangle(Coord::new(0, 0), width, height) {
+            let pixel: P = color.into();
+            let (fb_width, _) = self.framebuffer.get_size();
+            let buffer = self.framebuffer.buffer_mut();
+            for y in rect.top_left.y .. rect.bottom_right.y {
+                let row_start = y as usize * fb_width;
+                for p in &mut buffer[row_start + rect.top_left.x as usize .. row_start + rect.bottom_right.x as usize] {
+                    *p = pixel;
+                }
+            }
+        }
+    }
+
+    /// Draws a single pixel at the given `coordinate` with the given `color`.
+    pub fn draw_pixel(&mut self, coordinate: Coord, color: Color) {
+        self.put_pixel(coordinate, color.into());
+    }
+
+    /// Draws a one-pixel-wide line from `start` to `end`, including both endpoints.
+    pub fn draw_line(&mut self, start: Coord, end: Coord, color: Color) {
+        let pixel: P = color.into();
+        // Bresenham's line algorithm, which works in all octants.
+        let dx = (end.x - start.x).abs();
+        let dy = -(end.y - start.y).abs();
+        let step_x = if start.x < end.x { 1 } else { -1 };
+        let step_y = if start.y < end.y { 1 } else { -1 };
+        let mut error = dx + dy;
+        let mut current = start;
+        loop {
+            self.put_pixel(current, pixel);
+            if current == end {
+                break;
+            }
+            let error2 = 2 * error;
+            if error2 >= dy {
+                error += dy;
+                current.x += step_x;
+            }
+            if error2 <= dx {
+                error += dx;
+                current.y += step_y;
+            }
+        }
+    }
+
+    /// Draws the one-pixel-wide outline of the given rectangle.
+    pub fn draw_rectangle(&mut self, rect: Rectangle, color: Color) {
+        if rect.top_left.x >= rect.bottom_right.x || rect.top_left.y >= rect.bottom_right.y {
+            return;
+        }
+        let right = rect.bottom_right.x - 1;
+        let bottom = rect.bottom_right.y - 1;
+        self.draw_line(rect.top_left, Coord::new(right, rect.top_left.y), color);
+        if bottom > rect.top_left.y {
+            self.draw_line(Coord::new(rect.top_left.x, bottom), Coord::new(right, bottom), color);
+        }
+        if bottom - rect.top_left.y > 1 {
+            self.draw_line(Coord::new(rect.top_left.x, rect.top_left.y + 1), Coord::new(rect.top_left.x, bottom - 1), color);
+            if right > rect.top_left.x {
+                self.draw_line(Coord::new(right, rect.top_left.y + 1), Coord::new(right, bottom - 1), color);
+            }
+        }
+    }
+
+    /// Fills the given rectangle with the given `color`, which is blended with the existing pixels.
+    pub fn fill_rectangle(&mut self, rect: Rectangle, color: Color) {
+        if rect.top_left.x >= rect.bottom_right.x || rect.top_left.y >= rect.bottom_right.y {
+            return;
+        }
+        let pixel: P = color.into();
+        if let Some(area) = self.clip_rectangle(rect.top_left, rect.width(), rect.height()) {
+            for y in area.top_left.y .. area.bottom_right.y {
+                for x in area.top_left.x .. area.bottom_right.x {
+                    self.framebuffer.draw_pixel(Coord::new(x, y), pixel);
+                }
+            }
+        }
+    }
+
+    /// Draws the one-pixel-wide outline of a circle with the given `center` and `radius`.
+    pub fn draw_circle(&mut self, center: Coord, radius: usize, color: Color) {
+        let pixel: P = color.into();
+        // The midpoint circle algorithm, which plots all eight octants at once.
+        let mut x = radius as isize;
+        let mut y = 0isize;
+        let mut error = 1 - x;
+        while x >= y {
+            let mut points = [
+                (x, y), (y, x), (-y, x), (-x, y),
+                (-x, -y), (-y, -x), (y, -x), (x, -y),
+            ];
+            // Avoid blending the same pixel twice where octants meet.
+            points.sort_unstable();
+            let mut previous = None;
+            for &point in points.iter() {
+                if previous != Some(point) {
+                    self.put_pixel(center + point, pixel);
+                    previous = Some(point);
+                }
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Fills a circle with the given `center` and `radius` with the given `color`.
+    pub fn fill_circle(&mut self, center: Coord, radius: usize, color: Color) {
+        let pixel: P = color.into();
+        let r = radius as isize;
+        let r2 = r * r;
+        for dy in -r ..= r {
+            // the widest horizontal span at this row that is within the circle
+            let mut dx = 0;
+            while (dx + 1) * (dx + 1) + dy * dy <= r2 {
+                dx += 1;
+            }
+            for x in -dx ..= dx {
+                self.put_pixel(center + (x, dy), pixel);
+            }
+        }
+    }
+
+    /// Draws the given image at the `destination` coordinate, blending each pixel with the existing pixels.
+    ///
+    /// The `image` consists of `width * height` pixels stored row by row.
+    pub fn blit(&mut self, image: &[P], width: usize, height: usize, destination: Coord) -> Result<(), &'static str> {
+        if image.len() != width * height {
+            return Err("image length does not match the given width and height");
+        }
+        if let Some(area) = self.clip_rectangle(destination, width, height) {
+            let origin = destination + self.clip.top_left;
+            for y in area.top_left.y .. area.bottom_right.y {
+                let src_row = (y - origin.y) as usize * width;
+                for x in area.top_left.x .. area.bottom_right.x {
+                    let pixel = image[src_row + (x - origin.x) as usize];
+                    self.framebuffer.draw_pixel(Coord::new(x, y), pixel);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the given image to the `destination` coordinate, overwriting the existing pixels without blending.
+    ///
+    /// This is faster than [`blit()`](#method.blit) and should be used for opaque images.
+    pub fn copy_image(&mut self, image: &[P], width: usize, height: usize, destination: Coord) -> Result<(), &'static str> {
+        if image.len() != width * height {
+            return Err("image length does not match the given width and height");
+        }
+        if let Some(area) = self.clip_rectangle(destination, width, height) {
+            let origin = destination + self.clip.top_left;
+            let (fb_width, _) = self.framebuffer.get_size();
+            let src_x = (area.top_left.x - origin.x) as usize;
+            let row_len = area.width();
+            let buffer = self.framebuffer.buffer_mut();
+            for y in area.top_left.y .. area.bottom_right.y {
+                let src_start = (y - origin.y) as usize * width + src_x;
+                let dest_start = y as usize * fb_width + area.top_left.x as usize;
+                buffer[dest_start .. dest_start + row_len].copy_from_slice(&image[src_start .. src_start + row_len]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the contents of another framebuffer at the `destination` coordinate, blending it with the existing pixels.
+    pub fn blit_framebuffer(&mut self, source: &Framebuffer<P>, destination: Coord) {
+        let (width, height) = source.get_size();
+        // The source buffer always has exactly `width * height` pixels.
+        let _ = self.blit(source.buffer(), width, height, destination);
+    }
+
+    /// Draws the given `text` with its top-left corner at `position`, using the glyphs from the `font` crate.
+    /// Only the glyphs are drawn, so the existing contents show through behind the text.
+    /// A newline character moves to the beginning of the next line.
+    ///
+    /// Returns the rectangle that bounds the drawn text, relative to this canvas.
+    pub fn draw_text(&mut self, position: Coord, text: &str, color: Color) -> Rectangle {
+        self.draw_text_internal(position, text, color.into(), None)
+    }
+
+    /// Draws the given `text` like [`draw_text()`](#method.draw_text),
+    /// but fills the background of each character cell with the `background` color.
+    pub fn draw_text_with_background(&mut self, position: Coord, text: &str, color: Color, background: Color) -> Rectangle {
+        self.draw_text_internal(position, text, color.into(), Some(background.into()))
+    }
+
+    fn draw_text_internal(&mut self, position: Coord, text: &str, fg_pixel: P, bg_pixel: Option<P>) -> Rectangle {
+        let mut cursor = position;
+        let mut bottom_right = position;
+        for byte in text.bytes() {
+            if byte == b'\n' {
+                cursor = Coord::new(position.x, cursor.y + CHARACTER_HEIGHT as isize);
+                continue;
+            }
+            self.draw_glyph(cursor, byte, fg_pixel, bg_pixel);
+            cursor.x += CHARACTER_WIDTH as isize;
+            bottom_right = Coord::new(
+                max(bottom_right.x, cursor.x),
+                max(bottom_right.y, cursor.y + CHARACTER_HEIGHT as isize),
+            );
+        }
+        Rectangle { top_left: position, bottom_right }
+    }
+
+    /// Draws the glyph of one ASCII character with its top-left corner at `position`.
+    fn draw_glyph(&mut self, position: Coord, character: u8, fg_pixel: P, bg_pixel: Option<P>) {
+        if self.clip_rectangle(position, CHARACTER_WIDTH, CHARACTER_HEIGHT).is_none() {
+            return;
+        }
+        let glyph = &FONT_BASIC[character as usize];
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0 .. CHARACTER_WIDTH {
+                // The first column is a one-pixel gap between two characters.
+                let is_set = column >= 1 && (bits & (0x80 >> (column - 1))) != 0;
+                let coordinate = position + (column as isize, row as isize);
+                if is_set {
+                    self.put_pixel(coordinate, fg_pixel);
+                } else if let Some(bg) = bg_pixel {
+                    self.put_pixel(coordinate, bg);
+                }
+            }
+        }
+    }
+}
+
+
+/// Draws into the content area of the given `window`, i.e., excluding its title bar and border,
+/// and then renders the window to the screen.
+///
+/// The `draw` function is given a `Canvas` whose origin is the top-left corner of the window's content area.
+/// Its return value is returned from this function.
+///
+/// The window's framebuffer is locked while `draw` is running,
+/// so `draw` must not access the `window` or the window manager.
+pub fn draw_in_window<F, R>(window: &mut Window, draw: F) -> Result<R, &'static str>
+    where F: FnOnce(&mut Canvas<AlphaPixel>) -> R
+{
+    let area = window.area();
+    let result = {
+        let mut framebuffer = window.framebuffer_mut();
+        let mut canvas = Canvas::with_clip(&mut *framebuffer, area);
+        draw(&mut canvas)
+    };
+    window.render(Some(area))?;
+    Ok(result)
+}
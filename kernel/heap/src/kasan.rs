@@ -0,0 +1,423 @@
+//! A kernel address sanitizer (KASAN) for the global heap, which is enabled by the `kasan` feature.
+//!
+//! Every allocation is surrounded by red zones, and one byte of shadow memory records
+//! which of every 8 bytes of the heap may be accessed, see [`check_access()`]:
+//! * `0` means that all 8 bytes are accessible,
+//! * `1` to `7` means that only that many leading bytes are accessible, i.e., the end of an allocation,
+//! * `LEFT_REDZONE` and `RIGHT_REDZONE` mean that the bytes are part of a red zone before or after an allocation,
+//! * `FREED` means that the bytes belong to an allocation that was freed.
+//!
+//! Freed allocations are kept in a quarantine for a while before they can be reused,
+//! such that accesses to them are reported as use-after-free bugs rather than going unnoticed in a new allocation.
+//!
+//! Accesses are checked by [`check_access()`], which is also exported under the names of the functions
+//! that the compiler's outline address sanitizer instrumentation calls, e.g., `__asan_load8`.
+//! Independently of instrumentation, the allocator itself catches bugs in the kernel crates that use it:
+//! * freeing an allocation twice, or freeing a pointer that isn't the start of an allocation,
+//! * writing beyond the end of an allocation, which it detects from its red zone having been overwritten when it's freed,
+//! * writing to an allocation after it was freed, which it detects from the poison that filled it
+//!   having been overwritten when it leaves the quarantine.
+//!
+//! Each report includes the backtrace of where the allocation was allocated and freed,
+//! which needs the kernel to be built with frame pointers (the `frame_pointers` config option).
+//!
+//! [`check_access()`]: fn.check_access.html
+
+use core::{mem, ptr};
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+use irq_safety::MutexIrqSafe;
+use memory::{EntryFlags, FrameAllocator, PageTable};
+use kernel_config::memory::{KASAN_HEAP_COVERAGE, KASAN_QUARANTINE_ENTRIES};
+#[cfg(frame_pointers)]
+use kernel_config::memory::{KERNEL_STACK_SIZE_IN_PAGES, PAGE_SIZE};
+
+
+/// The number of heap bytes that each shadow byte describes.
+const GRANULE: usize = 8;
+/// The minimum size of the red zone after each allocation.
+const RIGHT_REDZONE_SIZE: usize = 16;
+/// The number of return addresses in each allocation's and free's backtrace.
+const TRACE_DEPTH: usize = 6;
+
+/// The shadow value of the red zone before an allocation, which holds its `Header`.
+pub const LEFT_REDZONE: u8 = 0xFA;
+/// The shadow value of the red zone after an allocation.
+pub const RIGHT_REDZONE: u8 = 0xFC;
+/// The shadow value of an allocation that was freed.
+pub const FREED: u8 = 0xFB;
+
+/// The byte that fills the red zone after each allocation, which must be intact when the allocation is freed.
+const RIGHT_REDZONE_PATTERN: u8 = 0xFC;
+/// The byte that fills each freed allocation, which must be intact when the allocation leaves the quarantine.
+const FREED_PATTERN: u8 = 0xFB;
+/// The magic value in every allocation's `Header`.
+const HEADER_MAGIC: usize = 0x4B41_5341_4E48_4452; // "KASANHDR"
+
+
+/// The location of the shadow memory.
+struct Shadow {
+    /// The virtual address of the shadow memory.
+    base: usize,
+    /// The first heap address that the shadow memory describes.
+    heap_start: usize,
+}
+
+static SHADOW: Once<Shadow> = Once::new();
+
+/// The number of bugs that have been reported.
+static REPORT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Information about an allocation, which is stored at the end of the red zone before it.
+#[repr(C)]
+struct Header {
+    magic: usize,
+    /// The size of the allocation, as requested by its `Layout`.
+    size: usize,
+    alloc_trace: [usize; TRACE_DEPTH],
+    free_trace: [usize; TRACE_DEPTH],
+}
+
+
+/// Maps and clears the shadow memory for the first `KASAN_HEAP_COVERAGE` bytes of the kernel heap.
+///
+/// This must be invoked before the heap is initialized, since allocations that are made before
+/// can't be distinguished from those with red zones.
+pub fn init<A: FrameAllocator>(page_table: &mut PageTable, allocator: &mut A) -> Result<(), &'static str> {
+    let shadow_size = KASAN_HEAP_COVERAGE / GRANULE;
+    let pages = memory::allocate_pages_by_bytes(shadow_size).ok_or("kasan: couldn't allocate pages for the shadow memory")?;
+    let mapped_pages = page_table.map_allocated_pages(pages, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, allocator)?;
+    let base = mapped_pages.start().start_address().value();
+    // SAFE: the shadow memory was just mapped as writable.
+    unsafe { ptr::write_bytes(base as *mut u8, 0, shadow_size) };
+    // The shadow memory is needed for as long as the heap exists.
+    mem::forget(mapped_pages);
+    SHADOW.call_once(|| Shadow { base, heap_start: ::kaslr::heap_start() });
+    Ok(())
+}
+
+
+/// Returns the number of bugs that have been reported so far, e.g., to check that a test didn't trigger any.
+pub fn report_count() -> usize {
+    REPORT_COUNT.load(Ordering::Relaxed)
+}
+
+
+/// Returns the address of the shadow byte for the given heap address, if it is covered by the shadow memory.
+fn shadow_addr(addr: usize) -> Option<*mut u8> {
+    let shadow = SHADOW.try()?;
+    let offset = addr.checked_sub(shadow.heap_start)?;
+    if offset >= KASAN_HEAP_COVERAGE {
+        return None;
+    }
+    Some((shadow.base + offset / GRANULE) as *mut u8)
+}
+
+fn shadow_value(addr: usize) -> Option<u8> {
+    // SAFE: the shadow memory is always mapped.
+    shadow_addr(addr).map(|s| unsafe { ptr::read_volatile(s) })
+}
+
+/// Sets the shadow of the granules in the given range of heap addresses to the given `value`.
+/// The `start` must be aligned to `GRANULE`.
+fn poison(start: usize, end: usize, value: u8) {
+    let mut addr = start;
+    while addr < end {
+        if let Some(s) = shadow_addr(addr) {
+            // SAFE: the shadow memory is always mapped.
+            unsafe { ptr::write_volatile(s, value) };
+        }
+        addr += GRANULE;
+    }
+}
+
+/// Marks the given range of heap addresses as accessible. The `start` must be aligned to `GRANULE`.
+fn unpoison(start: usize, size: usize) {
+    poison(start, start + size / GRANULE * GRANULE, 0);
+    if size % GRANULE != 0 {
+        poison(start + size / GRANULE * GRANULE, start + size, (size % GRANULE) as u8);
+    }
+}
+
+
+/// Checks whether the `size` bytes at `addr` may be accessed, and reports a bug if they may not.
+/// Addresses that aren't covered by the shadow memory are always accessible.
+pub fn check_access(addr: usize, size: usize, is_write: bool) {
+    for byte in addr .. addr.saturating_add(size) {
+        match shadow_value(byte) {
+            Some(0) | None => { }
+            Some(s) if s < GRANULE as u8 && (byte % GRANULE) < s as usize => { }
+            Some(s) => {
+                report(Bug::BadAccess { addr, size, is_write, shadow: s }, byte);
+                return;
+            }
+        }
+    }
+}
+
+/// Checks that the given range of bytes, which was filled with `pattern`, still contains only that pattern.
+/// Returns the address of the first byte that was overwritten.
+fn find_overwritten(start: usize, end: usize, pattern: u8) -> Option<usize> {
+    // SAFE: the range belongs to an allocation that the heap hasn't reused yet.
+    (start .. end).find(|&addr| unsafe { ptr::read_volatile(addr as *const u8) } != pattern)
+}
+
+
+/// Returns the layout of the block that holds an allocation with the given `layout` and its red zones.
+pub(crate) fn block_layout(layout: Layout) -> Option<Layout> {
+    let align = layout.align().max(GRANULE);
+    let left = round_up(mem::size_of::<Header>(), align);
+    let size = left.checked_add(round_up(layout.size(), GRANULE))?.checked_add(RIGHT_REDZONE_SIZE)?;
+    Layout::from_size_align(size, align).ok()
+}
+
+/// Returns the offset of an allocation with the given `layout` within its block.
+fn left_redzone_size(layout: Layout) -> usize {
+    round_up(mem::size_of::<Header>(), layout.align().max(GRANULE))
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+
+/// Sets up the red zones and the header of a new allocation with the requested `layout` in the given `block`,
+/// and returns the address of the allocation.
+pub(crate) unsafe fn on_alloc(block: *mut u8, layout: Layout) -> *mut u8 {
+    if block.is_null() {
+        return block;
+    }
+    let block_layout = match block_layout(layout) {
+        Some(l) => l,
+        None => return ptr::null_mut(),
+    };
+    let start = block as usize;
+    let user = start + left_redzone_size(layout);
+    let end = start + block_layout.size();
+
+    let header = (user - mem::size_of::<Header>()) as *mut Header;
+    ptr::write(header, Header {
+        magic: HEADER_MAGIC,
+        size: layout.size(),
+        alloc_trace: backtrace(),
+        free_trace: [0; TRACE_DEPTH],
+    });
+    ptr::write_bytes((user + layout.size()) as *mut u8, RIGHT_REDZONE_PATTERN, end - user - layout.size());
+
+    poison(start, user, LEFT_REDZONE);
+    unpoison(user, layout.size());
+    poison(user + round_up(layout.size(), GRANULE), end, RIGHT_REDZONE);
+    user as *mut u8
+}
+
+
+/// A freed allocation in the quarantine.
+#[derive(Clone, Copy)]
+struct QuarantinedBlock {
+    /// The address of the allocation, which is `0` for an empty slot.
+    user: usize,
+    size: usize,
+    align: usize,
+}
+
+/// A ring buffer of the most recently freed allocations.
+struct Quarantine {
+    blocks: [QuarantinedBlock; KASAN_QUARANTINE_ENTRIES],
+    next: usize,
+}
+
+static QUARANTINE: MutexIrqSafe<Quarantine> = MutexIrqSafe::new(Quarantine {
+    blocks: [QuarantinedBlock { user: 0, size: 0, align: 0 }; KASAN_QUARANTINE_ENTRIES],
+    next: 0,
+});
+
+
+/// Checks and poisons the given freed allocation, and puts it into the quarantine.
+///
+/// Returns the block (and its layout) of the oldest allocation in the quarantine, which must now actually be deallocated.
+/// If the allocation can't be freed, e.g., because it was already freed, a bug is reported and it is leaked.
+pub(crate) unsafe fn on_dealloc(ptr: *mut u8, layout: Layout) -> Option<(*mut u8, Layout)> {
+    let user = ptr as usize;
+    let header_addr = user - mem::size_of::<Header>();
+    match (shadow_value(header_addr), shadow_value(user)) {
+        (_, Some(FREED)) => {
+            report(Bug::DoubleFree { addr: user }, user);
+            return None;
+        }
+        (Some(s), _) if s != LEFT_REDZONE => {
+            report(Bug::InvalidFree { addr: user }, user);
+            return None;
+        }
+        _ => { }
+    }
+    let header = &mut *(header_addr as *mut Header);
+    if header.magic != HEADER_MAGIC {
+        report(Bug::InvalidFree { addr: user }, user);
+        return None;
+    }
+    if header.size != layout.size() {
+        report(Bug::SizeMismatch { addr: user, size: layout.size() }, user);
+    }
+    let end = user - left_redzone_size(layout) + block_layout(layout)?.size();
+    if let Some(overwritten) = find_overwritten(user + header.size, end, RIGHT_REDZONE_PATTERN) {
+        report(Bug::OverflowWrite { addr: overwritten }, overwritten);
+    }
+
+    header.free_trace = backtrace();
+    ptr::write_bytes(ptr, FREED_PATTERN, layout.size());
+    poison(user, user + round_up(layout.size(), GRANULE), FREED);
+
+    let evicted = {
+        let mut quarantine = QUARANTINE.lock();
+        let next = quarantine.next;
+        quarantine.next = (next + 1) % KASAN_QUARANTINE_ENTRIES;
+        mem::replace(&mut quarantine.blocks[next], QuarantinedBlock { user, size: layout.size(), align: layout.align() })
+    };
+    if evicted.user == 0 {
+        return None;
+    }
+    if let Some(overwritten) = find_overwritten(evicted.user, evicted.user + evicted.size, FREED_PATTERN) {
+        report(Bug::UseAfterFreeWrite { addr: overwritten }, overwritten);
+    }
+    let evicted_layout = Layout::from_size_align_unchecked(evicted.size, evicted.align);
+    Some(((evicted.user - left_redzone_size(evicted_layout)) as *mut u8, block_layout(evicted_layout)?))
+}
+
+
+/// The kinds of bugs that are reported.
+enum Bug {
+    BadAccess { addr: usize, size: usize, is_write: bool, shadow: u8 },
+    DoubleFree { addr: usize },
+    InvalidFree { addr: usize },
+    SizeMismatch { addr: usize, size: usize },
+    OverflowWrite { addr: usize },
+    UseAfterFreeWrite { addr: usize },
+}
+
+/// Reports the given bug, which involves the given heap address, including the backtraces of the allocation
+/// that the address belongs to, or is closest to.
+fn report(bug: Bug, bad_addr: usize) {
+    REPORT_COUNT.fetch_add(1, Ordering::Relaxed);
+    match bug {
+        Bug::BadAccess { addr, size, is_write, shadow } => error!("KASAN: {} on {} of size {} at {:#X}",
+            match shadow {
+                FREED => "use-after-free",
+                LEFT_REDZONE => "heap-buffer-underflow",
+                _ => "heap-buffer-overflow",
+            },
+            if is_write { "write" } else { "read" }, size, addr,
+        ),
+        Bug::DoubleFree { addr } => error!("KASAN: double free of {:#X}", addr),
+        Bug::InvalidFree { addr } => error!("KASAN: invalid free of {:#X}, which isn't the start of an allocation", addr),
+        Bug::SizeMismatch { addr, size } => error!("KASAN: allocation at {:#X} was freed with the wrong size {}", addr, size),
+        Bug::OverflowWrite { addr } => error!("KASAN: heap-buffer-overflow write at {:#X}, detected when the allocation was freed", addr),
+        Bug::UseAfterFreeWrite { addr } => error!("KASAN: use-after-free write at {:#X}, detected when the allocation left the quarantine", addr),
+    }
+
+    let header = match find_header(bad_addr) {
+        Some(h) => h,
+        None => return,
+    };
+    let user = header as *const Header as usize + mem::size_of::<Header>();
+    if bad_addr < user {
+        error!("    the address is {} bytes before the {}-byte allocation at {:#X}", user - bad_addr, header.size, user);
+    } else if bad_addr >= user + header.size {
+        error!("    the address is {} bytes after the {}-byte allocation at {:#X}", bad_addr - user - header.size, header.size, user);
+    } else {
+        error!("    the address is {} bytes into the {}-byte allocation at {:#X}", bad_addr - user, header.size, user);
+    }
+    error!("    allocated by: {:X?}", TraceDisplay(&header.alloc_trace));
+    if shadow_value(user) == Some(FREED) {
+        error!("    freed by:     {:X?}", TraceDisplay(&header.free_trace));
+    }
+}
+
+/// Finds the header of the allocation whose red zones or contents contain the given address,
+/// by searching backwards for the nearest left red zone.
+fn find_header(addr: usize) -> Option<&'static Header> {
+    /// The maximum distance that is searched, such that huge allocations don't take forever.
+    const MAX_SEARCH: usize = 1 << 20;
+    let mut granule = addr / GRANULE * GRANULE;
+    let lowest = granule.saturating_sub(MAX_SEARCH);
+    while shadow_value(granule)? != LEFT_REDZONE {
+        if granule <= lowest {
+            return None;
+        }
+        granule -= GRANULE;
+    }
+    while shadow_value(granule + GRANULE)? == LEFT_REDZONE {
+        granule += GRANULE;
+    }
+    let user = granule + GRANULE;
+    // SAFE: a left red zone always ends with a header.
+    let header = unsafe { &*((user - mem::size_of::<Header>()) as *const Header) };
+    if header.magic == HEADER_MAGIC { Some(header) } else { None }
+}
+
+/// Formats a backtrace without its empty entries.
+struct TraceDisplay<'t>(&'t [usize; TRACE_DEPTH]);
+
+impl<'t> core::fmt::Debug for TraceDisplay<'t> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if self.0[0] == 0 {
+            return write!(f, "(no backtrace, the kernel wasn't built with frame pointers)");
+        }
+        f.debug_list().entries(self.0.iter().take_while(|&&rip| rip != 0)).finish()
+    }
+}
+
+
+/// Returns the return addresses of the current call stack, if the kernel was built with frame pointers.
+#[inline(always)]
+fn backtrace() -> [usize; TRACE_DEPTH] {
+    #[allow(unused_mut)]
+    let mut trace = [0; TRACE_DEPTH];
+    #[cfg(frame_pointers)] {
+        let mut rbp: usize;
+        // SAFE: just reading the current frame pointer.
+        unsafe { llvm_asm!("" : "={rbp}"(rbp) : : "memory" : "intel", "volatile"); }
+        for entry in trace.iter_mut() {
+            if rbp == 0 || rbp % mem::size_of::<usize>() != 0 {
+                break;
+            }
+            // SAFE: the frame pointer chain is well-formed, and each frame is checked to be on the same stack below.
+            let (next_rbp, rip) = unsafe { (*(rbp as *const usize), *((rbp + mem::size_of::<usize>()) as *const usize)) };
+            *entry = rip;
+            if next_rbp <= rbp || next_rbp - rbp > KERNEL_STACK_SIZE_IN_PAGES * PAGE_SIZE {
+                break;
+            }
+            rbp = next_rbp;
+        }
+    }
+    trace
+}
+
+
+// The functions that the compiler's outline address sanitizer instrumentation calls before each memory access.
+macro_rules! asan_hooks {
+    ($($load:ident, $store:ident, $size:expr;)*) => {
+        $(
+            #[no_mangle]
+            pub extern "C" fn $load(addr: usize) { check_access(addr, $size, false) }
+            #[no_mangle]
+            pub extern "C" fn $store(addr: usize) { check_access(addr, $size, true) }
+        )*
+    };
+}
+
+asan_hooks! {
+    __asan_load1, __asan_store1, 1;
+    __asan_load2, __asan_store2, 2;
+    __asan_load4, __asan_store4, 4;
+    __asan_load8, __asan_store8, 8;
+    __asan_load16, __asan_store16, 16;
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn __asan_loadN(addr: usize, size: usize) { check_access(addr, size, false) }
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn __asan_storeN(addr: usize, size: usize) { check_access(addr, size, true) }
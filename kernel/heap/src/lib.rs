@@ -1,60 +1,215 @@
 //! The global allocator for the system. 
 //! It starts off as a single fixed size allocator.
 //! When a more complex heap is set up, it is set as the default allocator.
+//!
+//! The default allocator can also report statistics about its heap arenas, e.g., the per-core heaps,
+//! which are used to quantify heap fragmentation, see [`arena_stats()`](fn.arena_stats.html),
+//! and can return its empty slabs to the system, see [`release_empty_slabs()`](fn.release_empty_slabs.html).
+//! It also keeps statistics about the bytes and allocations that are in use and their sizes, e.g., to find memory leaks,
+//! see [`stats()`](fn.stats.html), and can attribute allocations to the crates that requested them,
+//! see the [`profiler`](profiler/index.html) module.
+//!
+//! With the `kasan` feature, every allocation is checked for out-of-bounds and use-after-free bugs,
+//! see the [`kasan`](kasan/index.html) module.
 
 #![feature(const_fn)]
 #![feature(allocator_api)]
+#![feature(llvm_asm)]
+#![feature(const_in_array_repeat_expressions)]
 #![no_std]
 
 extern crate alloc;
+#[cfg(feature = "kasan")] #[macro_use] extern crate log;
 extern crate irq_safety; 
 extern crate spin;
 extern crate memory;
 extern crate kernel_config;
+extern crate kaslr;
 extern crate block_allocator;
 
 use alloc::alloc::{GlobalAlloc, Layout};
 use memory::EntryFlags;
-use kernel_config::memory::{KERNEL_HEAP_START, KERNEL_HEAP_INITIAL_SIZE};
+use kernel_config::memory::KERNEL_HEAP_INITIAL_SIZE;
 use irq_safety::MutexIrqSafe;
 use spin::Once;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use block_allocator::FixedSizeBlockAllocator;
+use core::fmt;
+use profiler::{Instrumented, HeapStats};
+
+#[cfg(feature = "kasan")]
+pub mod kasan;
+pub mod profiler;
 
 
 #[global_allocator]
-pub static GLOBAL_ALLOCATOR: Heap = Heap::empty();
+pub static GLOBAL_ALLOCATOR: Instrumented<Heap> = Instrumented::new(Heap::empty());
 
 #[cfg(direct_access_to_multiple_heaps)]
 /// The default allocator is the one which is set up after the basic system initialization is completed. 
 /// Currently it is initialized with an instance of `MultipleHeaps`.
 /// We only make the default allocator visible when we want to explicitly use it without going through the global allocator.
-pub static DEFAULT_ALLOCATOR: Once<Box<dyn GlobalAlloc + Send + Sync>> = Once::new();
+pub static DEFAULT_ALLOCATOR: Once<Box<dyn KernelAllocator>> = Once::new();
 
 #[cfg(not(direct_access_to_multiple_heaps))]
 /// The default allocator is the one which is set up after the basic system initialization is completed. 
 /// Currently it is initialized with an instance of `MultipleHeaps`.
-static DEFAULT_ALLOCATOR: Once<Box<dyn GlobalAlloc + Send + Sync>> = Once::new();
+static DEFAULT_ALLOCATOR: Once<Box<dyn KernelAllocator>> = Once::new();
 
 /// The heap mapped pages should be writable
 pub const HEAP_FLAGS: EntryFlags = EntryFlags::WRITABLE;
 
-/// The ending address of the initial heap. It is used to determine which heap should be used during deallocation.
-const INITIAL_HEAP_END_ADDR: usize = KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE;
+/// Returns the ending address of the initial heap. It is used to determine which heap should be used during deallocation.
+/// The heap's start is randomized at boot, see the `kaslr` crate.
+fn initial_heap_end_addr() -> usize {
+    kaslr::heap_start() + KERNEL_HEAP_INITIAL_SIZE
+}
 
 
 /// Initializes the single heap, which is the first heap used by the system.
 pub fn init_single_heap(start_virt_addr: usize, size_in_bytes: usize) {
-    unsafe { GLOBAL_ALLOCATOR.initial_allocator.lock().init(start_virt_addr, size_in_bytes); }
+    unsafe { GLOBAL_ALLOCATOR.inner().initial_allocator.lock().init(start_virt_addr, size_in_bytes); }
 }
 
 
 /// Sets a new default allocator to be used by the global heap. It will start being used after this function is called.
-pub fn set_allocator(allocator: Box<dyn GlobalAlloc + Send + Sync>) {
+pub fn set_allocator(allocator: Box<dyn KernelAllocator>) {
     DEFAULT_ALLOCATOR.call_once(|| allocator);
 }
 
 
+/// An allocator that can be used as the default allocator of the global heap.
+/// 
+/// In addition to allocating, it can optionally report statistics about its heap arenas
+/// and return its empty slabs to the system, which the default methods don't do.
+pub trait KernelAllocator: GlobalAlloc + Send + Sync {
+    /// Returns statistics about each of this allocator's heap arenas.
+    fn arena_stats(&self) -> Vec<ArenaStats> {
+        Vec::new()
+    }
+
+    /// Returns the empty slabs of each heap arena to the system, such that their frames can be reused,
+    /// keeping at least `keep_per_arena` empty slabs in each arena for future allocations.
+    /// 
+    /// Returns the number of bytes that were returned to the system.
+    fn release_empty_slabs(&self, _keep_per_arena: usize) -> usize {
+        0
+    }
+}
+
+/// Returns statistics about each arena of the default allocator,
+/// or an empty list if the default allocator hasn't been set up yet.
+pub fn arena_stats() -> Vec<ArenaStats> {
+    DEFAULT_ALLOCATOR.try().map(|allocator| allocator.arena_stats()).unwrap_or_default()
+}
+
+/// Returns the empty slabs of the default allocator to the system, see [`KernelAllocator::release_empty_slabs()`].
+/// 
+/// Returns the number of bytes that were returned to the system.
+/// 
+/// [`KernelAllocator::release_empty_slabs()`]: trait.KernelAllocator.html#method.release_empty_slabs
+pub fn release_empty_slabs(keep_per_arena: usize) -> usize {
+    DEFAULT_ALLOCATOR.try().map_or(0, |allocator| allocator.release_empty_slabs(keep_per_arena))
+}
+
+
+/// Returns the number of bytes that are currently allocated from the global heap, across all heaps,
+/// as requested by their `Layout`s, i.e., excluding any padding within the heap's chunks.
+pub fn bytes_in_use() -> usize {
+    GLOBAL_ALLOCATOR.bytes_in_use()
+}
+
+/// Returns the number of allocations from the global heap that haven't been deallocated yet.
+pub fn allocations_in_use() -> usize {
+    GLOBAL_ALLOCATOR.allocations_in_use()
+}
+
+/// Returns statistics about the usage of the global heap since boot,
+/// along with the results of the allocation profiler, if it has been started.
+pub fn stats() -> HeapStats {
+    GLOBAL_ALLOCATOR.stats()
+}
+
+
+/// Statistics about the slabs of one size class within a heap arena.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeClassStats {
+    /// The size of the objects (chunks) of this size class.
+    pub object_size: usize,
+    /// The number of objects that fit within one slab.
+    pub objects_per_slab: usize,
+    /// The size in bytes of one slab.
+    pub slab_size: usize,
+    /// The number of slabs with no allocated objects.
+    pub empty_slabs: usize,
+    /// The number of slabs with some, but not all, objects allocated.
+    pub partial_slabs: usize,
+    /// The number of slabs in which every object is allocated.
+    pub full_slabs: usize,
+    /// The number of objects that are free within the partially-used slabs.
+    pub free_objects_in_partial_slabs: usize,
+}
+
+/// The `arena_id` of an allocator's shared overflow arena, which isn't owned by any one core.
+pub const OVERFLOW_ARENA_ID: usize = usize::max_value();
+
+/// Statistics about one heap arena, e.g., one per-core heap.
+#[derive(Clone, Debug)]
+pub struct ArenaStats {
+    /// The ID of this arena, e.g., the APIC ID of the core whose heap it is, or `OVERFLOW_ARENA_ID`.
+    pub arena_id: usize,
+    /// Statistics about each size class of this arena, from the smallest to the largest object size.
+    pub size_classes: Vec<SizeClassStats>,
+    /// The number of objects that were freed into this arena by other cores since boot.
+    pub remote_frees: usize,
+}
+
+impl ArenaStats {
+    /// Returns the number of bytes in this arena's empty slabs,
+    /// which can be used for objects of any size class or returned to the system.
+    pub fn empty_slab_bytes(&self) -> usize {
+        self.size_classes.iter().map(|sc| sc.empty_slabs * sc.slab_size).sum()
+    }
+
+    /// Returns the number of bytes in free objects within this arena's partially-used slabs,
+    /// which can only be used for objects of that slab's size class.
+    pub fn stranded_free_bytes(&self) -> usize {
+        self.size_classes.iter().map(|sc| sc.free_objects_in_partial_slabs * sc.object_size).sum()
+    }
+
+    /// Returns this arena's external fragmentation as a percentage from 0 to 100:
+    /// the fraction of its free bytes that are stranded within partially-used slabs,
+    /// and thus can't be used for other size classes or returned to the system.
+    pub fn external_fragmentation_percent(&self) -> usize {
+        let free = self.empty_slab_bytes() + self.stranded_free_bytes();
+        if free == 0 { 0 } else { self.stranded_free_bytes() * 100 / free }
+    }
+}
+
+/// Displays a histogram of the free chunks in each size class of this arena.
+impl fmt::Display for ArenaStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.arena_id == OVERFLOW_ARENA_ID {
+            write!(f, "Overflow arena: ")?;
+        } else {
+            write!(f, "Arena {}: ", self.arena_id)?;
+        }
+        writeln!(f, "{} bytes in empty slabs, {} bytes stranded in partial slabs, {}% external fragmentation, {} remote frees",
+            self.empty_slab_bytes(), self.stranded_free_bytes(), self.external_fragmentation_percent(), self.remote_frees
+        )?;
+        writeln!(f, "{:>8}  {:>6}  {:>8}  {:>5}  {:>11}", "SIZE", "EMPTY", "PARTIAL", "FULL", "FREE CHUNKS")?;
+        for sc in self.size_classes.iter() {
+            writeln!(f, "{:>8}  {:>6}  {:>8}  {:>5}  {:>11}",
+                sc.object_size, sc.empty_slabs, sc.partial_slabs, sc.full_slabs,
+                sc.free_objects_in_partial_slabs + sc.empty_slabs * sc.objects_per_slab,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+
 /// The heap which is used as a global allocator for the system.
 /// It starts off with one basic fixed size allocator, the `initial allocator`. 
 /// When a more complex heap is created and set as the `DEFAULT_ALLOCATOR`, then it is used.
@@ -75,18 +230,37 @@ impl Heap {
 unsafe impl GlobalAlloc for Heap {
 
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        match DEFAULT_ALLOCATOR.try() {
+        // With KASAN, each allocation is placed within a larger block, between its red zones.
+        #[cfg(feature = "kasan")]
+        let block_layout = match kasan::block_layout(layout) {
+            Some(l) => l,
+            None => return core::ptr::null_mut(),
+        };
+        #[cfg(not(feature = "kasan"))]
+        let block_layout = layout;
+
+        let ptr = match DEFAULT_ALLOCATOR.try() {
             Some(allocator) => {
-                allocator.alloc(layout)
+                allocator.alloc(block_layout)
             }
             None => {       
-                self.initial_allocator.lock().allocate(layout)
+                self.initial_allocator.lock().allocate(block_layout)
             }
-        }
+        };
+        #[cfg(feature = "kasan")]
+        let ptr = kasan::on_alloc(ptr, layout);
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if (ptr as usize) < INITIAL_HEAP_END_ADDR {
+        // With KASAN, the allocation is quarantined, and the block of an older one is deallocated instead, if any.
+        #[cfg(feature = "kasan")]
+        let (ptr, layout) = match kasan::on_dealloc(ptr, layout) {
+            Some(block) => block,
+            None => return,
+        };
+
+        if (ptr as usize) < initial_heap_end_addr() {
             self.initial_allocator.lock().deallocate(ptr, layout);
         }
         else {
@@ -0,0 +1,426 @@
+//! Statistics about the usage of the global heap, and an allocation profiler.
+//!
+//! The global allocator is wrapped in [`Instrumented`], which counts every allocation and deallocation,
+//! the bytes that are in use and their peak, and a histogram of allocation sizes; see [`stats()`](../fn.stats.html).
+//!
+//! While the profiler is running, see [`start()`], every allocation is also attributed to the crate that requested it,
+//! such that the crates that hold on to the most heap memory can be found, e.g., to track down a leak.
+//! The requesting crate is given by the function registered via [`set_requester_function()`],
+//! which is typically the application crate of the current task; allocations without one are attributed to the kernel.
+//! Since the profiler must not allocate itself, it records allocations in fixed-size tables,
+//! so up to [`MAX_PROFILED_REQUESTERS`] crates and [`MAX_PROFILED_ALLOCATIONS`] live allocations are tracked at once.
+//! Any more allocations are only counted as untracked.
+//!
+//! [`Instrumented`]: struct.Instrumented.html
+//! [`start()`]: fn.start.html
+//! [`set_requester_function()`]: fn.set_requester_function.html
+//! [`MAX_PROFILED_REQUESTERS`]: constant.MAX_PROFILED_REQUESTERS.html
+//! [`MAX_PROFILED_ALLOCATIONS`]: constant.MAX_PROFILED_ALLOCATIONS.html
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use irq_safety::MutexIrqSafe;
+use spin::Once;
+
+
+/// The number of buckets in the histogram of allocation sizes.
+/// Bucket `i` holds allocations of up to `8 << i` bytes, except for the last bucket, which holds all larger allocations.
+pub const NUM_SIZE_BUCKETS: usize = 18;
+/// The maximum number of crates that the profiler can attribute allocations to.
+pub const MAX_PROFILED_REQUESTERS: usize = 32;
+/// The maximum number of live allocations that the profiler can track. Must be a power of two.
+pub const MAX_PROFILED_ALLOCATIONS: usize = 4096;
+/// Names of requesting crates that are longer than this are truncated.
+const MAX_REQUESTER_NAME_LENGTH: usize = 48;
+/// The name that allocations are attributed to if the requester function doesn't return one.
+const KERNEL_REQUESTER_NAME: &'static str = "kernel";
+
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the histogram bucket of allocations of the given `size`.
+fn size_bucket(size: usize) -> usize {
+    if size <= 8 {
+        return 0;
+    }
+    let bits = (core::mem::size_of::<usize>() * 8) as u32 - (size - 1).leading_zeros();
+    core::cmp::min(bits as usize - 3, NUM_SIZE_BUCKETS - 1)
+}
+
+
+/// A `GlobalAlloc` wrapper that keeps statistics about the allocations made through the `inner` allocator,
+/// and records them in the profiler while it is running.
+pub struct Instrumented<A> {
+    inner: A,
+    bytes_in_use: AtomicUsize,
+    peak_bytes_in_use: AtomicUsize,
+    allocations_in_use: AtomicUsize,
+    total_allocations: AtomicUsize,
+    total_deallocations: AtomicUsize,
+    failed_allocations: AtomicUsize,
+    live_per_bucket: [AtomicUsize; NUM_SIZE_BUCKETS],
+    total_per_bucket: [AtomicUsize; NUM_SIZE_BUCKETS],
+}
+
+impl<A> Instrumented<A> {
+    /// Wraps the given allocator, with all statistics starting at zero.
+    pub const fn new(inner: A) -> Instrumented<A> {
+        Instrumented {
+            inner,
+            bytes_in_use: ZERO,
+            peak_bytes_in_use: ZERO,
+            allocations_in_use: ZERO,
+            total_allocations: ZERO,
+            total_deallocations: ZERO,
+            failed_allocations: ZERO,
+            live_per_bucket: [ZERO; NUM_SIZE_BUCKETS],
+            total_per_bucket: [ZERO; NUM_SIZE_BUCKETS],
+        }
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Returns the number of bytes that are currently allocated, as requested by their `Layout`s.
+    pub fn bytes_in_use(&self) -> usize {
+        self.bytes_in_use.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of allocations that haven't been deallocated yet.
+    pub fn allocations_in_use(&self) -> usize {
+        self.allocations_in_use.load(Ordering::Relaxed)
+    }
+
+    /// Returns the current statistics of this allocator, along with the profiler's results, if any.
+    pub fn stats(&self) -> HeapStats {
+        let mut size_buckets = [SizeBucketStats::default(); NUM_SIZE_BUCKETS];
+        for (i, bucket) in size_buckets.iter_mut().enumerate() {
+            bucket.max_size = if i == NUM_SIZE_BUCKETS - 1 { None } else { Some(8 << i) };
+            bucket.live_allocations = self.live_per_bucket[i].load(Ordering::Relaxed);
+            bucket.total_allocations = self.total_per_bucket[i].load(Ordering::Relaxed);
+        }
+        // The profile is copied before any allocation is made, since allocating records into the profile.
+        let (profiled, num_requesters, untracked_allocations) = {
+            let profile = PROFILE.lock();
+            (profile.requesters, profile.num_requesters, profile.untracked_allocations)
+        };
+        let mut requesters: Vec<RequesterStats> = profiled[.. num_requesters].iter().map(|r| RequesterStats {
+            name: String::from(core::str::from_utf8(&r.name[.. r.name_len]).unwrap_or("<invalid>")),
+            bytes_in_use: r.bytes_in_use,
+            allocations_in_use: r.allocations_in_use,
+            total_allocations: r.total_allocations,
+            total_bytes: r.total_bytes,
+        }).collect();
+        requesters.sort_by(|a, b| b.bytes_in_use.cmp(&a.bytes_in_use));
+
+        HeapStats {
+            bytes_in_use: self.bytes_in_use.load(Ordering::Relaxed),
+            peak_bytes_in_use: self.peak_bytes_in_use.load(Ordering::Relaxed),
+            allocations_in_use: self.allocations_in_use.load(Ordering::Relaxed),
+            total_allocations: self.total_allocations.load(Ordering::Relaxed),
+            total_deallocations: self.total_deallocations.load(Ordering::Relaxed),
+            failed_allocations: self.failed_allocations.load(Ordering::Relaxed),
+            size_buckets,
+            profiling: is_running(),
+            requesters,
+            untracked_allocations,
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Instrumented<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() {
+            self.failed_allocations.fetch_add(1, Ordering::Relaxed);
+            return ptr;
+        }
+        let bytes_in_use = self.bytes_in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        let mut peak = self.peak_bytes_in_use.load(Ordering::Relaxed);
+        while bytes_in_use > peak {
+            match self.peak_bytes_in_use.compare_exchange_weak(peak, bytes_in_use, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => peak = current,
+            }
+        }
+        self.allocations_in_use.fetch_add(1, Ordering::Relaxed);
+        self.total_allocations.fetch_add(1, Ordering::Relaxed);
+        let bucket = size_bucket(layout.size());
+        self.live_per_bucket[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_per_bucket[bucket].fetch_add(1, Ordering::Relaxed);
+        if is_running() {
+            PROFILE.lock().record_alloc(ptr as usize, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.bytes_in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.allocations_in_use.fetch_sub(1, Ordering::Relaxed);
+        self.total_deallocations.fetch_add(1, Ordering::Relaxed);
+        self.live_per_bucket[size_bucket(layout.size())].fetch_sub(1, Ordering::Relaxed);
+        // This must happen before the block is deallocated, otherwise another core could reuse its address first.
+        if is_running() {
+            PROFILE.lock().record_dealloc(ptr as usize, layout.size());
+        }
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+
+/// Statistics about the usage of the global heap, see [`stats()`](../fn.stats.html).
+#[derive(Clone, Debug)]
+pub struct HeapStats {
+    /// The number of bytes that are currently allocated, as requested by their `Layout`s.
+    pub bytes_in_use: usize,
+    /// The highest number of bytes that were allocated at once since boot.
+    pub peak_bytes_in_use: usize,
+    /// The number of allocations that haven't been deallocated yet.
+    pub allocations_in_use: usize,
+    /// The number of allocations since boot.
+    pub total_allocations: usize,
+    /// The number of deallocations since boot.
+    pub total_deallocations: usize,
+    /// The number of allocations that failed since boot.
+    pub failed_allocations: usize,
+    /// The histogram of allocation sizes, from the smallest to the largest size.
+    pub size_buckets: [SizeBucketStats; NUM_SIZE_BUCKETS],
+    /// Whether the profiler is currently running.
+    pub profiling: bool,
+    /// The allocations that were made since the profiler was last started, and are still in use,
+    /// per requesting crate, from the most to the fewest bytes in use.
+    /// If the profiler was stopped, these are its results as of when it was stopped.
+    pub requesters: Vec<RequesterStats>,
+    /// The number of allocations that the profiler couldn't attribute to a crate because its tables were full.
+    pub untracked_allocations: usize,
+}
+
+/// One bucket of the histogram of allocation sizes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeBucketStats {
+    /// The largest allocation size in this bucket, or `None` for the last bucket.
+    pub max_size: Option<usize>,
+    /// The number of allocations in this bucket that haven't been deallocated yet.
+    pub live_allocations: usize,
+    /// The number of allocations in this bucket since boot.
+    pub total_allocations: usize,
+}
+
+/// The allocations that the profiler attributed to one crate.
+#[derive(Clone, Debug)]
+pub struct RequesterStats {
+    /// The name of the crate, possibly truncated, or "kernel".
+    pub name: String,
+    /// The number of bytes that this crate allocated while profiling and hasn't deallocated yet.
+    pub bytes_in_use: usize,
+    /// The number of allocations that this crate made while profiling and hasn't deallocated yet.
+    pub allocations_in_use: usize,
+    /// The number of allocations that this crate made while profiling.
+    pub total_allocations: usize,
+    /// The number of bytes that this crate allocated while profiling.
+    pub total_bytes: usize,
+}
+
+/// Displays the heap's totals, the histogram of allocation sizes, and the profiler's results, if any.
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} bytes in {} allocations in use (peak: {} bytes)", self.bytes_in_use, self.allocations_in_use, self.peak_bytes_in_use)?;
+        writeln!(f, "{} allocations, {} deallocations, {} failed allocations since boot",
+            self.total_allocations, self.total_deallocations, self.failed_allocations
+        )?;
+        writeln!(f, "{:>10}  {:>10}  {:>12}", "SIZE", "LIVE", "TOTAL")?;
+        for bucket in self.size_buckets.iter().filter(|b| b.total_allocations > 0) {
+            match bucket.max_size {
+                Some(max_size) => write!(f, "<= {:>7}", max_size)?,
+                None => write!(f, "{:>10}", "larger")?,
+            }
+            writeln!(f, "  {:>10}  {:>12}", bucket.live_allocations, bucket.total_allocations)?;
+        }
+        if self.requesters.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "\nProfiler ({}): {} untracked allocations", if self.profiling { "running" } else { "stopped" }, self.untracked_allocations)?;
+        writeln!(f, "{:<32}  {:>12}  {:>10}  {:>12}  {:>14}", "CRATE", "LIVE BYTES", "LIVE", "TOTAL", "TOTAL BYTES")?;
+        for r in self.requesters.iter() {
+            writeln!(f, "{:<32}  {:>12}  {:>10}  {:>12}  {:>14}", r.name, r.bytes_in_use, r.allocations_in_use, r.total_allocations, r.total_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// The function that returns the name of the crate that is requesting the current allocation.
+static REQUESTER_FUNCTION: Once<fn() -> Option<&'static str>> = Once::new();
+static PROFILING: AtomicBool = AtomicBool::new(false);
+static PROFILE: MutexIrqSafe<Profile> = MutexIrqSafe::new(Profile::empty());
+
+/// Registers the function that returns the name of the crate that is requesting the current allocation,
+/// e.g., the application crate of the current task, or `None` for the kernel.
+///
+/// The function must not allocate, and must not block, since it's invoked on every allocation while profiling.
+pub fn set_requester_function(f: fn() -> Option<&'static str>) {
+    REQUESTER_FUNCTION.call_once(|| f);
+}
+
+/// Starts the profiler, discarding the results of its previous run.
+pub fn start() {
+    PROFILE.lock().reset();
+    PROFILING.store(true, Ordering::Release);
+}
+
+/// Stops the profiler, keeping its results until it's started again.
+pub fn stop() {
+    PROFILING.store(false, Ordering::Release);
+}
+
+/// Returns whether the profiler is running.
+pub fn is_running() -> bool {
+    PROFILING.load(Ordering::Acquire)
+}
+
+
+#[derive(Clone, Copy)]
+struct Requester {
+    name: [u8; MAX_REQUESTER_NAME_LENGTH],
+    name_len: usize,
+    bytes_in_use: usize,
+    allocations_in_use: usize,
+    total_allocations: usize,
+    total_bytes: usize,
+}
+
+impl Requester {
+    const EMPTY: Requester = Requester {
+        name: [0; MAX_REQUESTER_NAME_LENGTH],
+        name_len: 0,
+        bytes_in_use: 0,
+        allocations_in_use: 0,
+        total_allocations: 0,
+        total_bytes: 0,
+    };
+}
+
+/// A live allocation that was attributed to a requester, in an open-addressing hash table with linear probing.
+#[derive(Clone, Copy)]
+struct TrackedAllocation {
+    /// The address of the allocation, or `0` if this entry is empty.
+    addr: usize,
+    /// The index of the requester in `Profile::requesters`.
+    requester: usize,
+}
+
+const EMPTY_ALLOCATION: TrackedAllocation = TrackedAllocation { addr: 0, requester: 0 };
+
+/// The profiler's results, which are kept in fixed-size tables such that recording an allocation never allocates.
+struct Profile {
+    requesters: [Requester; MAX_PROFILED_REQUESTERS],
+    num_requesters: usize,
+    allocations: [TrackedAllocation; MAX_PROFILED_ALLOCATIONS],
+    num_allocations: usize,
+    untracked_allocations: usize,
+}
+
+impl Profile {
+    const fn empty() -> Profile {
+        Profile {
+            requesters: [Requester::EMPTY; MAX_PROFILED_REQUESTERS],
+            num_requesters: 0,
+            allocations: [EMPTY_ALLOCATION; MAX_PROFILED_ALLOCATIONS],
+            num_allocations: 0,
+            untracked_allocations: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        for r in self.requesters[.. self.num_requesters].iter_mut() {
+            *r = Requester::EMPTY;
+        }
+        self.num_requesters = 0;
+        for a in self.allocations.iter_mut() {
+            *a = EMPTY_ALLOCATION;
+        }
+        self.num_allocations = 0;
+        self.untracked_allocations = 0;
+    }
+
+    /// Returns the index in the hash table at which lookups for the given address start.
+    fn home_index(addr: usize) -> usize {
+        // Allocations are at least 8-byte aligned, so the lowest bits carry no information.
+        ((addr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15)) >> (64 - MAX_PROFILED_ALLOCATIONS.trailing_zeros())
+    }
+
+    /// Returns the index of the requester with the given name, adding it if it's new and there's room for it.
+    fn requester_index(&mut self, name: &str) -> Option<usize> {
+        let name = &name.as_bytes()[.. core::cmp::min(name.len(), MAX_REQUESTER_NAME_LENGTH)];
+        if let Some(i) = self.requesters[.. self.num_requesters].iter().position(|r| &r.name[.. r.name_len] == name) {
+            return Some(i);
+        }
+        if self.num_requesters == MAX_PROFILED_REQUESTERS {
+            return None;
+        }
+        let requester = &mut self.requesters[self.num_requesters];
+        requester.name[.. name.len()].copy_from_slice(name);
+        requester.name_len = name.len();
+        self.num_requesters += 1;
+        Some(self.num_requesters - 1)
+    }
+
+    fn record_alloc(&mut self, addr: usize, size: usize) {
+        let name = REQUESTER_FUNCTION.try().and_then(|f| f()).unwrap_or(KERNEL_REQUESTER_NAME);
+        // At least one entry must stay empty, which ends every lookup.
+        let requester = match self.requester_index(name) {
+            Some(r) if self.num_allocations < MAX_PROFILED_ALLOCATIONS - 1 => r,
+            _ => {
+                self.untracked_allocations += 1;
+                return;
+            }
+        };
+        let mut i = Profile::home_index(addr);
+        while self.allocations[i].addr != 0 {
+            i = (i + 1) % MAX_PROFILED_ALLOCATIONS;
+        }
+        self.allocations[i] = TrackedAllocation { addr, requester };
+        self.num_allocations += 1;
+
+        let r = &mut self.requesters[requester];
+        r.bytes_in_use += size;
+        r.allocations_in_use += 1;
+        r.total_allocations += 1;
+        r.total_bytes += size;
+    }
+
+    fn record_dealloc(&mut self, addr: usize, size: usize) {
+        let mut i = Profile::home_index(addr);
+        loop {
+            match self.allocations[i].addr {
+                // The allocation was made before the profiler started, or wasn't tracked.
+                0 => return,
+                a if a == addr => break,
+                _ => i = (i + 1) % MAX_PROFILED_ALLOCATIONS,
+            }
+        }
+        let r = &mut self.requesters[self.allocations[i].requester];
+        r.bytes_in_use -= size;
+        r.allocations_in_use -= 1;
+
+        // Move later entries of the same probe sequence back into the hole, such that no lookup stops early.
+        let mut hole = i;
+        let mut j = (i + 1) % MAX_PROFILED_ALLOCATIONS;
+        while self.allocations[j].addr != 0 {
+            let home = Profile::home_index(self.allocations[j].addr);
+            let distance_from_home = j.wrapping_sub(home) % MAX_PROFILED_ALLOCATIONS;
+            let distance_from_hole = j.wrapping_sub(hole) % MAX_PROFILED_ALLOCATIONS;
+            if distance_from_home >= distance_from_hole {
+                self.allocations[hole] = self.allocations[j];
+                hole = j;
+            }
+            j = (j + 1) % MAX_PROFILED_ALLOCATIONS;
+        }
+        self.allocations[hole] = EMPTY_ALLOCATION;
+        self.num_allocations -= 1;
+    }
+}
@@ -0,0 +1,79 @@
+//! A background service that returns the heaps' empty pages to the OS once the heaps are idle.
+//!
+//! The heaps grow on demand by allocating more pages from the OS when they run out of memory, see the `multiple_heaps` crate,
+//! but don't shrink on their own, so after a large workload has finished, its memory would stay with the heaps.
+//! This service's task runs at the lowest priority and sleeps for the interval given by the `heap.shrink_idle_ms` tunable;
+//! if the heaps haven't grown during that interval, it releases all but `heap.shrink_keep_slabs` empty slabs of each heap,
+//! see `heap::release_empty_slabs()`. It keeps doing so after every such interval until the heaps grow again.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate heap;
+extern crate multiple_heaps;
+extern crate config_registry;
+extern crate sleep;
+extern crate task;
+extern crate spawn;
+extern crate scheduler;
+
+use config_registry::Tunable;
+use task::TaskRef;
+
+
+/// The lowest task priority, see `scheduler::set_priority()`.
+const IDLE_PRIORITY: u8 = 0;
+/// How often the heap shrinker checks whether shrinking has been re-enabled, in milliseconds.
+const DISABLED_RECHECK_MS: u64 = 1000;
+
+/// How long the heaps must go without growing before their empty slabs are released.
+pub static IDLE_INTERVAL_MS: Tunable = Tunable::new(
+    "heap.shrink_idle_ms", "how long the heaps must go without growing before their empty pages are returned to the OS, in milliseconds (0: never)",
+    5000, 0, 3_600_000,
+);
+/// The number of empty slabs that each heap keeps for future allocations when it's shrunk.
+pub static KEEP_EMPTY_SLABS: Tunable = Tunable::new(
+    "heap.shrink_keep_slabs", "the number of empty slabs that each heap keeps when the heaps are shrunk",
+    16, 0, 4096,
+);
+
+
+/// Registers this crate's tunables and spawns the heap shrinker task.
+///
+/// Returns the newly-spawned heap shrinker task.
+/// This should only be invoked once, after the multiple heaps have been set up.
+pub fn init() -> Result<TaskRef, &'static str> {
+    config_registry::register(&IDLE_INTERVAL_MS, None)?;
+    config_registry::register(&KEEP_EMPTY_SLABS, None)?;
+
+    let taskref = spawn::new_task_builder(heap_shrinker_loop, ())
+        .name(format!("heap_shrinker"))
+        .spawn()?;
+    if let Err(_e) = scheduler::set_priority(&taskref, IDLE_PRIORITY) {
+        debug!("heap shrinker: couldn't lower task priority: {}", _e);
+    }
+    Ok(taskref)
+}
+
+
+/// The entry point of the heap shrinker task, which never returns.
+fn heap_shrinker_loop(_: ()) -> Result<(), &'static str> {
+    let mut last_growth_count = multiple_heaps::growth_count();
+    loop {
+        // If shrinking is disabled, check again later, since the tunable may be changed at any time.
+        let interval_ms = IDLE_INTERVAL_MS.get();
+        sleep::sleep_ms(if interval_ms == 0 { DISABLED_RECHECK_MS } else { interval_ms })?;
+
+        let growth_count = multiple_heaps::growth_count();
+        if interval_ms == 0 || growth_count != last_growth_count {
+            last_growth_count = growth_count;
+            continue;
+        }
+
+        let released = heap::release_empty_slabs(KEEP_EMPTY_SLABS.get() as usize);
+        if released > 0 {
+            debug!("heap shrinker: released {} bytes, the heaps now hold {} bytes", released, multiple_heaps::mapped_bytes());
+        }
+    }
+}
@@ -2,29 +2,25 @@
 
 #![no_std]
 
-extern crate alloc;
 #[macro_use] extern crate log;
 extern crate kernel_config;
 extern crate memory;
-extern crate volatile;
+#[macro_use] extern crate mmio_registers;
 extern crate zerocopy;
 extern crate sdt;
 extern crate acpi_table;
 extern crate spin;
-extern crate owning_ref;
 
 use core::ops::DerefMut;
-use volatile::{Volatile, ReadOnly};
+use mmio_registers::{MmioRegion, ReadOnly, ReadWrite};
 use zerocopy::FromBytes;
-use owning_ref::BoxRefMut;
-use alloc::boxed::Box;
 use spin::{Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use memory::{MappedPages, allocate_pages, get_frame_allocator_ref, FrameRange, PageTable, PhysicalAddress, EntryFlags};
+use memory::{allocate_pages, get_frame_allocator_ref, FrameRange, PageTable, PhysicalAddress, EntryFlags};
 use sdt::{Sdt, GenericAddressStructure};
 use acpi_table::{AcpiTables, AcpiSignature};
 
 /// The static instance of the HPET's ACPI memory region, which derefs to an Hpet instance.
-static HPET: Once<RwLock<BoxRefMut<MappedPages, Hpet>>> = Once::new();
+static HPET: Once<RwLock<MmioRegion<Hpet>>> = Once::new();
 
 
 /// Returns a reference to the HPET timer structure, wrapped in an Option,
@@ -33,7 +29,7 @@ static HPET: Once<RwLock<BoxRefMut<MappedPages, Hpet>>> = Once::new();
 /// ```
 /// let counter_val = get_hpet().as_ref().unwrap().get_counter();
 /// ```
-pub fn get_hpet() -> Option<RwLockReadGuard<'static, BoxRefMut<MappedPages, Hpet>>> {
+pub fn get_hpet() -> Option<RwLockReadGuard<'static, MmioRegion<Hpet>>> {
     HPET.try().map(|h| h.read())
 }
 
@@ -43,32 +39,48 @@ pub fn get_hpet() -> Option<RwLockReadGuard<'static, BoxRefMut<MappedPages, Hpet
 /// ```
 /// get_hpet_mut().as_mut().unwrap().enable_counter(true);
 /// ```
-pub fn get_hpet_mut() -> Option<RwLockWriteGuard<'static, BoxRefMut<MappedPages, Hpet>>> {
+pub fn get_hpet_mut() -> Option<RwLockWriteGuard<'static, MmioRegion<Hpet>>> {
     HPET.try().map(|h| h.write())
 }
 
 
-/// A structure that offers access to HPET through its I/O registers, 
-/// specified by the format here: <https://wiki.osdev.org/HPET#HPET_registers>.
-#[derive(FromBytes)]
-#[repr(C)]
-pub struct Hpet {
-    /// The General Capabilities and ID Register, at offset 0x0.
-    pub general_capabilities_and_id: ReadOnly<u64>,
-    _padding0:                       u64,
-    /// The General Configuration Register, at offset 0x10.
-    pub general_configuration:       Volatile<u64>,
-    _padding1:                       u64,
-    /// The General Interrupt Status Register, at offset 0x20.
-    pub general_interrupt_status:    Volatile<u64>,
-    _padding2:                       [u64; (0xF0 - 0x28) / 8], // 25 u64s
-    /// The Main Counter Value Register, at offset 0xF0.
-    pub main_counter_value:          Volatile<u64>,
-    _padding3:                       u64,
-    /// The timers (comparators) available for separate.
-    /// There is a minimum of 3 timers and a maximum of 32 in an HPET-enabled system.
-    /// Call [`num_timers`](#method.num_timers) to get the actual number of HPET timers.
-    pub timers:                      [HpetTimer; 32],
+register_structs! {
+    /// A structure that offers access to HPET through its I/O registers, 
+    /// specified by the format here: <https://wiki.osdev.org/HPET#HPET_registers>.
+    pub Hpet {
+        /// The General Capabilities and ID Register, at offset 0x0.
+        (0x000 => pub general_capabilities_and_id: ReadOnly<u64>),
+        (0x008 => _reserved0),
+        /// The General Configuration Register, at offset 0x10.
+        (0x010 => pub general_configuration: ReadWrite<u64>),
+        (0x018 => _reserved1),
+        /// The General Interrupt Status Register, at offset 0x20.
+        (0x020 => pub general_interrupt_status: ReadWrite<u64>),
+        (0x028 => _reserved2),
+        /// The Main Counter Value Register, at offset 0xF0.
+        (0x0F0 => pub main_counter_value: ReadWrite<u64>),
+        (0x0F8 => _reserved3),
+        /// The timers (comparators) available for separate.
+        /// There is a minimum of 3 timers and a maximum of 32 in an HPET-enabled system.
+        /// Call [`num_timers`](#method.num_timers) to get the actual number of HPET timers.
+        (0x100 => pub timers: [HpetTimer; 32]),
+        (0x500 => @END),
+    },
+
+    /// A structure that wraps HPET I/O register for each timer comparator, 
+    /// specified by the format here: <https://wiki.osdev.org/HPET#HPET_registers>.
+    /// There are between 3 and 32 of these in an HPET-enabled system.
+    pub HpetTimer {
+        /// This timer's Configuration and Capability register.
+        (0x00 => pub configuration_and_capability: ReadWrite<u64>),
+        /// This timer's Comparator Value register.
+        (0x08 => pub comparator_value: ReadWrite<u64>),
+        /// This timer's FSB Interrupt Route register.
+        /// Some info here: <https://wiki.osdev.org/HPET#FSB_mapping>
+        (0x10 => pub fsb_interrupt_route: ReadWrite<u64>),
+        (0x18 => _reserved),
+        (0x20 => @END),
+    }
 }
 
 impl Hpet {
@@ -78,14 +90,14 @@ impl Hpet {
     }
 
     /// Turns on or off the main counter
-    pub fn enable_counter(&mut self, enable: bool) {
+    pub fn enable_counter(&self, enable: bool) {
         if enable {
             // set bit 0
-            self.general_configuration.update(|old_val_ref| *old_val_ref |= 0x1);
+            self.general_configuration.modify(|old_val| old_val | 0x1);
         }
         else {
             // clear bit 0
-            self.general_configuration.update(|old_val_ref| *old_val_ref &= !0x1);
+            self.general_configuration.modify(|old_val| old_val & !0x1);
         }
             
     }
@@ -118,23 +130,6 @@ impl Hpet {
 }
 
 
-/// A structure that wraps HPET I/O register for each timer comparator, 
-/// specified by the format here: <https://wiki.osdev.org/HPET#HPET_registers>.
-/// There are between 3 and 32 of these in an HPET-enabled system.
-#[derive(FromBytes)]
-#[repr(C)]
-pub struct HpetTimer {
-    /// This timer's Configuration and Capability register.
-    pub configuration_and_capability: Volatile<u64>,
-    /// This timer's Comparator Value register.
-    pub comparator_value:             Volatile<u64>,
-    /// This timer's FSB Interrupt Route register.
-    /// Some info here: <https://wiki.osdev.org/HPET#FSB_mapping>
-    pub fsb_interrupt_route:          Volatile<u64>,
-    _padding:                         u64,
-}
-
-
 pub const HPET_SIGNATURE: &'static [u8; 4] = b"HPET";
 
 /// The handler for parsing the HPET table and adding it to the ACPI tables list.
@@ -172,7 +167,7 @@ impl HpetAcpiTable {
     /// based on the hardware details from this ACPI table.
     /// 
     /// Returns a reference to the initialized `Hpet` structure.
-    pub fn init_hpet(&self, page_table: &mut PageTable) -> Result<&'static RwLock<BoxRefMut<MappedPages, Hpet>>, &'static str> {
+    pub fn init_hpet(&self, page_table: &mut PageTable) -> Result<&'static RwLock<MmioRegion<Hpet>>, &'static str> {
         let phys_addr = PhysicalAddress::new(self.gen_addr_struct.phys_addr as usize)?;
         let frames = FrameRange::from_phys_addr(phys_addr, self.header.length as usize);
         let pages = allocate_pages(frames.size_in_frames()).ok_or("Couldn't allocate_pages for HPET")?;
@@ -184,7 +179,7 @@ impl HpetAcpiTable {
             fa.lock().deref_mut()
         )?;
 
-        let mut hpet = BoxRefMut::new(Box::new(hpet_mp)).try_map_mut(|mp| mp.as_type_mut::<Hpet>(phys_addr.frame_offset()))?;
+        let hpet = MmioRegion::<Hpet>::new(hpet_mp, phys_addr.frame_offset())?;
         // enable the main counter
         {
             hpet.enable_counter(true);
@@ -24,6 +24,8 @@ extern crate keyboard;
 extern crate mouse;
 extern crate ps2;
 extern crate tlb_shootdown;
+extern crate replay_log;
+extern crate sleep;
 
 
 
@@ -34,6 +36,7 @@ use kernel_config::time::{CONFIG_PIT_FREQUENCY_HZ}; //, CONFIG_RTC_FREQUENCY_HZ}
 // use rtc;
 use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
 use memory::VirtualAddress;
+use tss::InterruptStacks;
 use apic::{INTERRUPT_CHIP, InterruptChip};
 use pic::PIC_MASTER_OFFSET;
 
@@ -68,24 +71,30 @@ pub fn is_exception_handler_with_error_code(address: u64) -> bool {
 
 /// initializes the interrupt subsystem and properly sets up safer early exception handlers, but no other IRQ handlers.
 /// # Arguments: 
-/// * `double_fault_stack_top_unusable`: the address of the top of a newly allocated stack, to be used as the double fault exception handler stack.
+/// * `interrupt_stacks`: newly allocated stacks, to be used as the double fault, NMI, and machine check exception handler stacks.
 /// * `privilege_stack_top_unusable`: the address of the top of a newly allocated stack, to be used as the privilege stack (Ring 3 -> Ring 0 stack).
-pub fn init(double_fault_stack_top_unusable: VirtualAddress, privilege_stack_top_unusable: VirtualAddress) 
+pub fn init(interrupt_stacks: InterruptStacks, privilege_stack_top_unusable: VirtualAddress) 
     -> Result<&'static LockedIdt, &'static str> 
 {
     let bsp_id = apic::get_bsp_id().ok_or("couldn't get BSP's id")?;
     info!("Setting up TSS & GDT for BSP (id {})", bsp_id);
-    gdt::create_tss_gdt(bsp_id, double_fault_stack_top_unusable, privilege_stack_top_unusable);
+    gdt::create_tss_gdt(bsp_id, interrupt_stacks, privilege_stack_top_unusable);
 
     // initialize early exception handlers
     exceptions_early::init(&IDT);
     {
-        // set the special double fault handler's stack
+        // set the special stacks for the critical exception handlers
         let mut idt = IDT.lock(); // withholds interrupts
         unsafe {
             // use a special stack for the double fault handler, which prevents triple faults!
             idt.double_fault.set_handler_fn(exceptions_early::double_fault_handler)
                             .set_stack_index(tss::DOUBLE_FAULT_IST_INDEX as u16); 
+            // NMIs and machine checks can occur at any time, even while the current stack is unusable.
+            // The handlers set later on (e.g., by `exceptions_full`) keep using these stacks.
+            idt.non_maskable_interrupt.set_handler_fn(exceptions_early::nmi_handler)
+                            .set_stack_index(tss::NMI_IST_INDEX as u16);
+            idt.machine_check.set_handler_fn(exceptions_early::machine_check_handler)
+                            .set_stack_index(tss::MACHINE_CHECK_IST_INDEX as u16);
         }
        
         // fill all IDT entries with an unimplemented IRQ handler
@@ -108,11 +117,11 @@ pub fn init(double_fault_stack_top_unusable: VirtualAddress, privilege_stack_top
 
 /// Similar to `init()`, but for APs to call after the BSP has already invoked `init()`.
 pub fn init_ap(apic_id: u8, 
-               double_fault_stack_top_unusable: VirtualAddress, 
+               interrupt_stacks: InterruptStacks, 
                privilege_stack_top_unusable: VirtualAddress)
                -> Result<&'static LockedIdt, &'static str> {
     info!("Setting up TSS & GDT for AP {}", apic_id);
-    gdt::create_tss_gdt(apic_id, double_fault_stack_top_unusable, privilege_stack_top_unusable);
+    gdt::create_tss_gdt(apic_id, interrupt_stacks, privilege_stack_top_unusable);
 
     // We've already created the IDT initially (currently all APs share the BSP's IDT),
     // so we only need to re-load it here for each AP.
@@ -249,7 +258,8 @@ pub fn eoi(irq: Option<u8>) {
 
 
 /// 0x20
-extern "x86-interrupt" fn pit_timer_handler(_stack_frame: &mut ExceptionStackFrame) {
+extern "x86-interrupt" fn pit_timer_handler(stack_frame: &mut ExceptionStackFrame) {
+    replay_log::record_interrupt(PIC_MASTER_OFFSET, stack_frame.instruction_pointer.0);
     pit_clock::handle_timer_interrupt();
 
 	eoi(Some(PIC_MASTER_OFFSET));
@@ -260,7 +270,8 @@ extern "x86-interrupt" fn pit_timer_handler(_stack_frame: &mut ExceptionStackFra
 static EXTENDED_SCANCODE: AtomicBool = AtomicBool::new(false);
 
 /// 0x21
-extern "x86-interrupt" fn ps2_keyboard_handler(_stack_frame: &mut ExceptionStackFrame) {
+extern "x86-interrupt" fn ps2_keyboard_handler(stack_frame: &mut ExceptionStackFrame) {
+    replay_log::record_interrupt(PIC_MASTER_OFFSET + 0x1, stack_frame.instruction_pointer.0);
 
     let indicator = ps2::ps2_status_register();
 
@@ -292,6 +303,7 @@ extern "x86-interrupt" fn ps2_keyboard_handler(_stack_frame: &mut ExceptionStack
                     EXTENDED_SCANCODE.store(false, Ordering::SeqCst);
                 }
                 if scan_code != 0 {  // a scan code of zero is a PS2_PORT error that we can ignore
+                    replay_log::record(replay_log::EventKind::KeyboardInput { scan_code, extended });
                     if let Err(e) = keyboard::handle_keyboard_input(scan_code, extended) {
                         error!("ps2_keyboard_handler: error handling PS2_PORT input: {:?}", e);
                     }
@@ -304,7 +316,8 @@ extern "x86-interrupt" fn ps2_keyboard_handler(_stack_frame: &mut ExceptionStack
 }
 
 /// 0x2C
-extern "x86-interrupt" fn ps2_mouse_handler(_stack_frame: &mut ExceptionStackFrame) {
+extern "x86-interrupt" fn ps2_mouse_handler(stack_frame: &mut ExceptionStackFrame) {
+    replay_log::record_interrupt(PIC_MASTER_OFFSET + 0xc, stack_frame.instruction_pointer.0);
 
     let indicator = ps2::ps2_status_register();
 
@@ -313,6 +326,7 @@ extern "x86-interrupt" fn ps2_mouse_handler(_stack_frame: &mut ExceptionStackFra
         //whether the data is coming from the mouse
         if indicator & 0x20 == 0x20 {
             let readdata = handle_mouse_packet();
+            replay_log::record(replay_log::EventKind::MouseInput { packet: readdata });
             if (readdata & 0x80 == 0x80) || (readdata & 0x40 == 0x40) {
                 error!("The overflow bits in the mouse data packet's first byte are set! Discarding the whole packet.");
             } else if readdata & 0x08 == 0 {
@@ -331,14 +345,23 @@ extern "x86-interrupt" fn ps2_mouse_handler(_stack_frame: &mut ExceptionStackFra
 
 pub static APIC_TIMER_TICKS: AtomicUsize = AtomicUsize::new(0);
 /// 0x22
-extern "x86-interrupt" fn lapic_timer_handler(_stack_frame: &mut ExceptionStackFrame) {
+extern "x86-interrupt" fn lapic_timer_handler(stack_frame: &mut ExceptionStackFrame) {
+    replay_log::record_interrupt(0x22, stack_frame.instruction_pointer.0);
     let _ticks = APIC_TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
     // info!(" ({}) APIC TIMER HANDLER! TICKS = {}", apic::get_my_apic_id(), _ticks);
     
     // we must acknowledge the interrupt first before handling it because we switch tasks here, which doesn't return
     eoi(None); // None, because 0x22 IRQ cannot possibly be a PIC interrupt
+
+    // apply a new tick length, if it was changed since the last tick on this core
+    if let Err(e) = apic::sync_my_timer_period() {
+        error!("lapic_timer_handler(): failed to reprogram the timer: {}", e);
+    }
+
+    // wake up the sleeping tasks whose time has come, such that they can be scheduled right away
+    sleep::unblock_sleeping_tasks();
     
-    scheduler::schedule();
+    scheduler::timer_tick();
 }
 
 
@@ -430,7 +453,8 @@ extern "x86-interrupt" fn pic_spurious_interrupt_handler(_stack_frame: &mut Exce
 
 
 /// 0x2E
-extern "x86-interrupt" fn primary_ata_handler(_stack_frame: &mut ExceptionStackFrame ) {
+extern "x86-interrupt" fn primary_ata_handler(stack_frame: &mut ExceptionStackFrame ) {
+    replay_log::record_interrupt(PIC_MASTER_OFFSET + 0xE, stack_frame.instruction_pointer.0);
     info!("Primary ATA Interrupt (0x2E)");
 
     eoi(Some(PIC_MASTER_OFFSET + 0xE));
@@ -438,13 +462,15 @@ extern "x86-interrupt" fn primary_ata_handler(_stack_frame: &mut ExceptionStackF
 
 
 /// 0x2F
-extern "x86-interrupt" fn secondary_ata_handler(_stack_frame: &mut ExceptionStackFrame ) {
+extern "x86-interrupt" fn secondary_ata_handler(stack_frame: &mut ExceptionStackFrame ) {
+    replay_log::record_interrupt(PIC_MASTER_OFFSET + 0xF, stack_frame.instruction_pointer.0);
     info!("Secondary ATA Interrupt (0x2F)");
     
     eoi(Some(PIC_MASTER_OFFSET + 0xF));
 }
 
 
-extern "x86-interrupt" fn ipi_handler(_stack_frame: &mut ExceptionStackFrame) {
+extern "x86-interrupt" fn ipi_handler(stack_frame: &mut ExceptionStackFrame) {
+    replay_log::record_interrupt(tlb_shootdown::TLB_SHOOTDOWN_IPI_IRQ, stack_frame.instruction_pointer.0);
     eoi(None);
 }
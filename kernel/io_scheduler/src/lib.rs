@@ -0,0 +1,510 @@
+//! An I/O scheduler that sits between the block cache (e.g., `block_io`) and a storage device driver.
+//! 
+//! The [`IoScheduler`] wraps a backing storage device and is itself a [`StorageDevice`], 
+//! so it can be transparently stacked underneath any user of a storage device.
+//! It distinguishes between two classes of requests:
+//! * Reads are synchronous, i.e., the caller is waiting for them, so they are dispatched immediately.
+//!   Before a read is dispatched, any pending writes to the same sectors are dispatched first,
+//!   such that the read always observes the most recently-written data.
+//! * Writes are treated as background write-back, so they are queued and the caller returns immediately.
+//!   Queued writes are dispatched later in large batches, which avoids stalling reads behind them.
+//! 
+//! # Policies
+//! * **Merging**: a new write that is adjacent to a pending write from the same task is merged into it,
+//!   and adjacent writes are coalesced into a single device transfer upon dispatch.
+//!   A new write that overlaps a pending write causes the pending write to be dispatched first.
+//! * **Fairness**: each task has its own queue of pending writes.
+//!   Queues are serviced in round-robin order, up to `fairness_quantum` sectors per task at a time,
+//!   such that one task writing lots of data cannot starve the write-back of other tasks.
+//! * **Deadlines**: a pending write is dispatched once it has been pending for `write_deadline_ms`,
+//!   by the background dispatcher task that [`IoScheduler::new_shared()`] spawns,
+//!   which sleeps until the earliest deadline of all pending writes.
+//!   Expired writes are also dispatched whenever a new write is submitted. 
+//!   Writes are also dispatched once the total amount of pending data exceeds `max_pending_sectors`.
+//! 
+//! A scheduler created by [`IoScheduler::new()`] has no dispatcher task, so its deadlines are only checked
+//! when it is invoked (upon any write or explicit [`dispatch_expired()`] call). 
+//! The block cache stacks itself on top of a shared scheduler via `block_io::BlockIo::new_scheduled()`.
+//! 
+//! If the backing device fails to write some pending writes, they remain pending and are retried later.
+//! The error is only returned to the caller if its own request depends on those writes,
+//! i.e., a read or write of the same sectors, or an explicit [`flush()`] or [`dispatch_expired()`] call.
+//! Failures of background dispatches, which a caller merely happened to trigger, are logged and counted in the
+//! [`IoSchedulerStats`] instead, and the dispatcher task retries them after backing off.
+//! Users should call [`flush()`] to ensure that all pending writes have reached the backing device.
+//! All shared schedulers register with the `shutdown_manager` as [`SHUTDOWN_NAME`], 
+//! which flushes them before the storage controllers are quiesced.
+//! 
+//! [`IoScheduler`]: struct.IoScheduler.html
+//! [`StorageDevice`]: ../storage_device/trait.StorageDevice.html
+//! [`IoScheduler::new()`]: struct.IoScheduler.html#method.new
+//! [`IoScheduler::new_shared()`]: struct.IoScheduler.html#method.new_shared
+//! [`dispatch_expired()`]: struct.IoScheduler.html#method.dispatch_expired
+//! [`flush()`]: struct.IoScheduler.html#method.flush
+//! [`IoSchedulerStats`]: struct.IoSchedulerStats.html
+//! [`SHUTDOWN_NAME`]: constant.SHUTDOWN_NAME.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate storage_device;
+extern crate task;
+extern crate spawn;
+extern crate sleep;
+extern crate tsc;
+extern crate storage_manager;
+extern crate shutdown_manager;
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::{Mutex, Once};
+use storage_device::{StorageDevice, StorageDeviceRef};
+
+
+/// The name under which the shared `IoScheduler`s are registered with the `shutdown_manager`,
+/// which subsystems that write through a shared scheduler should list as a dependency.
+pub const SHUTDOWN_NAME: &str = "io_scheduler";
+
+/// How long flushing all shared schedulers may take upon shutdown.
+const SHUTDOWN_TIMEOUT_MS: u64 = 10000;
+
+/// All schedulers created by `IoScheduler::new_shared()`, which are flushed upon shutdown.
+static SHARED_SCHEDULERS: Mutex<Vec<Weak<Mutex<IoScheduler>>>> = Mutex::new(Vec::new());
+
+/// Whether the shared schedulers have been registered with the `shutdown_manager`.
+static SHUTDOWN_REGISTERED: Once<()> = Once::new();
+
+
+/// The tunable parameters of an `IoScheduler`.
+#[derive(Clone, Copy, Debug)]
+pub struct IoSchedulerConfig {
+    /// The maximum time in milliseconds that a write may remain pending before it is dispatched.
+    pub write_deadline_ms: u64,
+    /// The maximum number of sectors dispatched from one task's queue before moving on to the next task's queue.
+    pub fairness_quantum: usize,
+    /// The maximum number of sectors that may be pending across all queues before writes are dispatched.
+    pub max_pending_sectors: usize,
+}
+
+impl Default for IoSchedulerConfig {
+    fn default() -> IoSchedulerConfig {
+        IoSchedulerConfig {
+            write_deadline_ms: 500,
+            fairness_quantum: 64,
+            max_pending_sectors: 4096,
+        }
+    }
+}
+
+
+/// A write that has been queued but not yet dispatched to the backing device.
+struct PendingWrite {
+    /// The ID of the task that submitted this write, i.e., the queue it belongs to.
+    task_id: usize,
+    /// The first sector written.
+    start_sector: usize,
+    /// The data to be written, a multiple of the sector size.
+    data: Vec<u8>,
+    /// The TSC timestamp at which the oldest part of this write was submitted.
+    submitted_at: u64,
+}
+
+impl PendingWrite {
+    /// Returns the sector after the last sector written, i.e., an exclusive end bound.
+    fn end_sector(&self, sector_size: usize) -> usize {
+        self.start_sector + self.data.len() / sector_size
+    }
+}
+
+
+/// Statistics about the requests handled by an `IoScheduler`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IoSchedulerStats {
+    /// The number of read requests received.
+    pub reads: usize,
+    /// The number of write requests received.
+    pub writes: usize,
+    /// The number of write requests that were merged into an existing pending write.
+    pub merged_writes: usize,
+    /// The number of write transfers issued to the backing device.
+    pub dispatched_writes: usize,
+    /// The number of pending writes that were dispatched because their deadline expired.
+    pub expired_writes: usize,
+    /// The number of background dispatches, of expired writes or for fairness, that failed
+    /// and whose writes were requeued to be retried later.
+    pub failed_dispatches: usize,
+}
+
+
+/// A storage device wrapper that schedules reads and writes to its backing device.
+/// 
+/// See the [crate-level documentation](index.html) for a description of its policies.
+pub struct IoScheduler {
+    backing_device: StorageDeviceRef,
+    sector_size: usize,
+    config: IoSchedulerConfig,
+    /// The write deadline converted into TSC ticks.
+    write_deadline_ticks: u64,
+    /// The queues of pending writes, one per task ID, each sorted by submission time.
+    queues: BTreeMap<usize, VecDeque<PendingWrite>>,
+    /// The order in which task queues are serviced, for round-robin fairness.
+    round_robin: VecDeque<usize>,
+    /// The total number of sectors pending across all queues.
+    pending_sectors: usize,
+    /// The TSC timestamp until which writes don't trigger background dispatches, after one failed.
+    backoff_until: u64,
+    stats: IoSchedulerStats,
+}
+
+impl IoScheduler {
+    /// Creates a new `IoScheduler` for the given `backing_device` with the given `config`.
+    ///
+    /// The returned scheduler only checks write deadlines when it is invoked;
+    /// use [`new_shared()`](#method.new_shared) to also dispatch expired writes in the background.
+    pub fn new(backing_device: StorageDeviceRef, config: IoSchedulerConfig) -> Result<IoScheduler, &'static str> {
+        let sector_size = backing_device.lock().sector_size_in_bytes();
+        let tsc_freq = tsc::get_tsc_frequency()?;
+        Ok(IoScheduler {
+            backing_device,
+            sector_size,
+            config,
+            write_deadline_ticks: config.write_deadline_ms.saturating_mul(tsc_freq) / 1000,
+            queues: BTreeMap::new(),
+            round_robin: VecDeque::new(),
+            pending_sectors: 0,
+            backoff_until: 0,
+            stats: IoSchedulerStats::default(),
+        })
+    }
+
+    /// Creates a new shared `IoScheduler` for the given `backing_device` with the given `config`,
+    /// and spawns a task that dispatches its pending writes as soon as their deadline expires.
+    ///
+    /// The dispatcher task exits once the returned scheduler has been dropped.
+    /// The returned scheduler is also flushed upon shutdown.
+    pub fn new_shared(backing_device: StorageDeviceRef, config: IoSchedulerConfig) -> Result<Arc<Mutex<IoScheduler>>, &'static str> {
+        let scheduler = Arc::new(Mutex::new(IoScheduler::new(backing_device, config)?));
+        spawn::new_task_builder(dispatcher_loop, Arc::downgrade(&scheduler))
+            .name(String::from("io_scheduler_dispatcher"))
+            .spawn()?;
+        SHUTDOWN_REGISTERED.call_once(|| {
+            if let Err(e) = shutdown_manager::register(SHUTDOWN_NAME, &[storage_manager::SHUTDOWN_NAME], SHUTDOWN_TIMEOUT_MS, flush_shared) {
+                warn!("IoScheduler: couldn't register for shutdown: {}", e);
+            }
+        });
+        let mut shared_schedulers = SHARED_SCHEDULERS.lock();
+        shared_schedulers.retain(|s| s.upgrade().is_some());
+        shared_schedulers.push(Arc::downgrade(&scheduler));
+        Ok(scheduler)
+    }
+
+    /// Returns a reference to the backing storage device.
+    pub fn backing_device(&self) -> &StorageDeviceRef {
+        &self.backing_device
+    }
+
+    /// Returns the statistics of the requests handled by this scheduler so far.
+    pub fn stats(&self) -> IoSchedulerStats {
+        self.stats
+    }
+
+    /// Returns the number of sectors that are waiting to be written to the backing device.
+    pub fn pending_sectors(&self) -> usize {
+        self.pending_sectors
+    }
+
+    /// Dispatches all pending writes to the backing device.
+    ///
+    /// If the backing device fails to write some of them, those and all writes not yet issued remain pending.
+    pub fn flush(&mut self) -> Result<(), &'static str> {
+        let all_writes: Vec<PendingWrite> = self.queues.values_mut()
+            .flat_map(|queue| queue.drain(..))
+            .collect();
+        self.queues.clear();
+        self.round_robin.clear();
+        self.pending_sectors = 0;
+        self.dispatch_writes(all_writes)
+    }
+
+    /// Dispatches all pending writes whose deadline has expired.
+    pub fn dispatch_expired(&mut self) -> Result<(), &'static str> {
+        let now = tsc::tsc_ticks().into();
+        let deadline_ticks = self.write_deadline_ticks;
+        let expired = self.take_writes(|w| now.saturating_sub(w.submitted_at) >= deadline_ticks);
+        self.stats.expired_writes += expired.len();
+        self.dispatch_writes(expired)
+    }
+
+    /// Dispatches pending writes in round-robin order across tasks, 
+    /// up to `fairness_quantum` sectors per task, until the pending amount is below the limit.
+    fn dispatch_fairly(&mut self) -> Result<(), &'static str> {
+        while self.pending_sectors > self.config.max_pending_sectors {
+            let task_id = match self.round_robin.pop_front() {
+                Some(t) => t,
+                None => break,
+            };
+            let mut batch = Vec::new();
+            let mut batch_sectors = 0;
+            if let Some(queue) = self.queues.get_mut(&task_id) {
+                while batch_sectors < self.config.fairness_quantum {
+                    match queue.pop_front() {
+                        Some(w) => {
+                            batch_sectors += w.data.len() / self.sector_size;
+                            batch.push(w);
+                        }
+                        None => break,
+                    }
+                }
+                if queue.is_empty() {
+                    self.queues.remove(&task_id);
+                } else {
+                    self.round_robin.push_back(task_id);
+                }
+            }
+            self.pending_sectors -= batch_sectors;
+            self.dispatch_writes(batch)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the TSC timestamp at which the earliest deadline of all pending writes expires, if any are pending.
+    fn next_deadline(&self) -> Option<u64> {
+        self.queues.values()
+            .flat_map(|queue| queue.iter())
+            .map(|w| w.submitted_at.saturating_add(self.write_deadline_ticks))
+            .min()
+    }
+
+    /// Removes and returns all pending writes that match the given predicate.
+    fn take_writes<P: Fn(&PendingWrite) -> bool>(&mut self, predicate: P) -> Vec<PendingWrite> {
+        let mut taken = Vec::new();
+        for queue in self.queues.values_mut() {
+            let mut i = 0;
+            while i < queue.len() {
+                if predicate(&queue[i]) {
+                    if let Some(w) = queue.remove(i) {
+                        taken.push(w);
+                    }
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        let sector_size = self.sector_size;
+        self.pending_sectors -= taken.iter().map(|w| w.data.len() / sector_size).sum::<usize>();
+        let queues = &self.queues;
+        self.round_robin.retain(|t| queues.get(t).map_or(false, |q| !q.is_empty()));
+        self.queues.retain(|_, q| !q.is_empty());
+        taken
+    }
+
+    /// Issues the given writes to the backing device, coalescing adjacent writes into single transfers.
+    /// 
+    /// Writes to the same sectors are issued in the given order, such that later writes take precedence.
+    /// If a transfer fails, the writes in it and all writes after it are put back into their queues.
+    fn dispatch_writes(&mut self, mut writes: Vec<PendingWrite>) -> Result<(), &'static str> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        // A stable sort preserves the order of writes that start at the same sector.
+        writes.sort_by_key(|w| w.start_sector);
+        let sector_size = self.sector_size;
+        let backing_device = self.backing_device.clone();
+        let mut device = backing_device.lock();
+        let mut first = 0;
+        while first < writes.len() {
+            let mut end = first + 1;
+            while end < writes.len() && writes[end].start_sector == writes[end - 1].end_sector(sector_size) {
+                end += 1;
+            }
+            let result = if end - first == 1 {
+                device.write_sectors(&writes[first].data, writes[first].start_sector)
+            } else {
+                let mut data = Vec::with_capacity(writes[first..end].iter().map(|w| w.data.len()).sum());
+                for w in &writes[first..end] {
+                    data.extend_from_slice(&w.data);
+                }
+                device.write_sectors(&data, writes[first].start_sector)
+            };
+            if let Err(e) = result {
+                let unwritten = writes.split_off(first);
+                warn!("IoScheduler: failed to write sectors starting at {}, requeueing {} writes: {}",
+                    unwritten[0].start_sector, unwritten.len(), e
+                );
+                self.requeue_writes(unwritten);
+                return Err(e);
+            }
+            self.stats.dispatched_writes += 1;
+            first = end;
+        }
+        Ok(())
+    }
+
+    /// Records that a background dispatch of the given kind of writes failed with the given error.
+    /// 
+    /// The failed writes were already requeued by `dispatch_writes()`, so the dispatcher task will retry them.
+    /// Until then, i.e., for one write deadline, new writes don't trigger any more background dispatches.
+    fn note_failed_dispatch(&mut self, kind: &str, error: &'static str) {
+        self.stats.failed_dispatches += 1;
+        let now: u64 = tsc::tsc_ticks().into();
+        self.backoff_until = now.saturating_add(self.write_deadline_ticks);
+        warn!("IoScheduler: failed to dispatch {} in the background, will retry: {}", kind, error);
+    }
+
+    /// Puts the given writes, which could not be dispatched, back into their tasks' queues,
+    /// keeping each queue sorted by submission time.
+    fn requeue_writes(&mut self, writes: Vec<PendingWrite>) {
+        for w in writes {
+            self.pending_sectors += w.data.len() / self.sector_size;
+            let queue = self.queues.entry(w.task_id).or_insert_with(VecDeque::new);
+            if queue.is_empty() && !self.round_robin.contains(&w.task_id) {
+                self.round_robin.push_back(w.task_id);
+            }
+            let index = queue.iter().position(|q| q.submitted_at > w.submitted_at).unwrap_or(queue.len());
+            queue.insert(index, w);
+        }
+    }
+}
+
+/// Flushes all shared schedulers that still exist, which is invoked upon shutdown.
+///
+/// All of them are flushed even if some fail, and the first error is returned.
+fn flush_shared() -> Result<(), &'static str> {
+    let shared_schedulers: Vec<Arc<Mutex<IoScheduler>>> = SHARED_SCHEDULERS.lock().iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    let mut result = Ok(());
+    for scheduler in shared_schedulers {
+        if let Err(e) = scheduler.lock().flush() {
+            error!("IoScheduler: failed to flush pending writes upon shutdown: {}", e);
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+/// The entry point of an `IoScheduler`'s dispatcher task, see `IoScheduler::new_shared()`.
+///
+/// It sleeps until the earliest deadline of all pending writes, or for one whole deadline if no writes are pending,
+/// since any write submitted while it sleeps expires no earlier than that. 
+fn dispatcher_loop(scheduler: Weak<Mutex<IoScheduler>>) -> Result<(), &'static str> {
+    loop {
+        let wakeup = {
+            let shared_scheduler = match scheduler.upgrade() {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+            let mut locked_scheduler = shared_scheduler.lock();
+            let now: u64 = tsc::tsc_ticks().into();
+            let retry = now.saturating_add(locked_scheduler.write_deadline_ticks);
+            match locked_scheduler.dispatch_expired() {
+                // The failed writes were requeued, so back off for one deadline before retrying them.
+                Err(e) => {
+                    locked_scheduler.note_failed_dispatch("expired writes", e);
+                    retry
+                }
+                Ok(()) => locked_scheduler.next_deadline().map_or(retry, |d| core::cmp::min(d, retry)),
+            }
+        };
+        sleep::sleep_until(wakeup)?;
+    }
+}
+
+impl StorageDevice for IoScheduler {
+    fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        self.stats.reads += 1;
+        // Pending writes to the sectors being read must reach the device first.
+        let end = offset_in_sectors + buffer.len() / self.sector_size;
+        let sector_size = self.sector_size;
+        let overlapping = self.take_writes(|w| w.start_sector < end && offset_in_sectors < w.end_sector(sector_size));
+        self.dispatch_writes(overlapping)?;
+        self.backing_device.lock().read_sectors(buffer, offset_in_sectors)
+    }
+
+    fn write_sectors(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        if buffer.len() % self.sector_size != 0 {
+            return Err("IoScheduler::write_sectors(): buffer length must be a multiple of the sector size");
+        }
+        let num_sectors = buffer.len() / self.sector_size;
+        if offset_in_sectors + num_sectors > self.size_in_sectors() {
+            return Err("IoScheduler::write_sectors(): write extends past the end of the device");
+        }
+        self.stats.writes += 1;
+        let now: u64 = tsc::tsc_ticks().into();
+        let backing_off = now < self.backoff_until;
+        if !backing_off {
+            if let Err(e) = self.dispatch_expired() {
+                self.note_failed_dispatch("expired writes", e);
+            }
+        }
+
+        let task_id = task::get_my_current_task_id().unwrap_or(0);
+        let start = offset_in_sectors;
+        let end = offset_in_sectors + num_sectors;
+        let sector_size = self.sector_size;
+
+        // Pending writes to the same sectors must be dispatched first to preserve write ordering.
+        let overlapping = self.take_writes(|w| w.start_sector < end && start < w.end_sector(sector_size));
+        self.dispatch_writes(overlapping)?;
+
+        // Try to merge this write into an adjacent pending write from the same task.
+        let mut merged = false;
+        if let Some(queue) = self.queues.get_mut(&task_id) {
+            for w in queue.iter_mut() {
+                if w.end_sector(sector_size) == start {
+                    w.data.extend_from_slice(buffer);
+                    merged = true;
+                    break;
+                } else if end == w.start_sector {
+                    let mut data = Vec::with_capacity(buffer.len() + w.data.len());
+                    data.extend_from_slice(buffer);
+                    data.extend_from_slice(&w.data);
+                    w.data = data;
+                    w.start_sector = start;
+                    merged = true;
+                    break;
+                }
+            }
+        }
+        if merged {
+            self.stats.merged_writes += 1;
+        } else {
+            let queue = self.queues.entry(task_id).or_insert_with(VecDeque::new);
+            if queue.is_empty() {
+                self.round_robin.push_back(task_id);
+            }
+            queue.push_back(PendingWrite { task_id, start_sector: start, data: buffer.to_vec(), submitted_at: now });
+        }
+        self.pending_sectors += num_sectors;
+
+        // This write is already queued, so a failure to dispatch other writes doesn't concern the caller.
+        if !backing_off {
+            if let Err(e) = self.dispatch_fairly() {
+                self.note_failed_dispatch("writes over the pending limit", e);
+            }
+        }
+        Ok(num_sectors)
+    }
+
+    fn sector_size_in_bytes(&self) -> usize {
+        self.sector_size
+    }
+
+    fn size_in_sectors(&self) -> usize {
+        self.backing_device.lock().size_in_sectors()
+    }
+}
+
+impl Drop for IoScheduler {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("IoScheduler: failed to flush pending writes upon drop, losing {} sectors: {}", self.pending_sectors, e);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Kernel address space layout randomization (KASLR).
+//!
+//! The kernel's virtual address space is randomized in three ways:
+//! * Before any Rust code runs, the boot code (`nano_core/src/boot/arch_x86_64/boot.asm`) moves the higher-half
+//!   part of the `nano_core` image from its link address at `KERNEL_OFFSET` by a random [`nano_core_slide()`]:
+//!   it maps the image at the moved address and patches every absolute address that points into the image,
+//!   using the relocation table that `tools/kaslr_relocs` extracts from the `nano_core` binary at build time.
+//!   The image is moved in steps of 2MiB into the last GiB of the address space.
+//! * At early boot, [`init()`] chooses a random offset of the kernel heap, which starts at [`heap_start()`]
+//!   instead of at `KERNEL_HEAP_START`.
+//! * Every page allocation that doesn't request a specific address, e.g., every kernel stack
+//!   and every section of a loaded crate, is placed at a random offset into the free chunk of pages it's
+//!   taken from (see the `page_allocator`), using [`random_below()`], such that the distances between
+//!   allocations are random too. Because crates are relocated when they are loaded,
+//!   their sections work at any address.
+//!
+//! The random values are seeded by the `entropy` crate, i.e., from the `RDRAND` instruction (if the CPU supports it) and the TSC.
+//! KASLR can be disabled with the `KASLR` switch in `kernel_config` or the `nokaslr` boot argument,
+//! in which case nothing is moved.
+//!
+//! [`init()`]: fn.init.html
+//! [`nano_core_slide()`]: fn.nano_core_slide.html
+//! [`heap_start()`]: fn.heap_start.html
+//! [`random_below()`]: fn.random_below.html
+
+#![no_std]
+
+extern crate kernel_config;
+extern crate entropy;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use kernel_config::memory::{KASLR, KASLR_MAX_SLIDE, KERNEL_HEAP_START};
+
+
+/// The boot argument that disables KASLR.
+pub const NO_KASLR_BOOT_ARG: &'static str = "nokaslr";
+/// The alignment of the heap's slide, which allows the heap to be mapped with 2MiB huge pages.
+const HEAP_SLIDE_ALIGNMENT: usize = 2 * 1024 * 1024;
+
+/// Tells the boot code whether to move the `nano_core` image, which it reads before any Rust code runs.
+/// The boot code itself checks for the `nokaslr` boot argument.
+#[no_mangle]
+pub static KASLR_RELOCATE_NANO_CORE: u8 = KASLR as u8;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NANO_CORE_SLIDE: AtomicUsize = AtomicUsize::new(0);
+static HEAP_SLIDE: AtomicUsize = AtomicUsize::new(0);
+/// The state of the random number generator behind `random_below()`.
+static RNG_STATE: AtomicU64 = AtomicU64::new(0);
+
+
+/// Chooses the random slides of all randomized regions, unless KASLR is disabled
+/// by `kernel_config` or by the `nokaslr` argument in the given boot `command_line`.
+///
+/// The `nano_core_slide` is the number of bytes by which the boot code moved the `nano_core` image,
+/// which is zero if it wasn't moved.
+///
+/// This must be called once at early boot, before the heap is initialized and before any pages are allocated.
+/// Returns whether KASLR is enabled.
+pub fn init(command_line: Option<&str>, nano_core_slide: usize) -> bool {
+    NANO_CORE_SLIDE.store(nano_core_slide, Ordering::Release);
+    let disabled_by_arg = command_line.map_or(false, |c| c.split_whitespace().any(|arg| arg == NO_KASLR_BOOT_ARG));
+    if !KASLR || disabled_by_arg {
+        return false;
+    }
+
+    let mut rng = SplitMix64(entropy::seed());
+    HEAP_SLIDE.store(rng.slide(HEAP_SLIDE_ALIGNMENT), Ordering::Release);
+    RNG_STATE.store(rng.next(), Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+    true
+}
+
+/// Returns whether KASLR was enabled at boot.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Returns the number of bytes by which the higher-half sections of the `nano_core` were moved
+/// from the addresses they were linked at.
+pub fn nano_core_slide() -> usize {
+    NANO_CORE_SLIDE.load(Ordering::Acquire)
+}
+
+/// Returns the starting virtual address of the kernel heap.
+pub fn heap_start() -> usize {
+    KERNEL_HEAP_START + HEAP_SLIDE.load(Ordering::Acquire)
+}
+
+/// Returns a random number that is less than the given `bound`,
+/// or zero if KASLR is disabled or `bound` is zero.
+///
+/// This is cheap and can be used from any context, e.g., by the page allocator to randomize each allocation.
+pub fn random_below(bound: usize) -> usize {
+    if bound == 0 || !is_enabled() {
+        return 0;
+    }
+    let state = RNG_STATE.fetch_add(SPLITMIX64_GAMMA, Ordering::Relaxed).wrapping_add(SPLITMIX64_GAMMA);
+    (splitmix64_mix(state) % bound as u64) as usize
+}
+
+
+const SPLITMIX64_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+fn splitmix64_mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// A small pseudo-random number generator, which spreads the entropy of its seed over all of its outputs.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(SPLITMIX64_GAMMA);
+        splitmix64_mix(self.0)
+    }
+
+    /// Returns a random multiple of `alignment` that is less than `KASLR_MAX_SLIDE`.
+    fn slide(&mut self, alignment: usize) -> usize {
+        let slots = (KASLR_MAX_SLIDE / alignment) as u64;
+        if slots == 0 {
+            return 0;
+        }
+        (self.next() % slots) as usize * alignment
+    }
+}
@@ -61,6 +61,8 @@ pub const KERNEL_STACK_SIZE_IN_PAGES: usize = 16;
 /// i.e., the linear offset between physical memory and kernel memory.
 /// So, for example, the VGA buffer will be mapped from 0xb8000 to 0xFFFFFFFF800b8000.
 /// This is -2GiB from the end of the 64-bit address space.
+/// With KASLR, the boot code moves the nano_core's higher-half sections from here into the last GiB
+/// of the address space, see `kaslr::nano_core_slide()`, but the linear offset of physical memory stays the same.
 pub const KERNEL_OFFSET: usize = 0xFFFF_FFFF_8000_0000;
 
 
@@ -77,6 +79,7 @@ pub const KERNEL_TEXT_MAX_SIZE: usize = ADDRESSABILITY_PER_P4_ENTRY - (2 * 1024
 /// higher-half heap gets 512 GB address range starting at the 509th P4 entry,
 /// which is the slot right below the recursive P4 entry (510)
 /// actual value: 0o177777_775_000_000_000_0000, or 0xFFFF_FE80_0000_0000
+/// The heap actually starts at a random offset above this address when KASLR is enabled, see `kaslr::heap_start()`.
 pub const KERNEL_HEAP_START: usize = 0xFFFF_0000_0000_0000 | (KERNEL_HEAP_P4_INDEX << (P4_INDEX_SHIFT + PAGE_SHIFT));
 #[cfg(not(safe_heap))]
 pub const KERNEL_HEAP_INITIAL_SIZE: usize = 16 * 1024 * 1024; //16 MiB
@@ -101,3 +104,89 @@ pub const USER_STACK_ALLOCATOR_BOTTOM: usize = 0xFFFF_0000_0000_0000 | (USER_STA
 /// the highest actually usuable address in each userspace stack allocator
 pub const USER_STACK_ALLOCATOR_TOP_ADDR: usize = USER_STACK_ALLOCATOR_BOTTOM + ADDRESSABILITY_PER_P4_ENTRY - BYTES_PER_ADDR;
 
+
+
+/// The maximum number of available physical memory areas, and separately the maximum number of occupied areas, 
+/// that the frame allocator can track before the heap is set up.
+/// Platforms with highly-fragmented memory maps (e.g., UEFI servers with 100+ entries) should increase this.
+pub const MAX_PRE_HEAP_MEMORY_AREAS: usize = 32;
+
+/// The frame allocator backend that the system-wide frame allocator switches to once the heap is set up:
+/// `"area"`, `"bitmap"`, or `"buddy"`. This can be overridden by the `frame_allocator=` boot argument.
+pub const FRAME_ALLOCATOR_BACKEND: &'static str = "area";
+
+/// The maximum number of frames that each core's frame cache can hold.
+pub const FRAME_CACHE_CAPACITY: usize = 64;
+/// The number of frames moved at once between a core's frame cache and the system-wide frame allocator,
+/// when refilling an empty cache or draining a full one.
+pub const FRAME_CACHE_BATCH_SIZE: usize = FRAME_CACHE_CAPACITY / 2;
+
+/// The number of frames that are reserved at once from the system-wide frame allocator
+/// when a NUMA node's pool of free frames is empty. 
+pub const NUMA_NODE_RESERVE_CHUNK_FRAMES: usize = 1024; // 4 MiB
+
+/// The number of 2MiB huge frames that are reserved at boot, before physical memory becomes fragmented.
+pub const HUGE_FRAME_POOL_2MIB_COUNT: usize = 16; // 32 MiB
+/// The number of 1GiB huge frames that are reserved at boot, before physical memory becomes fragmented.
+pub const HUGE_FRAME_POOL_1GIB_COUNT: usize = 0;
+
+/// If `true`, `Mapper::map_allocated_pages_to()` maps every 2MiB or 1GiB region whose pages and frames are both
+/// suitably aligned with a single huge page entry, e.g., for the kernel's own sections.
+/// 1GiB pages are only used if the CPU supports them.
+pub const MAP_HUGE_PAGES: bool = true;
+
+/// If `true`, each page table is tagged with its own process-context identifier (PCID) if the CPU supports
+/// PCIDs and the `invpcid` instruction, such that switching page tables doesn't flush the whole TLB.
+pub const USE_PCID: bool = true;
+
+/// If `true`, supervisor-mode execution and access prevention (SMEP and SMAP) are enabled on every core
+/// that supports them, such that the kernel faults upon executing or accessing a user-accessible page
+/// outside of a `memory::with_user_access()` window.
+pub const ENABLE_SMEP_SMAP: bool = true;
+
+/// The size in bytes of the contiguous memory area (CMA) that is reserved at boot 
+/// for drivers that need large physically-contiguous buffers, e.g., framebuffers and NIC rings.
+/// The CMA is never used to satisfy regular frame allocations. Set this to `0` to disable it.
+pub const CMA_AREA_SIZE_IN_BYTES: usize = 16 * 1024 * 1024; // 16 MiB
+
+/// The size in bytes of the physical memory region that is reserved at boot for a crash kernel,
+/// which is never used by the frame allocator; see `memory::crash_kernel_region()`.
+/// This can be overridden with the `crashkernel=SIZE[@ADDRESS]` boot argument. Set this to `0` to disable it.
+pub const CRASH_KERNEL_SIZE_IN_BYTES: usize = 0;
+
+/// If `true`, the kernel's virtual address space layout is randomized at boot (KASLR):
+/// the nano_core is moved to a random address, the kernel heap starts at a random offset of up to `KASLR_MAX_SLIDE` bytes
+/// into its region, and every page allocation without a requested address (e.g., kernel stacks and the sections of loaded crates)
+/// is placed at a random offset of up to `KASLR_MAX_SLIDE` bytes into its free chunk. See the `kaslr` crate.
+/// Set this to `false` (or boot with the `nokaslr` argument) to get the same addresses on every boot for debugging.
+pub const KASLR: bool = true;
+/// The maximum random offset in bytes by which KASLR moves the kernel heap and each page allocation.
+pub const KASLR_MAX_SLIDE: usize = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+/// If `true`, frames are scrubbed (filled with zeros) when they are deallocated after being unmapped,
+/// such that their old contents cannot leak to whichever crate or task reuses them next.
+/// This is intended for security-sensitive builds, as it adds the cost of zeroing every freed frame.
+pub const SCRUB_FRAMES_ON_FREE: bool = false;
+/// If `true`, newly-allocated frames are scrubbed (filled with zeros) when they are first mapped,
+/// which lazily clears any old contents just before a frame is reused.
+pub const SCRUB_FRAMES_ON_ALLOC: bool = false;
+
+/// The number of bytes from the start of the kernel heap that are checked by the heap's `kasan` feature, if enabled.
+/// This requires one byte of shadow memory for every 8 bytes of the heap; allocations beyond it aren't checked.
+pub const KASAN_HEAP_COVERAGE: usize = 256 * 1024 * 1024; // 256 MiB, using 32 MiB of shadow memory
+/// The number of recently-freed allocations that the heap's `kasan` feature keeps from being reused,
+/// such that accesses to them are still detected as use-after-free bugs.
+pub const KASAN_QUARANTINE_ENTRIES: usize = 4096;
+
+/// The maximum number of pre-zeroed frames that are kept ready for allocations that require zeroed memory.
+/// These are zeroed in the background by the `frame_zeroer` task.
+pub const ZEROED_FRAME_POOL_CAPACITY: usize = 256; // 1 MiB
+/// The number of freed frames that the `frame_zeroer` task zeroes at once.
+pub const ZEROED_FRAME_BATCH_SIZE: usize = 16;
+
+/// When the number of free frames falls below this watermark, memory is considered to be under pressure
+/// and the registered reclaim callbacks are invoked to free up some memory, see `memory::register_reclaimer()`.
+pub const LOW_WATERMARK_FRAMES: usize = 4096; // 16 MiB
+/// When the number of free frames falls below this watermark, memory is critically low,
+/// so every cached frame is also released back to the system-wide frame allocator before invoking the reclaim callbacks.
+pub const MIN_WATERMARK_FRAMES: usize = 1024; // 4 MiB
@@ -9,6 +9,8 @@ pub const CONFIG_PIT_FREQUENCY_HZ: u32 = 1000;
 pub const CONFIG_RTC_FREQUENCY_HZ: usize = 128;
 
 /// The timeslice period, specified in microseconds.
+/// This is the default interval between timer interrupts, which can be changed at runtime
+/// via the `scheduler.tick_us` tunable (see the `config_registry` crate).
 pub const CONFIG_TIMESLICE_PERIOD_MICROSECONDS: u32 = 8000; // 8ms
 
 /// the heartbeat period in milliseconds
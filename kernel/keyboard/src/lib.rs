@@ -8,7 +8,7 @@ extern crate mpmc;
 #[macro_use] extern crate log;
 
 
-use keycodes_ascii::{Keycode, KeyboardModifiers, KEY_RELEASED_OFFSET, KeyAction, KeyEvent};
+use keycodes_ascii::{Keycode, KeyboardModifiers, KEY_RELEASED_OFFSET, KeyAction, KeyEvent, KeySym};
 use spin::Once;
 use mpmc::Queue;
 use event_types::Event;
@@ -18,6 +18,9 @@ use ps2::{init_ps2_port1,test_ps2_port1,keyboard_led,keyboard_detect,KeyboardTyp
 // TODO: avoid unsafe static mut using the following: https://www.reddit.com/r/rust/comments/1wvxcn/lazily_initialized_statics/cf61im5/
 static mut KBD_MODIFIERS: KeyboardModifiers = KeyboardModifiers::new();
 
+/// The accent of the dead key that was most recently pressed, which will be combined with the next character.
+static mut PENDING_DEAD_KEY: Option<char> = None;
+
 
 static KEYBOARD_PRODUCER: Once<Queue<Event>> = Once::new();
 
@@ -68,7 +71,10 @@ pub fn handle_keyboard_input(scan_code: u8, extended: bool) -> Result<(), &'stat
         x if x == Keycode::Control        as u8                       => { 
             modifiers.insert(if extended { KeyboardModifiers::CONTROL_RIGHT } else { KeyboardModifiers::CONTROL_LEFT});
         }
-        x if x == Keycode::Alt            as u8                       => { modifiers.insert(KeyboardModifiers::ALT);              }
+        x if x == Keycode::Alt            as u8                       => {
+            // The right Alt key is an extended key, which acts as AltGr in most non-US layouts.
+            modifiers.insert(if extended { KeyboardModifiers::ALT_GR } else { KeyboardModifiers::ALT });
+        }
         x if x == Keycode::LeftShift      as u8                       => { modifiers.insert(KeyboardModifiers::SHIFT_LEFT);       }
         x if x == Keycode::RightShift     as u8                       => { modifiers.insert(KeyboardModifiers::SHIFT_RIGHT);      }
         x if x == Keycode::SuperKeyLeft   as u8                       => { modifiers.insert(KeyboardModifiers::SUPER_KEY_LEFT);   }
@@ -77,7 +83,9 @@ pub fn handle_keyboard_input(scan_code: u8, extended: bool) -> Result<(), &'stat
         x if x == Keycode::Control        as u8 + KEY_RELEASED_OFFSET => {
             modifiers.remove(if extended { KeyboardModifiers::CONTROL_RIGHT } else { KeyboardModifiers::CONTROL_LEFT});
         }
-        x if x == Keycode::Alt            as u8 + KEY_RELEASED_OFFSET => { modifiers.remove(KeyboardModifiers::ALT);              }
+        x if x == Keycode::Alt            as u8 + KEY_RELEASED_OFFSET => {
+            modifiers.remove(if extended { KeyboardModifiers::ALT_GR } else { KeyboardModifiers::ALT });
+        }
         x if x == Keycode::LeftShift      as u8 + KEY_RELEASED_OFFSET => { modifiers.remove(KeyboardModifiers::SHIFT_LEFT);       }
         x if x == Keycode::RightShift     as u8 + KEY_RELEASED_OFFSET => { modifiers.remove(KeyboardModifiers::SHIFT_RIGHT);      }
         x if x == Keycode::SuperKeyLeft   as u8 + KEY_RELEASED_OFFSET => { modifiers.remove(KeyboardModifiers::SUPER_KEY_LEFT);   }
@@ -114,7 +122,11 @@ pub fn handle_keyboard_input(scan_code: u8, extended: bool) -> Result<(), &'stat
             let keycode = Keycode::from_scancode(adjusted_scan_code); 
             match keycode {
                 Some(keycode) => {
-                    let event = Event::new_keyboard_event(KeyEvent::new(keycode, action, modifiers.clone()));
+                    let mut key_event = KeyEvent::new(keycode, action, modifiers.clone());
+                    if action == KeyAction::Pressed {
+                        key_event.character = handle_dead_keys(keycode, modifiers);
+                    }
+                    let event = Event::new_keyboard_event(key_event);
                     if let Some(producer) = KEYBOARD_PRODUCER.try() {
                         producer.push(event).map_err(|_e| "keyboard input queue is full")
                     }
@@ -139,6 +151,33 @@ pub fn handle_keyboard_input(scan_code: u8, extended: bool) -> Result<(), &'stat
 }
 
 
+/// Determines the character produced by pressing the given key in the current keyboard layout,
+/// combining it with the previously-pressed dead key, if any.
+///
+/// Pressing a dead key produces no character; pressing the same dead key twice produces its accent.
+fn handle_dead_keys(keycode: Keycode, modifiers: &KeyboardModifiers) -> Option<char> {
+    // SAFE: no real race conditions with keyboard presses
+    let pending = unsafe { &mut PENDING_DEAD_KEY };
+    match keycodes_ascii::keyboard_layout().keysym(keycode, *modifiers) {
+        Some(KeySym::Dead(accent)) => {
+            if pending.take() == Some(accent) {
+                Some(accent)
+            } else {
+                *pending = Some(accent);
+                None
+            }
+        }
+        Some(KeySym::Char(c)) => match pending.take() {
+            // If the accent cannot be combined with this character, the accent is dropped.
+            Some(accent) => keycodes_ascii::compose_dead_key(accent, c).or(Some(c)),
+            None => Some(c),
+        },
+        // Keys without a character, e.g., modifier keys, don't affect a pending dead key.
+        None => None,
+    }
+}
+
+
 fn set_keyboard_led(modifiers: &KeyboardModifiers) {
     let mut led_bitmask: u8 = 0; 
     if modifiers.is_caps_lock() {
@@ -0,0 +1,383 @@
+//! The on-disk format of the key-value store, which is a log of committed transactions.
+//!
+//! The storage device is split into two equal regions, only one of which is active at a time.
+//! The first sector of each region is its header, which holds the region's generation number.
+//! The active region is the one whose header is valid and has the highest generation.
+//!
+//! After its header, the active region contains a sequence of records, each of which starts on a sector boundary
+//! and holds all operations of one committed transaction. Each record has the same generation as its region
+//! and a checksum over all of its contents, so when opening the store, all records are replayed until the first one
+//! that is invalid. A record that was only partially written when the system crashed is thus ignored,
+//! which makes each transaction atomic, and leftover records from an earlier use of the region are never replayed.
+//!
+//! Once the active region is full, the store is compacted by writing all live key-value pairs to the other region,
+//! using the next generation number. That region's header is written last,
+//! so the old region remains the active one until compaction has completed.
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    vec::Vec,
+};
+use storage_device::StorageDeviceRef;
+
+
+/// The magic number at the start of each region header, "KVRH".
+const REGION_MAGIC: u32 = 0x4856_524B;
+/// The magic number at the start of each record, "KVTX".
+const RECORD_MAGIC: u32 = 0x5854_564B;
+/// The size of a record header, which is followed by the record's operations.
+const RECORD_HEADER_LEN: usize = 24;
+/// The size of an operation's header, which is followed by its key and value.
+const OP_HEADER_LEN: usize = 7;
+/// Compaction splits the live key-value pairs into records of at most this many bytes.
+const MAX_COMPACTION_RECORD_LEN: usize = 128 * 1024;
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+/// The maximum length of a key in bytes.
+pub const MAX_KEY_LEN: usize = 256;
+/// The maximum length of a value in bytes.
+pub const MAX_VALUE_LEN: usize = 64 * 1024;
+
+
+/// A single change to the store.
+#[derive(Debug, Clone)]
+pub enum Op {
+    Put(String, Vec<u8>),
+    Delete(String),
+}
+
+impl Op {
+    fn key(&self) -> &str {
+        match self {
+            Op::Put(key, _) | Op::Delete(key) => key,
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            Op::Put(key, value) => OP_HEADER_LEN + key.len() + value.len(),
+            Op::Delete(key) => OP_HEADER_LEN + key.len(),
+        }
+    }
+
+    fn apply(&self, index: &mut BTreeMap<String, Vec<u8>>) {
+        match self {
+            Op::Put(key, value) => { index.insert(key.clone(), value.clone()); }
+            Op::Delete(key) => { index.remove(key); }
+        }
+    }
+}
+
+/// Returns an error if any of the given operations has a key or value that is too long to be stored.
+pub fn validate(ops: &[Op]) -> Result<(), &'static str> {
+    for op in ops {
+        if op.key().is_empty() {
+            return Err("kv_store: keys must not be empty");
+        }
+        if op.key().len() > MAX_KEY_LEN {
+            return Err("kv_store: key is too long");
+        }
+        if let Op::Put(_, value) = op {
+            if value.len() > MAX_VALUE_LEN {
+                return Err("kv_store: value is too long");
+            }
+        }
+    }
+    Ok(())
+}
+
+
+/// The log on a storage device, through which all changes to the store are persisted.
+pub struct Log {
+    device: StorageDeviceRef,
+    sector_size: usize,
+    /// The number of sectors in each of the two regions, including their header.
+    region_sectors: usize,
+    /// The index (0 or 1) of the active region.
+    active_region: usize,
+    /// The generation of the active region.
+    generation: u64,
+    /// The sector at which the next record will be written.
+    write_pos: usize,
+}
+
+impl Log {
+    /// Opens the log on the given `device`, and returns it along with all key-value pairs that it contains.
+    ///
+    /// If `format` is true, any existing contents of the device are discarded and an empty log is created.
+    /// Otherwise, an error is returned if the device doesn't contain a valid log.
+    pub fn open(device: StorageDeviceRef, format: bool) -> Result<(Log, BTreeMap<String, Vec<u8>>), &'static str> {
+        let (sector_size, size_in_sectors) = {
+            let dev = device.lock();
+            (dev.sector_size_in_bytes(), dev.size_in_sectors())
+        };
+        if sector_size < RECORD_HEADER_LEN {
+            return Err("kv_store: the storage device's sectors are too small");
+        }
+        let region_sectors = size_in_sectors / 2;
+        if region_sectors < 2 {
+            return Err("kv_store: the storage device is too small");
+        }
+        let mut log = Log { device, sector_size, region_sectors, active_region: 0, generation: 0, write_pos: 0 };
+
+        let generations = [log.read_region_header(0)?, log.read_region_header(1)?];
+        let newest = match generations {
+            [Some(a), Some(b)] => Some(if b > a { (1, b) } else { (0, a) }),
+            [Some(a), None] => Some((0, a)),
+            [None, Some(b)] => Some((1, b)),
+            [None, None] => None,
+        };
+
+        if format {
+            // Using a newer generation than any existing region ensures that the old contents are never replayed.
+            let generation = newest.map(|(_, g)| g + 1).unwrap_or(1);
+            log.write_region_header(0, generation)?;
+            log.active_region = 0;
+            log.generation = generation;
+            log.write_pos = log.region_start(0) + 1;
+            return Ok((log, BTreeMap::new()));
+        }
+
+        let (region, generation) = newest.ok_or("kv_store: the storage device doesn't contain a key-value store")?;
+        log.active_region = region;
+        log.generation = generation;
+        let index = log.replay()?;
+        Ok((log, index))
+    }
+
+    /// Persists the given operations as a single transaction, and applies them to the given `index`,
+    /// which must contain all key-value pairs in the log.
+    ///
+    /// If the active region doesn't have enough space left, the log is compacted first.
+    /// If an error is returned, neither the log nor the `index` were changed.
+    pub fn commit(&mut self, ops: &[Op], index: &mut BTreeMap<String, Vec<u8>>) -> Result<(), &'static str> {
+        validate(ops)?;
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let record = self.encode_record(ops, self.generation);
+        let record_sectors = record.len() / self.sector_size;
+
+        if self.write_pos + record_sectors <= self.region_end(self.active_region) {
+            self.write(&record, self.write_pos)?;
+            self.write_pos += record_sectors;
+            for op in ops {
+                op.apply(index);
+            }
+            return Ok(());
+        }
+
+        let mut compacted = index.clone();
+        for op in ops {
+            op.apply(&mut compacted);
+        }
+        self.compact(&compacted)?;
+        *index = compacted;
+        Ok(())
+    }
+
+    /// Writes all the given key-value pairs to the inactive region, and then makes it the active one.
+    fn compact(&mut self, index: &BTreeMap<String, Vec<u8>>) -> Result<(), &'static str> {
+        let region = 1 - self.active_region;
+        let generation = self.generation + 1;
+        let mut write_pos = self.region_start(region) + 1;
+
+        let mut ops = Vec::new();
+        let mut ops_len = 0;
+        let mut entries = index.iter().peekable();
+        while let Some((key, value)) = entries.next() {
+            let op = Op::Put(key.clone(), value.clone());
+            ops_len += op.encoded_len();
+            ops.push(op);
+            let next_len = entries.peek().map(|(k, v)| OP_HEADER_LEN + k.len() + v.len());
+            let is_full = next_len.map(|len| ops_len + len > MAX_COMPACTION_RECORD_LEN).unwrap_or(true);
+            if is_full {
+                let record = self.encode_record(&ops, generation);
+                let record_sectors = record.len() / self.sector_size;
+                if write_pos + record_sectors > self.region_end(region) {
+                    return Err("kv_store: the storage device is full");
+                }
+                self.write(&record, write_pos)?;
+                write_pos += record_sectors;
+                ops.clear();
+                ops_len = 0;
+            }
+        }
+
+        self.write_region_header(region, generation)?;
+        debug!("kv_store: compacted {} keys into region {} (generation {})", index.len(), region, generation);
+        self.active_region = region;
+        self.generation = generation;
+        self.write_pos = write_pos;
+        Ok(())
+    }
+
+    /// Replays all valid records in the active region, and returns the resulting key-value pairs.
+    fn replay(&mut self) -> Result<BTreeMap<String, Vec<u8>>, &'static str> {
+        let mut index = BTreeMap::new();
+        let region_end = self.region_end(self.active_region);
+        let mut pos = self.region_start(self.active_region) + 1;
+        let mut num_records = 0;
+
+        while pos < region_end {
+            let mut first = vec![0u8; self.sector_size];
+            self.read(&mut first, pos)?;
+            if read_u32(&first, 0) != RECORD_MAGIC || read_u64(&first, 4) != self.generation {
+                break;
+            }
+            let op_count = read_u32(&first, 12) as usize;
+            let payload_len = read_u32(&first, 16) as usize;
+            let record_sectors = sectors_for(RECORD_HEADER_LEN + payload_len, self.sector_size);
+            if pos + record_sectors > region_end {
+                break;
+            }
+            let mut record = first;
+            if record_sectors > 1 {
+                record.resize(record_sectors * self.sector_size, 0);
+                self.read(&mut record[self.sector_size..], pos + 1)?;
+            }
+            let crc = read_u32(&record, 20);
+            record[20..24].copy_from_slice(&[0; 4]);
+            if crc32(&record[..RECORD_HEADER_LEN + payload_len]) != crc {
+                warn!("kv_store: ignoring a corrupted or incomplete record at sector {}", pos);
+                break;
+            }
+            let ops = match decode_ops(&record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + payload_len], op_count) {
+                Some(ops) => ops,
+                None => {
+                    warn!("kv_store: ignoring a malformed record at sector {}", pos);
+                    break;
+                }
+            };
+            for op in &ops {
+                op.apply(&mut index);
+            }
+            num_records += 1;
+            pos += record_sectors;
+        }
+
+        debug!("kv_store: replayed {} records in region {} (generation {}), {} keys",
+            num_records, self.active_region, self.generation, index.len()
+        );
+        self.write_pos = pos;
+        Ok(index)
+    }
+
+    /// Encodes the given operations into a record, padded to a whole number of sectors.
+    fn encode_record(&self, ops: &[Op], generation: u64) -> Vec<u8> {
+        let payload_len: usize = ops.iter().map(|op| op.encoded_len()).sum();
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload_len);
+        record.extend_from_slice(&RECORD_MAGIC.to_le_bytes());
+        record.extend_from_slice(&generation.to_le_bytes());
+        record.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(payload_len as u32).to_le_bytes());
+        record.extend_from_slice(&[0; 4]); // the checksum, filled in below
+        for op in ops {
+            let (kind, key, value) = match op {
+                Op::Put(key, value) => (OP_PUT, key, &value[..]),
+                Op::Delete(key) => (OP_DELETE, key, &[][..]),
+            };
+            record.push(kind);
+            record.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            record.extend_from_slice(key.as_bytes());
+            record.extend_from_slice(value);
+        }
+        let crc = crc32(&record);
+        record[20..24].copy_from_slice(&crc.to_le_bytes());
+        record.resize(sectors_for(record.len(), self.sector_size) * self.sector_size, 0);
+        record
+    }
+
+    /// Returns the generation in the given region's header, or `None` if it isn't valid.
+    fn read_region_header(&self, region: usize) -> Result<Option<u64>, &'static str> {
+        let mut sector = vec![0u8; self.sector_size];
+        self.read(&mut sector, self.region_start(region))?;
+        if read_u32(&sector, 0) != REGION_MAGIC || crc32(&sector[..12]) != read_u32(&sector, 12) {
+            return Ok(None);
+        }
+        Ok(Some(read_u64(&sector, 4)))
+    }
+
+    fn write_region_header(&self, region: usize, generation: u64) -> Result<(), &'static str> {
+        let mut sector = vec![0u8; self.sector_size];
+        sector[0..4].copy_from_slice(&REGION_MAGIC.to_le_bytes());
+        sector[4..12].copy_from_slice(&generation.to_le_bytes());
+        let crc = crc32(&sector[..12]);
+        sector[12..16].copy_from_slice(&crc.to_le_bytes());
+        self.write(&sector, self.region_start(region))
+    }
+
+    fn region_start(&self, region: usize) -> usize {
+        region * self.region_sectors
+    }
+
+    fn region_end(&self, region: usize) -> usize {
+        self.region_start(region) + self.region_sectors
+    }
+
+    fn read(&self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<(), &'static str> {
+        self.device.lock().read_sectors(buffer, offset_in_sectors).map(|_| ())
+    }
+
+    fn write(&self, buffer: &[u8], offset_in_sectors: usize) -> Result<(), &'static str> {
+        self.device.lock().write_sectors(buffer, offset_in_sectors).map(|_| ())
+    }
+}
+
+
+/// Decodes exactly `op_count` operations from the given record payload,
+/// returning `None` if it's malformed.
+fn decode_ops(payload: &[u8], op_count: usize) -> Option<Vec<Op>> {
+    let mut ops = Vec::with_capacity(op_count);
+    let mut pos = 0;
+    for _ in 0..op_count {
+        let header = payload.get(pos..pos + OP_HEADER_LEN)?;
+        let kind = header[0];
+        let key_len = u16::from_le_bytes([header[1], header[2]]) as usize;
+        let value_len = read_u32(header, 3) as usize;
+        pos += OP_HEADER_LEN;
+        let key = String::from_utf8(payload.get(pos..pos + key_len)?.to_vec()).ok()?;
+        pos += key_len;
+        let value = payload.get(pos..pos + value_len)?.to_vec();
+        pos += value_len;
+        ops.push(match kind {
+            OP_PUT => Op::Put(key, value),
+            OP_DELETE => Op::Delete(key),
+            _ => return None,
+        });
+    }
+    if pos == payload.len() { Some(ops) } else { None }
+}
+
+fn sectors_for(len: usize, sector_size: usize) -> usize {
+    (len + sector_size - 1) / sector_size
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+/// Computes the standard CRC-32 (IEEE 802.3) checksum of the given bytes.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
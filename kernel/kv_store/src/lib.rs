@@ -0,0 +1,271 @@
+//! A small transactional key-value store that is persisted on a storage device,
+//! for applications and kernel components that need simple persistence without a full filesystem or database.
+//!
+//! Keys are strings and values are arbitrary bytes.
+//! The store is owned by a single service task, which is started via [`start()`] on a given storage device.
+//! All other tasks access it through this crate's functions, which send requests to the service task
+//! over a channel and wait for its reply, so they can be used from any task.
+//!
+//! Changes are made via a [`Transaction`], all of whose operations are persisted atomically once it's committed:
+//! after a crash, either all or none of them are visible.
+//! [`put()`] and [`delete()`] are shortcuts for a transaction with a single operation.
+//!
+//! All key-value pairs are also kept in memory, so reads never access the storage device.
+//! See the `journal` module for the on-disk format.
+//!
+//! Once started, the store also persists the values of all `config_registry` tunables,
+//! under keys that start with [`TUNABLE_KEY_PREFIX`], such that changed settings survive a reboot.
+//!
+//! The store registers itself with the `shutdown_manager` crate, which [`stop()`]s it before the storage device
+//! (and the I/O scheduler on top of it, if any) is quiesced.
+//!
+//! [`start()`]: fn.start.html
+//! [`Transaction`]: struct.Transaction.html
+//! [`put()`]: fn.put.html
+//! [`delete()`]: fn.delete.html
+//! [`stop()`]: fn.stop.html
+//! [`TUNABLE_KEY_PREFIX`]: constant.TUNABLE_KEY_PREFIX.html
+
+#![no_std]
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate alloc;
+extern crate spin;
+extern crate storage_device;
+extern crate async_channel;
+extern crate spawn;
+extern crate shutdown_manager;
+extern crate storage_manager;
+extern crate io_scheduler;
+extern crate config_registry;
+
+mod journal;
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use spin::Once;
+use storage_device::{StorageDevice, StorageDeviceRef};
+use async_channel::{new_channel, Sender, Receiver};
+use journal::{Log, Op};
+
+pub use journal::{MAX_KEY_LEN, MAX_VALUE_LEN};
+
+
+/// The maximum number of requests that can be waiting for the service task.
+const REQUEST_QUEUE_CAPACITY: usize = 16;
+
+/// How long the store may take to finish its in-flight transactions upon shutdown.
+const SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+/// The prefix of the keys under which the values of `config_registry` tunables are persisted,
+/// which is followed by the tunable's name. Each value is stored as a little-endian `u64`.
+pub const TUNABLE_KEY_PREFIX: &str = "config/";
+
+/// The channel to the service task, which exists once the store has been started.
+static SERVICE: Once<Sender<Request>> = Once::new();
+
+
+/// A request to the service task, which sends its result back through the `reply` channel.
+enum Request {
+    Get { key: String, reply: Sender<Option<Vec<u8>>> },
+    Keys { prefix: String, reply: Sender<Vec<String>> },
+    Commit { ops: Vec<Op>, reply: Sender<Result<(), &'static str>> },
+    Stop { reply: Sender<()> },
+}
+
+
+/// Starts the key-value store service on the given storage device, whose entire contents it uses.
+///
+/// If `format` is true, any existing contents of the device are discarded and the store starts out empty.
+/// Otherwise, the device must already contain a store, whose key-value pairs are then recovered.
+///
+/// This can only be done once; an error is returned if the store was already started.
+pub fn start(device: StorageDeviceRef, format: bool) -> Result<(), &'static str> {
+    if SERVICE.try().is_some() {
+        return Err("kv_store: the key-value store was already started");
+    }
+    // A store on top of a shared I/O scheduler must be stopped before that scheduler's pending writes are flushed.
+    let is_scheduled = (&*device.lock() as &dyn StorageDevice).is::<io_scheduler::IoScheduler>();
+    let shutdown_dependencies: &'static [&'static str] = if is_scheduled {
+        &[io_scheduler::SHUTDOWN_NAME, storage_manager::SHUTDOWN_NAME]
+    } else {
+        &[storage_manager::SHUTDOWN_NAME]
+    };
+    let (log, index) = Log::open(device, format)?;
+    let num_keys = index.len();
+    let (sender, receiver) = new_channel(REQUEST_QUEUE_CAPACITY);
+
+    let mut newly_started = false;
+    SERVICE.call_once(|| { newly_started = true; sender });
+    if !newly_started {
+        return Err("kv_store: the key-value store was already started");
+    }
+    spawn::new_task_builder(kv_store_loop, (log, index, receiver))
+        .name(String::from("kv_store"))
+        .spawn()?;
+    if let Err(e) = shutdown_manager::register("kv_store", shutdown_dependencies, SHUTDOWN_TIMEOUT_MS, stop) {
+        warn!("kv_store: couldn't register for shutdown: {}", e);
+    }
+    info!("Started key-value store with {} keys", num_keys);
+    match config_registry::set_persistence(config_registry::Persistence { load: load_tunable, save: save_tunable }) {
+        Ok(restored) => info!("kv_store: restored the persisted values of {} tunables", restored),
+        Err(e) => warn!("kv_store: couldn't persist tunables: {}", e),
+    }
+    Ok(())
+}
+
+/// Returns the persisted value of the `config_registry` tunable with the given name, if there is one.
+fn load_tunable(name: &str) -> Option<u64> {
+    let key = format!("{}{}", TUNABLE_KEY_PREFIX, name);
+    match get(&key) {
+        Ok(Some(value)) => {
+            if value.len() != 8 {
+                warn!("kv_store: ignoring the persisted value of tunable {}, which has an invalid length of {} bytes", name, value.len());
+                return None;
+            }
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&value);
+            Some(u64::from_le_bytes(bytes))
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("kv_store: couldn't load the persisted value of tunable {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Persists the given new value of the `config_registry` tunable with the given name.
+fn save_tunable(name: &str, value: u64) -> Result<(), &'static str> {
+    put(&format!("{}{}", TUNABLE_KEY_PREFIX, name), &value.to_le_bytes())
+}
+
+/// Returns whether the key-value store service has been started.
+pub fn is_started() -> bool {
+    SERVICE.try().is_some()
+}
+
+/// Stops the store from making any more changes, and returns once all previously committed transactions have been persisted.
+///
+/// Afterwards, the store can still be read, but committing a transaction returns an error.
+/// This is invoked upon shutdown.
+pub fn stop() -> Result<(), &'static str> {
+    request(|reply| Request::Stop { reply })
+}
+
+/// Returns the value of the given `key`, or `None` if it doesn't exist.
+pub fn get(key: &str) -> Result<Option<Vec<u8>>, &'static str> {
+    request(|reply| Request::Get { key: key.to_string(), reply })
+}
+
+/// Returns all keys that start with the given `prefix` in sorted order.
+/// An empty `prefix` returns all keys.
+pub fn keys(prefix: &str) -> Result<Vec<String>, &'static str> {
+    request(|reply| Request::Keys { prefix: prefix.to_string(), reply })
+}
+
+/// Sets the value of the given `key`, replacing its existing value if there is one.
+pub fn put(key: &str, value: &[u8]) -> Result<(), &'static str> {
+    let mut transaction = Transaction::new();
+    transaction.put(key, value);
+    transaction.commit()
+}
+
+/// Removes the given `key` and its value. Removing a key that doesn't exist is not an error.
+pub fn delete(key: &str) -> Result<(), &'static str> {
+    let mut transaction = Transaction::new();
+    transaction.delete(key);
+    transaction.commit()
+}
+
+
+/// A set of changes to the store, which are all made atomically once it's committed.
+///
+/// The changes are applied in the order they were added,
+/// so a later change to the same key overrides an earlier one.
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    ops: Vec<Op>,
+}
+
+impl Transaction {
+    /// Creates a new, empty transaction.
+    pub fn new() -> Transaction {
+        Transaction { ops: Vec::new() }
+    }
+
+    /// Sets the value of the given `key` once this transaction is committed.
+    pub fn put(&mut self, key: &str, value: &[u8]) -> &mut Transaction {
+        self.ops.push(Op::Put(key.to_string(), value.to_vec()));
+        self
+    }
+
+    /// Removes the given `key` once this transaction is committed.
+    pub fn delete(&mut self, key: &str) -> &mut Transaction {
+        self.ops.push(Op::Delete(key.to_string()));
+        self
+    }
+
+    /// Returns whether this transaction has no changes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Persists all changes in this transaction, and returns once they have been written to the storage device.
+    ///
+    /// If an error is returned, none of the changes were made.
+    pub fn commit(self) -> Result<(), &'static str> {
+        journal::validate(&self.ops)?;
+        let ops = self.ops;
+        request(|reply| Request::Commit { ops, reply })?
+    }
+}
+
+
+/// Sends the request built by `make_request` to the service task and waits for its reply.
+fn request<T: Send, F>(make_request: F) -> Result<T, &'static str>
+    where F: FnOnce(Sender<T>) -> Request
+{
+    let service = SERVICE.try().ok_or("kv_store: the key-value store hasn't been started")?;
+    let (reply_sender, reply_receiver) = new_channel(1);
+    service.send(make_request(reply_sender)).map_err(|_| "kv_store: failed to send request to the key-value store")?;
+    reply_receiver.receive().map_err(|_| "kv_store: failed to receive reply from the key-value store")
+}
+
+
+/// The entry point of the service task, which handles requests until the channel is disconnected.
+fn kv_store_loop(
+    (mut log, mut index, receiver): (Log, BTreeMap<String, Vec<u8>>, Receiver<Request>)
+) -> Result<(), &'static str> {
+    let mut stopped = false;
+    loop {
+        let request = receiver.receive().map_err(|_| "kv_store: the request channel was disconnected")?;
+        // A requester that has given up on its reply isn't an error for the store itself.
+        let _ = match request {
+            Request::Get { key, reply } => reply.send(index.get(&key).cloned()),
+            Request::Keys { prefix, reply } => {
+                let keys = index.range(prefix.clone()..)
+                    .take_while(|(key, _)| key.starts_with(&prefix))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                reply.send(keys)
+            }
+            Request::Commit { ops: _, reply } if stopped => reply.send(Err("kv_store: the key-value store was stopped")),
+            Request::Commit { ops, reply } => {
+                let result = log.commit(&ops, &mut index);
+                if let Err(e) = result {
+                    error!("kv_store: failed to commit transaction: {}", e);
+                }
+                reply.send(result)
+            }
+            // Requests are handled in order, so every earlier commit has already been persisted.
+            Request::Stop { reply } => {
+                stopped = true;
+                reply.send(())
+            }
+        };
+    }
+}
@@ -18,7 +18,7 @@ pub struct Cursor {
     pub offset_from_end: usize,
     /// The underlying character at the position of the cursor.
     /// It is shown when the cursor is unseen.
-    pub underlying_char: u8,
+    pub underlying_char: char,
 }
 
 impl Cursor {
@@ -91,7 +91,7 @@ impl Cursor {
                     self.color.into(),
                 );
             } else {
-                framebuffer_printer::print_ascii_character(
+                framebuffer_printer::print_character(
                     framebuffer,
                     self.underlying_char,
                     FONT_FOREGROUND_COLOR.into(),
@@ -127,12 +127,12 @@ impl Cursor {
     }
 
     /// Sets the character at the position of the cursor
-    pub fn set_underlying_char(&mut self, c: u8) {
+    pub fn set_underlying_char(&mut self, c: char) {
         self.underlying_char = c;
     }
 
     /// Gets the character at the position of the cursor
-    pub fn underlying_char(&self) -> u8 {
+    pub fn underlying_char(&self) -> char {
         self.underlying_char
     }
 }
@@ -146,7 +146,7 @@ impl Default for Cursor  {
             show: true,
             color: FONT_FOREGROUND_COLOR,
             offset_from_end: 0,
-            underlying_char: 0,
+            underlying_char: ' ',
         }
     }
 }
@@ -28,7 +28,7 @@ extern crate shapes;
 extern crate color;
 
 use core::ops::DerefMut;
-use alloc::string::{String, ToString};
+use alloc::string::String;
 use alloc::vec::Vec;
 use cursor::*;
 use text_display::TextDisplay;
@@ -42,6 +42,7 @@ use tsc::{tsc_ticks, TscTicks};
 use window::Window;
 
 pub mod cursor;
+pub mod text;
 
 pub const FONT_FOREGROUND_COLOR: Color = color::LIGHT_GREEN;
 pub const FONT_BACKGROUND_COLOR: Color = color::BLACK;
@@ -78,297 +79,135 @@ impl Terminal {
         self.text_display.get_dimensions()
     }
 
+    /// Returns the byte indices at which the display rows of the given part of the scrollback buffer begin,
+    /// given that a row begins at `start` and that `start..end` is part of a single line, i.e., has no newlines.
+    /// An empty part still occupies one row.
+    fn row_starts(&self, start: usize, end: usize) -> Vec<usize> {
+        let buffer_width = self.get_text_dimensions().0;
+        let mut rows = vec![start];
+        let mut idx = start;
+        loop {
+            let next = text::next_row_start(&self.scrollback_buffer, idx, end, buffer_width);
+            if next >= end {
+                break;
+            }
+            rows.push(next);
+            idx = next;
+        }
+        rows
+    }
+
     /// This function takes in the end index of some index in the scrollback buffer and calculates the starting index of the
     /// scrollback buffer so that a slice containing the starting and ending index would perfectly fit inside the dimensions of 
     /// text display. 
     /// If the text display's first line will display a continuation of a syntactical line in the scrollback buffer, this function 
     /// calculates the starting index so that when displayed on the text display, it preserves that line so that it looks the same
-    /// as if the whole physical line is displayed on the buffer
-    /// 
-    /// Return: starting index of the string and the cursor position(with respect to position on the screen, not in the scrollback buffer) in that order
-    fn calc_start_idx(&self, end_idx: usize) -> (usize, usize) {
-        let (buffer_width, buffer_height) = self.get_text_dimensions();
-        let mut start_idx = end_idx;
-        let result;
-        // Grabs a max-size slice of the scrollback buffer (usually does not totally fit because of newlines)
-        if end_idx > buffer_width * buffer_height {
-            result = self.scrollback_buffer.get(end_idx - buffer_width * buffer_height..end_idx);
-        } else {
-            result = self.scrollback_buffer.get(0..end_idx);
+    /// as if the whole physical line is displayed on the buffer.
+    ///
+    /// All indices are byte indices into the scrollback buffer, which are counted in display rows and columns,
+    /// such that multi-byte, wide, and combining characters are laid out the same way as the text display does.
+    fn calc_start_idx(&self, end_idx: usize) -> usize {
+        let buffer_height = self.get_text_dimensions().1;
+        if buffer_height == 0 {
+            return end_idx;
+        }
+        let text = self.scrollback_buffer.as_str();
+        // A newline right before `end_idx` is followed by an empty row only at the very end of the buffer,
+        // where the next output (and the cursor) will appear.
+        let mut line_end = end_idx;
+        if end_idx < text.len() && text[..end_idx].ends_with('\n') {
+            line_end -= 1;
         }
 
-        if let Some(slice) = result {
-            let mut total_lines = 0;
-            // Obtains a vector of indices of newlines in the slice in the REVERSE order that they occur
-            let new_line_indices: Vec<(usize, &str)> = slice.rmatch_indices('\n').collect();
-            // if there are no new lines in the slice
-            if new_line_indices.is_empty() {
-                if buffer_height * buffer_width > end_idx {
-                    return (0, end_idx);
-                } else {
-                    start_idx -= buffer_height * buffer_width; // text with no newlines will fill the entire buffer
-                    return (start_idx, buffer_height * buffer_width -1);
-                }
-            }
-
-            let mut last_line_chars = 0;
-            // Case where the last newline does not occur at the end of the slice
-            if new_line_indices[0].0 != slice.len() - 1 {
-                start_idx -= slice.len() -1 - new_line_indices[0].0;
-                total_lines += (slice.len()-1 - new_line_indices[0].0)/buffer_width + 1;
-                last_line_chars = (slice.len() -1 - new_line_indices[0].0) % buffer_width; // fix: account for more than one line
-            }
-            else {
-                start_idx -= 1;
-                total_lines += 1;
-            }
-
-            // covers everything *up to* the characters between the beginning of the slice and the first new line character
-            for i in 0..new_line_indices.len()-1 {
-                if total_lines >= buffer_height {
-                    break;
-                }
-                let num_chars = new_line_indices[i].0 - new_line_indices[i+1].0;
-                let num_lines = if (num_chars-1)%buffer_width != 0 || (num_chars -1) == 0 {
-                                    (num_chars-1) / buffer_width + 1 
-                                } else {
-                                    (num_chars-1)/buffer_width}; // using (num_chars -1) because that's the number of characters that actually show up on the screen
-                if num_chars > start_idx { // prevents subtraction overflow
-                    return (0, total_lines * buffer_width + last_line_chars);
-                }  
-                start_idx -= num_chars;
-                total_lines += num_lines;
-            }
-
-            // tracks the characters between the beginning of the slice and the first new line character
-            let first_chars = new_line_indices[new_line_indices.len() -1].0;
-            let first_chars_lines = first_chars/buffer_width + 1;
-
-            // covers the case where the text inside the new_lines_indices array overflow the text buffer 
-            if total_lines > buffer_height {
-                start_idx += (total_lines - buffer_height) * buffer_width; // adds back the overcounted lines to the starting index
-                total_lines = buffer_height;
-            // covers the case where the text between the last newline and the end of the slice overflow the text buffer
-            } else if first_chars_lines + total_lines > buffer_height {
-                let diff = buffer_height - total_lines;
-                total_lines += diff;
-                start_idx -= diff * buffer_width;
-            // covers the case where the text between the last newline and the end of the slice exactly fits the text buffer
-            } else if first_chars_lines + total_lines == buffer_height {
-                total_lines += first_chars_lines;
-                start_idx -= first_chars;
-            // covers the case where the slice fits within the text buffer (i.e. there is not enough output to fill the screen)
-            } else {
-                return (0, total_lines * buffer_width + last_line_chars); // In  the case that an end index argument corresponded to a string slice that underfits the text display
+        // Collect the starts of the rows before `end_idx` in reverse order, one line at a time, until the display is full.
+        let mut rows: Vec<usize> = Vec::new();
+        loop {
+            let line_start = text[..line_end].rfind('\n').map_or(0, |i| i + 1);
+            rows.extend(self.row_starts(line_start, line_end).iter().rev());
+            if rows.len() >= buffer_height || line_start == 0 {
+                break;
             }
-
-            // If the previous loop overcounted, this cuts off the excess string from string. Happens when there are many charcters between newlines at the beginning of the slice
-            return (start_idx, (total_lines - 1) * buffer_width + last_line_chars);
-
-        } else {
-            return (0,0); /* WARNING: should change to Option<> rather than returning (0, 0) */
-        }   
+            line_end = line_start - 1; // exclude the newline that ends the previous line
+        }
+        rows[core::cmp::min(rows.len(), buffer_height) - 1]
     }
 
     /// This function takes in the start index of some index in the scrollback buffer and calculates the end index of the
     /// scrollback buffer so that a slice containing the starting and ending index would perfectly fit inside the dimensions of 
-    /// text display. 
+    /// text display. The end index is exclusive.
+    ///
+    /// Returns `ScrollError::OffEndBound` if the remainder of the scrollback buffer fits inside the text display.
     fn calc_end_idx(&self, start_idx: usize) -> Result<usize, ScrollError> {
         let (buffer_width, buffer_height) = self.get_text_dimensions();
-        let scrollback_buffer_len = self.scrollback_buffer.len();
+        let text = self.scrollback_buffer.as_str();
         let mut end_idx = start_idx;
-        let result;
-        // Grabs a max-size slice of the scrollback buffer (usually does not totally fit because of newlines)
-        if start_idx + buffer_width * buffer_height > scrollback_buffer_len {
-            result = self.scrollback_buffer.get(start_idx..scrollback_buffer_len-1);
-        } else {
-            result = self.scrollback_buffer.get(start_idx..start_idx + buffer_width * buffer_height);
-        }
-
-        // calculate the starting index for the slice
-        if let Some(slice) = result {
-            let mut total_lines = 0;
-            // Obtains a vector of the indices of the slice where newlines occur in ascending order
-            let new_line_indices: Vec<(usize, &str)> = slice.match_indices('\n').collect();
-            // if there are no new lines in the slice
-            if new_line_indices.len() == 0 {
-                // indicates that the text is just one continuous string with no newlines and will therefore fill the buffer completely
-                end_idx += buffer_height * buffer_width;
-                if end_idx <= self.scrollback_buffer.len() -1 {
-                    return Ok(end_idx); 
-                } else {
-                    return Err(ScrollError::OffEndBound);
-                }
-            }
-
-            let mut counter = 0;
-            // Covers the case where the start idx argument corresponds to a string that does not start on a newline 
-            if new_line_indices[0].0 != 0 {
-                end_idx += new_line_indices[0].0;
-                total_lines += new_line_indices[0].0/buffer_width + 1;
-            }
-            // the characters between the last newline and the end of the slice
-            let last_line_chars = slice.len() -1 - new_line_indices[new_line_indices.len() -1].0;  
-            let num_last_lines = last_line_chars%buffer_width + 1; // +1 to account for the physical line that the last characters will take up
-
-            for i in 0..new_line_indices.len()-1 {
-                if total_lines >= buffer_height {
-                    break;
-                }
-                let num_chars = new_line_indices[i+1].0 - new_line_indices[i].0;
-                let num_lines = num_chars/buffer_width + 1;
-                end_idx += num_chars;
-                total_lines += num_lines;
-                counter += 1;
-            }
-            // covers the case where the text inside the new_line_indices array overflows the text buffer capacity            
-            if total_lines > buffer_height {
-                let num_chars = new_line_indices[counter].0 - new_line_indices[counter -1].0;
-                end_idx -= num_chars;
-                end_idx += buffer_width;
-            // covers the case where the characters between the last newline and the end of the slice overflow the text buffer capacity
-            } else if total_lines + num_last_lines >= total_lines {
-                let diff = buffer_height - total_lines;
-                end_idx += diff * buffer_width;
-            // covers the case where the entire slice exactly fits or is smaller than the text buffer capacity
-            } else {
-                end_idx += last_line_chars;
-            }
-
-            if end_idx <= self.scrollback_buffer.len() -1 {
-                return Ok(end_idx); 
-            } else {
+        for _ in 0..buffer_height {
+            if end_idx >= text.len() {
                 return Err(ScrollError::OffEndBound);
             }
+            end_idx = text::next_row_start(text, end_idx, text.len(), buffer_width);
+        }
+        if end_idx >= text.len() {
+            Err(ScrollError::OffEndBound)
         } else {
-            return Ok(self.scrollback_buffer.len() - 1) /* WARNING: maybe should return Error? */
+            Ok(end_idx)
         }
     }
 
     /// Scrolls the text display up one line
     fn scroll_up_one_line(&mut self) {
-        let buffer_width = self.get_text_dimensions().0;
-        let mut start_idx = self.scroll_start_idx;
+        let start_idx = self.scroll_start_idx;
         //indicates that the user has scrolled to the top of the page
-        if start_idx < 1 {
+        if start_idx == 0 {
             return; 
-        } else {
-            start_idx -= 1;
-        }
-        let new_start_idx;
-        let result;
-        let slice_len;
-        if buffer_width < start_idx {
-            result = self.scrollback_buffer.as_str().get(start_idx - buffer_width .. start_idx);
-            slice_len = buffer_width;
-        } else {
-            result = self.scrollback_buffer.as_str().get(0 .. start_idx);
-            slice_len = start_idx;
-        }
-        // Searches this slice for a newline
-
-        if let Some(slice) = result {
-            let index = slice.rfind('\n');   
-            new_start_idx = match index {
-                Some(index) => { start_idx - slice_len + index }, // Moves the starting index back to the position of the nearest newline back
-                None => { start_idx - slice_len}, // If no newline is found, moves the start index back by the buffer width value
-            }; // we're moving the cursor one position to the right relative to the end of the input string
-        } else {
-            return;
         }
+        // The previous row is the last row of the line (or the part of a line) before the current start.
+        let text = self.scrollback_buffer.as_str();
+        let prev_end = if text[..start_idx].ends_with('\n') { start_idx - 1 } else { start_idx };
+        let line_start = text[..prev_end].rfind('\n').map_or(0, |i| i + 1);
+        let new_start_idx = self.row_starts(line_start, prev_end).last().cloned().unwrap_or(0);
         self.scroll_start_idx = new_start_idx;
-        // Recalculates the end index after the new starting index is found
         self.is_scroll_end = false;
     }
 
     /// Scrolls the text display down one line
     fn scroll_down_one_line(&mut self) {
-        let buffer_width = self.get_text_dimensions().0;
-        let prev_start_idx;
         // Prevents the user from scrolling down if already at the bottom of the page
-        if self.is_scroll_end == true {
-            return;} 
-        prev_start_idx = self.scroll_start_idx;
-        let result = self.calc_end_idx(prev_start_idx);
-        let mut end_idx = match result {
-            Ok(end_idx) => end_idx,
-            Err(ScrollError::OffEndBound) => self.scrollback_buffer.len() -1,
-        };
-
-        // If the newly calculated end index is the bottom of the scrollback buffer, recalculates the start index and returns
-        if end_idx == self.scrollback_buffer.len() -1 {
-            self.is_scroll_end = true;
-            let new_start = self.calc_start_idx(end_idx).0;
-            self.scroll_start_idx = new_start;
+        if self.is_scroll_end {
             return;
         }
-        end_idx += 1; // Advances to the next character for the calculation
-        let new_end_idx;
-        {
-            let result;
-            let slice_len; // specifies the length of the grabbed slice
-            // Grabs a slice (the size of the buffer width at most) of the scrollback buffer that is directly below the current slice being displayed on the text display
-            if self.scrollback_buffer.len() > end_idx + buffer_width {
-                slice_len = buffer_width;
-                result = self.scrollback_buffer.as_str().get(end_idx .. end_idx + buffer_width);
-            } else {
-                slice_len = self.scrollback_buffer.len() - end_idx -1; 
-                result = self.scrollback_buffer.as_str().get(end_idx .. self.scrollback_buffer.len());
-            }
-            // Searches the grabbed slice for a newline
-            if let Some(slice) = result {
-                let index = slice.find('\n');   
-                new_end_idx = match index {
-                    Some(index) => { end_idx + index + 1}, // Moves end index forward to the next newline
-                    None => { end_idx + slice_len}, // If no newline is found, moves the end index forward by the buffer width value
-                }; 
-            } else {
-                return;
+        let buffer_width = self.get_text_dimensions().0;
+        let buffer_len = self.scrollback_buffer.len();
+        let new_start_idx = text::next_row_start(&self.scrollback_buffer, self.scroll_start_idx, buffer_len, buffer_width);
+        match self.calc_end_idx(new_start_idx) {
+            Ok(_) => self.scroll_start_idx = new_start_idx,
+            // If the rest of the scrollback buffer fits, we have reached the bottom of the page
+            Err(ScrollError::OffEndBound) => {
+                self.is_scroll_end = true;
+                self.scroll_start_idx = self.calc_start_idx(buffer_len);
             }
         }
-        // Recalculates new starting index
-        let start_idx = self.calc_start_idx(new_end_idx).0;
-        self.scroll_start_idx = start_idx;
     }
 
     /// Shifts the text display up by making the previous first line the last line displayed on the text display
     fn page_up(&mut self) {
         let new_end_idx = self.scroll_start_idx;
-        let new_start_idx = self.calc_start_idx(new_end_idx);
-        self.scroll_start_idx = new_start_idx.0;
+        self.scroll_start_idx = self.calc_start_idx(new_end_idx);
     }
 
     /// Shifts the text display down by making the previous last line the first line displayed on the text display
     fn page_down(&mut self) {
-        let start_idx = self.scroll_start_idx;
-        let result = self.calc_end_idx(start_idx);
-        let new_start_idx = match result {
-            Ok(idx) => idx+ 1, 
+        let next_page = self.calc_end_idx(self.scroll_start_idx)
+            .and_then(|new_start_idx| self.calc_end_idx(new_start_idx).map(|_| new_start_idx));
+        match next_page {
+            Ok(new_start_idx) => self.scroll_start_idx = new_start_idx,
+            // if the user page downs near the bottom of the page, it only gets a partial shift
             Err(ScrollError::OffEndBound) => {
-                let scrollback_buffer_len = self.scrollback_buffer.len();
-                let new_start_idx = self.calc_start_idx(scrollback_buffer_len).0;
-                self.scroll_start_idx = new_start_idx;
+                let buffer_len = self.scrollback_buffer.len();
+                self.scroll_start_idx = self.calc_start_idx(buffer_len);
                 self.is_scroll_end = true;
-                return;
-            },
-        };
-        let result = self.calc_end_idx(new_start_idx);
-        let new_end_idx = match result {
-            Ok(end_idx) => end_idx,
-            Err(ScrollError::OffEndBound) => {
-                let scrollback_buffer_len = self.scrollback_buffer.len();
-                let new_start_idx = self.calc_start_idx(scrollback_buffer_len).0;
-                self.scroll_start_idx = new_start_idx;
-                self.is_scroll_end = true;
-                return;
-            },
-        };
-        if new_end_idx == self.scrollback_buffer.len() -1 {
-            // if the user page downs near the bottom of the page so only gets a partial shift
-            self.is_scroll_end = true;
-            return;
+            }
         }
-        self.scroll_start_idx = new_start_idx;
     }
 
     /// Updates the text display by taking a string index and displaying as much as it starting from the passed string index (i.e. starts from the top of the display and goes down)
@@ -378,13 +217,12 @@ impl Terminal {
         let end_idx = match result {
             Ok(end_idx) => end_idx,
             Err(ScrollError::OffEndBound) => {
-                let new_end_idx = self.scrollback_buffer.len() -1;
-                let new_start_idx = self.calc_start_idx(new_end_idx).0;
-                self.scroll_start_idx = new_start_idx;
+                let new_end_idx = self.scrollback_buffer.len();
+                self.scroll_start_idx = self.calc_start_idx(new_end_idx);
                 new_end_idx
             },
         };
-        let result  = self.scrollback_buffer.get(start_idx..=end_idx); // =end_idx includes the end index in the slice
+        let result  = self.scrollback_buffer.get(self.scroll_start_idx..end_idx);
         if let Some(slice) = result {
             self.text_display.set_text(&slice);
             self.display_text()?;
@@ -403,7 +241,7 @@ impl Terminal {
 
     /// Updates the text display by taking a string index and displaying as much as it can going backwards from the passed string index (i.e. starts from the bottom of the display and goes up)
     fn update_display_backwards(&mut self, end_idx: usize) -> Result<(), &'static str> {
-        let (start_idx, _cursor_pos) = self.calc_start_idx(end_idx);
+        let start_idx = self.calc_start_idx(end_idx);
         self.scroll_start_idx = start_idx;
 
         let result = self.scrollback_buffer.get(start_idx..end_idx);
@@ -485,10 +323,9 @@ impl Terminal {
     ///
     /// After invoke this function, one must call `refresh_display` to get the updates actually showed on the screen.
     pub fn insert_char(&mut self, c: char, offset_from_end: usize) -> Result<(), &'static str> {
-        let buflen = self.scrollback_buffer.len();
-        if buflen < offset_from_end { return Err("offset_from_end is larger than length of scrollback buffer"); }
-        let insert_idx = buflen - offset_from_end;
-        self.scrollback_buffer.insert_str(insert_idx, &c.to_string());
+        let insert_idx = text::byte_index_from_end(&self.scrollback_buffer, offset_from_end)
+            .ok_or("offset_from_end is larger than length of scrollback buffer")?;
+        self.scrollback_buffer.insert(insert_idx, c);
         Ok(())
     }
 
@@ -502,10 +339,9 @@ impl Terminal {
     ///
     /// After invoke this function, one must call `refresh_display` to get the updates actually showed on the screen.
     pub fn remove_char(&mut self, offset_from_end: usize) -> Result<(), &'static str> {
-        let buflen = self.scrollback_buffer.len();
-        if buflen < offset_from_end { return Err("offset_from_end is larger than length of scrollback buffer"); }
         if offset_from_end == 0 { return Err("cannot remove character at offset_from_end == 0"); }
-        let remove_idx = buflen - offset_from_end;
+        let remove_idx = text::byte_index_from_end(&self.scrollback_buffer, offset_from_end)
+            .ok_or("offset_from_end is larger than length of scrollback buffer")?;
         self.scrollback_buffer.remove(remove_idx);
         Ok(())
     }
@@ -530,7 +366,7 @@ impl Terminal {
             self.display_cursor()?;
             self.is_scroll_end = true;
             let buffer_len = self.scrollback_buffer.len();
-            self.scroll_start_idx = self.calc_start_idx(buffer_len).0;
+            self.scroll_start_idx = self.calc_start_idx(buffer_len);
             self.cursor.enable();
         }
         Ok(())
@@ -602,23 +438,20 @@ impl Terminal {
 
     /// Display the cursor of the terminal.
     pub fn display_cursor(&mut self) -> Result<(), &'static str> {
-        // get info about the text displayable
-        let (col_num, line_num, text_next_pos) = {
-            let text_next_pos = self.text_display.get_next_index();
-            let (col_num, line_num) = self.get_text_dimensions();
-            (col_num, line_num, text_next_pos)
-        };
+        let (col_num, line_num) = self.get_text_dimensions();
 
         // return if the cursor is not in the screen
-        if text_next_pos >= col_num * line_num {
+        let cursor_idx = match text::byte_index_from_end(&self.scrollback_buffer, self.cursor.offset_from_end) {
+            Some(idx) if idx >= self.scroll_start_idx => idx,
+            _ => return Ok(()),
+        };
+
+        // calculate the cursor position, in display columns and rows rather than characters
+        let (cursor_col, cursor_line) = text::display_position(&self.scrollback_buffer, self.scroll_start_idx, cursor_idx, col_num);
+        if cursor_line >= line_num {
             return Ok(())
         }
 
-        // calculate the cursor position
-        let cursor_pos = text_next_pos - self.cursor.offset_from_end;
-        let cursor_line = cursor_pos / col_num;
-        let cursor_col = cursor_pos % col_num;
-
         // Get the bounding box that contains the displayed cursor.
         let bounding_box = {
             let coord = self.window.area().top_left;
@@ -642,8 +475,8 @@ impl Terminal {
     /// Updates the position of a cursor.
     /// # Arguments
     /// * `offset_from_end`: the position of the cursor relative to the end of text in number of characters.
-    /// * `underlying_char`: the ASCII code of the underlying character when the cursor is unseen.
-    pub fn update_cursor_pos(&mut self, offset_from_end: usize, underlying_char: u8) {
+    /// * `underlying_char`: the underlying character, which is shown when the cursor is unseen.
+    pub fn update_cursor_pos(&mut self, offset_from_end: usize, underlying_char: char) {
         self.cursor.offset_from_end = offset_from_end;
         self.cursor.underlying_char = underlying_char;
     }
@@ -0,0 +1,88 @@
+//! Functions for editing and laying out UTF-8 text in terms of characters and display columns rather than bytes.
+//!
+//! A character may occupy zero, one, or two columns on the screen, see `font::char_width()`.
+//! A *cluster* is a character together with the combining (zero-width) characters that follow it,
+//! which are displayed as a single character and should be edited as a unit, e.g., when moving the cursor.
+
+use font::char_width;
+
+
+/// Returns the byte index of the character that is `offset_from_end` characters before the end of `text`,
+/// or the length of `text` if `offset_from_end` is `0`.
+///
+/// Returns `None` if `text` has fewer than `offset_from_end` characters.
+pub fn byte_index_from_end(text: &str, offset_from_end: usize) -> Option<usize> {
+    if offset_from_end == 0 {
+        return Some(text.len());
+    }
+    text.char_indices().rev().nth(offset_from_end - 1).map(|(i, _)| i)
+}
+
+/// Returns the number of characters in the cluster that ends right before the byte index `end` of `text`,
+/// or `0` if `end` is at the beginning of `text`.
+pub fn cluster_len_before(text: &str, end: usize) -> usize {
+    let mut count = 0;
+    for c in text[..end].chars().rev() {
+        count += 1;
+        if char_width(c) != 0 {
+            break;
+        }
+    }
+    count
+}
+
+/// Returns the number of characters in the cluster that starts at the byte index `start` of `text`,
+/// or `0` if `start` is at the end of `text`.
+pub fn cluster_len_after(text: &str, start: usize) -> usize {
+    let mut chars = text[start..].chars();
+    match chars.next() {
+        Some(_) => 1 + chars.take_while(|&c| char_width(c) == 0).count(),
+        None => 0,
+    }
+}
+
+/// Returns the byte index at which the next display row begins, given that a row begins at the byte index `start`
+/// and that `text[start..limit]` is displayed in rows that are `width` columns wide.
+///
+/// A row ends after a newline, or before a character that doesn't fit into the remaining columns.
+/// Returns `limit` if the rest of the text fits into the row.
+pub fn next_row_start(text: &str, start: usize, limit: usize, width: usize) -> usize {
+    let mut column = 0;
+    for (i, c) in text[start..limit].char_indices() {
+        if c == '\n' {
+            return start + i + 1;
+        }
+        let w = char_width(c);
+        if column > 0 && column + w > width {
+            return start + i;
+        }
+        column += w;
+    }
+    limit
+}
+
+/// Returns the (column, row) at which the character at the byte index `end` of `text` is displayed,
+/// given that `text[start..]` is displayed from the first column of the first row in rows that are `width` columns wide.
+pub fn display_position(text: &str, start: usize, end: usize, width: usize) -> (usize, usize) {
+    let (mut column, mut row) = (0, 0);
+    for c in text[start..end].chars() {
+        if c == '\n' {
+            column = 0;
+            row += 1;
+            continue;
+        }
+        let w = char_width(c);
+        if column > 0 && column + w > width {
+            column = 0;
+            row += 1;
+        }
+        column += w;
+    }
+    // The character at `end` is displayed on the next row if it doesn't fit into this one.
+    let next_width = text[end..].chars().next().map_or(1, |c| core::cmp::max(char_width(c), 1));
+    if column > 0 && column + next_width > width {
+        column = 0;
+        row += 1;
+    }
+    (column, row)
+}
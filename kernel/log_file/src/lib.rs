@@ -0,0 +1,246 @@
+//! A log sink that appends log lines to a file, e.g., [`DEFAULT_LOG_FILE_PATH`].
+//!
+//! The sink is attached via [`attach()`] once the filesystem is available, and detached via [`detach()`].
+//! Since a log line may be logged from any context, e.g., an interrupt handler, the sink doesn't write to the file directly.
+//! Instead, it copies each line into a fixed-size ring buffer, which doesn't allocate or block,
+//! and a background task periodically appends the buffered lines to the file.
+//! Overly long lines are truncated.
+//! If lines are logged faster than they're written out, the newest ones are dropped,
+//! and a line stating how many were dropped is written to the file.
+//! Lines logged by the background task itself are never buffered,
+//! which prevents writing to the file from generating more lines to write.
+//!
+//! [`DEFAULT_LOG_FILE_PATH`]: constant.DEFAULT_LOG_FILE_PATH.html
+//! [`attach()`]: fn.attach.html
+//! [`detach()`]: fn.detach.html
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate irq_safety;
+extern crate logger;
+extern crate fs_node;
+extern crate memfs;
+extern crate vfs_node;
+extern crate root;
+extern crate task;
+extern crate spawn;
+extern crate sleep;
+extern crate tsc;
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use log::LevelFilter;
+use spin::{Mutex, Once};
+use irq_safety::MutexIrqSafe;
+use logger::{LogLine, LogSink};
+use fs_node::{DirRef, FileRef};
+use memfs::MemFile;
+use vfs_node::VFSDirectory;
+
+
+/// The path of the file that the kernel log is conventionally written to.
+pub const DEFAULT_LOG_FILE_PATH: &'static str = "/var/log/kernel.log";
+
+/// The name of the log sink that this crate attaches.
+const SINK_NAME: &'static str = "file";
+/// The number of bytes of log lines that can be waiting to be written out.
+const BUFFER_CAPACITY: usize = 64 * 1024;
+/// The maximum length in bytes of a buffered line, including its trailing newline.
+const MAX_LINE_LENGTH: usize = 512;
+/// How often buffered lines are written out to the file, in milliseconds.
+const FLUSH_INTERVAL_MS: u64 = 500;
+
+
+/// A ring buffer of log lines that are waiting to be written out.
+struct LineBuffer {
+    bytes: [u8; BUFFER_CAPACITY],
+    /// The index of the first buffered byte.
+    start: usize,
+    len: usize,
+    /// The number of lines that were dropped because the buffer was full.
+    dropped: usize,
+}
+
+impl LineBuffer {
+    /// Appends the given line to the buffer, or drops it if it doesn't fit.
+    fn push(&mut self, line: &[u8]) {
+        if BUFFER_CAPACITY - self.len < line.len() {
+            self.dropped += 1;
+            return;
+        }
+        for (i, byte) in line.iter().enumerate() {
+            self.bytes[(self.start + self.len + i) % BUFFER_CAPACITY] = *byte;
+        }
+        self.len += line.len();
+    }
+
+    /// Moves every buffered byte into the given vector, and returns the number of lines that were dropped.
+    fn drain_into(&mut self, out: &mut Vec<u8>) -> usize {
+        let first = core::cmp::min(self.len, BUFFER_CAPACITY - self.start);
+        out.extend_from_slice(&self.bytes[self.start .. self.start + first]);
+        out.extend_from_slice(&self.bytes[.. self.len - first]);
+        self.start = (self.start + self.len) % BUFFER_CAPACITY;
+        self.len = 0;
+        core::mem::replace(&mut self.dropped, 0)
+    }
+}
+
+static BUFFER: MutexIrqSafe<LineBuffer> = MutexIrqSafe::new(LineBuffer {
+    bytes: [0; BUFFER_CAPACITY],
+    start: 0,
+    len: 0,
+    dropped: 0,
+});
+
+/// The file that buffered lines are written to, if the sink is attached.
+static FILE: Mutex<Option<FileRef>> = Mutex::new(None);
+
+/// The ID of the background task that writes out buffered lines, whose own lines are never buffered.
+static FLUSHER_TASK_ID: AtomicUsize = AtomicUsize::new(usize::max_value());
+/// Ensures the background task is only spawned once.
+static FLUSHER_STARTED: Once<()> = Once::new();
+
+
+/// The log sink that copies each log line into the ring buffer.
+struct FileSink;
+
+static FILE_SINK: FileSink = FileSink;
+
+impl LogSink for FileSink {
+    fn write_line(&self, line: &LogLine) {
+        if task::get_my_current_task_id() == Some(FLUSHER_TASK_ID.load(Ordering::Relaxed)) {
+            return;
+        }
+        let mut bytes = [0u8; MAX_LINE_LENGTH];
+        let mut writer = TruncatingWriter { buf: &mut bytes[.. MAX_LINE_LENGTH - 1], len: 0 };
+        let _ = write!(writer, "{}", line);
+        let len = writer.len;
+        bytes[len] = b'\n';
+        BUFFER.lock().push(&bytes[..= len]);
+    }
+}
+
+/// A writer that fills a fixed-size byte buffer, silently discarding anything that doesn't fit.
+struct TruncatingWriter<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> fmt::Write for TruncatingWriter<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = core::cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len .. self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+
+/// Starts writing every log line that passes the given `level` filter to the file at the given absolute `path`,
+/// which is created along with its parent directories if it doesn't exist yet, or appended to if it does.
+///
+/// Returns an error if a log file is already attached.
+pub fn attach(path: &str, level: LevelFilter) -> Result<(), &'static str> {
+    let file = open_or_create(path)?;
+    {
+        let mut current = FILE.lock();
+        if current.is_some() {
+            return Err("log_file: a log file is already attached");
+        }
+        *current = Some(file);
+    }
+    if let Err(e) = logger::attach_sink(SINK_NAME, &FILE_SINK, level) {
+        FILE.lock().take();
+        return Err(e);
+    }
+
+    let mut spawn_result = Ok(());
+    FLUSHER_STARTED.call_once(|| {
+        spawn_result = tsc::get_tsc_frequency().and_then(|freq| {
+            let interval_ticks = freq.saturating_mul(FLUSH_INTERVAL_MS) / 1000;
+            spawn::new_task_builder(flusher_loop, interval_ticks)
+                .name(String::from("log_file_flusher"))
+                .spawn()
+        }).map(|task| FLUSHER_TASK_ID.store(task.lock().id, Ordering::Relaxed));
+    });
+    spawn_result?;
+    info!("log_file: writing log lines to {:?}", path);
+    Ok(())
+}
+
+/// Stops writing log lines to the log file, after writing out every line that is still buffered.
+///
+/// Returns an error if no log file is attached.
+pub fn detach() -> Result<(), &'static str> {
+    if !logger::detach_sink(SINK_NAME) {
+        return Err("log_file: no log file is attached");
+    }
+    let result = flush();
+    FILE.lock().take();
+    result
+}
+
+/// Returns the absolute path of the attached log file, if there is one.
+pub fn path() -> Option<String> {
+    FILE.lock().as_ref().map(|file| file.lock().get_absolute_path())
+}
+
+/// Writes out every buffered line to the log file right away.
+pub fn flush() -> Result<(), &'static str> {
+    let mut bytes = Vec::new();
+    let dropped = BUFFER.lock().drain_into(&mut bytes);
+    if dropped > 0 {
+        bytes.extend_from_slice(format!("[{} log lines were dropped]\n", dropped).as_bytes());
+    }
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    let file = FILE.lock().clone().ok_or("log_file: no log file is attached")?;
+    let mut file = file.lock();
+    let offset = file.size();
+    file.write(&bytes, offset).map(|_| ())
+}
+
+/// Returns the file at the given absolute `path`, creating it and any missing parent directories.
+fn open_or_create(path: &str) -> Result<FileRef, &'static str> {
+    if !path.starts_with('/') {
+        return Err("log_file: the log file's path must be absolute");
+    }
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    let (file_name, dir_names) = components.split_last().ok_or("log_file: the log file's path has no file name")?;
+
+    let mut dir: DirRef = root::get_root().clone();
+    for name in dir_names {
+        let existing = dir.lock().get_dir(name);
+        dir = match existing {
+            Some(child) => child,
+            None => VFSDirectory::new(String::from(*name), &dir)?,
+        };
+    }
+    let existing = dir.lock().get_file(file_name);
+    match existing {
+        Some(file) => Ok(file),
+        None => MemFile::new(String::from(*file_name), &dir),
+    }
+}
+
+fn flusher_loop(interval_ticks: u64) -> Result<(), &'static str> {
+    loop {
+        let start: u64 = tsc::tsc_ticks().into();
+        if FILE.lock().is_some() {
+            if let Err(e) = flush() {
+                error!("log_file: couldn't write log lines to the log file: {}", e);
+            }
+        }
+        sleep::sleep_until(start.saturating_add(interval_ticks))?;
+    }
+}
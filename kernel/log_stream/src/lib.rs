@@ -0,0 +1,432 @@
+//! Streams log records over TCP to remote clients, such that logs can be collected from many machines at once
+//! without a serial console attached to each one.
+//!
+//! Once started via [`start()`], a server task listens on the given TCP port of the default network interface
+//! and accepts up to `MAX_CLIENTS` clients at once.
+//! Each client subscribes to the log records it wants by sending a single line, after which
+//! every matching log record is streamed to it in real time, formatted like it is on the serial port.
+//!
+//! # Subscriptions
+//! A subscription line contains any of the following space-separated options; an empty line subscribes to everything.
+//! * `level=LEVEL`: only stream records of the given `LEVEL` or more severe, i.e., `error`, `warn`, `info`, `debug`, or `trace`.
+//! * `crates=CRATE[,CRATE...]`: only stream records logged by the given crates.
+//! * `backlog`: also stream the records that were logged before the client subscribed, as many as are still buffered.
+//!
+//! For example, from a host with `nc`:
+//! ```sh
+//! echo "level=info crates=memory,task" | nc <THESEUS_IP> 5555
+//! ```
+//!
+//! # Buffering
+//! Log records are copied into a fixed-size ring buffer when they are logged,
+//! which doesn't allocate and thus works from any context that can log.
+//! Overly long records are truncated.
+//! If a client doesn't receive records as quickly as they are logged, it misses the oldest ones,
+//! and is sent a line stating how many records it missed.
+//! Records logged by the server task itself are never buffered,
+//! which prevents sending records from generating more records, e.g., in the network driver.
+//!
+//! [`start()`]: fn.start.html
+
+#![no_std]
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate alloc;
+extern crate spin;
+extern crate irq_safety;
+extern crate logger;
+extern crate network_manager;
+extern crate smoltcp;
+#[macro_use] extern crate smoltcp_helper;
+extern crate hpet;
+extern crate spawn;
+extern crate task;
+extern crate scheduler;
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use log::{Level, LevelFilter};
+use spin::Once;
+use logger::{LogLine, LogSink};
+use irq_safety::MutexIrqSafe;
+use hpet::get_hpet;
+use network_manager::NetworkInterfaceRef;
+use smoltcp::socket::{SocketSet, SocketHandle, TcpSocket, TcpSocketBuffer, TcpState};
+use smoltcp_helper::{get_default_iface, poll_iface};
+
+
+/// The TCP port that the log streaming server listens on by default.
+pub const DEFAULT_PORT: u16 = 5555;
+
+/// The maximum number of clients that can be connected at once.
+const MAX_CLIENTS: usize = 4;
+/// The number of most-recent log records that are buffered.
+const RECORD_BUFFER_CAPACITY: usize = 256;
+/// The maximum length in bytes of a buffered record's target, i.e., its module path.
+const MAX_TARGET_LEN: usize = 48;
+/// The maximum length in bytes of a buffered record's message, including its file and line.
+const MAX_MESSAGE_LEN: usize = 208;
+/// The maximum length in bytes of a client's subscription line.
+const MAX_SUBSCRIPTION_LEN: usize = 256;
+/// The size in bytes of each client's TCP transmit buffer.
+const TX_BUFFER_SIZE: usize = 16 * 1024;
+/// The size in bytes of each client's TCP receive buffer.
+const RX_BUFFER_SIZE: usize = MAX_SUBSCRIPTION_LEN;
+
+/// The port that the server was started on, if it has been started.
+static SERVER_PORT: Once<u16> = Once::new();
+/// The ID of the server task, whose own log records are never buffered.
+static SERVER_TASK_ID: AtomicUsize = AtomicUsize::new(usize::max_value());
+
+
+/// A log record that has been copied into the ring buffer.
+#[derive(Clone, Copy)]
+struct BufferedRecord {
+    level: Level,
+    target: [u8; MAX_TARGET_LEN],
+    target_len: usize,
+    message: [u8; MAX_MESSAGE_LEN],
+    message_len: usize,
+}
+
+impl BufferedRecord {
+    fn target(&self) -> &[u8] {
+        &self.target[..self.target_len]
+    }
+}
+
+const EMPTY_RECORD: BufferedRecord = BufferedRecord {
+    level: Level::Trace,
+    target: [0; MAX_TARGET_LEN],
+    target_len: 0,
+    message: [0; MAX_MESSAGE_LEN],
+    message_len: 0,
+};
+
+/// The most recently logged records, in which record number `seq` is stored at index `seq % RECORD_BUFFER_CAPACITY`.
+struct RecordBuffer {
+    records: [BufferedRecord; RECORD_BUFFER_CAPACITY],
+    /// The sequence number of the next record to be logged.
+    next_seq: u64,
+}
+
+impl RecordBuffer {
+    /// Returns the sequence number of the oldest record that is still buffered.
+    fn oldest_seq(&self) -> u64 {
+        self.next_seq.saturating_sub(RECORD_BUFFER_CAPACITY as u64)
+    }
+}
+
+static RECORD_BUFFER: MutexIrqSafe<RecordBuffer> = MutexIrqSafe::new(RecordBuffer {
+    records: [EMPTY_RECORD; RECORD_BUFFER_CAPACITY],
+    next_seq: 0,
+});
+
+
+/// Starts the log streaming server on the given TCP `port` of the default network interface.
+///
+/// This can only be done once; an error is returned if the server was already started.
+pub fn start(port: u16) -> Result<(), &'static str> {
+    let mut newly_started = false;
+    SERVER_PORT.call_once(|| { newly_started = true; port });
+    if !newly_started {
+        return Err("the log streaming server was already started");
+    }
+
+    let iface = get_default_iface()?;
+    logger::attach_sink(SINK_NAME, &LOG_STREAM_SINK, LevelFilter::Trace)?;
+    spawn::new_task_builder(log_stream_loop, (iface, port))
+        .name(format!("log_stream_{}", port))
+        .spawn()?;
+    info!("Started log streaming server on TCP port {}", port);
+    Ok(())
+}
+
+/// Returns the TCP port that the log streaming server was started on, if it has been started.
+pub fn server_port() -> Option<u16> {
+    SERVER_PORT.try().cloned()
+}
+
+
+/// The name of the log sink that buffers records for the server to send.
+const SINK_NAME: &'static str = "log_stream";
+
+/// The log sink that copies each log line into the ring buffer.
+struct LogStreamSink;
+
+static LOG_STREAM_SINK: LogStreamSink = LogStreamSink;
+
+impl LogSink for LogStreamSink {
+    fn write_line(&self, line: &LogLine) {
+        buffer_record(line);
+    }
+}
+
+/// Copies the given log line into the ring buffer, truncating it if necessary.
+fn buffer_record(record: &LogLine) {
+    if task::get_my_current_task_id() == Some(SERVER_TASK_ID.load(Ordering::Relaxed)) {
+        return;
+    }
+
+    let mut buffer = RECORD_BUFFER.lock();
+    let index = (buffer.next_seq % RECORD_BUFFER_CAPACITY as u64) as usize;
+    buffer.next_seq += 1;
+    let entry = &mut buffer.records[index];
+    entry.level = record.level;
+
+    let mut target = TruncatingWriter::new(&mut entry.target);
+    let _ = target.write_str(record.target);
+    entry.target_len = target.len;
+
+    let mut message = TruncatingWriter::new(&mut entry.message);
+    let _ = match record.location {
+        Some((file, line)) => write!(message, "{}:{}: {}", file, line, record.args),
+        None => write!(message, "{}", record.args),
+    };
+    entry.message_len = message.len;
+}
+
+/// A writer that fills a fixed-size byte buffer, silently discarding anything that doesn't fit.
+struct TruncatingWriter<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> TruncatingWriter<'b> {
+    fn new(buf: &'b mut [u8]) -> TruncatingWriter<'b> {
+        TruncatingWriter { buf, len: 0 }
+    }
+}
+
+impl<'b> fmt::Write for TruncatingWriter<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = core::cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len .. self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+
+/// The set of log records that a client has subscribed to.
+struct Subscription {
+    /// The least severe level of records to stream.
+    level: Level,
+    /// The crates whose records should be streamed, or all crates if empty.
+    crates: Vec<String>,
+    /// Whether the records buffered before subscribing should also be streamed.
+    backlog: bool,
+}
+
+impl Subscription {
+    /// Parses a subscription line, as described in the crate-level documentation.
+    fn parse(line: &str) -> Result<Subscription, String> {
+        let mut subscription = Subscription { level: Level::Trace, crates: Vec::new(), backlog: false };
+        for option in line.split_whitespace() {
+            let mut parts = option.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("level"), Some(level)) => {
+                    subscription.level = level.parse().map_err(|_e| format!("unknown log level {:?}", level))?;
+                }
+                (Some("crates"), Some(crates)) => {
+                    subscription.crates = crates.split(',').filter(|c| !c.is_empty()).map(|c| c.to_string()).collect();
+                }
+                (Some("backlog"), None) => subscription.backlog = true,
+                _ => return Err(format!("unknown subscription option {:?}", option)),
+            }
+        }
+        Ok(subscription)
+    }
+
+    fn matches(&self, record: &BufferedRecord) -> bool {
+        if record.level > self.level {
+            return false;
+        }
+        // A record's target is its module path, which starts with the name of the crate that logged it.
+        let target = record.target();
+        self.crates.is_empty() || self.crates.iter().any(|c| {
+            let c = c.as_bytes();
+            target.starts_with(c) && (target.len() == c.len() || target[c.len()..].starts_with(b"::"))
+        })
+    }
+}
+
+impl fmt::Display for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "level={}", self.level)?;
+        if !self.crates.is_empty() {
+            write!(f, " crates={}", self.crates.join(","))?;
+        }
+        if self.backlog {
+            write!(f, " backlog")?;
+        }
+        Ok(())
+    }
+}
+
+
+/// The state of one client connection, which is reused for the next client once that connection is closed.
+struct Client {
+    handle: SocketHandle,
+    /// The part of the subscription line received so far.
+    request: Vec<u8>,
+    /// The client's subscription, once it has sent its subscription line.
+    subscription: Option<Subscription>,
+    /// The sequence number of the next record to be considered for streaming to this client.
+    next_seq: u64,
+    /// The bytes of the current line that have not yet been accepted by the socket.
+    pending: Vec<u8>,
+}
+
+impl Client {
+    fn reset(&mut self) {
+        self.request.clear();
+        self.subscription = None;
+        self.pending.clear();
+    }
+}
+
+
+/// The entry point of the log streaming server task.
+fn log_stream_loop((iface, port): (NetworkInterfaceRef, u16)) -> Result<(), &'static str> {
+    if let Some(id) = task::get_my_current_task_id() {
+        SERVER_TASK_ID.store(id, Ordering::Relaxed);
+    }
+    let startup_time = hpet_ticks!();
+
+    let mut sockets = SocketSet::new(Vec::with_capacity(MAX_CLIENTS));
+    let mut clients: Vec<Client> = (0..MAX_CLIENTS).map(|_| {
+        let rx_buffer = TcpSocketBuffer::new(vec![0; RX_BUFFER_SIZE]);
+        let tx_buffer = TcpSocketBuffer::new(vec![0; TX_BUFFER_SIZE]);
+        Client {
+            handle: sockets.add(TcpSocket::new(rx_buffer, tx_buffer)),
+            request: Vec::new(),
+            subscription: None,
+            next_seq: 0,
+            pending: Vec::new(),
+        }
+    }).collect();
+
+    loop {
+        let _packet_io_occurred = poll_iface(&iface, &mut sockets, startup_time)?;
+        for client in clients.iter_mut() {
+            service_client(&mut sockets, client, port);
+        }
+        scheduler::schedule();
+    }
+}
+
+/// Accepts, receives the subscription of, or streams records to the given `client`, depending on its state.
+fn service_client(sockets: &mut SocketSet, client: &mut Client, port: u16) {
+    let mut socket = sockets.get::<TcpSocket>(client.handle);
+    if !socket.is_open() {
+        // The previous client's connection was closed, so wait for the next client to connect.
+        client.reset();
+        if let Err(_e) = socket.listen(port) {
+            error!("log_stream: couldn't listen on port {}, error: {:?}", port, _e);
+        }
+        return;
+    }
+    if socket.state() == TcpState::CloseWait {
+        // The client closed its side of the connection, so it won't receive any more records.
+        socket.close();
+        return;
+    }
+    if !socket.may_send() {
+        return;
+    }
+
+    if client.subscription.is_none() {
+        while socket.can_recv() {
+            let mut buf = [0u8; 64];
+            match socket.recv_slice(&mut buf) {
+                Ok(n) => client.request.extend_from_slice(&buf[..n]),
+                Err(_e) => break,
+            }
+        }
+        let line_end = match client.request.iter().position(|&b| b == b'\n') {
+            Some(line_end) => line_end,
+            None if client.request.len() > MAX_SUBSCRIPTION_LEN => {
+                let _ = socket.send_slice(b"log_stream: error: subscription line was too long\n");
+                socket.close();
+                return;
+            }
+            None => return,
+        };
+        let parsed = core::str::from_utf8(&client.request[..line_end])
+            .map_err(|_e| String::from("subscription line was not valid UTF-8"))
+            .and_then(Subscription::parse);
+        match parsed {
+            Ok(subscription) => {
+                let buffer = RECORD_BUFFER.lock();
+                client.next_seq = if subscription.backlog { buffer.oldest_seq() } else { buffer.next_seq };
+                drop(buffer);
+                client.pending = format!("log_stream: subscribed to {}\n", subscription).into_bytes();
+                client.subscription = Some(subscription);
+            }
+            Err(e) => {
+                let _ = socket.send_slice(format!("log_stream: error: {}\n", e).as_bytes());
+                socket.close();
+                return;
+            }
+        }
+    }
+
+    let subscription = match client.subscription {
+        Some(ref s) => s,
+        None => return,
+    };
+    loop {
+        if !client.pending.is_empty() {
+            let sent = socket.send_slice(&client.pending).unwrap_or(0);
+            client.pending.drain(..sent);
+            if !client.pending.is_empty() {
+                // The socket's transmit buffer is full, so try again after the next poll.
+                return;
+            }
+        }
+        match next_line(subscription, &mut client.next_seq) {
+            Some(line) => client.pending = line.into_bytes(),
+            None => return,
+        }
+    }
+}
+
+/// Returns the next line to stream to a client with the given `subscription`,
+/// starting from the record numbered `*next_seq`, which is advanced past that record.
+fn next_line(subscription: &Subscription, next_seq: &mut u64) -> Option<String> {
+    let record = {
+        let buffer = RECORD_BUFFER.lock();
+        let oldest = buffer.oldest_seq();
+        if *next_seq < oldest {
+            let missed = oldest - *next_seq;
+            *next_seq = oldest;
+            return Some(format!("log_stream: missed {} records\n", missed));
+        }
+        let mut found = None;
+        while *next_seq < buffer.next_seq {
+            let record = buffer.records[(*next_seq % RECORD_BUFFER_CAPACITY as u64) as usize];
+            *next_seq += 1;
+            if subscription.matches(&record) {
+                found = Some(record);
+                break;
+            }
+        }
+        found?
+    };
+
+    let level_str = match record.level {
+        Level::Error => "[E] ",
+        Level::Warn =>  "[W] ",
+        Level::Info =>  "[I] ",
+        Level::Debug => "[D] ",
+        Level::Trace => "[T] ",
+    };
+    Some(format!("{}{}\n", level_str, String::from_utf8_lossy(&record.message[..record.message_len])))
+}
@@ -1,12 +1,36 @@
+//! The Theseus system logger, which writes every log record to any number of sinks at once.
+//!
+//! A sink is anything that implements [`LogSink`], e.g., the serial port, the screen, a network stream, or a file.
+//! Sinks are attached and detached at runtime under a unique name via [`attach_sink()`] and [`detach_sink()`],
+//! and each one has its own level filter, which can be changed via [`set_sink_level()`].
+//! The serial port sink, named `"serial"`, is attached by [`init()`];
+//! the screen sink, named `"vga"`, is attached by [`mirror_to_vga()`].
+//!
+//! [`LogSink`]: trait.LogSink.html
+//! [`attach_sink()`]: fn.attach_sink.html
+//! [`detach_sink()`]: fn.detach_sink.html
+//! [`set_sink_level()`]: fn.set_sink_level.html
+//! [`init()`]: fn.init.html
+//! [`mirror_to_vga()`]: fn.mirror_to_vga.html
+
 #![no_std]
 
+extern crate alloc;
 extern crate serial_port;
 extern crate log;
 extern crate spin;
+extern crate irq_safety;
+
+mod rate_limit;
 
-use log::{Record, Level, SetLoggerError, Metadata, Log};
+use log::{Record, Level, LevelFilter, SetLoggerError, Metadata, Log};
 use core::fmt;
+use alloc::vec::Vec;
 use spin::Once;
+use irq_safety::MutexIrqSafe;
+use rate_limit::RateLimiter;
+
+pub use rate_limit::DEFAULT_MAX_RECORDS_PER_SEC;
 
 
 /// The static logger instance, an empty struct that implements the `Log` trait.
@@ -15,9 +39,108 @@ static LOGGER: Logger = Logger { };
 /// By default, Theseus will log 
 const DEFAULT_LOG_LEVEL: Level = Level::Trace;
 
+/// The maximum number of sinks that can be attached at once.
+/// Sinks are stored in a fixed-size table, since the logger is initialized before the heap.
+pub const MAX_SINKS: usize = 8;
+
 pub type LogOutputFunc = fn(fmt::Arguments);
 static MIRROR_VGA_FUNC: Once<LogOutputFunc> = Once::new();
 
+/// Collapses repeated log records and limits how many records each call site can log per second.
+static RATE_LIMITER: MutexIrqSafe<RateLimiter> = MutexIrqSafe::new(RateLimiter::new());
+
+/// The sinks that are currently attached, in the order they were attached.
+static SINKS: MutexIrqSafe<[Option<AttachedSink>; MAX_SINKS]> = MutexIrqSafe::new([None; MAX_SINKS]);
+
+#[derive(Clone, Copy)]
+struct AttachedSink {
+    name: &'static str,
+    sink: &'static dyn LogSink,
+    level: LevelFilter,
+}
+
+
+/// One line of log output, which is given to every sink whose level filter it passes.
+pub struct LogLine<'a> {
+    pub level: Level,
+    /// The target of the log record, which is the path of the module that logged it by default.
+    pub target: &'a str,
+    /// The source file and line number that this line was logged from,
+    /// or `None` if this line is a notice from the logger itself, e.g., that the last line was repeated.
+    pub location: Option<(&'a str, u32)>,
+    pub args: fmt::Arguments<'a>,
+}
+
+impl<'a> LogLine<'a> {
+    /// Returns the short prefix that denotes this line's level, e.g., `"[E] "` for an error.
+    pub fn level_str(&self) -> &'static str {
+        match self.level {
+            Level::Error => "[E] ",
+            Level::Warn =>  "[W] ",
+            Level::Info =>  "[I] ",
+            Level::Debug => "[D] ",
+            Level::Trace => "[T] ",
+        }
+    }
+}
+
+/// Formats this line without a color or a trailing newline, e.g., `[E] kernel/memory/src/lib.rs:42: message`.
+impl<'a> fmt::Display for LogLine<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some((file, line)) => write!(f, "{}{}:{}: {}", self.level_str(), file, line, self.args),
+            None => write!(f, "{}", self.args),
+        }
+    }
+}
+
+/// A destination that log lines are written to.
+///
+/// A sink is invoked from whatever context the line was logged in, with interrupts possibly disabled,
+/// so it must not block, and it must not log anything itself.
+pub trait LogSink: Send + Sync {
+    fn write_line(&self, line: &LogLine);
+}
+
+/// The sink that writes log lines to the serial port, colored with ANSI escape sequences.
+pub struct SerialSink;
+
+impl LogSink for SerialSink {
+    fn write_line(&self, line: &LogLine) {
+        let color = match line.level {
+            Level::Error => LogColor::Red,
+            Level::Warn =>  LogColor::Yellow,
+            Level::Info =>  LogColor::Cyan,
+            Level::Debug => LogColor::Green,
+            Level::Trace => LogColor::Purple,
+        };
+        // If there was an error, there's literally nothing we can do but ignore it,
+        // because there is no other lower-level way to log errors than the serial port.
+        let _result = if line.location.is_some() {
+            serial_port::write_fmt(format_args!("{}{}{}", color.as_terminal_string(), line, LogColor::Reset.as_terminal_string()))
+        } else {
+            serial_port::write_fmt(format_args!("{}\n", line))
+        };
+    }
+}
+
+/// The sink that writes log lines to the screen via the function given to `mirror_to_vga()`.
+struct VgaSink;
+
+impl LogSink for VgaSink {
+    fn write_line(&self, line: &LogLine) {
+        if let Some(func) = MIRROR_VGA_FUNC.try() {
+            // Currently printing to the VGA terminal doesn't support ANSI color escape sequences.
+            func(format_args!("{}", line));
+        }
+    }
+}
+
+/// The sink that writes to the serial port, which `init()` attaches as `"serial"`.
+pub static SERIAL_SINK: SerialSink = SerialSink;
+static VGA_SINK: VgaSink = VgaSink;
+
+
 /// See ANSI terminal formatting schemes
 #[allow(dead_code)]
 pub enum LogColor {
@@ -49,9 +172,73 @@ impl LogColor {
     }
 }
 
-/// Call this to enable mirroring logging macros to the screen
+/// Call this to enable mirroring logging macros to the screen,
+/// which attaches a sink named `"vga"` that invokes the given function for every log line.
 pub fn mirror_to_vga(func: LogOutputFunc) {
     MIRROR_VGA_FUNC.call_once(|| func);
+    let _ = attach_sink("vga", &VGA_SINK, LevelFilter::Trace);
+}
+
+/// Attaches the given `sink` under the given unique `name`, such that every log line
+/// whose level passes the given `level` filter is written to it from now on.
+///
+/// Returns an error if a sink with that name is already attached, or if `MAX_SINKS` sinks are already attached.
+pub fn attach_sink(name: &'static str, sink: &'static dyn LogSink, level: LevelFilter) -> Result<(), &'static str> {
+    let mut sinks = SINKS.lock();
+    if sinks.iter().flatten().any(|s| s.name == name) {
+        return Err("logger: a sink with that name is already attached");
+    }
+    let slot = sinks.iter_mut().find(|s| s.is_none()).ok_or("logger: the maximum number of sinks are already attached")?;
+    *slot = Some(AttachedSink { name, sink, level });
+    Ok(())
+}
+
+/// Detaches the sink with the given `name`, such that nothing more is written to it.
+/// Returns `true` if it was found and detached.
+pub fn detach_sink(name: &str) -> bool {
+    let mut sinks = SINKS.lock();
+    match sinks.iter_mut().find(|s| s.map_or(false, |s| s.name == name)) {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Sets the level filter of the sink with the given `name`.
+///
+/// Records that are more verbose than the global log level (see [`set_log_level()`](fn.set_log_level.html))
+/// are never logged, regardless of any sink's level filter.
+pub fn set_sink_level(name: &str, level: LevelFilter) -> Result<(), &'static str> {
+    let mut sinks = SINKS.lock();
+    let sink = sinks.iter_mut().flatten().find(|s| s.name == name).ok_or("logger: no sink with that name is attached")?;
+    sink.level = level;
+    Ok(())
+}
+
+/// Returns the name and level filter of every attached sink, in the order they were attached.
+pub fn sinks() -> Vec<(&'static str, LevelFilter)> {
+    SINKS.lock().iter().flatten().map(|s| (s.name, s.level)).collect()
+}
+
+/// Sets the maximum number of `info!()`, `debug!()`, and `trace!()` records 
+/// that a single call site (source file and line) can log per second.
+/// Further records from that call site are suppressed until the next second,
+/// and the number of suppressed records is then reported.
+/// Warnings and errors are never suppressed.
+/// 
+/// A value of `0` disables rate limiting. The default is [`DEFAULT_MAX_RECORDS_PER_SEC`].
+/// 
+/// [`DEFAULT_MAX_RECORDS_PER_SEC`]: constant.DEFAULT_MAX_RECORDS_PER_SEC.html
+pub fn set_rate_limit(max_records_per_sec: u32) {
+    RATE_LIMITER.lock().max_records_per_sec = max_records_per_sec;
+}
+
+/// Sets the frequency of the TSC, which is used to measure time for rate limiting.
+/// Rate limiting is disabled until this has been invoked.
+pub fn set_timestamp_frequency(ticks_per_sec: u64) {
+    RATE_LIMITER.lock().ticks_per_sec = ticks_per_sec;
 }
 
 /// A dummy struct that exists so we can implement the Log trait's methods.
@@ -67,48 +254,64 @@ impl Log for Logger {
             return;
         }
 
-        let (level_str, color) = match record.level() {
-            Level::Error => ("[E] ", LogColor::Red),
-            Level::Warn =>  ("[W] ", LogColor::Yellow),
-            Level::Info =>  ("[I] ", LogColor::Cyan),
-            Level::Debug => ("[D] ", LogColor::Green),
-            Level::Trace => ("[T] ", LogColor::Purple),
-        };
+        let call_site = rate_limit::hash_call_site(record);
+        let record_hash = rate_limit::hash_record(record, call_site);
+        let verdict = RATE_LIMITER.lock().check(record.level(), call_site, record_hash);
 
-        let file_loc = record.file().unwrap_or("??");
-        let line_loc = record.line().unwrap_or(0);
-        let _result = serial_port::write_fmt(format_args!("{}{}{}:{}: {}{}",
-            color.as_terminal_string(),
-            level_str,
-            file_loc,
-            line_loc,
-            record.args(),
-            LogColor::Reset.as_terminal_string(),
-        ));
-        // If there was an error above, there's literally nothing we can do but ignore it,
-        // because there is no other lower-level way to log errors than the serial port.
-        
-        if let Some(func) = MIRROR_VGA_FUNC.try() {
-            // Currently printing to the VGA terminal doesn't support ANSI color escape sequences,
-            // so we exclude the first and the last elements that set those colors.
-            func(format_args!("{}{}:{}: {}",
-                level_str,
-                file_loc,
-                line_loc,
-                record.args(),
-            ));
+        if verdict.repeated > 0 {
+            write_repeated(verdict.repeated, verdict.repeated_level);
+        }
+        if !verdict.log {
+            return;
         }
+
+        let location = Some((record.file().unwrap_or("??"), record.line().unwrap_or(0)));
+        if verdict.suppressed > 0 {
+            write_line(&LogLine {
+                level: record.level(),
+                target: record.target(),
+                location,
+                args: format_args!("[{} messages from this call site were suppressed]", verdict.suppressed),
+            });
+        }
+        write_line(&LogLine { level: record.level(), target: record.target(), location, args: *record.args() });
     }
 
     fn flush(&self) {
-        // flushing the log is a no-op, since there is no write buffering yet
+        // The only buffered state is the count of repeats of the last record, which we report now.
+        let (repeated, level) = RATE_LIMITER.lock().take_repeated();
+        if repeated > 0 {
+            write_repeated(repeated, level);
+        }
+    }
+}
+
+/// Writes the given log line to every attached sink whose level filter it passes.
+fn write_line(line: &LogLine) {
+    // Copy the sinks such that the lock isn't held while they run, which allows them to attach or detach sinks.
+    let sinks = *SINKS.lock();
+    for sink in sinks.iter().flatten() {
+        if line.level <= sink.level {
+            sink.sink.write_line(line);
+        }
     }
 }
 
+/// Reports that the last logged record, which had the given level, was repeated the given number of times.
+fn write_repeated(repeated: u32, level: Level) {
+    write_line(&LogLine {
+        level,
+        target: "logger",
+        location: None,
+        args: format_args!("[last message repeated {} times]", repeated),
+    });
+}
+
 
 /// Initialize the Theseus system logger, which writes log messages to the serial port. 
 pub fn init() -> Result<(), SetLoggerError> {
     log::set_logger(&LOGGER)?;
+    let _ = attach_sink("serial", &SERIAL_SINK, LevelFilter::Trace);
     set_log_level(DEFAULT_LOG_LEVEL);
     Ok(())
 }
@@ -0,0 +1,190 @@
+//! Rate limiting of log records per call site, and collapsing of repeated log records.
+//!
+//! Consecutive identical records, i.e., ones with the same level, call site, and message,
+//! are collapsed into a single "last message repeated N times" line.
+//! In addition, each call site (source file and line) may log at most `max_records_per_sec` records
+//! less severe than `Level::Warn` per second; any further records from that call site are suppressed,
+//! and the number of suppressed records is reported with the next record from that call site that is logged.
+//! Warnings and errors are never rate limited.
+//!
+//! Rate limiting needs to know the TSC frequency, so it is only enabled once
+//! [`set_timestamp_frequency()`](../fn.set_timestamp_frequency.html) has been invoked.
+//! This doesn't allocate, since it is used before the heap is set up.
+
+use core::fmt::{self, Write};
+use log::{Level, Record};
+
+
+/// The number of records per second that each call site may log by default.
+pub const DEFAULT_MAX_RECORDS_PER_SEC: u32 = 100;
+
+/// The maximum number of call sites that are rate limited at once.
+/// When more call sites are logging at once, the least-recently-limited one is forgotten.
+const MAX_CALL_SITES: usize = 64;
+
+
+/// What to do with a record that is about to be logged.
+pub struct Verdict {
+    /// Whether the record should be logged.
+    pub log: bool,
+    /// The number of times the previously-logged record was repeated, which should be reported first.
+    pub repeated: u32,
+    /// The level of the previously-logged record, which its repeats should be reported at.
+    pub repeated_level: Level,
+    /// The number of records from this record's call site that were suppressed, which should be reported first.
+    pub suppressed: u32,
+}
+
+#[derive(Clone, Copy)]
+struct CallSite {
+    /// A hash of the call site's file and line; `0` for an unused entry.
+    key: u64,
+    /// The TSC timestamp at which the current one-second window started.
+    window_start: u64,
+    /// The number of records logged in the current window.
+    count: u32,
+    /// The number of records suppressed in the current window.
+    suppressed: u32,
+}
+
+const UNUSED_CALL_SITE: CallSite = CallSite { key: 0, window_start: 0, count: 0, suppressed: 0 };
+
+pub struct RateLimiter {
+    call_sites: [CallSite; MAX_CALL_SITES],
+    /// A hash of the most recently logged record, or `0` if there is none.
+    last_record: u64,
+    /// The number of times the most recently logged record was repeated since it was logged.
+    repeated: u32,
+    /// The level of the most recently logged record.
+    last_level: Level,
+    /// The number of records each call site may log per second, or `0` if rate limiting is disabled.
+    pub max_records_per_sec: u32,
+    /// The number of TSC ticks per second, or `0` if it's not yet known.
+    pub ticks_per_sec: u64,
+}
+
+impl RateLimiter {
+    pub const fn new() -> RateLimiter {
+        RateLimiter {
+            call_sites: [UNUSED_CALL_SITE; MAX_CALL_SITES],
+            last_record: 0,
+            repeated: 0,
+            last_level: Level::Trace,
+            max_records_per_sec: DEFAULT_MAX_RECORDS_PER_SEC,
+            ticks_per_sec: 0,
+        }
+    }
+
+    /// Decides whether the given record should be logged, given the hashes of its call site and its entire contents.
+    pub fn check(&mut self, level: Level, call_site: u64, record: u64) -> Verdict {
+        if record == self.last_record {
+            self.repeated = self.repeated.saturating_add(1);
+            return Verdict { log: false, repeated: 0, repeated_level: self.last_level, suppressed: 0 };
+        }
+        let repeated = self.repeated;
+        let repeated_level = self.last_level;
+        self.repeated = 0;
+
+        let (log, suppressed) = if level > Level::Warn && self.max_records_per_sec > 0 && self.ticks_per_sec > 0 {
+            self.check_call_site(call_site, timestamp())
+        } else {
+            (true, 0)
+        };
+        // A suppressed record can't be repeated, since it was never logged.
+        self.last_record = if log { record } else { 0 };
+        if log {
+            self.last_level = level;
+        }
+        Verdict { log, repeated, repeated_level, suppressed }
+    }
+
+    /// Returns the number of times the most recently logged record has been repeated since it was logged,
+    /// along with its level, such that it can be reported without waiting for the next distinct record to be logged.
+    pub fn take_repeated(&mut self) -> (u32, Level) {
+        let repeated = self.repeated;
+        self.repeated = 0;
+        self.last_record = 0;
+        (repeated, self.last_level)
+    }
+
+    /// Returns whether a record from the given `call_site` can be logged at time `now`,
+    /// and the number of records from that call site that were suppressed before it.
+    fn check_call_site(&mut self, call_site: u64, now: u64) -> (bool, u32) {
+        let index = match self.call_sites.iter().position(|s| s.key == call_site) {
+            Some(index) => index,
+            None => {
+                // Forget the call site whose window started the longest time ago.
+                let mut oldest = 0;
+                for (i, s) in self.call_sites.iter().enumerate() {
+                    if s.window_start < self.call_sites[oldest].window_start {
+                        oldest = i;
+                    }
+                }
+                self.call_sites[oldest] = CallSite { key: call_site, window_start: now, count: 0, suppressed: 0 };
+                oldest
+            }
+        };
+        let (max_records_per_sec, ticks_per_sec) = (self.max_records_per_sec, self.ticks_per_sec);
+        let site = &mut self.call_sites[index];
+
+        if now.wrapping_sub(site.window_start) >= ticks_per_sec {
+            let suppressed = site.suppressed;
+            *site = CallSite { key: call_site, window_start: now, count: 1, suppressed: 0 };
+            (true, suppressed)
+        } else if site.count < max_records_per_sec {
+            site.count += 1;
+            (true, 0)
+        } else {
+            site.suppressed = site.suppressed.saturating_add(1);
+            (false, 0)
+        }
+    }
+}
+
+
+/// Returns a hash of the given record's call site, i.e., its file and line.
+pub fn hash_call_site(record: &Record) -> u64 {
+    let mut hasher = FnvHasher::new();
+    let _ = hasher.write_str(record.file().unwrap_or("??"));
+    let _ = write!(hasher, ":{}", record.line().unwrap_or(0));
+    hasher.finish()
+}
+
+/// Returns a hash of the given record's level, call site, and message.
+pub fn hash_record(record: &Record, call_site: u64) -> u64 {
+    let mut hasher = FnvHasher { hash: call_site };
+    let _ = write!(hasher, "{}{}", record.level(), record.args());
+    hasher.finish()
+}
+
+/// An FNV-1a hasher that hashes everything written to it.
+struct FnvHasher {
+    hash: u64,
+}
+
+impl FnvHasher {
+    fn new() -> FnvHasher {
+        FnvHasher { hash: 0xcbf2_9ce4_8422_2325 }
+    }
+
+    fn finish(&self) -> u64 {
+        // `0` is reserved for "no record" and unused call sites.
+        if self.hash == 0 { 1 } else { self.hash }
+    }
+}
+
+impl fmt::Write for FnvHasher {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.hash ^= byte as u64;
+            self.hash = self.hash.wrapping_mul(0x100_0000_01b3);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the current TSC timestamp.
+fn timestamp() -> u64 {
+    // SAFE: just reading the TSC value
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
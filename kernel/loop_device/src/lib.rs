@@ -0,0 +1,133 @@
+//! A loopback storage device ("loop device") that presents a regular file as a block device.
+//! 
+//! This allows a filesystem image that is shipped as a file to be used like a real disk, 
+//! e.g., for mounting it or for testing partition and filesystem code against crafted images.
+//! 
+//! Each sector of a [`LoopDevice`] maps directly to the same range of bytes in its backing file,
+//! and all reads and writes go through the backing file's [`File`] trait methods.
+//! The device's size is fixed when it is created, based on the size of the file at that time;
+//! any trailing partial sector at the end of the file is not accessible.
+//! 
+//! Loop devices created via [`create_loop_device()`] are added to a single [`LoopDeviceController`],
+//! which is registered with the `storage_manager` so they can be found alongside other storage devices.
+//! 
+//! [`LoopDevice`]: struct.LoopDevice.html
+//! [`File`]: ../fs_node/trait.File.html
+//! [`create_loop_device()`]: fn.create_loop_device.html
+//! [`LoopDeviceController`]: type.LoopDeviceController.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate fs_node;
+extern crate storage_device;
+extern crate virtual_storage;
+
+use alloc::sync::Arc;
+use spin::{Mutex, Once};
+use fs_node::FileRef;
+use storage_device::StorageDevice;
+use virtual_storage::{VirtualStorageController, transfer_bounds};
+
+
+/// The size in bytes of a loop device sector.
+pub const LOOP_DEVICE_SECTOR_SIZE: usize = 512;
+
+
+/// A storage device whose contents are stored in a regular file.
+pub struct LoopDevice {
+    /// The file that holds the contents of this device.
+    file: FileRef,
+    /// The number of sectors in this device.
+    size_in_sectors: usize,
+    /// Whether writes to this device are disallowed.
+    read_only: bool,
+}
+
+impl LoopDevice {
+    /// Creates a new `LoopDevice` backed by the given `file`.
+    /// 
+    /// If `read_only` is true, all writes to the new device will fail, 
+    /// which ensures that the backing file will not be modified.
+    /// 
+    /// Returns an error if the file is smaller than one sector.
+    pub fn new(file: FileRef, read_only: bool) -> Result<LoopDevice, &'static str> {
+        let size_in_sectors = file.lock().size() / LOOP_DEVICE_SECTOR_SIZE;
+        if size_in_sectors == 0 {
+            return Err("LoopDevice::new(): backing file must be at least one sector in size");
+        }
+        Ok(LoopDevice { file, size_in_sectors, read_only })
+    }
+
+    /// Returns a reference to the file that backs this device.
+    pub fn backing_file(&self) -> &FileRef {
+        &self.file
+    }
+
+    /// Returns whether this device disallows writes.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+impl StorageDevice for LoopDevice {
+    fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        let (byte_offset, num_sectors) = transfer_bounds(self, buffer.len(), offset_in_sectors)?;
+        let bytes_read = self.file.lock().read(buffer, byte_offset)?;
+        // The file may have been truncated since this device was created, so treat missing bytes as zeroes.
+        for b in buffer[bytes_read ..].iter_mut() {
+            *b = 0;
+        }
+        Ok(num_sectors)
+    }
+
+    fn write_sectors(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        if self.read_only {
+            return Err("LoopDevice: cannot write to a read-only loop device");
+        }
+        let (byte_offset, _num_sectors) = transfer_bounds(self, buffer.len(), offset_in_sectors)?;
+        let bytes_written = self.file.lock().write(buffer, byte_offset)?;
+        Ok(bytes_written / LOOP_DEVICE_SECTOR_SIZE)
+    }
+
+    fn sector_size_in_bytes(&self) -> usize {
+        LOOP_DEVICE_SECTOR_SIZE
+    }
+
+    fn size_in_sectors(&self) -> usize {
+        self.size_in_sectors
+    }
+}
+
+
+/// The virtual storage controller that holds all of the loop devices created via [`create_loop_device()`](fn.create_loop_device.html).
+pub type LoopDeviceController = VirtualStorageController<LoopDevice>;
+
+/// The singleton controller for all loop devices, which is registered with the `storage_manager` upon first use.
+static LOOP_DEVICE_CONTROLLER: Once<Arc<Mutex<LoopDeviceController>>> = Once::new();
+
+/// Returns the controller that holds all loop devices, registering it with the `storage_manager` if needed.
+pub fn loop_device_controller() -> &'static Arc<Mutex<LoopDeviceController>> {
+    LOOP_DEVICE_CONTROLLER.call_once(LoopDeviceController::register)
+}
+
+/// Creates a new loop device backed by the given `file` and adds it to the loop device controller. 
+/// 
+/// Returns a reference to the new loop device, which can be used with `block_io` or a filesystem.
+pub fn create_loop_device(file: FileRef, read_only: bool) -> Result<Arc<Mutex<LoopDevice>>, &'static str> {
+    let loop_dev = loop_device_controller().lock().add(LoopDevice::new(file, read_only)?);
+    info!("Created loop device of {} bytes", loop_dev.lock().size_in_bytes());
+    Ok(loop_dev)
+}
+
+/// Removes the given `loop_dev` from the loop device controller, 
+/// such that its backing file will be released once all other references to it are dropped.
+/// 
+/// Returns an error if the `loop_dev` was not created via [`create_loop_device()`](fn.create_loop_device.html).
+pub fn remove_loop_device(loop_dev: &Arc<Mutex<LoopDevice>>) -> Result<(), &'static str> {
+    loop_device_controller().lock().remove(loop_dev)
+        .map(|_| ())
+        .ok_or("remove_loop_device(): loop device was not found in the loop device controller")
+}
@@ -11,18 +11,15 @@ extern crate memory;
 #[macro_use] extern crate log;
 extern crate irq_safety;
 extern crate kernel_config;
-#[cfg(target_arch = "x86_64")]
-extern crate memory_x86_64;
 extern crate memory_structs;
 extern crate rbtree;
 
 use core::ptr::Unique;
 use kernel_config::memory::{PAGE_SIZE, ENTRIES_PER_PAGE_TABLE};
-use memory::{Page, BROADCAST_TLB_SHOOTDOWN_FUNC, FrameAllocator, Frame, PhysicalAddress, VirtualAddress, EntryFlags};
+use memory::{Page, BROADCAST_TLB_SHOOTDOWN_FUNC, FrameAllocator, Frame, PhysicalAddress, VirtualAddress, EntryFlags, tlb_flush_virt_addr};
 use memory::paging::table::{Table, Level4, P4};
 use irq_safety::MutexIrqSafe;
 use memory_structs::{PageRange};
-use memory_x86_64::tlb_flush_virt_addr;
 use rbtree::RBTree;
 
 lazy_static! {
@@ -111,7 +108,7 @@ impl MapperSpillful {
             let mut allocator = allocator_ref.lock();
 
             for page in PageRange::from_virt_addr(vaddr, size).clone() {
-                let frame = allocator.allocate_frame().ok_or("MapperSpillful::map() -- out of memory trying to alloc frame")?;
+                let frame = allocator.allocate_frame().map_err(|_e| "MapperSpillful::map() -- out of memory trying to alloc frame")?;
                 let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, &mut *allocator);
                 let p2 = p3.next_table_create(page.p3_index(), top_level_flags, &mut *allocator);
                 let p1 = p2.next_table_create(page.p2_index(), top_level_flags, &mut *allocator);
@@ -15,7 +15,7 @@ extern crate irq_safety;
 // use alloc::vec::Vec;
 use core::ops::DerefMut;
 use alloc::string::String;
-use fs_node::{DirRef, WeakDirRef, File, FsNode};
+use fs_node::{DirRef, WeakDirRef, File, FsNode, BASE_FILE_MODE};
 use memory::{MappedPages, get_kernel_mmi_ref, allocate_pages_by_bytes, get_frame_allocator_ref, EntryFlags};
 use alloc::sync::Arc;
 use spin::Mutex;
@@ -32,22 +32,34 @@ pub struct MemFile {
     mp: MappedPages,
     /// The parent directory that contains this file.
     parent: WeakDirRef,
+    /// The mode bits that this file was created with.
+    mode: u16,
 }
 
 impl MemFile {
     /// Allocates writable memory space for the given `contents` and creates a new file containing that content in the given `parent` directory.
     pub fn new(name: String, parent: &DirRef) -> Result<FileRef, &'static str> {
-        let new_file = Self::from_mapped_pages(MappedPages::empty(), name, 0, parent)?;
-        Ok(new_file)
+        Self::new_with_mode(name, parent, BASE_FILE_MODE)
+    }
+
+    /// Like [`new()`](#method.new), but the new file is given the specified `mode` bits,
+    /// e.g., the current task's `Environment::default_file_mode()`.
+    pub fn new_with_mode(name: String, parent: &DirRef, mode: u16) -> Result<FileRef, &'static str> {
+        Self::create(MappedPages::empty(), name, 0, parent, mode)
     }
 
     /// Creates a new `MemFile` in the given `parent` directory with the contents of the given `mapped_pages`.
     pub fn from_mapped_pages(mapped_pages: MappedPages, name: String, size: usize, parent: &DirRef) -> Result<FileRef, &'static str> {
+        Self::create(mapped_pages, name, size, parent, BASE_FILE_MODE)
+    }
+
+    fn create(mapped_pages: MappedPages, name: String, size: usize, parent: &DirRef, mode: u16) -> Result<FileRef, &'static str> {
         let memfile = MemFile {
             name: name, 
             size: size, 
             mp: mapped_pages, 
             parent: Arc::downgrade(parent), 
+            mode: mode,
         };
         let file_ref = Arc::new(Mutex::new(memfile)) as FileRef;
         parent.lock().insert(FileOrDir::File(file_ref.clone()))?; // adds the newly created file to the tree
@@ -137,6 +149,10 @@ impl File for MemFile {
     fn as_mapping(&self) -> Result<&MappedPages, &'static str> {
         Ok(&self.mp)
     }
+
+    fn mode(&self) -> u16 {
+        self.mode
+    }
     
 }
 
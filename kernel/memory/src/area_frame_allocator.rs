@@ -7,17 +7,27 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use super::{Frame, FrameAllocator, FrameRange, PhysicalAddress, PhysicalMemoryArea};
+use super::{Frame, FrameAllocator, FrameAllocError, FrameRange, MemoryZone, PhysicalAddress, PhysicalMemoryArea};
+use super::system_frame_allocator::{FrameAllocatorBackend, Percent, free_frames_within};
+use super::telemetry;
 use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt;
 use kernel_config::memory::PAGE_SIZE;
 
+/// The maximum number of runs of freed frames that `dump_state()` lists individually.
+const MAX_DUMPED_FREED_RUNS: usize = 32;
 
-/// A stand-in for a Union
-pub enum VectorArray<T: Clone> {
-    Array((usize, [T; 32])),
+
+/// A stand-in for a Union.
+/// 
+/// Before the heap is set up, the elements are stored in a fixed-capacity array of `N` elements;
+/// afterwards, they are stored in a `Vec`, see `upgrade_to_vector()`.
+pub enum VectorArray<T: Clone, const N: usize> {
+    Array((usize, [T; N])),
     Vector(Vec<T>),
 }
-impl<T: Clone> VectorArray<T> {
+impl<T: Clone, const N: usize> VectorArray<T, N> {
     pub fn upgrade_to_vector(&mut self) {
         let new_val = { 
             match *self {
@@ -34,6 +44,65 @@ impl<T: Clone> VectorArray<T> {
         }
     }
 
+    /// Returns the valid elements of this `VectorArray` as a slice.
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            VectorArray::Array((count, arr)) => &arr[..*count],
+            VectorArray::Vector(v) => &v[..],
+        }
+    }
+
+    /// Removes and returns the element at the given `index`, shifting all elements after it to the left.
+    pub fn remove(&mut self, index: usize) -> T {
+        match self {
+            VectorArray::Array((count, arr)) => {
+                let elem = arr[index].clone();
+                for i in index .. *count - 1 {
+                    arr[i] = arr[i + 1].clone();
+                }
+                *count -= 1;
+                elem
+            }
+            VectorArray::Vector(v) => v.remove(index),
+        }
+    }
+
+    /// Inserts the given `elem` at the given `index`, shifting all elements after it to the right.
+    /// Returns an error if this is an `Array` that is already full.
+    pub fn insert(&mut self, index: usize, elem: T) -> Result<(), &'static str> {
+        match self {
+            VectorArray::Array((count, arr)) => {
+                if *count == N {
+                    return Err("array is already full");
+                }
+                for i in (index .. *count).rev() {
+                    arr[i + 1] = arr[i].clone();
+                }
+                arr[index] = elem;
+                *count += 1;
+            }
+            VectorArray::Vector(v) => v.insert(index, elem),
+        }
+        Ok(())
+    }
+
+    /// Retains only the elements for which the given closure `f` returns `true`.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        match self {
+            VectorArray::Array((count, arr)) => {
+                let mut kept = 0;
+                for i in 0 .. *count {
+                    if f(&arr[i]) {
+                        arr[kept] = arr[i].clone();
+                        kept += 1;
+                    }
+                }
+                *count = kept;
+            }
+            VectorArray::Vector(v) => v.retain(f),
+        }
+    }
+
     // pub fn iter(&self) -> ::core::slice::Iter<T> {
     //     match self {
     //         &VectorArray::Array((_count, arr)) => arr.iter(),
@@ -51,51 +120,148 @@ impl<T: Clone> VectorArray<T> {
 /// already in use.
 ///
 /// `kernel_end` and `multiboot_end` are _inclusive_ bounds.
-pub struct AreaFrameAllocator {
+/// 
+/// `N` is the maximum number of available memory areas and occupied memory areas (each) 
+/// that can be tracked before the heap is set up. 
+pub struct AreaFrameAllocator<const N: usize> {
     next_free_frame: Frame,
     current_area: Option<PhysicalMemoryArea>,
-    available: VectorArray<PhysicalMemoryArea>,
-    occupied: VectorArray<PhysicalMemoryArea>,
+    available: VectorArray<PhysicalMemoryArea, N>,
+    occupied: VectorArray<PhysicalMemoryArea, N>,
+    /// Frames that were previously allocated and have since been deallocated. 
+    /// These are handed out again before any new frames are taken from the available areas.
+    /// This can only be used after the heap has been set up (see `alloc_ready()`).
+    freed: Vec<Frame>,
+    /// Areas of physical memory that have been taken offline, see `offline_area()`.
+    offlined: Vec<OfflinedArea>,
+    /// The number of never-before-allocated frames that remain in the available areas,
+    /// excluding those in occupied areas. See `recount_fresh_frames()`.
+    fresh_frames: usize,
+    /// Frames that have been permanently removed from circulation, e.g., due to memory errors,
+    /// sorted in ascending order. See `quarantine_frame()`.
+    quarantined: Vec<Frame>,
+    /// The inclusive bounds of the never-before-allocated frames below the `Normal` zone 
+    /// that `next_free_frame` skipped over once the heap was set up, see `alloc_ready()`.
+    /// Regular allocations only take these once the `Normal` zone has run out, 
+    /// such that they remain available for devices that can only address the lower zones.
+    deferred_low_frames: Option<(Frame, Frame)>,
 }
 
-impl AreaFrameAllocator {
+/// An area of physical memory that has been taken offline,
+/// which may still contain frames that were in use at the time it was offlined.
+struct OfflinedArea {
+    /// The offlined area, which is also marked as occupied such that it will not be allocated from.
+    area: PhysicalMemoryArea,
+    /// The frames within the area that are still in use, sorted in ascending order.
+    /// These are removed once they are deallocated.
+    frames_in_use: Vec<Frame>,
+}
+
+/// Returns the inclusive frame bounds of the given page-aligned `area`. 
+fn area_frame_bounds(area: &PhysicalMemoryArea) -> Result<(Frame, Frame), &'static str> {
+    if area.size_in_bytes == 0 || area.base_addr.value() % PAGE_SIZE != 0 || area.size_in_bytes % PAGE_SIZE != 0 {
+        return Err("memory area must be nonzero in size and page-aligned");
+    }
+    Ok((Frame::containing_address(area.base_addr), Frame::containing_address(area.base_addr + (area.size_in_bytes - 1))))
+}
+
+impl<const N: usize> AreaFrameAllocator<N> {
     pub fn new(
-        available: [PhysicalMemoryArea; 32], 
+        available: [PhysicalMemoryArea; N], 
         avail_len: usize, 
-        occupied: [PhysicalMemoryArea; 32], 
+        occupied: [PhysicalMemoryArea; N], 
         occ_len: usize
-    ) -> Result<AreaFrameAllocator, &'static str> {
+    ) -> Result<AreaFrameAllocator<N>, &'static str> {
+        if avail_len > N || occ_len > N {
+            return Err("AreaFrameAllocator::new(): the number of memory areas exceeds the array capacity");
+        }
+        let (initial_available, initial_occupied) = (available, occupied);
         let mut allocator = AreaFrameAllocator {
             next_free_frame: Frame::containing_address(PhysicalAddress::zero()),
             current_area: None,
-            available: VectorArray::Array((avail_len, available)),
-            occupied: VectorArray::Array((occ_len, occupied)),
+            available: VectorArray::Array((0, available)),
+            occupied: VectorArray::Array((0, occupied)),
+            freed: Vec::new(),
+            offlined: Vec::new(),
+            fresh_frames: 0,
+            quarantined: Vec::new(),
+            deferred_low_frames: None,
         };
+        // Add the initial areas one by one, such that overlapping and adjacent areas are merged.
+        // An available area that conflicts with another can be safely ignored, but an occupied area cannot.
+        for area in initial_available[..avail_len].iter() {
+            if let Err(e) = allocator.add_area(*area, true) {
+                warn!("AreaFrameAllocator::new(): ignoring available memory area {:?}: {}", area, e);
+            }
+        }
+        for area in initial_occupied[..occ_len].iter() {
+            allocator.add_area(*area, false)?;
+        }
         allocator.select_next_area();
+        allocator.recount_fresh_frames();
         Ok(allocator)
     }
 
+    /// Adds the given `area` to the list of available or occupied memory areas.
     /// `available`: specifies whether the given `area` is an available or occupied memory area.
+    /// 
+    /// Both lists are kept normalized: the `area` is merged with all areas in the same list 
+    /// that overlap or are adjacent to it and have the same type, and each list is sorted by base address.
+    /// Occupied areas that this allocator adds itself end one byte short of their last frame
+    /// (see `reserve_frames_within()`), so in the occupied list, an area that starts one byte after another one ends
+    /// is also considered adjacent to it. This doesn't change which frames are occupied.
+    /// Offlined areas are never merged, since they must be found again by `online_area()`.
+    /// 
+    /// Returns an error if the `area` overlaps an area of a different type in either list,
+    /// since the same memory cannot be of two different types.
     pub fn add_area(&mut self, area: PhysicalMemoryArea, available: bool) -> Result<(), &'static str> {
-        // match self.available {
-        match if available { &mut self.available } else { &mut self.occupied } {
-            &mut VectorArray::Array((ref mut count, ref mut arr)) => {
-                if *count < arr.len() {
-                    arr[*count] = area;
-                    *count += 1;
-                }
-                else {
-                    error!("AreaFrameAllocator::add_area(): {} array is already full!", if available { "available" } else { "occupied" } );
-                    return Err("array is already full");
-                }
-            }
-            &mut VectorArray::Vector(ref mut v) => {
-                v.push(area);
-            }
+        let list_name = if available { "available" } else { "occupied" };
+        let area_start = area.base_addr.value();
+        let area_end = area_start + area.size_in_bytes;
+        let conflicting = self.available.as_slice().iter()
+            .chain(self.occupied.as_slice().iter())
+            .find(|other| other.typ != area.typ 
+                && other.base_addr.value() < area_end 
+                && area_start < other.base_addr.value() + other.size_in_bytes
+            );
+        if let Some(other) = conflicting {
+            error!("AreaFrameAllocator::add_area(): {} area {:?} conflicts with existing area {:?} of a different type", list_name, area, other);
+            return Err("memory area overlaps an existing memory area of a different type");
+        }
+
+        let offlined = &self.offlined;
+        let is_offlined = |a: &PhysicalMemoryArea| offlined.iter().any(|o| o.area.base_addr == a.base_addr && o.area.size_in_bytes == a.size_in_bytes);
+        let area_is_offlined = is_offlined(&area);
+        let list = if available { &mut self.available } else { &mut self.occupied };
+        let slack = if available { 0 } else { 1 };
+        let mut merged = area;
+        while !area_is_offlined {
+            let (merged_start, merged_end) = (merged.base_addr.value(), merged.base_addr.value() + merged.size_in_bytes);
+            let mergeable = list.as_slice().iter().position(|other| {
+                other.typ == merged.typ && other.acpi == merged.acpi && other.attributes == merged.attributes
+                    && other.base_addr.value() <= merged_end + slack
+                    && merged_start <= other.base_addr.value() + other.size_in_bytes + slack
+                    && !is_offlined(other)
+            });
+            let other = match mergeable {
+                Some(index) => list.remove(index),
+                None => break,
+            };
+            let end = core::cmp::max(merged_end, other.base_addr.value() + other.size_in_bytes);
+            merged.base_addr = core::cmp::min(merged.base_addr, other.base_addr);
+            merged.size_in_bytes = end - merged.base_addr.value();
+        }
+
+        let index = list.as_slice().iter()
+            .position(|other| other.base_addr > merged.base_addr)
+            .unwrap_or(list.as_slice().len());
+        if let Err(e) = list.insert(index, merged) {
+            error!("AreaFrameAllocator::add_area(): {} array is already full!", list_name);
+            return Err(e);
         }
 
         // debugging stuff below
-        trace!("AreaFrameAllocator: updated {} area: =======================================", if available { "available" } else { "occupied" });
+        trace!("AreaFrameAllocator: updated {} area: =======================================", list_name);
         match if available { &self.available } else { &self.occupied } {
             &VectorArray::Array((ref count, ref arr)) => {
                 trace!("   Array[{}]: {:?}", count, arr);
@@ -105,10 +271,61 @@ impl AreaFrameAllocator {
             }
         }
 
-
+        self.recount_fresh_frames();
         Ok(())
     }
 
+    /// Returns the inclusive bounds within which the frames have never been allocated before, in ascending order:
+    /// the deferred low-zone frames, if any, and all frames at or above `next_free_frame`.
+    /// 
+    /// Frames within these bounds are only free if they're within an available area and not within an occupied area.
+    fn fresh_bounds(&self) -> impl DoubleEndedIterator<Item = (Frame, Frame)> {
+        self.deferred_low_frames.into_iter()
+            .chain(core::iter::once((self.next_free_frame, Frame { number: usize::MAX })))
+    }
+
+    /// Returns whether the given `frame` lies within the bounds of never-before-allocated frames, see `fresh_bounds()`.
+    fn is_fresh(&self, frame: Frame) -> bool {
+        self.fresh_bounds().any(|(lo, hi)| frame >= lo && frame <= hi)
+    }
+
+    /// Recalculates the number of never-before-allocated frames that remain in the available areas,
+    /// i.e., the frames within `fresh_bounds()` that are not within an occupied area.
+    /// 
+    /// This must be invoked whenever the available or occupied areas change.
+    fn recount_fresh_frames(&mut self) {
+        let occupied = self.occupied.as_slice();
+        let mut count = 0;
+        for area in self.available.as_slice().iter().filter(|a| a.typ == 1 && a.size_in_bytes > 0) {
+            for (fresh_start, fresh_end) in self.fresh_bounds() {
+                let start = core::cmp::max(Frame::containing_address(area.base_addr), fresh_start);
+                let end = core::cmp::min(Frame::containing_address(area.base_addr + (area.size_in_bytes - 1)), fresh_end);
+                if start > end {
+                    continue;
+                }
+                let mut frames = end.number - start.number + 1;
+                for occ in occupied.iter() {
+                    // use the same inclusive end bound as `skip_occupied_frames()`
+                    let occ_start = core::cmp::max(Frame::containing_address(occ.base_addr), start);
+                    let occ_end = core::cmp::min(Frame::containing_address(occ.base_addr + occ.size_in_bytes), end);
+                    if occ_start <= occ_end {
+                        frames = frames.saturating_sub(occ_end.number - occ_start.number + 1);
+                    }
+                }
+                count += frames;
+            }
+        }
+        self.fresh_frames = count;
+    }
+
+    /// Returns the approximate number of frames that are available for allocation:
+    /// both previously-deallocated frames and never-before-allocated frames.
+    /// 
+    /// Frames held in the per-core frame caches or the pre-zeroed frame pool are not included.
+    pub fn free_frame_count(&self) -> usize {
+        self.freed.len() + self.fresh_frames
+    }
+
     fn select_next_area(&mut self) {
         self.current_area = match self.available {
             VectorArray::Array((len, ref arr)) => {
@@ -141,92 +358,559 @@ impl AreaFrameAllocator {
     }
 
     /// Determines whether or not the current `next_free_frame` is within any occupied memory area,
-    /// and advances it to the start of the next free region after the occupied area.
-    fn skip_occupied_frames(&mut self) {
-        let mut rerun = false;
-        match self.occupied {
-            VectorArray::Array((len, ref arr)) => {
-                for area in arr.iter().take(len) {
-                    let start = Frame::containing_address(area.base_addr);
-                    let end = Frame::containing_address(area.base_addr + area.size_in_bytes);
-                    if self.next_free_frame >= start && self.next_free_frame <= end {
-                        self.next_free_frame = end + 1; 
-                        trace!("AreaFrameAllocator: skipping occupied area to next frame {:?}", self.next_free_frame);
-                        rerun = true;
-                        break;
-                    }
+    /// and advances it to the start of the next free region after the occupied area(s).
+    /// 
+    /// Each iteration either advances `next_free_frame` beyond the end of an occupied area,
+    /// such that this area can never contain it again, or finishes.
+    /// Thus, this takes at most one iteration per occupied area; taking more indicates a bug, which returns an error.
+    fn skip_occupied_frames(&mut self) -> Result<(), &'static str> {
+        let occupied = self.occupied.as_slice();
+        let max_iterations = occupied.len() + 1;
+        for _ in 0..max_iterations {
+            let next_free_frame = self.next_free_frame;
+            let occupied_end = occupied.iter().find_map(|area| {
+                let start = Frame::containing_address(area.base_addr);
+                let end = Frame::containing_address(area.base_addr + area.size_in_bytes);
+                if next_free_frame >= start && next_free_frame <= end { Some(end) } else { None }
+            });
+            match occupied_end {
+                // We skipped an occupied area, so check again that we didn't skip into another occupied area.
+                Some(end) => {
+                    self.next_free_frame = end + 1;
+                    trace!("AreaFrameAllocator: skipping occupied area to next frame {:?}", self.next_free_frame);
                 }
+                None => return Ok(()),
             }
-            VectorArray::Vector(ref v) => {
-                for area in v.iter() {
-                    let start = Frame::containing_address(area.base_addr);
-                    let end = Frame::containing_address(area.base_addr + area.size_in_bytes);
-                    if self.next_free_frame >= start && self.next_free_frame <= end {
-                        self.next_free_frame = end + 1; 
-                        trace!("AreaFrameAllocator: skipping occupied area to next frame {:?}", self.next_free_frame);
-                        rerun = true;
+        }
+        error!("AreaFrameAllocator: failed to skip the occupied areas after {} iterations, at frame {:?}", max_iterations, self.next_free_frame);
+        Err("AreaFrameAllocator: failed to skip the occupied memory areas")
+    }
+
+    /// Returns the highest frame within any of the available memory areas.
+    pub(crate) fn highest_available_frame(&self) -> Option<Frame> {
+        self.available.as_slice().iter()
+            .filter(|a| a.typ == 1 && a.size_in_bytes > 0)
+            .map(|a| Frame::containing_address(a.base_addr + (a.size_in_bytes - 1)))
+            .max()
+    }
+
+    /// Returns the runs of never-before-allocated frames that remain in the available areas, in ascending order,
+    /// i.e., the frames within `fresh_bounds()` that are not within an occupied area.
+    fn fresh_runs(&self) -> Vec<FrameRange> {
+        // use the same inclusive end bound as `skip_occupied_frames()`
+        let mut occupied: Vec<(Frame, Frame)> = self.occupied.as_slice().iter()
+            .map(|occ| (Frame::containing_address(occ.base_addr), Frame::containing_address(occ.base_addr + occ.size_in_bytes)))
+            .collect();
+        occupied.sort_unstable();
+        let mut runs = Vec::new();
+        for area in self.available.as_slice().iter().filter(|a| a.typ == 1 && a.size_in_bytes > 0) {
+            for (fresh_start, fresh_end) in self.fresh_bounds() {
+                let mut start = core::cmp::max(Frame::containing_address(area.base_addr), fresh_start);
+                let end = core::cmp::min(Frame::containing_address(area.base_addr + (area.size_in_bytes - 1)), fresh_end);
+                for &(occ_start, occ_end) in occupied.iter() {
+                    if start > end {
                         break;
                     }
+                    if occ_end < start || occ_start > end {
+                        continue;
+                    }
+                    if occ_start > start {
+                        runs.push(FrameRange::new(start, occ_start - 1));
+                    }
+                    start = occ_end + 1;
+                }
+                if start <= end {
+                    runs.push(FrameRange::new(start, end));
                 }
             }
-        };
-        
-        // If we actually skipped an occupied area, then we need to rerun this again,
-        // to ensure that we didn't skip into another occupied area.
-        if rerun {
-            self.skip_occupied_frames();
         }
+        runs
     }
-}
 
-impl FrameAllocator for AreaFrameAllocator {
+    /// Removes and returns all of the free frames, both previously-deallocated and never-before-allocated ones,
+    /// as runs of contiguous frames in ascending order, such that they can be handed over to another frame allocator.
+    /// Afterwards, this allocator has no free frames left.
+    /// 
+    /// Returns an error if any frames have been quarantined or taken offline, 
+    /// since those must not be handed over.
+    pub(crate) fn take_free_frames(&mut self) -> Result<Vec<FrameRange>, &'static str> {
+        if !self.quarantined.is_empty() || !self.offlined.is_empty() {
+            return Err("AreaFrameAllocator: cannot hand over the free frames after frames were quarantined or taken offline");
+        }
+        let runs = FrameAllocatorBackend::free_runs(self);
+        self.freed.clear();
+        if let Some(highest) = self.highest_available_frame() {
+            self.next_free_frame = highest + 1;
+        }
+        self.current_area = None;
+        self.deferred_low_frames = None;
+        self.fresh_frames = 0;
+        Ok(runs)
+    }
 
-    fn allocate_frames(&mut self, num_frames: usize) -> Option<FrameRange> {
-        if num_frames == 0 { return None; }
+    /// Permanently removes the given `frame` from circulation, such that it will never be allocated again.
+    /// 
+    /// If the frame is free, it is removed immediately. If it has never been allocated before,
+    /// it is marked as an occupied area. Otherwise, the frame is currently in use,
+    /// so it will be discarded instead of reused once it is deallocated.
+    /// 
+    /// Returns `true` if the frame is currently in use.
+    /// This can only be used after the heap has been set up.
+    pub fn quarantine_frame(&mut self, frame: Frame) -> Result<bool, &'static str> {
+        let in_available_area = self.available.as_slice().iter()
+            .filter(|a| a.typ == 1 && a.size_in_bytes > 0)
+            .any(|a| frame >= Frame::containing_address(a.base_addr) && frame <= Frame::containing_address(a.base_addr + (a.size_in_bytes - 1)));
+        if !in_available_area {
+            return Err("frame is not within any available memory area");
+        }
+        let index = match self.quarantined.binary_search(&frame) {
+            Ok(_) => return Err("frame was already quarantined"),
+            Err(index) => index,
+        };
 
-        // this is just a shitty way to get contiguous frames, since right now it's really easy to get them
-        // it wastes the frames that are allocated 
+        let in_use = if let Some(index) = self.freed.iter().position(|f| *f == frame) {
+            self.freed.swap_remove(index);
+            false
+        } else if self.is_fresh(frame) && !self.occupied.as_slice().iter().any(|occ| {
+            // Use the same inclusive end bound as `skip_occupied_frames()`.
+            frame >= Frame::containing_address(occ.base_addr) && frame <= Frame::containing_address(occ.base_addr + occ.size_in_bytes)
+        }) {
+            // Use an end bound that is one byte short of the frame, to avoid also occupying the frame after it.
+            self.add_area(PhysicalMemoryArea::new(frame.start_address(), PAGE_SIZE - 1, 1, 0), false)?;
+            false
+        } else {
+            true
+        };
+        self.quarantined.insert(index, frame);
+        Ok(in_use)
+    }
 
-        if let Some(first_frame) = self.allocate_frame() {
-            let first_frame_paddr = first_frame.start_address();
+    /// Returns the frames that have been quarantined by `quarantine_frame()`, in ascending order.
+    pub fn quarantined_frames(&self) -> &[Frame] {
+        &self.quarantined
+    }
 
-            // here, we successfully got the first frame, so try to allocate the rest
-            for i in 1..num_frames {
-                if let Some(f) = self.allocate_frame() {
-                    if f.start_address() == (first_frame_paddr + (i * PAGE_SIZE)) {
-                        // still getting contiguous frames, so we're good
-                        continue;
+    /// Returns the number of frames that have been deallocated and are ready to be allocated again.
+    pub fn freed_frame_count(&self) -> usize {
+        self.freed.len()
+    }
+
+    /// Removes and returns up to `max` of the frames that have been deallocated,
+    /// e.g., such that they can be zeroed before they are allocated again.
+    pub fn take_freed_frames(&mut self, max: usize) -> Vec<Frame> {
+        let start = self.freed.len().saturating_sub(max);
+        self.freed.split_off(start)
+    }
+
+    /// Reserves between `min_frames` and `max_frames` contiguous frames (inclusive) 
+    /// that lie entirely within the given `bounds` and have never been allocated before. 
+    /// 
+    /// Unlike regular allocation, which always hands out the lowest available frame next,
+    /// the reserved frames are taken from the highest free part of `bounds`. 
+    /// The reserved frames are then marked as occupied, such that this allocator will never hand them out itself;
+    /// the caller is responsible for managing them from then on. 
+    /// 
+    /// This is used to obtain frames from a specific region of physical memory, e.g., a certain NUMA node.
+    /// Since each reservation is taken from right below the previous one in the same region, 
+    /// it is merged with that one's occupied area (see `add_area()`), so repeated reservations don't grow the occupied list.
+    /// It requires that the heap has been set up, since the list of occupied areas can still grow.
+    pub fn reserve_frames_within(&mut self, bounds: &PhysicalMemoryArea, min_frames: usize, max_frames: usize) -> Option<FrameRange> {
+        if bounds.size_in_bytes == 0 || min_frames == 0 || max_frames < min_frames {
+            return None;
+        }
+        let bounds_start = Frame::containing_address(bounds.base_addr);
+        let bounds_end = Frame::containing_address(bounds.base_addr + (bounds.size_in_bytes - 1));
+
+        // The frame bounds of an occupied area, using the same inclusive end bound as `skip_occupied_frames()`.
+        let occupied_frame_bounds = |area: &PhysicalMemoryArea| {
+            (Frame::containing_address(area.base_addr), Frame::containing_address(area.base_addr + area.size_in_bytes))
+        };
+
+        let mut reserved: Option<(Frame, Frame)> = None;
+        'areas: for area in self.available.as_slice().iter().filter(|a| a.typ == 1 && a.size_in_bytes > 0) {
+            let area_start = Frame::containing_address(area.base_addr);
+            let area_end = Frame::containing_address(area.base_addr + (area.size_in_bytes - 1));
+            for (fresh_start, fresh_end) in self.fresh_bounds().rev() {
+                let lo = core::cmp::max(core::cmp::max(bounds_start, area_start), fresh_start);
+                let mut hi = core::cmp::min(core::cmp::min(bounds_end, area_end), fresh_end);
+
+                // Walk downwards from the top of this region until we find a chunk that isn't occupied.
+                while lo <= hi {
+                    let chunk_start = core::cmp::max(lo, hi - (max_frames - 1));
+                    // Of all the occupied areas that overlap this chunk, find the one that ends the highest.
+                    let overlapping = self.occupied.as_slice().iter()
+                        .map(occupied_frame_bounds)
+                        .filter(|&(occ_start, occ_end)| occ_start <= hi && occ_end >= chunk_start)
+                        .max_by_key(|&(_occ_start, occ_end)| occ_end);
+                    match overlapping {
+                        None => {
+                            if hi.number - chunk_start.number + 1 >= min_frames {
+                                reserved = Some((chunk_start, hi));
+                                break 'areas;
+                            }
+                            break; // the remainder of this region is too small
+                        }
+                        Some((occ_start, occ_end)) => {
+                            // The frames above the highest-ending occupied area must be free.
+                            if occ_end < hi && hi.number - occ_end.number >= min_frames {
+                                reserved = Some((occ_end + 1, hi));
+                                break 'areas;
+                            }
+                            if occ_start <= lo {
+                                break;
+                            }
+                            hi = occ_start - 1;
+                        }
                     }
-                    else {
-                        // didn't get a contiguous frame, so let's try again
-                        warn!("AreaFrameAllocator::allocate_frames(): could only alloc {}/{} contiguous frames (those are wasted), trying again!", i, num_frames);
-                        return self.allocate_frames(num_frames);
+                }
+            }
+        }
+
+        let (start, end) = reserved?;
+        let num_frames = end.number - start.number + 1;
+        // Use an end bound that is one byte short of the last frame, to avoid also occupying the frame after it.
+        let reserved_area = PhysicalMemoryArea::new(start.start_address(), num_frames * PAGE_SIZE - 1, 1, 0);
+        self.add_area(reserved_area, false).ok()?;
+        Some(FrameRange::new(start, end))
+    }
+
+    /// Allocates `num_frames` contiguous frames that lie entirely within the given memory `zone`,
+    /// without falling back to any other zone. 
+    /// 
+    /// The frames are taken from the highest free part of the zone, see [`reserve_frames_within()`](#method.reserve_frames_within).
+    pub fn allocate_frames_in_zone(&mut self, zone: MemoryZone, num_frames: usize) -> Option<FrameRange> {
+        let (zone_start, zone_end) = zone.bounds();
+        let zone_area = PhysicalMemoryArea::new(PhysicalAddress::new_canonical(zone_start), zone_end - zone_start, 1, 0);
+        self.reserve_frames_within(&zone_area, num_frames, num_frames)
+    }
+
+    /// Reserves `num_frames` contiguous frames that have never been allocated before,
+    /// in which the first frame number is a multiple of `alignment_in_frames`, e.g., for huge pages.
+    /// 
+    /// Like [`reserve_frames_within()`](#method.reserve_frames_within), the reserved frames are taken 
+    /// from the highest free part of physical memory and are marked as occupied,
+    /// so the caller is responsible for managing them from then on. 
+    pub fn reserve_aligned_frames(&mut self, num_frames: usize, alignment_in_frames: usize) -> Option<FrameRange> {
+        if num_frames == 0 || alignment_in_frames == 0 {
+            return None;
+        }
+        let align_down = |frame_number: usize| frame_number - (frame_number % alignment_in_frames);
+
+        // The frame bounds of an occupied area, using the same inclusive end bound as `skip_occupied_frames()`.
+        let occupied_frame_bounds = |area: &PhysicalMemoryArea| {
+            (Frame::containing_address(area.base_addr).number, Frame::containing_address(area.base_addr + area.size_in_bytes).number)
+        };
+
+        let mut reserved: Option<usize> = None;
+        'areas: for area in self.available.as_slice().iter().rev().filter(|a| a.typ == 1 && a.size_in_bytes > 0) {
+            for (fresh_start, fresh_end) in self.fresh_bounds().rev() {
+                let lo = core::cmp::max(Frame::containing_address(area.base_addr), fresh_start).number;
+                let hi = core::cmp::min(Frame::containing_address(area.base_addr + (area.size_in_bytes - 1)), fresh_end).number;
+                if hi < lo || hi - lo + 1 < num_frames {
+                    continue;
+                }
+
+                // Walk downwards through the aligned candidate chunks in this region until we find one that isn't occupied.
+                let mut start = align_down(hi + 1 - num_frames);
+                while start >= lo {
+                    let end = start + num_frames - 1;
+                    // Of all the occupied areas that overlap this chunk, find the one that starts the lowest.
+                    let overlapping = self.occupied.as_slice().iter()
+                        .map(occupied_frame_bounds)
+                        .filter(|&(occ_start, occ_end)| occ_start <= end && occ_end >= start)
+                        .min_by_key(|&(occ_start, _occ_end)| occ_start);
+                    match overlapping {
+                        None => {
+                            reserved = Some(start);
+                            break 'areas;
+                        }
+                        Some((occ_start, _occ_end)) => {
+                            // The next candidate chunk must end below the overlapping occupied area.
+                            if occ_start < lo + num_frames {
+                                break;
+                            }
+                            start = align_down(occ_start - num_frames);
+                        }
                     }
                 }
-                else {
-                    error!("Error: AreaFrameAllocator::allocate_frames(): couldn't allocate {} contiguous frames, out of memory!", num_frames);
-                    return None;
+            }
+        }
+
+        let start = Frame { number: reserved? };
+        let end = start + (num_frames - 1);
+        // Use an end bound that is one byte short of the last frame, to avoid also occupying the frame after it.
+        let reserved_area = PhysicalMemoryArea::new(start.start_address(), num_frames * PAGE_SIZE - 1, 1, 0);
+        self.add_area(reserved_area, false).ok()?;
+        Some(FrameRange::new(start, end))
+    }
+
+    /// Brings the given `area` of physical memory online, such that its frames can be allocated.
+    /// 
+    /// The `area` can either be a new area of memory that was discovered after boot, e.g., via memory hotplug,
+    /// or an area that was previously taken offline via [`offline_area()`](#method.offline_area).
+    /// A new area must be page-aligned and must not overlap any existing available area. 
+    /// 
+    /// This can only be used after the heap has been set up.
+    pub fn online_area(&mut self, area: PhysicalMemoryArea) -> Result<(), &'static str> {
+        let (start, end) = area_frame_bounds(&area)?;
+        let overlaps = |other: &PhysicalMemoryArea| {
+            other.size_in_bytes > 0 
+                && other.base_addr.value() < area.base_addr.value() + area.size_in_bytes
+                && area.base_addr.value() < other.base_addr.value() + other.size_in_bytes
+        };
+
+        let frames_in_use = if let Some(index) = self.offlined.iter().position(|o| o.area.base_addr == area.base_addr && o.area.size_in_bytes + 1 == area.size_in_bytes) {
+            let offlined = self.offlined.remove(index);
+            let marker = offlined.area;
+            self.occupied.retain(|occ| !(occ.base_addr == marker.base_addr && occ.size_in_bytes == marker.size_in_bytes));
+            offlined.frames_in_use
+        } else {
+            if self.offlined.iter().any(|o| overlaps(&o.area)) {
+                return Err("online_area(): area partially overlaps an offlined area");
+            }
+            if self.available.as_slice().iter().any(|a| overlaps(a)) {
+                return Err("online_area(): area overlaps an existing available memory area");
+            }
+            self.add_area(PhysicalMemoryArea::new(area.base_addr, area.size_in_bytes, 1, area.acpi), true)?;
+            Vec::new()
+        };
+
+        // The never-before-allocated frames will be handed out normally, 
+        // but the other ones must be added to the freed list, except for the ones that are still in use.
+        let mut frame = start;
+        while frame <= end && frame < self.next_free_frame {
+            if !self.is_fresh(frame) && frames_in_use.binary_search(&frame).is_err() {
+                self.freed.push(frame);
+            }
+            frame += 1;
+        }
+
+        if self.current_area.is_none() {
+            self.select_next_area();
+        }
+        self.recount_fresh_frames();
+        info!("AreaFrameAllocator: onlined memory area {:?}, {} frames", area, end.number - start.number + 1);
+        Ok(())
+    }
+
+    /// Takes the given `area` of physical memory offline, such that none of its frames will be allocated again.
+    /// 
+    /// The `area` must be page-aligned, must lie within a single available memory area,
+    /// and must not overlap any occupied area (e.g., the kernel image or reserved frames).
+    /// 
+    /// Returns the frames within the area that are currently in use, 
+    /// which must be migrated elsewhere or deallocated before the area's memory can actually be removed. 
+    /// Such frames are discarded rather than reused when they are deallocated,
+    /// see [`offlined_frames_in_use()`](#method.offlined_frames_in_use).
+    /// 
+    /// This can only be used after the heap has been set up.
+    pub fn offline_area(&mut self, area: PhysicalMemoryArea) -> Result<Vec<FrameRange>, &'static str> {
+        let (start, end) = area_frame_bounds(&area)?;
+        let area_end_addr = area.base_addr.value() + area.size_in_bytes;
+        let within_available = self.available.as_slice().iter().any(|a| 
+            a.typ == 1 && a.base_addr.value() <= area.base_addr.value() && area_end_addr <= a.base_addr.value() + a.size_in_bytes
+        );
+        if !within_available {
+            return Err("offline_area(): area does not lie within a single available memory area");
+        }
+        let overlaps_occupied = self.occupied.as_slice().iter().any(|occ| {
+            let occ_start = Frame::containing_address(occ.base_addr);
+            let occ_end = Frame::containing_address(occ.base_addr + occ.size_in_bytes);
+            occ_start <= end && occ_end >= start
+        });
+        if overlaps_occupied {
+            return Err("offline_area(): area overlaps an occupied or already-offlined memory area");
+        }
+
+        // Remove the area's free frames from the freed list, such that they won't be handed out again.
+        let mut free_frames: Vec<Frame> = Vec::new();
+        self.freed.retain(|f| {
+            let in_area = *f >= start && *f <= end;
+            if in_area { free_frames.push(*f); }
+            !in_area
+        });
+        free_frames.sort_unstable();
+
+        // Every frame below `next_free_frame` that wasn't free and isn't a deferred low-zone frame is currently in use.
+        let mut frames_in_use: Vec<Frame> = Vec::new();
+        let mut in_use_ranges: Vec<FrameRange> = Vec::new();
+        let mut frame = start;
+        while frame <= end && frame < self.next_free_frame {
+            if !self.is_fresh(frame) && free_frames.binary_search(&frame).is_err() {
+                match in_use_ranges.last_mut() {
+                    Some(range) if *range.end() + 1 == frame => *range = FrameRange::new(*range.start(), frame),
+                    _ => in_use_ranges.push(FrameRange::new(frame, frame)),
                 }
+                frames_in_use.push(frame);
             }
+            frame += 1;
+        }
 
-            // here, we have allocated enough frames, and checked that they're all contiguous
-            let last_frame = first_frame + (num_frames - 1); // -1 for inclusive bound. Parenthesis needed to avoid overflow.
-            return Some(FrameRange::new(first_frame, last_frame));
+        // Use an end bound that is one byte short of the last frame, to avoid also occupying the frame after it.
+        // The marker is recorded as offlined first, such that `add_area()` doesn't merge it with an adjacent occupied area.
+        let marker = PhysicalMemoryArea::new(area.base_addr, area.size_in_bytes - 1, 1, 0);
+        self.offlined.push(OfflinedArea { area: marker, frames_in_use });
+        if let Err(e) = self.add_area(marker, false) {
+            self.offlined.pop();
+            self.freed.extend(free_frames);
+            return Err(e);
         }
+        info!("AreaFrameAllocator: offlined memory area {:?}, {} frames still in use", area, self.offlined_frames_in_use(&area).unwrap_or(0));
+        Ok(in_use_ranges)
+    }
 
-        error!("Error: AreaFrameAllocator::allocate_frames(): couldn't allocate {} contiguous frames, out of memory!", num_frames);
-        None
+    /// Returns the number of frames within the given offlined `area` that are still in use,
+    /// or `None` if the `area` was not taken offline via [`offline_area()`](#method.offline_area).
+    /// 
+    /// Once this returns `Some(0)`, the area's memory can be safely removed.
+    pub fn offlined_frames_in_use(&self, area: &PhysicalMemoryArea) -> Option<usize> {
+        self.offlined.iter()
+            .find(|o| o.area.base_addr == area.base_addr && o.area.size_in_bytes + 1 == area.size_in_bytes)
+            .map(|o| o.frames_in_use.len())
     }
 
+    /// Allocates `num_frames` frames as at most `max_segments` ranges of contiguous frames,
+    /// which need not be contiguous with each other, e.g., for a device that supports scatter-gather DMA.
+    /// 
+    /// Larger ranges are preferred: a single previously-deallocated range that can satisfy the rest of the request
+    /// is used first, otherwise the largest range available from either never-before-allocated frames
+    /// or previously-deallocated frames is used, and so on until enough frames have been allocated.
+    /// 
+    /// Returns the allocated ranges in the order they were chosen, which is roughly from largest to smallest,
+    /// or an error if `num_frames` frames could not be allocated within `max_segments` ranges,
+    /// in which case no frames are allocated.
+    pub fn allocate_frames_sg(&mut self, num_frames: usize, max_segments: usize) -> Result<Vec<FrameRange>, FrameAllocError> {
+        if num_frames == 0 || max_segments == 0 {
+            return Err(FrameAllocError::InvalidRequest);
+        }
+
+        // Group the previously-deallocated frames into contiguous runs, sorted from smallest to largest.
+        self.freed.sort_unstable();
+        let mut freed_runs: Vec<FrameRange> = Vec::new();
+        for &frame in self.freed.iter() {
+            match freed_runs.last_mut() {
+                Some(run) if *run.end() + 1 == frame => *run = FrameRange::new(*run.start(), frame),
+                _ => freed_runs.push(FrameRange::new(frame, frame)),
+            }
+        }
+        freed_runs.sort_by_key(|run| run.size_in_frames());
+
+        // Each segment is tracked along with whether it was taken from the freed frames.
+        let mut segments: Vec<(FrameRange, bool)> = Vec::new();
+        let mut remaining = num_frames;
+        while remaining > 0 {
+            if segments.len() == max_segments {
+                trace!("AreaFrameAllocator::allocate_frames_sg(): couldn't allocate {} frames in {} segments", num_frames, max_segments);
+                self.undo_allocate_frames_sg(&segments);
+                // The first segment was the largest range available.
+                return Err(FrameAllocError::Fragmented { largest_run: segments[0].0.size_in_frames() });
+            }
+            let fresh_len = self.fresh_run_len();
+            let largest_freed_len = freed_runs.last().map(|run| run.size_in_frames()).unwrap_or(0);
+
+            let segment = if let Some(index) = freed_runs.iter().position(|run| run.size_in_frames() >= remaining) {
+                // the smallest freed run that satisfies the rest of the request by itself
+                let run = freed_runs.remove(index);
+                (FrameRange::new(*run.start(), *run.start() + (remaining - 1)), true)
+            } else if fresh_len > 0 && fresh_len >= largest_freed_len {
+                let len = core::cmp::min(fresh_len, remaining);
+                let start = self.next_free_frame;
+                self.next_free_frame += len;
+                self.fresh_frames = self.fresh_frames.saturating_sub(len);
+                (FrameRange::new(start, start + (len - 1)), false)
+            } else if let Some(run) = freed_runs.pop() {
+                (run, true)
+            } else {
+                error!("AreaFrameAllocator::allocate_frames_sg(): couldn't allocate {} frames, out of memory!", num_frames);
+                self.undo_allocate_frames_sg(&segments);
+                return Err(FrameAllocError::OutOfMemory);
+            };
+            remaining -= segment.0.size_in_frames();
+            segments.push(segment);
+        }
+
+        // Only now remove the chosen previously-deallocated frames, since the allocation can no longer fail.
+        {
+            let freed_segments = segments.iter().filter(|(_, from_freed)| *from_freed).map(|(range, _)| range);
+            let mut freed_segments: Vec<&FrameRange> = freed_segments.collect();
+            freed_segments.sort_unstable_by_key(|range| *range.start());
+            self.freed.retain(|frame| {
+                freed_segments.binary_search_by(|range| {
+                    if range.end() < frame {
+                        Ordering::Less
+                    } else if range.start() > frame {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Equal
+                    }
+                }).is_err()
+            });
+        }
+        Ok(segments.into_iter().map(|(range, _)| range).collect())
+    }
+
+    /// Returns the never-before-allocated frames of a failed `allocate_frames_sg()` to the freed list.
+    /// The segments taken from the freed list were not yet removed from it, so they're already there.
+    fn undo_allocate_frames_sg(&mut self, segments: &[(FrameRange, bool)]) {
+        for (range, from_freed) in segments {
+            if !*from_freed {
+                self.freed.extend(range.clone());
+            }
+        }
+    }
+
+    /// Returns the number of contiguous never-before-allocated frames starting at `next_free_frame`,
+    /// first advancing `next_free_frame` beyond any occupied frames and to the next available area if needed.
+    fn fresh_run_len(&mut self) -> usize {
+        loop {
+            let area = match self.current_area {
+                Some(area) => area,
+                None => return 0,
+            };
+            if self.skip_occupied_frames().is_err() {
+                return 0;
+            }
+            let last_frame_in_current_area = Frame::containing_address(area.base_addr + area.size_in_bytes - 1);
+            if self.next_free_frame > last_frame_in_current_area {
+                self.select_next_area();
+                continue;
+            }
+            // The run ends right before the next occupied area that starts within the current area.
+            let start = self.next_free_frame;
+            let end = self.occupied.as_slice().iter()
+                .map(|occ| Frame::containing_address(occ.base_addr))
+                .filter(|&occ_start| occ_start > start && occ_start <= last_frame_in_current_area)
+                .min()
+                .map(|occ_start| occ_start - 1)
+                .unwrap_or(last_frame_in_current_area);
+            return end.number - start.number + 1;
+        }
+    }
+
+    /// Allocates the next never-before-allocated frame from the available memory areas,
+    /// ignoring any previously-deallocated frames. 
+    /// 
+    /// Each iteration either returns a frame or switches to the next available area,
+    /// which ends above the current one such that the current one is never selected again.
+    /// Thus, this takes at most one iteration per available area; taking more indicates a bug, which returns an error.
+    fn allocate_next_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        let max_iterations = self.available.as_slice().len() + 1;
+        for _ in 0..max_iterations {
+            let area = match self.current_area {
+                Some(area) => area,
+                None => {
+                    // the deferred low-zone frames may still be left, see `allocate_deferred_frames()`
+                    if self.deferred_low_frames.is_none() {
+                        error!("FATAL ERROR: AreaFrameAllocator: out of physical memory!!!");
+                    }
+                    return Err(FrameAllocError::OutOfMemory); // no free frames left
+                }
+            };
 
-    fn allocate_frame(&mut self) -> Option<Frame> {
-        if let Some(area) = self.current_area {
             // first, see if we need to skip beyond the current area (it may be already occupied)
-            self.skip_occupied_frames();
+            self.skip_occupied_frames().map_err(|_e| FrameAllocError::RegionConflict)?;
 
-            // "clone" the frame to return it if it's free. Frame doesn't
-            // implement Clone, but we can construct an identical frame.
-            let frame = Frame { number: self.next_free_frame.number };
+            let frame = self.next_free_frame;
 
             // the last frame of the current area
             let last_frame_in_current_area = {
@@ -235,25 +919,127 @@ impl FrameAllocator for AreaFrameAllocator {
             };
 
             if frame > last_frame_in_current_area {
-                // all frames of current area are used, switch to next area
+                // all frames of current area are used, switch to next area,
+                // and try it again with the updated `next_free_frame`
                 self.select_next_area();
             } else {
                 // frame is unused, increment `next_free_frame` and return it
                 self.next_free_frame += 1;
+                self.fresh_frames = self.fresh_frames.saturating_sub(1);
                 // trace!("AreaFrameAllocator: allocated frame {:?}", frame);
-                return Some(frame);
+                return Ok(frame);
             }
-            // `frame` was not valid, try it again with the updated `next_free_frame`
-            self.allocate_frame()
-        } else {
-            error!("FATAL ERROR: AreaFrameAllocator: out of physical memory!!!");
-            None // no free frames left
+        }
+        error!("AreaFrameAllocator: failed to allocate a frame after {} iterations, at frame {:?}", max_iterations, self.next_free_frame);
+        Err(FrameAllocError::RegionConflict)
+    }
+
+    /// Allocates `num_frames` contiguous never-before-allocated frames starting at `next_free_frame`.
+    fn allocate_next_frames(&mut self, num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+        // this is just a shitty way to get contiguous frames, since right now it's really easy to get them
+        // it wastes the frames that are allocated.
+        // Previously-deallocated frames are not used here, since they're very unlikely to be contiguous.
+
+        // The largest run of contiguous frames that we've seen so far, which is reported if we run out of frames.
+        let mut largest_run = 0;
+        let deferred = self.deferred_low_frames.is_some();
+        let out_of_frames = |e: FrameAllocError, largest_run: usize| {
+            let e = match e {
+                FrameAllocError::OutOfMemory if largest_run > 0 => FrameAllocError::Fragmented { largest_run },
+                _ => e,
+            };
+            // the caller reports the error once the deferred low-zone frames have run out too
+            if !deferred {
+                error!("Error: AreaFrameAllocator::allocate_frames(): couldn't allocate {} contiguous frames: {}", num_frames, e);
+            }
+            e
+        };
+
+        'attempts: loop {
+            let first_frame = self.allocate_next_frame().map_err(|e| out_of_frames(e, largest_run))?;
+            let first_frame_paddr = first_frame.start_address();
+
+            // here, we successfully got the first frame, so try to allocate the rest
+            for i in 1..num_frames {
+                match self.allocate_next_frame() {
+                    Ok(f) if f.start_address() == (first_frame_paddr + (i * PAGE_SIZE)) => {
+                        // still getting contiguous frames, so we're good
+                        continue;
+                    }
+                    Ok(_f) => {
+                        // didn't get a contiguous frame, so let's try again
+                        largest_run = core::cmp::max(largest_run, i);
+                        warn!("AreaFrameAllocator::allocate_frames(): could only alloc {}/{} contiguous frames (those are wasted), trying again!", i, num_frames);
+                        telemetry::record_contiguous_retry();
+                        continue 'attempts;
+                    }
+                    Err(e) => return Err(out_of_frames(e, core::cmp::max(largest_run, i))),
+                }
+            }
+
+            // here, we have allocated enough frames, and checked that they're all contiguous
+            let last_frame = first_frame + (num_frames - 1); // -1 for inclusive bound. Parenthesis needed to avoid overflow.
+            return Ok(FrameRange::new(first_frame, last_frame));
+        }
+    }
+
+    /// Allocates `num_frames` contiguous frames from the deferred low-zone frames, if there are any,
+    /// preferring the `Dma32` zone over the `Dma` zone.
+    /// 
+    /// This is the fallback for regular allocations once the frames at or above `next_free_frame` have run out. 
+    fn allocate_deferred_frames(&mut self, num_frames: usize) -> Option<FrameRange> {
+        self.deferred_low_frames?;
+        for zone in MemoryZone::Dma32.fallback_order() {
+            if let Some(frames) = self.allocate_frames_in_zone(*zone, num_frames) {
+                return Some(frames);
+            }
+        }
+        None
+    }
+}
+
+impl<const N: usize> FrameAllocator for AreaFrameAllocator<N> {
+
+    fn allocate_frames(&mut self, num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+        if num_frames == 0 { return Err(FrameAllocError::InvalidRequest); }
+        match self.allocate_next_frames(num_frames) {
+            // If the frames at or above `next_free_frame` have run out, fall back to the deferred low-zone frames.
+            Err(e) if self.deferred_low_frames.is_some() => self.allocate_deferred_frames(num_frames).ok_or_else(|| {
+                error!("Error: AreaFrameAllocator::allocate_frames(): couldn't allocate {} contiguous frames: {}", num_frames, e);
+                e
+            }),
+            result => result,
+        }
+    }
+
+
+    fn allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        // reuse previously-deallocated frames first 
+        let reused = self.freed.pop();
+        telemetry::record_freed_list_lookup(reused.is_some());
+        match reused {
+            Some(f) => Ok(f),
+            None => self.allocate_next_frame().or_else(|e| {
+                // If the frames at or above `next_free_frame` have run out, fall back to the deferred low-zone frames.
+                self.allocate_deferred_frames(1).map(|frames| *frames.start()).ok_or(e)
+            }),
         }
     }
 
     
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        unimplemented!()
+    fn deallocate_frame(&mut self, frame: Frame) {
+        // Quarantined frames are never reused.
+        if !self.quarantined.is_empty() && self.quarantined.binary_search(&frame).is_ok() {
+            return;
+        }
+        // Frames within an offlined area are discarded instead of being reused.
+        for offlined in self.offlined.iter_mut() {
+            if let Ok(index) = offlined.frames_in_use.binary_search(&frame) {
+                offlined.frames_in_use.remove(index);
+                return;
+            }
+        }
+        self.freed.push(frame);
     }
 
 
@@ -261,5 +1047,110 @@ impl FrameAllocator for AreaFrameAllocator {
     fn alloc_ready(&mut self) {
         self.available.upgrade_to_vector();
         self.occupied.upgrade_to_vector();
+
+        // From now on, take regular allocations from the Normal zone first, by skipping over the remaining lower-zone frames,
+        // which are allocated from the top down once the Normal zone has run out, see `allocate_deferred_frames()`.
+        let normal_start = Frame::containing_address(PhysicalAddress::new_canonical(MemoryZone::DMA32_END));
+        if self.next_free_frame < normal_start && self.highest_available_frame().map_or(false, |f| f >= normal_start) {
+            self.deferred_low_frames = Some((self.next_free_frame, normal_start - 1));
+            self.next_free_frame = normal_start;
+            self.select_next_area();
+            self.recount_fresh_frames();
+        }
+    }
+}
+
+impl<const N: usize> FrameAllocatorBackend for AreaFrameAllocator<N> {
+    fn free_frame_count(&self) -> usize {
+        AreaFrameAllocator::free_frame_count(self)
+    }
+
+    fn highest_frame(&self) -> Option<Frame> {
+        self.highest_available_frame()
+    }
+
+    fn add_free_frames(&mut self, frames: &FrameRange) -> Result<(), &'static str> {
+        if frames.start() > frames.end() {
+            return Ok(());
+        }
+        self.online_area(PhysicalMemoryArea::new(frames.start_address(), frames.size_in_frames() * PAGE_SIZE, 1, 0))
+    }
+
+    fn remove_free_frames(&mut self, frames: &FrameRange) -> usize {
+        if frames.start() > frames.end() {
+            return 0;
+        }
+        let free_before = AreaFrameAllocator::free_frame_count(self);
+        let (start, end) = (*frames.start(), *frames.end());
+        self.freed.retain(|f| *f < start || *f > end);
+        // Use an end bound that is one byte short of the last frame, to avoid also occupying the frame after it.
+        let area = PhysicalMemoryArea::new(start.start_address(), frames.size_in_frames() * PAGE_SIZE - 1, 1, 0);
+        if let Err(e) = self.add_area(area, false) {
+            warn!("AreaFrameAllocator::remove_free_frames(): couldn't mark frames {:?} as occupied: {}", frames, e);
+        }
+        free_before.saturating_sub(AreaFrameAllocator::free_frame_count(self))
+    }
+
+    fn free_runs(&self) -> Vec<FrameRange> {
+        let mut freed = self.freed.clone();
+        freed.sort_unstable();
+        let mut runs: Vec<FrameRange> = freed.into_iter().map(|f| FrameRange::new(f, f)).collect();
+        runs.extend(self.fresh_runs());
+        runs.sort_unstable_by_key(|run| *run.start());
+        let mut merged: Vec<FrameRange> = Vec::new();
+        for run in runs {
+            match merged.last_mut() {
+                Some(last) if *last.end() + 1 == *run.start() => *last = FrameRange::new(*last.start(), *run.end()),
+                _ => merged.push(run),
+            }
+        }
+        merged
+    }
+
+    fn dump_state(&self, out: &mut dyn fmt::Write, free_runs: &[FrameRange]) -> fmt::Result {
+        writeln!(out, "next_free_frame: {:?}, current_area: {:?}", self.next_free_frame, self.current_area)?;
+        if let Some((start, end)) = self.deferred_low_frames {
+            writeln!(out, "Deferred low-zone frames: {:?} - {:?}", start, end)?;
+        }
+        writeln!(out, "Available areas ({}):", self.available.as_slice().len())?;
+        for area in self.available.as_slice() {
+            write!(out, "    {:#X} - {:#X} (type {})", area.base_addr.value(), area.base_addr.value() + area.size_in_bytes, area.typ)?;
+            if area.typ == 1 && area.size_in_bytes > 0 {
+                let frames = FrameRange::from_phys_addr(area.base_addr, area.size_in_bytes);
+                let total = frames.size_in_frames();
+                let used = total - free_frames_within(&frames, free_runs);
+                write!(out, ": {} / {} frames in use ({})", used, total, Percent(used, total))?;
+            }
+            writeln!(out)?;
+        }
+        writeln!(out, "Occupied areas ({}):", self.occupied.as_slice().len())?;
+        for area in self.occupied.as_slice() {
+            writeln!(out, "    {:#X} - {:#X}", area.base_addr.value(), area.base_addr.value() + area.size_in_bytes)?;
+        }
+
+        writeln!(out, "Never-allocated frames: {}", self.fresh_frames)?;
+        let mut freed = self.freed.clone();
+        freed.sort_unstable();
+        let mut freed_runs: Vec<FrameRange> = Vec::new();
+        for frame in freed {
+            match freed_runs.last_mut() {
+                Some(run) if *run.end() + 1 == frame => *run = FrameRange::new(*run.start(), frame),
+                _ => freed_runs.push(FrameRange::new(frame, frame)),
+            }
+        }
+        writeln!(out, "Freed frames: {} in {} runs", self.freed.len(), freed_runs.len())?;
+        for run in freed_runs.iter().take(MAX_DUMPED_FREED_RUNS) {
+            writeln!(out, "    {:#X} - {:#X} ({} frames)", run.start_address().value(), run.end().start_address().value() + PAGE_SIZE, run.size_in_frames())?;
+        }
+        if freed_runs.len() > MAX_DUMPED_FREED_RUNS {
+            writeln!(out, "    ... and {} more runs", freed_runs.len() - MAX_DUMPED_FREED_RUNS)?;
+        }
+
+        for offlined in self.offlined.iter() {
+            writeln!(out, "Offlined area {:#X} - {:#X}: {} frames still in use",
+                offlined.area.base_addr.value(), offlined.area.base_addr.value() + offlined.area.size_in_bytes + 1, offlined.frames_in_use.len()
+            )?;
+        }
+        writeln!(out, "Quarantined frames: {:?}", self.quarantined)
     }
 }
@@ -0,0 +1,319 @@
+//! A frame allocator backend that tracks every frame within the bounds of physical memory with a single bit.
+//!
+//! Allocating a single frame takes the lowest free frame at or after the last allocated one,
+//! and allocating contiguous frames takes the lowest run of free frames that is large enough.
+//! Both prefer frames in the `Normal` zone, then the `Dma32` zone, and take frames from the `Dma` zone last,
+//! such that the lower zones remain available for devices that can only address them.
+//! Alongside the bitmap, the free frames are also indexed as runs of contiguous free frames,
+//! such that listing them, e.g., for allocating frames within certain bounds, doesn't need to scan the whole bitmap.
+//! The bitmap is allocated on the heap, so this can only be used after the heap has been set up,
+//! see [`SystemFrameAllocator::switch_backend()`](enum.SystemFrameAllocator.html#method.switch_backend).
+
+use super::{Frame, FrameAllocator, FrameAllocError, FrameRange, MemoryZone};
+use super::system_frame_allocator::{FrameAllocatorBackend, Percent};
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use core::fmt;
+use kernel_config::memory::PAGE_SIZE;
+
+const BITS_PER_WORD: usize = 64;
+
+
+/// A frame allocator that keeps one bit per frame, which is set if that frame is free.
+pub struct BitmapFrameAllocator {
+    /// The number of the first frame tracked by the bitmap.
+    base: usize,
+    /// The number of frames tracked by the bitmap.
+    num_frames: usize,
+    bitmap: Vec<u64>,
+    free_frames: usize,
+    /// The index of the word at which the next search for a single free frame begins.
+    next_word: usize,
+    /// The runs of free frames, as a map from the bitmap index of each run's first frame to the index after its last frame.
+    /// Adjacent runs are always merged.
+    runs: BTreeMap<usize, usize>,
+}
+
+impl BitmapFrameAllocator {
+    /// Creates a new bitmap frame allocator that tracks all of the frames within the given `bounds`,
+    /// in which the given `free_ranges` of frames are free and all other frames are in use.
+    ///
+    /// Frames outside of the `bounds` can never be allocated or deallocated,
+    /// so the `bounds` should include every frame that may be deallocated to this allocator later.
+    pub fn new(bounds: FrameRange, free_ranges: &[FrameRange]) -> Result<BitmapFrameAllocator, &'static str> {
+        let (base, num_frames) = if bounds.start() <= bounds.end() {
+            (bounds.start().number, bounds.end().number - bounds.start().number + 1)
+        } else {
+            (0, 0)
+        };
+        let mut allocator = BitmapFrameAllocator {
+            base,
+            num_frames,
+            bitmap: vec![0; (num_frames + BITS_PER_WORD - 1) / BITS_PER_WORD],
+            free_frames: 0,
+            next_word: 0,
+            runs: BTreeMap::new(),
+        };
+        for range in free_ranges {
+            allocator.add_free_frames(range)?;
+        }
+        Ok(allocator)
+    }
+
+    fn is_free(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_free(&mut self, index: usize, free: bool) {
+        let word = &mut self.bitmap[index / BITS_PER_WORD];
+        let bit = 1 << (index % BITS_PER_WORD);
+        if free { *word |= bit; } else { *word &= !bit; }
+    }
+
+    /// Adds the free bitmap indices `start..end` to the run index, merging them with any overlapping or adjacent runs.
+    fn insert_run(&mut self, start: usize, end: usize) {
+        let (mut run_start, mut run_end) = (start, end);
+        if let Some((&s, &e)) = self.runs.range(..=start).next_back() {
+            if e >= start {
+                run_start = s;
+                run_end = core::cmp::max(run_end, e);
+                self.runs.remove(&s);
+            }
+        }
+        while let Some((s, e)) = self.runs.range(start..=run_end).next().map(|(&s, &e)| (s, e)) {
+            self.runs.remove(&s);
+            run_end = core::cmp::max(run_end, e);
+        }
+        self.runs.insert(run_start, run_end);
+    }
+
+    /// Removes the bitmap indices `start..end`, which are no longer free, from the run index,
+    /// splitting any runs that only partially overlap them.
+    fn remove_run(&mut self, start: usize, end: usize) {
+        if let Some((s, e)) = self.runs.range(..start).next_back().map(|(&s, &e)| (s, e)) {
+            if e > start {
+                self.runs.insert(s, start);
+                if e > end {
+                    self.runs.insert(end, e);
+                    return;
+                }
+            }
+        }
+        while let Some((s, e)) = self.runs.range(start..end).next().map(|(&s, &e)| (s, e)) {
+            self.runs.remove(&s);
+            if e > end {
+                self.runs.insert(end, e);
+            }
+        }
+    }
+
+    /// Returns the bitmap indices of the given `range` that lie within the bitmap, as an exclusive range.
+    fn indices_of(&self, range: &FrameRange) -> (usize, usize) {
+        let start = range.start().number.saturating_sub(self.base);
+        let end = core::cmp::min((range.end().number + 1).saturating_sub(self.base), self.num_frames);
+        (core::cmp::min(start, end), end)
+    }
+
+    fn frame_at(&self, index: usize) -> Frame {
+        Frame { number: self.base + index }
+    }
+
+    /// Returns the bitmap indices of the frames within the given memory `zone`, as an exclusive range.
+    fn zone_indices(&self, zone: MemoryZone) -> (usize, usize) {
+        let (zone_start, zone_end) = zone.bounds();
+        self.indices_of(&FrameRange::new(Frame { number: zone_start / PAGE_SIZE }, Frame { number: zone_end / PAGE_SIZE - 1 }))
+    }
+
+    /// Returns the index of a free frame within the bitmap indices `start..end`,
+    /// searching from the word at `next_word` if it's within them and wrapping around.
+    fn find_free_frame(&self, start: usize, end: usize) -> Option<usize> {
+        if start >= end {
+            return None;
+        }
+        let (first_word, last_word) = (start / BITS_PER_WORD, (end - 1) / BITS_PER_WORD);
+        let num_words = last_word - first_word + 1;
+        let from = if self.next_word >= first_word && self.next_word <= last_word { self.next_word } else { first_word };
+        for i in 0..num_words {
+            let word_index = first_word + (from - first_word + i) % num_words;
+            let mut word = self.bitmap[word_index];
+            // ignore the frames outside of `start..end` in the first and last words
+            if word_index == first_word {
+                word &= !0 << (start % BITS_PER_WORD);
+            }
+            if word_index == last_word && end % BITS_PER_WORD != 0 {
+                word &= !(!0 << (end % BITS_PER_WORD));
+            }
+            if word != 0 {
+                return Some(word_index * BITS_PER_WORD + word.trailing_zeros() as usize);
+            }
+        }
+        None
+    }
+
+    /// Returns the index of the lowest run of `num_frames` free frames within the bitmap indices `start..end`,
+    /// or the length of the largest run of free frames within them if there is no such run.
+    fn find_free_run(&self, start: usize, end: usize, num_frames: usize) -> Result<usize, usize> {
+        let mut largest_run = 0;
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut index = start;
+        while index < end {
+            // skip entire words of frames that are in use
+            if index % BITS_PER_WORD == 0 && self.bitmap[index / BITS_PER_WORD] == 0 {
+                run_len = 0;
+                index += BITS_PER_WORD;
+                continue;
+            }
+            if self.is_free(index) {
+                if run_len == 0 {
+                    run_start = index;
+                }
+                run_len += 1;
+                largest_run = core::cmp::max(largest_run, run_len);
+                if run_len == num_frames {
+                    return Ok(run_start);
+                }
+            } else {
+                run_len = 0;
+            }
+            index += 1;
+        }
+        Err(largest_run)
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        if self.free_frames == 0 {
+            return Err(FrameAllocError::OutOfMemory);
+        }
+        for zone in MemoryZone::Normal.fallback_order() {
+            let (start, end) = self.zone_indices(*zone);
+            if let Some(index) = self.find_free_frame(start, end) {
+                self.set_free(index, false);
+                self.remove_run(index, index + 1);
+                self.free_frames -= 1;
+                self.next_word = index / BITS_PER_WORD;
+                return Ok(self.frame_at(index));
+            }
+        }
+        error!("BUG: BitmapFrameAllocator: free frame count was {}, but no free frames were found", self.free_frames);
+        Err(FrameAllocError::OutOfMemory)
+    }
+
+    fn allocate_frames(&mut self, num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+        if num_frames == 0 {
+            return Err(FrameAllocError::InvalidRequest);
+        }
+        if num_frames == 1 {
+            return self.allocate_frame().map(|f| FrameRange::new(f, f));
+        }
+        if self.free_frames == 0 {
+            return Err(FrameAllocError::OutOfMemory);
+        }
+        // Search each zone by itself first, then all frames, since a run may also span two zones.
+        let mut largest_run = 0;
+        let zones = MemoryZone::Normal.fallback_order().iter().map(|zone| self.zone_indices(*zone));
+        let mut found = None;
+        for (start, end) in zones.chain(core::iter::once((0, self.num_frames))) {
+            match self.find_free_run(start, end, num_frames) {
+                Ok(run_start) => {
+                    found = Some(run_start);
+                    break;
+                }
+                Err(largest) => largest_run = core::cmp::max(largest_run, largest),
+            }
+        }
+        let run_start = found.ok_or(FrameAllocError::Fragmented { largest_run })?;
+        for i in run_start..(run_start + num_frames) {
+            self.set_free(i, false);
+        }
+        self.remove_run(run_start, run_start + num_frames);
+        self.free_frames -= num_frames;
+        Ok(FrameRange::new(self.frame_at(run_start), self.frame_at(run_start + num_frames - 1)))
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        let index = match frame.number.checked_sub(self.base) {
+            Some(index) if index < self.num_frames => index,
+            _ => {
+                error!("BUG: BitmapFrameAllocator::deallocate_frame(): frame {:?} was not allocated by this allocator", frame);
+                return;
+            }
+        };
+        if self.is_free(index) {
+            error!("BUG: BitmapFrameAllocator::deallocate_frame(): frame {:?} was already free", frame);
+            return;
+        }
+        self.set_free(index, true);
+        self.insert_run(index, index + 1);
+        self.free_frames += 1;
+    }
+
+    fn alloc_ready(&mut self) {
+        // this is a no-op, since this allocator is only ever created after the heap has been set up
+    }
+}
+
+impl FrameAllocatorBackend for BitmapFrameAllocator {
+    fn free_frame_count(&self) -> usize {
+        self.free_frames
+    }
+
+    fn highest_frame(&self) -> Option<Frame> {
+        if self.num_frames == 0 { None } else { Some(self.frame_at(self.num_frames - 1)) }
+    }
+
+    fn add_free_frames(&mut self, frames: &FrameRange) -> Result<(), &'static str> {
+        if frames.start() > frames.end() {
+            return Ok(());
+        }
+        if frames.start().number < self.base || frames.end().number >= self.base + self.num_frames {
+            return Err("BitmapFrameAllocator: frames lie outside the bounds of the bitmap");
+        }
+        let (start, end) = self.indices_of(frames);
+        for index in start..end {
+            if !self.is_free(index) {
+                self.set_free(index, true);
+                self.free_frames += 1;
+            }
+        }
+        if start < end {
+            self.insert_run(start, end);
+        }
+        Ok(())
+    }
+
+    fn remove_free_frames(&mut self, frames: &FrameRange) -> usize {
+        let (start, end) = self.indices_of(frames);
+        let mut removed = 0;
+        for index in start..end {
+            if self.is_free(index) {
+                self.set_free(index, false);
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            self.remove_run(start, end);
+        }
+        self.free_frames -= removed;
+        removed
+    }
+
+    fn free_runs(&self) -> Vec<FrameRange> {
+        self.runs.iter()
+            .map(|(&start, &end)| FrameRange::new(self.frame_at(start), self.frame_at(end - 1)))
+            .collect()
+    }
+
+    fn dump_state(&self, out: &mut dyn fmt::Write, _free_runs: &[FrameRange]) -> fmt::Result {
+        let used = self.num_frames - self.free_frames;
+        writeln!(out, "Bitmap covers frame numbers {:#X} - {:#X}: {} / {} frames in use ({})",
+            self.base, self.base + self.num_frames, used, self.num_frames, Percent(used, self.num_frames)
+        )?;
+        writeln!(out, "{} runs of free frames", self.runs.len())?;
+        writeln!(out, "Next single-frame search starts at {:?}", self.frame_at(self.next_word * BITS_PER_WORD))
+    }
+}
@@ -0,0 +1,227 @@
+//! A frame allocator backend that manages free frames as power-of-two-sized blocks, i.e., a binary buddy allocator.
+//!
+//! A block of `2^order` frames always starts at a frame number that is a multiple of its size,
+//! so its "buddy" (the other half of the block of the next-higher order) can be found by flipping a single bit.
+//! When a block is freed and its buddy is also free, the two are merged into one block of the next-higher order.
+//! Allocating `n` contiguous frames takes a block of the smallest order that is large enough,
+//! splitting larger blocks as needed, and returns the unneeded frames at the end of the block.
+//! Blocks in the `Normal` zone are preferred, then the `Dma32` zone, and blocks in the `Dma` zone are taken last,
+//! such that the lower zones remain available for devices that can only address them.
+//! The free lists are allocated on the heap, so this can only be used after the heap has been set up.
+
+use super::{Frame, FrameAllocator, FrameAllocError, FrameRange, MemoryZone};
+use super::system_frame_allocator::FrameAllocatorBackend;
+use alloc::{
+    collections::BTreeSet,
+    vec::Vec,
+};
+use core::fmt;
+use kernel_config::memory::PAGE_SIZE;
+
+/// The number of block orders, such that the largest block has `2^(NUM_ORDERS - 1)` frames (2 GiB).
+const NUM_ORDERS: usize = 20;
+
+
+/// A frame allocator that keeps a list of free blocks for each order.
+pub struct BuddyFrameAllocator {
+    /// The starting frame numbers of the free blocks of each order.
+    free_lists: Vec<BTreeSet<usize>>,
+    free_frames: usize,
+    /// The highest frame that was ever added to this allocator.
+    highest: Option<Frame>,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates a new buddy frame allocator in which the given `free_ranges` of frames are free.
+    pub fn new(free_ranges: &[FrameRange]) -> BuddyFrameAllocator {
+        let mut allocator = BuddyFrameAllocator {
+            free_lists: vec![BTreeSet::new(); NUM_ORDERS],
+            free_frames: 0,
+            highest: None,
+        };
+        for range in free_ranges {
+            // cannot fail, since this allocator places no bounds on its frames
+            let _ = allocator.add_free_frames(range);
+        }
+        allocator
+    }
+
+    /// Frees the frames from `start` to `end` (inclusive), which must not already be free,
+    /// by splitting them into the largest aligned blocks possible.
+    fn free_frame_numbers(&mut self, start: usize, end: usize) {
+        let mut start = start;
+        while start <= end {
+            let alignment_order = if start == 0 { NUM_ORDERS - 1 } else { start.trailing_zeros() as usize };
+            let mut order = core::cmp::min(alignment_order, NUM_ORDERS - 1);
+            while (1 << order) > end - start + 1 {
+                order -= 1;
+            }
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    /// Frees the block of `2^order` frames at `start`, merging it with its buddy as long as the buddy is free.
+    fn free_block(&mut self, start: usize, order: usize) {
+        self.free_frames += 1 << order;
+        let (mut start, mut order) = (start, order);
+        while order + 1 < NUM_ORDERS {
+            let buddy = start ^ (1 << order);
+            if !self.free_lists[order].remove(&buddy) {
+                break;
+            }
+            start = core::cmp::min(start, buddy);
+            order += 1;
+        }
+        self.free_lists[order].insert(start);
+    }
+
+    /// Allocates a block of `2^order` frames, splitting the lowest block of the smallest sufficient order 
+    /// that starts within the most preferred zone that has one, and returns its starting frame number.
+    fn allocate_block(&mut self, order: usize) -> Option<usize> {
+        let free_lists = &self.free_lists;
+        let (from, start) = MemoryZone::Normal.fallback_order().iter().find_map(|zone| {
+            let (zone_start, zone_end) = zone.bounds();
+            (order..NUM_ORDERS).find_map(|o| {
+                free_lists[o].range((zone_start / PAGE_SIZE)..(zone_end / PAGE_SIZE)).next().map(|&start| (o, start))
+            })
+        })?;
+        self.free_lists[from].remove(&start);
+        // return the upper half of each split block to the free lists
+        for o in (order..from).rev() {
+            self.free_lists[o].insert(start + (1 << o));
+        }
+        self.free_frames -= 1 << order;
+        Some(start)
+    }
+
+    /// Returns the starting frame number and order of the free block that contains the given frame number, if it is free.
+    fn free_block_containing(&self, number: usize) -> Option<(usize, usize)> {
+        (0..NUM_ORDERS)
+            .map(|order| (number & !((1 << order) - 1), order))
+            .find(|(start, order)| self.free_lists[*order].contains(start))
+    }
+
+    fn track_highest(&mut self, frame: Frame) {
+        if self.highest.map_or(true, |h| frame > h) {
+            self.highest = Some(frame);
+        }
+    }
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        self.allocate_block(0)
+            .map(|number| Frame { number })
+            .ok_or(FrameAllocError::OutOfMemory)
+    }
+
+    fn allocate_frames(&mut self, num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+        if num_frames == 0 {
+            return Err(FrameAllocError::InvalidRequest);
+        }
+        let order = num_frames.next_power_of_two().trailing_zeros() as usize;
+        let start = if order < NUM_ORDERS { self.allocate_block(order) } else { None };
+        match start {
+            Some(start) => {
+                // return the unneeded frames at the end of the block
+                let block_end = start + (1 << order) - 1;
+                if start + num_frames <= block_end {
+                    self.free_frame_numbers(start + num_frames, block_end);
+                }
+                Ok(FrameRange::new(Frame { number: start }, Frame { number: start + num_frames - 1 }))
+            }
+            None if self.free_frames == 0 => Err(FrameAllocError::OutOfMemory),
+            None => {
+                let largest_run = self.free_runs().iter().map(|r| r.size_in_frames()).max().unwrap_or(0);
+                Err(FrameAllocError::Fragmented { largest_run })
+            }
+        }
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if let Some((start, order)) = self.free_block_containing(frame.number) {
+            error!("BUG: BuddyFrameAllocator::deallocate_frame(): frame {:?} was already free, in block {:#X} of order {}", frame, start, order);
+            return;
+        }
+        self.free_block(frame.number, 0);
+    }
+
+    fn alloc_ready(&mut self) {
+        // this is a no-op, since this allocator is only ever created after the heap has been set up
+    }
+}
+
+impl FrameAllocatorBackend for BuddyFrameAllocator {
+    fn free_frame_count(&self) -> usize {
+        self.free_frames
+    }
+
+    fn highest_frame(&self) -> Option<Frame> {
+        self.highest
+    }
+
+    fn add_free_frames(&mut self, frames: &FrameRange) -> Result<(), &'static str> {
+        if frames.start() > frames.end() {
+            return Ok(());
+        }
+        // Remove any of the frames that are already free, such that none of them are freed twice.
+        self.remove_free_frames(frames);
+        self.free_frame_numbers(frames.start().number, frames.end().number);
+        self.track_highest(*frames.end());
+        Ok(())
+    }
+
+    fn remove_free_frames(&mut self, frames: &FrameRange) -> usize {
+        if frames.start() > frames.end() {
+            return 0;
+        }
+        let (start, end) = (frames.start().number, frames.end().number);
+        let mut removed = 0;
+        for order in 0..NUM_ORDERS {
+            let size = 1 << order;
+            let overlapping: Vec<usize> = self.free_lists[order]
+                .range(start.saturating_sub(size - 1) ..= end)
+                .cloned()
+                .collect();
+            for block_start in overlapping {
+                let block_end = block_start + size - 1;
+                self.free_lists[order].remove(&block_start);
+                self.free_frames -= size;
+                removed += core::cmp::min(block_end, end) - core::cmp::max(block_start, start) + 1;
+                // the parts of the block outside of the given frames remain free
+                if block_start < start {
+                    self.free_frame_numbers(block_start, start - 1);
+                }
+                if block_end > end {
+                    self.free_frame_numbers(end + 1, block_end);
+                }
+            }
+        }
+        removed
+    }
+
+    fn free_runs(&self) -> Vec<FrameRange> {
+        let mut blocks: Vec<(usize, usize)> = self.free_lists.iter().enumerate()
+            .flat_map(|(order, list)| list.iter().map(move |&start| (start, start + (1 << order) - 1)))
+            .collect();
+        blocks.sort_unstable();
+        let mut runs: Vec<FrameRange> = Vec::new();
+        for (start, end) in blocks {
+            match runs.last_mut() {
+                Some(run) if run.end().number + 1 == start => *run = FrameRange::new(*run.start(), Frame { number: end }),
+                _ => runs.push(FrameRange::new(Frame { number: start }, Frame { number: end })),
+            }
+        }
+        runs
+    }
+
+    fn dump_state(&self, out: &mut dyn fmt::Write, _free_runs: &[FrameRange]) -> fmt::Result {
+        writeln!(out, "Highest frame: {:?}", self.highest)?;
+        writeln!(out, "Free blocks per order:")?;
+        for (order, list) in self.free_lists.iter().enumerate().filter(|(_, list)| !list.is_empty()) {
+            writeln!(out, "    order {:2} ({:6} frames): {} blocks", order, 1usize << order, list.len())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! A contiguous memory area (CMA): a large physically-contiguous region of memory
+//! that is reserved at boot and can only be allocated from explicitly via [`cma_alloc()`].
+//!
+//! Regular frame allocations never use the CMA, so drivers can obtain multi-megabyte
+//! physically-contiguous buffers from it long after physical memory has become fragmented.
+//! Its size is configured by `CMA_AREA_SIZE_IN_BYTES`.
+//!
+//! The CMA's free space is tracked as a list of free frame ranges, which uses first-fit allocation
+//! and coalesces adjacent ranges when they are freed.
+//!
+//! # Locking
+//! The CMA lock may be held while acquiring the system-wide frame allocator lock, but never vice versa.
+//!
+//! [`cma_alloc()`]: fn.cma_alloc.html
+
+use super::{Frame, FrameRange, FRAME_ALLOCATOR};
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::{PAGE_SIZE, CMA_AREA_SIZE_IN_BYTES};
+
+/// The alignment of the CMA itself, in frames (2 MiB).
+const CMA_ALIGNMENT_IN_FRAMES: usize = (2 * 1024 * 1024) / PAGE_SIZE;
+
+
+/// The state of the contiguous memory area.
+struct Cma {
+    /// The full range of frames reserved for the CMA.
+    bounds: Option<FrameRange>,
+    /// The free parts of the CMA, as `(first frame number, number of frames)`, sorted by frame number.
+    free: Vec<(usize, usize)>,
+}
+
+static CMA: MutexIrqSafe<Cma> = MutexIrqSafe::new(Cma { bounds: None, free: Vec::new() });
+
+
+/// Reserves the contiguous memory area from the system-wide frame allocator.
+/// 
+/// This should be invoked as early as possible, i.e., right after the heap has been initialized,
+/// before physical memory becomes fragmented. 
+/// If the contiguous memory area cannot be reserved, the system continues without it,
+/// and every [`cma_alloc()`](fn.cma_alloc.html) fails.
+pub(crate) fn init_cma() -> Result<(), &'static str> {
+    let num_frames = CMA_AREA_SIZE_IN_BYTES / PAGE_SIZE;
+    if num_frames == 0 {
+        return Ok(());
+    }
+    let mut cma = CMA.lock();
+    let frames = match FRAME_ALLOCATOR.try().ok_or("BUG: FRAME_ALLOCATOR not initialized")?
+        .lock()
+        .reserve_aligned_frames(num_frames, CMA_ALIGNMENT_IN_FRAMES)
+    {
+        Some(frames) => frames,
+        None => {
+            warn!("Couldn't reserve the contiguous memory area (CMA) of {} frames, continuing without it; try decreasing CMA_AREA_SIZE_IN_BYTES", num_frames);
+            return Ok(());
+        }
+    };
+    info!("Reserved contiguous memory area (CMA): {:?}", frames);
+    cma.free.clear();
+    cma.free.push((frames.start().number, num_frames));
+    cma.bounds = Some(frames);
+    Ok(())
+}
+
+
+/// Allocates `num_frames` physically-contiguous frames from the contiguous memory area.
+/// 
+/// Returns `None` if the CMA is disabled or doesn't have a large enough contiguous free range.
+/// The returned frames should be freed with [`cma_free()`](fn.cma_free.html).
+pub fn cma_alloc(num_frames: usize) -> Option<FrameRange> {
+    if num_frames == 0 {
+        return None;
+    }
+    let mut cma = CMA.lock();
+    let index = cma.free.iter().position(|&(_start, len)| len >= num_frames)?;
+    let (start, len) = cma.free[index];
+    if len == num_frames {
+        cma.free.remove(index);
+    } else {
+        cma.free[index] = (start + num_frames, len - num_frames);
+    }
+    Some(FrameRange::new(Frame { number: start }, Frame { number: start + num_frames - 1 }))
+}
+
+/// Returns the given `frames`, previously obtained from [`cma_alloc()`], to the contiguous memory area.
+/// 
+/// Returns an error if the frames do not lie within the CMA or are already free.
+/// 
+/// [`cma_alloc()`]: fn.cma_alloc.html
+pub fn cma_free(frames: FrameRange) -> Result<(), &'static str> {
+    let mut cma = CMA.lock();
+    let bounds = cma.bounds.as_ref().ok_or("cma_free(): the contiguous memory area is not initialized")?;
+    if frames.start() < bounds.start() || frames.end() > bounds.end() || frames.start() > frames.end() {
+        return Err("cma_free(): frames do not lie within the contiguous memory area");
+    }
+    let start = frames.start().number;
+    let len = frames.end().number + 1 - start;
+
+    // Find where this range belongs, and make sure it doesn't overlap an already-free range.
+    let index = cma.free.iter().position(|&(s, _l)| s > start).unwrap_or(cma.free.len());
+    let overlaps_prev = index > 0 && { let (s, l) = cma.free[index - 1]; s + l > start };
+    let overlaps_next = index < cma.free.len() && start + len > cma.free[index].0;
+    if overlaps_prev || overlaps_next {
+        return Err("cma_free(): frames were already free");
+    }
+    cma.free.insert(index, (start, len));
+
+    // Coalesce with the next and previous free ranges, if adjacent.
+    if index + 1 < cma.free.len() && start + len == cma.free[index + 1].0 {
+        let (_next_start, next_len) = cma.free.remove(index + 1);
+        cma.free[index].1 += next_len;
+    }
+    if index > 0 && cma.free[index - 1].0 + cma.free[index - 1].1 == start {
+        let (_this_start, this_len) = cma.free.remove(index);
+        cma.free[index - 1].1 += this_len;
+    }
+    Ok(())
+}
+
+/// Returns the total number of free frames in the contiguous memory area.
+pub fn cma_free_frame_count() -> usize {
+    CMA.lock().free.iter().map(|&(_start, len)| len).sum()
+}
@@ -0,0 +1,299 @@
+//! Physical memory compaction, which migrates the contents of movable mappings elsewhere in physical memory
+//! in order to create a large run of contiguous free frames, e.g., after a contiguous allocation failed due to fragmentation.
+//!
+//! Only mappings that were registered via [`register_movable()`] are ever migrated.
+//! A movable mapping is a `MappedPages` wrapped in a `Mutex`, whose owner promises to only access its contents
+//! while holding that lock, and to never rely upon the physical addresses of its frames, e.g., for DMA.
+//! The compactor holds that lock while migrating the mapping, and skips any mapping whose lock is currently held.
+//! Frames that are shared (see the `frame_refcount` module) or pinned (see [`pin_frames()`]) are never migrated.
+//!
+//! Compaction is triggered automatically when [`allocate_frames()`] fails because free memory is too fragmented,
+//! and can also be invoked directly via [`compact()`].
+//! The same migration is used to move in-use frames out of a memory area that is being taken offline,
+//! see [`migrate_frames()`], which is registered as the callback for `set_frame_migration_cb()`.
+//! It is only useful with the bitmap and buddy frame allocator backends,
+//! since the area frame allocator never reuses deallocated frames for contiguous allocations.
+//!
+//! # Locking / Deadlock
+//! Compaction acquires the kernel's `MemoryManagementInfo` lock, then the locks of the movable mappings,
+//! and then the system-wide frame allocator lock, but never holds the frame allocator lock while migrating a page.
+//! Automatic compaction skips the kernel's `MemoryManagementInfo` lock if it is already held,
+//! since the allocating task may be holding it.
+//!
+//! [`register_movable()`]: fn.register_movable.html
+//! [`pin_frames()`]: fn.pin_frames.html
+//! [`allocate_frames()`]: fn.allocate_frames.html
+//! [`compact()`]: fn.compact.html
+//! [`migrate_frames()`]: fn.migrate_frames.html
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::{
+    Frame, FrameAllocator, FrameRange, FrameAllocatorKind, MappedPages, Mapper, Page, PageRange, CachedFrameAllocator, TlbShootdownBatch,
+    FRAME_ALLOCATOR, get_kernel_mmi_ref, frame_accounting, frame_pinning, frame_refcount,
+};
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use irq_safety::MutexIrqSafe;
+use spin::{Mutex, MutexGuard};
+
+
+/// All registered movable mappings, some of which may have since been dropped.
+static MOVABLE_MAPPINGS: MutexIrqSafe<Vec<Weak<Mutex<MappedPages>>>> = MutexIrqSafe::new(Vec::new());
+
+/// Whether compaction is currently in progress, which prevents it from being re-entered,
+/// e.g., by an allocation that occurs while compacting.
+static COMPACTING: AtomicBool = AtomicBool::new(false);
+
+
+/// Registers the given `mapping` as movable, such that compaction may migrate its contents
+/// to different frames at any point, as described in the module-level docs.
+///
+/// The `mapping` must have been mapped to newly-allocated frames, e.g., via `Mapper::map_allocated_pages()`.
+/// It is unregistered automatically once it is dropped.
+pub fn register_movable(mapping: &Arc<Mutex<MappedPages>>) {
+    let mut mappings = MOVABLE_MAPPINGS.lock();
+    mappings.retain(|m| m.upgrade().is_some());
+    mappings.push(Arc::downgrade(mapping));
+}
+
+/// Unregisters the given `mapping`, such that compaction will no longer migrate its contents.
+pub fn unregister_movable(mapping: &Arc<Mutex<MappedPages>>) {
+    MOVABLE_MAPPINGS.lock().retain(|m| m.upgrade().map_or(false, |m| !Arc::ptr_eq(&m, mapping)));
+}
+
+
+/// Migrates the contents of movable mappings such that there is a run of at least `target_run_len`
+/// contiguous free frames, choosing the run that requires migrating the fewest frames.
+///
+/// Returns the number of frames that were migrated, which is `0` if such a run of free frames already existed.
+/// Returns an error if no such run could be created, e.g., because too few frames around the free frames are movable.
+pub fn compact(target_run_len: usize) -> Result<usize, &'static str> {
+    compact_internal(target_run_len, true)
+}
+
+/// Migrates the contents of the movable mappings that use any frames within the given `range`
+/// to newly-allocated frames outside of it, and then deallocates the old frames.
+///
+/// This is the callback that `offline_area()` uses to move in-use frames out of an area that is being taken offline.
+/// Frames that aren't part of a movable mapping are left in place.
+/// Returns an error if any movable frame within the `range` couldn't be migrated.
+pub fn migrate_frames(range: &FrameRange) -> Result<(), &'static str> {
+    if COMPACTING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return Err("migrate_frames(): compaction is already in progress");
+    }
+    let result = migrate_frames_locked(range);
+    COMPACTING.store(false, Ordering::SeqCst);
+    result
+}
+
+fn migrate_frames_locked(range: &FrameRange) -> Result<(), &'static str> {
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("migrate_frames(): KERNEL_MMI was not yet initialized")?;
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+    let mappings = movable_mappings();
+    let mut locked: Vec<MutexGuard<MappedPages>> = mappings.iter().filter_map(|m| m.try_lock()).collect();
+    let mapper: &mut Mapper = &mut kernel_mmi.page_table;
+    let movable = find_movable_frames(&locked, mapper);
+    let (migrated, failed) = migrate_within(range, &movable, &mut locked, mapper);
+    drop(locked);
+    drop(kernel_mmi);
+
+    debug!("migrate_frames(): migrated {} frames ({} failed) out of {:?}", migrated, failed, range);
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err("migrate_frames(): some movable frames couldn't be migrated")
+    }
+}
+
+/// Returns all registered movable mappings that haven't been dropped yet.
+fn movable_mappings() -> Vec<Arc<Mutex<MappedPages>>> {
+    let mut mappings = MOVABLE_MAPPINGS.lock();
+    mappings.retain(|m| m.upgrade().is_some());
+    mappings.iter().filter_map(Weak::upgrade).collect()
+}
+
+/// Returns every frame of the given `locked` movable mappings that can be migrated,
+/// along with the index of the mapping and the page that it's mapped by, sorted by frame.
+fn find_movable_frames(locked: &[MutexGuard<MappedPages>], mapper: &Mapper) -> Vec<(Frame, usize, Page)> {
+    let mut movable: Vec<(Frame, usize, Page)> = Vec::new();
+    for (index, mapping) in locked.iter().enumerate() {
+        for page in PageRange::clone(mapping) {
+            if let Some(frame) = mapper.translate_page(page) {
+                if frame_refcount::refcount(frame).is_none() && frame_pinning::pin_count(frame) == 0 {
+                    movable.push((frame, index, page));
+                }
+            }
+        }
+    }
+    movable.sort_unstable_by_key(|&(frame, _, _)| frame);
+    // A frame that is mapped by multiple pages can't be migrated, since only one of them would be remapped.
+    let duplicates: Vec<Frame> = movable.windows(2).filter(|w| w[0].0 == w[1].0).map(|w| w[0].0).collect();
+    movable.retain(|(frame, _, _)| duplicates.binary_search(frame).is_err());
+    movable
+}
+
+/// Migrates each of the `movable` frames within the given `window` to a newly-allocated frame outside of it,
+/// and then deallocates the old frames.
+///
+/// Returns the number of frames that were migrated and the number of frames that failed to be migrated.
+fn migrate_within(
+    window: &FrameRange,
+    movable: &[(Frame, usize, Page)],
+    locked: &mut [MutexGuard<MappedPages>],
+    mapper: &mut Mapper,
+) -> (usize, usize) {
+    let frame_allocator = match FRAME_ALLOCATOR.try() {
+        Some(fa) => fa,
+        None => return (0, movable.iter().filter(|(frame, _, _)| window.contains(frame)).count()),
+    };
+    // Frames within the window can't be used as migration destinations,
+    // so any that are allocated here are set aside until the end.
+    let mut set_aside: Vec<Frame> = Vec::new();
+    // The old frames can only be freed once no other core's TLB maps them anymore,
+    // so all migrated pages are shot down at once after migrating them.
+    let mut shootdowns = TlbShootdownBatch::new();
+    let mut old_frames: Vec<Frame> = Vec::new();
+    let mut migrated = 0;
+    let mut failed = 0;
+    for &(old_frame, index, page) in movable.iter().filter(|(frame, _, _)| window.contains(frame)) {
+        let new_frame = loop {
+            match frame_allocator.lock().allocate_frame() {
+                Ok(f) if window.contains(&f) => set_aside.push(f),
+                Ok(f) => break Some(f),
+                Err(_e) => break None,
+            }
+        };
+        let new_frame = match new_frame {
+            Some(f) => f,
+            None => {
+                failed += 1;
+                break;
+            }
+        };
+        match locked[index].migrate_page(page, new_frame, mapper, &mut CachedFrameAllocator, &mut shootdowns) {
+            Ok(old) => {
+                frame_accounting::transfer_owner(old, new_frame);
+                old_frames.push(old);
+                migrated += 1;
+            }
+            Err(_e) => {
+                warn!("failed to migrate {:?} from {:?}: {}", page, old_frame, _e);
+                frame_allocator.lock().deallocate_frame(new_frame);
+                failed += 1;
+            }
+        }
+    }
+    shootdowns.flush();
+    let mut frame_allocator = frame_allocator.lock();
+    for frame in set_aside.into_iter().chain(old_frames) {
+        frame_allocator.deallocate_frame(frame);
+    }
+    (migrated, failed)
+}
+
+/// Compacts memory after a failed allocation of `num_frames` contiguous frames,
+/// and returns `true` if a large enough run of free frames may now exist.
+pub(crate) fn compact_after_failed_allocation(num_frames: usize) -> bool {
+    match compact_internal(num_frames, false) {
+        Ok(migrated) => migrated > 0,
+        Err(_e) => {
+            debug!("Couldn't compact memory for {} contiguous frames: {}", num_frames, _e);
+            false
+        }
+    }
+}
+
+fn compact_internal(target_run_len: usize, blocking: bool) -> Result<usize, &'static str> {
+    if target_run_len == 0 {
+        return Err("compact(): target run length must be nonzero");
+    }
+    if COMPACTING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return Err("compact(): compaction is already in progress");
+    }
+    let result = compact_locked(target_run_len, blocking);
+    COMPACTING.store(false, Ordering::SeqCst);
+    result
+}
+
+fn compact_locked(target_run_len: usize, blocking: bool) -> Result<usize, &'static str> {
+    let frame_allocator = FRAME_ALLOCATOR.try().ok_or("compact(): the frame allocator was not yet initialized")?;
+    if frame_allocator.lock().kind() == FrameAllocatorKind::Area {
+        return Err("compact(): the area frame allocator doesn't reuse deallocated frames for contiguous allocations");
+    }
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("compact(): KERNEL_MMI was not yet initialized")?;
+    let mut kernel_mmi = if blocking {
+        kernel_mmi_ref.lock()
+    } else {
+        kernel_mmi_ref.try_lock().ok_or("compact(): the kernel's MemoryManagementInfo was already locked")?
+    };
+
+    let mappings = movable_mappings();
+    let mut locked: Vec<MutexGuard<MappedPages>> = mappings.iter().filter_map(|m| m.try_lock()).collect();
+
+    // Find every frame that can be migrated, along with the mapping and page that it's mapped by.
+    let mapper: &mut Mapper = &mut kernel_mmi.page_table;
+    let movable = find_movable_frames(&locked, mapper);
+
+    let free_runs = frame_allocator.lock().free_runs();
+    if free_runs.iter().any(|run| run.size_in_frames() >= target_run_len) {
+        return Ok(0);
+    }
+    let window = choose_window(&free_runs, &movable, target_run_len)
+        .ok_or("compact(): couldn't find a run of free and movable frames that is large enough")?;
+    let (migrated, failed) = migrate_within(&window, &movable, &mut locked, mapper);
+    drop(locked);
+    drop(kernel_mmi);
+
+    let created = frame_allocator.lock().free_runs().iter().any(|run| run.size_in_frames() >= target_run_len);
+    info!("compact(): migrated {} frames ({} failed) to free up {:?}", migrated, failed, window);
+    if created {
+        Ok(migrated)
+    } else {
+        Err("compact(): couldn't free all frames in the chosen run, some frames were allocated or couldn't be migrated")
+    }
+}
+
+/// Returns the range of `target_run_len` frames that consists only of free frames and `movable` frames
+/// and contains the fewest `movable` frames, if there is one.
+///
+/// The given `free_runs` and `movable` frames must be sorted.
+fn choose_window(free_runs: &[FrameRange], movable: &[(Frame, usize, Page)], target_run_len: usize) -> Option<FrameRange> {
+    // Merge the free and movable frames into maximal runs of usable frames.
+    let mut pieces: Vec<(usize, usize)> = free_runs.iter()
+        .map(|run| (run.start().number, run.end().number))
+        .chain(movable.iter().map(|&(frame, _, _)| (frame.number, frame.number)))
+        .collect();
+    pieces.sort_unstable();
+    let mut usable_runs: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in pieces {
+        match usable_runs.last_mut() {
+            Some(run) if run.1 + 1 >= start => run.1 = core::cmp::max(run.1, end),
+            _ => usable_runs.push((start, end)),
+        }
+    }
+
+    let movable_numbers: Vec<usize> = movable.iter().map(|&(frame, _, _)| frame.number).collect();
+    let movable_within = |start: usize, end: usize| {
+        let lower = match movable_numbers.binary_search(&start) { Ok(i) | Err(i) => i };
+        let upper = match movable_numbers.binary_search(&(end + 1)) { Ok(i) | Err(i) => i };
+        upper - lower
+    };
+
+    let mut best: Option<(usize, usize)> = None; // (number of movable frames, window start)
+    for &(run_start, run_end) in usable_runs.iter().filter(|(s, e)| e - s + 1 >= target_run_len) {
+        let last_start = run_end + 1 - target_run_len;
+        // A window's movable frame count only decreases when it starts just after a movable frame,
+        // so only the run's start and those positions need to be considered.
+        let candidates = core::iter::once(run_start)
+            .chain(movable_numbers.iter().map(|n| n + 1).filter(|&s| s > run_start && s <= last_start));
+        for start in candidates {
+            let count = movable_within(start, start + target_run_len - 1);
+            if best.map_or(true, |(best_count, _)| count < best_count) {
+                best = Some((count, start));
+            }
+        }
+    }
+    best.map(|(_, start)| FrameRange::new(Frame { number: start }, Frame { number: start + target_run_len - 1 }))
+}
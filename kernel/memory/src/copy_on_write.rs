@@ -0,0 +1,190 @@
+//! Copy-on-write sharing of frames between `MappedPages`, see [`MappedPages::clone_cow()`].
+//!
+//! A copy-on-write clone maps the same frames as the original mapping, and both mappings' pages are
+//! write-protected, even if the mappings themselves are writable. Each shared frame is reference counted
+//! (see the `frame_refcount` module), such that it's deallocated once neither mapping refers to it.
+//!
+//! The first write to a write-protected page of a writable copy-on-write mapping causes a page fault,
+//! upon which [`handle_cow_page_fault()`] gives that page a private copy of its frame and makes it writable.
+//! If the frame is no longer shared, e.g., because the other mapping already copied it or was dropped,
+//! the page is simply made writable again without copying.
+//!
+//! # Locking / Deadlock
+//! The page fault handler allocates frames, so a task must not write to a copy-on-write mapping
+//! while holding the frame allocator's lock.
+//! It also changes the page table while holding the lock on the kernel's `MemoryManagementInfo`,
+//! like every other change to the kernel's page table entries, e.g., remapping or migrating pages,
+//! so a task must not write to a copy-on-write mapping while holding that lock either.
+//!
+//! [`MappedPages::clone_cow()`]: ../struct.MappedPages.html#method.clone_cow
+//! [`handle_cow_page_fault()`]: fn.handle_cow_page_fault.html
+
+use core::slice;
+use super::{allocate_frame, deallocate_frame, frame_refcount, get_current_p4, get_kernel_mmi_ref, map_frame_temporarily, tlb_flush_virt_addr,
+    broadcast_tlb_shootdown, Entry, EntryFlags, Frame, Mapper, Page, PageRange, VirtualAddress};
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::PAGE_SIZE;
+
+
+/// A mapping whose pages may be write-protected because their frames are shared copy-on-write.
+struct CowRegion {
+    pages: PageRange,
+    /// The frame containing the top-level P4 page table that this region was mapped into.
+    page_table_p4: Frame,
+    /// The flags of the mapping, which its pages are mapped with once their frames are no longer shared.
+    flags: EntryFlags,
+}
+
+impl CowRegion {
+    fn matches(&self, pages: &PageRange, page_table_p4: Frame) -> bool {
+        self.page_table_p4 == page_table_p4 && self.pages.start() == pages.start() && self.pages.end() == pages.end()
+    }
+}
+
+/// All current copy-on-write mappings.
+static COW_REGIONS: MutexIrqSafe<Vec<CowRegion>> = MutexIrqSafe::new(Vec::new());
+
+
+/// Registers the given `pages` as a copy-on-write mapping in the given page table, whose flags are `flags`.
+pub(crate) fn register(pages: PageRange, page_table_p4: Frame, flags: EntryFlags) {
+    COW_REGIONS.lock().push(CowRegion { pages, page_table_p4, flags });
+}
+
+/// Removes the given copy-on-write mapping, such that faults on its pages are no longer fixed up.
+pub(crate) fn unregister(pages: &PageRange, page_table_p4: Frame) {
+    COW_REGIONS.lock().retain(|r| !r.matches(pages, page_table_p4));
+}
+
+/// Changes the flags of the given copy-on-write mapping.
+///
+/// The given `remap` function is invoked while faults on this mapping are held off,
+/// such that it can change the flags of its pages without racing with a fault.
+pub(crate) fn set_flags<F>(pages: &PageRange, page_table_p4: Frame, flags: EntryFlags, remap: F) -> Result<(), &'static str>
+    where F: FnOnce() -> Result<(), &'static str>
+{
+    let mut regions = COW_REGIONS.lock();
+    let region = regions.iter_mut().find(|r| r.matches(pages, page_table_p4)).ok_or("copy_on_write: not a copy-on-write mapping")?;
+    remap()?;
+    region.flags = flags;
+    Ok(())
+}
+
+/// Returns whether the given frame is mapped by more than one mapping.
+pub(crate) fn is_shared(frame: Frame) -> bool {
+    frame_refcount::refcount(frame).map_or(false, |count| count > 1)
+}
+
+
+/// Handles a page fault at the given `vaddr` that was caused by writing to a present, write-protected page.
+///
+/// If that page is part of a writable copy-on-write mapping in the current page table,
+/// it is given its own copy of its frame (unless its frame is no longer shared) and made writable,
+/// and `Ok(true)` is returned, meaning that the faulting write can be retried.
+/// Otherwise, `Ok(false)` is returned, meaning that the fault is a real invalid access.
+/// If no frame could be allocated for the copy, an error is returned.
+pub fn handle_cow_page_fault(vaddr: VirtualAddress) -> Result<bool, &'static str> {
+    let page = Page::containing_address(vaddr);
+    let current_p4 = get_current_p4();
+    let find_region = |regions: &Vec<CowRegion>| regions.iter()
+        .position(|r| r.page_table_p4 == current_p4 && r.pages.contains(&page) && r.flags.is_writable());
+
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("copy_on_write: KERNEL_MMI was not yet initialized")?;
+
+    let old_frame = {
+        let _kernel_mmi = kernel_mmi_ref.lock();
+        let regions = COW_REGIONS.lock();
+        let region = match find_region(&regions) {
+            Some(i) => &regions[i],
+            None => return Ok(false),
+        };
+        let mut mapper = Mapper::from_current();
+        let entry = entry_of(&mut mapper, page)?;
+        let frame = match entry.pointed_frame() {
+            Some(f) => f,
+            None => return Ok(false),
+        };
+        if entry.flags().is_writable() {
+            // Another core already made this page writable, so this core's TLB entry was stale.
+            tlb_flush_virt_addr(page.start_address());
+            return Ok(true);
+        }
+        if !is_shared(frame) {
+            entry.set(frame, region.flags | EntryFlags::PRESENT);
+            flush(page);
+            return Ok(true);
+        }
+        frame
+    };
+
+    // Frame allocation may invoke reclaim callbacks that drop other mappings, so the locks can't be held across it.
+    // The shared frame is write-protected in every mapping, so its contents can't change in the meantime.
+    let new_frame = allocate_frame().map_err(|e| {
+        error!("copy_on_write: couldn't copy page {:#X}: {}", page.start_address(), e);
+        "copy_on_write: out of memory, couldn't allocate a frame for a copy-on-write page"
+    })?;
+
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+    // Fill the new frame through a temporary mapping of it, such that other cores never see it partially filled.
+    if let Err(e) = copy_page_to_frame(&mut kernel_mmi.page_table, page, new_frame) {
+        drop(kernel_mmi);
+        deallocate_frame(new_frame);
+        return Err(e);
+    }
+
+    let regions = COW_REGIONS.lock();
+    // The mapping may have been dropped or remapped in the meantime, or another core may have copied the same page.
+    let region = match find_region(&regions) {
+        Some(i) => &regions[i],
+        None => {
+            drop(regions);
+            drop(kernel_mmi);
+            deallocate_frame(new_frame);
+            return Ok(false);
+        }
+    };
+    let mut mapper = Mapper::from_current();
+    let entry = entry_of(&mut mapper, page)?;
+    if entry.pointed_frame() != Some(old_frame) || entry.flags().is_writable() {
+        drop(regions);
+        drop(kernel_mmi);
+        deallocate_frame(new_frame);
+        return Ok(true);
+    }
+    entry.set(new_frame, region.flags | EntryFlags::PRESENT);
+    flush(page);
+    // Track the private copy too, such that it's deallocated once this mapping is dropped.
+    frame_refcount::incref(new_frame);
+    drop(regions);
+    drop(kernel_mmi);
+
+    // This mapping no longer refers to the shared frame, which is deallocated if the other mapping was dropped meanwhile.
+    frame_refcount::decref(old_frame);
+    Ok(true)
+}
+
+/// Copies the contents of the given mapped `page` into the given `frame`, through a temporary mapping in the given page table.
+fn copy_page_to_frame(mapper: &mut Mapper, page: Page, frame: Frame) -> Result<(), &'static str> {
+    let mut temp_mapping = map_frame_temporarily(mapper, frame)?;
+    // SAFE: the page is mapped and readable, and nothing can write to it.
+    let source: &[u8] = unsafe { slice::from_raw_parts(page.start_address().value() as *const u8, PAGE_SIZE) };
+    temp_mapping.as_slice_mut(0, PAGE_SIZE)?.copy_from_slice(source);
+    // Dropping the temporary mapping doesn't deallocate the frame, because it's not reference counted.
+    Ok(())
+}
+
+/// Returns the page table entry for the given `page`.
+fn entry_of(mapper: &mut Mapper, page: Page) -> Result<&mut Entry, &'static str> {
+    mapper.p4_mut()
+        .next_table_mut(page.p4_index())
+        .and_then(|p3| p3.next_table_mut(page.p3_index()))
+        .and_then(|p2| p2.next_table_mut(page.p2_index()))
+        .map(|p1| &mut p1[page.p1_index()])
+        .ok_or("copy_on_write: mapping code does not support huge pages")
+}
+
+/// Flushes the given page's stale TLB entries on all cores.
+fn flush(page: Page) {
+    tlb_flush_virt_addr(page.start_address());
+    broadcast_tlb_shootdown(PageRange::new(page, page));
+}
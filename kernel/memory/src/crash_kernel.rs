@@ -0,0 +1,161 @@
+//! A region of physical memory reserved at boot for a crash kernel, in the style of Linux's kexec/kdump.
+//!
+//! The frame allocator never hands out any frames in this region, so a minimal crash/diagnostic kernel image
+//! can be pre-loaded there (see the `crash_kernel` crate) and jumped to when the main kernel fails irrecoverably,
+//! such that it can capture a crash dump even when the main kernel's own memory is corrupted.
+//!
+//! The region's size is given by `CRASH_KERNEL_SIZE_IN_BYTES`, which is `0` (disabled) by default,
+//! and can be overridden on the boot command line with the `crashkernel=SIZE[@ADDRESS]` argument,
+//! e.g., `crashkernel=64M` or `crashkernel=64M@0x40000000`. The size may have a `K`, `M`, or `G` suffix.
+//! Without an explicit address, the highest suitably-aligned part of available memory that isn't reserved is used.
+//! The region must be reserved before the frame allocator is initialized, so this doesn't allocate.
+
+use super::{Frame, FrameRange, PhysicalAddress, PhysicalMemoryArea, reserved_regions};
+use kernel_config::memory::{PAGE_SIZE, CRASH_KERNEL_SIZE_IN_BYTES};
+use spin::Once;
+
+
+/// The name of the boot command-line argument that configures the crash kernel region.
+pub const CRASH_KERNEL_BOOT_ARG: &'static str = "crashkernel";
+
+/// The alignment of a crash kernel region that is placed automatically (2 MiB),
+/// such that it can be mapped with huge pages.
+const CRASH_KERNEL_ALIGNMENT: usize = 2 * 1024 * 1024;
+
+/// The frames reserved for the crash kernel, if any.
+static CRASH_KERNEL_REGION: Once<FrameRange> = Once::new();
+
+
+/// Returns the frames that were reserved for the crash kernel at boot, if any.
+pub fn crash_kernel_region() -> Option<FrameRange> {
+    CRASH_KERNEL_REGION.try().cloned()
+}
+
+/// Reserves the crash kernel region given by the `crashkernel=` argument in the boot `command_line`,
+/// or by `CRASH_KERNEL_SIZE_IN_BYTES` otherwise, within the given `available` memory areas.
+///
+/// Failing to find a suitable region is not an error, since the system can run without a crash kernel.
+pub(crate) fn reserve_crash_kernel(command_line: Option<&str>, available: &[PhysicalMemoryArea]) -> Result<(), &'static str> {
+    let (size, address) = match command_line.and_then(parse_boot_arg) {
+        Some(arg) => arg,
+        None => (CRASH_KERNEL_SIZE_IN_BYTES, None),
+    };
+    if size == 0 {
+        return Ok(());
+    }
+    let size = round_up(size, PAGE_SIZE);
+
+    let start = match address {
+        Some(addr) if addr % PAGE_SIZE != 0 => {
+            warn!("Not reserving a crash kernel region: address {:#X} is not page-aligned", addr);
+            return Ok(());
+        }
+        Some(addr) if fits(addr, size, available) => addr,
+        Some(addr) => {
+            warn!("Not reserving a crash kernel region: {:#X} - {:#X} is not entirely within available, unreserved memory", addr, addr + size);
+            return Ok(());
+        }
+        None => match find_region(size, available) {
+            Some(addr) => addr,
+            None => {
+                warn!("Not reserving a crash kernel region: no {} bytes of available memory are free", size);
+                return Ok(());
+            }
+        },
+    };
+
+    let paddr = PhysicalAddress::new(start)?;
+    // Use an end bound that is one byte short of the region, to avoid also occupying the frame after it.
+    reserved_regions::reserve_area(PhysicalMemoryArea::new(paddr, size - 1, 1, 0), "crash kernel")?;
+    let frames = FrameRange::new(Frame::containing_address(paddr), Frame::containing_address(paddr + (size - 1)));
+    info!("Reserved crash kernel region: {:?}", frames);
+    CRASH_KERNEL_REGION.call_once(|| frames);
+    Ok(())
+}
+
+/// Parses the `crashkernel=SIZE[@ADDRESS]` argument in the given boot `command_line`, if any.
+fn parse_boot_arg(command_line: &str) -> Option<(usize, Option<usize>)> {
+    let value = command_line.split_whitespace()
+        .filter_map(|arg| {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name == CRASH_KERNEL_BOOT_ARG => Some(value),
+                _ => None,
+            }
+        })
+        .last()?;
+
+    let mut parts = value.splitn(2, '@');
+    let size = parts.next().and_then(parse_size);
+    let address = parts.next().map(parse_number);
+    match (size, address) {
+        (Some(size), None) => Some((size, None)),
+        (Some(size), Some(Some(address))) => Some((size, Some(address))),
+        _ => {
+            warn!("Ignoring invalid boot argument {}={:?}, expected SIZE[@ADDRESS]", CRASH_KERNEL_BOOT_ARG, value);
+            None
+        }
+    }
+}
+
+/// Parses a size in bytes with an optional `K`, `M`, or `G` suffix.
+fn parse_size(s: &str) -> Option<usize> {
+    let (number, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    parse_number(number)?.checked_mul(1 << shift)
+}
+
+/// Parses a hexadecimal number with a `0x` prefix, or a decimal number otherwise.
+fn parse_number(s: &str) -> Option<usize> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        usize::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse::<usize>().ok()
+    }
+}
+
+/// Returns whether the `size` bytes at `start` lie entirely within one of the `available` areas
+/// and don't overlap any reserved region.
+fn fits(start: usize, size: usize, available: &[PhysicalMemoryArea]) -> bool {
+    let end = match start.checked_add(size) {
+        Some(end) => end,
+        None => return false,
+    };
+    available.iter().any(|a| a.base_addr.value() <= start && end <= a.base_addr.value() + a.size_in_bytes)
+        && !reserved_regions::overlaps_reserved_area(start, end)
+}
+
+/// Finds the highest suitably-aligned start address at which `size` bytes fit in any of the `available` areas,
+/// stepping below any reserved regions in the way.
+fn find_region(size: usize, available: &[PhysicalMemoryArea]) -> Option<usize> {
+    let mut best = None;
+    for area in available {
+        let area_start = area.base_addr.value();
+        let mut start = match (area_start + area.size_in_bytes).checked_sub(size) {
+            Some(start) => start & !(CRASH_KERNEL_ALIGNMENT - 1),
+            None => continue,
+        };
+        while start >= area_start {
+            if fits(start, size, available) {
+                if best.map_or(true, |b| start > b) {
+                    best = Some(start);
+                }
+                break;
+            }
+            // Try the next lower aligned position within this area.
+            match start.checked_sub(CRASH_KERNEL_ALIGNMENT) {
+                Some(lower) => start = lower,
+                None => break,
+            }
+        }
+    }
+    best
+}
+
+fn round_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
@@ -0,0 +1,179 @@
+//! Demand paging, in which the frames of a lazily-mapped region are only allocated when its pages are first accessed.
+//!
+//! A lazy mapping, see [`create_lazy_mapping()`], reserves a range of virtual pages and creates the page tables
+//! for them, but leaves their page table entries non-present.
+//! The first access to each page causes a page fault, upon which [`handle_demand_page_fault()`]
+//! allocates a frame, fills it with zeros through a temporary mapping, and only then maps the page to it with the mapping's flags,
+//! such that no core can ever see the frame's previous contents, after which the access is retried.
+//! Thus, a large mapping that is only sparsely used, e.g., a heap or a buffer whose final size isn't known,
+//! only consumes as much physical memory as it actually touches.
+//!
+//! Each lazy mapping counts how many of its pages have been committed, i.e., backed by a frame,
+//! see [`MappedPages::committed_pages()`] and [`demand_paging_stats()`].
+//! A page fault that can't be fixed up because there are no free frames left returns an error,
+//! such that running out of memory is reported as such instead of as an invalid memory access.
+//!
+//! # Locking / Deadlock
+//! The page fault handler allocates frames, so a task must not access a lazy mapping's pages
+//! for the first time while holding the frame allocator's lock.
+//! It also changes the page table while holding the lock on the kernel's `MemoryManagementInfo`,
+//! like every other change to the kernel's page table entries, e.g., remapping or migrating pages,
+//! so a task must not access a lazy mapping's pages for the first time while holding that lock either.
+//!
+//! [`create_lazy_mapping()`]: ../fn.create_lazy_mapping.html
+//! [`handle_demand_page_fault()`]: fn.handle_demand_page_fault.html
+//! [`MappedPages::committed_pages()`]: ../struct.MappedPages.html#method.committed_pages
+//! [`demand_paging_stats()`]: fn.demand_paging_stats.html
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::{allocate_frame, deallocate_frame, get_current_p4, get_kernel_mmi_ref, tlb_flush_virt_addr, zero_frame, zeroed_frames,
+    EntryFlags, Frame, Mapper, Page, PageRange, VirtualAddress};
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+
+
+/// A lazily-mapped region of virtual pages.
+struct LazyRegion {
+    pages: PageRange,
+    /// The frame containing the top-level P4 page table that this region was mapped into.
+    page_table_p4: Frame,
+    /// The flags that each page is mapped with once it has been committed.
+    flags: EntryFlags,
+    /// The number of pages in this region that have been committed.
+    committed: usize,
+}
+
+impl LazyRegion {
+    fn matches(&self, pages: &PageRange, page_table_p4: Frame) -> bool {
+        self.page_table_p4 == page_table_p4 && self.pages.start() == pages.start() && self.pages.end() == pages.end()
+    }
+}
+
+/// All current lazy mappings.
+static LAZY_REGIONS: MutexIrqSafe<Vec<LazyRegion>> = MutexIrqSafe::new(Vec::new());
+
+/// The number of page faults that couldn't be fixed up because no frame could be allocated.
+static FAILED_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Statistics about all current lazy mappings.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DemandPagingStats {
+    /// The number of lazy mappings.
+    pub lazy_mappings: usize,
+    /// The total number of pages in all lazy mappings.
+    pub reserved_pages: usize,
+    /// The number of those pages that have been committed, i.e., are backed by a frame.
+    pub committed_pages: usize,
+    /// The number of page faults on lazy mappings that failed because memory ran out, since boot.
+    pub failed_faults: usize,
+}
+
+/// Returns statistics about all current lazy mappings.
+pub fn demand_paging_stats() -> DemandPagingStats {
+    let regions = LAZY_REGIONS.lock();
+    DemandPagingStats {
+        lazy_mappings: regions.len(),
+        reserved_pages: regions.iter().map(|r| r.pages.size_in_pages()).sum(),
+        committed_pages: regions.iter().map(|r| r.committed).sum(),
+        failed_faults: FAILED_FAULTS.load(Ordering::Relaxed),
+    }
+}
+
+
+/// Registers the given `pages` as a lazy mapping in the given page table, whose pages will be mapped with `flags`.
+pub(crate) fn register(pages: PageRange, page_table_p4: Frame, flags: EntryFlags) {
+    LAZY_REGIONS.lock().push(LazyRegion { pages, page_table_p4, flags, committed: 0 });
+}
+
+/// Removes the given lazy mapping, such that faults on its pages are no longer fixed up.
+pub(crate) fn unregister(pages: &PageRange, page_table_p4: Frame) {
+    LAZY_REGIONS.lock().retain(|r| !r.matches(pages, page_table_p4));
+}
+
+/// Changes the flags that the not-yet-committed pages of the given lazy mapping will be mapped with.
+///
+/// The given `remap` function is invoked while faults on this mapping are held off,
+/// such that it can change the flags of the already-committed pages without racing with a fault.
+pub(crate) fn set_flags<F>(pages: &PageRange, page_table_p4: Frame, flags: EntryFlags, remap: F) -> Result<(), &'static str>
+    where F: FnOnce() -> Result<(), &'static str>
+{
+    let mut regions = LAZY_REGIONS.lock();
+    let region = regions.iter_mut().find(|r| r.matches(pages, page_table_p4)).ok_or("demand_paging: not a lazy mapping")?;
+    remap()?;
+    region.flags = flags;
+    Ok(())
+}
+
+/// Returns the number of committed pages in the given lazy mapping.
+pub(crate) fn committed_pages(pages: &PageRange, page_table_p4: Frame) -> usize {
+    LAZY_REGIONS.lock().iter().find(|r| r.matches(pages, page_table_p4)).map(|r| r.committed).unwrap_or(0)
+}
+
+
+/// Handles a page fault at the given `vaddr` that was caused by accessing a non-present page.
+///
+/// If that page is part of a lazy mapping in the current page table, it is committed by mapping it
+/// to a newly-allocated frame that is filled with zeros, and `Ok(true)` is returned,
+/// meaning that the faulting access can be retried.
+/// If the page is not part of a lazy mapping, `Ok(false)` is returned, meaning that the fault is a real invalid access.
+/// If no frame could be allocated, an error is returned.
+pub fn handle_demand_page_fault(vaddr: VirtualAddress) -> Result<bool, &'static str> {
+    let page = Page::containing_address(vaddr);
+    let current_p4 = get_current_p4();
+    let is_lazy = |regions: &Vec<LazyRegion>| regions.iter().position(|r| r.page_table_p4 == current_p4 && r.pages.contains(&page));
+
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("demand_paging: KERNEL_MMI was not yet initialized")?;
+
+    // Frame allocation may invoke reclaim callbacks that drop other mappings, so the lock can't be held across it.
+    if is_lazy(&LAZY_REGIONS.lock()).is_none() {
+        return Ok(false);
+    }
+    let frame = match zeroed_frames::take_zeroed_frame() {
+        Some(f) => f,
+        None => {
+            let frame = allocate_frame().map_err(|e| {
+                FAILED_FAULTS.fetch_add(1, Ordering::Relaxed);
+                error!("demand_paging: couldn't commit page {:#X}: {}", page.start_address(), e);
+                "demand_paging: out of memory, couldn't allocate a frame for a lazily-mapped page"
+            })?;
+            // The frame is zeroed through a temporary mapping, because it must not be mapped to the page before it's zeroed.
+            if let Err(e) = zero_frame(frame) {
+                deallocate_frame(frame);
+                return Err(e);
+            }
+            frame
+        }
+    };
+
+    let _kernel_mmi = kernel_mmi_ref.lock();
+    let mut regions = LAZY_REGIONS.lock();
+    // The mapping may have been dropped in the meantime, or another core may have committed the same page.
+    let region = match is_lazy(&regions) {
+        Some(i) => &mut regions[i],
+        None => {
+            drop(regions);
+            deallocate_frame(frame);
+            return Ok(false);
+        }
+    };
+    let mut mapper = Mapper::from_current();
+    let p1 = match mapper.p4_mut()
+        .next_table_mut(page.p4_index())
+        .and_then(|p3| p3.next_table_mut(page.p3_index()))
+        .and_then(|p2| p2.next_table_mut(page.p2_index()))
+    {
+        Some(p1) if p1[page.p1_index()].is_unused() => p1,
+        already_mapped_or_missing => {
+            drop(regions);
+            deallocate_frame(frame);
+            return already_mapped_or_missing.map(|_| true).ok_or("demand_paging: lazy mapping is missing its page tables");
+        }
+    };
+
+    // The entry was non-present, so no core can have a stale TLB entry for it.
+    p1[page.p1_index()].set(frame, region.flags | EntryFlags::PRESENT);
+    tlb_flush_virt_addr(page.start_address());
+    region.committed += 1;
+    Ok(true)
+}
@@ -0,0 +1,98 @@
+//! Statistics about page faults, classified by how each fault was handled.
+//!
+//! The page fault handler classifies every fault as a [`PageFaultKind`] and records it here via [`record_page_fault()`],
+//! which counts faults system-wide; the handler also counts them for the faulting task.
+//! The system-wide counts are returned by [`fault_stats()`].
+//!
+//! [`PageFaultKind`]: enum.PageFaultKind.html
+//! [`record_page_fault()`]: fn.record_page_fault.html
+//! [`fault_stats()`]: fn.fault_stats.html
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+
+/// How a page fault was handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageFaultKind {
+    /// The first access to a lazily-mapped page, which mapped it to a new zeroed frame.
+    DemandZero,
+    /// The first write to a page that was shared copy-on-write, which gave it its own copy of the frame.
+    CopyOnWrite,
+    /// An access to a page that was evicted to swap, which read it back in.
+    SwapIn,
+    /// An access within the guard page beneath a task's stack, i.e., a stack overflow.
+    GuardPage,
+    /// Any other fault, which couldn't be fixed up.
+    Invalid,
+}
+
+/// The number of page faults of each kind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageFaultCounts {
+    pub demand_zero: u64,
+    pub copy_on_write: u64,
+    pub swap_in: u64,
+    pub guard_page: u64,
+    pub invalid: u64,
+}
+
+impl PageFaultCounts {
+    /// Counts one more fault of the given `kind`.
+    pub fn record(&mut self, kind: PageFaultKind) {
+        let count = match kind {
+            PageFaultKind::DemandZero => &mut self.demand_zero,
+            PageFaultKind::CopyOnWrite => &mut self.copy_on_write,
+            PageFaultKind::SwapIn => &mut self.swap_in,
+            PageFaultKind::GuardPage => &mut self.guard_page,
+            PageFaultKind::Invalid => &mut self.invalid,
+        };
+        *count = count.saturating_add(1);
+    }
+
+    /// Returns the number of faults of all kinds.
+    pub fn total(&self) -> u64 {
+        self.demand_zero + self.copy_on_write + self.swap_in + self.guard_page + self.invalid
+    }
+}
+
+impl fmt::Display for PageFaultCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} faults ({} demand-zero, {} copy-on-write, {} swap-in, {} guard page, {} invalid)",
+            self.total(), self.demand_zero, self.copy_on_write, self.swap_in, self.guard_page, self.invalid
+        )
+    }
+}
+
+
+/// The system-wide number of page faults of each kind, in the order of `PageFaultKind`'s variants.
+static FAULT_COUNTS: [AtomicU64; 5] = [
+    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+];
+
+
+/// Counts one more system-wide page fault of the given `kind`.
+///
+/// This is invoked by the page fault handler, so it doesn't take any locks.
+pub fn record_page_fault(kind: PageFaultKind) {
+    FAULT_COUNTS[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the system-wide number of page faults of each kind since boot or the last [`reset_fault_stats()`](fn.reset_fault_stats.html).
+pub fn fault_stats() -> PageFaultCounts {
+    let count = |kind: PageFaultKind| FAULT_COUNTS[kind as usize].load(Ordering::Relaxed);
+    PageFaultCounts {
+        demand_zero: count(PageFaultKind::DemandZero),
+        copy_on_write: count(PageFaultKind::CopyOnWrite),
+        swap_in: count(PageFaultKind::SwapIn),
+        guard_page: count(PageFaultKind::GuardPage),
+        invalid: count(PageFaultKind::Invalid),
+    }
+}
+
+/// Resets the system-wide page fault counts to zero. Per-task counts are not affected.
+pub fn reset_fault_stats() {
+    for count in FAULT_COUNTS.iter() {
+        count.store(0, Ordering::Relaxed);
+    }
+}
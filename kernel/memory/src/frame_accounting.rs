@@ -0,0 +1,244 @@
+//! Optional per-owner accounting of allocated frames, which helps diagnose which crate or task
+//! has consumed the system's memory, e.g., when the system runs out of frames.
+//!
+//! Accounting is disabled by default, since it requires a table with one entry per frame of physical memory.
+//! Once enabled via [`enable_frame_accounting()`], every frame allocated from the system-wide frame allocator
+//! is attributed to the owner returned by the owner resolver (see [`set_frame_owner_resolver()`]),
+//! usually the current task, unless it was allocated with an explicit owner tag via
+//! [`allocate_frames_owned()`] or [`allocate_frame_owned()`].
+//! The frame counts of each owner can then be obtained via [`usage_by_owner()`].
+//!
+//! Frames that were allocated before accounting was enabled are not attributed to any owner.
+//! Frames held in the per-core frame caches and the pre-zeroed frame pool are attributed
+//! to the `frame_cache` and `zeroed_frames` owners, respectively.
+//!
+//! # Locking / Deadlock
+//! The accounting lock may be acquired while holding the system-wide frame allocator lock, but never vice versa.
+//! No heap allocations occur while the accounting lock is held, since the allocator may itself be
+//! allocating frames for the heap.
+//!
+//! [`enable_frame_accounting()`]: fn.enable_frame_accounting.html
+//! [`set_frame_owner_resolver()`]: fn.set_frame_owner_resolver.html
+//! [`allocate_frames_owned()`]: fn.allocate_frames_owned.html
+//! [`allocate_frame_owned()`]: fn.allocate_frame_owned.html
+//! [`usage_by_owner()`]: fn.usage_by_owner.html
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::{Frame, FrameRange, FRAME_ALLOCATOR};
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use spin::Once;
+
+
+/// The maximum number of distinct owners that can be tracked.
+/// Frames of any additional owners are attributed to `FrameOwner::Unknown`.
+const MAX_FRAME_OWNERS: usize = 1024;
+
+/// The entry in the per-frame table for frames that aren't attributed to any owner.
+const UNTRACKED: u16 = u16::max_value();
+
+/// The index of `FrameOwner::Unknown` in the list of owners.
+const UNKNOWN_OWNER_INDEX: u16 = 0;
+
+/// The owner that frames held in the per-core frame caches are attributed to.
+pub(crate) const FRAME_CACHE_OWNER: FrameOwner = FrameOwner::Crate("frame_cache");
+/// The owner that frames held in the pre-zeroed frame pool are attributed to.
+pub(crate) const ZEROED_FRAMES_OWNER: FrameOwner = FrameOwner::Crate("zeroed_frames");
+
+
+/// The entity that allocated frames are attributed to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FrameOwner {
+    /// A crate or subsystem, identified by its name.
+    Crate(&'static str),
+    /// A task, identified by its task ID.
+    Task(usize),
+    /// An owner that could not be determined, e.g., because there was no current task.
+    Unknown,
+}
+
+impl fmt::Display for FrameOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameOwner::Crate(name) => write!(f, "{}", name),
+            FrameOwner::Task(id) => write!(f, "task {}", id),
+            FrameOwner::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+
+/// The per-frame ownership table and per-owner frame counts.
+struct FrameAccounting {
+    /// The index into `owners` of the owner of each frame, indexed by frame number.
+    owner_of: Vec<u16>,
+    /// All owners that have ever been seen, which never grows beyond its initial capacity.
+    owners: Vec<FrameOwner>,
+    /// The number of frames currently attributed to each owner, with the same indices as `owners`.
+    counts: Vec<usize>,
+}
+
+impl FrameAccounting {
+    /// Returns the index of the given `owner`, adding it if it hasn't been seen before.
+    fn owner_index(&mut self, owner: FrameOwner) -> u16 {
+        if let Some(index) = self.owners.iter().position(|o| *o == owner) {
+            return index as u16;
+        }
+        // Only add new owners within the existing capacity, which avoids allocating.
+        if self.owners.len() == self.owners.capacity() {
+            return UNKNOWN_OWNER_INDEX;
+        }
+        self.owners.push(owner);
+        self.counts.push(0);
+        (self.owners.len() - 1) as u16
+    }
+
+    /// Attributes the given `frame` to the owner at `index`,
+    /// or to no owner at all if `index` is `UNTRACKED`.
+    fn set_owner(&mut self, frame: Frame, index: u16) {
+        let entry = match self.owner_of.get_mut(frame.number) {
+            Some(entry) => entry,
+            None => return, // e.g., frames in areas that were onlined after accounting was enabled
+        };
+        if *entry != UNTRACKED {
+            self.counts[*entry as usize] -= 1;
+        }
+        if index != UNTRACKED {
+            self.counts[index as usize] += 1;
+        }
+        *entry = index;
+    }
+}
+
+
+/// Whether frame accounting is enabled, which allows for checking it without acquiring the `ACCOUNTING` lock.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static ACCOUNTING: MutexIrqSafe<Option<FrameAccounting>> = MutexIrqSafe::new(None);
+
+/// The function that determines the owner of newly-allocated frames, see `set_frame_owner_resolver()`.
+static OWNER_RESOLVER: Once<fn() -> FrameOwner> = Once::new();
+
+
+/// Sets the function that determines which owner newly-allocated frames are attributed to by default,
+/// e.g., a function that returns the current task.
+///
+/// This function is invoked while the frame allocator lock is held,
+/// so it must not allocate memory or acquire any locks that may be held while allocating memory.
+pub fn set_frame_owner_resolver(func: fn() -> FrameOwner) {
+    OWNER_RESOLVER.call_once(|| func);
+}
+
+/// Returns the default owner of newly-allocated frames.
+fn current_owner() -> FrameOwner {
+    OWNER_RESOLVER.try().map(|func| func()).unwrap_or(FrameOwner::Unknown)
+}
+
+/// Returns `true` if frame accounting is currently enabled.
+pub fn frame_accounting_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Enables frame accounting, from which point on all newly-allocated frames are attributed to an owner.
+///
+/// This allocates a table with two bytes per frame of physical memory that was available at this time.
+pub fn enable_frame_accounting() -> Result<(), &'static str> {
+    if frame_accounting_enabled() {
+        return Ok(());
+    }
+    let num_frames = FRAME_ALLOCATOR.try()
+        .ok_or("enable_frame_accounting(): FRAME_ALLOCATOR not initialized")?
+        .lock()
+        .highest_available_frame()
+        .ok_or("enable_frame_accounting(): there are no available memory areas")?
+        .number + 1;
+
+    // Allocate everything before acquiring the lock.
+    let mut owners = Vec::with_capacity(MAX_FRAME_OWNERS);
+    owners.push(FrameOwner::Unknown);
+    let mut counts = Vec::with_capacity(MAX_FRAME_OWNERS);
+    counts.push(0);
+    let mut owner_of = Vec::new();
+    owner_of.resize(num_frames, UNTRACKED);
+    let accounting = FrameAccounting { owner_of, owners, counts };
+
+    let mut guard = ACCOUNTING.lock();
+    if guard.is_none() {
+        *guard = Some(accounting);
+        ENABLED.store(true, Ordering::Release);
+        info!("Enabled frame accounting for {} frames", num_frames);
+    }
+    Ok(())
+}
+
+/// Disables frame accounting and discards all per-owner frame counts.
+pub fn disable_frame_accounting() {
+    ENABLED.store(false, Ordering::Release);
+    // Drop the (large) table after releasing the lock.
+    let _accounting = ACCOUNTING.lock().take();
+}
+
+/// Returns the number of frames currently attributed to each owner, from the most to the fewest frames.
+/// Owners without any frames are omitted.
+///
+/// Returns an empty list if frame accounting is disabled.
+pub fn usage_by_owner() -> Vec<(FrameOwner, usize)> {
+    let mut usage = Vec::with_capacity(MAX_FRAME_OWNERS);
+    {
+        let accounting = ACCOUNTING.lock();
+        if let Some(accounting) = accounting.as_ref() {
+            // `usage` has enough capacity, so this doesn't allocate while holding the lock.
+            usage.extend(accounting.owners.iter().cloned().zip(accounting.counts.iter().cloned()).filter(|&(_, count)| count > 0));
+        }
+    }
+    usage.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    usage
+}
+
+
+/// Attributes the given newly-allocated `frames` to the current owner.
+pub(crate) fn record_allocation(frames: &FrameRange) {
+    if frame_accounting_enabled() {
+        set_owner(frames, Some(current_owner()));
+    }
+}
+
+/// Removes the given deallocated `frame` from its owner's frame count.
+pub(crate) fn record_deallocation(frame: Frame) {
+    if frame_accounting_enabled() {
+        set_owner(&FrameRange::new(frame, frame), None);
+    }
+}
+
+/// Attributes the given allocated `frames` to the given `owner`, or to the current owner if `None`.
+pub(crate) fn reassign(frames: &FrameRange, owner: Option<FrameOwner>) {
+    if frame_accounting_enabled() {
+        set_owner(frames, Some(owner.unwrap_or_else(current_owner)));
+    }
+}
+
+/// Attributes the given `new_frame` to the owner of the given `old_frame`, 
+/// e.g., because the contents of `old_frame` were migrated into `new_frame`.
+pub(crate) fn transfer_owner(old_frame: Frame, new_frame: Frame) {
+    if frame_accounting_enabled() {
+        let mut accounting = ACCOUNTING.lock();
+        if let Some(accounting) = accounting.as_mut() {
+            let index = accounting.owner_of.get(old_frame.number).cloned().unwrap_or(UNTRACKED);
+            accounting.set_owner(new_frame, index);
+        }
+    }
+}
+
+fn set_owner(frames: &FrameRange, owner: Option<FrameOwner>) {
+    let mut accounting = ACCOUNTING.lock();
+    if let Some(accounting) = accounting.as_mut() {
+        let index = match owner {
+            Some(owner) => accounting.owner_index(owner),
+            None => UNTRACKED,
+        };
+        for frame in frames.clone() {
+            accounting.set_owner(frame, index);
+        }
+    }
+}
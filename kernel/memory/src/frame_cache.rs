@@ -0,0 +1,199 @@
+//! Per-core caches of physical frames that sit in front of the system-wide frame allocator.
+//!
+//! Each core has its own small cache of frames, such that allocating and deallocating individual frames
+//! usually only requires acquiring that core's lock, rather than the lock on the one system-wide `AreaFrameAllocator`.
+//! When a core's cache is empty, it is refilled with a batch of frames from the system-wide allocator,
+//! and when a core's cache is full, a batch of frames is returned to the system-wide allocator.
+//!
+//! Contiguous multi-frame allocations always go directly to the system-wide allocator.
+//!
+//! # Locking
+//! A core's frame cache lock may be held while acquiring the system-wide frame allocator lock, but never vice versa.
+
+use super::{Frame, FrameRange, FrameAllocator, FrameAllocError, FRAME_ALLOCATOR, current_apic_id, frame_pinning};
+use super::frame_accounting::{self, FRAME_CACHE_OWNER};
+use super::numa::{my_numa_node, allocate_frame_on_node, allocate_frames_on_node};
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use spin::Once;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::{FRAME_CACHE_CAPACITY, FRAME_CACHE_BATCH_SIZE};
+
+
+/// The set of per-core frame caches, one per core, keyed by the core's APIC ID.
+static FRAME_CACHES: Once<BTreeMap<u8, MutexIrqSafe<FrameCache>>> = Once::new();
+
+
+/// A cache of free frames that belongs to a single core.
+struct FrameCache {
+    frames: Vec<Frame>,
+}
+
+impl FrameCache {
+    /// Moves up to `FRAME_CACHE_BATCH_SIZE` frames into this cache, 
+    /// preferably from the current core's NUMA node, otherwise from the system-wide frame allocator.
+    fn refill(&mut self) {
+        let previous_len = self.frames.len();
+        self.refill_frames();
+        for frame in &self.frames[previous_len..] {
+            frame_accounting::reassign(&FrameRange::new(*frame, *frame), Some(FRAME_CACHE_OWNER));
+        }
+    }
+
+    /// Moves up to `FRAME_CACHE_BATCH_SIZE` frames into this cache without attributing them to the cache.
+    fn refill_frames(&mut self) {
+        if let Some(node) = my_numa_node() {
+            for _ in 0..FRAME_CACHE_BATCH_SIZE {
+                match allocate_frame_on_node(node) {
+                    Some(f) => self.frames.push(f),
+                    None => break,
+                }
+            }
+            if !self.frames.is_empty() {
+                return;
+            }
+        }
+        if let Some(fa) = FRAME_ALLOCATOR.try() {
+            let mut fa = fa.lock();
+            for _ in 0..FRAME_CACHE_BATCH_SIZE {
+                match fa.allocate_frame() {
+                    Ok(f) => self.frames.push(f),
+                    Err(_e) => break,
+                }
+            }
+        }
+    }
+
+    /// Returns `num_frames` frames from this cache back to the system-wide frame allocator.
+    fn drain(&mut self, num_frames: usize) {
+        if let Some(fa) = FRAME_ALLOCATOR.try() {
+            let mut fa = fa.lock();
+            let start = self.frames.len().saturating_sub(num_frames);
+            for f in self.frames.drain(start..) {
+                fa.deallocate_frame(f);
+            }
+        }
+    }
+}
+
+
+/// Initializes a frame cache for each of the given cores. 
+/// 
+/// Until this is invoked, all frame allocation requests go directly to the system-wide frame allocator.
+/// This should be called once all cores have been discovered and the heap has been initialized.
+/// 
+/// # Arguments
+/// * `apic_ids`: the APIC IDs of the cores that should have a frame cache.
+pub fn init_frame_caches<I: IntoIterator<Item = u8>>(apic_ids: I) -> Result<(), &'static str> {
+    if FRAME_CACHES.try().is_some() {
+        return Err("per-core frame caches were already initialized");
+    }
+    let caches = apic_ids.into_iter()
+        .map(|id| (id, MutexIrqSafe::new(FrameCache { frames: Vec::with_capacity(FRAME_CACHE_CAPACITY) })))
+        .collect();
+    FRAME_CACHES.call_once(|| caches);
+    Ok(())
+}
+
+
+/// Returns the current core's frame cache, if it exists.
+fn my_frame_cache() -> Option<&'static MutexIrqSafe<FrameCache>> {
+    FRAME_CACHES.try().and_then(|caches| caches.get(&current_apic_id()))
+}
+
+
+/// Returns all cached frames on every core back to the system-wide frame allocator.
+/// 
+/// This is useful when memory is scarce, since frames cached on other cores 
+/// cannot otherwise be allocated by the current core. 
+/// Returns the number of frames that were returned.
+pub fn flush_frame_caches() -> usize {
+    let mut flushed = 0;
+    if let Some(caches) = FRAME_CACHES.try() {
+        for cache in caches.values() {
+            let mut cache = cache.lock();
+            let count = cache.frames.len();
+            cache.drain(count);
+            flushed += count;
+        }
+    }
+    flushed
+}
+
+
+/// Removes the given `frame` from whichever core's frame cache holds it, returning `true` if it was found.
+pub(crate) fn remove_cached_frame(frame: Frame) -> bool {
+    FRAME_CACHES.try().map_or(false, |caches| caches.values().any(|cache| {
+        let mut cache = cache.lock();
+        match cache.frames.iter().position(|f| *f == frame) {
+            Some(index) => { cache.frames.swap_remove(index); true }
+            None => false,
+        }
+    }))
+}
+
+
+/// A `FrameAllocator` that allocates single frames from the current core's frame cache,
+/// falling back to the system-wide frame allocator if the current core has no cache.
+/// Multiple contiguous frames are allocated from the current core's NUMA node if possible. 
+/// 
+/// This is a zero-sized type, so it's cheap to create one whenever it's needed, 
+/// e.g., to pass one into the functions that map pages.
+pub struct CachedFrameAllocator;
+
+impl FrameAllocator for CachedFrameAllocator {
+    fn allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        if let Some(cache) = my_frame_cache() {
+            let mut cache = cache.lock();
+            if cache.frames.is_empty() {
+                cache.refill();
+            }
+            if let Some(f) = cache.frames.pop() {
+                frame_accounting::reassign(&FrameRange::new(f, f), None);
+                return Ok(f);
+            }
+        }
+
+        // Here, either there are no frame caches, or the system-wide frame allocator is out of frames.
+        // Release frames from the other cores' caches and try again.
+        let fa = FRAME_ALLOCATOR.try().ok_or(FrameAllocError::OutOfMemory)?;
+        let frame = fa.lock().allocate_frame();
+        if frame == Err(FrameAllocError::OutOfMemory) && flush_frame_caches() > 0 {
+            return fa.lock().allocate_frame();
+        }
+        frame
+    }
+
+    fn allocate_frames(&mut self, num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+        if num_frames == 1 {
+            return self.allocate_frame().map(|f| FrameRange::new(f, f));
+        }
+        let frames = match my_numa_node().and_then(|node| allocate_frames_on_node(node, num_frames)) {
+            Some(frames) => frames,
+            None => FRAME_ALLOCATOR.try().ok_or(FrameAllocError::OutOfMemory)?.lock().allocate_frames(num_frames)?,
+        };
+        // Frames reserved from a NUMA node's memory aren't attributed to an owner by the system-wide allocator.
+        frame_accounting::reassign(&frames, None);
+        Ok(frames)
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if frame_pinning::is_pinned_on_dealloc(frame) {
+            return;
+        }
+        if let Some(cache) = my_frame_cache() {
+            let mut cache = cache.lock();
+            frame_accounting::reassign(&FrameRange::new(frame, frame), Some(FRAME_CACHE_OWNER));
+            cache.frames.push(frame);
+            if cache.frames.len() > FRAME_CACHE_CAPACITY {
+                cache.drain(FRAME_CACHE_BATCH_SIZE);
+            }
+        } else if let Some(fa) = FRAME_ALLOCATOR.try() {
+            fa.lock().deallocate_frame(frame);
+        }
+    }
+
+    fn alloc_ready(&mut self) { }
+}
@@ -0,0 +1,149 @@
+//! Pinning of physical frames that are the targets of in-progress device DMA.
+//!
+//! A pinned frame must not be moved, e.g., by memory compaction or page migration,
+//! nor reclaimed, e.g., by a balloon driver, because a device may read or write it at any time
+//! without going through the CPU's page tables.
+//! A driver invokes [`pin_frames()`] before handing a buffer's physical address to a device,
+//! and [`unpin_frames()`] once the device is known to be done with it.
+//!
+//! Pins are counted per frame, so the same frames can be pinned several times,
+//! e.g., by multiple descriptors that refer to the same buffer,
+//! and they remain pinned until every pin has been released.
+//! Deallocating a pinned frame is a bug, so such a frame is leaked instead of being reused.
+//!
+//! [`pin_frames()`]: fn.pin_frames.html
+//! [`unpin_frames()`]: fn.unpin_frames.html
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::{Frame, FrameRange};
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+
+
+/// The pin counts of all pinned frames.
+///
+/// This is a list of `(frame, pin count)` sorted by frame, in the same manner as the frame refcount map.
+struct PinCountMap {
+    counts: Vec<(Frame, usize)>,
+}
+impl PinCountMap {
+    fn index_of(&self, frame: Frame) -> Result<usize, usize> {
+        self.counts.binary_search_by_key(&frame, |&(f, _)| f)
+    }
+}
+
+static PIN_COUNTS: MutexIrqSafe<PinCountMap> = MutexIrqSafe::new(PinCountMap { counts: Vec::new() });
+
+/// The number of frames currently pinned, which allows the common case
+/// of no pinned frames to skip acquiring the pin count map lock.
+static NUM_PINNED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Pins the given `frames`, such that they will not be moved or reclaimed until they are unpinned.
+///
+/// Each frame's pin count is incremented, so frames that are already pinned are pinned again.
+pub fn pin_frames(frames: &FrameRange) {
+    if frames.start() > frames.end() {
+        return;
+    }
+    let mut map = PIN_COUNTS.lock();
+    let mut idx = match map.index_of(*frames.start()) {
+        Ok(idx) | Err(idx) => idx,
+    };
+    let mut newly_pinned = 0;
+    for frame in frames.clone() {
+        if idx < map.counts.len() && map.counts[idx].0 == frame {
+            map.counts[idx].1 += 1;
+        } else {
+            map.counts.insert(idx, (frame, 1));
+            newly_pinned += 1;
+        }
+        idx += 1;
+    }
+    NUM_PINNED_FRAMES.fetch_add(newly_pinned, Ordering::SeqCst);
+}
+
+/// Releases one pin on each of the given `frames`.
+/// The frames whose final pin was released can be moved or reclaimed again.
+///
+/// Returns an error without unpinning any frames if some of the given `frames` are not pinned,
+/// which indicates the caller has unpinned them more times than it pinned them.
+pub fn unpin_frames(frames: &FrameRange) -> Result<(), &'static str> {
+    if frames.start() > frames.end() {
+        return Ok(());
+    }
+    let mut map = PIN_COUNTS.lock();
+    let start_idx = map.index_of(*frames.start()).map_err(|_| "unpin_frames(): the first frame was not pinned")?;
+    let end_idx = start_idx + frames.size_in_frames();
+    // Pinned frames are unique and sorted, so the given frames are all pinned iff they're the contiguous entries starting here.
+    if end_idx > map.counts.len() || map.counts[end_idx - 1].0 != *frames.end() {
+        return Err("unpin_frames(): some of the frames were not pinned");
+    }
+    for entry in map.counts[start_idx .. end_idx].iter_mut() {
+        entry.1 -= 1;
+    }
+    let before = map.counts.len();
+    map.counts.retain(|&(_, count)| count > 0);
+    NUM_PINNED_FRAMES.fetch_sub(before - map.counts.len(), Ordering::SeqCst);
+    Ok(())
+}
+
+/// Returns the pin count of the given `frame`, which is `0` if it is not pinned.
+pub fn pin_count(frame: Frame) -> usize {
+    if !is_any_frame_pinned() {
+        return 0;
+    }
+    let map = PIN_COUNTS.lock();
+    map.index_of(frame).ok().map_or(0, |idx| map.counts[idx].1)
+}
+
+/// Returns the pinned parts of the given `frames` as a list of `(frame range, pin count)`, sorted by frame,
+/// in which each range is a maximal run of contiguous frames that all have the same pin count.
+///
+/// An empty list means that none of the given `frames` are pinned, so they can all be moved or reclaimed.
+pub fn pin_counts(frames: &FrameRange) -> Vec<(FrameRange, usize)> {
+    let mut runs: Vec<(FrameRange, usize)> = Vec::new();
+    if !is_any_frame_pinned() || frames.start() > frames.end() {
+        return runs;
+    }
+    let map = PIN_COUNTS.lock();
+    let start_idx = match map.index_of(*frames.start()) {
+        Ok(idx) | Err(idx) => idx,
+    };
+    for &(frame, count) in map.counts[start_idx..].iter().take_while(|&&(f, _)| f <= *frames.end()) {
+        match runs.last_mut() {
+            Some((run, run_count)) if *run_count == count && run.end().number + 1 == frame.number => {
+                *run = FrameRange::new(*run.start(), frame);
+            }
+            _ => runs.push((FrameRange::new(frame, frame), count)),
+        }
+    }
+    runs
+}
+
+/// Returns `true` if any of the given `frames` are pinned.
+pub fn is_pinned(frames: &FrameRange) -> bool {
+    if !is_any_frame_pinned() || frames.start() > frames.end() {
+        return false;
+    }
+    let map = PIN_COUNTS.lock();
+    match map.index_of(*frames.start()) {
+        Ok(_) => true,
+        Err(idx) => idx < map.counts.len() && map.counts[idx].0 <= *frames.end(),
+    }
+}
+
+/// Returns `true` if the given `frame` is pinned, in which case it must not be deallocated
+/// and should be leaked instead, because a device may still be accessing it.
+pub(crate) fn is_pinned_on_dealloc(frame: Frame) -> bool {
+    let count = pin_count(frame);
+    if count > 0 {
+        error!("BUG: leaking {:?} instead of deallocating it, since it is still pinned {} times for DMA", frame, count);
+    }
+    count > 0
+}
+
+/// Returns `true` if there are any pinned frames at all.
+pub(crate) fn is_any_frame_pinned() -> bool {
+    NUM_PINNED_FRAMES.load(Ordering::SeqCst) > 0
+}
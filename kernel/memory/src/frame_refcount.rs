@@ -0,0 +1,149 @@
+//! Reference counting for physical frames that are shared between multiple `MappedPages`.
+//!
+//! By default, a frame is not tracked here, meaning that it has no refcount
+//! and is never deallocated when a `MappedPages` that maps it is dropped
+//! (it may be MMIO memory, part of the kernel image, etc).
+//! A frame becomes tracked when [`incref()`] is first invoked on it;
+//! after that, its refcount is the number of mappings that currently refer to it:
+//! * Each time a tracked frame is mapped again via `Mapper::map_allocated_pages_to()`, its refcount is incremented.
+//! * `MappedPages::clone_cow()` starts tracking the frames it shares and increments their refcounts
+//!   for the clone's references to them.
+//! * Each time a `MappedPages` that maps a tracked frame is dropped, its refcount is decremented,
+//!   and the frame is only deallocated once the final reference is dropped.
+//!
+//! Thus, the typical usage is to invoke [`incref()`] on the frames of a newly-created `MappedPages`
+//! before sharing those frames with another mapping.
+//!
+//! # Locking
+//! The refcount map lock is never held while acquiring the system-wide frame allocator lock.
+//! This allows it to be used by the mapping functions while the caller holds the frame allocator lock.
+//!
+//! [`incref()`]: fn.incref.html
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::Frame;
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::SCRUB_FRAMES_ON_FREE;
+
+
+/// The refcounts of all tracked frames.
+///
+/// This is a list of `(frame, refcount)` sorted by frame, such that it can be created statically
+/// and looked up before the heap is initialized, as long as no frames are tracked yet.
+struct FrameRefcountMap {
+    counts: Vec<(Frame, usize)>,
+}
+impl FrameRefcountMap {
+    fn index_of(&self, frame: Frame) -> Result<usize, usize> {
+        self.counts.binary_search_by_key(&frame, |&(f, _)| f)
+    }
+}
+
+static FRAME_REFCOUNTS: MutexIrqSafe<FrameRefcountMap> = MutexIrqSafe::new(FrameRefcountMap { counts: Vec::new() });
+
+/// The number of frames currently being tracked, which allows the common case
+/// of no tracked frames to skip acquiring the refcount map lock.
+static NUM_TRACKED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Increments the refcount of the given `frame`, and returns the new refcount.
+///
+/// If the `frame` was not yet tracked, it starts being tracked with a refcount of 1,
+/// which represents the one existing mapping of that frame.
+pub fn incref(frame: Frame) -> usize {
+    let mut map = FRAME_REFCOUNTS.lock();
+    match map.index_of(frame) {
+        Ok(idx) => {
+            map.counts[idx].1 += 1;
+            map.counts[idx].1
+        }
+        Err(idx) => {
+            map.counts.insert(idx, (frame, 1));
+            NUM_TRACKED_FRAMES.fetch_add(1, Ordering::SeqCst);
+            1
+        }
+    }
+}
+
+/// Decrements the refcount of the given `frame`.
+///
+/// Returns `true` if that was the final reference to the `frame`, in which case it is no longer tracked
+/// and must be deallocated by the caller.
+/// Returns `false` if the `frame` is still referenced or was not being tracked at all.
+#[must_use]
+pub(crate) fn decref_no_dealloc(frame: Frame) -> bool {
+    if !is_any_frame_tracked() {
+        return false;
+    }
+    let mut map = FRAME_REFCOUNTS.lock();
+    match map.index_of(frame) {
+        Ok(idx) if map.counts[idx].1 > 1 => {
+            map.counts[idx].1 -= 1;
+            false
+        }
+        Ok(idx) => {
+            map.counts.remove(idx);
+            NUM_TRACKED_FRAMES.fetch_sub(1, Ordering::SeqCst);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Decrements the refcount of the given `frame`,
+/// deallocating it if that was the final reference to it.
+/// If `SCRUB_FRAMES_ON_FREE` is enabled, the frame is scrubbed before it is deallocated.
+///
+/// Returns the new refcount, or `None` if the given `frame` was not being tracked.
+///
+/// This should not be invoked on a frame that is still mapped by a `MappedPages` object,
+/// since that frame will be decremented again when the `MappedPages` object is dropped.
+pub fn decref(frame: Frame) -> Option<usize> {
+    let remaining = {
+        let mut map = FRAME_REFCOUNTS.lock();
+        let idx = map.index_of(frame).ok()?;
+        map.counts[idx].1 -= 1;
+        let remaining = map.counts[idx].1;
+        if remaining == 0 {
+            map.counts.remove(idx);
+            NUM_TRACKED_FRAMES.fetch_sub(1, Ordering::SeqCst);
+        }
+        remaining
+    };
+    if remaining == 0 {
+        if SCRUB_FRAMES_ON_FREE {
+            if let Err(e) = super::deallocate_frame_scrubbed(frame) {
+                error!("frame_refcount::decref(): failed to scrub frame {:?}, leaking it instead. Error: {}", frame, e);
+            }
+        } else {
+            super::deallocate_frame(frame);
+        }
+    }
+    Some(remaining)
+}
+
+/// Returns the current refcount of the given `frame`, or `None` if it is not being tracked.
+pub fn refcount(frame: Frame) -> Option<usize> {
+    if !is_any_frame_tracked() {
+        return None;
+    }
+    let map = FRAME_REFCOUNTS.lock();
+    map.index_of(frame).ok().map(|idx| map.counts[idx].1)
+}
+
+/// Increments the refcount of the given `frame` only if it is already being tracked.
+pub(crate) fn incref_if_tracked(frame: Frame) {
+    if !is_any_frame_tracked() {
+        return;
+    }
+    let mut map = FRAME_REFCOUNTS.lock();
+    if let Ok(idx) = map.index_of(frame) {
+        map.counts[idx].1 += 1;
+    }
+}
+
+/// Returns `true` if there are any tracked frames at all.
+pub(crate) fn is_any_frame_tracked() -> bool {
+    NUM_TRACKED_FRAMES.load(Ordering::SeqCst) > 0
+}
@@ -0,0 +1,142 @@
+//! Support for allocating huge frames, i.e., contiguous ranges of frames
+//! that are aligned to and sized in multiples of 2MiB or 1GiB.
+//!
+//! Huge frames are needed for huge page mappings and large DMA buffers,
+//! but are difficult to obtain once physical memory has become fragmented.
+//! Thus, a pool of huge frames of each size is reserved at boot, 
+//! as configured by `HUGE_FRAME_POOL_2MIB_COUNT` and `HUGE_FRAME_POOL_1GIB_COUNT`.
+//! Allocations are first satisfied from these pools, and then from the system-wide frame allocator.
+//! Deallocated huge frames are returned to their pool and can be reused.
+//!
+//! # Locking
+//! A huge frame pool lock may be held while acquiring the system-wide frame allocator lock, but never vice versa.
+
+use super::{Frame, FrameRange, FRAME_ALLOCATOR};
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::{PAGE_SIZE, HUGE_FRAME_POOL_2MIB_COUNT, HUGE_FRAME_POOL_1GIB_COUNT};
+
+
+/// The supported sizes of huge frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HugeSize {
+    /// A 2MiB huge frame, which can back a huge page mapped by a P2 entry.
+    Size2MiB,
+    /// A 1GiB huge frame, which can back a huge page mapped by a P3 entry.
+    Size1GiB,
+}
+
+impl HugeSize {
+    /// Returns the size of one huge frame in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        match self {
+            HugeSize::Size2MiB => 2 * 1024 * 1024,
+            HugeSize::Size1GiB => 1024 * 1024 * 1024,
+        }
+    }
+
+    /// Returns the number of regular frames in one huge frame, 
+    /// which is also the required alignment of its first frame number.
+    pub fn size_in_frames(&self) -> usize {
+        self.size_in_bytes() / PAGE_SIZE
+    }
+
+    fn pool(&self) -> &'static MutexIrqSafe<Vec<Frame>> {
+        match self {
+            HugeSize::Size2MiB => &HUGE_FRAME_POOL_2MIB,
+            HugeSize::Size1GiB => &HUGE_FRAME_POOL_1GIB,
+        }
+    }
+}
+
+/// The pool of free 2MiB huge frames, each represented by its first frame, sorted in ascending order.
+static HUGE_FRAME_POOL_2MIB: MutexIrqSafe<Vec<Frame>> = MutexIrqSafe::new(Vec::new());
+/// The pool of free 1GiB huge frames, each represented by its first frame, sorted in ascending order.
+static HUGE_FRAME_POOL_1GIB: MutexIrqSafe<Vec<Frame>> = MutexIrqSafe::new(Vec::new());
+
+
+/// Reserves the boot-time pools of huge frames from the system-wide frame allocator.
+/// 
+/// This should be invoked as early as possible, i.e., right after the heap has been initialized,
+/// before physical memory becomes fragmented. 
+/// If the configured number of huge frames cannot be reserved, as many as possible are reserved.
+pub(crate) fn init_huge_frame_pools() -> Result<(), &'static str> {
+    for &(size, count) in &[(HugeSize::Size1GiB, HUGE_FRAME_POOL_1GIB_COUNT), (HugeSize::Size2MiB, HUGE_FRAME_POOL_2MIB_COUNT)] {
+        if count == 0 {
+            continue;
+        }
+        let mut pool = size.pool().lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.try().ok_or("BUG: FRAME_ALLOCATOR not initialized")?.lock();
+        for _ in 0 .. count {
+            match frame_allocator.reserve_aligned_frames(size.size_in_frames(), size.size_in_frames()) {
+                Some(frames) => pool.push(*frames.start()),
+                None => break,
+            }
+        }
+        pool.sort_unstable();
+        if pool.len() < count {
+            warn!("Only reserved {} of {} requested {:?} huge frames", pool.len(), count, size);
+        } else {
+            info!("Reserved {} {:?} huge frames", pool.len(), size);
+        }
+    }
+    Ok(())
+}
+
+
+/// Allocates `count` contiguous huge frames of the given `size`, 
+/// returning a range of regular frames whose start is aligned to `size`.
+/// 
+/// The huge frames are taken from the boot-time pool if possible,
+/// otherwise they are reserved from the system-wide frame allocator.
+/// The returned frames should be freed with [`deallocate_huge_frames()`](fn.deallocate_huge_frames.html).
+pub fn allocate_huge_frames(count: usize, size: HugeSize) -> Option<FrameRange> {
+    if count == 0 {
+        return None;
+    }
+    let frames_per_huge_frame = size.size_in_frames();
+    let mut pool = size.pool().lock();
+
+    // Find a run of `count` adjacent huge frames in the sorted pool.
+    let run_start_index = (0 ..= pool.len().checked_sub(count)?).find(|&i| {
+        let first = pool[i].number;
+        pool[i .. i + count].iter().enumerate().all(|(j, f)| f.number == first + j * frames_per_huge_frame)
+    });
+    let start = match run_start_index {
+        Some(i) => pool.drain(i .. i + count).next()?,
+        None => {
+            let mut frame_allocator = FRAME_ALLOCATOR.try()?.lock();
+            *frame_allocator.reserve_aligned_frames(count * frames_per_huge_frame, frames_per_huge_frame)?.start()
+        }
+    };
+    Some(FrameRange::new(start, start + (count * frames_per_huge_frame - 1)))
+}
+
+/// Returns the given range of huge frames, previously obtained from [`allocate_huge_frames()`], 
+/// to the pool of free huge frames of the given `size`.
+/// 
+/// Returns an error if the range isn't aligned to and sized in multiples of `size`.
+/// 
+/// [`allocate_huge_frames()`]: fn.allocate_huge_frames.html
+pub fn deallocate_huge_frames(frames: FrameRange, size: HugeSize) -> Result<(), &'static str> {
+    let frames_per_huge_frame = size.size_in_frames();
+    let start = frames.start().number;
+    let num_frames = frames.end().number + 1 - start;
+    if start % frames_per_huge_frame != 0 || num_frames % frames_per_huge_frame != 0 {
+        return Err("deallocate_huge_frames(): frame range was not aligned to the given huge frame size");
+    }
+    let mut pool = size.pool().lock();
+    for i in 0 .. (num_frames / frames_per_huge_frame) {
+        let frame = Frame { number: start + i * frames_per_huge_frame };
+        match pool.binary_search(&frame) {
+            Ok(_) => return Err("deallocate_huge_frames(): huge frame was already free"),
+            Err(index) => pool.insert(index, frame),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the number of free huge frames of the given `size` in the pool.
+pub fn free_huge_frame_count(size: HugeSize) -> usize {
+    size.pool().lock().len()
+}
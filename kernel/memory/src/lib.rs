@@ -6,10 +6,16 @@
 #![no_std]
 #![feature(ptr_internals)]
 #![feature(unboxed_closures)]
+#![feature(min_const_generics)]
+
+#[cfg(test)]
+#[macro_use] extern crate std;
+#[cfg(test)]
+extern crate page_table_model;
 
 extern crate spin;
 extern crate multiboot2;
-extern crate alloc;
+#[macro_use] extern crate alloc;
 #[macro_use] extern crate log;
 extern crate irq_safety;
 extern crate kernel_config;
@@ -22,9 +28,34 @@ extern crate x86_64;
 extern crate memory_structs;
 extern crate page_allocator;
 extern crate zerocopy;
+extern crate raw_cpuid;
 
 
 mod area_frame_allocator;
+mod bitmap_frame_allocator;
+mod buddy_frame_allocator;
+mod cma;
+mod crash_kernel;
+mod compaction;
+mod copy_on_write;
+mod demand_paging;
+mod fault_stats;
+mod frame_accounting;
+mod frame_cache;
+mod frame_pinning;
+pub mod frame_refcount;
+mod huge_frames;
+mod memory_pressure;
+mod numa;
+mod quarantine;
+mod reserved_regions;
+mod swap;
+mod system_frame_allocator;
+mod telemetry;
+mod tlb_batch;
+mod user_access;
+mod vmalloc;
+mod zeroed_frames;
 #[cfg(not(mapper_spillful))]
 mod paging;
 
@@ -33,7 +64,48 @@ pub mod paging;
 
 
 pub use self::area_frame_allocator::AreaFrameAllocator;
+pub use self::bitmap_frame_allocator::BitmapFrameAllocator;
+pub use self::buddy_frame_allocator::BuddyFrameAllocator;
+pub use self::cma::{cma_alloc, cma_free, cma_free_frame_count};
+pub use self::compaction::{compact, migrate_frames, register_movable, unregister_movable};
+pub use self::crash_kernel::{CRASH_KERNEL_BOOT_ARG, crash_kernel_region};
+pub use self::copy_on_write::handle_cow_page_fault;
+pub use self::demand_paging::{DemandPagingStats, demand_paging_stats, handle_demand_page_fault};
+pub use self::fault_stats::{PageFaultKind, PageFaultCounts, record_page_fault, fault_stats, reset_fault_stats};
+pub use self::frame_accounting::{
+    FrameOwner, set_frame_owner_resolver, enable_frame_accounting, disable_frame_accounting,
+    frame_accounting_enabled, usage_by_owner,
+};
+pub use self::frame_cache::{CachedFrameAllocator, init_frame_caches, flush_frame_caches};
+pub use self::frame_pinning::{pin_frames, unpin_frames, pin_count, pin_counts, is_pinned};
+pub use self::huge_frames::{HugeSize, allocate_huge_frames, deallocate_huge_frames, free_huge_frame_count};
+pub use self::memory_pressure::{
+    PressureLevel, ReclaimFn, register_reclaimer, unregister_reclaimer, reclaimer_names,
+    set_watermarks, watermarks, free_frame_count, pressure_level, reclaim_memory,
+};
+pub use self::numa::*;
+pub use self::quarantine::{BAD_FRAMES_BOOT_ARG, quarantine_frame, quarantined_frames, quarantine_boot_arg};
+pub use self::reserved_regions::{ReservedRegion, reserve_physical_region, reserved_regions};
+pub use self::swap::{
+    SwapBackend, SwapStats, MAX_SWAP_SLOTS, set_swap_backend, remove_swap_backend, register_swappable, unregister_swappable,
+    swap_stats, swap_out, handle_swap_page_fault,
+};
+pub use self::system_frame_allocator::{
+    SystemFrameAllocator, FrameAllocatorBackend, FrameAllocatorKind, FRAME_ALLOCATOR_BOOT_ARG, selected_backend,
+};
+pub use self::telemetry::TelemetrySnapshot;
+pub use self::tlb_batch::{broadcast_tlb_shootdown, TlbShootdownBatch};
+#[cfg(feature = "memory_telemetry")]
+pub use self::telemetry::{telemetry_snapshot, reset_telemetry};
+pub use self::user_access::{UserAccessGuard, enable_smep_smap, smap_enabled, with_user_access};
+pub use self::vmalloc::{VmallocPages, vmalloc, vmalloc_with_max_segments};
+pub use self::zeroed_frames::{
+    allocate_zeroed_frame, add_zeroed_frames, take_freed_frames, freed_frame_count,
+    zeroed_frame_pool_deficit, set_frames_freed_notifier,
+};
 pub use self::paging::*;
+// These shadow the functions of the same names in `memory_x86_64`, which don't account for PCIDs.
+pub use self::paging::{tlb_flush_virt_addr, tlb_flush_all};
 
 pub use memory_structs::*;
 pub use page_allocator::*;
@@ -49,8 +121,7 @@ use spin::Once;
 use irq_safety::MutexIrqSafe;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
-use kernel_config::memory::KERNEL_OFFSET;
-use core::ops::DerefMut;
+use kernel_config::memory::{KERNEL_OFFSET, MAX_PRE_HEAP_MEMORY_AREAS, PAGE_SIZE};
 
 /// The memory management info and address space of the kernel
 static KERNEL_MMI: Once<MmiRef> = Once::new();
@@ -66,7 +137,7 @@ pub fn get_kernel_mmi_ref() -> Option<MmiRef> {
 
 
 /// The one and only frame allocator, a singleton. 
-static FRAME_ALLOCATOR: Once<MutexIrqSafe<AreaFrameAllocator>> = Once::new();
+static FRAME_ALLOCATOR: Once<MutexIrqSafe<SystemFrameAllocator>> = Once::new();
 
 /// A shareable reference to a `FrameAllocator` struct wrapper in a lock.
 #[allow(type_alias_bounds)]
@@ -75,19 +146,166 @@ pub type FrameAllocatorRef<A: FrameAllocator> = MutexIrqSafe<A>;
 /// Returns a reference to the system-wide `FrameAllocator`, if initialized.
 /// If not, it returns `None`.
 /// 
-/// Currently, the system-wide allocator is an `AreaFrameAllocator` reference.
-pub fn get_frame_allocator_ref() -> Option<&'static FrameAllocatorRef<AreaFrameAllocator>> {
+/// The system-wide allocator uses the frame allocator backend that was selected at boot,
+/// see [`SystemFrameAllocator`](enum.SystemFrameAllocator.html).
+pub fn get_frame_allocator_ref() -> Option<&'static FrameAllocatorRef<SystemFrameAllocator>> {
     FRAME_ALLOCATOR.try()
 }
 
+/// Returns the APIC ID of the current core.
+/// 
+/// This is the same way that the `apic` crate determines the current core's APIC ID,
+/// which we cannot depend on here. 
+fn current_apic_id() -> u8 {
+    x86_64::registers::msr::rdmsr(x86_64::registers::msr::IA32_TSC_AUX) as u8
+}
+
+/// Invokes the given allocation function, checking for memory pressure afterwards.
+/// If the allocation fails, memory is reclaimed and the allocation is retried once,
+/// see [`register_reclaimer()`](fn.register_reclaimer.html).
+/// If the retry also fails, its error is returned.
+fn allocate_with_reclaim<T, E, F: FnMut() -> Result<T, E>>(num_frames: usize, mut allocate: F) -> Result<T, E> {
+    match allocate() {
+        Ok(allocated) => {
+            memory_pressure::check_memory_pressure();
+            return Ok(allocated);
+        }
+        Err(e) => if memory_pressure::reclaim_after_failed_allocation(num_frames) == 0 {
+            return Err(e);
+        }
+    }
+    allocate()
+}
+
 /// Convenience method for allocating a new Frame.
-pub fn allocate_frame() -> Option<Frame> {
-    FRAME_ALLOCATOR.try().and_then(|fa| fa.lock().allocate_frame())
+/// 
+/// This uses the current core's frame cache, if one exists.
+pub fn allocate_frame() -> Result<Frame, FrameAllocError> {
+    allocate_with_reclaim(1, || CachedFrameAllocator.allocate_frame())
 }
 
 /// Convenience method for allocating several contiguous Frames.
-pub fn allocate_frames(num_frames: usize) -> Option<FrameRange> {
-    FRAME_ALLOCATOR.try().and_then(|fa| fa.lock().allocate_frames(num_frames))
+/// 
+/// The frames are allocated from the current core's NUMA node, if possible.
+/// If free memory is too fragmented, it is compacted and the allocation is retried once,
+/// see [`compact()`](fn.compact.html).
+pub fn allocate_frames(num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+    match allocate_with_reclaim(num_frames, || CachedFrameAllocator.allocate_frames(num_frames)) {
+        Err(FrameAllocError::Fragmented { .. }) if compaction::compact_after_failed_allocation(num_frames) => {
+            CachedFrameAllocator.allocate_frames(num_frames)
+        }
+        result => result,
+    }
+}
+
+/// Allocates a new Frame like [`allocate_frame()`](fn.allocate_frame.html),
+/// but attributes it to the given `owner` if frame accounting is enabled.
+pub fn allocate_frame_owned(owner: FrameOwner) -> Result<Frame, FrameAllocError> {
+    let frame = allocate_frame()?;
+    frame_accounting::reassign(&FrameRange::new(frame, frame), Some(owner));
+    Ok(frame)
+}
+
+/// Allocates several contiguous Frames like [`allocate_frames()`](fn.allocate_frames.html),
+/// but attributes them to the given `owner` if frame accounting is enabled.
+pub fn allocate_frames_owned(owner: FrameOwner, num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+    let frames = allocate_frames(num_frames)?;
+    frame_accounting::reassign(&frames, Some(owner));
+    Ok(frames)
+}
+
+/// Allocates `num_frames` contiguous frames from the given memory `zone`, 
+/// falling back to the lower zones in that zone's default [`fallback_order()`](enum.MemoryZone.html#method.fallback_order).
+/// 
+/// For example, a device that can only address 32-bit physical memory should use `MemoryZone::Dma32`.
+/// All other allocations take frames from the `Normal` zone first, so they only use the lower zones once it has run out.
+pub fn allocate_frames_in_zone(zone: MemoryZone, num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+    allocate_frames_in_zones(zone.fallback_order(), num_frames)
+}
+
+/// Allocates `num_frames` contiguous frames from the first of the given memory `zones` that can satisfy the request,
+/// trying them in the given order. 
+/// 
+/// This allows the caller to customize the zone fallback order, e.g., to never fall back to the `Dma` zone.
+pub fn allocate_frames_in_zones(zones: &[MemoryZone], num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+    if num_frames == 0 || zones.is_empty() {
+        return Err(FrameAllocError::InvalidRequest);
+    }
+    let frame_allocator = FRAME_ALLOCATOR.try().ok_or(FrameAllocError::OutOfMemory)?;
+    let frames = allocate_with_reclaim(num_frames, || {
+        let mut frame_allocator = frame_allocator.lock();
+        zones.iter()
+            .filter_map(|zone| frame_allocator.allocate_frames_in_zone(*zone, num_frames))
+            .next()
+            .ok_or(FrameAllocError::OutOfMemory)
+    })?;
+    // Frames reserved from a zone aren't attributed to an owner by the system-wide allocator itself.
+    frame_accounting::reassign(&frames, None);
+    Ok(frames)
+}
+
+/// Allocates `num_frames` frames as at most `max_segments` ranges of contiguous frames,
+/// which need not be contiguous with each other. 
+/// 
+/// This is intended for devices that accept scatter-gather lists for DMA, 
+/// which don't need all of their memory to be physically contiguous.
+/// Larger ranges are preferred, but several smaller ranges are used if needed, 
+/// see [`AreaFrameAllocator::allocate_frames_sg()`](struct.AreaFrameAllocator.html#method.allocate_frames_sg).
+/// 
+/// Frames held in the per-core frame caches are not used.
+pub fn allocate_frames_sg(num_frames: usize, max_segments: usize) -> Result<Vec<FrameRange>, FrameAllocError> {
+    let frame_allocator = FRAME_ALLOCATOR.try().ok_or(FrameAllocError::OutOfMemory)?;
+    allocate_with_reclaim(num_frames, || frame_allocator.lock().allocate_frames_sg(num_frames, max_segments))
+}
+
+/// Convenience method for deallocating a Frame that is no longer in use.
+/// 
+/// This returns the frame to the current core's frame cache, if one exists.
+pub fn deallocate_frame(frame: Frame) {
+    CachedFrameAllocator.deallocate_frame(frame);
+    zeroed_frames::notify_frames_freed();
+}
+
+/// Fills the given `frame` with zeros and then deallocates it, 
+/// such that its old contents cannot leak to whichever crate or task allocates it next.
+/// 
+/// This is intended for frames that are not mapped by any `MappedPages`, 
+/// since a frame that is deallocated when its `MappedPages` is dropped is scrubbed automatically
+/// if `SCRUB_FRAMES_ON_FREE` is enabled.
+/// 
+/// # Locking / Deadlock
+/// See [`zero_frame()`](fn.zero_frame.html).
+pub fn deallocate_frame_scrubbed(frame: Frame) -> Result<(), &'static str> {
+    zero_frame(frame)?;
+    deallocate_frame(frame);
+    Ok(())
+}
+
+/// Fills the given `frame` with zeros by temporarily mapping it.
+/// 
+/// The given `frame` must not be in use, i.e., the caller must own it.
+/// 
+/// # Locking / Deadlock
+/// This acquires the lock on the kernel's `MemoryManagementInfo` and possibly the frame allocator.
+/// Thus, the caller should ensure that the locks on those are not held.
+pub fn zero_frame(frame: Frame) -> Result<(), &'static str> {
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("zero_frame(): KERNEL_MMI was not yet initialized!")?;
+    let mut temp_mapping = map_frame_temporarily(&mut kernel_mmi_ref.lock().page_table, frame)?;
+    for byte in temp_mapping.as_slice_mut::<u8>(0, PAGE_SIZE)? {
+        *byte = 0;
+    }
+    // The temporary mapping is dropped here, which does not deallocate the frame because it's not reference counted.
+    Ok(())
+}
+
+/// Maps the given `frame` as writable at a newly-allocated page of the given page table,
+/// such that it can be filled before it's mapped where it will be used, and thus before any other core can see it.
+///
+/// The given `frame` must not be in use, i.e., the caller must own it.
+/// Dropping the returned mapping doesn't deallocate the frame, because it's not reference counted.
+pub(crate) fn map_frame_temporarily(mapper: &mut Mapper, frame: Frame) -> Result<MappedPages, &'static str> {
+    let allocated_page = allocate_pages(1).ok_or("couldn't allocate a temporary page")?;
+    mapper.map_allocated_pages_to(allocated_page, FrameRange::new(frame, frame), EntryFlags::WRITABLE, &mut CachedFrameAllocator)
 }
 
 
@@ -105,7 +323,8 @@ pub struct MemoryManagementInfo {
 
 
 /// A convenience function that creates a new memory mapping by allocating frames that are contiguous in physical memory.
-/// If contiguous frames are not required, then see [`create_mapping()`](fn.create_mapping.html).
+/// If contiguous frames are not required, then see [`create_mapping()`](fn.create_mapping.html),
+/// or [`vmalloc()`](fn.vmalloc.html) for a large buffer that should still be backed by a few physically contiguous ranges.
 /// Returns a tuple containing the new `MappedPages` and the starting PhysicalAddress of the first frame,
 /// which is a convenient way to get the physical address without walking the page tables.
 /// 
@@ -119,8 +338,10 @@ pub fn create_contiguous_mapping(size_in_bytes: usize, flags: EntryFlags) -> Res
     let mut kernel_mmi = kernel_mmi_ref.lock();
 
     let mut frame_allocator = get_frame_allocator_ref().ok_or("create_contiguous_mapping(): couldnt get frame allocator")?.lock();
-    let frames = frame_allocator.allocate_frames(allocated_pages.size_in_pages())
-        .ok_or("create_contiguous_mapping(): couldnt allocate a new frame")?;
+    let frames = frame_allocator.allocate_frames(allocated_pages.size_in_pages()).map_err(|e| {
+        error!("create_contiguous_mapping(): couldn't allocate {} contiguous frames: {}", allocated_pages.size_in_pages(), e);
+        "create_contiguous_mapping(): couldnt allocate a new frame"
+    })?;
     let starting_phys_addr = frames.start_address();
     let mp = kernel_mmi.page_table.map_allocated_pages_to(allocated_pages, frames, flags, &mut *frame_allocator)?;
     Ok((mp, starting_phys_addr))
@@ -132,6 +353,8 @@ pub fn create_contiguous_mapping(size_in_bytes: usize, flags: EntryFlags) -> Res
 /// then see [`create_contiguous_mapping()`](fn.create_contiguous_mapping.html).
 /// Returns the new `MappedPages.` 
 /// 
+/// The frames are allocated from the current core's frame cache, if one exists.
+/// 
 /// # Locking / Deadlock
 /// Currently, this function acquires the lock on the `FRAME_ALLOCATOR` and the kernel's `MemoryManagementInfo` instance.
 /// Thus, the caller should ensure that the locks on those two variables are not held when invoking this function.
@@ -141,14 +364,56 @@ pub fn create_mapping(size_in_bytes: usize, flags: EntryFlags) -> Result<MappedP
     let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("create_contiguous_mapping(): KERNEL_MMI was not yet initialized!")?;
     let mut kernel_mmi = kernel_mmi_ref.lock();
 
-    let mut frame_allocator = FRAME_ALLOCATOR.try()
-        .ok_or("create_contiguous_mapping(): couldnt get FRAME_ALLOCATOR")?
-        .lock();
-    
-    kernel_mmi.page_table.map_allocated_pages(allocated_pages, flags, frame_allocator.deref_mut())
+    if FRAME_ALLOCATOR.try().is_none() {
+        return Err("create_contiguous_mapping(): couldnt get FRAME_ALLOCATOR");
+    }
+    kernel_mmi.page_table.map_allocated_pages(allocated_pages, flags, &mut CachedFrameAllocator)
+}
+
+
+/// Like [`create_mapping()`](fn.create_mapping.html), but the new mapping is guaranteed to be filled with zeros. 
+/// 
+/// Pre-zeroed frames are used if available, see [`allocate_zeroed_frame()`](fn.allocate_zeroed_frame.html);
+/// any other frames are zeroed during the mapping procedure.
+/// 
+/// # Locking / Deadlock
+/// Same as [`create_mapping()`](fn.create_mapping.html).
+pub fn create_zeroed_mapping(size_in_bytes: usize, flags: EntryFlags) -> Result<MappedPages, &'static str> {
+    let allocated_pages = allocate_pages_by_bytes(size_in_bytes).ok_or("memory::create_zeroed_mapping(): couldn't allocate pages!")?;
+
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("create_zeroed_mapping(): KERNEL_MMI was not yet initialized!")?;
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+
+    if FRAME_ALLOCATOR.try().is_none() {
+        return Err("create_zeroed_mapping(): couldnt get FRAME_ALLOCATOR");
+    }
+    kernel_mmi.page_table.map_allocated_pages_zeroed(allocated_pages, flags, &mut CachedFrameAllocator)
 }
 
 
+/// Like [`create_mapping()`](fn.create_mapping.html), but no frames are allocated up front.
+/// Instead, each page is mapped to a new zeroed frame upon its first access, which is handled by the page fault handler.
+/// 
+/// This is intended for large mappings of which only a small portion may actually be used.
+/// Use [`MappedPages::committed_pages()`](struct.MappedPages.html#method.committed_pages)
+/// to find out how many of its pages currently consume physical memory.
+/// If no frame can be allocated when a page is first accessed, the accessing task is killed.
+/// 
+/// # Locking / Deadlock
+/// Same as [`create_mapping()`](fn.create_mapping.html).
+/// In addition, the new mapping must not be accessed for the first time while holding the lock on the `FRAME_ALLOCATOR`.
+pub fn create_lazy_mapping(size_in_bytes: usize, flags: EntryFlags) -> Result<MappedPages, &'static str> {
+    let allocated_pages = allocate_pages_by_bytes(size_in_bytes).ok_or("memory::create_lazy_mapping(): couldn't allocate pages!")?;
+
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("create_lazy_mapping(): KERNEL_MMI was not yet initialized!")?;
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+
+    if FRAME_ALLOCATOR.try().is_none() {
+        return Err("create_lazy_mapping(): couldnt get FRAME_ALLOCATOR");
+    }
+    kernel_mmi.page_table.map_allocated_pages_lazy(allocated_pages, flags, &mut CachedFrameAllocator)
+}
+
 pub static BROADCAST_TLB_SHOOTDOWN_FUNC: Once<fn(PageRange)> = Once::new();
 
 /// Set the function callback that will be invoked every time a TLB shootdown is necessary,
@@ -158,6 +423,63 @@ pub fn set_broadcast_tlb_shootdown_cb(func: fn(PageRange)) {
 }
 
 
+/// The function that is invoked to migrate in-use frames out of a memory area that is being taken offline.
+/// 
+/// It should move the contents of the given frames elsewhere, update any mappings of them, 
+/// and then deallocate them. It should return an error if any of the given frames could not be migrated.
+/// By default, this is `compaction::migrate_frames()`, which is set once the kernel's MMI is initialized.
+static FRAME_MIGRATION_FUNC: Once<fn(&FrameRange) -> Result<(), &'static str>> = Once::new();
+
+/// Set the function callback that will be invoked to migrate in-use frames out of an area being taken offline,
+/// see [`offline_area()`](fn.offline_area.html).
+/// This has no effect if a callback was already set.
+pub fn set_frame_migration_cb(func: fn(&FrameRange) -> Result<(), &'static str>) {
+    FRAME_MIGRATION_FUNC.call_once(|| func);
+}
+
+/// Adds the given `area` of physical memory to the system-wide frame allocator,
+/// e.g., memory that was hotplugged after boot, or an area previously taken offline via [`offline_area()`](fn.offline_area.html).
+pub fn online_area(area: PhysicalMemoryArea) -> Result<(), &'static str> {
+    FRAME_ALLOCATOR.try().ok_or("online_area(): FRAME_ALLOCATOR not initialized")?.lock().online_area(area)
+}
+
+/// Takes the given `area` of physical memory offline, such that none of its frames will be allocated again.
+/// 
+/// Any frames within the area that are currently in use are migrated elsewhere 
+/// using the callback set by [`set_frame_migration_cb()`](fn.set_frame_migration_cb.html), if there is one.
+/// 
+/// Returns `Ok` if the area has no frames in use, i.e., its memory can be safely removed.
+/// Otherwise, an error is returned, but the area remains offline; 
+/// the remaining frames in use will be discarded when they are eventually deallocated, 
+/// which can be checked using [`offlined_frames_in_use()`](fn.offlined_frames_in_use.html).
+pub fn offline_area(area: PhysicalMemoryArea) -> Result<(), &'static str> {
+    let frame_allocator = FRAME_ALLOCATOR.try().ok_or("offline_area(): FRAME_ALLOCATOR not initialized")?;
+    let in_use_ranges = frame_allocator.lock().offline_area(area)?;
+    // Any of the area's frames that are sitting in the per-core frame caches are not actually in use,
+    // so return them to the system-wide frame allocator, which will discard them.
+    flush_frame_caches();
+
+    if let Some(migrate) = FRAME_MIGRATION_FUNC.try() {
+        for range in in_use_ranges.iter() {
+            if let Err(e) = migrate(range) {
+                warn!("offline_area(): failed to migrate frames {:?}: {}", range, e);
+            }
+        }
+    }
+
+    match offlined_frames_in_use(&area) {
+        Some(0) => Ok(()),
+        _ => Err("offline_area(): area is offline, but some of its frames are still in use"),
+    }
+}
+
+/// Returns the number of frames within the given offlined `area` that are still in use,
+/// or `None` if the `area` is not offline.
+pub fn offlined_frames_in_use(area: &PhysicalMemoryArea) -> Option<usize> {
+    FRAME_ALLOCATOR.try()?.lock().offlined_frames_in_use(area)
+}
+
+
 
 /// Initializes the virtual memory management system.
 /// Consumes the given BootInformation, because after the memory system is initialized,
@@ -174,7 +496,7 @@ pub fn set_broadcast_tlb_shootdown_cb(func: fn(PageRange)) {
 ///  * the kernel's list of identity-mapped MappedPages that needs to be converted to a vector after heap initialization, and which should be dropped before starting the first userspace program. 
 pub fn init(boot_info: &BootInformation) 
     -> Result<(
-        &MutexIrqSafe<AreaFrameAllocator>,
+        &MutexIrqSafe<SystemFrameAllocator>,
         PageTable,
         MappedPages,
         MappedPages,
@@ -194,27 +516,52 @@ pub fn init(boot_info: &BootInformation)
     );
   
     // get available physical memory areas
-    let (available, avail_len) = get_available_memory(&boot_info, kernel_phys_end)?;
+    let (available, avail_len) = get_available_memory::<MAX_PRE_HEAP_MEMORY_AREAS>(&boot_info, kernel_phys_end)?;
 
     // Get the bounds of physical memory that is occupied by bootloader-loaded modules.
     let (modules_start_paddr, modules_end_paddr) = get_modules_address(&boot_info);
 
     // Set up the initial list of reserved physical memory frames such that the frame allocator does not re-use them.
-    let mut occupied: [PhysicalMemoryArea; 32] = Default::default();
+    reserved_regions::reserve_area(PhysicalMemoryArea::new(PhysicalAddress::zero(), 0x10_0000, 1, 0), "low memory (under 1 MiB)")?;
+    reserved_regions::reserve_area(PhysicalMemoryArea::new(kernel_phys_start, kernel_phys_end.value() - kernel_phys_start.value(), 1, 0), "kernel image")?; // the kernel boot image is already in use
+    reserved_regions::reserve_area(get_boot_info_mem_area(&boot_info)?, "multiboot information")?; // preserve the multiboot information for x86_64. 
+    reserved_regions::reserve_area(PhysicalMemoryArea::new(modules_start_paddr, modules_end_paddr.value() - modules_start_paddr.value(), 1, 0), "bootloader modules")?; // preserve all bootloader modules
+    // Set aside the crash kernel region, if one is configured, after everything else that must not be moved.
+    crash_kernel::reserve_crash_kernel(boot_info.command_line_tag().map(|tag| tag.command_line()), &available[..avail_len])?;
+    let mut occupied = [PhysicalMemoryArea::default(); MAX_PRE_HEAP_MEMORY_AREAS];
     let mut occup_index = 0;
-    occupied[occup_index] = PhysicalMemoryArea::new(PhysicalAddress::zero(), 0x10_0000, 1, 0); // reserve addresses under 1 MB
-    occup_index += 1;
-    occupied[occup_index] = PhysicalMemoryArea::new(kernel_phys_start, kernel_phys_end.value() - kernel_phys_start.value(), 1, 0); // the kernel boot image is already in use
-    occup_index += 1;
-    occupied[occup_index] = get_boot_info_mem_area(&boot_info)?; // preserve the multiboot information for x86_64. 
-    occup_index += 1;
-    occupied[occup_index] = PhysicalMemoryArea::new(modules_start_paddr, modules_end_paddr.value() - modules_start_paddr.value(), 1, 0); // preserve all bootloader modules
-    occup_index += 1;
+    reserved_regions::add_reserved_areas(&mut occupied, &mut occup_index)?;
+
+    // Never use the known-bad frames given on the boot command line, see the `quarantine` module.
+    let mut bad_frames = boot_info.command_line_tag()
+        .map(|tag| quarantine::parse_boot_bad_frames(tag.command_line()))
+        .unwrap_or([None; quarantine::MAX_BOOT_BAD_FRAMES]);
+    for slot in bad_frames.iter_mut() {
+        let bad_frame = match *slot {
+            Some(f) => f,
+            None => continue,
+        };
+        if occup_index == occupied.len() {
+            error!("Couldn't avoid bad frame {:?} given on the boot command line, too many occupied areas", bad_frame);
+            *slot = None;
+            continue;
+        }
+        // Use an end bound that is one byte short of the frame, to avoid also occupying the frame after it.
+        occupied[occup_index] = PhysicalMemoryArea::new(bad_frame.start_address(), PAGE_SIZE - 1, 1, 0);
+        occup_index += 1;
+        warn!("Avoiding bad frame {:?} given on the boot command line", bad_frame);
+    }
+    quarantine::set_boot_bad_frames(bad_frames);
+    if let Some(tag) = boot_info.command_line_tag() {
+        system_frame_allocator::parse_boot_backend(tag.command_line());
+    }
 
 
     // init the frame allocator with the available memory sections and the occupied memory sections
-    let fa = AreaFrameAllocator::new(available, avail_len, occupied, occup_index)?;
-    let frame_allocator_mutex: &MutexIrqSafe<AreaFrameAllocator> = FRAME_ALLOCATOR.call_once(|| {
+    // The area frame allocator is always used until the heap is set up, see `init_post_heap()`.
+    let fa = SystemFrameAllocator::Area(AreaFrameAllocator::new(available, avail_len, occupied, occup_index)?);
+    fa.update_free_frame_count();
+    let frame_allocator_mutex: &MutexIrqSafe<SystemFrameAllocator> = FRAME_ALLOCATOR.call_once(|| {
         MutexIrqSafe::new(fa) 
     });
 
@@ -257,6 +604,11 @@ pub fn init_post_heap(page_table: PageTable, mut higher_half_mapped_pages: [Opti
 
     page_allocator::convert_to_heap_allocated();
     FRAME_ALLOCATOR.try().ok_or("BUG: FRAME_ALLOCATOR not initialized")?.lock().alloc_ready();
+    // Reserve the contiguous memory area and the huge frame pools now, before physical memory becomes fragmented.
+    cma::init_cma()?;
+    huge_frames::init_huge_frame_pools()?;
+    // Now that the heap is set up, switch to the selected frame allocator backend.
+    FRAME_ALLOCATOR.try().ok_or("BUG: FRAME_ALLOCATOR not initialized")?.lock().switch_backend(selected_backend())?;
 
     let mut higher_half_mapped_pages: Vec<MappedPages> = higher_half_mapped_pages.iter_mut().filter_map(|opt| opt.take()).collect();
     higher_half_mapped_pages.push(heap_mapped_pages);
@@ -271,13 +623,57 @@ pub fn init_post_heap(page_table: PageTable, mut higher_half_mapped_pages: [Opti
     let kernel_mmi_ref = KERNEL_MMI.call_once( || {
         Arc::new(MutexIrqSafe::new(kernel_mmi))
     });
+    // Frames in use within an area being taken offline are migrated by compaction, which needs the kernel's MMI.
+    set_frame_migration_cb(compaction::migrate_frames);
 
     Ok( (kernel_mmi_ref.clone(), identity_mapped_pages) )
 }
 
+/// The reasons why a [`FrameAllocator`](trait.FrameAllocator.html) may fail to allocate frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameAllocError {
+    /// There are no free frames left to satisfy the request.
+    OutOfMemory,
+    /// There are free frames left, but not enough contiguous ones to satisfy the request.
+    /// `largest_run` is the number of contiguous frames in the largest run that was found.
+    Fragmented { largest_run: usize },
+    /// The allocator's memory areas conflict with each other, e.g., it couldn't skip past the occupied areas,
+    /// which indicates a bug in how the areas were added.
+    RegionConflict,
+    /// The request itself was invalid, e.g., for zero frames.
+    InvalidRequest,
+}
+
+impl FrameAllocError {
+    /// Returns a description of this error, for use in functions that return `&'static str` errors.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FrameAllocError::OutOfMemory => "frame allocator is out of memory",
+            FrameAllocError::Fragmented { .. } => "frame allocator couldn't find enough contiguous frames",
+            FrameAllocError::RegionConflict => "frame allocator's memory areas conflict with each other",
+            FrameAllocError::InvalidRequest => "invalid frame allocation request",
+        }
+    }
+}
+
+impl core::fmt::Display for FrameAllocError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            FrameAllocError::Fragmented { largest_run } => write!(f, "{} (largest run: {} frames)", self.as_str(), largest_run),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
+
+impl From<FrameAllocError> for &'static str {
+    fn from(e: FrameAllocError) -> &'static str {
+        e.as_str()
+    }
+}
+
 pub trait FrameAllocator {
-    fn allocate_frame(&mut self) -> Option<Frame>;
-    fn allocate_frames(&mut self, num_frames: usize) -> Option<FrameRange>;
+    fn allocate_frame(&mut self) -> Result<Frame, FrameAllocError>;
+    fn allocate_frames(&mut self, num_frames: usize) -> Result<FrameRange, FrameAllocError>;
     fn deallocate_frame(&mut self, frame: Frame);
     /// Call this when a heap is set up, and the `alloc` types can be used.
     fn alloc_ready(&mut self);
@@ -0,0 +1,204 @@
+//! Memory-pressure levels based on free frame watermarks, and a registry of callbacks that reclaim memory.
+//!
+//! Subsystems that hold onto memory they could give back, e.g., caches of disk blocks or network buffers,
+//! can register a reclaim callback via [`register_reclaimer()`].
+//! The callbacks are invoked when the number of free frames falls below the low watermark
+//! and again if it falls below the min watermark, such that memory can be freed before it runs out entirely.
+//! They are also invoked as a last resort when a frame allocation fails.
+//!
+//! # Locking / Deadlock
+//! Reclaim callbacks are invoked from the allocation path of whichever task allocated memory,
+//! but never while any of this crate's locks are held, so they may deallocate frames.
+//! However, the allocating task may hold other locks, so a callback should use `try_lock()`
+//! and simply skip any lock that isn't immediately available.
+//!
+//! [`register_reclaimer()`]: fn.register_reclaimer.html
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use super::{flush_frame_caches, zeroed_frames};
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::{LOW_WATERMARK_FRAMES, MIN_WATERMARK_FRAMES};
+
+
+/// How scarce free memory currently is, which is given to each reclaim callback.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PressureLevel {
+    /// The number of free frames is above the low watermark.
+    None = 0,
+    /// The number of free frames is below the low watermark.
+    Low = 1,
+    /// The number of free frames is below the min watermark, or an allocation has failed.
+    Min = 2,
+}
+
+impl PressureLevel {
+    fn from_u8(value: u8) -> PressureLevel {
+        match value {
+            0 => PressureLevel::None,
+            1 => PressureLevel::Low,
+            _ => PressureLevel::Min,
+        }
+    }
+}
+
+/// A function that tries to free at least `target_frames` frames,
+/// and returns the number of frames it actually freed (which can be an estimate).
+pub type ReclaimFn = fn(level: PressureLevel, target_frames: usize) -> usize;
+
+/// A registered reclaim callback along with the name it was registered under.
+#[derive(Copy, Clone)]
+struct Reclaimer {
+    name: &'static str,
+    func: ReclaimFn,
+}
+
+/// The registered reclaim callbacks, in the order they were registered.
+static RECLAIMERS: MutexIrqSafe<Vec<Reclaimer>> = MutexIrqSafe::new(Vec::new());
+
+/// The number of free frames in the system-wide frame allocator,
+/// which is updated by the allocator itself whenever it changes.
+/// This is read without acquiring the frame allocator's lock.
+static FREE_FRAMES: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+static LOW_WATERMARK: AtomicUsize = AtomicUsize::new(LOW_WATERMARK_FRAMES);
+static MIN_WATERMARK: AtomicUsize = AtomicUsize::new(MIN_WATERMARK_FRAMES);
+
+/// The pressure level at the time the reclaim callbacks were last invoked, such that
+/// they're only invoked again when the pressure level increases rather than on every allocation.
+static LAST_RECLAIM_LEVEL: AtomicU8 = AtomicU8::new(PressureLevel::None as u8);
+
+/// Whether memory is currently being reclaimed, which prevents reclaiming recursively
+/// if a reclaim callback allocates memory itself.
+static RECLAIM_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+
+/// Registers a function that will be invoked to reclaim memory when memory is under pressure.
+///
+/// The `name` identifies this callback, e.g., the name of the subsystem that registers it.
+/// Returns an error if a callback with that `name` was already registered.
+pub fn register_reclaimer(name: &'static str, func: ReclaimFn) -> Result<(), &'static str> {
+    let mut reclaimers = RECLAIMERS.lock();
+    if reclaimers.iter().any(|r| r.name == name) {
+        return Err("a reclaim callback with that name was already registered");
+    }
+    reclaimers.push(Reclaimer { name, func });
+    Ok(())
+}
+
+/// Removes the reclaim callback that was registered under the given `name`.
+/// Returns `true` if it was found and removed.
+pub fn unregister_reclaimer(name: &'static str) -> bool {
+    let mut reclaimers = RECLAIMERS.lock();
+    let len_before = reclaimers.len();
+    reclaimers.retain(|r| r.name != name);
+    reclaimers.len() != len_before
+}
+
+/// Returns the names of all registered reclaim callbacks.
+pub fn reclaimer_names() -> Vec<&'static str> {
+    RECLAIMERS.lock().iter().map(|r| r.name).collect()
+}
+
+
+/// Sets the `low` and `min` watermarks, in number of frames. The `min` watermark must not be above the `low` one.
+pub fn set_watermarks(low: usize, min: usize) -> Result<(), &'static str> {
+    if min > low {
+        return Err("the min watermark must not be greater than the low watermark");
+    }
+    LOW_WATERMARK.store(low, Ordering::SeqCst);
+    MIN_WATERMARK.store(min, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Returns the current `(low, min)` watermarks, in number of frames.
+pub fn watermarks() -> (usize, usize) {
+    (LOW_WATERMARK.load(Ordering::SeqCst), MIN_WATERMARK.load(Ordering::SeqCst))
+}
+
+/// Returns the approximate number of free frames in the system-wide frame allocator,
+/// see [`SystemFrameAllocator::free_frame_count()`](enum.SystemFrameAllocator.html#method.free_frame_count).
+pub fn free_frame_count() -> usize {
+    FREE_FRAMES.load(Ordering::SeqCst)
+}
+
+/// Sets the number of free frames, which is invoked by the system-wide frame allocator.
+pub(crate) fn set_free_frame_count(count: usize) {
+    FREE_FRAMES.store(count, Ordering::SeqCst);
+}
+
+/// Returns the current memory pressure level based on the number of free frames and the watermarks.
+pub fn pressure_level() -> PressureLevel {
+    let free = free_frame_count();
+    let (low, min) = watermarks();
+    if free < min {
+        PressureLevel::Min
+    } else if free < low {
+        PressureLevel::Low
+    } else {
+        PressureLevel::None
+    }
+}
+
+
+/// Checks the current memory pressure level and reclaims memory if it has increased
+/// since the last time memory was reclaimed.
+///
+/// This is invoked after allocating frames, and must not be invoked while holding the frame allocator lock.
+pub(crate) fn check_memory_pressure() {
+    let level = pressure_level();
+    let last_level = PressureLevel::from_u8(LAST_RECLAIM_LEVEL.load(Ordering::SeqCst));
+    if level <= last_level {
+        if level == PressureLevel::None && last_level != PressureLevel::None {
+            LAST_RECLAIM_LEVEL.store(PressureLevel::None as u8, Ordering::SeqCst);
+        }
+        return;
+    }
+    LAST_RECLAIM_LEVEL.store(level as u8, Ordering::SeqCst);
+    let (low, _min) = watermarks();
+    let target = low.saturating_sub(free_frame_count());
+    warn!("Memory is under pressure ({:?}): {} free frames, reclaiming {} frames", level, free_frame_count(), target);
+    reclaim_memory(level, target);
+}
+
+/// Tries to reclaim at least `target_frames` frames because an allocation of that many frames failed.
+/// Returns the number of frames that were reclaimed, after which the allocation should be retried.
+pub(crate) fn reclaim_after_failed_allocation(target_frames: usize) -> usize {
+    error!("Frame allocation of {} frames failed ({} free frames), trying to reclaim memory", target_frames, free_frame_count());
+    reclaim_memory(PressureLevel::Min, target_frames)
+}
+
+/// Invokes the reclaim callbacks in the order they were registered,
+/// stopping once at least `target_frames` frames have been reclaimed.
+///
+/// If the `level` is `Min`, the frames held in per-core frame caches and the pre-zeroed frame pool
+/// are first released back to the system-wide frame allocator.
+///
+/// Returns the number of frames that were reclaimed,
+/// or `0` if memory is already being reclaimed by a caller further up the stack.
+pub fn reclaim_memory(level: PressureLevel, target_frames: usize) -> usize {
+    if RECLAIM_IN_PROGRESS.compare_and_swap(false, true, Ordering::SeqCst) {
+        return 0;
+    }
+
+    let mut reclaimed = 0;
+    if level == PressureLevel::Min {
+        reclaimed += flush_frame_caches();
+        reclaimed += zeroed_frames::release_zeroed_frames();
+    }
+
+    // Copy the callbacks such that the lock isn't held while they run,
+    // allowing them to (un)register callbacks themselves.
+    let reclaimers = RECLAIMERS.lock().clone();
+    for reclaimer in reclaimers {
+        if reclaimed >= target_frames {
+            break;
+        }
+        let freed = (reclaimer.func)(level, target_frames - reclaimed);
+        debug!("Reclaim callback {:?} freed {} frames", reclaimer.name, freed);
+        reclaimed += freed;
+    }
+
+    RECLAIM_IN_PROGRESS.store(false, Ordering::SeqCst);
+    reclaimed
+}
@@ -0,0 +1,134 @@
+//! Support for allocating frames from specific NUMA nodes.
+//!
+//! Each NUMA node has a pool of free frames that are known to reside in that node's memory.
+//! When a node's pool is empty, it is refilled by reserving a chunk of frames within that node's 
+//! memory areas from the system-wide frame allocator (see `AreaFrameAllocator::reserve_frames_within()`).
+//!
+//! By default, single frames are allocated from the current core's NUMA node (via the per-core frame caches),
+//! which ensures that tasks pinned to a core receive memory that is local to that core. 
+//! Frames that are deallocated are returned to the system-wide frame allocator, not to their node's pool.
+//!
+//! # Locking
+//! A NUMA node's pool lock may be held while acquiring the system-wide frame allocator lock, but never vice versa.
+
+use super::{Frame, FrameRange, PhysicalMemoryArea, FRAME_ALLOCATOR, current_apic_id};
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use spin::Once;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::NUMA_NODE_RESERVE_CHUNK_FRAMES;
+
+
+/// The description of a single NUMA node, e.g., as obtained from the ACPI SRAT table.
+#[derive(Clone, Debug)]
+pub struct NumaNodeInfo {
+    /// The ID of this node, called a "proximity domain" in ACPI.
+    pub id: u32,
+    /// The areas of physical memory that belong to this node.
+    pub memory_areas: Vec<PhysicalMemoryArea>,
+    /// The APIC IDs of the cores that belong to this node.
+    pub apic_ids: Vec<u8>,
+}
+
+/// A NUMA node and its pool of free frames.
+struct NumaNode {
+    info: NumaNodeInfo,
+    free_frames: MutexIrqSafe<Vec<Frame>>,
+}
+
+/// All of the NUMA nodes in the system, keyed by node ID.
+static NUMA_NODES: Once<BTreeMap<u32, NumaNode>> = Once::new();
+/// A mapping from each core's APIC ID to the ID of the NUMA node that it belongs to.
+static CORE_TO_NODE: Once<BTreeMap<u8, u32>> = Once::new();
+
+
+/// Initializes the set of NUMA nodes, which enables frame allocation on a per-node basis.
+/// 
+/// Until this is invoked, all frames are allocated without regard to NUMA locality.
+/// This can only be invoked once, after the heap has been initialized.
+pub fn init_numa_nodes(nodes: Vec<NumaNodeInfo>) -> Result<(), &'static str> {
+    if NUMA_NODES.try().is_some() {
+        return Err("NUMA nodes were already initialized");
+    }
+    let mut core_to_node = BTreeMap::new();
+    let mut numa_nodes = BTreeMap::new();
+    for node in nodes {
+        for apic_id in &node.apic_ids {
+            core_to_node.insert(*apic_id, node.id);
+        }
+        info!("NUMA node {}: cores {:?}, memory areas {:?}", node.id, node.apic_ids, node.memory_areas);
+        numa_nodes.insert(node.id, NumaNode { info: node, free_frames: MutexIrqSafe::new(Vec::new()) });
+    }
+    CORE_TO_NODE.call_once(|| core_to_node);
+    NUMA_NODES.call_once(|| numa_nodes);
+    Ok(())
+}
+
+/// Returns the IDs of all NUMA nodes, or an empty list if NUMA nodes haven't been initialized.
+pub fn numa_node_ids() -> Vec<u32> {
+    NUMA_NODES.try().map(|nodes| nodes.keys().cloned().collect()).unwrap_or_default()
+}
+
+/// Returns the description of the NUMA node with the given ID.
+pub fn numa_node_info(node: u32) -> Option<&'static NumaNodeInfo> {
+    NUMA_NODES.try().and_then(|nodes| nodes.get(&node)).map(|n| &n.info)
+}
+
+/// Returns the ID of the NUMA node that the core with the given APIC ID belongs to.
+pub fn numa_node_of_core(apic_id: u8) -> Option<u32> {
+    CORE_TO_NODE.try().and_then(|map| map.get(&apic_id).cloned())
+}
+
+/// Returns the ID of the NUMA node that the current core belongs to.
+pub fn my_numa_node() -> Option<u32> {
+    numa_node_of_core(current_apic_id())
+}
+
+
+/// Allocates a single frame from the memory of the given NUMA node.
+/// 
+/// Returns `None` if there is no such node or if that node's memory has been exhausted.
+pub fn allocate_frame_on_node(node: u32) -> Option<Frame> {
+    let node = NUMA_NODES.try()?.get(&node)?;
+    let mut free_frames = node.free_frames.lock();
+    if free_frames.is_empty() {
+        let mut fa = FRAME_ALLOCATOR.try()?.lock();
+        for area in &node.info.memory_areas {
+            if let Some(frames) = fa.reserve_frames_within(area, 1, NUMA_NODE_RESERVE_CHUNK_FRAMES) {
+                // push them in reverse order such that the lowest frame is allocated first
+                free_frames.extend(frames.into_iter().rev());
+                break;
+            }
+        }
+    }
+    free_frames.pop()
+}
+
+/// Removes the given `frame` from the free frames that were reserved for any NUMA node,
+/// returning `true` if it was found.
+pub(crate) fn remove_free_frame(frame: Frame) -> bool {
+    NUMA_NODES.try().map_or(false, |nodes| nodes.values().any(|node| {
+        let mut free_frames = node.free_frames.lock();
+        match free_frames.iter().position(|f| *f == frame) {
+            Some(index) => { free_frames.remove(index); true }
+            None => false,
+        }
+    }))
+}
+
+/// Allocates `num_frames` contiguous frames from the memory of the given NUMA node.
+/// 
+/// Returns `None` if there is no such node or if that node doesn't have enough contiguous free memory.
+pub fn allocate_frames_on_node(node: u32, num_frames: usize) -> Option<FrameRange> {
+    if num_frames == 0 {
+        return None;
+    }
+    if num_frames == 1 {
+        return allocate_frame_on_node(node).map(|f| FrameRange::new(f, f));
+    }
+    let node = NUMA_NODES.try()?.get(&node)?;
+    let mut fa = FRAME_ALLOCATOR.try()?.lock();
+    node.info.memory_areas.iter().filter_map(|area| fa.reserve_frames_within(area, num_frames, num_frames)).next()
+}
@@ -13,6 +13,12 @@ use bit_field::BitField;
 use kernel_config::memory::PAGE_SHIFT;
 use zerocopy::FromBytes;
 
+/// The bit that marks a non-present entry as a swap entry, i.e., one whose page was evicted to swap.
+/// It's one of the bits that are available to the OS, though the hardware ignores every bit of a non-present entry.
+const SWAPPED_BIT: u64 = 1 << 9;
+/// The bits of a swap entry that hold its swap slot, which are the same bits that hold a present entry's frame address.
+const SWAP_SLOT_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
 /// A page table entry, which is a `u64` value under the hood.
 /// It contains a physical frame address and entry flag access bits.
 #[derive(FromBytes)]
@@ -46,6 +52,23 @@ impl Entry {
         self.0 = (frame.start_address().value() as u64) | flags.bits();
     }
 
+    /// Returns the swap slot that holds this entry's page, if this is a swap entry, see the `swap` module.
+    /// A swap entry's `flags()` are the flags that its page will be mapped with once it's swapped back in.
+    pub fn swap_slot(&self) -> Option<usize> {
+        if self.0 & (EntryFlags::PRESENT.bits() | SWAPPED_BIT) == SWAPPED_BIT {
+            Some(((self.0 & SWAP_SLOT_MASK) >> PAGE_SHIFT) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Makes this a non-present swap entry that refers to the given swap `slot`,
+    /// whose page will be mapped with the given `flags` once it's swapped back in.
+    pub fn set_swapped(&mut self, slot: usize, flags: EntryFlags) {
+        let flags = flags - (EntryFlags::PRESENT | EntryFlags::ACCESSED | EntryFlags::DIRTY | EntryFlags::HUGE_PAGE);
+        self.0 = (((slot as u64) << PAGE_SHIFT) & SWAP_SLOT_MASK) | flags.bits() | SWAPPED_BIT;
+    }
+
     // we use this to force explicit copying rather than deriving Copy/Clone
     pub fn copy(&self) -> Entry {
         Entry(self.0)
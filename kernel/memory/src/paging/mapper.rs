@@ -11,13 +11,19 @@ use core::mem;
 use core::ops::Deref;
 use core::ptr::Unique;
 use core::slice;
-use {BROADCAST_TLB_SHOOTDOWN_FUNC, VirtualAddress, PhysicalAddress, get_frame_allocator_ref, FrameRange, Page, Frame, FrameAllocator, AllocatedPages}; 
+use alloc::vec::Vec;
+use {allocate_pages, broadcast_tlb_shootdown, TlbShootdownBatch, copy_on_write, demand_paging, frame_pinning, frame_refcount, swap, zeroed_frames, VirtualAddress, PhysicalAddress, get_frame_allocator_ref, FrameRange, Page, Frame, FrameAllocator, AllocatedPages, HugeSize}; 
 use paging::{PageRange, get_current_p4};
+use paging::entry::Entry;
+use paging::wx;
+use swap::{Eviction, SwapBackend};
 use paging::table::{P4, Table, Level4};
-use kernel_config::memory::{ENTRIES_PER_PAGE_TABLE, PAGE_SIZE};
+use kernel_config::memory::{ENTRIES_PER_PAGE_TABLE, PAGE_SIZE, MAP_HUGE_PAGES, SCRUB_FRAMES_ON_ALLOC, SCRUB_FRAMES_ON_FREE};
 use irq_safety::MutexIrqSafe;
-use super::{EntryFlags, tlb_flush_virt_addr};
+use super::{EntryFlags, tlb_flush_virt_addr, tlb_flush_all};
 use zerocopy::FromBytes;
+use spin::Once;
+use raw_cpuid::CpuId;
 
 pub struct Mapper {
     p4: Unique<Table<Level4>>,
@@ -31,8 +37,13 @@ impl Mapper {
     }
 
     pub fn with_p4_frame(p4: Frame) -> Mapper {
+        #[cfg(not(test))]
+        let p4_table = P4;
+        // Host tests reach the P4 table through simulated physical memory instead of the recursive mapping.
+        #[cfg(test)]
+        let p4_table = super::simulated_memory::table_address(p4).value() as *mut Table<Level4>;
         Mapper { 
-            p4: Unique::new(P4).unwrap(), // cannot panic because we know the P4 value is valid
+            p4: Unique::new(p4_table).unwrap(), // cannot panic because we know the P4 value is valid
             target_p4: p4,
         }
     }
@@ -126,11 +137,16 @@ impl Mapper {
 
     /// Maps the given `AllocatedPages` to the given physical frames.
     /// 
+    /// Wherever both the pages and the frames are aligned to a huge page size (2MiB or 1GiB)
+    /// and at least that many pages remain to be mapped, they are mapped by a single huge page entry,
+    /// which saves page tables and TLB entries. See `MAP_HUGE_PAGES` in `kernel_config`.
+    /// 
     /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
     pub fn map_allocated_pages_to<A>(&mut self, pages: AllocatedPages, frames: FrameRange, flags: EntryFlags, allocator: &mut A)
         -> Result<MappedPages, &'static str>
         where A: FrameAllocator
     {
+        let flags = wx::enforce(flags, *pages.start());
         // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
         let mut top_level_flags = flags.clone();
         top_level_flags.set(EntryFlags::NO_EXECUTE, false);
@@ -144,28 +160,102 @@ impl Mapper {
             );
             return Err("map_allocated_pages_to(): page count must equal frame count");
         }
+        self.map_frames(*pages.start(), &frames, flags, top_level_flags, allocator)?;
 
-        // iterate over pages and frames in lockstep
-        for (page, frame) in pages.deref().clone().into_iter().zip(frames) {
-            let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator);
-            let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator);
-            let p1 = p2.next_table_create(page.p2_index(), top_level_flags, allocator);
+        Ok(MappedPages {
+            page_table_p4: self.target_p4.clone(),
+            pages,
+            flags,
+            lazy: false,
+            cow: false,
+        })
+    }
 
-            if !p1[page.p1_index()].is_unused() {
-                error!("map_allocated_pages_to(): page {:#X} -> frame {:#X}, page was already in use!", page.start_address(), frame.start_address());
-                return Err("map_allocated_pages_to(): page was already in use");
-            } 
 
-            p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+    /// Maps the given `AllocatedPages` to the given ranges of physical frames, in order,
+    /// which need not be contiguous with each other, e.g., the ranges returned by `allocate_frames_sg()`.
+    /// 
+    /// Huge pages are used within each range of frames wherever possible, as in [`map_allocated_pages_to()`](#method.map_allocated_pages_to).
+    /// 
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
+    pub fn map_allocated_pages_to_segments<A>(&mut self, pages: AllocatedPages, segments: &[FrameRange], flags: EntryFlags, allocator: &mut A)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator
+    {
+        let flags = wx::enforce(flags, *pages.start());
+        // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
+        let mut top_level_flags = flags.clone();
+        top_level_flags.set(EntryFlags::NO_EXECUTE, false);
+
+        let pages_count = pages.size_in_pages();
+        let frames_count: usize = segments.iter().map(|frames| frames.size_in_frames()).sum();
+        if pages_count != frames_count {
+            error!("map_allocated_pages_to_segments(): pages {:?} count {} must equal total frame count {} of {} segments!", 
+                pages, pages_count, frames_count, segments.len()
+            );
+            return Err("map_allocated_pages_to_segments(): page count must equal frame count");
+        }
+        let mut page = *pages.start();
+        for frames in segments {
+            self.map_frames(page, frames, flags, top_level_flags, allocator)?;
+            page = page + frames.size_in_frames();
         }
 
         Ok(MappedPages {
             page_table_p4: self.target_p4.clone(),
             pages,
             flags,
+            lazy: false,
+            cow: false,
         })
     }
 
+    /// Maps the pages starting at `start_page` to the given `frames` with the given `flags`,
+    /// creating any missing page tables with the given `top_level_flags`.
+    fn map_frames<A>(&mut self, start_page: Page, frames: &FrameRange, flags: EntryFlags, top_level_flags: EntryFlags, allocator: &mut A)
+        -> Result<(), &'static str>
+        where A: FrameAllocator
+    {
+        // iterate over pages and frames in lockstep
+        let (mut page, mut frame) = (start_page, *frames.start());
+        let mut remaining = frames.size_in_frames();
+        while remaining > 0 {
+            let num_pages = match self.huge_page_size_for(page, frame, remaining) {
+                Some(size) => {
+                    let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator);
+                    if size == HugeSize::Size1GiB {
+                        p3[page.p3_index()].set(frame, flags.into_huge() | EntryFlags::PRESENT);
+                    } else {
+                        let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator);
+                        p2[page.p2_index()].set(frame, flags.into_huge() | EntryFlags::PRESENT);
+                    }
+                    size.size_in_frames()
+                }
+                None => {
+                    let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator);
+                    let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator);
+                    let p1 = p2.next_table_create(page.p2_index(), top_level_flags, allocator);
+
+                    if !p1[page.p1_index()].is_unused() {
+                        error!("map_allocated_pages_to(): page {:#X} -> frame {:#X}, page was already in use!", page.start_address(), frame.start_address());
+                        return Err("map_allocated_pages_to(): page was already in use");
+                    } 
+
+                    p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+                    1
+                }
+            };
+            // If these frames are shared, this new mapping is another reference to them.
+            for i in 0 .. num_pages {
+                frame_refcount::incref_if_tracked(frame + i);
+            }
+            remaining -= num_pages;
+            page = page + num_pages;
+            frame = frame + num_pages;
+        }
+        Ok(())
+    }
+
 
     /// Maps the given `AllocatedPages` to randomly chosen (allocated) physical frames.
     /// 
@@ -174,14 +264,51 @@ impl Mapper {
         -> Result<MappedPages, &'static str>
         where A: FrameAllocator
     {
+        self.map_allocated_pages_internal(pages, flags, allocator, false)
+    }
+
+    /// Maps the given `AllocatedPages` to randomly chosen (allocated) physical frames that are filled with zeros.
+    /// 
+    /// Pre-zeroed frames are used if available; otherwise, new frames are allocated from the given `allocator`
+    /// and zeroed through their new mapping, which requires that this is the currently active page table.
+    /// 
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
+    pub fn map_allocated_pages_zeroed<A>(&mut self, pages: AllocatedPages, flags: EntryFlags, allocator: &mut A)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator
+    {
+        self.map_allocated_pages_internal(pages, flags, allocator, true)
+    }
+
+    fn map_allocated_pages_internal<A>(&mut self, pages: AllocatedPages, flags: EntryFlags, allocator: &mut A, zeroed: bool)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator
+    {
+        let flags = wx::enforce(flags, *pages.start());
         // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
         let mut top_level_flags = flags.clone();
         top_level_flags.set(EntryFlags::NO_EXECUTE, false);
         // top_level_flags.set(EntryFlags::WRITABLE, true); // is the same true for the WRITABLE bit?
 
+        // New frames can only be scrubbed through their new virtual addresses if this page table is currently active.
+        let can_scrub = self.target_p4 == get_current_p4();
+
         for page in pages.deref().clone() {
-            let frame = allocator.allocate_frame()
-                .ok_or("map_allocated_pages(): couldn't allocate new frame, out of memory!")?;
+            let prezeroed_frame = if zeroed { zeroed_frames::take_zeroed_frame() } else { None };
+            let (frame, already_zeroed) = match prezeroed_frame {
+                Some(f) => (f, true),
+                None => {
+                    if zeroed && !can_scrub {
+                        return Err("map_allocated_pages_zeroed(): no pre-zeroed frames left, and cannot zero frames mapped into an inactive page table");
+                    }
+                    let f = allocator.allocate_frame().map_err(|e| {
+                        error!("map_allocated_pages(): couldn't allocate new frame: {}", e);
+                        "map_allocated_pages(): couldn't allocate new frame, out of memory!"
+                    })?;
+                    (f, false)
+                }
+            };
+            let scrub = !already_zeroed && can_scrub && (zeroed || SCRUB_FRAMES_ON_ALLOC);
 
             let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator);
             let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator);
@@ -194,15 +321,221 @@ impl Mapper {
                 return Err("map_allocated_pages(): page was already in use");
             } 
 
-            p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+            if scrub {
+                // The page must be temporarily writable in order to zero it.
+                p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT | EntryFlags::WRITABLE);
+                tlb_flush_virt_addr(page.start_address());
+                scrub_page(&page);
+                if !flags.is_writable() {
+                    p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+                    tlb_flush_virt_addr(page.start_address());
+                }
+            } else {
+                p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+            }
+        }
+
+        Ok(MappedPages {
+            page_table_p4: self.target_p4.clone(),
+            pages,
+            flags,
+            lazy: false,
+            cow: false,
+        })
+    }
+
+    /// Maps the given `AllocatedPages` lazily, i.e., without allocating any frames for them yet.
+    /// 
+    /// Only the page tables that cover these pages are created, and the pages themselves are left non-present.
+    /// Each page is mapped to a new zeroed frame with the given `flags` when it is first accessed,
+    /// see the `demand_paging` module. Thus, this page table must be the one that is active whenever these pages are accessed.
+    /// 
+    /// Consumes the given `AllocatedPages` and returns a `MappedPages` object which contains those `AllocatedPages`.
+    pub fn map_allocated_pages_lazy<A>(&mut self, pages: AllocatedPages, flags: EntryFlags, allocator: &mut A)
+        -> Result<MappedPages, &'static str>
+        where A: FrameAllocator
+    {
+        let flags = wx::enforce(flags, *pages.start());
+        // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
+        let mut top_level_flags = flags.clone();
+        top_level_flags.set(EntryFlags::NO_EXECUTE, false);
+
+        for page in pages.deref().clone() {
+            let p3 = self.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator);
+            let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator);
+            let p1 = p2.next_table_create(page.p2_index(), top_level_flags, allocator);
+
+            if !p1[page.p1_index()].is_unused() {
+                error!("map_allocated_pages_lazy(): page {:#X} was already in use!", page.start_address());
+                return Err("map_allocated_pages_lazy(): page was already in use");
+            }
         }
 
+        demand_paging::register(pages.deref().clone(), self.target_p4, flags);
         Ok(MappedPages {
             page_table_p4: self.target_p4.clone(),
             pages,
             flags,
+            lazy: true,
+            cow: false,
         })
     }
+
+
+    /// Returns the largest huge page size that the given `page` can be mapped to the given `frame` with,
+    /// if both are aligned to it, at least that many of the `remaining` pages are left to be mapped,
+    /// and no page table entry is already in the way.
+    fn huge_page_size_for(&self, page: Page, frame: Frame, remaining: usize) -> Option<HugeSize> {
+        if !MAP_HUGE_PAGES {
+            return None;
+        }
+        let fits = |size: HugeSize| remaining >= size.size_in_frames() && frame.number % size.size_in_frames() == 0;
+        let p3 = self.p4().next_table(page.p4_index());
+
+        if fits(HugeSize::Size1GiB) && page.p2_index() == 0 && page.p1_index() == 0 && supports_1gib_pages()
+            && p3.map_or(true, |p3| p3[page.p3_index()].is_unused())
+        {
+            return Some(HugeSize::Size1GiB);
+        }
+        if fits(HugeSize::Size2MiB) && page.p1_index() == 0 {
+            let unused = match p3 {
+                None => true,
+                Some(p3) if p3[page.p3_index()].flags().is_huge() => false,
+                Some(p3) => p3.next_table(page.p3_index())
+                    .map_or(p3[page.p3_index()].is_unused(), |p2| p2[page.p2_index()].is_unused()),
+            };
+            if unused {
+                return Some(HugeSize::Size2MiB);
+            }
+        }
+        None
+    }
+
+    /// Returns the huge page entry that maps the given `page` and the size of that huge page,
+    /// or `None` if the `page` isn't mapped by a huge page.
+    fn huge_entry_mut(&mut self, page: Page) -> Option<(&mut Entry, HugeSize)> {
+        let p3 = self.p4_mut().next_table_mut(page.p4_index())?;
+        if p3[page.p3_index()].flags().is_huge() {
+            return Some((&mut p3[page.p3_index()], HugeSize::Size1GiB));
+        }
+        let p2 = p3.next_table_mut(page.p3_index())?;
+        if p2[page.p2_index()].flags().is_huge() {
+            return Some((&mut p2[page.p2_index()], HugeSize::Size2MiB));
+        }
+        None
+    }
+
+    /// Splits the huge page that maps the given `page`, if there is one, into smaller pages:
+    /// a 1GiB page into 2MiB pages, and a 2MiB page into regular 4KiB pages.
+    /// 
+    /// If `boundary_only` is `true`, a huge page is only split if the given `page` is not its first page,
+    /// which suffices to make `page` the start of a separate mapping. 
+    /// Otherwise, huge pages are split until `page` is mapped by a regular P1 entry.
+    /// 
+    /// Every address keeps its translation and flags, so other cores can keep accessing the huge page meanwhile:
+    /// its new page table is filled in through a temporary mapping before it replaces the huge page entry in a single write.
+    /// This mapper must be the active page table's mapper.
+    fn split_huge_page<A: FrameAllocator>(&mut self, page: Page, boundary_only: bool, allocator: &mut A) -> Result<(), &'static str> {
+        while let Some((entry, size)) = self.huge_entry_mut(page) {
+            // The offset of `page` within the huge page, and the number of pages that each entry of the new page table maps.
+            let (offset, pages_per_entry) = match size {
+                HugeSize::Size1GiB => (page.p2_index() * ENTRIES_PER_PAGE_TABLE + page.p1_index(), HugeSize::Size2MiB.size_in_frames()),
+                HugeSize::Size2MiB => (page.p1_index(), 1),
+            };
+            if boundary_only && offset == 0 {
+                return Ok(());
+            }
+
+            let start_frame = entry.pointed_frame().ok_or("split_huge_page(): huge page was not present")?;
+            let huge_flags = entry.flags();
+            // A 1GiB page is split into 2MiB pages, which are still huge, whereas 4KiB pages must not have the huge bit set.
+            let mut new_entry_flags = huge_flags;
+            new_entry_flags.set(EntryFlags::HUGE_PAGE, size == HugeSize::Size1GiB);
+            // P3 and P2 entries that point to a page table should never set NO_EXECUTE.
+            let mut table_flags = huge_flags;
+            table_flags.remove(EntryFlags::HUGE_PAGE | EntryFlags::NO_EXECUTE);
+
+            let table_frame = allocator.allocate_frame().map_err(|_e| "split_huge_page(): couldn't allocate a frame for a new page table")?;
+            if let Err(e) = self.fill_new_table(table_frame, start_frame, pages_per_entry, new_entry_flags, allocator) {
+                allocator.deallocate_frame(table_frame);
+                return Err(e);
+            }
+
+            // Mapping the temporary page may have changed the page tables, so the huge page entry is looked up again.
+            let (entry, _size) = self.huge_entry_mut(page).ok_or("BUG: split_huge_page(): the huge page entry disappeared")?;
+            entry.set(table_frame, table_flags.into_writable() | EntryFlags::PRESENT);
+
+            // Discard any translations that were cached from the huge page entry on every core.
+            flush_huge_page(page - offset, size);
+        }
+        Ok(())
+    }
+
+    /// Fills the new page table in the given `table_frame`, which isn't part of any page table hierarchy yet,
+    /// with entries that map consecutive runs of `frames_per_entry` frames, starting at `start_frame`, with the given `flags`.
+    ///
+    /// The new page table is written through a temporary mapping, which may change this mapper's page tables.
+    #[cfg(not(test))]
+    fn fill_new_table<A: FrameAllocator>(&mut self, table_frame: Frame, start_frame: Frame, frames_per_entry: usize, flags: EntryFlags, allocator: &mut A)
+        -> Result<(), &'static str>
+    {
+        let temp_page = allocate_pages(1).ok_or("split_huge_page(): couldn't allocate a temporary page")?;
+        let mut temp_mapping = self.map_allocated_pages_to(temp_page, FrameRange::new(table_frame, table_frame), EntryFlags::WRITABLE, allocator)?;
+        let new_table = temp_mapping.as_slice_mut::<Entry>(0, ENTRIES_PER_PAGE_TABLE)?;
+        for (i, new_entry) in new_table.iter_mut().enumerate() {
+            new_entry.set(start_frame + i * frames_per_entry, flags);
+        }
+        // Dropping the temporary mapping doesn't deallocate the frame, because it's not reference counted.
+        Ok(())
+    }
+
+    /// Host tests write the new page table directly into simulated physical memory, see the `simulated_memory` module.
+    #[cfg(test)]
+    fn fill_new_table<A: FrameAllocator>(&mut self, table_frame: Frame, start_frame: Frame, frames_per_entry: usize, flags: EntryFlags, _allocator: &mut A)
+        -> Result<(), &'static str>
+    {
+        // SAFE: the simulated contents of a page table frame are `ENTRIES_PER_PAGE_TABLE` entries that are never deallocated.
+        let new_table = unsafe { &mut *(super::simulated_memory::table_address(table_frame).value() as *mut [Entry; ENTRIES_PER_PAGE_TABLE]) };
+        for (i, new_entry) in new_table.iter_mut().enumerate() {
+            new_entry.set(start_frame + i * frames_per_entry, flags);
+        }
+        Ok(())
+    }
+}
+
+
+/// Returns whether the CPU supports mapping 1GiB pages.
+fn supports_1gib_pages() -> bool {
+    static SUPPORTS_1GIB_PAGES: Once<bool> = Once::new();
+    *SUPPORTS_1GIB_PAGES.call_once(|| {
+        CpuId::new().get_extended_function_info().map_or(false, |info| info.has_1gib_pages())
+    })
+}
+
+/// Flushes the TLB entries for every page of the huge page that starts at the given `first_page`, on all cores.
+fn flush_huge_page(first_page: Page, size: HugeSize) {
+    let pages = PageRange::new(first_page, first_page + (size.size_in_frames() - 1));
+    match size {
+        HugeSize::Size2MiB => {
+            for page in pages.clone() {
+                tlb_flush_virt_addr(page.start_address());
+            }
+        }
+        // Flushing the whole TLB is cheaper than flushing each page of a 1GiB page.
+        HugeSize::Size1GiB => tlb_flush_all(),
+    }
+    broadcast_tlb_shootdown(pages);
+}
+
+
+/// Fills the given page with zeros.
+/// 
+/// The page must be currently mapped as writable in the active page table.
+pub(crate) fn scrub_page(page: &Page) {
+    // SAFE: the caller guarantees that this page is mapped and writable, and it's not yet (or no longer) in use.
+    unsafe {
+        core::ptr::write_bytes(page.start_address().value() as *mut u8, 0, PAGE_SIZE);
+    }
 }
 
 
@@ -222,6 +555,10 @@ pub struct MappedPages {
     pages: AllocatedPages,
     // The EntryFlags that define the page permissions of this mapping
     flags: EntryFlags,
+    /// Whether this mapping's pages are only mapped to frames upon first access, see `Mapper::map_allocated_pages_lazy()`.
+    lazy: bool,
+    /// Whether this mapping may share its frames copy-on-write with other mappings, see `MappedPages::clone_cow()`.
+    cow: bool,
 }
 impl Deref for MappedPages {
     type Target = PageRange;
@@ -238,6 +575,8 @@ impl MappedPages {
             page_table_p4: get_current_p4(),
             pages: AllocatedPages::empty(),
             flags: Default::default(),
+            lazy: false,
+            cow: false,
         }
     }
 
@@ -246,6 +585,23 @@ impl MappedPages {
         self.flags
     }
 
+    /// Returns whether this mapping's pages are only mapped to frames upon first access,
+    /// in which case some of them may not be mapped yet.
+    pub fn is_lazy(&self) -> bool {
+        self.lazy
+    }
+
+    /// Returns the number of this mapping's pages that are currently mapped to frames.
+    /// 
+    /// This is every page unless this is a lazy mapping, see [`is_lazy()`](#method.is_lazy).
+    pub fn committed_pages(&self) -> usize {
+        if self.lazy {
+            demand_paging::committed_pages(self.pages.deref(), self.page_table_p4)
+        } else {
+            self.size_in_pages()
+        }
+    }
+
     /// Merges the given `MappedPages` object `mp` into this `MappedPages` object (`self`).
     ///
     /// For example, if you have the following `MappedPages` objects:    
@@ -273,6 +629,14 @@ impl MappedPages {
                 self.flags, mp.flags);
             return Err(("failed to merge MappedPages that were mapped with different flags", mp));
         }
+        if mp.lazy || self.lazy {
+            error!("MappedPages::merge(): lazy mappings can't be merged");
+            return Err(("failed to merge MappedPages because one of them was mapped lazily", mp));
+        }
+        if mp.cow || self.cow {
+            error!("MappedPages::merge(): copy-on-write mappings can't be merged");
+            return Err(("failed to merge MappedPages because one of them is copy-on-write", mp));
+        }
 
         // Attempt to merge the page ranges together, which will fail if they're not contiguous.
         // First, take ownership of the AllocatedPages inside of the `mp` argument.
@@ -291,6 +655,258 @@ impl MappedPages {
         Ok(())
     }
 
+    /// Splits this `MappedPages` into two separate `MappedPages` objects:
+    /// * `[beginning : at_page - 1]`
+    /// * `[at_page : end]`
+    /// 
+    /// This allows only part of a mapping to be unmapped, by dropping one of the two returned mappings.
+    /// If `at_page` lies within a huge page, that huge page is first split into smaller pages,
+    /// which may require allocating a new page table from the given `allocator`.
+    /// 
+    /// Lazy and copy-on-write mappings can't be split.
+    /// If an error occurs, such as `at_page` not being within the bounds of this mapping,
+    /// then a tuple including an error message and this original `MappedPages` will be returned.
+    pub fn split<A: FrameAllocator>(mut self, at_page: Page, active_table_mapper: &mut Mapper, allocator: &mut A)
+        -> Result<(MappedPages, MappedPages), (&'static str, MappedPages)>
+    {
+        if !(at_page > *self.pages.start() && at_page <= *self.pages.end()) {
+            return Err(("failed to split MappedPages because the page to split at was out of bounds", self));
+        }
+        if active_table_mapper.target_p4 != self.page_table_p4 {
+            return Err(("failed to split MappedPages that weren't mapped in the active page table", self));
+        }
+        if self.lazy || self.cow {
+            return Err(("failed to split MappedPages because it was mapped lazily or is copy-on-write", self));
+        }
+        if let Err(e) = active_table_mapper.split_huge_page(at_page, true, allocator) {
+            return Err((e, self));
+        }
+
+        let pages = mem::replace(&mut self.pages, AllocatedPages::empty());
+        let (first, second) = match pages.split(at_page) {
+            Some(split_pages) => split_pages,
+            None => return Err(("BUG: MappedPages::split(): failed to split AllocatedPages", self)),
+        };
+        let (page_table_p4, flags) = (self.page_table_p4, self.flags);
+        // Ensure this mapping doesn't run its drop handler, since its pages are now owned by the two new mappings.
+        mem::forget(self);
+        Ok((
+            MappedPages { page_table_p4, pages: first,  flags, lazy: false, cow: false },
+            MappedPages { page_table_p4, pages: second, flags, lazy: false, cow: false },
+        ))
+    }
+
+    /// Changes the permissions of only the given `pages` within this mapping to `new_flags`,
+    /// e.g., to make one section of a loaded crate read-only while the rest of its pages stay writable.
+    ///
+    /// Since all pages of a `MappedPages` object have the same flags, this mapping is first split
+    /// around the given `pages` as needed (see [`split()`](#method.split)), and the resulting mappings are returned:
+    /// * the pages before `pages`, if any, which keep their existing flags,
+    /// * the given `pages`, which now have the `new_flags`,
+    /// * the pages after `pages`, if any, which keep their existing flags.
+    ///
+    /// Lazy and copy-on-write mappings can't be split, so their flags can only be changed as a whole.
+    /// If an error occurs, such as `pages` not being within the bounds of this mapping,
+    /// then a tuple including an error message and this original `MappedPages` will be returned.
+    pub fn remap_range<A: FrameAllocator>(self, pages: PageRange, new_flags: EntryFlags, active_table_mapper: &mut Mapper, allocator: &mut A)
+        -> Result<(Option<MappedPages>, MappedPages, Option<MappedPages>), (&'static str, MappedPages)>
+    {
+        if pages.size_in_pages() == 0 || pages.start() < self.pages.start() || pages.end() > self.pages.end() {
+            return Err(("failed to remap a range of MappedPages because the range was out of bounds", self));
+        }
+
+        let (before, rest) = if pages.start() > self.pages.start() {
+            let (before, rest) = self.split(*pages.start(), active_table_mapper, allocator)?;
+            (Some(before), rest)
+        } else {
+            (None, self)
+        };
+        let (mut middle, after) = if pages.end() < rest.pages.end() {
+            match rest.split(*pages.end() + 1, active_table_mapper, allocator) {
+                Ok((middle, after)) => (middle, Some(after)),
+                Err((e, rest)) => return Err((e, Self::rejoin(before, rest, None))),
+            }
+        } else {
+            (rest, None)
+        };
+
+        if let Err(e) = middle.remap(active_table_mapper, new_flags) {
+            return Err((e, Self::rejoin(before, middle, after)));
+        }
+        Ok((before, middle, after))
+    }
+
+    /// Merges the mappings that were split from a single mapping by `remap_range()` back together.
+    ///
+    /// They all still have the original mapping's flags, so merging them only fails due to a bug.
+    /// In that case, the pieces that can't be merged are leaked rather than unmapped,
+    /// because other parts of the original mapping may still be in use.
+    fn rejoin(before: Option<MappedPages>, middle: MappedPages, after: Option<MappedPages>) -> MappedPages {
+        let mut mp = match before {
+            Some(mut before) => match before.merge(middle) {
+                Ok(()) => before,
+                Err((e, middle)) => {
+                    error!("BUG: MappedPages::remap_range(): {}", e);
+                    mem::forget(middle);
+                    before
+                }
+            },
+            None => middle,
+        };
+        if let Some(after) = after {
+            if let Err((e, after)) = mp.merge(after) {
+                error!("BUG: MappedPages::remap_range(): {}", e);
+                mem::forget(after);
+            }
+        }
+        mp
+    }
+
+
+    /// Grows this mapping to `new_size_in_pages` pages, mapping the added pages to newly-allocated frames
+    /// with this mapping's existing flags. 
+    /// 
+    /// If the virtual pages right after this mapping are free, it's extended in place.
+    /// Otherwise, it's moved to a new range of virtual pages that is large enough:
+    /// its existing frames are remapped to the new pages, so its contents are preserved without being copied,
+    /// but its starting virtual address changes. Any huge pages must then be split into regular pages.
+    /// 
+    /// Lazy and copy-on-write mappings can't be grown, and this mapping must be in the active page table.
+    /// If an error is returned, this mapping is unchanged.
+    pub fn grow<A: FrameAllocator>(&mut self, new_size_in_pages: usize, active_table_mapper: &mut Mapper, allocator: &mut A) -> Result<(), &'static str> {
+        let old_size_in_pages = self.size_in_pages();
+        if new_size_in_pages < old_size_in_pages {
+            return Err("MappedPages::grow(): the new size is smaller than the current size");
+        }
+        if new_size_in_pages == old_size_in_pages {
+            return Ok(());
+        }
+        if active_table_mapper.target_p4 != self.page_table_p4 {
+            return Err("MappedPages::grow(): mapping wasn't mapped in the active page table");
+        }
+        if self.lazy || self.cow {
+            return Err("MappedPages::grow(): lazy and copy-on-write mappings can't be grown");
+        }
+        use paging::{allocate_pages, allocate_pages_at};
+        if old_size_in_pages == 0 {
+            let pages = allocate_pages(new_size_in_pages).ok_or("MappedPages::grow(): couldn't allocate pages")?;
+            let flags = self.flags;
+            *self = active_table_mapper.map_allocated_pages(pages, flags, allocator)?;
+            return Ok(());
+        }
+
+        // First, try to extend this mapping in place.
+        let extra_size_in_pages = new_size_in_pages - old_size_in_pages;
+        let next_page = *self.pages.end() + 1;
+        if let Ok(extra_pages) = allocate_pages_at(next_page.start_address(), extra_size_in_pages) {
+            let extra = active_table_mapper.map_allocated_pages(extra_pages, self.flags, allocator)?;
+            return self.merge(extra).map_err(|(e, _extra)| e);
+        }
+
+        // Otherwise, move this mapping to new pages. The added pages are mapped first, such that nothing has changed if that fails.
+        let new_pages = allocate_pages(new_size_in_pages).ok_or("MappedPages::grow(): couldn't allocate pages")?;
+        let new_start = *new_pages.start();
+        let (moved_pages, extra_pages) = new_pages.split(new_start + old_size_in_pages)
+            .ok_or("BUG: MappedPages::grow(): failed to split AllocatedPages")?;
+        let extra = active_table_mapper.map_allocated_pages(extra_pages, self.flags, allocator)?;
+        self.move_to(moved_pages, active_table_mapper, allocator)?;
+        self.merge(extra).map_err(|(e, _extra)| e)
+    }
+
+    /// Shrinks this mapping to its first `new_size_in_pages` pages, unmapping the rest of them in place. 
+    /// 
+    /// If the new end of this mapping lies within a huge page, that huge page is first split into smaller pages,
+    /// which may require allocating a new page table from the given `allocator`.
+    /// 
+    /// Lazy and copy-on-write mappings can't be shrunk, unless they're shrunk to zero pages.
+    /// If an error is returned, this mapping is unchanged.
+    pub fn shrink<A: FrameAllocator>(&mut self, new_size_in_pages: usize, active_table_mapper: &mut Mapper, allocator: &mut A) -> Result<(), &'static str> {
+        let old_size_in_pages = self.size_in_pages();
+        if new_size_in_pages > old_size_in_pages {
+            return Err("MappedPages::shrink(): the new size is larger than the current size");
+        }
+        if new_size_in_pages == old_size_in_pages {
+            return Ok(());
+        }
+        if new_size_in_pages == 0 {
+            // Dropping this mapping unmaps all of its pages, but its flags are kept in case it's grown again.
+            let flags = self.flags;
+            *self = MappedPages::empty();
+            self.flags = flags;
+            return Ok(());
+        }
+
+        let at_page = *self.pages.start() + new_size_in_pages;
+        let mp = mem::replace(self, MappedPages::empty());
+        match mp.split(at_page, active_table_mapper, allocator) {
+            Ok((first, _unmapped_tail)) => {
+                *self = first;
+                Ok(())
+            }
+            Err((e, mp)) => {
+                *self = mp;
+                Err(e)
+            }
+        }
+    }
+
+    /// Moves this mapping to the given `new_pages`, which must be the same size,
+    /// by remapping each of its frames to the corresponding new page with the same flags.
+    /// Its old pages are then unmapped and deallocated.
+    fn move_to<A: FrameAllocator>(&mut self, new_pages: AllocatedPages, active_table_mapper: &mut Mapper, allocator: &mut A) -> Result<(), &'static str> {
+        if new_pages.size_in_pages() != self.size_in_pages() {
+            return Err("BUG: MappedPages::move_to(): the new pages must be the same size as this mapping");
+        }
+        // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
+        let mut top_level_flags = self.flags;
+        top_level_flags.set(EntryFlags::NO_EXECUTE, false);
+
+        // Create all page tables for the new pages and split all huge pages first, as both may fail,
+        // such that this mapping is only changed once nothing else can fail.
+        for page in new_pages.deref().clone() {
+            let p3 = active_table_mapper.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator);
+            let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator);
+            let p1 = p2.next_table_create(page.p2_index(), top_level_flags, allocator);
+            if !p1[page.p1_index()].is_unused() {
+                return Err("MappedPages::move_to(): new page was already in use");
+            }
+        }
+        for page in self.pages.deref().clone() {
+            active_table_mapper.split_huge_page(page, false, allocator)?;
+        }
+
+        // Each frame is moved from one mapping to another, so its reference count is unchanged.
+        // A page that was evicted to swap is moved along with its swap entry.
+        for (old_page, new_page) in self.pages.deref().clone().into_iter().zip(new_pages.deref().clone()) {
+            let entry = {
+                let old_entry = &mut active_table_mapper.p4_mut()
+                    .next_table_mut(old_page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(old_page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(old_page.p2_index()))
+                    .ok_or("BUG: MappedPages::move_to(): couldn't access the P1 table of an old page")?
+                    [old_page.p1_index()];
+                if old_entry.pointed_frame().is_none() && old_entry.swap_slot().is_none() {
+                    return Err("BUG: MappedPages::move_to(): old page was not mapped");
+                }
+                let entry = old_entry.copy();
+                old_entry.set_unused();
+                entry
+            };
+            tlb_flush_virt_addr(old_page.start_address());
+            let new_p1 = active_table_mapper.p4_mut()
+                .next_table_mut(new_page.p4_index())
+                .and_then(|p3| p3.next_table_mut(new_page.p3_index()))
+                .and_then(|p2| p2.next_table_mut(new_page.p2_index()))
+                .ok_or("BUG: MappedPages::move_to(): couldn't access the P1 table of a new page")?;
+            new_p1[new_page.p1_index()] = entry;
+        }
+        broadcast_tlb_shootdown(self.pages.deref().clone());
+
+        // The old pages are deallocated here, but not unmapped again, since they're no longer mapped.
+        let _old_pages = mem::replace(&mut self.pages, new_pages);
+        Ok(())
+    }
+
 
     /// Creates a deep copy of this `MappedPages` memory region,
     /// by duplicating not only the virtual memory mapping
@@ -334,69 +950,426 @@ impl MappedPages {
         Ok(new_mapped_pages)
     }
 
+
+    /// Creates a copy-on-write clone of this `MappedPages` memory region,
+    /// which maps the same underlying frames into a new virtual memory region.
+    /// 
+    /// Both this mapping's pages and the clone's pages are write-protected, and each frame's refcount is incremented
+    /// for the clone's reference to it (see the `frame_refcount` module).
+    /// If this mapping is writable, the first write to a page of either mapping is handled by the page fault handler,
+    /// which copies that page's frame such that the two mappings no longer share it, see the `copy_on_write` module.
+    /// Thus, this is much cheaper than [`deep_copy()`](#method.deep_copy) if only a few pages will be modified.
+    /// 
+    /// Returns the clone, which has the same flags as this mapping.
+    /// This mapping must be in the active page table, and must not be a lazy mapping.
+    pub fn clone_cow<A: FrameAllocator>(&mut self, active_table_mapper: &mut Mapper, allocator: &mut A) -> Result<MappedPages, &'static str> {
+        if active_table_mapper.target_p4 != self.page_table_p4 {
+            return Err("clone_cow(): this mapping is not in the active page table");
+        }
+        if self.lazy {
+            return Err("clone_cow(): lazy mappings can't be cloned copy-on-write");
+        }
+        use paging::allocate_pages;
+        let new_pages = allocate_pages(self.size_in_pages()).ok_or("clone_cow(): couldn't allocate pages")?;
+
+        // P4, P3, and P2 entries should never set NO_EXECUTE, only the lowest-level P1 entry should. 
+        let mut top_level_flags = self.flags.clone();
+        top_level_flags.set(EntryFlags::NO_EXECUTE, false);
+        let mut read_only_flags = self.flags.clone();
+        read_only_flags.set(EntryFlags::WRITABLE, false);
+
+        // Each page's frame is shared and copied individually, so any huge pages must be split into regular pages.
+        let (pages, page_table_p4) = (self.pages.deref().clone(), self.page_table_p4);
+        for page in pages.clone() {
+            active_table_mapper.split_huge_page(page, false, allocator)?;
+        }
+
+        // Hold off faults on this mapping while its pages are write-protected, so that none becomes writable again midway.
+        if !self.cow {
+            copy_on_write::register(pages.clone(), page_table_p4, self.flags);
+            self.cow = true;
+        }
+        let mut frames: Vec<Frame> = Vec::with_capacity(self.size_in_pages());
+        copy_on_write::set_flags(&pages, page_table_p4, self.flags, || {
+            for page in pages.clone() {
+                let p1 = active_table_mapper.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .ok_or("mapping code does not support huge pages")?;
+                let frame = p1[page.p1_index()].pointed_frame().ok_or("clone_cow(): page not mapped")?;
+                p1[page.p1_index()].set(frame, read_only_flags | EntryFlags::PRESENT);
+                tlb_flush_virt_addr(page.start_address());
+                // An untracked frame starts being tracked with a refcount of 1, for this mapping's reference to it.
+                if frame_refcount::refcount(frame).is_none() {
+                    frame_refcount::incref(frame);
+                }
+                frames.push(frame);
+            }
+            broadcast_tlb_shootdown(pages.clone());
+            Ok(())
+        })?;
+
+        for (page, frame) in new_pages.deref().clone().into_iter().zip(frames) {
+            let p3 = active_table_mapper.p4_mut().next_table_create(page.p4_index(), top_level_flags, allocator);
+            let p2 = p3.next_table_create(page.p3_index(), top_level_flags, allocator);
+            let p1 = p2.next_table_create(page.p2_index(), top_level_flags, allocator);
+            if !p1[page.p1_index()].is_unused() {
+                error!("clone_cow(): page {:#X} was already in use!", page.start_address());
+                return Err("clone_cow(): page was already in use");
+            }
+            p1[page.p1_index()].set(frame, read_only_flags | EntryFlags::PRESENT);
+            frame_refcount::incref(frame);
+        }
+
+        copy_on_write::register(new_pages.deref().clone(), page_table_p4, self.flags);
+        Ok(MappedPages {
+            page_table_p4,
+            pages: new_pages,
+            flags: self.flags,
+            lazy: false,
+            cow: true,
+        })
+    }
+
+    /// Returns whether this mapping may share its frames copy-on-write with other mappings,
+    /// i.e., whether it was created by or is the source of [`clone_cow()`](#method.clone_cow).
+    pub fn is_cow(&self) -> bool {
+        self.cow
+    }
+
     
     /// Change the permissions (`new_flags`) of this `MappedPages`'s page table entries.
+    /// 
+    /// To change the permissions of only some of its pages, see [`remap_range()`](#method.remap_range).
     pub fn remap(&mut self, active_table_mapper: &mut Mapper, new_flags: EntryFlags) -> Result<(), &'static str> {
         if self.size_in_pages() == 0 { return Ok(()); }
+        let new_flags = wx::enforce(new_flags, *self.pages.start());
 
         if new_flags == self.flags {
             trace!("remap(): new_flags were the same as existing flags, doing nothing.");
             return Ok(());
         }
 
-        for page in self.pages.clone() {
+        // Hold off faults on a lazy or copy-on-write mapping, such that no fault fixes up a page with the old flags after it's been remapped.
+        let (pages, page_table_p4) = (self.pages.deref().clone(), self.page_table_p4);
+        if self.lazy {
+            demand_paging::set_flags(&pages, page_table_p4, new_flags, || Self::remap_pages(&pages, true, false, active_table_mapper, new_flags))?;
+        } else if self.cow {
+            copy_on_write::set_flags(&pages, page_table_p4, new_flags, || Self::remap_pages(&pages, false, true, active_table_mapper, new_flags))?;
+        } else {
+            Self::remap_pages(&pages, false, false, active_table_mapper, new_flags)?;
+        }
+        self.flags = new_flags;
+        Ok(())
+    }   
+
+    /// Changes the flags of the given `pages`' page table entries.
+    /// 
+    /// Non-present pages are skipped if `lazy` is true, and pages whose frames are still shared
+    /// are kept write-protected if `cow` is true.
+    fn remap_pages(pages: &PageRange, lazy: bool, cow: bool, active_table_mapper: &mut Mapper, new_flags: EntryFlags) -> Result<(), &'static str> {
+        let mut page = *pages.start();
+        let mut remaining = pages.size_in_pages();
+        while remaining > 0 {
+            // A huge page is remapped as a whole, since it lies entirely within its mapping.
+            if let Some((entry, size)) = active_table_mapper.huge_entry_mut(page) {
+                let frame = entry.pointed_frame().ok_or("remap(): huge page not mapped")?;
+                entry.set(frame, new_flags.into_huge() | EntryFlags::PRESENT);
+                tlb_flush_virt_addr(page.start_address());
+                let num_pages = core::cmp::min(size.size_in_frames(), remaining);
+                remaining -= num_pages;
+                page = page + num_pages;
+                continue;
+            }
+
             let p1 = active_table_mapper.p4_mut()
                 .next_table_mut(page.p4_index())
                 .and_then(|p3| p3.next_table_mut(page.p3_index()))
                 .and_then(|p2| p2.next_table_mut(page.p2_index()))
                 .ok_or("mapping code does not support huge pages")?;
             
-            let frame = p1[page.p1_index()].pointed_frame().ok_or("remap(): page not mapped")?;
-            p1[page.p1_index()].set(frame, new_flags | EntryFlags::PRESENT);
+            // A page that was evicted to swap is mapped with the new flags once it's swapped back in.
+            if let Some(slot) = p1[page.p1_index()].swap_slot() {
+                p1[page.p1_index()].set_swapped(slot, new_flags);
+                remaining -= 1;
+                page = page + 1;
+                continue;
+            }
+            let frame = match p1[page.p1_index()].pointed_frame() {
+                Some(frame) => frame,
+                None if lazy => {
+                    remaining -= 1;
+                    page = page + 1;
+                    continue;
+                }
+                None => return Err("remap(): page not mapped"),
+            };
+            let mut flags = new_flags;
+            if cow && copy_on_write::is_shared(frame) {
+                flags.set(EntryFlags::WRITABLE, false);
+            }
+            p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
 
             tlb_flush_virt_addr(page.start_address());
+            remaining -= 1;
+            page = page + 1;
         }
         
-        if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.try() {
-            func(self.pages.deref().clone());
+        broadcast_tlb_shootdown(pages.clone());
+        Ok(())
+    }
+
+
+    /// Copies the contents of the given `page` of this mapping into the given `new_frame`,
+    /// and then remaps that `page` to the `new_frame` with this mapping's existing flags.
+    /// 
+    /// Returns the frame that the `page` was previously mapped to, which this mapping no longer refers to.
+    /// 
+    /// This is used by memory compaction to move this mapping's contents elsewhere in physical memory.
+    /// The caller must own the `new_frame` and ensure that nothing accesses this mapping's contents until this returns.
+    /// 
+    /// Other cores' TLB entries for the `page` are only invalidated once the given `shootdowns` batch is flushed,
+    /// so the returned frame must not be reused before then.
+    pub(crate) fn migrate_page<A: FrameAllocator>(
+        &mut self,
+        page: Page,
+        new_frame: Frame,
+        active_table_mapper: &mut Mapper,
+        allocator: &mut A,
+        shootdowns: &mut TlbShootdownBatch,
+    ) -> Result<Frame, &'static str> {
+        if active_table_mapper.target_p4 != self.page_table_p4 {
+            return Err("migrate_page(): this mapping is not in the active page table");
+        }
+        if !self.pages.contains(&page) {
+            return Err("migrate_page(): page is not part of this mapping");
         }
 
-        self.flags = new_flags;
-        Ok(())
-    }   
+        // Copy the page's contents into the new frame through a temporary mapping of it.
+        use paging::allocate_pages;
+        let temp_page = allocate_pages(1).ok_or("migrate_page(): couldn't allocate a temporary page")?;
+        let mut temp_mapping = active_table_mapper.map_allocated_pages_to(
+            temp_page, FrameRange::new(new_frame, new_frame), EntryFlags::WRITABLE, allocator
+        )?;
+        {
+            // SAFE: the page is mapped by this `MappedPages`, and the caller guarantees that it isn't being modified.
+            let source: &[u8] = unsafe { slice::from_raw_parts(page.start_address().value() as *const u8, PAGE_SIZE) };
+            let dest: &mut [u8] = temp_mapping.as_slice_mut(0, PAGE_SIZE)?;
+            dest.copy_from_slice(source);
+        }
+        // Dropping the temporary mapping doesn't deallocate the new frame, because it's not reference counted.
+        drop(temp_mapping);
+
+        // Only this one page moves to a different frame, so it can no longer be part of a huge page.
+        active_table_mapper.split_huge_page(page, false, allocator)?;
+        let p1 = active_table_mapper.p4_mut()
+            .next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .ok_or("mapping code does not support huge pages")?;
+        let old_frame = p1[page.p1_index()].pointed_frame().ok_or("migrate_page(): page not mapped")?;
+        p1[page.p1_index()].set(new_frame, self.flags | EntryFlags::PRESENT);
+        tlb_flush_virt_addr(page.start_address());
+
+        shootdowns.add(PageRange::new(page, page));
+        Ok(old_frame)
+    }
+
+
+    /// Tries to evict the given `page` of this mapping to the given swap `backend`, freeing up its frame.
+    /// 
+    /// This implements one step of the clock algorithm: if the page was accessed since it was last checked,
+    /// its accessed bit is cleared and it's given another chance, i.e., `Eviction::Referenced` is returned.
+    /// Otherwise, its contents are written to the `backend` and its page table entry is replaced by a swap entry,
+    /// and `Eviction::Evicted` is returned with the frame that it was mapped to.
+    /// Huge pages, copy-on-write pages, and pages whose frames are shared or pinned are never evicted.
+    /// 
+    /// The caller must ensure that nothing accesses this mapping's contents until this returns.
+    /// Other cores' TLB entries for the `page` are only invalidated once the given `shootdowns` batch is flushed,
+    /// so the returned frame must not be reused before then.
+    pub(crate) fn evict_page(
+        &mut self,
+        page: Page,
+        active_table_mapper: &mut Mapper,
+        backend: &mut dyn SwapBackend,
+        shootdowns: &mut TlbShootdownBatch,
+    ) -> Result<Eviction, &'static str> {
+        if active_table_mapper.target_p4 != self.page_table_p4 {
+            return Err("evict_page(): this mapping is not in the active page table");
+        }
+        if !self.pages.contains(&page) {
+            return Err("evict_page(): page is not part of this mapping");
+        }
+        if self.cow || active_table_mapper.huge_entry_mut(page).is_some() {
+            return Ok(Eviction::Ineligible);
+        }
+        let entry = match active_table_mapper.p4_mut()
+            .next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+        {
+            Some(p1) => &mut p1[page.p1_index()],
+            None => return Ok(Eviction::Ineligible),
+        };
+        let frame = match entry.pointed_frame() {
+            Some(frame) => frame,
+            None => return Ok(Eviction::Ineligible),
+        };
+        if frame_refcount::refcount(frame).is_some() || frame_pinning::pin_count(frame) > 0 {
+            return Ok(Eviction::Ineligible);
+        }
+
+        let flags = entry.flags();
+        if flags.contains(EntryFlags::ACCESSED) {
+            // The accessed bit is only set again once the page's TLB entries have been invalidated.
+            entry.set(frame, flags - EntryFlags::ACCESSED);
+            tlb_flush_virt_addr(page.start_address());
+            shootdowns.add(PageRange::new(page, page));
+            return Ok(Eviction::Referenced);
+        }
+
+        let slot = {
+            // SAFE: the page is mapped by this `MappedPages`, and the caller guarantees that it isn't being modified.
+            let contents: &[u8] = unsafe { slice::from_raw_parts(page.start_address().value() as *const u8, PAGE_SIZE) };
+            backend.store(contents)?
+        };
+        entry.set_swapped(slot, self.flags);
+        tlb_flush_virt_addr(page.start_address());
+        shootdowns.add(PageRange::new(page, page));
+        Ok(Eviction::Evicted(frame))
+    }
 
 
     /// Remove the virtual memory mapping for the given `Page`s.
     /// This should NOT be public because it should only be invoked when a `MappedPages` object is dropped.
-    fn unmap<A>(&mut self, active_table_mapper: &mut Mapper, _allocator_ref: &MutexIrqSafe<A>) -> Result<(), &'static str> 
+    /// 
+    /// Any reference-counted frames that were mapped by these pages have their refcount decremented,
+    /// and are deallocated if this was their final reference. See the `frame_refcount` module.
+    fn unmap<A>(&mut self, active_table_mapper: &mut Mapper, allocator_ref: &MutexIrqSafe<A>) -> Result<(), &'static str> 
         where A: FrameAllocator
     {
         if self.size_in_pages() == 0 { return Ok(()); }
 
-        for page in self.pages.clone() {            
-            let p1 = active_table_mapper.p4_mut()
+        // Frames can only be deallocated once every core has flushed its stale TLB entries for them.
+        let mut frames_to_deallocate: Vec<Frame> = Vec::new();
+
+        // Stop fixing up faults on this mapping's pages before unmapping them.
+        if self.lazy {
+            demand_paging::unregister(self.pages.deref(), self.page_table_p4);
+        }
+        if self.cow {
+            copy_on_write::unregister(self.pages.deref(), self.page_table_p4);
+        }
+
+        // An error stops the unmapping, but the pages that were already unmapped must still be shot down
+        // and their frames deallocated, so the loop is exited with `break` instead of returning early.
+        let mut result = Ok(());
+        let mut next_page = *self.pages.start();
+        let mut remaining = self.size_in_pages();
+        while remaining > 0 {
+            let page = next_page;
+            // A huge page is unmapped as a whole, so it must lie entirely within this mapping, see `split()`.
+            if let Some((entry, size)) = active_table_mapper.huge_entry_mut(page) {
+                let num_pages = size.size_in_frames();
+                if num_pages > remaining {
+                    result = Err("BUG: unmap(): huge page extends beyond the end of this mapping");
+                    break;
+                }
+                let start_frame = match entry.pointed_frame() {
+                    Some(frame) => frame,
+                    None => {
+                        result = Err("unmap(): huge page not mapped");
+                        break;
+                    }
+                };
+                if SCRUB_FRAMES_ON_FREE && !entry.flags().is_writable() {
+                    entry.set(start_frame, self.flags.into_huge() | EntryFlags::PRESENT | EntryFlags::WRITABLE);
+                    tlb_flush_virt_addr(page.start_address());
+                }
+                for i in 0 .. num_pages {
+                    let dealloc = frame_refcount::decref_no_dealloc(start_frame + i);
+                    if dealloc && SCRUB_FRAMES_ON_FREE {
+                        scrub_page(&(page + i));
+                    }
+                    if dealloc {
+                        frames_to_deallocate.push(start_frame + i);
+                    }
+                }
+                entry.set_unused();
+                tlb_flush_virt_addr(page.start_address());
+                remaining -= num_pages;
+                next_page = page + num_pages;
+                continue;
+            }
+            remaining -= 1;
+            next_page = page + 1;
+
+            let p1 = match active_table_mapper.p4_mut()
                 .next_table_mut(page.p4_index())
                 .and_then(|p3| p3.next_table_mut(page.p3_index()))
                 .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                .ok_or("mapping code does not support huge pages")?;
+            {
+                Some(p1) => p1,
+                None => {
+                    result = Err("mapping code does not support huge pages");
+                    break;
+                }
+            };
             
-            let _frame = p1[page.p1_index()].pointed_frame().ok_or("unmap(): page not mapped")?;
+            // A page that was evicted to swap no longer has a frame, but its swap slot must be freed.
+            if let Some(slot) = p1[page.p1_index()].swap_slot() {
+                swap::free_slot(slot);
+                p1[page.p1_index()].set_unused();
+                continue;
+            }
+            let frame = match p1[page.p1_index()].pointed_frame() {
+                Some(frame) => frame,
+                None if self.lazy => continue,
+                None => {
+                    result = Err("unmap(): page not mapped");
+                    break;
+                }
+            };
+            let dealloc = frame_refcount::decref_no_dealloc(frame);
+            if dealloc && SCRUB_FRAMES_ON_FREE {
+                // Scrub the frame through this page while it's still mapped, making it writable if necessary,
+                // e.g., if it was write-protected because it was shared copy-on-write.
+                if !p1[page.p1_index()].flags().is_writable() {
+                    p1[page.p1_index()].set(frame, self.flags | EntryFlags::PRESENT | EntryFlags::WRITABLE);
+                    tlb_flush_virt_addr(page.start_address());
+                }
+                scrub_page(&page);
+            }
             p1[page.p1_index()].set_unused();
 
             tlb_flush_virt_addr(page.start_address());
             
             // TODO free p(1,2,3) table if empty
-            // _allocator_ref.lock().deallocate_frame(frame);
+            if dealloc {
+                frames_to_deallocate.push(frame);
+            }
         }
     
+        // Every page before `next_page` has been unmapped, or at least wasn't modified.
         #[cfg(not(bm_map))]
         {
-            if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.try() {
-                func(self.pages.deref().clone());
+            if next_page > *self.pages.start() {
+                broadcast_tlb_shootdown(PageRange::new(*self.pages.start(), next_page - 1));
             }
         }
 
-        Ok(())
+        if !frames_to_deallocate.is_empty() {
+            {
+                let mut allocator = allocator_ref.lock();
+                for frame in frames_to_deallocate {
+                    allocator.deallocate_frame(frame);
+                }
+            }
+            zeroed_frames::notify_frames_freed();
+        }
+
+        result
     }
 
 
@@ -694,3 +1667,275 @@ impl Drop for MappedPages {
     }
 }
 
+
+
+#[cfg(test)]
+use super::simulated_memory::{self, SimulatedTables, TableFrameAllocator};
+#[cfg(test)]
+use page_table_model::{self, ExpectedMappings, Leaf, PageSize, PageTableModel};
+#[cfg(test)]
+use page_allocator::{allocate_pages_at, convert_to_heap_allocated};
+#[cfg(test)]
+use kernel_config::memory::KERNEL_TEXT_START;
+
+#[cfg(test)]
+const HUGE_2MIB_PAGES: usize = ENTRIES_PER_PAGE_TABLE;
+#[cfg(test)]
+const HUGE_1GIB_PAGES: usize = ENTRIES_PER_PAGE_TABLE * ENTRIES_PER_PAGE_TABLE;
+/// The physical address of the first frame that the tests map, which is aligned to a 1GiB page.
+#[cfg(test)]
+const TEST_PADDR: usize = 0x40_0000_0000;
+
+/// A page table in simulated memory that the real `Mapper` operates on, see the `simulated_memory` module,
+/// along with the mappings it should have and a `PageTableModel` that the same operations are performed on.
+///
+/// Tests run concurrently and pages are never returned to the page allocator,
+/// so each test maps its pages within its own two gigabytes of the kernel's virtual addresses.
+#[cfg(test)]
+struct TestPageTable {
+    mapper: Mapper,
+    allocator: TableFrameAllocator,
+    expected: ExpectedMappings,
+    model: PageTableModel,
+    test_index: usize,
+}
+
+#[cfg(test)]
+impl TestPageTable {
+    /// Creates an empty page table, which becomes the current thread's active page table.
+    fn new(test_index: usize) -> TestPageTable {
+        convert_to_heap_allocated();
+        let mut allocator = TableFrameAllocator { allocated: 0 };
+        let p4 = allocator.allocate_frame().unwrap();
+        simulated_memory::set_active_p4(p4);
+        let largest_page = if supports_1gib_pages() { PageSize::Size1GiB } else { PageSize::Size2MiB };
+        TestPageTable {
+            mapper: Mapper::with_p4_frame(p4),
+            allocator,
+            expected: ExpectedMappings::new(),
+            model: PageTableModel::new(largest_page),
+            test_index,
+        }
+    }
+
+    /// The virtual address of the page at the given `page_offset` within this test's virtual addresses.
+    fn vaddr(&self, page_offset: usize) -> usize {
+        KERNEL_TEXT_START + (self.test_index * 2 * HUGE_1GIB_PAGES + page_offset) * PAGE_SIZE
+    }
+
+    fn page(&self, page_offset: usize) -> Page {
+        Page::containing_address(VirtualAddress::new_canonical(self.vaddr(page_offset)))
+    }
+
+    /// Maps `num_pages` pages at `page_offset` to the frames at `frame_offset` after `TEST_PADDR`
+    /// with the given `flags`, both with the real `Mapper` and in the model.
+    fn map(&mut self, page_offset: usize, frame_offset: usize, num_pages: usize, flags: EntryFlags) -> MappedPages {
+        let pages = allocate_pages_at(VirtualAddress::new_canonical(self.vaddr(page_offset)), num_pages).unwrap();
+        let start_frame = Frame::containing_address(PhysicalAddress::new_canonical(TEST_PADDR + frame_offset * PAGE_SIZE));
+        let frames = FrameRange::new(start_frame, start_frame + (num_pages - 1));
+        let mapped_pages = self.mapper.map_allocated_pages_to(pages, frames, flags, &mut self.allocator).unwrap();
+
+        let (vaddr, paddr) = (self.vaddr(page_offset) as u64, start_frame.start_address().value() as u64);
+        self.model.map(vaddr, paddr, num_pages as u64, flags.bits()).unwrap();
+        self.expected.map(vaddr, paddr, num_pages as u64, flags.bits());
+        mapped_pages
+    }
+
+    /// Unmaps the given `mapped_pages` with the real `Mapper`, and unmaps the same pages in the model.
+    fn unmap(&mut self, mut mapped_pages: MappedPages) {
+        let (vaddr, num_pages) = (mapped_pages.start_address().value() as u64, mapped_pages.size_in_pages() as u64);
+        // The test frames aren't reference counted, so unmapping them never locks this allocator.
+        let unused_allocator = MutexIrqSafe::new(TableFrameAllocator { allocated: 0 });
+        mapped_pages.unmap(&mut self.mapper, &unused_allocator).unwrap();
+        // The drop handler would try to unmap the pages again.
+        mem::forget(mapped_pages);
+
+        self.model.unmap(vaddr, num_pages).unwrap();
+        self.expected.unmap(vaddr, num_pages);
+    }
+
+    /// Returns the leaf entries of the real page table that map `num_pages` pages at `page_offset`.
+    fn leaves(&self, page_offset: usize, num_pages: usize) -> Vec<Leaf> {
+        let range = self.vaddr(page_offset) as u64 .. self.vaddr(page_offset + num_pages) as u64;
+        page_table_model::walk(&SimulatedTables(self.mapper.target_p4), range).unwrap()
+    }
+
+    fn page_sizes(&self, page_offset: usize, num_pages: usize) -> Vec<PageSize> {
+        self.leaves(page_offset, num_pages).iter().map(|leaf| leaf.size).collect()
+    }
+
+    /// Checks that the real page table has exactly the expected mappings,
+    /// and that it maps them with the same page sizes and as many page tables as the model.
+    fn check(&self) {
+        let range = self.vaddr(0) as u64 .. self.vaddr(2 * HUGE_1GIB_PAGES) as u64;
+        let tables = SimulatedTables(self.mapper.target_p4);
+        self.expected.check(&tables, range.clone()).unwrap();
+        page_table_model::compare(&tables, &self.model, range).unwrap();
+        assert_eq!(self.allocator.allocated, self.model.table_count(), "the Mapper and the model have different numbers of page tables");
+    }
+}
+
+
+#[test]
+/// To run this test, execute: `cargo test test_map_huge_pages -- --nocapture`
+fn test_map_huge_pages() {
+    let mut table = TestPageTable::new(0);
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    let num_pages = 2 * HUGE_2MIB_PAGES + 3;
+    let mapped = table.map(0, 0, num_pages, flags);
+    table.check();
+    let sizes = table.page_sizes(0, num_pages);
+    println!("page sizes: {:?}", sizes);
+    assert_eq!(sizes, vec![PageSize::Size2MiB, PageSize::Size2MiB, PageSize::Size4KiB, PageSize::Size4KiB, PageSize::Size4KiB]);
+
+    // Frames that aren't aligned like their pages can only be mapped by regular pages.
+    let misaligned = table.map(4 * HUGE_2MIB_PAGES, 4 * HUGE_2MIB_PAGES + 1, HUGE_2MIB_PAGES, flags);
+    table.check();
+    assert!(table.page_sizes(4 * HUGE_2MIB_PAGES, HUGE_2MIB_PAGES).iter().all(|&size| size == PageSize::Size4KiB));
+
+    // A whole aligned gigabyte is mapped by a single entry, if the CPU supports 1GiB pages.
+    let gigabyte = table.map(HUGE_1GIB_PAGES, HUGE_1GIB_PAGES, HUGE_1GIB_PAGES, flags);
+    table.check();
+    let num_leaves = table.leaves(HUGE_1GIB_PAGES, HUGE_1GIB_PAGES).len();
+    assert_eq!(num_leaves, if supports_1gib_pages() { 1 } else { ENTRIES_PER_PAGE_TABLE });
+
+    table.unmap(mapped);
+    table.unmap(misaligned);
+    table.unmap(gigabyte);
+    table.check();
+    assert!(table.expected.is_empty());
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_map_flag_propagation -- --nocapture`
+fn test_map_flag_propagation() {
+    let mut table = TestPageTable::new(1);
+    // Pages with different flags share every page table, so the flags of the first must not restrict the others.
+    let pages = [
+        EntryFlags::NO_EXECUTE,
+        EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+        EntryFlags::empty(),
+        EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE,
+    ];
+    let mut mappings = Vec::new();
+    for (i, &flags) in pages.iter().enumerate() {
+        mappings.push(table.map(i, i, 1, flags));
+    }
+    table.check();
+    let leaves = table.leaves(0, pages.len());
+    for leaf in leaves.iter() {
+        println!("{:#X} -> {:#X}: {:#X}", leaf.vaddr, leaf.paddr, leaf.flags);
+    }
+    assert_eq!(leaves[0].flags & page_table_model::WRITABLE, 0);
+    assert_ne!(leaves[1].flags & page_table_model::WRITABLE, 0);
+    assert_eq!(leaves[2].flags & page_table_model::NO_EXECUTE, 0);
+
+    let vaddr = VirtualAddress::new_canonical(table.vaddr(3) + 0x10);
+    assert_eq!(table.mapper.translate(vaddr), Some(PhysicalAddress::new_canonical(TEST_PADDR + 3 * PAGE_SIZE + 0x10)));
+    assert_eq!(table.mapper.translate(VirtualAddress::new_canonical(table.vaddr(4))), None);
+
+    for mapped in mappings {
+        table.unmap(mapped);
+    }
+    table.check();
+    assert!(table.expected.is_empty());
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_split_huge_page -- --nocapture`
+fn test_split_huge_page() {
+    let mut table = TestPageTable::new(2);
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    let mapped = table.map(0, 0, 2 * HUGE_2MIB_PAGES, flags);
+    table.check();
+
+    // Splitting a mapping within a huge page splits that huge page into regular pages.
+    let (first, rest) = mapped.split(table.page(100), &mut table.mapper, &mut table.allocator).map_err(|(e, _mp)| e).unwrap();
+    table.model.split_huge_page(table.vaddr(100) as u64, true).unwrap();
+    table.check();
+    assert_eq!(table.page_sizes(0, 2 * HUGE_2MIB_PAGES).len(), HUGE_2MIB_PAGES + 1);
+
+    // Splitting a mapping at the first page of a huge page leaves the huge page intact.
+    let (middle, last) = rest.split(table.page(HUGE_2MIB_PAGES), &mut table.mapper, &mut table.allocator).map_err(|(e, _mp)| e).unwrap();
+    table.check();
+    assert_eq!(table.page_sizes(HUGE_2MIB_PAGES, HUGE_2MIB_PAGES), vec![PageSize::Size2MiB]);
+
+    // Unmapping part of what used to be a huge page leaves the rest of it mapped.
+    table.unmap(first);
+    table.check();
+
+    // Unless only the boundary of a mapping matters, even a huge page's first page is split into regular pages.
+    table.mapper.split_huge_page(table.page(HUGE_2MIB_PAGES), false, &mut table.allocator).unwrap();
+    table.model.split_huge_page(table.vaddr(HUGE_2MIB_PAGES) as u64, false).unwrap();
+    table.check();
+    assert!(table.page_sizes(HUGE_2MIB_PAGES, HUGE_2MIB_PAGES).iter().all(|&size| size == PageSize::Size4KiB));
+
+    table.unmap(middle);
+    table.unmap(last);
+    table.check();
+    assert!(table.expected.is_empty());
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_split_1gib_page -- --nocapture`
+fn test_split_1gib_page() {
+    let mut table = TestPageTable::new(3);
+    let mapped = table.map(0, 0, HUGE_1GIB_PAGES, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+    table.check();
+
+    // A 1GiB page is split into 2MiB pages first, and then the one that contains the split page into regular pages.
+    // Without 1GiB pages, the gigabyte was mapped by 2MiB pages in the first place, which results in the same page sizes.
+    let at_page = HUGE_2MIB_PAGES + 5;
+    let (first, second) = mapped.split(table.page(at_page), &mut table.mapper, &mut table.allocator).map_err(|(e, _mp)| e).unwrap();
+    table.model.split_huge_page(table.vaddr(at_page) as u64, true).unwrap();
+    table.check();
+    let mut expected_sizes = vec![PageSize::Size2MiB];
+    expected_sizes.extend(vec![PageSize::Size4KiB; HUGE_2MIB_PAGES]);
+    expected_sizes.extend(vec![PageSize::Size2MiB; ENTRIES_PER_PAGE_TABLE - 2]);
+    assert_eq!(table.page_sizes(0, HUGE_1GIB_PAGES), expected_sizes);
+
+    table.unmap(first);
+    table.unmap(second);
+    table.check();
+    assert!(table.expected.is_empty());
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_remap_range -- --nocapture`
+fn test_remap_range() {
+    let mut table = TestPageTable::new(4);
+    let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+    let mapped = table.map(0, 0, 3 * HUGE_2MIB_PAGES, flags);
+
+    // Only the huge page that the range starts within is split, since the range ends at the end of a huge page.
+    let (start, num_pages) = (HUGE_2MIB_PAGES + 100, HUGE_2MIB_PAGES - 100);
+    let pages = PageRange::new(table.page(start), table.page(start + num_pages - 1));
+    let (before, middle, after) = mapped.remap_range(pages, EntryFlags::NO_EXECUTE, &mut table.mapper, &mut table.allocator)
+        .map_err(|(e, _mp)| e).unwrap();
+    let (vaddr, new_flags) = (table.vaddr(start) as u64, EntryFlags::NO_EXECUTE.bits());
+    table.model.remap(vaddr, num_pages as u64, new_flags).unwrap();
+    table.expected.remap(vaddr, num_pages as u64, new_flags);
+    table.check();
+    let mut expected_sizes = vec![PageSize::Size2MiB];
+    expected_sizes.extend(vec![PageSize::Size4KiB; HUGE_2MIB_PAGES]);
+    expected_sizes.push(PageSize::Size2MiB);
+    assert_eq!(table.page_sizes(0, 3 * HUGE_2MIB_PAGES), expected_sizes);
+
+    // The pages before and after the range keep their flags.
+    let (before, after) = (before.unwrap(), after.unwrap());
+    assert_eq!((before.flags(), middle.flags(), after.flags()), (flags, EntryFlags::NO_EXECUTE, flags));
+
+    // A range that extends beyond the mapping is rejected, and the mapping is returned unchanged.
+    let pages = PageRange::new(table.page(2 * HUGE_2MIB_PAGES), table.page(3 * HUGE_2MIB_PAGES));
+    let after = match after.remap_range(pages, EntryFlags::NO_EXECUTE, &mut table.mapper, &mut table.allocator) {
+        Ok(_) => panic!("remap_range() accepted a range beyond the end of the mapping"),
+        Err((_e, after)) => after,
+    };
+    table.check();
+
+    table.unmap(before);
+    table.unmap(middle);
+    table.unmap(after);
+    table.check();
+    assert!(table.expected.is_empty());
+}
@@ -0,0 +1,185 @@
+//! Introspection of the current page table, which lists all of its mappings.
+//!
+//! The listing is produced by walking the entire P4 hierarchy of the currently-active page table,
+//! and neighboring pages are merged into a single `MappingRegion` if they are contiguous
+//! in both virtual and physical memory, have the same page size, and have the same effective flags.
+//! This is mostly useful for debugging mapping bugs and for verifying that no region is both writable and executable.
+
+use alloc::vec::Vec;
+use {VirtualAddress, PhysicalAddress, HugeSize, EntryFlags};
+use paging::entry::Entry;
+use paging::mapper::Mapper;
+use kernel_config::memory::{ENTRIES_PER_PAGE_TABLE, PAGE_SIZE, PAGE_SHIFT, P2_INDEX_SHIFT, P3_INDEX_SHIFT, P4_INDEX_SHIFT, RECURSIVE_P4_INDEX};
+
+
+/// A region of virtual memory that is mapped to a contiguous region of physical memory with the same flags.
+#[derive(Clone, Debug)]
+pub struct MappingRegion {
+    /// The first virtual address of the region.
+    pub start_vaddr: VirtualAddress,
+    /// The first physical address that the region is mapped to.
+    pub start_paddr: PhysicalAddress,
+    /// The number of pages in the region, each of which has the size given by `huge`.
+    pub num_pages: usize,
+    /// The huge page size of the region, or `None` for regular pages.
+    pub huge: Option<HugeSize>,
+    /// The effective flags of the region's pages, which combine the flags of the entries at every level:
+    /// a page is only writable or user-accessible if it's so at every level,
+    /// and it isn't executable if it's marked as `NO_EXECUTE` at any level.
+    /// The `ACCESSED`, `DIRTY` and `HUGE_PAGE` bits are omitted.
+    pub flags: EntryFlags,
+}
+
+impl MappingRegion {
+    /// The size of each page in this region, in bytes.
+    pub fn page_size(&self) -> usize {
+        self.huge.map_or(PAGE_SIZE, |h| h.size_in_bytes())
+    }
+
+    /// The size of this region, in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        self.num_pages * self.page_size()
+    }
+
+    /// The virtual address right after the end of this region.
+    pub fn end_vaddr(&self) -> VirtualAddress {
+        VirtualAddress::new_canonical(self.start_vaddr.value().wrapping_add(self.size_in_bytes()))
+    }
+
+    /// Whether this region is both writable and executable, which violates W^X.
+    pub fn is_writable_and_executable(&self) -> bool {
+        self.flags.is_writable() && self.flags.is_executable()
+    }
+
+    /// Appends the given page to this region if it continues this region, returning whether it did.
+    fn try_extend(&mut self, vaddr: VirtualAddress, paddr: PhysicalAddress, huge: Option<HugeSize>, flags: EntryFlags) -> bool {
+        let contiguous = self.end_vaddr() == vaddr
+            && self.start_paddr.value() + self.size_in_bytes() == paddr.value();
+        if contiguous && self.huge == huge && self.flags == flags {
+            self.num_pages += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+
+/// The flags at the higher levels of the hierarchy that restrict the pages below them.
+#[derive(Clone, Copy)]
+struct Restrictions {
+    writable: bool,
+    user_accessible: bool,
+    no_execute: bool,
+}
+
+impl Restrictions {
+    fn none() -> Restrictions {
+        Restrictions { writable: true, user_accessible: true, no_execute: false }
+    }
+
+    fn apply(self, entry: &Entry) -> Restrictions {
+        let flags = entry.flags();
+        Restrictions {
+            writable: self.writable && flags.contains(EntryFlags::WRITABLE),
+            user_accessible: self.user_accessible && flags.contains(EntryFlags::USER_ACCESSIBLE),
+            no_execute: self.no_execute || flags.contains(EntryFlags::NO_EXECUTE),
+        }
+    }
+
+    /// Returns the effective flags of the given leaf entry, which must already be applied to these restrictions.
+    fn effective_flags(&self, leaf: &Entry) -> EntryFlags {
+        let mut flags = leaf.flags() - (EntryFlags::ACCESSED | EntryFlags::DIRTY | EntryFlags::HUGE_PAGE);
+        flags.set(EntryFlags::WRITABLE, self.writable);
+        flags.set(EntryFlags::USER_ACCESSIBLE, self.user_accessible);
+        flags.set(EntryFlags::NO_EXECUTE, self.no_execute);
+        flags
+    }
+}
+
+fn vaddr_of(p4_index: usize, p3_index: usize, p2_index: usize, p1_index: usize) -> VirtualAddress {
+    VirtualAddress::new_canonical(
+        (p4_index << (P4_INDEX_SHIFT + PAGE_SHIFT))
+        | (p3_index << (P3_INDEX_SHIFT + PAGE_SHIFT))
+        | (p2_index << (P2_INDEX_SHIFT + PAGE_SHIFT))
+        | (p1_index << PAGE_SHIFT)
+    )
+}
+
+fn push_page(regions: &mut Vec<MappingRegion>, vaddr: VirtualAddress, entry: &Entry, huge: Option<HugeSize>, restrictions: Restrictions) {
+    let paddr = match entry.pointed_frame() {
+        Some(frame) => frame.start_address(),
+        _ => return,
+    };
+    let flags = restrictions.effective_flags(entry);
+    if let Some(last) = regions.last_mut() {
+        if last.try_extend(vaddr, paddr, huge, flags) {
+            return;
+        }
+    }
+    regions.push(MappingRegion { start_vaddr: vaddr, start_paddr: paddr, num_pages: 1, huge, flags });
+}
+
+
+impl Mapper {
+    /// Walks this page table's entire P4 hierarchy and returns all of its present mappings,
+    /// sorted by virtual address, as a list of maximal `MappingRegion`s.
+    ///
+    /// The recursive P4 entry, through which the page tables themselves are accessed, is skipped.
+    /// Pages that aren't present, e.g., swapped-out or lazily-mapped pages, are not included.
+    ///
+    /// This must only be called on the currently-active page table, which should be locked (e.g., as part of the
+    /// kernel's `MemoryManagementInfo`) such that its mappings don't change during the walk.
+    pub fn mappings(&self) -> Vec<MappingRegion> {
+        let mut regions = Vec::new();
+        let p4 = self.p4();
+        for i4 in 0..ENTRIES_PER_PAGE_TABLE {
+            let e4 = &p4[i4];
+            if i4 == RECURSIVE_P4_INDEX || !e4.flags().contains(EntryFlags::PRESENT) {
+                continue;
+            }
+            let r4 = Restrictions::none().apply(e4);
+            let p3 = match p4.next_table(i4) {
+                Some(p3) => p3,
+                _ => continue,
+            };
+            for i3 in 0..ENTRIES_PER_PAGE_TABLE {
+                let e3 = &p3[i3];
+                if !e3.flags().contains(EntryFlags::PRESENT) {
+                    continue;
+                }
+                let r3 = r4.apply(e3);
+                if e3.flags().is_huge() {
+                    push_page(&mut regions, vaddr_of(i4, i3, 0, 0), e3, Some(HugeSize::Size1GiB), r3);
+                    continue;
+                }
+                let p2 = match p3.next_table(i3) {
+                    Some(p2) => p2,
+                    _ => continue,
+                };
+                for i2 in 0..ENTRIES_PER_PAGE_TABLE {
+                    let e2 = &p2[i2];
+                    if !e2.flags().contains(EntryFlags::PRESENT) {
+                        continue;
+                    }
+                    let r2 = r3.apply(e2);
+                    if e2.flags().is_huge() {
+                        push_page(&mut regions, vaddr_of(i4, i3, i2, 0), e2, Some(HugeSize::Size2MiB), r2);
+                        continue;
+                    }
+                    let p1 = match p2.next_table(i2) {
+                        Some(p1) => p1,
+                        _ => continue,
+                    };
+                    for i1 in 0..ENTRIES_PER_PAGE_TABLE {
+                        let e1 = &p1[i1];
+                        if e1.flags().contains(EntryFlags::PRESENT) {
+                            push_page(&mut regions, vaddr_of(i4, i3, i2, i1), e1, None, r2.apply(e1));
+                        }
+                    }
+                }
+            }
+        }
+        regions
+    }
+}
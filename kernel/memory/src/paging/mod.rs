@@ -10,6 +10,11 @@
 mod entry;
 mod temporary_page;
 mod mapper;
+mod mappings;
+mod pcid;
+mod wx;
+#[cfg(test)]
+mod simulated_memory;
 #[cfg(not(mapper_spillful))]
 mod table;
 #[cfg(mapper_spillful)]
@@ -19,6 +24,9 @@ pub mod table;
 pub use self::entry::*;
 pub use self::temporary_page::TemporaryPage;
 pub use self::mapper::*;
+pub use self::mappings::MappingRegion;
+pub use self::pcid::{init_pcids, pcids_enabled, tlb_flush_virt_addr, tlb_flush_all};
+pub use self::wx::{WxEnforcement, WxExemption, set_wx_enforcement, wx_enforcement, allow_wx, audit_wx};
 
 use core::{
     ops::{Deref, DerefMut},
@@ -36,6 +44,11 @@ use kernel_config::memory::{RECURSIVE_P4_INDEX};
 pub struct PageTable {
     mapper: Mapper,
     p4_table: Frame,
+    /// The PCID that tags this page table's TLB entries, or 0 if it shares PCID 0 with other page tables.
+    pcid: u16,
+    /// Whether this page table was assigned its PCID, which it then releases when it is dropped.
+    /// The page tables returned by `switch()` only refer to the page table that was switched to, so they don't.
+    owns_pcid: bool,
 }
 impl fmt::Debug for PageTable {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -57,6 +70,14 @@ impl DerefMut for PageTable {
     }
 }
 
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        if self.owns_pcid {
+            pcid::release_pcid(self.pcid);
+        }
+    }
+}
+
 impl PageTable {
     /// An internal function to create a new top-level PageTable 
     /// based on the currently-active page table register (e.g., CR3). 
@@ -64,6 +85,9 @@ impl PageTable {
         PageTable { 
             mapper: Mapper::from_current(),
             p4_table: get_current_p4(),
+            // CR3 is only ever written with a PCID by `switch()`, which doesn't use this.
+            pcid: 0,
+            owns_pcid: false,
         }
     }
 
@@ -97,7 +121,9 @@ impl PageTable {
 
         Ok( PageTable { 
             mapper: Mapper::with_p4_frame(new_p4_frame.clone()),
-            p4_table: new_p4_frame 
+            p4_table: new_p4_frame,
+            pcid: pcid::assign_pcid(),
+            owns_pcid: true,
         })
         // temporary_page is auto unmapped here 
     }
@@ -147,13 +173,20 @@ impl PageTable {
 
     /// Switches from the currently-active page table (this `PageTable`, i.e., `self`) to the given `new_table`.
     /// Returns the newly-switched-to PageTable.
+    ///
+    /// If the current core uses PCIDs, the TLB entries of the `new_table` that are still
+    /// cached from the last time it was active are kept, see `init_pcids()`.
     pub fn switch(&mut self, new_table: &PageTable) -> PageTable {
         // debug!("PageTable::switch() old table: {:?}, new table: {:?}", self, new_table);
 
         // perform the actual page table switch
-        unsafe { x86_64::registers::control_regs::cr3_write(x86_64::PhysicalAddress(new_table.p4_table.start_address().value() as u64)) };
-        let current_table_after_switch = PageTable::from_current();
-        current_table_after_switch
+        pcid::switch_to(new_table.p4_table.start_address(), self.pcid, new_table.pcid);
+        PageTable {
+            mapper: Mapper::from_current(),
+            p4_table: get_current_p4(),
+            pcid: new_table.pcid,
+            owns_pcid: false,
+        }
     }
 
 
@@ -165,10 +198,17 @@ impl PageTable {
 
 
 /// Returns the current top-level page table frame
+#[cfg(not(test))]
 pub fn get_current_p4() -> Frame {
     Frame::containing_address(get_p4())
 }
 
+/// Host tests have no CR3 register, so each test chooses its active P4 table, see the `simulated_memory` module.
+#[cfg(test)]
+pub fn get_current_p4() -> Frame {
+    simulated_memory::active_p4()
+}
+
 
 /// Initializes a new page table and sets up all necessary mappings for the kernel to continue running. 
 /// Returns the following tuple, if successful:
@@ -183,7 +223,7 @@ pub fn get_current_p4() -> Frame {
 ///
 /// Otherwise, it returns a str error message. 
 pub fn init(
-    allocator_mutex: &MutexIrqSafe<AreaFrameAllocator>,
+    allocator_mutex: &MutexIrqSafe<SystemFrameAllocator>,
     boot_info: &multiboot2::BootInformation
 ) -> Result<(
         PageTable,
@@ -207,7 +247,7 @@ pub fn init(
     let (new_frame, temp_frames1, temp_frames2) = {
         let mut allocator = allocator_mutex.lock();
         // a quick closure to allocate one frame
-        let mut alloc_frame = || allocator.allocate_frame().ok_or("couldn't allocate frame"); 
+        let mut alloc_frame = || allocator.allocate_frame().map_err(|_e| "couldn't allocate frame"); 
         (
             alloc_frame()?,
             (alloc_frame()?, alloc_frame()?, alloc_frame()?),
@@ -238,11 +278,12 @@ pub fn init(
             // We will unmap these lower-half identity mappings later, before we start running applications.
             let mut index = 0;
             for sec in sections_memory_bounds.iter().filter_map(|s| s.as_ref()).fuse() {
-                let (start_virt_addr, start_phys_addr) = sec.start;
+                let (_start_virt_addr, start_phys_addr) = sec.start;
                 let (_end_virt_addr, end_phys_addr) = sec.end;
                 let size = end_phys_addr.value() - start_phys_addr.value();
                 let frames = FrameRange::from_phys_addr(start_phys_addr, size);
-                let pages = page_allocator::allocate_pages_at(start_virt_addr - KERNEL_OFFSET, frames.size_in_frames())?;
+                let identity_virt_addr = VirtualAddress::new_canonical(start_phys_addr.value());
+                let pages = page_allocator::allocate_pages_at(identity_virt_addr, frames.size_in_frames())?;
                 identity_mapped_pages[index] = Some(
                     mapper.map_allocated_pages_to(
                         pages,
@@ -251,7 +292,7 @@ pub fn init(
                         allocator.deref_mut()
                     )?
                 );
-                debug!("           also mapped vaddr {:#X} to paddr {:#x} (size {:#X})", start_virt_addr - KERNEL_OFFSET, start_phys_addr, size);
+                debug!("           also mapped vaddr {:#X} to paddr {:#x} (size {:#X})", identity_virt_addr, start_phys_addr, size);
                 index += 1;
             }
 
@@ -351,13 +392,13 @@ pub fn init(
     let stack_pages         = stack_pages        .ok_or("Couldn't map .stack section")?;
 
     debug!("switching to new page table {:?}", new_table);
-    let new_page_table = page_table.switch(&new_table); 
-    // here, new_page_table and new_table should be identical
-    debug!("switched to new page table {:?}.", new_page_table); 
+    let _new_page_table = page_table.switch(&new_table); 
+    // here, _new_page_table and new_table should be identical
+    debug!("switched to new page table {:?}.", new_table); 
 
-    // Return the new_page_table because that's the one that should be used by the kernel in future mappings. 
+    // Return the new_table, which owns its PCID, because that's the one that should be used by the kernel in future mappings. 
     Ok((
-        new_page_table,
+        new_table,
         text_mapped_pages,
         rodata_mapped_pages,
         data_mapped_pages,
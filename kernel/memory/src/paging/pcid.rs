@@ -0,0 +1,199 @@
+//! Process-context identifiers (PCIDs), which tag each TLB entry with the page table that it came from,
+//! such that switching between page tables doesn't have to flush the whole TLB.
+//!
+//! Each `PageTable` is assigned its own PCID when it is created, for as long as there are free ones,
+//! and releases it when it is dropped; the bootstrap page table and any page tables beyond that share PCID 0, whose TLB entries are always
+//! flushed when switching to one of them. PCIDs are only used if the CPU supports both PCIDs and
+//! the `invpcid` instruction, and only once [`init_pcids()`] has been invoked.
+//! Each core then enables PCIDs lazily, the first time it switches page tables.
+//!
+//! # Invalidation
+//! Invalidating a single page (`invlpg`) only affects the TLB entries of the current PCID,
+//! so the entries that other PCIDs have cached for that page on this core may now be stale.
+//! Thus, every core counts how many such invalidations it has performed, whether on its own or
+//! when handling a TLB shootdown, and records that count for a PCID whenever it switches away from it.
+//! When switching back to that PCID, its TLB entries are kept only if the count hasn't changed in between.
+//! Flushing the whole TLB uses `invpcid` to flush the entries of every PCID at once.
+//!
+//! [`init_pcids()`]: fn.init_pcids.html
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use spin::Once;
+use raw_cpuid::CpuId;
+use kernel_config::memory::USE_PCID;
+use memory_x86_64::{self, InvpcidKind, enable_pcid, invpcid, set_p4_with_pcid};
+use {PhysicalAddress, VirtualAddress, current_apic_id};
+
+
+/// The number of PCIDs that can be assigned to page tables, including the shared PCID 0.
+/// The hardware supports up to 4096, but each core keeps track of every PCID, so we use fewer.
+const MAX_PCIDS: usize = 64;
+
+/// The set of PCIDs that have been assigned to page tables, one bit per PCID.
+/// PCID 0 is always assigned, since it's shared by all page tables that don't have their own.
+static ASSIGNED_PCIDS: AtomicU64 = AtomicU64::new(1);
+
+/// The state of PCIDs on each core, keyed by the core's APIC ID.
+/// This only exists if PCIDs are used.
+static CORE_STATES: Once<BTreeMap<u8, CoreState>> = Once::new();
+
+
+/// The state of PCIDs on a single core.
+struct CoreState {
+    /// Whether this core has enabled PCIDs yet.
+    enabled: AtomicBool,
+    /// The number of single-page invalidations that this core has performed,
+    /// each of which only applied to the PCID that was current at that time.
+    invalidations: AtomicU64,
+    /// For each PCID, the value of `invalidations` when this core last switched away from it.
+    /// The TLB entries of a PCID are only up to date if `invalidations` hasn't changed since then.
+    last_invalidations: Vec<AtomicU64>,
+}
+
+
+/// Returns whether the current CPU supports both PCIDs and the `invpcid` instruction.
+fn cpu_supports_pcid() -> bool {
+    let cpuid = CpuId::new();
+    cpuid.get_feature_info().map_or(false, |info| info.has_pcid())
+        && cpuid.get_extended_feature_info().map_or(false, |info| info.has_invpcid())
+}
+
+
+/// Starts using PCIDs on each of the given cores, if enabled by `USE_PCID` and supported by the CPU.
+///
+/// Until this is invoked, switching page tables always flushes the whole TLB.
+/// This should be called once all cores have been discovered and the heap has been initialized.
+///
+/// # Arguments
+/// * `apic_ids`: the APIC IDs of the cores that should use PCIDs.
+pub fn init_pcids<I: IntoIterator<Item = u8>>(apic_ids: I) -> Result<(), &'static str> {
+    if CORE_STATES.try().is_some() {
+        return Err("PCIDs were already initialized");
+    }
+    if !USE_PCID || !cpu_supports_pcid() {
+        info!("Not using PCIDs (USE_PCID: {}, supported: {})", USE_PCID, cpu_supports_pcid());
+        return Ok(());
+    }
+    let states = apic_ids.into_iter()
+        .map(|id| (id, CoreState {
+            enabled: AtomicBool::new(false),
+            invalidations: AtomicU64::new(0),
+            // No PCID's TLB entries are known to be up to date before the core first switches away from it.
+            last_invalidations: (0 .. MAX_PCIDS).map(|_| AtomicU64::new(u64::MAX)).collect(),
+        }))
+        .collect();
+    CORE_STATES.call_once(|| states);
+    Ok(())
+}
+
+
+/// Returns whether the current core uses PCIDs.
+pub fn pcids_enabled() -> bool {
+    my_core_state().map_or(false, |state| state.enabled.load(Ordering::Relaxed))
+}
+
+
+/// Returns the current core's PCID state, if it uses PCIDs.
+fn my_core_state() -> Option<&'static CoreState> {
+    CORE_STATES.try().and_then(|states| states.get(&current_apic_id()))
+}
+
+
+/// Assigns a PCID to a new page table, or returns PCID 0 if none are left.
+pub(super) fn assign_pcid() -> u16 {
+    if !USE_PCID {
+        return 0;
+    }
+    let mut assigned = ASSIGNED_PCIDS.load(Ordering::Relaxed);
+    loop {
+        let pcid = (!assigned).trailing_zeros() as usize;
+        if pcid >= MAX_PCIDS {
+            return 0;
+        }
+        match ASSIGNED_PCIDS.compare_exchange_weak(assigned, assigned | (1 << pcid), Ordering::AcqRel, Ordering::Relaxed) {
+            Ok(_) => return pcid as u16,
+            Err(current) => assigned = current,
+        }
+    }
+}
+
+
+/// Releases the given `pcid` of a page table that is being dropped, such that it can be assigned to a new page table.
+///
+/// Every core forgets that its TLB entries for that PCID are up to date,
+/// so they are flushed the first time that the core switches to the page table that is assigned it next.
+pub(super) fn release_pcid(pcid: u16) {
+    if pcid == 0 || pcid as usize >= MAX_PCIDS {
+        return;
+    }
+    if let Some(states) = CORE_STATES.try() {
+        for state in states.values() {
+            state.last_invalidations[pcid as usize].store(u64::MAX, Ordering::Relaxed);
+        }
+    }
+    ASSIGNED_PCIDS.fetch_and(!(1 << pcid), Ordering::AcqRel);
+}
+
+
+/// Switches from the page table tagged with `old_pcid` to the page table at `new_p4`, which is tagged with `new_pcid`.
+///
+/// The TLB entries of `new_pcid` are kept if they are still up to date, see the module-level docs.
+pub(super) fn switch_to(new_p4: PhysicalAddress, old_pcid: u16, new_pcid: u16) {
+    let state = match my_core_state() {
+        Some(state) => state,
+        None => {
+            unsafe { ::x86_64::registers::control_regs::cr3_write(::x86_64::PhysicalAddress(new_p4.value() as u64)) };
+            return;
+        }
+    };
+
+    if !state.enabled.load(Ordering::Relaxed) {
+        // The current PCID is still 0, since CR3 has never been written with a PCID on this core.
+        unsafe { enable_pcid() };
+        state.enabled.store(true, Ordering::Relaxed);
+    }
+
+    let invalidations = state.invalidations.load(Ordering::SeqCst);
+    if old_pcid != 0 {
+        state.last_invalidations[old_pcid as usize].store(invalidations, Ordering::Relaxed);
+    }
+    let keep_entries = new_pcid != 0
+        && state.last_invalidations[new_pcid as usize].load(Ordering::Relaxed) == invalidations;
+    unsafe { set_p4_with_pcid(new_p4, new_pcid, keep_entries) };
+
+    // A TLB shootdown that arrived before CR3 was written only applied to the old PCID.
+    if keep_entries && state.invalidations.load(Ordering::SeqCst) != invalidations {
+        unsafe { invpcid(InvpcidKind::SingleContext, new_pcid, VirtualAddress::zero()) };
+    }
+}
+
+
+/// Flushes the TLB entry for the given virtual address on the current core.
+///
+/// If the current core uses PCIDs, this only flushes the entry of the current PCID,
+/// so the entries of all other PCIDs are considered stale afterwards.
+pub fn tlb_flush_virt_addr(vaddr: VirtualAddress) {
+    // Host tests have no TLB, see the `simulated_memory` module.
+    if !cfg!(test) {
+        memory_x86_64::tlb_flush_virt_addr(vaddr);
+    }
+    if let Some(state) = my_core_state() {
+        state.invalidations.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Flushes the whole TLB on the current core, including the entries of every PCID.
+pub fn tlb_flush_all() {
+    if cfg!(test) {
+        return;
+    }
+    if pcids_enabled() {
+        unsafe { invpcid(InvpcidKind::AllContextsIncludingGlobal, 0, VirtualAddress::zero()) };
+    } else {
+        memory_x86_64::tlb_flush_all();
+    }
+}
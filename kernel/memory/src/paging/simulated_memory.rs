@@ -0,0 +1,98 @@
+//! Simulated physical memory for page tables, which allows host tests to run the real `Mapper`.
+//!
+//! When this crate is built for tests, the `Mapper` reaches each page table through the host address
+//! at which this module stores the contents of the table's frame, instead of through the recursive P4 entry,
+//! and the currently active P4 table is the one that the test thread chose via [`set_active_p4()`].
+//! TLB flushes do nothing, since there are no real translations to flush.
+//! The frames of mapped pages are never accessed, so only page table frames are simulated.
+//!
+//! [`SimulatedTables`] reads the simulated page tables for the `page_table_model` crate,
+//! which checks them against the expected mappings independently of the `Mapper`'s own page table walks.
+//!
+//! [`set_active_p4()`]: fn.set_active_p4.html
+//! [`SimulatedTables`]: struct.SimulatedTables.html
+
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel_config::memory::ENTRIES_PER_PAGE_TABLE;
+use page_table_model::{PageTableAccess, ADDRESS_MASK};
+use spin::{Mutex, Once};
+use {Frame, FrameRange, FrameAllocator, FrameAllocError, PhysicalAddress, VirtualAddress};
+
+
+/// The number of the first frame that `TableFrameAllocator` hands out,
+/// which is far above the frames that tests map, such that page tables are easy to tell apart.
+const TABLE_FRAMES_START: usize = 0x80_0000_0000;
+
+/// The host address of the contents of every page table frame that has been accessed, keyed by frame number.
+static TABLE_FRAMES: Once<Mutex<BTreeMap<usize, usize>>> = Once::new();
+static NEXT_TABLE_FRAME: AtomicUsize = AtomicUsize::new(TABLE_FRAMES_START);
+
+thread_local! {
+    static ACTIVE_P4: Cell<Option<Frame>> = Cell::new(None);
+}
+
+
+/// Returns the host address at which the contents of the given page table `frame` are stored.
+/// A frame that hasn't been accessed before is filled with zeros.
+pub fn table_address(frame: Frame) -> VirtualAddress {
+    let mut tables = TABLE_FRAMES.call_once(|| Mutex::new(BTreeMap::new())).lock();
+    let address = *tables.entry(frame.number).or_insert_with(|| {
+        Box::into_raw(Box::new([0u64; ENTRIES_PER_PAGE_TABLE])) as usize
+    });
+    VirtualAddress::new_canonical(address)
+}
+
+/// Makes the P4 table in the given `frame` the active page table of the current thread.
+pub fn set_active_p4(frame: Frame) {
+    ACTIVE_P4.with(|p4| p4.set(Some(frame)));
+}
+
+/// Returns the frame of the current thread's active P4 table.
+pub fn active_p4() -> Frame {
+    ACTIVE_P4.with(|p4| p4.get()).expect("simulated_memory: the test didn't set an active P4 table")
+}
+
+
+/// Allocates frames for page tables, which are distinct from the frames allocated for any other test.
+pub struct TableFrameAllocator {
+    /// The number of frames allocated so far.
+    pub allocated: usize,
+}
+
+impl FrameAllocator for TableFrameAllocator {
+    fn allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        self.allocated += 1;
+        Ok(Frame { number: NEXT_TABLE_FRAME.fetch_add(1, Ordering::SeqCst) })
+    }
+
+    fn allocate_frames(&mut self, _num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+        Err(FrameAllocError::InvalidRequest)
+    }
+
+    fn deallocate_frame(&mut self, _frame: Frame) { }
+
+    fn alloc_ready(&mut self) { }
+}
+
+
+/// The simulated page table hierarchy below the P4 table in the given frame.
+pub struct SimulatedTables(pub Frame);
+
+impl PageTableAccess for SimulatedTables {
+    type Table = Frame;
+
+    fn root(&self) -> Frame {
+        self.0
+    }
+
+    fn entry(&self, table: Frame, index: usize) -> u64 {
+        // SAFE: every table address points to `ENTRIES_PER_PAGE_TABLE` entries, which are never deallocated.
+        unsafe { *(table_address(table).value() as *const u64).add(index) }
+    }
+
+    fn next_table(&self, table: Frame, index: usize) -> Frame {
+        Frame::containing_address(PhysicalAddress::new_canonical((self.entry(table, index) & ADDRESS_MASK) as usize))
+    }
+}
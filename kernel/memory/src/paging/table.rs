@@ -63,9 +63,15 @@ impl<L> Table<L>
     fn next_table_address(&self, index: usize) -> Option<VirtualAddress> {
         let entry_flags = self[index].flags();
         if entry_flags.contains(EntryFlags::PRESENT) && !entry_flags.is_huge() {
-            let table_address = self as *const _ as usize;
-            let next_table_vaddr: usize = (table_address << 9) | (index << PAGE_SHIFT);
-            Some(VirtualAddress::new_canonical(next_table_vaddr))
+            #[cfg(not(test))]
+            let next_table_vaddr = {
+                let table_address = self as *const _ as usize;
+                VirtualAddress::new_canonical((table_address << 9) | (index << PAGE_SHIFT))
+            };
+            // Host tests keep their page tables in simulated physical memory, which has no recursive mapping.
+            #[cfg(test)]
+            let next_table_vaddr = super::simulated_memory::table_address(self[index].pointed_frame()?);
+            Some(next_table_vaddr)
         } else {
             None
         }
@@ -10,7 +10,7 @@
 use {FrameRange};
 use paging::{PageTable, MappedPages};
 use super::table::{Table, Level1};
-use super::{Frame, FrameAllocator, VirtualAddress};
+use super::{Frame, FrameAllocator, FrameAllocError, VirtualAddress};
 use kernel_config::memory::TEMPORARY_PAGE_VIRT_ADDR;
 
 
@@ -73,17 +73,17 @@ impl TinyAllocator {
 }
 
 impl FrameAllocator for TinyAllocator {
-    fn allocate_frame(&mut self) -> Option<Frame> {
+    fn allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
         for frame_option in &mut self.0 {
-            if frame_option.is_some() {
-                return frame_option.take();
+            if let Some(frame) = frame_option.take() {
+                return Ok(frame);
             }
         }
-        None
+        Err(FrameAllocError::OutOfMemory)
     }
 
     
-    fn allocate_frames(&mut self, _num_frames: usize) -> Option<FrameRange> {
+    fn allocate_frames(&mut self, _num_frames: usize) -> Result<FrameRange, FrameAllocError> {
         unimplemented!();
     }
 
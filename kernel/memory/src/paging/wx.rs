@@ -0,0 +1,115 @@
+//! Enforcement and auditing of W^X ("write xor execute"): no page should be both writable and executable,
+//! since such a page allows an attacker who can write to memory to inject code.
+//!
+//! [`audit_wx()`] walks all mappings of a page table and reports every violation.
+//! In addition, every new mapping and every remapping is checked according to the current [`WxEnforcement`] mode,
+//! which is `Off` by default and can be changed at any time via [`set_wx_enforcement()`].
+//! Code that legitimately needs a mapping to be writable and executable for a short time,
+//! e.g., to rewrite relocations in a `.text` section that may be executing on other cores,
+//! can temporarily exempt its mappings from enforcement via [`allow_wx()`].
+//!
+//! [`audit_wx()`]: fn.audit_wx.html
+//! [`WxEnforcement`]: enum.WxEnforcement.html
+//! [`set_wx_enforcement()`]: fn.set_wx_enforcement.html
+//! [`allow_wx()`]: fn.allow_wx.html
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use {EntryFlags, MappingRegion, Page};
+use paging::mapper::Mapper;
+
+
+/// What to do when a mapping that is both writable and executable is created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum WxEnforcement {
+    /// Such mappings are created as requested.
+    Off = 0,
+    /// Such mappings are logged and created as non-executable instead.
+    Downgrade = 1,
+    /// Creating such a mapping causes a panic.
+    Panic = 2,
+}
+
+static ENFORCEMENT: AtomicU8 = AtomicU8::new(WxEnforcement::Off as u8);
+
+/// The number of `WxExemption`s that currently exist.
+static EXEMPTIONS: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Sets what to do when a mapping that is both writable and executable is created.
+pub fn set_wx_enforcement(mode: WxEnforcement) {
+    ENFORCEMENT.store(mode as u8, Ordering::SeqCst);
+}
+
+/// Returns what is currently done when a mapping that is both writable and executable is created.
+pub fn wx_enforcement() -> WxEnforcement {
+    match ENFORCEMENT.load(Ordering::SeqCst) {
+        1 => WxEnforcement::Downgrade,
+        2 => WxEnforcement::Panic,
+        _ => WxEnforcement::Off,
+    }
+}
+
+
+/// Exempts all mappings created or remapped while this exists from W^X enforcement, see [`allow_wx()`](fn.allow_wx.html).
+pub struct WxExemption {
+    _private: (),
+}
+
+impl Drop for WxExemption {
+    fn drop(&mut self) {
+        EXEMPTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Exempts all mappings from W^X enforcement until the returned `WxExemption` is dropped.
+///
+/// This applies to mappings created by any task, so it should only be held for as long as necessary,
+/// and every mapping that it covers should be made W^X-compliant again before it's dropped.
+/// Such mappings are still reported by `audit_wx()`.
+pub fn allow_wx() -> WxExemption {
+    EXEMPTIONS.fetch_add(1, Ordering::SeqCst);
+    WxExemption { _private: () }
+}
+
+
+/// Checks the flags of a new mapping or remapping that starts at the given `page`
+/// according to the current `WxEnforcement` mode, and returns the flags that it should be mapped with.
+///
+/// # Panics
+/// If the flags are both writable and executable and the mode is `WxEnforcement::Panic`.
+pub(crate) fn enforce(flags: EntryFlags, page: Page) -> EntryFlags {
+    if !(flags.is_writable() && flags.is_executable()) || EXEMPTIONS.load(Ordering::SeqCst) > 0 {
+        return flags;
+    }
+    match wx_enforcement() {
+        WxEnforcement::Off => flags,
+        WxEnforcement::Downgrade => {
+            warn!("W^X: mapping at {:#X} was requested as writable and executable ({:?}), mapping it as non-executable instead",
+                page.start_address(), flags
+            );
+            flags | EntryFlags::NO_EXECUTE
+        }
+        WxEnforcement::Panic => panic!("W^X: mapping at {:#X} was requested as writable and executable ({:?})",
+            page.start_address(), flags
+        ),
+    }
+}
+
+
+/// Walks all mappings of the given page table and returns those that are both writable and executable,
+/// logging each of them as an error.
+///
+/// The given page table must be the currently-active one, see `Mapper::mappings()`.
+pub fn audit_wx(mapper: &Mapper) -> Vec<MappingRegion> {
+    let violations: Vec<MappingRegion> = mapper.mappings().into_iter()
+        .filter(|region| region.is_writable_and_executable())
+        .collect();
+    for region in &violations {
+        error!("W^X audit: {:#X} - {:#X} ({} bytes) is both writable and executable, flags: {:?}",
+            region.start_vaddr, region.end_vaddr(), region.size_in_bytes(), region.flags
+        );
+    }
+    violations
+}
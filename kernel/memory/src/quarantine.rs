@@ -0,0 +1,129 @@
+//! A quarantine list of physical frames that must never be used again, e.g., because they reported memory errors.
+//!
+//! A frame is removed from circulation via [`quarantine_frame()`], which is intended to be invoked
+//! by whatever detects the memory error, e.g., a Machine Check Architecture (MCA) handler
+//! upon a corrected or uncorrected ECC error.
+//!
+//! # Persistence
+//! The quarantine list does not survive a reboot by itself, since there is no storage available
+//! when the frame allocator is initialized. Instead, known-bad frames can be given to the kernel
+//! on its boot command line as a comma-separated list of physical addresses, e.g.,
+//! `bad_frames=0x12345000,0x2000000`, which is what [`quarantine_boot_arg()`] returns.
+//! Those frames are marked as occupied before the frame allocator hands out any frames,
+//! so they are never used at all. When building an ISO image, the boot command line
+//! can be given via the `kernel_args` Makefile variable.
+//!
+//! [`quarantine_frame()`]: fn.quarantine_frame.html
+//! [`quarantine_boot_arg()`]: fn.quarantine_boot_arg.html
+
+use super::{Frame, PhysicalAddress, FRAME_ALLOCATOR, frame_cache, numa, zeroed_frames};
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use spin::Once;
+
+
+/// The name of the boot command-line argument that lists known-bad frames.
+pub const BAD_FRAMES_BOOT_ARG: &'static str = "bad_frames";
+
+/// The maximum number of bad frames that can be given on the boot command line,
+/// since each one requires an occupied memory area before the heap is set up.
+pub(crate) const MAX_BOOT_BAD_FRAMES: usize = 16;
+
+/// The bad frames that were given on the boot command line.
+static BOOT_BAD_FRAMES: Once<[Option<Frame>; MAX_BOOT_BAD_FRAMES]> = Once::new();
+
+
+/// Parses the bad frames given by the `bad_frames=` argument in the given boot `command_line`.
+///
+/// Addresses that cannot be parsed are skipped, as are any beyond the first `MAX_BOOT_BAD_FRAMES`.
+/// This doesn't allocate, since it runs before the heap is set up.
+pub(crate) fn parse_boot_bad_frames(command_line: &str) -> [Option<Frame>; MAX_BOOT_BAD_FRAMES] {
+    let mut frames = [None; MAX_BOOT_BAD_FRAMES];
+    let mut count = 0;
+    let list = command_line.split_whitespace()
+        .filter_map(|arg| {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name == BAD_FRAMES_BOOT_ARG => Some(value),
+                _ => None,
+            }
+        });
+    for address in list.flat_map(|value| value.split(',')).filter(|a| !a.is_empty()) {
+        let parsed = if address.starts_with("0x") || address.starts_with("0X") {
+            usize::from_str_radix(&address[2..], 16)
+        } else {
+            address.parse::<usize>()
+        };
+        match parsed.ok().and_then(|a| PhysicalAddress::new(a).ok()) {
+            Some(paddr) if count < MAX_BOOT_BAD_FRAMES => {
+                frames[count] = Some(Frame::containing_address(paddr));
+                count += 1;
+            }
+            Some(_) => {
+                warn!("Ignoring bad frame {:?} on the boot command line, at most {} are supported", address, MAX_BOOT_BAD_FRAMES);
+            }
+            None => {
+                warn!("Ignoring invalid bad frame address {:?} on the boot command line", address);
+            }
+        }
+    }
+    frames
+}
+
+/// Records the bad frames that were given on the boot command line and have been marked as occupied.
+pub(crate) fn set_boot_bad_frames(frames: [Option<Frame>; MAX_BOOT_BAD_FRAMES]) {
+    BOOT_BAD_FRAMES.call_once(|| frames);
+}
+
+
+/// Permanently removes the given `frame` from circulation, such that it will never be allocated again.
+///
+/// If the frame is currently in use, it will be discarded once it is deallocated
+/// rather than being reused; the caller is responsible for migrating its contents, if needed.
+/// Frames within the contiguous memory area or the huge frame pools are not supported.
+///
+/// Returns `true` if the frame is currently in use.
+pub fn quarantine_frame(frame: Frame) -> Result<bool, &'static str> {
+    if BOOT_BAD_FRAMES.try().map_or(false, |frames| frames.contains(&Some(frame))) {
+        return Err("frame was already quarantined at boot");
+    }
+    // Frames held in these pools aren't in use, but the system-wide frame allocator considers them allocated.
+    let was_pooled = frame_cache::remove_cached_frame(frame)
+        || zeroed_frames::remove_zeroed_frame(frame)
+        || numa::remove_free_frame(frame);
+    let in_use = FRAME_ALLOCATOR.try()
+        .ok_or("quarantine_frame(): FRAME_ALLOCATOR not initialized")?
+        .lock()
+        .quarantine_frame(frame)?
+        && !was_pooled;
+    warn!("Quarantined frame {:?}{}", frame, if in_use { ", which is currently in use" } else { "" });
+    Ok(in_use)
+}
+
+/// Returns all quarantined frames, including those given on the boot command line, in ascending order.
+pub fn quarantined_frames() -> Vec<Frame> {
+    let mut frames: Vec<Frame> = FRAME_ALLOCATOR.try()
+        .map(|fa| fa.lock().quarantined_frames().to_vec())
+        .unwrap_or_else(Vec::new);
+    if let Some(boot_frames) = BOOT_BAD_FRAMES.try() {
+        frames.extend(boot_frames.iter().filter_map(|f| *f));
+    }
+    frames.sort_unstable();
+    frames.dedup();
+    frames
+}
+
+/// Returns the boot command-line argument that lists all quarantined frames,
+/// which can be given to the kernel such that it avoids those frames upon the next boot.
+///
+/// Returns `None` if there are no quarantined frames.
+pub fn quarantine_boot_arg() -> Option<String> {
+    let frames = quarantined_frames();
+    if frames.is_empty() {
+        return None;
+    }
+    let addresses: Vec<String> = frames.iter().map(|f| format!("{:#X}", f.start_address().value())).collect();
+    Some(format!("{}={}", BAD_FRAMES_BOOT_ARG, addresses.join(",")))
+}
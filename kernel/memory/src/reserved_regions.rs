@@ -0,0 +1,122 @@
+//! Labeled reservations of physical memory regions that the frame allocator must never hand out,
+//! e.g., ACPI tables, the multiboot information structure, and the AP startup trampoline code.
+//!
+//! A region can be reserved via [`reserve_physical_region()`] at any point during boot.
+//! Regions reserved before the frame allocator is initialized are marked as occupied when it is created;
+//! afterwards, they are removed from the frame allocator immediately.
+//! The reservations are kept in a fixed-capacity list, since some of them are made before the heap is set up,
+//! and are listed in the `Debug` output of the [`SystemFrameAllocator`].
+//!
+//! [`reserve_physical_region()`]: fn.reserve_physical_region.html
+//! [`SystemFrameAllocator`]: enum.SystemFrameAllocator.html
+
+use super::{PhysicalAddress, PhysicalMemoryArea, FRAME_ALLOCATOR};
+use alloc::vec::Vec;
+use core::fmt;
+use irq_safety::MutexIrqSafe;
+
+
+/// The maximum number of labeled reservations that can be listed.
+const MAX_RESERVED_REGIONS: usize = 64;
+
+/// All labeled reservations, in the order they were made.
+static RESERVED_REGIONS: MutexIrqSafe<[Option<ReservedRegion>; MAX_RESERVED_REGIONS]> = MutexIrqSafe::new([None; MAX_RESERVED_REGIONS]);
+
+
+/// A region of physical memory that has been reserved, along with a label that describes what it contains.
+#[derive(Clone, Copy)]
+pub struct ReservedRegion {
+    /// The area that was marked as occupied in the frame allocator.
+    pub area: PhysicalMemoryArea,
+    /// A description of the region's contents, e.g., `"ACPI table"`.
+    pub label: &'static str,
+}
+
+impl fmt::Debug for ReservedRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {:#X} - {:#X}", self.label, self.area.base_addr.value(), self.area.base_addr.value() + self.area.size_in_bytes)
+    }
+}
+
+
+/// Reserves the `size_in_bytes` bytes of physical memory starting at `paddr`,
+/// such that the frame allocator will never hand out any frames that contain them.
+/// The `label` describes the region's contents, e.g., `"ACPI table"`.
+///
+/// This can be used both before and after the frame allocator has been initialized.
+/// Reserving a region again, e.g., one that was already reserved by someone else, is harmless.
+/// Frames that were already allocated before the region was reserved are not affected.
+pub fn reserve_physical_region(paddr: PhysicalAddress, size_in_bytes: usize, label: &'static str) -> Result<(), &'static str> {
+    if size_in_bytes == 0 {
+        return Err("reserve_physical_region(): region must be nonzero in size");
+    }
+    // Use an end bound that is one byte short of the region, to avoid also occupying the frame after it.
+    reserve_area(PhysicalMemoryArea::new(paddr, size_in_bytes - 1, 1, 0), label)
+}
+
+/// Marks the given `area` as occupied, as in [`reserve_physical_region()`](fn.reserve_physical_region.html).
+pub(crate) fn reserve_area(area: PhysicalMemoryArea, label: &'static str) -> Result<(), &'static str> {
+    let frame_allocator_initialized = if let Some(fa) = FRAME_ALLOCATOR.try() {
+        fa.lock().add_area(area, false)?;
+        true
+    } else {
+        false
+    };
+
+    let mut regions = RESERVED_REGIONS.lock();
+    let already_reserved = regions.iter().flatten().any(|r|
+        r.area.base_addr == area.base_addr && r.area.size_in_bytes == area.size_in_bytes
+    );
+    if already_reserved {
+        return Ok(());
+    }
+    match regions.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(ReservedRegion { area, label }),
+        // Once the frame allocator is initialized, the region is reserved even if it can't be listed.
+        None if frame_allocator_initialized => warn!("reserve_physical_region(): too many reservations to list {:?} ({})", area, label),
+        None => {
+            error!("reserve_physical_region(): couldn't reserve {:?} ({}), too many reservations before the frame allocator was initialized", area, label);
+            return Err("reserve_physical_region(): too many reservations before the frame allocator was initialized");
+        }
+    }
+    trace!("Reserved physical memory region {:?} ({})", area, label);
+    Ok(())
+}
+
+/// Returns whether any region reserved so far overlaps the physical addresses from `start` up to (but excluding) `end`.
+pub(crate) fn overlaps_reserved_area(start: usize, end: usize) -> bool {
+    RESERVED_REGIONS.lock().iter().flatten().any(|r|
+        // A reserved area's end bound is inclusive, see `reserve_physical_region()`.
+        r.area.base_addr.value() < end && start <= r.area.base_addr.value() + r.area.size_in_bytes
+    )
+}
+
+/// Adds the areas of all regions reserved so far to the given `occupied` areas, starting at `*count`,
+/// such that a new frame allocator will never hand them out. `*count` is advanced past the added areas.
+///
+/// This doesn't allocate, since it runs before the heap is set up.
+pub(crate) fn add_reserved_areas(occupied: &mut [PhysicalMemoryArea], count: &mut usize) -> Result<(), &'static str> {
+    for region in RESERVED_REGIONS.lock().iter().flatten() {
+        if *count == occupied.len() {
+            error!("Couldn't avoid reserved region {:?} ({}), too many occupied areas", region.area, region.label);
+            return Err("too many reserved physical memory regions for the initial occupied areas");
+        }
+        occupied[*count] = region.area;
+        *count += 1;
+    }
+    Ok(())
+}
+
+/// Returns all labeled reservations, in the order they were made.
+pub fn reserved_regions() -> Vec<ReservedRegion> {
+    RESERVED_REGIONS.lock().iter().flatten().cloned().collect()
+}
+
+/// A helper for listing all labeled reservations in the `Debug` output of the frame allocator.
+pub(crate) struct ReservedRegionsDebug;
+
+impl fmt::Debug for ReservedRegionsDebug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(RESERVED_REGIONS.lock().iter().flatten()).finish()
+    }
+}
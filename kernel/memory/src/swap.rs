@@ -0,0 +1,393 @@
+//! Swapping, in which the contents of rarely-used pages are evicted to a swap device in order to free up their frames.
+//!
+//! Only mappings that were registered via [`register_swappable()`] are ever swapped out.
+//! Like a movable mapping (see [`register_movable()`]), a swappable mapping is a `MappedPages` wrapped in a `Mutex`,
+//! whose owner promises to only access its contents while holding that lock,
+//! such that no write can be lost while a page is being written to the swap device.
+//! The pager holds that lock while evicting the mapping's pages, and skips any mapping whose lock is currently held.
+//!
+//! Pages are evicted via the clock algorithm, which approximates evicting the least-recently-used pages:
+//! the pager sweeps over the pages of all swappable mappings, and evicts each page that hasn't been accessed
+//! since the previous sweep, whereas a page that was accessed has its accessed bit cleared and is skipped.
+//! An evicted page's contents are written to the swap backend, and its page table entry is replaced by a
+//! non-present swap entry that refers to the backend's slot holding them (see `Entry::swap_slot()`).
+//! The next access to that page causes a page fault, upon which [`handle_swap_page_fault()`]
+//! reads its contents back into a new frame and maps it again, after which the access is retried.
+//!
+//! The swap backend is provided by a higher-level crate via [`set_swap_backend()`], since this crate can't access storage devices.
+//! Once a backend is set, pages are evicted automatically whenever memory is under pressure,
+//! i.e., when free frames fall below the watermarks (see [`register_reclaimer()`]),
+//! and can also be evicted directly via [`swap_out()`].
+//!
+//! # Locking / Deadlock
+//! Eviction acquires the kernel's `MemoryManagementInfo` lock, then the locks of the swappable mappings,
+//! and then the swap backend lock, but never holds the frame allocator lock.
+//! Automatic eviction skips any of those locks that are already held, since the allocating task may be holding them.
+//! The page fault handler acquires the kernel's `MemoryManagementInfo` lock and then the swap backend lock,
+//! so a task must not access a swappable mapping while holding either of them.
+//! The swap backend lock disables interrupts, so a swap backend must be able to access its device without them.
+//!
+//! [`register_swappable()`]: fn.register_swappable.html
+//! [`register_movable()`]: fn.register_movable.html
+//! [`handle_swap_page_fault()`]: fn.handle_swap_page_fault.html
+//! [`set_swap_backend()`]: fn.set_swap_backend.html
+//! [`register_reclaimer()`]: fn.register_reclaimer.html
+//! [`swap_out()`]: fn.swap_out.html
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::{
+    allocate_frame, deallocate_frame, deallocate_frame_scrubbed, get_kernel_mmi_ref, map_frame_temporarily, memory_pressure, tlb_flush_virt_addr,
+    EntryFlags, Frame, MappedPages, Mapper, Page, PageRange, PressureLevel, TlbShootdownBatch, VirtualAddress,
+};
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::{PAGE_SIZE, SCRUB_FRAMES_ON_FREE};
+use spin::{Mutex, MutexGuard};
+
+
+/// The maximum number of slots that a swap backend can have, which is limited by the size of a swap entry.
+pub const MAX_SWAP_SLOTS: usize = 1 << 40;
+
+/// The name under which the pager's reclaim callback is registered.
+const RECLAIMER_NAME: &'static str = "swap";
+
+/// A place where the contents of evicted pages are stored, e.g., a swap partition on a storage device.
+///
+/// The backend chooses where to store each page, and identifies that place with a swap slot number,
+/// which must be less than [`MAX_SWAP_SLOTS`](constant.MAX_SWAP_SLOTS.html).
+pub trait SwapBackend: Send {
+    /// Stores the given page-sized `contents` in a free slot, and returns that slot.
+    fn store(&mut self, contents: &[u8]) -> Result<usize, &'static str>;
+
+    /// Reads the contents of the page stored in the given `slot` into the given page-sized buffer.
+    /// The `slot` remains in use until it's freed.
+    fn load(&mut self, slot: usize, contents: &mut [u8]) -> Result<(), &'static str>;
+
+    /// Frees the given `slot`, whose contents are no longer needed.
+    fn free(&mut self, slot: usize);
+}
+
+/// The outcome of trying to evict a single page, see `MappedPages::evict_page()`.
+pub(crate) enum Eviction {
+    /// The page was evicted, and the frame it was mapped to must be deallocated.
+    Evicted(Frame),
+    /// The page was accessed recently, so it was given another chance.
+    Referenced,
+    /// The page can't be evicted, e.g., because it's not present or its frame is shared.
+    Ineligible,
+}
+
+/// Statistics about swapping since boot.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SwapStats {
+    /// Whether a swap backend is currently set.
+    pub enabled: bool,
+    /// The number of registered swappable mappings.
+    pub swappable_mappings: usize,
+    /// The number of pages that are currently swapped out.
+    pub swapped_pages: usize,
+    /// The number of pages that have been evicted to swap.
+    pub pages_swapped_out: usize,
+    /// The number of pages that have been read back in from swap.
+    pub pages_swapped_in: usize,
+}
+
+
+/// The current swap backend, if any.
+static BACKEND: MutexIrqSafe<Option<Box<dyn SwapBackend>>> = MutexIrqSafe::new(None);
+
+/// All registered swappable mappings, some of which may have since been dropped.
+static SWAPPABLE_MAPPINGS: MutexIrqSafe<Vec<Weak<Mutex<MappedPages>>>> = MutexIrqSafe::new(Vec::new());
+
+/// The position of the clock hand: the index of a swappable mapping, and the index of a page within it.
+static CLOCK_MAPPING: AtomicUsize = AtomicUsize::new(0);
+static CLOCK_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+static SWAPPED_PAGES: AtomicUsize = AtomicUsize::new(0);
+static PAGES_SWAPPED_OUT: AtomicUsize = AtomicUsize::new(0);
+static PAGES_SWAPPED_IN: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Sets the swap backend that evicted pages are stored in, which enables swapping.
+///
+/// Returns an error if a swap backend was already set.
+pub fn set_swap_backend(backend: Box<dyn SwapBackend>) -> Result<(), &'static str> {
+    {
+        let mut current = BACKEND.lock();
+        if current.is_some() {
+            return Err("swap: a swap backend was already set");
+        }
+        *current = Some(backend);
+    }
+    memory_pressure::register_reclaimer(RECLAIMER_NAME, reclaim)
+}
+
+/// Removes the current swap backend, which disables swapping, and returns it.
+///
+/// Returns an error if any pages are still swapped out to it,
+/// in which case they must first be swapped back in, e.g., via [`unregister_swappable()`](fn.unregister_swappable.html).
+pub fn remove_swap_backend() -> Result<Box<dyn SwapBackend>, &'static str> {
+    let mut current = BACKEND.lock();
+    if SWAPPED_PAGES.load(Ordering::SeqCst) > 0 {
+        return Err("swap: pages are still swapped out to the swap backend");
+    }
+    let backend = current.take().ok_or("swap: no swap backend was set")?;
+    memory_pressure::unregister_reclaimer(RECLAIMER_NAME);
+    Ok(backend)
+}
+
+/// Registers the given `mapping` as swappable, such that its pages may be evicted to swap at any point,
+/// as described in the module-level docs.
+///
+/// The `mapping` must have been mapped to newly-allocated frames, e.g., via `Mapper::map_allocated_pages()`.
+/// It is unregistered automatically once it is dropped, which also frees the swap slots of its evicted pages.
+pub fn register_swappable(mapping: &Arc<Mutex<MappedPages>>) {
+    let mut mappings = SWAPPABLE_MAPPINGS.lock();
+    mappings.retain(|m| m.upgrade().is_some());
+    mappings.push(Arc::downgrade(mapping));
+}
+
+/// Unregisters the given `mapping`, such that its pages will no longer be evicted,
+/// and swaps all of its evicted pages back in.
+pub fn unregister_swappable(mapping: &Arc<Mutex<MappedPages>>) -> Result<(), &'static str> {
+    SWAPPABLE_MAPPINGS.lock().retain(|m| m.upgrade().map_or(false, |m| !Arc::ptr_eq(&m, mapping)));
+    let mapping = mapping.lock();
+    for page in PageRange::clone(&mapping) {
+        handle_swap_page_fault(page.start_address())?;
+    }
+    Ok(())
+}
+
+/// Returns statistics about swapping since boot.
+pub fn swap_stats() -> SwapStats {
+    SwapStats {
+        enabled: BACKEND.lock().is_some(),
+        swappable_mappings: SWAPPABLE_MAPPINGS.lock().iter().filter(|m| m.upgrade().is_some()).count(),
+        swapped_pages: SWAPPED_PAGES.load(Ordering::Relaxed),
+        pages_swapped_out: PAGES_SWAPPED_OUT.load(Ordering::Relaxed),
+        pages_swapped_in: PAGES_SWAPPED_IN.load(Ordering::Relaxed),
+    }
+}
+
+
+/// Evicts up to `target_frames` pages of swappable mappings to swap, and returns the number of frames that were freed.
+///
+/// Each swappable mapping's pages are checked at most twice, once to clear their accessed bits and once to evict them,
+/// so fewer frames may be freed if most pages are in active use.
+pub fn swap_out(target_frames: usize) -> Result<usize, &'static str> {
+    evict(target_frames, true)
+}
+
+/// The reclaim callback that evicts pages when memory is under pressure.
+fn reclaim(_level: PressureLevel, target_frames: usize) -> usize {
+    match evict(target_frames, false) {
+        Ok(freed) => freed,
+        Err(_e) => {
+            debug!("swap: couldn't evict pages to reclaim {} frames: {}", target_frames, _e);
+            0
+        }
+    }
+}
+
+fn evict(target_frames: usize, blocking: bool) -> Result<usize, &'static str> {
+    let mappings: Vec<Arc<Mutex<MappedPages>>> = {
+        let mut mappings = SWAPPABLE_MAPPINGS.lock();
+        mappings.retain(|m| m.upgrade().is_some());
+        mappings.iter().filter_map(Weak::upgrade).collect()
+    };
+    if mappings.is_empty() || target_frames == 0 {
+        return Ok(0);
+    }
+    // The mappings must be dropped after all locks have been released,
+    // since dropping the final reference to one frees its swap slots.
+    let result = evict_locked(&mappings, target_frames, blocking);
+    drop(mappings);
+
+    let frames = result?;
+    let freed = frames.len();
+    for frame in frames {
+        if SCRUB_FRAMES_ON_FREE {
+            if let Err(e) = deallocate_frame_scrubbed(frame) {
+                error!("swap: failed to scrub evicted frame {:?}, leaking it instead. Error: {}", frame, e);
+            }
+        } else {
+            deallocate_frame(frame);
+        }
+    }
+    Ok(freed)
+}
+
+/// Sweeps the clock hand over the given `mappings`, and returns the frames of the pages that were evicted,
+/// which no core's TLB refers to anymore.
+fn evict_locked(mappings: &[Arc<Mutex<MappedPages>>], target_frames: usize, blocking: bool) -> Result<Vec<Frame>, &'static str> {
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("swap: KERNEL_MMI was not yet initialized")?;
+    let mut kernel_mmi = if blocking {
+        kernel_mmi_ref.lock()
+    } else {
+        kernel_mmi_ref.try_lock().ok_or("swap: the kernel's MemoryManagementInfo was already locked")?
+    };
+    let mut locked: Vec<MutexGuard<MappedPages>> = mappings.iter().filter_map(|m| m.try_lock()).collect();
+    let mut backend_guard = if blocking {
+        BACKEND.lock()
+    } else {
+        BACKEND.try_lock().ok_or("swap: the swap backend was already locked")?
+    };
+    let backend = backend_guard.as_mut().ok_or("swap: no swap backend was set")?;
+    if locked.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mapper = &mut kernel_mmi.page_table;
+    let total_pages: usize = locked.iter().map(|m| m.size_in_pages()).sum();
+    let mut shootdowns = TlbShootdownBatch::new();
+    let mut evicted: Vec<Frame> = Vec::with_capacity(target_frames);
+    let mut index = CLOCK_MAPPING.load(Ordering::Relaxed) % locked.len();
+    let mut offset = CLOCK_PAGE.load(Ordering::Relaxed);
+    let mut scanned = 0;
+    let mut error = None;
+    while evicted.len() < target_frames && scanned < 2 * total_pages {
+        let mapping = &mut locked[index];
+        if offset >= mapping.size_in_pages() {
+            index = (index + 1) % locked.len();
+            offset = 0;
+            continue;
+        }
+        let page = *mapping.start() + offset;
+        offset += 1;
+        scanned += 1;
+        match mapping.evict_page(page, mapper, &mut **backend, &mut shootdowns) {
+            Ok(Eviction::Evicted(frame)) => evicted.push(frame),
+            Ok(Eviction::Referenced) | Ok(Eviction::Ineligible) => { }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        }
+    }
+    CLOCK_MAPPING.store(index, Ordering::Relaxed);
+    CLOCK_PAGE.store(offset, Ordering::Relaxed);
+
+    // Evicted frames can only be freed once no other core's TLB maps them anymore.
+    shootdowns.flush();
+    SWAPPED_PAGES.fetch_add(evicted.len(), Ordering::SeqCst);
+    PAGES_SWAPPED_OUT.fetch_add(evicted.len(), Ordering::Relaxed);
+    match error {
+        Some(e) if evicted.is_empty() => Err(e),
+        Some(_e) => {
+            warn!("swap: stopped evicting pages after {} evictions: {}", evicted.len(), _e);
+            Ok(evicted)
+        }
+        None => Ok(evicted),
+    }
+}
+
+
+/// Handles a page fault at the given `vaddr` that was caused by accessing a non-present page.
+///
+/// If that page was evicted to swap, its contents are read back into a newly-allocated frame
+/// that the page is then mapped to, and `Ok(true)` is returned, meaning that the faulting access can be retried.
+/// If the page was not evicted, `Ok(false)` is returned, meaning that the fault must be handled otherwise.
+/// If no frame could be allocated or the page couldn't be read from swap, an error is returned.
+pub fn handle_swap_page_fault(vaddr: VirtualAddress) -> Result<bool, &'static str> {
+    let page = Page::containing_address(vaddr);
+    if swap_slot_of(page).is_none() {
+        return Ok(false);
+    }
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("swap: KERNEL_MMI was not yet initialized")?;
+    // Frame allocation may evict other pages, so the swap backend lock can't be held across it.
+    let frame = allocate_frame().map_err(|e| {
+        error!("swap: couldn't swap in page {:#X}: {}", page.start_address(), e);
+        "swap: out of memory, couldn't allocate a frame for a swapped-out page"
+    })?;
+
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+    // The page's contents are read into the frame through a temporary mapping, and only then is the page mapped to it,
+    // such that no core can see the frame's previous contents, nor write to the page if it's read-only.
+    let mut temp_mapping = match map_frame_temporarily(&mut kernel_mmi.page_table, frame) {
+        Ok(mp) => mp,
+        Err(e) => {
+            drop(kernel_mmi);
+            deallocate_frame(frame);
+            return Err(e);
+        }
+    };
+    let mut backend_guard = BACKEND.lock();
+    let mut mapper = Mapper::from_current();
+    let entry = match mapper.p4_mut()
+        .next_table_mut(page.p4_index())
+        .and_then(|p3| p3.next_table_mut(page.p3_index()))
+        .and_then(|p2| p2.next_table_mut(page.p2_index()))
+    {
+        Some(p1) => &mut p1[page.p1_index()],
+        None => {
+            drop(backend_guard);
+            drop(temp_mapping);
+            drop(kernel_mmi);
+            deallocate_frame(frame);
+            return Ok(false);
+        }
+    };
+    // Another core may have swapped in the same page in the meantime, or its mapping may have been dropped.
+    let slot = match entry.swap_slot() {
+        Some(slot) => slot,
+        None => {
+            let present = entry.pointed_frame().is_some();
+            drop(backend_guard);
+            drop(temp_mapping);
+            drop(kernel_mmi);
+            deallocate_frame(frame);
+            return Ok(present);
+        }
+    };
+    let backend = match backend_guard.as_mut() {
+        Some(backend) => backend,
+        None => {
+            drop(backend_guard);
+            drop(temp_mapping);
+            drop(kernel_mmi);
+            deallocate_frame(frame);
+            return Err("BUG: swap: a page is swapped out but there is no swap backend");
+        }
+    };
+
+    let load_result = temp_mapping.as_slice_mut::<u8>(0, PAGE_SIZE).and_then(|contents| backend.load(slot, contents));
+    if let Err(e) = load_result {
+        drop(backend_guard);
+        drop(temp_mapping);
+        drop(kernel_mmi);
+        deallocate_frame(frame);
+        error!("swap: couldn't read page {:#X} from swap slot {}: {}", page.start_address(), slot, e);
+        return Err(e);
+    }
+    backend.free(slot);
+    // The entry was non-present, so no core can have a stale TLB entry for it.
+    let flags = entry.flags();
+    entry.set(frame, flags | EntryFlags::PRESENT);
+    tlb_flush_virt_addr(page.start_address());
+    SWAPPED_PAGES.fetch_sub(1, Ordering::SeqCst);
+    PAGES_SWAPPED_IN.fetch_add(1, Ordering::Relaxed);
+    Ok(true)
+}
+
+/// Returns the swap slot of the given `page` in the current page table, if it was evicted to swap.
+fn swap_slot_of(page: Page) -> Option<usize> {
+    let mut mapper = Mapper::from_current();
+    let p1 = mapper.p4_mut()
+        .next_table_mut(page.p4_index())
+        .and_then(|p3| p3.next_table_mut(page.p3_index()))
+        .and_then(|p2| p2.next_table_mut(page.p2_index()))?;
+    p1[page.p1_index()].swap_slot()
+}
+
+/// Frees the swap slot of a page that was unmapped while it was swapped out.
+pub(crate) fn free_slot(slot: usize) {
+    match BACKEND.lock().as_mut() {
+        Some(backend) => backend.free(slot),
+        None => error!("BUG: swap: a swapped-out page was unmapped but there is no swap backend"),
+    }
+    SWAPPED_PAGES.fetch_sub(1, Ordering::SeqCst);
+}
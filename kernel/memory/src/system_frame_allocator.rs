@@ -0,0 +1,748 @@
+//! The system-wide frame allocator, which can use one of several frame allocator backends.
+//!
+//! The `AreaFrameAllocator` is always used to boot, since it works before the heap has been set up.
+//! Once the heap has been set up, [`init_post_heap()`](../fn.init_post_heap.html) switches to the selected backend,
+//! handing over all of the free frames to it, see [`SystemFrameAllocator::switch_backend()`].
+//! The backend is selected by the `frame_allocator=` boot argument, e.g., `frame_allocator=buddy`,
+//! and otherwise by `kernel_config::memory::FRAME_ALLOCATOR_BACKEND`.
+//!
+//! Every backend implements the [`FrameAllocatorBackend`] trait, which allows the system-wide frame allocator
+//! to provide the same features (e.g., reserving frames within a certain region) on top of any of them.
+//! The few features that only the `AreaFrameAllocator` supports, e.g., handing out deallocated frames for zeroing,
+//! do nothing when another backend is in use.
+//! Because the other backends only track free frames, the memory areas taken offline from them
+//! and the frames quarantined from them are tracked alongside them, by a [`RetiredFrames`] instance.
+//!
+//! [`SystemFrameAllocator::switch_backend()`]: enum.SystemFrameAllocator.html#method.switch_backend
+//! [`FrameAllocatorBackend`]: trait.FrameAllocatorBackend.html
+//! [`RetiredFrames`]: struct.RetiredFrames.html
+
+use super::{
+    Frame, FrameAllocator, FrameAllocError, FrameRange, MemoryZone, PhysicalAddress, PhysicalMemoryArea,
+    AreaFrameAllocator, BitmapFrameAllocator, BuddyFrameAllocator, memory_pressure, frame_accounting, frame_pinning, telemetry,
+};
+use super::reserved_regions::ReservedRegionsDebug;
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use core::fmt;
+use kernel_config::memory::{FRAME_ALLOCATOR_BACKEND, MAX_PRE_HEAP_MEMORY_AREAS, PAGE_SIZE};
+use spin::Once;
+
+
+/// The name of the boot command-line argument that selects the frame allocator backend.
+pub const FRAME_ALLOCATOR_BOOT_ARG: &'static str = "frame_allocator";
+
+/// The backend that was selected on the boot command line, if any.
+static BOOT_BACKEND: Once<FrameAllocatorKind> = Once::new();
+
+
+/// The interface that every frame allocator backend must implement,
+/// in addition to the basic allocation functions of `FrameAllocator`.
+///
+/// A backend only tracks which frames are free; it never accesses the memory of any frame.
+pub trait FrameAllocatorBackend: FrameAllocator {
+    /// Returns the number of frames that are available for allocation.
+    fn free_frame_count(&self) -> usize;
+    /// Returns the highest frame that this allocator manages, whether or not it is free.
+    fn highest_frame(&self) -> Option<Frame>;
+    /// Adds the given `frames`, which must not be in use, to this allocator's free frames.
+    fn add_free_frames(&mut self, frames: &FrameRange) -> Result<(), &'static str>;
+    /// Removes any free frames within the given `frames` from this allocator, such that they will not be allocated.
+    /// The caller is responsible for those frames from then on.
+    ///
+    /// Returns the number of free frames that were removed.
+    fn remove_free_frames(&mut self, frames: &FrameRange) -> usize;
+    /// Returns all runs of contiguous free frames, sorted in ascending order.
+    fn free_runs(&self) -> Vec<FrameRange>;
+    /// Writes a human-readable description of this allocator's internal state to `out`,
+    /// given its current `free_runs`, for diagnosing frame leaks and fragmentation.
+    fn dump_state(&self, out: &mut dyn fmt::Write, free_runs: &[FrameRange]) -> fmt::Result;
+}
+
+
+/// The kinds of frame allocator backends that the system-wide frame allocator can use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameAllocatorKind {
+    /// The `AreaFrameAllocator`, which hands out frames from the available memory areas in ascending order.
+    Area,
+    /// The `BitmapFrameAllocator`, which tracks each frame with a single bit.
+    Bitmap,
+    /// The `BuddyFrameAllocator`, which manages free frames as power-of-two-sized blocks.
+    Buddy,
+}
+
+impl FrameAllocatorKind {
+    /// Returns the name of this kind of backend, as used by `kernel_config` and the boot command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FrameAllocatorKind::Area => "area",
+            FrameAllocatorKind::Bitmap => "bitmap",
+            FrameAllocatorKind::Buddy => "buddy",
+        }
+    }
+
+    /// Returns the kind of backend with the given `name`, if there is one.
+    pub fn from_name(name: &str) -> Option<FrameAllocatorKind> {
+        match name {
+            "area" => Some(FrameAllocatorKind::Area),
+            "bitmap" => Some(FrameAllocatorKind::Bitmap),
+            "buddy" => Some(FrameAllocatorKind::Buddy),
+            _ => None,
+        }
+    }
+}
+
+
+/// Records the backend selected by the `frame_allocator=` argument in the given boot `command_line`, if any.
+/// This doesn't allocate, since it runs before the heap is set up.
+pub(crate) fn parse_boot_backend(command_line: &str) {
+    let value = command_line.split_whitespace()
+        .filter_map(|arg| {
+            let mut parts = arg.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name == FRAME_ALLOCATOR_BOOT_ARG => Some(value),
+                _ => None,
+            }
+        })
+        .last();
+    if let Some(value) = value {
+        match FrameAllocatorKind::from_name(value) {
+            Some(kind) => { BOOT_BACKEND.call_once(|| kind); }
+            None => warn!("Ignoring unknown frame allocator backend {:?} on the boot command line", value),
+        }
+    }
+}
+
+/// Returns the frame allocator backend that was selected on the boot command line,
+/// or the one given by `kernel_config::memory::FRAME_ALLOCATOR_BACKEND` otherwise.
+pub fn selected_backend() -> FrameAllocatorKind {
+    if let Some(kind) = BOOT_BACKEND.try() {
+        return *kind;
+    }
+    FrameAllocatorKind::from_name(FRAME_ALLOCATOR_BACKEND).unwrap_or_else(|| {
+        warn!("Unknown frame allocator backend {:?} in kernel_config, using the area frame allocator", FRAME_ALLOCATOR_BACKEND);
+        FrameAllocatorKind::Area
+    })
+}
+
+
+/// The system-wide frame allocator, which dispatches to one of the frame allocator backends.
+///
+/// In addition to dispatching, this keeps the free frame count used for memory pressure
+/// and the per-owner frame accounting up to date, regardless of which backend is in use.
+pub enum SystemFrameAllocator {
+    Area(AreaFrameAllocator<MAX_PRE_HEAP_MEMORY_AREAS>),
+    Bitmap(BitmapFrameAllocator, RetiredFrames),
+    Buddy(BuddyFrameAllocator, RetiredFrames),
+}
+
+impl SystemFrameAllocator {
+    /// Returns the kind of backend that is currently in use.
+    pub fn kind(&self) -> FrameAllocatorKind {
+        match self {
+            SystemFrameAllocator::Area(_) => FrameAllocatorKind::Area,
+            SystemFrameAllocator::Bitmap(..) => FrameAllocatorKind::Bitmap,
+            SystemFrameAllocator::Buddy(..) => FrameAllocatorKind::Buddy,
+        }
+    }
+
+    fn backend(&self) -> &dyn FrameAllocatorBackend {
+        match self {
+            SystemFrameAllocator::Area(fa) => fa,
+            SystemFrameAllocator::Bitmap(fa, _) => fa,
+            SystemFrameAllocator::Buddy(fa, _) => fa,
+        }
+    }
+
+    fn backend_mut(&mut self) -> &mut dyn FrameAllocatorBackend {
+        match self {
+            SystemFrameAllocator::Area(fa) => fa,
+            SystemFrameAllocator::Bitmap(fa, _) => fa,
+            SystemFrameAllocator::Buddy(fa, _) => fa,
+        }
+    }
+
+    /// Updates the free frame count that is used to determine the memory pressure level.
+    /// This must be invoked whenever the number of free frames changes.
+    pub(crate) fn update_free_frame_count(&self) {
+        memory_pressure::set_free_frame_count(self.free_frame_count());
+    }
+
+    /// Switches from the `AreaFrameAllocator` to a new backend of the given `kind`,
+    /// handing over all of its free frames to the new backend.
+    ///
+    /// This must be invoked after the heap has been set up, but before any frames have been quarantined
+    /// or taken offline, since the area frame allocator's records of those are not handed over to the new backend.
+    /// Frames that were allocated before the switch can be deallocated to the new backend as usual.
+    pub fn switch_backend(&mut self, kind: FrameAllocatorKind) -> Result<(), &'static str> {
+        if self.kind() == kind {
+            return Ok(());
+        }
+        let area_fa = match self {
+            SystemFrameAllocator::Area(fa) => fa,
+            _ => return Err("switch_backend(): can only switch away from the area frame allocator"),
+        };
+        let highest = area_fa.highest_available_frame();
+        let free_runs = area_fa.take_free_frames()?;
+        let new_fa = match kind {
+            FrameAllocatorKind::Bitmap => {
+                // Cover every frame from zero, such that frames allocated before the switch can be deallocated.
+                let bounds = match highest {
+                    Some(highest) => FrameRange::new(Frame { number: 0 }, highest),
+                    None => FrameRange::empty(),
+                };
+                SystemFrameAllocator::Bitmap(BitmapFrameAllocator::new(bounds, &free_runs)?, RetiredFrames::default())
+            }
+            FrameAllocatorKind::Buddy => SystemFrameAllocator::Buddy(BuddyFrameAllocator::new(&free_runs), RetiredFrames::default()),
+            FrameAllocatorKind::Area => unreachable!("the area frame allocator is already in use"),
+        };
+        *self = new_fa;
+        self.update_free_frame_count();
+        info!("Switched to the {} frame allocator, {} free frames", kind.name(), self.free_frame_count());
+        Ok(())
+    }
+
+    /// Returns the approximate number of frames that are available for allocation.
+    ///
+    /// Frames held in the per-core frame caches or the pre-zeroed frame pool are not included.
+    pub fn free_frame_count(&self) -> usize {
+        self.backend().free_frame_count()
+    }
+
+    /// Returns the runs of contiguous free frames, sorted by frame.
+    pub(crate) fn free_runs(&self) -> Vec<FrameRange> {
+        self.backend().free_runs()
+    }
+
+    /// Renders the current state of this allocator into a human-readable string,
+    /// including the backend-specific state, e.g., the `AreaFrameAllocator`'s memory areas and freed frames,
+    /// which is useful for diagnosing frame leaks and fragmentation.
+    pub fn dump_state(&self) -> String {
+        let mut out = String::new();
+        // writing to a `String` cannot fail
+        let _ = self.write_state(&mut out);
+        out
+    }
+
+    fn write_state(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let free_runs = self.free_runs();
+        let largest_run = free_runs.iter().map(|run| run.size_in_frames()).max().unwrap_or(0);
+        writeln!(out, "Frame allocator backend: {}", self.kind().name())?;
+        writeln!(out, "Free frames: {} ({} KiB)", self.free_frame_count(), self.free_frame_count() * PAGE_SIZE / 1024)?;
+        writeln!(out, "Free runs: {}, largest run: {} frames", free_runs.len(), largest_run)?;
+        self.backend().dump_state(out, &free_runs)?;
+        writeln!(out, "Reserved regions: {:?}", ReservedRegionsDebug)
+    }
+
+    /// Returns the highest frame within any of the available memory areas.
+    pub(crate) fn highest_available_frame(&self) -> Option<Frame> {
+        self.backend().highest_frame()
+    }
+
+    /// Adds the given `area` to the list of available or occupied memory areas.
+    /// `available`: specifies whether the given `area` is an available or occupied memory area.
+    ///
+    /// See [`AreaFrameAllocator::add_area()`](struct.AreaFrameAllocator.html#method.add_area).
+    /// Other backends just add or remove the area's frames to or from their free frames.
+    pub fn add_area(&mut self, area: PhysicalMemoryArea, available: bool) -> Result<(), &'static str> {
+        let result = match self {
+            SystemFrameAllocator::Area(fa) => fa.add_area(area, available),
+            _ => {
+                if available {
+                    self.backend_mut().add_free_frames(&area_frames(&area))
+                } else {
+                    self.backend_mut().remove_free_frames(&occupied_area_frames(&area));
+                    Ok(())
+                }
+            }
+        };
+        self.update_free_frame_count();
+        result
+    }
+
+    /// Permanently removes the given `frame` from circulation,
+    /// see [`AreaFrameAllocator::quarantine_frame()`](struct.AreaFrameAllocator.html#method.quarantine_frame).
+    ///
+    /// For other backends, see [`RetiredFrames::quarantine_frame()`](struct.RetiredFrames.html#method.quarantine_frame).
+    pub fn quarantine_frame(&mut self, frame: Frame) -> Result<bool, &'static str> {
+        let result = match self {
+            SystemFrameAllocator::Area(fa) => fa.quarantine_frame(frame),
+            SystemFrameAllocator::Bitmap(fa, retired) => retired.quarantine_frame(fa, frame),
+            SystemFrameAllocator::Buddy(fa, retired) => retired.quarantine_frame(fa, frame),
+        };
+        self.update_free_frame_count();
+        result
+    }
+
+    /// Returns the frames that have been quarantined by `quarantine_frame()`, in ascending order.
+    pub fn quarantined_frames(&self) -> &[Frame] {
+        match self {
+            SystemFrameAllocator::Area(fa) => fa.quarantined_frames(),
+            SystemFrameAllocator::Bitmap(_, retired) | SystemFrameAllocator::Buddy(_, retired) => retired.quarantined_frames(),
+        }
+    }
+
+    /// Returns the number of frames that have been deallocated and are ready to be allocated again.
+    ///
+    /// Only the `AreaFrameAllocator` keeps deallocated frames separately, so this is `0` for other backends.
+    pub fn freed_frame_count(&self) -> usize {
+        match self {
+            SystemFrameAllocator::Area(fa) => fa.freed_frame_count(),
+            _ => 0,
+        }
+    }
+
+    /// Removes and returns up to `max` of the frames that have been deallocated,
+    /// e.g., such that they can be zeroed before they are allocated again.
+    ///
+    /// Only the `AreaFrameAllocator` keeps deallocated frames separately, so this is empty for other backends.
+    pub fn take_freed_frames(&mut self, max: usize) -> Vec<Frame> {
+        let frames = match self {
+            SystemFrameAllocator::Area(fa) => fa.take_freed_frames(max),
+            _ => Vec::new(),
+        };
+        for frame in frames.iter() {
+            frame_accounting::record_allocation(&FrameRange::new(*frame, *frame));
+        }
+        self.update_free_frame_count();
+        frames
+    }
+
+    /// Reserves between `min_frames` and `max_frames` contiguous frames (inclusive)
+    /// that lie entirely within the given `bounds`, taken from the highest free part of `bounds`.
+    /// The caller is responsible for managing the reserved frames from then on.
+    ///
+    /// See [`AreaFrameAllocator::reserve_frames_within()`](struct.AreaFrameAllocator.html#method.reserve_frames_within).
+    pub fn reserve_frames_within(&mut self, bounds: &PhysicalMemoryArea, min_frames: usize, max_frames: usize) -> Option<FrameRange> {
+        let frames = match self {
+            SystemFrameAllocator::Area(fa) => fa.reserve_frames_within(bounds, min_frames, max_frames),
+            _ => allocate_frames_within(self.backend_mut(), &area_frames(bounds), min_frames, max_frames, 1),
+        };
+        self.update_free_frame_count();
+        frames
+    }
+
+    /// Allocates `num_frames` contiguous frames that lie entirely within the given memory `zone`,
+    /// without falling back to any other zone.
+    pub fn allocate_frames_in_zone(&mut self, zone: MemoryZone, num_frames: usize) -> Option<FrameRange> {
+        let (zone_start, zone_end) = zone.bounds();
+        let zone_area = PhysicalMemoryArea::new(PhysicalAddress::new_canonical(zone_start), zone_end - zone_start, 1, 0);
+        self.reserve_frames_within(&zone_area, num_frames, num_frames)
+    }
+
+    /// Reserves `num_frames` contiguous frames, in which the first frame number is a multiple of `alignment_in_frames`,
+    /// taken from the highest free part of physical memory.
+    /// The caller is responsible for managing the reserved frames from then on.
+    ///
+    /// See [`AreaFrameAllocator::reserve_aligned_frames()`](struct.AreaFrameAllocator.html#method.reserve_aligned_frames).
+    pub fn reserve_aligned_frames(&mut self, num_frames: usize, alignment_in_frames: usize) -> Option<FrameRange> {
+        let frames = match self {
+            SystemFrameAllocator::Area(fa) => fa.reserve_aligned_frames(num_frames, alignment_in_frames),
+            _ => {
+                let highest = self.highest_available_frame()?;
+                let bounds = FrameRange::new(Frame { number: 0 }, highest);
+                allocate_frames_within(self.backend_mut(), &bounds, num_frames, num_frames, alignment_in_frames)
+            }
+        };
+        self.update_free_frame_count();
+        frames
+    }
+
+    /// Brings the given `area` of physical memory online, such that its frames can be allocated,
+    /// see [`AreaFrameAllocator::online_area()`](struct.AreaFrameAllocator.html#method.online_area).
+    ///
+    /// For other backends, see [`RetiredFrames::online_area()`](struct.RetiredFrames.html#method.online_area).
+    pub fn online_area(&mut self, area: PhysicalMemoryArea) -> Result<(), &'static str> {
+        let result = match self {
+            SystemFrameAllocator::Area(fa) => fa.online_area(area),
+            SystemFrameAllocator::Bitmap(fa, retired) => retired.online_area(fa, &area),
+            SystemFrameAllocator::Buddy(fa, retired) => retired.online_area(fa, &area),
+        };
+        self.update_free_frame_count();
+        result
+    }
+
+    /// Takes the given `area` of physical memory offline, such that none of its frames will be allocated again,
+    /// see [`AreaFrameAllocator::offline_area()`](struct.AreaFrameAllocator.html#method.offline_area).
+    ///
+    /// For other backends, see [`RetiredFrames::offline_area()`](struct.RetiredFrames.html#method.offline_area).
+    pub fn offline_area(&mut self, area: PhysicalMemoryArea) -> Result<Vec<FrameRange>, &'static str> {
+        let result = match self {
+            SystemFrameAllocator::Area(fa) => fa.offline_area(area),
+            SystemFrameAllocator::Bitmap(fa, retired) => retired.offline_area(fa, &area),
+            SystemFrameAllocator::Buddy(fa, retired) => retired.offline_area(fa, &area),
+        };
+        self.update_free_frame_count();
+        result
+    }
+
+    /// Returns the number of frames within the given offlined `area` that are still in use,
+    /// or `None` if the `area` was not taken offline via [`offline_area()`](#method.offline_area).
+    pub fn offlined_frames_in_use(&self, area: &PhysicalMemoryArea) -> Option<usize> {
+        match self {
+            SystemFrameAllocator::Area(fa) => fa.offlined_frames_in_use(area),
+            SystemFrameAllocator::Bitmap(_, retired) | SystemFrameAllocator::Buddy(_, retired) => retired.offlined_frames_in_use(area),
+        }
+    }
+
+    /// Allocates `num_frames` frames as at most `max_segments` ranges of contiguous frames,
+    /// which need not be contiguous with each other, e.g., for a device that supports scatter-gather DMA.
+    ///
+    /// See [`AreaFrameAllocator::allocate_frames_sg()`](struct.AreaFrameAllocator.html#method.allocate_frames_sg).
+    /// Other backends use the smallest free run that can satisfy the rest of the request by itself,
+    /// otherwise the largest free run, and so on until enough frames have been allocated.
+    pub fn allocate_frames_sg(&mut self, num_frames: usize, max_segments: usize) -> Result<Vec<FrameRange>, FrameAllocError> {
+        let segments = match self {
+            SystemFrameAllocator::Area(fa) => fa.allocate_frames_sg(num_frames, max_segments),
+            _ => allocate_frames_sg(self.backend_mut(), num_frames, max_segments),
+        };
+        for range in segments.iter().flatten() {
+            frame_accounting::record_allocation(range);
+        }
+        self.update_free_frame_count();
+        segments
+    }
+}
+
+impl fmt::Debug for SystemFrameAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SystemFrameAllocator")
+            .field("backend", &self.kind())
+            .field("free_frames", &self.free_frame_count())
+            .field("reserved_regions", &ReservedRegionsDebug)
+            .finish()
+    }
+}
+
+impl FrameAllocator for SystemFrameAllocator {
+    fn allocate_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        let start = telemetry::allocation_start();
+        let frame = self.backend_mut().allocate_frame();
+        telemetry::record_allocation(start, 1, frame.is_ok());
+        if let Ok(f) = frame {
+            frame_accounting::record_allocation(&FrameRange::new(f, f));
+        }
+        self.update_free_frame_count();
+        frame
+    }
+
+    fn allocate_frames(&mut self, num_frames: usize) -> Result<FrameRange, FrameAllocError> {
+        let start = telemetry::allocation_start();
+        let frames = self.backend_mut().allocate_frames(num_frames);
+        telemetry::record_allocation(start, num_frames, frames.is_ok());
+        if let Ok(ref f) = frames {
+            frame_accounting::record_allocation(f);
+        }
+        self.update_free_frame_count();
+        frames
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if frame_pinning::is_pinned_on_dealloc(frame) {
+            return;
+        }
+        frame_accounting::record_deallocation(frame);
+        telemetry::record_deallocation();
+        let discarded = match self {
+            // The area frame allocator keeps track of its offlined areas and quarantined frames itself.
+            SystemFrameAllocator::Area(_) => false,
+            SystemFrameAllocator::Bitmap(_, retired) | SystemFrameAllocator::Buddy(_, retired) => retired.discard_on_dealloc(frame),
+        };
+        if !discarded {
+            self.backend_mut().deallocate_frame(frame);
+        }
+        self.update_free_frame_count();
+    }
+
+    fn alloc_ready(&mut self) {
+        self.backend_mut().alloc_ready();
+    }
+}
+
+
+/// The frames that have been retired from a bitmap or buddy backend, which only tracks free frames
+/// and would otherwise hand them out again once they are deallocated:
+/// the memory areas that have been taken offline, and the frames that have been quarantined.
+///
+/// This behaves like the `AreaFrameAllocator`'s offlined areas and quarantined frames:
+/// their free frames are removed from the backend, and those that were in use
+/// are discarded rather than freed when they are deallocated.
+#[derive(Debug, Default)]
+pub struct RetiredFrames {
+    /// The frames of each offlined area, along with the frames within it that are still in use, sorted in ascending order.
+    /// The latter are removed once they are deallocated.
+    offlined: Vec<(FrameRange, Vec<Frame>)>,
+    /// The frames that must never be used again, sorted in ascending order.
+    quarantined: Vec<Frame>,
+}
+
+impl RetiredFrames {
+    /// Permanently removes the given `frame` of the given `backend` from circulation, e.g., because it reported memory errors.
+    ///
+    /// Returns `true` if the frame is currently in use, in which case it is discarded once it is deallocated,
+    /// or `false` if it was free and has been removed from the `backend`.
+    pub fn quarantine_frame(&mut self, backend: &mut dyn FrameAllocatorBackend, frame: Frame) -> Result<bool, &'static str> {
+        if backend.highest_frame().map_or(true, |highest| frame > highest) {
+            return Err("frame is not within the memory managed by the frame allocator");
+        }
+        let index = match self.quarantined.binary_search(&frame) {
+            Ok(_) => return Err("frame was already quarantined"),
+            Err(index) => index,
+        };
+        let in_use = backend.remove_free_frames(&FrameRange::new(frame, frame)) == 0;
+        self.quarantined.insert(index, frame);
+        Ok(in_use)
+    }
+
+    /// Returns the frames that have been quarantined by `quarantine_frame()`, in ascending order.
+    pub fn quarantined_frames(&self) -> &[Frame] {
+        &self.quarantined
+    }
+
+    /// Takes the given `area` offline from the given `backend`, such that none of its frames will be allocated again.
+    ///
+    /// The `area` must be page-aligned, must lie within the frames that the `backend` manages,
+    /// and must not overlap an already-offlined area.
+    /// Every frame within the area that isn't free is considered to be in use,
+    /// except for quarantined frames, which are never freed again anyway.
+    ///
+    /// Returns the runs of frames within the area that are currently in use.
+    pub fn offline_area(&mut self, backend: &mut dyn FrameAllocatorBackend, area: &PhysicalMemoryArea) -> Result<Vec<FrameRange>, &'static str> {
+        let frames = page_aligned_area_frames(area)?;
+        if backend.highest_frame().map_or(true, |highest| *frames.end() > highest) {
+            return Err("offline_area(): area extends beyond the memory managed by the frame allocator");
+        }
+        if self.offlined.iter().any(|(offlined, _)| ranges_overlap(offlined, &frames)) {
+            return Err("offline_area(): area overlaps an already-offlined memory area");
+        }
+
+        // The frames between the free runs within the area are in use.
+        let mut frames_in_use: Vec<Frame> = Vec::new();
+        let mut next = *frames.start();
+        let free_runs = backend.free_runs();
+        let ends = free_runs.iter()
+            .filter(|run| ranges_overlap(run, &frames))
+            .map(|run| (*run.start(), *run.end() + 1))
+            .chain(core::iter::once((*frames.end() + 1, *frames.end() + 1)));
+        for (run_start, run_end) in ends {
+            let mut frame = next;
+            while frame < run_start {
+                if self.quarantined.binary_search(&frame).is_err() {
+                    frames_in_use.push(frame);
+                }
+                frame = frame + 1;
+            }
+            next = run_end;
+        }
+        backend.remove_free_frames(&frames);
+
+        let in_use_ranges = contiguous_runs(&frames_in_use);
+        info!("Offlined memory area {:?}, {} frames still in use", area, frames_in_use.len());
+        self.offlined.push((frames, frames_in_use));
+        Ok(in_use_ranges)
+    }
+
+    /// Brings the given `area` online, adding its frames to the given `backend`, except for any quarantined frames.
+    ///
+    /// If the `area` was taken offline via [`offline_area()`](#method.offline_area), 
+    /// only its frames that are no longer in use are added.
+    /// Otherwise, it must be a new area that doesn't overlap any offlined area.
+    pub fn online_area(&mut self, backend: &mut dyn FrameAllocatorBackend, area: &PhysicalMemoryArea) -> Result<(), &'static str> {
+        let area_frames = area_frames(area);
+        let index = self.offlined.iter().position(|(offlined, _)| {
+            area_frames.start() == offlined.start() && area_frames.end() == offlined.end()
+        });
+        let frames_in_use = match index {
+            Some(index) => self.offlined.remove(index).1,
+            None if self.offlined.iter().any(|(offlined, _)| ranges_overlap(offlined, &area_frames)) => {
+                return Err("online_area(): area partially overlaps an offlined area");
+            }
+            None => Vec::new(),
+        };
+
+        // Add every frame within the area except for those that are still in use or quarantined.
+        let mut skipped: Vec<Frame> = frames_in_use.iter()
+            .chain(self.quarantined.iter().filter(|frame| area_frames.contains(frame)))
+            .cloned()
+            .collect();
+        skipped.sort_unstable();
+        skipped.dedup();
+        let mut next = *area_frames.start();
+        for &frame in skipped.iter() {
+            if frame > next {
+                backend.add_free_frames(&FrameRange::new(next, frame - 1))?;
+            }
+            next = frame + 1;
+        }
+        if next <= *area_frames.end() {
+            backend.add_free_frames(&FrameRange::new(next, *area_frames.end()))?;
+        }
+        if index.is_some() {
+            info!("Onlined memory area {:?}, {} frames still in use", area, frames_in_use.len());
+        }
+        Ok(())
+    }
+
+    /// Returns the number of frames within the given offlined `area` that are still in use,
+    /// or `None` if the `area` was not taken offline via [`offline_area()`](#method.offline_area).
+    pub fn offlined_frames_in_use(&self, area: &PhysicalMemoryArea) -> Option<usize> {
+        let frames = area_frames(area);
+        self.offlined.iter()
+            .find(|(offlined, _)| offlined.start() == frames.start() && offlined.end() == frames.end())
+            .map(|(_, frames_in_use)| frames_in_use.len())
+    }
+
+    /// Returns `true` if the given `frame`, which is being deallocated, is quarantined or lies within an offlined area,
+    /// in which case it must be discarded instead of being freed.
+    pub(crate) fn discard_on_dealloc(&mut self, frame: Frame) -> bool {
+        let mut discard = self.quarantined.binary_search(&frame).is_ok();
+        for (offlined, frames_in_use) in self.offlined.iter_mut() {
+            if offlined.contains(&frame) {
+                if let Ok(index) = frames_in_use.binary_search(&frame) {
+                    frames_in_use.remove(index);
+                }
+                discard = true;
+                break;
+            }
+        }
+        discard
+    }
+}
+
+/// Returns the runs of contiguous frames within the given sorted `frames`.
+fn contiguous_runs(frames: &[Frame]) -> Vec<FrameRange> {
+    let mut runs: Vec<FrameRange> = Vec::new();
+    for &frame in frames {
+        match runs.last_mut() {
+            Some(run) if *run.end() + 1 == frame => *run = FrameRange::new(*run.start(), frame),
+            _ => runs.push(FrameRange::new(frame, frame)),
+        }
+    }
+    runs
+}
+
+/// Returns the frames of the given `area`, which must be nonzero in size and page-aligned.
+fn page_aligned_area_frames(area: &PhysicalMemoryArea) -> Result<FrameRange, &'static str> {
+    if area.size_in_bytes == 0 || area.base_addr.value() % PAGE_SIZE != 0 || area.size_in_bytes % PAGE_SIZE != 0 {
+        return Err("memory area must be nonzero in size and page-aligned");
+    }
+    Ok(area_frames(area))
+}
+
+/// Returns whether the two given frame ranges have any frames in common.
+fn ranges_overlap(a: &FrameRange, b: &FrameRange) -> bool {
+    a.start() <= b.end() && b.start() <= a.end()
+}
+
+
+/// Returns the frames that lie entirely within the given `area`.
+fn area_frames(area: &PhysicalMemoryArea) -> FrameRange {
+    let start = (area.base_addr.value() + PAGE_SIZE - 1) / PAGE_SIZE;
+    let end = (area.base_addr.value() + area.size_in_bytes) / PAGE_SIZE;
+    if start < end {
+        FrameRange::new(Frame { number: start }, Frame { number: end - 1 })
+    } else {
+        FrameRange::empty()
+    }
+}
+
+/// Returns all frames that contain any part of the given occupied `area`,
+/// using the same inclusive end bound as the `AreaFrameAllocator` does for occupied areas.
+fn occupied_area_frames(area: &PhysicalMemoryArea) -> FrameRange {
+    FrameRange::new(Frame::containing_address(area.base_addr), Frame::containing_address(area.base_addr + area.size_in_bytes))
+}
+
+/// Returns the number of frames within the given `frames` that are within the given sorted `free_runs`.
+pub(crate) fn free_frames_within(frames: &FrameRange, free_runs: &[FrameRange]) -> usize {
+    free_runs.iter()
+        .filter(|run| run.end() >= frames.start() && run.start() <= frames.end())
+        .map(|run| {
+            let start = core::cmp::max(run.start().number, frames.start().number);
+            let end = core::cmp::min(run.end().number, frames.end().number);
+            end - start + 1
+        })
+        .sum()
+}
+
+/// Displays the fraction `used / total` as a percentage with one decimal place, without using floating point.
+pub(crate) struct Percent(pub usize, pub usize);
+
+impl fmt::Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tenths = if self.1 == 0 { 0 } else { self.0 * 1000 / self.1 };
+        write!(f, "{}.{}%", tenths / 10, tenths % 10)
+    }
+}
+
+/// Removes between `min_frames` and `max_frames` contiguous free frames (inclusive) from the given `backend`
+/// that lie entirely within the given `bounds`, in which the first frame number is a multiple of `alignment`.
+/// The frames are taken from the highest free part of `bounds`.
+pub(crate) fn allocate_frames_within(
+    backend: &mut dyn FrameAllocatorBackend,
+    bounds: &FrameRange,
+    min_frames: usize,
+    max_frames: usize,
+    alignment: usize,
+) -> Option<FrameRange> {
+    if min_frames == 0 || max_frames < min_frames || alignment == 0 || bounds.start() > bounds.end() {
+        return None;
+    }
+    let chosen = backend.free_runs().into_iter().rev().find_map(|run| {
+        let lo = core::cmp::max(*run.start(), *bounds.start()).number;
+        let hi = core::cmp::min(*run.end(), *bounds.end()).number;
+        let first = (lo + alignment - 1) / alignment * alignment;
+        if lo > hi || first > hi || hi - first + 1 < min_frames {
+            return None;
+        }
+        let num_frames = core::cmp::min(max_frames, hi - first + 1);
+        let start = (hi + 1 - num_frames) / alignment * alignment;
+        Some(FrameRange::new(Frame { number: start }, Frame { number: start + num_frames - 1 }))
+    })?;
+    if backend.remove_free_frames(&chosen) != chosen.size_in_frames() {
+        error!("BUG: allocate_frames_within(): frames {:?} were not all free", chosen);
+        return None;
+    }
+    Some(chosen)
+}
+
+/// Allocates `num_frames` frames from the given `backend` as at most `max_segments` ranges of contiguous frames,
+/// using the smallest free run that can satisfy the rest of the request by itself, otherwise the largest free run.
+///
+/// If the request cannot be satisfied, no frames are allocated.
+pub(crate) fn allocate_frames_sg(backend: &mut dyn FrameAllocatorBackend, num_frames: usize, max_segments: usize) -> Result<Vec<FrameRange>, FrameAllocError> {
+    if num_frames == 0 || max_segments == 0 {
+        return Err(FrameAllocError::InvalidRequest);
+    }
+    let mut runs = backend.free_runs();
+    runs.sort_by_key(|run| run.size_in_frames());
+
+    let mut segments: Vec<FrameRange> = Vec::new();
+    let mut remaining = num_frames;
+    while remaining > 0 && segments.len() < max_segments {
+        let run = match runs.iter().position(|run| run.size_in_frames() >= remaining) {
+            Some(index) => runs.remove(index),
+            None => match runs.pop() {
+                Some(run) => run,
+                None => break,
+            },
+        };
+        let len = core::cmp::min(run.size_in_frames(), remaining);
+        segments.push(FrameRange::new(*run.start(), *run.start() + (len - 1)));
+        remaining -= len;
+    }
+    if remaining > 0 {
+        trace!("allocate_frames_sg(): couldn't allocate {} frames in {} segments", num_frames, max_segments);
+        // Either every free run was used, or the largest ones didn't fit into `max_segments`.
+        return Err(if runs.is_empty() {
+            FrameAllocError::OutOfMemory
+        } else {
+            FrameAllocError::Fragmented { largest_run: segments.first().map(|s| s.size_in_frames()).unwrap_or(0) }
+        });
+    }
+    for segment in segments.iter() {
+        backend.remove_free_frames(segment);
+    }
+    Ok(segments)
+}
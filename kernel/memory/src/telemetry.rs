@@ -0,0 +1,179 @@
+//! Lightweight counters of the system-wide frame allocator's activity and latency,
+//! which quantify the effect of changes to the frame allocator over time.
+//!
+//! The counters are only kept when the `memory_telemetry` feature is enabled;
+//! otherwise, recording is a no-op and [`telemetry_snapshot()`] and [`reset_telemetry()`] don't exist.
+//! Only allocations and deallocations that reach the system-wide frame allocator are counted,
+//! not those that are satisfied by a per-core frame cache.
+//! Allocation latency is measured in TSC cycles, and only covers the time spent within the frame allocator backend,
+//! not the time spent waiting for the frame allocator's lock.
+//!
+//! [`telemetry_snapshot()`]: fn.telemetry_snapshot.html
+//! [`reset_telemetry()`]: fn.reset_telemetry.html
+
+#[cfg(feature = "memory_telemetry")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+
+#[cfg(feature = "memory_telemetry")]
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "memory_telemetry")]
+static CONTIGUOUS_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "memory_telemetry")]
+static FAILED_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "memory_telemetry")]
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "memory_telemetry")]
+static CONTIGUOUS_RETRIES: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "memory_telemetry")]
+static FREED_LIST_HITS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "memory_telemetry")]
+static FREED_LIST_MISSES: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "memory_telemetry")]
+static TOTAL_ALLOCATION_CYCLES: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "memory_telemetry")]
+static MAX_ALLOCATION_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+
+/// A snapshot of the frame allocator's telemetry counters, see [`telemetry_snapshot()`](fn.telemetry_snapshot.html).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TelemetrySnapshot {
+    /// The number of successful allocations, of either a single frame or contiguous frames.
+    pub allocations: u64,
+    /// The number of successful allocations of more than one contiguous frame, included in `allocations`.
+    pub contiguous_allocations: u64,
+    /// The number of allocations that failed.
+    pub failed_allocations: u64,
+    /// The number of frames that were deallocated.
+    pub deallocations: u64,
+    /// The number of times that a contiguous allocation had to start over,
+    /// because the frames it had allocated so far weren't contiguous.
+    pub contiguous_retries: u64,
+    /// The number of single-frame allocations that reused a previously-deallocated frame.
+    pub freed_list_hits: u64,
+    /// The number of single-frame allocations that had to take a never-before-allocated frame,
+    /// because there were no previously-deallocated frames.
+    pub freed_list_misses: u64,
+    /// The total number of TSC cycles spent in all allocations, both successful and failed.
+    pub total_allocation_cycles: u64,
+    /// The highest number of TSC cycles that any one allocation took.
+    pub max_allocation_cycles: u64,
+}
+
+impl TelemetrySnapshot {
+    /// Returns the average number of TSC cycles that an allocation took.
+    pub fn average_allocation_cycles(&self) -> u64 {
+        let count = self.allocations + self.failed_allocations;
+        if count == 0 { 0 } else { self.total_allocation_cycles / count }
+    }
+
+    /// Returns the percentage of single-frame allocations that reused a previously-deallocated frame,
+    /// or `None` if the freed list was never consulted, e.g., because the backend doesn't have one.
+    pub fn freed_list_hit_percent(&self) -> Option<u64> {
+        let lookups = self.freed_list_hits + self.freed_list_misses;
+        if lookups == 0 { None } else { Some(self.freed_list_hits * 100 / lookups) }
+    }
+}
+
+/// Returns the current values of the frame allocator's telemetry counters.
+///
+/// The counters are read individually, so a snapshot taken during concurrent allocations may be slightly inconsistent.
+#[cfg(feature = "memory_telemetry")]
+pub fn telemetry_snapshot() -> TelemetrySnapshot {
+    TelemetrySnapshot {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        contiguous_allocations: CONTIGUOUS_ALLOCATIONS.load(Ordering::Relaxed),
+        failed_allocations: FAILED_ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        contiguous_retries: CONTIGUOUS_RETRIES.load(Ordering::Relaxed),
+        freed_list_hits: FREED_LIST_HITS.load(Ordering::Relaxed),
+        freed_list_misses: FREED_LIST_MISSES.load(Ordering::Relaxed),
+        total_allocation_cycles: TOTAL_ALLOCATION_CYCLES.load(Ordering::Relaxed),
+        max_allocation_cycles: MAX_ALLOCATION_CYCLES.load(Ordering::Relaxed),
+    }
+}
+
+/// Resets all of the frame allocator's telemetry counters to zero,
+/// e.g., before running a benchmark, and returns their values from right before they were reset.
+#[cfg(feature = "memory_telemetry")]
+pub fn reset_telemetry() -> TelemetrySnapshot {
+    TelemetrySnapshot {
+        allocations: ALLOCATIONS.swap(0, Ordering::Relaxed),
+        contiguous_allocations: CONTIGUOUS_ALLOCATIONS.swap(0, Ordering::Relaxed),
+        failed_allocations: FAILED_ALLOCATIONS.swap(0, Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.swap(0, Ordering::Relaxed),
+        contiguous_retries: CONTIGUOUS_RETRIES.swap(0, Ordering::Relaxed),
+        freed_list_hits: FREED_LIST_HITS.swap(0, Ordering::Relaxed),
+        freed_list_misses: FREED_LIST_MISSES.swap(0, Ordering::Relaxed),
+        total_allocation_cycles: TOTAL_ALLOCATION_CYCLES.swap(0, Ordering::Relaxed),
+        max_allocation_cycles: MAX_ALLOCATION_CYCLES.swap(0, Ordering::Relaxed),
+    }
+}
+
+
+/// Returns the timestamp at which an allocation starts, to be passed into `record_allocation()`.
+#[cfg(feature = "memory_telemetry")]
+#[inline(always)]
+pub(crate) fn allocation_start() -> u64 {
+    // SAFE: just reading the TSC value
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(not(feature = "memory_telemetry"))]
+#[inline(always)]
+pub(crate) fn allocation_start() -> u64 {
+    0
+}
+
+/// Records an allocation of `num_frames` frames that began at the given `start` timestamp.
+#[inline(always)]
+pub(crate) fn record_allocation(_start: u64, _num_frames: usize, _succeeded: bool) {
+    #[cfg(feature = "memory_telemetry")] {
+        // SAFE: just reading the TSC value
+        let cycles = unsafe { core::arch::x86_64::_rdtsc() }.saturating_sub(_start);
+        TOTAL_ALLOCATION_CYCLES.fetch_add(cycles, Ordering::Relaxed);
+        let mut max = MAX_ALLOCATION_CYCLES.load(Ordering::Relaxed);
+        while cycles > max {
+            match MAX_ALLOCATION_CYCLES.compare_exchange_weak(max, cycles, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => max = current,
+            }
+        }
+        if !_succeeded {
+            FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        if _num_frames > 1 {
+            CONTIGUOUS_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Records the deallocation of one frame.
+#[inline(always)]
+pub(crate) fn record_deallocation() {
+    #[cfg(feature = "memory_telemetry")] {
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Records that a contiguous allocation had to start over.
+#[inline(always)]
+pub(crate) fn record_contiguous_retry() {
+    #[cfg(feature = "memory_telemetry")] {
+        CONTIGUOUS_RETRIES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Records whether a single-frame allocation reused a previously-deallocated frame.
+#[inline(always)]
+pub(crate) fn record_freed_list_lookup(_hit: bool) {
+    #[cfg(feature = "memory_telemetry")] {
+        if _hit {
+            FREED_LIST_HITS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            FREED_LIST_MISSES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Invalidating other cores' TLB entries after a mapping has changed, i.e., TLB shootdowns.
+//!
+//! Every function that changes or removes a mapping must first flush the current core's TLB entries for it,
+//! and then send a shootdown for the same pages to all other cores, which this module does
+//! via the callback set by [`set_broadcast_tlb_shootdown_cb()`](../fn.set_broadcast_tlb_shootdown_cb.html).
+//! Creating a new mapping doesn't require a shootdown, since no core can have cached a mapping that didn't exist.
+//!
+//! Each shootdown interrupts all other cores and waits for them, so functions that change many mappings
+//! one by one should collect the changed pages in a [`TlbShootdownBatch`](struct.TlbShootdownBatch.html)
+//! and send a single shootdown for all of them at the end.
+
+use core::cmp::{min, max};
+use {BROADCAST_TLB_SHOOTDOWN_FUNC, Page, PageRange};
+
+
+/// Makes all other cores invalidate their TLB entries for the given `pages`,
+/// and waits until they have done so.
+///
+/// The current core's TLB entries are not affected.
+pub fn broadcast_tlb_shootdown(pages: PageRange) {
+    if let Some(func) = BROADCAST_TLB_SHOOTDOWN_FUNC.try() {
+        func(pages);
+    }
+}
+
+
+/// Collects pages whose mappings have changed, such that other cores are sent a single TLB shootdown
+/// for all of them when the batch is flushed or dropped.
+///
+/// Until then, other cores may still access those pages through their old mappings,
+/// so the frames that were previously mapped to them must not be reused before the batch is flushed.
+///
+/// Pages that are added separately are covered by a single range from the lowest to the highest page,
+/// so a batch should only collect pages that are close together.
+pub struct TlbShootdownBatch {
+    /// The first and last page that must be invalidated, if any.
+    pages: Option<(Page, Page)>,
+}
+
+impl TlbShootdownBatch {
+    /// Creates a new, empty batch.
+    pub fn new() -> TlbShootdownBatch {
+        TlbShootdownBatch { pages: None }
+    }
+
+    /// Adds the given `pages`, whose TLB entries the current core must already have flushed.
+    pub fn add(&mut self, pages: PageRange) {
+        if pages.size_in_pages() == 0 {
+            return;
+        }
+        let (start, end) = (*pages.start(), *pages.end());
+        self.pages = Some(match self.pages {
+            Some((s, e)) => (min(s, start), max(e, end)),
+            None => (start, end),
+        });
+    }
+
+    /// Sends a single TLB shootdown for all pages added so far, and empties this batch.
+    pub fn flush(&mut self) {
+        if let Some((start, end)) = self.pages.take() {
+            broadcast_tlb_shootdown(PageRange::new(start, end));
+        }
+    }
+}
+
+impl Drop for TlbShootdownBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
@@ -0,0 +1,98 @@
+//! Supervisor-mode execution and access prevention (SMEP and SMAP),
+//! which forbid the kernel from executing or accessing pages that are user-accessible.
+//!
+//! Theseus runs everything in kernel mode, so no legitimate code path should execute a user-accessible page,
+//! and only a few should access one, e.g., when sharing a buffer with a user-mode task.
+//! With SMEP and SMAP enabled, a stray pointer into such a page causes a page fault rather than silently succeeding.
+//! Each core enables them for itself via [`enable_smep_smap()`] as it boots, if `ENABLE_SMEP_SMAP` is set
+//! and the CPU supports them.
+//!
+//! Code that must access user-accessible pages does so within [`with_user_access()`],
+//! or while holding a [`UserAccessGuard`], which temporarily lift SMAP on the current core.
+//!
+//! [`enable_smep_smap()`]: fn.enable_smep_smap.html
+//! [`with_user_access()`]: fn.with_user_access.html
+//! [`UserAccessGuard`]: struct.UserAccessGuard.html
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use raw_cpuid::CpuId;
+use kernel_config::memory::ENABLE_SMEP_SMAP;
+use memory_x86_64::{enable_smep, enable_smap, stac, clac, user_access_allowed};
+
+
+/// Whether SMAP has been enabled, in which case the `stac` and `clac` instructions are supported.
+/// All cores are assumed to support the same features.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+
+/// Enables SMEP and SMAP on the current core, if `ENABLE_SMEP_SMAP` is set and the CPU supports them.
+///
+/// This should be invoked once by each core as it boots.
+/// Returns whether SMEP and SMAP, respectively, are now enabled on the current core.
+pub fn enable_smep_smap() -> (bool, bool) {
+    if !ENABLE_SMEP_SMAP {
+        return (false, false);
+    }
+    let (has_smep, has_smap) = CpuId::new().get_extended_feature_info()
+        .map_or((false, false), |info| (info.has_smep(), info.has_smap()));
+    if has_smep {
+        unsafe { enable_smep() };
+    }
+    if has_smap {
+        // AC must be clear before SMAP is enabled, otherwise SMAP wouldn't take effect until it's next cleared.
+        unsafe {
+            clac();
+            enable_smap();
+        }
+        SMAP_ENABLED.store(true, Ordering::Release);
+    }
+    (has_smep, has_smap)
+}
+
+/// Returns whether SMAP is enabled, i.e., whether the kernel needs a [`UserAccessGuard`](struct.UserAccessGuard.html)
+/// to access user-accessible pages.
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Acquire)
+}
+
+
+/// Allows the kernel to access user-accessible pages on the current core for as long as this guard exists.
+///
+/// The AC flag in RFLAGS isn't saved across context switches, so the current task must not
+/// block or yield while holding this guard, otherwise the next task would run with SMAP lifted.
+/// Guards may be nested; SMAP is only restored once the outermost one is dropped.
+pub struct UserAccessGuard {
+    /// Whether AC was already set when this guard was created, in which case it's left set when this is dropped.
+    was_allowed: bool,
+}
+
+impl UserAccessGuard {
+    /// Lifts SMAP on the current core until the returned guard is dropped.
+    pub fn new() -> UserAccessGuard {
+        if !smap_enabled() {
+            return UserAccessGuard { was_allowed: true };
+        }
+        let was_allowed = user_access_allowed();
+        if !was_allowed {
+            unsafe { stac() };
+        }
+        UserAccessGuard { was_allowed }
+    }
+}
+
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        if !self.was_allowed {
+            unsafe { clac() };
+        }
+    }
+}
+
+
+/// Runs the given closure with SMAP lifted on the current core, such that it may access user-accessible pages.
+///
+/// The closure must not block or yield, see [`UserAccessGuard`](struct.UserAccessGuard.html).
+pub fn with_user_access<F: FnOnce() -> R, R>(f: F) -> R {
+    let _guard = UserAccessGuard::new();
+    f()
+}
@@ -0,0 +1,120 @@
+//! Kernel allocations that are contiguous in virtual memory but backed by physically scattered frames,
+//! similar to Linux's `vmalloc()`.
+//!
+//! Large kernel buffers, e.g., for drivers or the network stack, rarely need to be physically contiguous,
+//! and requiring them to be fails once physical memory becomes fragmented.
+//! [`vmalloc()`] instead allocates frames via [`allocate_frames_sg()`], which prefers a few large ranges of frames
+//! but falls back to many smaller ones, and maps them all into one virtually contiguous range of pages.
+//!
+//! The returned [`VmallocPages`] can be used just like a `MappedPages`, and its frames are freed when it's dropped.
+//! It also remembers the physical ranges that back it, such that a device that supports scatter-gather DMA
+//! can be given the buffer's [`segments()`] directly.
+//!
+//! [`vmalloc()`]: fn.vmalloc.html
+//! [`allocate_frames_sg()`]: fn.allocate_frames_sg.html
+//! [`VmallocPages`]: struct.VmallocPages.html
+//! [`segments()`]: struct.VmallocPages.html#method.segments
+
+use core::ops::{Deref, DerefMut};
+use alloc::vec::Vec;
+use kernel_config::memory::{PAGE_SIZE, SCRUB_FRAMES_ON_ALLOC};
+use {
+    allocate_frames_sg, allocate_pages_by_bytes, get_kernel_mmi_ref, CachedFrameAllocator,
+    EntryFlags, FrameRange, MappedPages, PhysicalAddress,
+};
+
+
+/// A mapping that is contiguous in virtual memory but whose frames may be scattered throughout physical memory.
+///
+/// This dereferences to the `MappedPages` that covers the whole mapping.
+#[derive(Debug)]
+pub struct VmallocPages {
+    mapped_pages: MappedPages,
+    /// The ranges of frames that back this mapping, in the order they are mapped.
+    segments: Vec<FrameRange>,
+}
+
+impl Deref for VmallocPages {
+    type Target = MappedPages;
+    fn deref(&self) -> &MappedPages {
+        &self.mapped_pages
+    }
+}
+
+impl DerefMut for VmallocPages {
+    fn deref_mut(&mut self) -> &mut MappedPages {
+        &mut self.mapped_pages
+    }
+}
+
+impl VmallocPages {
+    /// Returns the ranges of physically contiguous frames that back this mapping, in the order they are mapped,
+    /// i.e., the first range backs the first pages of this mapping.
+    pub fn segments(&self) -> &[FrameRange] {
+        &self.segments
+    }
+
+    /// Returns the physical address of the byte at the given `offset` into this mapping,
+    /// or `None` if the `offset` is beyond the end of this mapping.
+    pub fn translate(&self, offset: usize) -> Option<PhysicalAddress> {
+        let mut segment_offset = offset;
+        for frames in &self.segments {
+            let segment_size = frames.size_in_frames() * PAGE_SIZE;
+            if segment_offset < segment_size {
+                return Some(frames.start_address() + segment_offset);
+            }
+            segment_offset -= segment_size;
+        }
+        None
+    }
+
+    /// Returns the underlying `MappedPages`, which still frees this mapping's frames when it's dropped.
+    pub fn into_mapped_pages(self) -> MappedPages {
+        self.mapped_pages
+    }
+}
+
+
+/// Creates a new mapping of at least `size_in_bytes` bytes that is contiguous in virtual memory,
+/// but whose frames need not be contiguous in physical memory.
+///
+/// Any number of physical ranges may be used; see [`vmalloc_with_max_segments()`](fn.vmalloc_with_max_segments.html)
+/// for a device that can only handle a limited number of scatter-gather entries.
+///
+/// # Locking / Deadlock
+/// Same as [`create_mapping()`](fn.create_mapping.html).
+pub fn vmalloc(size_in_bytes: usize, flags: EntryFlags) -> Result<VmallocPages, &'static str> {
+    let num_pages = (size_in_bytes + PAGE_SIZE - 1) / PAGE_SIZE;
+    vmalloc_with_max_segments(size_in_bytes, flags, num_pages)
+}
+
+/// Like [`vmalloc()`](fn.vmalloc.html), but the new mapping is backed by at most `max_segments` ranges of physically contiguous frames.
+///
+/// # Locking / Deadlock
+/// Same as [`create_mapping()`](fn.create_mapping.html).
+pub fn vmalloc_with_max_segments(size_in_bytes: usize, flags: EntryFlags, max_segments: usize) -> Result<VmallocPages, &'static str> {
+    let allocated_pages = allocate_pages_by_bytes(size_in_bytes).ok_or("vmalloc(): couldn't allocate pages!")?;
+    let num_pages = allocated_pages.size_in_pages();
+    let segments = allocate_frames_sg(num_pages, max_segments).map_err(|e| {
+        error!("vmalloc(): couldn't allocate {} frames in at most {} segments: {}", num_pages, max_segments, e);
+        e.as_str()
+    })?;
+
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("vmalloc(): KERNEL_MMI was not yet initialized!")?;
+    let mut kernel_mmi = kernel_mmi_ref.lock();
+
+    // The frames come straight from the frame allocator, so they must be scrubbed through the new mapping.
+    let mapping_flags = if SCRUB_FRAMES_ON_ALLOC { flags | EntryFlags::WRITABLE } else { flags };
+    let mut mapped_pages = kernel_mmi.page_table.map_allocated_pages_to_segments(
+        allocated_pages, &segments, mapping_flags, &mut CachedFrameAllocator
+    )?;
+    if SCRUB_FRAMES_ON_ALLOC {
+        for byte in mapped_pages.as_slice_mut::<u8>(0, num_pages * PAGE_SIZE)? {
+            *byte = 0;
+        }
+        if mapping_flags != flags {
+            mapped_pages.remap(&mut kernel_mmi.page_table, flags)?;
+        }
+    }
+    Ok(VmallocPages { mapped_pages, segments })
+}
@@ -0,0 +1,145 @@
+//! A pool of frames that have already been filled with zeros,
+//! which are preferred for allocations that require zeroed memory.
+//!
+//! Zeroing frames synchronously when they're allocated adds latency to that allocation,
+//! e.g., when handling a page fault. Instead, freed frames can be zeroed in the background
+//! (see the `frame_zeroer` crate) and then added to this pool via [`add_zeroed_frames()`].
+//! The pool holds at most `ZEROED_FRAME_POOL_CAPACITY` frames.
+//!
+//! [`add_zeroed_frames()`]: fn.add_zeroed_frames.html
+
+use super::{Frame, FrameAllocError, FrameAllocator, FrameRange, FRAME_ALLOCATOR, allocate_frame, zero_frame};
+use super::frame_accounting::{self, ZEROED_FRAMES_OWNER};
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::ZEROED_FRAME_POOL_CAPACITY;
+use spin::Once;
+
+
+/// Frames that are known to contain only zeros.
+static ZEROED_FRAMES: MutexIrqSafe<Vec<Frame>> = MutexIrqSafe::new(Vec::new());
+
+/// The function that is invoked after frames have been freed, see `set_frames_freed_notifier()`.
+static FRAMES_FREED_NOTIFIER: Once<fn()> = Once::new();
+
+
+/// Sets the function that will be invoked after frames have been deallocated back to the frame allocator,
+/// which is used to wake up the task that zeroes freed frames in the background.
+///
+/// The notifier is never invoked while the frame allocator lock is held.
+pub fn set_frames_freed_notifier(func: fn()) {
+    FRAMES_FREED_NOTIFIER.call_once(|| func);
+}
+
+/// Invokes the frames freed notifier, if one has been set.
+pub(crate) fn notify_frames_freed() {
+    if let Some(func) = FRAMES_FREED_NOTIFIER.try() {
+        func();
+    }
+}
+
+
+/// Removes a frame from the pool of pre-zeroed frames, if any are available.
+pub(crate) fn take_zeroed_frame() -> Option<Frame> {
+    let frame = ZEROED_FRAMES.lock().pop();
+    if let Some(f) = frame {
+        frame_accounting::reassign(&FrameRange::new(f, f), None);
+    }
+    frame
+}
+
+/// Adds the given frames, which the caller guarantees have been filled with zeros, to the pool of pre-zeroed frames.
+///
+/// If the pool is already full, the excess frames are simply deallocated.
+pub fn add_zeroed_frames(mut frames: Vec<Frame>) {
+    {
+        let mut pool = ZEROED_FRAMES.lock();
+        let space = ZEROED_FRAME_POOL_CAPACITY.saturating_sub(pool.len());
+        let split_index = core::cmp::min(space, frames.len());
+        let excess = frames.split_off(split_index);
+        for frame in frames.iter() {
+            frame_accounting::reassign(&FrameRange::new(*frame, *frame), Some(ZEROED_FRAMES_OWNER));
+        }
+        pool.extend(frames);
+        frames = excess;
+    }
+    if !frames.is_empty() {
+        if let Some(fa) = FRAME_ALLOCATOR.try() {
+            let mut fa = fa.lock();
+            for frame in frames {
+                fa.deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+/// Deallocates all frames in the pool of pre-zeroed frames back to the system-wide frame allocator,
+/// which is used to reclaim memory when memory is under pressure.
+///
+/// Returns the number of frames that were released.
+pub(crate) fn release_zeroed_frames() -> usize {
+    let frames = core::mem::replace(&mut *ZEROED_FRAMES.lock(), Vec::new());
+    let count = frames.len();
+    if count > 0 {
+        if let Some(fa) = FRAME_ALLOCATOR.try() {
+            let mut fa = fa.lock();
+            for frame in frames {
+                fa.deallocate_frame(frame);
+            }
+        }
+    }
+    count
+}
+
+/// Removes the given `frame` from the pool of pre-zeroed frames, returning `true` if it was found.
+pub(crate) fn remove_zeroed_frame(frame: Frame) -> bool {
+    let mut pool = ZEROED_FRAMES.lock();
+    match pool.iter().position(|f| *f == frame) {
+        Some(index) => { pool.swap_remove(index); true }
+        None => false,
+    }
+}
+
+/// Returns the number of frames needed to fill the pool of pre-zeroed frames to its capacity.
+pub fn zeroed_frame_pool_deficit() -> usize {
+    ZEROED_FRAME_POOL_CAPACITY.saturating_sub(ZEROED_FRAMES.lock().len())
+}
+
+/// Removes and returns up to `max` frames from the list of frames that have been deallocated
+/// back to the system-wide frame allocator, such that they can be zeroed and added to the pool.
+pub fn take_freed_frames(max: usize) -> Vec<Frame> {
+    FRAME_ALLOCATOR.try()
+        .map(|fa| fa.lock().take_freed_frames(max))
+        .unwrap_or_else(Vec::new)
+}
+
+/// Returns the number of frames that have been deallocated back to the system-wide frame allocator
+/// and are ready to be allocated again.
+pub fn freed_frame_count() -> usize {
+    FRAME_ALLOCATOR.try()
+        .map(|fa| fa.lock().freed_frame_count())
+        .unwrap_or(0)
+}
+
+
+/// Allocates a frame that is filled with zeros.
+///
+/// A pre-zeroed frame is used if one is available, otherwise a new frame is allocated and zeroed synchronously.
+///
+/// # Locking / Deadlock
+/// If there are no pre-zeroed frames, this temporarily maps the new frame in order to zero it,
+/// see [`zero_frame()`](../fn.zero_frame.html).
+pub fn allocate_zeroed_frame() -> Result<Frame, FrameAllocError> {
+    if let Some(frame) = take_zeroed_frame() {
+        return Ok(frame);
+    }
+    let frame = allocate_frame()?;
+    match zero_frame(frame) {
+        Ok(()) => Ok(frame),
+        Err(e) => {
+            error!("allocate_zeroed_frame(): failed to zero frame {:?}: {}", frame, e);
+            super::deallocate_frame(frame);
+            Err(FrameAllocError::OutOfMemory)
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Snapshots of the system's memory usage, which are compared to find memory leaks.
+//!
+//! A [`MemorySnapshot`] records the number of free frames, the bytes and allocations in use on the heap,
+//! and every mapping of the kernel's page table. Comparing two snapshots with [`MemorySnapshot::diff()`]
+//! shows which of these resources were taken in between and not returned.
+//!
+//! [`check()`] runs a closure several times, e.g., one that loads and unloads a crate or binds and unbinds a driver,
+//! and compares the snapshots taken after each iteration. Because these counters are system-wide,
+//! the activity of other tasks also shows up in each difference, and the first run of an operation often
+//! fills caches or initializes statics that are never freed. Thus, `check()` runs warm-up iterations first,
+//! and only reports that the closure leaks a resource if every single iteration took more of it.
+//!
+//! [`MemorySnapshot`]: struct.MemorySnapshot.html
+//! [`MemorySnapshot::diff()`]: struct.MemorySnapshot.html#method.diff
+//! [`check()`]: fn.check.html
+
+#![no_std]
+
+extern crate alloc;
+extern crate memory;
+extern crate heap;
+extern crate kernel_config;
+
+use core::{fmt, mem, ops::Range};
+use alloc::vec::Vec;
+use memory::MappingRegion;
+use kernel_config::memory::ZEROED_FRAME_POOL_CAPACITY;
+
+
+/// The memory usage of the whole system at one point in time.
+#[derive(Debug)]
+pub struct MemorySnapshot {
+    /// The number of frames that can be allocated, including pre-zeroed frames.
+    pub free_frames: usize,
+    /// The number of bytes allocated from the heap, excluding this snapshot itself.
+    pub heap_bytes: usize,
+    /// The number of allocations from the heap, excluding this snapshot itself.
+    pub heap_allocations: usize,
+    /// The mappings of the kernel's page table, see `Mapper::mappings()`.
+    pub mappings: Vec<MappingRegion>,
+    /// The number of bytes that this snapshot allocated from the heap.
+    own_heap_bytes: usize,
+}
+
+/// Takes a snapshot of the system's current memory usage.
+///
+/// Frames that are cached on each core and the heap's empty slabs aren't in use,
+/// so they are first returned to the frame allocator.
+pub fn snapshot() -> Result<MemorySnapshot, &'static str> {
+    memory::flush_frame_caches();
+    heap::release_empty_slabs(0);
+
+    let kernel_mmi_ref = memory::get_kernel_mmi_ref().ok_or("memory_diff: couldn't get kernel_mmi_ref")?;
+    let mut mappings = kernel_mmi_ref.lock().page_table.mappings();
+    mappings.shrink_to_fit();
+    let own_heap_bytes = mappings.capacity() * mem::size_of::<MappingRegion>();
+    let own_heap_allocations = if own_heap_bytes > 0 { 1 } else { 0 };
+    Ok(MemorySnapshot {
+        free_frames: memory::free_frame_count() + (ZEROED_FRAME_POOL_CAPACITY - memory::zeroed_frame_pool_deficit()),
+        heap_bytes: heap::bytes_in_use().saturating_sub(own_heap_bytes),
+        heap_allocations: heap::allocations_in_use().saturating_sub(own_heap_allocations),
+        mappings,
+        own_heap_bytes,
+    })
+}
+
+impl MemorySnapshot {
+    /// Returns which resources were taken between this snapshot and the given `later` snapshot.
+    ///
+    /// Since this snapshot still exists when the `later` one is taken, its own heap usage is not counted.
+    pub fn diff(&self, later: &MemorySnapshot) -> MemoryDiff {
+        let own_heap_allocations = if self.own_heap_bytes > 0 { 1 } else { 0 };
+        MemoryDiff {
+            frames_not_freed: self.free_frames as isize - later.free_frames as isize,
+            heap_bytes_not_freed: later.heap_bytes as isize - self.heap_bytes as isize - self.own_heap_bytes as isize,
+            heap_allocations_not_freed: later.heap_allocations as isize - self.heap_allocations as isize - own_heap_allocations,
+            mapped: uncovered(&later.mappings, &self.mappings),
+            unmapped: uncovered(&self.mappings, &later.mappings),
+        }
+    }
+}
+
+
+/// The difference in memory usage between two snapshots.
+///
+/// Negative counts mean that more of a resource was freed than was taken.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryDiff {
+    /// The number of frames that were allocated and not freed.
+    pub frames_not_freed: isize,
+    /// The number of heap bytes that were allocated and not freed.
+    pub heap_bytes_not_freed: isize,
+    /// The number of heap allocations that were not freed.
+    pub heap_allocations_not_freed: isize,
+    /// The ranges of virtual addresses that were mapped and not unmapped.
+    pub mapped: Vec<Range<usize>>,
+    /// The ranges of virtual addresses that were unmapped.
+    pub unmapped: Vec<Range<usize>>,
+}
+
+impl MemoryDiff {
+    /// Returns whether every resource that was taken was also returned.
+    pub fn is_clean(&self) -> bool {
+        self.frames_not_freed <= 0 && self.heap_bytes_not_freed <= 0 && self.heap_allocations_not_freed <= 0 && self.mapped.is_empty()
+    }
+}
+
+impl fmt::Display for MemoryDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} frames, {} heap bytes in {} allocations not freed",
+            self.frames_not_freed, self.heap_bytes_not_freed, self.heap_allocations_not_freed
+        )?;
+        for range in self.mapped.iter() {
+            write!(f, "\n    still mapped:  {:#018X} - {:#018X} ({} KiB)", range.start, range.end, (range.end - range.start) / 1024)?;
+        }
+        for range in self.unmapped.iter() {
+            write!(f, "\n    unmapped:      {:#018X} - {:#018X} ({} KiB)", range.start, range.end, (range.end - range.start) / 1024)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// The results of [`check()`](fn.check.html).
+#[derive(Clone, Debug, Default)]
+pub struct LeakReport {
+    /// The difference that each iteration left behind, after the warm-up iterations.
+    pub iterations: Vec<MemoryDiff>,
+    /// The difference that all iterations together left behind.
+    pub total: MemoryDiff,
+}
+
+impl LeakReport {
+    /// Returns whether every iteration left frames behind.
+    pub fn leaks_frames(&self) -> bool {
+        !self.iterations.is_empty() && self.iterations.iter().all(|d| d.frames_not_freed > 0)
+    }
+
+    /// Returns whether every iteration left heap allocations behind.
+    pub fn leaks_heap(&self) -> bool {
+        !self.iterations.is_empty() && self.iterations.iter().all(|d| d.heap_bytes_not_freed > 0 || d.heap_allocations_not_freed > 0)
+    }
+
+    /// Returns whether the iterations left any mappings behind.
+    pub fn leaks_mappings(&self) -> bool {
+        !self.total.mapped.is_empty()
+    }
+
+    /// Returns whether any resource was leaked.
+    pub fn leaks(&self) -> bool {
+        self.leaks_frames() || self.leaks_heap() || self.leaks_mappings()
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diff) in self.iterations.iter().enumerate() {
+            writeln!(f, "iteration {}: {}", i, diff)?;
+        }
+        writeln!(f, "total: {}", self.total)?;
+        if self.leaks() {
+            write!(f, "LEAKED:")?;
+            if self.leaks_frames() { write!(f, " frames")?; }
+            if self.leaks_heap() { write!(f, " heap")?; }
+            if self.leaks_mappings() { write!(f, " mappings")?; }
+            Ok(())
+        } else {
+            write!(f, "no leaks found")
+        }
+    }
+}
+
+
+/// Runs the given closure `warmup` times without checking it, and then `iterations` more times,
+/// comparing memory snapshots after each of those iterations.
+///
+/// Returns an error if the closure or a snapshot fails.
+pub fn check<F>(warmup: usize, iterations: usize, mut f: F) -> Result<LeakReport, &'static str>
+    where F: FnMut() -> Result<(), &'static str>
+{
+    for _ in 0 .. warmup {
+        f()?;
+    }
+
+    // Allocate the report up front, such that it doesn't grow the heap between snapshots.
+    let mut report = LeakReport { iterations: Vec::with_capacity(iterations), total: MemoryDiff::default() };
+    let first = snapshot()?;
+    let mut previous: Option<MemorySnapshot> = None;
+    for _ in 0 .. iterations {
+        f()?;
+        let next = snapshot()?;
+        report.iterations.push(previous.as_ref().unwrap_or(&first).diff(&next));
+        previous = Some(next);
+    }
+
+    if let Some(last) = previous {
+        let mut total = first.diff(&last);
+        // Other snapshots existed while the last one was taken, so the totals are summed up from each iteration instead.
+        total.frames_not_freed = report.iterations.iter().map(|d| d.frames_not_freed).sum();
+        total.heap_bytes_not_freed = report.iterations.iter().map(|d| d.heap_bytes_not_freed).sum();
+        total.heap_allocations_not_freed = report.iterations.iter().map(|d| d.heap_allocations_not_freed).sum();
+        report.total = total;
+    }
+    Ok(report)
+}
+
+
+/// Returns the ranges of virtual addresses that are mapped by the given `regions` but not by the `others`.
+/// Both lists must be sorted by virtual address and must not overlap, like those from `Mapper::mappings()`.
+fn uncovered(regions: &[MappingRegion], others: &[MappingRegion]) -> Vec<Range<usize>> {
+    let bounds = |r: &MappingRegion| (r.start_vaddr.value(), r.start_vaddr.value().saturating_add(r.size_in_bytes()));
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut push = |range: Range<usize>| match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    };
+
+    let mut first_other = 0;
+    for region in regions {
+        let (mut start, end) = bounds(region);
+        while first_other < others.len() && bounds(&others[first_other]).1 <= start {
+            first_other += 1;
+        }
+        for other in others[first_other ..].iter() {
+            let (other_start, other_end) = bounds(other);
+            if other_start >= end {
+                break;
+            }
+            if other_start > start {
+                push(start .. other_start);
+            }
+            start = start.max(other_end);
+        }
+        if start < end {
+            push(start .. end);
+        }
+    }
+    ranges
+}
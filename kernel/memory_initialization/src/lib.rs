@@ -3,13 +3,14 @@
 extern crate alloc;
 extern crate heap;
 extern crate kernel_config;
+extern crate kaslr;
 #[macro_use] extern crate log;
 extern crate memory;
 extern crate stack;
 extern crate multiboot2;
 
 use memory::{MmiRef, MappedPages, VirtualAddress};
-use kernel_config::memory::{KERNEL_HEAP_START, KERNEL_HEAP_INITIAL_SIZE};
+use kernel_config::memory::KERNEL_HEAP_INITIAL_SIZE;
 use multiboot2::BootInformation;
 use alloc::vec::Vec;
 use heap::HEAP_FLAGS;
@@ -36,6 +37,7 @@ macro_rules! try_forget {
 /// which represents the initial (kernel) address space. 
 /// Consumes the given BootInformation, because after the memory system is initialized,
 /// the original BootInformation will be unmapped and inaccessible.
+/// The `nano_core_slide` is the number of bytes by which the boot code moved the nano_core image, see the `kaslr` crate.
 /// 
 /// Returns the following tuple, if successful:
 ///  * The kernel's new MemoryManagementInfo
@@ -44,9 +46,15 @@ macro_rules! try_forget {
 ///  * the MappedPages of the kernel's data section,
 ///  * the initial stack for this CPU (e.g., the BSP stack) that is currently in use,
 ///  * the kernel's list of identity-mapped MappedPages which should be dropped before starting the first user application. 
-pub fn init_memory_management(boot_info: &BootInformation)  
+pub fn init_memory_management(boot_info: &BootInformation, nano_core_slide: usize)  
     -> Result<(MmiRef, MappedPages, MappedPages, MappedPages, Stack, Vec<MappedPages>), &'static str>
 {
+    // Randomize the kernel's virtual layout before any pages are allocated,
+    // which also tells the memory subsystem where the nano_core's sections actually are.
+    if kaslr::init(boot_info.command_line_tag().map(|tag| tag.command_line()), nano_core_slide) {
+        info!("KASLR: nano_core moved by {:#X}, heap starts at {:#X}", kaslr::nano_core_slide(), kaslr::heap_start());
+    }
+
     // Initialize memory management: paging (create a new page table), essential kernel mappings
     let (
         frame_allocator_mutex, 
@@ -72,7 +80,7 @@ pub fn init_memory_management(boot_info: &BootInformation)
     };
 
     // Initialize the kernel heap.
-    let heap_start = KERNEL_HEAP_START;
+    let heap_start = kaslr::heap_start();
     let heap_initial_size = KERNEL_HEAP_INITIAL_SIZE;
     
     let heap_mapped_pages = {
@@ -82,10 +90,15 @@ pub fn init_memory_management(boot_info: &BootInformation)
         );
         debug!("Initial heap starts at: {:#X}, size: {:#X}, pages: {:?}", heap_start, heap_initial_size, pages);
         let mut allocator = frame_allocator_mutex.lock();
+        #[cfg(feature = "kasan")]
+        try_forget!(
+            heap::kasan::init(&mut page_table, allocator.deref_mut()),
+            text_mapped_pages, rodata_mapped_pages, data_mapped_pages, stack, higher_half_mapped_pages, identity_mapped_pages
+        );
         let heap_mp = try_forget!(
             page_table.map_allocated_pages(pages, HEAP_FLAGS, allocator.deref_mut())
                 .map_err(|e| {
-                    error!("Failed to map kernel heap memory pages, {} bytes starting at virtual address {:#X}. Error: {:?}", KERNEL_HEAP_INITIAL_SIZE, KERNEL_HEAP_START, e);
+                    error!("Failed to map kernel heap memory pages, {} bytes starting at virtual address {:#X}. Error: {:?}", KERNEL_HEAP_INITIAL_SIZE, heap_start, e);
                     "Failed to map the kernel heap memory. Perhaps the KERNEL_HEAP_INITIAL_SIZE exceeds the size of the system's physical memory?"
                 }),
             text_mapped_pages, rodata_mapped_pages, data_mapped_pages, stack, higher_half_mapped_pages, identity_mapped_pages
@@ -228,14 +228,69 @@ impl From<PhysicalAddress> for usize {
 }
 
 
+/// The type of an area of physical memory, as reported by the bootloader or firmware.
+///
+/// The first five types have the same values as the multiboot memory area types,
+/// so a multiboot area's `typ` can be used directly as a `PhysicalMemoryArea::typ`.
+/// The remaining types can only be reported by a UEFI memory map.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MemoryAreaType {
+    /// Regular RAM that can be used freely.
+    Usable = 1,
+    /// Memory that must not be used, e.g., because it's in use by the firmware.
+    Reserved = 2,
+    /// Memory holding ACPI tables, which can be reused once the tables are no longer needed.
+    AcpiReclaimable = 3,
+    /// Memory that must be preserved across ACPI sleep states.
+    AcpiNvs = 4,
+    /// Memory in which errors have been detected.
+    Defective = 5,
+    /// Memory holding the bootloader's code and data, e.g., the boot information and modules,
+    /// which can be reused once the bootloader's data is no longer needed.
+    BootloaderReclaimable = 0x100,
+    /// Memory used by UEFI boot services that have not yet been exited.
+    BootServices = 0x101,
+    /// Memory used by UEFI runtime services, which must be preserved and remain mapped.
+    RuntimeServices = 0x102,
+    /// Memory-mapped I/O regions, including I/O port space.
+    Mmio = 0x103,
+    /// Persistent (non-volatile) memory.
+    Persistent = 0x104,
+}
+
+impl MemoryAreaType {
+    /// Returns the type with the given value, as stored in `PhysicalMemoryArea::typ`.
+    /// Unknown values are treated as `Reserved`.
+    pub fn from_u32(typ: u32) -> MemoryAreaType {
+        match typ {
+            1 => MemoryAreaType::Usable,
+            3 => MemoryAreaType::AcpiReclaimable,
+            4 => MemoryAreaType::AcpiNvs,
+            5 => MemoryAreaType::Defective,
+            0x100 => MemoryAreaType::BootloaderReclaimable,
+            0x101 => MemoryAreaType::BootServices,
+            0x102 => MemoryAreaType::RuntimeServices,
+            0x103 => MemoryAreaType::Mmio,
+            0x104 => MemoryAreaType::Persistent,
+            _ => MemoryAreaType::Reserved,
+        }
+    }
+}
+
+
 /// An area of physical memory.
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 pub struct PhysicalMemoryArea {
     pub base_addr: PhysicalAddress,
     pub size_in_bytes: usize,
+    /// The type of this area, which is a `MemoryAreaType` value; see [`area_type()`](#method.area_type).
     pub typ: u32,
     pub acpi: u32,
+    /// The UEFI memory attribute flags of this area, e.g., `EFI_MEMORY_WB`,
+    /// or `0` if this area didn't come from a UEFI memory map.
+    pub attributes: u64,
 }
 impl PhysicalMemoryArea {
     pub fn new(
@@ -249,6 +304,89 @@ impl PhysicalMemoryArea {
             size_in_bytes: size_in_bytes,
             typ: typ,
             acpi: acpi,
+            attributes: 0,
+        }
+    }
+
+    /// Returns the type of this memory area.
+    pub fn area_type(&self) -> MemoryAreaType {
+        MemoryAreaType::from_u32(self.typ)
+    }
+
+    /// Returns the zone that the start of this memory area belongs to.
+    /// Note that a large memory area may span multiple zones; see [`intersect_zone()`](#method.intersect_zone).
+    pub fn zone(&self) -> MemoryZone {
+        MemoryZone::containing_address(self.base_addr)
+    }
+
+    /// Returns the part of this memory area that lies within the given `zone`, if any.
+    pub fn intersect_zone(&self, zone: MemoryZone) -> Option<PhysicalMemoryArea> {
+        let (zone_start, zone_end) = zone.bounds();
+        let start = core::cmp::max(self.base_addr.value(), zone_start);
+        let end = core::cmp::min(self.base_addr.value().saturating_add(self.size_in_bytes), zone_end);
+        if start >= end {
+            return None;
+        }
+        Some(PhysicalMemoryArea {
+            base_addr: PhysicalAddress::new_canonical(start),
+            size_in_bytes: end - start,
+            ..*self
+        })
+    }
+}
+
+
+/// A zone of physical memory, classified by which devices are able to address it.
+/// 
+/// Zones are ordered from lowest to highest physical address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemoryZone {
+    /// Physical memory below 16 MiB, which legacy ISA DMA devices can address.
+    Dma,
+    /// Physical memory from 16 MiB up to 4 GiB, which devices that only support 32-bit physical addresses can address.
+    Dma32,
+    /// All physical memory at or above 4 GiB.
+    Normal,
+}
+
+impl MemoryZone {
+    /// The exclusive upper bound of the `Dma` zone.
+    pub const DMA_END: usize = 16 * 1024 * 1024;
+    /// The exclusive upper bound of the `Dma32` zone.
+    pub const DMA32_END: usize = 4 * 1024 * 1024 * 1024;
+    /// The exclusive upper bound of the `Normal` zone, which is the maximum physical address width (52 bits).
+    pub const NORMAL_END: usize = 1 << 52;
+
+    /// Returns the zone that the given physical address belongs to.
+    pub fn containing_address(paddr: PhysicalAddress) -> MemoryZone {
+        match paddr.value() {
+            a if a < Self::DMA_END => MemoryZone::Dma,
+            a if a < Self::DMA32_END => MemoryZone::Dma32,
+            _ => MemoryZone::Normal,
+        }
+    }
+
+    /// Returns the bounds of this zone as a tuple of `(start, end)` physical addresses,
+    /// in which `start` is inclusive and `end` is exclusive.
+    pub fn bounds(&self) -> (usize, usize) {
+        match self {
+            MemoryZone::Dma    => (0, Self::DMA_END),
+            MemoryZone::Dma32  => (Self::DMA_END, Self::DMA32_END),
+            MemoryZone::Normal => (Self::DMA32_END, Self::NORMAL_END),
+        }
+    }
+
+    /// Returns the default order of zones that should be tried when allocating from this zone,
+    /// starting with this zone itself.
+    /// 
+    /// An allocation can always fall back to a lower zone than the one requested, 
+    /// since any device that can address a higher zone can also address the lower zones,
+    /// but never to a higher zone.
+    pub fn fallback_order(&self) -> &'static [MemoryZone] {
+        match self {
+            MemoryZone::Normal => &[MemoryZone::Normal, MemoryZone::Dma32, MemoryZone::Dma],
+            MemoryZone::Dma32  => &[MemoryZone::Dma32, MemoryZone::Dma],
+            MemoryZone::Dma    => &[MemoryZone::Dma],
         }
     }
 }
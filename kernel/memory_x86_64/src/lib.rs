@@ -7,20 +7,26 @@
 #![no_std]
 #![feature(ptr_internals)]
 #![feature(unboxed_closures)]
+#![feature(min_const_generics)]
+#![feature(llvm_asm)]
 
 extern crate multiboot2;
 #[macro_use] extern crate log;
 extern crate kernel_config;
+extern crate kaslr;
 extern crate memory_structs;
 extern crate entryflags_x86_64;
 extern crate x86_64;
 
+mod uefi_memory_map;
+
 pub use multiboot2::BootInformation;
+pub use uefi_memory_map::*;
 pub use entryflags_x86_64::EntryFlags;
 
 use kernel_config::memory::KERNEL_OFFSET;
 use memory_structs::{
-    Frame, PhysicalAddress, PhysicalMemoryArea, VirtualAddress, SectionMemoryBounds, AggregatedSectionMemoryBounds,
+    Frame, MemoryAreaType, PhysicalAddress, PhysicalMemoryArea, VirtualAddress, SectionMemoryBounds, AggregatedSectionMemoryBounds,
 };
 use x86_64::{registers::control_regs, instructions::tlb};
 
@@ -65,27 +71,65 @@ pub fn get_kernel_address(
 
 /// Gets the available physical memory areas from the bootloader-provided list.
 ///
+/// If the bootloader provided a UEFI memory map, that is used instead of the multiboot memory map,
+/// since it describes physical memory more accurately when booted from UEFI firmware.
+///
 /// Returns the following tuple, if successful:
-///  * An array of available physical memory areas,
+///  * An array of up to `N` available physical memory areas,
 ///  * The number of valid entries in that array.
-pub fn get_available_memory(
+pub fn get_available_memory<const N: usize>(
     boot_info: &BootInformation,
     kernel_phys_end: PhysicalAddress,
-) -> Result<([PhysicalMemoryArea; 32], usize), &'static str> {
+) -> Result<([PhysicalMemoryArea; N], usize), &'static str> {
+    if let Some(efi_memory_map) = find_efi_memory_map(boot_info) {
+        info!("Using the UEFI memory map provided by the bootloader");
+        for area in efi_memory_map.memory_areas() {
+            debug!("UEFI memory area base_addr={:#x} length={:#x} type={:?} attributes={:#x}",
+                area.base_addr, area.size_in_bytes, area.area_type(), area.attributes
+            );
+        }
+        return get_available_memory_from_areas(efi_memory_map.usable_areas(), kernel_phys_end);
+    }
+
     // parse the list of physical memory areas from multiboot
-    let mut available: [PhysicalMemoryArea; 32] = Default::default();
-    let mut avail_index = 0;
     let memory_map_tag = boot_info
         .memory_map_tag()
         .ok_or("Memory map tag not found")?;
     for area in memory_map_tag.memory_areas() {
-        let area_start = PhysicalAddress::new(area.start_address() as usize)?;
-        let area_end = PhysicalAddress::new(area.end_address() as usize)?;
-        let area_size = area.size() as usize;
-        debug!(
-            "memory area base_addr={:#x} length={:#x} ({:?})",
-            area_start, area_size, area
+        debug!("memory area base_addr={:#x} length={:#x} ({:?})",
+            area.start_address(), area.size(), area
         );
+        // ensure all areas have valid addresses before using them below
+        PhysicalAddress::new(area.start_address() as usize)?;
+        PhysicalAddress::new(area.end_address() as usize)?;
+    }
+    let areas = memory_map_tag.memory_areas().map(|area| PhysicalMemoryArea::new(
+        PhysicalAddress::new_canonical(area.start_address() as usize),
+        area.size() as usize,
+        MemoryAreaType::Usable as u32,
+        0,
+    ));
+    get_available_memory_from_areas(areas, kernel_phys_end)
+}
+
+/// Gets the available physical memory areas from the given list of usable memory `areas`,
+/// excluding the memory below the end of the kernel image.
+///
+/// This can be used by any boot path, e.g., with the usable areas of an [`EfiMemoryMap`](struct.EfiMemoryMap.html).
+///
+/// Returns the following tuple, if successful:
+///  * An array of up to `N` available physical memory areas,
+///  * The number of valid entries in that array.
+pub fn get_available_memory_from_areas<I: Iterator<Item = PhysicalMemoryArea>, const N: usize>(
+    areas: I,
+    kernel_phys_end: PhysicalAddress,
+) -> Result<([PhysicalMemoryArea; N], usize), &'static str> {
+    let mut available = [PhysicalMemoryArea::default(); N];
+    let mut avail_index = 0;
+    for area in areas {
+        let area_start = area.base_addr;
+        let area_end = PhysicalAddress::new(area.base_addr.value() + area.size_in_bytes)?;
+        let area_size = area.size_in_bytes;
 
         // optimization: we reserve memory from areas below the end of the kernel's physical address,
         // which includes addresses beneath 1 MB
@@ -100,12 +144,13 @@ pub fn get_available_memory(
         };
         let start_paddr = (Frame::containing_address(start_paddr) + 1).start_address(); // align up to next page
 
-        let new_entry = available.get_mut(avail_index).ok_or("Found more than 32 physical memory areas, only 32 are supported.")?;
+        let new_entry = available.get_mut(avail_index).ok_or("Found more physical memory areas than are supported; try increasing MAX_PRE_HEAP_MEMORY_AREAS.")?;
         *new_entry = PhysicalMemoryArea {
             base_addr: start_paddr,
             size_in_bytes: area_size,
-            typ: 1,
+            typ: MemoryAreaType::Usable as u32,
             acpi: 0,
+            attributes: area.attributes,
         };
 
         info!("--> memory region established: start={:#x}, size_in_bytes={:#x}",
@@ -184,10 +229,16 @@ pub fn find_section_memory_bounds(boot_info: &BootInformation) -> Result<(Aggreg
         debug!("Looking at loaded section {} at {:#X}, size {:#X}", section.name(), section.start_address(), section.size());
         let flags = EntryFlags::from_multiboot2_section_flags(&section) | EntryFlags::GLOBAL;
 
+        // The table of relocations for KASLR is only needed by the boot code, see `kaslr::nano_core_slide()`.
+        if section.name() == ".kaslr_relocs" {
+            continue;
+        }
+
         // even though the linker stipulates that the kernel sections have a higher-half virtual address,
         // they are still loaded at a lower physical address, in which phys_addr = virt_addr - KERNEL_OFFSET.
         // thus, we must map the zeroeth kernel section from its low address to a higher-half address,
-        // and we must map all the other sections from their higher given virtual address to the proper lower phys addr
+        // and we must map all the other sections from their higher given virtual address to the proper lower phys addr.
+        // The boot code may also have moved the higher-half sections by a random slide, see the `kaslr` crate.
         let mut start_phys_addr = section.start_address() as usize;
         if start_phys_addr >= KERNEL_OFFSET {
             // true for all sections but the first section (inittext)
@@ -199,6 +250,7 @@ pub fn find_section_memory_bounds(boot_info: &BootInformation) -> Result<(Aggreg
             // special case to handle the first section only
             start_virt_addr += KERNEL_OFFSET;
         }
+        start_virt_addr += kaslr::nano_core_slide();
 
         let start_phys_addr = PhysicalAddress::new(start_phys_addr)?;
         let start_virt_addr = VirtualAddress::new(start_virt_addr)?;
@@ -349,5 +401,130 @@ pub fn tlb_flush_all() {
 
 /// Returns the current top-level page table address.
 pub fn get_p4() -> PhysicalAddress {
-    PhysicalAddress::new_canonical(control_regs::cr3().0 as usize)
+    PhysicalAddress::new_canonical((control_regs::cr3().0 & !CR3_PCID_MASK) as usize)
+}
+
+
+/// The bits of CR3 that hold the current process-context identifier (PCID), when PCIDs are enabled.
+const CR3_PCID_MASK: u64 = 0xFFF;
+/// When this bit is set in a value written to CR3, the TLB entries tagged with the new PCID are kept.
+const CR3_NOFLUSH: u64 = 1 << 63;
+/// The bit of CR4 that enables PCIDs.
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// Enables process-context identifiers (PCIDs) on the current core by setting CR4.PCIDE.
+///
+/// # Safety
+/// The CPU must support PCIDs, and the current PCID in CR3 must be 0, i.e.,
+/// CR3 must have been written without a PCID, otherwise this causes a general protection fault.
+pub unsafe fn enable_pcid() {
+    let cr4: u64;
+    llvm_asm!("mov %cr4, $0" : "=r"(cr4) : : : "volatile");
+    llvm_asm!("mov $0, %cr4" : : "r"(cr4 | CR4_PCIDE) : "memory" : "volatile");
+}
+
+/// The bit of CR4 that enables supervisor-mode execution prevention (SMEP).
+const CR4_SMEP: u64 = 1 << 20;
+/// The bit of CR4 that enables supervisor-mode access prevention (SMAP).
+const CR4_SMAP: u64 = 1 << 21;
+/// The bit of CR4 that enables protection keys for supervisor-mode pages (PKS).
+const CR4_PKS: u64 = 1 << 24;
+/// The alignment check flag in RFLAGS, which temporarily lifts SMAP while it is set.
+const RFLAGS_AC: u64 = 1 << 18;
+
+/// Enables SMEP on the current core by setting CR4.SMEP,
+/// such that the kernel can no longer execute code in pages that are user-accessible.
+///
+/// # Safety
+/// The CPU must support SMEP, otherwise this causes a general protection fault.
+pub unsafe fn enable_smep() {
+    let cr4: u64;
+    llvm_asm!("mov %cr4, $0" : "=r"(cr4) : : : "volatile");
+    llvm_asm!("mov $0, %cr4" : : "r"(cr4 | CR4_SMEP) : "memory" : "volatile");
+}
+
+/// Enables SMAP on the current core by setting CR4.SMAP,
+/// such that the kernel can no longer access data in pages that are user-accessible unless RFLAGS.AC is set.
+///
+/// # Safety
+/// The CPU must support SMAP, otherwise this causes a general protection fault.
+pub unsafe fn enable_smap() {
+    let cr4: u64;
+    llvm_asm!("mov %cr4, $0" : "=r"(cr4) : : : "volatile");
+    llvm_asm!("mov $0, %cr4" : : "r"(cr4 | CR4_SMAP) : "memory" : "volatile");
+}
+
+/// Enables protection keys for supervisor-mode pages (PKS) on the current core by setting CR4.PKS,
+/// such that the access rights in the IA32_PKRS MSR apply to every page tagged with a protection key.
+///
+/// # Safety
+/// The CPU must support PKS, otherwise this causes a general protection fault.
+/// The IA32_PKRS MSR should be initialized first, since all pages are tagged with protection key 0 by default.
+pub unsafe fn enable_pks() {
+    let cr4: u64;
+    llvm_asm!("mov %cr4, $0" : "=r"(cr4) : : : "volatile");
+    llvm_asm!("mov $0, %cr4" : : "r"(cr4 | CR4_PKS) : "memory" : "volatile");
+}
+
+/// Sets RFLAGS.AC with the `stac` instruction, which allows accesses to user-accessible pages despite SMAP.
+///
+/// # Safety
+/// The CPU must support SMAP, otherwise this causes an invalid opcode exception.
+pub unsafe fn stac() {
+    llvm_asm!("stac" : : : "memory" : "volatile");
+}
+
+/// Clears RFLAGS.AC with the `clac` instruction, which forbids accesses to user-accessible pages again.
+///
+/// # Safety
+/// The CPU must support SMAP, otherwise this causes an invalid opcode exception.
+pub unsafe fn clac() {
+    llvm_asm!("clac" : : : "memory" : "volatile");
+}
+
+/// Returns whether RFLAGS.AC is set on the current core, i.e., whether accesses to user-accessible pages are allowed.
+pub fn user_access_allowed() -> bool {
+    let rflags: u64;
+    unsafe { llvm_asm!("pushfq; pop $0" : "=r"(rflags) : : "memory" : "volatile") };
+    rflags & RFLAGS_AC != 0
+}
+
+/// Switches to the page table at the given `p4` address, tagging its TLB entries with the given `pcid`.
+///
+/// If `noflush` is `true`, the TLB entries that are already tagged with that `pcid` are kept,
+/// otherwise they are flushed (except for global entries).
+///
+/// # Safety
+/// PCIDs must be enabled on the current core, see [`enable_pcid()`](fn.enable_pcid.html),
+/// and the `pcid` must not be greater than `0xFFF`.
+pub unsafe fn set_p4_with_pcid(p4: PhysicalAddress, pcid: u16, noflush: bool) {
+    let mut cr3 = p4.value() as u64 | (pcid as u64 & CR3_PCID_MASK);
+    if noflush {
+        cr3 |= CR3_NOFLUSH;
+    }
+    llvm_asm!("mov $0, %cr3" : : "r"(cr3) : "memory" : "volatile");
+}
+
+/// The kinds of TLB invalidation that the `invpcid` instruction performs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum InvpcidKind {
+    /// Invalidates the entry for the given virtual address that is tagged with the given PCID.
+    IndividualAddress = 0,
+    /// Invalidates all non-global entries that are tagged with the given PCID.
+    SingleContext = 1,
+    /// Invalidates all entries of every PCID, including global entries.
+    AllContextsIncludingGlobal = 2,
+    /// Invalidates all non-global entries of every PCID.
+    AllContexts = 3,
+}
+
+/// Invalidates TLB entries with the `invpcid` instruction.
+/// The `pcid` and `vaddr` are ignored by the kinds of invalidation that don't use them.
+///
+/// # Safety
+/// The CPU must support the `invpcid` instruction, and the `pcid` must not be greater than `0xFFF`.
+pub unsafe fn invpcid(kind: InvpcidKind, pcid: u16, vaddr: VirtualAddress) {
+    let descriptor: [u64; 2] = [pcid as u64, vaddr.value() as u64];
+    llvm_asm!("invpcid ($0), $1" : : "r"(&descriptor), "r"(kind as u64) : "memory" : "volatile");
 }
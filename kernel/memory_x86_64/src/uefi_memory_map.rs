@@ -0,0 +1,308 @@
+//! Support for the UEFI memory map, an array of `EFI_MEMORY_DESCRIPTOR`s returned by UEFI's `GetMemoryMap()`.
+//!
+//! When booting from UEFI firmware, the UEFI memory map is the authoritative description of physical memory.
+//! A multiboot2 bootloader passes it along in an EFI memory map tag, see [`find_efi_memory_map()`];
+//! a native UEFI bootloader can hand its memory map buffer directly to [`EfiMemoryMap::new()`].
+//! Each descriptor is converted into a `PhysicalMemoryArea` with the corresponding `MemoryAreaType`,
+//! preserving its attribute flags.
+//! The bootloader also passes along the physical address of the EFI system table, see [`find_efi_system_table()`].
+//!
+//! [`find_efi_memory_map()`]: fn.find_efi_memory_map.html
+//! [`EfiMemoryMap::new()`]: struct.EfiMemoryMap.html#method.new
+//! [`find_efi_system_table()`]: fn.find_efi_system_table.html
+
+use core::{mem::size_of, ptr, slice};
+use memory_structs::{MemoryAreaType, PhysicalAddress, PhysicalMemoryArea};
+use kernel_config::memory::PAGE_SIZE;
+use super::BootInformation;
+
+
+/// The memory region supports being configured as not cacheable.
+pub const EFI_MEMORY_UC: u64 = 0x1;
+/// The memory region supports being configured as write combining.
+pub const EFI_MEMORY_WC: u64 = 0x2;
+/// The memory region supports being configured as write through.
+pub const EFI_MEMORY_WT: u64 = 0x4;
+/// The memory region supports being configured as write back, which regular RAM must support.
+pub const EFI_MEMORY_WB: u64 = 0x8;
+/// The memory region supports being configured as not cacheable, exported, and supporting "fetch and add" semaphores.
+pub const EFI_MEMORY_UCE: u64 = 0x10;
+/// The memory region supports being configured as write protected.
+pub const EFI_MEMORY_WP: u64 = 0x1000;
+/// The memory region supports being configured as read protected.
+pub const EFI_MEMORY_RP: u64 = 0x2000;
+/// The memory region supports being configured so it is protected against execution.
+pub const EFI_MEMORY_XP: u64 = 0x4000;
+/// The memory region is persistent (non-volatile) memory.
+pub const EFI_MEMORY_NV: u64 = 0x8000;
+/// The memory region provides higher reliability than other memory in the system.
+pub const EFI_MEMORY_MORE_RELIABLE: u64 = 0x10000;
+/// The memory region supports being configured as read only.
+pub const EFI_MEMORY_RO: u64 = 0x20000;
+/// The memory region is earmarked for specific purposes, e.g., by specific device drivers.
+pub const EFI_MEMORY_SP: u64 = 0x40000;
+/// The memory region is capable of being protected with the CPU's memory cryptographic capabilities.
+pub const EFI_MEMORY_CPU_CRYPTO: u64 = 0x80000;
+/// The memory region must be given a virtual mapping by the OS when UEFI runtime services are used.
+pub const EFI_MEMORY_RUNTIME: u64 = 0x8000_0000_0000_0000;
+
+
+/// The memory types of an `EFI_MEMORY_DESCRIPTOR`, as defined by the UEFI specification.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum EfiMemoryType {
+    ReservedMemoryType = 0,
+    LoaderCode = 1,
+    LoaderData = 2,
+    BootServicesCode = 3,
+    BootServicesData = 4,
+    RuntimeServicesCode = 5,
+    RuntimeServicesData = 6,
+    ConventionalMemory = 7,
+    UnusableMemory = 8,
+    AcpiReclaimMemory = 9,
+    AcpiMemoryNvs = 10,
+    MemoryMappedIo = 11,
+    MemoryMappedIoPortSpace = 12,
+    PalCode = 13,
+    PersistentMemory = 14,
+    UnacceptedMemory = 15,
+}
+
+impl EfiMemoryType {
+    /// Returns the memory type with the given raw value, or `None` if it is OEM- or OS-defined.
+    pub fn from_u32(typ: u32) -> Option<EfiMemoryType> {
+        use self::EfiMemoryType::*;
+        let t = match typ {
+            0 => ReservedMemoryType,
+            1 => LoaderCode,
+            2 => LoaderData,
+            3 => BootServicesCode,
+            4 => BootServicesData,
+            5 => RuntimeServicesCode,
+            6 => RuntimeServicesData,
+            7 => ConventionalMemory,
+            8 => UnusableMemory,
+            9 => AcpiReclaimMemory,
+            10 => AcpiMemoryNvs,
+            11 => MemoryMappedIo,
+            12 => MemoryMappedIoPortSpace,
+            13 => PalCode,
+            14 => PersistentMemory,
+            15 => UnacceptedMemory,
+            _ => return None,
+        };
+        Some(t)
+    }
+}
+
+
+/// An `EFI_MEMORY_DESCRIPTOR`, as defined by the UEFI specification.
+///
+/// Note that the firmware may use a larger descriptor size than the size of this struct,
+/// so descriptors must be accessed using the descriptor size given along with the memory map.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct EfiMemoryDescriptor {
+    pub typ: u32,
+    _padding: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+impl EfiMemoryDescriptor {
+    /// UEFI pages are always 4 KiB, regardless of the page size used by the OS.
+    pub const EFI_PAGE_SIZE: usize = 4096;
+
+    /// Converts this descriptor into a `PhysicalMemoryArea` with the corresponding `MemoryAreaType`.
+    ///
+    /// If `boot_services_exited` is `false`, the memory used by UEFI boot services is still in use
+    /// and therefore isn't considered to be usable.
+    /// Returns `None` if this descriptor has an invalid physical address.
+    pub fn to_memory_area(&self, boot_services_exited: bool) -> Option<PhysicalMemoryArea> {
+        let typ = if self.attribute & EFI_MEMORY_RUNTIME != 0 {
+            // Runtime memory must never be reused, regardless of its type.
+            MemoryAreaType::RuntimeServices
+        } else {
+            match EfiMemoryType::from_u32(self.typ) {
+                Some(EfiMemoryType::ConventionalMemory) => MemoryAreaType::Usable,
+                Some(EfiMemoryType::BootServicesCode) | Some(EfiMemoryType::BootServicesData) => {
+                    if boot_services_exited { MemoryAreaType::Usable } else { MemoryAreaType::BootServices }
+                }
+                Some(EfiMemoryType::LoaderCode) | Some(EfiMemoryType::LoaderData) => MemoryAreaType::BootloaderReclaimable,
+                Some(EfiMemoryType::RuntimeServicesCode) | Some(EfiMemoryType::RuntimeServicesData) => MemoryAreaType::RuntimeServices,
+                Some(EfiMemoryType::UnusableMemory) => MemoryAreaType::Defective,
+                Some(EfiMemoryType::AcpiReclaimMemory) => MemoryAreaType::AcpiReclaimable,
+                Some(EfiMemoryType::AcpiMemoryNvs) => MemoryAreaType::AcpiNvs,
+                Some(EfiMemoryType::MemoryMappedIo) | Some(EfiMemoryType::MemoryMappedIoPortSpace) => MemoryAreaType::Mmio,
+                Some(EfiMemoryType::PersistentMemory) => MemoryAreaType::Persistent,
+                // Unaccepted memory must be accepted via a firmware protocol before it can be used.
+                Some(EfiMemoryType::UnacceptedMemory)
+                | Some(EfiMemoryType::ReservedMemoryType)
+                | Some(EfiMemoryType::PalCode)
+                | None => MemoryAreaType::Reserved,
+            }
+        };
+        // Regular RAM must support write-back caching, otherwise we can't use it as normal memory.
+        let typ = if typ == MemoryAreaType::Usable && self.attribute & EFI_MEMORY_WB == 0 {
+            MemoryAreaType::Reserved
+        } else {
+            typ
+        };
+
+        Some(PhysicalMemoryArea {
+            base_addr: PhysicalAddress::new(self.physical_start as usize).ok()?,
+            size_in_bytes: (self.number_of_pages as usize).checked_mul(Self::EFI_PAGE_SIZE)?,
+            typ: typ as u32,
+            acpi: 0,
+            attributes: self.attribute,
+        })
+    }
+}
+
+
+/// A UEFI memory map, i.e., an array of `EFI_MEMORY_DESCRIPTOR`s.
+#[derive(Copy, Clone)]
+pub struct EfiMemoryMap<'m> {
+    buffer: &'m [u8],
+    descriptor_size: usize,
+    boot_services_exited: bool,
+}
+
+impl<'m> EfiMemoryMap<'m> {
+    /// Creates a UEFI memory map from the given `buffer` of descriptors,
+    /// each of which is `descriptor_size` bytes long.
+    ///
+    /// `boot_services_exited` should be `true` if `ExitBootServices()` has been called,
+    /// such that the memory used by boot services can be reused.
+    pub fn new(buffer: &'m [u8], descriptor_size: usize, boot_services_exited: bool) -> Result<EfiMemoryMap<'m>, &'static str> {
+        if descriptor_size < size_of::<EfiMemoryDescriptor>() {
+            return Err("EFI memory descriptor size is smaller than EFI_MEMORY_DESCRIPTOR");
+        }
+        Ok(EfiMemoryMap { buffer, descriptor_size, boot_services_exited })
+    }
+
+    /// Returns an iterator over the raw descriptors in this memory map.
+    pub fn descriptors(&self) -> impl Iterator<Item = EfiMemoryDescriptor> + 'm {
+        let buffer = self.buffer;
+        buffer.chunks_exact(self.descriptor_size).map(|chunk| {
+            // SAFE: the chunk is at least as large as a descriptor, which may not be aligned within the buffer.
+            unsafe { ptr::read_unaligned(chunk.as_ptr() as *const EfiMemoryDescriptor) }
+        })
+    }
+
+    /// Returns an iterator over the memory areas described by this memory map.
+    /// Descriptors with invalid physical addresses are skipped.
+    pub fn memory_areas(&self) -> impl Iterator<Item = PhysicalMemoryArea> + 'm {
+        let boot_services_exited = self.boot_services_exited;
+        self.descriptors().filter_map(move |desc| desc.to_memory_area(boot_services_exited))
+    }
+
+    /// Returns an iterator over the usable memory areas described by this memory map,
+    /// in which physically-contiguous usable areas are merged together.
+    ///
+    /// UEFI memory maps are typically much more fragmented than multiboot memory maps,
+    /// so merging adjacent areas avoids exhausting the limited number of areas that can be tracked before the heap exists.
+    /// This assumes the memory map is sorted by physical address, as UEFI memory maps typically are.
+    pub fn usable_areas(&self) -> impl Iterator<Item = PhysicalMemoryArea> + 'm {
+        let mut areas = self.memory_areas()
+            .filter(|area| area.area_type() == MemoryAreaType::Usable && area.size_in_bytes >= PAGE_SIZE)
+            .peekable();
+        core::iter::from_fn(move || {
+            let mut merged = areas.next()?;
+            while let Some(next) = areas.peek() {
+                if merged.base_addr.value() + merged.size_in_bytes != next.base_addr.value() {
+                    break;
+                }
+                merged.size_in_bytes += next.size_in_bytes;
+                merged.attributes &= next.attributes;
+                areas.next();
+            }
+            Some(merged)
+        })
+    }
+}
+
+
+/// The multiboot2 tag type of the tag that holds the physical address of the 64-bit EFI system table.
+const MULTIBOOT2_TAG_EFI64: u32 = 12;
+/// The multiboot2 tag type of the EFI memory map tag.
+const MULTIBOOT2_TAG_EFI_MMAP: u32 = 17;
+/// The multiboot2 tag type that indicates that UEFI boot services were not exited by the bootloader.
+const MULTIBOOT2_TAG_EFI_BS_NOT_TERMINATED: u32 = 18;
+/// The multiboot2 tag type that terminates the list of tags.
+const MULTIBOOT2_TAG_END: u32 = 0;
+
+/// Invokes the given closure with the type and the bytes (including the header) of each tag
+/// in the multiboot2 boot information.
+fn for_each_tag<'b, F: FnMut(u32, &'b [u8])>(boot_info: &'b BootInformation, mut f: F) {
+    // The multiboot2 crate doesn't support the EFI tags, so we walk the list of tags ourselves.
+    // Each tag starts with a u32 type and a u32 size (including its header) and is 8-byte aligned,
+    // and the list of tags starts after the u32 total size and u32 reserved fields.
+    let start = boot_info.start_address();
+    let end = boot_info.end_address();
+    // SAFE: the boot information is mapped and remains valid for as long as `boot_info` does.
+    let info = unsafe { slice::from_raw_parts(start as *const u8, end - start) };
+
+    let mut offset = 8;
+    while let (Some(typ), Some(size)) = (read_u32(info, offset), read_u32(info, offset + 4)) {
+        let size = size as usize;
+        if typ == MULTIBOOT2_TAG_END || size < 8 {
+            break;
+        }
+        match info.get(offset .. offset + size) {
+            Some(tag) => f(typ, tag),
+            None => break,
+        }
+        offset += (size + 7) & !7;
+    }
+}
+
+/// Reads the little-endian u32 at the given `offset` into the given `bytes`.
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset .. offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Finds the UEFI memory map in the multiboot2 boot information, which a bootloader provides
+/// when it was booted from UEFI firmware.
+///
+/// Returns `None` if there is no EFI memory map tag, e.g., when booted via legacy BIOS.
+pub fn find_efi_memory_map(boot_info: &BootInformation) -> Option<EfiMemoryMap> {
+    let mut mmap_tag: Option<&[u8]> = None;
+    let mut boot_services_exited = true;
+    for_each_tag(boot_info, |typ, tag| match typ {
+        MULTIBOOT2_TAG_EFI_MMAP => mmap_tag = Some(tag),
+        MULTIBOOT2_TAG_EFI_BS_NOT_TERMINATED => boot_services_exited = false,
+        _ => { }
+    });
+
+    // The EFI memory map tag has a u32 descriptor size and a u32 descriptor version after the tag header.
+    let tag = mmap_tag?;
+    let descriptor_size = read_u32(tag, 8)? as usize;
+    let buffer = tag.get(16 ..)?;
+    match EfiMemoryMap::new(buffer, descriptor_size, boot_services_exited) {
+        Ok(map) => Some(map),
+        Err(e) => {
+            error!("Ignoring invalid EFI memory map tag: {}", e);
+            None
+        }
+    }
+}
+
+/// Finds the physical address of the 64-bit EFI system table in the multiboot2 boot information,
+/// which a bootloader provides when it was booted from UEFI firmware.
+///
+/// Returns `None` if there is no such tag, e.g., when booted via legacy BIOS.
+pub fn find_efi_system_table(boot_info: &BootInformation) -> Option<PhysicalAddress> {
+    let mut address: Option<u64> = None;
+    // The tag holds a u64 physical address after the tag header.
+    for_each_tag(boot_info, |typ, tag| if typ == MULTIBOOT2_TAG_EFI64 {
+        address = tag.get(8 .. 16).map(|bytes| {
+            u64::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]])
+        });
+    });
+    PhysicalAddress::new(address? as usize).ok()
+}
@@ -0,0 +1,335 @@
+//! Typed, volatile, access-checked definitions of memory-mapped I/O (MMIO) register blocks.
+//!
+//! A device's register block is defined with the [`register_structs!`] macro,
+//! which takes the offset of each register and generates a `#[repr(C)]` struct
+//! with the correct reserved padding between registers,
+//! such that drivers no longer need to count padding bytes or compute offsets by hand.
+//! Each register is one of the following types, which determine how it can be accessed:
+//! * [`ReadOnly`]: can only be read,
+//! * [`WriteOnly`]: can only be written,
+//! * [`ReadWrite`]: can be read, written, and modified (read, then written).
+//!
+//! Reserved regions have no type and are not accessible at all.
+//! All accesses are volatile, and they only require a `&` reference to the register block,
+//! since hardware registers don't follow Rust's mutability rules anyway.
+//!
+//! A register block is accessed by wrapping the `MappedPages` that cover it in an [`MmioRegion`],
+//! which checks that the register block fits within those pages and is properly aligned,
+//! and then derefs to the register block.
+//!
+//! # Example
+//! ```
+//! register_structs! {
+//!     /// The registers of an example device.
+//!     pub ExampleRegisters {
+//!         /// The device's identifier.
+//!         (0x00 => pub id: ReadOnly<u32>),
+//!         (0x04 => _reserved0),
+//!         /// The control register.
+//!         (0x10 => pub control: ReadWrite<u32>),
+//!         /// Writing to this register rings the doorbell.
+//!         (0x14 => pub doorbell: WriteOnly<u32>),
+//!         (0x18 => _reserved1),
+//!         (0x100 => @END),
+//!     }
+//! }
+//!
+//! let regs = MmioRegion::<ExampleRegisters>::new(mapped_pages, 0)?;
+//! if regs.id.read() == EXPECTED_ID {
+//!     regs.control.modify(|c| c | 0x1);
+//!     regs.doorbell.write(1);
+//! }
+//! ```
+//!
+//! [`register_structs!`]: macro.register_structs.html
+//! [`ReadOnly`]: struct.ReadOnly.html
+//! [`WriteOnly`]: struct.WriteOnly.html
+//! [`ReadWrite`]: struct.ReadWrite.html
+//! [`MmioRegion`]: struct.MmioRegion.html
+
+#![no_std]
+#![feature(min_const_generics)]
+
+#[macro_use] extern crate log;
+extern crate memory;
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::Deref,
+    ptr,
+};
+use memory::{EntryFlags, MappedPages};
+
+
+/// A type that can be the value of a register, which must be valid for any bit pattern
+/// that the hardware may produce, e.g., a primitive integer.
+///
+/// # Safety
+/// Every bit pattern of the size of this type must be a valid value of this type.
+pub unsafe trait RegisterValue: Copy {}
+
+unsafe impl RegisterValue for u8 {}
+unsafe impl RegisterValue for u16 {}
+unsafe impl RegisterValue for u32 {}
+unsafe impl RegisterValue for u64 {}
+unsafe impl RegisterValue for i8 {}
+unsafe impl RegisterValue for i16 {}
+unsafe impl RegisterValue for i32 {}
+unsafe impl RegisterValue for i64 {}
+
+
+/// A type that can be overlaid onto MMIO memory: a register, an array of registers,
+/// or a register block generated by [`register_structs!`](macro.register_structs.html).
+///
+/// # Safety
+/// Every bit pattern must be a valid value of this type,
+/// and the type must only access its underlying memory with volatile accesses.
+/// This should not be implemented manually; use the [`register_structs!`](macro.register_structs.html) macro instead.
+pub unsafe trait RegisterBlock: Sized {}
+
+unsafe impl<R: RegisterBlock, const N: usize> RegisterBlock for [R; N] {}
+
+
+macro_rules! register_type {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[repr(transparent)]
+        pub struct $name<T: RegisterValue> {
+            value: UnsafeCell<T>,
+        }
+
+        // SAFE: each access to a register is a single volatile access to device memory.
+        // Ordering multiple accesses to a device's registers is the responsibility of its driver.
+        unsafe impl<T: RegisterValue> Sync for $name<T> {}
+        unsafe impl<T: RegisterValue> Send for $name<T> {}
+
+        unsafe impl<T: RegisterValue> RegisterBlock for $name<T> {}
+    };
+}
+
+register_type!(
+    /// A register that can only be read.
+    ReadOnly
+);
+register_type!(
+    /// A register that can only be written.
+    WriteOnly
+);
+register_type!(
+    /// A register that can be read and written.
+    ReadWrite
+);
+
+impl<T: RegisterValue> ReadOnly<T> {
+    /// Reads the value of this register.
+    #[inline(always)]
+    pub fn read(&self) -> T {
+        // SAFE: the register lies within device memory that is mapped for as long as `self` exists.
+        unsafe { ptr::read_volatile(self.value.get()) }
+    }
+}
+
+impl<T: RegisterValue> WriteOnly<T> {
+    /// Writes the given `value` to this register.
+    #[inline(always)]
+    pub fn write(&self, value: T) {
+        // SAFE: the register lies within device memory that is mapped for as long as `self` exists.
+        unsafe { ptr::write_volatile(self.value.get(), value) }
+    }
+}
+
+impl<T: RegisterValue> ReadWrite<T> {
+    /// Reads the value of this register.
+    #[inline(always)]
+    pub fn read(&self) -> T {
+        // SAFE: the register lies within device memory that is mapped for as long as `self` exists.
+        unsafe { ptr::read_volatile(self.value.get()) }
+    }
+
+    /// Writes the given `value` to this register.
+    #[inline(always)]
+    pub fn write(&self, value: T) {
+        // SAFE: the register lies within device memory that is mapped for as long as `self` exists.
+        unsafe { ptr::write_volatile(self.value.get(), value) }
+    }
+
+    /// Reads the value of this register, passes it into the given function `f`,
+    /// and writes the value that `f` returns back to this register.
+    ///
+    /// This is not atomic with respect to the hardware or other cores.
+    #[inline(always)]
+    pub fn modify<F: FnOnce(T) -> T>(&self, f: F) {
+        self.write(f(self.read()));
+    }
+}
+
+impl<T: RegisterValue + fmt::Debug> fmt::Debug for ReadOnly<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReadOnly({:?})", self.read())
+    }
+}
+
+impl<T: RegisterValue> fmt::Debug for WriteOnly<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Reading a write-only register may have side effects, so we don't.
+        write!(f, "WriteOnly(..)")
+    }
+}
+
+impl<T: RegisterValue + fmt::Debug> fmt::Debug for ReadWrite<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReadWrite({:?})", self.read())
+    }
+}
+
+
+/// A register block of type `T` that lies within, and owns, a memory-mapped I/O region.
+///
+/// This derefs to the register block `T`, which stays valid for as long as this `MmioRegion` exists.
+pub struct MmioRegion<T: RegisterBlock> {
+    mapped_pages: MappedPages,
+    offset: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: RegisterBlock> MmioRegion<T> {
+    /// Creates a new `MmioRegion` for the register block of type `T`
+    /// that starts at the given `offset` into the given `mapped_pages`.
+    ///
+    /// Returns an error if the register block would not fit within the `mapped_pages`,
+    /// if it would not be properly aligned, or if the `mapped_pages` are not writable.
+    pub fn new(mapped_pages: MappedPages, offset: usize) -> Result<MmioRegion<T>, &'static str> {
+        let size = mem::size_of::<T>();
+        if offset + size > mapped_pages.size_in_bytes() {
+            error!("MmioRegion::new(): register block {} with size {} at offset {} doesn't fit within MappedPages of size {}",
+                core::any::type_name::<T>(), size, offset, mapped_pages.size_in_bytes()
+            );
+            return Err("MmioRegion::new(): register block would not fit within the MappedPages bounds");
+        }
+        if (mapped_pages.start_address().value() + offset) % mem::align_of::<T>() != 0 {
+            return Err("MmioRegion::new(): register block would not be properly aligned");
+        }
+        if !mapped_pages.flags().is_writable() {
+            return Err("MmioRegion::new(): MappedPages were not writable");
+        }
+        if !mapped_pages.flags().contains(EntryFlags::NO_CACHE) {
+            warn!("MmioRegion::new(): MappedPages for register block {} are cacheable (flags: {:?})",
+                core::any::type_name::<T>(), mapped_pages.flags()
+            );
+        }
+
+        Ok(MmioRegion {
+            mapped_pages,
+            offset,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Returns the offset into the underlying `MappedPages` at which the register block starts.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns a reference to the underlying `MappedPages`.
+    pub fn mapped_pages(&self) -> &MappedPages {
+        &self.mapped_pages
+    }
+
+    /// Consumes this `MmioRegion` and returns the underlying `MappedPages`.
+    pub fn into_mapped_pages(self) -> MappedPages {
+        self.mapped_pages
+    }
+}
+
+impl<T: RegisterBlock> Deref for MmioRegion<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFE: we checked the size, alignment, and writability of the mapping when creating this `MmioRegion`,
+        // and `T: RegisterBlock` is valid for any bit pattern and only accessed with volatile accesses.
+        unsafe { &*((self.mapped_pages.start_address().value() + self.offset) as *const T) }
+    }
+}
+
+
+/// Defines one or more MMIO register block structs, given the offset of each register within the block.
+///
+/// Each entry has the form `(offset => visibility name: Type)` for a register,
+/// or `(offset => name)` for a reserved region,
+/// and the last entry must be `(offset => @END)`, which gives the total size of the register block.
+/// Entries must be listed in order of increasing offset, and may have doc comments and other attributes.
+///
+/// A register's type must be a [`ReadOnly`], [`WriteOnly`], or [`ReadWrite`] register,
+/// an array of registers, or another register block struct defined with this macro.
+/// Reserved regions are filled with padding bytes up to the next entry's offset.
+///
+/// This checks at compile time that the size of each register matches the distance to the next entry,
+/// and that the total size of the struct matches the `@END` offset,
+/// which fails with an "expected an array with a fixed size of N elements" error if they don't match.
+///
+/// See the [crate-level documentation](index.html) for an example.
+///
+/// [`ReadOnly`]: struct.ReadOnly.html
+/// [`WriteOnly`]: struct.WriteOnly.html
+/// [`ReadWrite`]: struct.ReadWrite.html
+#[macro_export]
+macro_rules! register_structs {
+    ($( $(#[$attr:meta])* $vis:vis $name:ident { $($fields:tt)* } ),* $(,)?) => {
+        $( $crate::__register_struct!(@munch ($(#[$attr])*) ($vis) $name [] $($fields)*); )*
+    };
+}
+
+/// The implementation of [`register_structs!`](macro.register_structs.html), which handles one entry at a time.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __register_struct {
+    // The end of the register block: emit the struct itself.
+    (@munch ($($attr:tt)*) ($vis:vis) $name:ident [$($acc:tt)*]
+        $(#[$($eattr:tt)*])* ($end:expr => @END) $(,)?
+    ) => {
+        $($attr)*
+        #[repr(C)]
+        $vis struct $name {
+            $($acc)*
+        }
+
+        const _: [(); $end] = [(); ::core::mem::size_of::<$name>()];
+
+        unsafe impl $crate::RegisterBlock for $name {}
+    };
+
+    // A register, which must end at the next entry's offset.
+    (@munch $attrs:tt $vis:tt $name:ident [$($acc:tt)*]
+        $(#[$($fattr:tt)*])* ($off:expr => $fvis:vis $fname:ident : $fty:ty),
+        $(#[$($nattr:tt)*])* ($next:expr => $($ntail:tt)*)
+        $($rest:tt)*
+    ) => {
+        const _: [(); $next - $off] = [(); ::core::mem::size_of::<$fty>()];
+        const _: () = {
+            fn assert_register_block<R: $crate::RegisterBlock>() {}
+            #[allow(dead_code)]
+            fn check() { assert_register_block::<$fty>(); }
+        };
+
+        $crate::__register_struct!(@munch $attrs $vis $name
+            [$($acc)* $(#[$($fattr)*])* $fvis $fname: $fty,]
+            $(#[$($nattr)*])* ($next => $($ntail)*)
+            $($rest)*
+        );
+    };
+
+    // A reserved region, which extends until the next entry's offset.
+    (@munch $attrs:tt $vis:tt $name:ident [$($acc:tt)*]
+        $(#[$($fattr:tt)*])* ($off:expr => $fname:ident),
+        $(#[$($nattr:tt)*])* ($next:expr => $($ntail:tt)*)
+        $($rest:tt)*
+    ) => {
+        $crate::__register_struct!(@munch $attrs $vis $name
+            [$($acc)* $(#[$($fattr)*])* $fname: [u8; $next - $off],]
+            $(#[$($nattr)*])* ($next => $($ntail)*)
+            $($rest)*
+        );
+    };
+}
@@ -22,6 +22,8 @@ extern crate path;
 extern crate memfs;
 extern crate cstr_core;
 extern crate hashbrown;
+extern crate rcu;
+extern crate kaslr;
 
 use core::{
     fmt,
@@ -49,6 +51,7 @@ use vfs_node::VFSDirectory;
 use path::Path;
 use memfs::MemFile;
 use hashbrown::HashMap;
+use rcu::Rcu;
 pub use crate_name_utils::{get_containing_crate_name, replace_containing_crate_name, crate_name_from_path};
 pub use crate_metadata::*;
 
@@ -333,12 +336,14 @@ impl Drop for AppCrateRef {
         // First, remove the actual crate from the namespace.
         if let Some(_removed_app_crate) = self.namespace.crate_tree().lock().remove_str(&crate_locked.crate_name) {
             // Second, remove all of the crate's global symbols from the namespace's symbol map.
-            let mut symbol_map = self.namespace.symbol_map().lock();
-            for sec_to_remove in crate_locked.global_sections_iter() {
-                if symbol_map.remove_str(&sec_to_remove.name).is_none() {
-                    error!("NOTE: couldn't find old symbol {:?} in the old crate {:?} to remove from namespace {:?}.", sec_to_remove.name, crate_locked.crate_name, self.namespace.name());
+            let namespace = &self.namespace;
+            namespace.symbol_map().modify(|symbol_map| {
+                for sec_to_remove in crate_locked.global_sections_iter() {
+                    if symbol_map.remove_str(&sec_to_remove.name).is_none() {
+                        error!("NOTE: couldn't find old symbol {:?} in the old crate {:?} to remove from namespace {:?}.", sec_to_remove.name, crate_locked.crate_name, namespace.name());
+                    }
                 }
-            }
+            });
         } else {
             error!("BUG: the dropped AppCrateRef {:?} could not be removed from namespace {:?}", self.crate_ref, self.namespace.name());
         }
@@ -383,7 +388,11 @@ pub struct CrateNamespace {
     /// Maps a fully-qualified symbol name string to a corresponding `LoadedSection`,
     /// which is guaranteed to be part of one of the crates in this `CrateNamespace`.  
     /// Symbols declared as "no_mangle" will appear in the map with no crate prefix, as expected.
-    symbol_map: Mutex<SymbolMap>,
+    /// 
+    /// Because this map is read far more often than it is changed, it is protected by RCU:
+    /// symbol lookups never wait for a lock, even while a crate swap is updating the map,
+    /// whereas changes are made to a copy of the map that then replaces it.
+    symbol_map: Rcu<SymbolMap>,
 
     /// The `CrateNamespace` that lies below this namespace, and can also be used by this namespace
     /// to resolve symbols and load crates that are relied on by other crates in this namespace.
@@ -417,7 +426,7 @@ impl CrateNamespace {
             dir,
             recursive_namespace,
             crate_tree: Mutex::new(Trie::new()),
-            symbol_map: Mutex::new(SymbolMap::new()),
+            symbol_map: Rcu::new(SymbolMap::new()),
             fuzzy_symbol_matching: false,
         }
     } 
@@ -444,7 +453,7 @@ impl CrateNamespace {
     }
 
     #[doc(hidden)]
-    pub fn symbol_map(&self) -> &Mutex<SymbolMap> {
+    pub fn symbol_map(&self) -> &Rcu<SymbolMap> {
         &self.symbol_map
     }
 
@@ -795,9 +804,10 @@ impl CrateNamespace {
         let mut partially_loaded_crates: Vec<(StrongCrateRef, ElfFile)> = Vec::with_capacity(locked_crate_files.len()); 
         for locked_crate_file in &locked_crate_files {            
             let (new_crate_ref, elf_file) = self.load_crate_sections(locked_crate_file.deref(), kernel_mmi_ref, verbose_log)?;
-            let _new_syms = self.add_symbols(new_crate_ref.lock_as_ref().sections.values(), verbose_log);
             partially_loaded_crates.push((new_crate_ref, elf_file));
         }
+        // The symbols of all crates are added at once, such that the symbol map is only copied once.
+        let _new_syms = self.add_symbols_from_crates(partially_loaded_crates.iter().map(|(crate_ref, _)| crate_ref), verbose_log);
         
         // Finally, we do all of the relocations.
         for (new_crate_ref, elf_file) in partially_loaded_crates {
@@ -837,7 +847,7 @@ impl CrateNamespace {
             dir: self.dir.clone(),
             recursive_namespace: self.recursive_namespace.clone(),
             crate_tree: Mutex::new(self.crate_tree.lock().clone()),
-            symbol_map: Mutex::new(self.symbol_map.lock().clone()),
+            symbol_map: Rcu::new(self.symbol_map.read().clone()),
             fuzzy_symbol_matching: self.fuzzy_symbol_matching,
         }
     }
@@ -861,7 +871,9 @@ impl CrateNamespace {
 
             // If the target_sec's mapped pages aren't writable (which is common in the case of swapping),
             // then we need to temporarily remap them as writable here so we can fix up the target_sec's new relocation entry.
+            // They must stay executable meanwhile, since other tasks may be running code in them, so this violates W^X.
             {
+                let _wx_exemption = memory::allow_wx();
                 let mut target_sec_mapped_pages = target_sec.mapped_pages.lock();
                 let target_sec_initial_flags = target_sec_mapped_pages.flags();
                 if !target_sec_initial_flags.is_writable() {
@@ -927,7 +939,12 @@ impl CrateNamespace {
     /// * `crate_file`: the object file for the crate that will be loaded into this `CrateNamespace`.
     /// * `kernel_mmi_ref`: the kernel's MMI struct, for memory mapping use.
     /// * `verbose_log`: whether to log detailed messages for debugging.
-    fn load_crate_sections<'f>(
+    /// 
+    /// This is public only so that crates like `parallel_crate_loader` can split up
+    /// the loading and relocation stages across multiple tasks; 
+    /// most code should use [`load_crate`](#method.load_crate) or [`load_crates`](#method.load_crates) instead.
+    #[doc(hidden)]
+    pub fn load_crate_sections<'f>(
         &self,
         crate_file: &'f dyn File,
         kernel_mmi_ref: &MmiRef,
@@ -1376,7 +1393,11 @@ impl CrateNamespace {
     /// The second stage of parsing and loading a new kernel crate, 
     /// filling in the missing relocation information in the already-loaded sections. 
     /// It also remaps the `new_crate`'s MappedPages according to each of their section permissions.
-    fn perform_relocations(
+    /// 
+    /// Like [`load_crate_sections`](#method.load_crate_sections), this is only public 
+    /// for use by loaders that split the two stages across multiple tasks.
+    #[doc(hidden)]
+    pub fn perform_relocations(
         &self,
         elf_file: &ElfFile,
         new_crate_ref: &StrongCrateRef,
@@ -1545,6 +1566,17 @@ impl CrateNamespace {
         }
         // data/bss sections are already mapped properly, since they're supposed to be writable
 
+        // In debug builds, check that loading this crate didn't leave any mappings both writable and executable.
+        #[cfg(debug_assertions)]
+        {
+            let violations = memory::audit_wx(&kernel_mmi_ref.lock().page_table);
+            if !violations.is_empty() {
+                warn!("perform_relocations(): {} mappings are writable and executable after loading crate {:?}",
+                    violations.len(), new_crate.crate_name
+                );
+            }
+        }
+
 
         // By default, we can safely remove the metadata for all private (non-global) .rodata sections
         // that do not have any strong dependencies (its `sections_i_depend_on` list is empty).
@@ -1631,27 +1663,69 @@ impl CrateNamespace {
         where I: IntoIterator<Item = &'a StrongSectionRef>,
               F: Fn(&LoadedSection) -> bool
     {
-        let mut existing_map = self.symbol_map.lock();
+        // add all the global symbols to a new copy of the symbol map, in a way that lets us inspect/log each one
+        self.symbol_map.modify(|existing_map| {
+            CrateNamespace::add_symbols_to_map(existing_map, sections, filter_func, log_replacements)
+        })
+    }
+
+
+    /// Adds only *global* symbols from all of the given crates to this namespace's symbol map.
+    /// 
+    /// This is equivalent to invoking [`add_symbols()`](#method.add_symbols) on each crate's sections, 
+    /// but the symbol map is only copied and republished once for the entire batch of crates
+    /// rather than once per crate, which matters when many crates are loaded at once, e.g., during boot.
+    /// 
+    /// Returns the number of *new* unique symbols added.
+    pub fn add_symbols_from_crates<'a, I>(
+        &self,
+        crates: I,
+        log_replacements: bool,
+    ) -> usize
+        where I: IntoIterator<Item = &'a StrongCrateRef>,
+    {
+        self.symbol_map.modify(|existing_map| {
+            crates.into_iter()
+                .map(|crate_ref| CrateNamespace::add_symbols_to_map(
+                    existing_map,
+                    crate_ref.lock_as_ref().sections.values(),
+                    |_sec| true,
+                    log_replacements,
+                ))
+                .sum()
+        })
+    }
+
 
-        // add all the global symbols to the symbol map, in a way that lets us inspect/log each one
+    /// Adds symbols in the given `sections` iterator to the given `existing_map`,
+    /// but only sections that are *global* AND for which the given `filter_func` returns true. 
+    /// 
+    /// Returns the number of *new* unique symbols added.
+    fn add_symbols_to_map<'a, I, F>(
+        existing_map: &mut SymbolMap,
+        sections: I,
+        filter_func: F,
+        log_replacements: bool,
+    ) -> usize
+        where I: IntoIterator<Item = &'a StrongSectionRef>,
+              F: Fn(&LoadedSection) -> bool
+    {
         let mut count = 0;
         for sec in sections.into_iter() {
             let condition = filter_func(&sec) && sec.global;
             if condition {
-                // trace!("add_symbols_filtered(): adding symbol {:?}", sec);
-                let added = CrateNamespace::add_symbol(&mut existing_map, sec.name.clone(), sec, log_replacements);
+                // trace!("add_symbols_to_map(): adding symbol {:?}", sec);
+                let added = CrateNamespace::add_symbol(existing_map, sec.name.clone(), sec, log_replacements);
                 if added {
                     count += 1;
                 }
             }
             // else {
-            //     trace!("add_symbols_filtered(): skipping symbol {:?}", sec);
+            //     trace!("add_symbols_to_map(): skipping symbol {:?}", sec);
             // }
         }
-        
         count
     }
-
     
     /// Finds the crate that contains the given `VirtualAddress` in its loaded code.
     /// 
@@ -1768,7 +1842,7 @@ impl CrateNamespace {
 
     /// Like [`get_symbol()`](#method.get_symbol), but also returns the exact `CrateNamespace` where the symbol was found.
     pub fn get_symbol_and_namespace(&self, demangled_full_symbol: &str) -> Option<(WeakSectionRef, &CrateNamespace)> {
-        let weak_symbol = self.symbol_map.lock().get_str(demangled_full_symbol).cloned();
+        let weak_symbol = self.symbol_map.read().get_str(demangled_full_symbol).cloned();
         weak_symbol.map(|sym| (sym, self))
             // search the recursive namespace if the symbol cannot be found in this namespace
             .or_else(|| self.recursive_namespace.as_ref().and_then(|rns| rns.get_symbol_and_namespace(demangled_full_symbol)))
@@ -2021,7 +2095,7 @@ impl CrateNamespace {
     /// Calling `find_symbols_starting_with("my_crate::foo")` will return 
     /// a vector containing both sections, which can then be iterated through.
     pub fn find_symbols_starting_with(&self, symbol_prefix: &str) -> Vec<(String, WeakSectionRef)> { 
-        let mut syms: Vec<(String, WeakSectionRef)> = self.symbol_map.lock()
+        let mut syms: Vec<(String, WeakSectionRef)> = self.symbol_map.read()
             .iter_prefix_str(symbol_prefix)
             .map(|(k, v)| (String::from(k.as_str()), v.clone()))
             .collect();
@@ -2037,7 +2111,7 @@ impl CrateNamespace {
     /// Similar to `find_symbols_starting_with`, but also includes a reference to the exact `CrateNamespace`
     /// where the matching symbol was found.
     pub fn find_symbols_starting_with_and_namespace(&self, symbol_prefix: &str) -> Vec<(String, WeakSectionRef, &CrateNamespace)> { 
-        let mut syms: Vec<(String, WeakSectionRef, &CrateNamespace)> = self.symbol_map.lock()
+        let mut syms: Vec<(String, WeakSectionRef, &CrateNamespace)> = self.symbol_map.read()
             .iter_prefix_str(symbol_prefix)
             .map(|(k, v)| (String::from(k.as_str()), v.clone(), self))
             .collect();
@@ -2084,7 +2158,7 @@ impl CrateNamespace {
     /// that returns an Option to allow easier recursive use.
     fn get_symbol_starting_with_internal(&self, symbol_prefix: &str) -> Option<WeakSectionRef> { 
         // First, we see if there's a single matching symbol in this namespace. 
-        let map = self.symbol_map.lock();
+        let map = self.symbol_map.read();
         let mut iter = map.iter_prefix_str(symbol_prefix).map(|tuple| tuple.1);
         let symbol_in_this_namespace = iter.next()
             .filter(|_| iter.next().is_none()) // ensure single element
@@ -2103,7 +2177,7 @@ impl CrateNamespace {
     pub fn dump_symbol_map(&self) -> String {
         use core::fmt::Write;
         let mut output: String = String::new();
-        let sysmap = self.symbol_map.lock();
+        let sysmap = self.symbol_map.read();
         match write!(&mut output, "{:?}", sysmap.keys().collect::<Vec<_>>()) {
             Ok(_) => output,
             _ => String::from("(error)"),
@@ -2223,7 +2297,8 @@ fn allocate_section_pages(elf_file: &ElfFile, kernel_mmi_ref: &MmiRef) -> Result
 fn allocate_and_map_as_writable(size_in_bytes: usize, flags: EntryFlags, kernel_mmi_ref: &MmiRef) -> Result<MappedPages, &'static str> {
     let frame_allocator = get_frame_allocator_ref().ok_or("couldn't get frame allocator")?;
     let allocated_pages = allocate_pages_by_bytes(size_in_bytes).ok_or("Couldn't allocate_pages_by_bytes, out of virtual address space")?;
-    kernel_mmi_ref.lock().page_table.map_allocated_pages(allocated_pages, flags | EntryFlags::PRESENT | EntryFlags::WRITABLE, frame_allocator.lock().deref_mut())
+    // The pages are non-executable until they're remapped with their final flags, such that they never violate W^X.
+    kernel_mmi_ref.lock().page_table.map_allocated_pages(allocated_pages, flags | EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE, frame_allocator.lock().deref_mut())
 }
 
 
@@ -41,6 +41,13 @@ macro_rules! try_mp {
 }
 
 
+/// Converts the given link-time address in one of the nano_core's higher-half sections
+/// into the address at which it actually is, since the boot code may have moved those sections, see the `kaslr` crate.
+fn runtime_vaddr(link_vaddr: usize) -> Result<VirtualAddress, &'static str> {
+    VirtualAddress::new(link_vaddr + kaslr::nano_core_slide())
+}
+
+
 /// Convenience function for calculating the address range of a MappedPages object.
 fn mp_range(mp_ref: &Arc<Mutex<MappedPages>>) -> Range<VirtualAddress> {
     let mp = mp_ref.lock();
@@ -182,7 +189,7 @@ fn parse_nano_core_symbol_file(
         let size_hex_str = tokens.next();
         // parse both the Address and Size fields as hex strings
         addr_hex_str.and_then(|a| usize::from_str_radix(a, 16).ok())
-            .and_then(|addr| VirtualAddress::new(addr).ok())
+            .and_then(|addr| runtime_vaddr(addr).ok())
             .and_then(|vaddr| {
                 size_hex_str.and_then(|s| usize::from_str_radix(s, 16).ok())
                     .and_then(|size| Some((vaddr, size)))
@@ -450,7 +457,7 @@ fn parse_nano_core_binary(
                 bss_shndx = Some(shndx);
             }
             Ok(".gcc_except_table") => {
-                let sec_vaddr = VirtualAddress::new(sec.address() as usize)?;
+                let sec_vaddr = runtime_vaddr(sec.address() as usize)?;
                 let mapped_pages_offset = rodata_pages.lock().offset_of_address(sec_vaddr)
                     .ok_or("the nano_core .gcc_except_table section wasn't covered by the read-only mapped pages!")?;
                 crate_items.sections.insert(
@@ -469,7 +476,7 @@ fn parse_nano_core_binary(
                 section_counter += 1;
             }
             Ok(".eh_frame") => {
-                let sec_vaddr = VirtualAddress::new(sec.address() as usize)?;
+                let sec_vaddr = runtime_vaddr(sec.address() as usize)?;
                 let mapped_pages_offset = rodata_pages.lock().offset_of_address(sec_vaddr)
                     .ok_or("the nano_core .eh_frame section wasn't covered by the read-only mapped pages!")?;
                 crate_items.sections.insert(
@@ -595,7 +602,7 @@ fn add_new_section(
     global: bool,
 ) -> Result<(), &'static str> {
     let new_section = if sec_ndx == shndxs.text_shndx {
-        let sec_vaddr = VirtualAddress::new(sec_vaddr)?;
+        let sec_vaddr = runtime_vaddr(sec_vaddr)?;
         Some(LoadedSection::new(
             SectionType::Text,
             sec_name,
@@ -608,7 +615,7 @@ fn add_new_section(
         ))
     }
     else if sec_ndx == shndxs.rodata_shndx {
-        let sec_vaddr = VirtualAddress::new(sec_vaddr)?;
+        let sec_vaddr = runtime_vaddr(sec_vaddr)?;
         Some(LoadedSection::new(
             SectionType::Rodata,
             sec_name,
@@ -621,7 +628,7 @@ fn add_new_section(
         ))
     }
     else if sec_ndx == shndxs.data_shndx {
-        let sec_vaddr = VirtualAddress::new(sec_vaddr)?;
+        let sec_vaddr = runtime_vaddr(sec_vaddr)?;
         Some(LoadedSection::new(
             SectionType::Data,
             sec_name,
@@ -634,7 +641,7 @@ fn add_new_section(
         ))
     }
     else if sec_ndx == shndxs.bss_shndx {
-        let sec_vaddr = VirtualAddress::new(sec_vaddr)?;
+        let sec_vaddr = runtime_vaddr(sec_vaddr)?;
         Some(LoadedSection::new(
             SectionType::Bss,
             sec_name,
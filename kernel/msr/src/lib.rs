@@ -0,0 +1,341 @@
+//! Validated access to x86 model-specific registers (MSRs) on any core, with caching of read-mostly MSRs.
+//!
+//! Only the MSRs listed in [`KNOWN_MSRS`] can be accessed, and only in the ways that their [`MsrAccess`] allows,
+//! such that a typo'd index or a write to a read-only MSR is reported as an error instead of causing a general protection fault.
+//! MSRs that are set up once during boot, e.g., `IA32_EFER` and the syscall MSRs, can be read but not written here.
+//!
+//! MSRs are per-core, so [`read()`], [`write()`], and [`modify()`] access the current core's MSRs,
+//! while [`read_on_core()`], [`write_on_core()`], and [`modify_all_cores()`] access other cores' MSRs
+//! by running the access in a task pinned on each target core and waiting for it to complete.
+//! The cross-core functions must therefore be invoked from a regular task with interrupts enabled.
+//!
+//! The values of read-mostly MSRs, e.g., `IA32_ARCH_CAPABILITIES` or `IA32_PERF_CTL`, are cached per core after they are first read,
+//! and the cache is updated on every write through this crate.
+//! Thus, such MSRs must not be written via raw `wrmsr` instructions elsewhere.
+//!
+//! [`KNOWN_MSRS`]: constant.KNOWN_MSRS.html
+//! [`MsrAccess`]: enum.MsrAccess.html
+//! [`read()`]: fn.read.html
+//! [`write()`]: fn.write.html
+//! [`modify()`]: fn.modify.html
+//! [`read_on_core()`]: fn.read_on_core.html
+//! [`write_on_core()`]: fn.write_on_core.html
+//! [`modify_all_cores()`]: fn.modify_all_cores.html
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate irq_safety;
+extern crate x86_64;
+extern crate apic;
+extern crate task;
+extern crate spawn;
+
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use irq_safety::MutexIrqSafe;
+use task::{ExitValue, TaskRef};
+
+
+pub const IA32_TIME_STAMP_COUNTER: u32 = 0x10;
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const IA32_FEATURE_CONTROL: u32 = 0x3A;
+pub const IA32_SPEC_CTRL: u32 = 0x48;
+pub const IA32_PRED_CMD: u32 = 0x49;
+pub const IA32_PMC0: u32 = 0xC1;
+pub const IA32_MPERF: u32 = 0xE7;
+pub const IA32_APERF: u32 = 0xE8;
+pub const IA32_ARCH_CAPABILITIES: u32 = 0x10A;
+pub const IA32_FLUSH_CMD: u32 = 0x10B;
+pub const IA32_PERFEVTSEL0: u32 = 0x186;
+pub const IA32_PERF_STATUS: u32 = 0x198;
+pub const IA32_PERF_CTL: u32 = 0x199;
+pub const IA32_CLOCK_MODULATION: u32 = 0x19A;
+pub const IA32_THERM_INTERRUPT: u32 = 0x19B;
+pub const IA32_THERM_STATUS: u32 = 0x19C;
+pub const IA32_MISC_ENABLE: u32 = 0x1A0;
+pub const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+pub const IA32_ENERGY_PERF_BIAS: u32 = 0x1B0;
+pub const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+pub const IA32_PACKAGE_THERM_INTERRUPT: u32 = 0x1B2;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_FIXED_CTR0: u32 = 0x309;
+pub const IA32_PERF_CAPABILITIES: u32 = 0x345;
+pub const IA32_FIXED_CTR_CTRL: u32 = 0x38D;
+pub const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+pub const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+pub const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_STAR: u32 = 0xC000_0081;
+pub const IA32_LSTAR: u32 = 0xC000_0082;
+pub const IA32_FMASK: u32 = 0xC000_0084;
+pub const IA32_FS_BASE: u32 = 0xC000_0100;
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+pub const IA32_TSC_AUX: u32 = 0xC000_0103;
+
+
+/// How an MSR may be accessed through this crate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsrAccess {
+    /// The MSR can only be read.
+    ReadOnly,
+    /// The MSR can only be written, e.g., a command MSR like `IA32_PRED_CMD`.
+    WriteOnly,
+    /// The MSR can be read and written.
+    ReadWrite,
+}
+
+/// A description of a known MSR, or of a contiguous block of identical MSRs like `IA32_PMC0` through `IA32_PMC7`.
+#[derive(Clone, Copy, Debug)]
+pub struct MsrInfo {
+    /// The index of the first MSR in this block.
+    pub index: u32,
+    /// The number of consecutive MSRs in this block, which is `1` for a single MSR.
+    pub count: u32,
+    /// The architectural name of the (first) MSR.
+    pub name: &'static str,
+    /// How the MSR may be accessed.
+    pub access: MsrAccess,
+    /// Whether the MSR's value is read-mostly, such that it is cached after it is first read.
+    pub cached: bool,
+}
+
+impl MsrInfo {
+    const fn new(index: u32, count: u32, name: &'static str, access: MsrAccess, cached: bool) -> MsrInfo {
+        MsrInfo { index, count, name, access, cached }
+    }
+
+    fn contains(&self, index: u32) -> bool {
+        index >= self.index && index - self.index < self.count
+    }
+}
+
+/// All MSRs that can be accessed through this crate.
+pub const KNOWN_MSRS: [MsrInfo; 36] = [
+    MsrInfo::new(IA32_TIME_STAMP_COUNTER,      1, "IA32_TIME_STAMP_COUNTER",      MsrAccess::ReadOnly,  false),
+    MsrInfo::new(IA32_APIC_BASE,               1, "IA32_APIC_BASE",               MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_FEATURE_CONTROL,         1, "IA32_FEATURE_CONTROL",         MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_SPEC_CTRL,               1, "IA32_SPEC_CTRL",               MsrAccess::ReadWrite, true),
+    MsrInfo::new(IA32_PRED_CMD,                1, "IA32_PRED_CMD",                MsrAccess::WriteOnly, false),
+    MsrInfo::new(IA32_PMC0,                    8, "IA32_PMC0",                    MsrAccess::ReadWrite, false),
+    MsrInfo::new(IA32_MPERF,                   1, "IA32_MPERF",                   MsrAccess::ReadWrite, false),
+    MsrInfo::new(IA32_APERF,                   1, "IA32_APERF",                   MsrAccess::ReadWrite, false),
+    MsrInfo::new(IA32_ARCH_CAPABILITIES,       1, "IA32_ARCH_CAPABILITIES",       MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_FLUSH_CMD,               1, "IA32_FLUSH_CMD",               MsrAccess::WriteOnly, false),
+    MsrInfo::new(IA32_PERFEVTSEL0,             8, "IA32_PERFEVTSEL0",             MsrAccess::ReadWrite, false),
+    MsrInfo::new(IA32_PERF_STATUS,             1, "IA32_PERF_STATUS",             MsrAccess::ReadOnly,  false),
+    MsrInfo::new(IA32_PERF_CTL,                1, "IA32_PERF_CTL",                MsrAccess::ReadWrite, true),
+    MsrInfo::new(IA32_CLOCK_MODULATION,        1, "IA32_CLOCK_MODULATION",        MsrAccess::ReadWrite, true),
+    MsrInfo::new(IA32_THERM_INTERRUPT,         1, "IA32_THERM_INTERRUPT",         MsrAccess::ReadWrite, true),
+    MsrInfo::new(IA32_THERM_STATUS,            1, "IA32_THERM_STATUS",            MsrAccess::ReadWrite, false),
+    MsrInfo::new(IA32_MISC_ENABLE,             1, "IA32_MISC_ENABLE",             MsrAccess::ReadWrite, true),
+    MsrInfo::new(MSR_TEMPERATURE_TARGET,       1, "MSR_TEMPERATURE_TARGET",       MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_ENERGY_PERF_BIAS,        1, "IA32_ENERGY_PERF_BIAS",        MsrAccess::ReadWrite, true),
+    MsrInfo::new(IA32_PACKAGE_THERM_STATUS,    1, "IA32_PACKAGE_THERM_STATUS",    MsrAccess::ReadWrite, false),
+    MsrInfo::new(IA32_PACKAGE_THERM_INTERRUPT, 1, "IA32_PACKAGE_THERM_INTERRUPT", MsrAccess::ReadWrite, true),
+    MsrInfo::new(IA32_PAT,                     1, "IA32_PAT",                     MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_FIXED_CTR0,              3, "IA32_FIXED_CTR0",              MsrAccess::ReadWrite, false),
+    MsrInfo::new(IA32_PERF_CAPABILITIES,       1, "IA32_PERF_CAPABILITIES",       MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_FIXED_CTR_CTRL,          1, "IA32_FIXED_CTR_CTRL",          MsrAccess::ReadWrite, false),
+    MsrInfo::new(IA32_PERF_GLOBAL_STATUS,      1, "IA32_PERF_GLOBAL_STATUS",      MsrAccess::ReadOnly,  false),
+    MsrInfo::new(IA32_PERF_GLOBAL_CTRL,        1, "IA32_PERF_GLOBAL_CTRL",        MsrAccess::ReadWrite, false),
+    MsrInfo::new(IA32_PERF_GLOBAL_OVF_CTRL,    1, "IA32_PERF_GLOBAL_OVF_CTRL",    MsrAccess::WriteOnly, false),
+    MsrInfo::new(IA32_EFER,                    1, "IA32_EFER",                    MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_STAR,                    1, "IA32_STAR",                    MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_LSTAR,                   1, "IA32_LSTAR",                   MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_FMASK,                   1, "IA32_FMASK",                   MsrAccess::ReadOnly,  true),
+    MsrInfo::new(IA32_FS_BASE,                 1, "IA32_FS_BASE",                 MsrAccess::ReadOnly,  false),
+    MsrInfo::new(IA32_GS_BASE,                 1, "IA32_GS_BASE",                 MsrAccess::ReadOnly,  false),
+    MsrInfo::new(IA32_KERNEL_GS_BASE,          1, "IA32_KERNEL_GS_BASE",          MsrAccess::ReadOnly,  false),
+    MsrInfo::new(IA32_TSC_AUX,                 1, "IA32_TSC_AUX",                 MsrAccess::ReadOnly,  true),
+];
+
+
+/// The cached values of read-mostly MSRs, keyed by (APIC ID, MSR index).
+static CACHE: MutexIrqSafe<BTreeMap<(u8, u32), u64>> = MutexIrqSafe::new(BTreeMap::new());
+
+/// The result of an MSR access on another core, which is the exit value of the task that performed it.
+type MsrResult = Result<u64, &'static str>;
+
+
+/// Returns the description of the given MSR `index`, if it is known.
+pub fn msr_info(index: u32) -> Option<&'static MsrInfo> {
+    KNOWN_MSRS.iter().find(|info| info.contains(index))
+}
+
+/// Returns the description of the given MSR `index`, checking that it is known and can be accessed as requested.
+fn checked_info(index: u32, write: bool) -> Result<&'static MsrInfo, &'static str> {
+    let info = msr_info(index).ok_or_else(|| {
+        error!("msr: MSR {:#X} is not a known MSR", index);
+        "msr: unknown MSR index"
+    })?;
+    match (info.access, write) {
+        (MsrAccess::ReadOnly, true) => {
+            error!("msr: {} ({:#X}) cannot be written", info.name, index);
+            Err("msr: MSR cannot be written")
+        }
+        (MsrAccess::WriteOnly, false) => {
+            error!("msr: {} ({:#X}) cannot be read", info.name, index);
+            Err("msr: MSR cannot be read")
+        }
+        _ => Ok(info),
+    }
+}
+
+
+/// Reads the given MSR on the current core, or returns its cached value if it is read-mostly.
+pub fn read(index: u32) -> Result<u64, &'static str> {
+    let info = checked_info(index, false)?;
+    if !info.cached {
+        return Ok(x86_64::registers::msr::rdmsr(index));
+    }
+    // Hold the cache lock while reading, such that this core can't be switched away from in between.
+    let mut cache = CACHE.lock();
+    let value = *cache.entry((apic::get_my_apic_id(), index))
+        .or_insert_with(|| x86_64::registers::msr::rdmsr(index));
+    Ok(value)
+}
+
+/// Writes the given `value` to the given MSR on the current core.
+pub fn write(index: u32, value: u64) -> Result<(), &'static str> {
+    let info = checked_info(index, true)?;
+    if !info.cached {
+        // SAFE: the MSR is known to exist and be writable
+        unsafe { x86_64::registers::msr::wrmsr(index, value) };
+        return Ok(());
+    }
+    let mut cache = CACHE.lock();
+    // SAFE: the MSR is known to exist and be writable
+    unsafe { x86_64::registers::msr::wrmsr(index, value) };
+    cache.insert((apic::get_my_apic_id(), index), value);
+    Ok(())
+}
+
+/// Reads the given MSR on the current core, passes its value into the given function `f`,
+/// and writes the value that `f` returns back to the MSR.
+pub fn modify<F: FnOnce(u64) -> u64>(index: u32, f: F) -> Result<(), &'static str> {
+    checked_info(index, false)?;
+    checked_info(index, true)?;
+    // Hold interrupts, such that this read-modify-write sequence isn't interrupted by another one on this core.
+    let _held_interrupts = irq_safety::hold_interrupts();
+    let value = read(index)?;
+    write(index, f(value))
+}
+
+
+/// Reads the given MSR on the core with the given APIC ID.
+pub fn read_on_core(core: u8, index: u32) -> Result<u64, &'static str> {
+    checked_info(index, false)?;
+    run_on_cores(&[core], MsrOp::Read(index)).map(|values| values[0])
+}
+
+/// Writes the given `value` to the given MSR on the core with the given APIC ID.
+pub fn write_on_core(core: u8, index: u32, value: u64) -> Result<(), &'static str> {
+    checked_info(index, true)?;
+    run_on_cores(&[core], MsrOp::Write(index, value)).map(|_| ())
+}
+
+/// Writes the given `value` to the given MSR on every core.
+pub fn write_all_cores(index: u32, value: u64) -> Result<(), &'static str> {
+    checked_info(index, true)?;
+    run_on_cores(&all_cores(), MsrOp::Write(index, value)).map(|_| ())
+}
+
+/// Modifies the given MSR on every core, as in [`modify()`](fn.modify.html).
+pub fn modify_all_cores(index: u32, f: fn(u64) -> u64) -> Result<(), &'static str> {
+    checked_info(index, false)?;
+    checked_info(index, true)?;
+    run_on_cores(&all_cores(), MsrOp::Modify(index, f)).map(|_| ())
+}
+
+/// Reads the given MSR on every core, returning each core's APIC ID and value.
+pub fn read_all_cores(index: u32) -> Result<Vec<(u8, u64)>, &'static str> {
+    checked_info(index, false)?;
+    let cores = all_cores();
+    let values = run_on_cores(&cores, MsrOp::Read(index))?;
+    Ok(cores.into_iter().zip(values).collect())
+}
+
+
+/// An access to an MSR that is performed on a specific core.
+#[derive(Clone, Copy)]
+enum MsrOp {
+    Read(u32),
+    Write(u32, u64),
+    Modify(u32, fn(u64) -> u64),
+}
+
+impl MsrOp {
+    /// Performs this access on the current core, returning the MSR's value afterwards (or the value written).
+    fn run(self) -> MsrResult {
+        match self {
+            MsrOp::Read(index) => read(index),
+            MsrOp::Write(index, value) => write(index, value).map(|_| value),
+            MsrOp::Modify(index, f) => {
+                let _held_interrupts = irq_safety::hold_interrupts();
+                let value = f(read(index)?);
+                write(index, value).map(|_| value)
+            }
+        }
+    }
+}
+
+fn all_cores() -> Vec<u8> {
+    apic::get_lapics().iter().map(|(apic_id, _lapic)| *apic_id).collect()
+}
+
+/// Performs the given `op` on each of the given `cores`, returning the resulting values in the same order.
+///
+/// The op is performed directly on the current core, and by a task pinned on each other core.
+/// All of those tasks are spawned before waiting for any of them, so the cores perform the op concurrently.
+fn run_on_cores(cores: &[u8], op: MsrOp) -> Result<Vec<u64>, &'static str> {
+    let my_core = apic::get_my_apic_id();
+    let mut workers: Vec<Option<TaskRef>> = Vec::with_capacity(cores.len());
+    let mut spawn_result = Ok(());
+    for &core in cores {
+        if core == my_core {
+            workers.push(None);
+            continue;
+        }
+        match spawn::new_task_builder(MsrOp::run, op).name(format!("msr_core_{}", core)).pin_on_core(core).spawn() {
+            Ok(taskref) => workers.push(Some(taskref)),
+            Err(e) => {
+                spawn_result = Err(e);
+                break;
+            }
+        }
+    }
+
+    // Join every worker that was spawned, even if spawning another one failed.
+    let mut values = Vec::with_capacity(workers.len());
+    let mut result = Ok(());
+    for worker in workers {
+        let value = match worker {
+            None => op.run(),
+            Some(worker) => join_worker(worker),
+        };
+        match value {
+            Ok(value) => values.push(value),
+            Err(e) => if result.is_ok() { result = Err(e); },
+        }
+    }
+    spawn_result?;
+    result?;
+    Ok(values)
+}
+
+fn join_worker(worker: TaskRef) -> MsrResult {
+    worker.join()?;
+    match worker.take_exit_value() {
+        Some(ExitValue::Completed(exit_value)) => exit_value
+            .downcast_ref::<MsrResult>()
+            .cloned()
+            .unwrap_or(Err("BUG: msr worker returned an unexpected exit value type")),
+        Some(ExitValue::Killed(_kill_reason)) => Err("msr worker task was killed"),
+        None => Err("BUG: msr worker had no exit value"),
+    }
+}
@@ -101,6 +101,9 @@ pub fn handle_ap_cores(
         let ap_startup_frames = FrameRange::from_phys_addr(PhysicalAddress::new_canonical(AP_STARTUP), ap_startup_size_in_bytes);
         let ap_startup_pages  = memory::allocate_pages_at(VirtualAddress::new_canonical(AP_STARTUP), ap_startup_frames.size_in_frames())
             .map_err(|_e| "handle_ap_cores(): failed to allocate AP startup pages")?;
+        // Ensure the frame allocator never hands out these frames, since APs may use them at any point while booting.
+        memory::reserve_physical_region(PhysicalAddress::new_canonical(TRAMPOLINE), PAGE_SIZE, "AP trampoline")?;
+        memory::reserve_physical_region(PhysicalAddress::new_canonical(AP_STARTUP), ap_startup_size_in_bytes, "AP startup code")?;
         let mut allocator = frame_allocator_ref.lock();
         
         trampoline_mapped_pages = page_table.map_allocated_pages_to(
@@ -14,10 +14,21 @@
 //! 
 //! The per-core heap which will be used on allocation is determined by the cpu that the task is running on.
 //! On deallocation of a block, the heap id is retrieved from metadata at the end of the allocable page which contains the block.
+//! A block that is deallocated on a different core than the one whose heap it belongs to isn't returned to that heap directly,
+//! since that would contend for the other core's heap lock. Instead, it is pushed onto that heap's lock-free remote-free queue
+//! for its size class, and the owning core returns it to its heap the next time it allocates from that size class.
+//! 
+//! There is also a shared overflow heap, which is used by cores that don't have their own heap,
+//! and when a core's own heap is momentarily locked by another core or can't be grown.
 //! 
 //! When a per-core heap runs out of memory, pages are first moved between the slab allocators of the per-core heap, then requested from other per-core heaps.
 //! If no empty pages are available within any of the per-core heaps, then more virtual pages are allocated from the range of virtual addresses dedicated to the heap
-//! [KERNEL_HEAP_START](../kernel_config/memory/constant.KERNEL_HEAP_START.html) and dynamically mapped to physical memory frames.
+//! [KERNEL_HEAP_START](../kernel_config/memory/constant.KERNEL_HEAP_START.html) (plus a random offset, see the `kaslr` crate)
+//! and dynamically mapped to physical memory frames.
+//! The heaps can't take more memory from the OS than the `heap.max_size_mib` tunable allows, see [`MAX_SIZE_MIB`].
+//! Empty pages are only returned to the OS by `heap::release_empty_slabs()`, e.g., by the `heap_shrinker` once the heaps are idle.
+//!
+//! [`MAX_SIZE_MIB`]: static.MAX_SIZE_MIB.html
 
 #![feature(const_fn)]
 #![feature(allocator_api)]
@@ -29,9 +40,12 @@ extern crate irq_safety;
 extern crate memory;
 extern crate page_allocator;
 extern crate kernel_config;
+extern crate kaslr;
 extern crate apic;
+extern crate cpu_topology;
 extern crate heap;
 extern crate hashbrown;
+extern crate config_registry;
 #[macro_use] extern crate cfg_if;
 
 #[cfg(all(not(unsafe_heap), not(safe_heap)))]
@@ -46,14 +60,17 @@ extern crate slabmalloc_safe;
 use core::ptr::NonNull;
 use alloc::alloc::{GlobalAlloc, Layout};
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use hashbrown::HashMap;
 use memory::{MappedPages, VirtualAddress, get_frame_allocator_ref, get_kernel_mmi_ref, create_mapping};
-use kernel_config::memory::{PAGE_SIZE, KERNEL_HEAP_START, KERNEL_HEAP_INITIAL_SIZE};
+use kernel_config::memory::{PAGE_SIZE, KERNEL_HEAP_START, KERNEL_HEAP_INITIAL_SIZE, KERNEL_HEAP_MAX_SIZE};
 use core::ops::{Deref, DerefMut};
 use core::ptr;
-use heap::HEAP_FLAGS;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use heap::{HEAP_FLAGS, KernelAllocator, ArenaStats, SizeClassStats, OVERFLOW_ARENA_ID};
 use irq_safety::MutexIrqSafe;
 use page_allocator::{DeferredAllocAction, allocate_pages_by_bytes_deferred};
+use config_registry::Tunable;
 
 #[cfg(all(not(unsafe_heap), not(safe_heap)))]
 use slabmalloc::{ZoneAllocator, ObjectPage8k, AllocablePage, MappedPages8k};
@@ -94,6 +111,55 @@ pub const PER_CORE_HEAP_INITIAL_SIZE_PAGES: usize = ZoneAllocator::MAX_BASE_SIZE
 /// `(3 * HEAP_GROWTH_AMOUNT * sizeof(Chunk)` bytes must fit within one 8KiB heap page set.
 const HEAP_GROWTH_AMOUNT: usize = 2;
 
+/// The size of the heap memory area beyond the initial heap, in MiB, which is the most that the heaps can ever grow to.
+const HEAP_MEMORY_AREA_SIZE_MIB: u64 = ((KERNEL_HEAP_MAX_SIZE - KERNEL_HEAP_INITIAL_SIZE) / (1024 * 1024)) as u64;
+
+/// The maximum amount of memory that the per-core heaps and the overflow heap can hold together, in MiB.
+/// Once they have reached this size, a heap that runs out of memory can only take empty pages from the other heaps.
+/// By default, the heaps can grow until the heap memory area is exhausted.
+pub static MAX_SIZE_MIB: Tunable = Tunable::new(
+    "heap.max_size_mib", "the maximum amount of memory that the heaps can take from the OS, in MiB",
+    HEAP_MEMORY_AREA_SIZE_MIB, 1, HEAP_MEMORY_AREA_SIZE_MIB,
+);
+
+/// The number of bytes in the pages that are currently mapped for the heaps.
+static MAPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// The number of heap page sets that have been allocated from the OS to grow the heaps since boot.
+static GROWTH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Registers this crate's tunables with the `config_registry`, such that they can be changed at runtime.
+///
+/// Until this is invoked, the tunables keep their default values.
+pub fn register_tunables() -> Result<(), &'static str> {
+    config_registry::register(&MAX_SIZE_MIB, None)
+}
+
+/// Returns the number of bytes in the pages that are currently mapped for the per-core heaps and the overflow heap,
+/// including their free memory, but excluding the initial heap and large allocations.
+pub fn mapped_bytes() -> usize {
+    MAPPED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Returns the number of times since boot that a heap was grown by a page set that was allocated from the OS,
+/// which stays the same while the heaps can serve every allocation from the memory they already have.
+pub fn growth_count() -> usize {
+    GROWTH_COUNT.load(Ordering::Relaxed)
+}
+
+/// Returns an error if the heaps can't be grown by another `size_in_bytes` bytes starting at `heap_end`,
+/// either because that's beyond the heap memory area or because the heaps would exceed `MAX_SIZE_MIB`.
+fn check_growth(heap_end: VirtualAddress, size_in_bytes: usize) -> Result<(), &'static str> {
+    if heap_end.value() + size_in_bytes > KERNEL_HEAP_START + KERNEL_HEAP_MAX_SIZE {
+        return Err("multiple_heaps: there is no more memory to be allocated in the heap memory area");
+    }
+    let max_bytes = MAX_SIZE_MIB.get() as usize * 1024 * 1024;
+    if MAPPED_BYTES.load(Ordering::Relaxed) + size_in_bytes > max_bytes {
+        return Err("multiple_heaps: the heaps have reached their maximum size, see the `heap.max_size_mib` tunable");
+    }
+    Ok(())
+}
+
 /// Creates and initializes the multiple heaps using the apic id as the key, which is mapped to a heap.
 /// If we want to change the value the heap id is based on, we would substitute 
 /// the lapic iterator with an iterator containing the desired keys.
@@ -103,6 +169,7 @@ fn initialize_multiple_heaps() -> Result<MultipleHeaps, &'static str> {
     for (apic_id, _lapic) in apic::get_lapics().iter() {
         init_individual_heap(*apic_id as usize, &mut multiple_heaps)?;
     }
+    init_individual_heap(OVERFLOW_HEAP_ID, &mut multiple_heaps)?;
 
     Ok(multiple_heaps)       
 }
@@ -194,6 +261,7 @@ if #[cfg(unsafe_heap)] {
 
                 // update the end address of the heap
                 heap_end_addr += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+                MAPPED_BYTES.fetch_add(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
                 // trace!("Added an object page {:#X} to slab of size {}", addr, sizes[slab]);
             }
         }
@@ -202,7 +270,7 @@ if #[cfg(unsafe_heap)] {
         *heap_end = heap_end_addr;
 
         // store the newly created allocator in the multiple heaps object
-        if let Some(_heap) = multiple_heaps.heaps.insert(key, LockedHeap(MutexIrqSafe::new(zone_allocator))) {
+        if let Some(_heap) = multiple_heaps.heaps.insert(key, LockedHeap(MutexIrqSafe::new(zone_allocator), RemoteFrees::new())) {
             return Err("New heap created with a previously used id");
         }
         trace!("Created heap {} with max alloc size: {} bytes", key, ZoneAllocator::MAX_ALLOC_SIZE);
@@ -243,6 +311,7 @@ if #[cfg(unsafe_heap)] {
                 // update the end address of the heap
                 // trace!("Added an object page {:#X} to slab of size {}", heap_end_addr, size);
                 heap_end_addr += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+                MAPPED_BYTES.fetch_add(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
             }
         }
 
@@ -250,7 +319,7 @@ if #[cfg(unsafe_heap)] {
         *heap_end = heap_end_addr;
 
         // store the newly created allocator in the multiple heaps object
-        if let Some(_heap) = multiple_heaps.heaps.insert(key, LockedHeap(MutexIrqSafe::new(zone_allocator))) {
+        if let Some(_heap) = multiple_heaps.heaps.insert(key, LockedHeap(MutexIrqSafe::new(zone_allocator), RemoteFrees::new())) {
             return Err("New heap created with a previously used id");
         }
         trace!("Created heap {} with max alloc size: {} bytes", key, ZoneAllocator::MAX_ALLOC_SIZE);
@@ -274,7 +343,7 @@ fn get_key() -> usize {
 cfg_if! {
 if #[cfg(safe_heap)] {
     #[repr(align(64))]
-    struct LockedHeap (MutexIrqSafe<ZoneAllocator>);
+    struct LockedHeap (MutexIrqSafe<ZoneAllocator>, RemoteFrees);
 
     impl Deref for LockedHeap {
         type Target = MutexIrqSafe<ZoneAllocator>;
@@ -284,7 +353,7 @@ if #[cfg(safe_heap)] {
     }
 } else {
     #[repr(align(64))]
-    struct LockedHeap (MutexIrqSafe<ZoneAllocator<'static>>);
+    struct LockedHeap (MutexIrqSafe<ZoneAllocator<'static>>, RemoteFrees);
 
     impl Deref for LockedHeap {
         type Target = MutexIrqSafe<ZoneAllocator<'static>>;
@@ -296,6 +365,78 @@ if #[cfg(safe_heap)] {
 } // end cfg_if for LockedHeap versions
 
 
+/// The key of the shared overflow heap, which is also the heap id stored in the metadata of its pages.
+const OVERFLOW_HEAP_ID: usize = OVERFLOW_ARENA_ID;
+
+const EMPTY_REMOTE_FREE_LIST: AtomicUsize = AtomicUsize::new(0);
+
+/// The blocks that other cores have deallocated but not yet returned to a heap, with one lock-free list per size class.
+/// The first word of each block holds the address of the next block in its list, or `0` at the end of the list.
+struct RemoteFrees {
+    lists: [AtomicUsize; ZoneAllocator::MAX_BASE_SIZE_CLASSES],
+    /// The number of blocks that other cores have deallocated into this heap since boot.
+    count: AtomicUsize,
+}
+
+impl RemoteFrees {
+    fn new() -> RemoteFrees {
+        RemoteFrees {
+            lists: [EMPTY_REMOTE_FREE_LIST; ZoneAllocator::MAX_BASE_SIZE_CLASSES],
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the index of the size class that blocks of the given size are allocated from.
+    fn size_class(size: usize) -> usize {
+        ZoneAllocator::BASE_ALLOC_SIZES.iter().position(|class_size| size <= *class_size)
+            .unwrap_or(ZoneAllocator::MAX_BASE_SIZE_CLASSES - 1)
+    }
+
+    /// Pushes the given deallocated block of the given size onto the list of its size class.
+    /// 
+    /// # Safety
+    /// The block must belong to this heap and not be in use anymore.
+    unsafe fn push(&self, ptr: *mut u8, size: usize) {
+        let list = &self.lists[RemoteFrees::size_class(size)];
+        let mut head = list.load(Ordering::Acquire);
+        loop {
+            *(ptr as *mut usize) = head;
+            match list.compare_exchange_weak(head, ptr as usize, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl LockedHeap {
+    /// Returns every block on the remote-free list of the given size class to this heap,
+    /// which is given by `heap`, i.e., this heap's lock must be held.
+    fn drain_remote_frees(&self, heap: &mut ZoneAllocator, size_class: usize) {
+        // Taking the whole list at once means that no block can be popped by anyone else in the meantime.
+        let mut block = self.1.lists[size_class].swap(0, Ordering::Acquire);
+        if block == 0 {
+            return;
+        }
+        // The size class only depends on the size, and every block of a size class has at least an 8-byte alignment.
+        let layout = unsafe { Layout::from_size_align_unchecked(ZoneAllocator::BASE_ALLOC_SIZES[size_class], 8) };
+        while block != 0 {
+            let next = unsafe { *(block as *const usize) };
+            unsafe { heap.deallocate(NonNull::new_unchecked(block as *mut u8), layout) }.expect("Couldn't deallocate a remotely-freed block");
+            block = next;
+        }
+    }
+
+    /// Returns every block on all of the remote-free lists to this heap, see `drain_remote_frees()`.
+    fn drain_all_remote_frees(&self, heap: &mut ZoneAllocator) {
+        for size_class in 0..ZoneAllocator::MAX_BASE_SIZE_CLASSES {
+            self.drain_remote_frees(heap, size_class);
+        }
+    }
+}
+
+
 /// An allocator that contains multiple heaps. The heap that is used on each allocation is
 /// determined by a key. Currently the apic id is used as the key.
 pub struct MultipleHeaps{
@@ -304,8 +445,8 @@ pub struct MultipleHeaps{
     /// Red-black tree to store large allocations
     #[cfg(not(unsafe_large_allocations))]    
     large_allocations: MutexIrqSafe<RBTree<LargeAllocationAdapter>>,
-    /// We currently don't return memory back to the OS. Because of this all memory in the heap is contiguous
-    /// and extra memory for the heap is always allocated from the end.
+    /// Memory is only returned back to the OS by `release_empty_slabs()`, which leaves unused holes in the heap,
+    /// so extra memory for the heap is always allocated from the end.
     /// The Mutex also serves the purpose of helping to synchronize new allocations.
     end: MutexIrqSafe<VirtualAddress>, 
     /// The mapped pages for the unsafe heap are stored here so that they are not dropped and unmapped.
@@ -313,6 +454,37 @@ pub struct MultipleHeaps{
     mp: Once<MutexIrqSafe<MappedPages>>
 }
 
+impl MultipleHeaps {
+    fn overflow_heap(&self) -> &LockedHeap {
+        self.heaps.get(&OVERFLOW_HEAP_ID).expect("Multiple Heaps: the overflow heap is not initialized!")
+    }
+
+    /// Allocates the given `layout` from the given heap, growing it if it's out of memory.
+    /// Returns a null pointer if the heap couldn't be grown.
+    unsafe fn allocate_from(&self, layout: Layout, heap: &LockedHeap) -> *mut u8 {
+        if let Ok(ptr) = { heap.lock().allocate(layout) } {
+            return ptr.as_ptr();
+        };
+        // If it fails the first time, we try to grow the heap and then try again. 
+        // We must not hold any heap locks while doing so, since growing the heap may result in
+        // additional heap allocation by virtue of allocating more pages. 
+        self.grow_heap(layout, heap)
+            .and_then(|_| heap.lock().allocate(layout))    // try again
+            .map(|nn| nn.as_ptr())                         // convert to raw ptr
+            .unwrap_or(ptr::null_mut())
+    }
+
+    /// Returns the per-core heaps, starting with those of the cores in the same package as the core given by `key`,
+    /// such that the heap pages taken from other heaps stay within the same package's caches if possible.
+    ///
+    /// This doesn't allocate, since it's used when a heap has run out of memory.
+    fn heaps_nearest_first<'a>(&'a self, key: usize) -> impl Iterator<Item = &'a LockedHeap> + 'a {
+        let near = move |other: &usize| cpu_topology::same_package(key as u8, *other as u8);
+        self.heaps.iter().filter(move |&(k, _)| near(k)).map(|(_, heap)| heap)
+            .chain(self.heaps.iter().filter(move |&(k, _)| !near(k)).map(|(_, heap)| heap))
+    }
+}
+
 // The grow_heap() function for the MultipleHeaps changes depending on the slabmalloc version used.
 //
 // In the default version, MappedPages8k objects are passed to the heap that needs to be grown.
@@ -329,7 +501,7 @@ if #[cfg(unsafe_heap)] {
                 #[cfg(not(unsafe_large_allocations))]
                 large_allocations: MutexIrqSafe::new(RBTree::new(LargeAllocationAdapter::new())),
 
-                end: MutexIrqSafe::new(VirtualAddress::new_canonical(KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE)),
+                end: MutexIrqSafe::new(VirtualAddress::new_canonical(kaslr::heap_start() + KERNEL_HEAP_INITIAL_SIZE)),
 
                 mp: Once::new()
             }
@@ -339,14 +511,15 @@ if #[cfg(unsafe_heap)] {
         /// (1) Pages are first taken from another heap.
         /// (2) If the above fails, then more pages are allocated from the OS.
         /// 
-        /// An Err is returned if there is no more memory to be allocated in the heap memory area.
+        /// An Err is returned if there is no more memory to be allocated in the heap memory area,
+        /// or if the heaps have reached their maximum size, see `MAX_SIZE_MIB`.
         /// 
         /// # Arguments
         /// * `layout`: layout.size will determine which allocation size the retrieved pages will be used for. 
         /// * `heap_to_grow`: heap that needs to grow.
         fn grow_heap(&self, layout: Layout, heap_to_grow: &LockedHeap) -> Result<(), &'static str> {
             // (1) Try to retrieve a page from the another heap
-            for heap_ref in self.heaps.values() {
+            for heap_ref in self.heaps_nearest_first(get_key()) {
                 if let Some(mp) = heap_ref.try_lock().and_then(|mut giving_heap| giving_heap.retrieve_empty_page(EMPTY_PAGES_THRESHOLD)) {
                     info!("Added page from another heap to heap: {}", heap_to_grow.lock().heap_id);
                     return heap_to_grow.lock().refill(layout, mp);
@@ -356,6 +529,7 @@ if #[cfg(unsafe_heap)] {
             let mut deferred_alloc_actions = [None; HEAP_GROWTH_AMOUNT];
             let mut heap_end = self.end.lock();
             for saved_action in &mut deferred_alloc_actions {
+                check_growth(*heap_end, HEAP_MAPPED_PAGES_SIZE_IN_BYTES)?;
                 let (mp, action) = create_heap_mapping(*heap_end, HEAP_MAPPED_PAGES_SIZE_IN_BYTES)?;
                 let start_addr = mp.start_address().value();
                 self.extend_heap_mp(mp)?;
@@ -364,6 +538,8 @@ if #[cfg(unsafe_heap)] {
                     heap_to_grow.lock().heap_id, layout.size(), *heap_end
                 );
                 *heap_end += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+                MAPPED_BYTES.fetch_add(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+                GROWTH_COUNT.fetch_add(1, Ordering::Relaxed);
                 heap_to_grow.lock().refill(layout, page)?;
                 *saved_action = Some(action);
             }
@@ -393,7 +569,7 @@ if #[cfg(unsafe_heap)] {
                 #[cfg(not(unsafe_large_allocations))]
                 large_allocations: MutexIrqSafe::new(RBTree::new(LargeAllocationAdapter::new())),
 
-                end: MutexIrqSafe::new(VirtualAddress::new_canonical(KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE))
+                end: MutexIrqSafe::new(VirtualAddress::new_canonical(kaslr::heap_start() + KERNEL_HEAP_INITIAL_SIZE))
             }
         }
 
@@ -401,14 +577,15 @@ if #[cfg(unsafe_heap)] {
         /// (1) Pages are first taken from another heap.
         /// (2) If the above fails, then more pages are allocated from the OS.
         /// 
-        /// An Err is returned if there is no more memory to be allocated in the heap memory area or if the heap page limit is reached.
+        /// An Err is returned if there is no more memory to be allocated in the heap memory area,
+        /// or if the heaps have reached their maximum size, see `MAX_SIZE_MIB`.
         /// 
         /// # Arguments
         /// * `layout`: layout.size will determine which allocation size the retrieved pages will be used for. 
         /// * `heap_to_grow`: heap that needs to grow.
         fn grow_heap(&self, layout: Layout, heap_to_grow: &LockedHeap) -> Result<(), &'static str> {
             // (1) Try to retrieve a page from the another heap
-            for heap_ref in self.heaps.values() {
+            for heap_ref in self.heaps_nearest_first(get_key()) {
                 if let Some(mp) = heap_ref.try_lock().and_then(|mut giving_heap| giving_heap.retrieve_empty_page(EMPTY_PAGES_THRESHOLD)) {
                     info!("Added page from another heap to heap: {}", heap_to_grow.lock().heap_id);
                     return heap_to_grow.lock().refill(layout, mp);
@@ -418,12 +595,15 @@ if #[cfg(unsafe_heap)] {
             let mut deferred_alloc_actions = [None; HEAP_GROWTH_AMOUNT];
             let mut heap_end = self.end.lock();
             for saved_action in &mut deferred_alloc_actions {
+                check_growth(*heap_end, HEAP_MAPPED_PAGES_SIZE_IN_BYTES)?;
                 let (mp, action) = create_heap_mapping(*heap_end, HEAP_MAPPED_PAGES_SIZE_IN_BYTES)?;
                 let mp = MappedPages8k::new(mp)?;
                 info!("grow_heap:: Allocated a page to refill core heap {} for size :{} at address: {:#X}", 
                     heap_to_grow.lock().heap_id, layout.size(), *heap_end
                 );
                 *heap_end += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+                MAPPED_BYTES.fetch_add(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+                GROWTH_COUNT.fetch_add(1, Ordering::Relaxed);
                 heap_to_grow.lock().refill(layout, mp)?;
                 *saved_action = Some(action);
             }
@@ -441,7 +621,7 @@ if #[cfg(unsafe_heap)] {
                 #[cfg(not(unsafe_large_allocations))]
                 large_allocations: MutexIrqSafe::new(RBTree::new(LargeAllocationAdapter::new())),
 
-                end: MutexIrqSafe::new(VirtualAddress::new_canonical(KERNEL_HEAP_START + KERNEL_HEAP_INITIAL_SIZE))
+                end: MutexIrqSafe::new(VirtualAddress::new_canonical(kaslr::heap_start() + KERNEL_HEAP_INITIAL_SIZE))
             }
         }
 
@@ -449,14 +629,15 @@ if #[cfg(unsafe_heap)] {
         /// (1) Pages are first taken from another heap.
         /// (2) If the above fails, then more pages are allocated from the OS.
         /// 
-        /// An Err is returned if there is no more memory to be allocated in the heap memory area.
+        /// An Err is returned if there is no more memory to be allocated in the heap memory area,
+        /// or if the heaps have reached their maximum size, see `MAX_SIZE_MIB`.
         /// 
         /// # Arguments
         /// * `layout`: layout.size will determine which allocation size the retrieved pages will be used for. 
         /// * `heap_to_grow`: heap that needs to grow.
         fn grow_heap(&self, layout: Layout, heap_to_grow: &LockedHeap) -> Result<(), &'static str> {
             // (1) Try to retrieve a page from the another heap
-            for heap_ref in self.heaps.values() {
+            for heap_ref in self.heaps_nearest_first(get_key()) {
                 if let Some(mp) = heap_ref.try_lock().and_then(|mut giving_heap| giving_heap.retrieve_empty_page(EMPTY_PAGES_THRESHOLD)) {
                     info!("Added page from another heap to heap: {}", heap_to_grow.lock().heap_id);
                     return heap_to_grow.lock().refill(layout, mp);
@@ -467,12 +648,15 @@ if #[cfg(unsafe_heap)] {
             let mut deferred_alloc_actions = [None; HEAP_GROWTH_AMOUNT];
             let mut heap_end = self.end.lock();
             for saved_action in &mut deferred_alloc_actions {
+                check_growth(*heap_end, HEAP_MAPPED_PAGES_SIZE_IN_BYTES)?;
                 let (mp, action) = create_heap_mapping(*heap_end, HEAP_MAPPED_PAGES_SIZE_IN_BYTES)?;
                 let mp = MappedPages8k::new(mp)?;
                 info!("grow_heap:: Allocated a page to refill core heap {} for size :{} at address: {:#X}", 
                     heap_to_grow.lock().heap_id, layout.size(), *heap_end
                 );
                 *heap_end += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+                MAPPED_BYTES.fetch_add(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+                GROWTH_COUNT.fetch_add(1, Ordering::Relaxed);
                 heap_to_grow.lock().refill(layout, mp)?;
                 *saved_action = Some(action);
             }
@@ -504,17 +688,29 @@ unsafe impl GlobalAlloc for MultipleHeaps {
 
         // For regular-sized allocations, we first try to allocated from "our" heap, 
         // which is currently the per-core heap for the current CPU core. 
-        let our_heap = self.heaps.get(&get_key()).expect("Multiple Heaps: heap is not initialized!");
-        if let Ok(ptr) = { our_heap.lock().allocate(layout) } {
-            return ptr.as_ptr();
+        // A core without its own heap uses the overflow heap instead.
+        let our_heap = match self.heaps.get(&get_key()) {
+            Some(heap) => heap,
+            None => return self.allocate_from(layout, self.overflow_heap()),
         };
-        // If it fails the first time, we try to grow the heap and then try again. 
-        // We must not hold any heap locks while doing so, since growing the heap may result in
-        // additional heap allocation by virtue of allocating more pages. 
-        self.grow_heap(layout, our_heap)
-            .and_then(|_| our_heap.lock().allocate(layout))    // try again
-            .map(|nn| nn.as_ptr())                             // convert to raw ptr
-            .unwrap_or(ptr::null_mut())
+        match our_heap.try_lock() {
+            Some(mut heap) => {
+                // First, take back the blocks of this size class that other cores have freed into our heap.
+                our_heap.drain_remote_frees(&mut heap, RemoteFrees::size_class(layout.size()));
+                if let Ok(ptr) = heap.allocate(layout) {
+                    return ptr.as_ptr();
+                }
+            }
+            // Another core only holds our heap's lock briefly, e.g., to take an empty page from it,
+            // so rather than waiting for it, we allocate from the overflow heap.
+            None => return self.allocate_from(layout, self.overflow_heap()),
+        }
+        let ptr = self.allocate_from(layout, our_heap);
+        if ptr.is_null() {
+            self.allocate_from(layout, self.overflow_heap())
+        } else {
+            ptr
+        }
     }
 
     /// Deallocates the memory at the address given by `ptr`.
@@ -541,13 +737,72 @@ unsafe impl GlobalAlloc for MultipleHeaps {
         let page_addr = (ptr as usize) & !(ObjectPage8k::SIZE - 1);
         // find the heap id
         let id = *((page_addr as *mut u8).offset(ObjectPage8k::HEAP_ID_OFFSET as isize) as *mut usize);
-        let mut heap = self.heaps.get(&id).expect("Multiple Heaps: Heap not initialized").lock();
-        heap.deallocate(NonNull::new_unchecked(ptr), layout).expect("Couldn't deallocate");
+        let heap = self.heaps.get(&id).expect("Multiple Heaps: Heap not initialized");
+        // Blocks of another core's heap are queued for that core to return to its heap, see `RemoteFrees`.
+        // The overflow heap isn't owned by any core, so its blocks are always returned directly.
+        if id == get_key() || id == OVERFLOW_HEAP_ID {
+            heap.lock().deallocate(NonNull::new_unchecked(ptr), layout).expect("Couldn't deallocate");
+        } else {
+            heap.1.push(ptr, layout.size());
+        }
     }
 }
 
 
 
+impl KernelAllocator for MultipleHeaps {
+    /// Returns statistics about each per-core heap, sorted by heap id.
+    fn arena_stats(&self) -> Vec<ArenaStats> {
+        let mut arenas = Vec::with_capacity(self.heaps.len());
+        for (id, heap) in self.heaps.iter() {
+            // The heap's lock is released before collecting the size classes, since that allocates from a heap.
+            let slab_stats = {
+                let mut locked_heap = heap.lock();
+                heap.drain_all_remote_frees(&mut locked_heap);
+                locked_heap.slab_stats()
+            };
+            let size_classes = slab_stats.iter().map(|s| SizeClassStats {
+                object_size: s.object_size,
+                objects_per_slab: s.objects_per_slab,
+                slab_size: HEAP_MAPPED_PAGES_SIZE_IN_BYTES,
+                empty_slabs: s.empty_slabs,
+                partial_slabs: s.partial_slabs,
+                full_slabs: s.full_slabs,
+                free_objects_in_partial_slabs: s.free_objects_in_partial_slabs,
+            }).collect();
+            arenas.push(ArenaStats { arena_id: *id, size_classes, remote_frees: heap.1.count.load(Ordering::Relaxed) });
+        }
+        arenas.sort_unstable_by_key(|arena| arena.arena_id);
+        arenas
+    }
+
+    /// Unmaps the empty pages of each per-core heap beyond the first `keep_per_arena` ones.
+    /// 
+    /// This isn't supported by the unsafe heap, whose pages are all part of a single `MappedPages` object.
+    #[cfg(not(unsafe_heap))]
+    fn release_empty_slabs(&self, keep_per_arena: usize) -> usize {
+        let mut released = 0;
+        for heap in self.heaps.values() {
+            // Each page is dropped only after the heap's lock is released, since unmapping it may allocate from a heap.
+            heap.drain_all_remote_frees(&mut heap.lock());
+            loop {
+                let mp = heap.lock().retrieve_empty_page(keep_per_arena);
+                match mp {
+                    Some(mp) => drop(mp),
+                    None => break,
+                }
+                released += HEAP_MAPPED_PAGES_SIZE_IN_BYTES;
+                MAPPED_BYTES.fetch_sub(HEAP_MAPPED_PAGES_SIZE_IN_BYTES, Ordering::Relaxed);
+            }
+        }
+        if released > 0 {
+            info!("Released {} bytes of empty heap pages", released);
+        }
+        released
+    }
+}
+
+
 cfg_if! {
 if #[cfg(unsafe_large_allocations)] {
     /// Any memory request greater than MAX_ALLOC_SIZE is satisfied through a request to the OS.
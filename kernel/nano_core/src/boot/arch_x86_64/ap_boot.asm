@@ -121,8 +121,9 @@ long_mode_start_ap:
 	mov gs, ax
 
 
-	; Load the new GDT
-	lgdt [rel GDT_AP.ptr]
+	; Load the new GDT. This code runs from its identity mapping, at which GDT_AP.ptr is also mapped,
+	; whereas its unmoved higher-half address is not mapped if KASLR moved the kernel image.
+	lgdt [GDT_AP.ptr - KERNEL_OFFSET]
 
 
 	; mov rsp, 0xFC00
@@ -10,7 +10,19 @@
 ; Kernel is linked to run at -2Gb
 KERNEL_OFFSET equ 0xFFFFFFFF80000000
 
+; With KASLR, the higher-half part of the kernel image is moved by at least 1GiB, i.e., from the linear mapping
+; of the first GiB of physical memory (at KERNEL_OFFSET) into the last GiB of the address space,
+; and then by a random multiple of 2MiB within that last GiB. See `choose_nano_core_slide`.
+NANO_CORE_MIN_SLIDE equ 0x40000000
+HUGE_PAGE_SIZE equ 0x200000
+; The number of 2MiB pages in the last GiB that the moved kernel image may use;
+; the last one is left unmapped for the temporary page (see `TEMPORARY_PAGE_VIRT_ADDR` in `kernel_config`).
+NANO_CORE_SLIDE_PAGES equ 511
+
 global start
+extern KASLR_RELOCATE_NANO_CORE
+extern __kaslr_relocs_start
+extern __kaslr_relocs_end
 
 ; Section must have the permissions of .text
 section .init.text32 progbits alloc exec nowrite
@@ -42,7 +54,9 @@ start:
 	call set_up_AVX
 %endif
 
+	call choose_nano_core_slide
 	call set_up_page_tables
+	call relocate_nano_core
 	call enable_paging
 
 	; Load the 64-bit GDT
@@ -109,6 +123,136 @@ set_up_page_tables:
 	cmp ecx, 256       ; if counter = 256, the whole megabyte is mapped
 	jne .map_megabyte_table ; else map the next entry
 
+	; if the kernel image is moved, map the last P3 entry to the kaslr table,
+	; which maps the moved kernel image with huge 2MiB pages
+	mov ecx, [nano_core_slide - KERNEL_OFFSET]
+	test ecx, ecx
+	jz .end
+	mov eax, kaslr_table - KERNEL_OFFSET
+	or eax, 11b ; present + writable
+	mov [high_p3_table - KERNEL_OFFSET + (511 * 8)], eax
+
+	; the P2 entry at index (slide - NANO_CORE_MIN_SLIDE) / 2MiB maps physical address 0
+	sub ecx, NANO_CORE_MIN_SLIDE
+	shr ecx, 21
+	xor eax, eax       ; physical address of the current page
+
+.map_kaslr_table:
+	mov edx, eax
+	or edx, 10000011b  ; present + writable + huge
+	mov [(kaslr_table - KERNEL_OFFSET) + (ecx * 8)], edx ; map ecx-th entry
+
+	add eax, HUGE_PAGE_SIZE
+	inc ecx            ; increase counter
+	cmp ecx, NANO_CORE_SLIDE_PAGES
+	jne .map_kaslr_table ; else map the next entry
+
+.end:
+	ret
+
+; Chooses the random number of bytes (the "slide") by which the higher-half part of the kernel image
+; is moved from the address it was linked at, and stores it in `nano_core_slide`. See the `kaslr` crate.
+; The slide stays zero if KASLR is disabled, either in `kernel_config` or by the `nokaslr` boot argument.
+; Expects the multiboot2 information structure in `edi`, which is preserved.
+choose_nano_core_slide:
+	cmp byte [KASLR_RELOCATE_NANO_CORE - KERNEL_OFFSET], 0
+	je .done
+
+	; find the multiboot2 command line tag (type 1), the first tag follows the 8-byte fixed header
+	lea esi, [edi + 8]
+.next_tag:
+	mov eax, [esi]     ; tag type
+	test eax, eax      ; the end tag, so there is no command line
+	jz .enabled
+	cmp eax, 1
+	je .found_command_line
+	mov eax, [esi + 4] ; tag size
+	add eax, 7         ; tags are 8-byte aligned
+	and eax, ~7
+	add esi, eax
+	jmp .next_tag
+
+.found_command_line:
+	; look for the `nokaslr` argument, which must be a whole word of the null-terminated command line
+	add esi, 8
+	mov bl, ' '        ; the previous character, such that the start of the command line counts as a word boundary
+.next_char:
+	mov al, [esi]
+	test al, al
+	jz .enabled
+	cmp bl, ' '
+	ja .skip_char
+	xor ecx, ecx
+.compare_arg:
+	mov dl, [esi + ecx]
+	cmp dl, [(nokaslr_arg - KERNEL_OFFSET) + ecx]
+	jne .skip_char
+	inc ecx
+	cmp ecx, nokaslr_arg.len
+	jne .compare_arg
+	cmp byte [esi + ecx], ' ' ; whitespace or the null terminator must follow the argument
+	jbe .done
+.skip_char:
+	mov bl, al
+	inc esi
+	jmp .next_char
+
+.enabled:
+	call random_u32
+
+	; the number of possible slides, such that the whole moved kernel image fits in the kaslr table
+	mov ecx, (__kaslr_relocs_end - KERNEL_OFFSET) + HUGE_PAGE_SIZE - 1
+	shr ecx, 21        ; the number of 2MiB pages spanned by the kernel image
+	mov ebx, NANO_CORE_SLIDE_PAGES
+	sub ebx, ecx
+	jbe .done          ; the kernel image is too large to be moved
+
+	xor edx, edx
+	div ebx            ; edx = random value % number of possible slides
+	shl edx, 21
+	add edx, NANO_CORE_MIN_SLIDE
+	mov [nano_core_slide - KERNEL_OFFSET], edx
+.done:
+	ret
+
+; Returns a random value in `eax`, from the `RDRAND` instruction (if supported) mixed with the TSC.
+random_u32:
+	rdtsc
+	mov esi, eax
+	mov eax, 0x1
+	cpuid
+	test ecx, 1 << 30  ; is RDRAND supported?
+	jz .done
+	mov ecx, 10        ; retry a few times if it fails to produce a value, as recommended by Intel
+.retry:
+	rdrand eax
+	jc .mix
+	loop .retry
+	jmp .done
+.mix:
+	xor esi, eax
+.done:
+	mov eax, esi
+	ret
+
+; Moves the higher-half part of the kernel image by `nano_core_slide` bytes (if non-zero), by adding the slide
+; to every absolute address that points into it. The physical addresses at which those are stored are listed
+; in the `.kaslr_relocs` section, see `tools/kaslr_relocs`.
+; Since the moved kernel image stays within the top 2GiB of the address space, only the lower 32 bits of
+; an address change, which is also all that a 32-bit sign-extended address consists of.
+relocate_nano_core:
+	mov edx, [nano_core_slide - KERNEL_OFFSET]
+	test edx, edx
+	jz .done
+	mov esi, __kaslr_relocs_start - KERNEL_OFFSET
+.next:
+	cmp esi, __kaslr_relocs_end - KERNEL_OFFSET
+	jae .done
+	mov eax, [esi]     ; the physical address of the next absolute address to patch
+	add [eax], edx
+	add esi, 4
+	jmp .next
+.done:
 	ret
 
 enable_paging:
@@ -262,7 +406,8 @@ bits 64
 section .init.text.high
 global long_mode_start
 long_mode_start:
-	; Load the new GDT
+	; Load the new GDT. The unmoved address of GDT.ptr is still mapped by the linear mapping of the first GiB,
+	; but the GDT address within it has been moved like all other absolute addresses.
 	lgdt [rel GDT.ptr]
 
 	; Long jump to the higher half. Because `jmp` does not take
@@ -279,8 +424,9 @@ extern puts
 
 global start_high
 start_high:
-	; Set up high stack
+	; Set up high stack, which is in the moved part of the kernel image
 	add rsp, KERNEL_OFFSET
+	add rsp, [rel nano_core_slide]
 
 	
 	; for easy use of multiboot2 data structures,
@@ -322,6 +468,8 @@ start_high:
 
 	; Give rust the higher half address to the multiboot2 information structure
 	add rdi, KERNEL_OFFSET
+	; and the slide by which the kernel image was moved
+	mov rsi, [rel nano_core_slide]
 	
 	call nano_core_start
 
@@ -365,12 +513,21 @@ GDT:
 	dw .end - GDT - 1
 	dq GDT
 
+nokaslr_arg:
+	db 'nokaslr'
+.len equ $ - nokaslr_arg
+
 strings:
 .os_return:
 	db 'OS returned',0
 .long_start:
 	db 'Hello long mode!',0
 
+section .data
+; The number of bytes by which the higher-half part of the kernel image was moved at boot, see `choose_nano_core_slide`.
+nano_core_slide:
+	dq 0
+
 section .bss
 ; This reserves space for the first page table that we must set up
 ; before enabling paging and jumping to long mode.
@@ -387,6 +544,8 @@ megabyte_table:
 	resb 4096
 kernel_table:
 	resb 4096
+kaslr_table:
+	resb 4096
 
 
 ; Although x86 only requires 16-byte alignment for its stacks, 
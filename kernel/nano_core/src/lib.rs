@@ -35,6 +35,7 @@ extern crate exceptions_early;
 extern crate panic_entry; // contains required panic-related lang items
 #[cfg(not(loadable))] extern crate captain;
 extern crate memory_initialization;
+extern crate uefi_runtime;
 
 
 use core::ops::DerefMut;
@@ -73,7 +74,8 @@ fn shutdown(msg: core::fmt::Arguments) -> ! {
 
 /// The main entry point into Theseus, that is, the first Rust code that the Theseus kernel runs. 
 ///
-/// This is called from assembly code entry point for Theseus, found in `nano_core/src/boot/arch_x86_64/boot.asm`.
+/// This is called from assembly code entry point for Theseus, found in `nano_core/src/boot/arch_x86_64/boot.asm`,
+/// which also passes the number of bytes by which it moved the nano_core's higher-half sections (see the `kaslr` crate).
 ///
 /// This function does the following things: 
 ///
@@ -92,7 +94,7 @@ fn shutdown(msg: core::fmt::Arguments) -> ! {
 /// then change the [`captain::init`](../captain/fn.init.html) routine.
 /// 
 #[no_mangle]
-pub extern "C" fn nano_core_start(multiboot_information_virtual_address: usize) {
+pub extern "C" fn nano_core_start(multiboot_information_virtual_address: usize, nano_core_slide: usize) {
     println_raw!("Entered nano_core_start()."); 
 	
 	// start the kernel with interrupts disabled
@@ -116,7 +118,7 @@ pub extern "C" fn nano_core_start(multiboot_information_virtual_address: usize)
 
     // init memory management: set up stack with guard page, heap, kernel text/data mappings, etc
     let (kernel_mmi_ref, text_mapped_pages, rodata_mapped_pages, data_mapped_pages, stack, identity_mapped_pages) = 
-        try_exit!(memory_initialization::init_memory_management(&boot_info));
+        try_exit!(memory_initialization::init_memory_management(&boot_info, nano_core_slide));
     println_raw!("nano_core_start(): initialized memory subsystem."); 
     // After this point, we must "forget" all of the above mapped_pages instances if an error occurs,
     // because they will be auto-unmapped upon a returned error, causing all execution to stop. 
@@ -127,6 +129,12 @@ pub extern "C" fn nano_core_start(multiboot_information_virtual_address: usize)
     trace!("state_store initialized.");
     println_raw!("nano_core_start(): initialized state store.");     
 
+    // UEFI runtime services need the boot information, which isn't available after this function.
+    // They're optional, e.g., when booted via legacy BIOS.
+    if let Err(e) = uefi_runtime::init(&boot_info) {
+        info!("UEFI runtime services are unavailable: {}", e);
+    }
+
     // initialize the module management subsystem, so we can create the default crate namespace
     let default_namespace = match mod_mgmt::init(&boot_info, kernel_mmi_ref.lock().deref_mut()) {
         Ok(namespace) => namespace,
@@ -148,17 +156,18 @@ pub extern "C" fn nano_core_start(multiboot_information_virtual_address: usize)
     ) {
         Ok((nano_core_crate_ref, init_symbols, _num_new_syms)) => {
             // Get symbols from the boot assembly code that defines where the ap_start code are.
-            // They will be present in the ".init" sections, i.e., in the `init_symbols` list. 
+            // They will be present in the ".init" sections, i.e., in the `init_symbols` list,
+            // which are mapped into the higher half along with the other (possibly moved) kernel sections. 
             let ap_realmode_begin = try_exit!(
                 init_symbols.get("ap_start_realmode")
                     .ok_or("Missing expected symbol from assembly code \"ap_start_realmode\"")
-                    .and_then(|v| VirtualAddress::new(*v + KERNEL_OFFSET)
+                    .and_then(|v| VirtualAddress::new(*v + KERNEL_OFFSET + nano_core_slide)
                 )
             );
             let ap_realmode_end   = try_exit!(
                 init_symbols.get("ap_start_realmode_end")
                     .ok_or("Missing expected symbol from assembly code \"ap_start_realmode_end\"")
-                    .and_then(|v| VirtualAddress::new(*v + KERNEL_OFFSET)
+                    .and_then(|v| VirtualAddress::new(*v + KERNEL_OFFSET + nano_core_slide)
                 )
             );
             // debug!("ap_realmode_begin: {:#X}, ap_realmode_end: {:#X}", ap_realmode_begin, ap_realmode_end);
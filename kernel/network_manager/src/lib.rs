@@ -1,15 +1,22 @@
 #![no_std]
 
 extern crate alloc;
-// #[macro_use] extern crate log;
+#[macro_use] extern crate log;
 #[macro_use] extern crate lazy_static;
 extern crate spin;
 extern crate owning_ref;
 extern crate smoltcp;
+extern crate hpet;
+extern crate shutdown_manager;
+
+pub mod routing;
+pub mod shaping;
+pub mod stats;
 
 use alloc::vec::Vec;
 use alloc::sync::Arc;
-use spin::Mutex;
+use spin::{Mutex, Once};
+use hpet::get_hpet;
 use smoltcp::{
     socket::SocketSet,
     time::Instant,
@@ -53,14 +60,80 @@ pub trait NetworkInterface {
     fn routes(&self) -> &Routes<'static>;
 
     fn routes_mut(&mut self) -> &mut Routes<'static>;
+
+    /// Sends any outgoing packets that the interface is still holding back, which is invoked upon shutdown.
+    ///
+    /// The default implementation does nothing, which suits interfaces that send every packet as soon as they're polled.
+    fn quiesce(&mut self) -> Result<(), &'static str> {
+        Ok(())
+    }
 }
 
 /// A trait object wrapped in an Arc and Mutex that allows 
 /// arbitrary network interfaces to be shared in a thread-safe manner.
 pub type NetworkInterfaceRef = Arc<Mutex<dyn NetworkInterface + Send>>;
 
+/// The name under which the network interfaces are registered with the `shutdown_manager`,
+/// which subsystems that send or receive packets should list as a dependency.
+pub const SHUTDOWN_NAME: &str = "network";
+
+/// How long the network interfaces may take to send their remaining packets upon shutdown.
+const SHUTDOWN_TIMEOUT_MS: u64 = 1000;
+
+/// Whether the network interfaces have been registered with the `shutdown_manager`.
+static SHUTDOWN_REGISTERED: Once<()> = Once::new();
+
 /// Add a Nic to the global list of network interfaces.
 /// The Nic must implement the NetworkInterface trait.
-pub fn add_to_network_interfaces<T: NetworkInterface + 'static + Send> (iface: T) {
-    NETWORK_INTERFACES.lock().push(Arc::new(Mutex::new(iface)));
+/// 
+/// A connected route to each of the interface's local subnets is added to the routing table;
+/// its default gateway must be added separately using [`routing::add_route()`](routing/fn.add_route.html).
+/// Returns a reference to the newly-added interface.
+///
+/// Adding the first interface also registers the network interfaces with the `shutdown_manager`,
+/// which [`quiesce()`](trait.NetworkInterface.html#method.quiesce)s each of them upon shutdown.
+pub fn add_to_network_interfaces<T: NetworkInterface + 'static + Send> (iface: T) -> NetworkInterfaceRef {
+    SHUTDOWN_REGISTERED.call_once(|| {
+        if let Err(e) = shutdown_manager::register(SHUTDOWN_NAME, &[], SHUTDOWN_TIMEOUT_MS, quiesce) {
+            warn!("network_manager: couldn't register for shutdown: {}", e);
+        }
+    });
+    let iface_ref: NetworkInterfaceRef = Arc::new(Mutex::new(iface));
+    let local_subnets: Vec<IpCidr> = iface_ref.lock().ip_addrs().to_vec();
+    for subnet in local_subnets {
+        // Connected routes have no gateway, so adding them can't fail.
+        let _ = routing::add_route(subnet, None, routing::CONNECTED_ROUTE_METRIC, &iface_ref);
+    }
+    NETWORK_INTERFACES.lock().push(iface_ref.clone());
+    iface_ref
+}
+
+/// Quiesces all network interfaces, which is invoked upon shutdown.
+///
+/// They are quiesced in the reverse order they were added, since a newer interface, e.g., a tunnel,
+/// may send its packets through an older one. 
+/// All of them are quiesced even if some fail, and the first error is returned.
+fn quiesce() -> Result<(), &'static str> {
+    let mut result = Ok(());
+    for iface in NETWORK_INTERFACES.lock().iter().rev() {
+        if let Err(e) = iface.lock().quiesce() {
+            error!("network_manager: failed to quiesce a network interface: {}", e);
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+
+/// Returns the current time in milliseconds according to the HPET, or 0 if there is no HPET.
+fn now_millis() -> u64 {
+    const FEMTOSECONDS_PER_MILLISECOND: u64 = 1_000_000_000_000;
+    static HPET_PERIOD_FEMTOSECONDS: Once<u64> = Once::new();
+
+    let hpet = match get_hpet() {
+        Some(hpet) => hpet,
+        _ => return 0,
+    };
+    let period = *HPET_PERIOD_FEMTOSECONDS.call_once(|| hpet.counter_period_femtoseconds() as u64);
+    ((hpet.get_counter() as u128) * (period as u128) / (FEMTOSECONDS_PER_MILLISECOND as u128)) as u64
 }
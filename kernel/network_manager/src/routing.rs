@@ -0,0 +1,141 @@
+//! The system-wide routing table, which determines which network interface is used to reach a given destination.
+//!
+//! Each route covers a destination subnet and is reached either directly through its interface (a connected route),
+//! or through a gateway on that interface's local network.
+//! When looking up a destination, the route with the longest matching prefix is chosen,
+//! and among equally specific routes, the one with the lowest metric.
+//! If those are equal too, the route that was added first wins.
+//!
+//! Adding an interface with [`add_to_network_interfaces()`] adds a connected route for each of its IP addresses,
+//! whereas default gateways and other gateway routes must be added explicitly with [`add_route()`].
+//! Gateway routes are also added to the interface's own smoltcp routes, such that smoltcp sends
+//! packets for that destination to the right gateway.
+//!
+//! [`add_to_network_interfaces()`]: ../fn.add_to_network_interfaces.html
+//! [`add_route()`]: fn.add_route.html
+
+use alloc::{
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use smoltcp::{
+    iface::Route as SmoltcpRoute,
+    wire::{IpAddress, IpCidr, Ipv4Address, Ipv6Address},
+};
+use super::NetworkInterfaceRef;
+
+
+/// The metric of the routes to an interface's local subnets, which are preferred over any gateway route.
+pub const CONNECTED_ROUTE_METRIC: u32 = 0;
+/// The default metric for routes through a gateway.
+pub const DEFAULT_ROUTE_METRIC: u32 = 100;
+
+/// A route to a destination subnet through a network interface.
+#[derive(Clone)]
+pub struct Route {
+    /// The subnet of addresses that this route covers.
+    /// A prefix length of 0 makes this a default route, which covers all addresses.
+    pub destination: IpCidr,
+    /// The gateway that packets for this destination are sent to,
+    /// or `None` if the destination is directly reachable on the interface's local network.
+    pub gateway: Option<IpAddress>,
+    /// The cost of this route, where lower metrics are preferred.
+    pub metric: u32,
+    /// The interface that packets for this destination are sent from.
+    pub interface: NetworkInterfaceRef,
+}
+
+lazy_static! {
+    /// All routes, in the order they were added.
+    static ref ROUTING_TABLE: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+}
+
+
+/// Returns the default route's destination for the given address family: all addresses, with a prefix length of 0.
+pub fn default_destination(ipv6: bool) -> IpCidr {
+    if ipv6 {
+        IpCidr::new(IpAddress::Ipv6(Ipv6Address::UNSPECIFIED), 0)
+    } else {
+        IpCidr::new(IpAddress::Ipv4(Ipv4Address::UNSPECIFIED), 0)
+    }
+}
+
+/// Adds a route to the given `destination` through the given `interface`.
+///
+/// If `gateway` is `Some`, packets for the destination are sent to that gateway,
+/// which must be reachable on the interface's local network.
+/// Any existing route to the same destination through the same interface is replaced.
+pub fn add_route(
+    destination: IpCidr,
+    gateway: Option<IpAddress>,
+    metric: u32,
+    interface: &NetworkInterfaceRef,
+) -> Result<(), &'static str> {
+    if let Some(gateway) = gateway {
+        let smoltcp_route = match gateway {
+            IpAddress::Ipv4(ipv4) => SmoltcpRoute::new_ipv4_gateway(ipv4),
+            IpAddress::Ipv6(ipv6) => SmoltcpRoute::new_ipv6_gateway(ipv6),
+            _ => return Err("routing: the gateway must be an Ipv4Address or an Ipv6Address"),
+        };
+        let mut result = Ok(());
+        interface.lock().routes_mut().update(|storage| {
+            result = storage.insert(destination, smoltcp_route)
+                .map(|_old_route| ())
+                .map_err(|_e| "routing: the interface's route storage is full");
+        });
+        result?;
+    }
+
+    let mut table = ROUTING_TABLE.lock();
+    table.retain(|r| !(r.destination == destination && Arc::ptr_eq(&r.interface, interface)));
+    table.push(Route { destination, gateway, metric, interface: interface.clone() });
+    Ok(())
+}
+
+/// Removes the route to the given `destination` through the given `interface`, and returns it.
+pub fn remove_route(destination: IpCidr, interface: &NetworkInterfaceRef) -> Option<Route> {
+    let removed = {
+        let mut table = ROUTING_TABLE.lock();
+        let index = table.iter().position(|r| r.destination == destination && Arc::ptr_eq(&r.interface, interface))?;
+        table.remove(index)
+    };
+    if removed.gateway.is_some() {
+        interface.lock().routes_mut().update(|storage| { storage.remove(&destination); });
+    }
+    Some(removed)
+}
+
+/// Removes all routes through the given `interface`, e.g., because it's going away.
+pub fn remove_routes_via(interface: &NetworkInterfaceRef) {
+    ROUTING_TABLE.lock().retain(|r| !Arc::ptr_eq(&r.interface, interface));
+}
+
+/// Returns the best route to the given `destination` address, if there is any.
+pub fn lookup_route(destination: IpAddress) -> Option<Route> {
+    let table = ROUTING_TABLE.lock();
+    let mut best: Option<&Route> = None;
+    for route in table.iter().filter(|r| r.destination.contains_addr(&destination)) {
+        let better = match best {
+            None => true,
+            Some(b) => {
+                let (prefix, best_prefix) = (route.destination.prefix_len(), b.destination.prefix_len());
+                prefix > best_prefix || (prefix == best_prefix && route.metric < b.metric)
+            }
+        };
+        if better {
+            best = Some(route);
+        }
+    }
+    best.cloned()
+}
+
+/// Returns the interface that packets for the given `destination` address should be sent from.
+pub fn interface_for(destination: IpAddress) -> Option<NetworkInterfaceRef> {
+    lookup_route(destination).map(|r| r.interface)
+}
+
+/// Returns a copy of all routes, in the order they were added.
+pub fn routes() -> Vec<Route> {
+    ROUTING_TABLE.lock().clone()
+}
@@ -0,0 +1,347 @@
+//! Traffic shaping, which limits the bandwidth of selected sockets such that they can't starve other traffic.
+//!
+//! Traffic is shaped per traffic class, each of which has an optional token bucket for each direction.
+//! A class covers the sockets whose local ports were assigned to it with [`shape_port()`],
+//! so a single socket or a whole group of tasks' sockets can share one class and thus one limit.
+//! Traffic of ports without a class is never limited, which is the case for interactive sessions by default.
+//!
+//! The limits are enforced by the network devices:
+//! * Outgoing frames of a class that has run out of tokens are queued in a per-class queue of the device
+//!   and sent once enough tokens have accumulated, while unshaped frames are sent right away.
+//! * Incoming frames of a class that has run out of tokens are dropped (policed),
+//!   which causes the remote TCP sender to back off; this is how downloads are limited.
+//!
+//! Bulk transfers, such as crate downloads from the update server, use the [`BULK_CLASS`].
+//!
+//! [`shape_port()`]: fn.shape_port.html
+//! [`BULK_CLASS`]: constant.BULK_CLASS.html
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use super::now_millis;
+
+
+/// The name of the traffic class for bulk transfers.
+pub const BULK_CLASS: &'static str = "bulk";
+/// The default rate limit of the [`BULK_CLASS`](constant.BULK_CLASS.html) in each direction, in bytes per second.
+pub const DEFAULT_BULK_RATE: u64 = 4 * 1024 * 1024;
+/// The default burst size of a token bucket, in bytes.
+pub const DEFAULT_BURST: u64 = 64 * 1024;
+/// The smallest allowed burst size, which must fit a full-size Ethernet frame so that every frame can eventually pass.
+pub const MIN_BURST: u64 = 1518;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+
+/// The rate limits of a traffic class.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The limit for outgoing traffic in bytes per second, or `None` for no limit.
+    pub egress_rate: Option<u64>,
+    /// The limit for incoming traffic in bytes per second, or `None` for no limit.
+    pub ingress_rate: Option<u64>,
+    /// The number of bytes that can be sent or received at once after the class has been idle.
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// A limit of `rate` bytes per second in both directions, with the default burst size.
+    pub fn symmetric(rate: u64) -> RateLimit {
+        RateLimit {
+            egress_rate: Some(rate),
+            ingress_rate: Some(rate),
+            burst: DEFAULT_BURST,
+        }
+    }
+}
+
+
+/// A token bucket, in which tokens accumulate at a fixed rate up to the burst size.
+/// Each byte of traffic consumes one token.
+struct TokenBucket {
+    /// The rate in bytes per second, or `None` if this bucket never runs out.
+    rate: Option<u64>,
+    /// The burst size in bytes, which is the most tokens the bucket can hold.
+    burst: u64,
+    /// The available tokens in thousandths of a byte, such that even low rates accumulate tokens every millisecond.
+    milli_tokens: u64,
+    /// The time in milliseconds at which tokens were last added.
+    last_refill: u64,
+}
+
+impl TokenBucket {
+    fn new(rate: Option<u64>, burst: u64, now: u64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst,
+            milli_tokens: burst * 1000,
+            last_refill: now,
+        }
+    }
+
+    /// Adds the tokens that have accumulated since the last refill.
+    fn refill(&mut self, now: u64) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.saturating_sub(self.last_refill);
+            self.milli_tokens = self.milli_tokens
+                .saturating_add(elapsed.saturating_mul(rate))
+                .min(self.burst * 1000);
+        }
+        self.last_refill = now;
+    }
+
+    /// Consumes `len` tokens if that many are available, returning whether they were.
+    fn try_consume(&mut self, len: usize, now: u64) -> bool {
+        if self.rate.is_none() {
+            return true;
+        }
+        self.refill(now);
+        let needed = (len as u64) * 1000;
+        if self.milli_tokens >= needed {
+            self.milli_tokens -= needed;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+
+/// A traffic class, whose sockets share its rate limits.
+pub struct TrafficClass {
+    name: String,
+    egress: Mutex<TokenBucket>,
+    ingress: Mutex<TokenBucket>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_delayed: AtomicU64,
+    frames_dropped: AtomicU64,
+}
+
+/// A reference to a traffic class that is shared by the network devices and the ports assigned to it.
+pub type TrafficClassRef = Arc<TrafficClass>;
+
+/// Statistics about the traffic of a class.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClassStats {
+    /// The number of bytes of outgoing frames that were sent.
+    pub bytes_sent: u64,
+    /// The number of bytes of incoming frames that were accepted.
+    pub bytes_received: u64,
+    /// The number of outgoing frames that had to wait in a queue before being sent.
+    pub frames_delayed: u64,
+    /// The number of frames that were dropped, either incoming frames over the limit
+    /// or outgoing frames that didn't fit into a full queue.
+    pub frames_dropped: u64,
+}
+
+impl TrafficClass {
+    /// The name of this class.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The current rate limits of this class.
+    pub fn limit(&self) -> RateLimit {
+        let egress = self.egress.lock();
+        RateLimit {
+            egress_rate: egress.rate,
+            ingress_rate: self.ingress.lock().rate,
+            burst: egress.burst,
+        }
+    }
+
+    /// Statistics about the traffic of this class so far.
+    pub fn stats(&self) -> ClassStats {
+        ClassStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            frames_delayed: self.frames_delayed.load(Ordering::Relaxed),
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Consumes tokens for an outgoing frame of `len` bytes,
+    /// returning `false` if the frame must wait because the class has exceeded its egress rate.
+    pub fn try_send(&self, len: usize) -> bool {
+        let allowed = self.egress.lock().try_consume(len, now_millis());
+        if allowed {
+            self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Consumes tokens for an incoming frame of `len` bytes,
+    /// returning `false` if the frame must be dropped because the class has exceeded its ingress rate.
+    pub fn try_receive(&self, len: usize) -> bool {
+        let allowed = self.ingress.lock().try_consume(len, now_millis());
+        if allowed {
+            self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        } else {
+            self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Records that an outgoing frame was queued because the class had exceeded its egress rate.
+    pub fn note_delayed(&self) {
+        self.frames_delayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that an outgoing frame was dropped because its queue was full.
+    pub fn note_dropped(&self) {
+        self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn set_limit(&self, limit: RateLimit) {
+        let now = now_millis();
+        *self.egress.lock() = TokenBucket::new(limit.egress_rate, limit.burst, now);
+        *self.ingress.lock() = TokenBucket::new(limit.ingress_rate, limit.burst, now);
+    }
+}
+
+
+lazy_static! {
+    /// All traffic classes, by name.
+    static ref CLASSES: Mutex<BTreeMap<String, TrafficClassRef>> = Mutex::new(BTreeMap::new());
+    /// The traffic class of each shaped local port.
+    static ref PORT_CLASSES: Mutex<BTreeMap<u16, TrafficClassRef>> = Mutex::new(BTreeMap::new());
+}
+
+
+/// Creates the traffic class with the given `name` and `limit`,
+/// or changes the limit of that class if it already exists.
+pub fn set_class<S: Into<String>>(name: S, mut limit: RateLimit) -> TrafficClassRef {
+    limit.burst = limit.burst.max(MIN_BURST);
+    let name = name.into();
+    let mut classes = CLASSES.lock();
+    if let Some(class) = classes.get(&name) {
+        class.set_limit(limit);
+        return class.clone();
+    }
+    let now = now_millis();
+    let class = Arc::new(TrafficClass {
+        name: name.clone(),
+        egress: Mutex::new(TokenBucket::new(limit.egress_rate, limit.burst, now)),
+        ingress: Mutex::new(TokenBucket::new(limit.ingress_rate, limit.burst, now)),
+        bytes_sent: AtomicU64::new(0),
+        bytes_received: AtomicU64::new(0),
+        frames_delayed: AtomicU64::new(0),
+        frames_dropped: AtomicU64::new(0),
+    });
+    classes.insert(name, class.clone());
+    class
+}
+
+/// Returns the traffic class with the given `name`.
+///
+/// The [`BULK_CLASS`](constant.BULK_CLASS.html) always exists; it is created with the
+/// [`DEFAULT_BULK_RATE`](constant.DEFAULT_BULK_RATE.html) when it is first used.
+pub fn get_class(name: &str) -> Option<TrafficClassRef> {
+    if let Some(class) = CLASSES.lock().get(name) {
+        return Some(class.clone());
+    }
+    if name == BULK_CLASS {
+        Some(set_class(BULK_CLASS, RateLimit::symmetric(DEFAULT_BULK_RATE)))
+    } else {
+        None
+    }
+}
+
+/// Removes the traffic class with the given `name`, such that no new ports can be assigned to it.
+/// Ports that are still assigned to it remain limited until their assignments are dropped.
+pub fn remove_class(name: &str) -> Option<TrafficClassRef> {
+    CLASSES.lock().remove(name)
+}
+
+/// Returns all traffic classes, sorted by name.
+pub fn classes() -> Vec<TrafficClassRef> {
+    CLASSES.lock().values().cloned().collect()
+}
+
+
+/// The assignment of a local port to a traffic class, which lasts until this object is dropped.
+pub struct ShapedPort {
+    port: u16,
+    class: TrafficClassRef,
+}
+
+impl ShapedPort {
+    /// The local port whose traffic is shaped.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The traffic class that the port is assigned to.
+    pub fn class(&self) -> &TrafficClassRef {
+        &self.class
+    }
+}
+
+impl Drop for ShapedPort {
+    fn drop(&mut self) {
+        let mut port_classes = PORT_CLASSES.lock();
+        // The port may have been reassigned to another class in the meantime, which must be preserved.
+        if port_classes.get(&self.port).map_or(false, |c| Arc::ptr_eq(c, &self.class)) {
+            port_classes.remove(&self.port);
+        }
+    }
+}
+
+/// Assigns the given local TCP/UDP `port` to the traffic class with the given name,
+/// such that all traffic of the socket(s) bound to that port is shaped according to that class.
+///
+/// The assignment is removed when the returned `ShapedPort` is dropped,
+/// which should happen when the socket is closed.
+pub fn shape_port(port: u16, class_name: &str) -> Result<ShapedPort, &'static str> {
+    let class = get_class(class_name).ok_or("no such traffic class")?;
+    PORT_CLASSES.lock().insert(port, class.clone());
+    Ok(ShapedPort { port, class })
+}
+
+
+/// Returns the traffic class of an outgoing Ethernet frame, based on its source port,
+/// or `None` if the frame isn't shaped.
+pub fn classify_outgoing(frame: &[u8]) -> Option<TrafficClassRef> {
+    classify(frame, false)
+}
+
+/// Returns the traffic class of an incoming Ethernet frame, based on its destination port,
+/// or `None` if the frame isn't shaped.
+pub fn classify_incoming(frame: &[u8]) -> Option<TrafficClassRef> {
+    classify(frame, true)
+}
+
+/// Finds the class of the local port of a TCP or UDP segment within an IPv4 Ethernet frame.
+fn classify(frame: &[u8], incoming: bool) -> Option<TrafficClassRef> {
+    let port_classes = PORT_CLASSES.lock();
+    if port_classes.is_empty() {
+        return None;
+    }
+    if frame.len() < ETHERNET_HEADER_LEN + 20 || u16::from_be_bytes([frame[12], frame[13]]) != ETHERTYPE_IPV4 {
+        return None;
+    }
+    let ip = &frame[ETHERNET_HEADER_LEN..];
+    let header_len = ((ip[0] & 0x0F) as usize) * 4;
+    let protocol = ip[9];
+    if protocol != IP_PROTOCOL_TCP && protocol != IP_PROTOCOL_UDP {
+        return None;
+    }
+    let transport = ip.get(header_len..header_len + 4)?;
+    let port = if incoming {
+        u16::from_be_bytes([transport[2], transport[3]])
+    } else {
+        u16::from_be_bytes([transport[0], transport[1]])
+    };
+    port_classes.get(&port).cloned()
+}
+
@@ -0,0 +1,409 @@
+//! Statistics about the network stack: per-protocol packet counters and the state of open sockets.
+//!
+//! The network devices report every frame they send, receive, or drop with [`record_outgoing()`],
+//! [`record_incoming()`], and [`record_drop()`], and the counters of each protocol are read with [`protocol_stats()`].
+//! Incoming packets with an invalid checksum are counted as checksum errors and should be dropped by the device.
+//! A retransmission is an outgoing TCP segment whose sequence numbers were already sent by the same connection.
+//!
+//! Sockets don't belong to a global list; each task keeps its own `SocketSet`.
+//! Thus, whenever a socket set is polled, e.g., by `smoltcp_helper::poll_iface()`,
+//! a snapshot of its sockets is recorded with [`record_sockets()`], and [`sockets()`] returns the latest snapshots
+//! of all socket sets that were polled recently.
+//!
+//! [`record_outgoing()`]: fn.record_outgoing.html
+//! [`record_incoming()`]: fn.record_incoming.html
+//! [`record_drop()`]: fn.record_drop.html
+//! [`protocol_stats()`]: fn.protocol_stats.html
+//! [`record_sockets()`]: fn.record_sockets.html
+//! [`sockets()`]: fn.sockets.html
+
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use smoltcp::{
+    socket::{Socket, SocketSet, TcpState},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+};
+use super::now_millis;
+
+
+/// A socket set whose snapshot hasn't been refreshed for this many milliseconds is considered closed.
+pub const SOCKET_SET_TIMEOUT_MILLIS: u64 = 5000;
+/// The maximum number of TCP connections whose sent sequence numbers are tracked to detect retransmissions.
+const MAX_TRACKED_CONNECTIONS: usize = 1024;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const IP_PROTOCOL_ICMP: u8 = 1;
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+
+
+/// The protocols that are counted separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// ICMP over IPv4.
+    Icmp,
+    /// Everything else, e.g., ARP, IPv6, and other IPv4 protocols.
+    Other,
+}
+
+impl Protocol {
+    /// All protocols, in the order they are usually displayed.
+    pub const ALL: [Protocol; 4] = [Protocol::Tcp, Protocol::Udp, Protocol::Icmp, Protocol::Other];
+
+    fn index(self) -> usize {
+        match self {
+            Protocol::Tcp => 0,
+            Protocol::Udp => 1,
+            Protocol::Icmp => 2,
+            Protocol::Other => 3,
+        }
+    }
+}
+
+/// The counters of a protocol.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProtocolStats {
+    /// Packets (TCP segments, UDP datagrams, etc.) received and accepted.
+    pub packets_in: u64,
+    /// Packets sent.
+    pub packets_out: u64,
+    /// TCP segments that were retransmitted; always zero for other protocols.
+    pub retransmits: u64,
+    /// Packets that were dropped, including those with checksum errors.
+    pub drops: u64,
+    /// Received packets with an invalid checksum.
+    pub checksum_errors: u64,
+}
+
+struct Counters {
+    packets_in: AtomicU64,
+    packets_out: AtomicU64,
+    retransmits: AtomicU64,
+    drops: AtomicU64,
+    checksum_errors: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Counters {
+        Counters {
+            packets_in: AtomicU64::new(0),
+            packets_out: AtomicU64::new(0),
+            retransmits: AtomicU64::new(0),
+            drops: AtomicU64::new(0),
+            checksum_errors: AtomicU64::new(0),
+        }
+    }
+}
+
+static COUNTERS: [Counters; 4] = [Counters::new(), Counters::new(), Counters::new(), Counters::new()];
+
+fn counters(protocol: Protocol) -> &'static Counters {
+    &COUNTERS[protocol.index()]
+}
+
+/// Returns the counters of the given protocol since boot.
+pub fn protocol_stats(protocol: Protocol) -> ProtocolStats {
+    let c = counters(protocol);
+    ProtocolStats {
+        packets_in: c.packets_in.load(Ordering::Relaxed),
+        packets_out: c.packets_out.load(Ordering::Relaxed),
+        retransmits: c.retransmits.load(Ordering::Relaxed),
+        drops: c.drops.load(Ordering::Relaxed),
+        checksum_errors: c.checksum_errors.load(Ordering::Relaxed),
+    }
+}
+
+
+/// The parts of an Ethernet frame that the counters depend on.
+struct ParsedFrame<'f> {
+    protocol: Protocol,
+    src_addr: [u8; 4],
+    dst_addr: [u8; 4],
+    /// The IPv4 header, or empty if this isn't an IPv4 packet.
+    ip_header: &'f [u8],
+    /// The IPv4 payload, truncated to the length given in the IPv4 header.
+    payload: &'f [u8],
+}
+
+fn parse(frame: &[u8]) -> ParsedFrame {
+    let other = ParsedFrame { protocol: Protocol::Other, src_addr: [0; 4], dst_addr: [0; 4], ip_header: &[], payload: &[] };
+    if frame.len() < ETHERNET_HEADER_LEN + 20 || u16::from_be_bytes([frame[12], frame[13]]) != ETHERTYPE_IPV4 {
+        return other;
+    }
+    let ip = &frame[ETHERNET_HEADER_LEN..];
+    let header_len = ((ip[0] & 0x0F) as usize) * 4;
+    let total_len = (u16::from_be_bytes([ip[2], ip[3]]) as usize).min(ip.len());
+    if header_len < 20 || total_len < header_len {
+        return other;
+    }
+    let protocol = match ip[9] {
+        IP_PROTOCOL_TCP => Protocol::Tcp,
+        IP_PROTOCOL_UDP => Protocol::Udp,
+        IP_PROTOCOL_ICMP => Protocol::Icmp,
+        _ => Protocol::Other,
+    };
+    ParsedFrame {
+        protocol,
+        src_addr: [ip[12], ip[13], ip[14], ip[15]],
+        dst_addr: [ip[16], ip[17], ip[18], ip[19]],
+        ip_header: &ip[..header_len],
+        payload: &ip[header_len..total_len],
+    }
+}
+
+/// Records an incoming Ethernet frame.
+///
+/// Returns `false` if the frame's IPv4 header or its TCP, UDP, or ICMP packet has an invalid checksum,
+/// in which case it's counted as a drop and the device should drop it.
+pub fn record_incoming(frame: &[u8]) -> bool {
+    let parsed = parse(frame);
+    let c = counters(parsed.protocol);
+    if !has_valid_checksums(&parsed) {
+        c.checksum_errors.fetch_add(1, Ordering::Relaxed);
+        c.drops.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    c.packets_in.fetch_add(1, Ordering::Relaxed);
+    if parsed.protocol == Protocol::Tcp && parsed.payload.len() >= 20 && parsed.payload[13] & TCP_RST != 0 {
+        let tcp = parsed.payload;
+        let port = u16::from_be_bytes([tcp[2], tcp[3]]);
+        let peer_port = u16::from_be_bytes([tcp[0], tcp[1]]);
+        SENT_SEQUENCE_NUMBERS.lock().remove(&(port, parsed.src_addr, peer_port));
+    }
+    true
+}
+
+/// Records an outgoing Ethernet frame that is being sent, and returns its protocol.
+///
+/// If sending it fails, the device should then record the drop with [`record_protocol_drop()`].
+///
+/// [`record_protocol_drop()`]: fn.record_protocol_drop.html
+pub fn record_outgoing(frame: &[u8]) -> Protocol {
+    let parsed = parse(frame);
+    let c = counters(parsed.protocol);
+    c.packets_out.fetch_add(1, Ordering::Relaxed);
+    if parsed.protocol == Protocol::Tcp && is_retransmission(&parsed) {
+        c.retransmits.fetch_add(1, Ordering::Relaxed);
+    }
+    parsed.protocol
+}
+
+/// Records a frame that was dropped by the device, e.g., because a queue was full.
+pub fn record_drop(frame: &[u8]) {
+    record_protocol_drop(parse(frame).protocol);
+}
+
+/// Records a dropped packet of the given protocol.
+pub fn record_protocol_drop(protocol: Protocol) {
+    counters(protocol).drops.fetch_add(1, Ordering::Relaxed);
+}
+
+
+/// The key of a TCP connection: the local port, the remote address, and the remote port.
+type ConnectionKey = (u16, [u8; 4], u16);
+
+lazy_static! {
+    /// For each TCP connection, the sequence number after the last one that it has sent.
+    static ref SENT_SEQUENCE_NUMBERS: Mutex<BTreeMap<ConnectionKey, u32>> = Mutex::new(BTreeMap::new());
+}
+
+/// Returns whether the given outgoing TCP segment starts before the end of what its connection already sent.
+fn is_retransmission(parsed: &ParsedFrame) -> bool {
+    let tcp = parsed.payload;
+    if tcp.len() < 20 {
+        return false;
+    }
+    let key = (u16::from_be_bytes([tcp[0], tcp[1]]), parsed.dst_addr, u16::from_be_bytes([tcp[2], tcp[3]]));
+    let seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+    let data_offset = ((tcp[12] >> 4) as usize) * 4;
+    let flags = tcp[13];
+    let mut seq_len = tcp.len().saturating_sub(data_offset) as u32;
+    if flags & TCP_SYN != 0 { seq_len += 1; }
+    if flags & TCP_FIN != 0 { seq_len += 1; }
+
+    let mut sent = SENT_SEQUENCE_NUMBERS.lock();
+    if flags & TCP_RST != 0 {
+        sent.remove(&key);
+        return false;
+    }
+    if seq_len == 0 {
+        // Pure ACKs don't occupy sequence space, so they can't be retransmissions.
+        return false;
+    }
+    let end = seq.wrapping_add(seq_len);
+    let (retransmitted, next) = match sent.get(&key) {
+        // A new SYN from a reused port starts a new connection, unless it's the same SYN again.
+        Some(&next) if flags & TCP_SYN != 0 && end != next => (false, end),
+        // Sequence numbers wrap around, so compare them by their signed distance.
+        Some(&next) if (next.wrapping_sub(seq) as i32) > 0 => {
+            (true, if (end.wrapping_sub(next) as i32) > 0 { end } else { next })
+        }
+        _ => (false, end),
+    };
+    if !sent.contains_key(&key) && sent.len() >= MAX_TRACKED_CONNECTIONS {
+        let oldest = sent.keys().next().cloned();
+        if let Some(oldest) = oldest {
+            sent.remove(&oldest);
+        }
+    }
+    sent.insert(key, next);
+    retransmitted
+}
+
+
+/// Returns whether the checksums of the given received packet are valid, or if it has none that can be checked.
+fn has_valid_checksums(parsed: &ParsedFrame) -> bool {
+    if parsed.ip_header.is_empty() {
+        return true;
+    }
+    if checksum(0, parsed.ip_header) != 0xFFFF {
+        return false;
+    }
+    // Fragments can't be checked on their own, so only unfragmented packets are checked.
+    let ip = parsed.ip_header;
+    let more_fragments = ip[6] & 0x20 != 0;
+    let fragment_offset = u16::from_be_bytes([ip[6] & 0x1F, ip[7]]);
+    if more_fragments || fragment_offset != 0 {
+        return true;
+    }
+    let payload = parsed.payload;
+    let (protocol_number, min_len) = match parsed.protocol {
+        Protocol::Tcp => (IP_PROTOCOL_TCP, 20),
+        Protocol::Udp => {
+            // A UDP checksum of zero means the sender didn't compute one.
+            if payload.len() >= 8 && payload[6] == 0 && payload[7] == 0 {
+                return true;
+            }
+            (IP_PROTOCOL_UDP, 8)
+        }
+        Protocol::Icmp => return payload.len() < 4 || checksum(0, payload) == 0xFFFF,
+        Protocol::Other => return true,
+    };
+    if payload.len() < min_len {
+        return false;
+    }
+    let mut pseudo_header = [0u8; 12];
+    pseudo_header[0..4].copy_from_slice(&parsed.src_addr);
+    pseudo_header[4..8].copy_from_slice(&parsed.dst_addr);
+    pseudo_header[9] = protocol_number;
+    pseudo_header[10..12].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    checksum(checksum(0, &pseudo_header), payload) == 0xFFFF
+}
+
+/// Adds the given bytes to a running 16-bit ones' complement sum, as used by the Internet checksum.
+/// The sum over a packet including its checksum field is 0xFFFF if the checksum is valid.
+fn checksum(initial: u16, data: &[u8]) -> u16 {
+    let mut sum = initial as u32;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 { u16::from_be_bytes([chunk[0], chunk[1]]) } else { (chunk[0] as u16) << 8 };
+        sum += word as u32;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+
+/// The state of a socket, as of the last time its socket set was polled.
+#[derive(Clone, Copy, Debug)]
+pub struct SocketInfo {
+    pub protocol: Protocol,
+    /// The state of a TCP socket, or `None` for other sockets.
+    pub tcp_state: Option<TcpState>,
+    /// Whether the socket is open, i.e., a TCP socket that isn't closed or a UDP socket that is bound.
+    pub is_open: bool,
+    /// The local endpoint, whose address is unspecified if the socket accepts packets for any local address.
+    pub local_endpoint: IpEndpoint,
+    /// The remote endpoint of a connected TCP socket.
+    pub remote_endpoint: Option<IpEndpoint>,
+    /// The ID of the task that polled the socket's set.
+    pub task_id: Option<usize>,
+}
+
+impl SocketInfo {
+    /// Whether this is a TCP socket that is waiting for incoming connections.
+    pub fn is_listener(&self) -> bool {
+        self.tcp_state == Some(TcpState::Listen)
+    }
+}
+
+struct SocketSetSnapshot {
+    sockets: Vec<SocketInfo>,
+    last_polled: u64,
+}
+
+lazy_static! {
+    /// The latest snapshot of each socket set, keyed by the address of the socket set.
+    static ref SOCKET_SETS: Mutex<BTreeMap<usize, SocketSetSnapshot>> = Mutex::new(BTreeMap::new());
+}
+
+/// Records a snapshot of the sockets in the given set, which is being polled by the task with the given ID.
+///
+/// This should be called whenever a socket set is polled, such that its sockets appear in [`sockets()`](fn.sockets.html).
+pub fn record_sockets(sockets: &SocketSet, task_id: Option<usize>) {
+    let key = sockets as *const SocketSet as usize;
+    let now = now_millis();
+    let mut socket_sets = SOCKET_SETS.lock();
+    let snapshot = socket_sets.entry(key).or_insert_with(|| SocketSetSnapshot { sockets: Vec::new(), last_polled: now });
+    snapshot.last_polled = now;
+    // Reuse the existing vector, since socket sets are polled very frequently.
+    snapshot.sockets.clear();
+    for socket in sockets.iter() {
+        let info = match *socket {
+            Socket::Tcp(ref tcp) => SocketInfo {
+                protocol: Protocol::Tcp,
+                tcp_state: Some(tcp.state()),
+                is_open: tcp.is_open(),
+                local_endpoint: tcp.local_endpoint(),
+                remote_endpoint: if tcp.remote_endpoint().is_specified() { Some(tcp.remote_endpoint()) } else { None },
+                task_id,
+            },
+            Socket::Udp(ref udp) => SocketInfo {
+                protocol: Protocol::Udp,
+                tcp_state: None,
+                is_open: udp.is_open(),
+                local_endpoint: udp.endpoint(),
+                remote_endpoint: None,
+                task_id,
+            },
+            Socket::Icmp(ref icmp) => SocketInfo {
+                protocol: Protocol::Icmp,
+                tcp_state: None,
+                is_open: icmp.is_open(),
+                local_endpoint: IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::UNSPECIFIED), 0),
+                remote_endpoint: None,
+                task_id,
+            },
+            _ => SocketInfo {
+                protocol: Protocol::Other,
+                tcp_state: None,
+                is_open: true,
+                local_endpoint: IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::UNSPECIFIED), 0),
+                remote_endpoint: None,
+                task_id,
+            },
+        };
+        snapshot.sockets.push(info);
+    }
+}
+
+/// Returns all sockets of the socket sets that were polled within the last
+/// [`SOCKET_SET_TIMEOUT_MILLIS`](constant.SOCKET_SET_TIMEOUT_MILLIS.html),
+/// as of the last time each set was polled.
+pub fn sockets() -> Vec<SocketInfo> {
+    let now = now_millis();
+    let mut socket_sets = SOCKET_SETS.lock();
+    socket_sets.retain(|_, snapshot| now.saturating_sub(snapshot.last_polled) <= SOCKET_SET_TIMEOUT_MILLIS);
+    socket_sets.values().flat_map(|snapshot| snapshot.sockets.iter().cloned()).collect()
+}
@@ -0,0 +1,361 @@
+//! Per-type caches of fixed-size kernel objects, i.e., a slab allocator layer above the frame allocator.
+//!
+//! Allocating many small objects of the same type through the general heap, e.g., task structs,
+//! wait queue nodes, or packet metadata, fragments it over time. An [`ObjectCache`] instead carves
+//! dedicated page-sized slabs into slots for one type of object, such that those objects are packed together
+//! and a slab's pages can be given back to the frame allocator as a whole once all of its objects are free.
+//!
+//! Every object in a slab is built by the cache's constructor when the slab is created,
+//! and objects keep their state while they're free in the cache, just like in a classic object-caching allocator.
+//! Thus, an object returned by [`ObjectCache::alloc()`] is either freshly constructed or exactly as its last user left it,
+//! so a type's constructor should set up only what every user expects, and users should reset what they change.
+//! Objects are only dropped when their slab is released.
+//!
+//! # Memory pressure
+//! All caches are registered with the `memory` crate's reclaim callbacks as a whole.
+//! When memory is under pressure, each cache first invokes its own shrink callback, if it has one
+//! (see [`ObjectCache::set_shrink_callback()`]), such that its users can return the objects they're holding onto,
+//! and then releases its empty slabs. Under low pressure each cache keeps one empty slab around,
+//! whereas under min pressure every empty slab is released.
+//!
+//! [`ObjectCache`]: struct.ObjectCache.html
+//! [`ObjectCache::alloc()`]: struct.ObjectCache.html#method.alloc
+//! [`ObjectCache::set_shrink_callback()`]: struct.ObjectCache.html#method.set_shrink_callback
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate irq_safety;
+extern crate kernel_config;
+extern crate memory;
+
+use core::{
+    fmt,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    vec::Vec,
+};
+use spin::Once;
+use irq_safety::MutexIrqSafe;
+use kernel_config::memory::PAGE_SIZE;
+use memory::{create_mapping, EntryFlags, MappedPages, PressureLevel};
+
+
+/// The name that this crate's reclaim callback is registered under.
+const RECLAIMER_NAME: &'static str = "object_cache";
+
+/// Each slab is made large enough to hold at least this many objects.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// A function that is invoked when memory is under pressure, before a cache releases its empty slabs,
+/// such that the cache's users can free the objects they're holding onto but don't need.
+pub type ShrinkFn = fn(level: PressureLevel);
+
+/// Statistics about one object cache.
+#[derive(Clone, Debug)]
+pub struct CacheStats {
+    pub name: &'static str,
+    /// The size of each object, including the padding needed to align the next one.
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub pages_per_slab: usize,
+    pub slabs: usize,
+    /// The number of slabs in which every object is free, which can be released.
+    pub empty_slabs: usize,
+    pub objects_in_use: usize,
+    /// The number of objects in all slabs, whether in use or free.
+    pub objects_total: usize,
+}
+
+/// The operations that the reclaim callback and `stats()` perform on every cache, regardless of its object type.
+trait Cache: Send + Sync {
+    fn stats(&self) -> CacheStats;
+    fn reclaim(&self, level: PressureLevel, target_frames: usize) -> usize;
+}
+
+/// All object caches that have been created, in the order they were created.
+static CACHES: MutexIrqSafe<Vec<&'static dyn Cache>> = MutexIrqSafe::new(Vec::new());
+
+/// Ensures the reclaim callback is only registered once.
+static RECLAIMER_REGISTERED: Once<()> = Once::new();
+
+
+/// A slab of pages that holds a fixed number of objects, each of which is always constructed.
+struct Slab {
+    pages: MappedPages,
+    /// The indices of the objects in this slab that are free.
+    free: Vec<u16>,
+}
+
+struct Slabs {
+    /// The slabs of a cache, keyed by their starting virtual address.
+    slabs: BTreeMap<usize, Slab>,
+    objects_in_use: usize,
+}
+
+/// A cache of objects of type `T`, which are stored in slabs dedicated to that type.
+///
+/// A cache is created via [`ObjectCache::create()`](#method.create), and lives forever.
+pub struct ObjectCache<T: Send + 'static> {
+    name: &'static str,
+    constructor: fn() -> T,
+    /// The distance in bytes between two objects in a slab.
+    stride: usize,
+    objects_per_slab: usize,
+    pages_per_slab: usize,
+    slabs: MutexIrqSafe<Slabs>,
+    shrink_callback: Once<ShrinkFn>,
+}
+
+impl<T: Send + 'static> ObjectCache<T> {
+    /// Creates a new cache named `name` for objects of type `T`, which are built by the given `constructor`.
+    ///
+    /// No memory is allocated until the first object is allocated.
+    /// Returns an error if `T` is zero-sized or must be aligned to more than a page.
+    pub fn create(name: &'static str, constructor: fn() -> T) -> Result<&'static ObjectCache<T>, &'static str> {
+        let align = mem::align_of::<T>();
+        if mem::size_of::<T>() == 0 {
+            return Err("object_cache: zero-sized types don't need an object cache");
+        }
+        if align > PAGE_SIZE {
+            return Err("object_cache: objects can't be aligned to more than a page");
+        }
+        let stride = (mem::size_of::<T>() + align - 1) & !(align - 1);
+        let pages_per_slab = (stride * MIN_OBJECTS_PER_SLAB + PAGE_SIZE - 1) / PAGE_SIZE;
+        let objects_per_slab = core::cmp::min(pages_per_slab * PAGE_SIZE / stride, u16::max_value() as usize);
+
+        let cache: &'static ObjectCache<T> = Box::leak(Box::new(ObjectCache {
+            name,
+            constructor,
+            stride,
+            objects_per_slab,
+            pages_per_slab,
+            slabs: MutexIrqSafe::new(Slabs { slabs: BTreeMap::new(), objects_in_use: 0 }),
+            shrink_callback: Once::new(),
+        }));
+
+        CACHES.lock().push(cache);
+        RECLAIMER_REGISTERED.call_once(|| {
+            if let Err(e) = memory::register_reclaimer(RECLAIMER_NAME, reclaim) {
+                error!("object_cache: couldn't register reclaim callback: {}", e);
+            }
+        });
+        debug!("object_cache: created cache {:?} with {} objects of {} bytes per {}-page slab",
+            name, objects_per_slab, stride, pages_per_slab
+        );
+        Ok(cache)
+    }
+
+    /// Returns the name of this cache.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Sets the function that is invoked when memory is under pressure, before this cache releases its empty slabs.
+    ///
+    /// Like all reclaim callbacks, it must not block on any locks, see the `memory` crate's memory-pressure docs.
+    /// This can only be done once.
+    pub fn set_shrink_callback(&self, callback: ShrinkFn) -> Result<(), &'static str> {
+        let mut newly_set = false;
+        self.shrink_callback.call_once(|| { newly_set = true; callback });
+        if newly_set { Ok(()) } else { Err("object_cache: a shrink callback was already set for this cache") }
+    }
+
+    /// Allocates an object from this cache, creating a new slab if every existing one is full.
+    ///
+    /// The object is returned to this cache when the returned `CachedObject` is dropped.
+    pub fn alloc(&'static self) -> Result<CachedObject<T>, &'static str> {
+        if let Some(ptr) = self.take_free_object() {
+            return Ok(CachedObject { ptr, cache: self });
+        }
+
+        // Create a new slab without holding the lock, since that may run the constructor many times.
+        let mut slab = self.create_slab()?;
+        let start = slab.pages.start_address().value();
+        let index = slab.free.pop().ok_or("object_cache: BUG: a newly-created slab had no free objects")?;
+        let ptr = NonNull::new((start + index as usize * self.stride) as *mut T)
+            .ok_or("object_cache: BUG: a newly-created slab was mapped at address 0")?;
+        let mut slabs = self.slabs.lock();
+        slabs.slabs.insert(start, slab);
+        slabs.objects_in_use += 1;
+        Ok(CachedObject { ptr, cache: self })
+    }
+
+    /// Releases every empty slab of this cache, and returns the number of frames that were freed.
+    pub fn shrink(&self) -> usize {
+        let released = self.remove_empty_slabs(&mut self.slabs.lock(), 0, usize::max_value());
+        self.release_slabs(released)
+    }
+
+    /// Returns statistics about this cache.
+    pub fn stats(&self) -> CacheStats {
+        let slabs = self.slabs.lock();
+        CacheStats {
+            name: self.name,
+            object_size: self.stride,
+            objects_per_slab: self.objects_per_slab,
+            pages_per_slab: self.pages_per_slab,
+            slabs: slabs.slabs.len(),
+            empty_slabs: slabs.slabs.values().filter(|s| s.free.len() == self.objects_per_slab).count(),
+            objects_in_use: slabs.objects_in_use,
+            objects_total: slabs.slabs.len() * self.objects_per_slab,
+        }
+    }
+
+    /// Takes a free object from the lowest-addressed slab that has one,
+    /// which keeps higher slabs empty such that they can be released.
+    fn take_free_object(&self) -> Option<NonNull<T>> {
+        let mut slabs = self.slabs.lock();
+        let (start, index) = slabs.slabs.iter_mut()
+            .find_map(|(start, slab)| slab.free.pop().map(|index| (*start, index)))?;
+        slabs.objects_in_use += 1;
+        NonNull::new((start + index as usize * self.stride) as *mut T)
+    }
+
+    /// Returns the object at the given address to the slab that it belongs to.
+    fn free_object(&self, ptr: NonNull<T>) {
+        let addr = ptr.as_ptr() as usize;
+        let mut slabs = self.slabs.lock();
+        match slabs.slabs.range_mut(..=addr).next_back() {
+            Some((start, slab)) if addr < start + slab.pages.size_in_bytes() => {
+                slab.free.push(((addr - start) / self.stride) as u16);
+            }
+            _ => {
+                error!("object_cache: BUG: object at {:#X} doesn't belong to any slab of cache {:?}", addr, self.name);
+                return;
+            }
+        }
+        slabs.objects_in_use -= 1;
+    }
+
+    /// Allocates the pages for a new slab and constructs every object in it.
+    fn create_slab(&self) -> Result<Slab, &'static str> {
+        let pages = create_mapping(self.pages_per_slab * PAGE_SIZE, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)?;
+        let start = pages.start_address().value();
+        for index in 0..self.objects_per_slab {
+            // SAFE: the slot is within the newly-mapped writable pages, and is aligned since the stride is a multiple of T's alignment.
+            unsafe { ptr::write((start + index * self.stride) as *mut T, (self.constructor)()) };
+        }
+        // Objects are taken from the end of the free list, so reverse it such that lower objects are used first.
+        let free = (0..self.objects_per_slab as u16).rev().collect();
+        Ok(Slab { pages, free })
+    }
+
+    /// Removes up to enough empty slabs to cover `target_frames`, but keeps `keep` empty slabs, and returns the removed slabs.
+    fn remove_empty_slabs(&self, slabs: &mut Slabs, keep: usize, target_frames: usize) -> Vec<Slab> {
+        let mut empty: Vec<usize> = slabs.slabs.iter()
+            .filter(|(_, slab)| slab.free.len() == self.objects_per_slab)
+            .map(|(start, _)| *start)
+            .collect();
+        // Release the highest slabs first, since lower ones are preferred for allocation.
+        empty.reverse();
+        let count = core::cmp::min(
+            empty.len().saturating_sub(keep),
+            (target_frames + self.pages_per_slab - 1) / self.pages_per_slab,
+        );
+        empty.iter().take(count).filter_map(|start| slabs.slabs.remove(start)).collect()
+    }
+
+    /// Drops every object in the given slabs and unmaps them, returning the number of frames that were freed.
+    fn release_slabs(&self, released: Vec<Slab>) -> usize {
+        let mut frames = 0;
+        for slab in released {
+            let start = slab.pages.start_address().value();
+            for index in 0..self.objects_per_slab {
+                // SAFE: every object in an empty slab is constructed, and nothing refers to it anymore.
+                unsafe { ptr::drop_in_place((start + index * self.stride) as *mut T) };
+            }
+            frames += slab.pages.size_in_pages();
+        }
+        frames
+    }
+}
+
+impl<T: Send + 'static> Cache for ObjectCache<T> {
+    fn stats(&self) -> CacheStats {
+        ObjectCache::stats(self)
+    }
+
+    fn reclaim(&self, level: PressureLevel, target_frames: usize) -> usize {
+        if let Some(callback) = self.shrink_callback.try() {
+            callback(level);
+        }
+        let keep = if level == PressureLevel::Min { 0 } else { 1 };
+        let released = match self.slabs.try_lock() {
+            Some(mut slabs) => self.remove_empty_slabs(&mut slabs, keep, target_frames),
+            None => return 0,
+        };
+        self.release_slabs(released)
+    }
+}
+
+
+/// An object allocated from an [`ObjectCache`](struct.ObjectCache.html), which is returned to it when dropped.
+pub struct CachedObject<T: Send + 'static> {
+    ptr: NonNull<T>,
+    cache: &'static ObjectCache<T>,
+}
+
+// SAFE: a `CachedObject` uniquely owns its object, just like a `Box`.
+unsafe impl<T: Send + 'static> Send for CachedObject<T> { }
+unsafe impl<T: Send + Sync + 'static> Sync for CachedObject<T> { }
+
+impl<T: Send + 'static> Deref for CachedObject<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFE: the object is constructed and owned by this `CachedObject` until it is dropped.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: Send + 'static> DerefMut for CachedObject<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFE: the object is constructed and owned by this `CachedObject` until it is dropped.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: Send + 'static> Drop for CachedObject<T> {
+    fn drop(&mut self) {
+        self.cache.free_object(self.ptr);
+    }
+}
+
+impl<T: Send + fmt::Debug + 'static> fmt::Debug for CachedObject<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.deref(), f)
+    }
+}
+
+
+/// Returns statistics about every object cache, in the order they were created.
+pub fn stats() -> Vec<CacheStats> {
+    let caches = CACHES.lock().clone();
+    caches.iter().map(|cache| cache.stats()).collect()
+}
+
+/// The reclaim callback that shrinks every object cache when memory is under pressure.
+fn reclaim(level: PressureLevel, target_frames: usize) -> usize {
+    let caches = match CACHES.try_lock() {
+        Some(caches) => caches.clone(),
+        None => return 0,
+    };
+    let mut freed = 0;
+    for cache in caches {
+        if freed >= target_frames {
+            break;
+        }
+        freed += cache.reclaim(level, target_frames - freed);
+    }
+    if freed > 0 {
+        warn!("object_cache: released {} frames of empty slabs due to memory pressure", freed);
+    }
+    freed
+}
@@ -30,18 +30,18 @@ use itertools::Itertools;
 use hpet::get_hpet;
 use smoltcp::{
     wire::{Ipv4Address, IpEndpoint},
-    socket::{SocketSet, TcpSocket, TcpSocketBuffer, TcpState},
+    socket::{SocketSet, TcpSocket, TcpState},
 };
 use sha3::{Digest, Sha3_512};
 use percent_encoding::{DEFAULT_ENCODE_SET, utf8_percent_encode};
-use network_manager::{NetworkInterfaceRef};
+use network_manager::{NetworkInterfaceRef, shaping::{self, BULK_CLASS}};
 use rand::{
     SeedableRng,
     RngCore,
     rngs::SmallRng
 };
 use http_client::{HttpResponse, ConnectedTcpSocket, send_request, check_http_request};
-use smoltcp_helper::{STARTING_FREE_PORT, connect, millis_since, poll_iface};
+use smoltcp_helper::{STARTING_FREE_PORT, DEFAULT_TCP_BUFFER_SIZE, BULK_TCP_BUFFER_SIZE, connect, millis_since, new_tcp_socket, poll_iface};
 
 /// The IP address of the update server.
 // const DEFAULT_DESTINATION_IP_ADDR: [u8; 4] = [168, 7, 138, 84]; // the static IP of `kevin.recg.rice.edu`
@@ -330,11 +330,12 @@ fn download_files<S: AsRef<str>>(
 
     // the below items may be overwritten on each loop iteration, if the socket was closed and we need to create a new one
     let mut local_port = STARTING_FREE_PORT + (rng.next_u32() as u16 % rng_upper_bound);
-    let mut tcp_rx_buffer = TcpSocketBuffer::new(vec![0; 4096]);
-    let mut tcp_tx_buffer = TcpSocketBuffer::new(vec![0; 4096]);
-    let mut tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
+    // The downloaded files are much larger than the requests, so only the receive buffer needs to be large.
+    let mut tcp_socket = new_tcp_socket(BULK_TCP_BUFFER_SIZE, DEFAULT_TCP_BUFFER_SIZE);
     let mut sockets = SocketSet::new(Vec::with_capacity(1));
     let mut tcp_handle = sockets.add(tcp_socket);
+    // Downloads are rate-limited as bulk traffic, such that they don't starve interactive sessions.
+    let mut _shaped_port = shaping::shape_port(local_port, BULK_CLASS)?;
 
     // first, attempt to connect the socket to the remote server
     connect(iface, &mut sockets, tcp_handle, remote_endpoint, local_port, startup_time)?;
@@ -380,11 +381,10 @@ fn download_files<S: AsRef<str>>(
             
             // second, create an entirely new socket and connect it
             local_port = STARTING_FREE_PORT + (rng.next_u32() as u16 % rng_upper_bound);
-            tcp_rx_buffer = TcpSocketBuffer::new(vec![0; 4096]);
-            tcp_tx_buffer = TcpSocketBuffer::new(vec![0; 4096]);
-            tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
+            tcp_socket = new_tcp_socket(BULK_TCP_BUFFER_SIZE, DEFAULT_TCP_BUFFER_SIZE);
             sockets = SocketSet::new(Vec::with_capacity(1));
             tcp_handle = sockets.add(tcp_socket);
+            _shaped_port = shaping::shape_port(local_port, BULK_CLASS)?;
             connect(iface, &mut sockets, tcp_handle, remote_endpoint, local_port, startup_time)?;
         }
 
@@ -8,12 +8,16 @@
 //! 
 //! The core allocation function is [`allocate_pages_deferred()`](fn.allocate_pages_deferred.html), 
 //! but there are several convenience functions that offer simpler interfaces for general usage. 
+//! 
+//! When KASLR is enabled, allocations that don't request a specific address are placed at a random offset
+//! into the free chunk they're taken from, once the list of chunks is heap-allocated; see the `kaslr` crate.
 
 #![no_std]
 
 extern crate alloc;
 #[macro_use] extern crate log;
 extern crate kernel_config;
+extern crate kaslr;
 extern crate memory_structs;
 extern crate spin;
 
@@ -27,6 +31,9 @@ use memory_structs::{VirtualAddress, Page, PageRange};
 use spin::Mutex;
 
 
+/// The maximum random offset in pages at which an allocation is placed into its free chunk, see `kaslr::random_below()`.
+const MAX_RANDOM_OFFSET_IN_PAGES: usize = KASLR_MAX_SLIDE / PAGE_SIZE;
+
 /// The single, system-wide list of free virtual memory pages.
 /// Currently this list includes both free and allocated chunks of pages together in the same list,
 /// but it may be better to separate them in the future,
@@ -239,6 +246,11 @@ impl<T> StaticArrayLinkedList<T> {
 		}
 	}
 
+	/// Returns whether the contained collection is a heap-allocated `LinkedList`.
+	pub fn is_heap_allocated(&self) -> bool {
+		if let StaticArrayLinkedList::LinkedList(_) = self { true } else { false }
+	}
+
 	/// Converts the contained collection from a primitive array into a LinkedList.
 	/// If the contained collection is already using heap allocation, this is a no-op.
 	/// 
@@ -364,7 +376,8 @@ impl<'list> Drop for DeferredAllocAction<'list> {
 /// # Arguments
 /// * `requested_vaddr`: if `Some`, the returned `AllocatedPages` will start at the `Page`
 ///   containing this `VirtualAddress`. 
-///   If `None`, the first available `Page` range will be used, starting at any random virtual address.
+///   If `None`, the first available `Page` range will be used, starting at any random virtual address;
+///   with KASLR, that is a random offset into the first free chunk that is large enough.
 /// * `num_pages`: the number of `Page`s to be allocated. 
 /// 
 /// # Return
@@ -385,11 +398,21 @@ pub fn allocate_pages_deferred(
 	let desired_start_page = requested_vaddr.map(|vaddr| Page::containing_address(vaddr));
 
 	let mut locked_list = FREE_PAGE_LIST.lock();
+	// Before the list is heap-allocated, splitting chunks into more pieces would quickly use up its few static entries.
+	let randomize = desired_start_page.is_none() && kaslr::is_enabled() && locked_list.is_heap_allocated();
 	for c in locked_list.iter_mut() {
 		// Look for the chunk that contains the desired address, 
 		// or any chunk that is large enough, if no desired address was requested.
 		// Obviously, we cannot use any chunk that is already allocated. 
-		let potential_start_page = desired_start_page.unwrap_or(*c.pages.start());
+		let potential_start_page = desired_start_page.unwrap_or_else(|| {
+			let random_offset = if randomize && !c.allocated {
+				let slack = c.pages.size_in_pages().saturating_sub(num_pages);
+				kaslr::random_below(core::cmp::min(slack, MAX_RANDOM_OFFSET_IN_PAGES) + 1)
+			} else {
+				0
+			};
+			*c.pages.start() + random_offset
+		});
 		// The end page is an inclusive bound, hence the -1. Parentheses are needed to avoid overflow.
 		let potential_end_page   = potential_start_page + (num_pages - 1); 
 		if potential_start_page >= *c.pages.start() && potential_end_page <= *c.pages.end() {
@@ -14,6 +14,7 @@ extern crate alloc;
 #[macro_use] extern crate vga_buffer;
 extern crate memory;
 extern crate mod_mgmt;
+extern crate crash_kernel;
 #[cfg(not(loadable))] extern crate panic_wrapper;
 #[cfg(not(loadable))] extern crate unwind;
 
@@ -67,6 +68,8 @@ fn panic_entry_point(info: &PanicInfo) -> ! {
         // basic early panic printing with no dependencies
         println_raw!("\nPANIC: {}", info);
         error!("PANIC: {}", info);
+        // The panic couldn't be handled, so hand over to the crash kernel, if one has been loaded.
+        crash_kernel::jump_to_crash_kernel(format_args!("PANIC: {}", info));
     }
 
     // If we failed to handle the panic, there's not really much we can do about it,
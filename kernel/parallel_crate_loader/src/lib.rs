@@ -0,0 +1,345 @@
+//! Loads and links a set of crates in parallel, spreading the work across all cores.
+//!
+//! Loading a crate consists of two stages, which are described in more detail in `mod_mgmt`:
+//! 1. Parsing the crate object file, mapping and copying its sections into memory,
+//!    and adding its public symbols to the namespace's symbol map.
+//! 2. Performing relocations, which links the crate's sections against the sections they depend on.
+//!
+//! The second stage of any crate may depend on symbols from any other crate in the set,
+//! so we cannot start relocating any crate until *every* crate has finished the first stage.
+//! Thus, each worker task runs the first stage for all of its assigned crates,
+//! then waits at a barrier for all other workers to reach that point,
+//! and then relocates its assigned crates.
+//! The last worker to reach the barrier adds the public symbols of every worker's crates to the symbol map at once,
+//! such that the symbol map is only copied once rather than once per crate.
+//! This is the same ordering that [`CrateNamespace::load_crates()`] uses, just spread out across several tasks.
+//!
+//! Crates are distributed to workers based on their object file size (largest first),
+//! which is a decent approximation of how long each one takes to load.
+//!
+//! [`CrateNamespace::load_crates()`]: ../mod_mgmt/struct.CrateNamespace.html#method.load_crates
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate memory;
+extern crate mod_mgmt;
+extern crate fs_node;
+extern crate path;
+extern crate apic;
+extern crate task;
+extern crate spawn;
+extern crate scheduler;
+
+
+use core::{
+    ops::Deref,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use alloc::{
+    boxed::Box,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use memory::MmiRef;
+use mod_mgmt::{CrateNamespace, StrongCrateRef};
+use fs_node::FileRef;
+use path::Path;
+use task::{TaskRef, ExitValue};
+
+
+/// The return type of each worker task.
+type WorkerResult = Result<(), &'static str>;
+
+
+/// Loads all of the given `crate_files` into the given `namespace`,
+/// using one worker task per core (or fewer, if there are fewer crates than cores).
+///
+/// This is the parallel equivalent of [`CrateNamespace::load_crates()`], and accepts the same arguments.
+/// Like that function, it supports crates with circular dependencies on each other.
+///
+/// This function blocks until all crates have been loaded and linked,
+/// so it must be called from a regular task with interrupts enabled.
+///
+/// If loading any crate fails, or any worker panics or is killed, all workers will stop as soon as possible and an error is returned.
+/// Note that the crates that were already loaded at that point may remain in the namespace.
+///
+/// [`CrateNamespace::load_crates()`]: ../mod_mgmt/struct.CrateNamespace.html#method.load_crates
+pub fn load_crates_parallel(
+    namespace: &Arc<CrateNamespace>,
+    crate_files: Vec<FileRef>,
+    temp_backup_namespace: Option<&Arc<CrateNamespace>>,
+    kernel_mmi_ref: &MmiRef,
+    verbose_log: bool,
+) -> Result<(), &'static str> {
+    if crate_files.is_empty() {
+        return Ok(());
+    }
+
+    let cores: Vec<u8> = apic::get_lapics().iter().map(|(apic_id, _lapic)| *apic_id).collect();
+    let num_workers = core::cmp::min(cores.len(), crate_files.len());
+    if num_workers <= 1 {
+        // No point in spawning tasks if there's only one core or one crate.
+        let crate_files_iter = crate_files.iter();
+        return namespace.load_crates(crate_files_iter, temp_backup_namespace.map(|ns| ns.deref()), kernel_mmi_ref, verbose_log);
+    }
+
+    let num_crates = crate_files.len();
+    let assignments = assign_crates_to_workers(crate_files, num_workers);
+    let barrier = Arc::new(StageBarrier::new(num_workers));
+
+    let mut workers: Vec<TaskRef> = Vec::with_capacity(num_workers);
+    for (worker_index, (core, assigned_files)) in cores.into_iter().zip(assignments).enumerate() {
+        let args = LoadWorkerArgs {
+            namespace: Arc::clone(namespace),
+            crate_files: assigned_files,
+            temp_backup_namespace: temp_backup_namespace.cloned(),
+            kernel_mmi_ref: Arc::clone(kernel_mmi_ref),
+            barrier: Arc::clone(&barrier),
+            verbose_log,
+        };
+        let spawn_result = spawn::new_task_builder(load_worker, args)
+            .name(format!("crate_loader_{}", worker_index))
+            .pin_on_core(core)
+            .spawn();
+        match spawn_result {
+            Ok(taskref) => workers.push(taskref),
+            Err(e) => {
+                // Make sure the workers that were already spawned don't wait forever for this one.
+                barrier.fail();
+                // The spawn failure is the root cause, so it takes precedence over the workers' own errors.
+                let _ = join_workers(workers, &barrier);
+                return Err(e);
+            }
+        }
+    }
+
+    let result = join_workers(workers, &barrier);
+    if result.is_ok() {
+        debug!("load_crates_parallel(): loaded {} crates using {} workers", num_crates, num_workers);
+    }
+    result
+}
+
+
+/// Waits for all of the given worker tasks to exit,
+/// returning the first error that any of them returned or that occurred while joining them.
+/// 
+/// Every worker is joined even if an earlier one failed, such that no worker is left running.
+/// A worker that panicked or was killed counts as a failure.
+fn join_workers(workers: Vec<TaskRef>, barrier: &StageBarrier) -> Result<(), &'static str> {
+    // A worker that is killed without unwinding, e.g., upon request, cannot release the barrier itself,
+    // so we release it on that worker's behalf to ensure the other workers don't wait for it forever.
+    while !workers.iter().all(|w| w.lock().has_exited()) {
+        let any_killed = workers.iter().any(|w| match w.lock().get_exit_value() {
+            Some(ExitValue::Killed(_)) => true,
+            _ => false,
+        });
+        if any_killed {
+            barrier.fail();
+        }
+        scheduler::schedule();
+    }
+
+    let mut result = Ok(());
+    for worker in workers {
+        if let Err(e) = worker.join() {
+            if result.is_ok() {
+                result = Err(e);
+            }
+            continue;
+        }
+        let worker_result = match worker.take_exit_value() {
+            Some(ExitValue::Completed(exit_value)) => exit_value
+                .downcast_ref::<WorkerResult>()
+                .cloned()
+                .unwrap_or(Err("BUG: crate loader worker returned an unexpected exit value type")),
+            Some(ExitValue::Killed(kill_reason)) => {
+                error!("load_crates_parallel(): crate loader worker {:?} was killed: {}", worker, kill_reason);
+                Err("crate loader worker task panicked or was killed")
+            }
+            None => Err("BUG: crate loader worker had no exit value"),
+        };
+        if result.is_ok() {
+            result = worker_result;
+        }
+    }
+    result
+}
+
+
+/// Splits the given crate files into `num_workers` groups of roughly equal total size.
+///
+/// The largest crates are assigned first, each going to whichever worker has the least total work so far.
+fn assign_crates_to_workers(mut crate_files: Vec<FileRef>, num_workers: usize) -> Vec<Vec<FileRef>> {
+    crate_files.sort_by_cached_key(|f| core::cmp::Reverse(f.lock().size()));
+
+    let mut assignments: Vec<Vec<FileRef>> = (0..num_workers).map(|_| Vec::new()).collect();
+    let mut assigned_bytes = vec![0usize; num_workers];
+    for file in crate_files {
+        let size = file.lock().size();
+        let (least_loaded_worker, _) = assigned_bytes.iter()
+            .enumerate()
+            .min_by_key(|&(_, bytes)| *bytes)
+            .unwrap(); // cannot fail, because `num_workers` is nonzero
+        assigned_bytes[least_loaded_worker] += size;
+        assignments[least_loaded_worker].push(file);
+    }
+    assignments
+}
+
+
+/// A simple reusable-once barrier that separates the section loading stage from the relocation stage.
+struct StageBarrier {
+    /// The number of workers that have not yet finished the section loading stage.
+    remaining: AtomicUsize,
+    /// Set to `true` if any worker failed, such that the other workers stop waiting.
+    failed: AtomicBool,
+    /// Set to `true` once all loaded crates' symbols have been added to the symbol map,
+    /// which releases the workers into the relocation stage.
+    released: AtomicBool,
+    /// The crates loaded by the workers that have already arrived at this barrier.
+    loaded_crates: Mutex<Vec<StrongCrateRef>>,
+}
+impl StageBarrier {
+    fn new(num_workers: usize) -> StageBarrier {
+        StageBarrier {
+            remaining: AtomicUsize::new(num_workers),
+            failed: AtomicBool::new(false),
+            released: AtomicBool::new(false),
+            loaded_crates: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Marks the current worker as having finished the loading stage of its `new_crates`,
+    /// and then waits for all other workers to do the same.
+    ///
+    /// The last worker to arrive adds the symbols of all workers' crates to the `namespace`'s symbol map,
+    /// and then releases the other workers.
+    ///
+    /// Returns an error if another worker failed while we were waiting.
+    fn arrive_and_wait(&self, new_crates: Vec<StrongCrateRef>, namespace: &CrateNamespace, verbose_log: bool) -> Result<(), &'static str> {
+        self.loaded_crates.lock().extend(new_crates);
+        if self.remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let loaded_crates = core::mem::replace(&mut *self.loaded_crates.lock(), Vec::new());
+            let _new_syms = namespace.add_symbols_from_crates(loaded_crates.iter(), verbose_log);
+            self.released.store(true, Ordering::SeqCst);
+        }
+        while !self.released.load(Ordering::SeqCst) {
+            if self.failed.load(Ordering::SeqCst) {
+                return Err("another crate loader worker failed");
+            }
+            scheduler::schedule();
+        }
+        if self.failed.load(Ordering::SeqCst) {
+            return Err("another crate loader worker failed");
+        }
+        Ok(())
+    }
+
+    /// Indicates that a worker has failed, which releases all waiting workers.
+    fn fail(&self) {
+        self.failed.store(true, Ordering::SeqCst);
+    }
+}
+
+
+/// The argument passed to each worker task.
+struct LoadWorkerArgs {
+    namespace: Arc<CrateNamespace>,
+    crate_files: Vec<FileRef>,
+    temp_backup_namespace: Option<Arc<CrateNamespace>>,
+    kernel_mmi_ref: MmiRef,
+    barrier: Arc<StageBarrier>,
+    verbose_log: bool,
+}
+
+
+/// The entry point for each worker task.
+fn load_worker(args: LoadWorkerArgs) -> WorkerResult {
+    let mut exit_guard = BarrierExitGuard { barrier: &args.barrier, succeeded: false };
+    // If this worker panics or faults, it may never return, so its kill handler must also release the barrier.
+    let kill_handler_barrier = Arc::clone(&args.barrier);
+    task::set_my_kill_handler(Box::new(move |_kill_reason| kill_handler_barrier.fail()))?;
+
+    let result = load_worker_inner(&args);
+    exit_guard.succeeded = result.is_ok();
+    drop(exit_guard);
+
+    // Don't leave the kill handler behind once this worker can no longer fail.
+    let _kill_handler = task::get_my_current_task().and_then(|t| t.take_kill_handler());
+    result
+}
+
+/// Releases the barrier upon being dropped unless the worker succeeded, 
+/// i.e., on every other exit path from a worker, including an error return or an unwinding panic.
+struct BarrierExitGuard<'b> {
+    barrier: &'b StageBarrier,
+    succeeded: bool,
+}
+impl<'b> Drop for BarrierExitGuard<'b> {
+    fn drop(&mut self) {
+        if !self.succeeded {
+            self.barrier.fail();
+        }
+    }
+}
+
+fn load_worker_inner(args: &LoadWorkerArgs) -> WorkerResult {
+    let namespace = &args.namespace;
+    let temp_backup_namespace = args.temp_backup_namespace.as_ref().map(|ns| ns.deref());
+
+    // The crate files must remain locked throughout both stages,
+    // since each loaded ELF file borrows its underlying file's contents.
+    let mut locked_crate_files = Vec::with_capacity(args.crate_files.len());
+    for crate_file_ref in &args.crate_files {
+        locked_crate_files.push(crate_file_ref.lock());
+    }
+
+    // Stage 1: load all sections.
+    let mut partially_loaded_crates = Vec::with_capacity(locked_crate_files.len());
+    for locked_crate_file in &locked_crate_files {
+        if args.barrier.failed.load(Ordering::SeqCst) {
+            return Err("another crate loader worker failed");
+        }
+        let (new_crate_ref, elf_file) = namespace.load_crate_sections(locked_crate_file.deref(), &args.kernel_mmi_ref, args.verbose_log)?;
+        partially_loaded_crates.push((new_crate_ref, elf_file));
+    }
+
+    // Wait until every worker's crates have their public symbols in the symbol map.
+    let new_crates = partially_loaded_crates.iter().map(|(crate_ref, _)| crate_ref.clone_shallow()).collect();
+    args.barrier.arrive_and_wait(new_crates, namespace, args.verbose_log)?;
+
+    // Stage 2: perform relocations, after which the crates are ready to be used.
+    for (new_crate_ref, elf_file) in partially_loaded_crates {
+        namespace.perform_relocations(&elf_file, &new_crate_ref, temp_backup_namespace, &args.kernel_mmi_ref, args.verbose_log)?;
+        let name = new_crate_ref.lock_as_ref().crate_name.clone();
+        namespace.crate_tree().lock().insert(name.into(), new_crate_ref);
+    }
+
+    Ok(())
+}
+
+
+/// Loads every crate object file in the given `namespace`'s directory
+/// that has not yet been loaded into that namespace, using [`load_crates_parallel()`].
+///
+/// This is intended to be used during boot to eagerly load all kernel crates at once,
+/// rather than loading them one by one on demand.
+///
+/// [`load_crates_parallel()`]: fn.load_crates_parallel.html
+pub fn preload_namespace_crates(namespace: &Arc<CrateNamespace>, kernel_mmi_ref: &MmiRef) -> Result<(), &'static str> {
+    let crate_files: Vec<FileRef> = namespace.dir().get_files_starting_with("")
+        .into_iter()
+        .filter(|f| {
+            let path = Path::new(f.lock().get_absolute_path());
+            namespace.get_crate(mod_mgmt::crate_name_from_path(&path)).is_none()
+        })
+        .collect();
+
+    info!("Preloading {} crates into namespace {:?} in parallel...", crate_files.len(), namespace.name());
+    load_crates_parallel(namespace, crate_files, None, kernel_mmi_ref, false)
+}
@@ -6,6 +6,7 @@
 extern crate alloc;
 extern crate spin;
 extern crate port_io;
+extern crate port_registry;
 extern crate memory;
 extern crate bit_field;
 
@@ -66,6 +67,8 @@ const CONFIG_DATA: u16 = 0xCFC;
 
 static PCI_CONFIG_ADDRESS_PORT: Mutex<Port<u32>> = Mutex::new(Port::new(CONFIG_ADDRESS));
 static PCI_CONFIG_DATA_PORT: Mutex<Port<u32>> = Mutex::new(Port::new(CONFIG_DATA));
+/// The number of I/O ports that the PCI configuration space occupies, starting at `CONFIG_ADDRESS`.
+const CONFIG_PORTS_COUNT: u16 = 8;
 
 
 
@@ -73,7 +76,14 @@ static PCI_CONFIG_DATA_PORT: Mutex<Port<u32>> = Mutex::new(Port::new(CONFIG_DATA
 /// If the PCI bus hasn't been initialized, this initializes the PCI bus & scans it to enumerates devices.
 pub fn get_pci_buses() -> &'static Vec<PciBus> {
     static PCI_BUSES: Once<Vec<PciBus>> = Once::new();
-    PCI_BUSES.call_once( || scan_pci() )
+    PCI_BUSES.call_once( || {
+        // Claim the configuration space ports for good before using them, such that no other crate can.
+        match port_registry::claim_ports(CONFIG_ADDRESS, CONFIG_PORTS_COUNT, "pci") {
+            Ok(claim) => claim.make_permanent(),
+            Err(_e) => error!("Couldn't claim the PCI configuration space ports, scanning PCI anyway. Error: {}", _e),
+        }
+        scan_pci()
+    })
 }
 
 
@@ -194,6 +204,12 @@ pub struct PciLocation {
 }
 
 impl PciLocation {
+    /// Creates the location of the PCI function at the given `bus`, `slot`, and `func`,
+    /// which is useful for accessing the configuration space of a function that may not exist, e.g., from ACPI.
+    pub fn new(bus: u16, slot: u16, func: u16) -> PciLocation {
+        PciLocation { bus, slot, func }
+    }
+
     pub fn bus(&self) -> u16 { self.bus }
     pub fn slot(&self) -> u16 { self.slot }
     pub fn function(&self) -> u16 { self.func }
@@ -7,6 +7,7 @@
 extern crate spin;
 #[macro_use] extern crate log;
 extern crate port_io;
+extern crate port_registry;
 extern crate x86_64;
 
 use port_io::Port;
@@ -35,6 +36,9 @@ static PIT_CHANNEL_2: Mutex<Port<u8>> = Mutex::new( Port::new(CHANNEL2) );
 
 static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
 
+/// The divisor that PIT channel 0 was configured with by `init()`, or `0` if it hasn't been configured.
+static PIT_CHANNEL_0_DIVISOR: AtomicUsize = AtomicUsize::new(0);
+
 
 pub fn init(freq_hertz: u32) {
     let divisor = PIT_DEFAULT_DIVIDEND_HZ / freq_hertz;
@@ -43,6 +47,29 @@ pub fn init(freq_hertz: u32) {
                 freq_hertz, PIT_MINIMUM_FREQ);
     }
 
+    // the PIT owns all of its channels and its command register for good
+    match port_registry::claim_ports(CHANNEL0, COMMAND_REGISTER - CHANNEL0 + 1, "pit_clock") {
+        Ok(claim) => claim.make_permanent(),
+        Err(_e) => error!("pit_clock::init(): couldn't claim the PIT's ports. Error: {}", _e),
+    }
+
+    PIT_CHANNEL_0_DIVISOR.store(divisor as usize, Ordering::Release);
+    program_channel_0(divisor);
+}
+
+/// Reconfigures PIT channel 0 with the frequency it was given in `init()`,
+/// which is necessary after a system suspend because the PIT doesn't retain its configuration.
+pub fn restore_after_resume() -> Result<(), &'static str> {
+    match PIT_CHANNEL_0_DIVISOR.load(Ordering::Acquire) {
+        0 => Err("pit_clock::restore_after_resume(): the PIT was never initialized"),
+        divisor => {
+            program_channel_0(divisor as u32);
+            Ok(())
+        }
+    }
+}
+
+fn program_channel_0(divisor: u32) {
     // SAFE because we're simply configuring the PIT clock, and the code below is correct.
     unsafe {
         use x86_64::instructions::port::inb;
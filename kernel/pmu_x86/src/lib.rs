@@ -73,8 +73,9 @@ extern crate apic;
 extern crate mod_mgmt;
 extern crate bit_field;
 extern crate atomic;
+extern crate msr;
 
-use x86_64::registers::msr::*;
+use msr::{IA32_PMC0, IA32_PERFEVTSEL0, IA32_FIXED_CTR0, IA32_FIXED_CTR_CTRL, IA32_PERF_GLOBAL_CTRL, IA32_PERF_GLOBAL_OVF_CTRL};
 use x86_64::VirtualAddress;
 use x86_64::structures::idt::ExceptionStackFrame;
 use x86_64::instructions::rdpmc;
@@ -88,6 +89,7 @@ use bit_field::BitField;
 use core::sync::atomic::{Ordering, AtomicU64, AtomicU8};
 
 pub mod stat;
+pub mod session;
 
 /// The minimum version ID a PMU can have, as retrieved by cpuid. Anything lower than this means a PMU is not supported.
 const MIN_PMU_VERSION: u8 = 1;
@@ -143,6 +145,7 @@ lazy_static!{
 
 /// Used to select the event type to count. Event types are described in the Intel SDM 18.2.1 for PMU Version 1.
 /// The discriminant value for each event type is the value written to the event select register for a general purpose PMC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EventType{
     /// This event counts the number of instructions at retirement. For instructions that consist of multiple micro-ops,
     /// this event counts the retirement of the last micro-op of the instruction.
@@ -258,7 +261,7 @@ pub fn init() -> Result<(), &'static str> {
             return Err("This machine does not support a PMU");
         }
         
-        init_registers();
+        init_registers()?;
         cores_initialized.insert(core_id);
         trace!("PMU initialized on core {}", core_id);
     }
@@ -268,24 +271,21 @@ pub fn init() -> Result<(), &'static str> {
 
 /// Part of the initialization routine which actually does the work of setting up the registers.
 /// This must be called for every core that wants to use the PMU.
-fn init_registers() {
-    unsafe {
-        // disables all the performance counters
-        wrmsr(IA32_PERF_GLOBAL_CTRL, 0);
-        // clear the general purpose PMCs
-        wrmsr(IA32_PMC0, 0);
-        wrmsr(IA32_PMC1, 0);
-        wrmsr(IA32_PMC2, 0);
-        wrmsr(IA32_PMC3, 0);
-        // clear the fixed event counters
-        wrmsr(IA32_FIXED_CTR0, 0);
-        wrmsr(IA32_FIXED_CTR1, 0);
-        wrmsr(IA32_FIXED_CTR2, 0);
-        // sets fixed function counters to count events at all privilege levels
-        wrmsr(IA32_FIXED_CTR_CTRL, ENABLE_FIXED_COUNTERS_FOR_ALL_PRIVILEGE_LEVELS);
-        // enables all counters: each counter has another enable bit in other MSRs so these should likely never be cleared once first set
-        wrmsr(IA32_PERF_GLOBAL_CTRL, ENABLE_FIXED_PERFORMANCE_COUNTERS | ENABLE_GENERAL_PERFORMANCE_COUNTERS);
+fn init_registers() -> Result<(), &'static str> {
+    // disables all the performance counters
+    msr::write(IA32_PERF_GLOBAL_CTRL, 0)?;
+    // clear the general purpose PMCs
+    for pmc in 0..4 {
+        msr::write(IA32_PMC0 + pmc, 0)?;
     }
+    // clear the fixed event counters
+    for fixed_counter in 0..3 {
+        msr::write(IA32_FIXED_CTR0 + fixed_counter, 0)?;
+    }
+    // sets fixed function counters to count events at all privilege levels
+    msr::write(IA32_FIXED_CTR_CTRL, ENABLE_FIXED_COUNTERS_FOR_ALL_PRIVILEGE_LEVELS)?;
+    // enables all counters: each counter has another enable bit in other MSRs so these should likely never be cleared once first set
+    msr::write(IA32_PERF_GLOBAL_CTRL, ENABLE_FIXED_PERFORMANCE_COUNTERS | ENABLE_GENERAL_PERFORMANCE_COUNTERS)
 }
 
 /// A logical counter object to correspond to a physical PMC
@@ -326,8 +326,7 @@ impl Counter {
         // for a general PMC, it enables the counter to start counting from 0
         else {
             self.start_count = 0;
-            let umask = rdmsr(IA32_PERFEVTSEL0 + self.pmc as u32);
-            unsafe{wrmsr(IA32_PERFEVTSEL0 + self.pmc as u32, umask | PMC_ENABLE);}
+            msr::modify(IA32_PERFEVTSEL0 + self.pmc as u32, |umask| umask | PMC_ENABLE)?;
         }
         Ok(())
     }
@@ -369,9 +368,8 @@ impl Drop for Counter {
         // Otherwise the counter is a fixed function counter and nothing needs to be done.
         if self.msr_mask < num_pmc as u32 {
             // clears event counting settings and counter 
-            unsafe{
-                wrmsr(IA32_PERFEVTSEL0 + self.msr_mask as u32, 0);
-                wrmsr(IA32_PMC0 + self.msr_mask as u32, 0);
+            if let Err(e) = msr::write(IA32_PERFEVTSEL0 + self.msr_mask, 0).and_then(|_| msr::write(IA32_PMC0 + self.msr_mask, 0)) {
+                error!("pmu_x86: couldn't clear PMC {} on core {}: {}", self.msr_mask, self.core, e);
             }
             free_counter(self.core, self.msr_mask as u8); 
         }
@@ -568,10 +566,8 @@ fn programmable_start(event_mask: u64) -> Result<Counter, &'static str> {
         //Claims the counter using the AtomicMap and writes the values to initialize counter (except for enable bit)
         claim_counter(my_core, pmc)?;
 
-        unsafe{
-            wrmsr(IA32_PMC0 + (pmc as u32), 0);
-            wrmsr(IA32_PERFEVTSEL0 + (pmc as u32), event_mask);
-        }
+        msr::write(IA32_PMC0 + (pmc as u32), 0)?;
+        msr::write(IA32_PERFEVTSEL0 + (pmc as u32), event_mask)?;
         return Ok(Counter {
             start_count: 0, 
             msr_mask: pmc as u32, 
@@ -704,10 +700,8 @@ pub fn start_samples(event_type: EventType, event_per_sample: u32, task_id: Opti
     // selects the appropriate mask for the event type and starts the counter
     let event_mask = event_type as u64;
 
-    unsafe{
-        wrmsr(IA32_PMC0, start_value as u64);
-        wrmsr(IA32_PERFEVTSEL0, event_mask | PMC_ENABLE | INTERRUPT_ENABLE);
-    }
+    msr::write(IA32_PMC0, start_value as u64)?;
+    msr::write(IA32_PERFEVTSEL0, event_mask | PMC_ENABLE | INTERRUPT_ENABLE)?;
 
     return Ok(());
 
@@ -716,11 +710,9 @@ pub fn start_samples(event_type: EventType, event_per_sample: u32, task_id: Opti
 /// Function to manually stop the sampling interrupts. Marks the stored instruction pointers and task IDs as ready to retrieve. 
 fn stop_samples(core_id: u8, samples: &mut SampledEvents) -> Result<(), &'static str> {
     // immediately stops counting and clears the counter
-    unsafe{
-        wrmsr(IA32_PERFEVTSEL0, 0);
-        wrmsr(IA32_PMC0, 0);
-        wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, CLEAR_PERF_STATUS_MSR);
-    }
+    msr::write(IA32_PERFEVTSEL0, 0)?;
+    msr::write(IA32_PMC0, 0)?;
+    msr::write(IA32_PERF_GLOBAL_OVF_CTRL, CLEAR_PERF_STATUS_MSR)?;
 
     // clears values so that even if exception is somehow triggered, it stops at the next iteration
     samples.start_value = 0;
@@ -796,10 +788,10 @@ pub fn find_function_names_from_samples(sample_results: &SampleResults) -> Resul
 /// Function called in the interrupt handler to store the instruction pointer and task ID. 
 /// The counter is then reset to its starting value or turned off.
 pub fn handle_sample(stack_frame: &mut ExceptionStackFrame) -> Result<(), &'static str> {
-    unsafe { wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, CLEAR_PERF_STATUS_MSR); }
+    msr::write(IA32_PERF_GLOBAL_OVF_CTRL, CLEAR_PERF_STATUS_MSR)?;
 
     let my_core_id = apic::get_my_apic_id();
-    let event_mask = rdmsr(IA32_PERFEVTSEL0);
+    let event_mask = msr::read(IA32_PERFEVTSEL0)?;
 
     let mut sampling_info = SAMPLING_INFO.lock();
     let mut samples = sampling_info.get_mut(&my_core_id).ok_or("pmu_x86::handle_sample: Could not retrieve sampling information for this core")?;
@@ -828,12 +820,10 @@ pub fn handle_sample(stack_frame: &mut ExceptionStackFrame) -> Result<(), &'stat
     }
 
     // stops the counter, resets it, and restarts it
-    unsafe {
-        wrmsr(IA32_PERFEVTSEL0, 0);
-        wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, CLEAR_PERF_STATUS_MSR);
-        wrmsr(IA32_PMC0, samples.start_value as u64);
-        wrmsr(IA32_PERFEVTSEL0, event_mask);
-    }
+    msr::write(IA32_PERFEVTSEL0, 0)?;
+    msr::write(IA32_PERF_GLOBAL_OVF_CTRL, CLEAR_PERF_STATUS_MSR)?;
+    msr::write(IA32_PMC0, samples.start_value as u64)?;
+    msr::write(IA32_PERFEVTSEL0, event_mask)?;
 
     if let Some(my_apic) = apic::get_my_apic() {
         my_apic.write().clear_pmi_mask();
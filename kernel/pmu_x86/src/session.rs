@@ -0,0 +1,252 @@
+//! Per-task performance counter sessions, which let a task measure its own events
+//! without owning the whole PMU.
+//!
+//! A task opens a session for a set of events via [`open()`], after which those events are only counted
+//! while that task is running: its counters are started when it is switched in and stopped when it is switched out,
+//! and the counts are accumulated into the task's totals across all of its time slices.
+//! Since programmable counters are only claimed while the task runs, other tasks and the sampling code
+//! can use them in the meantime. If no programmable counter is free when the task is switched in,
+//! that event isn't counted for that time slice, and the session's counts are marked as incomplete.
+//!
+//! Every count is also aggregated per core, see [`core_counts()`].
+//!
+//! `pmu_x86::init()` must have been invoked on every core that a task with a session may run on.
+//!
+//! # Example
+//! ```
+//! pmu_x86::session::open(&[EventType::LastLevelCacheMisses, EventType::BranchMissesRetired])?;
+//! ...
+//! // code to be measured, which may block or be preempted
+//! ...
+//! let counts = pmu_x86::session::close()?;
+//! ```
+//!
+//! [`open()`]: fn.open.html
+//! [`core_counts()`]: fn.core_counts.html
+
+use alloc::boxed::Box;
+use task::{Task, TaskRef, PMU_SWITCH_FUNCTION};
+use crate::*;
+
+/// Every event that can be counted, in the order that their per-core counts are stored.
+const ALL_EVENTS: [EventType; 7] = [
+    EventType::InstructionsRetired,
+    EventType::UnhaltedCoreCycles,
+    EventType::UnhaltedReferenceCycles,
+    EventType::LastLevelCacheReferences,
+    EventType::LastLevelCacheMisses,
+    EventType::BranchInstructionsRetired,
+    EventType::BranchMissesRetired,
+];
+
+/// The counters are 48 bits wide on all supported PMUs, so differences between two reads must wrap at that width.
+const COUNTER_MASK: u64 = (1 << 48) - 1;
+
+lazy_static! {
+    /// The counts of every event in `ALL_EVENTS` that sessions have accumulated on each core, indexed by APIC ID.
+    /// These are allocated up front, since they're updated during context switches.
+    static ref CORE_COUNTS: MutexIrqSafe<Vec<[u64; 7]>> = MutexIrqSafe::new(
+        (0..CORES_SUPPORTED_BY_PMU).map(|_| [0; 7]).collect()
+    );
+}
+
+fn event_index(event: EventType) -> usize {
+    ALL_EVENTS.iter().position(|e| *e == event).unwrap_or(0)
+}
+
+
+/// The counts of a session's events.
+#[derive(Clone, Debug)]
+pub struct SessionCounts {
+    /// The count of each event in the session, in the order they were given to `open()`.
+    pub counts: Vec<(EventType, u64)>,
+    /// Whether some events weren't counted during some of the task's time slices,
+    /// because no programmable counter was free when it was switched in.
+    pub incomplete: bool,
+}
+
+impl SessionCounts {
+    /// Returns the count of the given `event`, if it is part of the session.
+    pub fn get(&self, event: EventType) -> Option<u64> {
+        self.counts.iter().find(|(e, _)| *e == event).map(|(_, count)| *count)
+    }
+}
+
+/// A counter that is counting one event of a session while its task is running.
+struct LiveCounter {
+    /// The counter to pass to `rdpmc`.
+    msr_mask: u32,
+    /// The programmable counter that was claimed for this event, or `None` for a fixed counter.
+    pmc: Option<u8>,
+    start_count: u64,
+}
+
+/// The state of a task's session, which is stored in its `pmu_session`.
+struct TaskSession {
+    events: Vec<EventType>,
+    /// The count of each event, accumulated over all of the task's previous time slices.
+    totals: Vec<u64>,
+    /// The counter for each event during the current time slice, if the task is running and a counter was available.
+    live: Vec<Option<LiveCounter>>,
+    /// The core that the task was last switched in on.
+    core: u8,
+    incomplete: bool,
+}
+
+impl TaskSession {
+    fn switch_in(&mut self, core: u8) {
+        self.core = core;
+        for (i, event) in self.events.iter().enumerate() {
+            self.live[i] = start_live_counter(*event, core);
+            if self.live[i].is_none() {
+                self.incomplete = true;
+            }
+        }
+    }
+
+    fn switch_out(&mut self) {
+        let mut core_counts = CORE_COUNTS.lock();
+        for (i, event) in self.events.iter().enumerate() {
+            if let Some(counter) = self.live[i].take() {
+                let count = stop_live_counter(counter, self.core);
+                self.totals[i] += count;
+                if let Some(per_core) = core_counts.get_mut(self.core as usize) {
+                    per_core[event_index(*event)] += count;
+                }
+            }
+        }
+    }
+
+    /// Returns the counts of this session, including the current time slice if `include_live` is true.
+    fn counts(&self, include_live: bool) -> SessionCounts {
+        let counts = self.events.iter().enumerate().map(|(i, event)| {
+            let live = match self.live[i] {
+                Some(ref counter) if include_live => read_live_counter(counter),
+                _ => 0,
+            };
+            (*event, self.totals[i] + live)
+        }).collect();
+        SessionCounts { counts, incomplete: self.incomplete }
+    }
+}
+
+/// Starts counting the given event on the given (current) core, or returns `None` if no programmable counter is free.
+fn start_live_counter(event: EventType, core: u8) -> Option<LiveCounter> {
+    let fixed = match event {
+        EventType::InstructionsRetired => Some(FIXED_FUNC_0_RDPMC),
+        EventType::UnhaltedCoreCycles => Some(FIXED_FUNC_1_RDPMC),
+        EventType::UnhaltedReferenceCycles => Some(FIXED_FUNC_2_RDPMC),
+        _ => None,
+    };
+    if let Some(msr_mask) = fixed {
+        // The fixed counters are always running, so they can be shared by every session.
+        return Some(LiveCounter { msr_mask, pmc: None, start_count: rdpmc(msr_mask) });
+    }
+
+    for pmc in 0..num_general_purpose_counters() {
+        if claim_counter(core, pmc).is_err() {
+            continue;
+        }
+        if msr::write(IA32_PMC0 + (pmc as u32), 0).is_err()
+            || msr::write(IA32_PERFEVTSEL0 + (pmc as u32), event as u64 | PMC_ENABLE).is_err()
+        {
+            free_counter(core, pmc);
+            return None;
+        }
+        return Some(LiveCounter { msr_mask: pmc as u32, pmc: Some(pmc), start_count: 0 });
+    }
+    None
+}
+
+fn read_live_counter(counter: &LiveCounter) -> u64 {
+    rdpmc(counter.msr_mask).wrapping_sub(counter.start_count) & COUNTER_MASK
+}
+
+/// Stops the given counter, releasing its programmable counter if it has one, and returns its count.
+fn stop_live_counter(counter: LiveCounter, core: u8) -> u64 {
+    let count = read_live_counter(&counter);
+    if let Some(pmc) = counter.pmc {
+        let _ = msr::write(IA32_PERFEVTSEL0 + (pmc as u32), 0);
+        let _ = msr::write(IA32_PMC0 + (pmc as u32), 0);
+        free_counter(core, pmc);
+    }
+    count
+}
+
+/// Invoked by the task crate on every context switch from `prev` to `next` where either has a session.
+fn switch_sessions(prev: &mut Task, next: &mut Task) {
+    // `prev` must release its programmable counters before `next` can claim them.
+    if let Some(session) = prev.pmu_session.as_mut().and_then(|s| s.downcast_mut::<TaskSession>()) {
+        session.switch_out();
+    }
+    if let Some(session) = next.pmu_session.as_mut().and_then(|s| s.downcast_mut::<TaskSession>()) {
+        session.switch_in(apic::get_my_apic_id());
+    }
+}
+
+
+/// Opens a session for the current task that counts the given `events` while it is running.
+///
+/// Returns an error if the PMU isn't available on this core, or if the current task already has a session open.
+pub fn open(events: &[EventType]) -> Result<(), &'static str> {
+    check_pmu_availability()?;
+    if events.is_empty() {
+        return Err("pmu_x86: a session must count at least one event");
+    }
+    PMU_SWITCH_FUNCTION.call_once(|| switch_sessions);
+
+    let curr = task::get_my_current_task().ok_or("pmu_x86: couldn't get the current task")?;
+    // Holding the task's lock prevents a context switch until the session has been stored.
+    let mut task = curr.lock_mut();
+    if task.pmu_session.is_some() {
+        return Err("pmu_x86: the current task already has a session open");
+    }
+    let mut session = TaskSession {
+        events: events.to_vec(),
+        totals: events.iter().map(|_| 0).collect(),
+        live: events.iter().map(|_| None).collect(),
+        core: 0,
+        incomplete: false,
+    };
+    session.switch_in(apic::get_my_apic_id());
+    task.pmu_session = Some(Box::new(session));
+    Ok(())
+}
+
+/// Returns the counts of the current task's session so far, without closing it.
+pub fn read() -> Result<SessionCounts, &'static str> {
+    let curr = task::get_my_current_task().ok_or("pmu_x86: couldn't get the current task")?;
+    let task = curr.lock();
+    let session = task.pmu_session.as_ref()
+        .and_then(|s| s.downcast_ref::<TaskSession>())
+        .ok_or("pmu_x86: the current task has no session open")?;
+    Ok(session.counts(true))
+}
+
+/// Closes the current task's session, releasing its counters, and returns its final counts.
+pub fn close() -> Result<SessionCounts, &'static str> {
+    let curr = task::get_my_current_task().ok_or("pmu_x86: couldn't get the current task")?;
+    let mut task = curr.lock_mut();
+    let session = task.pmu_session.take().ok_or("pmu_x86: the current task has no session open")?;
+    let mut session = session.downcast::<TaskSession>().map_err(|_| "pmu_x86: the current task's session is invalid")?;
+    session.switch_out();
+    Ok(session.counts(false))
+}
+
+/// Returns the counts of the given task's session, if it has one open.
+///
+/// For a task other than the current one, this only includes its completed time slices.
+pub fn task_counts(task: &TaskRef) -> Option<SessionCounts> {
+    let is_current = task::get_my_current_task_id() == Some(task.lock().id);
+    let task = task.lock();
+    task.pmu_session.as_ref()
+        .and_then(|s| s.downcast_ref::<TaskSession>())
+        .map(|session| session.counts(is_current))
+}
+
+/// Returns the count of every event that sessions have accumulated on the core with the given APIC ID.
+pub fn core_counts(core: u8) -> Vec<(EventType, u64)> {
+    let core_counts = CORE_COUNTS.lock();
+    let per_core = core_counts.get(core as usize).cloned().unwrap_or([0; 7]);
+    ALL_EVENTS.iter().cloned().zip(per_core.iter().cloned()).collect()
+}
@@ -0,0 +1,160 @@
+//! Tracks which crate owns each range of legacy x86 I/O ports, and detects conflicting claims.
+//!
+//! A driver claims the range of I/O ports it uses, e.g., the PCI configuration space ports at `0xCF8`,
+//! via [`claim_ports()`], which fails if any of those ports were already claimed by someone else.
+//! The returned [`PortClaim`] is the only way to obtain typed [`Port`]s within that range through this crate,
+//! and each port it hands out is checked to lie entirely within the claimed range.
+//! Dropping a `PortClaim` releases its ports; a driver that owns its ports for the lifetime of the system
+//! can [`make_permanent()`] its claim instead.
+//!
+//! The claims are kept in a fixed-capacity list, since some devices (e.g., serial ports) are set up
+//! before the heap is. Claiming is cooperative: ports created directly with `port_io` are not tracked.
+//!
+//! [`claim_ports()`]: fn.claim_ports.html
+//! [`PortClaim`]: struct.PortClaim.html
+//! [`Port`]: ../port_io/struct.Port.html
+//! [`make_permanent()`]: struct.PortClaim.html#method.make_permanent
+
+#![no_std]
+
+#[macro_use] extern crate log;
+extern crate alloc;
+extern crate irq_safety;
+extern crate port_io;
+
+use alloc::vec::Vec;
+use core::{fmt, mem};
+use irq_safety::MutexIrqSafe;
+use port_io::{Port, PortIn, PortOut, PortReadOnly, PortWriteOnly};
+
+
+/// The maximum number of port ranges that can be claimed at once.
+const MAX_CLAIMS: usize = 64;
+
+/// All current claims, in no particular order.
+static PORT_CLAIMS: MutexIrqSafe<[Option<PortRange>; MAX_CLAIMS]> = MutexIrqSafe::new([None; MAX_CLAIMS]);
+
+
+/// A range of I/O ports along with the name of the crate or device that owns them.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    /// The first port in this range.
+    pub start: u16,
+    /// The last port in this range, inclusive.
+    pub end: u16,
+    /// Who claimed these ports, e.g., `"pci"`.
+    pub owner: &'static str,
+}
+
+impl PortRange {
+    fn overlaps(&self, start: u16, end: u16) -> bool {
+        self.start <= end && start <= self.end
+    }
+}
+
+impl fmt::Debug for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {:#X} - {:#X}", self.owner, self.start, self.end)
+    }
+}
+
+
+/// Claims the `count` I/O ports starting at port `start` on behalf of the given `owner`.
+///
+/// Returns an error if the range is empty or exceeds the I/O port space,
+/// or if any of its ports were already claimed, in which case the existing owner is logged.
+pub fn claim_ports(start: u16, count: u16, owner: &'static str) -> Result<PortClaim, &'static str> {
+    if count == 0 {
+        return Err("claim_ports(): must claim at least one port");
+    }
+    let end = start.checked_add(count - 1).ok_or("claim_ports(): port range exceeds the I/O port space")?;
+
+    let mut claims = PORT_CLAIMS.lock();
+    if let Some(existing) = claims.iter().flatten().find(|c| c.overlaps(start, end)) {
+        error!("claim_ports(): {} couldn't claim ports {:#X} - {:#X}, which conflict with {:?}", owner, start, end, existing);
+        return Err("claim_ports(): some of the ports were already claimed by another owner");
+    }
+    let range = PortRange { start, end, owner };
+    let slot = claims.iter_mut().find(|slot| slot.is_none()).ok_or_else(|| {
+        error!("claim_ports(): couldn't claim {:?}, too many port ranges have been claimed", range);
+        "claim_ports(): too many port ranges have been claimed"
+    })?;
+    *slot = Some(range);
+    trace!("Claimed I/O ports {:?}", range);
+    Ok(PortClaim { range })
+}
+
+/// Returns the owner of the given I/O `port`, if it has been claimed.
+pub fn port_owner(port: u16) -> Option<&'static str> {
+    PORT_CLAIMS.lock().iter().flatten().find(|c| c.overlaps(port, port)).map(|c| c.owner)
+}
+
+/// Returns all currently-claimed port ranges, sorted by their first port.
+pub fn claimed_ports() -> Vec<PortRange> {
+    let mut ranges: Vec<PortRange> = PORT_CLAIMS.lock().iter().flatten().cloned().collect();
+    ranges.sort_unstable_by_key(|r| r.start);
+    ranges
+}
+
+
+/// Exclusive ownership of a range of I/O ports, obtained from [`claim_ports()`](fn.claim_ports.html).
+///
+/// The ports are released when this is dropped, after which any ports obtained from it must no longer be used.
+pub struct PortClaim {
+    range: PortRange,
+}
+
+impl PortClaim {
+    /// Returns the range of ports that this claim owns.
+    pub fn range(&self) -> PortRange {
+        self.range
+    }
+
+    /// Keeps these ports claimed for the lifetime of the system, even though this `PortClaim` is consumed.
+    pub fn make_permanent(self) {
+        mem::forget(self);
+    }
+
+    /// Returns a readable and writable port of type `T` at the given `offset` from the start of this claim.
+    pub fn port<T: PortIn + PortOut>(&self, offset: u16) -> Result<Port<T>, &'static str> {
+        self.port_number::<T>(offset).map(Port::new)
+    }
+
+    /// Returns a read-only port of type `T` at the given `offset` from the start of this claim.
+    pub fn read_only_port<T: PortIn>(&self, offset: u16) -> Result<PortReadOnly<T>, &'static str> {
+        self.port_number::<T>(offset).map(PortReadOnly::new)
+    }
+
+    /// Returns a write-only port of type `T` at the given `offset` from the start of this claim.
+    pub fn write_only_port<T: PortOut>(&self, offset: u16) -> Result<PortWriteOnly<T>, &'static str> {
+        self.port_number::<T>(offset).map(PortWriteOnly::new)
+    }
+
+    /// Returns the number of the port at the given `offset`,
+    /// checking that all of the bytes that an access of type `T` covers lie within this claim.
+    fn port_number<T>(&self, offset: u16) -> Result<u16, &'static str> {
+        let port = self.range.start.checked_add(offset).ok_or("PortClaim: port offset exceeds the I/O port space")?;
+        let last = port.checked_add(mem::size_of::<T>() as u16 - 1).ok_or("PortClaim: port access exceeds the I/O port space")?;
+        if last > self.range.end {
+            error!("PortClaim: {}-byte port at {:#X} is outside of claimed ports {:?}", mem::size_of::<T>(), port, self.range);
+            return Err("PortClaim: port is outside of the claimed port range");
+        }
+        Ok(port)
+    }
+}
+
+impl Drop for PortClaim {
+    fn drop(&mut self) {
+        let mut claims = PORT_CLAIMS.lock();
+        if let Some(slot) = claims.iter_mut().find(|slot| **slot == Some(self.range)) {
+            *slot = None;
+            trace!("Released I/O ports {:?}", self.range);
+        }
+    }
+}
+
+impl fmt::Debug for PortClaim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PortClaim({:?})", self.range)
+    }
+}
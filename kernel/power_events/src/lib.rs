@@ -0,0 +1,105 @@
+//! An event bus for power-management events, such as the laptop lid being closed or the battery running low.
+//!
+//! Drivers that detect these events, e.g., the `acpi_ec` embedded controller driver
+//! or the `acpi_events` fixed-event and GPE handler, [`publish()`] them,
+//! and each subscriber receives its own copy of every event through the queue returned by [`subscribe()`].
+//! Subscribers are expected to pop events from their queue regularly;
+//! if a subscriber's queue is full, new events for it are dropped rather than blocking the publisher.
+//!
+//! [`publish()`]: fn.publish.html
+//! [`subscribe()`]: fn.subscribe.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate irq_safety;
+extern crate mpmc;
+
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use mpmc::Queue;
+
+
+/// A power-management event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PowerEvent {
+    /// The lid was opened (`open` is `true`) or closed.
+    LidSwitch { open: bool },
+    /// The AC adapter was plugged in (`online` is `true`) or unplugged.
+    AcAdapter { online: bool },
+    /// The status of the battery at the given `index` changed, e.g., it started charging or its charge level changed.
+    BatteryStatus { index: usize, status: BatteryStatus },
+    /// The temperature of the thermal zone at the given `index` changed.
+    Thermal { index: usize, temperature: Temperature, critical: bool },
+    /// The power button was pressed.
+    PowerButton,
+    /// The sleep button was pressed.
+    SleepButton,
+}
+
+/// The status of a battery.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatteryStatus {
+    /// Whether the battery is inserted. If not, the remaining fields are meaningless.
+    pub present: bool,
+    pub charging: bool,
+    pub discharging: bool,
+    /// Whether the battery's charge is critically low.
+    pub critical: bool,
+    /// The remaining charge as a percentage of the last full charge, if known.
+    pub percent: Option<u8>,
+}
+
+/// A temperature in tenths of a kelvin, which is how ACPI reports temperatures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Temperature(pub u64);
+
+impl Temperature {
+    /// Returns this temperature in whole degrees Celsius.
+    pub fn celsius(&self) -> i64 {
+        (self.0 as i64 - 2732) / 10
+    }
+}
+
+
+/// A subscriber's queue of events, along with the name it subscribed under.
+struct Subscriber {
+    name: &'static str,
+    queue: Queue<PowerEvent>,
+}
+
+/// All subscribers, in the order they subscribed.
+static SUBSCRIBERS: MutexIrqSafe<Vec<Subscriber>> = MutexIrqSafe::new(Vec::new());
+
+
+/// Subscribes to all power-management events that are published from now on.
+///
+/// Returns the queue that the events will be pushed onto, which holds at least `capacity` events.
+/// The `name` identifies this subscriber; subscribing again with the same `name` replaces its queue.
+pub fn subscribe(name: &'static str, capacity: usize) -> Queue<PowerEvent> {
+    let queue = Queue::with_capacity(capacity);
+    let mut subscribers = SUBSCRIBERS.lock();
+    subscribers.retain(|s| s.name != name);
+    subscribers.push(Subscriber { name, queue: queue.clone() });
+    queue
+}
+
+/// Removes the subscriber with the given `name`, such that no more events are pushed onto its queue.
+pub fn unsubscribe(name: &'static str) {
+    SUBSCRIBERS.lock().retain(|s| s.name != name);
+}
+
+/// Publishes the given `event` to all subscribers, and returns how many of them received it.
+pub fn publish(event: PowerEvent) -> usize {
+    debug!("power_events: publishing {:?}", event);
+    let subscribers = SUBSCRIBERS.lock();
+    let mut delivered = 0;
+    for subscriber in subscribers.iter() {
+        match subscriber.queue.push(event.clone()) {
+            Ok(()) => delivered += 1,
+            Err(_) => warn!("power_events: dropped {:?} because subscriber {:?}'s queue is full", event, subscriber.name),
+        }
+    }
+    delivered
+}
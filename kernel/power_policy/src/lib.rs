@@ -0,0 +1,160 @@
+//! Decides what to do when a power-management event is published on the `power_events` bus.
+//!
+//! The action that is taken when the power button is pressed, the sleep button is pressed, or the lid is closed
+//! is a [`PowerAction`], which is configurable at runtime through these tunables in the `config_registry`:
+//! * `power.power_button_action`, which shuts down by default,
+//! * `power.sleep_button_action`, which sleeps by default,
+//! * `power.lid_close_action`, which sleeps by default.
+//!
+//! Shutting down goes through the `shutdown_manager`, such that all subsystems are quiesced first.
+//! Theseus can't suspend itself yet, so sleeping invokes the handler given to [`set_suspend_handler()`],
+//! or only logs an error if there is none.
+//!
+//! [`PowerAction`]: enum.PowerAction.html
+//! [`set_suspend_handler()`]: fn.set_suspend_handler.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate mpmc;
+extern crate power_events;
+extern crate shutdown_manager;
+extern crate config_registry;
+extern crate tsc;
+extern crate spawn;
+extern crate sleep;
+
+use alloc::string::String;
+use spin::Once;
+use mpmc::Queue;
+use config_registry::Tunable;
+use power_events::PowerEvent;
+use shutdown_manager::ShutdownKind;
+
+
+/// A function that suspends the machine, and returns once it has resumed.
+pub type SuspendFn = fn() -> Result<(), &'static str>;
+
+/// What to do upon a power-management event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerAction {
+    /// Do nothing.
+    Ignore = 0,
+    /// Quiesce all subsystems and power off the machine.
+    Shutdown = 1,
+    /// Suspend the machine, see [`set_suspend_handler()`](fn.set_suspend_handler.html).
+    Sleep = 2,
+}
+
+impl PowerAction {
+    fn from_tunable(tunable: &Tunable) -> PowerAction {
+        match tunable.get() {
+            1 => PowerAction::Shutdown,
+            2 => PowerAction::Sleep,
+            _ => PowerAction::Ignore,
+        }
+    }
+}
+
+
+/// What to do when the power button is pressed.
+pub static POWER_BUTTON_ACTION: Tunable = Tunable::new(
+    "power.power_button_action", "what to do when the power button is pressed (0: ignore, 1: shut down, 2: sleep)",
+    PowerAction::Shutdown as u64, 0, 2,
+);
+
+/// What to do when the sleep button is pressed.
+pub static SLEEP_BUTTON_ACTION: Tunable = Tunable::new(
+    "power.sleep_button_action", "what to do when the sleep button is pressed (0: ignore, 1: shut down, 2: sleep)",
+    PowerAction::Sleep as u64, 0, 2,
+);
+
+/// What to do when the lid is closed.
+pub static LID_CLOSE_ACTION: Tunable = Tunable::new(
+    "power.lid_close_action", "what to do when the lid is closed (0: ignore, 1: shut down, 2: sleep)",
+    PowerAction::Sleep as u64, 0, 2,
+);
+
+/// How often the queue of power events is checked, in milliseconds.
+const POLL_INTERVAL_MS: u64 = 100;
+/// The number of power events that can be waiting to be handled.
+const EVENT_QUEUE_CAPACITY: usize = 16;
+
+/// The function that suspends the machine, if there is one.
+static SUSPEND_HANDLER: Once<SuspendFn> = Once::new();
+
+
+/// Sets the function that is invoked to suspend the machine when a `PowerAction::Sleep` is taken.
+///
+/// This can only be done once; an error is returned if a suspend handler was already set.
+pub fn set_suspend_handler(handler: SuspendFn) -> Result<(), &'static str> {
+    let mut newly_set = false;
+    SUSPEND_HANDLER.call_once(|| { newly_set = true; handler });
+    if newly_set { Ok(()) } else { Err("power_policy: a suspend handler was already set") }
+}
+
+/// Takes the given `action` in response to the given `reason`, e.g., "the power button was pressed".
+pub fn take_action(action: PowerAction, reason: &str) -> Result<(), &'static str> {
+    match action {
+        PowerAction::Ignore => {
+            debug!("power_policy: ignoring that {}", reason);
+            Ok(())
+        }
+        PowerAction::Shutdown => {
+            info!("power_policy: shutting down because {}", reason);
+            shutdown_manager::shutdown(ShutdownKind::PowerOff)
+        }
+        PowerAction::Sleep => {
+            let suspend = SUSPEND_HANDLER.try().ok_or("power_policy: suspending the machine isn't supported yet")?;
+            info!("power_policy: suspending because {}", reason);
+            suspend()
+        }
+    }
+}
+
+
+/// Registers this crate's tunables, and starts handling power events as they are published.
+///
+/// This should be invoked before any power events are published,
+/// otherwise the initial state of the lid may be missed.
+pub fn init() -> Result<(), &'static str> {
+    config_registry::register(&POWER_BUTTON_ACTION, None)?;
+    config_registry::register(&SLEEP_BUTTON_ACTION, None)?;
+    config_registry::register(&LID_CLOSE_ACTION, None)?;
+
+    let interval_ticks = tsc::get_tsc_frequency()?.saturating_mul(POLL_INTERVAL_MS) / 1000;
+    let events = power_events::subscribe("power_policy", EVENT_QUEUE_CAPACITY);
+    spawn::new_task_builder(power_policy_loop, (events, interval_ticks))
+        .name(String::from("power_policy"))
+        .spawn()?;
+    Ok(())
+}
+
+fn power_policy_loop((events, interval_ticks): (Queue<PowerEvent>, u64)) -> Result<(), &'static str> {
+    // Only closing a lid that was open is acted upon, not a lid that was already closed at boot, e.g., in a docking station.
+    let mut lid_open = None;
+    loop {
+        let start: u64 = tsc::tsc_ticks().into();
+        while let Some(event) = events.pop() {
+            let result = match event {
+                PowerEvent::PowerButton => take_action(PowerAction::from_tunable(&POWER_BUTTON_ACTION), "the power button was pressed"),
+                PowerEvent::SleepButton => take_action(PowerAction::from_tunable(&SLEEP_BUTTON_ACTION), "the sleep button was pressed"),
+                PowerEvent::LidSwitch { open } => {
+                    let was_open = lid_open.replace(open);
+                    if !open && was_open == Some(true) {
+                        take_action(PowerAction::from_tunable(&LID_CLOSE_ACTION), "the lid was closed")
+                    } else {
+                        Ok(())
+                    }
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                error!("power_policy: couldn't handle {:?}: {}", event, e);
+            }
+        }
+        sleep::sleep_until(start.saturating_add(interval_ticks))?;
+    }
+}
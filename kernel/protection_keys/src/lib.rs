@@ -0,0 +1,270 @@
+//! Memory protection keys, which restrict access to tagged mappings without needing separate page tables.
+//!
+//! Every page table entry holds a 4-bit protection key, so there are 16 keys, of which key 0 is the default for all mappings.
+//! A [`ProtectionKey`] is obtained from [`allocate_key()`], and a `MappedPages` is tagged with it via [`tag_mapped_pages()`].
+//! The access rights of every key are held in a per-core register, whose contents are represented by [`KeyRights`];
+//! revoking write or all access for a key instantly applies to every mapping tagged with that key on that core.
+//!
+//! Intel's memory protection keys come in two flavors: PKU, whose PKRU register only governs user-mode pages,
+//! and PKS, whose IA32_PKRS MSR governs supervisor-mode pages. Since all of Theseus runs in kernel mode with
+//! supervisor-mode mappings, this crate uses PKS. If the CPU doesn't support PKS, keys can still be allocated
+//! and mappings can still be tagged, but their access rights aren't enforced.
+//!
+//! # Switching rights
+//! Each task has its own rights, which are saved and restored on every context switch.
+//! A new task inherits the rights of the task that spawned it.
+//! The rights of an application crate can be restricted via [`set_crate_rights()`], which applies to every task
+//! spawned from that crate, and to every call made through [`call_into_crate()`].
+//! Within a task, [`restrict_rights()`] temporarily revokes rights until the returned [`RightsGuard`] is dropped.
+//!
+//! This protects sensitive mappings against stray accesses by untrusted or experimental crates,
+//! but not against deliberately malicious ones, since any code in kernel mode may rewrite the rights register.
+//!
+//! [`ProtectionKey`]: struct.ProtectionKey.html
+//! [`allocate_key()`]: fn.allocate_key.html
+//! [`tag_mapped_pages()`]: fn.tag_mapped_pages.html
+//! [`KeyRights`]: struct.KeyRights.html
+//! [`set_crate_rights()`]: fn.set_crate_rights.html
+//! [`call_into_crate()`]: fn.call_into_crate.html
+//! [`restrict_rights()`]: fn.restrict_rights.html
+//! [`RightsGuard`]: struct.RightsGuard.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
+extern crate spin;
+extern crate x86_64;
+extern crate memory;
+extern crate memory_x86_64;
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+};
+use spin::Mutex;
+use x86_64::registers::msr::{rdmsr, wrmsr};
+use memory::{MappedPages, get_kernel_mmi_ref};
+use memory_x86_64::enable_pks;
+
+
+/// The MSR that holds the access rights of every protection key for supervisor-mode pages.
+const IA32_PKRS: u32 = 0x6E1;
+/// The bit in CPUID leaf 7's `ecx` that indicates support for PKS.
+const CPUID_7_ECX_PKS: u32 = 1 << 31;
+
+/// The number of protection keys, including the default key 0.
+pub const NUM_KEYS: u8 = 16;
+
+
+/// A protection key, which every mapping is tagged with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProtectionKey(u8);
+
+impl ProtectionKey {
+    /// The key that all mappings are tagged with by default, whose access can't be restricted.
+    pub const DEFAULT: ProtectionKey = ProtectionKey(0);
+
+    /// Returns the number of this key, from 0 to 15.
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+}
+
+/// The access that the current task has to the mappings tagged with a given protection key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    ReadWrite,
+    ReadOnly,
+    NoAccess,
+}
+
+/// The access rights of every protection key, in the format of the rights register:
+/// bit `2k` disables all access for key `k`, and bit `2k + 1` disables writes for key `k`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyRights(u32);
+
+impl KeyRights {
+    /// Allows all access for every key.
+    pub const ALL_ACCESS: KeyRights = KeyRights(0);
+
+    /// Returns the access that these rights allow for the given `key`.
+    pub fn access(&self, key: ProtectionKey) -> Access {
+        match (self.0 >> (2 * key.0)) & 0b11 {
+            0b00 => Access::ReadWrite,
+            0b10 => Access::ReadOnly,
+            _ => Access::NoAccess,
+        }
+    }
+
+    /// Returns a copy of these rights that allow the given `access` for the given `key`.
+    ///
+    /// The access of `ProtectionKey::DEFAULT` can't be restricted, since it covers all of the kernel's own mappings.
+    pub fn with_access(self, key: ProtectionKey, access: Access) -> KeyRights {
+        if key == ProtectionKey::DEFAULT {
+            return self;
+        }
+        let bits = match access {
+            Access::ReadWrite => 0b00,
+            Access::ReadOnly => 0b10,
+            Access::NoAccess => 0b01,
+        };
+        KeyRights((self.0 & !(0b11 << (2 * key.0))) | (bits << (2 * key.0)))
+    }
+
+    /// Returns the rights that allow only what both these rights and the `other` rights allow.
+    pub fn intersect(self, other: KeyRights) -> KeyRights {
+        KeyRights(self.0 | other.0)
+    }
+
+    /// Returns the raw value of the rights register for these rights.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for KeyRights {
+    fn default() -> KeyRights {
+        KeyRights::ALL_ACCESS
+    }
+}
+
+
+/// Whether PKS has been enabled, in which case the rights register exists.
+/// All cores are assumed to support the same features.
+static PKS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The set of protection keys that have been allocated, one bit per key.
+/// The default key 0 is always allocated.
+static ALLOCATED_KEYS: AtomicU16 = AtomicU16::new(1);
+
+lazy_static! {
+    /// The rights of each application crate whose rights have been restricted, keyed by crate name.
+    static ref CRATE_RIGHTS: Mutex<BTreeMap<String, KeyRights>> = Mutex::new(BTreeMap::new());
+}
+
+
+/// Enables protection keys on the current core, if the CPU supports PKS.
+///
+/// This should be invoked once by each core as it boots, before any tasks are spawned onto it.
+/// Returns whether protection keys are now enforced on the current core.
+pub fn init_current_core() -> bool {
+    // Safe because CPUID is supported on all x86_64 CPUs, and leaf 7 is only read if it exists.
+    let supported = unsafe { __cpuid(0) }.eax >= 7 && unsafe { __cpuid_count(7, 0) }.ecx & CPUID_7_ECX_PKS != 0;
+    if supported {
+        unsafe {
+            wrmsr(IA32_PKRS, KeyRights::ALL_ACCESS.bits() as u64);
+            enable_pks();
+        }
+        PKS_ENABLED.store(true, Ordering::Release);
+    }
+    supported
+}
+
+/// Returns whether protection keys are enforced, i.e., whether the CPU supports PKS.
+pub fn is_enabled() -> bool {
+    PKS_ENABLED.load(Ordering::Acquire)
+}
+
+
+/// Allocates a protection key that isn't used by any other mappings yet.
+pub fn allocate_key() -> Result<ProtectionKey, &'static str> {
+    let mut allocated = ALLOCATED_KEYS.load(Ordering::Relaxed);
+    loop {
+        let key = (!allocated).trailing_zeros() as u8;
+        if key >= NUM_KEYS {
+            return Err("protection_keys: all protection keys are already allocated");
+        }
+        match ALLOCATED_KEYS.compare_exchange_weak(allocated, allocated | (1 << key), Ordering::AcqRel, Ordering::Relaxed) {
+            Ok(_) => return Ok(ProtectionKey(key)),
+            Err(current) => allocated = current,
+        }
+    }
+}
+
+/// Frees the given protection key, such that it can be allocated again.
+///
+/// The caller must first re-tag every mapping that is still tagged with this key,
+/// otherwise those mappings will be governed by the rights of whoever allocates it next.
+pub fn free_key(key: ProtectionKey) {
+    if key != ProtectionKey::DEFAULT {
+        ALLOCATED_KEYS.fetch_and(!(1 << key.0), Ordering::AcqRel);
+    }
+}
+
+/// Tags every page of the given `mapped_pages` with the given protection `key`.
+///
+/// To untag the `mapped_pages`, tag them with `ProtectionKey::DEFAULT`.
+pub fn tag_mapped_pages(mapped_pages: &mut MappedPages, key: ProtectionKey) -> Result<(), &'static str> {
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("protection_keys: KERNEL_MMI was not yet initialized!")?;
+    let new_flags = mapped_pages.flags().with_protection_key(key.0);
+    mapped_pages.remap(&mut kernel_mmi_ref.lock().page_table, new_flags)
+}
+
+
+/// Returns the rights that are currently in effect on this core, i.e., the rights of the current task.
+///
+/// If protection keys aren't enforced, all access is allowed.
+pub fn current_rights() -> KeyRights {
+    if is_enabled() {
+        KeyRights(unsafe { rdmsr(IA32_PKRS) } as u32)
+    } else {
+        KeyRights::ALL_ACCESS
+    }
+}
+
+/// Sets the rights that are in effect on this core, i.e., the rights of the current task.
+///
+/// This does nothing if protection keys aren't enforced.
+/// Prefer [`restrict_rights()`](fn.restrict_rights.html), which restores the previous rights afterwards.
+pub fn set_current_rights(rights: KeyRights) {
+    if is_enabled() {
+        // Writing IA32_PKRS doesn't need a TLB flush, the new rights apply to the very next access.
+        unsafe { wrmsr(IA32_PKRS, rights.bits() as u64) };
+    }
+}
+
+/// Restores the rights that were in effect before it was created when dropped, see [`restrict_rights()`](fn.restrict_rights.html).
+pub struct RightsGuard {
+    previous: KeyRights,
+}
+
+impl Drop for RightsGuard {
+    fn drop(&mut self) {
+        set_current_rights(self.previous);
+    }
+}
+
+/// Restricts the current task's rights to only what both its current rights and the given `rights` allow,
+/// until the returned guard is dropped.
+pub fn restrict_rights(rights: KeyRights) -> RightsGuard {
+    let previous = current_rights();
+    set_current_rights(previous.intersect(rights));
+    RightsGuard { previous }
+}
+
+
+/// Restricts the rights of the application crate with the given name,
+/// which applies to every task spawned from that crate and to every call made through `call_into_crate()`.
+pub fn set_crate_rights(crate_name: &str, rights: KeyRights) {
+    if rights == KeyRights::ALL_ACCESS {
+        CRATE_RIGHTS.lock().remove(crate_name);
+    } else {
+        info!("protection_keys: restricting crate {:?} to rights {:#X}", crate_name, rights.bits());
+        CRATE_RIGHTS.lock().insert(String::from(crate_name), rights);
+    }
+}
+
+/// Returns the rights of the application crate with the given name, which allow all access unless they were restricted.
+pub fn crate_rights(crate_name: &str) -> KeyRights {
+    CRATE_RIGHTS.lock().get(crate_name).cloned().unwrap_or(KeyRights::ALL_ACCESS)
+}
+
+/// Runs the given closure, which calls into the crate with the given name, with that crate's rights in effect.
+pub fn call_into_crate<F: FnOnce() -> R, R>(crate_name: &str, f: F) -> R {
+    let _guard = restrict_rights(crate_rights(crate_name));
+    f()
+}
@@ -0,0 +1,217 @@
+//! A pseudo-terminal (pty), which lets applications use a terminal that isn't a local terminal window,
+//! e.g., one at the other end of a network connection.
+//!
+//! A `Pty` has two sides:
+//! * The *slave* side is a pair of ordinary stdio queues, which are given to applications
+//!   as their stdin and their stdout/stderr, see [`slave_stdin()`] and [`slave_stdout()`].
+//! * The *master* side is driven by whatever provides the terminal, e.g., a remote shell server.
+//!   It passes the bytes typed by the user to [`input()`],
+//!   and sends the bytes returned by [`read_output()`] back to the user.
+//!
+//! In between, a simple line discipline works like the canonical mode of a Unix terminal:
+//! * Input is buffered until the end of each line, such that it can be edited with backspace,
+//!   and applications receive whole lines only. `\r` and `\r\n` are converted to `\n`.
+//! * Ctrl+C and Ctrl+D are never passed to applications, but reported to the master side as a [`PtyEvent`].
+//! * If echoing is enabled, typed characters are echoed back to the output.
+//!   It's disabled by default, as line-based clients like `nc` echo what the user types themselves.
+//! * `\n` in the output is converted to `\r\n`.
+//!
+//! [`slave_stdin()`]: struct.Pty.html#method.slave_stdin
+//! [`slave_stdout()`]: struct.Pty.html#method.slave_stdout
+//! [`input()`]: struct.Pty.html#method.input
+//! [`read_output()`]: struct.Pty.html#method.read_output
+//! [`PtyEvent`]: enum.PtyEvent.html
+
+#![no_std]
+
+extern crate alloc;
+extern crate bare_io;
+extern crate stdio;
+
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use bare_io::Write;
+use stdio::{Stdio, StdioReader, StdioWriter};
+
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+
+/// A special input that the master side must handle, as it isn't passed to applications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtyEvent {
+    /// The user pressed Ctrl+C, which should interrupt the running application.
+    /// The line being typed and the rest of the given input are discarded.
+    Interrupt,
+    /// The user pressed Ctrl+D at the start of a line, so the current input stream has ended.
+    /// The rest of the given input is discarded.
+    EndOfInput,
+}
+
+/// A pseudo-terminal, see the crate-level documentation.
+pub struct Pty {
+    /// The queue that applications read their input from.
+    input: Stdio,
+    /// The master side's own reader of `input`, for reading lines while no application is using them.
+    input_reader: StdioReader,
+    /// The queue that applications write their output to.
+    output: Stdio,
+    output_reader: StdioReader,
+    /// The line that is currently being typed, which hasn't been passed to `input` yet.
+    line: Vec<u8>,
+    /// The bytes of an incomplete line that `try_read_line()` has already read from `input`.
+    partial_read: Vec<u8>,
+    /// Whether the last input byte was `\r`, such that a following `\n` belongs to the same line ending.
+    last_was_cr: bool,
+    echo: bool,
+}
+
+impl Pty {
+    /// Creates a new pseudo-terminal with echoing disabled.
+    pub fn new() -> Pty {
+        let input = Stdio::new();
+        let output = Stdio::new();
+        Pty {
+            input_reader: input.get_reader(),
+            output_reader: output.get_reader(),
+            input,
+            output,
+            line: Vec::new(),
+            partial_read: Vec::new(),
+            last_was_cr: false,
+            echo: false,
+        }
+    }
+
+    /// Returns whether typed characters are echoed back to the output.
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    /// Sets whether typed characters are echoed back to the output.
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Returns a reader of the slave side's input, to be used as an application's stdin.
+    pub fn slave_stdin(&self) -> StdioReader {
+        self.input.get_reader()
+    }
+
+    /// Returns a writer of the slave side's output, to be used as an application's stdout or stderr.
+    pub fn slave_stdout(&self) -> StdioWriter {
+        self.output.get_writer()
+    }
+
+    /// Passes the given bytes typed by the user through the line discipline.
+    ///
+    /// Returns the first special input that the master side must handle, if any,
+    /// in which case the remaining bytes are discarded.
+    pub fn input(&mut self, bytes: &[u8]) -> Option<PtyEvent> {
+        for &byte in bytes {
+            let last_was_cr = self.last_was_cr;
+            self.last_was_cr = byte == b'\r';
+            match byte {
+                CTRL_C => {
+                    self.line.clear();
+                    self.echo_bytes(b"^C\n");
+                    return Some(PtyEvent::Interrupt);
+                }
+                CTRL_D if self.line.is_empty() => {
+                    self.input.get_writer().lock().set_eof();
+                    return Some(PtyEvent::EndOfInput);
+                }
+                BACKSPACE | DELETE => {
+                    if self.line.pop().is_some() {
+                        self.echo_bytes(b"\x08 \x08");
+                    }
+                }
+                b'\n' if last_was_cr => { }
+                b'\r' | b'\n' => {
+                    self.line.push(b'\n');
+                    self.echo_bytes(b"\n");
+                    let line = core::mem::replace(&mut self.line, Vec::new());
+                    // Writing only fails after the end of input, in which case there's no one left to read it anyway.
+                    let _ = self.input.get_writer().lock().write_all(&line);
+                }
+                _ => {
+                    self.line.push(byte);
+                    self.echo_bytes(&[byte]);
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns whether the current input stream was ended by a Ctrl+D.
+    pub fn input_ended(&self) -> bool {
+        self.input_reader.lock().is_eof()
+    }
+
+    /// Replaces an input stream that was ended by a Ctrl+D with a new one,
+    /// such that the next readers returned by [`slave_stdin()`](#method.slave_stdin) can receive input again.
+    ///
+    /// Any input that wasn't read from the old stream is discarded.
+    pub fn reopen_input(&mut self) {
+        if self.input_ended() {
+            self.input = Stdio::new();
+            self.input_reader = self.input.get_reader();
+            self.partial_read.clear();
+        }
+    }
+
+    /// Reads the next complete line from the input, without its trailing newline, if one has been typed.
+    ///
+    /// This is meant for the master side itself, e.g., for a shell that reads command lines
+    /// while it isn't running any applications, because applications would otherwise receive them.
+    /// No application should be reading from the input at the same time.
+    pub fn try_read_line(&mut self) -> Option<String> {
+        let mut buf = [0u8; 64];
+        loop {
+            if let Some(end) = self.partial_read.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.partial_read.drain(..=end).collect();
+                return Some(String::from_utf8_lossy(&line[..end]).into_owned());
+            }
+            let count = self.input_reader.lock().try_read(&mut buf).unwrap_or(0);
+            if count == 0 {
+                return None;
+            }
+            self.partial_read.extend_from_slice(&buf[..count]);
+        }
+    }
+
+    /// Writes the given bytes to the output on behalf of the master side, e.g., a shell prompt.
+    pub fn write_output(&self, bytes: &[u8]) {
+        let _ = self.output.get_writer().lock().write_all(bytes);
+    }
+
+    /// Appends the output that hasn't been read yet to `out`, converting each `\n` to `\r\n`.
+    /// Returns the number of bytes that were appended.
+    pub fn read_output(&mut self, out: &mut Vec<u8>) -> usize {
+        let start_len = out.len();
+        let mut buf = [0u8; 256];
+        loop {
+            let count = self.output_reader.lock().try_read(&mut buf).unwrap_or(0);
+            if count == 0 {
+                break;
+            }
+            for &byte in &buf[..count] {
+                if byte == b'\n' {
+                    out.push(b'\r');
+                }
+                out.push(byte);
+            }
+        }
+        out.len() - start_len
+    }
+
+    fn echo_bytes(&self, bytes: &[u8]) {
+        if self.echo {
+            self.write_output(bytes);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! A RAM-backed storage device ("ramdisk") of a configurable size.
+//! 
+//! Each [`RamDisk`] is backed by a zeroed region of kernel memory, 
+//! and implements the [`StorageDevice`] trait just like a real disk drive. 
+//! This allows filesystems and the block I/O layer to be tested without any disk hardware or disk images.
+//! 
+//! Ramdisks created via [`create_ramdisk()`] are added to a single [`RamDiskController`],
+//! which is registered with the `storage_manager` so they can be found alongside other storage devices.
+//! 
+//! [`RamDisk`]: struct.RamDisk.html
+//! [`StorageDevice`]: ../storage_device/trait.StorageDevice.html
+//! [`create_ramdisk()`]: fn.create_ramdisk.html
+//! [`RamDiskController`]: type.RamDiskController.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate memory;
+extern crate storage_device;
+extern crate virtual_storage;
+
+use alloc::sync::Arc;
+use spin::{Mutex, Once};
+use memory::{EntryFlags, MappedPages};
+use storage_device::StorageDevice;
+use virtual_storage::{VirtualStorageController, transfer_bounds};
+
+
+/// The size in bytes of a ramdisk sector.
+pub const RAMDISK_SECTOR_SIZE: usize = 512;
+
+
+/// A storage device whose contents are stored in memory.
+/// 
+/// The contents are lost when the `RamDisk` is dropped.
+pub struct RamDisk {
+    /// The memory that holds the contents of this ramdisk.
+    pages: MappedPages,
+    /// The number of sectors in this ramdisk.
+    size_in_sectors: usize,
+}
+
+impl RamDisk {
+    /// Creates a new `RamDisk` that can hold at least `size_in_bytes` bytes,
+    /// rounded up to the nearest sector size. All of its contents are initially zero.
+    pub fn new(size_in_bytes: usize) -> Result<RamDisk, &'static str> {
+        if size_in_bytes == 0 {
+            return Err("RamDisk::new(): size must be nonzero");
+        }
+        let size_in_sectors = (size_in_bytes + RAMDISK_SECTOR_SIZE - 1) / RAMDISK_SECTOR_SIZE;
+        let size_in_bytes = size_in_sectors * RAMDISK_SECTOR_SIZE;
+        let mut pages = memory::create_mapping(size_in_bytes, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE)?;
+        // The newly-mapped frames may contain stale data, so we must clear them.
+        for b in pages.as_slice_mut::<u8>(0, size_in_bytes)?.iter_mut() {
+            *b = 0;
+        }
+        Ok(RamDisk { pages, size_in_sectors })
+    }
+}
+
+impl StorageDevice for RamDisk {
+    fn read_sectors(&mut self, buffer: &mut [u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        let (byte_offset, num_sectors) = transfer_bounds(self, buffer.len(), offset_in_sectors)?;
+        buffer.copy_from_slice(self.pages.as_slice::<u8>(byte_offset, buffer.len())?);
+        Ok(num_sectors)
+    }
+
+    fn write_sectors(&mut self, buffer: &[u8], offset_in_sectors: usize) -> Result<usize, &'static str> {
+        let (byte_offset, num_sectors) = transfer_bounds(self, buffer.len(), offset_in_sectors)?;
+        self.pages.as_slice_mut::<u8>(byte_offset, buffer.len())?.copy_from_slice(buffer);
+        Ok(num_sectors)
+    }
+
+    fn sector_size_in_bytes(&self) -> usize {
+        RAMDISK_SECTOR_SIZE
+    }
+
+    fn size_in_sectors(&self) -> usize {
+        self.size_in_sectors
+    }
+}
+
+
+/// The virtual storage controller that holds all of the ramdisks created via [`create_ramdisk()`](fn.create_ramdisk.html).
+pub type RamDiskController = VirtualStorageController<RamDisk>;
+
+/// The singleton controller for all ramdisks, which is registered with the `storage_manager` upon first use.
+static RAMDISK_CONTROLLER: Once<Arc<Mutex<RamDiskController>>> = Once::new();
+
+/// Returns the controller that holds all ramdisks, registering it with the `storage_manager` if needed.
+pub fn ramdisk_controller() -> &'static Arc<Mutex<RamDiskController>> {
+    RAMDISK_CONTROLLER.call_once(RamDiskController::register)
+}
+
+/// Creates a new ramdisk of at least `size_in_bytes` bytes and adds it to the ramdisk controller. 
+/// 
+/// Returns a reference to the new ramdisk, which can be used with `block_io` or a filesystem.
+pub fn create_ramdisk(size_in_bytes: usize) -> Result<Arc<Mutex<RamDisk>>, &'static str> {
+    let ramdisk = ramdisk_controller().lock().add(RamDisk::new(size_in_bytes)?);
+    info!("Created ramdisk of {} bytes", ramdisk.lock().size_in_bytes());
+    Ok(ramdisk)
+}
+
+/// Removes the given `ramdisk` from the ramdisk controller, 
+/// such that its memory will be freed once all other references to it are dropped.
+/// 
+/// Returns an error if the `ramdisk` was not created via [`create_ramdisk()`](fn.create_ramdisk.html).
+pub fn remove_ramdisk(ramdisk: &Arc<Mutex<RamDisk>>) -> Result<(), &'static str> {
+    ramdisk_controller().lock().remove(ramdisk)
+        .map(|_| ())
+        .ok_or("remove_ramdisk(): ramdisk was not found in the ramdisk controller")
+}
@@ -0,0 +1,266 @@
+//! A read-copy-update (RCU) synchronization primitive based on epoch-based reclamation.
+//!
+//! This is intended for read-mostly kernel data structures, e.g., symbol maps or device registries.
+//! Readers never take a lock: they simply mark the current core as being within a read-side critical section
+//! and then dereference the current version of the data. 
+//! Writers create an updated copy of the data, atomically publish it, and then *retire* the old version,
+//! which is only dropped once every reader that could possibly still be using it has finished. 
+//!
+//! # How reclamation works
+//! There is a single global epoch counter. When entering a read-side critical section, 
+//! a core announces the global epoch that it observed. 
+//! The global epoch can only be advanced once every core that is within a critical section 
+//! has announced the current epoch. 
+//! An old version retired during epoch `e` can no longer be reachable by any reader once the 
+//! global epoch has reached `e + 2`, at which point it is dropped.
+//!
+//! Retired versions are reclaimed lazily by later writers, or explicitly via [`try_reclaim()`];
+//! a writer can also block until all current readers have finished via [`synchronize()`].
+//!
+//! [`try_reclaim()`]: fn.try_reclaim.html
+//! [`synchronize()`]: fn.synchronize.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate lazy_static;
+extern crate spin;
+extern crate irq_safety;
+extern crate apic;
+extern crate pause;
+
+use core::{
+    fmt,
+    ops::Deref,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    vec::Vec,
+};
+use spin::Mutex;
+use irq_safety::MutexIrqSafe;
+
+
+/// The per-core state used to track read-side critical sections.
+struct CoreEpochState {
+    /// The number of read-side critical sections currently active on this core,
+    /// which may be greater than one if they are nested or if a reader task was preempted.
+    active: AtomicUsize,
+    /// The global epoch that was observed when this core entered its outermost read-side critical section. 
+    epoch: AtomicUsize,
+}
+
+lazy_static! {
+    /// The epoch state of every core, indexed by APIC ID.
+    static ref CORE_STATES: Vec<CoreEpochState> = (0 ..= u8::max_value() as usize)
+        .map(|_| CoreEpochState { active: AtomicUsize::new(0), epoch: AtomicUsize::new(0) })
+        .collect();
+
+    /// Old versions that have been retired but may still be in use by readers,
+    /// along with the global epoch at which they were retired. 
+    static ref RETIRED: MutexIrqSafe<VecDeque<(usize, Box<dyn Send>)>> = MutexIrqSafe::new(VecDeque::new());
+}
+
+/// The global epoch counter.
+static GLOBAL_EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+
+/// A guard that represents a read-side critical section on a particular core.
+/// The critical section ends when this is dropped.
+/// 
+/// While any read-side critical section is active, no object that was reachable 
+/// when it began will be reclaimed.
+pub struct ReadSection {
+    /// The core on which this critical section began,
+    /// which may differ from the current core if the reader task has since migrated. 
+    core: usize,
+}
+
+impl Drop for ReadSection {
+    fn drop(&mut self) {
+        CORE_STATES[self.core].active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Enters a read-side RCU critical section on the current core.
+/// 
+/// Most users should use [`Rcu::read()`](struct.Rcu.html#method.read) instead.
+pub fn read_section() -> ReadSection {
+    let core = apic::get_my_apic_id() as usize;
+    let state = &CORE_STATES[core];
+    // Only the outermost critical section announces the epoch.
+    // Inner ones would have observed an epoch at least as new, so the older announcement is still conservative. 
+    if state.active.fetch_add(1, Ordering::SeqCst) == 0 {
+        state.epoch.store(GLOBAL_EPOCH.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+    ReadSection { core }
+}
+
+
+/// Attempts to advance the global epoch, which only succeeds if every core that is currently 
+/// within a read-side critical section has observed the current global epoch.
+/// 
+/// Returns the global epoch after the attempt.
+fn try_advance_epoch() -> usize {
+    let global = GLOBAL_EPOCH.load(Ordering::SeqCst);
+    let all_caught_up = CORE_STATES.iter().all(|state| 
+        state.active.load(Ordering::SeqCst) == 0 || state.epoch.load(Ordering::SeqCst) == global
+    );
+    if !all_caught_up {
+        return global;
+    }
+    match GLOBAL_EPOCH.compare_exchange(global, global + 1, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => global + 1,
+        Err(newer) => newer,
+    }
+}
+
+
+/// Retires the given object, which will be dropped once no reader could possibly be using it anymore.
+/// 
+/// The object must already be unreachable by new readers before this is called.
+pub fn retire<T: Send + 'static>(object: Box<T>) {
+    let epoch = GLOBAL_EPOCH.load(Ordering::SeqCst);
+    RETIRED.lock().push_back((epoch, object));
+}
+
+
+/// Drops all retired objects whose grace period has passed. 
+/// 
+/// Returns the number of objects that were dropped.
+pub fn try_reclaim() -> usize {
+    let global = try_advance_epoch();
+    let mut reclaimable: Vec<Box<dyn Send>> = Vec::new();
+    {
+        let mut retired = RETIRED.lock();
+        // Objects are retired in epoch order, so we only need to look at the front of the queue.
+        while retired.front().map_or(false, |(epoch, _)| epoch + 2 <= global) {
+            if let Some((_epoch, object)) = retired.pop_front() {
+                reclaimable.push(object);
+            }
+        }
+    }
+    // Drop the objects after releasing the lock, since their destructors may be arbitrarily complex.
+    let count = reclaimable.len();
+    drop(reclaimable);
+    count
+}
+
+
+/// Blocks until every read-side critical section that was active when this was called has ended,
+/// and then reclaims all retired objects whose grace period has passed. 
+/// 
+/// # Deadlock
+/// This must not be invoked from within a read-side critical section, 
+/// as the current core's critical section would then never end.
+pub fn synchronize() {
+    let target = GLOBAL_EPOCH.load(Ordering::SeqCst) + 2;
+    while try_advance_epoch() < target {
+        pause::spin_loop_hint();
+    }
+    try_reclaim();
+}
+
+
+/// A container for read-mostly data that is protected by RCU. 
+/// 
+/// Reading the data via [`read()`](#method.read) never blocks. 
+/// Updating the data via [`update()`](#method.update) or [`replace()`](#method.replace)
+/// publishes a new version of the data, while existing readers may still continue to use the previous version.
+/// Writers are serialized with respect to each other.
+pub struct Rcu<T: Send + Sync + 'static> {
+    current: AtomicPtr<T>,
+    write_lock: Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync + 'static> Send for Rcu<T> { }
+unsafe impl<T: Send + Sync + 'static> Sync for Rcu<T> { }
+
+impl<T: Send + Sync + 'static> Rcu<T> {
+    /// Creates a new `Rcu` container with the given initial value.
+    pub fn new(value: T) -> Rcu<T> {
+        Rcu {
+            current: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns a guard that dereferences to the current version of the data.
+    /// 
+    /// The returned version will remain valid for as long as the guard exists,
+    /// even if a newer version is published in the meantime.
+    /// Holding a guard for a long time delays the reclamation of all retired objects, 
+    /// so guards should be short-lived. 
+    pub fn read(&self) -> RcuReadGuard<T> {
+        let section = read_section();
+        // Safe because the pointer is always valid, and it cannot be reclaimed while `section` exists.
+        let value = unsafe { &*self.current.load(Ordering::SeqCst) };
+        RcuReadGuard { value, _section: section }
+    }
+
+    /// Publishes a new version of the data created by invoking the given `updater` function
+    /// on the current version, and then retires the previous version.
+    pub fn update<F: FnOnce(&T) -> T>(&self, updater: F) {
+        let _guard = self.write_lock.lock();
+        // Safe because only writers can retire the current version, and we hold the write lock.
+        let new_value = updater(unsafe { &*self.current.load(Ordering::SeqCst) });
+        self.publish(new_value);
+    }
+
+    /// Publishes a modified copy of the current version, which is created by cloning the current version
+    /// and then invoking the given `modifier` function on the copy, and then retires the previous version.
+    /// 
+    /// Returns the value returned by the `modifier` function.
+    pub fn modify<R, F: FnOnce(&mut T) -> R>(&self, modifier: F) -> R where T: Clone {
+        let _guard = self.write_lock.lock();
+        // Safe because only writers can retire the current version, and we hold the write lock.
+        let mut new_value = unsafe { &*self.current.load(Ordering::SeqCst) }.clone();
+        let result = modifier(&mut new_value);
+        self.publish(new_value);
+        result
+    }
+
+    /// Publishes the given `new_value` as the new version of the data, and then retires the previous version.
+    pub fn replace(&self, new_value: T) {
+        let _guard = self.write_lock.lock();
+        self.publish(new_value);
+    }
+
+    /// Swaps in the `new_value` and retires the old one. The write lock must be held.
+    fn publish(&self, new_value: T) {
+        let old = self.current.swap(Box::into_raw(Box::new(new_value)), Ordering::SeqCst);
+        // Safe because `old` was created by `Box::into_raw()` and is no longer reachable by new readers.
+        retire(unsafe { Box::from_raw(old) });
+        try_reclaim();
+    }
+}
+
+impl<T: Send + Sync + 'static> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        // There can be no readers, since we have exclusive access to `self`.
+        unsafe { drop(Box::from_raw(*self.current.get_mut())); }
+    }
+}
+
+impl<T: Send + Sync + fmt::Debug + 'static> fmt::Debug for Rcu<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Rcu").field(&*self.read()).finish()
+    }
+}
+
+
+/// A guard that provides access to a particular version of the data within an `Rcu`.
+/// See [`Rcu::read()`](struct.Rcu.html#method.read).
+pub struct RcuReadGuard<'r, T: 'r> {
+    value: &'r T,
+    _section: ReadSection,
+}
+
+impl<'r, T> Deref for RcuReadGuard<'r, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
@@ -0,0 +1,71 @@
+//! A background service that applies the relocation fixups deferred by 
+//! [`crate_swap::swap_crates_deferred()`](../crate_swap/fn.swap_crates_deferred.html).
+//! 
+//! Swapping out a crate that many other crates depend on requires rewriting a relocation entry 
+//! in every section that depends on it, which can take a long time. 
+//! With deferred swapping, the swapping task only enqueues those fixups as a batch, 
+//! and this service's task applies them in the background, one batch at a time.
+//! Meanwhile, other tasks can still look up symbols without waiting,
+//! because the namespaces' symbol maps are read-mostly RCU-protected tables, see `mod_mgmt::CrateNamespace`.
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate crate_swap;
+extern crate task;
+extern crate spawn;
+extern crate wait_queue;
+
+use spin::Once;
+use task::TaskRef;
+use wait_queue::WaitQueue;
+
+
+/// The queue that the relink service task waits on until there are pending relink batches.
+static RELINK_WAIT_QUEUE: Once<WaitQueue> = Once::new();
+
+
+/// Spawns the relink service task and registers it with `crate_swap` 
+/// such that it will be notified of newly-deferred relink batches.
+/// 
+/// Returns the newly-spawned relink service task. 
+/// This should only be invoked once; subsequent invocations return an error.
+pub fn init() -> Result<TaskRef, &'static str> {
+    if RELINK_WAIT_QUEUE.try().is_some() {
+        return Err("relink service was already initialized");
+    }
+    RELINK_WAIT_QUEUE.call_once(|| WaitQueue::new());
+
+    let taskref = spawn::new_task_builder(relink_service_loop, ())
+        .name(format!("relink_service"))
+        .spawn()?;
+    crate_swap::set_relink_notifier(notify_relink_service);
+    Ok(taskref)
+}
+
+
+/// Wakes up the relink service task, which is invoked by `crate_swap` after it enqueues a new relink batch.
+fn notify_relink_service() {
+    if let Some(wq) = RELINK_WAIT_QUEUE.try() {
+        wq.notify_one();
+    }
+}
+
+
+/// The entry point of the relink service task, which never returns unless an error occurs.
+fn relink_service_loop(_: ()) -> Result<(), &'static str> {
+    let wait_queue = RELINK_WAIT_QUEUE.try().ok_or("BUG: relink service wait queue wasn't initialized")?;
+    loop {
+        wait_queue.wait_until(&|| if crate_swap::has_pending_relinks() { Some(()) } else { None })
+            .map_err(|_e| "relink service failed to wait on its wait queue")?;
+
+        match crate_swap::apply_pending_relinks() {
+            Ok(num_relocations) => debug!("relink service: applied {} deferred relocation fixups", num_relocations),
+            // An error here means that the swap is only partially complete, which we cannot recover from.
+            // However, we keep the service running such that future batches are still applied.
+            Err(e) => error!("relink service: failed to apply deferred relocation fixups: {}", e),
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! A remote shell server, which lets headless machines be administered interactively over the network.
+//!
+//! Once started via [`start()`], a server task listens on the given TCP port of a WireGuard tunnel's interface
+//! (see the `wireguard` crate) and accepts up to `MAX_SESSIONS` sessions at once.
+//! Each session has its own pseudo-terminal (see the `pty` crate), which the applications it runs use
+//! as their stdin, stdout, and stderr, as well as its own environment, e.g., its working directory.
+//!
+//! # Protocol
+//! The protocol is line-based, such that any plain TCP client on the tunnel's peer can be used, 
+//! e.g., `nc <THESEUS_TUNNEL_IP> 2222`.
+//! 1. The server asks for a key, and the client must reply with the key that the server was started with.
+//!    After `MAX_AUTH_ATTEMPTS` wrong keys, the connection is closed.
+//! 2. The server then prompts for a command line, which runs the named application with the given arguments.
+//!    While the application runs, each line sent by the client is passed to its stdin,
+//!    and Ctrl+C kills it. Once it exits, the server prompts for the next command line.
+//!
+//! The following commands are handled by the server itself:
+//! * `exit` ends the session, as does Ctrl+D at the prompt.
+//! * `stty echo` and `stty -echo` enable and disable echoing typed characters back to the client,
+//!   for clients that don't echo them locally.
+//!
+//! # Security
+//! The server only accepts connections that arrive through a WireGuard tunnel, which encrypts the whole session
+//! and authenticates the peer by its public key, so no one else on the path can read the key or reach the server.
+//! The key additionally authenticates the user on the peer machine.
+//!
+//! [`start()`]: fn.start.html
+
+#![no_std]
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate alloc;
+extern crate spin;
+extern crate dfqueue;
+extern crate event_types;
+extern crate terminal_print;
+extern crate pty;
+extern crate environment;
+extern crate path;
+extern crate network_manager;
+extern crate smoltcp;
+#[macro_use] extern crate smoltcp_helper;
+extern crate hpet;
+extern crate spawn;
+extern crate task;
+extern crate scheduler;
+extern crate wireguard;
+extern crate crypto;
+
+use core::ops::Deref;
+use alloc::{
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::{Mutex, Once};
+use dfqueue::{DFQueue, DFQueueConsumer};
+use event_types::Event;
+use pty::{Pty, PtyEvent};
+use environment::Environment;
+use path::Path;
+use hpet::get_hpet;
+use network_manager::NetworkInterfaceRef;
+use smoltcp::socket::{SocketSet, SocketHandle, TcpSocket, TcpSocketBuffer, TcpState};
+use smoltcp_helper::poll_iface;
+use crypto::hmac::HmacSha3_256;
+use crypto::util::constant_time_eq;
+use task::{TaskRef, ExitValue, KillReason};
+
+
+/// The TCP port that the remote shell server listens on by default.
+pub const DEFAULT_PORT: u16 = 2222;
+
+/// The maximum number of sessions that can be connected at once.
+const MAX_SESSIONS: usize = 4;
+/// The number of wrong keys that a client may send before its connection is closed.
+const MAX_AUTH_ATTEMPTS: usize = 3;
+/// The size in bytes of each session's TCP transmit buffer.
+const TX_BUFFER_SIZE: usize = 16 * 1024;
+/// The size in bytes of each session's TCP receive buffer.
+const RX_BUFFER_SIZE: usize = 4 * 1024;
+
+/// The port that the server was started on, if it has been started.
+static SERVER_PORT: Once<u16> = Once::new();
+/// The key that clients must send to start a session.
+static AUTH_KEY: Once<String> = Once::new();
+
+
+/// Starts the remote shell server on the given TCP `port` of the given WireGuard tunnel's interface `tunnel_iface`.
+/// Clients must send the given `key` before they can run any commands.
+///
+/// Returns an error if `tunnel_iface` is not a tunnel's interface, because the session would then not be encrypted.
+/// This can only be done once; an error is returned if the server was already started.
+pub fn start(tunnel_iface: NetworkInterfaceRef, port: u16, key: String) -> Result<(), &'static str> {
+    if key.is_empty() {
+        return Err("the remote shell key must not be empty");
+    }
+    if !wireguard::is_tunnel_interface(&tunnel_iface) {
+        return Err("the remote shell can only be served over a WireGuard tunnel interface");
+    }
+    let mut newly_started = false;
+    SERVER_PORT.call_once(|| { newly_started = true; port });
+    if !newly_started {
+        return Err("the remote shell server was already started");
+    }
+    AUTH_KEY.call_once(|| key);
+
+    spawn::new_task_builder(remote_shell_loop, (tunnel_iface, port))
+        .name(format!("remote_shell_{}", port))
+        .spawn()?;
+    info!("Started remote shell server on TCP port {}", port);
+    Ok(())
+}
+
+/// Returns the TCP port that the remote shell server was started on, if it has been started.
+pub fn server_port() -> Option<u16> {
+    SERVER_PORT.try().cloned()
+}
+
+
+/// The stage that a session is in.
+enum SessionState {
+    /// No client is connected.
+    Disconnected,
+    /// A client is connected, but hasn't sent the right key yet.
+    Authenticating { failed_attempts: usize },
+    /// The client is at the prompt.
+    Idle,
+    /// The client is running the given application task.
+    Running(TaskRef),
+}
+
+/// The state of one session, which is reused for the next client once its connection is closed.
+struct Session {
+    handle: SocketHandle,
+    state: SessionState,
+    pty: Pty,
+    /// The environment that is shared by all applications that this session runs.
+    env: Arc<Mutex<Environment>>,
+    /// Receives the output of applications that print via the legacy `terminal_print` crate.
+    print_consumer: DFQueueConsumer<Event>,
+    /// The output bytes that have not yet been accepted by the socket.
+    pending: Vec<u8>,
+}
+
+impl Session {
+    fn new(handle: SocketHandle) -> Session {
+        Session {
+            handle,
+            state: SessionState::Disconnected,
+            pty: Pty::new(),
+            env: Arc::new(Mutex::new(Environment::default())),
+            print_consumer: DFQueue::new().into_consumer(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Ends this session, killing the application it's running, if any.
+    fn reset(&mut self) {
+        if let SessionState::Running(ref task) = self.state {
+            if let Err(e) = task.kill(KillReason::Requested) {
+                error!("remote_shell: couldn't kill task {:?}: {}", task, e);
+            }
+            let _ = terminal_print::remove_child(task.lock().id);
+        }
+        *self = Session::new(self.handle);
+    }
+
+    /// Handles a Ctrl+C typed by the client.
+    fn interrupt(&mut self) {
+        match self.state {
+            SessionState::Running(ref task) => {
+                if let Err(e) = task.kill(KillReason::Requested) {
+                    self.pty.write_output(format!("remote_shell: couldn't kill task: {}\n", e).as_bytes());
+                }
+            }
+            SessionState::Idle => self.prompt(),
+            _ => { }
+        }
+    }
+
+    /// Advances this session according to the client's input and the state of its application.
+    /// Returns `false` if the session has ended, in which case the connection should be closed.
+    fn update(&mut self) -> bool {
+        let state = core::mem::replace(&mut self.state, SessionState::Disconnected);
+        let next_state = match state {
+            SessionState::Disconnected => {
+                self.pty.write_output(b"Key: ");
+                Some(SessionState::Authenticating { failed_attempts: 0 })
+            }
+            SessionState::Authenticating { failed_attempts } => self.authenticate(failed_attempts),
+            SessionState::Idle => self.run_next_command(),
+            SessionState::Running(task) => {
+                if task.has_exited() {
+                    self.finish(task);
+                    self.pty.reopen_input();
+                    self.prompt();
+                    Some(SessionState::Idle)
+                } else {
+                    Some(SessionState::Running(task))
+                }
+            }
+        };
+        self.drain_print_events();
+        match next_state {
+            Some(state) => {
+                self.state = state;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn authenticate(&mut self, failed_attempts: usize) -> Option<SessionState> {
+        let line = match self.pty.try_read_line() {
+            Some(line) => line,
+            None if self.pty.input_ended() => return None,
+            None => return Some(SessionState::Authenticating { failed_attempts }),
+        };
+        let key = AUTH_KEY.try().map(|k| k.as_bytes()).unwrap_or(&[]);
+        if keys_match(line.as_bytes(), key) {
+            self.pty.write_output(b"Welcome to the Theseus remote shell. Type \"exit\" to end the session.\n");
+            self.prompt();
+            return Some(SessionState::Idle);
+        }
+
+        let failed_attempts = failed_attempts + 1;
+        warn!("remote_shell: a client sent a wrong key ({} of {} attempts)", failed_attempts, MAX_AUTH_ATTEMPTS);
+        if failed_attempts >= MAX_AUTH_ATTEMPTS {
+            self.pty.write_output(b"Too many wrong keys.\n");
+            None
+        } else {
+            self.pty.write_output(b"Wrong key.\nKey: ");
+            Some(SessionState::Authenticating { failed_attempts })
+        }
+    }
+
+    fn run_next_command(&mut self) -> Option<SessionState> {
+        let line = match self.pty.try_read_line() {
+            Some(line) => line,
+            None if self.pty.input_ended() => return None,
+            None => return Some(SessionState::Idle),
+        };
+        let mut args: Vec<String> = line.split_whitespace().map(String::from).collect();
+        if args.is_empty() {
+            self.prompt();
+            return Some(SessionState::Idle);
+        }
+        let command = args.remove(0);
+        match command.as_str() {
+            "exit" => return None,
+            "stty" => {
+                match args.get(0).map(|a| a.as_str()) {
+                    Some("echo") => self.pty.set_echo(true),
+                    Some("-echo") => self.pty.set_echo(false),
+                    _ => self.pty.write_output(b"Usage: stty [echo | -echo]\n"),
+                }
+            }
+            _ => match self.spawn_application(&command, args) {
+                Ok(task) => return Some(SessionState::Running(task)),
+                Err(e) => self.pty.write_output(format!("{}\n", e).as_bytes()),
+            }
+        }
+        self.prompt();
+        Some(SessionState::Idle)
+    }
+
+    /// Spawns the application named `command`, whose stdio streams are this session's pseudo-terminal.
+    fn spawn_application(&mut self, command: &str, args: Vec<String>) -> Result<TaskRef, String> {
+        let namespace_dir = task::get_my_current_task()
+            .map(|t| t.get_namespace().dir().clone())
+            .ok_or("couldn't find the directory of application executables")?;
+        let mut matching_apps = namespace_dir.get_files_starting_with(&format!("{}-", command)).into_iter();
+        let app_file = matching_apps.next();
+        let second_match = matching_apps.next(); // the command is ambiguous if there are multiple matching apps
+        let app_path = app_file.xor(second_match)
+            .map(|f| Path::new(f.lock().get_absolute_path()))
+            .ok_or_else(|| format!("{:?} command not found.", command))?;
+
+        {
+            let mut env = self.env.lock();
+            env.stdin = Some(self.pty.slave_stdin());
+            env.stdout = Some(self.pty.slave_stdout());
+            env.stderr = Some(self.pty.slave_stdout());
+        }
+        let task = spawn::new_application_task_builder(app_path, None)?
+            .argument(args)
+            .env(Arc::clone(&self.env))
+            .block()
+            .spawn()?;
+        terminal_print::add_child(task.lock().id, self.print_consumer.obtain_producer())?;
+        // The task's output streams are set up, so it's safe to let it run now.
+        task.unblock();
+        Ok(task)
+    }
+
+    /// Reports how the given application task exited, and cleans up after it.
+    fn finish(&mut self, task: TaskRef) {
+        let task_id = task.lock().id;
+        match task.take_exit_value() {
+            Some(ExitValue::Completed(exit_status)) => {
+                if let Some(val) = exit_status.downcast_ref::<isize>() {
+                    if *val < 0 {
+                        self.pty.write_output(format!("task [{}] returned error value {:?}\n", task_id, val).as_bytes());
+                    }
+                }
+            }
+            // The client pressed Ctrl+C, which was already echoed.
+            Some(ExitValue::Killed(KillReason::Requested)) | None => { }
+            Some(ExitValue::Killed(kill_reason)) => {
+                self.pty.write_output(format!("task [{}] was killed because {:?}\n", task_id, kill_reason).as_bytes());
+            }
+        }
+        // Print any output that the task printed right before exiting.
+        self.drain_print_events();
+        let _ = terminal_print::remove_child(task_id);
+    }
+
+    /// Prints the prompt, which contains the working directory like the shell's prompt does.
+    fn prompt(&mut self) {
+        let prompt = format!("{}: ", self.env.lock().get_wd_path());
+        self.pty.write_output(prompt.as_bytes());
+    }
+
+    /// Moves the output of applications that print via `terminal_print` to the pseudo-terminal.
+    fn drain_print_events(&mut self) {
+        while let Some(print_event) = self.print_consumer.peek() {
+            if let &Event::OutputEvent(ref s) = print_event.deref() {
+                self.pty.write_output(s.as_bytes());
+            }
+            print_event.mark_completed();
+        }
+    }
+}
+
+/// Compares the given key with the right one in an amount of time that doesn't depend on their contents or the key's length,
+/// such that clients can't guess the key byte by byte based on how long it took to reject it.
+/// 
+/// Both keys are first turned into fixed-length MACs, which are then compared in constant time.
+fn keys_match(guess: &[u8], key: &[u8]) -> bool {
+    let hmac = HmacSha3_256::new(key);
+    constant_time_eq(&hmac.mac(&[guess]), &hmac.mac(&[key]))
+}
+
+
+/// The entry point of the remote shell server task.
+fn remote_shell_loop((iface, port): (NetworkInterfaceRef, u16)) -> Result<(), &'static str> {
+    let startup_time = hpet_ticks!();
+
+    let mut sockets = SocketSet::new(Vec::with_capacity(MAX_SESSIONS));
+    let mut sessions: Vec<Session> = (0..MAX_SESSIONS).map(|_| {
+        let rx_buffer = TcpSocketBuffer::new(vec![0; RX_BUFFER_SIZE]);
+        let tx_buffer = TcpSocketBuffer::new(vec![0; TX_BUFFER_SIZE]);
+        Session::new(sockets.add(TcpSocket::new(rx_buffer, tx_buffer)))
+    }).collect();
+
+    loop {
+        let _packet_io_occurred = poll_iface(&iface, &mut sockets, startup_time)?;
+        for session in sessions.iter_mut() {
+            service_session(&mut sockets, session, port);
+        }
+        scheduler::schedule();
+    }
+}
+
+/// Accepts a client for, or exchanges input and output with, the given `session`, depending on its state.
+fn service_session(sockets: &mut SocketSet, session: &mut Session, port: u16) {
+    let mut socket = sockets.get::<TcpSocket>(session.handle);
+    if !socket.is_open() {
+        // The previous client's connection was closed, so wait for the next client to connect.
+        if let SessionState::Disconnected = session.state { } else {
+            session.reset();
+        }
+        if let Err(_e) = socket.listen(port) {
+            error!("remote_shell: couldn't listen on port {}, error: {:?}", port, _e);
+        }
+        return;
+    }
+    if socket.state() == TcpState::CloseWait {
+        // The client closed its side of the connection, so it won't send any more commands.
+        session.reset();
+        socket.close();
+        return;
+    }
+    if !socket.may_send() {
+        return;
+    }
+    if let SessionState::Disconnected = session.state {
+        info!("remote_shell: accepted a connection from {}", socket.remote_endpoint());
+    }
+
+    let mut buf = [0u8; 256];
+    while socket.can_recv() {
+        let count = match socket.recv_slice(&mut buf) {
+            Ok(count) => count,
+            Err(_e) => break,
+        };
+        // An end of input is handled by `update()` once the input before it has been read.
+        if let Some(PtyEvent::Interrupt) = session.pty.input(&buf[..count]) {
+            session.interrupt();
+        }
+    }
+
+    let keep_open = session.update();
+    session.pty.read_output(&mut session.pending);
+    if !session.pending.is_empty() {
+        let sent = socket.send_slice(&session.pending).unwrap_or(0);
+        session.pending.drain(..sent);
+    }
+    if !keep_open {
+        info!("remote_shell: closing the connection from {}", socket.remote_endpoint());
+        session.reset();
+        socket.close();
+    }
+}
@@ -0,0 +1,165 @@
+//! A log of the nondeterministic events that shape an execution of Theseus, for debugging concurrency bugs
+//! via deterministic record and replay in QEMU.
+//!
+//! The events are scheduling decisions, the points at which interrupts were delivered, and input events.
+//! They are only logged when Theseus is built with the `deterministic_replay` config option, which the
+//! `make record` target does. That target runs QEMU in its record mode, which records every input to the guest,
+//! e.g., device interrupts, keystrokes, and timer values, to a file on the host. `make replay` then re-executes
+//! the same build with those exact inputs, such that every instruction, and thus every event in this log,
+//! happens at exactly the same point as in the recorded execution, as often as needed to reproduce a heisenbug.
+//!
+//! Since QEMU re-executes the whole machine, this log isn't needed to replay the execution;
+//! rather, it shows which interleaving the recorded execution took, and verifies that a replay is faithful:
+//! every event is folded into a running [`digest()`], which must be identical at the same point of the
+//! recorded and replayed executions. If it differs, something was nondeterministic despite QEMU's replay mode,
+//! e.g., a device that QEMU can't record, and the first differing event in [`events()`] shows where.
+//!
+//! To keep the overhead small and avoid allocating in interrupt handlers, the most recent [`CAPACITY`] events
+//! are kept in a fixed-size ring buffer.
+//!
+//! [`digest()`]: fn.digest.html
+//! [`events()`]: fn.events.html
+//! [`CAPACITY`]: constant.CAPACITY.html
+
+#![no_std]
+
+extern crate alloc;
+extern crate irq_safety;
+extern crate apic;
+extern crate tsc;
+
+use core::fmt;
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+
+
+/// The number of most recent events that are kept.
+pub const CAPACITY: usize = 4096;
+
+/// The offset basis of the 64-bit FNV-1a hash that the digest is computed with.
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+
+/// A nondeterministic event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// The scheduler switched from the task with ID `from` to the task with ID `to`.
+    TaskSwitch { from: usize, to: usize },
+    /// The interrupt with the given vector was delivered while executing the instruction at `instruction_pointer`.
+    Interrupt { vector: u8, instruction_pointer: usize },
+    /// A scancode was received from the PS/2 keyboard.
+    KeyboardInput { scan_code: u8, extended: bool },
+    /// A packet was received from the PS/2 mouse.
+    MouseInput { packet: u32 },
+}
+
+/// One entry of the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The number of events that were logged before this one since boot.
+    pub sequence: u64,
+    /// The APIC ID of the core that the event happened on.
+    pub core: u8,
+    /// The time stamp counter when the event was logged, which is also deterministic under QEMU's record/replay mode.
+    pub tsc: u64,
+    pub kind: EventKind,
+}
+
+const EMPTY_EVENT: Event = Event { sequence: 0, core: 0, tsc: 0, kind: EventKind::TaskSwitch { from: 0, to: 0 } };
+
+impl Event {
+    /// Returns the given digest with this event folded into it.
+    fn fold_into(&self, digest: u64) -> u64 {
+        let (tag, a, b) = match self.kind {
+            EventKind::TaskSwitch { from, to } => (0u8, from as u64, to as u64),
+            EventKind::Interrupt { vector, instruction_pointer } => (1, vector as u64, instruction_pointer as u64),
+            EventKind::KeyboardInput { scan_code, extended } => (2, scan_code as u64, extended as u64),
+            EventKind::MouseInput { packet } => (3, packet as u64, 0),
+        };
+        let mut digest = digest;
+        let fields = [self.sequence, self.core as u64, self.tsc, tag as u64, a, b];
+        for field in fields.iter() {
+            for byte in field.to_le_bytes().iter() {
+                digest = (digest ^ *byte as u64).wrapping_mul(FNV_PRIME);
+            }
+        }
+        digest
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:<8} core {:<3} tsc {:<16} ", self.sequence, self.core, self.tsc)?;
+        match self.kind {
+            EventKind::TaskSwitch { from, to } => write!(f, "task switch {} -> {}", from, to),
+            EventKind::Interrupt { vector, instruction_pointer } => write!(f, "interrupt {:#X} at {:#X}", vector, instruction_pointer),
+            EventKind::KeyboardInput { scan_code, extended } => write!(f, "keyboard scancode {:#X}{}", scan_code, if extended { " (extended)" } else { "" }),
+            EventKind::MouseInput { packet } => write!(f, "mouse packet {:#08X}", packet),
+        }
+    }
+}
+
+
+struct Log {
+    events: [Event; CAPACITY],
+    /// The number of events that were logged since boot, the latest of which is at `(count - 1) % CAPACITY`.
+    count: u64,
+    digest: u64,
+}
+
+static LOG: MutexIrqSafe<Log> = MutexIrqSafe::new(Log {
+    events: [EMPTY_EVENT; CAPACITY],
+    count: 0,
+    digest: FNV_OFFSET_BASIS,
+});
+
+
+/// Returns whether events are being logged, i.e., whether Theseus was built with the `deterministic_replay` config option.
+pub fn is_enabled() -> bool {
+    cfg!(deterministic_replay)
+}
+
+/// Logs the given event as having happened on the current core, if logging is enabled.
+///
+/// This doesn't allocate, so it can be invoked from interrupt handlers and the scheduler.
+pub fn record(kind: EventKind) {
+    if !is_enabled() {
+        return;
+    }
+    let core = apic::get_my_apic_id();
+    let mut log = LOG.lock();
+    // The time stamp is taken while holding the lock, such that events are ordered by their time stamps.
+    let event = Event { sequence: log.count, core, tsc: tsc::tsc_ticks().into(), kind };
+    log.digest = event.fold_into(log.digest);
+    log.events[(log.count % CAPACITY as u64) as usize] = event;
+    log.count += 1;
+}
+
+/// Logs a task switch from the task with ID `from` to the task with ID `to`, see [`record()`](fn.record.html).
+pub fn record_task_switch(from: usize, to: usize) {
+    record(EventKind::TaskSwitch { from, to });
+}
+
+/// Logs the delivery of the interrupt with the given vector at the given instruction, see [`record()`](fn.record.html).
+pub fn record_interrupt(vector: u8, instruction_pointer: usize) {
+    record(EventKind::Interrupt { vector, instruction_pointer });
+}
+
+/// Returns the number of events that were logged since boot, and the digest of all of them.
+pub fn digest() -> (u64, u64) {
+    let log = LOG.lock();
+    (log.count, log.digest)
+}
+
+/// Returns the most recent events, from the oldest to the newest, which are at most `CAPACITY` events.
+pub fn events() -> Vec<Event> {
+    let mut events = Vec::with_capacity(CAPACITY);
+    // The vector is allocated before taking the lock, which holds off interrupts, such that it's held as briefly as possible.
+    let log = LOG.lock();
+    let kept = core::cmp::min(log.count, CAPACITY as u64);
+    for sequence in log.count - kept .. log.count {
+        events.push(log.events[(sequence % CAPACITY as u64) as usize]);
+    }
+    events
+}
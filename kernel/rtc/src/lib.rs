@@ -105,6 +105,23 @@ pub struct RtcTime {
     pub months: u8,
     pub years: u8,
 }
+
+impl RtcTime {
+    /// Returns the number of seconds from the start of the year 2000 until this time,
+    /// since the RTC only stores the last two digits of the year.
+    pub fn seconds_since_2000(&self) -> u64 {
+        const DAYS_BEFORE_MONTH: [u64; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+        let year = self.years as u64;
+        let month = core::cmp::min(core::cmp::max(self.months, 1), 12) as usize;
+        // every year divisible by 4 within 2000 to 2099 is a leap year
+        let leap_days_before_year = (year + 3) / 4;
+        let leap_day_this_year = if year % 4 == 0 && month > 2 { 1 } else { 0 };
+        let days = year * 365 + leap_days_before_year + DAYS_BEFORE_MONTH[month - 1] + leap_day_this_year
+            + (self.days as u64).saturating_sub(1);
+        ((days * 24 + self.hours as u64) * 60 + self.minutes as u64) * 60 + self.seconds as u64
+    }
+}
+
 use core::fmt;
 impl fmt::Display for RtcTime {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
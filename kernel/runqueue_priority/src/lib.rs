@@ -11,6 +11,7 @@ extern crate alloc;
 extern crate irq_safety;
 extern crate atomic_linked_list;
 extern crate task;
+extern crate cpu_topology;
 
 #[cfg(single_simd_task_optimization)]
 extern crate single_simd_task_optimization;
@@ -20,6 +21,7 @@ use irq_safety::{RwLockIrqSafe, MutexIrqSafeGuardRef};
 use atomic_linked_list::atomic_map::AtomicMap;
 use task::{TaskRef, Task};
 use core::ops::{Deref, DerefMut};
+use cpu_topology::{CoreType, CoreTypeHint};
 
 pub const MAX_PRIORITY: u8 = 40;
 pub const DEFAULT_PRIORITY: u8 = 20;
@@ -168,17 +170,29 @@ impl RunQueue {
 
     /// Returns the "least busy" core, which is currently very simple, based on runqueue size.
     pub fn get_least_busy_core() -> Option<u8> {
-        Self::get_least_busy_runqueue().map(|rq| rq.read().core)
+        Self::get_least_busy_runqueue(None, false).map(|rq| rq.read().core)
     }
 
 
     /// Returns the `RunQueue` for the "least busy" core.
+    /// If a `core_type` is given, only cores of that type are considered.
+    /// If `avoid_smt_siblings` is `true`, the tasks on each core's SMT siblings count towards that core's load,
+    /// such that a core whose siblings are idle is preferred.
     /// See [`get_least_busy_core()`](#method.get_least_busy_core)
-    fn get_least_busy_runqueue() -> Option<&'static RwLockIrqSafe<RunQueue>> {
+    fn get_least_busy_runqueue(core_type: Option<CoreType>, avoid_smt_siblings: bool) -> Option<&'static RwLockIrqSafe<RunQueue>> {
         let mut min_rq: Option<(&'static RwLockIrqSafe<RunQueue>, usize)> = None;
 
-        for (_, rq) in RUNQUEUES.iter() {
-            let rq_size = rq.read().queue.len();
+        for (core, rq) in RUNQUEUES.iter() {
+            if core_type.is_some() && cpu_topology::core_type(*core) != core_type {
+                continue;
+            }
+            let mut rq_size = rq.read().queue.len();
+            if avoid_smt_siblings {
+                rq_size += cpu_topology::smt_siblings(*core).into_iter()
+                    .filter_map(|sibling| RUNQUEUES.get(&sibling))
+                    .map(|sibling_rq| sibling_rq.read().queue.len())
+                    .sum::<usize>();
+            }
 
             if let Some(min) = min_rq {
                 if rq_size < min.1 {
@@ -195,8 +209,14 @@ impl RunQueue {
 
     /// Chooses the "least busy" core's runqueue (based on simple runqueue-size-based load balancing)
     /// and adds the given `Task` reference to that core's runqueue.
+    ///
+    /// If the `Task` has a core type hint, the least busy core of the preferred type is chosen,
+    /// unless there are no cores of that type. A latency-critical `Task` also avoids cores whose SMT siblings are busy.
     pub fn add_task_to_any_runqueue(task: TaskRef) -> Result<(), &'static str> {
-        let rq = RunQueue::get_least_busy_runqueue()
+        let hint = task.core_type_hint();
+        let avoid_smt_siblings = hint == CoreTypeHint::LatencyCritical;
+        let rq = hint.preferred_core_type().and_then(|t| RunQueue::get_least_busy_runqueue(Some(t), avoid_smt_siblings))
+            .or_else(|| RunQueue::get_least_busy_runqueue(None, avoid_smt_siblings))
             .or_else(|| RUNQUEUES.iter().next().map(|r| r.1))
             .ok_or("couldn't find any runqueues to add the task to!")?;
 
@@ -1,22 +1,141 @@
+//! Provides the scheduling entry points that select the next task and switch to it,
+//! which dispatch to the scheduler policy chosen at build time (round robin or priority).
+//!
+//! The timer tick length, the length of a timeslice for each priority class,
+//! and the priority threshold at which tasks are no longer preempted by the timer
+//! can be tuned at runtime through the `config_registry`, see [`register_tunables()`].
+//!
+//! [`register_tunables()`]: fn.register_tunables.html
+
 #![no_std]
 
 extern crate alloc;
-// #[macro_use] extern crate log;
+#[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
 extern crate irq_safety;
+extern crate atomic_linked_list;
+extern crate kernel_config;
+extern crate config_registry;
 extern crate apic;
 extern crate task;
 extern crate runqueue;
+extern crate replay_log;
 #[cfg(priority_scheduler)] extern crate scheduler_priority;
 #[cfg(not(priority_scheduler))] extern crate scheduler_round_robin;
 
 
 use core::ops::Deref;
+use core::sync::atomic::{AtomicU64, Ordering};
 use irq_safety::hold_interrupts;
+use atomic_linked_list::atomic_map::AtomicMap;
 use apic::get_my_apic_id;
+use kernel_config::time::CONFIG_TIMESLICE_PERIOD_MICROSECONDS;
+use config_registry::Tunable;
 use task::{Task, get_my_current_task, TaskRef};
 #[cfg(priority_scheduler)] use scheduler_priority::select_next_task;
 #[cfg(not(priority_scheduler))] use scheduler_round_robin::select_next_task;
 
+
+/// The interval between timer interrupts, i.e., scheduler ticks.
+pub static TICK_MICROSECONDS: Tunable = Tunable::new(
+    "scheduler.tick_us", "the interval between timer interrupts (scheduler ticks), in microseconds",
+    CONFIG_TIMESLICE_PERIOD_MICROSECONDS as u64, 100, 100_000,
+);
+/// The number of ticks that a task in the low priority class can run before it's preempted.
+pub static TIMESLICE_TICKS_LOW: Tunable = Tunable::new(
+    "scheduler.timeslice_ticks.low", "the timeslice of tasks with a priority below 14, in ticks",
+    1, 1, 1000,
+);
+/// The number of ticks that a task in the normal priority class can run before it's preempted.
+/// Without a priority scheduler, every task is in the normal priority class.
+pub static TIMESLICE_TICKS_NORMAL: Tunable = Tunable::new(
+    "scheduler.timeslice_ticks.normal", "the timeslice of tasks with a priority from 14 to 27, or of all tasks without a priority scheduler, in ticks",
+    1, 1, 1000,
+);
+/// The number of ticks that a task in the high priority class can run before it's preempted.
+pub static TIMESLICE_TICKS_HIGH: Tunable = Tunable::new(
+    "scheduler.timeslice_ticks.high", "the timeslice of tasks with a priority of 28 or above, in ticks",
+    1, 1, 1000,
+);
+/// Tasks with at least this priority are never preempted by a timer tick,
+/// so they only stop running when they block or yield. The default is above the maximum priority.
+pub static NONPREEMPTIBLE_PRIORITY: Tunable = Tunable::new(
+    "scheduler.nonpreemptible_priority", "tasks with at least this priority are never preempted by the timer (41: none)",
+    41, 0, 41,
+);
+
+/// The lowest priority of the normal priority class.
+const NORMAL_PRIORITY_CLASS_START: u8 = 14;
+/// The lowest priority of the high priority class.
+const HIGH_PRIORITY_CLASS_START: u8 = 28;
+
+lazy_static! {
+    /// The number of ticks that have elapsed in the current timeslice on each core, keyed by APIC ID.
+    static ref TIMESLICE_TICKS_ELAPSED: AtomicMap<u8, AtomicU64> = AtomicMap::new();
+}
+
+
+/// Registers the scheduler's tunables with the `config_registry`, such that they can be changed at runtime.
+///
+/// Until this is invoked, the tunables keep their default values.
+pub fn register_tunables() -> Result<(), &'static str> {
+    config_registry::register(&TICK_MICROSECONDS, Some(apply_tick_microseconds))?;
+    config_registry::register(&TIMESLICE_TICKS_LOW, None)?;
+    config_registry::register(&TIMESLICE_TICKS_NORMAL, None)?;
+    config_registry::register(&TIMESLICE_TICKS_HIGH, None)?;
+    config_registry::register(&NONPREEMPTIBLE_PRIORITY, None)?;
+    Ok(())
+}
+
+/// Applies a new value of `TICK_MICROSECONDS` by reprogramming every core's timer.
+fn apply_tick_microseconds(microseconds: u64) -> Result<(), &'static str> {
+    apic::set_timer_period_microseconds(microseconds as u32)
+}
+
+/// Returns the counter of ticks elapsed in the current timeslice on the given core.
+fn timeslice_ticks_elapsed(apic_id: u8) -> &'static AtomicU64 {
+    if let Some(ticks) = TIMESLICE_TICKS_ELAPSED.get(&apic_id) {
+        return ticks;
+    }
+    TIMESLICE_TICKS_ELAPSED.insert(apic_id, AtomicU64::new(0));
+    TIMESLICE_TICKS_ELAPSED.get(&apic_id).expect("BUG: timeslice_ticks_elapsed(): counter wasn't inserted")
+}
+
+/// Handles a timer tick on the current core: charges the current task for the tick,
+/// and then preempts it if its timeslice has run out.
+///
+/// The length of a timeslice depends on the priority class of the current task,
+/// and tasks whose priority is at least `NONPREEMPTIBLE_PRIORITY` are never preempted here.
+/// Every invocation of `schedule()` begins a new timeslice.
+///
+/// This should be invoked upon every timer interrupt.
+pub fn timer_tick() {
+    charge_current_task_timeslice();
+
+    let curr = match get_my_current_task() {
+        Some(curr) => curr,
+        None => return,
+    };
+    // a task that was just killed for exceeding its CPU time limit must be switched away from immediately
+    if !curr.lock().is_runnable() {
+        schedule();
+        return;
+    }
+    let priority = get_priority(curr);
+    if priority.map_or(false, |p| p as u64 >= NONPREEMPTIBLE_PRIORITY.get()) {
+        return;
+    }
+    let timeslice = match priority {
+        Some(p) if p >= HIGH_PRIORITY_CLASS_START => &TIMESLICE_TICKS_HIGH,
+        Some(p) if p < NORMAL_PRIORITY_CLASS_START => &TIMESLICE_TICKS_LOW,
+        _ => &TIMESLICE_TICKS_NORMAL,
+    };
+    let elapsed = timeslice_ticks_elapsed(get_my_apic_id()).fetch_add(1, Ordering::Relaxed) + 1;
+    if elapsed >= timeslice.get() {
+        schedule();
+    }
+}
+
 /// Yields the current CPU by selecting a new `Task` to run 
 /// and then performs a task switch to that new `Task`.
 ///
@@ -28,6 +147,9 @@ pub fn schedule() -> bool {
     let next_task: *mut Task; 
     let apic_id = get_my_apic_id();
 
+    // whichever task runs next, it starts a new timeslice
+    timeslice_ticks_elapsed(apic_id).store(0, Ordering::Relaxed);
+
     {
         if let Some(selected_next_task) = select_next_task(apic_id) {
             next_task = selected_next_task.lock().deref() as *const Task as *mut Task;
@@ -60,6 +182,7 @@ pub fn schedule() -> bool {
 
     // trace!("BEFORE TASK_SWITCH CALL (AP {}), current={}, next={}, interrupts are {}", apic_id, curr, next, irq_safety::interrupts_enabled());
 
+    replay_log::record_task_switch(curr.id, next.id);
     curr.task_switch(next, apic_id); 
 
     // let new_current: TaskId = CURRENT_TASK.load(Ordering::SeqCst);
@@ -68,6 +191,21 @@ pub fn schedule() -> bool {
     true
 }
 
+/// Charges the task currently running on this core for one tick of CPU time,
+/// and enforces its CPU time limit, if any (see `task::CpuTimeLimit`).
+/// 
+/// This should be invoked upon every timer tick, before `schedule()`, 
+/// such that a task that is killed for exceeding its limit is switched away from immediately.
+/// Thus, CPU time is accounted at the granularity of a tick: 
+/// the whole tick is charged to whichever task is running when the timer tick occurs.
+pub fn charge_current_task_timeslice() {
+    if let Some(curr) = get_my_current_task() {
+        if let Err(e) = curr.charge_cpu_time(TICK_MICROSECONDS.get()) {
+            error!("charge_current_task_timeslice(): failed to enforce CPU time limit of {:?}: {}", curr, e);
+        }
+    }
+}
+
 /// Changes the priority of the given task with the given priority level.
 /// Priority values must be between 40 (maximum priority) and 0 (minimum prriority).
 /// This function returns an error when a scheduler without priority is loaded. 
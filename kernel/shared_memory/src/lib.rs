@@ -0,0 +1,205 @@
+//! Named regions of shared memory, through which tasks can communicate without copying data.
+//!
+//! A task creates a region with a unique name via [`SharedMapping::create()`],
+//! and other tasks map that same region's physical frames via [`SharedMapping::open()`],
+//! or via [`SharedMapping::open_read_only()`] if they only consume its contents.
+//! Each of these returns a separate `SharedMapping` at its own virtual addresses,
+//! all of which refer to the same frames, so a write through any writable mapping is visible through all others.
+//!
+//! The region's frames are reference counted by the frame allocator (see `memory::frame_refcount`),
+//! with one reference for each mapping and one for the region itself.
+//! A region exists as long as any mapping of it exists; once the final mapping is dropped,
+//! its frames are deallocated and its name can be used again.
+//!
+//! There is no synchronization between the tasks that share a region;
+//! they must coordinate their accesses themselves, e.g., through atomic types placed in the region.
+//!
+//! [`SharedMapping::create()`]: struct.SharedMapping.html#method.create
+//! [`SharedMapping::open()`]: struct.SharedMapping.html#method.open
+//! [`SharedMapping::open_read_only()`]: struct.SharedMapping.html#method.open_read_only
+
+#![no_std]
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
+extern crate alloc;
+extern crate spin;
+extern crate memory;
+extern crate kernel_config;
+
+use core::ops::Deref;
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use spin::Mutex;
+use memory::{EntryFlags, FrameRange, MappedPages, CachedFrameAllocator, frame_refcount, get_kernel_mmi_ref};
+use kernel_config::memory::PAGE_SIZE;
+
+
+lazy_static! {
+    /// All regions that currently exist, by name.
+    /// A region that has been dropped remains here until its name is next looked up.
+    static ref REGIONS: Mutex<BTreeMap<String, Weak<SharedRegion>>> = Mutex::new(BTreeMap::new());
+}
+
+
+/// A named region of physical memory, which is kept alive by the `SharedMapping`s of it.
+struct SharedRegion {
+    name: String,
+    /// The size that the region was created with, which may be less than the size of its frames.
+    size_in_bytes: usize,
+    frames: FrameRange,
+}
+
+impl Drop for SharedRegion {
+    fn drop(&mut self) {
+        // Release the region's own reference to each frame, which deallocates it
+        // once the mappings of it have also been dropped.
+        for frame in self.frames.clone() {
+            frame_refcount::decref(frame);
+        }
+    }
+}
+
+
+/// A mapping of a named shared memory region into the current address space.
+///
+/// The mapping is writable unless it was opened via [`open_read_only()`](#method.open_read_only).
+/// It derefs to its underlying `MappedPages`, which can be used to access the region as arbitrary types,
+/// but only the first [`size_in_bytes()`](#method.size_in_bytes) bytes of it belong to the region.
+pub struct SharedMapping {
+    // The mapping is dropped before the region, such that the region's frames are deallocated
+    // when the region's own reference to them is released.
+    mapping: MappedPages,
+    region: Arc<SharedRegion>,
+}
+
+impl SharedMapping {
+    /// Creates a new shared memory region with the given `name` that is `size_in_bytes` bytes long,
+    /// and returns a writable mapping of it. The region's contents are initially zeroed.
+    ///
+    /// Returns an error if a region with the same name already exists.
+    pub fn create(name: &str, size_in_bytes: usize) -> Result<SharedMapping, &'static str> {
+        if size_in_bytes == 0 {
+            return Err("shared_memory: a region's size must be greater than zero");
+        }
+        let mut regions = REGIONS.lock();
+        if regions.get(name).map_or(false, |r| r.upgrade().is_some()) {
+            return Err("shared_memory: a region with that name already exists");
+        }
+
+        let num_frames = (size_in_bytes + PAGE_SIZE - 1) / PAGE_SIZE;
+        let frames = memory::allocate_frames(num_frames).map_err(|e| {
+            error!("shared_memory: couldn't allocate {} frames for region {:?}: {}", num_frames, name, e);
+            "shared_memory: couldn't allocate frames for the region"
+        })?;
+        // Start tracking the frames with the region's own reference to them,
+        // such that each mapping of them is another reference to them.
+        for frame in frames.clone() {
+            frame_refcount::incref(frame);
+        }
+        let region = Arc::new(SharedRegion {
+            name: name.to_string(),
+            size_in_bytes,
+            frames,
+        });
+
+        let mut mapping = SharedMapping::map(region.clone(), true)?;
+        let num_bytes = mapping.mapping.size_in_bytes();
+        for byte in mapping.mapping.as_slice_mut::<u8>(0, num_bytes)?.iter_mut() {
+            *byte = 0;
+        }
+        regions.insert(name.to_string(), Arc::downgrade(&region));
+        debug!("shared_memory: created region {:?} of {} bytes at {:#X}", name, size_in_bytes, mapping.region.frames.start_address());
+        Ok(mapping)
+    }
+
+    /// Maps the existing shared memory region with the given `name`, and returns a writable mapping of it.
+    pub fn open(name: &str) -> Result<SharedMapping, &'static str> {
+        SharedMapping::map(find_region(name)?, true)
+    }
+
+    /// Maps the existing shared memory region with the given `name`, and returns a read-only mapping of it.
+    pub fn open_read_only(name: &str) -> Result<SharedMapping, &'static str> {
+        SharedMapping::map(find_region(name)?, false)
+    }
+
+    /// Maps the frames of the given `region` to newly-allocated pages.
+    fn map(region: Arc<SharedRegion>, writable: bool) -> Result<SharedMapping, &'static str> {
+        let pages = memory::allocate_pages(region.frames.size_in_frames()).ok_or("shared_memory: couldn't allocate pages for the mapping")?;
+        let flags = if writable {
+            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE
+        } else {
+            EntryFlags::NO_EXECUTE
+        };
+        let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("shared_memory: KERNEL_MMI was not yet initialized")?;
+        let mapping = kernel_mmi_ref.lock().page_table.map_allocated_pages_to(pages, region.frames.clone(), flags, &mut CachedFrameAllocator)?;
+        Ok(SharedMapping { mapping, region })
+    }
+
+    /// Returns the name of this mapping's region.
+    pub fn name(&self) -> &str {
+        &self.region.name
+    }
+
+    /// Returns the size of this mapping's region, as given when it was created.
+    pub fn size_in_bytes(&self) -> usize {
+        self.region.size_in_bytes
+    }
+
+    /// Returns whether the region can be written through this mapping.
+    pub fn is_writable(&self) -> bool {
+        self.mapping.flags().is_writable()
+    }
+
+    /// Returns the contents of this mapping's region.
+    pub fn as_slice(&self) -> Result<&[u8], &'static str> {
+        self.mapping.as_slice(0, self.region.size_in_bytes)
+    }
+
+    /// Returns the contents of this mapping's region mutably.
+    /// Returns an error if this is a read-only mapping.
+    pub fn as_slice_mut(&mut self) -> Result<&mut [u8], &'static str> {
+        let size_in_bytes = self.region.size_in_bytes;
+        self.mapping.as_slice_mut(0, size_in_bytes)
+    }
+}
+
+impl Deref for SharedMapping {
+    type Target = MappedPages;
+    fn deref(&self) -> &MappedPages {
+        &self.mapping
+    }
+}
+
+
+/// Returns the names and sizes of all shared memory regions that currently exist.
+pub fn regions() -> Vec<(String, usize)> {
+    let mut regions = REGIONS.lock();
+    remove_dropped_regions(&mut regions);
+    regions.values()
+        .filter_map(|r| r.upgrade())
+        .map(|r| (r.name.clone(), r.size_in_bytes))
+        .collect()
+}
+
+fn find_region(name: &str) -> Result<Arc<SharedRegion>, &'static str> {
+    let mut regions = REGIONS.lock();
+    remove_dropped_regions(&mut regions);
+    regions.get(name)
+        .and_then(|r| r.upgrade())
+        .ok_or("shared_memory: no region with that name exists")
+}
+
+fn remove_dropped_regions(regions: &mut BTreeMap<String, Weak<SharedRegion>>) {
+    let dropped: Vec<String> = regions.iter()
+        .filter(|(_, r)| r.upgrade().is_none())
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in dropped {
+        regions.remove(&name);
+    }
+}
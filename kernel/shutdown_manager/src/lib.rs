@@ -0,0 +1,281 @@
+//! An orderly shutdown path that quiesces all registered subsystems before powering off or rebooting the machine.
+//!
+//! Subsystems that must finish their work before the machine goes down, e.g., a network stack,
+//! filesystems with dirty caches, storage drivers, or services like the `kv_store`,
+//! [`register()`] a quiesce function along with the names of the subsystems they depend on.
+//! [`shutdown()`] quiesces them in reverse dependency order, i.e., every subsystem is quiesced
+//! before the subsystems it depends on, such that a filesystem can still flush its cache to storage.
+//!
+//! Each quiesce function runs in its own task and is given a timeout;
+//! a subsystem that doesn't finish in time is logged and left behind, so a hung subsystem can't prevent shutdown.
+//!
+//! [`register()`]: fn.register.html
+//! [`shutdown()`]: fn.shutdown.html
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate irq_safety;
+extern crate port_io;
+extern crate acpi;
+extern crate fadt;
+extern crate acpi_aml;
+extern crate task;
+extern crate spawn;
+extern crate sleep;
+extern crate tsc;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::vec::Vec;
+use irq_safety::MutexIrqSafe;
+use port_io::Port;
+use fadt::Fadt;
+use acpi_aml::aml::AmlValue;
+use task::{ExitValue, TaskRef};
+
+
+/// A function that quiesces a subsystem, after which it must not need any of its dependencies anymore.
+pub type QuiesceFn = fn() -> Result<(), &'static str>;
+
+/// What to do with the machine once all subsystems have been quiesced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownKind {
+    PowerOff,
+    Reboot,
+}
+
+
+/// A subsystem that is quiesced upon shutdown.
+#[derive(Clone, Copy)]
+struct Subsystem {
+    name: &'static str,
+    dependencies: &'static [&'static str],
+    timeout_ms: u64,
+    quiesce: QuiesceFn,
+}
+
+/// All registered subsystems, in the order they were registered.
+static SUBSYSTEMS: MutexIrqSafe<Vec<Subsystem>> = MutexIrqSafe::new(Vec::new());
+
+/// Whether a shutdown has already started, in which case subsystems are (being) quiesced.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// How often a subsystem's quiesce task is checked for having exited, in milliseconds.
+const EXIT_POLL_INTERVAL_MS: u64 = 10;
+
+/// The bit offset of the sleep type (SLP_TYPx) field in the PM1 control register.
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+/// The mask of the sleep type (SLP_TYPx) field in the PM1 control register.
+const PM1_CNT_SLP_TYP_MASK: u16 = 0b111 << PM1_CNT_SLP_TYP_SHIFT;
+/// The bit in the PM1 control register that enters the sleep state given by SLP_TYPx.
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+
+/// The status/command port of the PS/2 keyboard controller.
+const KEYBOARD_CONTROLLER_PORT: u16 = 0x64;
+/// The keyboard controller's status bit that indicates it hasn't yet consumed the last command.
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+/// The keyboard controller command that pulses the CPU reset line.
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xFE;
+
+
+/// Registers a subsystem to be quiesced upon shutdown.
+///
+/// # Arguments
+/// * `name`: the name of this subsystem, which other subsystems use to depend on it.
+/// * `dependencies`: the names of the subsystems that this subsystem uses, which are only quiesced after this one.
+///   They should be registered before this subsystem; a warning is logged for each one that hasn't been registered yet,
+///   since a misspelled or never-registered dependency would silently be quiesced out of order.
+/// * `timeout_ms`: how long `quiesce` may take before the shutdown proceeds without it.
+/// * `quiesce`: the function that quiesces this subsystem.
+///
+/// Returns an error if a subsystem with the same `name` was already registered or a shutdown is already in progress.
+pub fn register(
+    name: &'static str,
+    dependencies: &'static [&'static str],
+    timeout_ms: u64,
+    quiesce: QuiesceFn,
+) -> Result<(), &'static str> {
+    let mut subsystems = SUBSYSTEMS.lock();
+    if SHUTTING_DOWN.load(Ordering::SeqCst) {
+        return Err("shutdown_manager: can't register a subsystem while shutting down");
+    }
+    if subsystems.iter().any(|s| s.name == name) {
+        return Err("shutdown_manager: a subsystem with that name was already registered");
+    }
+    for dependency in dependencies.iter().filter(|&&d| !subsystems.iter().any(|s| s.name == d)) {
+        warn!("shutdown_manager: {} depends on subsystem {:?}, which hasn't been registered", name, dependency);
+    }
+    subsystems.push(Subsystem { name, dependencies, timeout_ms, quiesce });
+    Ok(())
+}
+
+/// Returns the names of all registered subsystems in the order they will be quiesced.
+pub fn quiesce_order() -> Vec<&'static str> {
+    let subsystems = SUBSYSTEMS.lock();
+    order(&subsystems).into_iter().map(|i| subsystems[i].name).collect()
+}
+
+/// Returns whether a shutdown has started.
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+
+/// Quiesces all registered subsystems and then powers off or reboots the machine, depending on `kind`.
+///
+/// This only returns if the machine couldn't be powered off or reset, or if a shutdown is already in progress.
+/// The subsystems remain quiesced in that case, since there is no way to restart them.
+pub fn shutdown(kind: ShutdownKind) -> Result<(), &'static str> {
+    let tsc_frequency = tsc::get_tsc_frequency()?;
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        return Err("shutdown_manager: a shutdown is already in progress");
+    }
+    info!("shutdown_manager: starting {:?}", kind);
+
+    let subsystems = SUBSYSTEMS.lock().clone();
+    for i in order(&subsystems) {
+        quiesce(&subsystems[i], tsc_frequency);
+    }
+
+    match kind {
+        ShutdownKind::PowerOff => power_off(),
+        ShutdownKind::Reboot => reboot(),
+    }
+}
+
+
+/// Returns the indices of the given `subsystems` in reverse dependency order.
+///
+/// Among the subsystems that no remaining subsystem depends on, the most recently registered one goes first.
+/// If there is a dependency cycle, the most recently registered subsystem in it goes first.
+fn order(subsystems: &[Subsystem]) -> Vec<usize> {
+    let mut remaining: Vec<usize> = (0 .. subsystems.len()).collect();
+    let mut order = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let is_needed = |i: usize| remaining.iter().any(|&j| j != i && subsystems[j].dependencies.contains(&subsystems[i].name));
+        let next = match remaining.iter().rposition(|&i| !is_needed(i)) {
+            Some(next) => next,
+            None => {
+                warn!("shutdown_manager: dependency cycle among subsystems {:?}",
+                    remaining.iter().map(|&i| subsystems[i].name).collect::<Vec<_>>()
+                );
+                remaining.len() - 1
+            }
+        };
+        order.push(remaining.remove(next));
+    }
+    order
+}
+
+/// Runs the given subsystem's quiesce function in a new task, and waits for it until its timeout elapses.
+fn quiesce(subsystem: &Subsystem, tsc_frequency: u64) {
+    info!("shutdown_manager: quiescing {}", subsystem.name);
+    let task = match spawn::new_task_builder(run_quiesce, subsystem.quiesce)
+        .name(format!("quiesce_{}", subsystem.name))
+        .spawn()
+    {
+        Ok(task) => task,
+        Err(e) => {
+            warn!("shutdown_manager: couldn't spawn a task to quiesce {}, quiescing it without a timeout: {}", subsystem.name, e);
+            if let Err(e) = (subsystem.quiesce)() {
+                error!("shutdown_manager: failed to quiesce {}: {}", subsystem.name, e);
+            }
+            return;
+        }
+    };
+
+    let timeout_ticks = tsc_frequency.saturating_mul(subsystem.timeout_ms) / 1000;
+    let poll_ticks = tsc_frequency.saturating_mul(EXIT_POLL_INTERVAL_MS) / 1000;
+    if !wait_for_exit(&task, timeout_ticks, poll_ticks) {
+        // Killing the task could leave behind locks that it holds, which might hang the remaining subsystems.
+        error!("shutdown_manager: {} didn't quiesce within {} ms, continuing without it", subsystem.name, subsystem.timeout_ms);
+        return;
+    }
+    match task.take_exit_value() {
+        Some(ExitValue::Completed(exit_value)) => match exit_value.downcast_ref::<Result<(), &'static str>>() {
+            Some(Ok(())) => {}
+            Some(Err(e)) => error!("shutdown_manager: failed to quiesce {}: {}", subsystem.name, e),
+            None => error!("shutdown_manager: quiescing {} returned an unexpected value", subsystem.name),
+        },
+        Some(ExitValue::Killed(reason)) => error!("shutdown_manager: quiescing {} was killed: {}", subsystem.name, reason),
+        None => error!("shutdown_manager: couldn't get the result of quiescing {}", subsystem.name),
+    }
+}
+
+fn run_quiesce(quiesce: QuiesceFn) -> Result<(), &'static str> {
+    quiesce()
+}
+
+/// Waits for the given `task` to exit, for at most `timeout_ticks` TSC ticks,
+/// checking whether it has exited every `poll_ticks` TSC ticks.
+/// Returns whether the task has exited.
+fn wait_for_exit(task: &TaskRef, timeout_ticks: u64, poll_ticks: u64) -> bool {
+    let start: u64 = tsc::tsc_ticks().into();
+    let deadline = start.saturating_add(timeout_ticks);
+    while !task.lock().has_exited() {
+        let now: u64 = tsc::tsc_ticks().into();
+        if now >= deadline {
+            return false;
+        }
+        if let Err(_e) = sleep::sleep_until(core::cmp::min(now.saturating_add(poll_ticks), deadline)) {
+            warn!("shutdown_manager: couldn't sleep while waiting for a task to exit: {}", _e);
+            return task.lock().has_exited();
+        }
+    }
+    true
+}
+
+
+/// Returns a copy of the FADT, which describes the ACPI power management registers.
+fn fadt() -> Result<Fadt, &'static str> {
+    let tables = acpi::get_acpi_tables().lock();
+    Fadt::get(&tables).map(|fadt| *fadt).ok_or("shutdown_manager: the FADT ACPI table wasn't found")
+}
+
+/// Powers off the machine by entering the ACPI S5 (soft off) sleep state.
+fn power_off() -> Result<(), &'static str> {
+    let fadt = fadt()?;
+    let (pm1a_control_block, pm1b_control_block) = (fadt.pm1a_control_block, fadt.pm1b_control_block);
+    if pm1a_control_block == 0 {
+        return Err("shutdown_manager: the FADT has no PM1a control block");
+    }
+
+    // The sleep type values for S5 are the first two elements of the `\_S5` package.
+    let (slp_typa, slp_typb) = match acpi_aml::evaluate(&acpi_aml::path("\\_S5")?, acpi_aml::args(&[]))? {
+        AmlValue::Package(elements) => match (elements.get(0), elements.get(1)) {
+            (Some(&AmlValue::Integer(a)), Some(&AmlValue::Integer(b))) => (a as u16, b as u16),
+            (Some(&AmlValue::Integer(a)), None) => (a as u16, a as u16),
+            _ => return Err("shutdown_manager: the \\_S5 package doesn't contain sleep type values"),
+        },
+        _ => return Err("shutdown_manager: \\_S5 is not a package"),
+    };
+    // Give the firmware a chance to prepare for S5; most machines don't need this, so failures are ignored.
+    let _ = acpi_aml::evaluate(&acpi_aml::path("\\_PTS")?, acpi_aml::args(&[AmlValue::Integer(5)]));
+
+    info!("shutdown_manager: powering off");
+    enter_sleep_state(pm1a_control_block as u16, slp_typa);
+    if pm1b_control_block != 0 {
+        enter_sleep_state(pm1b_control_block as u16, slp_typb);
+    }
+    Err("shutdown_manager: the machine didn't power off")
+}
+
+/// Writes the given sleep type to the PM1 control register at `port` and sets its sleep enable bit.
+fn enter_sleep_state(port: u16, slp_typ: u16) {
+    let control = Port::<u16>::new(port);
+    let value = (control.read() & !PM1_CNT_SLP_TYP_MASK)
+        | ((slp_typ << PM1_CNT_SLP_TYP_SHIFT) & PM1_CNT_SLP_TYP_MASK)
+        | PM1_CNT_SLP_EN;
+    unsafe { control.write(value) };
+}
+
+/// Reboots the machine by pulsing the CPU reset line through the PS/2 keyboard controller.
+fn reboot() -> Result<(), &'static str> {
+    info!("shutdown_manager: rebooting");
+    let controller = Port::<u8>::new(KEYBOARD_CONTROLLER_PORT);
+    while controller.read() & KEYBOARD_CONTROLLER_INPUT_FULL != 0 { }
+    unsafe { controller.write(KEYBOARD_CONTROLLER_RESET) };
+    Err("shutdown_manager: the machine didn't reboot")
+}
+
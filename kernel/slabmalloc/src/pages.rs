@@ -16,6 +16,7 @@ pub(crate) trait Bitfield {
     fn clear_bit(&self, idx: usize);
     fn is_full(&self) -> bool;
     fn all_free(&self, relevant_bits: usize) -> bool;
+    fn free_count(&self) -> usize;
 }
 
 /// Implementation of bit operations on u64 slices.
@@ -148,6 +149,14 @@ impl Bitfield for [AtomicU64] {
 
         true
     }
+
+    /// Returns the number of free slots in the page.
+    ///
+    /// This works because the slots beyond the page's capacity are always marked allocated, see `initialize()`.
+    #[inline(always)]
+    fn free_count(&self) -> usize {
+        self.iter().map(|bitmap| bitmap.load(Ordering::Relaxed).count_zeros() as usize).sum()
+    }
 }
 
 /// This trait is used to define a page from which objects are allocated
@@ -215,6 +224,11 @@ pub trait AllocablePage {
         self.bitfield().all_free(relevant_bits)
     }
 
+    /// Returns the number of objects that can still be allocated within this page.
+    fn free_objects(&self) -> usize {
+        self.bitfield().free_count()
+    }
+
     /// Deallocates a memory object within this page.
     fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) -> Result<(), &'static str> {
         // trace!(
@@ -23,6 +23,23 @@ fn cmin(a: usize, b: usize) -> usize {
     core::cmp::min(a, b)
 }
 
+/// Statistics about the slabs of one `SCAllocator`, see [`SCAllocator::stats()`](struct.SCAllocator.html#method.stats).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SlabStats {
+    /// The size of the objects allocated by this `SCAllocator`.
+    pub object_size: usize,
+    /// The number of objects that fit within one slab (allocable page).
+    pub objects_per_slab: usize,
+    /// The number of slabs with no allocated objects.
+    pub empty_slabs: usize,
+    /// The number of slabs with some, but not all, objects allocated.
+    pub partial_slabs: usize,
+    /// The number of slabs in which every object is allocated.
+    pub full_slabs: usize,
+    /// The number of objects that are free within the partially-used slabs.
+    pub free_objects_in_partial_slabs: usize,
+}
+
 /// A slab allocator allocates elements of a fixed size.
 ///
 /// It maintains three internal lists of `MappedPages8k`
@@ -92,6 +109,20 @@ impl SCAllocator {
         self.size
     }
 
+    /// Returns statistics about the slabs of this allocator, e.g., for measuring fragmentation.
+    /// 
+    /// This takes `&mut self` because it walks the list of partially-used slabs.
+    pub fn stats(&mut self) -> SlabStats {
+        SlabStats {
+            object_size: self.size,
+            objects_per_slab: self.obj_per_page,
+            empty_slabs: self.empty_count,
+            partial_slabs: self.slabs.len(),
+            full_slabs: self.full_slabs.len(),
+            free_objects_in_partial_slabs: self.slabs.iter_mut().map(|mp| mp.as_objectpage8k_mut().free_objects()).sum(),
+        }
+    }
+
     /// Add a page to the partial list
     fn insert_partial(&mut self, new_page: MappedPages8k) {
         self.slabs.push(new_page);
@@ -148,6 +148,16 @@ impl ZoneAllocator {
         self.refill(layout, mp)
     }  
 
+    /// Returns statistics about the slabs of each of the size classes in this zone allocator,
+    /// in the same order as `BASE_ALLOC_SIZES`.
+    pub fn slab_stats(&mut self) -> [SlabStats; ZoneAllocator::MAX_BASE_SIZE_CLASSES] {
+        let mut stats = [SlabStats::default(); ZoneAllocator::MAX_BASE_SIZE_CLASSES];
+        for (stat, slab) in stats.iter_mut().zip(self.small_slabs.iter_mut()) {
+            *stat = slab.stats();
+        }
+        stats
+    }
+
     /// The total number of empty pages in this zone allocator
     pub fn empty_pages(&self) -> usize {
         let mut empty_pages = 0;
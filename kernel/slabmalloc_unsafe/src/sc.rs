@@ -23,6 +23,23 @@ fn cmin(a: usize, b: usize) -> usize {
     core::cmp::min(a, b)
 }
 
+/// Statistics about the slabs of one `SCAllocator`, see [`SCAllocator::stats()`](struct.SCAllocator.html#method.stats).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SlabStats {
+    /// The size of the objects allocated by this `SCAllocator`.
+    pub object_size: usize,
+    /// The number of objects that fit within one slab (allocable page).
+    pub objects_per_slab: usize,
+    /// The number of slabs with no allocated objects.
+    pub empty_slabs: usize,
+    /// The number of slabs with some, but not all, objects allocated.
+    pub partial_slabs: usize,
+    /// The number of slabs in which every object is allocated.
+    pub full_slabs: usize,
+    /// The number of objects that are free within the partially-used slabs.
+    pub free_objects_in_partial_slabs: usize,
+}
+
 /// A slab allocator allocates elements of a fixed size.
 ///
 /// It maintains three internal lists of objects that implement `AllocablePage`
@@ -93,6 +110,20 @@ impl<'a, P: AllocablePage> SCAllocator<'a, P> {
         self.size
     }
 
+    /// Returns statistics about the slabs of this allocator, e.g., for measuring fragmentation.
+    /// 
+    /// This takes `&mut self` because it walks the list of partially-used slabs.
+    pub fn stats(&mut self) -> SlabStats {
+        SlabStats {
+            object_size: self.size,
+            objects_per_slab: self.obj_per_page,
+            empty_slabs: self.empty_slabs.elements,
+            partial_slabs: self.slabs.elements,
+            full_slabs: self.full_slabs.elements,
+            free_objects_in_partial_slabs: self.slabs.iter_mut().map(|page| page.free_objects()).sum(),
+        }
+    }
+
     /// Add page to partial list.
     fn insert_partial_slab(&mut self, new_head: &'a mut P) {
         self.slabs.insert_front(new_head);
@@ -0,0 +1,97 @@
+//! Timer-based sleeping, which blocks the current task until a given amount of time has passed.
+//!
+//! A sleeping task is blocked and placed on a list of sleeping tasks, sorted by the TSC value at which it should wake up.
+//! On every local APIC timer tick, the timer interrupt handler invokes [`unblock_sleeping_tasks()`],
+//! which unblocks every task whose wakeup time has passed.
+//! Thus, a task wakes up at most one timer tick later than requested, and a sleeping task never uses the CPU.
+//!
+//! [`unblock_sleeping_tasks()`]: fn.unblock_sleeping_tasks.html
+
+#![no_std]
+
+extern crate alloc;
+extern crate irq_safety;
+extern crate task;
+extern crate scheduler;
+extern crate tsc;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use irq_safety::MutexIrqSafe;
+use task::TaskRef;
+
+
+/// The sleeping tasks and the TSC values at which they should wake up, sorted from the latest wakeup to the earliest,
+/// such that the tasks that should wake up next are at the end.
+static SLEEPING_TASKS: MutexIrqSafe<Vec<(u64, TaskRef)>> = MutexIrqSafe::new(Vec::new());
+/// The earliest wakeup time of all sleeping tasks, or `u64::MAX` if no task is sleeping,
+/// which lets the timer interrupt handler skip the list on most ticks.
+static NEXT_WAKEUP: AtomicU64 = AtomicU64::new(core::u64::MAX);
+
+
+/// Blocks the current task for at least the given number of milliseconds.
+pub fn sleep_ms(ms: u64) -> Result<(), &'static str> {
+    sleep(ms.saturating_mul(tsc::get_tsc_frequency()?) / 1000)
+}
+
+/// Blocks the current task for at least the given number of TSC ticks.
+pub fn sleep(ticks: u64) -> Result<(), &'static str> {
+    let now: u64 = tsc::tsc_ticks().into();
+    sleep_until(now.saturating_add(ticks))
+}
+
+/// Blocks the current task until the TSC reaches at least the given `deadline`.
+///
+/// If the task is unblocked earlier by something else, it is put back to sleep until the deadline.
+pub fn sleep_until(deadline: u64) -> Result<(), &'static str> {
+    let curr_task = task::get_my_current_task().ok_or("sleep: couldn't get the current task")?;
+    loop {
+        {
+            // Adding this task to the list and blocking it must happen atomically with respect to the timer interrupt,
+            // which is ensured by the list lock disabling interrupts.
+            let mut sleeping = SLEEPING_TASKS.lock();
+            let now: u64 = tsc::tsc_ticks().into();
+            let position = sleeping.iter().position(|(_, t)| t == curr_task);
+            if now >= deadline {
+                if let Some(index) = position {
+                    sleeping.remove(index);
+                    update_next_wakeup(&sleeping);
+                }
+                return Ok(());
+            }
+            if position.is_none() {
+                let index = sleeping.iter().position(|&(wakeup, _)| wakeup < deadline).unwrap_or(sleeping.len());
+                sleeping.insert(index, (deadline, curr_task.clone()));
+                update_next_wakeup(&sleeping);
+            }
+            curr_task.block();
+        }
+        scheduler::schedule();
+    }
+}
+
+/// Unblocks every sleeping task whose wakeup time has passed.
+///
+/// This is invoked by the timer interrupt handler on every tick, so it never waits for the list lock;
+/// if another core holds it, the tasks are unblocked on a later tick instead.
+pub fn unblock_sleeping_tasks() {
+    let now: u64 = tsc::tsc_ticks().into();
+    if NEXT_WAKEUP.load(Ordering::Acquire) > now {
+        return;
+    }
+    let mut sleeping = match SLEEPING_TASKS.try_lock() {
+        Some(sleeping) => sleeping,
+        None => return,
+    };
+    while sleeping.last().map_or(false, |&(wakeup, _)| wakeup <= now) {
+        if let Some((_, task)) = sleeping.pop() {
+            task.unblock();
+        }
+    }
+    update_next_wakeup(&sleeping);
+}
+
+fn update_next_wakeup(sleeping: &[(u64, TaskRef)]) {
+    let next = sleeping.last().map_or(core::u64::MAX, |&(wakeup, _)| wakeup);
+    NEXT_WAKEUP.store(next, Ordering::Release);
+}
@@ -1,5 +1,9 @@
 
 //! Collection of functions to set up a TCP connection using a smoltcp device
+//!
+//! Note that the version of smoltcp we use (0.5) implements neither the TCP window scale option,
+//! selective acknowledgments (SACK), nor a choice of congestion control algorithm,
+//! so the only TCP tuning available here is the size of each socket's buffers.
 
 #![no_std]
 
@@ -9,20 +13,45 @@ extern crate smoltcp;
 extern crate network_manager;
 extern crate spin;
 extern crate hpet;
+extern crate task;
 
 use core::convert::TryInto;
 use spin::Once;
 use hpet::get_hpet;
 use smoltcp::{
-    wire::IpEndpoint,
-    socket::{SocketSet, TcpSocket, SocketHandle},
+    wire::{IpAddress, IpEndpoint, Ipv4Address},
+    socket::{SocketSet, TcpSocket, TcpSocketBuffer, SocketHandle},
     time::Instant
 };
-use network_manager::{NetworkInterfaceRef, NETWORK_INTERFACES};
+use network_manager::{routing, stats, NetworkInterfaceRef, NETWORK_INTERFACES};
 
 /// The starting number for freely-available (non-reserved) standard TCP/UDP ports.
 pub const STARTING_FREE_PORT: u16 = 49152;
 
+/// The size of each of a TCP socket's buffers for interactive or low-volume traffic.
+pub const DEFAULT_TCP_BUFFER_SIZE: usize = 4096;
+
+/// The size of a TCP socket's receive buffer for bulk transfers, e.g., downloading crates from the update server.
+///
+/// The receive buffer's size is the largest window that the socket can advertise,
+/// which bounds throughput to one window per round trip, so a small buffer severely limits bulk transfers.
+/// This is the largest window that fits in the TCP header's 16-bit window field;
+/// a larger buffer would be wasted, because our version of smoltcp doesn't negotiate the window scale option.
+pub const BULK_TCP_BUFFER_SIZE: usize = u16::max_value() as usize;
+
+/// Creates a new TCP socket with a receive buffer of `rx_buffer_size` bytes
+/// and a transmit buffer of `tx_buffer_size` bytes.
+///
+/// See [`DEFAULT_TCP_BUFFER_SIZE`] and [`BULK_TCP_BUFFER_SIZE`] for suitable sizes.
+///
+/// [`DEFAULT_TCP_BUFFER_SIZE`]: constant.DEFAULT_TCP_BUFFER_SIZE.html
+/// [`BULK_TCP_BUFFER_SIZE`]: constant.BULK_TCP_BUFFER_SIZE.html
+pub fn new_tcp_socket(rx_buffer_size: usize, tx_buffer_size: usize) -> TcpSocket<'static> {
+    let rx_buffer = TcpSocketBuffer::new(vec![0; rx_buffer_size]);
+    let tx_buffer = TcpSocketBuffer::new(vec![0; tx_buffer_size]);
+    TcpSocket::new(rx_buffer, tx_buffer)
+}
+
 /// A simple macro to get the current HPET clock ticks.
 #[macro_export]
 macro_rules! hpet_ticks {
@@ -52,15 +81,20 @@ pub fn millis_since(start_time: u64) -> Result<u64, &'static str> {
 }
 
 
-/// Returns the first network interface available in the system.
+/// Returns the network interface of the default IPv4 route,
+/// or the first network interface available in the system if there is no default route.
 pub fn get_default_iface() -> Result<NetworkInterfaceRef, &'static str> {
-    NETWORK_INTERFACES.lock()
-        .iter()
-        .next()
-        .cloned()
+    routing::lookup_route(Ipv4Address::UNSPECIFIED.into())
+        .map(|route| route.interface)
+        .or_else(|| NETWORK_INTERFACES.lock().iter().next().cloned())
         .ok_or_else(|| "no network interfaces available")
 }
 
+/// Returns the network interface that the routing table chooses for sending packets to the given `destination`.
+pub fn get_iface_for(destination: IpAddress) -> Result<NetworkInterfaceRef, &'static str> {
+    routing::interface_for(destination).ok_or_else(|| "no route to the destination address")
+}
+
 /// A convenience function for connecting a socket.
 /// If the given socket is already open, it is forcibly closed immediately and reconnected.
 pub fn connect(
@@ -113,6 +147,8 @@ pub fn connect(
 
 /// A convenience function to poll the given network interface (i.e., flush tx/rx).
 /// Returns true if any packets were sent or received through that interface on the given `sockets`.
+///
+/// The state of the given `sockets` is recorded in the network statistics afterwards.
 pub fn poll_iface(iface: &NetworkInterfaceRef, sockets: &mut SocketSet, startup_time: u64) -> Result<bool, &'static str> {
     let timestamp: i64 = millis_since(startup_time)?
         .try_into()
@@ -125,5 +161,6 @@ pub fn poll_iface(iface: &NetworkInterfaceRef, sockets: &mut SocketSet, startup_
             false
         }
     };
+    stats::record_sockets(sockets, task::get_my_current_task_id());
     Ok(packets_were_sent_or_received)
 }
@@ -0,0 +1,132 @@
+//! Support for running a function on other cores, also known as a cross-core function call.
+//!
+//! The calling core sends an inter-processor interrupt (IPI) to the target cores,
+//! each of which runs the function in its IPI handler, and the caller waits until all of them have finished.
+//! This is needed for per-core state that a core can only change on itself,
+//! e.g., MSRs that must be written on every core, or each core's performance counters.
+//!
+//! The function runs with interrupts disabled on each target core, so it must be short and must not block,
+//! e.g., on a lock that the calling task may hold.
+//! Only one cross-core call happens at a time; concurrent callers wait until the current call has completed.
+
+#![no_std]
+
+extern crate spin;
+extern crate irq_safety;
+extern crate apic;
+extern crate interrupts;
+extern crate pause;
+extern crate x86_64;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use irq_safety::{hold_interrupts, interrupts_enabled};
+use apic::LapicIpiDestination;
+use pause::spin_loop_hint;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+
+/// The IRQ number used for cross-core function call IPIs.
+pub const SMP_CALL_IPI_IRQ: u8 = 0x41;
+
+/// The lock that makes sure only one cross-core call is concurrently happening.
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+/// The address of the function of the current call, which is a `&(dyn Fn() + Sync)` on the caller's stack,
+/// or `0` if there is no current call.
+static CALL_FUNC: AtomicUsize = AtomicUsize::new(0);
+/// The number of target cores that still need to run the function of the current call.
+static CALL_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+
+/// The cores that a function is run on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Destination {
+    One(u8),
+    All,
+    AllButMe,
+}
+
+
+/// Registers the IPI handler for cross-core function calls.
+///
+/// This must be invoked once the IDT has been set up, before any function is run on other cores.
+pub fn init() -> Result<(), &'static str> {
+    interrupts::register_interrupt(SMP_CALL_IPI_IRQ, smp_call_ipi_handler)
+}
+
+/// Runs `func` on the core with the given APIC ID, and returns once it has finished.
+///
+/// If that's the current core, `func` is run directly with interrupts disabled.
+pub fn call_on_core<F: Fn() + Sync>(apic_id: u8, func: F) -> Result<(), &'static str> {
+    if apic::get_lapics().get(&apic_id).is_none() {
+        return Err("smp_call: there is no core with the given APIC ID");
+    }
+    call(Destination::One(apic_id), &func)
+}
+
+/// Runs `func` on every core, including the current one, and returns once all of them have finished.
+pub fn call_on_all_cores<F: Fn() + Sync>(func: F) -> Result<(), &'static str> {
+    call(Destination::All, &func)
+}
+
+/// Runs `func` on every core except the current one, and returns once all of them have finished.
+pub fn call_on_other_cores<F: Fn() + Sync>(func: F) -> Result<(), &'static str> {
+    call(Destination::AllButMe, &func)
+}
+
+
+fn call(destination: Destination, func: &(dyn Fn() + Sync)) -> Result<(), &'static str> {
+    // A core that's waiting for the call lock with interrupts disabled could never handle the current call's IPI,
+    // so the current caller would wait for it forever.
+    if !interrupts_enabled() {
+        return Err("smp_call: a function can't be run on other cores while interrupts are disabled");
+    }
+    let _call_guard = CALL_LOCK.lock();
+
+    {
+        // This task must stay on the same core from determining which cores are the "other" cores until `func` has run locally.
+        let _held_ints = hold_interrupts();
+        let my_apic_id = apic::get_my_apic_id();
+        let other_cores = match apic::core_count() {
+            0 | 1 => None,
+            count => Some((LapicIpiDestination::AllButMe, count - 1)),
+        };
+        let (targets, run_locally) = match destination {
+            Destination::One(apic_id) if apic_id == my_apic_id => (None, true),
+            Destination::One(apic_id) => (Some((LapicIpiDestination::One(apic_id), 1)), false),
+            Destination::All => (other_cores, true),
+            Destination::AllButMe => (other_cores, false),
+        };
+
+        if let Some((ipi_destination, count)) = targets {
+            let my_lapic = apic::get_my_apic().ok_or("smp_call: couldn't get this core's local APIC")?;
+            CALL_FUNC.store(&func as *const &(dyn Fn() + Sync) as usize, Ordering::Release);
+            CALL_PENDING.store(count, Ordering::Release);
+            my_lapic.write().send_ipi(SMP_CALL_IPI_IRQ, ipi_destination);
+        }
+        if run_locally {
+            func();
+        }
+    }
+
+    // wait for all target cores to run `func`, which is borrowed from this stack frame until then
+    while CALL_PENDING.load(Ordering::Acquire) > 0 {
+        spin_loop_hint();
+    }
+    CALL_FUNC.store(0, Ordering::Release);
+    Ok(())
+}
+
+
+/// Runs the function of the current cross-core call on this core.
+extern "x86-interrupt" fn smp_call_ipi_handler(_stack_frame: &mut ExceptionStackFrame) {
+    let func = CALL_FUNC.load(Ordering::Acquire);
+    if func != 0 {
+        // This is safe because the caller waits until every target core has decremented `CALL_PENDING`
+        // before its function goes out of scope.
+        let func = unsafe { *(func as *const &(dyn Fn() + Sync)) };
+        func();
+        CALL_PENDING.fetch_sub(1, Ordering::AcqRel);
+    }
+    interrupts::eoi(None);
+}
@@ -15,6 +15,7 @@
 #[macro_use] extern crate alloc;
 #[macro_use] extern crate log;
 #[macro_use] extern crate debugit;
+extern crate spin;
 extern crate irq_safety;
 extern crate memory;
 extern crate stack;
@@ -29,6 +30,9 @@ extern crate fs_node;
 extern crate catch_unwind;
 extern crate fault_crate_swap;
 extern crate pause;
+extern crate environment;
+extern crate cpu_topology;
+extern crate protection_keys;
 
 
 use core::{
@@ -42,14 +46,17 @@ use alloc::{
     sync::Arc,
     boxed::Box,
 };
+use spin::Mutex;
 use irq_safety::{MutexIrqSafe, hold_interrupts, enable_interrupts};
-use memory::{get_kernel_mmi_ref, MemoryManagementInfo};
+use memory::{get_kernel_mmi_ref, MemoryManagementInfo, FrameOwner};
 use stack::Stack;
-use task::{Task, TaskRef, get_my_current_task, RunState, RestartInfo, TASKLIST};
+use task::{Task, TaskRef, get_my_current_task, RunState, RestartInfo, CpuTimeLimit, TASKLIST};
+use cpu_topology::CoreTypeHint;
 use mod_mgmt::{CrateNamespace, SectionType, SECTION_HASH_DELIMITER};
 use path::Path;
 use apic::get_my_apic_id;
 use fs_node::FileOrDir;
+use environment::Environment;
 
 #[cfg(simd_personality)]
 use task::SimdExt;
@@ -63,6 +70,7 @@ pub fn init(
     stack: Stack,
 ) -> Result<BootstrapTaskRef, &'static str> {
     runqueue::init(apic_id)?;
+    memory::set_frame_owner_resolver(current_task_frame_owner);
     
     let task_ref = task::bootstrap_task(apic_id, stack, kernel_mmi_ref)?;
     runqueue::add_task_to_specific_runqueue(apic_id, task_ref.clone())?;
@@ -72,6 +80,11 @@ pub fn init(
     })
 }
 
+/// Attributes newly-allocated frames to the current task, if frame accounting is enabled.
+fn current_task_frame_owner() -> FrameOwner {
+    task::get_my_current_task_id().map(FrameOwner::Task).unwrap_or(FrameOwner::Unknown)
+}
+
 /// A wrapper around a `TaskRef` that is for bootstrapped tasks. 
 /// 
 /// See `spawn::init()` and `task::bootstrap_task()`.
@@ -172,8 +185,10 @@ pub fn new_application_task_builder(
 
     // Create the underlying task builder. 
     // Give it a default name based on the app crate's name, but that can be changed later. 
+    let crate_name = app_crate_ref.lock_as_ref().crate_name.clone();
+    let crate_rights = protection_keys::crate_rights(&crate_name);
     let mut tb = TaskBuilder::new(*main_func, MainFuncArg::default())
-        .name(app_crate_ref.lock_as_ref().crate_name.clone()); 
+        .name(crate_name); 
 
     // Once the new application task is created (but before its scheduled in),
     // ensure it has the relevant app-specific fields set properly.
@@ -181,6 +196,7 @@ pub fn new_application_task_builder(
         move |new_task| {
             new_task.app_crate = Some(Arc::new(app_crate_ref));
             new_task.namespace = namespace;
+            new_task.protection_key_rights = new_task.protection_key_rights.intersect(crate_rights);
             Ok(())
         }
     ));
@@ -208,6 +224,9 @@ pub struct TaskBuilder<F, A, R> {
     blocked: bool,
     idle: bool,
     post_build_function: Option<Box< dyn FnOnce(&mut Task) -> Result<(), &'static str> >>,
+    env: Option<Arc<Mutex<Environment>>>,
+    cpu_time_limit: Option<CpuTimeLimit>,
+    core_type_hint: CoreTypeHint,
 
     #[cfg(simd_personality)]
     simd: SimdExt,
@@ -230,6 +249,9 @@ impl<F, A, R> TaskBuilder<F, A, R>
             blocked: false,
             idle: false,
             post_build_function: None,
+            env: None,
+            cpu_time_limit: None,
+            core_type_hint: CoreTypeHint::default(),
 
             #[cfg(simd_personality)]
             simd: SimdExt::None,
@@ -248,6 +270,32 @@ impl<F, A, R> TaskBuilder<F, A, R>
         self
     }
 
+    /// Set the `Environment` (working directory, environment variables, etc) of the new Task.
+    /// 
+    /// The given environment is shared with the new Task, 
+    /// so any changes made by either the new Task or the caller will be visible to both.
+    /// By default, the new Task receives its own copy of the current task's environment.
+    pub fn env(mut self, env: Arc<Mutex<Environment>>) -> TaskBuilder<F, A, R> {
+        self.env = Some(env);
+        self
+    }
+
+    /// Limit the amount of CPU time that the new Task may use. 
+    /// 
+    /// Once it exceeds the soft limit, it is requested to cancel itself;
+    /// once it exceeds the hard limit, it is killed. See `task::CpuTimeLimit`.
+    pub fn cpu_time_limit(mut self, limit: CpuTimeLimit) -> TaskBuilder<F, A, R> {
+        self.cpu_time_limit = Some(limit);
+        self
+    }
+
+    /// Hint which type of core the new Task should preferably run on, e.g., a performance core for a latency-critical task
+    /// or an efficiency core for background work. This has no effect if the new Task is pinned to a specific core.
+    pub fn core_type_hint(mut self, hint: CoreTypeHint) -> TaskBuilder<F, A, R> {
+        self.core_type_hint = hint;
+        self
+    }
+
     /// Pin the new Task to a specific core.
     pub fn pin_on_core(mut self, core_apic_id: u8) -> TaskBuilder<F, A, R> {
         self.pin_on_core = Some(core_apic_id);
@@ -288,6 +336,14 @@ impl<F, A, R> TaskBuilder<F, A, R>
             new_task.simd = self.simd;
         }
 
+        if let Some(env) = self.env {
+            new_task.env = env;
+        }
+        new_task.cpu_time_limit = self.cpu_time_limit;
+        new_task.core_type_hint = self.core_type_hint;
+        // A new task can't access more than the task that spawned it.
+        new_task.protection_key_rights = protection_keys::current_rights();
+
         setup_context_trampoline(&mut new_task, task_wrapper::<F, A, R>)?;
 
         // Currently we're using the very bottom of the kstack for kthread arguments. 
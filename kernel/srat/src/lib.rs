@@ -0,0 +1,321 @@
+//! Support for the SRAT ACPI table (System Resource Affinity Table),
+//! which describes which cores and memory regions belong to which NUMA node ("proximity domain").
+
+#![no_std]
+#![allow(safe_packed_borrows)]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate memory;
+extern crate sdt;
+extern crate acpi_table;
+extern crate zerocopy;
+
+use core::mem::size_of;
+use alloc::{
+    collections::BTreeMap,
+    vec::Vec,
+};
+use memory::{MappedPages, PhysicalAddress, PhysicalMemoryArea, NumaNodeInfo};
+use sdt::Sdt;
+use acpi_table::{AcpiSignature, AcpiTables};
+use zerocopy::FromBytes;
+
+
+pub const SRAT_SIGNATURE: &'static [u8; 4] = b"SRAT";
+
+
+/// The handler for parsing the SRAT table and adding it to the ACPI tables list.
+pub fn handle(
+    acpi_tables: &mut AcpiTables,
+    signature: AcpiSignature,
+    _length: usize,
+    phys_addr: PhysicalAddress
+) -> Result<(), &'static str> {
+    // The SRAT has a variable number of entries, and each entry is of variable size. 
+    // So we can't determine the slice_length (just use 0 instead), but we can determine where it starts.
+    let slice_start_paddr = phys_addr + size_of::<SratAcpiTable>();
+    acpi_tables.add_table_location(signature, phys_addr, Some((slice_start_paddr, 0)))
+}
+
+
+/// The fixed-size components of the SRAT ACPI table.
+/// Its layout and total size must exactly match that of the ACPI specification.
+/// 
+/// At the end, there is an unknown number of table entries, each of variable size. 
+#[derive(Debug, FromBytes)]
+#[repr(packed)]
+struct SratAcpiTable {
+    header: Sdt,
+    /// Must be `1` for backwards compatibility.
+    _reserved1: u32,
+    _reserved2: u64,
+    // Following this is a variable number of variable-sized table entries,
+    // so we cannot include them here.
+}
+
+
+/// A wrapper around the SRAT ACPI table (System Resource Affinity Table),
+/// which contains the NUMA topology of the system. 
+pub struct Srat<'t> {
+    /// The fixed-size part of the actual SRAT ACPI table.
+    table: &'t SratAcpiTable,
+    /// The underlying MappedPages that cover this SRAT
+    mapped_pages: &'t MappedPages,
+    /// The starting offset of the dynamic part of the SRAT table.
+    /// This is to be used as an offset into the above `mapped_pages`.
+    dynamic_entries_starting_offset: usize,
+    /// The total size in bytes of all dynamic entries.
+    /// This is *not* the number of entries.
+    dynamic_entries_total_size: usize,
+}
+
+impl<'t> Srat<'t> {
+    /// Finds the SRAT in the given `AcpiTables` and returns a reference to it.
+    pub fn get(acpi_tables: &'t AcpiTables) -> Option<Srat<'t>> {
+        let table: &SratAcpiTable = acpi_tables.table(&SRAT_SIGNATURE).ok()?;
+        let total_length = table.header.length as usize;
+        let dynamic_part_length = total_length - size_of::<SratAcpiTable>();
+        let loc = acpi_tables.table_location(&SRAT_SIGNATURE)?;
+        Some(Srat {
+            table: table,
+            mapped_pages: acpi_tables.mapping(),
+            dynamic_entries_starting_offset: loc.slice_offset_and_length?.0,
+            dynamic_entries_total_size: dynamic_part_length,
+        })
+    }
+
+    /// Returns an iterator over the SRAT's entries,
+    /// which are variable in both number and size.
+    pub fn iter(&self) -> SratIter {
+        SratIter {
+            mapped_pages: self.mapped_pages,
+            offset: self.dynamic_entries_starting_offset,
+            end_of_entries: self.dynamic_entries_starting_offset + self.dynamic_entries_total_size,
+        }
+    }
+
+    /// Returns a reference to the `Sdt` header in this SRAT table.
+    pub fn sdt(&self) -> &Sdt {
+        &self.table.header
+    }
+
+    /// Groups all of the enabled entries in this SRAT into a list of NUMA nodes, 
+    /// each of which contains its cores and memory areas.
+    /// 
+    /// Cores with an x2APIC ID that doesn't fit into a regular APIC ID are ignored.
+    pub fn numa_nodes(&self) -> Vec<NumaNodeInfo> {
+        let mut nodes: BTreeMap<u32, NumaNodeInfo> = BTreeMap::new();
+        fn node_entry(nodes: &mut BTreeMap<u32, NumaNodeInfo>, id: u32) -> &mut NumaNodeInfo {
+            nodes.entry(id).or_insert_with(|| NumaNodeInfo { id, memory_areas: Vec::new(), apic_ids: Vec::new() })
+        }
+
+        for entry in self.iter() {
+            match entry {
+                SratEntry::LocalApicAffinity(lapic) if lapic.is_enabled() => {
+                    node_entry(&mut nodes, lapic.proximity_domain()).apic_ids.push(lapic.apic_id);
+                }
+                SratEntry::MemoryAffinity(mem) if mem.is_enabled() && mem.length() > 0 => {
+                    let area = PhysicalMemoryArea::new(PhysicalAddress::new_canonical(mem.base_address() as usize), mem.length() as usize, 1, 0);
+                    node_entry(&mut nodes, mem.proximity_domain).memory_areas.push(area);
+                }
+                SratEntry::X2ApicAffinity(x2apic) if x2apic.is_enabled() => {
+                    if x2apic.x2apic_id <= u8::max_value() as u32 {
+                        node_entry(&mut nodes, x2apic.proximity_domain).apic_ids.push(x2apic.x2apic_id as u8);
+                    } else {
+                        warn!("SRAT: ignoring x2APIC {} in proximity domain {}, its ID is too large", { x2apic.x2apic_id }, { x2apic.proximity_domain });
+                    }
+                }
+                _ => { }
+            }
+        }
+        nodes.into_iter().map(|(_id, node)| node).collect()
+    }
+}
+
+
+/// An Iterator over the dynamic entries of the SRAT.
+/// Its lifetime is dependent upon the lifetime of its `Srat` instance,
+/// which itself is bound to the lifetime of the underlying `AcpiTables`. 
+#[derive(Clone)]
+pub struct SratIter<'t> {
+    /// The underlying MappedPages that contain all ACPI tables.
+    mapped_pages: &'t MappedPages,
+    /// The offset of the next entry, which should point to a `EntryRecord`
+    /// at the start of each iteration.
+    offset: usize,
+    /// The end bound of all SRAT entries. 
+    /// This is fixed and should not ever change throughout iteration.
+    end_of_entries: usize,
+}
+
+impl<'t> Iterator for SratIter<'t> {
+    type Item = SratEntry<'t>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if (self.offset + ENTRY_RECORD_SIZE) < self.end_of_entries {
+            // First, we get the next entry record to get the type and size of the actual entry.
+            let (entry_type, entry_size) = { 
+                let entry_record: &EntryRecord = self.mapped_pages.as_type(self.offset).ok()?;
+                (entry_record.typ, entry_record.size as usize)
+            };
+            // A zero-sized entry would cause us to loop forever.
+            if entry_size == 0 {
+                return None;
+            }
+            // Second, use that entry type and size to return the specific Srat entry struct.
+            if (self.offset + entry_size) <= self.end_of_entries {
+                let entry: Option<SratEntry> = match entry_type {
+                    ENTRY_TYPE_LOCAL_APIC_AFFINITY if entry_size == size_of::<SratLocalApicAffinity>() => {
+                        self.mapped_pages.as_type(self.offset).ok().map(|ent| SratEntry::LocalApicAffinity(ent))
+                    },
+                    ENTRY_TYPE_MEMORY_AFFINITY if entry_size == size_of::<SratMemoryAffinity>() => {
+                        self.mapped_pages.as_type(self.offset).ok().map(|ent| SratEntry::MemoryAffinity(ent))
+                    },
+                    ENTRY_TYPE_X2APIC_AFFINITY if entry_size == size_of::<SratX2ApicAffinity>() => {
+                        self.mapped_pages.as_type(self.offset).ok().map(|ent| SratEntry::X2ApicAffinity(ent))
+                    },
+                    _ => None,
+                };
+                // move the offset to the end of this entry, i.e., the beginning of the next entry record
+                self.offset += entry_size;
+                // return the SRAT entry if properly formed, or if not, return an unknown/corrupt entry.
+                entry.or(Some(SratEntry::UnknownOrCorrupt(entry_type)))
+            }
+            else {
+                None
+            }
+        }
+        else {
+            None
+        }
+    }
+}
+
+
+/// A SRAT entry record, which precedes each actual SRAT entry
+/// and describes its type and size.
+#[derive(Clone, Copy, Debug, FromBytes)]
+#[repr(packed)]
+struct EntryRecord {
+    /// The type identifier of a SRAT entry.
+    typ: u8,
+    /// The size in bytes of a SRAT entry.
+    size: u8,
+}
+const ENTRY_RECORD_SIZE: usize = size_of::<EntryRecord>();
+
+
+// The following list specifies SRAT entry type IDs.
+const ENTRY_TYPE_LOCAL_APIC_AFFINITY: u8 = 0;
+const ENTRY_TYPE_MEMORY_AFFINITY:     u8 = 1;
+const ENTRY_TYPE_X2APIC_AFFINITY:     u8 = 2;
+
+/// The flag bit that indicates an SRAT entry is enabled, which is the same for all entry types.
+const SRAT_ENTRY_ENABLED: u32 = 1 << 0;
+
+
+/// The set of possible SRAT Entries.
+#[derive(Copy, Clone, Debug)]
+pub enum SratEntry<'t> {
+    /// A Processor Local APIC/SAPIC Affinity SRAT entry.
+    LocalApicAffinity(&'t SratLocalApicAffinity),
+    /// A Memory Affinity SRAT entry.
+    MemoryAffinity(&'t SratMemoryAffinity),
+    /// A Processor Local x2APIC Affinity SRAT entry.
+    X2ApicAffinity(&'t SratX2ApicAffinity),
+    /// The SRAT table had an entry of an unknown type or mismatched length,
+    /// so the table entry was malformed and unusable.
+    /// The entry type ID is included.
+    UnknownOrCorrupt(u8)
+}
+
+/// SRAT Processor Local APIC/SAPIC Affinity
+#[derive(Copy, Clone, Debug, FromBytes)]
+#[repr(packed)]
+pub struct SratLocalApicAffinity {
+    header: EntryRecord,
+    /// Bits 0-7 of the proximity domain
+    proximity_domain_low: u8,
+    /// Local APIC ID
+    pub apic_id: u8,
+    /// Flags. Bit 0 means that the entry is enabled
+    pub flags: u32,
+    /// Local SAPIC EID
+    pub local_sapic_eid: u8,
+    /// Bits 8-31 of the proximity domain
+    proximity_domain_high: [u8; 3],
+    /// Clock domain
+    pub clock_domain: u32,
+}
+impl SratLocalApicAffinity {
+    /// Returns the full proximity domain (NUMA node ID) of this core.
+    pub fn proximity_domain(&self) -> u32 {
+        (self.proximity_domain_low as u32)
+            | (self.proximity_domain_high[0] as u32) << 8
+            | (self.proximity_domain_high[1] as u32) << 16
+            | (self.proximity_domain_high[2] as u32) << 24
+    }
+
+    /// Returns whether this entry is enabled. Disabled entries should be ignored.
+    pub fn is_enabled(&self) -> bool {
+        self.flags & SRAT_ENTRY_ENABLED != 0
+    }
+}
+
+/// SRAT Memory Affinity
+#[derive(Copy, Clone, Debug, FromBytes)]
+#[repr(packed)]
+pub struct SratMemoryAffinity {
+    header: EntryRecord,
+    /// Proximity domain (NUMA node ID)
+    pub proximity_domain: u32,
+    _reserved1: u16,
+    base_address_low: u32,
+    base_address_high: u32,
+    length_low: u32,
+    length_high: u32,
+    _reserved2: u32,
+    /// Flags. Bit 0 means enabled, bit 1 means hot-pluggable, bit 2 means non-volatile
+    pub flags: u32,
+    _reserved3: u64,
+}
+impl SratMemoryAffinity {
+    /// Returns the starting physical address of this memory range.
+    pub fn base_address(&self) -> u64 {
+        (self.base_address_high as u64) << 32 | self.base_address_low as u64
+    }
+
+    /// Returns the length in bytes of this memory range.
+    pub fn length(&self) -> u64 {
+        (self.length_high as u64) << 32 | self.length_low as u64
+    }
+
+    /// Returns whether this entry is enabled. Disabled entries should be ignored.
+    pub fn is_enabled(&self) -> bool {
+        self.flags & SRAT_ENTRY_ENABLED != 0
+    }
+}
+
+/// SRAT Processor Local x2APIC Affinity
+#[derive(Copy, Clone, Debug, FromBytes)]
+#[repr(packed)]
+pub struct SratX2ApicAffinity {
+    header: EntryRecord,
+    _reserved1: u16,
+    /// Proximity domain (NUMA node ID)
+    pub proximity_domain: u32,
+    /// x2APIC ID
+    pub x2apic_id: u32,
+    /// Flags. Bit 0 means that the entry is enabled
+    pub flags: u32,
+    /// Clock domain
+    pub clock_domain: u32,
+    _reserved2: u32,
+}
+impl SratX2ApicAffinity {
+    /// Returns whether this entry is enabled. Disabled entries should be ignored.
+    pub fn is_enabled(&self) -> bool {
+        self.flags & SRAT_ENTRY_ENABLED != 0
+    }
+}
@@ -111,6 +111,13 @@ impl Stack {
         self.pages.start_address()
     }
 
+    /// Returns the unmapped guard page(s) beneath the bottom of this stack.
+    ///
+    /// An access within the guard page is almost certainly caused by this stack overflowing.
+    pub fn guard_page(&self) -> &AllocatedPages {
+        &self.guard_page
+    }
+
     /// Creates a stack from its constituent parts: 
     /// a guard page and a series of mapped pages. 
     /// 
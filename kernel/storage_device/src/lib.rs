@@ -157,9 +157,22 @@ pub trait StorageDevice: Downcast {
             last_block_offset,
         })
     }
+
+    /// Reads this device's SMART / Health Information log page (NVMe log identifier `02h`),
+    /// which reports its temperature, wear, and media errors.
+    ///
+    /// NVMe drivers should override this; the default implementation returns an error,
+    /// since other storage devices don't have this log page.
+    fn nvme_health_log(&mut self) -> Result<[u8; NVME_HEALTH_LOG_SIZE], &'static str> {
+        Err("device is not an NVMe device")
+    }
 }
 impl_downcast!(StorageDevice);
 
+/// The size in bytes of the NVMe SMART / Health Information log page,
+/// see [`StorageDevice::nvme_health_log()`](trait.StorageDevice.html#method.nvme_health_log).
+pub const NVME_HEALTH_LOG_SIZE: usize = 512;
+
 /// A trait object wrapped in an Arc and Mutex that allows 
 /// arbitrary storage devices to be shared in a thread-safe manner.
 pub type StorageDeviceRef = Arc<Mutex<dyn StorageDevice + Send>>;
@@ -0,0 +1,391 @@
+//! Health monitoring for storage devices based on S.M.A.R.T.
+//! (Self-Monitoring, Analysis, and Reporting Technology) data.
+//!
+//! The health of every storage device in `storage_manager::STORAGE_CONTROLLERS` can be queried
+//! via [`check_all_devices()`], which returns a [`HealthReport`] containing the device's
+//! temperature, reallocated sector count, and wear level (for SSDs), among other things.
+//! The most recent report for each device is cached and can be obtained via [`latest_reports()`].
+//!
+//! Each report is compared against the system-wide [`HealthThresholds`],
+//! and a [`HealthAlert`] is published when a device newly breaches one of them.
+//! Like the `power_events` bus, each subscriber receives its own copy of every alert
+//! through the queue returned by [`subscribe()`].
+//! For long-running deployments, [`spawn_health_monitor()`] starts a task that periodically checks all devices.
+//!
+//! ATA drives are queried through their S.M.A.R.T. attributes, and NVMe devices through their
+//! SMART / Health Information log page, see `StorageDevice::nvme_health_log()`.
+//! Devices that support neither are skipped.
+//!
+//! [`check_all_devices()`]: fn.check_all_devices.html
+//! [`HealthReport`]: struct.HealthReport.html
+//! [`latest_reports()`]: fn.latest_reports.html
+//! [`HealthThresholds`]: struct.HealthThresholds.html
+//! [`HealthAlert`]: struct.HealthAlert.html
+//! [`subscribe()`]: fn.subscribe.html
+//! [`spawn_health_monitor()`]: fn.spawn_health_monitor.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate mpmc;
+extern crate ata;
+extern crate storage_manager;
+extern crate tsc;
+extern crate spawn;
+extern crate sleep;
+
+use core::fmt;
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use spin::Mutex;
+use mpmc::Queue;
+use ata::{AtaDrive, SmartAttributes};
+use storage_manager::{StorageDeviceRef, STORAGE_CONTROLLERS, NVME_HEALTH_LOG_SIZE};
+
+
+/// Common S.M.A.R.T. attribute IDs that are used consistently across most vendors.
+const ATTR_REALLOCATED_SECTORS: u8 = 5;
+const ATTR_POWER_ON_HOURS:      u8 = 9;
+const ATTR_WEAR_LEVELING_COUNT: u8 = 177;
+const ATTR_AIRFLOW_TEMPERATURE: u8 = 190;
+const ATTR_TEMPERATURE:         u8 = 194;
+const ATTR_SSD_LIFE_LEFT:       u8 = 231;
+const ATTR_MEDIA_WEAROUT:       u8 = 233;
+
+/// Byte offsets of the fields in the NVMe SMART / Health Information log page.
+const NVME_LOG_CRITICAL_WARNING: usize = 0;
+const NVME_LOG_TEMPERATURE:      usize = 1;
+const NVME_LOG_PERCENTAGE_USED:  usize = 5;
+const NVME_LOG_POWER_ON_HOURS:   usize = 128;
+const NVME_LOG_MEDIA_ERRORS:     usize = 160;
+/// NVMe reports temperatures in kelvin.
+const KELVIN_AT_ZERO_CELSIUS: u16 = 273;
+
+
+/// Identifies a storage device by its position in `storage_manager::STORAGE_CONTROLLERS`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId {
+    /// The index of the device's storage controller.
+    pub controller: usize,
+    /// The index of the device within its storage controller.
+    pub device: usize,
+}
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "c{}d{}", self.controller, self.device)
+    }
+}
+
+
+/// A summary of the health of a single storage device.
+#[derive(Clone, Debug)]
+pub struct HealthReport {
+    /// The device that this report describes.
+    pub device: DeviceId,
+    /// The device's model name.
+    pub model: String,
+    /// The device's own overall assessment of its health:
+    /// `false` means that the device has predicted its own imminent failure.
+    pub passed: bool,
+    /// The device's current temperature in degrees Celsius, if reported.
+    pub temperature_celsius: Option<u8>,
+    /// The number of sectors that the device has remapped due to errors, if reported.
+    /// For NVMe devices, this is the number of unrecovered media and data integrity errors.
+    pub reallocated_sectors: Option<u64>,
+    /// The percentage of the device's rated endurance that has been used (SSDs only), if reported.
+    pub wear_percent_used: Option<u8>,
+    /// The total number of hours that the device has been powered on, if reported.
+    pub power_on_hours: Option<u64>,
+    /// All raw S.M.A.R.T. attributes reported by the device, which is empty for NVMe devices.
+    pub attributes: SmartAttributes,
+}
+
+
+/// The limits beyond which a device's health is considered to be degraded.
+#[derive(Copy, Clone, Debug)]
+pub struct HealthThresholds {
+    /// An alert is raised when a device's temperature is above this value.
+    pub max_temperature_celsius: u8,
+    /// An alert is raised when a device has reallocated more sectors than this.
+    pub max_reallocated_sectors: u64,
+    /// An alert is raised when a device has used more than this percentage of its rated endurance.
+    pub max_wear_percent_used: u8,
+}
+impl Default for HealthThresholds {
+    fn default() -> HealthThresholds {
+        DEFAULT_THRESHOLDS
+    }
+}
+
+const DEFAULT_THRESHOLDS: HealthThresholds = HealthThresholds {
+    max_temperature_celsius: 60,
+    max_reallocated_sectors: 0,
+    max_wear_percent_used: 90,
+};
+
+
+/// The kind of health threshold that a device has breached.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AlertKind {
+    /// The device reported that its overall health assessment failed.
+    PredictedFailure,
+    /// The device exceeded `HealthThresholds::max_temperature_celsius`.
+    Temperature,
+    /// The device exceeded `HealthThresholds::max_reallocated_sectors`.
+    ReallocatedSectors,
+    /// The device exceeded `HealthThresholds::max_wear_percent_used`.
+    Wear,
+}
+
+/// An alert that is raised when a device newly breaches one of the `HealthThresholds`.
+#[derive(Clone, Debug)]
+pub struct HealthAlert {
+    /// The device that breached the threshold.
+    pub device: DeviceId,
+    /// Which threshold was breached.
+    pub kind: AlertKind,
+    /// The device's value that breached the threshold, e.g., its temperature.
+    /// This is always `0` for `AlertKind::PredictedFailure`.
+    pub value: u64,
+}
+
+
+
+/// The mutable state of the health monitor.
+struct HealthState {
+    thresholds: HealthThresholds,
+    /// The most recent report for each device, sorted by `DeviceId`.
+    latest_reports: Vec<HealthReport>,
+    /// The alerts that each device currently has, such that each alert is only raised once per breach.
+    active_alerts: Vec<(DeviceId, AlertKind)>,
+}
+
+static HEALTH_STATE: Mutex<HealthState> = Mutex::new(HealthState {
+    thresholds: DEFAULT_THRESHOLDS,
+    latest_reports: Vec::new(),
+    active_alerts: Vec::new(),
+});
+
+/// A subscriber's queue of alerts, along with the name it subscribed under.
+struct Subscriber {
+    name: &'static str,
+    queue: Queue<HealthAlert>,
+}
+
+/// All alert subscribers, in the order they subscribed.
+static SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(Vec::new());
+
+
+/// Sets the thresholds that health reports are checked against.
+pub fn set_thresholds(thresholds: HealthThresholds) {
+    HEALTH_STATE.lock().thresholds = thresholds;
+}
+
+/// Returns the thresholds that health reports are currently checked against.
+pub fn thresholds() -> HealthThresholds {
+    HEALTH_STATE.lock().thresholds
+}
+
+/// Subscribes to all `HealthAlert`s that are raised from now on.
+///
+/// Returns the queue that the alerts will be pushed onto, which holds at least `capacity` alerts.
+/// The `name` identifies this subscriber; subscribing again with the same `name` replaces its queue.
+pub fn subscribe(name: &'static str, capacity: usize) -> Queue<HealthAlert> {
+    let queue = Queue::with_capacity(capacity);
+    let mut subscribers = SUBSCRIBERS.lock();
+    subscribers.retain(|s| s.name != name);
+    subscribers.push(Subscriber { name, queue: queue.clone() });
+    queue
+}
+
+/// Removes the subscriber with the given `name`, such that no more alerts are pushed onto its queue.
+pub fn unsubscribe(name: &'static str) {
+    SUBSCRIBERS.lock().retain(|s| s.name != name);
+}
+
+/// Publishes the given `alert` to all subscribers, dropping it for those whose queue is full.
+fn publish(alert: &HealthAlert) {
+    for subscriber in SUBSCRIBERS.lock().iter() {
+        if subscriber.queue.push(alert.clone()).is_err() {
+            warn!("storage_health: dropped {:?} because subscriber {:?}'s queue is full", alert, subscriber.name);
+        }
+    }
+}
+
+/// Returns the most recent health report for each device that has been checked,
+/// sorted by `DeviceId`.
+pub fn latest_reports() -> Vec<HealthReport> {
+    HEALTH_STATE.lock().latest_reports.clone()
+}
+
+
+/// Queries the health of the given storage device.
+///
+/// Returns an error if the device is neither an ATA drive that supports S.M.A.R.T. nor an NVMe device,
+/// or if querying it failed.
+pub fn query_health(device_id: DeviceId, device: &StorageDeviceRef) -> Result<HealthReport, &'static str> {
+    let mut locked_device = device.lock();
+    let ata_drive: &mut AtaDrive = match locked_device.as_any_mut().downcast_mut() {
+        Some(ata_drive) => ata_drive,
+        None => {
+            let log = locked_device.nvme_health_log()?;
+            return Ok(parse_nvme_health_log(device_id, &log));
+        }
+    };
+    ata_drive.smart_enable()?;
+    let passed = ata_drive.smart_status()?;
+    let attributes = ata_drive.smart_attributes()?;
+    let model = format_model(ata_drive);
+
+    // The temperature is usually in the lowest byte of the raw value.
+    let temperature_celsius = attributes.get(ATTR_TEMPERATURE)
+        .or_else(|| attributes.get(ATTR_AIRFLOW_TEMPERATURE))
+        .map(|a| a.raw as u8);
+    let reallocated_sectors = attributes.get(ATTR_REALLOCATED_SECTORS).map(|a| a.raw);
+    // Wear indicators count down from a normalized value of 100 (brand new).
+    let wear_percent_used = attributes.get(ATTR_MEDIA_WEAROUT)
+        .or_else(|| attributes.get(ATTR_SSD_LIFE_LEFT))
+        .or_else(|| attributes.get(ATTR_WEAR_LEVELING_COUNT))
+        .map(|a| 100u8.saturating_sub(a.current));
+    // Some vendors use the upper bytes of the raw value for minutes or seconds.
+    let power_on_hours = attributes.get(ATTR_POWER_ON_HOURS).map(|a| a.raw & 0xFFFF_FFFF);
+
+    Ok(HealthReport {
+        device: device_id,
+        model,
+        passed,
+        temperature_celsius,
+        reallocated_sectors,
+        wear_percent_used,
+        power_on_hours,
+        attributes,
+    })
+}
+
+/// Builds a health report from the given NVMe SMART / Health Information log page.
+fn parse_nvme_health_log(device_id: DeviceId, log: &[u8; NVME_HEALTH_LOG_SIZE]) -> HealthReport {
+    let read_u64 = |offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&log[offset .. offset + 8]);
+        // These fields are 128 bits wide, of which only the lower 64 bits are used in practice.
+        u64::from_le_bytes(bytes)
+    };
+    let kelvin = u16::from_le_bytes([log[NVME_LOG_TEMPERATURE], log[NVME_LOG_TEMPERATURE + 1]]);
+    let temperature_celsius = if kelvin == 0 {
+        None
+    } else {
+        Some(core::cmp::min(kelvin.saturating_sub(KELVIN_AT_ZERO_CELSIUS), core::u8::MAX as u16) as u8)
+    };
+
+    HealthReport {
+        device: device_id,
+        model: String::from("NVMe device"),
+        // Any critical warning bit means that the device considers its health degraded.
+        passed: log[NVME_LOG_CRITICAL_WARNING] == 0,
+        temperature_celsius,
+        reallocated_sectors: Some(read_u64(NVME_LOG_MEDIA_ERRORS)),
+        // The percentage used may exceed 100 once the rated endurance has been exceeded.
+        wear_percent_used: Some(log[NVME_LOG_PERCENTAGE_USED]),
+        power_on_hours: Some(read_u64(NVME_LOG_POWER_ON_HOURS)),
+        attributes: SmartAttributes(Vec::new()),
+    }
+}
+
+fn format_model(ata_drive: &AtaDrive) -> String {
+    use core::fmt::Write;
+    let model_number = ata_drive.identify_data().model_number;
+    let mut model = String::new();
+    let _ = write!(model, "{}", model_number);
+    String::from(model.trim())
+}
+
+
+/// Checks the health of every storage device that supports S.M.A.R.T.,
+/// caching the results (see [`latest_reports()`](fn.latest_reports.html))
+/// and raising alerts for any newly-breached thresholds.
+///
+/// Returns the new health reports.
+/// Devices that failed to report their health are logged and omitted.
+pub fn check_all_devices() -> Vec<HealthReport> {
+    let mut devices: Vec<(DeviceId, StorageDeviceRef)> = Vec::new();
+    for (controller_index, controller) in STORAGE_CONTROLLERS.lock().iter().enumerate() {
+        for (device_index, device) in controller.lock().devices().enumerate() {
+            devices.push((DeviceId { controller: controller_index, device: device_index }, device));
+        }
+    }
+
+    let mut reports = Vec::with_capacity(devices.len());
+    for (device_id, device) in devices {
+        match query_health(device_id, &device) {
+            Ok(report) => reports.push(report),
+            Err(e) => debug!("storage_health: skipping device {}: {}", device_id, e),
+        }
+    }
+
+    let alerts = {
+        let mut state = HEALTH_STATE.lock();
+        let thresholds = state.thresholds;
+        let mut alerts = Vec::new();
+        let mut active_alerts = Vec::new();
+        for report in &reports {
+            for (kind, value) in breached_thresholds(report, &thresholds) {
+                if !state.active_alerts.contains(&(report.device, kind)) {
+                    alerts.push(HealthAlert { device: report.device, kind, value });
+                }
+                active_alerts.push((report.device, kind));
+            }
+        }
+        state.active_alerts = active_alerts;
+        state.latest_reports = reports.clone();
+        alerts
+    };
+
+    for alert in &alerts {
+        warn!("storage_health: device {} breached its {:?} threshold (value: {})", alert.device, alert.kind, alert.value);
+        publish(alert);
+    }
+    reports
+}
+
+/// Returns the kind and value of each of the given `thresholds` that the given `report` breaches.
+fn breached_thresholds(report: &HealthReport, thresholds: &HealthThresholds) -> Vec<(AlertKind, u64)> {
+    let mut breached = Vec::new();
+    if !report.passed {
+        breached.push((AlertKind::PredictedFailure, 0));
+    }
+    if let Some(temp) = report.temperature_celsius.filter(|&t| t > thresholds.max_temperature_celsius) {
+        breached.push((AlertKind::Temperature, temp as u64));
+    }
+    if let Some(sectors) = report.reallocated_sectors.filter(|&s| s > thresholds.max_reallocated_sectors) {
+        breached.push((AlertKind::ReallocatedSectors, sectors));
+    }
+    if let Some(wear) = report.wear_percent_used.filter(|&w| w > thresholds.max_wear_percent_used) {
+        breached.push((AlertKind::Wear, wear as u64));
+    }
+    breached
+}
+
+
+/// Spawns a task that checks the health of all storage devices every `interval_secs` seconds, forever.
+pub fn spawn_health_monitor(interval_secs: u64) -> Result<(), &'static str> {
+    if interval_secs == 0 {
+        return Err("the health monitor interval must be nonzero");
+    }
+    let interval_ticks = interval_secs.saturating_mul(tsc::get_tsc_frequency()?);
+    spawn::new_task_builder(health_monitor_loop, interval_ticks)
+        .name(String::from("storage_health_monitor"))
+        .spawn()?;
+    Ok(())
+}
+
+fn health_monitor_loop(interval_ticks: u64) -> Result<(), &'static str> {
+    loop {
+        let start: u64 = tsc::tsc_ticks().into();
+        check_all_devices();
+        sleep::sleep_until(start.saturating_add(interval_ticks))?;
+    }
+}
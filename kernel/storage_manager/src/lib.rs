@@ -11,12 +11,13 @@ extern crate owning_ref;
 extern crate pci;
 extern crate ata;
 extern crate storage_device;
+extern crate shutdown_manager;
 
 use alloc::{
     vec::Vec,
     sync::Arc,
 };
-use spin::Mutex;
+use spin::{Mutex, Once};
 use pci::PciDevice;
 use storage_device::StorageControllerRef;
 
@@ -28,6 +29,45 @@ lazy_static! {
     pub static ref STORAGE_CONTROLLERS: Mutex<Vec<StorageControllerRef>> = Mutex::new(Vec::new());
 }
 
+/// The name under which the storage controllers are registered with the `shutdown_manager`,
+/// which subsystems that use storage devices should list as a dependency.
+pub const SHUTDOWN_NAME: &str = "storage";
+
+/// How long the storage controllers may take to finish their in-flight transfers upon shutdown.
+const SHUTDOWN_TIMEOUT_MS: u64 = 2000;
+
+/// Whether the storage controllers have been registered with the `shutdown_manager`.
+static SHUTDOWN_REGISTERED: Once<()> = Once::new();
+
+
+/// Adds the given `controller` to the list of [`STORAGE_CONTROLLERS`](struct.STORAGE_CONTROLLERS.html).
+///
+/// Adding the first controller also registers the storage controllers with the `shutdown_manager`.
+pub fn add_storage_controller(controller: StorageControllerRef) {
+    SHUTDOWN_REGISTERED.call_once(|| {
+        if let Err(e) = shutdown_manager::register(SHUTDOWN_NAME, &[], SHUTDOWN_TIMEOUT_MS, quiesce) {
+            warn!("storage_manager: couldn't register for shutdown: {}", e);
+        }
+    });
+    STORAGE_CONTROLLERS.lock().push(controller);
+}
+
+/// Waits for the in-flight transfers of all storage devices to complete, which is invoked upon shutdown.
+///
+/// A device's transfers are issued while holding its lock, so acquiring the lock of every device suffices.
+/// Drivers such as `ata` flush the drive's write cache as part of every write.
+fn quiesce() -> Result<(), &'static str> {
+    let mut num_devices = 0;
+    for controller in STORAGE_CONTROLLERS.lock().iter() {
+        for device in controller.lock().devices() {
+            let _locked_device = device.lock();
+            num_devices += 1;
+        }
+    }
+    info!("storage_manager: quiesced {} storage devices", num_devices);
+    Ok(())
+}
+
 
 /// Attempts to handle the initialization of the given `PciDevice`,
 /// if it is a recognized storage device.
@@ -40,7 +80,7 @@ pub fn init_device(pci_device: &PciDevice) -> Result<bool, &'static str> {
     if pci_device.class == 0x01 && pci_device.subclass == 0x01 {
         info!("IDE controller PCI device found at: {:?}", pci_device.location);
         let ide_controller = ata::IdeController::new(pci_device)?;
-        STORAGE_CONTROLLERS.lock().push(Arc::new(Mutex::new(ide_controller)));
+        add_storage_controller(Arc::new(Mutex::new(ide_controller)));
         return Ok(true);
     }
 
@@ -0,0 +1,165 @@
+//! Swap space on a storage device, into which the memory subsystem evicts rarely-used pages when memory runs low.
+//!
+//! The swap space is a contiguous range of sectors on a storage device, e.g., a partition of a disk.
+//! A swap file can be used by exposing that file as a storage device via the `loop_device` crate.
+//! The range is divided into page-sized slots, each of which holds the contents of one evicted page;
+//! see the `swap` module of the `memory` crate for how pages are chosen, evicted, and faulted back in.
+//!
+//! Nothing is written to the swap space except the contents of evicted pages, which are only valid until reboot,
+//! so any existing contents of the given sectors are overwritten.
+
+#![no_std]
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate alloc;
+extern crate spin;
+extern crate memory;
+extern crate kernel_config;
+extern crate storage_device;
+
+use alloc::{boxed::Box, vec::Vec};
+use kernel_config::memory::PAGE_SIZE;
+use memory::{SwapBackend, MAX_SWAP_SLOTS};
+use storage_device::StorageDeviceRef;
+
+
+/// Swap space that consists of a range of sectors on a storage device.
+pub struct SwapSpace {
+    device: StorageDeviceRef,
+    start_sector: usize,
+    sectors_per_slot: usize,
+    num_slots: usize,
+    /// A bitmap of which slots are in use.
+    used: Vec<u64>,
+    num_used: usize,
+    /// The slot after the most recently allocated one, from which the search for a free slot starts.
+    next_slot: usize,
+}
+
+impl SwapSpace {
+    /// Creates swap space on the given storage `device`,
+    /// which spans the `num_sectors` sectors starting at `start_sector`.
+    ///
+    /// If `num_sectors` is `None`, the swap space extends to the end of the device.
+    pub fn new(device: StorageDeviceRef, start_sector: usize, num_sectors: Option<usize>) -> Result<SwapSpace, &'static str> {
+        let (sector_size, device_sectors) = {
+            let dev = device.lock();
+            (dev.sector_size_in_bytes(), dev.size_in_sectors())
+        };
+        if sector_size == 0 || PAGE_SIZE % sector_size != 0 {
+            return Err("swap_space: the device's sector size must evenly divide the page size");
+        }
+        let num_sectors = match num_sectors {
+            Some(n) => n,
+            None => device_sectors.saturating_sub(start_sector),
+        };
+        if start_sector.checked_add(num_sectors).map_or(true, |end| end > device_sectors) {
+            return Err("swap_space: the swap space extends beyond the end of the device");
+        }
+        let sectors_per_slot = PAGE_SIZE / sector_size;
+        let num_slots = core::cmp::min(num_sectors / sectors_per_slot, MAX_SWAP_SLOTS);
+        if num_slots == 0 {
+            return Err("swap_space: the swap space is smaller than a page");
+        }
+        Ok(SwapSpace {
+            device,
+            start_sector,
+            sectors_per_slot,
+            num_slots,
+            used: vec![0; (num_slots + 63) / 64],
+            num_used: 0,
+            next_slot: 0,
+        })
+    }
+
+    /// Returns the number of page-sized slots in this swap space.
+    pub fn num_slots(&self) -> usize {
+        self.num_slots
+    }
+
+    /// Returns the number of slots that currently hold an evicted page.
+    pub fn used_slots(&self) -> usize {
+        self.num_used
+    }
+
+    fn is_used(&self, slot: usize) -> bool {
+        self.used[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn set_used(&mut self, slot: usize, used: bool) {
+        if used {
+            self.used[slot / 64] |= 1 << (slot % 64);
+        } else {
+            self.used[slot / 64] &= !(1 << (slot % 64));
+        }
+    }
+
+    /// Finds a free slot, starting at `next_slot` such that consecutive evictions are written sequentially.
+    fn find_free_slot(&self) -> Option<usize> {
+        if self.num_used == self.num_slots {
+            return None;
+        }
+        (self.next_slot .. self.num_slots).chain(0 .. self.next_slot).find(|&slot| !self.is_used(slot))
+    }
+
+    fn sector_of(&self, slot: usize) -> usize {
+        self.start_sector + slot * self.sectors_per_slot
+    }
+}
+
+impl SwapBackend for SwapSpace {
+    fn store(&mut self, contents: &[u8]) -> Result<usize, &'static str> {
+        if contents.len() != PAGE_SIZE {
+            return Err("swap_space: the contents to store must be exactly one page");
+        }
+        let slot = self.find_free_slot().ok_or("swap_space: the swap space is full")?;
+        let written = self.device.lock().write_sectors(contents, self.sector_of(slot))?;
+        if written != self.sectors_per_slot {
+            return Err("swap_space: failed to write an entire page to the device");
+        }
+        self.set_used(slot, true);
+        self.num_used += 1;
+        self.next_slot = (slot + 1) % self.num_slots;
+        Ok(slot)
+    }
+
+    fn load(&mut self, slot: usize, contents: &mut [u8]) -> Result<(), &'static str> {
+        if slot >= self.num_slots || !self.is_used(slot) {
+            return Err("swap_space: the given slot isn't in use");
+        }
+        if contents.len() != PAGE_SIZE {
+            return Err("swap_space: the buffer to load into must be exactly one page");
+        }
+        let read = self.device.lock().read_sectors(contents, self.sector_of(slot))?;
+        if read != self.sectors_per_slot {
+            return Err("swap_space: failed to read an entire page from the device");
+        }
+        Ok(())
+    }
+
+    fn free(&mut self, slot: usize) {
+        if slot >= self.num_slots || !self.is_used(slot) {
+            error!("swap_space: tried to free slot {}, which isn't in use", slot);
+            return;
+        }
+        self.set_used(slot, false);
+        self.num_used -= 1;
+    }
+}
+
+
+/// Enables swapping to swap space on the given storage `device`, see [`SwapSpace::new()`](struct.SwapSpace.html#method.new).
+///
+/// Returns the number of pages that the swap space can hold.
+pub fn enable_swap(device: StorageDeviceRef, start_sector: usize, num_sectors: Option<usize>) -> Result<usize, &'static str> {
+    let swap_space = SwapSpace::new(device, start_sector, num_sectors)?;
+    let num_slots = swap_space.num_slots();
+    memory::set_swap_backend(Box::new(swap_space))?;
+    info!("Enabled swapping to {} pages of swap space", num_slots);
+    Ok(num_slots)
+}
+
+/// Disables swapping, which requires that no pages are currently swapped out.
+pub fn disable_swap() -> Result<(), &'static str> {
+    memory::remove_swap_backend().map(|_backend| info!("Disabled swapping"))
+}
@@ -42,6 +42,8 @@ extern crate root;
 extern crate x86_64;
 extern crate spin;
 extern crate kernel_config;
+extern crate cpu_topology;
+extern crate protection_keys;
 
 
 use core::fmt;
@@ -56,7 +58,7 @@ use alloc::{
     sync::Arc,
 };
 use irq_safety::{MutexIrqSafe, MutexIrqSafeGuardRef, MutexIrqSafeGuardRefMut, interrupts_enabled};
-use memory::{MmiRef, VirtualAddress, get_frame_allocator_ref};
+use memory::{MmiRef, VirtualAddress, get_frame_allocator_ref, PageFaultCounts, PageFaultKind};
 use stack::Stack;
 use kernel_config::memory::KERNEL_STACK_SIZE_IN_PAGES;
 // use tss::tss_set_rsp0;
@@ -67,6 +69,8 @@ use mod_mgmt::{
 use environment::Environment;
 use spin::Mutex;
 use x86_64::registers::msr::{rdmsr, wrmsr, IA32_FS_BASE};
+use cpu_topology::CoreTypeHint;
+use protection_keys::KeyRights;
 
 
 /// The function signature of the callback that will be invoked
@@ -134,6 +138,8 @@ pub enum KillReason {
     /// A non-language-level problem, such as a Page Fault or some other machine exception.
     /// The number of the exception is included, e.g., 15 (0xE) for a Page Fault.
     Exception(u8),
+    /// This `Task` used more CPU time than the hard limit of its [`CpuTimeLimit`](struct.CpuTimeLimit.html).
+    CpuTimeLimitExceeded,
 }
 impl fmt::Display for KillReason {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
@@ -141,6 +147,7 @@ impl fmt::Display for KillReason {
             &Self::Requested         => write!(f, "Requested"),
             &Self::Panic(panic_info) => write!(f, "Panicked at {}", panic_info),
             &Self::Exception(num)    => write!(f, "Exception {:#X}({})", num, num),
+            &Self::CpuTimeLimitExceeded => write!(f, "CPU time limit exceeded"),
         }
     }
 }
@@ -186,6 +193,12 @@ pub enum RunState {
 pub static RUNQUEUE_REMOVAL_FUNCTION: spin::Once<fn(&TaskRef, u8) -> Result<(), &'static str>> = spin::Once::new();
 
 
+/// A callback that is invoked on every context switch between two tasks of which at least one has a `pmu_session`,
+/// before the switch happens. It's given the previous task and the next task, in that order.
+/// Should be initialized by the `pmu_x86` crate.
+pub static PMU_SWITCH_FUNCTION: spin::Once<fn(&mut Task, &mut Task)> = spin::Once::new();
+
+
 #[cfg(simd_personality)]
 /// The supported levels of SIMD extensions that a `Task` can use.
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -207,6 +220,19 @@ pub struct RestartInfo {
     pub func: Box<dyn Any + Send>,
 }
 
+/// A limit on the amount of CPU time that a `Task` may use, see [`TaskRef::charge_cpu_time()`](struct.TaskRef.html#method.charge_cpu_time).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTimeLimit {
+    /// Once the task has used this much CPU time (in microseconds), it is requested to cancel itself,
+    /// see [`TaskRef::is_cancel_requested()`](struct.TaskRef.html#method.is_cancel_requested).
+    pub soft_limit_us: u64,
+    /// Once the task has used this much CPU time (in microseconds), it is killed
+    /// with `KillReason::CpuTimeLimitExceeded`. 
+    /// This should be at least the `soft_limit_us`, to give the task a chance to cancel itself cleanly.
+    pub hard_limit_us: u64,
+}
+
+
 /// The signature of a Task's failure cleanup function.
 pub type FailureCleanupFunction = fn(TaskRef, KillReason) -> !;
 
@@ -254,7 +280,8 @@ pub struct Task {
     /// It will be invoked before the task is cleaned up via stack unwinding.
     /// This is similar to Rust's built-in panic hook, but is also called upon a machine exception, not just a panic.
     pub kill_handler: Option<KillHandler>,
-    /// The environment of the task, Wrapped in an Arc & Mutex because it is shared among child and parent tasks
+    /// The environment of the task, Wrapped in an Arc & Mutex because it can be shared among multiple tasks.
+    /// By default, a new task receives its own copy of its parent task's environment.
     pub env: Arc<Mutex<Environment>>,
     /// The function that should be run as a last-ditch attempt to recover from this task's failure,
     /// e.g., this can be called when unwinding itself fails. 
@@ -264,6 +291,24 @@ pub struct Task {
     /// Stores the restartable information of the task. 
     /// `Some(RestartInfo)` indicates that the task is restartable.
     pub restart_info: Option<RestartInfo>,
+    /// The amount of CPU time (in microseconds) that this task has used so far.
+    pub cpu_time_us: u64,
+    /// The limit on the amount of CPU time that this task may use, if any.
+    pub cpu_time_limit: Option<CpuTimeLimit>,
+    /// The number of page faults of each kind that this task has caused so far.
+    pub page_faults: PageFaultCounts,
+    /// Which type of core this task should preferably be scheduled on, on a CPU with heterogeneous cores.
+    /// This only affects which runqueue the task is added to; it doesn't move a task that's already on a runqueue.
+    pub core_type_hint: CoreTypeHint,
+    /// The access rights of this task to the mappings tagged with each protection key,
+    /// which are saved and restored on every context switch.
+    pub protection_key_rights: KeyRights,
+    /// The state of this task's performance counter session, if it has opened one via `pmu_x86::session`.
+    /// Its counters only count while this task is running, so they're saved and restored on every context switch.
+    pub pmu_session: Option<Box<dyn Any + Send>>,
+    /// Whether this task has been requested to cancel itself, e.g., because it exceeded its soft CPU time limit.
+    /// It is up to the task to check this and exit cleanly.
+    cancel_requested: bool,
     
     #[cfg(simd_personality)]
     /// Whether this Task is SIMD enabled and what level of SIMD extensions it uses.
@@ -298,7 +343,8 @@ impl Task {
         let curr_task = get_my_current_task().ok_or("Task::new(): couldn't get current task (not yet initialized)")?;
         let (mmi, namespace, env, app_crate) = {
             let t = curr_task.lock();
-            (Arc::clone(&t.mmi), Arc::clone(&t.namespace), Arc::clone(&t.env), t.app_crate.clone())
+            let env_snapshot = t.env.lock().clone();
+            (Arc::clone(&t.mmi), Arc::clone(&t.namespace), Arc::new(Mutex::new(env_snapshot)), t.app_crate.clone())
         };
 
         let kstack = kstack
@@ -349,6 +395,13 @@ impl Task {
             env,
             failure_cleanup_function,
             restart_info: None,
+            cpu_time_us: 0,
+            cpu_time_limit: None,
+            page_faults: PageFaultCounts::default(),
+            core_type_hint: CoreTypeHint::default(),
+            protection_key_rights: KeyRights::default(),
+            pmu_session: None,
+            cancel_requested: false,
             
             #[cfg(simd_personality)]
             simd: SimdExt::None,
@@ -534,6 +587,20 @@ impl Task {
             }
         }
        
+        // Each task has its own protection key rights, which it may have changed since it was switched in.
+        if protection_keys::is_enabled() {
+            self.protection_key_rights = protection_keys::current_rights();
+            if next.protection_key_rights != self.protection_key_rights {
+                protection_keys::set_current_rights(next.protection_key_rights);
+            }
+        }
+
+        if self.pmu_session.is_some() || next.pmu_session.is_some() {
+            if let Some(switch_pmu_session) = PMU_SWITCH_FUNCTION.try() {
+                switch_pmu_session(self, next);
+            }
+        }
+
         // update the current task to `next`
         next.set_as_current_task();
 
@@ -683,10 +750,12 @@ impl TaskRef {
     /// to determine the current `Task` on each processor core.
     pub fn new(task: Task) -> TaskRef {
         let task_id = task.id;
+        let app_crate_name = task.app_crate.as_ref().map(|app_crate| app_crate.lock_as_ref().crate_name.clone());
         let taskref = TaskRef(Arc::new((MutexIrqSafe::new(task), AtomicBool::new(false))));
         let tld = TaskLocalData {
             current_taskref: taskref.clone(),
             current_task_id: task_id,
+            current_app_crate_name: app_crate_name,
         };
         let tld_ptr = Box::into_raw(Box::new(tld));
         taskref.0.deref().0.lock().task_local_data_ptr = VirtualAddress::new_canonical(tld_ptr as usize);
@@ -908,6 +977,99 @@ impl TaskRef {
     pub fn is_restartable(&self) -> bool {
         self.0.deref().0.lock().restart_info.is_some()
     }
+
+    /// Returns the amount of CPU time (in microseconds) that this `Task` has used so far.
+    pub fn cpu_time_us(&self) -> u64 {
+        self.0.deref().0.lock().cpu_time_us
+    }
+
+    /// Returns the number of page faults of each kind that this `Task` has caused so far.
+    pub fn page_faults(&self) -> PageFaultCounts {
+        self.0.deref().0.lock().page_faults
+    }
+
+    /// Counts one more page fault of the given `kind` caused by this `Task`.
+    ///
+    /// This is invoked by the page fault handler, which may have interrupted this `Task` while it held its own lock,
+    /// in which case the fault isn't counted rather than deadlocking.
+    pub fn record_page_fault(&self, kind: PageFaultKind) {
+        if let Some(mut task) = self.0.deref().0.try_lock() {
+            task.page_faults.record(kind);
+        }
+    }
+
+    /// Sets or removes the limit on the amount of CPU time that this `Task` may use.
+    /// The limit includes the CPU time that this `Task` has already used.
+    pub fn set_cpu_time_limit(&self, limit: Option<CpuTimeLimit>) {
+        self.0.deref().0.lock().cpu_time_limit = limit;
+    }
+
+    /// Returns the limit on the amount of CPU time that this `Task` may use, if any.
+    pub fn cpu_time_limit(&self) -> Option<CpuTimeLimit> {
+        self.0.deref().0.lock().cpu_time_limit
+    }
+
+    /// Sets the hint about which type of core this `Task` should preferably be scheduled on.
+    /// This takes effect the next time this `Task` is added to a runqueue.
+    pub fn set_core_type_hint(&self, hint: CoreTypeHint) {
+        self.0.deref().0.lock().core_type_hint = hint;
+    }
+
+    /// Returns the hint about which type of core this `Task` should preferably be scheduled on.
+    pub fn core_type_hint(&self) -> CoreTypeHint {
+        self.0.deref().0.lock().core_type_hint
+    }
+
+    /// Requests that this `Task` cancel itself, i.e., stop what it's doing and exit cleanly.
+    /// 
+    /// This is merely advisory: the `Task` must check [`is_cancel_requested()`](#method.is_cancel_requested) itself.
+    pub fn request_cancel(&self) {
+        self.0.deref().0.lock().cancel_requested = true;
+    }
+
+    /// Returns `true` if this `Task` has been requested to cancel itself,
+    /// e.g., because it used more CPU time than the soft limit of its `CpuTimeLimit`.
+    pub fn is_cancel_requested(&self) -> bool {
+        self.0.deref().0.lock().cancel_requested
+    }
+
+    /// Adds the given amount of CPU time (in microseconds) to the CPU time that this `Task` has used,
+    /// and enforces its `CpuTimeLimit`, if any:
+    /// * once the soft limit is exceeded, this `Task` is requested to cancel itself, 
+    ///   see [`request_cancel()`](#method.request_cancel),
+    /// * once the hard limit is exceeded, this `Task` is killed with `KillReason::CpuTimeLimitExceeded`.
+    /// 
+    /// This is typically invoked by the scheduler for the currently-running task. 
+    /// 
+    /// # Return
+    /// * Returns `Ok(true)` if this `Task` was killed because it exceeded its hard limit, 
+    /// * Returns `Ok(false)` if it was not killed,
+    /// * Returns `Err` if it should have been killed but it had already exited.
+    pub fn charge_cpu_time(&self, microseconds: u64) -> Result<bool, &'static str> {
+        let exceeded_hard_limit = {
+            let mut task = self.0.deref().0.lock();
+            task.cpu_time_us = task.cpu_time_us.saturating_add(microseconds);
+            match task.cpu_time_limit {
+                Some(limit) if !task.has_exited() => {
+                    if task.cpu_time_us > limit.soft_limit_us && !task.cancel_requested {
+                        warn!("Task {} exceeded its soft CPU time limit of {} us, requesting that it cancel itself.", &*task, limit.soft_limit_us);
+                        task.cancel_requested = true;
+                    }
+                    let exceeded = task.cpu_time_us > limit.hard_limit_us;
+                    if exceeded {
+                        warn!("Task {} exceeded its hard CPU time limit of {} us, killing it.", &*task, limit.hard_limit_us);
+                    }
+                    exceeded
+                }
+                _ => false,
+            }
+        };
+
+        if exceeded_hard_limit {
+            self.kill(KillReason::CpuTimeLimitExceeded)?;
+        }
+        Ok(exceeded_hard_limit)
+    }
 }
 
 impl PartialEq for TaskRef {
@@ -985,6 +1147,8 @@ fn bootstrap_task_cleanup_failure(current_task: TaskRef, kill_reason: KillReason
 struct TaskLocalData {
     current_taskref: TaskRef,
     current_task_id: usize,
+    /// The name of the task's application crate, which is cached here such that it can be read without locking the task.
+    current_app_crate_name: Option<String>,
 }
 
 /// Returns a reference to the current task's `TaskLocalData` 
@@ -1008,8 +1172,24 @@ pub fn get_my_current_task() -> Option<&'static TaskRef> {
     get_task_local_data().map(|tld| &tld.current_taskref)
 }
 
+/// Returns `true` if the current task has been requested to cancel itself,
+/// e.g., because it used more CPU time than the soft limit of its `CpuTimeLimit`.
+/// 
+/// Long-running tasks should check this periodically and exit cleanly if it returns `true`.
+pub fn is_cancel_requested() -> bool {
+    get_my_current_task().map_or(false, |t| t.is_cancel_requested())
+}
+
 /// Returns the current Task's id by using the `TaskLocalData` pointer
 /// stored in the thread-local storage (FS base model-specific register).
 pub fn get_my_current_task_id() -> Option<usize> {
     get_task_local_data().map(|tld| tld.current_task_id)
 }
+
+/// Returns the name of the current Task's application crate, if it has one,
+/// by using the `TaskLocalData` pointer stored in the thread-local storage (FS base model-specific register).
+/// 
+/// Unlike accessing the Task's `app_crate`, this doesn't lock or allocate anything.
+pub fn get_my_current_app_crate_name() -> Option<&'static str> {
+    get_task_local_data().and_then(|tld| tld.current_app_crate_name.as_ref().map(|name| name.as_str()))
+}
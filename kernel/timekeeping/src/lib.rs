@@ -0,0 +1,110 @@
+//! The wall-clock time, and the timekeeping state that must be saved before and restored after a system suspend.
+//!
+//! The wall-clock time is derived from the monotonic TSC plus an offset that is read from the real-time clock (RTC),
+//! see [`wall_clock_seconds()`].
+//!
+//! During a suspend to RAM (ACPI S3), memory keeps its contents, but the timer hardware loses its state:
+//! the TSC may start counting from zero again, and the local APIC timers and the PIT forget their configuration.
+//! Only the RTC keeps running. Thus, the suspend path must invoke [`save()`] right before entering S3,
+//! and [`restore()`] on the BSP right after waking up, before enabling interrupts or booting the other cores.
+//! This ensures that:
+//! * the TSC continues from where it was before the suspend, advanced by how long the system was suspended,
+//!   such that monotonic time never jumps backwards,
+//! * the scheduler's APIC timer and the PIT are reprogrammed with their original calibration,
+//!   without the slow PIT-based calibration that happens at boot, and
+//! * the wall-clock offset is re-read from the RTC.
+//!
+//! Tasks that wait for a deadline measure it in TSC ticks, so any deadline that passed while the system was suspended
+//! expires right after resuming, rather than being delayed or firing early.
+//!
+//! [`wall_clock_seconds()`]: fn.wall_clock_seconds.html
+//! [`save()`]: fn.save.html
+//! [`restore()`]: fn.restore.html
+
+#![no_std]
+
+#[macro_use] extern crate log;
+extern crate tsc;
+extern crate rtc;
+extern crate pit_clock;
+extern crate apic;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The wall-clock time, in seconds since the start of the year 2000, at which the TSC was zero.
+/// `u64::MAX` means that it hasn't been read from the RTC yet.
+static WALL_CLOCK_OFFSET: AtomicU64 = AtomicU64::new(core::u64::MAX);
+
+
+/// Returns the number of seconds that have passed since the TSC started counting, which is roughly since boot.
+fn monotonic_seconds() -> Result<u64, &'static str> {
+    tsc::tsc_ticks().to_ns().map(|ns| ns / NANOS_PER_SEC).ok_or("timekeeping: the TSC frequency is unknown")
+}
+
+/// Re-reads the wall-clock time from the RTC, which [`wall_clock_seconds()`](fn.wall_clock_seconds.html) is based on.
+pub fn sync_wall_clock() -> Result<(), &'static str> {
+    let rtc_seconds = rtc::read_rtc().seconds_since_2000();
+    let offset = rtc_seconds.saturating_sub(monotonic_seconds()?);
+    WALL_CLOCK_OFFSET.store(offset, Ordering::Release);
+    Ok(())
+}
+
+/// Returns the current wall-clock time, in seconds since the start of the year 2000.
+///
+/// The RTC is read only the first time this is invoked, or by [`sync_wall_clock()`](fn.sync_wall_clock.html),
+/// because reading it is slow; afterwards, the time is advanced using the TSC.
+pub fn wall_clock_seconds() -> Result<u64, &'static str> {
+    if WALL_CLOCK_OFFSET.load(Ordering::Acquire) == core::u64::MAX {
+        sync_wall_clock()?;
+    }
+    Ok(WALL_CLOCK_OFFSET.load(Ordering::Acquire) + monotonic_seconds()?)
+}
+
+
+/// The timekeeping state at the time the system was suspended, which is returned by [`save()`](fn.save.html).
+#[derive(Debug)]
+pub struct TimekeepingState {
+    /// The TSC value right before the suspend.
+    tsc_ticks: u64,
+    /// The RTC time right before the suspend, in seconds since the start of the year 2000.
+    rtc_seconds: u64,
+}
+
+/// Saves the timekeeping state right before the system is suspended.
+///
+/// This should be invoked as late as possible during the suspend path, after all other cores have been stopped.
+pub fn save() -> Result<TimekeepingState, &'static str> {
+    // Ensure the TSC frequency has been calibrated, since the PIT can't be used to calibrate it while resuming.
+    tsc::get_tsc_frequency()?;
+    let state = TimekeepingState {
+        rtc_seconds: rtc::read_rtc().seconds_since_2000(),
+        tsc_ticks: tsc::tsc_ticks().into(),
+    };
+    debug!("timekeeping: saved {:?} before suspending", state);
+    Ok(state)
+}
+
+/// Restores the timekeeping state that was saved by [`save()`](fn.save.html) right before the system was suspended.
+///
+/// This must be invoked on the BSP right after the system resumes, before interrupts are enabled.
+/// Returns the number of seconds that the system was suspended for, according to the RTC.
+pub fn restore(state: &TimekeepingState) -> Result<u64, &'static str> {
+    // The RTC only has a resolution of seconds, and may have been adjusted backwards while suspended.
+    let slept_seconds = rtc::read_rtc().seconds_since_2000().saturating_sub(state.rtc_seconds);
+    tsc::advance_after_resume(state.tsc_ticks, slept_seconds.saturating_mul(NANOS_PER_SEC))?;
+
+    // The PIT is only initialized when using the legacy PIC, so it's not an error if it wasn't.
+    if let Err(e) = pit_clock::restore_after_resume() {
+        debug!("timekeeping: not restoring the PIT: {}", e);
+    }
+    let bsp_id = apic::get_bsp_id().ok_or("timekeeping: the BSP's APIC ID is unknown")?;
+    let bsp_apic = apic::get_lapics().get(&bsp_id).ok_or("timekeeping: the BSP's local APIC wasn't found")?;
+    bsp_apic.write().restore_after_resume()?;
+
+    sync_wall_clock()?;
+    info!("timekeeping: restored the timers after being suspended for {} seconds", slept_seconds);
+    Ok(slept_seconds)
+}
@@ -1,35 +1,95 @@
-//! Support for broadcasting and handling TLB shootdown IPIs. 
+//! Support for broadcasting and handling TLB shootdown IPIs.
+//!
+//! When a core changes or removes a mapping, other cores may still have the old mapping cached in their TLBs.
+//! The memory subsystem thus invokes the callback registered by [`init()`] after every such change,
+//! which makes all other cores invalidate the affected pages before it returns.
+//!
+//! Each core has its own queue of pending invalidations.
+//! A shootdown appends the pages to every other core's queue and then sends each of them an IPI,
+//! which it handles by invalidating everything in its queue at once.
+//! Thus, shootdowns that are sent by multiple cores at the same time are batched into a single round of invalidations.
+//! If a core's queue overflows, or would invalidate more than `MAX_PAGES_TO_INVALIDATE` pages,
+//! that core flushes its entire TLB instead, which is cheaper than invalidating that many pages one by one.
+//!
+//! The IPIs are sent as NMIs, such that they're handled even by cores that currently have interrupts disabled,
+//! e.g., a core that is itself waiting for its own shootdown to complete.
+//! Since NMIs can't be masked, the NMI handler never waits for a queue's lock: if another core is currently
+//! appending to this core's queue, e.g., while this core was itself appending to that core's queue,
+//! the handler flushes its entire TLB instead, which completes every invalidation that was enqueued so far.
+//!
+//! [`init()`]: fn.init.html
 
 #![no_std]
 
 // #[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
 extern crate irq_safety;
 extern crate memory;
 extern crate apic;
-extern crate x86_64;
 extern crate pause;
+extern crate atomic_linked_list;
 
 
-use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
-use irq_safety::{hold_interrupts, RwLockIrqSafe};
-use memory::PageRange;
-use apic::{LocalApic, get_my_apic, core_count, LapicIpiDestination};
+use core::sync::atomic::{AtomicU64, Ordering};
+use irq_safety::{hold_interrupts, MutexIrqSafe};
+use memory::{Page, PageRange};
+use apic::{get_my_apic, get_my_apic_id, LapicIpiDestination};
 use pause::spin_loop_hint;
+use atomic_linked_list::atomic_map::AtomicMap;
 
 
 /// The IRQ number used for IPIs
 pub const TLB_SHOOTDOWN_IPI_IRQ: u8 = 0x40;
-/// The number of remaining cores that still need to handle the curerent TLB shootdown IPI
-pub static TLB_SHOOTDOWN_IPI_COUNT: AtomicUsize = AtomicUsize::new(0);
-/// The lock that makes sure only one set of TLB shootdown IPIs is concurrently happening
-pub static TLB_SHOOTDOWN_IPI_LOCK: AtomicBool = AtomicBool::new(false);
-/// The range of pages for a TLB shootdown IPI.
-pub static TLB_SHOOTDOWN_IPI_PAGES: RwLockIrqSafe<Option<PageRange>> = RwLockIrqSafe::new(None);
+/// The maximum number of invalidations that can be pending in each core's queue.
+const QUEUE_CAPACITY: usize = 8;
+/// The maximum number of pages that a core invalidates one by one; beyond that, it flushes its entire TLB.
+const MAX_PAGES_TO_INVALIDATE: usize = 32;
+
+
+/// The invalidations that a core has yet to perform.
+struct InvalidationQueue {
+    /// The first and last page of each range of pages to invalidate.
+    ranges: [Option<(Page, Page)>; QUEUE_CAPACITY],
+    len: usize,
+    /// Whether the entire TLB must be flushed, which covers all of the above `ranges`.
+    flush_all: bool,
+    /// The number of the most recently enqueued invalidation.
+    last_ticket: u64,
+}
+
+/// The TLB shootdown state of one core.
+struct CoreState {
+    queue: MutexIrqSafe<InvalidationQueue>,
+    /// The number of the last invalidation that was enqueued onto this core's queue,
+    /// which is the queue's `last_ticket` but can be read without its lock.
+    enqueued_ticket: AtomicU64,
+    /// The number of the last invalidation that this core has completed.
+    /// Invalidations are completed in order, so every one numbered less than this has been completed too.
+    completed_ticket: AtomicU64,
+}
+
+lazy_static! {
+    /// The shootdown state of every core that has been initialized via `init()`, keyed by APIC ID.
+    /// This is lock-free, such that it can be accessed from the NMI handler.
+    static ref CORE_STATES: AtomicMap<u8, CoreState> = AtomicMap::new();
+}
 
 
-/// Initializes data, functions, and structures for the TLB shootdown. 
-/// TODO: redesign this, it's weird and silly just to set one callback.
+/// Initializes TLB shootdowns on the current core, which must be invoked once on every core
+/// before it runs anything that accesses memory mapped by another core.
+/// Only cores that have been initialized receive shootdowns.
 pub fn init() {
+    let state = CoreState {
+        queue: MutexIrqSafe::new(InvalidationQueue {
+            ranges: [None; QUEUE_CAPACITY],
+            len: 0,
+            flush_all: false,
+            last_ticket: 0,
+        }),
+        enqueued_ticket: AtomicU64::new(0),
+        completed_ticket: AtomicU64::new(0),
+    };
+    CORE_STATES.insert(get_my_apic_id(), state);
     memory::set_broadcast_tlb_shootdown_cb(broadcast_tlb_shootdown);
 }
 
@@ -38,64 +98,112 @@ pub fn init() {
 /// Do not invoke this directly, but rather pass it as a callback to the memory subsystem,
 /// which will invoke it as needed (on remap/unmap operations).
 fn broadcast_tlb_shootdown(pages_to_invalidate: PageRange) {
-    if let Some(my_lapic) = get_my_apic() {
-        // info!("broadcast_tlb_shootdown():  AP {}, vaddrs: {:?}", my_lapic.read().apic_id, virtual_addresses);
-        send_tlb_shootdown_ipi(&mut my_lapic.write(), pages_to_invalidate);
-    }
+    send_tlb_shootdown_ipi(pages_to_invalidate);
 }
 
 
-/// Handles a TLB shootdown ipi by flushing the `VirtualAddress`es 
-/// covered by the given range of `pages_to_invalidate`.
-/// 
-/// There is no need to invoke this directly, it will be called by an IPI interrupt handler.
-pub fn handle_tlb_shootdown_ipi(pages_to_invalidate: PageRange) {
-    // trace!("handle_tlb_shootdown_ipi(): AP {}, pages: {:?}", apic::get_my_apic_id(), pages_to_invalidate);
-
-    for page in pages_to_invalidate {
-        x86_64::instructions::tlb::flush(x86_64::VirtualAddress(page.start_address().value()));
+/// Handles a TLB shootdown IPI by invalidating all pages in the current core's queue.
+/// Returns `false` if there were no pending invalidations, meaning that the NMI wasn't sent for a TLB shootdown.
+///
+/// There is no need to invoke this directly, it will be called by the NMI handler.
+pub fn handle_tlb_shootdown_ipi() -> bool {
+    let state = match CORE_STATES.get(&get_my_apic_id()) {
+        Some(state) => state,
+        None => return false,
+    };
+    // Another core may be holding this queue's lock while waiting for something that this core holds,
+    // e.g., the lock on that core's own queue, so this NMI handler must not wait for the lock.
+    let mut queue = match state.queue.try_lock() {
+        Some(queue) => queue,
+        None => {
+            // Every invalidation that was enqueued before this point had already changed its page table entries,
+            // so flushing the entire TLB completes them all. Their ranges are invalidated again once they're dequeued.
+            let enqueued = state.enqueued_ticket.load(Ordering::Acquire);
+            if enqueued <= state.completed_ticket.load(Ordering::Acquire) {
+                return false;
+            }
+            memory::tlb_flush_all();
+            state.completed_ticket.store(enqueued, Ordering::Release);
+            return true;
+        }
+    };
+    if queue.len == 0 && !queue.flush_all {
+        return false;
+    }
+    // trace!("handle_tlb_shootdown_ipi(): AP {}, {} ranges, flush_all: {}", get_my_apic_id(), queue.len, queue.flush_all);
+
+    if queue.flush_all {
+        memory::tlb_flush_all();
+    } else {
+        for &(start, end) in queue.ranges[..queue.len].iter().flatten() {
+            for page in PageRange::new(start, end) {
+                memory::tlb_flush_virt_addr(page.start_address());
+            }
+        }
     }
-    TLB_SHOOTDOWN_IPI_COUNT.fetch_sub(1, Ordering::SeqCst);
+    queue.len = 0;
+    queue.flush_all = false;
+    state.completed_ticket.store(queue.last_ticket, Ordering::Release);
+    true
 }
 
 
-/// Sends an IPI to all other cores (except me) to trigger 
-/// a TLB flush of the given pages' virtual addresses.
-pub fn send_tlb_shootdown_ipi(my_lapic: &mut LocalApic, pages_to_invalidate: PageRange) {        
+/// Sends an IPI to all other cores (except me) to trigger
+/// a TLB flush of the given pages' virtual addresses,
+/// and waits until they have all done so.
+pub fn send_tlb_shootdown_ipi(pages_to_invalidate: PageRange) {
+    let my_apic_id = get_my_apic_id();
     // skip sending IPIs if there are no other cores running
-    let core_count = core_count();
-    if core_count <= 1 {
+    if CORE_STATES.iter().all(|(&apic_id, _)| apic_id == my_apic_id) {
         return;
     }
 
-    // trace!("send_tlb_shootdown_ipi(): from AP {}, core_count: {}, {:?}", my_lapic.apic_id, core_count, pages_to_invalidate);
-
-    // interrupts must be disabled here, because this IPI sequence must be fully synchronous with other cores,
-    // and we wouldn't want this core to be interrupted while coordinating IPI responses across multiple cores.
-    let _held_ints = hold_interrupts(); 
-
-    // acquire lock
-    // TODO: add timeout!!
-    while TLB_SHOOTDOWN_IPI_LOCK.compare_and_swap(false, true, Ordering::SeqCst) { 
-        spin_loop_hint();
+    // trace!("send_tlb_shootdown_ipi(): from AP {}, {:?}", my_apic_id, pages_to_invalidate);
+
+    // interrupts must be disabled here, such that this core isn't descheduled while other cores are waiting to
+    // handle the IPI or while it waits for them, which would delay them and whoever relies on this shootdown.
+    // Shootdowns from other cores still reach this core while it's waiting, as they are NMIs.
+    let _held_ints = hold_interrupts();
+
+    let num_pages = pages_to_invalidate.size_in_pages();
+    let (start, end) = (*pages_to_invalidate.start(), *pages_to_invalidate.end());
+    // The ticket of this invalidation in each core's queue, indexed by APIC ID.
+    // This can't be heap-allocated, since the heap itself may be unmapping pages.
+    let mut tickets = [0u64; 256];
+
+    // Enqueue the invalidation onto every other core's queue, and send each of them an IPI.
+    for (&apic_id, state) in CORE_STATES.iter().filter(|&(&apic_id, _)| apic_id != my_apic_id) {
+        let ticket = {
+            let mut queue = state.queue.lock();
+            let pending_pages: usize = queue.ranges[..queue.len].iter().flatten()
+                .map(|&(s, e)| PageRange::new(s, e).size_in_pages())
+                .sum();
+            if queue.flush_all || queue.len == QUEUE_CAPACITY || pending_pages + num_pages > MAX_PAGES_TO_INVALIDATE {
+                queue.flush_all = true;
+            } else {
+                let index = queue.len;
+                queue.ranges[index] = Some((start, end));
+                queue.len += 1;
+            }
+            queue.last_ticket += 1;
+            state.enqueued_ticket.store(queue.last_ticket, Ordering::Release);
+            queue.last_ticket
+        };
+
+        // An NMI is used since it will interrupt everyone forcibly and result in the fastest handling.
+        // If the target core is still handling an earlier NMI, this one is delivered right after it, so it won't be missed.
+        if let Some(my_lapic) = get_my_apic() {
+            my_lapic.write().send_nmi_ipi(LapicIpiDestination::One(apic_id));
+        }
+        tickets[apic_id as usize] = ticket;
     }
 
-    *TLB_SHOOTDOWN_IPI_PAGES.write() = Some(pages_to_invalidate);
-    TLB_SHOOTDOWN_IPI_COUNT.store(core_count - 1, Ordering::SeqCst); // -1 to exclude this core 
-
-    // let's try to use NMI instead, since it will interrupt everyone forcibly and result in the fastest handling
-    my_lapic.send_nmi_ipi(LapicIpiDestination::AllButMe); // send IPI to all other cores but this one
-
-    // wait for all other cores to handle this IPI
+    // Then, wait for all of them to handle this invalidation.
     // it must be a blocking, synchronous operation to ensure stale TLB entries don't cause problems
     // TODO: add timeout!!
-    while TLB_SHOOTDOWN_IPI_COUNT.load(Ordering::SeqCst) > 0 { 
-        spin_loop_hint();
+    for (&apic_id, state) in CORE_STATES.iter().filter(|&(&apic_id, _)| apic_id != my_apic_id) {
+        while state.completed_ticket.load(Ordering::Acquire) < tickets[apic_id as usize] {
+            spin_loop_hint();
+        }
     }
-
-    // clear TLB shootdown data
-    *TLB_SHOOTDOWN_IPI_PAGES.write() = None;
-
-    // release lock
-    TLB_SHOOTDOWN_IPI_LOCK.store(false, Ordering::SeqCst); 
 }
@@ -0,0 +1,296 @@
+//! A minimal driver for a TPM 2.0 that uses the FIFO (TIS) interface,
+//! which can unseal secrets that were sealed to the TPM, e.g., the key of an encrypted storage device.
+//!
+//! The TPM is accessed through locality 0 of its memory-mapped FIFO interface at the standard address
+//! [`TPM_FIFO_BASE`], as defined by the TCG PC Client Platform TPM Profile (PTP) specification.
+//! The TPM is detected and mapped the first time it is used.
+//! Commands are sent one at a time, and the driver waits for each response by polling
+//! the TPM's status register, sleeping in between polls.
+//!
+//! Only password authorization is supported, i.e., a sealed object must have been created
+//! with an authorization value (which may be empty) rather than an authorization policy.
+//! For example, a 64-byte key in `key.bin` can be sealed with the `tpm2-tools` as follows:
+//! ```sh
+//! tpm2_createprimary -C o -c primary.ctx
+//! tpm2_create -C primary.ctx -i key.bin -p <password> -u key.pub -r key.priv
+//! tpm2_load -C primary.ctx -u key.pub -r key.priv -c key.ctx
+//! tpm2_evictcontrol -C o -c key.ctx 0x81010001
+//! ```
+//! after which [`unseal(0x81010001, b"<password>")`](fn.unseal.html) returns the key.
+//!
+//! [`TPM_FIFO_BASE`]: constant.TPM_FIFO_BASE.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate memory;
+#[macro_use] extern crate mmio_registers;
+extern crate sleep;
+
+use alloc::vec::Vec;
+use core::ops::DerefMut;
+use spin::{Mutex, Once};
+use memory::{allocate_pages, get_frame_allocator_ref, get_kernel_mmi_ref, EntryFlags, FrameRange, PhysicalAddress};
+use mmio_registers::{MmioRegion, ReadOnly, ReadWrite};
+
+
+/// The physical address of locality 0 of the TPM's FIFO interface.
+pub const TPM_FIFO_BASE: usize = 0xFED4_0000;
+
+/// The largest command or response that this driver sends or accepts, in bytes.
+const MAX_MESSAGE_SIZE: usize = 4096;
+/// The size of the header of every command and response: a tag, a size, and a command or response code.
+const HEADER_SIZE: usize = 10;
+
+// Timeouts from the PTP specification, in milliseconds.
+const TIMEOUT_A_MS: u64 = 750;
+const TIMEOUT_B_MS: u64 = 2000;
+/// How long to wait for the TPM to execute a command.
+const COMMAND_DURATION_MS: u64 = 5000;
+/// How long to sleep between two polls of the TPM's registers.
+const POLL_INTERVAL_MS: u64 = 1;
+
+// Bits of the access register.
+const ACCESS_VALID: u8 = 1 << 7;
+const ACCESS_ACTIVE_LOCALITY: u8 = 1 << 5;
+const ACCESS_REQUEST_USE: u8 = 1 << 1;
+// Bits of the status register.
+const STATUS_VALID: u8 = 1 << 7;
+const STATUS_COMMAND_READY: u8 = 1 << 6;
+const STATUS_GO: u8 = 1 << 5;
+const STATUS_DATA_AVAILABLE: u8 = 1 << 4;
+const STATUS_EXPECT: u8 = 1 << 3;
+
+// Values from the TPM 2.0 Library specification, Part 2: Structures.
+const TPM_ST_SESSIONS: u16 = 0x8002;
+const TPM_CC_UNSEAL: u32 = 0x0000_015E;
+const TPM_RS_PW: u32 = 0x4000_0009;
+const TPM_RC_SUCCESS: u32 = 0;
+/// The largest authorization value of an object, which is the size of a SHA-512 digest.
+const MAX_AUTH_VALUE_SIZE: usize = 64;
+
+
+register_structs! {
+    /// The registers of one locality of the TPM's FIFO interface, see the PTP specification.
+    pub FifoRegisters {
+        /// The access register, used to request and release the locality.
+        (0x000 => pub access: ReadWrite<u8>),
+        (0x001 => _reserved0),
+        /// The status register, which also starts the execution of a command.
+        (0x018 => pub status: ReadWrite<u8>),
+        /// The low and high bytes of the burst count, i.e., how many bytes can be written to or read from the FIFO
+        /// without waiting.
+        (0x019 => pub burst_count_low: ReadOnly<u8>),
+        (0x01A => pub burst_count_high: ReadOnly<u8>),
+        (0x01B => _reserved1),
+        /// The FIFO through which commands are written and responses are read.
+        (0x024 => pub data_fifo: ReadWrite<u8>),
+        (0x025 => _reserved2),
+        /// The TPM's device ID (upper 16 bits) and vendor ID (lower 16 bits).
+        (0xF00 => pub did_vid: ReadOnly<u32>),
+        (0xF04 => _reserved3),
+        (0x1000 => @END),
+    }
+}
+
+/// The TPM, once it has been detected.
+static TPM: Once<Mutex<Tpm>> = Once::new();
+
+
+/// Unseals the sealed data object with the given persistent `object_handle`,
+/// using the given `auth_value` (password) to authorize it.
+///
+/// Returns the unsealed secret, which the caller should zero out once it's no longer needed.
+/// Returns an error if there is no TPM, or if the TPM refuses to unseal the object,
+/// e.g., because the handle or the `auth_value` is wrong.
+pub fn unseal(object_handle: u32, auth_value: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if auth_value.len() > MAX_AUTH_VALUE_SIZE {
+        return Err("tpm: the authorization value is too long");
+    }
+    let tpm = get_tpm()?;
+
+    // TPM2_Unseal with a single password authorization session.
+    let auth_area_size = 4 + 2 + 1 + 2 + auth_value.len();
+    let command_size = HEADER_SIZE + 4 + 4 + auth_area_size;
+    let mut command = Vec::with_capacity(command_size);
+    command.extend_from_slice(&TPM_ST_SESSIONS.to_be_bytes());
+    command.extend_from_slice(&(command_size as u32).to_be_bytes());
+    command.extend_from_slice(&TPM_CC_UNSEAL.to_be_bytes());
+    command.extend_from_slice(&object_handle.to_be_bytes());
+    command.extend_from_slice(&(auth_area_size as u32).to_be_bytes());
+    command.extend_from_slice(&TPM_RS_PW.to_be_bytes());
+    command.extend_from_slice(&0u16.to_be_bytes()); // an empty nonce
+    command.push(0); // no session attributes
+    command.extend_from_slice(&(auth_value.len() as u16).to_be_bytes());
+    command.extend_from_slice(auth_value);
+
+    let result = tpm.lock().execute(&command);
+    zero(&mut command);
+    let mut response = result?;
+
+    // The response consists of the header, the size of the response parameters,
+    // the unsealed data as a sized buffer, and then the response of the authorization session.
+    let code = read_u32(&response, 6)?;
+    let secret = if code != TPM_RC_SUCCESS {
+        error!("tpm: TPM2_Unseal of object {:#X} failed with response code {:#X}", object_handle, code);
+        Err("tpm: the TPM refused to unseal the object")
+    } else {
+        let data_size = read_u16(&response, HEADER_SIZE + 4)? as usize;
+        let data_start = HEADER_SIZE + 4 + 2;
+        response.get(data_start .. data_start + data_size)
+            .map(|data| data.to_vec())
+            .ok_or("tpm: the response to TPM2_Unseal was truncated")
+    };
+    zero(&mut response);
+    secret
+}
+
+/// Returns the TPM, detecting and mapping it if this is the first time it is used.
+fn get_tpm() -> Result<&'static Mutex<Tpm>, &'static str> {
+    if let Some(tpm) = TPM.try() {
+        return Ok(tpm);
+    }
+    let tpm = Tpm::probe()?;
+    Ok(TPM.call_once(|| Mutex::new(tpm)))
+}
+
+
+struct Tpm {
+    registers: MmioRegion<FifoRegisters>,
+}
+
+impl Tpm {
+    /// Maps locality 0 of the FIFO interface and checks whether a TPM is present.
+    fn probe() -> Result<Tpm, &'static str> {
+        let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("tpm: KERNEL_MMI was not yet initialized")?;
+        let phys_addr = PhysicalAddress::new(TPM_FIFO_BASE)?;
+        let frames = FrameRange::from_phys_addr(phys_addr, core::mem::size_of::<FifoRegisters>());
+        let pages = allocate_pages(frames.size_in_frames()).ok_or("tpm: couldn't allocate pages for the TPM registers")?;
+        let fa = get_frame_allocator_ref().ok_or("tpm: couldn't get the frame allocator")?;
+        let mapped_pages = kernel_mmi_ref.lock().page_table.map_allocated_pages_to(
+            pages,
+            frames,
+            EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE,
+            fa.lock().deref_mut(),
+        )?;
+        let registers = MmioRegion::<FifoRegisters>::new(mapped_pages, 0)?;
+
+        // Reads from an address that no device responds to return all ones.
+        let did_vid = registers.did_vid.read();
+        if did_vid == 0 || did_vid == 0xFFFF_FFFF || registers.access.read() & ACCESS_VALID == 0 {
+            return Err("tpm: no TPM with a FIFO interface was found");
+        }
+        info!("tpm: found TPM with vendor ID {:#06X}, device ID {:#06X}", did_vid & 0xFFFF, did_vid >> 16);
+        Ok(Tpm { registers })
+    }
+
+    /// Sends the given `command` to the TPM and returns its response.
+    fn execute(&mut self, command: &[u8]) -> Result<Vec<u8>, &'static str> {
+        self.request_locality()?;
+        let response = self.transmit(command);
+        // Return the TPM to its idle state, which also discards any unread response bytes, and release the locality.
+        self.registers.status.write(STATUS_COMMAND_READY);
+        self.registers.access.write(ACCESS_ACTIVE_LOCALITY);
+        response
+    }
+
+    fn request_locality(&self) -> Result<(), &'static str> {
+        let active = ACCESS_VALID | ACCESS_ACTIVE_LOCALITY;
+        self.registers.access.write(ACCESS_REQUEST_USE);
+        self.wait_until(TIMEOUT_A_MS, |regs| regs.access.read() & active == active)
+            .map_err(|_| "tpm: timed out requesting locality 0")
+    }
+
+    fn transmit(&self, command: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if command.len() < HEADER_SIZE || command.len() > MAX_MESSAGE_SIZE {
+            return Err("tpm: invalid command size");
+        }
+        self.registers.status.write(STATUS_COMMAND_READY);
+        self.wait_until(TIMEOUT_B_MS, |regs| regs.status.read() & STATUS_COMMAND_READY != 0)
+            .map_err(|_| "tpm: timed out waiting for the TPM to become ready")?;
+
+        let mut sent = 0;
+        while sent < command.len() {
+            let burst_count = self.burst_count()?;
+            for &byte in command[sent ..].iter().take(burst_count) {
+                self.registers.data_fifo.write(byte);
+            }
+            sent += core::cmp::min(burst_count, command.len() - sent);
+        }
+        self.wait_until(TIMEOUT_A_MS, |regs| regs.status.read() & STATUS_VALID != 0)
+            .map_err(|_| "tpm: timed out waiting for the TPM to receive the command")?;
+        if self.registers.status.read() & STATUS_EXPECT != 0 {
+            return Err("tpm: the TPM expected more bytes of the command");
+        }
+
+        self.registers.status.write(STATUS_GO);
+        let available = STATUS_VALID | STATUS_DATA_AVAILABLE;
+        self.wait_until(COMMAND_DURATION_MS, |regs| regs.status.read() & available == available)
+            .map_err(|_| "tpm: timed out waiting for the TPM's response")?;
+
+        let mut response = Vec::with_capacity(HEADER_SIZE);
+        self.read_fifo(&mut response, HEADER_SIZE)?;
+        let response_size = read_u32(&response, 2)? as usize;
+        if response_size < HEADER_SIZE || response_size > MAX_MESSAGE_SIZE {
+            return Err("tpm: the TPM's response had an invalid size");
+        }
+        self.read_fifo(&mut response, response_size - HEADER_SIZE)?;
+        Ok(response)
+    }
+
+    /// Reads `count` bytes from the FIFO into the given `buffer`.
+    fn read_fifo(&self, buffer: &mut Vec<u8>, count: usize) -> Result<(), &'static str> {
+        let mut remaining = count;
+        while remaining > 0 {
+            let burst_count = core::cmp::min(self.burst_count()?, remaining);
+            for _ in 0 .. burst_count {
+                buffer.push(self.registers.data_fifo.read());
+            }
+            remaining -= burst_count;
+        }
+        Ok(())
+    }
+
+    /// Waits until the TPM can accept or provide at least one byte through the FIFO, and returns how many.
+    fn burst_count(&self) -> Result<usize, &'static str> {
+        let read_burst_count = |regs: &FifoRegisters| regs.burst_count_low.read() as usize | (regs.burst_count_high.read() as usize) << 8;
+        self.wait_until(TIMEOUT_A_MS, |regs| read_burst_count(regs) > 0)
+            .map_err(|_| "tpm: timed out waiting for the TPM's FIFO")?;
+        Ok(read_burst_count(&*self.registers))
+    }
+
+    /// Polls the registers until the given `condition` holds, sleeping in between polls.
+    /// Returns an error if it doesn't hold within `timeout_ms` milliseconds.
+    fn wait_until<F: Fn(&FifoRegisters) -> bool>(&self, timeout_ms: u64, condition: F) -> Result<(), ()> {
+        let mut waited_ms = 0;
+        while !condition(&*self.registers) {
+            if waited_ms >= timeout_ms {
+                return Err(());
+            }
+            sleep::sleep_ms(POLL_INTERVAL_MS).map_err(|_e| error!("tpm: couldn't sleep: {}", _e))?;
+            waited_ms += POLL_INTERVAL_MS;
+        }
+        Ok(())
+    }
+}
+
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, &'static str> {
+    let b = bytes.get(offset .. offset + 2).ok_or("tpm: the TPM's response was truncated")?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, &'static str> {
+    let b = bytes.get(offset .. offset + 4).ok_or("tpm: the TPM's response was truncated")?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Zeroes the given bytes, which may contain secrets, with volatile writes
+/// such that the compiler can't optimize away the zeroing.
+fn zero(bytes: &mut [u8]) {
+    for b in bytes.iter_mut() {
+        unsafe { core::ptr::write_volatile(b, 0); }
+    }
+}
@@ -3,7 +3,7 @@
 #[macro_use] extern crate log;
 extern crate pit_clock;
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 
 #[derive(Debug)]
@@ -40,12 +40,36 @@ impl TscTicks {
 
 
 
+/// The number of ticks that is added to the hardware TSC value,
+/// which keeps the ticks returned by `tsc_ticks()` monotonic even though the TSC is reset by a system suspend.
+static TSC_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 /// Returns the current number of ticks from the TSC, i.e., `rdtscp`. 
+/// 
+/// This never decreases, even across a system suspend, see [`advance_after_resume()`](fn.advance_after_resume.html).
 pub fn tsc_ticks() -> TscTicks {
     let mut val = 0;
     // SAFE: just reading TSC value
     let ticks = unsafe { core::arch::x86_64::__rdtscp(&mut val) };
-    TscTicks(ticks)
+    TscTicks(ticks.wrapping_add(TSC_OFFSET.load(Ordering::Relaxed)))
+}
+
+/// Adjusts the TSC after the system has resumed from a suspend that the hardware TSC may not have kept counting across,
+/// such that `tsc_ticks()` continues from the `last_ticks` it returned before the suspend,
+/// advanced by the `slept_ns` nanoseconds that the system was suspended for.
+/// 
+/// Thus, deadlines that are measured in TSC ticks expire as if the TSC had kept counting throughout the suspend.
+/// This must be invoked before any other core reads the TSC after resuming. 
+pub fn advance_after_resume(last_ticks: u64, slept_ns: u64) -> Result<(), &'static str> {
+    let freq = get_tsc_frequency()?;
+    let slept_ticks = ((slept_ns as u128 * freq as u128) / 1_000_000_000) as u64;
+    let target = last_ticks.saturating_add(slept_ticks);
+    let current = tsc_ticks().0;
+    // If the hardware TSC did keep counting, it's already past the target, so the offset stays as it is.
+    if current < target {
+        TSC_OFFSET.fetch_add(target - current, Ordering::SeqCst);
+    }
+    Ok(())
 }
 
 /// Returns the frequency of the TSC for the system, 
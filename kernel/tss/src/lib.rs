@@ -7,20 +7,63 @@ extern crate memory;
 extern crate x86_64;
 extern crate apic;
 extern crate spin;
+extern crate stack;
+extern crate kernel_config;
 
 use x86_64::structures::tss::TaskStateSegment;
 use atomic_linked_list::atomic_map::AtomicMap;
 use spin::Mutex;
-use memory::VirtualAddress;
+use memory::{VirtualAddress, FrameAllocator, FrameAllocatorRef, Mapper};
+use stack::Stack;
+use kernel_config::memory::KERNEL_STACK_SIZE_IN_PAGES;
 
 
 /// The index of the double fault stack in a TaskStateSegment (TSS)
 pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+/// The index of the non-maskable interrupt (NMI) stack in a TaskStateSegment (TSS)
+pub const NMI_IST_INDEX: usize = 1;
+/// The index of the machine check exception stack in a TaskStateSegment (TSS)
+pub const MACHINE_CHECK_IST_INDEX: usize = 2;
 
 
 lazy_static! {
     /// The TSS list, one per core, indexed by a key of apic_id.
     static ref TSS: AtomicMap<u8, Mutex<TaskStateSegment>> = AtomicMap::new();
+    /// The interrupt stacks referenced by each core's TSS, indexed by a key of apic_id.
+    /// They are kept here such that they live as long as the TSS that points to them.
+    static ref INTERRUPT_STACKS: AtomicMap<u8, InterruptStacks> = AtomicMap::new();
+}
+
+
+/// The dedicated stacks of a single core that the CPU switches to via the Interrupt Stack Table (IST)
+/// when handling a double fault, an NMI, or a machine check exception. 
+/// 
+/// These exceptions can occur at any time, even when the current stack is unusable,
+/// e.g., when it has overflowed into its guard page. 
+/// Handling them on a separate stack allows them to be reported instead of causing a triple fault.
+/// Each stack has its own guard page, so an overflow of one of these stacks causes a double fault
+/// that is handled on the (separate) double fault stack.
+#[derive(Debug)]
+pub struct InterruptStacks {
+    pub double_fault: Stack,
+    pub nmi: Stack,
+    pub machine_check: Stack,
+}
+
+impl InterruptStacks {
+    /// Allocates a new set of interrupt stacks and maps them into the given `page_table`.
+    pub fn new<FA>(page_table: &mut Mapper, frame_allocator_ref: &FrameAllocatorRef<FA>) -> Result<InterruptStacks, &'static str> 
+        where FA: FrameAllocator
+    {
+        Ok(InterruptStacks {
+            double_fault: stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, page_table, frame_allocator_ref)
+                .ok_or("could not allocate double fault stack")?,
+            nmi: stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, page_table, frame_allocator_ref)
+                .ok_or("could not allocate NMI stack")?,
+            machine_check: stack::alloc_stack(KERNEL_STACK_SIZE_IN_PAGES, page_table, frame_allocator_ref)
+                .ok_or("could not allocate machine check stack")?,
+        })
+    }
 }
 
 
@@ -41,19 +84,24 @@ pub fn tss_set_rsp0(new_privilege_stack_top: VirtualAddress) -> Result<(), &'sta
 
 
 /// set up TSS entry for the given AP core. 
+/// The given `interrupt_stacks` are used for the IST entries, and are kept alive as long as the TSS.
 /// Returns a reference to a Mutex wrapping the new TSS entry.
 pub fn create_tss(apic_id: u8, 
-                double_fault_stack_top_unusable: VirtualAddress, 
+                interrupt_stacks: InterruptStacks, 
                 privilege_stack_top_unusable: VirtualAddress) 
                 -> &'static Mutex<TaskStateSegment>
 {
+    let ist_entry = |stack: &Stack| x86_64::VirtualAddress(stack.top_unusable().value());
     let mut tss = TaskStateSegment::new();
     // TSS.RSP0 is used in kernel space after a transition from Ring 3 -> Ring 0
     tss.privilege_stack_table[0] = x86_64::VirtualAddress(privilege_stack_top_unusable.value());
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = x86_64::VirtualAddress(double_fault_stack_top_unusable.value());
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = ist_entry(&interrupt_stacks.double_fault);
+    tss.interrupt_stack_table[NMI_IST_INDEX] = ist_entry(&interrupt_stacks.nmi);
+    tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX] = ist_entry(&interrupt_stacks.machine_check);
 
     // insert into TSS list
     TSS.insert(apic_id, Mutex::new(tss));
+    INTERRUPT_STACKS.insert(apic_id, interrupt_stacks);
     let tss_ref = TSS.get(&apic_id).unwrap(); // safe to unwrap since we just added it to the list
     // debug!("Created TSS for apic {}, TSS: {:?}", apic_id, tss_ref);
     tss_ref
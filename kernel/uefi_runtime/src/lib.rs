@@ -0,0 +1,348 @@
+//! Access to UEFI runtime services, which remain usable after the bootloader has exited UEFI boot services.
+//!
+//! Currently, this supports the runtime services that read and write firmware variables,
+//! which are stored in NVRAM and thus persist across reboots, e.g., boot options (`Boot####` and `BootOrder`),
+//! provisioning keys, or markers that a crash leaves behind for the next boot.
+//! Variables are identified by a name and a vendor [`Guid`]; Theseus's own variables use [`THESEUS_VARIABLE`].
+//!
+//! Theseus never calls `SetVirtualAddressMap()`, so the firmware expects to run at its physical addresses.
+//! Thus, [`init()`] identity-maps every memory region that the UEFI memory map marks as used by runtime services,
+//! and keeps those mappings forever.
+//! Runtime services are not reentrant, so calls into the firmware are serialized and run with interrupts disabled.
+//!
+//! [`Guid`]: struct.Guid.html
+//! [`THESEUS_VARIABLE`]: constant.THESEUS_VARIABLE.html
+//! [`init()`]: fn.init.html
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate irq_safety;
+extern crate memory;
+extern crate memory_x86_64;
+
+use core::{fmt, ops::DerefMut, str::FromStr};
+use alloc::{
+    string::String,
+    vec::Vec,
+};
+use spin::Once;
+use irq_safety::MutexIrqSafe;
+use memory::{EntryFlags, FrameRange, MappedPages, PhysicalAddress, VirtualAddress, get_frame_allocator_ref, get_kernel_mmi_ref};
+use memory_x86_64::{BootInformation, EfiMemoryDescriptor, EfiMemoryType, EFI_MEMORY_RUNTIME, find_efi_memory_map, find_efi_system_table};
+
+
+/// The variable is stored in NVRAM, such that it persists across reboots.
+pub const VARIABLE_NON_VOLATILE: u32 = 0x1;
+/// The variable can be accessed while UEFI boot services are running, e.g., by the bootloader.
+pub const VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+/// The variable can be accessed through runtime services, i.e., by the OS.
+/// Such a variable must also have `VARIABLE_BOOTSERVICE_ACCESS`.
+pub const VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+/// The variable is a hardware error record.
+pub const VARIABLE_HARDWARE_ERROR_RECORD: u32 = 0x8;
+/// The variable can only be written with time-based authentication.
+pub const VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS: u32 = 0x20;
+/// When setting the variable, the given data is appended to its existing value instead of replacing it.
+pub const VARIABLE_APPEND_WRITE: u32 = 0x40;
+/// The attributes of a variable that persists across reboots and can be accessed by the OS and the bootloader.
+pub const VARIABLE_DEFAULT_ATTRIBUTES: u32 = VARIABLE_NON_VOLATILE | VARIABLE_BOOTSERVICE_ACCESS | VARIABLE_RUNTIME_ACCESS;
+
+
+/// A globally-unique identifier, which specifies the vendor of a firmware variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(C)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl Guid {
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Guid {
+        Guid { data1, data2, data3, data4 }
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.data4;
+        write!(f, "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            self.data1, self.data2, self.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+        )
+    }
+}
+
+impl FromStr for Guid {
+    type Err = &'static str;
+
+    /// Parses a GUID in its usual form, e.g., `8be4df61-93ca-11d2-aa0d-00e098032b8c`.
+    fn from_str(s: &str) -> Result<Guid, &'static str> {
+        const ERROR: &'static str = "a GUID must have the form XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX";
+        let groups: Vec<&str> = s.split('-').collect();
+        let lengths = [8, 4, 4, 4, 12];
+        if groups.len() != lengths.len() || groups.iter().zip(lengths.iter()).any(|(g, len)| g.len() != *len) {
+            return Err(ERROR);
+        }
+        let hex = |digits: &str| u64::from_str_radix(digits, 16).map_err(|_| ERROR);
+        let mut data4 = [0u8; 8];
+        let last = [groups[3], groups[4]].concat();
+        for (i, byte) in data4.iter_mut().enumerate() {
+            *byte = hex(&last[i * 2 .. i * 2 + 2])? as u8;
+        }
+        Ok(Guid::new(hex(groups[0])? as u32, hex(groups[1])? as u16, hex(groups[2])? as u16, data4))
+    }
+}
+
+/// The vendor of the variables defined by the UEFI specification, e.g., `BootOrder` and `Boot####`.
+pub const EFI_GLOBAL_VARIABLE: Guid = Guid::new(0x8BE4_DF61, 0x93CA, 0x11D2, [0xAA, 0x0D, 0x00, 0xE0, 0x98, 0x03, 0x2B, 0x8C]);
+/// The vendor of Theseus's own variables.
+pub const THESEUS_VARIABLE: Guid = Guid::new(0x5C6F_1D2E, 0x3B8A, 0x4F47, [0x9E, 0x2D, 0x7A, 0x1C, 0x0B, 0x9E, 0x4D, 0x31]);
+
+
+/// The header at the start of every UEFI table.
+#[allow(dead_code)]
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+/// The beginning of the `EFI_SYSTEM_TABLE`, up to the pointer to the runtime services table.
+#[allow(dead_code)]
+#[repr(C)]
+struct SystemTable {
+    header: TableHeader,
+    firmware_vendor: u64,
+    firmware_revision: u32,
+    console_in_handle: u64,
+    con_in: u64,
+    console_out_handle: u64,
+    con_out: u64,
+    standard_error_handle: u64,
+    std_err: u64,
+    runtime_services: *const RuntimeServicesTable,
+}
+
+type GetVariableFn = unsafe extern "win64" fn(*const u16, *const Guid, *mut u32, *mut usize, *mut u8) -> usize;
+type GetNextVariableNameFn = unsafe extern "win64" fn(*mut usize, *mut u16, *mut Guid) -> usize;
+type SetVariableFn = unsafe extern "win64" fn(*const u16, *const Guid, u32, usize, *const u8) -> usize;
+
+/// The beginning of the `EFI_RUNTIME_SERVICES` table, up to the variable services.
+/// UEFI functions use the Microsoft x64 calling convention, i.e., `win64`.
+#[allow(dead_code)]
+#[repr(C)]
+struct RuntimeServicesTable {
+    header: TableHeader,
+    get_time: usize,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable: GetVariableFn,
+    get_next_variable_name: GetNextVariableNameFn,
+    set_variable: SetVariableFn,
+}
+
+const EFI_SYSTEM_TABLE_SIGNATURE: u64 = 0x5453_5953_2049_4249; // "IBI SYST"
+const EFI_RUNTIME_SERVICES_SIGNATURE: u64 = 0x5652_4553_544E_5552; // "RUNTSERV"
+
+
+/// The runtime services of the firmware, and the identity mappings of the memory that they use.
+struct Firmware {
+    get_variable: GetVariableFn,
+    get_next_variable_name: GetNextVariableNameFn,
+    set_variable: SetVariableFn,
+    _mappings: Vec<MappedPages>,
+}
+
+/// The lock serializes all calls into the firmware, which also disables interrupts during each call.
+static FIRMWARE: Once<MutexIrqSafe<Firmware>> = Once::new();
+
+
+/// Identity-maps the memory used by UEFI runtime services and finds the runtime services table,
+/// based on the EFI system table and memory map that the bootloader passed along in the given `boot_info`.
+///
+/// Returns an error if Theseus wasn't booted from UEFI firmware, in which case runtime services are unavailable.
+pub fn init(boot_info: &BootInformation) -> Result<(), &'static str> {
+    if FIRMWARE.try().is_some() {
+        return Err("UEFI runtime services were already initialized");
+    }
+    let system_table_paddr = find_efi_system_table(boot_info).ok_or("not booted from UEFI: there is no EFI system table")?;
+    let memory_map = find_efi_memory_map(boot_info).ok_or("not booted from UEFI: there is no EFI memory map")?;
+    let kernel_mmi_ref = get_kernel_mmi_ref().ok_or("uefi_runtime::init(): couldn't get kernel_mmi_ref")?;
+    let allocator = get_frame_allocator_ref().ok_or("uefi_runtime::init(): couldn't get the frame allocator")?;
+
+    let mut mappings = Vec::new();
+    let mut system_table_mapped = false;
+    for desc in memory_map.descriptors().filter(|desc| desc.attribute & EFI_MEMORY_RUNTIME != 0) {
+        let paddr = PhysicalAddress::new(desc.physical_start as usize)
+            .map_err(|_| "UEFI runtime services region had an invalid physical address")?;
+        let size_in_bytes = desc.number_of_pages as usize * EfiMemoryDescriptor::EFI_PAGE_SIZE;
+        let flags = match EfiMemoryType::from_u32(desc.typ) {
+            Some(EfiMemoryType::RuntimeServicesCode) => EntryFlags::PRESENT | EntryFlags::WRITABLE,
+            Some(EfiMemoryType::MemoryMappedIo) | Some(EfiMemoryType::MemoryMappedIoPortSpace) =>
+                EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE,
+            _ => EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+        };
+        let vaddr = VirtualAddress::new(paddr.value())
+            .map_err(|_| "UEFI runtime services region cannot be identity mapped")?;
+        let pages = memory::allocate_pages_by_bytes_at(vaddr, size_in_bytes)
+            .map_err(|_| "couldn't allocate pages to identity map a UEFI runtime services region")?;
+        let mapped_pages = kernel_mmi_ref.lock().page_table.map_allocated_pages_to(
+            pages,
+            FrameRange::from_phys_addr(paddr, size_in_bytes),
+            flags,
+            allocator.lock().deref_mut(),
+        )?;
+        debug!("Identity mapped UEFI runtime services region {:#X} - {:#X}, type {:?}", paddr, paddr + size_in_bytes, EfiMemoryType::from_u32(desc.typ));
+        if system_table_paddr >= paddr && system_table_paddr < paddr + size_in_bytes {
+            system_table_mapped = true;
+        }
+        mappings.push(mapped_pages);
+    }
+    if !system_table_mapped {
+        return Err("the EFI system table isn't in a UEFI runtime services region");
+    }
+
+    // SAFE: the system table and the runtime services table are in runtime services data, which is now identity mapped.
+    let system_table = unsafe { &*(system_table_paddr.value() as *const SystemTable) };
+    if system_table.header.signature != EFI_SYSTEM_TABLE_SIGNATURE {
+        return Err("the EFI system table had an invalid signature");
+    }
+    let runtime_services = unsafe { system_table.runtime_services.as_ref() }
+        .ok_or("the EFI system table had no runtime services table")?;
+    if runtime_services.header.signature != EFI_RUNTIME_SERVICES_SIGNATURE {
+        return Err("the EFI runtime services table had an invalid signature");
+    }
+    info!("UEFI runtime services revision {}.{} at {:#X}",
+        runtime_services.header.revision >> 16, runtime_services.header.revision & 0xFFFF, runtime_services as *const _ as usize
+    );
+
+    FIRMWARE.call_once(|| MutexIrqSafe::new(Firmware {
+        get_variable: runtime_services.get_variable,
+        get_next_variable_name: runtime_services.get_next_variable_name,
+        set_variable: runtime_services.set_variable,
+        _mappings: mappings,
+    }));
+    Ok(())
+}
+
+
+/// Returns whether UEFI runtime services are available, i.e., whether `init()` succeeded.
+pub fn is_available() -> bool {
+    FIRMWARE.try().is_some()
+}
+
+fn firmware() -> Result<&'static MutexIrqSafe<Firmware>, &'static str> {
+    FIRMWARE.try().ok_or("UEFI runtime services are unavailable")
+}
+
+
+/// Returns the value and attributes of the firmware variable with the given `name` and `vendor`.
+pub fn get_variable(name: &str, vendor: &Guid) -> Result<(Vec<u8>, u32), &'static str> {
+    let name = to_ucs2(name);
+    let firmware = firmware()?.lock();
+    let mut data: Vec<u8> = vec_with_len(256);
+    loop {
+        let mut attributes = 0u32;
+        let mut size = data.len();
+        let status = unsafe { (firmware.get_variable)(name.as_ptr(), vendor, &mut attributes, &mut size, data.as_mut_ptr()) };
+        match status {
+            EFI_BUFFER_TOO_SMALL => data = vec_with_len(size),
+            _ => {
+                status_to_result(status)?;
+                data.truncate(size);
+                return Ok((data, attributes));
+            }
+        }
+    }
+}
+
+/// Sets the firmware variable with the given `name` and `vendor` to the given `data`,
+/// creating it with the given `attributes` if it doesn't yet exist.
+///
+/// Variables that should persist across reboots typically use `VARIABLE_DEFAULT_ATTRIBUTES`.
+/// Setting a variable to empty `data` deletes it, unless `attributes` contains `VARIABLE_APPEND_WRITE`.
+pub fn set_variable(name: &str, vendor: &Guid, attributes: u32, data: &[u8]) -> Result<(), &'static str> {
+    let name = to_ucs2(name);
+    let firmware = firmware()?.lock();
+    let status = unsafe { (firmware.set_variable)(name.as_ptr(), vendor, attributes, data.len(), data.as_ptr()) };
+    status_to_result(status)
+}
+
+/// Deletes the firmware variable with the given `name` and `vendor`.
+pub fn delete_variable(name: &str, vendor: &Guid) -> Result<(), &'static str> {
+    let (_data, attributes) = get_variable(name, vendor)?;
+    set_variable(name, vendor, attributes & !VARIABLE_APPEND_WRITE, &[])
+}
+
+/// Returns the name and vendor of every firmware variable that can be accessed through runtime services.
+pub fn variable_names() -> Result<Vec<(String, Guid)>, &'static str> {
+    let firmware = firmware()?.lock();
+    let mut names = Vec::new();
+    // Each call returns the variable after the one whose name and vendor are passed in, starting from an empty name.
+    let mut name: Vec<u16> = vec_with_len(128);
+    let mut vendor = Guid::new(0, 0, 0, [0; 8]);
+    loop {
+        let mut size_in_bytes = name.len() * 2;
+        let status = unsafe { (firmware.get_next_variable_name)(&mut size_in_bytes, name.as_mut_ptr(), &mut vendor) };
+        match status {
+            // The previous name must be kept in the larger buffer.
+            EFI_BUFFER_TOO_SMALL => name.resize((size_in_bytes + 1) / 2, 0),
+            EFI_NOT_FOUND => return Ok(names),
+            _ => {
+                status_to_result(status)?;
+                let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                names.push((String::from_utf16_lossy(&name[.. len]), vendor));
+            }
+        }
+    }
+}
+
+
+/// Converts the given string into a null-terminated UCS-2 string, as UEFI expects.
+fn to_ucs2(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(core::iter::once(0)).collect()
+}
+
+/// Returns a vector of `len` zeroed elements.
+fn vec_with_len<T: Copy + Default>(len: usize) -> Vec<T> {
+    let mut v = Vec::with_capacity(len);
+    v.resize(len, T::default());
+    v
+}
+
+
+/// The high bit of an `EFI_STATUS` is set for errors, but not for warnings.
+const EFI_ERROR: usize = 1 << 63;
+const EFI_BUFFER_TOO_SMALL: usize = EFI_ERROR | 5;
+const EFI_NOT_FOUND: usize = EFI_ERROR | 14;
+
+/// Converts the given `EFI_STATUS` into a result, ignoring warnings.
+fn status_to_result(status: usize) -> Result<(), &'static str> {
+    if status & EFI_ERROR == 0 {
+        return Ok(());
+    }
+    Err(match status & !EFI_ERROR {
+        2  => "UEFI: invalid parameter",
+        3  => "UEFI: unsupported",
+        5  => "UEFI: buffer too small",
+        6  => "UEFI: not ready",
+        7  => "UEFI: device error",
+        8  => "UEFI: write protected",
+        9  => "UEFI: out of resources",
+        14 => "UEFI: variable not found",
+        15 => "UEFI: access denied",
+        26 => "UEFI: security violation",
+        _  => "UEFI: unknown error",
+    })
+}
+
@@ -0,0 +1,181 @@
+//! Support for virtio devices, which hypervisors like QEMU/KVM provide as paravirtualized hardware.
+//!
+//! Only the legacy PCI transport (virtio 0.9.5) is supported, in which a device is configured
+//! through the I/O ports in its first BAR. Transitional devices, which are the default in QEMU,
+//! support this transport as well as the modern one.
+//! Each device has a number of [`Virtqueue`]s, through which the driver submits requests to the device.
+//!
+//! A driver for a specific device type, e.g., `virtio_balloon`, uses this crate as follows:
+//! 1. [`LegacyPciTransport::new()`] resets the device and acknowledges it,
+//! 2. [`negotiate_features()`] agrees on the device features that the driver supports,
+//! 3. [`setup_queue()`] allocates each virtqueue and gives it to the device,
+//! 4. [`driver_ok()`] tells the device that the driver is ready.
+//!
+//! [`Virtqueue`]: virtqueue/struct.Virtqueue.html
+//! [`LegacyPciTransport::new()`]: struct.LegacyPciTransport.html#method.new
+//! [`negotiate_features()`]: struct.LegacyPciTransport.html#method.negotiate_features
+//! [`setup_queue()`]: struct.LegacyPciTransport.html#method.setup_queue
+//! [`driver_ok()`]: struct.LegacyPciTransport.html#method.driver_ok
+
+#![no_std]
+
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate log;
+extern crate kernel_config;
+extern crate memory;
+extern crate pci;
+extern crate port_io;
+extern crate port_registry;
+
+pub mod virtqueue;
+
+use pci::PciDevice;
+use port_io::Port;
+use port_registry::{claim_ports, PortClaim};
+use virtqueue::Virtqueue;
+
+
+/// The PCI vendor ID of all virtio devices.
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+// The bits of the device status register.
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FAILED: u8 = 0x80;
+
+// The offsets of the legacy registers within the device's I/O port range.
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13;
+/// The offset of the device-specific configuration, when MSI-X is disabled.
+const REG_DEVICE_CONFIG: u16 = 0x14;
+
+/// The legacy transport requires virtqueues to be aligned to and addressed in units of 4 KiB.
+const QUEUE_PFN_SHIFT: usize = 12;
+
+
+/// A virtio device that is accessed through the legacy PCI transport.
+pub struct LegacyPciTransport {
+    io_base: u16,
+    /// The device-specific configuration's size in bytes.
+    config_size: u16,
+    _claim: PortClaim,
+}
+
+impl LegacyPciTransport {
+    /// Resets the given virtio PCI device and acknowledges that a driver was found for it,
+    /// claiming its I/O ports on behalf of the given `owner`.
+    ///
+    /// `config_size` is the size in bytes of the device type's configuration.
+    pub fn new(dev: &PciDevice, config_size: u16, owner: &'static str) -> Result<LegacyPciTransport, &'static str> {
+        if dev.vendor_id != VIRTIO_VENDOR_ID {
+            return Err("virtio: not a virtio device");
+        }
+        // A legacy virtio device's registers are in the I/O space given by BAR0.
+        let bar0 = dev.bars[0];
+        if bar0 & 0x1 == 0 {
+            return Err("virtio: BAR0 is not an I/O space BAR, only the legacy PCI transport is supported");
+        }
+        let io_base = (bar0 & !0x3) as u16;
+        let claim = claim_ports(io_base, REG_DEVICE_CONFIG + config_size, owner)?;
+        dev.pci_set_command_bus_master_bit();
+
+        let transport = LegacyPciTransport { io_base, config_size, _claim: claim };
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        debug!("virtio: found {} device at {:?} with I/O ports at {:#X}", owner, dev.location, io_base);
+        Ok(transport)
+    }
+
+    fn read_u8(&self, offset: u16) -> u8 { Port::<u8>::new(self.io_base + offset).read() }
+    fn read_u16(&self, offset: u16) -> u16 { Port::<u16>::new(self.io_base + offset).read() }
+    fn read_u32(&self, offset: u16) -> u32 { Port::<u32>::new(self.io_base + offset).read() }
+    fn write_u8(&self, offset: u16, value: u8) { unsafe { Port::<u8>::new(self.io_base + offset).write(value) } }
+    fn write_u16(&self, offset: u16, value: u16) { unsafe { Port::<u16>::new(self.io_base + offset).write(value) } }
+    fn write_u32(&self, offset: u16, value: u32) { unsafe { Port::<u32>::new(self.io_base + offset).write(value) } }
+
+    /// Returns the device status register.
+    pub fn status(&self) -> u8 {
+        self.read_u8(REG_DEVICE_STATUS)
+    }
+
+    /// Sets the device status register. Writing `0` resets the device.
+    pub fn set_status(&self, status: u8) {
+        self.write_u8(REG_DEVICE_STATUS, status)
+    }
+
+    /// Accepts the features that are both offered by the device and in the given `supported` features,
+    /// and returns the accepted features.
+    pub fn negotiate_features(&self, supported: u32) -> u32 {
+        let features = self.read_u32(REG_DEVICE_FEATURES) & supported;
+        self.write_u32(REG_GUEST_FEATURES, features);
+        features
+    }
+
+    /// Allocates the device's virtqueue at the given `index` and gives it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, &'static str> {
+        self.write_u16(REG_QUEUE_SELECT, index);
+        if self.read_u32(REG_QUEUE_PFN) != 0 {
+            return Err("virtio: virtqueue is already in use");
+        }
+        let size = self.read_u16(REG_QUEUE_SIZE);
+        if size == 0 {
+            return Err("virtio: virtqueue doesn't exist");
+        }
+        let queue = Virtqueue::new(index, size)?;
+        // The queue is page-aligned, so this is exact.
+        let pfn = queue.phys_addr().value() >> QUEUE_PFN_SHIFT;
+        if pfn > core::u32::MAX as usize {
+            return Err("virtio: virtqueue can't be addressed by the legacy transport");
+        }
+        self.write_u32(REG_QUEUE_PFN, pfn as u32);
+        Ok(queue)
+    }
+
+    /// Tells the device that the driver is ready to use it.
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device that the driver couldn't set it up.
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    /// Tells the device that new requests were added to the given `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        self.write_u16(REG_QUEUE_NOTIFY, queue.index());
+    }
+
+    /// Reads the ISR status register, which also acknowledges the device's interrupt.
+    /// Bit 0 means a virtqueue was used, and bit 1 means the device configuration changed.
+    pub fn read_isr(&self) -> u8 {
+        self.read_u8(REG_ISR_STATUS)
+    }
+
+    /// Reads the little-endian `u32` at the given `offset` in the device-specific configuration.
+    pub fn read_config_u32(&self, offset: u16) -> Result<u32, &'static str> {
+        self.check_config_offset(offset, 4)?;
+        Ok(u32::from_le(self.read_u32(REG_DEVICE_CONFIG + offset)))
+    }
+
+    /// Writes the given `value` as a little-endian `u32` at the given `offset` in the device-specific configuration.
+    pub fn write_config_u32(&self, offset: u16, value: u32) -> Result<(), &'static str> {
+        self.check_config_offset(offset, 4)?;
+        self.write_u32(REG_DEVICE_CONFIG + offset, value.to_le());
+        Ok(())
+    }
+
+    fn check_config_offset(&self, offset: u16, size: u16) -> Result<(), &'static str> {
+        if offset % size != 0 || offset + size > self.config_size {
+            return Err("virtio: invalid device configuration offset");
+        }
+        Ok(())
+    }
+}
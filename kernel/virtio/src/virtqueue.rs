@@ -0,0 +1,213 @@
+//! A split virtqueue in the legacy (virtio 0.9.5) memory layout.
+//!
+//! The descriptor table, the available ring, and the used ring are in one physically-contiguous region,
+//! with the used ring starting at the next page boundary after the available ring.
+
+use core::{mem::size_of, ptr, sync::atomic::{fence, Ordering}};
+use alloc::vec::Vec;
+use kernel_config::memory::PAGE_SIZE;
+use memory::{create_contiguous_mapping, EntryFlags, MappedPages, PhysicalAddress};
+
+
+/// This descriptor continues via its `next` field.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The device writes to this descriptor's buffer, rather than reading from it.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// A descriptor in the descriptor table.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer of physically-contiguous memory that is given to the device as part of a request.
+#[derive(Clone, Copy, Debug)]
+pub struct Buffer {
+    pub phys_addr: PhysicalAddress,
+    pub len: u32,
+    /// Whether the device writes to this buffer, e.g., to return a response.
+    pub device_writable: bool,
+}
+
+
+/// A virtqueue that requests are submitted to and completed from.
+pub struct Virtqueue {
+    mapped_pages: MappedPages,
+    phys_addr: PhysicalAddress,
+    /// The index of this queue within its device.
+    index: u16,
+    /// The number of descriptors in this queue, which is chosen by the device.
+    size: u16,
+    /// The byte offset of the available ring within the `mapped_pages`.
+    avail_offset: usize,
+    /// The byte offset of the used ring within the `mapped_pages`.
+    used_offset: usize,
+    /// The head of the list of free descriptors, which are chained via their `next` fields.
+    free_head: u16,
+    num_free: u16,
+    /// The number of entries in the used ring that have been consumed.
+    last_used_idx: u16,
+    /// The number of descriptors in the chain that starts at each head, if that chain is in flight.
+    chain_lengths: Vec<u16>,
+}
+
+impl Virtqueue {
+    /// Allocates a new virtqueue with `size` descriptors, which will be the queue at `index` within its device.
+    pub(crate) fn new(index: u16, size: u16) -> Result<Virtqueue, &'static str> {
+        if size == 0 || !size.is_power_of_two() {
+            return Err("virtio: queue size must be a nonzero power of two");
+        }
+        let (avail_offset, used_offset, total_size) = Self::layout(size as usize);
+        let (mapped_pages, phys_addr) = create_contiguous_mapping(
+            total_size,
+            EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE,
+        )?;
+        let mut queue = Virtqueue {
+            mapped_pages,
+            phys_addr,
+            index,
+            size,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            num_free: size,
+            last_used_idx: 0,
+            chain_lengths: vec![0; size as usize],
+        };
+        // The mapping's frames may hold stale data, so clear the whole queue before chaining all descriptors into the free list.
+        unsafe { ptr::write_bytes(queue.base(), 0, total_size) };
+        for i in 0 .. size {
+            queue.write_descriptor(i, Descriptor { addr: 0, len: 0, flags: 0, next: i.wrapping_add(1) });
+        }
+        Ok(queue)
+    }
+
+    /// Returns the byte offsets of the available ring and the used ring, and the total size of a queue with `size` descriptors.
+    fn layout(size: usize) -> (usize, usize, usize) {
+        let avail_offset = size_of::<Descriptor>() * size;
+        // flags, idx, ring, used_event
+        let avail_size = size_of::<u16>() * (3 + size);
+        let used_offset = round_up(avail_offset + avail_size, PAGE_SIZE);
+        // flags, idx, ring of (id: u32, len: u32), avail_event
+        let used_size = size_of::<u16>() * 3 + size_of::<u32>() * 2 * size;
+        (avail_offset, used_offset, round_up(used_offset + used_size, PAGE_SIZE))
+    }
+
+    /// Returns the index of this queue within its device.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Returns the number of descriptors in this queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the physical address of this queue, which is given to the device.
+    pub fn phys_addr(&self) -> PhysicalAddress {
+        self.phys_addr
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.mapped_pages.start_address().value() as *mut u8
+    }
+
+    fn write_descriptor(&mut self, index: u16, descriptor: Descriptor) {
+        unsafe { ptr::write_volatile((self.base() as *mut Descriptor).add(index as usize), descriptor) }
+    }
+
+    fn read_descriptor(&self, index: u16) -> Descriptor {
+        unsafe { ptr::read_volatile((self.base() as *const Descriptor).add(index as usize)) }
+    }
+
+    /// Returns a pointer to the `u16` at the given index of the available ring, where index 1 is `idx` and 2 is `ring[0]`.
+    fn avail_u16(&self, index: usize) -> *mut u16 {
+        unsafe { (self.base().add(self.avail_offset) as *mut u16).add(index) }
+    }
+
+    fn used_idx(&self) -> u16 {
+        unsafe { ptr::read_volatile((self.base().add(self.used_offset) as *const u16).add(1)) }
+    }
+
+    /// Returns the `(id, len)` of the used ring entry at the given `slot`.
+    fn used_elem(&self, slot: u16) -> (u32, u32) {
+        unsafe {
+            let elem = (self.base().add(self.used_offset + 2 * size_of::<u16>()) as *const u32).add(2 * slot as usize);
+            (ptr::read_volatile(elem), ptr::read_volatile(elem.add(1)))
+        }
+    }
+
+    /// Submits a request that consists of the given `buffers`, in order, to the device.
+    ///
+    /// Returns the ID of the request, which is later returned by [`pop_used()`](#method.pop_used).
+    /// The device must be notified separately, see `LegacyPciTransport::notify()`.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, &'static str> {
+        if buffers.is_empty() {
+            return Err("virtio: a request must have at least one buffer");
+        }
+        if buffers.len() > self.num_free as usize {
+            return Err("virtio: not enough free descriptors in the virtqueue");
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let next = self.read_descriptor(index).next;
+            let mut flags = if buffer.device_writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            self.write_descriptor(index, Descriptor { addr: buffer.phys_addr.value() as u64, len: buffer.len, flags, next });
+            if i + 1 < buffers.len() {
+                index = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.num_free -= buffers.len() as u16;
+        self.chain_lengths[head as usize] = buffers.len() as u16;
+
+        // Publish the chain in the available ring, and only then advance its index.
+        unsafe {
+            let avail_idx = ptr::read_volatile(self.avail_u16(1));
+            ptr::write_volatile(self.avail_u16(2 + (avail_idx % self.size) as usize), head);
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(self.avail_u16(1), avail_idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// Returns the ID of a request that the device has completed and the number of bytes it wrote, if any,
+    /// and frees that request's descriptors.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if self.used_idx() == self.last_used_idx {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let (id, len) = self.used_elem(self.last_used_idx % self.size);
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        // Return the request's chain of descriptors to the free list.
+        let head = id as u16;
+        let chain_length = self.chain_lengths[head as usize];
+        let mut last = head;
+        for _ in 1 .. chain_length {
+            last = self.read_descriptor(last).next;
+        }
+        let mut descriptor = self.read_descriptor(last);
+        descriptor.next = self.free_head;
+        self.write_descriptor(last, descriptor);
+        self.free_head = head;
+        self.num_free += chain_length;
+        self.chain_lengths[head as usize] = 0;
+        Some((head, len))
+    }
+}
+
+fn round_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
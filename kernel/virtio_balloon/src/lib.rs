@@ -0,0 +1,286 @@
+//! A driver for the virtio memory balloon device, which lets the hypervisor reclaim unused guest memory.
+//!
+//! The host sets the number of pages it wants the guest to give up in the device's `num_pages` configuration field.
+//! To inflate the balloon, the driver allocates free frames from the frame allocator and tells the host their page numbers,
+//! after which the host may take away the memory backing them. To deflate the balloon, the driver tells the host
+//! which frames it takes back (if the host requires that), and returns them to the frame allocator.
+//!
+//! The balloon cooperates with the memory-pressure watermarks in the `memory` crate:
+//! * it never inflates below the low watermark, no matter how many pages the host requests, and
+//! * it registers a reclaim callback that deflates the balloon when memory is under pressure,
+//!   if the host allows that via the `VIRTIO_BALLOON_F_DEFLATE_ON_OOM` feature.
+//!
+//! The device's configuration is polled by a background task, since device interrupts are not yet supported.
+
+#![no_std]
+
+extern crate alloc;
+#[macro_use] extern crate log;
+extern crate spin;
+extern crate kernel_config;
+extern crate memory;
+extern crate pci;
+extern crate virtio;
+extern crate spawn;
+extern crate scheduler;
+extern crate sleep;
+extern crate tsc;
+
+use core::{cmp::min, sync::atomic::spin_loop_hint};
+use alloc::{string::String, vec::Vec};
+use spin::{Mutex, Once};
+use kernel_config::memory::PAGE_SIZE;
+use memory::{create_contiguous_mapping, EntryFlags, Frame, FrameOwner, MappedPages, PhysicalAddress, PressureLevel};
+use pci::PciDevice;
+use virtio::{LegacyPciTransport, VIRTIO_VENDOR_ID, virtqueue::{Buffer, Virtqueue}};
+
+
+/// The PCI device ID of a legacy or transitional virtio balloon device.
+pub const VIRTIO_BALLOON_LEGACY_DEVICE_ID: u16 = 0x1002;
+
+/// The host must be told before the guest uses frames that it took back from the balloon.
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 1 << 0;
+/// The guest may deflate the balloon when it runs out of memory, even if the host didn't ask it to.
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 1 << 2;
+
+// The offsets of the fields in the balloon's device configuration.
+/// The number of pages that the host wants in the balloon.
+const CONFIG_NUM_PAGES: u16 = 0;
+/// The number of pages that are actually in the balloon, which the driver updates.
+const CONFIG_ACTUAL: u16 = 4;
+const CONFIG_SIZE: u16 = 8;
+
+const INFLATE_QUEUE: u16 = 0;
+const DEFLATE_QUEUE: u16 = 1;
+
+/// The balloon always describes memory in 4 KiB pages, regardless of the guest's page size.
+const BALLOON_PAGE_SHIFT: usize = 12;
+/// The maximum number of page numbers sent to the host in one request.
+const PFNS_PER_REQUEST: usize = 256;
+/// How many times to poll a virtqueue for the host's response before giving up.
+const HOST_TIMEOUT_ITERATIONS: usize = 10_000_000;
+/// How often the background task checks the number of pages that the host wants.
+const POLL_INTERVAL_MS: u64 = 1000;
+/// The name under which the balloon's reclaim callback is registered.
+const RECLAIMER_NAME: &'static str = "virtio_balloon";
+
+static BALLOON: Once<Mutex<VirtioBalloon>> = Once::new();
+
+
+struct VirtioBalloon {
+    transport: LegacyPciTransport,
+    inflate_queue: Virtqueue,
+    deflate_queue: Virtqueue,
+    /// The buffer of page numbers for each request, which must be in physically-contiguous memory.
+    pfn_buffer: MappedPages,
+    pfn_buffer_paddr: PhysicalAddress,
+    /// The frames that are currently in the balloon.
+    frames: Vec<Frame>,
+    features: u32,
+}
+
+impl VirtioBalloon {
+    /// Returns the number of frames that the host wants in the balloon.
+    fn target_frames(&self) -> Result<usize, &'static str> {
+        let pages = self.transport.read_config_u32(CONFIG_NUM_PAGES)? as usize;
+        Ok(pages * (1 << BALLOON_PAGE_SHIFT) / PAGE_SIZE)
+    }
+
+    /// Inflates or deflates the balloon towards the number of frames the host wants,
+    /// by at most one request, and returns whether the balloon's size changed.
+    fn adjust(&mut self) -> Result<bool, &'static str> {
+        let target = self.target_frames()?;
+        let current = self.frames.len();
+        let changed = if target > current {
+            self.inflate(min(target - current, PFNS_PER_REQUEST))? > 0
+        } else if target < current {
+            self.deflate(min(current - target, PFNS_PER_REQUEST))? > 0
+        } else {
+            false
+        };
+        if changed {
+            self.update_actual()?;
+        }
+        Ok(changed)
+    }
+
+    /// Tells the host how many pages are actually in the balloon.
+    fn update_actual(&self) -> Result<(), &'static str> {
+        self.transport.write_config_u32(CONFIG_ACTUAL, (self.frames.len() * PAGE_SIZE >> BALLOON_PAGE_SHIFT) as u32)
+    }
+
+    /// Moves up to `count` free frames into the balloon, without going below the low watermark.
+    /// Returns the number of frames that were moved.
+    fn inflate(&mut self, count: usize) -> Result<usize, &'static str> {
+        let (low_watermark, _min) = memory::watermarks();
+        let count = min(count, memory::free_frame_count().saturating_sub(low_watermark));
+        let mut frames = Vec::with_capacity(count);
+        for _ in 0 .. count {
+            match memory::allocate_frame_owned(FrameOwner::Crate(RECLAIMER_NAME)) {
+                Ok(frame) => frames.push(frame),
+                Err(_) => break,
+            }
+        }
+        if frames.is_empty() {
+            return Ok(0);
+        }
+        if let Err(e) = self.send_pfns(INFLATE_QUEUE, &frames) {
+            // The host didn't take the frames, so they can be used again.
+            for frame in frames {
+                memory::deallocate_frame(frame);
+            }
+            return Err(e);
+        }
+        let inflated = frames.len();
+        self.frames.extend(frames);
+        debug!("virtio_balloon: inflated by {} frames to {} frames", inflated, self.frames.len());
+        Ok(inflated)
+    }
+
+    /// Moves up to `count` frames out of the balloon and returns them to the frame allocator.
+    /// Returns the number of frames that were moved.
+    fn deflate(&mut self, count: usize) -> Result<usize, &'static str> {
+        let count = min(min(count, self.frames.len()), PFNS_PER_REQUEST);
+        if count == 0 {
+            return Ok(0);
+        }
+        let frames: Vec<Frame> = self.frames.split_off(self.frames.len() - count);
+        if self.features & VIRTIO_BALLOON_F_MUST_TELL_HOST != 0 {
+            if let Err(e) = self.send_pfns(DEFLATE_QUEUE, &frames) {
+                self.frames.extend(frames);
+                return Err(e);
+            }
+        }
+        for frame in frames {
+            memory::deallocate_frame(frame);
+        }
+        debug!("virtio_balloon: deflated by {} frames to {} frames", count, self.frames.len());
+        Ok(count)
+    }
+
+    /// Sends the page numbers of the given `frames` to the host via the given queue, and waits for the host to process them.
+    fn send_pfns(&mut self, queue: u16, frames: &[Frame]) -> Result<(), &'static str> {
+        let pfns_per_frame = PAGE_SIZE >> BALLOON_PAGE_SHIFT;
+        let num_pfns = frames.len() * pfns_per_frame;
+        {
+            let pfns: &mut [u32] = self.pfn_buffer.as_slice_mut(0, num_pfns)?;
+            for (i, frame) in frames.iter().enumerate() {
+                let first_pfn = frame.start_address().value() >> BALLOON_PAGE_SHIFT;
+                for j in 0 .. pfns_per_frame {
+                    pfns[i * pfns_per_frame + j] = ((first_pfn + j) as u32).to_le();
+                }
+            }
+        }
+        let buffer = Buffer { phys_addr: self.pfn_buffer_paddr, len: (num_pfns * 4) as u32, device_writable: false };
+        let (transport, queue) = match queue {
+            INFLATE_QUEUE => (&self.transport, &mut self.inflate_queue),
+            _ => (&self.transport, &mut self.deflate_queue),
+        };
+        let id = queue.add(&[buffer])?;
+        transport.notify(queue);
+        for _ in 0 .. HOST_TIMEOUT_ITERATIONS {
+            if let Some((used_id, _len)) = queue.pop_used() {
+                if used_id == id {
+                    return Ok(());
+                }
+            }
+            spin_loop_hint();
+        }
+        error!("virtio_balloon: host didn't process the request on queue {}", queue.index());
+        Err("virtio_balloon: timed out waiting for the host")
+    }
+}
+
+
+/// Checks whether the given PCI device is a virtio balloon, and if so, initializes it
+/// and starts adjusting the balloon to the size that the host requests.
+///
+/// Returns `Ok(true)` if the device was used as the balloon, `Ok(false)` otherwise.
+pub fn init_device(dev: &PciDevice) -> Result<bool, &'static str> {
+    if dev.vendor_id != VIRTIO_VENDOR_ID || dev.device_id != VIRTIO_BALLOON_LEGACY_DEVICE_ID {
+        return Ok(false);
+    }
+    if BALLOON.try().is_some() {
+        warn!("virtio_balloon: ignoring another balloon device at {:?}", dev.location);
+        return Ok(false);
+    }
+
+    let transport = LegacyPciTransport::new(dev, CONFIG_SIZE, "virtio_balloon")?;
+    let balloon = setup(transport)?;
+    info!("virtio_balloon: initialized balloon device at {:?}, features {:#X}", dev.location, balloon.features);
+    let deflate_on_oom = balloon.features & VIRTIO_BALLOON_F_DEFLATE_ON_OOM != 0;
+    BALLOON.call_once(|| Mutex::new(balloon));
+
+    if deflate_on_oom {
+        memory::register_reclaimer(RECLAIMER_NAME, reclaim)?;
+    }
+    let interval_ticks = POLL_INTERVAL_MS.saturating_mul(tsc::get_tsc_frequency()?) / 1000;
+    spawn::new_task_builder(balloon_loop, interval_ticks)
+        .name(String::from("virtio_balloon"))
+        .spawn()?;
+    Ok(true)
+}
+
+fn setup(transport: LegacyPciTransport) -> Result<VirtioBalloon, &'static str> {
+    let features = transport.negotiate_features(VIRTIO_BALLOON_F_MUST_TELL_HOST | VIRTIO_BALLOON_F_DEFLATE_ON_OOM);
+    let queues = transport.setup_queue(INFLATE_QUEUE).and_then(|inflate| {
+        transport.setup_queue(DEFLATE_QUEUE).map(|deflate| (inflate, deflate))
+    });
+    let buffer = create_contiguous_mapping(
+        PFNS_PER_REQUEST * (PAGE_SIZE >> BALLOON_PAGE_SHIFT) * 4,
+        EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+    );
+    match (queues, buffer) {
+        (Ok((inflate_queue, deflate_queue)), Ok((pfn_buffer, pfn_buffer_paddr))) => {
+            transport.driver_ok();
+            Ok(VirtioBalloon { transport, inflate_queue, deflate_queue, pfn_buffer, pfn_buffer_paddr, frames: Vec::new(), features })
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            transport.fail();
+            Err(e)
+        }
+    }
+}
+
+/// Returns the number of frames that are currently in the balloon, if there is a balloon device.
+pub fn balloon_frames() -> Option<usize> {
+    BALLOON.try().map(|b| b.lock().frames.len())
+}
+
+/// The reclaim callback, which deflates the balloon to relieve memory pressure.
+///
+/// This is invoked from the allocation path, so it skips deflating if the balloon is busy.
+fn reclaim(_level: PressureLevel, target_frames: usize) -> usize {
+    let mut balloon = match BALLOON.try().and_then(|b| b.try_lock()) {
+        Some(balloon) => balloon,
+        None => return 0,
+    };
+    match balloon.deflate(target_frames) {
+        Ok(deflated) => {
+            let _ = balloon.update_actual();
+            warn!("virtio_balloon: deflated {} frames due to memory pressure", deflated);
+            deflated
+        }
+        Err(e) => {
+            error!("virtio_balloon: couldn't deflate the balloon: {}", e);
+            0
+        }
+    }
+}
+
+fn balloon_loop(interval_ticks: u64) -> Result<(), &'static str> {
+    let balloon = BALLOON.try().ok_or("virtio_balloon: balloon wasn't initialized")?;
+    loop {
+        // Keep adjusting without waiting while the balloon's size is still changing.
+        let changed = balloon.lock().adjust().unwrap_or_else(|e| {
+            error!("virtio_balloon: couldn't adjust the balloon: {}", e);
+            false
+        });
+        if changed {
+            scheduler::schedule();
+            continue;
+        }
+        let start: u64 = tsc::tsc_ticks().into();
+        sleep::sleep_until(start.saturating_add(interval_ticks))?;
+    }
+}
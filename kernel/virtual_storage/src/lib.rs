@@ -0,0 +1,86 @@
+//! Common scaffolding for virtual storage devices, i.e., those that are backed by software
+//! rather than by disk hardware, such as ramdisks and loop devices.
+//! 
+//! Each kind of virtual storage device keeps the devices it creates in its own [`VirtualStorageController`],
+//! a singleton that is registered with the `storage_manager` via [`VirtualStorageController::register()`]
+//! so that its devices can be found alongside other storage devices.
+//! 
+//! [`VirtualStorageController`]: struct.VirtualStorageController.html
+//! [`VirtualStorageController::register()`]: struct.VirtualStorageController.html#method.register
+
+#![no_std]
+
+extern crate alloc;
+extern crate spin;
+extern crate storage_device;
+extern crate storage_manager;
+
+use alloc::{
+    boxed::Box,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use storage_device::{StorageController, StorageDevice, StorageDeviceRef};
+
+
+/// A storage controller that holds virtual storage devices of type `D`.
+pub struct VirtualStorageController<D: StorageDevice + Send> {
+    devices: Vec<Arc<Mutex<D>>>,
+}
+
+impl<D: StorageDevice + Send> VirtualStorageController<D> {
+    /// Creates a new empty controller and registers it with the `storage_manager`.
+    /// 
+    /// This is intended to be used to initialize a crate's singleton controller, e.g.,
+    /// `CONTROLLER.call_once(VirtualStorageController::register)`.
+    pub fn register() -> Arc<Mutex<VirtualStorageController<D>>> {
+        let controller = Arc::new(Mutex::new(VirtualStorageController { devices: Vec::new() }));
+        storage_manager::add_storage_controller(controller.clone());
+        controller
+    }
+
+    /// Returns an iterator over all of the devices in this controller.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Mutex<D>>> {
+        self.devices.iter()
+    }
+
+    /// Adds the given `device` to this controller, and returns a reference to it.
+    pub fn add(&mut self, device: D) -> Arc<Mutex<D>> {
+        let device = Arc::new(Mutex::new(device));
+        self.devices.push(Arc::clone(&device));
+        device
+    }
+
+    /// Removes the given `device` from this controller.
+    /// 
+    /// Returns the removed device, or `None` if it was not in this controller.
+    pub fn remove(&mut self, device: &Arc<Mutex<D>>) -> Option<Arc<Mutex<D>>> {
+        let index = self.devices.iter().position(|d| Arc::ptr_eq(d, device))?;
+        Some(self.devices.remove(index))
+    }
+}
+
+impl<D: StorageDevice + Send> StorageController for VirtualStorageController<D> {
+    fn devices<'c>(&'c self) -> Box<(dyn Iterator<Item = StorageDeviceRef> + 'c)> {
+        Box::new(
+            self.devices.iter().map(|device_ref| Arc::clone(device_ref) as StorageDeviceRef)
+        )
+    }
+}
+
+
+/// Checks that a transfer of `buffer_len` bytes starting at the given sector is within the bounds of the given `device`.
+/// 
+/// Returns the byte offset into the device at which the transfer starts, and the number of sectors transferred.
+pub fn transfer_bounds<D: StorageDevice + ?Sized>(device: &D, buffer_len: usize, offset_in_sectors: usize) -> Result<(usize, usize), &'static str> {
+    let sector_size = device.sector_size_in_bytes();
+    if buffer_len % sector_size != 0 {
+        return Err("buffer length must be a multiple of the sector size");
+    }
+    let num_sectors = buffer_len / sector_size;
+    if offset_in_sectors.checked_add(num_sectors).map_or(true, |end| end > device.size_in_sectors()) {
+        return Err("transfer extends past the end of the device");
+    }
+    Ok((offset_in_sectors * sector_size, num_sectors))
+}
@@ -0,0 +1,122 @@
+//! A system-wide clipboard and drag-and-drop data slot that is shared by all windowed applications.
+//!
+//! Applications copy data by invoking [`set_clipboard()`] and paste it by invoking [`get_clipboard()`]
+//! or, for plain text, [`get_clipboard_text()`]. The clipboard holds at most one item at a time,
+//! so setting new data replaces the previous contents.
+//!
+//! Dragging works similarly: the source application invokes [`start_drag()`] with the dragged data,
+//! and the application that receives the drop takes that data via [`take_drag_data()`].
+//!
+//! [`set_clipboard()`]: fn.set_clipboard.html
+//! [`get_clipboard()`]: fn.get_clipboard.html
+//! [`get_clipboard_text()`]: fn.get_clipboard_text.html
+//! [`start_drag()`]: fn.start_drag.html
+//! [`take_drag_data()`]: fn.take_drag_data.html
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use framebuffer::AlphaPixel;
+use spin::Mutex;
+
+
+/// The typed data that can be copied to the clipboard or dragged between windows.
+#[derive(Clone, Debug)]
+pub enum ClipboardData {
+    /// Plain text.
+    Text(String),
+    /// An image with the given dimensions, whose pixels are stored row by row.
+    Image {
+        width: usize,
+        height: usize,
+        pixels: Vec<AlphaPixel>,
+    },
+}
+
+impl ClipboardData {
+    /// Returns a short name for the type of this data, e.g., for display purposes.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ClipboardData::Text(_) => "text",
+            ClipboardData::Image { .. } => "image",
+        }
+    }
+}
+
+/// The current contents of the clipboard.
+static CLIPBOARD: Mutex<Option<ClipboardData>> = Mutex::new(None);
+
+/// Incremented each time the clipboard contents change,
+/// allowing applications to cheaply check whether new data was copied.
+static CLIPBOARD_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// The data currently being dragged from one window to another.
+static DRAG_DATA: Mutex<Option<ClipboardData>> = Mutex::new(None);
+
+
+/// Copies the given `data` to the clipboard, replacing its previous contents.
+///
+/// An `Image` whose number of pixels does not match its dimensions is rejected.
+pub fn set_clipboard(data: ClipboardData) -> Result<(), &'static str> {
+    if let ClipboardData::Image { width, height, ref pixels } = data {
+        if width * height != pixels.len() {
+            return Err("clipboard image dimensions do not match its number of pixels");
+        }
+    }
+    *CLIPBOARD.lock() = Some(data);
+    CLIPBOARD_GENERATION.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Copies the given text to the clipboard, replacing its previous contents.
+pub fn set_clipboard_text(text: String) {
+    *CLIPBOARD.lock() = Some(ClipboardData::Text(text));
+    CLIPBOARD_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Returns a copy of the current contents of the clipboard, if any.
+pub fn get_clipboard() -> Option<ClipboardData> {
+    CLIPBOARD.lock().clone()
+}
+
+/// Returns a copy of the text in the clipboard,
+/// or `None` if the clipboard is empty or holds non-text data.
+pub fn get_clipboard_text() -> Option<String> {
+    match *CLIPBOARD.lock() {
+        Some(ClipboardData::Text(ref text)) => Some(text.clone()),
+        _ => None,
+    }
+}
+
+/// Empties the clipboard.
+pub fn clear_clipboard() {
+    *CLIPBOARD.lock() = None;
+    CLIPBOARD_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Returns the number of times the clipboard contents have changed.
+pub fn clipboard_generation() -> usize {
+    CLIPBOARD_GENERATION.load(Ordering::SeqCst)
+}
+
+
+/// Starts dragging the given `data`, replacing any data from a previous drag that was never dropped.
+pub fn start_drag(data: ClipboardData) {
+    *DRAG_DATA.lock() = Some(data);
+}
+
+/// Returns `true` if there is data currently being dragged.
+pub fn is_dragging() -> bool {
+    DRAG_DATA.lock().is_some()
+}
+
+/// Takes the data currently being dragged, which ends the drag.
+/// This should be invoked by the application that receives the drop.
+pub fn take_drag_data() -> Option<ClipboardData> {
+    DRAG_DATA.lock().take()
+}
+
+/// Cancels the current drag, discarding its data.
+pub fn cancel_drag() {
+    DRAG_DATA.lock().take();
+}
@@ -27,6 +27,9 @@ extern crate spawn;
 extern crate window_inner;
 extern crate shapes;
 extern crate color;
+extern crate backlight;
+
+pub mod clipboard;
 
 use alloc::collections::VecDeque;
 use alloc::string::ToString;
@@ -94,6 +97,61 @@ pub struct WindowManager {
     top_fb: Framebuffer<AlphaPixel>,
     /// The final framebuffer which is mapped to the screen (the actual display device).
     pub final_fb: Framebuffer<AlphaPixel>,
+    /// Additional displays that extend the desktop beyond the primary display (`final_fb`).
+    secondary_displays: Vec<SecondaryDisplay>,
+}
+
+/// An additional display device with its own final framebuffer, 
+/// which shows the part of the desktop at `origin` that has the same size as the display.
+/// 
+/// Secondary displays only show windows and a plain background;
+/// the mouse pointer and floating window border are only shown on the primary display.
+struct SecondaryDisplay {
+    /// The position of this display's top-left corner within the desktop,
+    /// relative to the top-left corner of the primary display.
+    origin: Coord,
+    /// The background of this display, which is displayed where no windows exist on top of it.
+    bottom_fb: Framebuffer<AlphaPixel>,
+    /// The final framebuffer which is mapped to this display device.
+    final_fb: Framebuffer<AlphaPixel>,
+}
+
+impl SecondaryDisplay {
+    /// Returns the area of the desktop shown by this display.
+    fn area(&self) -> Rectangle {
+        let (width, height) = self.final_fb.get_size();
+        Rectangle {
+            top_left: self.origin,
+            bottom_right: self.origin + (width as isize, height as isize),
+        }
+    }
+}
+
+/// Returns `true` if the two rectangles share at least one pixel.
+fn rectangles_overlap(a: &Rectangle, b: &Rectangle) -> bool {
+    a.top_left.x < b.bottom_right.x && b.top_left.x < a.bottom_right.x
+        && a.top_left.y < b.bottom_right.y && b.top_left.y < a.bottom_right.y
+}
+
+/// Blends the entire `src` framebuffer onto the `dest` framebuffer at the given `coordinate`,
+/// ignoring any part of `src` that lies outside of `dest`.
+/// 
+/// This does not use the `FRAME_COMPOSITOR`, whose cache only supports a single destination framebuffer.
+fn blend_framebuffer(src: &Framebuffer<AlphaPixel>, dest: &mut Framebuffer<AlphaPixel>, coordinate: Coord) {
+    let (src_width, src_height) = src.get_size();
+    let (dest_width, dest_height) = dest.get_size();
+    let start_x = core::cmp::max(coordinate.x, 0);
+    let end_x = core::cmp::min(coordinate.x + src_width as isize, dest_width as isize);
+    let start_y = core::cmp::max(coordinate.y, 0);
+    let end_y = core::cmp::min(coordinate.y + src_height as isize, dest_height as isize);
+    if start_x >= end_x || start_y >= end_y {
+        return;
+    }
+    for y in start_y..end_y {
+        let src_start = (y - coordinate.y) as usize * src_width + (start_x - coordinate.x) as usize;
+        let row = &src.buffer()[src_start .. src_start + (end_x - start_x) as usize];
+        dest.composite_buffer(row, y as usize * dest_width + start_x as usize);
+    }
 }
 
 impl WindowManager {
@@ -204,6 +262,7 @@ impl WindowManager {
                 } else {
                     self.active = Weak::new(); // delete reference
                 }
+                self.refresh_secondary_displays()?;
                 return Ok(());
             }
         }
@@ -286,6 +345,8 @@ impl WindowManager {
         &mut self, 
         bounding_box: impl IntoIterator<Item = B> + Clone,
     ) -> Result<(), &'static str> {
+        self.refresh_secondary_displays()?;
+
         // reference of windows
         let mut window_ref_list = Vec::new();
         for window in &self.hide_list {
@@ -319,6 +380,7 @@ impl WindowManager {
 
     /// Refresh the part in `bounding_box` of the active window. `bounding_box` is a region relative to the top-left of the screen. Refresh the whole screen if the bounding box is None.
     pub fn refresh_active_window(&mut self, bounding_box: Option<Rectangle>) -> Result<(), &'static str> {
+        self.refresh_secondary_displays()?;
         if let Some(window_ref) = self.active.upgrade() {
             let window = window_ref.lock();
             let buffer_update = FramebufferUpdates {
@@ -608,10 +670,136 @@ impl WindowManager {
             .unwrap_or(false)
     }
 
+    /// Returns the currently active window, if any.
+    pub fn active_window(&self) -> Option<Arc<Mutex<WindowInner>>> {
+        self.active.upgrade()
+    }
+
     /// Returns the `(width, height)` in pixels of the screen itself (the final framebuffer).
     pub fn get_screen_size(&self) -> (usize, usize) {
         self.final_fb.get_size()
     }
+
+    /// Replaces the final framebuffer of the primary display, e.g., after its display mode was changed,
+    /// and redraws the whole screen onto it.
+    /// 
+    /// The bottom and top framebuffers are recreated with the new size,
+    /// and the mouse pointer is moved back onto the screen if it's now outside of it.
+    /// 
+    /// Returns the previous final framebuffer.
+    pub fn set_primary_framebuffer(&mut self, framebuffer: Framebuffer<AlphaPixel>) -> Result<Framebuffer<AlphaPixel>, &'static str> {
+        let (width, height) = framebuffer.get_size();
+        let new_area = Rectangle {
+            top_left: Coord::new(0, 0),
+            bottom_right: Coord::new(width as isize, height as isize),
+        };
+        if self.secondary_displays.iter().any(|display| rectangles_overlap(&display.area(), &new_area)) {
+            return Err("the new primary display size overlaps a secondary display");
+        }
+        let mut bottom_fb = Framebuffer::new(width, height, None)?;
+        let mut top_fb = Framebuffer::new(width, height, None)?;
+        bottom_fb.fill(color::LIGHT_GRAY.into());
+        top_fb.fill(color::TRANSPARENT.into());
+
+        self.bottom_fb = bottom_fb;
+        self.top_fb = top_fb;
+        let old_fb = core::mem::replace(&mut self.final_fb, framebuffer);
+        // The compositor's cache refers to the contents of the old final framebuffer.
+        FRAME_COMPOSITOR.lock().clear_cache();
+
+        self.mouse = Coord::new(
+            core::cmp::min(self.mouse.x, width as isize - 1),
+            core::cmp::min(self.mouse.y, height as isize - 1),
+        );
+        self.refresh_bottom_windows(Option::<Rectangle>::None, true)?;
+        let mouse = self.mouse;
+        self.move_mouse_to(mouse)?;
+        Ok(old_fb)
+    }
+
+    /// Adds a secondary display with the given final `framebuffer`, which shows the area of the desktop
+    /// starting at `origin`, relative to the top-left corner of the primary display.
+    /// For example, a display to the right of the primary display has an origin of `(primary_width, 0)`.
+    /// 
+    /// The display must not overlap the primary display or any other secondary display.
+    /// Windows can be moved onto a secondary display by positioning them within its area.
+    /// 
+    /// Returns the number of the new display, in which the primary display is number `0`.
+    pub fn add_display(&mut self, framebuffer: Framebuffer<AlphaPixel>, origin: Coord) -> Result<usize, &'static str> {
+        let (width, height) = framebuffer.get_size();
+        let mut bottom_fb = Framebuffer::new(width, height, None)?;
+        bottom_fb.fill(color::LIGHT_GRAY.into());
+        let display = SecondaryDisplay { origin, bottom_fb, final_fb: framebuffer };
+        
+        let new_area = display.area();
+        if self.displays().iter().any(|area| rectangles_overlap(area, &new_area)) {
+            return Err("the new display overlaps an existing display");
+        }
+        self.secondary_displays.push(display);
+        self.refresh_secondary_displays()?;
+        Ok(self.secondary_displays.len())
+    }
+
+    /// Removes the secondary display with the given number, as returned by [`add_display()`](#method.add_display),
+    /// and returns its final framebuffer.
+    /// 
+    /// Removing a display changes the numbers of the displays after it.
+    /// Windows that were shown on the removed display are not moved.
+    pub fn remove_display(&mut self, display_number: usize) -> Result<Framebuffer<AlphaPixel>, &'static str> {
+        if display_number == 0 {
+            return Err("the primary display cannot be removed");
+        }
+        if display_number > self.secondary_displays.len() {
+            return Err("no display exists with that number");
+        }
+        Ok(self.secondary_displays.remove(display_number - 1).final_fb)
+    }
+
+    /// Returns the areas of the desktop shown by each display, in order of their display numbers.
+    /// The first area is always that of the primary display, which starts at `(0, 0)`.
+    pub fn displays(&self) -> Vec<Rectangle> {
+        let (width, height) = self.get_screen_size();
+        let primary = Rectangle {
+            top_left: Coord::new(0, 0),
+            bottom_right: Coord::new(width as isize, height as isize),
+        };
+        Some(primary).into_iter()
+            .chain(self.secondary_displays.iter().map(|display| display.area()))
+            .collect()
+    }
+
+    /// Redraws every secondary display in its entirety.
+    fn refresh_secondary_displays(&mut self) -> Result<(), &'static str> {
+        if self.secondary_displays.is_empty() {
+            return Ok(());
+        }
+
+        // Windows from the bottom to the top, i.e., in the same order as `refresh_windows()`.
+        let window_ref_list = self.hide_list.iter()
+            .chain(self.show_list.iter())
+            .chain(Some(&self.active))
+            .filter_map(|window| window.upgrade())
+            .collect::<Vec<_>>();
+        let locked_window_list = window_ref_list.iter().map(|x| x.lock()).collect::<Vec<_>>();
+
+        for display in self.secondary_displays.iter_mut() {
+            let area = display.area();
+            let SecondaryDisplay { origin, bottom_fb, final_fb } = display;
+            final_fb.buffer_mut().copy_from_slice(bottom_fb.buffer());
+            for window in locked_window_list.iter() {
+                let position = window.get_position();
+                let (width, height) = window.get_size();
+                let window_area = Rectangle {
+                    top_left: position,
+                    bottom_right: position + (width as isize, height as isize),
+                };
+                if rectangles_overlap(&area, &window_area) {
+                    blend_framebuffer(window.framebuffer(), final_fb, position - *origin);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Initialize the window manager. It returns (keyboard_producer, mouse_producer) for the I/O devices.
@@ -641,6 +829,7 @@ pub fn init() -> Result<(Queue<Event>, Queue<Event>), &'static str> {
         bottom_fb: bottom_framebuffer,
         top_fb: top_framebuffer,
         final_fb: final_framebuffer,
+        secondary_displays: Vec::new(),
     };
     let _wm = WINDOW_MANAGER.call_once(|| Mutex::new(window_manager));
 
@@ -743,6 +932,21 @@ fn keyboard_handle_application(key_input: KeyEvent) -> Result<(), &'static str>
     
     // First, we handle keyboard shortcuts understood by the window manager.
     
+    // "Super + F11" and "Super + F12" will decrease and increase the display brightness, respectively.
+    // Laptop brightness keys are usually reported as ACPI notifications rather than keyboard scancodes,
+    // which we can't yet receive, so these shortcuts stand in for them.
+    if key_input.modifiers.is_super_key() && key_input.action == KeyAction::Pressed
+        && (key_input.keycode == Keycode::F11 || key_input.keycode == Keycode::F12)
+    {
+        let step = backlight::BRIGHTNESS_STEP_PERCENT as i8;
+        let delta = if key_input.keycode == Keycode::F11 { -step } else { step };
+        match backlight::adjust_brightness(delta) {
+            Ok(percent) => debug!("window_manager: set display brightness to {}%", percent),
+            Err(e) => warn!("window_manager: failed to change display brightness: {}", e),
+        }
+        return Ok(());
+    }
+
     // "Super + Arrow" will resize and move windows to the specified half of the screen (left, right, top, or bottom)
     if key_input.modifiers.is_super_key() && key_input.action == KeyAction::Pressed {
         let screen_dimensions = win_mgr.lock().get_screen_size();
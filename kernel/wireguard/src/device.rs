@@ -0,0 +1,191 @@
+//! The virtual Ethernet device underneath a tunnel's smoltcp interface.
+//!
+//! smoltcp's interface only supports Ethernet devices, whereas the tunnel carries raw IP packets.
+//! Thus, the Ethernet header of every frame that the interface transmits is stripped before its IP packet
+//! is sent through the tunnel, and every IP packet received from the peer is given a new Ethernet header.
+//! The peer appears to be a single neighbor with the fixed address `PEER_MAC` that is reachable at every IP address,
+//! so the device itself answers the interface's ARP requests for any address with that neighbor's address.
+
+use alloc::{
+    collections::VecDeque,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use smoltcp::{
+    phy::{self, DeviceCapabilities},
+    time::Instant,
+    wire::EthernetAddress,
+};
+use network_manager::stats;
+
+
+/// The Ethernet address of the local side of the tunnel, a locally-administered unicast address.
+pub const LOCAL_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+/// The Ethernet address that the peer appears to have.
+pub const PEER_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x02]);
+/// The largest IP packet that can be sent through the tunnel,
+/// which leaves room for the outer IP, UDP, and WireGuard headers within a 1500-byte Ethernet frame.
+pub const TUNNEL_MTU: usize = 1420;
+/// The maximum number of packets that can wait in each direction; further packets are dropped.
+pub const MAX_QUEUED_PACKETS: usize = 256;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_PACKET_LEN: usize = 28;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+
+/// The packets that are exchanged between the tunnel and its smoltcp interface.
+pub struct PacketQueues {
+    /// IP packets sent by the interface, which must be encrypted and sent to the peer.
+    pub outgoing: VecDeque<Vec<u8>>,
+    /// Ethernet frames to be received by the interface, i.e., packets from the peer and ARP replies.
+    incoming: VecDeque<Vec<u8>>,
+    /// The interface's own Ethernet address, to which incoming frames are addressed.
+    pub local_mac: EthernetAddress,
+}
+
+pub type PacketQueuesRef = Arc<Mutex<PacketQueues>>;
+
+impl PacketQueues {
+    pub fn new() -> PacketQueues {
+        PacketQueues {
+            outgoing: VecDeque::new(),
+            incoming: VecDeque::new(),
+            local_mac: LOCAL_MAC,
+        }
+    }
+
+    /// Queues the given IPv4 `packet` that was received from the peer, such that the interface receives it.
+    /// Returns `false` if it was dropped because too many packets are already waiting.
+    pub fn receive_from_peer(&mut self, packet: &[u8]) -> bool {
+        let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + packet.len());
+        push_ethernet_header(&mut frame, self.local_mac, PEER_MAC, ETHERTYPE_IPV4);
+        frame.extend_from_slice(packet);
+        self.push_incoming(frame)
+    }
+
+    fn push_incoming(&mut self, frame: Vec<u8>) -> bool {
+        if self.incoming.len() >= MAX_QUEUED_PACKETS {
+            return false;
+        }
+        self.incoming.push_back(frame);
+        true
+    }
+
+    /// Handles a frame that the interface transmitted.
+    fn transmit(&mut self, frame: &[u8]) {
+        if frame.len() < ETHERNET_HEADER_LEN {
+            return;
+        }
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        let payload = &frame[ETHERNET_HEADER_LEN..];
+        match ethertype {
+            ETHERTYPE_IPV4 => {
+                if self.outgoing.len() < MAX_QUEUED_PACKETS {
+                    self.outgoing.push_back(payload.to_vec());
+                }
+            }
+            ETHERTYPE_ARP => self.answer_arp(payload),
+            // Only IPv4 is supported inside the tunnel.
+            _ => { }
+        }
+    }
+
+    /// Answers an ARP request for any IPv4 address with the peer's Ethernet address.
+    fn answer_arp(&mut self, arp: &[u8]) {
+        if arp.len() < ARP_PACKET_LEN {
+            return;
+        }
+        let is_ethernet_ipv4 = arp[0..6] == [0u8, 1, 0x08, 0x00, 6, 4];
+        let operation = u16::from_be_bytes([arp[6], arp[7]]);
+        if !is_ethernet_ipv4 || operation != ARP_REQUEST {
+            return;
+        }
+        let sender_mac = &arp[8..14];
+        let sender_ip = &arp[14..18];
+        let target_ip = &arp[24..28];
+
+        let mut reply = Vec::with_capacity(ETHERNET_HEADER_LEN + ARP_PACKET_LEN);
+        push_ethernet_header(&mut reply, EthernetAddress::from_bytes(sender_mac), PEER_MAC, ETHERTYPE_ARP);
+        reply.extend_from_slice(&arp[0..6]);
+        reply.extend_from_slice(&ARP_REPLY.to_be_bytes());
+        reply.extend_from_slice(PEER_MAC.as_bytes());
+        reply.extend_from_slice(target_ip);
+        reply.extend_from_slice(sender_mac);
+        reply.extend_from_slice(sender_ip);
+        self.push_incoming(reply);
+    }
+}
+
+fn push_ethernet_header(frame: &mut Vec<u8>, dst: EthernetAddress, src: EthernetAddress, ethertype: u16) {
+    frame.extend_from_slice(dst.as_bytes());
+    frame.extend_from_slice(src.as_bytes());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+}
+
+
+/// An implementation of smoltcp's `Device` trait that exchanges frames with the tunnel through its `PacketQueues`.
+pub struct TunnelDevice {
+    queues: PacketQueuesRef,
+}
+
+impl TunnelDevice {
+    pub fn new(queues: PacketQueuesRef) -> TunnelDevice {
+        TunnelDevice { queues }
+    }
+}
+
+impl<'d> phy::Device<'d> for TunnelDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = TUNNEL_MTU + ETHERNET_HEADER_LEN;
+        caps
+    }
+
+    fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = loop {
+            let frame = self.queues.lock().incoming.pop_front()?;
+            if stats::record_incoming(&frame) {
+                break frame;
+            }
+        };
+        Some((RxToken(frame), TxToken(self.queues.clone())))
+    }
+
+    fn transmit(&mut self) -> Option<Self::TxToken> {
+        Some(TxToken(self.queues.clone()))
+    }
+}
+
+/// The transmit token of a `TunnelDevice`, which hands the transmitted frame to its `PacketQueues`.
+pub struct TxToken(PacketQueuesRef);
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&mut [u8]) -> smoltcp::Result<R>
+    {
+        let mut frame = vec![0u8; len];
+        let retval = f(&mut frame)?;
+        stats::record_outgoing(&frame);
+        self.0.lock().transmit(&frame);
+        Ok(retval)
+    }
+}
+
+/// The receive token of a `TunnelDevice`, which contains a received frame.
+pub struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&mut [u8]) -> smoltcp::Result<R>
+    {
+        f(&mut self.0)
+    }
+}
@@ -0,0 +1,729 @@
+//! A virtual network interface that tunnels IPv4 packets to a single peer over an authenticated, encrypted channel,
+//! which is compatible with WireGuard.
+//! This allows a test machine to join a secure overlay network, e.g., one that leads back to the development host.
+//!
+//! A tunnel is created via [`create_tunnel()`], which adds it to the system's network interfaces
+//! and routes the peer's allowed IPs through it, such that it can be used like any other interface.
+//! Its packets are encrypted and sent as UDP datagrams through an underlying interface,
+//! i.e., the one that the routing table chooses for the peer's endpoint.
+//!
+//! Sessions are established and periodically renewed by the Noise_IKpsk2 handshake, see the `noise` module.
+//! Either side can initiate it, but the local side can only do so once it knows the peer's endpoint,
+//! either because it was configured or because the peer has contacted it first.
+//! Every packet from the peer is checked against its allowed IPs, and packets to any other destination
+//! are never sent to it, just like WireGuard's cryptokey routing.
+//!
+//! A background task services each tunnel, i.e., it handles handshakes, timers, and received packets
+//! even while no application is polling the tunnel's interface.
+//!
+//! # Limitations
+//! * Each tunnel has exactly one peer.
+//! * Only IPv4 is supported, both inside the tunnel and for the peer's endpoint.
+//! * Cookie replies, which WireGuard uses for DoS protection under load, are not supported.
+//!
+//! [`create_tunnel()`]: fn.create_tunnel.html
+
+#![no_std]
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate alloc;
+#[macro_use] extern crate lazy_static;
+extern crate spin;
+extern crate smoltcp;
+extern crate network_manager;
+extern crate smoltcp_helper;
+extern crate spawn;
+extern crate scheduler;
+extern crate task;
+extern crate tsc;
+extern crate timekeeping;
+extern crate entropy;
+extern crate crypto;
+
+mod noise;
+mod random;
+mod device;
+
+pub use noise::{Key, KEY_LEN};
+pub use device::TUNNEL_MTU;
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use spin::Mutex;
+use smoltcp::{
+    iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache, Routes},
+    socket::{SocketHandle, SocketSet, UdpPacketMetadata, UdpSocket, UdpSocketBuffer},
+    time::Instant,
+    wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address},
+};
+use network_manager::{routing, stats, NetworkInterface, NetworkInterfaceRef};
+use device::{PacketQueues, PacketQueuesRef, TunnelDevice};
+use noise::{Handshake, Session};
+
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// An initiator starts a new handshake once its session is this old.
+const REKEY_AFTER_TIME: u64 = 120 * NANOS_PER_SEC;
+/// A session is no longer used once it's this old.
+const REJECT_AFTER_TIME: u64 = 180 * NANOS_PER_SEC;
+/// A handshake is given up if it hasn't completed after being retried for this long.
+const REKEY_ATTEMPT_TIME: u64 = 90 * NANOS_PER_SEC;
+/// A handshake initiation is retried if it hasn't been answered within this time.
+const REKEY_TIMEOUT: u64 = 5 * NANOS_PER_SEC;
+/// A keepalive is sent if data was received but nothing was sent back within this time.
+const KEEPALIVE_TIMEOUT: u64 = 10 * NANOS_PER_SEC;
+/// The maximum number of packets that can wait for a session to be established.
+const MAX_PENDING_PACKETS: usize = 64;
+
+/// The TAI64 label of the Unix epoch.
+const TAI64_UNIX_EPOCH: u64 = 0x4000_0000_0000_000A;
+/// The number of seconds from the Unix epoch to the start of the year 2000, which wall-clock time is based on.
+const UNIX_SECONDS_AT_2000: u64 = 946_684_800;
+
+const UDP_BUFFER_PACKETS: usize = 64;
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+
+
+lazy_static! {
+    /// All tunnels that have been created.
+    static ref TUNNELS: Mutex<Vec<Arc<Mutex<Tunnel>>>> = Mutex::new(Vec::new());
+    /// The network interfaces of all tunnels that have been created, in the same order as `TUNNELS`.
+    static ref TUNNEL_INTERFACES: Mutex<Vec<NetworkInterfaceRef>> = Mutex::new(Vec::new());
+}
+
+
+/// The configuration of a tunnel.
+pub struct TunnelConfig {
+    /// The local side's private key.
+    pub private_key: Key,
+    /// The UDP port on which the tunnel receives packets from the peer, or `0` to choose a free port.
+    pub listen_port: u16,
+    /// The local side's IPv4 address inside the tunnel, along with the tunnel's subnet.
+    pub address: IpCidr,
+    /// The peer's public key.
+    pub peer_public_key: Key,
+    /// An optional additional secret that both sides share, which adds a layer of symmetric encryption.
+    pub preshared_key: Option<Key>,
+    /// The peer's UDP endpoint. If `None`, the local side waits until the peer contacts it.
+    pub peer_endpoint: Option<IpEndpoint>,
+    /// The peer's own IPv4 address inside the tunnel, which is used as the gateway for all `allowed_ips`
+    /// that lie outside of the tunnel's subnet. It's only required if there are such `allowed_ips`.
+    pub peer_address: Option<Ipv4Address>,
+    /// The destinations that are reachable through the peer, and the sources that packets from the peer may have.
+    pub allowed_ips: Vec<IpCidr>,
+    /// If `Some`, a keepalive is sent to the peer whenever nothing else was sent for that many seconds,
+    /// which keeps NAT mappings and firewall state between the two sides alive.
+    pub persistent_keepalive_secs: Option<u64>,
+}
+
+/// The state of a tunnel, see [`tunnels()`](fn.tunnels.html).
+#[derive(Debug, Clone)]
+pub struct TunnelStatus {
+    /// The local side's public key.
+    pub public_key: Key,
+    /// The UDP port on which the tunnel receives packets.
+    pub listen_port: u16,
+    /// The local side's address inside the tunnel.
+    pub address: IpCidr,
+    /// The peer's current endpoint, if known.
+    pub peer_endpoint: Option<IpEndpoint>,
+    /// The number of seconds since the last completed handshake, or `None` if there was none yet.
+    pub seconds_since_handshake: Option<u64>,
+    /// The number of bytes received from the peer, including handshakes and tunnel overhead.
+    pub rx_bytes: u64,
+    /// The number of bytes sent to the peer, including handshakes and tunnel overhead.
+    pub tx_bytes: u64,
+}
+
+
+/// Creates a new tunnel with the given configuration, adds its interface to the system's network interfaces,
+/// and routes its `allowed_ips` through it.
+///
+/// The underlying interface is chosen when the tunnel is created, so the route to the peer's endpoint
+/// must already exist and must not lead through the tunnel itself.
+pub fn create_tunnel(config: TunnelConfig) -> Result<NetworkInterfaceRef, &'static str> {
+    let subnet = match config.address {
+        IpCidr::Ipv4(cidr) => cidr,
+        _ => return Err("wireguard: the tunnel's address must be an IPv4 address"),
+    };
+    if config.allowed_ips.is_empty() {
+        return Err("wireguard: the peer must have at least one allowed IP");
+    }
+    let mut gateway_routes = Vec::new();
+    for allowed in &config.allowed_ips {
+        let allowed = match allowed {
+            IpCidr::Ipv4(cidr) => cidr,
+            _ => return Err("wireguard: the peer's allowed IPs must be IPv4 subnets"),
+        };
+        let within_subnet = allowed.prefix_len() >= subnet.prefix_len() && subnet.contains_addr(&allowed.address());
+        if !within_subnet {
+            gateway_routes.push(IpCidr::Ipv4(*allowed));
+        }
+    }
+    let gateway = match config.peer_address {
+        Some(addr) if subnet.contains_addr(&addr) => Some(IpAddress::Ipv4(addr)),
+        Some(_) => return Err("wireguard: the peer's address must lie within the tunnel's subnet"),
+        None if !gateway_routes.is_empty() => {
+            return Err("wireguard: the peer's address is required to route allowed IPs outside of the tunnel's subnet");
+        }
+        None => None,
+    };
+    if let Some(endpoint) = config.peer_endpoint {
+        match endpoint.addr {
+            IpAddress::Ipv4(_) => { }
+            _ => return Err("wireguard: the peer's endpoint must be an IPv4 address"),
+        }
+    }
+
+    let underlay = match config.peer_endpoint {
+        Some(endpoint) => smoltcp_helper::get_iface_for(endpoint.addr)?,
+        None => smoltcp_helper::get_default_iface()?,
+    };
+    let listen_port = match config.listen_port {
+        0 => smoltcp_helper::STARTING_FREE_PORT + (random::random_u32(&config.private_key) % 16384) as u16,
+        port => port,
+    };
+    let mut sockets = SocketSet::new(Vec::with_capacity(1));
+    let udp_handle = {
+        let rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; UDP_BUFFER_PACKETS], vec![0; UDP_BUFFER_SIZE]);
+        let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; UDP_BUFFER_PACKETS], vec![0; UDP_BUFFER_SIZE]);
+        sockets.add(UdpSocket::new(rx_buffer, tx_buffer))
+    };
+    sockets.get::<UdpSocket>(udp_handle).bind(listen_port)
+        .map_err(|_e| "wireguard: couldn't bind the tunnel's UDP socket")?;
+
+    let queues: PacketQueuesRef = Arc::new(Mutex::new(PacketQueues::new()));
+    let iface = EthernetInterfaceBuilder::new(TunnelDevice::new(queues.clone()))
+        .ethernet_addr(device::LOCAL_MAC)
+        .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+        .ip_addrs(vec![config.address])
+        .routes(Routes::new(BTreeMap::new()))
+        .finalize();
+
+    let tunnel = Arc::new(Mutex::new(Tunnel {
+        handshake: Handshake::new(config.private_key, config.peer_public_key, config.preshared_key),
+        private_key: config.private_key,
+        current: None,
+        previous: None,
+        next: None,
+        address: config.address,
+        endpoint: config.peer_endpoint,
+        allowed_ips: config.allowed_ips,
+        persistent_keepalive: config.persistent_keepalive_secs.map(|secs| secs * NANOS_PER_SEC),
+        last_timestamp: (0, 0),
+        first_initiation_ns: None,
+        last_initiation_ns: None,
+        last_handshake_ns: None,
+        last_sent_ns: 0,
+        last_data_received_ns: 0,
+        pending_packets: VecDeque::new(),
+        queues: queues.clone(),
+        underlay,
+        sockets,
+        udp_handle,
+        listen_port,
+        rx_bytes: 0,
+        tx_bytes: 0,
+    }));
+
+    let iface_ref = network_manager::add_to_network_interfaces(TunnelInterface { iface, tunnel: tunnel.clone(), queues });
+    for destination in gateway_routes {
+        routing::add_route(destination, gateway, routing::DEFAULT_ROUTE_METRIC, &iface_ref)?;
+    }
+    TUNNELS.lock().push(tunnel.clone());
+    TUNNEL_INTERFACES.lock().push(iface_ref.clone());
+
+    spawn::new_task_builder(tunnel_loop, tunnel)
+        .name(format!("wireguard_{}", listen_port))
+        .spawn()?;
+    info!("Created WireGuard tunnel {} on UDP port {}", config.address, listen_port);
+    Ok(iface_ref)
+}
+
+/// Returns the state of all tunnels that have been created.
+pub fn tunnels() -> Vec<TunnelStatus> {
+    let now = now_ns();
+    TUNNELS.lock().iter().map(|tunnel| {
+        let tunnel = tunnel.lock();
+        TunnelStatus {
+            public_key: tunnel.handshake.local_public_key(),
+            listen_port: tunnel.listen_port,
+            address: tunnel.address,
+            peer_endpoint: tunnel.endpoint,
+            seconds_since_handshake: tunnel.last_handshake_ns.map(|t| now.saturating_sub(t) / NANOS_PER_SEC),
+            rx_bytes: tunnel.rx_bytes,
+            tx_bytes: tunnel.tx_bytes,
+        }
+    }).collect()
+}
+
+/// Returns the network interfaces of all tunnels that have been created, in the order they were created.
+pub fn tunnel_interfaces() -> Vec<NetworkInterfaceRef> {
+    TUNNEL_INTERFACES.lock().clone()
+}
+
+/// Returns whether the given network interface belongs to a tunnel.
+///
+/// Every packet that such an interface receives was decrypted and authenticated as coming from the tunnel's peer,
+/// so services that must only be reachable by that peer can listen on it exclusively.
+pub fn is_tunnel_interface(iface: &NetworkInterfaceRef) -> bool {
+    TUNNEL_INTERFACES.lock().iter().any(|tunnel_iface| Arc::ptr_eq(tunnel_iface, iface))
+}
+
+/// Creates a new random private key.
+pub fn generate_private_key() -> Key {
+    let mut key = random::random_key(&[]);
+    // Clamp the key as described for Curve25519, like `wg genkey` does.
+    key[0] &= 248;
+    key[31] &= 127;
+    key[31] |= 64;
+    key
+}
+
+/// Returns the public key that belongs to the given `private_key`.
+pub fn public_key(private_key: &Key) -> Key {
+    crypto::x25519::x25519_public_key(private_key)
+}
+
+const BASE64_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Formats the given key in base64, which is how WireGuard represents keys.
+pub fn format_key(key: &Key) -> String {
+    let mut out = String::with_capacity(44);
+    for chunk in key.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0 .. 4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Parses a key that is formatted in base64, as produced by [`format_key()`](fn.format_key.html) or `wg genkey`.
+pub fn parse_key(s: &str) -> Result<Key, &'static str> {
+    let s = s.trim();
+    if s.len() != 44 || !s.ends_with('=') {
+        return Err("wireguard: a key must be 44 characters of base64");
+    }
+    let mut bits: u32 = 0;
+    let mut num_bits = 0;
+    let mut key = [0u8; KEY_LEN];
+    let mut len = 0;
+    for c in s.trim_end_matches('=').bytes() {
+        let value = BASE64_ALPHABET.iter().position(|&a| a == c).ok_or("wireguard: a key must be 44 characters of base64")?;
+        bits = (bits << 6) | value as u32;
+        num_bits += 6;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            if len == KEY_LEN {
+                return Err("wireguard: a key must be 44 characters of base64");
+            }
+            key[len] = (bits >> num_bits) as u8;
+            len += 1;
+        }
+    }
+    if len != KEY_LEN {
+        return Err("wireguard: a key must be 44 characters of base64");
+    }
+    Ok(key)
+}
+
+
+/// The network interface of a tunnel, which is a smoltcp interface on top of a `TunnelDevice`.
+struct TunnelInterface {
+    iface: EthernetInterface<'static, 'static, 'static, TunnelDevice>,
+    tunnel: Arc<Mutex<Tunnel>>,
+    queues: PacketQueuesRef,
+}
+
+impl NetworkInterface for TunnelInterface {
+    fn ethernet_addr(&self) -> EthernetAddress {
+        self.iface.ethernet_addr()
+    }
+
+    fn set_ethernet_addr(&mut self, addr: EthernetAddress) {
+        self.queues.lock().local_mac = addr;
+        self.iface.set_ethernet_addr(addr)
+    }
+
+    fn poll(&mut self, sockets: &mut SocketSet, timestamp: Instant) -> smoltcp::Result<bool> {
+        // Receive packets from the peer first, then send the packets that the interface produced.
+        self.tunnel.lock().service();
+        let result = self.iface.poll(sockets, timestamp);
+        self.tunnel.lock().service();
+        result
+    }
+
+    fn ip_addrs(&self) -> &[IpCidr] {
+        self.iface.ip_addrs()
+    }
+
+    fn has_ip_addr(&self, addr: IpAddress) -> bool {
+        self.iface.has_ip_addr(addr)
+    }
+
+    fn routes(&self) -> &Routes<'static> {
+        self.iface.routes()
+    }
+
+    fn routes_mut(&mut self) -> &mut Routes<'static> {
+        self.iface.routes_mut()
+    }
+
+    /// Encrypts and sends the packets that the interface produced but the tunnel hasn't sent yet.
+    fn quiesce(&mut self) -> Result<(), &'static str> {
+        self.tunnel.lock().service();
+        Ok(())
+    }
+}
+
+
+/// Which of a tunnel's sessions a received message belongs to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Current,
+    Next,
+    Previous,
+}
+
+/// The cryptographic and networking state of a tunnel.
+struct Tunnel {
+    handshake: Handshake,
+    private_key: Key,
+    /// The session used for sending.
+    current: Option<Session>,
+    /// The session that `current` replaced, which is kept to receive packets that were sent before the replacement.
+    previous: Option<Session>,
+    /// A session that the peer initiated, which is only used for sending once the peer has sent a packet on it,
+    /// since that confirms that the peer has received the handshake response.
+    next: Option<Session>,
+    address: IpCidr,
+    endpoint: Option<IpEndpoint>,
+    allowed_ips: Vec<IpCidr>,
+    persistent_keepalive: Option<u64>,
+    /// The seconds and nanoseconds of the TAI64N timestamp in the last initiation that was sent.
+    last_timestamp: (u64, u32),
+    /// When the first initiation of the current handshake attempt was sent.
+    first_initiation_ns: Option<u64>,
+    last_initiation_ns: Option<u64>,
+    last_handshake_ns: Option<u64>,
+    last_sent_ns: u64,
+    last_data_received_ns: u64,
+    /// Packets that are waiting for a session to be established.
+    pending_packets: VecDeque<Vec<u8>>,
+    queues: PacketQueuesRef,
+    underlay: NetworkInterfaceRef,
+    sockets: SocketSet<'static, 'static, 'static>,
+    udp_handle: SocketHandle,
+    listen_port: u16,
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+impl Tunnel {
+    /// Receives and handles all messages from the peer, sends all packets that the tunnel's interface has produced,
+    /// and handles the tunnel's timers.
+    fn service(&mut self) {
+        let now = now_ns();
+        self.poll_underlay(now);
+        loop {
+            let (datagram, source) = {
+                let mut socket = self.sockets.get::<UdpSocket>(self.udp_handle);
+                match socket.recv() {
+                    Ok((data, source)) => (data.to_vec(), source),
+                    Err(_) => break,
+                }
+            };
+            self.rx_bytes += datagram.len() as u64;
+            self.handle_datagram(&datagram, source, now);
+        }
+
+        self.expire_sessions(now);
+        let packets: Vec<Vec<u8>> = self.queues.lock().outgoing.drain(..).collect();
+        for packet in packets {
+            self.send_packet(packet, now);
+        }
+        self.handle_timers(now);
+        self.poll_underlay(now);
+    }
+
+    fn poll_underlay(&mut self, now: u64) {
+        let timestamp = Instant::from_millis((now / 1_000_000) as i64);
+        if let Err(_e) = self.underlay.lock().poll(&mut self.sockets, timestamp) {
+            debug!("wireguard: error polling the underlying interface: {}", _e);
+        }
+        stats::record_sockets(&self.sockets, task::get_my_current_task_id());
+    }
+
+    fn handle_datagram(&mut self, datagram: &[u8], source: IpEndpoint, now: u64) {
+        match datagram.first().cloned() {
+            Some(noise::MESSAGE_INITIATION) => {
+                let local_index = random::random_u32(&self.private_key);
+                let ephemeral = random::random_key(&self.private_key);
+                match self.handshake.consume_initiation(datagram, local_index, ephemeral, now) {
+                    Ok((response, session)) => {
+                        debug!("wireguard: received handshake initiation from {}", source);
+                        self.endpoint = Some(source);
+                        self.next = Some(session);
+                        self.last_handshake_ns = Some(now);
+                        self.send_datagram(&response, now);
+                    }
+                    Err(_e) => debug!("wireguard: dropping handshake initiation from {}: {}", source, _e),
+                }
+            }
+            Some(noise::MESSAGE_RESPONSE) => {
+                match self.handshake.consume_response(datagram, now) {
+                    Ok(session) => {
+                        debug!("wireguard: completed handshake with {}", source);
+                        self.endpoint = Some(source);
+                        self.install_session(session, now);
+                        // Confirm the new session to the peer, with a keepalive if there's nothing else to send.
+                        if self.pending_packets.is_empty() {
+                            self.send_transport(&[], now);
+                        }
+                        self.send_pending_packets(now);
+                    }
+                    Err(_e) => debug!("wireguard: dropping handshake response from {}: {}", source, _e),
+                }
+            }
+            Some(noise::MESSAGE_COOKIE_REPLY) => debug!("wireguard: ignoring cookie reply from {}", source),
+            Some(noise::MESSAGE_TRANSPORT) => self.handle_transport(datagram, source, now),
+            _ => debug!("wireguard: dropping unknown message from {}", source),
+        }
+    }
+
+    fn handle_transport(&mut self, msg: &[u8], source: IpEndpoint, now: u64) {
+        let index = match noise::transport_receiver_index(msg) {
+            Some(index) => index,
+            None => return,
+        };
+        let has_index = |session: &Option<Session>| session.as_ref().map_or(false, |s| s.local_index == index);
+        let slot = if has_index(&self.current) {
+            Some(Slot::Current)
+        } else if has_index(&self.next) {
+            Some(Slot::Next)
+        } else if has_index(&self.previous) {
+            Some(Slot::Previous)
+        } else {
+            None
+        };
+        let session = match slot {
+            Some(Slot::Current) => self.current.as_mut(),
+            Some(Slot::Next) => self.next.as_mut(),
+            Some(Slot::Previous) => self.previous.as_mut(),
+            None => None,
+        };
+        let packet = match session.map(|s| s.decrypt(msg)) {
+            Some(Ok(packet)) => packet,
+            Some(Err(_e)) => {
+                debug!("wireguard: dropping transport message from {}: {}", source, _e);
+                return;
+            }
+            None => {
+                debug!("wireguard: dropping transport message from {} for an unknown session", source);
+                return;
+            }
+        };
+        self.endpoint = Some(source);
+
+        if slot == Some(Slot::Next) {
+            // The peer has confirmed the session that it initiated, so it replaces the current one.
+            let session = self.next.take();
+            self.previous = self.current.take();
+            self.current = session;
+            self.send_pending_packets(now);
+        }
+        // A responder's session is about to expire, and only the initiator rekeys it based on time.
+        let needs_rekey = self.current.as_ref().map_or(false, |s| {
+            !s.is_initiator && now.saturating_sub(s.created_ns) >= REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
+        });
+        if needs_rekey {
+            self.initiate_handshake(now);
+        }
+
+        if packet.is_empty() {
+            // a keepalive
+            return;
+        }
+        self.last_data_received_ns = now;
+        // Strip the padding, and make sure that the peer is allowed to send from the packet's source address.
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            debug!("wireguard: dropping non-IPv4 packet from the peer");
+            return;
+        }
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if total_len < 20 || total_len > packet.len() {
+            return;
+        }
+        let src = IpAddress::Ipv4(Ipv4Address::from_bytes(&packet[12..16]));
+        if !self.allowed_ips.iter().any(|cidr| cidr.contains_addr(&src)) {
+            debug!("wireguard: dropping packet from disallowed source {}", src);
+            return;
+        }
+        if !self.queues.lock().receive_from_peer(&packet[..total_len]) {
+            debug!("wireguard: dropping packet from the peer because the receive queue is full");
+        }
+    }
+
+    /// Sends the given IP packet from the tunnel's interface to the peer,
+    /// or queues it until a session has been established.
+    fn send_packet(&mut self, packet: Vec<u8>, now: u64) {
+        if packet.len() < 20 {
+            return;
+        }
+        let dst = IpAddress::Ipv4(Ipv4Address::from_bytes(&packet[16..20]));
+        if !self.allowed_ips.iter().any(|cidr| cidr.contains_addr(&dst)) {
+            debug!("wireguard: dropping packet to {}, which isn't one of the peer's allowed IPs", dst);
+            return;
+        }
+        if self.current.is_some() {
+            self.send_transport(&packet, now);
+            let needs_rekey = self.current.as_ref().map_or(false, |s| s.is_initiator && now.saturating_sub(s.created_ns) >= REKEY_AFTER_TIME);
+            if needs_rekey {
+                self.initiate_handshake(now);
+            }
+        } else {
+            if self.pending_packets.len() >= MAX_PENDING_PACKETS {
+                self.pending_packets.pop_front();
+            }
+            self.pending_packets.push_back(packet);
+            self.initiate_handshake(now);
+        }
+    }
+
+    fn send_pending_packets(&mut self, now: u64) {
+        while let Some(packet) = self.pending_packets.pop_front() {
+            self.send_transport(&packet, now);
+        }
+    }
+
+    /// Encrypts the given packet with the current session and sends it to the peer. An empty packet is a keepalive.
+    fn send_transport(&mut self, packet: &[u8], now: u64) {
+        let msg = match self.current.as_mut().map(|s| s.encrypt(packet)) {
+            Some(Ok(msg)) => msg,
+            Some(Err(e)) => {
+                warn!("wireguard: {}", e);
+                self.current = None;
+                return;
+            }
+            None => return,
+        };
+        self.send_datagram(&msg, now);
+    }
+
+    fn send_datagram(&mut self, msg: &[u8], now: u64) {
+        let endpoint = match self.endpoint {
+            Some(endpoint) => endpoint,
+            None => return,
+        };
+        let mut socket = self.sockets.get::<UdpSocket>(self.udp_handle);
+        match socket.send_slice(msg, endpoint) {
+            Ok(()) => {
+                self.tx_bytes += msg.len() as u64;
+                self.last_sent_ns = now;
+            }
+            Err(_e) => debug!("wireguard: couldn't send {} bytes to {}: {}", msg.len(), endpoint, _e),
+        }
+    }
+
+    /// Sends a handshake initiation to the peer, unless one was sent too recently or the handshake has been given up.
+    fn initiate_handshake(&mut self, now: u64) {
+        if self.endpoint.is_none() {
+            return;
+        }
+        if self.last_initiation_ns.map_or(false, |t| now.saturating_sub(t) < REKEY_TIMEOUT) {
+            return;
+        }
+        let first = *self.first_initiation_ns.get_or_insert(now);
+        if now.saturating_sub(first) >= REKEY_ATTEMPT_TIME {
+            warn!("wireguard: giving up on handshake with {:?} after {} seconds", self.endpoint, REKEY_ATTEMPT_TIME / NANOS_PER_SEC);
+            self.handshake.cancel();
+            self.pending_packets.clear();
+            self.first_initiation_ns = None;
+            self.last_initiation_ns = None;
+            return;
+        }
+
+        let local_index = random::random_u32(&self.private_key);
+        let ephemeral = random::random_key(&self.private_key);
+        let timestamp = self.next_timestamp();
+        let msg = self.handshake.create_initiation(local_index, ephemeral, timestamp);
+        debug!("wireguard: sending handshake initiation to {:?}", self.endpoint);
+        self.send_datagram(&msg, now);
+        self.last_initiation_ns = Some(now);
+    }
+
+    /// Makes the given session that the local side initiated the current one.
+    fn install_session(&mut self, session: Session, now: u64) {
+        self.previous = self.current.take();
+        self.current = Some(session);
+        self.next = None;
+        self.first_initiation_ns = None;
+        self.last_initiation_ns = None;
+        self.last_handshake_ns = Some(now);
+    }
+
+    fn expire_sessions(&mut self, now: u64) {
+        let is_expired = |session: &Option<Session>| session.as_ref().map_or(false, |s| now.saturating_sub(s.created_ns) >= REJECT_AFTER_TIME);
+        if is_expired(&self.current) {
+            self.current = None;
+        }
+        if is_expired(&self.next) {
+            self.next = None;
+        }
+        if is_expired(&self.previous) {
+            self.previous = None;
+        }
+    }
+
+    /// Retries unanswered handshakes and sends keepalives.
+    fn handle_timers(&mut self, now: u64) {
+        if self.current.is_none() {
+            let wants_session = !self.pending_packets.is_empty() || self.handshake.is_pending() || self.persistent_keepalive.is_some();
+            if wants_session {
+                self.initiate_handshake(now);
+            }
+            return;
+        }
+        let persistent_keepalive_due = self.persistent_keepalive.map_or(false, |interval| now.saturating_sub(self.last_sent_ns) >= interval);
+        let passive_keepalive_due = self.last_data_received_ns > self.last_sent_ns
+            && now.saturating_sub(self.last_data_received_ns) >= KEEPALIVE_TIMEOUT;
+        if persistent_keepalive_due || passive_keepalive_due {
+            self.send_transport(&[], now);
+        }
+    }
+
+    /// Returns a TAI64N timestamp of the current time that is greater than the previous one.
+    fn next_timestamp(&mut self) -> [u8; noise::TIMESTAMP_LEN] {
+        let seconds = TAI64_UNIX_EPOCH + UNIX_SECONDS_AT_2000 + timekeeping::wall_clock_seconds().unwrap_or(0);
+        let (last_seconds, last_nanos) = self.last_timestamp;
+        self.last_timestamp = if seconds > last_seconds { (seconds, 0) } else { (last_seconds, last_nanos + 1) };
+        let mut timestamp = [0u8; noise::TIMESTAMP_LEN];
+        timestamp[..8].copy_from_slice(&self.last_timestamp.0.to_be_bytes());
+        timestamp[8..].copy_from_slice(&self.last_timestamp.1.to_be_bytes());
+        timestamp
+    }
+}
+
+
+/// The entry point of a tunnel's background task, which services the tunnel forever.
+fn tunnel_loop(tunnel: Arc<Mutex<Tunnel>>) -> Result<(), &'static str> {
+    loop {
+        tunnel.lock().service();
+        scheduler::schedule();
+    }
+}
+
+/// Returns the monotonic time in nanoseconds, on which all of a tunnel's timers are based.
+fn now_ns() -> u64 {
+    tsc::tsc_ticks().to_ns().unwrap_or(0)
+}
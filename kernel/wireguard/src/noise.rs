@@ -0,0 +1,448 @@
+//! The cryptographic protocol of WireGuard: the Noise_IKpsk2 handshake and the transport data messages,
+//! as described in the [WireGuard whitepaper](https://www.wireguard.com/papers/wireguard.pdf).
+//!
+//! Cookie reply messages (for DoS protection under load) are not supported,
+//! so `mac2` is always zero and received cookie replies are ignored.
+
+use alloc::vec::Vec;
+use crypto::blake2s::Blake2s;
+use crypto::chacha20poly1305::{ChaCha20Poly1305, CHACHA20_NONCE_SIZE, POLY1305_TAG_SIZE};
+use crypto::hmac::hmac_blake2s;
+use crypto::util::constant_time_eq;
+use crypto::x25519::{x25519, x25519_public_key};
+
+
+/// The length of every key: private, public, preshared, and symmetric ones.
+pub const KEY_LEN: usize = 32;
+/// A private, public, preshared, or symmetric key.
+pub type Key = [u8; KEY_LEN];
+
+const CONSTRUCTION: &'static [u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &'static [u8] = b"WireGuard v1 zx2c4 Jason@zx2c4.com";
+const LABEL_MAC1: &'static [u8] = b"mac1----";
+
+pub const MESSAGE_INITIATION: u8 = 1;
+pub const MESSAGE_RESPONSE: u8 = 2;
+pub const MESSAGE_COOKIE_REPLY: u8 = 3;
+pub const MESSAGE_TRANSPORT: u8 = 4;
+
+pub const INITIATION_LEN: usize = 148;
+pub const RESPONSE_LEN: usize = 92;
+/// The length of a transport message's header, which is followed by the encrypted packet.
+pub const TRANSPORT_HEADER_LEN: usize = 16;
+/// The length of the authentication tag that is appended to every encrypted message.
+const TAG_LEN: usize = POLY1305_TAG_SIZE;
+const MAC_LEN: usize = 16;
+/// The length of a TAI64N timestamp.
+pub const TIMESTAMP_LEN: usize = 12;
+
+/// A session's keys must not be used to send more than this many messages.
+const REJECT_AFTER_MESSAGES: u64 = core::u64::MAX - (1 << 13);
+/// The number of most recent counters that the replay window keeps track of.
+const REPLAY_WINDOW_SIZE: u64 = 64;
+
+
+/// The long-term keys of the local side and of its peer, and the state of an ongoing handshake.
+pub struct Handshake {
+    local_private: Key,
+    local_public: Key,
+    peer_public: Key,
+    preshared_key: Key,
+    /// The key for the `mac1` of messages that are sent to the peer.
+    peer_mac1_key: Key,
+    /// The key for the `mac1` of messages that are received from the peer.
+    local_mac1_key: Key,
+    /// The timestamp of the most recent initiation from the peer, which later initiations must exceed.
+    last_peer_timestamp: [u8; TIMESTAMP_LEN],
+    /// The state of the initiation that was sent to the peer, if it hasn't been answered yet.
+    pending: Option<PendingInitiation>,
+}
+
+/// The handshake state that the initiator keeps until it receives the response.
+struct PendingInitiation {
+    local_index: u32,
+    hash: Key,
+    chaining_key: Key,
+    ephemeral_private: Key,
+}
+
+impl Handshake {
+    pub fn new(local_private: Key, peer_public: Key, preshared_key: Option<Key>) -> Handshake {
+        let local_public = x25519_public_key(&local_private);
+        Handshake {
+            peer_mac1_key: hash(&[LABEL_MAC1, &peer_public]),
+            local_mac1_key: hash(&[LABEL_MAC1, &local_public]),
+            local_private,
+            local_public,
+            peer_public,
+            preshared_key: preshared_key.unwrap_or([0; KEY_LEN]),
+            last_peer_timestamp: [0; TIMESTAMP_LEN],
+            pending: None,
+        }
+    }
+
+    pub fn local_public_key(&self) -> Key {
+        self.local_public
+    }
+
+    /// Returns whether an initiation has been sent that hasn't been answered yet.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Forgets the initiation that was sent last, such that a response to it is no longer accepted.
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+
+    /// Creates a handshake initiation message to be sent to the peer.
+    ///
+    /// The `local_index` identifies the resulting session in the peer's messages,
+    /// the `ephemeral_private` key must be random, and the `timestamp` must be greater than any previous one.
+    pub fn create_initiation(&mut self, local_index: u32, ephemeral_private: Key, timestamp: [u8; TIMESTAMP_LEN]) -> Vec<u8> {
+        let ephemeral_public = x25519_public_key(&ephemeral_private);
+
+        let mut chaining_key = hash(&[CONSTRUCTION]);
+        let mut h = hash(&[&chaining_key, IDENTIFIER]);
+        h = hash(&[&h, &self.peer_public]);
+        chaining_key = kdf1(&chaining_key, &ephemeral_public);
+        h = hash(&[&h, &ephemeral_public]);
+
+        let (ck, key) = kdf2(&chaining_key, &x25519(&ephemeral_private, &self.peer_public));
+        chaining_key = ck;
+        let encrypted_static = aead_encrypt(&key, 0, &self.local_public, &h);
+        h = hash(&[&h, &encrypted_static]);
+
+        let (ck, key) = kdf2(&chaining_key, &x25519(&self.local_private, &self.peer_public));
+        chaining_key = ck;
+        let encrypted_timestamp = aead_encrypt(&key, 0, &timestamp, &h);
+        h = hash(&[&h, &encrypted_timestamp]);
+
+        let mut msg = Vec::with_capacity(INITIATION_LEN);
+        msg.extend_from_slice(&[MESSAGE_INITIATION, 0, 0, 0]);
+        msg.extend_from_slice(&local_index.to_le_bytes());
+        msg.extend_from_slice(&ephemeral_public);
+        msg.extend_from_slice(&encrypted_static);
+        msg.extend_from_slice(&encrypted_timestamp);
+        let mac1 = mac(&self.peer_mac1_key, &msg);
+        msg.extend_from_slice(&mac1);
+        msg.extend_from_slice(&[0; MAC_LEN]);
+
+        self.pending = Some(PendingInitiation { local_index, hash: h, chaining_key, ephemeral_private });
+        msg
+    }
+
+    /// Processes the peer's response to the pending initiation, and returns the resulting session.
+    pub fn consume_response(&mut self, msg: &[u8], now_ns: u64) -> Result<Session, &'static str> {
+        if msg.len() != RESPONSE_LEN || msg[0] != MESSAGE_RESPONSE {
+            return Err("wireguard: malformed handshake response");
+        }
+        self.check_mac1(msg)?;
+        let remote_index = read_u32(msg, 4);
+        let receiver_index = read_u32(msg, 8);
+        match self.pending {
+            Some(ref p) if p.local_index == receiver_index => { }
+            _ => return Err("wireguard: handshake response doesn't match a pending initiation"),
+        }
+        let pending = self.pending.take().ok_or("wireguard: no pending initiation")?;
+        let ephemeral_public = read_key(msg, 12);
+
+        let mut chaining_key = kdf1(&pending.chaining_key, &ephemeral_public);
+        let mut h = hash(&[&pending.hash, &ephemeral_public]);
+        chaining_key = kdf1(&chaining_key, &x25519(&pending.ephemeral_private, &ephemeral_public));
+        chaining_key = kdf1(&chaining_key, &x25519(&self.local_private, &ephemeral_public));
+        let (ck, tau, key) = kdf3(&chaining_key, &self.preshared_key);
+        chaining_key = ck;
+        h = hash(&[&h, &tau]);
+        let encrypted_nothing = &msg[44 .. 44 + TAG_LEN];
+        aead_decrypt(&key, 0, encrypted_nothing, &h).map_err(|_| {
+            // The initiation was answered, just not validly, so it can't be accepted anymore.
+            "wireguard: handshake response failed to decrypt"
+        })?;
+
+        let (send_key, recv_key) = kdf2(&chaining_key, &[]);
+        Ok(Session::new(pending.local_index, remote_index, send_key, recv_key, true, now_ns))
+    }
+
+    /// Processes an initiation from the peer, and returns the response to send back along with the resulting session.
+    ///
+    /// The `local_index` identifies the resulting session in the peer's messages,
+    /// and the `ephemeral_private` key must be random.
+    pub fn consume_initiation(&mut self, msg: &[u8], local_index: u32, ephemeral_private: Key, now_ns: u64)
+        -> Result<(Vec<u8>, Session), &'static str>
+    {
+        if msg.len() != INITIATION_LEN || msg[0] != MESSAGE_INITIATION {
+            return Err("wireguard: malformed handshake initiation");
+        }
+        self.check_mac1(msg)?;
+        let remote_index = read_u32(msg, 4);
+        let peer_ephemeral = read_key(msg, 8);
+
+        let mut chaining_key = hash(&[CONSTRUCTION]);
+        let mut h = hash(&[&chaining_key, IDENTIFIER]);
+        h = hash(&[&h, &self.local_public]);
+        chaining_key = kdf1(&chaining_key, &peer_ephemeral);
+        h = hash(&[&h, &peer_ephemeral]);
+
+        let (ck, key) = kdf2(&chaining_key, &x25519(&self.local_private, &peer_ephemeral));
+        chaining_key = ck;
+        let encrypted_static = &msg[40 .. 40 + KEY_LEN + TAG_LEN];
+        let peer_static = aead_decrypt(&key, 0, encrypted_static, &h)
+            .map_err(|_| "wireguard: handshake initiation failed to decrypt")?;
+        if peer_static[..] != self.peer_public[..] {
+            return Err("wireguard: handshake initiation is from an unknown peer");
+        }
+        h = hash(&[&h, encrypted_static]);
+
+        let (ck, key) = kdf2(&chaining_key, &x25519(&self.local_private, &self.peer_public));
+        chaining_key = ck;
+        let encrypted_timestamp = &msg[88 .. 88 + TIMESTAMP_LEN + TAG_LEN];
+        let timestamp = aead_decrypt(&key, 0, encrypted_timestamp, &h)
+            .map_err(|_| "wireguard: handshake initiation failed to decrypt")?;
+        // Timestamps are big-endian, so they can be compared bytewise.
+        if timestamp[..] <= self.last_peer_timestamp[..] {
+            return Err("wireguard: handshake initiation was replayed");
+        }
+        self.last_peer_timestamp.copy_from_slice(&timestamp);
+        h = hash(&[&h, encrypted_timestamp]);
+
+        // Now create the response.
+        let ephemeral_public = x25519_public_key(&ephemeral_private);
+        chaining_key = kdf1(&chaining_key, &ephemeral_public);
+        h = hash(&[&h, &ephemeral_public]);
+        chaining_key = kdf1(&chaining_key, &x25519(&ephemeral_private, &peer_ephemeral));
+        chaining_key = kdf1(&chaining_key, &x25519(&ephemeral_private, &self.peer_public));
+        let (ck, tau, key) = kdf3(&chaining_key, &self.preshared_key);
+        chaining_key = ck;
+        h = hash(&[&h, &tau]);
+        let encrypted_nothing = aead_encrypt(&key, 0, &[], &h);
+
+        let mut response = Vec::with_capacity(RESPONSE_LEN);
+        response.extend_from_slice(&[MESSAGE_RESPONSE, 0, 0, 0]);
+        response.extend_from_slice(&local_index.to_le_bytes());
+        response.extend_from_slice(&remote_index.to_le_bytes());
+        response.extend_from_slice(&ephemeral_public);
+        response.extend_from_slice(&encrypted_nothing);
+        let mac1 = mac(&self.peer_mac1_key, &response);
+        response.extend_from_slice(&mac1);
+        response.extend_from_slice(&[0; MAC_LEN]);
+
+        let (recv_key, send_key) = kdf2(&chaining_key, &[]);
+        Ok((response, Session::new(local_index, remote_index, send_key, recv_key, false, now_ns)))
+    }
+
+    /// Checks the `mac1` of a handshake message that was received from the peer, which precedes the `mac2` at its end.
+    fn check_mac1(&self, msg: &[u8]) -> Result<(), &'static str> {
+        let mac1_offset = msg.len() - 2 * MAC_LEN;
+        let expected = mac(&self.local_mac1_key, &msg[.. mac1_offset]);
+        if !constant_time_eq(&expected, &msg[mac1_offset .. mac1_offset + MAC_LEN]) {
+            return Err("wireguard: handshake message has an invalid mac1");
+        }
+        Ok(())
+    }
+}
+
+
+/// The symmetric keys and counters of an established session, which are used for transport data messages.
+pub struct Session {
+    pub local_index: u32,
+    remote_index: u32,
+    send_cipher: ChaCha20Poly1305,
+    recv_cipher: ChaCha20Poly1305,
+    send_counter: u64,
+    replay: ReplayWindow,
+    /// Whether the local side initiated the handshake that created this session.
+    pub is_initiator: bool,
+    pub created_ns: u64,
+}
+
+impl Session {
+    fn new(local_index: u32, remote_index: u32, send_key: Key, recv_key: Key, is_initiator: bool, created_ns: u64) -> Session {
+        Session {
+            local_index,
+            remote_index,
+            send_cipher: ChaCha20Poly1305::new(&send_key),
+            recv_cipher: ChaCha20Poly1305::new(&recv_key),
+            send_counter: 0,
+            replay: ReplayWindow { greatest: 0, bitmap: 0 },
+            is_initiator,
+            created_ns,
+        }
+    }
+
+    /// Encrypts the given `packet`, which is padded to a multiple of 16 bytes first, into a transport message.
+    /// An empty `packet` is a keepalive.
+    pub fn encrypt(&mut self, packet: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if self.send_counter >= REJECT_AFTER_MESSAGES {
+            return Err("wireguard: session has sent too many messages");
+        }
+        let counter = self.send_counter;
+        self.send_counter += 1;
+
+        let padded_len = (packet.len() + 15) / 16 * 16;
+        let mut msg = Vec::with_capacity(TRANSPORT_HEADER_LEN + padded_len + TAG_LEN);
+        msg.extend_from_slice(&[MESSAGE_TRANSPORT, 0, 0, 0]);
+        msg.extend_from_slice(&self.remote_index.to_le_bytes());
+        msg.extend_from_slice(&counter.to_le_bytes());
+        msg.extend_from_slice(packet);
+        msg.resize(TRANSPORT_HEADER_LEN + padded_len, 0);
+        let tag = self.send_cipher.encrypt_in_place(&nonce(counter), &[], &mut msg[TRANSPORT_HEADER_LEN ..])
+            .map_err(|_| "wireguard: failed to encrypt packet")?;
+        msg.extend_from_slice(&tag);
+        Ok(msg)
+    }
+
+    /// Decrypts the given transport message, which must be addressed to this session,
+    /// and returns the padded packet that it contains.
+    pub fn decrypt(&mut self, msg: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if msg.len() < TRANSPORT_HEADER_LEN + TAG_LEN || msg[0] != MESSAGE_TRANSPORT {
+            return Err("wireguard: malformed transport message");
+        }
+        let counter = read_u64(msg, 8);
+        if !self.replay.is_fresh(counter) {
+            return Err("wireguard: transport message was replayed");
+        }
+        let packet = aead_open(&self.recv_cipher, counter, &msg[TRANSPORT_HEADER_LEN ..], &[])
+            .map_err(|_| "wireguard: transport message failed to decrypt")?;
+        // Only authenticated messages may advance the window.
+        self.replay.mark(counter);
+        Ok(packet)
+    }
+
+    /// Returns whether this session has received any transport message from the peer.
+    pub fn has_received(&self) -> bool {
+        self.replay.bitmap != 0
+    }
+}
+
+/// Returns the receiver index of the given transport message, or `None` if it's malformed.
+pub fn transport_receiver_index(msg: &[u8]) -> Option<u32> {
+    if msg.len() < TRANSPORT_HEADER_LEN + TAG_LEN {
+        return None;
+    }
+    Some(read_u32(msg, 4))
+}
+
+
+/// Keeps track of the counters of recently received messages, such that each one is accepted only once.
+struct ReplayWindow {
+    /// The greatest counter received so far.
+    greatest: u64,
+    /// Bit `i` is set if the counter `greatest - i` has been received.
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, counter: u64) -> bool {
+        if self.bitmap == 0 || counter > self.greatest {
+            return true;
+        }
+        let age = self.greatest - counter;
+        age < REPLAY_WINDOW_SIZE && self.bitmap & (1 << age) == 0
+    }
+
+    fn mark(&mut self, counter: u64) {
+        if self.bitmap == 0 {
+            self.greatest = counter;
+            self.bitmap = 1;
+        } else if counter > self.greatest {
+            let shift = counter - self.greatest;
+            self.bitmap = if shift < REPLAY_WINDOW_SIZE { (self.bitmap << shift) | 1 } else { 1 };
+            self.greatest = counter;
+        } else {
+            self.bitmap |= 1 << (self.greatest - counter);
+        }
+    }
+}
+
+
+/// Computes the BLAKE2s hash of the concatenation of the given `parts`.
+pub fn hash(parts: &[&[u8]]) -> Key {
+    let mut hasher = Blake2s::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+/// Computes the keyed BLAKE2s hash of the given `input` with a 16-byte output.
+fn mac(key: &Key, input: &[u8]) -> [u8; MAC_LEN] {
+    let mut hasher = Blake2s::new_keyed(key, MAC_LEN)
+        .expect("BLAKE2s supports 32-byte keys and 16-byte outputs");
+    hasher.update(input);
+    let mut out = [0u8; MAC_LEN];
+    out.copy_from_slice(&hasher.finalize()[.. MAC_LEN]);
+    out
+}
+
+fn kdf1(chaining_key: &Key, input: &[u8]) -> Key {
+    let prk = hmac_blake2s(chaining_key, &[input]);
+    hmac_blake2s(&prk, &[&[1]])
+}
+
+fn kdf2(chaining_key: &Key, input: &[u8]) -> (Key, Key) {
+    let prk = hmac_blake2s(chaining_key, &[input]);
+    let t1 = hmac_blake2s(&prk, &[&[1]]);
+    let t2 = hmac_blake2s(&prk, &[&t1, &[2]]);
+    (t1, t2)
+}
+
+fn kdf3(chaining_key: &Key, input: &[u8]) -> (Key, Key, Key) {
+    let prk = hmac_blake2s(chaining_key, &[input]);
+    let t1 = hmac_blake2s(&prk, &[&[1]]);
+    let t2 = hmac_blake2s(&prk, &[&t1, &[2]]);
+    let t3 = hmac_blake2s(&prk, &[&t2, &[3]]);
+    (t1, t2, t3)
+}
+
+/// Returns the 96-bit AEAD nonce for the given message counter.
+fn nonce(counter: u64) -> [u8; CHACHA20_NONCE_SIZE] {
+    let mut nonce = [0u8; CHACHA20_NONCE_SIZE];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Encrypts the given `plaintext` of a handshake field, and returns the ciphertext followed by the tag.
+fn aead_encrypt(key: &Key, counter: u64, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut ciphertext = Vec::with_capacity(plaintext.len() + TAG_LEN);
+    ciphertext.extend_from_slice(plaintext);
+    match ChaCha20Poly1305::new(key).encrypt_in_place(&nonce(counter), aad, &mut ciphertext) {
+        Ok(tag) => ciphertext.extend_from_slice(&tag),
+        // Encryption only fails if the plaintext is too long, which handshake fields never are.
+        Err(_) => ciphertext.clear(),
+    }
+    ciphertext
+}
+
+fn aead_decrypt(key: &Key, counter: u64, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, &'static str> {
+    aead_open(&ChaCha20Poly1305::new(key), counter, ciphertext, aad)
+}
+
+/// Authenticates and decrypts the given `ciphertext`, which is followed by its tag, and returns the plaintext.
+fn aead_open(cipher: &ChaCha20Poly1305, counter: u64, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if ciphertext.len() < TAG_LEN {
+        return Err("wireguard: ciphertext is shorter than its tag");
+    }
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_LEN);
+    let mut plaintext = ciphertext.to_vec();
+    cipher.decrypt_in_place(&nonce(counter), aad, &mut plaintext, tag)?;
+    Ok(plaintext)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[offset .. offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[offset .. offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+fn read_key(bytes: &[u8], offset: usize) -> Key {
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&bytes[offset .. offset + KEY_LEN]);
+    key
+}
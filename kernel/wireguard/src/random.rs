@@ -0,0 +1,27 @@
+//! Random bytes for ephemeral keys, private keys, and session indices.
+//!
+//! There is no system-wide random number generator yet, so each random value is the BLAKE2s hash of
+//! a seed from the `entropy` crate (i.e., the `RDRAND` instruction's output, if the CPU supports it, and the TSC),
+//! a counter, and a secret.
+//! The counter ensures that no value repeats, and the secret (e.g., the local private key) ensures
+//! that values can't be predicted by anyone who doesn't know it, even if `RDRAND` isn't available.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use noise::{hash, Key};
+
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Returns 32 random bytes, mixing in the given `secret`.
+pub fn random_key(secret: &[u8]) -> Key {
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes();
+    let mut seed = [0u8; 32];
+    entropy::fill_bytes(&mut seed);
+    hash(&[&seed, &counter, secret])
+}
+
+/// Returns a random 32-bit value, e.g., for a session index.
+pub fn random_u32(secret: &[u8]) -> u32 {
+    let key = random_key(secret);
+    u32::from_le_bytes([key[0], key[1], key[2], key[3]])
+}
@@ -0,0 +1,233 @@
+//! The AES block cipher (FIPS 197) with 256-bit keys.
+//!
+//! This is a byte-oriented software implementation, whose S-box and multiplication tables
+//! are generated at compile time. Note that its table lookups depend on the data being encrypted,
+//! so it is not hardened against cache-timing side channels.
+
+/// The size in bytes of one AES block.
+pub const AES_BLOCK_SIZE: usize = 16;
+
+/// The size in bytes of an AES-256 key.
+pub const AES_256_KEY_SIZE: usize = 32;
+
+/// The number of rounds of AES-256.
+const ROUNDS: usize = 14;
+
+/// The forward S-box, used by `SubBytes` and the key schedule.
+static SBOX: [u8; 256] = sbox();
+/// The inverse S-box, used by `InvSubBytes`.
+static INV_SBOX: [u8; 256] = inv_sbox();
+/// Multiplication tables for the coefficients used by `InvMixColumns`.
+static MUL_9:  [u8; 256] = mul_table(9);
+static MUL_11: [u8; 256] = mul_table(11);
+static MUL_13: [u8; 256] = mul_table(13);
+static MUL_14: [u8; 256] = mul_table(14);
+
+
+/// An AES-256 cipher with an expanded key, which encrypts and decrypts single 16-byte blocks.
+///
+/// The expanded key is zeroed out when this is dropped.
+pub struct Aes256 {
+    round_keys: [[u8; AES_BLOCK_SIZE]; ROUNDS + 1],
+}
+
+impl Aes256 {
+    /// Creates a new cipher by expanding the given 256-bit key.
+    pub fn new(key: &[u8; AES_256_KEY_SIZE]) -> Aes256 {
+        const KEY_WORDS: usize = AES_256_KEY_SIZE / 4;
+        let mut words = [[0u8; 4]; 4 * (ROUNDS + 1)];
+        for (word, key_word) in words.iter_mut().zip(key.chunks_exact(4)) {
+            word.copy_from_slice(key_word);
+        }
+        let mut round_constant = 1u8;
+        for i in KEY_WORDS .. words.len() {
+            let mut temp = words[i - 1];
+            if i % KEY_WORDS == 0 {
+                temp = [
+                    SBOX[temp[1] as usize] ^ round_constant,
+                    SBOX[temp[2] as usize],
+                    SBOX[temp[3] as usize],
+                    SBOX[temp[0] as usize],
+                ];
+                round_constant = xtime(round_constant);
+            } else if i % KEY_WORDS == 4 {
+                for b in temp.iter_mut() {
+                    *b = SBOX[*b as usize];
+                }
+            }
+            let previous = words[i - KEY_WORDS];
+            for (b, (p, t)) in words[i].iter_mut().zip(previous.iter().zip(temp.iter())) {
+                *b = p ^ t;
+            }
+        }
+
+        let mut round_keys = [[0u8; AES_BLOCK_SIZE]; ROUNDS + 1];
+        for (round_key, round_words) in round_keys.iter_mut().zip(words.chunks_exact(4)) {
+            for (column, word) in round_key.chunks_exact_mut(4).zip(round_words) {
+                column.copy_from_slice(word);
+            }
+        }
+        zero(words.iter_mut().flat_map(|w| w.iter_mut()));
+        Aes256 { round_keys }
+    }
+
+    /// Encrypts the given `block` in place.
+    pub fn encrypt_block(&self, block: &mut [u8; AES_BLOCK_SIZE]) {
+        add_round_key(block, &self.round_keys[0]);
+        for round in 1 ..= ROUNDS {
+            for b in block.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            shift_rows(block);
+            if round != ROUNDS {
+                mix_columns(block);
+            }
+            add_round_key(block, &self.round_keys[round]);
+        }
+    }
+
+    /// Decrypts the given `block` in place.
+    pub fn decrypt_block(&self, block: &mut [u8; AES_BLOCK_SIZE]) {
+        add_round_key(block, &self.round_keys[ROUNDS]);
+        for round in (0 .. ROUNDS).rev() {
+            inv_shift_rows(block);
+            for b in block.iter_mut() {
+                *b = INV_SBOX[*b as usize];
+            }
+            add_round_key(block, &self.round_keys[round]);
+            if round != 0 {
+                inv_mix_columns(block);
+            }
+        }
+    }
+}
+
+impl Drop for Aes256 {
+    fn drop(&mut self) {
+        zero(self.round_keys.iter_mut().flat_map(|k| k.iter_mut()));
+    }
+}
+
+/// Zeroes the given bytes with volatile writes, such that the compiler can't optimize away the zeroing.
+fn zero<'b, I: Iterator<Item = &'b mut u8>>(bytes: I) {
+    for b in bytes {
+        unsafe { core::ptr::write_volatile(b, 0); }
+    }
+}
+
+
+// The state is stored in column-major order, as in FIPS 197: the byte in row `r` and column `c` is `block[r + 4c]`.
+
+fn add_round_key(block: &mut [u8; AES_BLOCK_SIZE], round_key: &[u8; AES_BLOCK_SIZE]) {
+    for (b, k) in block.iter_mut().zip(round_key.iter()) {
+        *b ^= *k;
+    }
+}
+
+/// Rotates row `r` of the state to the left by `r` bytes.
+fn shift_rows(block: &mut [u8; AES_BLOCK_SIZE]) {
+    let old = *block;
+    for r in 1 .. 4 {
+        for c in 0 .. 4 {
+            block[r + 4 * c] = old[r + 4 * ((c + r) % 4)];
+        }
+    }
+}
+
+/// Rotates row `r` of the state to the right by `r` bytes.
+fn inv_shift_rows(block: &mut [u8; AES_BLOCK_SIZE]) {
+    let old = *block;
+    for r in 1 .. 4 {
+        for c in 0 .. 4 {
+            block[r + 4 * ((c + r) % 4)] = old[r + 4 * c];
+        }
+    }
+}
+
+fn mix_columns(block: &mut [u8; AES_BLOCK_SIZE]) {
+    for column in block.chunks_exact_mut(4) {
+        let a = [column[0], column[1], column[2], column[3]];
+        let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+        for i in 0 .. 4 {
+            // 2*a[i] + 3*a[i+1] + a[i+2] + a[i+3] = a[i] + xtime(a[i] + a[i+1]) + (a[0] + a[1] + a[2] + a[3])
+            column[i] = a[i] ^ xtime(a[i] ^ a[(i + 1) % 4]) ^ all;
+        }
+    }
+}
+
+fn inv_mix_columns(block: &mut [u8; AES_BLOCK_SIZE]) {
+    for column in block.chunks_exact_mut(4) {
+        let a = [column[0] as usize, column[1] as usize, column[2] as usize, column[3] as usize];
+        for i in 0 .. 4 {
+            column[i] = MUL_14[a[i]] ^ MUL_11[a[(i + 1) % 4]] ^ MUL_13[a[(i + 2) % 4]] ^ MUL_9[a[(i + 3) % 4]];
+        }
+    }
+}
+
+
+/// Multiplies the given element of GF(2^8) by `x`, modulo the AES polynomial `x^8 + x^4 + x^3 + x + 1`.
+const fn xtime(a: u8) -> u8 {
+    (a << 1) ^ (((a >> 7) & 1) * 0x1B)
+}
+
+/// Multiplies two elements of GF(2^8), modulo the AES polynomial.
+const fn gf_mul(a: u8, b: u8) -> u8 {
+    let mut a = a;
+    let mut b = b;
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    product
+}
+
+/// Returns the multiplicative inverse of the given element of GF(2^8), i.e., `a^254`, which maps 0 to 0.
+const fn gf_inverse(a: u8) -> u8 {
+    let mut result = 1;
+    let mut base = a;
+    let mut exponent = 254u8;
+    while exponent != 0 {
+        if exponent & 1 != 0 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+const fn sbox() -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        let b = gf_inverse(i as u8);
+        table[i] = b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63;
+        i += 1;
+    }
+    table
+}
+
+const fn inv_sbox() -> [u8; 256] {
+    let forward = sbox();
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[forward[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+const fn mul_table(factor: u8) -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = gf_mul(i as u8, factor);
+        i += 1;
+    }
+    table
+}
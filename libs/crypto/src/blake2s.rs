@@ -0,0 +1,171 @@
+//! The BLAKE2s hash function (RFC 7693), optionally keyed, with an output of up to 32 bytes.
+
+/// The maximum (and default) output size of BLAKE2s in bytes.
+pub const BLAKE2S_OUTPUT_SIZE: usize = 32;
+
+/// The block size of BLAKE2s in bytes, which is also its block size for HMAC.
+pub const BLAKE2S_BLOCK_SIZE: usize = 64;
+
+/// The maximum key size of keyed BLAKE2s in bytes.
+pub const BLAKE2S_MAX_KEY_SIZE: usize = 32;
+
+/// The initialization vector, which is the same as SHA-256's.
+const IV: [u32; 8] = [
+    0x6A09_E667, 0xBB67_AE85, 0x3C6E_F372, 0xA54F_F53A, 0x510E_527F, 0x9B05_688C, 0x1F83_D9AB, 0x5BE0_CD19,
+];
+
+/// The message word permutations of each round.
+const SIGMA: [[usize; 16]; 10] = [
+    [ 0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15],
+    [14, 10,  4,  8,  9, 15, 13,  6,  1, 12,  0,  2, 11,  7,  5,  3],
+    [11,  8, 12,  0,  5,  2, 15, 13, 10, 14,  3,  6,  7,  1,  9,  4],
+    [ 7,  9,  3,  1, 13, 12, 11, 14,  2,  6,  5, 10,  4,  0, 15,  8],
+    [ 9,  0,  5,  7,  2,  4, 10, 15, 14,  1, 11, 12,  6,  8,  3, 13],
+    [ 2, 12,  6, 10,  0, 11,  8,  3,  4, 13,  7,  5, 15, 14,  1,  9],
+    [12,  5,  1, 15, 14, 13,  4, 10,  0,  7,  6,  3,  9,  2,  8, 11],
+    [13, 11,  7, 14, 12,  1,  3,  9,  5,  0, 15,  4,  8,  6,  2, 10],
+    [ 6, 15, 14,  9, 11,  3,  0,  8, 12,  2, 13,  7,  1,  4, 10,  5],
+    [10,  2,  8,  4,  7,  6,  1,  5, 15, 11,  9, 14,  3, 12, 13,  0],
+];
+
+
+/// An incremental BLAKE2s hasher.
+///
+/// Cloning a hasher copies its state, e.g., to reuse a common prefix of several messages.
+#[derive(Clone)]
+pub struct Blake2s {
+    state: [u32; 8],
+    /// The number of bytes hashed so far, not including those in `buffer`.
+    counter: u64,
+    /// The most recent block, which is only compressed once more data arrives,
+    /// since the last block must be compressed differently.
+    buffer: [u8; BLAKE2S_BLOCK_SIZE],
+    buffer_len: usize,
+    output_len: usize,
+}
+
+impl Default for Blake2s {
+    fn default() -> Blake2s {
+        Blake2s::new()
+    }
+}
+
+impl Blake2s {
+    /// Creates a new unkeyed hasher with a 32-byte output for an empty message.
+    pub fn new() -> Blake2s {
+        Blake2s::with_parameters(&[], BLAKE2S_OUTPUT_SIZE)
+    }
+
+    /// Creates a new hasher that is keyed with the given `key` and produces an output of `output_len` bytes.
+    ///
+    /// Returns an error if the key is longer than 32 bytes, or if `output_len` isn't within `1 ..= 32`.
+    pub fn new_keyed(key: &[u8], output_len: usize) -> Result<Blake2s, &'static str> {
+        if key.len() > BLAKE2S_MAX_KEY_SIZE {
+            return Err("BLAKE2s keys can be at most 32 bytes long");
+        }
+        if output_len == 0 || output_len > BLAKE2S_OUTPUT_SIZE {
+            return Err("BLAKE2s outputs must be between 1 and 32 bytes long");
+        }
+        Ok(Blake2s::with_parameters(key, output_len))
+    }
+
+    /// Returns the unkeyed 32-byte BLAKE2s hash of the given `data`.
+    pub fn digest(data: &[u8]) -> [u8; BLAKE2S_OUTPUT_SIZE] {
+        let mut hasher = Blake2s::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    fn with_parameters(key: &[u8], output_len: usize) -> Blake2s {
+        let mut state = IV;
+        // The parameter block: a fanout and depth of 1 for sequential hashing, along with the key and output lengths.
+        state[0] ^= 0x0101_0000 ^ ((key.len() as u32) << 8) ^ output_len as u32;
+        let mut hasher = Blake2s { state, counter: 0, buffer: [0; BLAKE2S_BLOCK_SIZE], buffer_len: 0, output_len };
+        if !key.is_empty() {
+            // The key is padded to a whole block, which is hashed before the message.
+            hasher.buffer[.. key.len()].copy_from_slice(key);
+            hasher.buffer_len = BLAKE2S_BLOCK_SIZE;
+        }
+        hasher
+    }
+
+    /// Appends the given `data` to the message being hashed.
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.buffer_len == BLAKE2S_BLOCK_SIZE {
+                self.counter += BLAKE2S_BLOCK_SIZE as u64;
+                let block = self.buffer;
+                self.compress(&block, false);
+                self.buffer_len = 0;
+            }
+            let len = core::cmp::min(data.len(), BLAKE2S_BLOCK_SIZE - self.buffer_len);
+            self.buffer[self.buffer_len .. self.buffer_len + len].copy_from_slice(&data[.. len]);
+            self.buffer_len += len;
+            data = &data[len ..];
+        }
+    }
+
+    /// Returns the hash of the message, of which only the first `output_len` bytes are used;
+    /// the remaining bytes are zero.
+    pub fn finalize(mut self) -> [u8; BLAKE2S_OUTPUT_SIZE] {
+        self.counter += self.buffer_len as u64;
+        for byte in self.buffer[self.buffer_len ..].iter_mut() {
+            *byte = 0;
+        }
+        let block = self.buffer;
+        self.compress(&block, true);
+
+        let mut output = [0u8; BLAKE2S_OUTPUT_SIZE];
+        for (bytes, word) in output.chunks_exact_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        for byte in output[self.output_len ..].iter_mut() {
+            *byte = 0;
+        }
+        output
+    }
+
+    /// The compression function F, which mixes the given `block` into the state.
+    fn compress(&mut self, block: &[u8; BLAKE2S_BLOCK_SIZE], is_last: bool) {
+        let mut m = [0u32; 16];
+        for (word, bytes) in m.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let mut v = [0u32; 16];
+        v[.. 8].copy_from_slice(&self.state);
+        v[8 ..].copy_from_slice(&IV);
+        v[12] ^= self.counter as u32;
+        v[13] ^= (self.counter >> 32) as u32;
+        if is_last {
+            v[14] = !v[14];
+        }
+
+        for s in SIGMA.iter() {
+            g(&mut v, 0, 4,  8, 12, m[s[ 0]], m[s[ 1]]);
+            g(&mut v, 1, 5,  9, 13, m[s[ 2]], m[s[ 3]]);
+            g(&mut v, 2, 6, 10, 14, m[s[ 4]], m[s[ 5]]);
+            g(&mut v, 3, 7, 11, 15, m[s[ 6]], m[s[ 7]]);
+            g(&mut v, 0, 5, 10, 15, m[s[ 8]], m[s[ 9]]);
+            g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+            g(&mut v, 2, 7,  8, 13, m[s[12]], m[s[13]]);
+            g(&mut v, 3, 4,  9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0 .. 8 {
+            self.state[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+/// The mixing function G, which mixes the two message words `x` and `y` into four words of the working vector.
+#[inline(always)]
+#[allow(clippy::many_single_char_names)] // the names of RFC 7693
+fn g(v: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, x: u32, y: u32) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(12);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(8);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(7);
+}
@@ -0,0 +1,284 @@
+//! The ChaCha20 stream cipher, the Poly1305 one-time authenticator,
+//! and their combination into the ChaCha20-Poly1305 AEAD construction (RFC 8439).
+
+use util::constant_time_eq;
+
+/// The key size of ChaCha20 and ChaCha20-Poly1305 in bytes.
+pub const CHACHA20_KEY_SIZE: usize = 32;
+/// The nonce size of ChaCha20 and ChaCha20-Poly1305 in bytes.
+pub const CHACHA20_NONCE_SIZE: usize = 12;
+/// The size of a ChaCha20 keystream block in bytes.
+pub const CHACHA20_BLOCK_SIZE: usize = 64;
+/// The key size of Poly1305 in bytes.
+pub const POLY1305_KEY_SIZE: usize = 32;
+/// The size of a Poly1305 or ChaCha20-Poly1305 authentication tag in bytes.
+pub const POLY1305_TAG_SIZE: usize = 16;
+
+/// The constant first row of the ChaCha20 state, "expand 32-byte k".
+const CHACHA20_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+const MASK_44: u64 = (1 << 44) - 1;
+const MASK_42: u64 = (1 << 42) - 1;
+
+
+/// Returns the ChaCha20 keystream block for the given `key`, block `counter`, and `nonce`.
+pub fn chacha20_block(key: &[u8; CHACHA20_KEY_SIZE], counter: u32, nonce: &[u8; CHACHA20_NONCE_SIZE]) -> [u8; CHACHA20_BLOCK_SIZE] {
+    let mut state = [0u32; 16];
+    state[.. 4].copy_from_slice(&CHACHA20_CONSTANTS);
+    for (word, bytes) in state[4 .. 12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    state[12] = counter;
+    for (word, bytes) in state[13 ..].iter_mut().zip(nonce.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+
+    let mut working = state;
+    for _ in 0 .. 10 {
+        quarter_round(&mut working, 0, 4,  8, 12);
+        quarter_round(&mut working, 1, 5,  9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7,  8, 13);
+        quarter_round(&mut working, 3, 4,  9, 14);
+    }
+
+    let mut block = [0u8; CHACHA20_BLOCK_SIZE];
+    for ((bytes, w), s) in block.chunks_exact_mut(4).zip(working.iter()).zip(state.iter()) {
+        bytes.copy_from_slice(&w.wrapping_add(*s).to_le_bytes());
+    }
+    block
+}
+
+#[inline(always)]
+#[allow(clippy::many_single_char_names)] // the names of RFC 8439
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// Encrypts or decrypts the given `data` in place by XORing it with the ChaCha20 keystream,
+/// starting at the block with the given `initial_counter`.
+///
+/// Returns an error if the data is too long for the remaining block counters.
+pub fn chacha20_xor(
+    key: &[u8; CHACHA20_KEY_SIZE],
+    initial_counter: u32,
+    nonce: &[u8; CHACHA20_NONCE_SIZE],
+    data: &mut [u8],
+) -> Result<(), &'static str> {
+    let num_blocks = (data.len() + CHACHA20_BLOCK_SIZE - 1) / CHACHA20_BLOCK_SIZE;
+    if num_blocks as u64 > (1u64 << 32) - initial_counter as u64 {
+        return Err("the data is too long for ChaCha20 with the given initial block counter");
+    }
+    for (i, chunk) in data.chunks_mut(CHACHA20_BLOCK_SIZE).enumerate() {
+        let keystream = chacha20_block(key, initial_counter.wrapping_add(i as u32), nonce);
+        for (byte, k) in chunk.iter_mut().zip(keystream.iter()) {
+            *byte ^= k;
+        }
+    }
+    Ok(())
+}
+
+
+/// An incremental Poly1305 authenticator for one message, with 44-bit limbs.
+///
+/// A Poly1305 key must only ever be used for a single message.
+pub struct Poly1305 {
+    r: [u64; 3],
+    h: [u64; 3],
+    pad: [u64; 2],
+    buffer: [u8; 16],
+    buffer_len: usize,
+}
+
+impl Poly1305 {
+    /// Creates a new authenticator with the given one-time `key`.
+    pub fn new(key: &[u8; POLY1305_KEY_SIZE]) -> Poly1305 {
+        let t0 = read_u64(&key[0 .. 8]);
+        let t1 = read_u64(&key[8 .. 16]);
+        Poly1305 {
+            // `r` is clamped as required.
+            r: [
+                t0 & 0xFFC_0FFF_FFFF,
+                ((t0 >> 44) | (t1 << 20)) & 0xFFF_FFC0_FFFF,
+                (t1 >> 24) & 0x00F_FFFF_FC0F,
+            ],
+            h: [0; 3],
+            pad: [read_u64(&key[16 .. 24]), read_u64(&key[24 .. 32])],
+            buffer: [0; 16],
+            buffer_len: 0,
+        }
+    }
+
+    /// Appends the given `data` to the message being authenticated.
+    pub fn update(&mut self, mut data: &[u8]) {
+        if self.buffer_len > 0 {
+            let len = core::cmp::min(data.len(), 16 - self.buffer_len);
+            self.buffer[self.buffer_len .. self.buffer_len + len].copy_from_slice(&data[.. len]);
+            self.buffer_len += len;
+            data = &data[len ..];
+            if self.buffer_len < 16 {
+                return;
+            }
+            let block = self.buffer;
+            self.process_block(&block, 1 << 40);
+            self.buffer_len = 0;
+        }
+        while data.len() >= 16 {
+            let mut block = [0u8; 16];
+            block.copy_from_slice(&data[.. 16]);
+            self.process_block(&block, 1 << 40);
+            data = &data[16 ..];
+        }
+        self.buffer[.. data.len()].copy_from_slice(data);
+        self.buffer_len = data.len();
+    }
+
+    /// Returns the authentication tag of the message.
+    pub fn finalize(mut self) -> [u8; POLY1305_TAG_SIZE] {
+        if self.buffer_len > 0 {
+            // A partial final block is padded with a one byte and zeros instead of the high bit.
+            let mut block = [0u8; 16];
+            block[.. self.buffer_len].copy_from_slice(&self.buffer[.. self.buffer_len]);
+            block[self.buffer_len] = 1;
+            self.process_block(&block, 0);
+        }
+        let [mut h0, mut h1, mut h2] = self.h;
+
+        // Fully carry `h`.
+        let mut c;
+        c = h1 >> 44; h1 &= MASK_44; h2 += c;
+        c = h2 >> 42; h2 &= MASK_42; h0 += c * 5;
+        c = h0 >> 44; h0 &= MASK_44; h1 += c;
+        c = h1 >> 44; h1 &= MASK_44; h2 += c;
+        c = h2 >> 42; h2 &= MASK_42; h0 += c * 5;
+        c = h0 >> 44; h0 &= MASK_44; h1 += c;
+
+        // Compute `h - p` and select it in constant time if `h >= p`.
+        let mut g0 = h0 + 5; c = g0 >> 44; g0 &= MASK_44;
+        let mut g1 = h1 + c; c = g1 >> 44; g1 &= MASK_44;
+        let mut g2 = h2.wrapping_add(c).wrapping_sub(1 << 42);
+        let select_g = (g2 >> 63).wrapping_sub(1);
+        g0 &= select_g; g1 &= select_g; g2 &= select_g;
+        h0 = (h0 & !select_g) | g0;
+        h1 = (h1 & !select_g) | g1;
+        h2 = (h2 & !select_g) | g2;
+
+        // Add the pad, modulo 2^128.
+        let [t0, t1] = self.pad;
+        h0 += t0 & MASK_44; c = h0 >> 44; h0 &= MASK_44;
+        h1 += (((t0 >> 44) | (t1 << 20)) & MASK_44) + c; c = h1 >> 44; h1 &= MASK_44;
+        h2 += ((t1 >> 24) & MASK_42) + c; h2 &= MASK_42;
+
+        let mut tag = [0u8; POLY1305_TAG_SIZE];
+        tag[.. 8].copy_from_slice(&(h0 | (h1 << 44)).to_le_bytes());
+        tag[8 ..].copy_from_slice(&((h1 >> 20) | (h2 << 24)).to_le_bytes());
+        tag
+    }
+
+    /// Computes `h = (h + block) * r` modulo 2^130 - 5, where `high_bit` is added above the block's 128 bits.
+    fn process_block(&mut self, block: &[u8; 16], high_bit: u64) {
+        let [r0, r1, r2] = self.r;
+        let s1 = r1 * (5 << 2);
+        let s2 = r2 * (5 << 2);
+        let t0 = read_u64(&block[0 .. 8]);
+        let t1 = read_u64(&block[8 .. 16]);
+        let [mut h0, mut h1, mut h2] = self.h;
+        h0 += t0 & MASK_44;
+        h1 += ((t0 >> 44) | (t1 << 20)) & MASK_44;
+        h2 += ((t1 >> 24) & MASK_42) | high_bit;
+
+        let m = |a: u64, b: u64| a as u128 * b as u128;
+        let d0 = m(h0, r0) + m(h1, s2) + m(h2, s1);
+        let mut d1 = m(h0, r1) + m(h1, r0) + m(h2, s2);
+        let mut d2 = m(h0, r2) + m(h1, r1) + m(h2, r0);
+
+        let mut c = (d0 >> 44) as u64; h0 = d0 as u64 & MASK_44;
+        d1 += c as u128; c = (d1 >> 44) as u64; h1 = d1 as u64 & MASK_44;
+        d2 += c as u128; c = (d2 >> 42) as u64; h2 = d2 as u64 & MASK_42;
+        h0 += c * 5; c = h0 >> 44; h0 &= MASK_44;
+        h1 += c;
+        self.h = [h0, h1, h2];
+    }
+}
+
+/// Returns the Poly1305 authentication tag of the given `message` with the given one-time `key`.
+pub fn poly1305(key: &[u8; POLY1305_KEY_SIZE], message: &[u8]) -> [u8; POLY1305_TAG_SIZE] {
+    let mut authenticator = Poly1305::new(key);
+    authenticator.update(message);
+    authenticator.finalize()
+}
+
+
+/// The ChaCha20-Poly1305 AEAD construction with one key, which encrypts and authenticates messages in place.
+///
+/// A nonce must never be used more than once with the same key.
+#[derive(Clone)]
+pub struct ChaCha20Poly1305 {
+    key: [u8; CHACHA20_KEY_SIZE],
+}
+
+impl ChaCha20Poly1305 {
+    /// Creates a new AEAD instance for the given `key`.
+    pub fn new(key: &[u8; CHACHA20_KEY_SIZE]) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305 { key: *key }
+    }
+
+    /// Encrypts the given `buffer` in place, and returns the tag that authenticates it along with the
+    /// additional data `aad`, which is not encrypted.
+    ///
+    /// Returns an error if the buffer is too long to be encrypted.
+    pub fn encrypt_in_place(
+        &self,
+        nonce: &[u8; CHACHA20_NONCE_SIZE],
+        aad: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<[u8; POLY1305_TAG_SIZE], &'static str> {
+        chacha20_xor(&self.key, 1, nonce, buffer)?;
+        Ok(self.tag(nonce, aad, buffer))
+    }
+
+    /// Checks that the given `tag` authenticates the encrypted `buffer` and the additional data `aad`,
+    /// and if so, decrypts the `buffer` in place.
+    ///
+    /// Returns an error, leaving the `buffer` unchanged, if the tag is invalid.
+    pub fn decrypt_in_place(
+        &self,
+        nonce: &[u8; CHACHA20_NONCE_SIZE],
+        aad: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), &'static str> {
+        if !constant_time_eq(&self.tag(nonce, aad, buffer), tag) {
+            return Err("the ChaCha20-Poly1305 authentication tag is invalid");
+        }
+        chacha20_xor(&self.key, 1, nonce, buffer)
+    }
+
+    /// Returns the tag of the given `ciphertext` and additional data `aad`,
+    /// with the one-time Poly1305 key from the first keystream block.
+    fn tag(&self, nonce: &[u8; CHACHA20_NONCE_SIZE], aad: &[u8], ciphertext: &[u8]) -> [u8; POLY1305_TAG_SIZE] {
+        let mut poly_key = [0u8; POLY1305_KEY_SIZE];
+        poly_key.copy_from_slice(&chacha20_block(&self.key, 0, nonce)[.. POLY1305_KEY_SIZE]);
+        let mut authenticator = Poly1305::new(&poly_key);
+        let zeros = [0u8; 16];
+        authenticator.update(aad);
+        authenticator.update(&zeros[.. (16 - aad.len() % 16) % 16]);
+        authenticator.update(ciphertext);
+        authenticator.update(&zeros[.. (16 - ciphertext.len() % 16) % 16]);
+        authenticator.update(&(aad.len() as u64).to_le_bytes());
+        authenticator.update(&(ciphertext.len() as u64).to_le_bytes());
+        authenticator.finalize()
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
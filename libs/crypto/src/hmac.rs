@@ -0,0 +1,100 @@
+//! The HMAC message authentication code (RFC 2104) with SHA3-256 or BLAKE2s.
+
+use sha3::{Sha3_256, SHA3_256_OUTPUT_SIZE, SHA3_256_RATE};
+use blake2s::{Blake2s, BLAKE2S_OUTPUT_SIZE, BLAKE2S_BLOCK_SIZE};
+
+/// The output size of every HMAC in this module, since both supported hash functions have 32-byte outputs.
+pub const HMAC_OUTPUT_SIZE: usize = 32;
+
+/// The largest block size of all hash functions that HMAC can be used with.
+const MAX_BLOCK_SIZE: usize = SHA3_256_RATE;
+
+
+/// A hash function with a 32-byte output that HMAC can be built upon.
+pub trait HmacHash: Clone {
+    /// The block size of the hash function in bytes, which must be at most `SHA3_256_RATE`.
+    const BLOCK_SIZE: usize;
+    fn new() -> Self;
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self) -> [u8; HMAC_OUTPUT_SIZE];
+}
+
+impl HmacHash for Sha3_256 {
+    const BLOCK_SIZE: usize = SHA3_256_RATE;
+    fn new() -> Self { Sha3_256::new() }
+    fn update(&mut self, data: &[u8]) { Sha3_256::update(self, data) }
+    fn finalize(self) -> [u8; SHA3_256_OUTPUT_SIZE] { Sha3_256::finalize(self) }
+}
+
+impl HmacHash for Blake2s {
+    const BLOCK_SIZE: usize = BLAKE2S_BLOCK_SIZE;
+    fn new() -> Self { Blake2s::new() }
+    fn update(&mut self, data: &[u8]) { Blake2s::update(self, data) }
+    fn finalize(self) -> [u8; BLAKE2S_OUTPUT_SIZE] { Blake2s::finalize(self) }
+}
+
+
+/// An HMAC instance for one key, which can compute the MAC of any number of messages.
+///
+/// The hash states after absorbing the padded key are computed once,
+/// such that each MAC only needs to hash the message itself, e.g., for the many iterations of PBKDF2.
+#[derive(Clone)]
+pub struct Hmac<H: HmacHash> {
+    inner: H,
+    outer: H,
+}
+
+/// HMAC with SHA3-256.
+pub type HmacSha3_256 = Hmac<Sha3_256>;
+/// HMAC with BLAKE2s, e.g., for the key derivation of WireGuard.
+pub type HmacBlake2s = Hmac<Blake2s>;
+
+impl<H: HmacHash> Hmac<H> {
+    /// Creates a new HMAC instance for the given `key`.
+    /// A key that is longer than the hash function's block size is hashed first.
+    pub fn new(key: &[u8]) -> Hmac<H> {
+        let block_size = H::BLOCK_SIZE;
+        let mut key_block = [0u8; MAX_BLOCK_SIZE];
+        if key.len() > block_size {
+            let mut hasher = H::new();
+            hasher.update(key);
+            key_block[.. HMAC_OUTPUT_SIZE].copy_from_slice(&hasher.finalize());
+        } else {
+            key_block[.. key.len()].copy_from_slice(key);
+        }
+
+        let mut inner_pad = [0x36u8; MAX_BLOCK_SIZE];
+        let mut outer_pad = [0x5Cu8; MAX_BLOCK_SIZE];
+        for i in 0 .. block_size {
+            inner_pad[i] ^= key_block[i];
+            outer_pad[i] ^= key_block[i];
+        }
+        let mut inner = H::new();
+        inner.update(&inner_pad[.. block_size]);
+        let mut outer = H::new();
+        outer.update(&outer_pad[.. block_size]);
+        Hmac { inner, outer }
+    }
+
+    /// Returns the MAC of the concatenation of the given `message_parts`.
+    pub fn mac(&self, message_parts: &[&[u8]]) -> [u8; HMAC_OUTPUT_SIZE] {
+        let mut inner = self.inner.clone();
+        for part in message_parts {
+            inner.update(part);
+        }
+        let inner_hash = inner.finalize();
+        let mut outer = self.outer.clone();
+        outer.update(&inner_hash);
+        outer.finalize()
+    }
+}
+
+/// Returns the HMAC-SHA3-256 of the concatenation of the given `message_parts`.
+pub fn hmac_sha3_256(key: &[u8], message_parts: &[&[u8]]) -> [u8; SHA3_256_OUTPUT_SIZE] {
+    HmacSha3_256::new(key).mac(message_parts)
+}
+
+/// Returns the HMAC-BLAKE2s of the concatenation of the given `message_parts`.
+pub fn hmac_blake2s(key: &[u8], message_parts: &[&[u8]]) -> [u8; BLAKE2S_OUTPUT_SIZE] {
+    HmacBlake2s::new(key).mac(message_parts)
+}
@@ -0,0 +1,325 @@
+//! Cryptographic primitives for use within the kernel, e.g., by the `block_crypt` storage encryption layer
+//! and the `wireguard` tunnel.
+//!
+//! * [`aes`]: the AES-256 block cipher,
+//! * [`xts`]: the XTS block cipher mode on top of AES-256, for encrypting storage sectors,
+//! * [`sha3`]: the SHA3-256 hash function,
+//! * [`blake2s`]: the BLAKE2s hash function, optionally keyed,
+//! * [`hmac`]: HMAC with SHA3-256 or BLAKE2s,
+//! * [`pbkdf2`]: PBKDF2 with HMAC-SHA3-256, for deriving keys from passphrases,
+//! * [`chacha20poly1305`]: the ChaCha20 stream cipher, the Poly1305 authenticator, and their AEAD combination,
+//! * [`x25519`]: the X25519 Diffie-Hellman function,
+//! * [`util`]: helpers such as constant-time comparison.
+//!
+//! This crate doesn't depend on any other crate, such that it can be tested against
+//! known-answer test vectors on the host, e.g., with `cargo test -- --nocapture` in this directory.
+//!
+//! [`aes`]: aes/index.html
+//! [`xts`]: xts/index.html
+//! [`sha3`]: sha3/index.html
+//! [`blake2s`]: blake2s/index.html
+//! [`hmac`]: hmac/index.html
+//! [`pbkdf2`]: pbkdf2/index.html
+//! [`chacha20poly1305`]: chacha20poly1305/index.html
+//! [`x25519`]: x25519/index.html
+//! [`util`]: util/index.html
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use] extern crate std;
+
+pub mod aes;
+pub mod xts;
+pub mod sha3;
+pub mod blake2s;
+pub mod hmac;
+pub mod pbkdf2;
+pub mod chacha20poly1305;
+pub mod x25519;
+pub mod util;
+
+
+#[cfg(test)]
+use std::vec::Vec;
+
+/// Decodes the given hexadecimal string, ignoring whitespace.
+#[cfg(test)]
+fn hex(s: &str) -> Vec<u8> {
+    let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    digits.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap()).collect()
+}
+
+/// The two AES-256 keys of the IEEE 1619 XTS-AES-256 test vectors 10 through 14.
+#[cfg(test)]
+const IEEE_1619_XTS_AES_256_KEY: &str = "
+    2718281828459045235360287471352662497757247093699959574966967627
+    3141592653589793238462643383279502884197169399375105820974944592";
+
+/// The plaintext of the IEEE 1619 XTS-AES-256 test vectors 10 through 14: the bytes 0x00 to 0xFF, twice.
+#[cfg(test)]
+fn ieee_1619_xts_aes_256_plaintext() -> Vec<u8> {
+    (0 ..= 255u8).chain(0 ..= 255u8).collect()
+}
+
+#[test]
+/// The example vector from FIPS 197, Appendix C.3.
+fn test_aes_256_fips_197() {
+    let key = hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+    let mut key_array = [0u8; aes::AES_256_KEY_SIZE];
+    key_array.copy_from_slice(&key);
+    let cipher = aes::Aes256::new(&key_array);
+
+    let mut block = [0u8; aes::AES_BLOCK_SIZE];
+    block.copy_from_slice(&hex("00112233445566778899aabbccddeeff"));
+    cipher.encrypt_block(&mut block);
+    assert_eq!(block[..], hex("8ea2b7ca516745bfeafc49904b496089")[..]);
+    cipher.decrypt_block(&mut block);
+    assert_eq!(block[..], hex("00112233445566778899aabbccddeeff")[..]);
+}
+
+#[test]
+/// IEEE 1619-2007, Annex B, XTS-AES-256 test vectors 10 and 11.
+/// To run this test, execute: `cargo test test_xts_ieee_1619 -- --nocapture`
+fn test_xts_ieee_1619() {
+    let vectors = [
+        (0xFF, "
+            1c3b3a102f770386e4836c99e370cf9bea00803f5e482357a4ae12d414a3e63b5d31e276f8fe4a8d66b317f9ac683f44680a86ac35adfc3345befecb4bb188fd
+            5776926c49a3095eb108fd1098baec70aaa66999a72a82f27d848b21d4a741b0c5cd4d5fff9dac89aeba122961d03a757123e9870f8acf1000020887891429ca2
+            a3e7a7d7df7b10355165c8b9a6d0a7de8b062c4500dc4cd120c0f7418dae3d0b5781c34803fa75421c790dfe1de1834f280d7667b327f6c8cd7557e12ac3a0f93
+            ec05c52e0493ef31a12d3d9260f79a289d6a379bc70c50841473d1a8cc81ec583e9645e07b8d9670655ba5bbcfecc6dc3966380ad8fecb17b6ba02469a020a84e
+            18e8f84252070c13e9f1f289be54fbc481457778f616015e1327a02b140f1505eb309326d68378f8374595c849d84f4c333ec4423885143cb47bd71c5edae9be6
+            9a2ffeceb1bec9de244fbe15992b11b77c040f12bd8f6a975a44a0f90c29a9abc3d4d893927284c58754cce294529f8614dcd2aba991925fedc4ae74ffac6e333
+            b93eb4aff0479da9a410e4450e0dd7ae4c6e2910900575da401fc07059f645e8b7e9bfdef33943054ff84011493c27b3429eaedb4ed5376441a77ed43851ad77f
+            16f541dfd269d50d6a5f14fb0aab1cbb4c1550be97f7ab4066193c4caa773dad38014bd2092fa755c824bb5e54c4f36ffda9fcea70b9c6e693e148c151"),
+        (0xFFFF, "
+            77a31251618a15e6b92d1d66dffe7b50b50bad552305ba0217a610688eff7e11e1d0225438e093242d6db274fde801d4cae06f2092c728b2478559df58e837c2
+            469ee4a4fa794e4bbc7f39bc026e3cb72c33b0888f25b4acf56a2a9804f1ce6d3d6e1dc6ca181d4b546179d55544aa7760c40d06741539c7e3cd9d2f6650b201
+            3fd0eeb8c2b8e3d8d240ccae2d4c98320a7442e1c8d75a42d6e6cfa4c2eca1798d158c7aecdf82490f24bb9b38e108bcda12c3faf9a21141c3613b58367f922aa
+            a26cd22f23d708dae699ad7cb40a8ad0b6e2784973dcb605684c08b8d6998c69aac049921871ebb65301a4619ca80ecb485a31d744223ce8ddc2394828d6a8047
+            0c092f5ba413c3378fa6054255c6f9df4495862bbb3287681f931b687c888abf844dfc8fc28331e579928cd12bd2390ae123cf03818d14dedde5c0c24c8ab018b
+            fca75ca096f2d531f3d1619e785f1ada437cab92e980558b3dce1474afb75bfedbf8ff54cb2618e0244c9ac0d3c66fb51598cd2db11f9be39791abe447c63094f
+            7c453b7ff87cb5bb36b7c79efb0872d17058b83b15ab0866ad8a58656c5a7e20dbdf308b2461d97c0ec0024a2715055249cf3b478ddd4740de654f75ca686e0d7
+            345c69ed50cdc2a8b332b1f8824108ac937eb050585608ee734097fc09054fbff89eeaeea791f4a7ab1f9868294a4f9e27b42af8100cb9d59cef9645803"),
+    ];
+    let mut key = [0u8; xts::XTS_KEY_SIZE];
+    key.copy_from_slice(&hex(IEEE_1619_XTS_AES_256_KEY));
+    let cipher = xts::XtsCipher::new(&key).unwrap();
+
+    for &(sequence_number, ciphertext) in vectors.iter() {
+        let mut sector = ieee_1619_xts_aes_256_plaintext();
+        cipher.encrypt_sector(&mut sector, sequence_number).unwrap();
+        assert_eq!(sector, hex(ciphertext), "wrong ciphertext for data unit sequence number {:#X}", sequence_number);
+        cipher.decrypt_sector(&mut sector, sequence_number).unwrap();
+        assert_eq!(sector, ieee_1619_xts_aes_256_plaintext());
+    }
+}
+
+#[test]
+fn test_xts_invalid_input() {
+    let mut key = [0u8; xts::XTS_KEY_SIZE];
+    assert!(xts::XtsCipher::new(&key).is_err());
+    key.copy_from_slice(&hex(IEEE_1619_XTS_AES_256_KEY));
+    let cipher = xts::XtsCipher::new(&key).unwrap();
+    assert!(cipher.encrypt_sector(&mut [0u8; 17], 0).is_err());
+}
+
+#[test]
+/// Test vectors from the NIST SHA-3 examples, including one that spans more than one block.
+fn test_sha3_256() {
+    assert_eq!(sha3::Sha3_256::digest(b"")[..], hex("a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a")[..]);
+    assert_eq!(sha3::Sha3_256::digest(b"abc")[..], hex("3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532")[..]);
+    assert_eq!(sha3::Sha3_256::digest(&[0xA3; 200])[..], hex("79f38adec5c20307a98ef76e8324afbfd46cfd81b22e3973c65fa1bd9de31787")[..]);
+
+    // Hashing incrementally must give the same result, regardless of how the message is split.
+    let mut hasher = sha3::Sha3_256::new();
+    hasher.update(&[0xA3; 135]);
+    hasher.update(&[0xA3; 65]);
+    assert_eq!(hasher.finalize()[..], hex("79f38adec5c20307a98ef76e8324afbfd46cfd81b22e3973c65fa1bd9de31787")[..]);
+}
+
+#[test]
+fn test_hmac_sha3_256() {
+    let mac = hmac::hmac_sha3_256(b"key", &[b"The quick brown fox ", b"jumps over the lazy dog"]);
+    assert_eq!(mac[..], hex("8c6e0683409427f8931711b10ca92a506eb1fafa48fadd66d76126f47ac2c333")[..]);
+    // A key that is longer than the block size is hashed first.
+    let mac = hmac::hmac_sha3_256(&[b'k'; 200], &[b"message"]);
+    assert_eq!(mac[..], hex("6e52f6eec362bb1d2864c87c2a81fe6f65e71cc1008b197c5fffa8043fcab284")[..]);
+}
+
+#[test]
+/// PBKDF2-HMAC-SHA3-256 vectors with the RFC 6070 inputs, for a 64-byte key as used by `block_crypt`.
+/// To run this test, execute: `cargo test test_pbkdf2_hmac_sha3_256 -- --nocapture`
+fn test_pbkdf2_hmac_sha3_256() {
+    let vectors: [(&[u8], &[u8], u32, &str); 4] = [
+        (b"password", b"salt", 1,
+            "94613f3ee2ea730e0b06754f3fc816d4f87c9be9cbd8556b5d59b52330e333a801e338de77f38790bfd934b773de26e2d81253b98e6e5b64fe4e0ceec14d7700"),
+        (b"password", b"salt", 2,
+            "4c915baedd1773383e77fcfe38114ca7514010adec24b47290ec170208423f76f876ee35e753a3f7db245273aef5d8ba6908e3e58b29a8c729f5d0fba30196c7"),
+        (b"password", b"salt", 4096,
+            "778b6e237a0f49621549ff70d218d2080756b9fb38d71b5d7ef447fa2254af6117d7ca350908e28d29391136ee8ffc9273b40d67647da772fa6b480cec314990"),
+        (b"passwordPASSWORDpassword", b"saltSALTsaltSALTsaltSALTsaltSALTsalt", 4096,
+            "7aef8f1ad8c7f12205334f624d4af9e2863121618f7a0b3209bef3934801c39feac24ef0ac6a5c252eb5a977f4036f5b04193036c24a6e5d32ba267f2dc5e3f6"),
+    ];
+    for &(password, salt, iterations, expected) in vectors.iter() {
+        let mut output = [0u8; 64];
+        pbkdf2::pbkdf2_hmac_sha3_256(password, salt, iterations, &mut output);
+        assert_eq!(output[..], hex(expected)[..], "wrong key for {} iterations", iterations);
+    }
+}
+
+#[test]
+fn test_constant_time_eq() {
+    assert!(util::constant_time_eq(b"", b""));
+    assert!(util::constant_time_eq(b"secret", b"secret"));
+    assert!(!util::constant_time_eq(b"secret", b"secreT"));
+    assert!(!util::constant_time_eq(b"secret", b"secrets"));
+}
+
+#[test]
+/// The example from RFC 7693, Appendix B, and vectors from the reference implementation's KAT,
+/// including keyed hashing and one message that spans several blocks.
+fn test_blake2s() {
+    assert_eq!(blake2s::Blake2s::digest(b"abc")[..], hex("508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982")[..]);
+    assert_eq!(blake2s::Blake2s::digest(b"")[..], hex("69217a3079908094e11121d042354a7c1f55b6482ca1a51e1b250dfd1ed0eef9")[..]);
+
+    let key: Vec<u8> = (0 .. 32u8).collect();
+    let message: Vec<u8> = (0 .. 255u8).collect();
+    let mut hasher = blake2s::Blake2s::new_keyed(&key, 32).unwrap();
+    hasher.update(&message);
+    assert_eq!(hasher.finalize()[..], hex("3fb735061abc519dfe979e54c1ee5bfad0a9d858b3315bad34bde999efd724dd")[..]);
+    // A keyed hash of an empty message only hashes the key block.
+    let hasher = blake2s::Blake2s::new_keyed(&key, 32).unwrap();
+    assert_eq!(hasher.finalize()[..], hex("48a8997da407876b3d79c0d92325ad3b89cbb754d86ab71aee047ad345fd2c49")[..]);
+
+    // A shorter output is a different hash, not a truncation, and the rest of the output is zero.
+    let mut hasher = blake2s::Blake2s::new_keyed(&key, 16).unwrap();
+    hasher.update(b"The quick brown fox jumps over the lazy dog");
+    let output = hasher.finalize();
+    assert_eq!(output[.. 16], hex("4e2219ec6d234a478e4ace91429bbce4")[..]);
+    assert_eq!(output[16 ..], [0u8; 16]);
+
+    // Hashing incrementally must give the same result, regardless of how the message is split.
+    let mut hasher = blake2s::Blake2s::new();
+    hasher.update(&[0; 64]);
+    hasher.update(&[0; 100]);
+    hasher.update(&[0; 36]);
+    assert_eq!(hasher.finalize()[..], hex("3c94fdf9c93a0d2f4e63bfd0e4a181dfb7ad5dc6e1e479cf9f8f1cb2b22d93ae")[..]);
+
+    assert!(blake2s::Blake2s::new_keyed(&[0; 33], 32).is_err());
+    assert!(blake2s::Blake2s::new_keyed(&key, 0).is_err());
+    assert!(blake2s::Blake2s::new_keyed(&key, 33).is_err());
+}
+
+#[test]
+fn test_hmac_blake2s() {
+    let mac = hmac::hmac_blake2s(b"key", &[b"The quick brown fox ", b"jumps over the lazy dog"]);
+    assert_eq!(mac[..], hex("f93215bb90d4af4c3061cd932fb169fb8bb8a91d0b4022baea1271e1323cd9a0")[..]);
+    // A key that is longer than the block size is hashed first.
+    let mac = hmac::hmac_blake2s(&[b'k'; 200], &[b"message"]);
+    assert_eq!(mac[..], hex("86b8ec7badab87180b6a071486607cbf82afbae9ba1cadbc050ee36ea1ac54d3")[..]);
+}
+
+#[test]
+/// The ChaCha20 block function test vector from RFC 8439, section 2.3.2.
+fn test_chacha20_block() {
+    let mut key = [0u8; chacha20poly1305::CHACHA20_KEY_SIZE];
+    key.copy_from_slice(&(0 .. 32u8).collect::<Vec<u8>>());
+    let mut nonce = [0u8; chacha20poly1305::CHACHA20_NONCE_SIZE];
+    nonce.copy_from_slice(&hex("000000090000004a00000000"));
+    let block = chacha20poly1305::chacha20_block(&key, 1, &nonce);
+    assert_eq!(block[..], hex("
+        10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e
+        d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e")[..]);
+}
+
+#[test]
+/// The Poly1305 test vector from RFC 8439, section 2.5.2, also authenticated incrementally.
+fn test_poly1305() {
+    let mut key = [0u8; chacha20poly1305::POLY1305_KEY_SIZE];
+    key.copy_from_slice(&hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b"));
+    let message = b"Cryptographic Forum Research Group";
+    assert_eq!(chacha20poly1305::poly1305(&key, message)[..], hex("a8061dc1305136c6c22b8baf0c0127a9")[..]);
+
+    let mut authenticator = chacha20poly1305::Poly1305::new(&key);
+    authenticator.update(&message[.. 5]);
+    authenticator.update(&message[5 .. 21]);
+    authenticator.update(&message[21 ..]);
+    assert_eq!(authenticator.finalize()[..], hex("a8061dc1305136c6c22b8baf0c0127a9")[..]);
+}
+
+#[test]
+/// The ChaCha20-Poly1305 AEAD test vector from RFC 8439, section 2.8.2, and an empty message.
+/// To run this test, execute: `cargo test test_chacha20_poly1305 -- --nocapture`
+fn test_chacha20_poly1305() {
+    let mut key = [0u8; chacha20poly1305::CHACHA20_KEY_SIZE];
+    key.copy_from_slice(&(0x80 .. 0xA0u8).collect::<Vec<u8>>());
+    let mut nonce = [0u8; chacha20poly1305::CHACHA20_NONCE_SIZE];
+    nonce.copy_from_slice(&hex("070000004041424344454647"));
+    let aad = hex("50515253c0c1c2c3c4c5c6c7");
+    let plaintext: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+    let expected = hex("
+        d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d63dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36
+        92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc3ff4def08e4b7a9de576d26586cec64b6116
+        1ae10b594f09e26a7e902ecbd0600691");
+    let (expected_ciphertext, expected_tag) = expected.split_at(plaintext.len());
+
+    let cipher = chacha20poly1305::ChaCha20Poly1305::new(&key);
+    let mut buffer = plaintext.to_vec();
+    let tag = cipher.encrypt_in_place(&nonce, &aad, &mut buffer).unwrap();
+    assert_eq!(buffer[..], expected_ciphertext[..]);
+    assert_eq!(tag[..], expected_tag[..]);
+
+    // A modified ciphertext or additional data must be rejected without decrypting anything.
+    buffer[0] ^= 1;
+    assert!(cipher.decrypt_in_place(&nonce, &aad, &mut buffer, &tag).is_err());
+    assert_eq!(buffer[1 ..], expected_ciphertext[1 ..]);
+    buffer[0] ^= 1;
+    assert!(cipher.decrypt_in_place(&nonce, b"", &mut buffer, &tag).is_err());
+    cipher.decrypt_in_place(&nonce, &aad, &mut buffer, &tag).unwrap();
+    assert_eq!(buffer[..], plaintext[..]);
+
+    let tag = cipher.encrypt_in_place(&nonce, b"", &mut []).unwrap();
+    assert_eq!(tag[..], hex("a0784d7a4716f3feb4f64e7f4b39bf04")[..]);
+}
+
+#[test]
+/// The test vectors from RFC 7748, sections 5.2 and 6.1.
+/// To run this test, execute: `cargo test test_x25519 -- --nocapture`
+fn test_x25519() {
+    let key = |s: &str| {
+        let mut key = [0u8; x25519::X25519_KEY_SIZE];
+        key.copy_from_slice(&hex(s));
+        key
+    };
+    assert_eq!(
+        x25519::x25519(&key("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4"), &key("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c")),
+        key("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552"),
+    );
+    assert_eq!(
+        x25519::x25519(&key("4b66e9d4d1b4673c5ad22691957d6af5c11b6421e0ea01d42ca4169e7918ba0d"), &key("e5210f12786811d3f4b7959d0538ae2c31dbe7106fc03c3efc4cd549c715a493")),
+        key("95cbde9476e8907d7aade45cb4b873f88b595a68799fa152e6f8f7647aac7957"),
+    );
+
+    let alice_private = key("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+    let bob_private = key("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+    let alice_public = x25519::x25519_public_key(&alice_private);
+    let bob_public = x25519::x25519_public_key(&bob_private);
+    assert_eq!(alice_public, key("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"));
+    assert_eq!(bob_public, key("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f"));
+    let shared = key("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+    assert_eq!(x25519::x25519(&alice_private, &bob_public), shared);
+    assert_eq!(x25519::x25519(&bob_private, &alice_public), shared);
+
+    // Iterating the function on its own output, starting from the base point.
+    let (mut k, mut u) = (key("0900000000000000000000000000000000000000000000000000000000000000"), key("0900000000000000000000000000000000000000000000000000000000000000"));
+    for i in 1 ..= 1000 {
+        let result = x25519::x25519(&k, &u);
+        u = k;
+        k = result;
+        if i == 1 {
+            assert_eq!(k, key("422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079"));
+        }
+    }
+    assert_eq!(k, key("684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51"));
+}
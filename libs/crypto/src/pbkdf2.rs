@@ -0,0 +1,25 @@
+//! The PBKDF2 password-based key derivation function (RFC 8018) with HMAC-SHA3-256.
+
+use hmac::HmacSha3_256;
+use sha3::SHA3_256_OUTPUT_SIZE;
+
+/// Derives a key from the given `password` and `salt` using PBKDF2 with HMAC-SHA3-256,
+/// filling the entire `output`.
+///
+/// A larger number of `iterations` makes brute-forcing the password slower;
+/// zero iterations are treated as one.
+pub fn pbkdf2_hmac_sha3_256(password: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) {
+    let prf = HmacSha3_256::new(password);
+    for (i, output_block) in output.chunks_mut(SHA3_256_OUTPUT_SIZE).enumerate() {
+        let block_index = (i as u32 + 1).to_be_bytes();
+        let mut u = prf.mac(&[salt, &block_index[..]]);
+        let mut t = u;
+        for _ in 1 .. iterations {
+            u = prf.mac(&[&u[..]]);
+            for (t_byte, u_byte) in t.iter_mut().zip(u.iter()) {
+                *t_byte ^= *u_byte;
+            }
+        }
+        output_block.copy_from_slice(&t[.. output_block.len()]);
+    }
+}
@@ -0,0 +1,118 @@
+//! The SHA3-256 hash function (FIPS 202), based on the Keccak-f[1600] permutation.
+
+/// The output size of SHA3-256 in bytes.
+pub const SHA3_256_OUTPUT_SIZE: usize = 32;
+
+/// The rate of SHA3-256 in bytes, i.e., how many bytes of input are absorbed per permutation.
+/// This is also its block size for HMAC.
+pub const SHA3_256_RATE: usize = 136;
+
+/// The number of 64-bit lanes in the Keccak state.
+const LANES: usize = 25;
+
+/// The round constants of the iota step.
+const ROUND_CONSTANTS: [u64; 24] = [
+    0x0000_0000_0000_0001, 0x0000_0000_0000_8082, 0x8000_0000_0000_808A, 0x8000_0000_8000_8000,
+    0x0000_0000_0000_808B, 0x0000_0000_8000_0001, 0x8000_0000_8000_8081, 0x8000_0000_0000_8009,
+    0x0000_0000_0000_008A, 0x0000_0000_0000_0088, 0x0000_0000_8000_8009, 0x0000_0000_8000_000A,
+    0x0000_0000_8000_808B, 0x8000_0000_0000_008B, 0x8000_0000_0000_8089, 0x8000_0000_0000_8003,
+    0x8000_0000_0000_8002, 0x8000_0000_0000_0080, 0x0000_0000_0000_800A, 0x8000_0000_8000_000A,
+    0x8000_0000_8000_8081, 0x8000_0000_0000_8080, 0x0000_0000_8000_0001, 0x8000_0000_8000_8008,
+];
+/// The rotation offsets of the rho step, in the order in which the pi step visits the lanes.
+const RHO_OFFSETS: [u32; 24] = [1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44];
+/// The order in which the pi step visits the lanes, starting from lane 1.
+const PI_LANES: [usize; 24] = [10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1];
+
+
+/// An incremental SHA3-256 hasher.
+///
+/// Cloning a hasher copies its state, e.g., to reuse a common prefix of several messages.
+#[derive(Clone, Default)]
+pub struct Sha3_256 {
+    state: [u64; LANES],
+    /// The number of bytes absorbed into the current block of the state.
+    position: usize,
+}
+
+impl Sha3_256 {
+    /// Creates a new hasher for an empty message.
+    pub fn new() -> Sha3_256 {
+        Sha3_256::default()
+    }
+
+    /// Returns the SHA3-256 hash of the given `data`.
+    pub fn digest(data: &[u8]) -> [u8; SHA3_256_OUTPUT_SIZE] {
+        let mut hasher = Sha3_256::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Appends the given `data` to the message being hashed.
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.absorb_byte(byte);
+            if self.position == SHA3_256_RATE {
+                keccak_f(&mut self.state);
+                self.position = 0;
+            }
+        }
+    }
+
+    /// Returns the hash of the message.
+    pub fn finalize(mut self) -> [u8; SHA3_256_OUTPUT_SIZE] {
+        // The SHA3 domain separation bits `01` followed by the `pad10*1` padding.
+        self.absorb_byte(0x06);
+        self.position = SHA3_256_RATE - 1;
+        self.absorb_byte(0x80);
+        keccak_f(&mut self.state);
+
+        let mut output = [0u8; SHA3_256_OUTPUT_SIZE];
+        for (bytes, lane) in output.chunks_exact_mut(8).zip(self.state.iter()) {
+            bytes.copy_from_slice(&lane.to_le_bytes());
+        }
+        output
+    }
+
+    /// XORs the given `byte` into the state at the current position, and advances the position.
+    fn absorb_byte(&mut self, byte: u8) {
+        self.state[self.position / 8] ^= (byte as u64) << (8 * (self.position % 8));
+        self.position += 1;
+    }
+}
+
+/// The Keccak-f[1600] permutation.
+fn keccak_f(state: &mut [u64; LANES]) {
+    for round_constant in ROUND_CONSTANTS.iter() {
+        // theta
+        let mut parities = [0u64; 5];
+        for x in 0 .. 5 {
+            parities[x] = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+        }
+        for x in 0 .. 5 {
+            let t = parities[(x + 4) % 5] ^ parities[(x + 1) % 5].rotate_left(1);
+            for y in (0 .. LANES).step_by(5) {
+                state[y + x] ^= t;
+            }
+        }
+
+        // rho and pi
+        let mut current = state[1];
+        for (&lane, &offset) in PI_LANES.iter().zip(RHO_OFFSETS.iter()) {
+            let next = state[lane];
+            state[lane] = current.rotate_left(offset);
+            current = next;
+        }
+
+        // chi
+        for y in (0 .. LANES).step_by(5) {
+            let row = [state[y], state[y + 1], state[y + 2], state[y + 3], state[y + 4]];
+            for x in 0 .. 5 {
+                state[y + x] = row[x] ^ (!row[(x + 1) % 5] & row[(x + 2) % 5]);
+            }
+        }
+
+        // iota
+        state[0] ^= round_constant;
+    }
+}
@@ -0,0 +1,10 @@
+//! Helpers that are shared by the other primitives and by users of this crate.
+
+/// Returns whether the given byte slices are equal, in an amount of time that depends only on their lengths,
+/// not on their contents, such that comparing secret values like MACs doesn't leak where they first differ.
+///
+/// Note that the lengths themselves are not hidden, so secrets of varying length, e.g., passwords,
+/// should first be turned into values of a fixed length, e.g., by a MAC, and then compared.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
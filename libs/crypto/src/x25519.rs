@@ -0,0 +1,211 @@
+//! The X25519 Diffie-Hellman function on Curve25519 (RFC 7748).
+//!
+//! Field elements modulo 2^255 - 19 are represented by five 51-bit limbs,
+//! and the Montgomery ladder processes every scalar bit with the same sequence of operations,
+//! such that the running time doesn't depend on the (secret) scalar.
+
+/// The size of X25519 scalars (private keys), u-coordinates (public keys), and shared secrets in bytes.
+pub const X25519_KEY_SIZE: usize = 32;
+
+/// The u-coordinate of the Curve25519 base point.
+const BASE_POINT: [u8; X25519_KEY_SIZE] = [
+    9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// The constant (A - 2) / 4 of the Curve25519 ladder step, where A = 486662.
+const A24: u64 = 121_665;
+
+const MASK_51: u64 = (1 << 51) - 1;
+
+
+/// Returns the public key that corresponds to the given `private_key`,
+/// i.e., the X25519 function of the private key and the base point.
+pub fn x25519_public_key(private_key: &[u8; X25519_KEY_SIZE]) -> [u8; X25519_KEY_SIZE] {
+    x25519(private_key, &BASE_POINT)
+}
+
+/// Returns the X25519 function of the given `scalar`, e.g., a private key,
+/// and the given `u_coordinate`, e.g., the peer's public key, which is their shared secret.
+///
+/// The scalar is clamped as specified by RFC 7748, so any 32 bytes are a valid private key.
+#[allow(clippy::many_single_char_names)] // the names of RFC 7748
+pub fn x25519(scalar: &[u8; X25519_KEY_SIZE], u_coordinate: &[u8; X25519_KEY_SIZE]) -> [u8; X25519_KEY_SIZE] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+
+    let x1 = Fe::from_bytes(u_coordinate);
+    let mut x2 = Fe::ONE;
+    let mut z2 = Fe::ZERO;
+    let mut x3 = x1;
+    let mut z3 = Fe::ONE;
+    let mut swap = 0u64;
+    for t in (0 .. 255).rev() {
+        let k_t = ((k[t / 8] >> (t % 8)) & 1) as u64;
+        swap ^= k_t;
+        Fe::conditional_swap(&mut x2, &mut x3, swap);
+        Fe::conditional_swap(&mut z2, &mut z3, swap);
+        swap = k_t;
+
+        let a = x2.add(&z2);
+        let aa = a.square();
+        let b = x2.sub(&z2);
+        let bb = b.square();
+        let e = aa.sub(&bb);
+        let c = x3.add(&z3);
+        let d = x3.sub(&z3);
+        let da = d.mul(&a);
+        let cb = c.mul(&b);
+        x3 = da.add(&cb).square();
+        z3 = x1.mul(&da.sub(&cb).square());
+        x2 = aa.mul(&bb);
+        z2 = e.mul(&aa.add(&e.mul(&Fe([A24, 0, 0, 0, 0]))));
+    }
+    Fe::conditional_swap(&mut x2, &mut x3, swap);
+    Fe::conditional_swap(&mut z2, &mut z3, swap);
+    x2.mul(&z2.invert()).to_bytes()
+}
+
+
+/// An element of the field modulo 2^255 - 19, whose limbs may exceed 51 bits between reductions.
+#[derive(Clone, Copy)]
+struct Fe([u64; 5]);
+
+impl Fe {
+    const ZERO: Fe = Fe([0, 0, 0, 0, 0]);
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    /// Decodes a little-endian field element, ignoring the most significant bit as RFC 7748 requires.
+    fn from_bytes(bytes: &[u8; 32]) -> Fe {
+        let load = |offset: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&bytes[offset .. offset + 8]);
+            u64::from_le_bytes(buf)
+        };
+        Fe([
+            load(0) & MASK_51,
+            (load(6) >> 3) & MASK_51,
+            (load(12) >> 6) & MASK_51,
+            (load(19) >> 1) & MASK_51,
+            (load(24) >> 12) & MASK_51,
+        ])
+    }
+
+    /// Encodes this field element as 32 little-endian bytes, fully reduced modulo 2^255 - 19.
+    fn to_bytes(&self) -> [u8; 32] {
+        let mut l = self.carry().carry().0;
+        // Now `l < 2^255 + 2^13`, so subtracting p once at most gives the canonical value.
+        // `q` is 1 if `l >= p`, i.e., if `l + 19 >= 2^255`.
+        let mut q = (l[0] + 19) >> 51;
+        q = (l[1] + q) >> 51;
+        q = (l[2] + q) >> 51;
+        q = (l[3] + q) >> 51;
+        q = (l[4] + q) >> 51;
+        l[0] += 19 * q;
+        for i in 0 .. 4 {
+            l[i + 1] += l[i] >> 51;
+            l[i] &= MASK_51;
+        }
+        // Dropping bit 255 subtracts the remaining 2^255.
+        l[4] &= MASK_51;
+
+        let mut bytes = [0u8; 32];
+        let mut accumulator: u128 = 0;
+        let mut bits = 0;
+        let mut position = 0;
+        for &limb in l.iter() {
+            accumulator |= (limb as u128) << bits;
+            bits += 51;
+            while bits >= 8 && position < 32 {
+                bytes[position] = accumulator as u8;
+                accumulator >>= 8;
+                bits -= 8;
+                position += 1;
+            }
+        }
+        if position < 32 {
+            bytes[position] = accumulator as u8;
+        }
+        bytes
+    }
+
+    /// Propagates the carries of all limbs, such that every limb has at most 51 bits, except for a tiny excess in the first.
+    fn carry(&self) -> Fe {
+        let mut l = self.0;
+        for i in 0 .. 4 {
+            l[i + 1] += l[i] >> 51;
+            l[i] &= MASK_51;
+        }
+        l[0] += 19 * (l[4] >> 51);
+        l[4] &= MASK_51;
+        Fe(l)
+    }
+
+    fn add(&self, other: &Fe) -> Fe {
+        let (a, b) = (self.0, other.0);
+        Fe([a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3], a[4] + b[4]])
+    }
+
+    /// Subtracts `other`, whose limbs must be below 2^53, by first adding 4p to avoid underflow.
+    fn sub(&self, other: &Fe) -> Fe {
+        let (a, b) = (self.0, other.0);
+        Fe([
+            (a[0] + 0x1F_FFFF_FFFF_FFB4) - b[0],
+            (a[1] + 0x1F_FFFF_FFFF_FFFC) - b[1],
+            (a[2] + 0x1F_FFFF_FFFF_FFFC) - b[2],
+            (a[3] + 0x1F_FFFF_FFFF_FFFC) - b[3],
+            (a[4] + 0x1F_FFFF_FFFF_FFFC) - b[4],
+        ]).carry()
+    }
+
+    fn mul(&self, other: &Fe) -> Fe {
+        let (a, b) = (self.0, other.0);
+        let m = |x: u64, y: u64| x as u128 * y as u128;
+        let (b1_19, b2_19, b3_19, b4_19) = (b[1] * 19, b[2] * 19, b[3] * 19, b[4] * 19);
+        let c0 = m(a[0], b[0]) + m(a[4], b1_19) + m(a[3], b2_19) + m(a[2], b3_19) + m(a[1], b4_19);
+        let mut c1 = m(a[1], b[0]) + m(a[0], b[1]) + m(a[4], b2_19) + m(a[3], b3_19) + m(a[2], b4_19);
+        let mut c2 = m(a[2], b[0]) + m(a[1], b[1]) + m(a[0], b[2]) + m(a[4], b3_19) + m(a[3], b4_19);
+        let mut c3 = m(a[3], b[0]) + m(a[2], b[1]) + m(a[1], b[2]) + m(a[0], b[3]) + m(a[4], b4_19);
+        let mut c4 = m(a[4], b[0]) + m(a[3], b[1]) + m(a[2], b[2]) + m(a[1], b[3]) + m(a[0], b[4]);
+
+        let mut l = [0u64; 5];
+        c1 += c0 >> 51; l[0] = c0 as u64 & MASK_51;
+        c2 += c1 >> 51; l[1] = c1 as u64 & MASK_51;
+        c3 += c2 >> 51; l[2] = c2 as u64 & MASK_51;
+        c4 += c3 >> 51; l[3] = c3 as u64 & MASK_51;
+        let carry = c4 >> 51; l[4] = c4 as u64 & MASK_51;
+        let t0 = l[0] as u128 + carry * 19;
+        l[0] = t0 as u64 & MASK_51;
+        l[1] += (t0 >> 51) as u64;
+        Fe(l)
+    }
+
+    fn square(&self) -> Fe {
+        self.mul(self)
+    }
+
+    /// Returns the multiplicative inverse of this element, as `self^(p - 2)`; the inverse of zero is zero.
+    fn invert(&self) -> Fe {
+        // p - 2 = 2^255 - 21, whose bits are all set except for bits 2, 4 and 255.
+        let mut result = Fe::ONE;
+        for bit in (0 .. 255).rev() {
+            result = result.square();
+            if bit != 2 && bit != 4 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+
+    /// Swaps `a` and `b` if `swap` is 1, and leaves them unchanged if it's 0, in constant time.
+    fn conditional_swap(a: &mut Fe, b: &mut Fe, swap: u64) {
+        let mask = 0u64.wrapping_sub(swap);
+        for i in 0 .. 5 {
+            let t = mask & (a.0[i] ^ b.0[i]);
+            a.0[i] ^= t;
+            b.0[i] ^= t;
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! The XTS block cipher mode (IEEE 1619) on top of AES-256,
+//! which is the standard mode for encrypting storage devices at the sector level.
+//!
+//! Each sector is encrypted independently using a "tweak" derived from its sector number,
+//! such that identical plaintext sectors at different locations produce different ciphertext.
+//! Because sectors are always a multiple of the AES block size, ciphertext stealing is not supported.
+
+use core::convert::TryInto;
+use aes::{Aes256, AES_BLOCK_SIZE, AES_256_KEY_SIZE};
+
+/// The size in bytes of an XTS key, which consists of two AES-256 keys:
+/// the first half is the data key, and the second half is the tweak key.
+pub const XTS_KEY_SIZE: usize = 2 * AES_256_KEY_SIZE;
+
+/// A pair of AES-256 ciphers that encrypt and decrypt whole sectors in XTS mode.
+pub struct XtsCipher {
+    /// The cipher used to encrypt/decrypt the sector contents.
+    data_cipher: Aes256,
+    /// The cipher used to encrypt the sector number into the initial tweak value.
+    tweak_cipher: Aes256,
+}
+
+impl XtsCipher {
+    /// Creates a new `XtsCipher` from the given 512-bit key.
+    ///
+    /// Returns an error if the two halves of the key are identical,
+    /// which would nullify the security guarantees of XTS mode.
+    pub fn new(key: &[u8; XTS_KEY_SIZE]) -> Result<XtsCipher, &'static str> {
+        let (data_key, tweak_key) = key.split_at(AES_256_KEY_SIZE);
+        if data_key == tweak_key {
+            return Err("XtsCipher::new(): the data key and tweak key must be different");
+        }
+        let data_key: &[u8; AES_256_KEY_SIZE] = data_key.try_into().map_err(|_| "BUG: XtsCipher::new(): invalid data key size")?;
+        let tweak_key: &[u8; AES_256_KEY_SIZE] = tweak_key.try_into().map_err(|_| "BUG: XtsCipher::new(): invalid tweak key size")?;
+        Ok(XtsCipher {
+            data_cipher: Aes256::new(data_key),
+            tweak_cipher: Aes256::new(tweak_key),
+        })
+    }
+
+    /// Encrypts the given `sector` in place, using the given `sector_number` as the tweak.
+    ///
+    /// The length of `sector` must be a multiple of the AES block size (16 bytes).
+    pub fn encrypt_sector(&self, sector: &mut [u8], sector_number: u64) -> Result<(), &'static str> {
+        self.process_sector(sector, sector_number, true)
+    }
+
+    /// Decrypts the given `sector` in place, using the given `sector_number` as the tweak.
+    ///
+    /// The length of `sector` must be a multiple of the AES block size (16 bytes).
+    pub fn decrypt_sector(&self, sector: &mut [u8], sector_number: u64) -> Result<(), &'static str> {
+        self.process_sector(sector, sector_number, false)
+    }
+
+    fn process_sector(&self, sector: &mut [u8], sector_number: u64, encrypt: bool) -> Result<(), &'static str> {
+        if sector.len() % AES_BLOCK_SIZE != 0 {
+            return Err("XtsCipher: sector length must be a multiple of the AES block size");
+        }
+
+        // The initial tweak is the encrypted little-endian sector number ("plain64" in Linux dm-crypt terms),
+        // which IEEE 1619 calls the data unit sequence number.
+        let mut tweak = [0u8; AES_BLOCK_SIZE];
+        tweak[.. 8].copy_from_slice(&sector_number.to_le_bytes());
+        self.tweak_cipher.encrypt_block(&mut tweak);
+
+        for block in sector.chunks_exact_mut(AES_BLOCK_SIZE) {
+            let block: &mut [u8; AES_BLOCK_SIZE] = block.try_into().map_err(|_| "BUG: XtsCipher: invalid block size")?;
+            xor_in_place(block, &tweak);
+            if encrypt {
+                self.data_cipher.encrypt_block(block);
+            } else {
+                self.data_cipher.decrypt_block(block);
+            }
+            xor_in_place(block, &tweak);
+            multiply_tweak_by_alpha(&mut tweak);
+        }
+        Ok(())
+    }
+}
+
+fn xor_in_place(block: &mut [u8; AES_BLOCK_SIZE], tweak: &[u8; AES_BLOCK_SIZE]) {
+    for (b, t) in block.iter_mut().zip(tweak.iter()) {
+        *b ^= *t;
+    }
+}
+
+/// Multiplies the tweak by the primitive element α (i.e., `x`) in GF(2^128),
+/// using the little-endian byte order and reduction polynomial `x^128 + x^7 + x^2 + x + 1` defined by XTS.
+fn multiply_tweak_by_alpha(tweak: &mut [u8; AES_BLOCK_SIZE]) {
+    let mut carry = 0u8;
+    for byte in tweak.iter_mut() {
+        let next_carry = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next_carry;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
@@ -0,0 +1,319 @@
+//! A randomized stress test harness that checks the invariants of any frame allocator.
+//!
+//! The harness drives a [`StressTarget`] with a random sequence of single-frame allocations,
+//! contiguous allocations, and deallocations, and after every step it checks that:
+//! * no frame is handed out twice, i.e., while it is still allocated,
+//! * no frame outside of the managed ranges, e.g., an occupied frame, is ever handed out,
+//! * contiguous allocations return exactly the requested number of frames,
+//! * the allocator's free frame count matches the number of frames that are not allocated.
+//!
+//! At the end, it deallocates every frame and checks that all of them can be allocated again,
+//! i.e., that freed frames are eventually reusable.
+//!
+//! This crate only deals with frame numbers and doesn't depend on the `memory` crate,
+//! such that it can be tested on the host, e.g., with `cargo test -- --nocapture` in this directory.
+//! Within Theseus, the `test_frame_allocators` application runs it on every frame allocator backend.
+//!
+//! [`StressTarget`]: trait.StressTarget.html
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use] extern crate std;
+
+extern crate alloc;
+
+use alloc::{
+    collections::BTreeSet,
+    vec::Vec,
+};
+use core::{fmt, ops::Range};
+
+
+/// A frame allocator that can be stress tested, in terms of frame numbers.
+pub trait StressTarget {
+    /// Allocates a single frame and returns its number, or `None` if the allocation failed.
+    fn allocate_frame(&mut self) -> Option<usize>;
+    /// Allocates `num_frames` contiguous frames and returns the range of their numbers,
+    /// or `None` if the allocation failed.
+    fn allocate_frames(&mut self, num_frames: usize) -> Option<Range<usize>>;
+    /// Deallocates the frame with the given number, which was previously allocated.
+    fn deallocate_frame(&mut self, frame: usize);
+    /// Returns the number of frames that are available for allocation.
+    fn free_frame_count(&self) -> usize;
+}
+
+
+/// The parameters of one stress test run.
+#[derive(Clone, Debug)]
+pub struct StressConfig<'r> {
+    /// The seed of the random sequence of operations, such that a failing run can be reproduced.
+    pub seed: u64,
+    /// The number of random operations to perform.
+    pub steps: usize,
+    /// The maximum number of frames that a contiguous allocation requests.
+    pub max_contiguous: usize,
+    /// The ranges of frame numbers that the allocator manages, all of which must initially be free.
+    pub managed: &'r [Range<usize>],
+}
+
+/// Statistics about a successful stress test run.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StressStats {
+    /// The number of single frames that were allocated.
+    pub frames_allocated: usize,
+    /// The number of contiguous allocations that succeeded.
+    pub contiguous_allocated: usize,
+    /// The number of contiguous allocations that failed, e.g., due to fragmentation.
+    pub contiguous_failed: usize,
+    /// The number of frames that were deallocated.
+    pub frames_deallocated: usize,
+    /// The highest number of frames that were allocated at once.
+    pub max_allocated: usize,
+}
+
+/// A violation of one of the frame allocator's invariants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StressFailure {
+    /// The seed of the failing run.
+    pub seed: u64,
+    /// The step at which the violation was found; `steps` if it was found at the end of the run.
+    pub step: usize,
+    /// The frame involved in the violation, if any.
+    pub frame: Option<usize>,
+    /// Which invariant was violated.
+    pub reason: &'static str,
+}
+
+impl fmt::Display for StressFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (seed {:#X}, step {}", self.reason, self.seed, self.step)?;
+        if let Some(frame) = self.frame {
+            write!(f, ", frame {:#X}", frame)?;
+        }
+        write!(f, ")")
+    }
+}
+
+
+/// Runs the stress test described by `config` on the given `target`, which must be freshly created,
+/// i.e., every frame in `config.managed` must be free and no other frame may be free.
+///
+/// The target is left with every frame deallocated.
+pub fn run(target: &mut dyn StressTarget, config: &StressConfig) -> Result<StressStats, StressFailure> {
+    let mut run = Run {
+        target,
+        config,
+        rng: Xorshift::new(config.seed),
+        allocated: Vec::new(),
+        allocated_set: BTreeSet::new(),
+        total_frames: config.managed.iter().map(|r| r.end - r.start).sum(),
+        stats: StressStats::default(),
+        step: 0,
+    };
+    run.check_free_count()?;
+
+    for step in 0 .. config.steps {
+        run.step = step;
+        match run.rng.next() % 100 {
+            0 ..= 44 => run.allocate_frame()?,
+            45 ..= 64 => {
+                let num_frames = 1 + (run.rng.next() as usize) % config.max_contiguous.max(1);
+                run.allocate_frames(num_frames)?;
+            }
+            _ => run.deallocate_random_frame(),
+        }
+        run.check_free_count()?;
+    }
+
+    run.step = config.steps;
+    run.deallocate_all();
+    run.check_free_count()?;
+
+    // Every frame must be reusable once it has been freed.
+    while run.allocated.len() < run.total_frames {
+        run.allocate_frame()?;
+    }
+    if run.target.allocate_frame().is_some() {
+        return Err(run.failure(None, "allocated more frames than the allocator manages"));
+    }
+    run.deallocate_all();
+    run.check_free_count()?;
+    Ok(run.stats)
+}
+
+
+struct Run<'t, 'c, 'r> {
+    target: &'t mut dyn StressTarget,
+    config: &'c StressConfig<'r>,
+    rng: Xorshift,
+    /// The currently-allocated frames, in no particular order.
+    allocated: Vec<usize>,
+    /// The same frames as `allocated`, for fast lookups.
+    allocated_set: BTreeSet<usize>,
+    total_frames: usize,
+    stats: StressStats,
+    step: usize,
+}
+
+impl<'t, 'c, 'r> Run<'t, 'c, 'r> {
+    fn failure(&self, frame: Option<usize>, reason: &'static str) -> StressFailure {
+        StressFailure { seed: self.config.seed, step: self.step, frame, reason }
+    }
+
+    fn is_managed(&self, frame: usize) -> bool {
+        self.config.managed.iter().any(|r| r.contains(&frame))
+    }
+
+    /// Records that the given `frame` was handed out, checking that it may be.
+    fn take(&mut self, frame: usize) -> Result<(), StressFailure> {
+        if !self.is_managed(frame) {
+            return Err(self.failure(Some(frame), "allocated a frame outside of the managed ranges"));
+        }
+        if !self.allocated_set.insert(frame) {
+            return Err(self.failure(Some(frame), "allocated a frame that was already allocated"));
+        }
+        self.allocated.push(frame);
+        self.stats.max_allocated = self.stats.max_allocated.max(self.allocated.len());
+        Ok(())
+    }
+
+    fn allocate_frame(&mut self) -> Result<(), StressFailure> {
+        match self.target.allocate_frame() {
+            Some(frame) => {
+                self.stats.frames_allocated += 1;
+                self.take(frame)
+            }
+            None if self.allocated.len() < self.total_frames => {
+                Err(self.failure(None, "couldn't allocate a frame even though some frames were free"))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn allocate_frames(&mut self, num_frames: usize) -> Result<(), StressFailure> {
+        match self.target.allocate_frames(num_frames) {
+            Some(frames) => {
+                if frames.end - frames.start != num_frames {
+                    return Err(self.failure(Some(frames.start), "allocated the wrong number of contiguous frames"));
+                }
+                self.stats.contiguous_allocated += 1;
+                for frame in frames {
+                    self.take(frame)?;
+                }
+                Ok(())
+            }
+            // Contiguous allocations may fail due to fragmentation, so only check that it wasn't spurious.
+            None if num_frames == 1 && self.allocated.len() < self.total_frames => {
+                Err(self.failure(None, "couldn't allocate one contiguous frame even though some frames were free"))
+            }
+            None => {
+                self.stats.contiguous_failed += 1;
+                Ok(())
+            }
+        }
+    }
+
+    fn deallocate_random_frame(&mut self) {
+        if self.allocated.is_empty() {
+            return;
+        }
+        let index = (self.rng.next() as usize) % self.allocated.len();
+        let frame = self.allocated.swap_remove(index);
+        self.allocated_set.remove(&frame);
+        self.target.deallocate_frame(frame);
+        self.stats.frames_deallocated += 1;
+    }
+
+    fn deallocate_all(&mut self) {
+        while !self.allocated.is_empty() {
+            self.deallocate_random_frame();
+        }
+    }
+
+    fn check_free_count(&self) -> Result<(), StressFailure> {
+        if self.target.free_frame_count() != self.total_frames - self.allocated.len() {
+            return Err(self.failure(None, "free frame count doesn't match the number of frames that aren't allocated"));
+        }
+        Ok(())
+    }
+}
+
+
+/// A simple xorshift64* pseudorandom number generator, which is good enough for picking operations.
+struct Xorshift {
+    state: u64,
+}
+
+impl Xorshift {
+    fn new(seed: u64) -> Xorshift {
+        // The state must never be zero.
+        Xorshift { state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed } }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+
+/// A trivially-correct mock frame allocator, which scans a bitmap for free frames.
+#[cfg(test)]
+struct MockAllocator {
+    start: usize,
+    used: Vec<bool>,
+    /// Makes the allocator hand out the frame that was most recently allocated again, to check that the harness notices.
+    buggy: bool,
+}
+
+#[cfg(test)]
+impl StressTarget for MockAllocator {
+    fn allocate_frame(&mut self) -> Option<usize> {
+        self.allocate_frames(1).map(|r| r.start)
+    }
+
+    fn allocate_frames(&mut self, num_frames: usize) -> Option<Range<usize>> {
+        let index = (0 ..= self.used.len().checked_sub(num_frames)?)
+            .find(|&i| self.used[i .. i + num_frames].iter().all(|used| !used))?;
+        if !(self.buggy && index > 0) {
+            for used in &mut self.used[index .. index + num_frames] {
+                *used = true;
+            }
+        }
+        Some(self.start + index .. self.start + index + num_frames)
+    }
+
+    fn deallocate_frame(&mut self, frame: usize) {
+        self.used[frame - self.start] = false;
+    }
+
+    fn free_frame_count(&self) -> usize {
+        self.used.iter().filter(|used| !**used).count()
+    }
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_mock_allocator -- --nocapture`
+fn test_mock_allocator() {
+    let managed = [0x100 .. 0x180];
+    for seed in 1 .. 20 {
+        let mut target = MockAllocator { start: 0x100, used: vec![false; 0x80], buggy: false };
+        let config = StressConfig { seed, steps: 2000, max_contiguous: 8, managed: &managed };
+        let stats = run(&mut target, &config).unwrap_or_else(|e| panic!("{}", e));
+        println!("seed {}: {:?}", seed, stats);
+        assert_eq!(target.free_frame_count(), 0x80);
+    }
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_buggy_allocator -- --nocapture`
+fn test_buggy_allocator() {
+    let managed = [0x100 .. 0x180];
+    let mut target = MockAllocator { start: 0x100, used: vec![false; 0x80], buggy: true };
+    let config = StressConfig { seed: 1, steps: 2000, max_contiguous: 8, managed: &managed };
+    let failure = run(&mut target, &config).expect_err("the harness didn't notice a frame being handed out twice");
+    println!("{}", failure);
+}
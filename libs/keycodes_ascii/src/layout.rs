@@ -0,0 +1,256 @@
+//! Keyboard layouts that map a physical key (a `Keycode`) to the character it produces.
+//!
+//! `Keycode`s are named after the keys of a US QWERTY keyboard, so they describe the position of a key
+//! rather than its label. A `KeyboardLayout` determines which character each key produces
+//! under the current modifiers, which can be changed at runtime via [`set_keyboard_layout()`].
+//!
+//! Some layouts have dead keys, which don't produce a character by themselves
+//! but instead modify the character produced by the next key, e.g., `^` followed by `a` produces `â`.
+//! Dead keys are returned as `KeySym::Dead` and can be combined with the next character via [`compose_dead_key()`].
+//!
+//! [`set_keyboard_layout()`]: fn.set_keyboard_layout.html
+//! [`compose_dead_key()`]: fn.compose_dead_key.html
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use super::{Keycode, KeyboardModifiers};
+
+
+/// The supported keyboard layouts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyboardLayout {
+    /// The US QWERTY layout, which is the default.
+    Us = 0,
+    /// The German QWERTZ layout, with dead keys for accents.
+    De = 1,
+    /// The US Dvorak layout.
+    Dvorak = 2,
+}
+
+/// All supported keyboard layouts.
+pub const KEYBOARD_LAYOUTS: [KeyboardLayout; 3] = [KeyboardLayout::Us, KeyboardLayout::De, KeyboardLayout::Dvorak];
+
+impl KeyboardLayout {
+    /// Returns the short name of this layout, e.g., `"us"`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyboardLayout::Us => "us",
+            KeyboardLayout::De => "de",
+            KeyboardLayout::Dvorak => "dvorak",
+        }
+    }
+
+    /// Returns a description of this layout.
+    pub fn description(&self) -> &'static str {
+        match self {
+            KeyboardLayout::Us => "English (US), QWERTY",
+            KeyboardLayout::De => "German, QWERTZ with dead keys",
+            KeyboardLayout::Dvorak => "English (US), Dvorak",
+        }
+    }
+
+    /// Returns the layout with the given short name, as returned by `name()`.
+    pub fn from_name(name: &str) -> Option<KeyboardLayout> {
+        KEYBOARD_LAYOUTS.iter().cloned().find(|layout| layout.name() == name)
+    }
+
+    fn from_u8(value: u8) -> KeyboardLayout {
+        match value {
+            1 => KeyboardLayout::De,
+            2 => KeyboardLayout::Dvorak,
+            _ => KeyboardLayout::Us,
+        }
+    }
+
+    /// Returns the symbol produced by the given `keycode` under the given `modifiers` in this layout.
+    pub fn keysym(&self, keycode: Keycode, modifiers: KeyboardModifiers) -> Option<KeySym> {
+        let level = if modifiers.is_alt_gr() {
+            Level::AltGr
+        } else {
+            // Caps Lock inverts the effect of Shift, but only on keys that produce letters.
+            let is_letter = match self.lookup(keycode, Level::Normal) {
+                Some(KeySym::Char(c)) => c.is_alphabetic(),
+                _ => false,
+            };
+            if modifiers.is_shift() != (modifiers.is_caps_lock() && is_letter) {
+                Level::Shift
+            } else {
+                Level::Normal
+            }
+        };
+        self.lookup(keycode, level)
+    }
+
+    fn lookup(&self, keycode: Keycode, level: Level) -> Option<KeySym> {
+        match self {
+            KeyboardLayout::Us => us(keycode, level),
+            KeyboardLayout::De => de(keycode, level),
+            KeyboardLayout::Dvorak => dvorak(keycode, level),
+        }
+    }
+}
+
+
+/// The symbol that a key produces.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeySym {
+    /// A regular character.
+    Char(char),
+    /// A dead key, which modifies the next character. The inner `char` is the (spacing) accent it adds.
+    Dead(char),
+}
+
+/// Which set of symbols of a key is used, depending on the modifiers.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Level {
+    Normal,
+    Shift,
+    AltGr,
+}
+
+
+static CURRENT_LAYOUT: AtomicU8 = AtomicU8::new(KeyboardLayout::Us as u8);
+
+/// Returns the current keyboard layout.
+pub fn keyboard_layout() -> KeyboardLayout {
+    KeyboardLayout::from_u8(CURRENT_LAYOUT.load(Ordering::Relaxed))
+}
+
+/// Changes the current keyboard layout.
+pub fn set_keyboard_layout(layout: KeyboardLayout) {
+    CURRENT_LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+
+/// Combines the given `dead` key accent with the following character `c`, e.g., `^` and `a` into `â`.
+///
+/// A dead key followed by a space produces the accent itself.
+/// Returns `None` if the accent cannot be combined with `c`.
+pub fn compose_dead_key(dead: char, c: char) -> Option<char> {
+    if c == ' ' {
+        return Some(dead);
+    }
+    let composed = match (dead, c) {
+        ('^', 'a') => 'â', ('^', 'e') => 'ê', ('^', 'i') => 'î', ('^', 'o') => 'ô', ('^', 'u') => 'û',
+        ('^', 'A') => 'Â', ('^', 'E') => 'Ê', ('^', 'I') => 'Î', ('^', 'O') => 'Ô', ('^', 'U') => 'Û',
+        ('´', 'a') => 'á', ('´', 'e') => 'é', ('´', 'i') => 'í', ('´', 'o') => 'ó', ('´', 'u') => 'ú', ('´', 'y') => 'ý',
+        ('´', 'A') => 'Á', ('´', 'E') => 'É', ('´', 'I') => 'Í', ('´', 'O') => 'Ó', ('´', 'U') => 'Ú', ('´', 'Y') => 'Ý',
+        ('`', 'a') => 'à', ('`', 'e') => 'è', ('`', 'i') => 'ì', ('`', 'o') => 'ò', ('`', 'u') => 'ù',
+        ('`', 'A') => 'À', ('`', 'E') => 'È', ('`', 'I') => 'Ì', ('`', 'O') => 'Ò', ('`', 'U') => 'Ù',
+        _ => return None,
+    };
+    Some(composed)
+}
+
+
+/// The US QWERTY layout, which is what the `Keycode` names are based on.
+fn us(keycode: Keycode, level: Level) -> Option<KeySym> {
+    match level {
+        Level::Normal => keycode.as_ascii(),
+        Level::Shift => keycode.as_ascii_shifted(),
+        Level::AltGr => None,
+    }.map(KeySym::Char)
+}
+
+/// The German QWERTZ layout.
+fn de(keycode: Keycode, level: Level) -> Option<KeySym> {
+    use self::KeySym::{Char, Dead};
+    let sym = match (level, keycode) {
+        (Level::Normal, Keycode::Backtick)       => Dead('^'),
+        (Level::Normal, Keycode::Minus)          => Char('ß'),
+        (Level::Normal, Keycode::Equals)         => Dead('´'),
+        (Level::Normal, Keycode::Y)              => Char('z'),
+        (Level::Normal, Keycode::LeftBracket)    => Char('ü'),
+        (Level::Normal, Keycode::RightBracket)   => Char('+'),
+        (Level::Normal, Keycode::Semicolon)      => Char('ö'),
+        (Level::Normal, Keycode::Quote)          => Char('ä'),
+        (Level::Normal, Keycode::Backslash)      => Char('#'),
+        (Level::Normal, Keycode::NonUsBackslash) => Char('<'),
+        (Level::Normal, Keycode::Z)              => Char('y'),
+        (Level::Normal, Keycode::Slash)          => Char('-'),
+
+        (Level::Shift, Keycode::Backtick)        => Char('°'),
+        (Level::Shift, Keycode::Num2)            => Char('"'),
+        (Level::Shift, Keycode::Num3)            => Char('§'),
+        (Level::Shift, Keycode::Num6)            => Char('&'),
+        (Level::Shift, Keycode::Num7)            => Char('/'),
+        (Level::Shift, Keycode::Num8)            => Char('('),
+        (Level::Shift, Keycode::Num9)            => Char(')'),
+        (Level::Shift, Keycode::Num0)            => Char('='),
+        (Level::Shift, Keycode::Minus)           => Char('?'),
+        (Level::Shift, Keycode::Equals)          => Dead('`'),
+        (Level::Shift, Keycode::Y)               => Char('Z'),
+        (Level::Shift, Keycode::LeftBracket)     => Char('Ü'),
+        (Level::Shift, Keycode::RightBracket)    => Char('*'),
+        (Level::Shift, Keycode::Semicolon)       => Char('Ö'),
+        (Level::Shift, Keycode::Quote)           => Char('Ä'),
+        (Level::Shift, Keycode::Backslash)       => Char('\''),
+        (Level::Shift, Keycode::NonUsBackslash)  => Char('>'),
+        (Level::Shift, Keycode::Z)               => Char('Y'),
+        (Level::Shift, Keycode::Comma)           => Char(';'),
+        (Level::Shift, Keycode::Period)          => Char(':'),
+        (Level::Shift, Keycode::Slash)           => Char('_'),
+
+        (Level::AltGr, Keycode::Num2)            => Char('²'),
+        (Level::AltGr, Keycode::Num3)            => Char('³'),
+        (Level::AltGr, Keycode::Num7)            => Char('{'),
+        (Level::AltGr, Keycode::Num8)            => Char('['),
+        (Level::AltGr, Keycode::Num9)            => Char(']'),
+        (Level::AltGr, Keycode::Num0)            => Char('}'),
+        (Level::AltGr, Keycode::Minus)           => Char('\\'),
+        (Level::AltGr, Keycode::Q)               => Char('@'),
+        (Level::AltGr, Keycode::E)               => Char('€'),
+        (Level::AltGr, Keycode::RightBracket)    => Char('~'),
+        (Level::AltGr, Keycode::M)               => Char('µ'),
+        (Level::AltGr, Keycode::NonUsBackslash)  => Char('|'),
+
+        // All other keys are the same as in the US layout.
+        (level, keycode) => return us(keycode, level),
+    };
+    Some(sym)
+}
+
+/// The US Dvorak layout.
+fn dvorak(keycode: Keycode, level: Level) -> Option<KeySym> {
+    let (normal, shifted) = match keycode {
+        Keycode::Minus        => ('[', '{'),
+        Keycode::Equals       => (']', '}'),
+        Keycode::Q            => ('\'', '"'),
+        Keycode::W            => (',', '<'),
+        Keycode::E            => ('.', '>'),
+        Keycode::R            => ('p', 'P'),
+        Keycode::T            => ('y', 'Y'),
+        Keycode::Y            => ('f', 'F'),
+        Keycode::U            => ('g', 'G'),
+        Keycode::I            => ('c', 'C'),
+        Keycode::O            => ('r', 'R'),
+        Keycode::P            => ('l', 'L'),
+        Keycode::LeftBracket  => ('/', '?'),
+        Keycode::RightBracket => ('=', '+'),
+        Keycode::S            => ('o', 'O'),
+        Keycode::D            => ('e', 'E'),
+        Keycode::F            => ('u', 'U'),
+        Keycode::G            => ('i', 'I'),
+        Keycode::H            => ('d', 'D'),
+        Keycode::J            => ('h', 'H'),
+        Keycode::K            => ('t', 'T'),
+        Keycode::L            => ('n', 'N'),
+        Keycode::Semicolon    => ('s', 'S'),
+        Keycode::Quote        => ('-', '_'),
+        Keycode::Z            => (';', ':'),
+        Keycode::X            => ('q', 'Q'),
+        Keycode::C            => ('j', 'J'),
+        Keycode::V            => ('k', 'K'),
+        Keycode::B            => ('x', 'X'),
+        Keycode::N            => ('b', 'B'),
+        Keycode::Comma        => ('w', 'W'),
+        Keycode::Period       => ('v', 'V'),
+        Keycode::Slash        => ('z', 'Z'),
+        // All other keys are the same as in the US layout.
+        _ => return us(keycode, level),
+    };
+    match level {
+        Level::Normal => Some(KeySym::Char(normal)),
+        Level::Shift => Some(KeySym::Char(shifted)),
+        Level::AltGr => None,
+    }
+}
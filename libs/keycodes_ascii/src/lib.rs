@@ -5,6 +5,10 @@
 
 #[macro_use] extern crate bitflags;
 
+mod layout;
+
+pub use layout::{KeyboardLayout, KeySym, KEYBOARD_LAYOUTS, keyboard_layout, set_keyboard_layout, compose_dead_key};
+
 // use core::cell::RefCell;
 
 // TODO: use these tables and tips:
@@ -122,14 +126,19 @@ pub struct KeyEvent {
     pub keycode: Keycode,
     pub action: KeyAction,
     pub modifiers: KeyboardModifiers,
+    /// The character this key event produces under the current keyboard layout, if any.
+    /// This accounts for dead keys, so it may differ from what `keycode.to_char(modifiers)` returns.
+    pub character: Option<char>,
 }
 
 impl KeyEvent {
+    /// Creates a new key event, whose `character` is determined by the current keyboard layout.
     pub fn new(keycode: Keycode, action: KeyAction, modifiers: KeyboardModifiers,) -> KeyEvent {
         KeyEvent {
             keycode, 
             action,
             modifiers,
+            character: keycode.to_char(modifiers),
         }
     }
 }
@@ -383,6 +392,17 @@ impl Keycode {
         // TODO: handle numlock
     }
 
+    /// Obtains the character for a keycode under the given modifiers in the current keyboard layout.
+    ///
+    /// Unlike `to_ascii()`, this may return non-ASCII characters, e.g., `ä` in the German layout.
+    /// Dead keys don't produce a character by themselves, so this returns `None` for them.
+    pub fn to_char(&self, modifiers: KeyboardModifiers) -> Option<char> {
+        match keyboard_layout().keysym(*self, modifiers) {
+            Some(KeySym::Char(c)) => Some(c),
+            _ => None,
+        }
+    }
+
 
 
     /// returns true if this keycode was a letter from A-Z
@@ -0,0 +1,790 @@
+//! A software model of x86_64 page tables, which is used to check the `memory` crate's `Mapper`.
+//!
+//! This crate has three parts:
+//! * [`walk()`] walks any 4-level page table hierarchy that can be read through the [`PageTableAccess`] trait,
+//!   and returns its leaf mappings with their effective flags, i.e., combined over every level.
+//!   It also checks structural rules, e.g., that huge pages are aligned and that entries pointing to
+//!   page tables never set `NO_EXECUTE`.
+//! * [`ExpectedMappings`] is a flat map from every 4KiB page to its frame and flags, which serves as the
+//!   specification of what a sequence of map, remap, and unmap operations should result in.
+//!   Its [`check()`] method compares the walked mappings of a page table against it.
+//! * [`PageTableModel`] is a page table whose tables live in simulated physical memory.
+//!   It mirrors the `Mapper`'s algorithms, such as when to use huge pages and how to split them,
+//!   with the real x86_64 entry encoding.
+//!
+//! This crate doesn't depend on the `memory` crate, such that it can be tested on the host,
+//! e.g., with `cargo test -- --nocapture` in this directory.
+//! The `memory` crate's host tests run the real `Mapper` on page tables in simulated physical memory,
+//! and check them against [`ExpectedMappings`] and a `PageTableModel` with it.
+//! Within Theseus, the `test_paging` application walks the live page tables with it,
+//! performing the same operations on the `Mapper` and on a `PageTableModel`.
+//!
+//! [`walk()`]: fn.walk.html
+//! [`PageTableAccess`]: trait.PageTableAccess.html
+//! [`ExpectedMappings`]: struct.ExpectedMappings.html
+//! [`check()`]: struct.ExpectedMappings.html#method.check
+//! [`PageTableModel`]: struct.PageTableModel.html
+
+#![no_std]
+
+#[cfg(test)]
+#[macro_use] extern crate std;
+
+extern crate alloc;
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::{fmt, ops::Range};
+
+
+pub const PRESENT: u64         = 1 << 0;
+pub const WRITABLE: u64        = 1 << 1;
+pub const USER_ACCESSIBLE: u64 = 1 << 2;
+pub const WRITE_THROUGH: u64   = 1 << 3;
+pub const NO_CACHE: u64        = 1 << 4;
+pub const ACCESSED: u64        = 1 << 5;
+pub const DIRTY: u64           = 1 << 6;
+pub const HUGE_PAGE: u64       = 1 << 7;
+pub const NO_EXECUTE: u64      = 1 << 63;
+
+/// The flags that determine how a page may be accessed, which are the only flags that are compared.
+pub const PERMISSION_FLAGS: u64 = PRESENT | WRITABLE | USER_ACCESSIBLE | WRITE_THROUGH | NO_CACHE | NO_EXECUTE;
+/// The bits of an entry that hold the physical address of a frame or page table.
+pub const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+pub const PAGE_SIZE: u64 = 4096;
+const ENTRIES_PER_PAGE_TABLE: usize = 512;
+/// The simulated physical address of the first page table allocated by a `PageTableModel`,
+/// which is far above the frames that tests map.
+const TABLE_FRAMES_START: u64 = 0x8_0000_0000_0000;
+
+
+/// The size of a page, in increasing order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    pub fn size_in_bytes(self) -> u64 {
+        PAGE_SIZE << (9 * (self.level() - 1))
+    }
+
+    pub fn size_in_pages(self) -> u64 {
+        self.size_in_bytes() / PAGE_SIZE
+    }
+
+    /// The level of the page table whose entries map pages of this size, where a P1 table is level 1.
+    fn level(self) -> usize {
+        match self {
+            PageSize::Size4KiB => 1,
+            PageSize::Size2MiB => 2,
+            PageSize::Size1GiB => 3,
+        }
+    }
+}
+
+
+/// Read access to a 4-level page table hierarchy.
+pub trait PageTableAccess {
+    /// A handle to one page table, e.g., its physical or virtual address.
+    type Table: Copy;
+    /// Returns the top-level P4 table.
+    fn root(&self) -> Self::Table;
+    /// Returns the raw value of the entry at `index` of the given `table`.
+    fn entry(&self, table: Self::Table, index: usize) -> u64;
+    /// Returns the table that the entry at `index` of the given `table` points to.
+    /// This is only called for present entries that aren't huge pages in P4, P3, and P2 tables.
+    fn next_table(&self, table: Self::Table, index: usize) -> Self::Table;
+}
+
+
+/// A present leaf entry, which maps one page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Leaf {
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub size: PageSize,
+    /// The effective `PERMISSION_FLAGS` of the page: it's only writable or user-accessible if it's so at every level,
+    /// and it isn't executable if it's marked as `NO_EXECUTE` at any level.
+    pub flags: u64,
+}
+
+/// A violation of a structural rule, or a difference between a page table and its expected mappings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModelError {
+    /// The virtual address at which the problem was found.
+    pub vaddr: u64,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at {:#X})", self.reason, self.vaddr)
+    }
+}
+
+
+/// Walks the page table hierarchy of the given `access` and returns all present leaf entries
+/// that map at least part of the given `range` of virtual addresses, sorted by virtual address.
+///
+/// The `range` must not span the non-canonical hole, and should exclude Theseus's recursive P4 entry.
+pub fn walk<A: PageTableAccess>(access: &A, range: Range<u64>) -> Result<Vec<Leaf>, ModelError> {
+    let mut leaves = Vec::new();
+    walk_table(access, access.root(), 4, 0, Restrictions::none(), &range, &mut leaves)?;
+    Ok(leaves)
+}
+
+/// Returns the physical address that the given `vaddr` is mapped to, or `None` if it isn't mapped.
+pub fn translate<A: PageTableAccess>(access: &A, vaddr: u64) -> Result<Option<u64>, ModelError> {
+    Ok(walk(access, vaddr .. vaddr + 1)?.first().map(|leaf| leaf.paddr + (vaddr - leaf.vaddr)))
+}
+
+/// Returns the raw entry for `vaddr` in the page table at the given `level`, where a P1 table is level 1,
+/// or `None` if that page table doesn't exist, e.g., because a huge page at a higher level maps `vaddr`.
+pub fn entry_at<A: PageTableAccess>(access: &A, vaddr: u64, level: usize) -> Option<u64> {
+    let mut table = access.root();
+    for table_level in (level + 1 ..= 4).rev() {
+        let entry = access.entry(table, index_of(vaddr, table_level));
+        if entry & PRESENT == 0 || entry & HUGE_PAGE != 0 {
+            return None;
+        }
+        table = access.next_table(table, index_of(vaddr, table_level));
+    }
+    Some(access.entry(table, index_of(vaddr, level)))
+}
+
+/// Checks that the two given page tables map the given `range` with exactly the same leaf entries,
+/// including their page sizes.
+pub fn compare<A: PageTableAccess, B: PageTableAccess>(a: &A, b: &B, range: Range<u64>) -> Result<(), ModelError> {
+    let (a_leaves, b_leaves) = (walk(a, range.clone())?, walk(b, range)?);
+    for (a_leaf, b_leaf) in a_leaves.iter().zip(b_leaves.iter()) {
+        if a_leaf != b_leaf {
+            return Err(ModelError { vaddr: a_leaf.vaddr.min(b_leaf.vaddr), reason: "the page tables map a page differently" });
+        }
+    }
+    if a_leaves.len() != b_leaves.len() {
+        let extra = a_leaves.get(b_leaves.len()).or_else(|| b_leaves.get(a_leaves.len()));
+        return Err(ModelError { vaddr: extra.map_or(0, |leaf| leaf.vaddr), reason: "only one of the page tables maps a page" });
+    }
+    Ok(())
+}
+
+
+/// The flags at the higher levels of the hierarchy that restrict the pages below them.
+#[derive(Clone, Copy)]
+struct Restrictions {
+    writable: bool,
+    user_accessible: bool,
+    no_execute: bool,
+}
+
+impl Restrictions {
+    fn none() -> Restrictions {
+        Restrictions { writable: true, user_accessible: true, no_execute: false }
+    }
+
+    fn apply(self, entry: u64) -> Restrictions {
+        Restrictions {
+            writable: self.writable && entry & WRITABLE != 0,
+            user_accessible: self.user_accessible && entry & USER_ACCESSIBLE != 0,
+            no_execute: self.no_execute || entry & NO_EXECUTE != 0,
+        }
+    }
+
+    /// Returns the effective flags of the given leaf entry, which must already be applied to these restrictions.
+    fn effective_flags(self, leaf: u64) -> u64 {
+        let mut flags = leaf & (PERMISSION_FLAGS & !(WRITABLE | USER_ACCESSIBLE | NO_EXECUTE));
+        if self.writable { flags |= WRITABLE; }
+        if self.user_accessible { flags |= USER_ACCESSIBLE; }
+        if self.no_execute { flags |= NO_EXECUTE; }
+        flags
+    }
+}
+
+/// Returns the given address with its upper 16 bits set to copies of bit 47.
+fn canonical(vaddr: u64) -> u64 {
+    (((vaddr << 16) as i64) >> 16) as u64
+}
+
+/// Returns the index of the entry that maps the given `vaddr` in a page table of the given `level`.
+fn index_of(vaddr: u64, level: usize) -> usize {
+    ((vaddr >> (12 + 9 * (level - 1))) as usize) % ENTRIES_PER_PAGE_TABLE
+}
+
+fn walk_table<A: PageTableAccess>(
+    access: &A,
+    table: A::Table,
+    level: usize,
+    table_vaddr: u64,
+    restrictions: Restrictions,
+    range: &Range<u64>,
+    leaves: &mut Vec<Leaf>,
+) -> Result<(), ModelError> {
+    // The number of bytes that each entry of this table maps.
+    let span = PAGE_SIZE << (9 * (level - 1));
+    for index in 0 .. ENTRIES_PER_PAGE_TABLE {
+        let vaddr = canonical(table_vaddr + index as u64 * span);
+        if vaddr >= range.end || vaddr + (span - 1) < range.start {
+            continue;
+        }
+        let entry = access.entry(table, index);
+        if entry & PRESENT == 0 {
+            continue;
+        }
+        let entry_restrictions = restrictions.apply(entry);
+
+        if level > 1 && entry & HUGE_PAGE == 0 {
+            if entry & NO_EXECUTE != 0 {
+                return Err(ModelError { vaddr, reason: "an entry that points to a page table has NO_EXECUTE set" });
+            }
+            walk_table(access, access.next_table(table, index), level - 1, vaddr, entry_restrictions, range, leaves)?;
+            continue;
+        }
+        let size = match level {
+            1 if entry & HUGE_PAGE != 0 => return Err(ModelError { vaddr, reason: "a P1 entry has the huge page bit set" }),
+            1 => PageSize::Size4KiB,
+            2 => PageSize::Size2MiB,
+            3 => PageSize::Size1GiB,
+            _ => return Err(ModelError { vaddr, reason: "a P4 entry has the huge page bit set" }),
+        };
+        let paddr = entry & ADDRESS_MASK;
+        if paddr % size.size_in_bytes() != 0 {
+            return Err(ModelError { vaddr, reason: "a huge page's frame is not aligned to its size" });
+        }
+        leaves.push(Leaf { vaddr, paddr, size, flags: entry_restrictions.effective_flags(entry) });
+    }
+    Ok(())
+}
+
+
+/// The specification of a page table's mappings, as a map from every mapped 4KiB page to its frame and flags.
+#[derive(Clone, Debug, Default)]
+pub struct ExpectedMappings {
+    /// Maps the virtual address of each page to its physical address and `PERMISSION_FLAGS`.
+    pages: BTreeMap<u64, (u64, u64)>,
+}
+
+impl ExpectedMappings {
+    pub fn new() -> ExpectedMappings {
+        ExpectedMappings::default()
+    }
+
+    /// The number of mapped pages.
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Returns the physical address and flags of the page that starts at the given `vaddr`, if it's mapped.
+    pub fn get(&self, vaddr: u64) -> Option<(u64, u64)> {
+        self.pages.get(&vaddr).cloned()
+    }
+
+    /// Records that `num_pages` pages starting at `vaddr` are mapped to the frames starting at `paddr` with the given `flags`.
+    pub fn map(&mut self, vaddr: u64, paddr: u64, num_pages: u64, flags: u64) {
+        for i in 0 .. num_pages {
+            self.pages.insert(vaddr + i * PAGE_SIZE, (paddr + i * PAGE_SIZE, (flags | PRESENT) & PERMISSION_FLAGS));
+        }
+    }
+
+    /// Records that `num_pages` pages starting at `vaddr` are no longer mapped.
+    pub fn unmap(&mut self, vaddr: u64, num_pages: u64) {
+        for i in 0 .. num_pages {
+            self.pages.remove(&(vaddr + i * PAGE_SIZE));
+        }
+    }
+
+    /// Records that the mapped pages among `num_pages` pages starting at `vaddr` now have the given `flags`.
+    pub fn remap(&mut self, vaddr: u64, num_pages: u64, flags: u64) {
+        for i in 0 .. num_pages {
+            if let Some(page) = self.pages.get_mut(&(vaddr + i * PAGE_SIZE)) {
+                page.1 = (flags | PRESENT) & PERMISSION_FLAGS;
+            }
+        }
+    }
+
+    /// Checks that, within the given `range`, the page table of the given `access` maps exactly the expected pages,
+    /// each to the expected frame and with the expected effective flags.
+    pub fn check<A: PageTableAccess>(&self, access: &A, range: Range<u64>) -> Result<(), ModelError> {
+        let mut seen = BTreeSet::new();
+        for leaf in walk(access, range.clone())? {
+            for i in 0 .. leaf.size.size_in_pages() {
+                let vaddr = leaf.vaddr + i * PAGE_SIZE;
+                if !range.contains(&vaddr) {
+                    continue;
+                }
+                match self.pages.get(&vaddr) {
+                    None => return Err(ModelError { vaddr, reason: "a page is mapped but shouldn't be" }),
+                    Some(&(paddr, _)) if paddr != leaf.paddr + i * PAGE_SIZE => {
+                        return Err(ModelError { vaddr, reason: "a page is mapped to the wrong frame" });
+                    }
+                    Some(&(_, flags)) if flags != leaf.flags => {
+                        return Err(ModelError { vaddr, reason: "a page has the wrong effective flags" });
+                    }
+                    Some(_) => { seen.insert(vaddr); }
+                }
+            }
+        }
+        match self.pages.range(range).find(|(vaddr, _)| !seen.contains(vaddr)) {
+            Some((&vaddr, _)) => Err(ModelError { vaddr, reason: "a page should be mapped but isn't" }),
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// A 4-level page table whose tables are stored in simulated physical memory.
+///
+/// Its operations mirror those of the `Mapper` and `MappedPages`, with one difference:
+/// an existing page table's entry is made user-accessible once a user-accessible page is mapped below it,
+/// whereas the `Mapper` only sets the flags of a page table's entry when it creates the page table.
+pub struct PageTableModel {
+    /// The contents of every page table, keyed by their simulated physical address.
+    tables: BTreeMap<u64, [u64; ENTRIES_PER_PAGE_TABLE]>,
+    root: u64,
+    next_table_frame: u64,
+    /// The largest page size that `map()` may use, like `MAP_HUGE_PAGES` and the CPU's support for 1GiB pages.
+    largest_page: PageSize,
+}
+
+impl PageTableAccess for PageTableModel {
+    type Table = u64;
+
+    fn root(&self) -> u64 {
+        self.root
+    }
+
+    fn entry(&self, table: u64, index: usize) -> u64 {
+        self.tables.get(&table).map_or(0, |t| t[index])
+    }
+
+    fn next_table(&self, table: u64, index: usize) -> u64 {
+        self.entry(table, index) & ADDRESS_MASK
+    }
+}
+
+impl PageTableModel {
+    /// Creates an empty page table, whose `map()` uses huge pages up to the size of `largest_page`.
+    pub fn new(largest_page: PageSize) -> PageTableModel {
+        let mut model = PageTableModel { tables: BTreeMap::new(), root: 0, next_table_frame: TABLE_FRAMES_START, largest_page };
+        model.root = model.allocate_table();
+        model
+    }
+
+    /// The number of page tables, including the P4 table.
+    pub fn table_count(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// Creates any missing page tables down to the table at the given `level` whose entry maps `vaddr`,
+    /// with the given `flags`, e.g., to mirror page tables that another page table has left over from earlier mappings.
+    pub fn create_page_tables(&mut self, vaddr: u64, level: usize, flags: u64) -> Result<(), &'static str> {
+        self.table_for(vaddr, level, flags).map(|_table| ())
+    }
+
+    /// Maps `num_pages` pages starting at `vaddr` to the frames starting at `paddr` with the given `flags`,
+    /// using a huge page wherever the pages and frames are aligned to it and no entry is in the way.
+    pub fn map(&mut self, vaddr: u64, paddr: u64, num_pages: u64, flags: u64) -> Result<(), &'static str> {
+        if vaddr % PAGE_SIZE != 0 || paddr % PAGE_SIZE != 0 {
+            return Err("map(): address was not page-aligned");
+        }
+        let flags = flags & !(ADDRESS_MASK | HUGE_PAGE);
+        let (mut vaddr, mut paddr, mut remaining) = (vaddr, paddr, num_pages);
+        while remaining > 0 {
+            let size = self.huge_page_size_for(vaddr, paddr, remaining).unwrap_or(PageSize::Size4KiB);
+            let table = self.table_for(vaddr, size.level(), flags)?;
+            let index = index_of(vaddr, size.level());
+            if self.entry(table, index) != 0 {
+                return Err("map(): page was already in use");
+            }
+            let huge = if size == PageSize::Size4KiB { 0 } else { HUGE_PAGE };
+            self.set_entry(table, index, paddr | flags | huge | PRESENT);
+            remaining -= size.size_in_pages();
+            vaddr += size.size_in_bytes();
+            paddr += size.size_in_bytes();
+        }
+        Ok(())
+    }
+
+    /// Unmaps `num_pages` pages starting at `vaddr`, first splitting any huge page that extends beyond them.
+    pub fn unmap(&mut self, vaddr: u64, num_pages: u64) -> Result<(), &'static str> {
+        let end = vaddr + num_pages * PAGE_SIZE;
+        self.split_huge_page(vaddr, true)?;
+        self.split_huge_page(end, true)?;
+        let mut page = vaddr;
+        while page < end {
+            let (table, index, size) = self.leaf(page).ok_or("unmap(): page was not mapped")?;
+            self.set_entry(table, index, 0);
+            page += size.size_in_bytes();
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of `num_pages` pages starting at `vaddr` to the given `flags`,
+    /// first splitting any huge page that extends beyond them.
+    pub fn remap(&mut self, vaddr: u64, num_pages: u64, flags: u64) -> Result<(), &'static str> {
+        let flags = flags & !(ADDRESS_MASK | HUGE_PAGE);
+        let end = vaddr + num_pages * PAGE_SIZE;
+        self.split_huge_page(vaddr, true)?;
+        self.split_huge_page(end, true)?;
+        let mut page = vaddr;
+        while page < end {
+            let (_, _, size) = self.leaf(page).ok_or("remap(): page was not mapped")?;
+            // This only makes the page tables above the page user-accessible if needed, as they already exist.
+            let table = self.table_for(page, size.level(), flags)?;
+            let index = index_of(page, size.level());
+            let huge = if size == PageSize::Size4KiB { 0 } else { HUGE_PAGE };
+            let frame = self.entry(table, index) & ADDRESS_MASK;
+            self.set_entry(table, index, frame | flags | huge | PRESENT);
+            page += size.size_in_bytes();
+        }
+        Ok(())
+    }
+
+    /// Splits the huge page that maps the given `vaddr`, if there is one, into smaller pages:
+    /// a 1GiB page into 2MiB pages, and a 2MiB page into regular 4KiB pages.
+    ///
+    /// If `boundary_only` is `true`, a huge page is only split if `vaddr` is not its first address.
+    /// Otherwise, huge pages are split until `vaddr` is mapped by a regular P1 entry.
+    pub fn split_huge_page(&mut self, vaddr: u64, boundary_only: bool) -> Result<(), &'static str> {
+        while let Some((table, index, size)) = self.leaf(vaddr) {
+            let smaller = match size {
+                PageSize::Size1GiB => PageSize::Size2MiB,
+                PageSize::Size2MiB => PageSize::Size4KiB,
+                PageSize::Size4KiB => return Ok(()),
+            };
+            if boundary_only && vaddr % size.size_in_bytes() == 0 {
+                return Ok(());
+            }
+            let entry = self.entry(table, index);
+            let start_frame = entry & ADDRESS_MASK;
+            let huge_flags = entry & !ADDRESS_MASK;
+            let new_entry_flags = if smaller == PageSize::Size4KiB { huge_flags & !HUGE_PAGE } else { huge_flags };
+            let table_flags = (huge_flags & !(HUGE_PAGE | NO_EXECUTE)) | WRITABLE | PRESENT;
+
+            let new_table = self.allocate_table();
+            for i in 0 .. ENTRIES_PER_PAGE_TABLE {
+                self.set_entry(new_table, i, (start_frame + i as u64 * smaller.size_in_bytes()) | new_entry_flags);
+            }
+            self.set_entry(table, index, new_table | table_flags);
+        }
+        Ok(())
+    }
+
+
+    fn allocate_table(&mut self) -> u64 {
+        let frame = self.next_table_frame;
+        self.next_table_frame += PAGE_SIZE;
+        self.tables.insert(frame, [0; ENTRIES_PER_PAGE_TABLE]);
+        frame
+    }
+
+    fn set_entry(&mut self, table: u64, index: usize, value: u64) {
+        if let Some(t) = self.tables.get_mut(&table) {
+            t[index] = value;
+        }
+    }
+
+    /// Returns the table at the given `level` whose entry maps `vaddr`, creating any missing page tables above it.
+    /// New page tables' entries get the given `flags` of the page that is being mapped, except for `NO_EXECUTE`.
+    fn table_for(&mut self, vaddr: u64, level: usize, flags: u64) -> Result<u64, &'static str> {
+        let mut table = self.root;
+        for table_level in (level + 1 ..= 4).rev() {
+            let index = index_of(vaddr, table_level);
+            let entry = self.entry(table, index);
+            if entry & PRESENT == 0 {
+                if entry != 0 {
+                    return Err("a page table entry that isn't present is in use");
+                }
+                let new_table = self.allocate_table();
+                self.set_entry(table, index, new_table | (flags & !NO_EXECUTE) | WRITABLE | PRESENT);
+                table = new_table;
+            } else if entry & HUGE_PAGE != 0 {
+                return Err("a huge page is in the way of a page table");
+            } else {
+                self.set_entry(table, index, entry | (flags & USER_ACCESSIBLE));
+                table = entry & ADDRESS_MASK;
+            }
+        }
+        Ok(table)
+    }
+
+    /// Returns the table, index, and page size of the present leaf entry that maps `vaddr`, if any.
+    fn leaf(&self, vaddr: u64) -> Option<(u64, usize, PageSize)> {
+        let mut table = self.root;
+        for &(level, size) in [(4, None), (3, Some(PageSize::Size1GiB)), (2, Some(PageSize::Size2MiB)), (1, Some(PageSize::Size4KiB))].iter() {
+            let index = index_of(vaddr, level);
+            let entry = self.entry(table, index);
+            if entry & PRESENT == 0 {
+                return None;
+            }
+            if level == 1 || (entry & HUGE_PAGE != 0 && size.is_some()) {
+                return size.map(|size| (table, index, size));
+            }
+            table = entry & ADDRESS_MASK;
+        }
+        None
+    }
+
+    /// Returns the largest huge page size that `vaddr` can be mapped to `paddr` with, if both are aligned to it,
+    /// at least that many of the `remaining` pages are left to be mapped, and no entry is already in the way.
+    fn huge_page_size_for(&self, vaddr: u64, paddr: u64, remaining: u64) -> Option<PageSize> {
+        [PageSize::Size1GiB, PageSize::Size2MiB].iter().cloned().find(|&size| {
+            size <= self.largest_page
+                && remaining >= size.size_in_pages()
+                && vaddr % size.size_in_bytes() == 0
+                && paddr % size.size_in_bytes() == 0
+                && self.is_unused(vaddr, size.level())
+        })
+    }
+
+    /// Returns whether the entry for `vaddr` in the table at the given `level` is unused, or its table doesn't exist yet.
+    fn is_unused(&self, vaddr: u64, level: usize) -> bool {
+        let mut table = self.root;
+        for table_level in (level + 1 ..= 4).rev() {
+            let entry = self.entry(table, index_of(vaddr, table_level));
+            if entry & PRESENT == 0 {
+                return entry == 0;
+            }
+            if entry & HUGE_PAGE != 0 {
+                return false;
+            }
+            table = entry & ADDRESS_MASK;
+        }
+        self.entry(table, index_of(vaddr, level)) == 0
+    }
+}
+
+
+/// A simple xorshift64* pseudorandom number generator, which is good enough for picking operations.
+#[cfg(test)]
+struct Xorshift {
+    state: u64,
+}
+
+#[cfg(test)]
+impl Xorshift {
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[cfg(test)]
+const TEST_VADDR: u64 = 0x40_0000_0000;
+#[cfg(test)]
+const TEST_PADDR: u64 = 0x1_0000_0000;
+#[cfg(test)]
+const HUGE_2MIB_PAGES: u64 = 512;
+
+#[cfg(test)]
+fn test_range(num_pages: u64) -> Range<u64> {
+    TEST_VADDR .. TEST_VADDR + num_pages * PAGE_SIZE
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_huge_pages -- --nocapture`
+fn test_huge_pages() {
+    let mut model = PageTableModel::new(PageSize::Size1GiB);
+    let mut expected = ExpectedMappings::new();
+    let num_pages = 2 * HUGE_2MIB_PAGES + 3;
+    model.map(TEST_VADDR, TEST_PADDR, num_pages, WRITABLE | NO_EXECUTE).unwrap();
+    expected.map(TEST_VADDR, TEST_PADDR, num_pages, WRITABLE | NO_EXECUTE);
+    expected.check(&model, test_range(2048)).unwrap();
+
+    let sizes: Vec<PageSize> = walk(&model, test_range(2048)).unwrap().iter().map(|leaf| leaf.size).collect();
+    println!("page sizes: {:?}", sizes);
+    assert_eq!(sizes, vec![PageSize::Size2MiB, PageSize::Size2MiB, PageSize::Size4KiB, PageSize::Size4KiB, PageSize::Size4KiB]);
+
+    // A whole aligned gigabyte is mapped by a single entry, unless 1GiB pages aren't allowed.
+    let gib = PageSize::Size1GiB.size_in_bytes();
+    model.map(TEST_VADDR + gib, TEST_PADDR + gib, PageSize::Size1GiB.size_in_pages(), WRITABLE).unwrap();
+    assert_eq!(walk(&model, TEST_VADDR + gib .. TEST_VADDR + 2 * gib).unwrap().len(), 1);
+    let mut small = PageTableModel::new(PageSize::Size2MiB);
+    small.map(TEST_VADDR, TEST_PADDR, PageSize::Size1GiB.size_in_pages(), WRITABLE).unwrap();
+    assert_eq!(walk(&small, TEST_VADDR .. TEST_VADDR + gib).unwrap().len(), 512);
+
+    // Misaligned frames can't be mapped by huge pages.
+    let mut misaligned = PageTableModel::new(PageSize::Size1GiB);
+    misaligned.map(TEST_VADDR, TEST_PADDR + PAGE_SIZE, HUGE_2MIB_PAGES, WRITABLE).unwrap();
+    assert!(walk(&misaligned, test_range(HUGE_2MIB_PAGES)).unwrap().iter().all(|leaf| leaf.size == PageSize::Size4KiB));
+    assert_eq!(misaligned.map(TEST_VADDR, TEST_PADDR, 1, WRITABLE), Err("map(): page was already in use"));
+
+    // A page table that was left over from earlier mappings is in the way of a huge page.
+    let mut leftover = PageTableModel::new(PageSize::Size1GiB);
+    leftover.create_page_tables(TEST_VADDR, 1, WRITABLE).unwrap();
+    assert_eq!(entry_at(&leftover, TEST_VADDR, 1), Some(0));
+    leftover.map(TEST_VADDR, TEST_PADDR, HUGE_2MIB_PAGES, WRITABLE).unwrap();
+    assert_eq!(walk(&leftover, test_range(HUGE_2MIB_PAGES)).unwrap().len(), 512);
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_flag_propagation -- --nocapture`
+fn test_flag_propagation() {
+    let mut model = PageTableModel::new(PageSize::Size1GiB);
+    let mut expected = ExpectedMappings::new();
+    // Pages with different flags share every page table, so the flags of the first must not restrict the others.
+    let pages: [(u64, u64); 4] = [
+        (NO_EXECUTE, 0),
+        (WRITABLE | NO_EXECUTE, 1),
+        (0, 2),
+        (USER_ACCESSIBLE | WRITABLE | NO_CACHE, 3),
+    ];
+    for &(flags, page) in pages.iter() {
+        model.map(TEST_VADDR + page * PAGE_SIZE, TEST_PADDR + page * PAGE_SIZE, 1, flags).unwrap();
+        expected.map(TEST_VADDR + page * PAGE_SIZE, TEST_PADDR + page * PAGE_SIZE, 1, flags);
+    }
+    expected.check(&model, test_range(4)).unwrap();
+    for leaf in walk(&model, test_range(4)).unwrap() {
+        println!("{:#X} -> {:#X}: {:#X}", leaf.vaddr, leaf.paddr, leaf.flags);
+    }
+
+    // A user-accessible mapping of one page doesn't make the kernel's pages user-accessible.
+    let flags = walk(&model, test_range(1)).unwrap()[0].flags;
+    assert_eq!(flags & USER_ACCESSIBLE, 0);
+    assert_eq!(translate(&model, TEST_VADDR + 3 * PAGE_SIZE + 0x10).unwrap(), Some(TEST_PADDR + 3 * PAGE_SIZE + 0x10));
+    assert_eq!(translate(&model, TEST_VADDR + 4 * PAGE_SIZE).unwrap(), None);
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_split -- --nocapture`
+fn test_split() {
+    let mut model = PageTableModel::new(PageSize::Size1GiB);
+    let mut expected = ExpectedMappings::new();
+    let gib_pages = PageSize::Size1GiB.size_in_pages();
+    model.map(TEST_VADDR, TEST_PADDR, gib_pages, WRITABLE | NO_EXECUTE).unwrap();
+    expected.map(TEST_VADDR, TEST_PADDR, gib_pages, WRITABLE | NO_EXECUTE);
+    let range = test_range(gib_pages);
+
+    // Splitting at the start of a huge page does nothing.
+    model.split_huge_page(TEST_VADDR, true).unwrap();
+    assert_eq!(walk(&model, range.clone()).unwrap().len(), 1);
+
+    // Splitting at a 2MiB boundary only splits the 1GiB page.
+    model.split_huge_page(TEST_VADDR + 3 * PageSize::Size2MiB.size_in_bytes(), true).unwrap();
+    let leaves = walk(&model, range.clone()).unwrap();
+    assert_eq!(leaves.len(), 512);
+    assert!(leaves.iter().all(|leaf| leaf.size == PageSize::Size2MiB));
+    expected.check(&model, range.clone()).unwrap();
+
+    // Splitting fully makes the page a regular page, and every address keeps its translation and flags.
+    let page = TEST_VADDR + 5 * PageSize::Size2MiB.size_in_bytes();
+    model.split_huge_page(page, false).unwrap();
+    let leaves = walk(&model, page .. page + PageSize::Size2MiB.size_in_bytes()).unwrap();
+    assert_eq!(leaves.len(), 512);
+    assert!(leaves.iter().all(|leaf| leaf.size == PageSize::Size4KiB));
+    expected.check(&model, range).unwrap();
+    println!("page tables after splitting: {}", model.table_count());
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_unmap_and_remap -- --nocapture`
+fn test_unmap_and_remap() {
+    let mut model = PageTableModel::new(PageSize::Size1GiB);
+    let mut expected = ExpectedMappings::new();
+    let num_pages = 2 * HUGE_2MIB_PAGES;
+    model.map(TEST_VADDR, TEST_PADDR, num_pages, WRITABLE | NO_EXECUTE).unwrap();
+    expected.map(TEST_VADDR, TEST_PADDR, num_pages, WRITABLE | NO_EXECUTE);
+
+    // Unmapping part of a huge page only unmaps those pages.
+    let hole = TEST_VADDR + 100 * PAGE_SIZE;
+    model.unmap(hole, 10).unwrap();
+    expected.unmap(hole, 10);
+    expected.check(&model, test_range(num_pages)).unwrap();
+    assert_eq!(translate(&model, hole).unwrap(), None);
+    assert_eq!(model.unmap(hole, 1), Err("unmap(): page was not mapped"));
+
+    // Remapping pages that straddle the two huge pages splits the second one, too.
+    let start = TEST_VADDR + (HUGE_2MIB_PAGES - 4) * PAGE_SIZE;
+    model.remap(start, 8, PRESENT).unwrap();
+    expected.remap(start, 8, PRESENT);
+    expected.check(&model, test_range(num_pages)).unwrap();
+    assert!(walk(&model, test_range(num_pages)).unwrap().iter().all(|leaf| leaf.size == PageSize::Size4KiB));
+
+    model.unmap(TEST_VADDR, 100).unwrap();
+    model.unmap(hole + 10 * PAGE_SIZE, num_pages - 110).unwrap();
+    ExpectedMappings::new().check(&model, test_range(num_pages)).unwrap();
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_detects_bugs -- --nocapture`
+fn test_detects_bugs() {
+    let mut model = PageTableModel::new(PageSize::Size1GiB);
+    let mut expected = ExpectedMappings::new();
+    model.map(TEST_VADDR, TEST_PADDR, 2, WRITABLE).unwrap();
+    expected.map(TEST_VADDR, TEST_PADDR, 2, WRITABLE);
+    let (p1, index, _) = model.leaf(TEST_VADDR).unwrap();
+
+    // A page table entry with NO_EXECUTE makes every page below it non-executable.
+    let p3 = model.next_table(model.root, index_of(TEST_VADDR, 4));
+    let p2 = model.next_table(p3, index_of(TEST_VADDR, 3));
+    let p2_index = index_of(TEST_VADDR, 2);
+    let p2_entry = model.entry(p2, p2_index);
+    model.set_entry(p2, p2_index, p2_entry | NO_EXECUTE);
+    let error = expected.check(&model, test_range(2)).unwrap_err();
+    println!("{}", error);
+    assert_eq!(error.reason, "an entry that points to a page table has NO_EXECUTE set");
+    model.set_entry(p2, p2_index, p2_entry);
+
+    // A read-only page table entry makes every page below it read-only.
+    model.set_entry(p2, p2_index, p2_entry & !WRITABLE);
+    assert_eq!(expected.check(&model, test_range(2)).unwrap_err().reason, "a page has the wrong effective flags");
+    model.set_entry(p2, p2_index, p2_entry);
+
+    let entry = model.entry(p1, index);
+    model.set_entry(p1, index, entry + PAGE_SIZE);
+    assert_eq!(expected.check(&model, test_range(2)).unwrap_err().reason, "a page is mapped to the wrong frame");
+    model.set_entry(p1, index, 0);
+    assert_eq!(expected.check(&model, test_range(2)).unwrap_err(), ModelError { vaddr: TEST_VADDR, reason: "a page should be mapped but isn't" });
+    model.set_entry(p1, index, entry);
+    expected.unmap(TEST_VADDR + PAGE_SIZE, 1);
+    assert_eq!(expected.check(&model, test_range(2)).unwrap_err().reason, "a page is mapped but shouldn't be");
+}
+
+#[test]
+/// To run this test, execute: `cargo test test_randomized -- --nocapture`
+fn test_randomized() {
+    const WINDOW_PAGES: u64 = 8 * HUGE_2MIB_PAGES;
+    const FLAGS: [u64; 4] = [WRITABLE | NO_EXECUTE, NO_EXECUTE, 0, WRITABLE | NO_CACHE | NO_EXECUTE];
+    for seed in 1 .. 20u64 {
+        let mut rng = Xorshift { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) };
+        let mut model = PageTableModel::new(PageSize::Size1GiB);
+        let mut expected = ExpectedMappings::new();
+        for step in 0 .. 300 {
+            let mut start = rng.next() % WINDOW_PAGES;
+            if rng.next() % 2 == 0 {
+                start -= start % HUGE_2MIB_PAGES;
+            }
+            let num_pages = (1 + rng.next() % 1200).min(WINDOW_PAGES - start);
+            let flags = FLAGS[(rng.next() % 4) as usize];
+            let (vaddr, paddr) = (TEST_VADDR + start * PAGE_SIZE, TEST_PADDR + start * PAGE_SIZE);
+            let mapped = (0 .. num_pages).filter(|i| expected.get(vaddr + i * PAGE_SIZE).is_some()).count() as u64;
+            if mapped == 0 {
+                model.map(vaddr, paddr, num_pages, flags).unwrap();
+                expected.map(vaddr, paddr, num_pages, flags);
+            } else if mapped == num_pages && rng.next() % 2 == 0 {
+                model.unmap(vaddr, num_pages).unwrap();
+                expected.unmap(vaddr, num_pages);
+            } else if mapped == num_pages {
+                model.remap(vaddr, num_pages, flags).unwrap();
+                expected.remap(vaddr, num_pages, flags);
+            } else {
+                continue;
+            }
+            if let Err(e) = expected.check(&model, test_range(WINDOW_PAGES)) {
+                panic!("seed {}, step {}: {}", seed, step, e);
+            }
+        }
+        println!("seed {}: {} pages mapped with {} page tables", seed, expected.len(), model.table_count());
+    }
+}
@@ -0,0 +1,90 @@
+#!/bin/bash
+set -e
+
+### This script is invoked from the Theseus top-level Makefile, using 'make netboot',
+### and should be run on a machine that the target machines can reach over HTTP.
+### It publishes a new build of Theseus for network booting, marks it as the latest build,
+### and creates a small boot image that always boots the latest build from this machine.
+
+
+### the directory containing this script 
+SCRIPTS_DIR="$(dirname "$(readlink -f "$0")")"
+THESEUS_BASE_DIR=$SCRIPTS_DIR/..
+TOOLS_DIR=$THESEUS_BASE_DIR/tools
+GRUB_CFG_GENERATION="cargo run --release --manifest-path $TOOLS_DIR/grub_cfg_generation/Cargo.toml --"
+
+### This script requires python
+if ! command -v python > /dev/null ; then
+  echo "The 'python' program is missing, please install it."
+fi
+
+
+### required argument:  directory containing the newly-built "boot" and "modules" directories
+if [ -z $ISOFILES_DIR ] ; then 
+	echo "Error: missing ISOFILES_DIR var: the directory containing the newly-built kernel image and module files"
+	exit 1
+fi
+ISOFILES_DIR=$(readlink -m $ISOFILES_DIR)
+
+### required argument:  the IP address of this machine, as seen by the target machines
+if [ -z $SERVER_IP ] ; then 
+	echo "Error: missing SERVER_IP var: the IP address at which target machines can reach this machine"
+	exit 1
+fi
+
+### optional argument:  the TCP port of the HTTP server
+if [ -z $SERVER_PORT ] ; then 
+  SERVER_PORT=8091
+fi
+
+### optional argument:  directory that is being exposed as the root of the HTTP web server
+if [ -z $HTTP_ROOT ] ; then 
+  HTTP_ROOT=$THESEUS_BASE_DIR/.theseus_netboot_server
+	echo "No HTTP_ROOT directory given, using the default directory \"$HTTP_ROOT\""
+fi
+HTTP_ROOT=$(readlink -m $HTTP_ROOT)
+
+### optional argument:  the name of the directory that will contain the new build
+if [ -z $NEW_DIR_NAME ] ; then 
+  NEW_DIR_NAME=$(date -u +%Y-%m-%d_%H-%M-%S)
+	echo "No new directory name given, using the current date instead: $NEW_DIR_NAME"
+fi
+
+### required argument:  the path of the boot image that will be created
+if [ -z $NETBOOT_ISO ] ; then 
+	echo "Error: missing NETBOOT_ISO var: the path of the network boot image to create"
+	exit 1
+fi
+if [ -z $GRUB_MKRESCUE ] ; then 
+  GRUB_MKRESCUE=grub-mkrescue
+fi
+
+
+### Copy the new kernel image and modules into their own directory, along with a grub.cfg that loads them from there.
+### Each build keeps its own directory, such that a machine that is in the middle of booting
+### will still load all of its files from a single build, even if a newer build is published meanwhile.
+NEW_DIR=$(readlink -m $HTTP_ROOT/$NEW_DIR_NAME)
+rm -rf $NEW_DIR
+mkdir -p $NEW_DIR
+cp -r $ISOFILES_DIR/boot $ISOFILES_DIR/modules $NEW_DIR/
+rm -rf $NEW_DIR/boot/grub
+$GRUB_CFG_GENERATION $NEW_DIR/modules/ -r /$NEW_DIR_NAME -o $NEW_DIR/grub.cfg -a "$KERNEL_ARGS"
+
+### Mark the new build as the latest one, which is the one that target machines will boot.
+ln -sfn $NEW_DIR_NAME $HTTP_ROOT/latest
+
+
+### Create the boot image that target machines boot from, which only needs to be created once per server address.
+NETBOOT_ISOFILES=$(mktemp -d)
+mkdir -p $NETBOOT_ISOFILES/boot/grub
+$GRUB_CFG_GENERATION --netboot $SERVER_IP,$SERVER_PORT -o $NETBOOT_ISOFILES/boot/grub/grub.cfg
+$GRUB_MKRESCUE -o $NETBOOT_ISO $NETBOOT_ISOFILES  2> /dev/null
+rm -rf $NETBOOT_ISOFILES
+
+
+### Start up the actual HTTP server (after killing off any existing identical web server)
+WEBSERVER_CMD="python -m SimpleHTTPServer $SERVER_PORT"
+pkill -f "$WEBSERVER_CMD" || true
+echo "Starting simple HTTP server at \"$HTTP_ROOT\" with new build \"$NEW_DIR_NAME\""
+echo "Target machines can boot the latest build from the network boot image at \"$NETBOOT_ISO\""
+cd $HTTP_ROOT && $($WEBSERVER_CMD) > /dev/null  2>&1  &
@@ -1,5 +1,9 @@
 //! Parses the directory of built object files
 //! and generates the multiboot2-compliant grub.cfg file.
+//!
+//! With the `--netboot` option, it instead generates a small grub.cfg for network booting,
+//! which uses the firmware's network stack to fetch the latest build's own grub.cfg
+//! (and thus its kernel image and all of its modules) from an HTTP server.
 
 
 extern crate getopts;
@@ -15,6 +19,9 @@ fn main() -> Result<(), String> {
 
     let mut opts = Options::new();
     opts.optopt("o", "", "set output file path, e.g., \"/my/dir/grub.cfg\"", "OUTPUT_PATH");
+    opts.optopt("a", "args", "set the kernel's boot command line, e.g., \"bad_frames=0x12345000\"", "KERNEL_ARGS");
+    opts.optopt("r", "root", "set the directory that contains the \"boot\" and \"modules\" directories, e.g., \"/my_build\" (default: \"\")", "ROOT_DIR");
+    opts.optopt("n", "netboot", "generate a grub.cfg that boots the latest build from the given HTTP server, e.g., \"192.168.1.105,8091\"", "SERVER[,PORT]");
     opts.optflag("h", "help", "print this help menu");
 
     let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
@@ -24,15 +31,21 @@ fn main() -> Result<(), String> {
         process::exit(0);
     }
 
-    // Require input directory 
-    let input_directory = match matches.free.len() {
-        0 => return Err(format!("no input directory provided")),
-        1 => matches.free[0].clone(), 
-        _ => return Err(format!("Too many arguments entered")),
+    let grub_cfg_string = if let Some(server) = matches.opt_str("n") {
+        create_netboot_grub_cfg_string(&server)
+    } else {
+        // Require input directory 
+        let input_directory = match matches.free.len() {
+            0 => return Err(format!("no input directory provided")),
+            1 => matches.free[0].clone(), 
+            _ => return Err(format!("Too many arguments entered")),
+        };
+
+        let kernel_args = matches.opt_str("a").unwrap_or_default();
+        let root_dir = matches.opt_str("r").unwrap_or_default();
+        create_grub_cfg_string(input_directory, &kernel_args, &root_dir)?
     };
     
-    let grub_cfg_string = create_grub_cfg_string(input_directory)?;
-    
     // Write to output file (if provided) 
     if matches.opt_present("o") {
         let output_file_path = matches.opt_str("o")
@@ -48,37 +61,62 @@ fn main() -> Result<(), String> {
 }
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options] INPUT_DIRECTORY", program);
+    let brief = format!("Usage: {0} [options] INPUT_DIRECTORY\n       {0} [options] --netboot SERVER[,PORT]", program);
     print!("{}", opts.usage(&brief));
 }
 
-fn create_grub_cfg_string(input_directory: String) -> Result<String, String> {
-    // Creates string to write to grub.cfg file by looking through all files in input_directory
-    let mut content = String::new();
-    
+/// Returns the header comment at the top of every generated grub.cfg file.
+fn header() -> String {
     let mut path_to_exe = std::env::current_exe().unwrap_or(std::path::PathBuf::new());
     // go up three directories to remove the "target/<build_mode>/name"
     path_to_exe.pop(); path_to_exe.pop(); path_to_exe.pop();
 
+    let mut content = String::new();
     content.push_str("### This file has been autogenerated, do not manually modify it!\n");
     content.push_str(&format!("### Generated by program: \"{}\"\n", path_to_exe.display()));
+    content
+}
+
+fn create_grub_cfg_string(input_directory: String, kernel_args: &str, root_dir: &str) -> Result<String, String> {
+    // Creates string to write to grub.cfg file by looking through all files in input_directory
+    let mut content = header();
+    let root_dir = root_dir.trim_end_matches('/');
+
     content.push_str(&format!("### Input directory: \"{}\"\n\n", &input_directory));
     content.push_str("set timeout=0\n");
     content.push_str("set default=0\n\n");
     content.push_str("menuentry \"Theseus OS\" {\n");
-    content.push_str("\tmultiboot2 /boot/kernel.bin \n");
+    content.push_str(&format!("\tmultiboot2 {}/boot/kernel.bin {}\n", root_dir, kernel_args));
 
     for path in fs::read_dir(input_directory).map_err(|e| e.to_string())? {
         let path = path.map_err(|e| e.to_string())?;
         let p = path.path();
         let file_name = p.file_name().and_then(|f| f.to_str()).ok_or(format!("Path error in path {:?}", path))?;
-        content.push_str(&format!("\tmodule2 /modules/{0:50}\t\t{1:50}\n", file_name, file_name));
+        content.push_str(&format!("\tmodule2 {0}/modules/{1:50}\t\t{2:50}\n", root_dir, file_name, file_name));
     }
 
     content.push_str("\n\tboot\n}\n");
     Ok(content)
 }
 
+/// Creates a grub.cfg that obtains an IP address using DHCP and then loads the grub.cfg 
+/// of the latest build from the given HTTP `server`, which is an IP address optionally followed by a comma and port.
+/// The server hosts each build in its own directory, with a `latest` symlink to the most recent one
+/// (see `scripts/netboot_server.sh`).
+///
+/// On UEFI machines, GRUB uses the firmware's network stack, so this grub.cfg can be booted from any local media.
+/// On BIOS machines, GRUB itself must have been booted over PXE, e.g., from an image created by `grub-mknetdir`.
+fn create_netboot_grub_cfg_string(server: &str) -> String {
+    let mut content = header();
+    content.push_str(&format!("### Build server: \"{}\"\n\n", server));
+    content.push_str("if [ \"$grub_platform\" = \"efi\" ]; then\n\tinsmod efinet\nelse\n\tinsmod pxe\nfi\n");
+    content.push_str("insmod http\n\n");
+    content.push_str("net_bootp\n");
+    content.push_str(&format!("set root=(http,{})\n", server));
+    content.push_str("configfile /latest/grub.cfg\n");
+    content
+}
+
 fn write_content(content: String, output_file_path: String) {
     if let Ok(mut file) = fs::File::create(output_file_path) {
         if file.write(content.as_bytes()).is_ok(){ process::exit(0); }
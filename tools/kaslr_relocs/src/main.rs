@@ -0,0 +1,161 @@
+//! Extracts the relocation table that the boot code (`nano_core/src/boot/arch_x86_64/boot.asm`) uses
+//! to move the nano_core to a random virtual address at boot, see the `kaslr` crate.
+//!
+//! The input must be the nano_core binary linked with `--emit-relocs`, such that it still contains
+//! the relocation entries of every section. Only absolute relocations whose value points into the
+//! higher-half part of the kernel image need to be patched when that part is moved;
+//! PC-relative relocations stay correct because all higher-half sections are moved together.
+//!
+//! The output is a flat binary file of little-endian `u32` entries, each of which is the physical address
+//! at which one such absolute address is stored, either as a 64-bit (`R_X86_64_64`)
+//! or a 32-bit sign-extended (`R_X86_64_32S`) value.
+//! Because the boot code only moves the kernel image within the top 2GiB of the address space,
+//! it patches both kinds the same way, by adding the slide to their lower 32 bits.
+//!
+//! Usage: `kaslr_relocs INPUT_ELF OUTPUT_FILE`
+
+use std::env;
+use std::fs;
+use std::process;
+
+/// The virtual address at which the higher-half kernel sections are linked,
+/// which must match `KERNEL_OFFSET` in the linker script and in `kernel_config`.
+const KERNEL_OFFSET: u64 = 0xFFFF_FFFF_8000_0000;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_RELA: u32 = 4;
+const SHF_ALLOC: u64 = 0x2;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_32S: u32 = 11;
+
+struct Section {
+    sh_type: u32,
+    flags:   u64,
+    addr:    u64,
+    offset:  u64,
+    size:    u64,
+    info:    u32,
+    entsize: u64,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} INPUT_ELF OUTPUT_FILE", args[0]);
+        process::exit(1);
+    }
+
+    let result = fs::read(&args[1])
+        .map_err(|e| format!("couldn't read {:?}: {}", args[1], e))
+        .and_then(|elf| relocation_table(&elf))
+        .and_then(|entries| {
+            let bytes: Vec<u8> = entries.iter().flat_map(|e| e.to_le_bytes().to_vec()).collect();
+            fs::write(&args[2], bytes).map_err(|e| format!("couldn't write {:?}: {}", args[2], e))
+        });
+    if let Err(e) = result {
+        eprintln!("kaslr_relocs: error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Returns the sorted relocation table entries of the given ELF file, see the crate-level docs.
+fn relocation_table(elf: &[u8]) -> Result<Vec<u32>, String> {
+    let sections = parse_section_headers(elf)?;
+
+    // The range of virtual addresses covered by the higher-half kernel sections, including the end address
+    // since some symbols (e.g., the top of the initial stack) point just past the end of a section.
+    let higher_half = sections.iter().filter(|s| s.flags & SHF_ALLOC != 0 && s.addr >= KERNEL_OFFSET);
+    let image_start = higher_half.clone().map(|s| s.addr).min().ok_or("no higher-half sections found")?;
+    let image_end = higher_half.map(|s| s.addr + s.size).max().ok_or("no higher-half sections found")?;
+
+    let mut entries = Vec::new();
+    for rela in sections.iter().filter(|s| s.sh_type == SHT_RELA) {
+        let target = sections.get(rela.info as usize).ok_or("relocation section had an invalid target section index")?;
+        // Relocations in non-loaded sections, e.g., debug info, don't matter at runtime.
+        if target.flags & SHF_ALLOC == 0 {
+            continue;
+        }
+        let entsize = if rela.entsize == 0 { 24 } else { rela.entsize };
+        for i in 0 .. rela.size / entsize {
+            let entry = slice(elf, rela.offset + i * entsize, 24)?;
+            let r_offset = read_u64(entry, 0);
+            let r_type = read_u64(entry, 8) as u32;
+            if r_type != R_X86_64_64 && r_type != R_X86_64_32S {
+                continue;
+            }
+            if target.sh_type != SHT_PROGBITS {
+                return Err(format!("absolute relocation at {:#X} is in a section without file contents", r_offset));
+            }
+            let value = if r_type == R_X86_64_64 {
+                read_u64(target_bytes(elf, target, r_offset, 8)?, 0)
+            } else {
+                read_u32(target_bytes(elf, target, r_offset, 4)?, 0) as i32 as i64 as u64
+            };
+            if value < image_start || value > image_end {
+                continue;
+            }
+            let phys_addr = if r_offset >= KERNEL_OFFSET { r_offset - KERNEL_OFFSET } else { r_offset };
+            if phys_addr > u32::MAX as u64 {
+                return Err(format!("absolute relocation at {:#X} has a physical address above 4GiB", r_offset));
+            }
+            entries.push(phys_addr as u32);
+        }
+    }
+    entries.sort();
+    entries.dedup();
+    Ok(entries)
+}
+
+fn parse_section_headers(elf: &[u8]) -> Result<Vec<Section>, String> {
+    let header = slice(elf, 0, 64)?;
+    if &header[0..4] != b"\x7fELF" || header[4] != 2 || header[5] != 1 {
+        return Err("input is not a little-endian 64-bit ELF file".into());
+    }
+    let shoff = read_u64(header, 0x28);
+    let shentsize = read_u16(header, 0x3A) as u64;
+    let shnum = read_u16(header, 0x3C) as u64;
+
+    (0 .. shnum).map(|i| {
+        let sh = slice(elf, shoff + i * shentsize, 64)?;
+        Ok(Section {
+            sh_type: read_u32(sh, 4),
+            flags:   read_u64(sh, 8),
+            addr:    read_u64(sh, 16),
+            offset:  read_u64(sh, 24),
+            size:    read_u64(sh, 32),
+            info:    read_u32(sh, 44),
+            entsize: read_u64(sh, 56),
+        })
+    }).collect()
+}
+
+/// Returns the `len` bytes of the given `section`'s contents at the virtual address `vaddr`.
+fn target_bytes<'e>(elf: &'e [u8], section: &Section, vaddr: u64, len: u64) -> Result<&'e [u8], String> {
+    if vaddr < section.addr || vaddr + len > section.addr + section.size {
+        return Err(format!("relocation at {:#X} is outside of its target section", vaddr));
+    }
+    slice(elf, section.offset + (vaddr - section.addr), len)
+}
+
+fn slice(elf: &[u8], offset: u64, len: u64) -> Result<&[u8], String> {
+    elf.get(offset as usize .. (offset + len) as usize)
+        .ok_or_else(|| format!("ELF file is truncated at offset {:#X}", offset))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    let mut b = [0; 2];
+    b.copy_from_slice(&bytes[offset .. offset + 2]);
+    u16::from_le_bytes(b)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&bytes[offset .. offset + 4]);
+    u32::from_le_bytes(b)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&bytes[offset .. offset + 8]);
+    u64::from_le_bytes(b)
+}